@@ -12,13 +12,10 @@ use arch::fdt::{
     Error, Result,
 };
 use arch::SERIAL_ADDR;
-use devices::{PciAddress, PciInterruptPin};
+use devices::{PciAddress, PciInterruptPin, PCI_CONFIG_REGISTER_BITS};
 use hypervisor::PsciVersion;
 use vm_memory::{GuestAddress, GuestMemory};
 
-// This is the start of DRAM in the physical address space.
-use crate::AARCH64_PHYS_MEM_START;
-
 // These are GIC address-space location constants.
 use crate::AARCH64_GIC_CPUI_BASE;
 use crate::AARCH64_GIC_CPUI_SIZE;
@@ -61,9 +58,9 @@ const IRQ_TYPE_EDGE_RISING: u32 = 0x00000001;
 const IRQ_TYPE_LEVEL_HIGH: u32 = 0x00000004;
 const IRQ_TYPE_LEVEL_LOW: u32 = 0x00000008;
 
-fn create_memory_node(fdt: &mut Vec<u8>, guest_mem: &GuestMemory) -> Result<()> {
+fn create_memory_node(fdt: &mut Vec<u8>, guest_mem: &GuestMemory, ram_start: u64) -> Result<()> {
     let mem_size = guest_mem.memory_size();
-    let mem_reg_prop = generate_prop64(&[AARCH64_PHYS_MEM_START, mem_size]);
+    let mem_reg_prop = generate_prop64(&[ram_start, mem_size]);
 
     begin_node(fdt, "memory")?;
     property_string(fdt, "device_type", "memory")?;
@@ -269,7 +266,7 @@ fn create_pci_nodes(
 
     for (address, irq_num, irq_pin) in pci_irqs.iter() {
         // PCI_DEVICE(3)
-        interrupts.push(address.to_config_address(0));
+        interrupts.push(address.to_config_address(0, PCI_CONFIG_REGISTER_BITS));
         interrupts.push(0);
         interrupts.push(0);
 
@@ -350,9 +347,10 @@ fn create_rtc_node(fdt: &mut Vec<u8>) -> Result<()> {
 ///
 /// * `fdt_max_size` - The amount of space reserved for the device tree
 /// * `guest_mem` - The guest memory object
+/// * `ram_start` - The guest physical address RAM starts at
 /// * `pci_irqs` - List of PCI device address to PCI interrupt number and pin mappings
 /// * `num_cpus` - Number of virtual CPUs the guest will have
-/// * `fdt_load_offset` - The offset into physical memory for the device tree
+/// * `fdt_load_offset` - The offset into RAM for the device tree
 /// * `pci_device_base` - The offset into physical memory for PCI device memory
 /// * `pci_device_size` - The size of PCI device memory
 /// * `cmdline` - The kernel commandline
@@ -363,6 +361,7 @@ fn create_rtc_node(fdt: &mut Vec<u8>) -> Result<()> {
 pub fn create_fdt(
     fdt_max_size: usize,
     guest_mem: &GuestMemory,
+    ram_start: u64,
     pci_irqs: Vec<(PciAddress, u32, PciInterruptPin)>,
     num_cpus: u32,
     fdt_load_offset: u64,
@@ -388,7 +387,7 @@ pub fn create_fdt(
         arch::android::create_android_fdt(&mut fdt, android_fstab)?;
     }
     create_chosen_node(&mut fdt, cmdline, initrd)?;
-    create_memory_node(&mut fdt, guest_mem)?;
+    create_memory_node(&mut fdt, guest_mem, ram_start)?;
     create_cpu_nodes(&mut fdt, num_cpus)?;
     create_gic_node(&mut fdt, is_gicv3, num_cpus as u64)?;
     create_timer_node(&mut fdt, num_cpus)?;
@@ -406,7 +405,7 @@ pub fn create_fdt(
     let mut fdt_final = vec![0; fdt_max_size];
     finish_fdt(&mut fdt, &mut fdt_final, fdt_max_size)?;
 
-    let fdt_address = GuestAddress(AARCH64_PHYS_MEM_START + fdt_load_offset);
+    let fdt_address = GuestAddress(ram_start + fdt_load_offset);
     let written = guest_mem
         .write_at_addr(fdt_final.as_slice(), fdt_address)
         .map_err(|_| Error::FdtGuestMemoryWriteError)?;
@@ -11,12 +11,13 @@ use std::io;
 use std::sync::Arc;
 
 use arch::{
-    get_serial_cmdline, GetSerialCmdlineError, RunnableLinuxVm, SerialHardware, SerialParameters,
-    VmComponents, VmImage,
+    get_serial_cmdline, ArchLayout, GetSerialCmdlineError, PvFeatures, RunnableLinuxVm,
+    SerialHardware, SerialParameters, VmComponents, VmImage,
 };
 use base::Event;
 use devices::{
     Bus, BusError, IrqChip, IrqChipAArch64, PciAddress, PciConfigMmio, PciDevice, PciInterruptPin,
+    PCI_CONFIG_REGISTER_BITS,
 };
 use hypervisor::{
     DeviceKind, Hypervisor, HypervisorCap, PsciVersion, VcpuAArch64, VcpuFeature, VmAArch64,
@@ -25,7 +26,7 @@ use minijail::Minijail;
 use remain::sorted;
 use resources::SystemAllocator;
 use sync::Mutex;
-use vm_control::BatteryType;
+use vm_control::{BatteryType, RtcWakeAlarm};
 use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
 
 mod fdt;
@@ -76,8 +77,8 @@ macro_rules! arm64_core_reg {
     };
 }
 
-fn get_kernel_addr() -> GuestAddress {
-    GuestAddress(AARCH64_PHYS_MEM_START + AARCH64_KERNEL_OFFSET)
+fn get_kernel_addr(ram_start: u64) -> GuestAddress {
+    GuestAddress(ram_start + AARCH64_KERNEL_OFFSET)
 }
 
 // Serial device requires 8 bytes of registers;
@@ -129,6 +130,7 @@ pub enum Error {
     GetPsciVersion(base::Error),
     GetSerialCmdline(GetSerialCmdlineError),
     InitrdLoadFailure(arch::LoadImageError),
+    InvalidLayout(&'static str),
     KernelLoadFailure(arch::LoadImageError),
     KernelMissing,
     RegisterIrqfd(base::Error),
@@ -163,6 +165,7 @@ impl Display for Error {
             GetPsciVersion(e) => write!(f, "failed to get PSCI version: {}", e),
             GetSerialCmdline(e) => write!(f, "failed to get serial cmdline: {}", e),
             InitrdLoadFailure(e) => write!(f, "initrd cound not be loaded: {}", e),
+            InvalidLayout(reason) => write!(f, "invalid guest address space layout: {}", reason),
             KernelLoadFailure(e) => write!(f, "kernel cound not be loaded: {}", e),
             KernelMissing => write!(f, "aarch64 requires a kernel"),
             RegisterIrqfd(e) => write!(f, "failed to register irq fd: {}", e),
@@ -182,8 +185,26 @@ impl std::error::Error for Error {}
 
 /// Returns a Vec of the valid memory addresses.
 /// These should be used to configure the GuestMemory structure for the platfrom.
-pub fn arch_memory_regions(size: u64) -> Vec<(GuestAddress, u64)> {
-    vec![(GuestAddress(AARCH64_PHYS_MEM_START), size)]
+pub fn arch_memory_regions(size: u64, ram_start: u64) -> Vec<(GuestAddress, u64)> {
+    vec![(GuestAddress(ram_start), size)]
+}
+
+// Returns where RAM starts in the guest physical address space. RAM can move up, but not below
+// the GIC, and has to stay aligned for the kernel.
+fn get_ram_start(layout: &ArchLayout) -> Result<u64> {
+    const RAM_START_ALIGN: u64 = 0x200000;
+    if layout.low_mmio_size.is_some() || layout.identity_map_addr.is_some() {
+        return Err(Error::InvalidLayout(
+            "only the RAM start and high MMIO window can be set on aarch64",
+        ));
+    }
+    match layout.ram_start {
+        Some(start) if start < AARCH64_AXI_BASE || start % RAM_START_ALIGN != 0 => Err(
+            Error::InvalidLayout("RAM must start on a 2 MiB boundary at or above 1 GiB"),
+        ),
+        Some(start) => Ok(start),
+        None => Ok(AARCH64_PHYS_MEM_START),
+    }
 }
 
 fn fdt_offset(mem_size: u64) -> u64 {
@@ -208,7 +229,7 @@ impl arch::LinuxArch for AArch64 {
         create_irq_chip: FI,
     ) -> std::result::Result<RunnableLinuxVm<V, Vcpu, I>, Self::Error>
     where
-        V: VmAArch64,
+        V: VmAArch64 + 'static,
         Vcpu: VcpuAArch64,
         I: IrqChipAArch64,
         FD: FnOnce(
@@ -223,9 +244,14 @@ impl arch::LinuxArch for AArch64 {
         E2: StdError + 'static,
         E3: StdError + 'static,
     {
-        let mut resources =
-            Self::get_resource_allocator(components.memory_size, components.wayland_dmabuf);
-        let mem = Self::setup_memory(components.memory_size)?;
+        let ram_start = get_ram_start(&components.layout)?;
+        let mut resources = Self::get_resource_allocator(
+            components.memory_size,
+            ram_start,
+            &components.layout,
+            components.wayland_dmabuf,
+        )?;
+        let mem = Self::setup_memory(components.memory_size, ram_start)?;
         let mut vm = create_vm(mem.clone()).map_err(|e| Error::CreateVm(Box::new(e)))?;
 
         let mut use_pmu = vm
@@ -239,7 +265,7 @@ impl arch::LinuxArch for AArch64 {
                 .map_err(Error::CreateVcpu)?
                 .downcast::<Vcpu>()
                 .map_err(|_| Error::DowncastVcpu)?;
-            Self::configure_vcpu_early(vm.get_memory(), &vcpu, vcpu_id, use_pmu)?;
+            Self::configure_vcpu_early(vm.get_memory(), &vcpu, vcpu_id, ram_start, use_pmu)?;
             vcpus.push(vcpu);
         }
 
@@ -269,7 +295,11 @@ impl arch::LinuxArch for AArch64 {
             (devices::AARCH64_GIC_NR_IRQS - AARCH64_IRQ_BASE) as usize,
         )
         .map_err(Error::CreatePciRoot)?;
-        let pci_bus = Arc::new(Mutex::new(PciConfigMmio::new(pci)));
+        let pci = Arc::new(Mutex::new(pci));
+        let pci_bus = Arc::new(Mutex::new(PciConfigMmio::new(
+            pci.clone(),
+            PCI_CONFIG_REGISTER_BITS,
+        )));
 
         // ARM doesn't really use the io bus like x86, so just create an empty bus.
         let io_bus = devices::Bus::new();
@@ -314,14 +344,17 @@ impl arch::LinuxArch for AArch64 {
 
         // separate out kernel loading from other setup to get a specific error for
         // kernel loading
-        let kernel_size = arch::load_image(&mem, kernel_image, get_kernel_addr(), u64::max_value())
+        let kernel_addr = get_kernel_addr(ram_start);
+        let kernel_size = arch::load_image(&mem, kernel_image, kernel_addr, u64::max_value())
             .map_err(Error::KernelLoadFailure)?;
-        let kernel_end = get_kernel_addr().offset() + kernel_size as u64;
+        let kernel_end = kernel_addr.offset() + kernel_size as u64;
         let psci_version = vcpus[0].get_psci_version().map_err(Error::GetPsciVersion)?;
 
         Self::setup_system_memory(
             &mem,
             components.memory_size,
+            ram_start,
+            &components.layout,
             vcpu_count,
             &CString::new(cmdline).unwrap(),
             components.initrd_image,
@@ -341,6 +374,8 @@ impl arch::LinuxArch for AArch64 {
             vcpus: Some(vcpus),
             vcpu_affinity: components.vcpu_affinity,
             no_smt: components.no_smt,
+            pv_features: components.pv_features,
+            layout: components.layout,
             irq_chip,
             has_bios: false,
             io_bus,
@@ -349,6 +384,8 @@ impl arch::LinuxArch for AArch64 {
             suspend_evt,
             rt_cpus: components.rt_cpus,
             bat_control: None,
+            rtc_wake_alarm: RtcWakeAlarm::default(),
+            pci_root: pci,
         })
     }
 
@@ -361,6 +398,8 @@ impl arch::LinuxArch for AArch64 {
         _num_cpus: usize,
         _has_bios: bool,
         _no_smt: bool,
+        _pv_features: PvFeatures,
+        _layout: ArchLayout,
     ) -> std::result::Result<(), Self::Error> {
         // AArch64 doesn't configure vcpus on the vcpu thread, so nothing to do here.
         Ok(())
@@ -371,6 +410,8 @@ impl AArch64 {
     fn setup_system_memory(
         mem: &GuestMemory,
         mem_size: u64,
+        ram_start: u64,
+        layout: &ArchLayout,
         vcpu_count: usize,
         cmdline: &CStr,
         initrd_file: Option<File>,
@@ -386,7 +427,7 @@ impl AArch64 {
                 let mut initrd_file = initrd_file;
                 let initrd_addr =
                     (kernel_end + (AARCH64_INITRD_ALIGN - 1)) & !(AARCH64_INITRD_ALIGN - 1);
-                let initrd_max_size = mem_size - (initrd_addr - AARCH64_PHYS_MEM_START);
+                let initrd_max_size = mem_size - (initrd_addr - ram_start);
                 let initrd_addr = GuestAddress(initrd_addr);
                 let initrd_size =
                     arch::load_image(mem, &mut initrd_file, initrd_addr, initrd_max_size)
@@ -395,10 +436,12 @@ impl AArch64 {
             }
             None => None,
         };
-        let (pci_device_base, pci_device_size) = Self::get_high_mmio_base_size(mem_size);
+        let (pci_device_base, pci_device_size) =
+            Self::get_high_mmio_base_size(mem_size, ram_start, layout)?;
         fdt::create_fdt(
             AARCH64_FDT_MAX_SIZE as usize,
             mem,
+            ram_start,
             pci_irqs,
             vcpu_count as u32,
             fdt_offset(mem_size),
@@ -415,16 +458,37 @@ impl AArch64 {
         Ok(())
     }
 
-    fn setup_memory(mem_size: u64) -> Result<GuestMemory> {
-        let arch_mem_regions = arch_memory_regions(mem_size);
+    fn setup_memory(mem_size: u64, ram_start: u64) -> Result<GuestMemory> {
+        let arch_mem_regions = arch_memory_regions(mem_size, ram_start);
         let mem = GuestMemory::new(&arch_mem_regions).map_err(Error::SetupGuestMemory)?;
         Ok(mem)
     }
 
-    fn get_high_mmio_base_size(mem_size: u64) -> (u64, u64) {
-        let base = AARCH64_PHYS_MEM_START + mem_size;
-        let size = u64::max_value() - base;
-        (base, size)
+    fn get_high_mmio_base_size(
+        mem_size: u64,
+        ram_start: u64,
+        layout: &ArchLayout,
+    ) -> Result<(u64, u64)> {
+        let ram_end = ram_start + mem_size;
+        let base = match layout.high_mmio_start {
+            Some(base) if base < ram_end => {
+                return Err(Error::InvalidLayout(
+                    "the high MMIO window must start above RAM",
+                ))
+            }
+            Some(base) => base,
+            None => ram_end,
+        };
+        let size = match layout.high_mmio_size {
+            Some(size) if size == 0 || base.checked_add(size - 1).is_none() => {
+                return Err(Error::InvalidLayout(
+                    "the high MMIO window must fit in the address space",
+                ))
+            }
+            Some(size) => size,
+            None => u64::max_value() - base,
+        };
+        Ok((base, size))
     }
 
     /// This returns a base part of the kernel command for this architecture
@@ -435,13 +499,19 @@ impl AArch64 {
     }
 
     /// Returns a system resource allocator.
-    fn get_resource_allocator(mem_size: u64, gpu_allocation: bool) -> SystemAllocator {
-        let (high_mmio_base, high_mmio_size) = Self::get_high_mmio_base_size(mem_size);
-        SystemAllocator::builder()
+    fn get_resource_allocator(
+        mem_size: u64,
+        ram_start: u64,
+        layout: &ArchLayout,
+        gpu_allocation: bool,
+    ) -> Result<SystemAllocator> {
+        let (high_mmio_base, high_mmio_size) =
+            Self::get_high_mmio_base_size(mem_size, ram_start, layout)?;
+        Ok(SystemAllocator::builder()
             .add_high_mmio_addresses(high_mmio_base, high_mmio_size)
             .add_low_mmio_addresses(AARCH64_MMIO_BASE, AARCH64_MMIO_SIZE)
             .create_allocator(AARCH64_IRQ_BASE, gpu_allocation)
-            .unwrap()
+            .unwrap())
     }
 
     /// This adds any early platform devices for this architecture.
@@ -475,11 +545,13 @@ impl AArch64 {
     /// * `guest_mem` - The guest memory object.
     /// * `vcpu` - The vcpu to configure.
     /// * `vcpu_id` - The VM's index for `vcpu`.
+    /// * `ram_start` - The guest physical address RAM starts at.
     /// * `use_pmu` - Should `vcpu` be configured to use the Performance Monitor Unit.
     fn configure_vcpu_early(
         guest_mem: &GuestMemory,
         vcpu: &dyn VcpuAArch64,
         vcpu_id: usize,
+        ram_start: u64,
         use_pmu: bool,
     ) -> Result<()> {
         let mut features = vec![VcpuFeature::PsciV0_2];
@@ -503,13 +575,13 @@ impl AArch64 {
 
         // Other cpus are powered off initially
         if vcpu_id == 0 {
-            data = AARCH64_PHYS_MEM_START + AARCH64_KERNEL_OFFSET;
+            data = get_kernel_addr(ram_start).offset();
             reg_id = arm64_core_reg!(pc);
             vcpu.set_one_reg(reg_id, data).map_err(Error::SetReg)?;
 
             /* X0 -- fdt address */
             let mem_size = guest_mem.memory_size();
-            data = (AARCH64_PHYS_MEM_START + fdt_offset(mem_size)) as u64;
+            data = (ram_start + fdt_offset(mem_size)) as u64;
             // hack -- can't get this to do offsetof(regs[0]) but luckily it's at offset 0
             reg_id = arm64_core_reg!(regs);
             vcpu.set_one_reg(reg_id, data).map_err(Error::SetReg)?;
@@ -31,6 +31,7 @@ use sync::Mutex;
 use vm_control::VmControlRequestSocket;
 use vm_control::{
     BatControl, BatControlCommand, BatControlRequestSocket, BatControlResult, BatteryType,
+    RtcWakeAlarm,
 };
 use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
 
@@ -76,6 +77,52 @@ pub enum VcpuAffinity {
     PerVcpu(BTreeMap<usize, Vec<usize>>),
 }
 
+/// Paravirtualized scheduling features offered to the guest, if the hypervisor supports them.
+///
+/// They help overcommitted guests, whose VCPUs may be preempted by the host at any time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PvFeatures {
+    /// Accounting of the time the host spent running other tasks instead of a VCPU, reported by
+    /// the guest as steal time.
+    pub steal_time: bool,
+    /// Deferring TLB flushes of preempted VCPUs until they run again, instead of waiting for them
+    /// to handle an IPI. The guest also needs steal time for this.
+    pub tlb_flush: bool,
+    /// Sending an IPI to several VCPUs with a single hypercall.
+    pub send_ipi: bool,
+}
+
+impl Default for PvFeatures {
+    fn default() -> Self {
+        PvFeatures {
+            steal_time: true,
+            tlb_flush: true,
+            send_ipi: true,
+        }
+    }
+}
+
+/// Overrides for where the guest physical address space puts RAM and the windows devices are
+/// mapped in. Fields left `None` keep the architecture's default.
+///
+/// Moving things around makes room for passed-through devices with large BARs, or matches the
+/// memory map some firmware expects. Architectures reject the fields they can't honor.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ArchLayout {
+    /// Address RAM starts at. Only AArch64 can move it.
+    pub ram_start: Option<u64>,
+    /// Size of the hole at the top of the 32-bit address space that holds the MMIO regions below
+    /// 4 GiB, including the part assigned to devices. x86_64 only.
+    pub low_mmio_size: Option<u64>,
+    /// Start of the PCI window above RAM. It can't overlap RAM.
+    pub high_mmio_start: Option<u64>,
+    /// Size of the PCI window above RAM. Defaults to the rest of the address space.
+    pub high_mmio_size: Option<u64>,
+    /// Address of the page the hypervisor uses for the identity-mapped page table of real mode
+    /// code, followed by the three pages of the TSS. x86_64 only.
+    pub identity_map_addr: Option<u64>,
+}
+
 /// Holds the pieces needed to build a VM. Passed to `build_vm` in the `LinuxArch` trait below to
 /// create a `RunnableLinuxVm`.
 pub struct VmComponents {
@@ -83,15 +130,20 @@ pub struct VmComponents {
     pub vcpu_count: usize,
     pub vcpu_affinity: Option<VcpuAffinity>,
     pub no_smt: bool,
+    pub pv_features: PvFeatures,
+    pub layout: ArchLayout,
     pub vm_image: VmImage,
     pub android_fstab: Option<File>,
     pub pstore: Option<Pstore>,
     pub initrd_image: Option<File>,
+    pub pflash_image: Option<File>,
     pub extra_kernel_params: Vec<String>,
     pub wayland_dmabuf: bool,
     pub acpi_sdts: Vec<SDT>,
     pub rt_cpus: Vec<usize>,
     pub protected_vm: bool,
+    /// The system UUID the guest sees, in RFC 4122 byte order.
+    pub uuid: Option<[u8; 16]>,
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     pub gdb: Option<(u32, VmControlRequestSocket)>, // port and control socket.
 }
@@ -107,6 +159,8 @@ pub struct RunnableLinuxVm<V: VmArch, Vcpu: VcpuArch, I: IrqChipArch> {
     pub vcpus: Option<Vec<Vcpu>>,
     pub vcpu_affinity: Option<VcpuAffinity>,
     pub no_smt: bool,
+    pub pv_features: PvFeatures,
+    pub layout: ArchLayout,
     pub irq_chip: I,
     pub has_bios: bool,
     pub io_bus: Bus,
@@ -115,6 +169,9 @@ pub struct RunnableLinuxVm<V: VmArch, Vcpu: VcpuArch, I: IrqChipArch> {
     pub suspend_evt: Event,
     pub rt_cpus: Vec<usize>,
     pub bat_control: Option<BatControl>,
+    pub rtc_wake_alarm: RtcWakeAlarm,
+    /// Root of the PCI devices, used to put them to sleep while the VM is suspended.
+    pub pci_root: Arc<Mutex<PciRoot>>,
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     pub gdb: Option<(u32, VmControlRequestSocket)>,
 }
@@ -150,7 +207,7 @@ pub trait LinuxArch {
         create_irq_chip: FI,
     ) -> std::result::Result<RunnableLinuxVm<V, Vcpu, I>, Self::Error>
     where
-        V: VmArch,
+        V: VmArch + 'static,
         Vcpu: VcpuArch,
         I: IrqChipArch,
         FD: FnOnce(
@@ -176,6 +233,9 @@ pub trait LinuxArch {
     /// * `vcpu_id` - The id of the given `vcpu`.
     /// * `num_cpus` - Number of virtual CPUs the guest will have.
     /// * `has_bios` - Whether the `VmImage` is a `Bios` image
+    /// * `no_smt` - Whether the guest should see each VCPU as a separate core.
+    /// * `pv_features` - The paravirtualized features offered to the guest.
+    /// * `layout` - The layout of the guest physical address space given to `build_vm`.
     fn configure_vcpu(
         guest_mem: &GuestMemory,
         hypervisor: &dyn HypervisorArch,
@@ -185,6 +245,8 @@ pub trait LinuxArch {
         num_cpus: usize,
         has_bios: bool,
         no_smt: bool,
+        pv_features: PvFeatures,
+        layout: ArchLayout,
     ) -> Result<(), Self::Error>;
 
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
@@ -250,6 +312,8 @@ pub enum DeviceRegistrationError {
     MissingRequiredSerialDevice(u8),
     /// Could not add a device to the mmio bus.
     MmioInsert(BusError),
+    /// Could not clone the VM for the PCI root.
+    CloneVm(base::Error),
     /// Failed to register ioevent with VM.
     RegisterIoevent(base::Error),
     /// Failed to register irq event with VM.
@@ -285,6 +349,7 @@ impl Display for DeviceRegistrationError {
             EventCreate(e) => write!(f, "failed to create event: {}", e),
             MissingRequiredSerialDevice(n) => write!(f, "missing required serial device {}", n),
             MmioInsert(e) => write!(f, "failed to add to mmio bus: {}", e),
+            CloneVm(e) => write!(f, "failed to clone VM: {}", e),
             RegisterIoevent(e) => write!(f, "failed to register ioevent to VM: {}", e),
             RegisterIrqfd(e) => write!(f, "failed to register irq event to VM: {}", e),
             ProxyDeviceCreation(e) => write!(f, "failed to create proxy device: {}", e),
@@ -304,7 +369,7 @@ pub fn generate_pci_root(
     irq_chip: &mut impl IrqChip,
     mmio_bus: &mut Bus,
     resources: &mut SystemAllocator,
-    vm: &mut impl Vm,
+    vm: &mut (impl Vm + 'static),
     max_irqs: usize,
 ) -> Result<
     (
@@ -314,7 +379,8 @@ pub fn generate_pci_root(
     ),
     DeviceRegistrationError,
 > {
-    let mut root = PciRoot::new();
+    let root_vm = vm.try_clone().map_err(DeviceRegistrationError::CloneVm)?;
+    let mut root = PciRoot::new(mmio_bus.clone(), Some(Box::new(root_vm)));
     let mut pci_irqs = Vec::new();
     let mut pid_labels = BTreeMap::new();
 
@@ -390,11 +456,16 @@ pub fn generate_pci_root(
         device
             .register_device_capabilities()
             .map_err(DeviceRegistrationError::RegisterDeviceCapabilities)?;
+        let mut ioevents = Vec::new();
         for (event, addr, datamatch) in device.ioevents() {
             let io_addr = IoEventAddress::Mmio(addr);
             vm.register_ioevent(&event, io_addr, datamatch)
                 .map_err(DeviceRegistrationError::RegisterIoevent)?;
             keep_rds.push(event.as_raw_descriptor());
+            let event = event
+                .try_clone()
+                .map_err(DeviceRegistrationError::EventClone)?;
+            ioevents.push((event, addr, datamatch));
         }
         let arced_dev: Arc<Mutex<dyn BusDevice>> = if let Some(jail) = jail {
             let proxy = ProxyDevice::new(device, &jail, keep_rds)
@@ -405,18 +476,11 @@ pub fn generate_pci_root(
             device.on_sandboxed();
             Arc::new(Mutex::new(device))
         };
-        root.add_device(address, arced_dev.clone());
-        for range in &ranges {
-            mmio_bus
-                .insert(arced_dev.clone(), range.0, range.1)
-                .map_err(DeviceRegistrationError::MmioInsert)?;
-        }
-
-        for range in &device_ranges {
-            mmio_bus
-                .insert(arced_dev.clone(), range.0, range.1)
-                .map_err(DeviceRegistrationError::MmioInsert)?;
-        }
+        root.add_device(address, arced_dev);
+        root.add_device_ioevents(address, ioevents);
+        let mmio_ranges: Vec<(u64, u64)> = ranges.into_iter().chain(device_ranges).collect();
+        root.add_device_mmio_ranges(address, &mmio_ranges)
+            .map_err(DeviceRegistrationError::MmioInsert)?;
     }
     Ok((root, pci_irqs, pid_labels))
 }
@@ -7,14 +7,14 @@ use std::collections::BTreeMap;
 use std::fmt::{self, Display};
 use std::fs::{File, OpenOptions};
 use std::io::{self, stdin, stdout, ErrorKind};
-use std::os::unix::net::UnixDatagram;
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
-use base::{error, info, read_raw_stdin, syslog, AsRawDescriptor, Event, RawDescriptor};
+use base::{error, info, read_raw_stdin, syslog, AsRawDescriptor, Event, Pty, RawDescriptor};
 use devices::{Bus, ProxyDevice, Serial, SerialDevice};
 use minijail::Minijail;
 use sync::Mutex;
@@ -29,6 +29,8 @@ pub enum Error {
     InvalidSerialType(String),
     InvalidPath,
     PathRequired,
+    PtyCreateFailed(base::Error),
+    SocketBindFailed(std::io::Error),
     SocketCreateFailed,
     Unimplemented(SerialType),
 }
@@ -44,6 +46,8 @@ impl Display for Error {
             InvalidSerialType(e) => write!(f, "invalid serial type: {}", e),
             InvalidPath => write!(f, "serial device path is invalid"),
             PathRequired => write!(f, "serial device type file requires a path"),
+            PtyCreateFailed(e) => write!(f, "failed to allocate a pty: {}", e),
+            SocketBindFailed(e) => write!(f, "failed to bind serial socket: {}", e),
             SocketCreateFailed => write!(f, "failed to create unbound socket"),
             Unimplemented(e) => write!(f, "serial device type {} not implemented", e.to_string()),
         }
@@ -58,6 +62,8 @@ pub enum SerialType {
     Sink,
     Syslog,
     UnixSocket,
+    Pty,
+    UnixStream,
 }
 
 impl Display for SerialType {
@@ -68,6 +74,8 @@ impl Display for SerialType {
             SerialType::Sink => "Sink".to_string(),
             SerialType::Syslog => "Syslog".to_string(),
             SerialType::UnixSocket => "UnixSocket".to_string(),
+            SerialType::Pty => "Pty".to_string(),
+            SerialType::UnixStream => "UnixStream".to_string(),
         };
 
         write!(f, "{}", s)
@@ -83,6 +91,8 @@ impl FromStr for SerialType {
             "sink" | "Sink" => Ok(SerialType::Sink),
             "syslog" | "Syslog" => Ok(SerialType::Syslog),
             "unix" | "UnixSocket" => Ok(SerialType::UnixSocket),
+            "pty" | "Pty" => Ok(SerialType::Pty),
+            "unix-stream" | "UnixStream" => Ok(SerialType::UnixStream),
             _ => Err(Error::InvalidSerialType(s.to_string())),
         }
     }
@@ -184,6 +194,181 @@ impl io::Write for WriteSocket {
     }
 }
 
+impl SerialType {
+    /// Returns true if this type of serial device takes the guest's input from the same place its
+    /// output goes, rather than from stdin or an input file.
+    pub fn is_interactive(&self) -> bool {
+        match self {
+            SerialType::Pty | SerialType::UnixStream => true,
+            _ => false,
+        }
+    }
+}
+
+// Writes the guest's output to a pty, dropping it instead of blocking the guest once the terminal
+// side's buffer is full because nothing is reading from it.
+struct PtyOutput {
+    pty: Arc<Pty>,
+}
+
+impl io::Write for PtyOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pollfd = libc::pollfd {
+            fd: self.pty.controller().as_raw_descriptor(),
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        // Safe because we give a single valid pollfd and a zero timeout, and check the return value.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if pollfd.revents & libc::POLLOUT == 0 {
+            return Ok(buf.len());
+        }
+        self.pty.controller().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct PtyInput {
+    pty: Arc<Pty>,
+}
+
+impl io::Read for PtyInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.pty.controller().read(buf)
+    }
+}
+
+// Accepts clients on a listening unix stream socket, one at a time. Each client is sent the
+// guest's output and its input is passed to the guest until it disconnects, at which point the
+// next client is accepted.
+struct SocketInput {
+    listener: UnixListener,
+    client: Arc<Mutex<Option<UnixStream>>>,
+    current: Option<UnixStream>,
+}
+
+impl SocketInput {
+    fn detach(&mut self) {
+        self.current = None;
+        *self.client.lock() = None;
+    }
+}
+
+impl io::Read for SocketInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.current.is_none() {
+                let (stream, _) = self.listener.accept()?;
+                *self.client.lock() = Some(stream.try_clone()?);
+                self.current = Some(stream);
+            }
+            let result = match self.current.as_mut() {
+                Some(stream) => stream.read(buf),
+                None => continue,
+            };
+            match result {
+                // Returning 0 would end the guest's input, so wait for the next client instead.
+                Ok(0) => self.detach(),
+                Ok(n) => return Ok(n),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => return result,
+                Err(e) => {
+                    info!("serial socket client detached: {}", e);
+                    self.detach();
+                }
+            }
+        }
+    }
+}
+
+struct SocketOutput {
+    client: Arc<Mutex<Option<UnixStream>>>,
+}
+
+impl io::Write for SocketOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut client = self.client.lock();
+        if let Some(stream) = client.as_ref() {
+            // The guest's output is dropped rather than blocking the guest on a slow client, and a
+            // client that went away must not raise SIGPIPE.
+            // Safe because `buf` is valid for `buf.len()` bytes and we check the return value.
+            let ret = unsafe {
+                libc::send(
+                    stream.as_raw_descriptor(),
+                    buf.as_ptr() as *const libc::c_void,
+                    buf.len(),
+                    libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+                )
+            };
+            if ret < 0 && io::Error::last_os_error().kind() != ErrorKind::WouldBlock {
+                *client = None;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+type SerialStreams = (
+    Option<Box<dyn io::Read + Send>>,
+    Option<Box<dyn io::Write + Send>>,
+);
+
+// Allocates a pty for an interactive serial device and prints its path.
+fn create_pty_streams(
+    hardware: SerialHardware,
+    num: u8,
+    keep_rds: &mut Vec<RawDescriptor>,
+) -> std::result::Result<SerialStreams, Error> {
+    let pty = Arc::new(Pty::new().map_err(Error::PtyCreateFailed)?);
+    keep_rds.push(pty.controller().as_raw_descriptor());
+    keep_rds.push(pty.terminal().as_raw_descriptor());
+    info!(
+        "{} {} is attached to {}",
+        hardware,
+        num,
+        pty.path().display()
+    );
+    Ok((
+        Some(Box::new(PtyInput { pty: pty.clone() })),
+        Some(Box::new(PtyOutput { pty })),
+    ))
+}
+
+// Binds the listening socket for an interactive serial device, replacing a stale socket left at
+// `path` by a previous run.
+fn create_socket_streams(
+    path: &Path,
+    keep_rds: &mut Vec<RawDescriptor>,
+) -> std::result::Result<SerialStreams, Error> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        use std::os::unix::fs::FileTypeExt;
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path).map_err(Error::SocketBindFailed)?;
+        }
+    }
+    let listener = UnixListener::bind(path).map_err(Error::SocketBindFailed)?;
+    keep_rds.push(listener.as_raw_descriptor());
+    info!("serial socket listening at {}", path.display());
+    let client = Arc::new(Mutex::new(None));
+    Ok((
+        Some(Box::new(SocketInput {
+            listener,
+            client: client.clone(),
+            current: None,
+        })),
+        Some(Box::new(SocketOutput { client })),
+    ))
+}
+
 /// Holds the parameters for a serial device
 #[derive(Clone, Debug)]
 pub struct SerialParameters {
@@ -217,6 +402,18 @@ impl SerialParameters {
     ) -> std::result::Result<T, Error> {
         let evt = evt.try_clone().map_err(Error::CloneEvent)?;
         keep_rds.push(evt.as_raw_descriptor());
+        match self.type_ {
+            SerialType::Pty => {
+                let (input, output) = create_pty_streams(self.hardware, self.num, keep_rds)?;
+                return Ok(T::new(protected_vm, evt, input, output, keep_rds.to_vec()));
+            }
+            SerialType::UnixStream => {
+                let path = self.path.as_ref().ok_or(Error::PathRequired)?;
+                let (input, output) = create_socket_streams(path, keep_rds)?;
+                return Ok(T::new(protected_vm, evt, input, output, keep_rds.to_vec()));
+            }
+            _ => {}
+        }
         let input: Option<Box<dyn io::Read + Send>> = if let Some(input_path) = &self.input {
             let input_file = File::open(input_path.as_path()).map_err(Error::FileError)?;
             keep_rds.push(input_file.as_raw_descriptor());
@@ -327,6 +524,7 @@ impl SerialParameters {
                     None => return Err(Error::PathRequired),
                 }
             }
+            SerialType::Pty | SerialType::UnixStream => unreachable!(),
         };
         Ok(T::new(protected_vm, evt, input, output, keep_rds.to_vec()))
     }
@@ -536,6 +734,8 @@ pub fn get_serial_cmdline(
 mod tests {
     use super::*;
     use kernel_cmdline::Cmdline;
+    use std::io::{Read, Write};
+    use tempfile::TempDir;
 
     #[test]
     fn get_serial_cmdline_default() {
@@ -646,4 +846,35 @@ mod tests {
         get_serial_cmdline(&mut cmdline, &serial_parameters, "io")
             .expect_err("get_serial_cmdline succeeded");
     }
+
+    #[test]
+    fn unix_stream_reattach() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("serial.sock");
+        let mut keep_rds = Vec::new();
+        let (input, output) = create_socket_streams(&path, &mut keep_rds).unwrap();
+        let (mut input, mut output) = (input.unwrap(), output.unwrap());
+
+        // Output is dropped while no client is attached.
+        output.write_all(b"dropped").unwrap();
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        let mut buf = [0u8; 1];
+        client.write_all(b"a").unwrap();
+        input.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"a");
+        output.write_all(b"b").unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"b");
+        drop(client);
+
+        // The next client is accepted once the first one detaches.
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"c").unwrap();
+        input.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"c");
+        output.write_all(b"d").unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"d");
+    }
 }
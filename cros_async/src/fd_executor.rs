@@ -14,6 +14,7 @@ use std::collections::VecDeque;
 use std::fmt::{self, Display};
 use std::fs::File;
 use std::future::Future;
+use std::io;
 use std::os::unix::io::FromRawFd;
 use std::os::unix::io::RawFd;
 use std::pin::Pin;
@@ -64,6 +65,21 @@ impl Display for Error {
     }
 }
 
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        use Error::*;
+        match err {
+            DuplicatingFd(e) => e.into(),
+            CreatingContext(e) => e.into(),
+            PollContextError(e) => e.into(),
+            SubmittingWaker(e) => e.into(),
+            e => io::Error::new(io::ErrorKind::Other, e),
+        }
+    }
+}
+
 // Temporary vectors of new additions to the executor.
 
 // Tracks active wakers and the futures they are associated with.
@@ -19,8 +19,10 @@ use crate::poll_source::PollSource;
 use crate::UringSource;
 use async_trait::async_trait;
 use std::fs::File;
+use std::io;
 use std::os::unix::io::AsRawFd;
 use std::rc::Rc;
+use std::sync::Arc;
 use thiserror::Error as ThisError;
 
 use crate::uring_mem::{BackingMemory, MemRegion};
@@ -37,6 +39,16 @@ pub enum Error {
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        use Error::*;
+        match e {
+            Poll(e) => e.into(),
+            Uring(e) => e.into(),
+        }
+    }
+}
+
 /// Ergonomic methods for async reads.
 #[async_trait(?Send)]
 pub trait ReadAsync {
@@ -94,6 +106,32 @@ pub trait IoSourceExt<F>: ReadAsync + WriteAsync {
 
     /// Provides a ref to the underlying IO source.
     fn as_source(&self) -> &F;
+
+    /// Sets the priority of operations on this source relative to other sources on the same
+    /// executor. Only the uring executor acts on it; the default is `IoPriority::Normal`.
+    fn set_priority(&mut self, _priority: IoPriority) {}
+}
+
+/// How urgently completed operations on a source are handed back to their futures.
+///
+/// When the uring returns a batch of completions that mixes priorities, completions of `Low`
+/// sources are held back until the next turn of the executor so that futures waiting on
+/// latency-critical sources run first. A deferred completion is always delivered on the next
+/// turn, so bulk I/O is delayed but never starved.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IoPriority {
+    /// Bulk transfers such as block or network device data.
+    Low,
+    /// The default for sources that don't set a priority.
+    Normal,
+    /// Latency-critical sources such as consoles and input devices.
+    High,
+}
+
+impl Default for IoPriority {
+    fn default() -> Self {
+        IoPriority::Normal
+    }
 }
 
 /// Marker trait signifying that the implementor is suitable for use with
@@ -105,6 +143,7 @@ pub trait IoSourceExt<F>: ReadAsync + WriteAsync {
 pub trait IntoAsync: AsRawFd {}
 
 impl IntoAsync for File {}
+impl IntoAsync for Arc<File> {}
 impl IntoAsync for UnixSeqpacket {}
 impl IntoAsync for &UnixSeqpacket {}
 
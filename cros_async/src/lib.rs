@@ -75,8 +75,8 @@ mod waker;
 pub use event::EventAsync;
 pub use executor::Executor;
 pub use io_ext::{
-    async_from, Error as AsyncError, IntoAsync, IoSourceExt, ReadAsync, Result as AsyncResult,
-    WriteAsync,
+    async_from, Error as AsyncError, IntoAsync, IoPriority, IoSourceExt, ReadAsync,
+    Result as AsyncResult, WriteAsync,
 };
 pub use poll_source::PollSource;
 pub use select::SelectResult;
@@ -118,6 +118,12 @@ fn run_executor<T: FutureList>(future_list: T) -> Result<T::Output> {
     }
 }
 
+/// Returns true if the kernel supports io_uring. `run_one_uring` fails if it doesn't, so callers
+/// that need io_uring can check this first and fall back to a synchronous implementation.
+pub fn uring_available() -> bool {
+    uring_executor::use_uring()
+}
+
 /// Adds a new top level future to the Executor.
 /// These futures must return `()`, indicating they are intended to create side-effects only.
 pub fn add_future(future: Pin<Box<dyn Future<Output = ()>>>) -> Result<()> {
@@ -8,6 +8,7 @@
 use async_trait::async_trait;
 use std::borrow::Borrow;
 use std::future::Future;
+use std::io;
 use std::ops::{Deref, DerefMut};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::pin::Pin;
@@ -51,6 +52,21 @@ pub enum Error {
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        use Error::*;
+        match err {
+            AddingWaker(e) => e.into(),
+            Fallocate(e) => e.into(),
+            Fsync(e) => e.into(),
+            Read(e) => e.into(),
+            Seeking(e) => e.into(),
+            SettingNonBlocking(e) => e.into(),
+            Write(e) => e.into(),
+        }
+    }
+}
+
 /// Async wrapper for an IO source that uses the FD executor to drive async operations.
 /// Used by `IoSourceExt::new` when uring isn't available.
 pub struct PollSource<F: AsRawFd> {
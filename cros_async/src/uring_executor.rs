@@ -58,6 +58,7 @@ use std::fmt::{self, Display};
 use std::fs::File;
 use std::future::Future;
 use std::io;
+use std::mem;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::pin::Pin;
 use std::rc::Rc;
@@ -72,6 +73,7 @@ use io_uring::URingContext;
 use sys_util::WatchingEvents;
 
 use crate::executor::{ExecutableFuture, Executor, FutureList};
+use crate::io_ext::IoPriority;
 use crate::uring_mem::{BackingMemory, MemRegion};
 use crate::waker::WakerToken;
 
@@ -127,6 +129,17 @@ impl Display for Error {
 
 impl std::error::Error for Error {}
 
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        use Error::*;
+        match err {
+            DuplicatingFd(e) => e.into(),
+            Io(e) => e,
+            e => io::Error::new(io::ErrorKind::Other, e),
+        }
+    }
+}
+
 // Checks if the uring executor is available.
 // Caches the result so that the check is only run once.
 // Useful for falling back to the FD executor on pre-uring kernels.
@@ -177,6 +190,7 @@ static GENERATION: AtomicU64 = AtomicU64::new(0);
 pub struct RegisteredSource {
     generation: u64,
     tag: usize,
+    priority: IoPriority,
 
     // Since a `RegisteredSource` is associated with a thread-local executor, it cannot be Send or
     // Sync. However, negative trait impls are not supported yet so use an Rc, which is neither Send
@@ -276,6 +290,11 @@ impl RegisteredSource {
         pin_mut!(op);
         op.poll(cx)
     }
+
+    /// Sets the priority of operations started on this source from now on.
+    pub fn set_priority(&mut self, priority: IoPriority) {
+        self.priority = priority;
+    }
 }
 
 impl Drop for RegisteredSource {
@@ -290,6 +309,7 @@ struct OpData {
     _mem: Option<Rc<dyn BackingMemory>>,
     waker: Option<Waker>,
     canceled: bool,
+    priority: IoPriority,
 }
 
 // The current status of an operation that's been submitted to the uring.
@@ -304,6 +324,8 @@ struct RingWakerState {
     ops: Slab<OpStatus>,
     registered_sources: Slab<Rc<File>>,
     generation: u64,
+    // Completions of low priority ops held back from the last batch returned by the uring.
+    deferred: Vec<(usize, io::Result<u32>)>,
 }
 
 impl RingWakerState {
@@ -313,6 +335,7 @@ impl RingWakerState {
             ops: Slab::with_capacity(256),
             registered_sources: Slab::with_capacity(256),
             generation: GENERATION.fetch_add(1, Ordering::Relaxed),
+            deferred: Vec::new(),
         })
     }
 
@@ -327,6 +350,7 @@ impl RingWakerState {
             Ok(RegisteredSource {
                 generation: state.generation,
                 tag,
+                priority: IoPriority::default(),
                 _block_send_sync: Rc::new(()),
             })
         })?
@@ -363,6 +387,7 @@ impl RingWakerState {
             _mem: None,
             waker: None,
             canceled: false,
+            priority: source.priority,
         }));
         Ok(WakerToken(next_op_token))
     }
@@ -395,6 +420,7 @@ impl RingWakerState {
             _mem: None,
             waker: None,
             canceled: false,
+            priority: source.priority,
         }));
         Ok(WakerToken(next_op_token))
     }
@@ -415,6 +441,7 @@ impl RingWakerState {
             _mem: None,
             waker: None,
             canceled: false,
+            priority: source.priority,
         }));
         Ok(WakerToken(next_op_token))
     }
@@ -462,6 +489,7 @@ impl RingWakerState {
             _mem: Some(mem),
             waker: None,
             canceled: false,
+            priority: source.priority,
         }));
 
         Ok(WakerToken(next_op_token))
@@ -510,6 +538,7 @@ impl RingWakerState {
             _mem: Some(mem),
             waker: None,
             canceled: false,
+            priority: source.priority,
         }));
 
         Ok(WakerToken(next_op_token))
@@ -543,43 +572,79 @@ impl RingWakerState {
     }
 
     // Waits until one of the FDs is readable and wakes the associated waker.
+    //
+    // If the uring returns low priority completions alongside higher priority ones, the low
+    // priority ones are held back and delivered by the next call, without waiting on the uring.
     fn wait_wake_event() -> Result<()> {
         Self::with(|state| {
-            let events = state.ctx.wait().map_err(Error::URingEnter)?;
-            for (raw_token, result) in events {
-                // While the `expect()` might fail on arbitrary `u64`s, the `raw_token` was
-                // something that we originally gave to the kernel and that was created from a
-                // `usize` so we should always be able to convert it back into a `usize`.
-                let token = raw_token
-                    .try_into()
-                    .expect("`u64` doesn't fit inside a `usize`");
-
-                if let Some(op) = state.ops.get_mut(token) {
-                    match op {
-                        OpStatus::Pending(data) => {
-                            if data.canceled {
-                                // No one is waiting for this operation and the uring is done with
-                                // it so it's safe to remove.
-                                state.ops.remove(token);
-                            } else {
-                                let waker = data.waker.take();
-                                *op = OpStatus::Completed(Some(result));
-
-                                if let Some(waker) = waker {
-                                    waker.wake();
-                                }
-                            }
-                        }
-                        OpStatus::Completed(_) => {
-                            panic!("uring operation completed more than once")
-                        }
-                    }
+            if !state.deferred.is_empty() {
+                for (token, result) in mem::take(&mut state.deferred) {
+                    state.complete(token, result);
+                }
+                return Ok(());
+            }
+
+            let events: Vec<(usize, io::Result<u32>)> = state
+                .ctx
+                .wait()
+                .map_err(Error::URingEnter)?
+                .map(|(raw_token, result)| {
+                    // While the `expect()` might fail on arbitrary `u64`s, the `raw_token` was
+                    // something that we originally gave to the kernel and that was created from
+                    // a `usize` so we should always be able to convert it back into a `usize`.
+                    let token = raw_token
+                        .try_into()
+                        .expect("`u64` doesn't fit inside a `usize`");
+                    (token, result)
+                })
+                .collect();
+
+            let defer_low = events
+                .iter()
+                .any(|&(token, _)| state.op_priority(token) > IoPriority::Low);
+            for (token, result) in events {
+                if defer_low && state.op_priority(token) == IoPriority::Low {
+                    state.deferred.push((token, result));
+                } else {
+                    state.complete(token, result);
                 }
             }
             Ok(())
         })?
     }
 
+    // Returns the priority of the pending op for `token`. Ops that are no longer tracked are
+    // treated as `Normal` so they never cause or suffer a deferral.
+    fn op_priority(&self, token: usize) -> IoPriority {
+        match self.ops.get(token) {
+            Some(OpStatus::Pending(data)) => data.priority,
+            _ => IoPriority::Normal,
+        }
+    }
+
+    // Records the result of the op for `token` and wakes the future waiting on it.
+    fn complete(&mut self, token: usize, result: io::Result<u32>) {
+        if let Some(op) = self.ops.get_mut(token) {
+            match op {
+                OpStatus::Pending(data) => {
+                    if data.canceled {
+                        // No one is waiting for this operation and the uring is done with it so
+                        // it's safe to remove.
+                        self.ops.remove(token);
+                    } else {
+                        let waker = data.waker.take();
+                        *op = OpStatus::Completed(Some(result));
+
+                        if let Some(waker) = waker {
+                            waker.wake();
+                        }
+                    }
+                }
+                OpStatus::Completed(_) => panic!("uring operation completed more than once"),
+            }
+        }
+    }
+
     fn get_result(token: &WakerToken, waker: Waker) -> Result<Option<io::Result<u32>>> {
         Self::with(|state| {
             if let Some(op) = state.ops.get_mut(token.0) {
@@ -891,4 +956,45 @@ mod tests {
                 .expect("Failed to check `RingWakerState` for canceled operation")
         );
     }
+
+    #[test]
+    fn low_priority_completion_deferred() {
+        let bm = Rc::new(VecIoWrapper::from(vec![0u8; 16])) as Rc<dyn BackingMemory>;
+
+        let (low_rx, mut low_tx) = sys_util::pipe(true).expect("Pipe failed");
+        let (high_rx, mut high_tx) = sys_util::pipe(true).expect("Pipe failed");
+
+        let _ex = URingExecutor::new(crate::executor::UnitFutures::new()).unwrap();
+
+        let mut low_source = register_source(&low_rx).expect("register source failed");
+        low_source.set_priority(IoPriority::Low);
+        let mut high_source = register_source(&high_rx).expect("register source failed");
+        high_source.set_priority(IoPriority::High);
+
+        // Make both reads ready before they are submitted so they come back in the same batch.
+        low_tx.write(&[0u8; 8]).expect("write failed");
+        high_tx.write(&[0u8; 8]).expect("write failed");
+
+        let low_op = low_source
+            .start_read_to_mem(0, Rc::clone(&bm), &[MemRegion { offset: 0, len: 8 }])
+            .expect("failed to start read to mem");
+        let high_op = high_source
+            .start_read_to_mem(0, Rc::clone(&bm), &[MemRegion { offset: 8, len: 8 }])
+            .expect("failed to start read to mem");
+        let token = |op: &PendingOperation| op.waker_token.as_ref().map(|t| t.0).unwrap();
+        let completed = |token| {
+            RingWakerState::with(|state| {
+                matches!(state.ops.get(token), Some(OpStatus::Completed(_)))
+            })
+            .expect("Failed to check `RingWakerState` for completed operation")
+        };
+
+        RingWakerState::wait_wake_event().expect("Failed to wait for read pipes ready");
+        assert!(completed(token(&high_op)));
+        assert!(!completed(token(&low_op)));
+
+        // The deferred completion is delivered on the next turn.
+        RingWakerState::wait_wake_event().expect("Failed to deliver deferred completion");
+        assert!(completed(token(&low_op)));
+    }
 }
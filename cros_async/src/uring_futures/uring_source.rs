@@ -210,6 +210,11 @@ impl<F: AsRawFd> crate::IoSourceExt<F> for UringSource<F> {
     fn as_source_mut(&mut self) -> &mut F {
         &mut self.source
     }
+
+    /// Sets the priority used when delivering completions of this source's operations.
+    fn set_priority(&mut self, priority: crate::IoPriority) {
+        self.registered_source.set_priority(priority);
+    }
 }
 
 impl<F: AsRawFd> Deref for UringSource<F> {
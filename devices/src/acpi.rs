@@ -2,7 +2,8 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use crate::{BusAccessInfo, BusDevice, BusResumeDevice};
+use crate::suspendable;
+use crate::{BusAccessInfo, BusDevice, BusResumeDevice, Suspendable};
 use acpi_tables::{aml, aml::Aml};
 use base::{error, warn, Event};
 
@@ -129,6 +130,13 @@ impl BusDevice for ACPIPMResource {
     }
 }
 
+// The registers only act on guest accesses.
+impl Suspendable for ACPIPMResource {
+    fn sleep(&mut self) -> suspendable::Result<()> {
+        Ok(())
+    }
+}
+
 impl BusResumeDevice for ACPIPMResource {
     fn resume_imminent(&mut self) {
         let val = self.pm1_status;
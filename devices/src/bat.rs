@@ -2,7 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use crate::{BusAccessInfo, BusDevice};
+use crate::{BusAccessInfo, BusDevice, Suspendable};
 use acpi_tables::{aml, aml::Aml};
 use base::{
     error, warn, AsRawDescriptor, Descriptor, Event, PollToken, RawDescriptor, WaitContext,
@@ -476,6 +476,8 @@ impl BusDevice for GoldfishBattery {
     }
 }
 
+impl Suspendable for GoldfishBattery {}
+
 impl Aml for GoldfishBattery {
     fn to_aml_bytes(&self, bytes: &mut Vec<u8>) {
         aml::Device::new(
@@ -12,7 +12,10 @@ use std::sync::Arc;
 
 use base::RawDescriptor;
 use msg_socket::MsgOnSocket;
-use sync::Mutex;
+use sync::{Mutex, RwLock};
+use vm_control::QueueStats;
+
+use crate::Suspendable;
 
 /// Information about how a device was accessed.
 #[derive(Copy, Clone, Eq, PartialEq, Debug, MsgOnSocket)]
@@ -36,7 +39,7 @@ impl std::fmt::Display for BusAccessInfo {
 /// The device does not care where it exists in address space as each method is only given an offset
 /// into its allocated portion of address space.
 #[allow(unused_variables)]
-pub trait BusDevice: Send {
+pub trait BusDevice: Send + Suspendable {
     /// Returns a label suitable for debug output.
     fn debug_label(&self) -> String;
     /// Reads at `offset` from this device
@@ -54,6 +57,10 @@ pub trait BusDevice: Send {
     }
     /// Invoked when the device is sandboxed.
     fn on_sandboxed(&mut self) {}
+    /// Gets the counters of the device's virtio queues, if it has any. Only used by PCI.
+    fn queue_stats(&self) -> Vec<QueueStats> {
+        Vec::new()
+    }
 }
 
 pub trait BusDeviceSync: BusDevice + Sync {
@@ -69,6 +76,8 @@ pub trait BusResumeDevice: Send {
 
 #[derive(Debug)]
 pub enum Error {
+    /// The removal failed because no device was registered at the given range.
+    NotFound,
     /// The insertion failed because the new device overlapped with an old device.
     Overlap,
 }
@@ -78,6 +87,7 @@ impl Display for Error {
         use self::Error::*;
 
         match self {
+            NotFound => write!(f, "no device registered at the given range"),
             Overlap => write!(f, "new device overlaps with an old device"),
         }
     }
@@ -138,11 +148,14 @@ enum BusDeviceEntry {
 /// This doesn't have any restrictions on what kind of device or address space this applies to. The
 /// only restriction is that no two devices can overlap in this address space.
 ///
+/// Clones of a bus share the same address space, so devices inserted into or removed from one clone
+/// are seen by all of them.
+///
 /// the 'resume_notify_devices' contains the devices which requires to be notified before the system
 /// resume back from S3 suspended state.
 #[derive(Clone)]
 pub struct Bus {
-    devices: BTreeMap<BusRange, BusDeviceEntry>,
+    devices: Arc<RwLock<BTreeMap<BusRange, BusDeviceEntry>>>,
     resume_notify_devices: Vec<Arc<Mutex<dyn BusResumeDevice>>>,
     access_id: usize,
 }
@@ -151,7 +164,7 @@ impl Bus {
     /// Constructs an a bus with an empty address space.
     pub fn new() -> Bus {
         Bus {
-            devices: BTreeMap::new(),
+            devices: Arc::new(RwLock::new(BTreeMap::new())),
             resume_notify_devices: Vec::new(),
             access_id: 0,
         }
@@ -162,16 +175,18 @@ impl Bus {
         self.access_id = id;
     }
 
-    fn first_before(&self, addr: u64) -> Option<(BusRange, &BusDeviceEntry)> {
-        let (range, dev) = self
-            .devices
+    fn first_before(&self, addr: u64) -> Option<(BusRange, BusDeviceEntry)> {
+        let devices = self.devices.read();
+        let (range, dev) = devices
             .range(..=BusRange { base: addr, len: 1 })
             .rev()
             .next()?;
-        Some((*range, dev))
+        Some((*range, dev.clone()))
     }
 
-    fn get_device(&self, addr: u64) -> Option<(u64, u64, &BusDeviceEntry)> {
+    // The device entry is cloned out so the address space lock is not held while the device
+    // handles the access, which may itself insert or remove devices.
+    fn get_device(&self, addr: u64) -> Option<(u64, u64, BusDeviceEntry)> {
         if let Some((range, dev)) = self.first_before(addr) {
             let offset = addr - range.base;
             if offset < range.len {
@@ -187,17 +202,16 @@ impl Bus {
             return Err(Error::Overlap);
         }
 
+        let mut devices = self.devices.write();
         // Reject all cases where the new device's range overlaps with an existing device.
-        if self
-            .devices
+        if devices
             .iter()
             .any(|(range, _dev)| range.overlaps(base, len))
         {
             return Err(Error::Overlap);
         }
 
-        if self
-            .devices
+        if devices
             .insert(BusRange { base, len }, BusDeviceEntry::OuterSync(device))
             .is_some()
         {
@@ -220,17 +234,16 @@ impl Bus {
             return Err(Error::Overlap);
         }
 
+        let mut devices = self.devices.write();
         // Reject all cases where the new device's range overlaps with an existing device.
-        if self
-            .devices
+        if devices
             .iter()
             .any(|(range, _dev)| range.overlaps(base, len))
         {
             return Err(Error::Overlap);
         }
 
-        if self
-            .devices
+        if devices
             .insert(BusRange { base, len }, BusDeviceEntry::InnerSync(device))
             .is_some()
         {
@@ -240,6 +253,18 @@ impl Bus {
         Ok(())
     }
 
+    /// Removes the device registered at exactly the range given by `base` and `len`.
+    pub fn remove(&mut self, base: u64, len: u64) -> Result<()> {
+        let mut devices = self.devices.write();
+        let range = BusRange { base, len };
+        match devices.get_key_value(&range) {
+            Some((r, _dev)) if r.len == len => {}
+            _ => return Err(Error::NotFound),
+        }
+        devices.remove(&range);
+        Ok(())
+    }
+
     /// Reads data from the device that owns the range containing `addr` and puts it into `data`.
     ///
     /// Returns true on success, otherwise `data` is untouched.
@@ -305,6 +330,8 @@ mod tests {
         }
     }
 
+    impl Suspendable for DummyDevice {}
+
     struct ConstantDevice {
         uses_full_addr: bool,
     }
@@ -337,6 +364,8 @@ mod tests {
         }
     }
 
+    impl Suspendable for ConstantDevice {}
+
     #[test]
     fn bus_insert() {
         let mut bus = Bus::new();
@@ -422,6 +451,30 @@ mod tests {
         assert!(bus.write(0x15, &values));
     }
 
+    #[test]
+    fn bus_remove() {
+        let mut bus = Bus::new();
+        let dummy = Arc::new(Mutex::new(DummyDevice));
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10).is_ok());
+        assert!(bus.remove(0x10, 0x20).is_err());
+        assert!(bus.remove(0x11, 0x10).is_err());
+        assert!(bus.remove(0x10, 0x10).is_ok());
+        assert!(!bus.read(0x10, &mut [0, 0, 0, 0]));
+        assert!(bus.remove(0x10, 0x10).is_err());
+        assert!(bus.insert(dummy.clone(), 0x18, 0x10).is_ok());
+    }
+
+    #[test]
+    fn bus_clone_shares_devices() {
+        let mut bus = Bus::new();
+        let mut bus_clone = bus.clone();
+        let dummy = Arc::new(Mutex::new(DummyDevice));
+        assert!(bus.insert(dummy.clone(), 0x10, 0x10).is_ok());
+        assert!(bus_clone.read(0x10, &mut [0, 0, 0, 0]));
+        assert!(bus_clone.remove(0x10, 0x10).is_ok());
+        assert!(!bus.read(0x10, &mut [0, 0, 0, 0]));
+    }
+
     #[test]
     fn bus_range_contains() {
         let a = BusRange {
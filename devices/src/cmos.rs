@@ -5,28 +5,127 @@
 use libc::{gmtime_r, time, time_t, tm};
 use std::cmp::min;
 use std::mem;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::{BusAccessInfo, BusDevice};
+use vm_control::RtcWakeAlarm;
+
+use crate::suspendable;
+use crate::{BusAccessInfo, BusDevice, BusResumeDevice, Suspendable};
 
 const INDEX_MASK: u8 = 0x7f;
 const INDEX_OFFSET: u64 = 0x0;
 const DATA_OFFSET: u64 = 0x1;
 const DATA_LEN: usize = 128;
 
+const RTC_REG_SEC_ALARM: u8 = 0x01;
+const RTC_REG_MIN_ALARM: u8 = 0x03;
+const RTC_REG_HOUR_ALARM: u8 = 0x05;
+const RTC_REG_B: u8 = 0x0B;
+const RTC_REG_C: u8 = 0x0C;
+
+const RTC_REG_B_24_HOUR_MODE: u8 = 0x02;
+const RTC_REG_B_ALARM_ENABLE: u8 = 0x20;
+const RTC_REG_C_ALARM_FLAG: u8 = 0x20;
+const RTC_REG_C_IRQ_FLAG: u8 = 0x80;
+
+// Alarm register values with both top bits set match any time.
+const ALARM_DONT_CARE: u8 = 0xC0;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+fn to_bcd(v: u8) -> u8 {
+    assert!(v < 100);
+    ((v / 10) << 4) | (v % 10)
+}
+
+fn from_bcd(v: u8) -> u8 {
+    (v >> 4) * 10 + (v & 0x0f)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Decodes an alarm register, returning `None` if it matches any time.
+fn alarm_value(reg: u8) -> Option<u8> {
+    if reg >= ALARM_DONT_CARE {
+        None
+    } else {
+        Some(from_bcd(reg))
+    }
+}
+
+/// Returns the first time after `now`, in seconds since the epoch, whose UTC time of day matches
+/// the alarm `hour`, `minute`, and `second`. Fields that are `None` match any value. Returns `None`
+/// if no time of day matches.
+fn next_alarm(now: u64, hour: Option<u8>, minute: Option<u8>, second: Option<u8>) -> Option<u64> {
+    if hour.map_or(false, |h| h >= 24)
+        || minute.map_or(false, |m| m >= 60)
+        || second.map_or(false, |s| s >= 60)
+    {
+        return None;
+    }
+
+    // Each field that doesn't match moves the time forward to the first one where it does, which
+    // takes at most two passes over the fields.
+    let mut t = now + 1;
+    loop {
+        let day = t - t % SECONDS_PER_DAY;
+        let hour_start = t - t % 3600;
+        let minute_start = t - t % 60;
+        let time_of_day = t % SECONDS_PER_DAY;
+        match (hour, minute, second) {
+            (Some(h), _, _) if u64::from(h) != time_of_day / 3600 => {
+                let h = u64::from(h) * 3600;
+                t = if h > time_of_day {
+                    day + h
+                } else {
+                    day + SECONDS_PER_DAY + h
+                };
+            }
+            (_, Some(m), _) if u64::from(m) != time_of_day / 60 % 60 => {
+                let m = u64::from(m) * 60;
+                t = if m > t - hour_start {
+                    hour_start + m
+                } else {
+                    hour_start + 3600 + m
+                };
+            }
+            (_, _, Some(s)) if u64::from(s) != time_of_day % 60 => {
+                let s = u64::from(s);
+                t = if s > t - minute_start {
+                    minute_start + s
+                } else {
+                    minute_start + 60 + s
+                };
+            }
+            _ => return Some(t),
+        }
+    }
+}
+
 /// A CMOS/RTC device commonly seen on x86 I/O port 0x70/0x71.
+///
+/// The alarm registers don't raise an interrupt while the guest is running. Instead the time they
+/// are set to is published through `RtcWakeAlarm`, so that the guest can be woken from suspend.
 pub struct Cmos {
     index: u8,
     data: [u8; DATA_LEN],
+    wake_alarm: RtcWakeAlarm,
 }
 
 impl Cmos {
     /// Constructs a CMOS/RTC device with initial data.
     /// `mem_below_4g` is the size of memory in bytes below the 32-bit gap.
     /// `mem_above_4g` is the size of memory in bytes above the 32-bit gap.
-    pub fn new(mem_below_4g: u64, mem_above_4g: u64) -> Cmos {
+    /// `wake_alarm` receives the wake time programmed into the alarm registers.
+    pub fn new(mem_below_4g: u64, mem_above_4g: u64, wake_alarm: RtcWakeAlarm) -> Cmos {
         let mut data = [0u8; DATA_LEN];
 
-        data[0x0B] = 0x02; // Status Register B: 24-hour mode
+        data[RTC_REG_B as usize] = RTC_REG_B_24_HOUR_MODE;
 
         // Extended memory from 16 MB to 4 GB in units of 64 KB
         let ext_mem = min(
@@ -42,7 +141,25 @@ impl Cmos {
         data[0x5c] = (high_mem >> 8) as u8;
         data[0x5d] = (high_mem >> 16) as u8;
 
-        Cmos { index: 0, data }
+        Cmos {
+            index: 0,
+            data,
+            wake_alarm,
+        }
+    }
+
+    fn update_wake_alarm(&self) {
+        let wake_time = if self.data[RTC_REG_B as usize] & RTC_REG_B_ALARM_ENABLE != 0 {
+            next_alarm(
+                now(),
+                alarm_value(self.data[RTC_REG_HOUR_ALARM as usize]),
+                alarm_value(self.data[RTC_REG_MIN_ALARM as usize]),
+                alarm_value(self.data[RTC_REG_SEC_ALARM as usize]),
+            )
+        } else {
+            None
+        };
+        self.wake_alarm.set(wake_time);
     }
 }
 
@@ -58,17 +175,20 @@ impl BusDevice for Cmos {
 
         match info.offset {
             INDEX_OFFSET => self.index = data[0] & INDEX_MASK,
-            DATA_OFFSET => self.data[self.index as usize] = data[0],
+            DATA_OFFSET => match self.index {
+                // Register C only holds flags set by the device.
+                RTC_REG_C => (),
+                RTC_REG_SEC_ALARM | RTC_REG_MIN_ALARM | RTC_REG_HOUR_ALARM | RTC_REG_B => {
+                    self.data[self.index as usize] = data[0];
+                    self.update_wake_alarm();
+                }
+                _ => self.data[self.index as usize] = data[0],
+            },
             o => panic!("bad write offset on CMOS device: {}", o),
         }
     }
 
     fn read(&mut self, info: BusAccessInfo, data: &mut [u8]) {
-        fn to_bcd(v: u8) -> u8 {
-            assert!(v < 100);
-            ((v / 10) << 4) | (v % 10)
-        }
-
         if data.len() != 1 {
             return;
         }
@@ -109,6 +229,8 @@ impl BusDevice for Cmos {
                     0x08 => to_bcd(month as u8),
                     0x09 => to_bcd((year % 100) as u8),
                     0x32 => to_bcd(((year + 1900) / 100) as u8),
+                    // Reading register C acknowledges the flags in it.
+                    RTC_REG_C => mem::replace(&mut self.data[RTC_REG_C as usize], 0),
                     _ => {
                         // self.index is always guaranteed to be in range via INDEX_MASK.
                         self.data[(self.index & INDEX_MASK) as usize]
@@ -119,3 +241,137 @@ impl BusDevice for Cmos {
         }
     }
 }
+
+// The clock only acts on guest accesses.
+impl Suspendable for Cmos {
+    fn sleep(&mut self) -> suspendable::Result<()> {
+        Ok(())
+    }
+}
+
+impl BusResumeDevice for Cmos {
+    fn resume_imminent(&mut self) {
+        // Let the guest see that the alarm went off if it is what woke it, and arm the alarm for
+        // the next day as the hardware does.
+        if let Some(wake_time) = self.wake_alarm.get() {
+            if wake_time <= now() {
+                self.data[RTC_REG_C as usize] |= RTC_REG_C_ALARM_FLAG | RTC_REG_C_IRQ_FLAG;
+                self.update_wake_alarm();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_reg(cmos: &mut Cmos, index: u8, val: u8) {
+        let info = |offset| BusAccessInfo {
+            offset,
+            address: 0x70 + offset,
+            id: 0,
+        };
+        cmos.write(info(INDEX_OFFSET), &[index]);
+        cmos.write(info(DATA_OFFSET), &[val]);
+    }
+
+    fn read_reg(cmos: &mut Cmos, index: u8) -> u8 {
+        let info = |offset| BusAccessInfo {
+            offset,
+            address: 0x70 + offset,
+            id: 0,
+        };
+        let mut data = [0];
+        cmos.write(info(INDEX_OFFSET), &[index]);
+        cmos.read(info(DATA_OFFSET), &mut data);
+        data[0]
+    }
+
+    #[test]
+    fn next_alarm_time() {
+        // 2020-01-01 00:00:00 UTC.
+        let midnight = 1_577_836_800;
+        let at = |h: u64, m: u64, s: u64| midnight + h * 3600 + m * 60 + s;
+
+        assert_eq!(
+            next_alarm(at(1, 0, 0), Some(6), Some(30), Some(15)),
+            Some(at(6, 30, 15))
+        );
+        // Times earlier in the day ring tomorrow.
+        assert_eq!(
+            next_alarm(at(7, 0, 0), Some(6), Some(30), Some(0)),
+            Some(at(30, 30, 0))
+        );
+        // The current second is never returned.
+        assert_eq!(
+            next_alarm(at(6, 30, 0), Some(6), Some(30), Some(0)),
+            Some(at(30, 30, 0))
+        );
+        assert_eq!(
+            next_alarm(at(6, 59, 59), None, Some(0), Some(0)),
+            Some(at(7, 0, 0))
+        );
+        assert_eq!(next_alarm(at(6, 0, 0), None, None, None), Some(at(6, 0, 1)));
+        // A minute or second that has passed moves on to the next hour or minute.
+        assert_eq!(
+            next_alarm(at(6, 45, 0), None, Some(30), None),
+            Some(at(7, 30, 0))
+        );
+        assert_eq!(
+            next_alarm(at(23, 59, 30), Some(23), None, Some(10)),
+            Some(at(47, 0, 10))
+        );
+        assert_eq!(next_alarm(at(6, 0, 0), Some(24), None, None), None);
+        assert_eq!(next_alarm(at(6, 0, 0), None, Some(60), None), None);
+    }
+
+    #[test]
+    fn alarm_registers() {
+        let wake_alarm = RtcWakeAlarm::default();
+        let mut cmos = Cmos::new(0, 0, wake_alarm.clone());
+        assert_eq!(wake_alarm.get(), None);
+
+        write_reg(&mut cmos, RTC_REG_SEC_ALARM, ALARM_DONT_CARE);
+        write_reg(&mut cmos, RTC_REG_MIN_ALARM, ALARM_DONT_CARE);
+        write_reg(&mut cmos, RTC_REG_HOUR_ALARM, ALARM_DONT_CARE);
+        assert_eq!(wake_alarm.get(), None);
+
+        let before = now();
+        write_reg(
+            &mut cmos,
+            RTC_REG_B,
+            RTC_REG_B_24_HOUR_MODE | RTC_REG_B_ALARM_ENABLE,
+        );
+        let wake_time = wake_alarm.get().unwrap();
+        assert!(wake_time > before && wake_time <= now() + 1);
+
+        write_reg(&mut cmos, RTC_REG_B, RTC_REG_B_24_HOUR_MODE);
+        assert_eq!(wake_alarm.get(), None);
+    }
+
+    #[test]
+    fn alarm_flag_on_resume() {
+        let wake_alarm = RtcWakeAlarm::default();
+        let mut cmos = Cmos::new(0, 0, wake_alarm.clone());
+        write_reg(
+            &mut cmos,
+            RTC_REG_B,
+            RTC_REG_B_24_HOUR_MODE | RTC_REG_B_ALARM_ENABLE,
+        );
+
+        // Resuming before the alarm doesn't set the flag.
+        wake_alarm.set(Some(now() + 60));
+        cmos.resume_imminent();
+        assert_eq!(read_reg(&mut cmos, RTC_REG_C), 0);
+
+        wake_alarm.set(Some(now() - 1));
+        cmos.resume_imminent();
+        assert_eq!(
+            read_reg(&mut cmos, RTC_REG_C),
+            RTC_REG_C_ALARM_FLAG | RTC_REG_C_IRQ_FLAG
+        );
+        assert_eq!(read_reg(&mut cmos, RTC_REG_C), 0);
+        assert!(wake_alarm.get().unwrap() > now() - 1);
+    }
+}
@@ -4,7 +4,8 @@
 
 use base::{error, Event};
 
-use crate::{BusAccessInfo, BusDevice};
+use crate::suspendable;
+use crate::{BusAccessInfo, BusDevice, Suspendable};
 
 /// A i8042 PS/2 controller that emulates just enough to shutdown the machine.
 pub struct I8042Device {
@@ -43,3 +44,10 @@ impl BusDevice for I8042Device {
         }
     }
 }
+
+// The controller only acts on guest accesses.
+impl Suspendable for I8042Device {
+    fn sleep(&mut self) -> suspendable::Result<()> {
+        Ok(())
+    }
+}
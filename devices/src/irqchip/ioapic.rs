@@ -9,7 +9,8 @@ use std::fmt::{self, Display};
 
 use super::IrqEvent;
 use crate::bus::BusAccessInfo;
-use crate::BusDevice;
+use crate::suspendable;
+use crate::{BusDevice, Suspendable};
 use base::{error, warn, AsRawDescriptor, Error, Event, Result};
 use hypervisor::{IoapicState, MsiAddressMessage, MsiDataMessage, TriggerMode, NUM_IOAPIC_PINS};
 use msg_socket::{MsgError, MsgReceiver, MsgSender};
@@ -128,6 +129,13 @@ impl BusDevice for Ioapic {
     }
 }
 
+// The IOAPIC only acts on guest accesses and on interrupts from other devices.
+impl Suspendable for Ioapic {
+    fn sleep(&mut self) -> suspendable::Result<()> {
+        Ok(())
+    }
+}
+
 impl Ioapic {
     pub fn new(irq_socket: VmIrqRequestSocket) -> Result<Ioapic> {
         let mut state = IoapicState::default();
@@ -13,7 +13,8 @@
 // emotional context, this file refers to them instead as "primary" and "secondary" PICs.
 
 use crate::bus::BusAccessInfo;
-use crate::BusDevice;
+use crate::suspendable;
+use crate::{BusDevice, Suspendable};
 use base::{debug, warn, Event};
 use hypervisor::{PicInitState, PicSelect, PicState};
 
@@ -124,6 +125,13 @@ impl BusDevice for Pic {
     }
 }
 
+// The PIC only acts on guest accesses and on interrupts from other devices.
+impl Suspendable for Pic {
+    fn sleep(&mut self) -> suspendable::Result<()> {
+        Ok(())
+    }
+}
+
 impl Pic {
     pub fn new() -> Pic {
         let mut primary_pic: PicState = Default::default();
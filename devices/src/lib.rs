@@ -9,6 +9,7 @@ mod cmos;
 mod i8042;
 pub mod irqchip;
 mod pci;
+mod pflash;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 mod pit;
 pub mod pl030;
@@ -17,8 +18,10 @@ mod proxy;
 mod register_space;
 pub mod acpi;
 pub mod bat;
+mod scsi;
 mod serial;
 mod serial_device;
+mod suspendable;
 pub mod usb;
 mod utils;
 pub mod vfio;
@@ -34,9 +37,11 @@ pub use self::irqchip::*;
 #[cfg(feature = "audio")]
 pub use self::pci::{Ac97Backend, Ac97Dev, Ac97Parameters};
 pub use self::pci::{
-    PciAddress, PciConfigIo, PciConfigMmio, PciDevice, PciDeviceError, PciInterruptPin, PciRoot,
-    VfioPciDevice,
+    Ivshmem, IvshmemError, PciAddress, PciConfigIo, PciConfigMmio, PciDevice, PciDeviceError,
+    PciInterruptPin, PciRoot, PcieRootPort, VfioPciDevice, E1000, PCIE_CONFIG_REGISTER_BITS,
+    PCI_CONFIG_REGISTER_BITS,
 };
+pub use self::pflash::{Pflash, PflashError, PFLASH_BLOCK_SIZE};
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 pub use self::pit::{Pit, PitError};
 pub use self::pl030::Pl030;
@@ -44,6 +49,8 @@ pub use self::proxy::Error as ProxyError;
 pub use self::proxy::ProxyDevice;
 pub use self::serial::Serial;
 pub use self::serial_device::SerialDevice;
+pub use self::suspendable::Error as SuspendableError;
+pub use self::suspendable::Suspendable;
 pub use self::usb::host_backend::host_backend_device_provider::HostBackendDeviceProvider;
 pub use self::usb::xhci::xhci_controller::XhciController;
 pub use self::vfio::{VfioContainer, VfioDevice};
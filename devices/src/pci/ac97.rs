@@ -21,6 +21,7 @@ use crate::pci::pci_configuration::{
 };
 use crate::pci::pci_device::{self, PciDevice, Result};
 use crate::pci::{PciAddress, PciInterruptPin};
+use crate::Suspendable;
 
 // Use 82801AA because it's what qemu does.
 const PCI_DEVICE_ID_INTEL_82801AA_5: u16 = 0x2415;
@@ -344,6 +345,8 @@ impl PciDevice for Ac97Dev {
     }
 }
 
+impl Suspendable for Ac97Dev {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
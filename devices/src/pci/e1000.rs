@@ -0,0 +1,1343 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Emulation of an Intel 82540EM gigabit ethernet controller, for guests that don't have virtio
+//! drivers. Frames are sent and received through the same tap backend as virtio-net, with the
+//! tap's vnet header used to hand checksum and segmentation offloads to the host kernel.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::net::Ipv4Addr;
+use std::os::unix::io::FromRawFd;
+use std::sync::Arc;
+use std::thread;
+
+use base::Error as SysError;
+use base::{error, warn, AsRawDescriptor, Event, EventType, PollToken, RawDescriptor, WaitContext};
+use data_model::{DataInit, Le16, Le32, Le64};
+use net_util::{MacAddress, TapT};
+use resources::{Alloc, MmioType, SystemAllocator};
+use sync::Mutex;
+use virtio_sys::virtio_net::{
+    virtio_net_hdr_v1, VIRTIO_NET_HDR_F_NEEDS_CSUM, VIRTIO_NET_HDR_GSO_TCPV4,
+    VIRTIO_NET_HDR_GSO_TCPV6,
+};
+use vm_memory::{GuestAddress, GuestMemory};
+
+use crate::pci::e1000_regs::*;
+use crate::pci::pci_configuration::{
+    PciBarConfiguration, PciClassCode, PciConfiguration, PciHeaderType, PciNetworkSubclass,
+};
+use crate::pci::pci_device::{self, PciDevice, Result};
+use crate::pci::{PciAddress, PciInterruptPin};
+use crate::virtio::{create_tap, validate_and_configure_tap, NetError};
+use crate::Suspendable;
+
+const PCI_VENDOR_ID_INTEL: u16 = 0x8086;
+const PCI_DEVICE_ID_INTEL_82540EM: u16 = 0x100e;
+
+// The largest frame accepted from the guest, which is bounded by the 16-bit IP length of a TSO
+// packet plus its link layer headers.
+const MAX_TX_FRAME: usize = 0x10000 + 64;
+// The largest frame read from the tap. The tap never sends GSO frames because all offloads are
+// disabled on it, but leave room for jumbo frames.
+const MAX_RX_FRAME: usize = 0x10000;
+// Frames shorter than this are padded, as the hardware would have received them from the wire.
+const MIN_RX_FRAME: usize = 60;
+const VNET_HDR_LEN: usize = size_of::<virtio_net_hdr_v1>();
+// Offset of the ethertype in an untagged ethernet frame, where a VLAN tag is inserted.
+const VLAN_TAG_OFFSET: usize = 12;
+const VLAN_TAG_LEN: usize = 4;
+const DESC_LEN: u64 = 16;
+
+// Transmit descriptor. The legacy, context and data descriptor formats all share this layout and
+// are told apart by the DEXT command bit and the descriptor type.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct TxDesc {
+    addr: Le64,
+    lower: Le32,
+    upper: Le32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for TxDesc {}
+
+// Legacy receive descriptor.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct RxDesc {
+    addr: Le64,
+    length: Le16,
+    csum: Le16,
+    status: u8,
+    errors: u8,
+    special: Le16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for RxDesc {}
+
+// Layout of `virtio_net_hdr_v1` that can be written to the tap directly.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VnetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: Le16,
+    gso_size: Le16,
+    csum_start: Le16,
+    csum_offset: Le16,
+    num_buffers: Le16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for VnetHdr {}
+
+// Offload parameters set up by a transmit context descriptor.
+#[derive(Copy, Clone, Debug, Default)]
+struct TxContext {
+    ipcss: usize,
+    ipcso: usize,
+    ipcse: usize,
+    tucss: usize,
+    tucso: usize,
+    tucmd: u8,
+    hdr_len: usize,
+    mss: u16,
+}
+
+// The packet being assembled from the transmit descriptors seen since the last EOP.
+#[derive(Default)]
+struct TxPacket {
+    data: Vec<u8>,
+    // Set by the first data descriptor of the packet.
+    tse: bool,
+    popts: u8,
+    // Checksum start and offset requested by a legacy descriptor.
+    legacy_csum: Option<(usize, usize)>,
+    vlan: Option<u16>,
+    // Set if the packet outgrew `MAX_TX_FRAME` and will be dropped at EOP.
+    oversized: bool,
+}
+
+// State of the bit-banged microwire interface to the EEPROM.
+#[derive(Default)]
+struct EecdState {
+    old_eecd: u32,
+    val_in: u32,
+    bitnum_in: u32,
+    bitnum_out: u32,
+    reading: bool,
+}
+
+fn phy_reset_values() -> [u16; 32] {
+    let mut phy = [0u16; 32];
+    phy[PHY_CTRL] = 0x1140;
+    phy[PHY_STATUS] = 0x796d;
+    phy[PHY_ID1] = 0x0141;
+    phy[PHY_ID2] = 0x0c20;
+    phy[PHY_AUTONEG_ADV] = 0x0de1;
+    phy[PHY_LP_ABILITY] = 0x45e0;
+    phy[PHY_AUTONEG_EXP] = 0x0001;
+    phy[PHY_1000T_CTRL] = 0x0e00;
+    phy[PHY_1000T_STATUS] = 0x3c00;
+    phy[PHY_EXT_STATUS] = 0x3000;
+    phy[M88_PHY_SPEC_CTRL] = 0x0360;
+    phy[M88_PHY_SPEC_STATUS] = 0xac00;
+    phy[M88_EXT_PHY_SPEC_CTRL] = 0x0d60;
+    phy
+}
+
+// Builds the EEPROM contents the e1000 drivers expect, including the MAC address and a checksum
+// word that makes all words add up to `EEPROM_SUM`.
+fn eeprom_contents(mac: &MacAddress) -> [u16; EEPROM_WORDS] {
+    const TEMPLATE: [u16; EEPROM_WORDS] = [
+        0x0000,
+        0x0000,
+        0x0000,
+        0x0000,
+        0xffff,
+        0x0000,
+        0x0000,
+        0x0000, //
+        0x3000,
+        0x1000,
+        0x6403,
+        PCI_DEVICE_ID_INTEL_82540EM,
+        0x8086,
+        PCI_DEVICE_ID_INTEL_82540EM,
+        0x8086,
+        0x3040, //
+        0x0008,
+        0x2000,
+        0x7e14,
+        0x0048,
+        0x1000,
+        0x00d8,
+        0x0000,
+        0x2700, //
+        0x6cc9,
+        0x3150,
+        0x0722,
+        0x040b,
+        0x0984,
+        0x0000,
+        0xc000,
+        0x0706, //
+        0x1008,
+        0x0000,
+        0x0f04,
+        0x7fff,
+        0x4d01,
+        0xffff,
+        0xffff,
+        0xffff, //
+        0xffff,
+        0xffff,
+        0xffff,
+        0xffff,
+        0xffff,
+        0xffff,
+        0xffff,
+        0xffff, //
+        0x0100,
+        0x4000,
+        0x121c,
+        0xffff,
+        0xffff,
+        0xffff,
+        0xffff,
+        0xffff, //
+        0xffff,
+        0xffff,
+        0xffff,
+        0xffff,
+        0xffff,
+        0xffff,
+        0xffff,
+        0x0000, //
+    ];
+    let mut eeprom = TEMPLATE;
+    let octets = mac.octets();
+    for i in 0..3 {
+        eeprom[i] = u16::from(octets[2 * i]) | u16::from(octets[2 * i + 1]) << 8;
+    }
+    let sum = eeprom[..EEPROM_CHECKSUM_REG]
+        .iter()
+        .fold(0u16, |sum, w| sum.wrapping_add(*w));
+    eeprom[EEPROM_CHECKSUM_REG] = EEPROM_SUM.wrapping_sub(sum);
+    eeprom
+}
+
+// Returns the one's complement sum of `data` as big-endian 16-bit words, before folding.
+fn ones_complement_sum(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|c| u32::from(c[0]) << 8 | u32::from(*c.get(1).unwrap_or(&0)))
+        .sum()
+}
+
+fn fold_checksum(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+fn write_be16(data: &mut [u8], offset: usize, val: u16) {
+    if let Some(b) = data.get_mut(offset..offset + 2) {
+        b.copy_from_slice(&val.to_be_bytes());
+    }
+}
+
+fn read_be16(data: &[u8], offset: usize) -> u16 {
+    match data.get(offset..offset + 2) {
+        Some(b) => u16::from(b[0]) << 8 | u16::from(b[1]),
+        None => 0,
+    }
+}
+
+// Computes the checksum over `data[start..end]` and stores it at `offset`.
+fn insert_checksum(data: &mut [u8], start: usize, offset: usize, end: usize) {
+    let end = end.min(data.len());
+    if start >= end || offset + 2 > data.len() {
+        return;
+    }
+    write_be16(data, offset, 0);
+    let sum = !fold_checksum(ones_complement_sum(&data[start..end]));
+    write_be16(data, offset, sum);
+}
+
+// Registers and other state shared by the MMIO handlers and the receive worker.
+struct E1000Core {
+    mem: GuestMemory,
+    regs: Vec<u32>,
+    phy: [u16; 32],
+    eeprom: [u16; EEPROM_WORDS],
+    eecd: EecdState,
+    tx_ctx: TxContext,
+    tx_packet: TxPacket,
+    // A duplicate of the tap descriptor, so frames can be sent from the vcpu thread while the
+    // worker blocks on reads.
+    tx_tap: File,
+    irq_evt: Option<Event>,
+    // Wakes the worker when the guest makes receive descriptors available.
+    rx_kick_evt: Event,
+}
+
+impl E1000Core {
+    fn new(mem: GuestMemory, mac: &MacAddress, tx_tap: File, rx_kick_evt: Event) -> E1000Core {
+        let mut core = E1000Core {
+            mem,
+            regs: vec![0; (E1000_MMIO_SIZE / 4) as usize],
+            phy: phy_reset_values(),
+            eeprom: eeprom_contents(mac),
+            eecd: Default::default(),
+            tx_ctx: Default::default(),
+            tx_packet: Default::default(),
+            tx_tap,
+            irq_evt: None,
+            rx_kick_evt,
+        };
+        core.reset();
+        core
+    }
+
+    fn reg(&self, offset: u64) -> u32 {
+        self.regs[(offset / 4) as usize]
+    }
+
+    fn set_reg(&mut self, offset: u64, val: u32) {
+        self.regs[(offset / 4) as usize] = val;
+    }
+
+    // Returns the registers to their power on values. The receive address is reloaded from the
+    // EEPROM, as the hardware does.
+    fn reset(&mut self) {
+        for r in self.regs.iter_mut() {
+            *r = 0;
+        }
+        self.phy = phy_reset_values();
+        self.eecd = Default::default();
+        self.tx_ctx = Default::default();
+        self.tx_packet = Default::default();
+
+        self.set_reg(CTRL, CTRL_SLU | CTRL_SPD_1000);
+        self.set_reg(
+            STATUS,
+            STATUS_FD
+                | STATUS_LU
+                | STATUS_SPEED_1000
+                | STATUS_ASDV_1000
+                | STATUS_MTXCKOK
+                | STATUS_GIO_MASTER_ENABLE,
+        );
+        self.set_reg(EECD, EECD_SK | EECD_CS | EECD_DI | EECD_AUTO_RD);
+        self.set_reg(LEDCTL, 0x0000_0602);
+        self.set_reg(PBA, 0x0010_0030);
+        self.set_reg(VET, 0x8100);
+        let ral = u32::from(self.eeprom[0]) | u32::from(self.eeprom[1]) << 16;
+        self.set_reg(RAL0, ral);
+        self.set_reg(RAH0, u32::from(self.eeprom[2]) | RAH_AV);
+    }
+
+    fn read_reg(&mut self, offset: u64) -> u32 {
+        match offset {
+            EECD => self.get_eecd(),
+            ICR => {
+                // Reading the cause register clears it.
+                let icr = self.reg(ICR);
+                self.set_reg(ICR, 0);
+                if icr & self.reg(IMS) != 0 {
+                    icr | ICR_INT_ASSERTED
+                } else {
+                    icr
+                }
+            }
+            _ => self.reg(offset),
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, val: u32) {
+        match offset {
+            CTRL => {
+                if val & CTRL_RST != 0 {
+                    self.reset();
+                } else {
+                    self.set_reg(CTRL, val & !(CTRL_RST | CTRL_PHY_RST));
+                }
+            }
+            STATUS => (),
+            EECD => self.set_eecd(val),
+            EERD => self.set_eerd(val),
+            MDIC => self.set_mdic(val),
+            ICR => {
+                let icr = self.reg(ICR) & !val;
+                self.set_reg(ICR, icr);
+            }
+            ICS => self.set_ics(val),
+            IMS => {
+                let pending = self.pending();
+                self.set_reg(IMS, self.reg(IMS) | val);
+                if pending == 0 {
+                    self.update_irq();
+                }
+            }
+            IMC => {
+                let ims = self.reg(IMS) & !val;
+                self.set_reg(IMS, ims);
+            }
+            RCTL => {
+                self.set_reg(RCTL, val);
+                self.kick_rx();
+            }
+            RDLEN | TDLEN => self.set_reg(offset, val & RING_LEN_MASK),
+            RDH | TDH => self.set_reg(offset, val & 0xffff),
+            RDT => {
+                self.set_reg(RDT, val & 0xffff);
+                self.kick_rx();
+            }
+            TCTL => {
+                self.set_reg(TCTL, val);
+                self.start_xmit();
+            }
+            TDT => {
+                self.set_reg(TDT, val & 0xffff);
+                self.start_xmit();
+            }
+            _ => self.set_reg(offset, val),
+        }
+    }
+
+    fn pending(&self) -> u32 {
+        self.reg(ICR) & self.reg(IMS)
+    }
+
+    // Sets interrupt causes and raises the interrupt if one of them is newly unmasked.
+    fn set_ics(&mut self, val: u32) {
+        let pending = self.pending();
+        self.set_reg(ICR, self.reg(ICR) | val);
+        if pending == 0 {
+            self.update_irq();
+        }
+    }
+
+    // Asserts the interrupt if any unmasked cause is set. Called again on resample so that a cause
+    // the driver hasn't acknowledged keeps the level interrupt asserted.
+    fn update_irq(&self) {
+        if self.pending() == 0 {
+            return;
+        }
+        if let Some(irq_evt) = &self.irq_evt {
+            if let Err(e) = irq_evt.write(1) {
+                error!("e1000: failed to signal interrupt: {}", e);
+            }
+        }
+    }
+
+    fn kick_rx(&self) {
+        if let Err(e) = self.rx_kick_evt.write(1) {
+            error!("e1000: failed to kick rx worker: {}", e);
+        }
+    }
+
+    fn get_eecd(&self) -> u32 {
+        let eecd = &self.eecd;
+        let mut ret = EECD_PRES | EECD_GNT | eecd.old_eecd;
+        let word = self.eeprom[((eecd.bitnum_out >> 4) & 0x3f) as usize];
+        if !eecd.reading || (word >> ((eecd.bitnum_out & 0xf) ^ 0xf)) & 1 != 0 {
+            ret |= EECD_DO;
+        }
+        ret
+    }
+
+    fn set_eecd(&mut self, val: u32) {
+        let eecd = &mut self.eecd;
+        let old = eecd.old_eecd;
+        eecd.old_eecd = val & (EECD_SK | EECD_CS | EECD_DI | EECD_FWE_MASK | EECD_REQ);
+        if val & EECD_CS == 0 {
+            return;
+        }
+        // Selecting the chip starts a new command.
+        if (val ^ old) & EECD_CS != 0 {
+            eecd.val_in = 0;
+            eecd.bitnum_in = 0;
+            eecd.bitnum_out = 0;
+            eecd.reading = false;
+        }
+        if (val ^ old) & EECD_SK == 0 {
+            return;
+        }
+        // Data is shifted out on the falling edge of the clock and in on the rising edge.
+        if val & EECD_SK == 0 {
+            eecd.bitnum_out = eecd.bitnum_out.wrapping_add(1);
+            return;
+        }
+        eecd.val_in <<= 1;
+        if val & EECD_DI != 0 {
+            eecd.val_in |= 1;
+        }
+        eecd.bitnum_in += 1;
+        // A command is a 3 bit opcode followed by a 6 bit word address.
+        if eecd.bitnum_in == 9 && !eecd.reading {
+            eecd.bitnum_out = ((eecd.val_in & 0x3f) << 4).wrapping_sub(1);
+            eecd.reading = (eecd.val_in >> 6) & 7 == EEPROM_READ_OPCODE_MICROWIRE;
+        }
+    }
+
+    fn set_eerd(&mut self, val: u32) {
+        if val & EERD_START == 0 {
+            self.set_reg(EERD, val);
+            return;
+        }
+        let addr = (val >> EERD_ADDR_SHIFT) & 0xff;
+        let data = self.eeprom.get(addr as usize).copied().unwrap_or(0);
+        self.set_reg(
+            EERD,
+            EERD_DONE | addr << EERD_ADDR_SHIFT | u32::from(data) << EERD_DATA_SHIFT,
+        );
+    }
+
+    fn set_mdic(&mut self, val: u32) {
+        let data = val & MDIC_DATA_MASK;
+        let reg = ((val >> MDIC_REG_SHIFT) & 0x1f) as usize;
+        let mut mdic = val;
+        if (val >> MDIC_PHY_SHIFT) & 0x1f != PHY_ADDR {
+            mdic |= MDIC_ERROR;
+        } else if val & MDIC_OP_READ != 0 {
+            mdic = (val & !MDIC_DATA_MASK) | u32::from(self.phy[reg]);
+        } else if val & MDIC_OP_WRITE != 0 {
+            let mut data = data as u16;
+            if reg == PHY_CTRL {
+                // Resets and autonegotiation complete immediately.
+                data &= !(PHY_CTRL_RESET | PHY_CTRL_RESTART_AUTONEG);
+            }
+            self.phy[reg] = data;
+        }
+        self.set_reg(MDIC, mdic | MDIC_READY);
+        if val & MDIC_INT_EN != 0 {
+            self.set_ics(ICR_MDAC);
+        }
+    }
+
+    fn ring_base(&self, bal: u64, bah: u64) -> GuestAddress {
+        GuestAddress(u64::from(self.reg(bah)) << 32 | u64::from(self.reg(bal) & !0xf))
+    }
+
+    // Sends the frames described by the transmit descriptors between the head and tail.
+    fn start_xmit(&mut self) {
+        if self.reg(TCTL) & TCTL_EN == 0 {
+            return;
+        }
+        let base = self.ring_base(TDBAL, TDBAH);
+        let count = u64::from(self.reg(TDLEN)) / DESC_LEN;
+        let mut head = u64::from(self.reg(TDH));
+        let tail = u64::from(self.reg(TDT));
+        if count == 0 {
+            return;
+        }
+        // The head would never catch up with a tail outside of the ring.
+        if head >= count || tail >= count {
+            error!(
+                "e1000: tx head {} or tail {} outside of ring of {}",
+                head, tail, count
+            );
+            return;
+        }
+
+        let mut processed = false;
+        while head != tail {
+            let desc_addr = match base.checked_add(head * DESC_LEN) {
+                Some(a) => a,
+                None => break,
+            };
+            let desc: TxDesc = match self.mem.read_obj_from_addr(desc_addr) {
+                Ok(d) => d,
+                Err(e) => {
+                    error!("e1000: failed to read tx descriptor: {}", e);
+                    break;
+                }
+            };
+            self.process_tx_desc(&desc);
+            let cmd = (desc.lower.to_native() >> 24) as u8;
+            if cmd & TXD_CMD_RS != 0 {
+                let upper = Le32::from(desc.upper.to_native() | TXD_STAT_DD);
+                if let Err(e) = self
+                    .mem
+                    .write_obj_at_addr(upper, desc_addr.unchecked_add(12))
+                {
+                    error!("e1000: failed to write tx descriptor status: {}", e);
+                }
+            }
+            head = (head + 1) % count;
+            processed = true;
+        }
+        self.set_reg(TDH, head as u32);
+        if processed {
+            self.set_ics(ICR_TXDW | ICR_TXQE);
+        }
+    }
+
+    fn process_tx_desc(&mut self, desc: &TxDesc) {
+        let lower = desc.lower.to_native();
+        let upper = desc.upper.to_native();
+        let cmd = (lower >> 24) as u8;
+        let first = self.tx_packet.data.is_empty() && !self.tx_packet.oversized;
+
+        let length = if cmd & TXD_CMD_DEXT != 0 {
+            match (lower >> 20) & 0xf {
+                TXD_DTYP_CONTEXT => {
+                    let addr = desc.addr.to_native();
+                    self.tx_ctx = TxContext {
+                        ipcss: (addr & 0xff) as usize,
+                        ipcso: ((addr >> 8) & 0xff) as usize,
+                        ipcse: ((addr >> 16) & 0xffff) as usize,
+                        tucss: ((addr >> 32) & 0xff) as usize,
+                        tucso: ((addr >> 40) & 0xff) as usize,
+                        tucmd: cmd,
+                        hdr_len: ((upper >> 8) & 0xff) as usize,
+                        mss: (upper >> 16) as u16,
+                    };
+                    return;
+                }
+                TXD_DTYP_DATA => {
+                    if first {
+                        self.tx_packet.tse = cmd & TXD_CMD_TSE != 0;
+                        self.tx_packet.popts = (upper >> 8) as u8;
+                    }
+                    (lower & 0xfffff) as usize
+                }
+                dtyp => {
+                    warn!("e1000: unknown tx descriptor type {}", dtyp);
+                    return;
+                }
+            }
+        } else {
+            if first && cmd & TXD_CMD_IC != 0 {
+                let cso = ((lower >> 16) & 0xff) as usize;
+                let css = ((upper >> 8) & 0xff) as usize;
+                self.tx_packet.legacy_csum = Some((css, cso));
+            }
+            (lower & 0xffff) as usize
+        };
+
+        if cmd & TXD_CMD_VLE != 0 {
+            self.tx_packet.vlan = Some((upper >> 16) as u16);
+        }
+
+        let packet = &mut self.tx_packet;
+        let start = packet.data.len();
+        if start + length > MAX_TX_FRAME {
+            packet.oversized = true;
+        } else if !packet.oversized {
+            packet.data.resize(start + length, 0);
+            let addr = GuestAddress(desc.addr.to_native());
+            if let Err(e) = self.mem.read_exact_at_addr(&mut packet.data[start..], addr) {
+                error!("e1000: failed to read tx buffer: {}", e);
+                packet.oversized = true;
+            }
+        }
+
+        if cmd & TXD_CMD_EOP != 0 {
+            let packet = std::mem::take(&mut self.tx_packet);
+            if packet.oversized {
+                warn!("e1000: dropping oversized or unreadable tx frame");
+            } else if let Err(e) = self.send_packet(packet) {
+                error!("e1000: failed to write frame to tap: {}", e);
+            }
+        }
+    }
+
+    // Applies the offloads requested for `packet` and writes it to the tap. Checksums and
+    // segmentation that the host kernel can do are passed on in the vnet header.
+    fn send_packet(&mut self, mut packet: TxPacket) -> io::Result<()> {
+        let ctx = self.tx_ctx;
+        let mut hdr = VnetHdr::default();
+        let mut csum: Option<(usize, usize)> = None;
+        let mut hdr_len = 0;
+        let data = &mut packet.data;
+
+        if packet.tse && ctx.mss != 0 {
+            // The driver leaves the lengths for the hardware to fill in, and seeds the TCP
+            // checksum with a pseudo header that doesn't include the length.
+            let len = data.len();
+            if ctx.tucmd & TXD_TUCMD_IP != 0 {
+                write_be16(data, ctx.ipcss + 2, len.saturating_sub(ctx.ipcss) as u16);
+                let ihl = data.get(ctx.ipcss).map_or(0, |b| usize::from(b & 0xf) * 4);
+                insert_checksum(data, ctx.ipcss, ctx.ipcso, ctx.ipcss + ihl);
+                hdr.gso_type = VIRTIO_NET_HDR_GSO_TCPV4 as u8;
+            } else {
+                write_be16(
+                    data,
+                    ctx.ipcss + 4,
+                    len.saturating_sub(ctx.ipcss + 40) as u16,
+                );
+                hdr.gso_type = VIRTIO_NET_HDR_GSO_TCPV6 as u8;
+            }
+            if ctx.tucmd & TXD_TUCMD_TCP != 0 {
+                let seed = u32::from(read_be16(data, ctx.tucso));
+                let tcp_len = len.saturating_sub(ctx.tucss) as u32;
+                write_be16(data, ctx.tucso, fold_checksum(seed + tcp_len));
+            }
+            hdr.gso_size = Le16::from(ctx.mss);
+            hdr_len = ctx.hdr_len;
+            csum = Some((ctx.tucss, ctx.tucso));
+        } else if let Some(legacy_csum) = packet.legacy_csum {
+            csum = Some(legacy_csum);
+        } else {
+            if packet.popts & TXD_POPTS_IXSM != 0 {
+                let end = if ctx.ipcse == 0 {
+                    data.len()
+                } else {
+                    ctx.ipcse + 1
+                };
+                insert_checksum(data, ctx.ipcss, ctx.ipcso, end);
+            }
+            if packet.popts & TXD_POPTS_TXSM != 0 {
+                csum = Some((ctx.tucss, ctx.tucso));
+            }
+        }
+
+        let mut vlan_len = 0;
+        if let Some(tci) = packet.vlan {
+            if self.reg(CTRL) & CTRL_VME != 0 && data.len() >= VLAN_TAG_OFFSET {
+                let mut tag = [0u8; VLAN_TAG_LEN];
+                tag[..2].copy_from_slice(&(self.reg(VET) as u16).to_be_bytes());
+                tag[2..].copy_from_slice(&tci.to_be_bytes());
+                data.splice(VLAN_TAG_OFFSET..VLAN_TAG_OFFSET, tag.iter().copied());
+                vlan_len = VLAN_TAG_LEN;
+            }
+        }
+
+        if let Some((start, offset)) = csum {
+            if offset > start {
+                hdr.flags = VIRTIO_NET_HDR_F_NEEDS_CSUM as u8;
+                hdr.csum_start = Le16::from((start + vlan_len) as u16);
+                hdr.csum_offset = Le16::from((offset - start) as u16);
+            }
+        }
+        if hdr_len != 0 {
+            hdr.hdr_len = Le16::from((hdr_len + vlan_len) as u16);
+        }
+
+        let mut frame = Vec::with_capacity(VNET_HDR_LEN + data.len());
+        frame.extend_from_slice(hdr.as_slice());
+        frame.extend_from_slice(data);
+        self.tx_tap.write_all(&frame)
+    }
+
+    fn rx_buffer_size(&self) -> usize {
+        let rctl = self.reg(RCTL);
+        let size = match (rctl & RCTL_BSIZE_MASK) >> RCTL_BSIZE_SHIFT {
+            0 => 2048,
+            1 => 1024,
+            2 => 512,
+            _ => 256,
+        };
+        if rctl & RCTL_BSEX != 0 {
+            size * 16
+        } else {
+            size
+        }
+    }
+
+    // Places `frame` in the receive ring. Returns false if the guest hasn't made enough
+    // descriptors available to hold it, in which case it should be retried later. Frames received
+    // while the receiver is disabled are dropped.
+    fn receive(&mut self, frame: &[u8]) -> bool {
+        if self.reg(RCTL) & RCTL_EN == 0 {
+            return true;
+        }
+        let base = self.ring_base(RDBAL, RDBAH);
+        let count = u64::from(self.reg(RDLEN)) / DESC_LEN;
+        let mut head = u64::from(self.reg(RDH));
+        let tail = u64::from(self.reg(RDT));
+        if count == 0 || head >= count || tail >= count {
+            return false;
+        }
+
+        let mut status = RXD_STAT_DD | RXD_STAT_IXSM;
+        let mut special = 0;
+        let mut data = frame.to_vec();
+        let vet = self.reg(VET) as u16;
+        if self.reg(CTRL) & CTRL_VME != 0 && read_be16(&data, VLAN_TAG_OFFSET) == vet {
+            special = read_be16(&data, VLAN_TAG_OFFSET + 2);
+            data.drain(VLAN_TAG_OFFSET..VLAN_TAG_OFFSET + VLAN_TAG_LEN);
+            status |= RXD_STAT_VP;
+        }
+        if data.len() < MIN_RX_FRAME {
+            data.resize(MIN_RX_FRAME, 0);
+        }
+
+        let buf_size = self.rx_buffer_size();
+        let needed = ((data.len() + buf_size - 1) / buf_size) as u64;
+        let available = (tail + count - head) % count;
+        if needed > available {
+            return false;
+        }
+
+        let mut chunks = data.chunks(buf_size).peekable();
+        while let Some(chunk) = chunks.next() {
+            let desc_addr = base.unchecked_add(head * DESC_LEN);
+            let mut desc: RxDesc = match self.mem.read_obj_from_addr(desc_addr) {
+                Ok(d) => d,
+                Err(e) => {
+                    error!("e1000: failed to read rx descriptor: {}", e);
+                    return true;
+                }
+            };
+            if let Err(e) = self
+                .mem
+                .write_all_at_addr(chunk, GuestAddress(desc.addr.to_native()))
+            {
+                error!("e1000: failed to write rx buffer: {}", e);
+            }
+            desc.length = Le16::from(chunk.len() as u16);
+            desc.csum = Le16::from(0);
+            desc.status = if chunks.peek().is_none() {
+                status | RXD_STAT_EOP
+            } else {
+                status
+            };
+            desc.errors = 0;
+            desc.special = Le16::from(special);
+            if let Err(e) = self.mem.write_obj_at_addr(desc, desc_addr) {
+                error!("e1000: failed to write rx descriptor: {}", e);
+            }
+            head = (head + 1) % count;
+        }
+        self.set_reg(RDH, head as u32);
+        self.set_ics(ICR_RXT0);
+        true
+    }
+}
+
+// Reads frames from the tap and passes them to the receive ring.
+struct Worker<T: TapT> {
+    core: Arc<Mutex<E1000Core>>,
+    tap: T,
+    rx_kick_evt: Event,
+    irq_resample_evt: Event,
+    kill_evt: Event,
+}
+
+impl<T: TapT> Worker<T> {
+    // Reads a frame from the tap into `buf`, returning the length of the frame without its vnet
+    // header, or `None` if no frame is ready.
+    fn read_frame(&mut self, buf: &mut [u8]) -> Option<usize> {
+        loop {
+            match self.tap.read(buf) {
+                Ok(0) => return None,
+                Ok(n) if n > VNET_HDR_LEN => return Some(n - VNET_HDR_LEN),
+                // Skip frames too short to hold a vnet header.
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(e) => {
+                    warn!("e1000: failed to read from tap: {}", e);
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn run(&mut self) -> std::result::Result<(), NetError> {
+        #[derive(PollToken)]
+        enum Token {
+            // A frame is available for reading from the tap device.
+            RxTap,
+            // The guest has made receive descriptors available.
+            RxKick,
+            // Check if the interrupt needs to be re-asserted.
+            InterruptResample,
+            // crosvm has requested the device to shut down.
+            Kill,
+        }
+
+        let wait_ctx: WaitContext<Token> = WaitContext::build_with(&[
+            (&self.tap, Token::RxTap),
+            (&self.rx_kick_evt, Token::RxKick),
+            (&self.irq_resample_evt, Token::InterruptResample),
+            (&self.kill_evt, Token::Kill),
+        ])
+        .map_err(NetError::CreateWaitContext)?;
+
+        let mut buf = vec![0u8; VNET_HDR_LEN + MAX_RX_FRAME];
+        // Length of a frame in `buf` that didn't fit in the receive ring.
+        let mut pending: Option<usize> = None;
+        let mut tap_polling_enabled = true;
+        'wait: loop {
+            let events = wait_ctx.wait().map_err(NetError::WaitError)?;
+            for event in events.iter().filter(|e| e.is_readable) {
+                match event.token {
+                    Token::RxTap => {
+                        while pending.is_none() {
+                            let len = match self.read_frame(&mut buf) {
+                                Some(len) => len,
+                                None => break,
+                            };
+                            let frame = &buf[VNET_HDR_LEN..VNET_HDR_LEN + len];
+                            if !self.core.lock().receive(frame) {
+                                pending = Some(len);
+                            }
+                        }
+                        if pending.is_some() {
+                            wait_ctx
+                                .modify(&self.tap, EventType::None, Token::RxTap)
+                                .map_err(NetError::WaitContextDisableTap)?;
+                            tap_polling_enabled = false;
+                        }
+                    }
+                    Token::RxKick => {
+                        let _ = self.rx_kick_evt.read();
+                        if let Some(len) = pending {
+                            let frame = &buf[VNET_HDR_LEN..VNET_HDR_LEN + len];
+                            if self.core.lock().receive(frame) {
+                                pending = None;
+                            }
+                        }
+                        if pending.is_none() && !tap_polling_enabled {
+                            wait_ctx
+                                .modify(&self.tap, EventType::Read, Token::RxTap)
+                                .map_err(NetError::WaitContextEnableTap)?;
+                            tap_polling_enabled = true;
+                        }
+                    }
+                    Token::InterruptResample => {
+                        let _ = self.irq_resample_evt.read();
+                        self.core.lock().update_irq();
+                    }
+                    Token::Kill => {
+                        let _ = self.kill_evt.read();
+                        break 'wait;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Emulated Intel 82540EM network controller.
+///
+/// Only the memory mapped register BAR is exposed; drivers that reset the device through the I/O
+/// BAR fall back to the memory mapped `CTRL` register.
+pub struct E1000<T: TapT> {
+    config_regs: PciConfiguration,
+    pci_address: Option<PciAddress>,
+    core: Arc<Mutex<E1000Core>>,
+    // The tap and events are handed to the worker once the device is sandboxed.
+    tap: Option<T>,
+    irq_resample_evt: Option<Event>,
+    worker_rx_kick_evt: Option<Event>,
+    worker_kill_evt: Option<Event>,
+    kill_evt: Event,
+    worker_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl<T> E1000<T>
+where
+    T: 'static + TapT,
+{
+    /// Creates an e1000 device backed by a new tap device with the given host IP address, netmask
+    /// and MAC address. The guest sees `guest_mac` as the NIC's address.
+    pub fn new(
+        mem: GuestMemory,
+        ip_addr: Ipv4Addr,
+        netmask: Ipv4Addr,
+        host_mac: MacAddress,
+        guest_mac: MacAddress,
+    ) -> std::result::Result<E1000<T>, NetError> {
+        let tap: T = create_tap(ip_addr, netmask, host_mac, false)?;
+        E1000::from(mem, tap, guest_mac)
+    }
+
+    /// Creates an e1000 device from a tap device that has already been configured.
+    pub fn from(
+        mem: GuestMemory,
+        tap: T,
+        guest_mac: MacAddress,
+    ) -> std::result::Result<E1000<T>, NetError> {
+        validate_and_configure_tap(&tap, 1)?;
+        // Frames are handed to the guest as they are received, so the host must not coalesce them
+        // or leave their checksums unfinished.
+        tap.set_offload(0).map_err(NetError::TapSetOffload)?;
+
+        // Safe because we own the tap descriptor and check the result.
+        let fd = unsafe { libc::dup(tap.as_raw_descriptor()) };
+        if fd < 0 {
+            return Err(NetError::CloneTap(SysError::last()));
+        }
+        // Safe because `fd` was just duplicated and nothing else owns it.
+        let tx_tap = unsafe { File::from_raw_fd(fd) };
+
+        let rx_kick_evt = Event::new().map_err(NetError::CreateEvent)?;
+        let worker_rx_kick_evt = rx_kick_evt.try_clone().map_err(NetError::CloneEvent)?;
+        let kill_evt = Event::new().map_err(NetError::CreateKillEvent)?;
+        let worker_kill_evt = kill_evt.try_clone().map_err(NetError::CloneKillEvent)?;
+
+        let config_regs = PciConfiguration::new(
+            PCI_VENDOR_ID_INTEL,
+            PCI_DEVICE_ID_INTEL_82540EM,
+            PciClassCode::NetworkController,
+            &PciNetworkSubclass::EthernetController,
+            None, // No Programming interface.
+            PciHeaderType::Device,
+            PCI_VENDOR_ID_INTEL,
+            PCI_DEVICE_ID_INTEL_82540EM,
+        );
+
+        Ok(E1000 {
+            config_regs,
+            pci_address: None,
+            core: Arc::new(Mutex::new(E1000Core::new(
+                mem,
+                &guest_mac,
+                tx_tap,
+                rx_kick_evt,
+            ))),
+            tap: Some(tap),
+            irq_resample_evt: None,
+            worker_rx_kick_evt: Some(worker_rx_kick_evt),
+            worker_kill_evt: Some(worker_kill_evt),
+            kill_evt,
+            worker_thread: None,
+        })
+    }
+
+    fn start_worker(&mut self) {
+        let (tap, irq_resample_evt, rx_kick_evt, kill_evt) = match (
+            self.tap.take(),
+            self.irq_resample_evt.take(),
+            self.worker_rx_kick_evt.take(),
+            self.worker_kill_evt.take(),
+        ) {
+            (Some(a), Some(b), Some(c), Some(d)) => (a, b, c, d),
+            _ => {
+                error!("e1000: worker started without its resources");
+                return;
+            }
+        };
+        let core = self.core.clone();
+        let worker_result = thread::Builder::new()
+            .name("e1000 worker".to_string())
+            .spawn(move || {
+                let mut worker = Worker {
+                    core,
+                    tap,
+                    rx_kick_evt,
+                    irq_resample_evt,
+                    kill_evt,
+                };
+                if let Err(e) = worker.run() {
+                    error!("e1000 worker thread exited with error: {}", e);
+                }
+            });
+        match worker_result {
+            Err(e) => error!("failed to spawn e1000 worker: {}", e),
+            Ok(join_handle) => self.worker_thread = Some(join_handle),
+        }
+    }
+}
+
+impl<T: TapT> Drop for E1000<T> {
+    fn drop(&mut self) {
+        if let Some(worker_thread) = self.worker_thread.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = self.kill_evt.write(1);
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl<T> PciDevice for E1000<T>
+where
+    T: 'static + TapT,
+{
+    fn debug_label(&self) -> String {
+        "e1000".to_owned()
+    }
+
+    fn assign_address(&mut self, address: PciAddress) {
+        self.pci_address = Some(address);
+    }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let core = self.core.lock();
+        let mut keep_rds = vec![
+            self.kill_evt.as_raw_descriptor(),
+            core.tx_tap.as_raw_descriptor(),
+            core.rx_kick_evt.as_raw_descriptor(),
+        ];
+        if let Some(tap) = &self.tap {
+            keep_rds.push(tap.as_raw_descriptor());
+        }
+        if let Some(evt) = &self.worker_rx_kick_evt {
+            keep_rds.push(evt.as_raw_descriptor());
+        }
+        if let Some(evt) = &self.worker_kill_evt {
+            keep_rds.push(evt.as_raw_descriptor());
+        }
+        keep_rds
+    }
+
+    fn assign_irq(
+        &mut self,
+        irq_evt: Event,
+        irq_resample_evt: Event,
+        irq_num: u32,
+        irq_pin: PciInterruptPin,
+    ) {
+        self.config_regs.set_irq(irq_num as u8, irq_pin);
+        self.core.lock().irq_evt = Some(irq_evt);
+        self.irq_resample_evt = Some(irq_resample_evt);
+    }
+
+    fn allocate_io_bars(&mut self, resources: &mut SystemAllocator) -> Result<Vec<(u64, u64)>> {
+        let address = self
+            .pci_address
+            .expect("assign_address must be called prior to allocate_io_bars");
+        let regs_addr = resources
+            .mmio_allocator(MmioType::Low)
+            .allocate_with_align(
+                E1000_MMIO_SIZE,
+                Alloc::PciBar {
+                    bus: address.bus,
+                    dev: address.dev,
+                    func: address.func,
+                    bar: 0,
+                },
+                "e1000-regs".to_string(),
+                E1000_MMIO_SIZE,
+            )
+            .map_err(|e| pci_device::Error::IoAllocationFailed(E1000_MMIO_SIZE, e))?;
+        let config = PciBarConfiguration::default()
+            .set_register_index(0)
+            .set_address(regs_addr)
+            .set_size(E1000_MMIO_SIZE);
+        self.config_regs
+            .add_pci_bar(config)
+            .map_err(|e| pci_device::Error::IoRegistrationFailed(regs_addr, e))?;
+        Ok(vec![(regs_addr, E1000_MMIO_SIZE)])
+    }
+
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        self.config_regs.read_reg(reg_idx)
+    }
+
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        self.config_regs.write_reg(reg_idx, offset, data)
+    }
+
+    fn read_bar(&mut self, addr: u64, data: &mut [u8]) {
+        let bar0 = self.config_regs.get_bar_addr(0);
+        if addr < bar0 || addr >= bar0 + E1000_MMIO_SIZE {
+            return;
+        }
+        let offset = addr - bar0;
+        let shift = (offset & 3) * 8;
+        if (offset & 3) as usize + data.len() > 4 {
+            warn!("e1000: unaligned register read at {:#x}", offset);
+            return;
+        }
+        let val = self.core.lock().read_reg(offset & !3) >> shift;
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = (val >> (i * 8)) as u8;
+        }
+    }
+
+    fn write_bar(&mut self, addr: u64, data: &[u8]) {
+        let bar0 = self.config_regs.get_bar_addr(0);
+        if addr < bar0 || addr >= bar0 + E1000_MMIO_SIZE {
+            return;
+        }
+        let offset = addr - bar0;
+        if offset & 3 != 0 || data.len() != 4 {
+            warn!(
+                "e1000: unsupported register write of {} bytes at {:#x}",
+                data.len(),
+                offset
+            );
+            return;
+        }
+        let val = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        self.core.lock().write_reg(offset, val);
+    }
+
+    fn on_device_sandboxed(&mut self) {
+        self.start_worker();
+    }
+}
+
+impl<T: TapT> Suspendable for E1000<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::pipe;
+    use std::io::Read;
+
+    fn test_core() -> (E1000Core, File) {
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 0x10000)]).unwrap();
+        let mac: MacAddress = "52:54:00:12:34:56".parse().unwrap();
+        let (rx, tx) = pipe(true).unwrap();
+        (E1000Core::new(mem, &mac, tx, Event::new().unwrap()), rx)
+    }
+
+    // Reads an EEPROM word through the microwire interface the way the Linux driver does.
+    fn microwire_read(core: &mut E1000Core, addr: u32) -> u16 {
+        let clock_in = |core: &mut E1000Core, bit: bool| {
+            let di = if bit { EECD_DI } else { 0 };
+            core.write_reg(EECD, EECD_CS | di);
+            core.write_reg(EECD, EECD_CS | di | EECD_SK);
+            core.write_reg(EECD, EECD_CS | di);
+        };
+        core.write_reg(EECD, 0);
+        core.write_reg(EECD, EECD_CS);
+        let cmd = EEPROM_READ_OPCODE_MICROWIRE << 6 | addr;
+        for i in (0..9).rev() {
+            clock_in(core, cmd & (1 << i) != 0);
+        }
+        let mut word = 0;
+        for _ in 0..16 {
+            core.write_reg(EECD, EECD_CS | EECD_SK);
+            let bit = core.read_reg(EECD) & EECD_DO != 0;
+            core.write_reg(EECD, EECD_CS);
+            word = word << 1 | u16::from(bit);
+        }
+        core.write_reg(EECD, 0);
+        word
+    }
+
+    #[test]
+    fn eeprom_microwire() {
+        let (mut core, _rx) = test_core();
+        assert_eq!(microwire_read(&mut core, 0), 0x5452);
+        assert_eq!(microwire_read(&mut core, 1), 0x1200);
+        assert_eq!(microwire_read(&mut core, 2), 0x5634);
+        let sum = (0..EEPROM_WORDS as u32)
+            .map(|i| microwire_read(&mut core, i))
+            .fold(0u16, |sum, w| sum.wrapping_add(w));
+        assert_eq!(sum, EEPROM_SUM);
+    }
+
+    #[test]
+    fn eeprom_eerd() {
+        let (mut core, _rx) = test_core();
+        core.write_reg(EERD, 11 << EERD_ADDR_SHIFT | EERD_START);
+        let eerd = core.read_reg(EERD);
+        assert_ne!(eerd & EERD_DONE, 0);
+        assert_eq!(
+            eerd >> EERD_DATA_SHIFT,
+            u32::from(PCI_DEVICE_ID_INTEL_82540EM)
+        );
+    }
+
+    #[test]
+    fn phy_id() {
+        let (mut core, _rx) = test_core();
+        core.write_reg(
+            MDIC,
+            MDIC_OP_READ | PHY_ADDR << MDIC_PHY_SHIFT | (PHY_ID1 as u32) << MDIC_REG_SHIFT,
+        );
+        let mdic = core.read_reg(MDIC);
+        assert_ne!(mdic & MDIC_READY, 0);
+        assert_eq!(mdic & MDIC_DATA_MASK, 0x0141);
+
+        core.write_reg(MDIC, MDIC_OP_READ | 2 << MDIC_PHY_SHIFT);
+        assert_ne!(core.read_reg(MDIC) & MDIC_ERROR, 0);
+    }
+
+    #[test]
+    fn transmit_legacy_checksum() {
+        let (mut core, mut rx) = test_core();
+        let frame: Vec<u8> = (0..64).collect();
+        core.mem
+            .write_all_at_addr(&frame, GuestAddress(0x2000))
+            .unwrap();
+        let cmd = TXD_CMD_EOP | TXD_CMD_IC | TXD_CMD_RS;
+        let desc = TxDesc {
+            addr: Le64::from(0x2000),
+            lower: Le32::from(u32::from(cmd) << 24 | 50 << 16 | 64),
+            upper: Le32::from(34 << 8),
+        };
+        core.mem
+            .write_obj_at_addr(desc, GuestAddress(0x1000))
+            .unwrap();
+        core.write_reg(TDBAL, 0x1000);
+        core.write_reg(TDLEN, 128);
+        core.write_reg(TCTL, TCTL_EN);
+        core.write_reg(TDT, 1);
+
+        assert_eq!(core.reg(TDH), 1);
+        assert_eq!(core.reg(ICR) & ICR_TXDW, ICR_TXDW);
+        let done: TxDesc = core.mem.read_obj_from_addr(GuestAddress(0x1000)).unwrap();
+        assert_eq!(done.upper.to_native() & TXD_STAT_DD, TXD_STAT_DD);
+
+        let mut written = vec![0u8; VNET_HDR_LEN + frame.len()];
+        rx.read_exact(&mut written).unwrap();
+        let hdr = VnetHdr::from_slice(&written[..VNET_HDR_LEN]).unwrap();
+        assert_eq!(hdr.flags, VIRTIO_NET_HDR_F_NEEDS_CSUM as u8);
+        assert_eq!(hdr.csum_start.to_native(), 34);
+        assert_eq!(hdr.csum_offset.to_native(), 16);
+        assert_eq!(&written[VNET_HDR_LEN..], &frame[..]);
+    }
+
+    #[test]
+    fn transmit_tail_outside_ring() {
+        let (mut core, _rx) = test_core();
+        core.write_reg(TDBAL, 0x1000);
+        core.write_reg(TDLEN, 128);
+        core.write_reg(TCTL, TCTL_EN);
+        // The ring has 8 descriptors, so this tail is never reached.
+        core.write_reg(TDT, 8);
+
+        assert_eq!(core.reg(TDH), 0);
+        assert_eq!(core.reg(ICR) & ICR_TXDW, 0);
+    }
+
+    #[test]
+    fn receive_into_ring() {
+        let (mut core, _rx) = test_core();
+        for i in 0..4 {
+            let desc = RxDesc {
+                addr: Le64::from(0x4000 + i * 0x800),
+                ..Default::default()
+            };
+            core.mem
+                .write_obj_at_addr(desc, GuestAddress(0x1000 + i * DESC_LEN))
+                .unwrap();
+        }
+        core.write_reg(RDBAL, 0x1000);
+        core.write_reg(RDLEN, 128);
+        core.write_reg(IMS, ICR_RXT0);
+
+        let frame = vec![0xa5u8; 3000];
+        // The receiver is disabled so the frame is dropped.
+        assert!(core.receive(&frame));
+        assert_eq!(core.reg(RDH), 0);
+
+        core.write_reg(RCTL, RCTL_EN);
+        // Only one descriptor is available, but the frame needs two.
+        core.write_reg(RDT, 1);
+        assert!(!core.receive(&frame));
+        core.write_reg(RDT, 3);
+        assert!(core.receive(&frame));
+        assert_eq!(core.reg(RDH), 2);
+        assert_ne!(core.read_reg(ICR) & ICR_RXT0, 0);
+
+        let first: RxDesc = core.mem.read_obj_from_addr(GuestAddress(0x1000)).unwrap();
+        let second: RxDesc = core
+            .mem
+            .read_obj_from_addr(GuestAddress(0x1000 + DESC_LEN))
+            .unwrap();
+        assert_eq!(first.length.to_native(), 2048);
+        assert_eq!(first.status & (RXD_STAT_DD | RXD_STAT_EOP), RXD_STAT_DD);
+        assert_eq!(second.length.to_native(), 952);
+        assert_eq!(
+            second.status & (RXD_STAT_DD | RXD_STAT_EOP),
+            RXD_STAT_DD | RXD_STAT_EOP
+        );
+    }
+}
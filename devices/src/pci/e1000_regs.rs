@@ -0,0 +1,155 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+// Register offsets and bits of the Intel 8254x family, as described in the "PCI/PCI-X Family of
+// Gigabit Ethernet Controllers Software Developer's Manual".
+
+// Size of the memory mapped register BAR.
+pub const E1000_MMIO_SIZE: u64 = 0x20000;
+
+// Device control.
+pub const CTRL: u64 = 0x0000;
+pub const CTRL_SLU: u32 = 1 << 6;
+pub const CTRL_SPD_1000: u32 = 1 << 9;
+pub const CTRL_RST: u32 = 1 << 26;
+pub const CTRL_VME: u32 = 1 << 30;
+pub const CTRL_PHY_RST: u32 = 1 << 31;
+
+// Device status.
+pub const STATUS: u64 = 0x0008;
+pub const STATUS_FD: u32 = 1 << 0;
+pub const STATUS_LU: u32 = 1 << 1;
+pub const STATUS_SPEED_1000: u32 = 1 << 7;
+pub const STATUS_ASDV_1000: u32 = 2 << 8;
+pub const STATUS_MTXCKOK: u32 = 1 << 10;
+pub const STATUS_GIO_MASTER_ENABLE: u32 = 1 << 19;
+
+// EEPROM control and data.
+pub const EECD: u64 = 0x0010;
+pub const EECD_SK: u32 = 1 << 0;
+pub const EECD_CS: u32 = 1 << 1;
+pub const EECD_DI: u32 = 1 << 2;
+pub const EECD_DO: u32 = 1 << 3;
+pub const EECD_FWE_MASK: u32 = 3 << 4;
+pub const EECD_REQ: u32 = 1 << 6;
+pub const EECD_GNT: u32 = 1 << 7;
+pub const EECD_PRES: u32 = 1 << 8;
+pub const EECD_AUTO_RD: u32 = 1 << 9;
+
+// EEPROM read.
+pub const EERD: u64 = 0x0014;
+pub const EERD_START: u32 = 1 << 0;
+pub const EERD_DONE: u32 = 1 << 4;
+pub const EERD_ADDR_SHIFT: u32 = 8;
+pub const EERD_DATA_SHIFT: u32 = 16;
+
+// MDI control, for PHY register access.
+pub const MDIC: u64 = 0x0020;
+pub const MDIC_DATA_MASK: u32 = 0xffff;
+pub const MDIC_REG_SHIFT: u32 = 16;
+pub const MDIC_PHY_SHIFT: u32 = 21;
+pub const MDIC_OP_WRITE: u32 = 1 << 26;
+pub const MDIC_OP_READ: u32 = 2 << 26;
+pub const MDIC_READY: u32 = 1 << 28;
+pub const MDIC_INT_EN: u32 = 1 << 29;
+pub const MDIC_ERROR: u32 = 1 << 30;
+
+// Interrupt cause read, cause set, mask set/read and mask clear.
+pub const ICR: u64 = 0x00c0;
+pub const ICS: u64 = 0x00c8;
+pub const IMS: u64 = 0x00d0;
+pub const IMC: u64 = 0x00d8;
+pub const ICR_TXDW: u32 = 1 << 0;
+pub const ICR_TXQE: u32 = 1 << 1;
+pub const ICR_RXT0: u32 = 1 << 7;
+pub const ICR_MDAC: u32 = 1 << 9;
+pub const ICR_INT_ASSERTED: u32 = 1 << 31;
+
+// Receive control.
+pub const RCTL: u64 = 0x0100;
+pub const RCTL_EN: u32 = 1 << 1;
+pub const RCTL_BSIZE_SHIFT: u32 = 16;
+pub const RCTL_BSIZE_MASK: u32 = 3 << RCTL_BSIZE_SHIFT;
+pub const RCTL_BSEX: u32 = 1 << 25;
+
+// Transmit control.
+pub const TCTL: u64 = 0x0400;
+pub const TCTL_EN: u32 = 1 << 1;
+
+pub const LEDCTL: u64 = 0x0e00;
+pub const PBA: u64 = 0x1000;
+
+// Receive descriptor ring.
+pub const RDBAL: u64 = 0x2800;
+pub const RDBAH: u64 = 0x2804;
+pub const RDLEN: u64 = 0x2808;
+pub const RDH: u64 = 0x2810;
+pub const RDT: u64 = 0x2818;
+
+// Transmit descriptor ring.
+pub const TDBAL: u64 = 0x3800;
+pub const TDBAH: u64 = 0x3804;
+pub const TDLEN: u64 = 0x3808;
+pub const TDH: u64 = 0x3810;
+pub const TDT: u64 = 0x3818;
+
+// Descriptor ring lengths are multiples of 128 bytes.
+pub const RING_LEN_MASK: u32 = 0x000f_ff80;
+
+// Receive address 0, low and high.
+pub const RAL0: u64 = 0x5400;
+pub const RAH0: u64 = 0x5404;
+pub const RAH_AV: u32 = 1 << 31;
+
+// VLAN ether type.
+pub const VET: u64 = 0x0038;
+
+// Transmit descriptor command bits, shared by the legacy and extended formats.
+pub const TXD_CMD_EOP: u8 = 1 << 0;
+pub const TXD_CMD_IC: u8 = 1 << 2;
+pub const TXD_CMD_TSE: u8 = 1 << 2;
+pub const TXD_CMD_RS: u8 = 1 << 3;
+pub const TXD_CMD_DEXT: u8 = 1 << 5;
+pub const TXD_CMD_VLE: u8 = 1 << 6;
+// Extended transmit descriptor types.
+pub const TXD_DTYP_CONTEXT: u32 = 0;
+pub const TXD_DTYP_DATA: u32 = 1;
+// Extended data descriptor packet options.
+pub const TXD_POPTS_IXSM: u8 = 1 << 0;
+pub const TXD_POPTS_TXSM: u8 = 1 << 1;
+// Context descriptor TUCMD bits.
+pub const TXD_TUCMD_TCP: u8 = 1 << 0;
+pub const TXD_TUCMD_IP: u8 = 1 << 1;
+// Transmit descriptor status: descriptor done.
+pub const TXD_STAT_DD: u32 = 1 << 0;
+
+// Receive descriptor status bits.
+pub const RXD_STAT_DD: u8 = 1 << 0;
+pub const RXD_STAT_EOP: u8 = 1 << 1;
+pub const RXD_STAT_IXSM: u8 = 1 << 2;
+pub const RXD_STAT_VP: u8 = 1 << 3;
+
+// PHY registers.
+pub const PHY_ADDR: u32 = 1;
+pub const PHY_CTRL: usize = 0x00;
+pub const PHY_STATUS: usize = 0x01;
+pub const PHY_ID1: usize = 0x02;
+pub const PHY_ID2: usize = 0x03;
+pub const PHY_AUTONEG_ADV: usize = 0x04;
+pub const PHY_LP_ABILITY: usize = 0x05;
+pub const PHY_AUTONEG_EXP: usize = 0x06;
+pub const PHY_1000T_CTRL: usize = 0x09;
+pub const PHY_1000T_STATUS: usize = 0x0a;
+pub const PHY_EXT_STATUS: usize = 0x0f;
+pub const M88_PHY_SPEC_CTRL: usize = 0x10;
+pub const M88_PHY_SPEC_STATUS: usize = 0x11;
+pub const M88_EXT_PHY_SPEC_CTRL: usize = 0x14;
+pub const PHY_CTRL_RESTART_AUTONEG: u16 = 1 << 9;
+pub const PHY_CTRL_RESET: u16 = 1 << 15;
+
+// EEPROM words.
+pub const EEPROM_WORDS: usize = 64;
+pub const EEPROM_CHECKSUM_REG: usize = 0x3f;
+pub const EEPROM_SUM: u16 = 0xbaba;
+pub const EEPROM_READ_OPCODE_MICROWIRE: u32 = 0x6;
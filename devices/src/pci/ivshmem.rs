@@ -0,0 +1,665 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! An ivshmem-compatible device that shares a host memory region between VMs, with doorbells that
+//! let each VM interrupt the others. The region and the doorbells are handed out by the ivshmem
+//! broker, so the same guest drivers work as with QEMU's ivshmem-doorbell.
+//!
+//! Only the legacy INTx interrupt is offered, so each doorbell has a single vector.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+
+use base::Error as SysError;
+use base::{error, warn, AsRawDescriptor, Event, PollToken, RawDescriptor, WaitContext};
+use msg_socket::{MsgReceiver, MsgSender};
+use resources::{Alloc, MmioType, SystemAllocator};
+use sync::Mutex;
+use vm_control::{
+    IvshmemPeerSocket, IvshmemSetup, MaybeOwnedDescriptor, MemSlot, VmMemoryControlRequestSocket,
+    VmMemoryRequest, VmMemoryResponse,
+};
+
+use crate::pci::pci_configuration::{
+    PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciClassCode, PciConfiguration,
+    PciHeaderType, PciMemorySubclass, BAR0_REG,
+};
+use crate::pci::pci_device::{self, PciDevice, Result};
+use crate::pci::{PciAddress, PciInterruptPin};
+use crate::Suspendable;
+
+const PCI_VENDOR_ID_REDHAT_QUMRANET: u16 = 0x1af4;
+const PCI_DEVICE_ID_IVSHMEM: u16 = 0x1110;
+
+const REGS_BAR: u8 = 0;
+const REGS_SIZE: u64 = 0x100;
+const REGION_BAR: u8 = 2;
+
+// Offsets of the registers in the register BAR.
+const INTR_MASK: u64 = 0x0;
+const INTR_STATUS: u64 = 0x4;
+const IV_POSITION: u64 = 0x8;
+const DOORBELL: u64 = 0xc;
+
+// The interrupt status bit set when another VM rings this VM's doorbell.
+const INTR_DOORBELL: u32 = 1;
+
+#[derive(Debug)]
+pub enum IvshmemError {
+    /// Cloning an event failed.
+    CloneEvent(SysError),
+    /// Creating the kill event failed.
+    CreateKillEvent(SysError),
+    /// The broker gave a peer id without a doorbell.
+    InvalidPeerId(u16),
+    /// The region can't back a BAR, which needs a power of two size of at least a page.
+    InvalidRegionSize(u64),
+    /// Getting the size of the region failed.
+    RegionSize(io::Error),
+}
+
+impl Display for IvshmemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::IvshmemError::*;
+
+        match self {
+            CloneEvent(e) => write!(f, "failed to clone event: {}", e),
+            CreateKillEvent(e) => write!(f, "failed to create kill event: {}", e),
+            InvalidPeerId(id) => write!(f, "peer id {} has no doorbell", id),
+            InvalidRegionSize(size) => write!(f, "invalid shared memory size {:#x}", size),
+            RegionSize(e) => write!(f, "failed to get the shared memory size: {}", e),
+        }
+    }
+}
+
+// The interrupt state, shared with the worker that waits for this VM's doorbell.
+struct IrqState {
+    intr_mask: u32,
+    intr_status: u32,
+    irq_evt: Option<Event>,
+}
+
+impl IrqState {
+    fn doorbell_rang(&mut self) {
+        self.intr_status |= INTR_DOORBELL;
+        self.update();
+    }
+
+    // Asserts the interrupt while an unmasked status bit is set.
+    fn update(&self) {
+        if self.intr_status & self.intr_mask == 0 {
+            return;
+        }
+        if let Some(irq_evt) = &self.irq_evt {
+            if let Err(e) = irq_evt.write(1) {
+                error!("ivshmem: failed to assert interrupt: {}", e);
+            }
+        }
+    }
+}
+
+struct Worker {
+    irq: Arc<Mutex<IrqState>>,
+    doorbell: Event,
+    irq_resample_evt: Event,
+    kill_evt: Event,
+}
+
+impl Worker {
+    fn run(&self) -> base::Result<()> {
+        #[derive(PollToken)]
+        enum Token {
+            Doorbell,
+            InterruptResample,
+            Kill,
+        }
+
+        let wait_ctx: WaitContext<Token> = WaitContext::build_with(&[
+            (&self.doorbell, Token::Doorbell),
+            (&self.irq_resample_evt, Token::InterruptResample),
+            (&self.kill_evt, Token::Kill),
+        ])?;
+
+        loop {
+            for event in wait_ctx.wait()?.iter().filter(|e| e.is_readable) {
+                match event.token {
+                    Token::Doorbell => {
+                        self.doorbell.read()?;
+                        self.irq.lock().doorbell_rang();
+                    }
+                    Token::InterruptResample => {
+                        self.irq_resample_evt.read()?;
+                        self.irq.lock().update();
+                    }
+                    Token::Kill => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+// Sends a request for the main process to change the guest memory map and waits for its response.
+fn vm_memory_request(
+    socket: &VmMemoryControlRequestSocket,
+    request: &VmMemoryRequest,
+) -> Option<VmMemoryResponse> {
+    if let Err(e) = socket.send(request) {
+        error!("ivshmem: failed to send memory request: {}", e);
+        return None;
+    }
+    match socket.recv() {
+        Ok(VmMemoryResponse::Err(e)) => {
+            error!("ivshmem: memory request failed: {}", e);
+            None
+        }
+        Ok(response) => Some(response),
+        Err(e) => {
+            error!("ivshmem: failed to receive memory response: {}", e);
+            None
+        }
+    }
+}
+
+// The shared region and where it is mapped in the guest.
+struct RegionMapping {
+    socket: VmMemoryControlRequestSocket,
+    region: File,
+    size: u64,
+    // The address of the region BAR, which the region is mapped at while `slot` is set.
+    addr: u64,
+    slot: Option<MemSlot>,
+}
+
+impl RegionMapping {
+    fn map(&mut self) {
+        let request = VmMemoryRequest::RegisterMmapMemory {
+            descriptor: MaybeOwnedDescriptor::Borrowed(self.region.as_raw_descriptor()),
+            size: self.size as usize,
+            offset: 0,
+            gpa: self.addr,
+        };
+        match vm_memory_request(&self.socket, &request) {
+            Some(VmMemoryResponse::RegisterMemory { slot, .. }) => self.slot = Some(slot),
+            Some(_) => error!("ivshmem: unexpected response to shared memory mapping"),
+            None => {}
+        }
+    }
+
+    fn unmap(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            vm_memory_request(&self.socket, &VmMemoryRequest::UnregisterMemory(slot));
+        }
+    }
+
+    // Follows the region BAR to `addr`, where the guest moved it.
+    fn move_to(&mut self, addr: u64) {
+        if addr == self.addr {
+            return;
+        }
+        let mapped = self.slot.is_some();
+        self.unmap();
+        self.addr = addr;
+        if mapped {
+            self.map();
+        }
+    }
+}
+
+/// A PCI device exposing a memory region shared with other VMs.
+///
+/// BAR 0 holds the registers and BAR 2 maps the shared region, which moves along with the BAR when
+/// the guest reprograms it.
+pub struct Ivshmem {
+    config_regs: PciConfiguration,
+    pci_address: Option<PciAddress>,
+    peer_id: u16,
+    region_size: u64,
+    doorbells: Vec<Event>,
+    irq: Arc<Mutex<IrqState>>,
+    // Kept open for as long as the device exists, so the broker knows the peer id is in use.
+    broker_socket: IvshmemPeerSocket,
+    region: Arc<Mutex<RegionMapping>>,
+    // The events are handed to the worker once the device is sandboxed.
+    irq_resample_evt: Option<Event>,
+    worker_doorbell: Option<Event>,
+    worker_kill_evt: Option<Event>,
+    kill_evt: Event,
+    worker_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Ivshmem {
+    /// Creates a device for the region and doorbells given by the broker on `broker_socket`.
+    pub fn new(
+        setup: IvshmemSetup,
+        broker_socket: IvshmemPeerSocket,
+        vm_memory_socket: VmMemoryControlRequestSocket,
+    ) -> std::result::Result<Ivshmem, IvshmemError> {
+        let region_size = setup
+            .region
+            .metadata()
+            .map_err(IvshmemError::RegionSize)?
+            .len();
+        if !region_size.is_power_of_two() || region_size < base::pagesize() as u64 {
+            return Err(IvshmemError::InvalidRegionSize(region_size));
+        }
+        let worker_doorbell = setup
+            .doorbells
+            .get(setup.peer_id as usize)
+            .ok_or(IvshmemError::InvalidPeerId(setup.peer_id))?
+            .try_clone()
+            .map_err(IvshmemError::CloneEvent)?;
+        let kill_evt = Event::new().map_err(IvshmemError::CreateKillEvent)?;
+        let worker_kill_evt = kill_evt.try_clone().map_err(IvshmemError::CloneEvent)?;
+
+        let config_regs = PciConfiguration::new(
+            PCI_VENDOR_ID_REDHAT_QUMRANET,
+            PCI_DEVICE_ID_IVSHMEM,
+            PciClassCode::MemoryController,
+            &PciMemorySubclass::RamController,
+            None, // No Programming interface.
+            PciHeaderType::Device,
+            PCI_VENDOR_ID_REDHAT_QUMRANET,
+            PCI_DEVICE_ID_IVSHMEM,
+        );
+
+        Ok(Ivshmem {
+            config_regs,
+            pci_address: None,
+            peer_id: setup.peer_id,
+            region_size,
+            doorbells: setup.doorbells,
+            irq: Arc::new(Mutex::new(IrqState {
+                intr_mask: 0,
+                intr_status: 0,
+                irq_evt: None,
+            })),
+            broker_socket,
+            region: Arc::new(Mutex::new(RegionMapping {
+                socket: vm_memory_socket,
+                region: setup.region,
+                size: region_size,
+                addr: 0,
+                slot: None,
+            })),
+            irq_resample_evt: None,
+            worker_doorbell: Some(worker_doorbell),
+            worker_kill_evt: Some(worker_kill_evt),
+            kill_evt,
+            worker_thread: None,
+        })
+    }
+
+    fn read_reg(&mut self, offset: u64) -> u32 {
+        match offset {
+            INTR_MASK => self.irq.lock().intr_mask,
+            INTR_STATUS => {
+                // Reading the status acknowledges the interrupt.
+                let mut irq = self.irq.lock();
+                let status = irq.intr_status;
+                irq.intr_status = 0;
+                status
+            }
+            IV_POSITION => u32::from(self.peer_id),
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, val: u32) {
+        match offset {
+            INTR_MASK => {
+                let mut irq = self.irq.lock();
+                irq.intr_mask = val;
+                irq.update();
+            }
+            INTR_STATUS => {
+                let mut irq = self.irq.lock();
+                irq.intr_status = val;
+                irq.update();
+            }
+            DOORBELL => {
+                let peer_id = (val >> 16) as usize;
+                let vector = val & 0xffff;
+                if vector != 0 {
+                    warn!("ivshmem: doorbell for unsupported vector {}", vector);
+                    return;
+                }
+                match self.doorbells.get(peer_id) {
+                    Some(doorbell) => {
+                        if let Err(e) = doorbell.write(1) {
+                            error!(
+                                "ivshmem: failed to ring doorbell of peer {}: {}",
+                                peer_id, e
+                            );
+                        }
+                    }
+                    None => warn!("ivshmem: doorbell for unknown peer {}", peer_id),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn region_alloc(&self) -> Option<Alloc> {
+        self.pci_address.map(|address| Alloc::PciBar {
+            bus: address.bus,
+            dev: address.dev,
+            func: address.func,
+            bar: REGION_BAR,
+        })
+    }
+
+    fn start_worker(&mut self) {
+        let (irq_resample_evt, doorbell, kill_evt) = match (
+            self.irq_resample_evt.take(),
+            self.worker_doorbell.take(),
+            self.worker_kill_evt.take(),
+        ) {
+            (Some(a), Some(b), Some(c)) => (a, b, c),
+            _ => {
+                error!("ivshmem: worker started without its resources");
+                return;
+            }
+        };
+        let region = self.region.clone();
+        let irq = self.irq.clone();
+        let worker_result = thread::Builder::new()
+            .name("ivshmem worker".to_string())
+            .spawn(move || {
+                // This waits for the main process to serve the request, so it runs on the worker
+                // thread rather than while the devices are being set up.
+                region.lock().map();
+                let worker = Worker {
+                    irq,
+                    doorbell,
+                    irq_resample_evt,
+                    kill_evt,
+                };
+                if let Err(e) = worker.run() {
+                    error!("ivshmem worker thread exited with error: {}", e);
+                }
+            });
+        match worker_result {
+            Err(e) => error!("failed to spawn ivshmem worker: {}", e),
+            Ok(join_handle) => self.worker_thread = Some(join_handle),
+        }
+    }
+}
+
+impl Drop for Ivshmem {
+    fn drop(&mut self) {
+        if let Some(worker_thread) = self.worker_thread.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = self.kill_evt.write(1);
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl PciDevice for Ivshmem {
+    fn debug_label(&self) -> String {
+        "ivshmem".to_owned()
+    }
+
+    fn assign_address(&mut self, address: PciAddress) {
+        self.pci_address = Some(address);
+    }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = vec![
+            self.broker_socket.as_raw_descriptor(),
+            self.kill_evt.as_raw_descriptor(),
+        ];
+        keep_rds.extend(self.doorbells.iter().map(|evt| evt.as_raw_descriptor()));
+        {
+            let region = self.region.lock();
+            keep_rds.push(region.region.as_raw_descriptor());
+            keep_rds.push(region.socket.as_raw_descriptor());
+        }
+        if let Some(evt) = &self.worker_doorbell {
+            keep_rds.push(evt.as_raw_descriptor());
+        }
+        if let Some(evt) = &self.worker_kill_evt {
+            keep_rds.push(evt.as_raw_descriptor());
+        }
+        if let Some(irq_evt) = &self.irq.lock().irq_evt {
+            keep_rds.push(irq_evt.as_raw_descriptor());
+        }
+        if let Some(evt) = &self.irq_resample_evt {
+            keep_rds.push(evt.as_raw_descriptor());
+        }
+        keep_rds
+    }
+
+    fn assign_irq(
+        &mut self,
+        irq_evt: Event,
+        irq_resample_evt: Event,
+        irq_num: u32,
+        irq_pin: PciInterruptPin,
+    ) {
+        self.config_regs.set_irq(irq_num as u8, irq_pin);
+        self.irq.lock().irq_evt = Some(irq_evt);
+        self.irq_resample_evt = Some(irq_resample_evt);
+    }
+
+    fn allocate_io_bars(&mut self, resources: &mut SystemAllocator) -> Result<Vec<(u64, u64)>> {
+        let address = self
+            .pci_address
+            .expect("assign_address must be called prior to allocate_io_bars");
+        let regs_addr = resources
+            .mmio_allocator(MmioType::Low)
+            .allocate_with_align(
+                REGS_SIZE,
+                Alloc::PciBar {
+                    bus: address.bus,
+                    dev: address.dev,
+                    func: address.func,
+                    bar: REGS_BAR,
+                },
+                "ivshmem-regs".to_string(),
+                REGS_SIZE,
+            )
+            .map_err(|e| pci_device::Error::IoAllocationFailed(REGS_SIZE, e))?;
+        let config = PciBarConfiguration::default()
+            .set_register_index(REGS_BAR as usize)
+            .set_address(regs_addr)
+            .set_size(REGS_SIZE);
+        self.config_regs
+            .add_pci_bar(config)
+            .map_err(|e| pci_device::Error::IoRegistrationFailed(regs_addr, e))?;
+        Ok(vec![(regs_addr, REGS_SIZE)])
+    }
+
+    fn allocate_device_bars(&mut self, resources: &mut SystemAllocator) -> Result<Vec<(u64, u64)>> {
+        let alloc = self
+            .region_alloc()
+            .expect("assign_address must be called prior to allocate_device_bars");
+        let region_addr = resources
+            .mmio_allocator(MmioType::High)
+            .allocate_with_align(
+                self.region_size,
+                alloc,
+                "ivshmem-region".to_string(),
+                self.region_size,
+            )
+            .map_err(|e| pci_device::Error::IoAllocationFailed(self.region_size, e))?;
+        let config = PciBarConfiguration::new(
+            REGION_BAR as usize,
+            self.region_size,
+            PciBarRegionType::Memory64BitRegion,
+            PciBarPrefetchable::Prefetchable,
+        )
+        .set_address(region_addr);
+        self.config_regs
+            .add_pci_bar(config)
+            .map_err(|e| pci_device::Error::IoRegistrationFailed(region_addr, e))?;
+        self.region.lock().addr = region_addr;
+        Ok(vec![(region_addr, self.region_size)])
+    }
+
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        self.config_regs.read_reg(reg_idx)
+    }
+
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        self.config_regs.write_reg(reg_idx, offset, data);
+
+        // The upper half of the 64-bit region BAR is written last, so the region moves then.
+        if reg_idx == BAR0_REG + REGION_BAR as usize + 1 {
+            let addr = self.config_regs.get_bar_addr(REGION_BAR as usize);
+            // Writing all ones is the guest sizing the BAR, not moving it.
+            if addr != 0 && addr != !(self.region_size - 1) {
+                self.region.lock().move_to(addr);
+            }
+        }
+    }
+
+    fn read_bar(&mut self, addr: u64, data: &mut [u8]) {
+        let bar0 = self.config_regs.get_bar_addr(REGS_BAR as usize);
+        if addr < bar0 || addr >= bar0 + REGS_SIZE {
+            return;
+        }
+        let offset = addr - bar0;
+        if offset & 3 != 0 || data.len() != 4 {
+            warn!(
+                "ivshmem: unsupported register read of {} bytes at {:#x}",
+                data.len(),
+                offset
+            );
+            return;
+        }
+        data.copy_from_slice(&self.read_reg(offset).to_le_bytes());
+    }
+
+    fn write_bar(&mut self, addr: u64, data: &[u8]) {
+        let bar0 = self.config_regs.get_bar_addr(REGS_BAR as usize);
+        if addr < bar0 || addr >= bar0 + REGS_SIZE {
+            return;
+        }
+        let offset = addr - bar0;
+        if offset & 3 != 0 || data.len() != 4 {
+            warn!(
+                "ivshmem: unsupported register write of {} bytes at {:#x}",
+                data.len(),
+                offset
+            );
+            return;
+        }
+        let val = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        self.write_reg(offset, val);
+    }
+
+    fn on_device_sandboxed(&mut self) {
+        self.start_worker();
+    }
+}
+
+impl Suspendable for Ivshmem {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempfile;
+
+    fn test_device(peer_id: u16, region_size: u64) -> std::result::Result<Ivshmem, IvshmemError> {
+        let region = tempfile().unwrap();
+        region.set_len(region_size).unwrap();
+        let setup = IvshmemSetup {
+            peer_id,
+            region,
+            doorbells: vec![Event::new().unwrap(), Event::new().unwrap()],
+        };
+        let (broker_socket, _) = msg_socket::pair().unwrap();
+        let (vm_memory_socket, _) = msg_socket::pair().unwrap();
+        Ivshmem::new(setup, broker_socket, vm_memory_socket)
+    }
+
+    #[test]
+    fn invalid_setup() {
+        assert!(matches!(
+            test_device(0, 0x3000),
+            Err(IvshmemError::InvalidRegionSize(0x3000))
+        ));
+        assert!(matches!(
+            test_device(2, 0x4000),
+            Err(IvshmemError::InvalidPeerId(2))
+        ));
+    }
+
+    #[test]
+    fn ring_peer_doorbell() {
+        let mut dev = test_device(0, 0x4000).unwrap();
+        assert_eq!(dev.read_reg(IV_POSITION), 0);
+
+        dev.write_reg(DOORBELL, 1 << 16);
+        assert_eq!(dev.doorbells[1].read().unwrap(), 1);
+    }
+
+    #[test]
+    fn doorbell_interrupt() {
+        let mut dev = test_device(1, 0x4000).unwrap();
+        let irq_evt = Event::new().unwrap();
+        dev.irq.lock().irq_evt = Some(irq_evt.try_clone().unwrap());
+
+        // A masked doorbell is only recorded in the status.
+        dev.irq.lock().doorbell_rang();
+        assert_eq!(dev.read_reg(INTR_STATUS), INTR_DOORBELL);
+        assert_eq!(dev.read_reg(INTR_STATUS), 0);
+
+        dev.write_reg(INTR_MASK, INTR_DOORBELL);
+        dev.irq.lock().doorbell_rang();
+        assert_eq!(irq_evt.read().unwrap(), 1);
+        assert_eq!(dev.read_reg(INTR_STATUS), INTR_DOORBELL);
+    }
+
+    #[test]
+    fn region_follows_bar() {
+        let (vm_memory_socket, host_socket) =
+            msg_socket::pair::<VmMemoryRequest, VmMemoryResponse>().unwrap();
+        let region = tempfile().unwrap();
+        region.set_len(0x4000).unwrap();
+        let mut mapping = RegionMapping {
+            socket: vm_memory_socket,
+            region,
+            size: 0x4000,
+            addr: 0x1000_0000,
+            slot: None,
+        };
+
+        // Serve the map, unmap and map requests of a move.
+        let host = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for slot in 0..3 {
+                let request = host_socket.recv().unwrap();
+                let response = match request {
+                    VmMemoryRequest::RegisterMmapMemory { gpa, .. } => {
+                        requests.push(gpa);
+                        VmMemoryResponse::RegisterMemory {
+                            pfn: gpa >> 12,
+                            slot,
+                        }
+                    }
+                    VmMemoryRequest::UnregisterMemory(slot) => {
+                        assert_eq!(slot, 0);
+                        VmMemoryResponse::Ok
+                    }
+                    _ => panic!("unexpected request"),
+                };
+                host_socket.send(&response).unwrap();
+            }
+            requests
+        });
+
+        mapping.map();
+        assert_eq!(mapping.slot, Some(0));
+        // Moving to the same address leaves the mapping alone.
+        mapping.move_to(0x1000_0000);
+        mapping.move_to(0x2000_0000);
+        assert_eq!(mapping.slot, Some(2));
+        assert_eq!(host.join().unwrap(), vec![0x1000_0000, 0x2000_0000]);
+    }
+}
@@ -12,14 +12,20 @@ mod ac97_bus_master;
 mod ac97_mixer;
 #[cfg(feature = "audio")]
 mod ac97_regs;
+mod e1000;
+mod e1000_regs;
+mod ivshmem;
 mod msix;
 mod pci_configuration;
 mod pci_device;
 mod pci_root;
+mod pcie_root_port;
 mod vfio_pci;
 
 #[cfg(feature = "audio")]
 pub use self::ac97::{Ac97Backend, Ac97Dev, Ac97Parameters};
+pub use self::e1000::E1000;
+pub use self::ivshmem::{Ivshmem, IvshmemError};
 pub use self::msix::{MsixCap, MsixConfig, MsixStatus};
 pub use self::pci_configuration::{
     PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciCapability, PciCapabilityID,
@@ -28,7 +34,11 @@ pub use self::pci_configuration::{
 };
 pub use self::pci_device::Error as PciDeviceError;
 pub use self::pci_device::PciDevice;
-pub use self::pci_root::{PciAddress, PciConfigIo, PciConfigMmio, PciRoot};
+pub use self::pci_root::{
+    PciAddress, PciConfigIo, PciConfigMmio, PciRoot, PCIE_CONFIG_REGISTER_BITS,
+    PCI_CONFIG_REGISTER_BITS,
+};
+pub use self::pcie_root_port::PcieRootPort;
 pub use self::vfio_pci::VfioPciDevice;
 
 /// PCI has four interrupt pins A->D.
@@ -232,8 +232,9 @@ impl MsixConfig {
     }
 
     fn msix_enable(&mut self) -> MsixResult<()> {
-        self.irq_vec.clear();
-        for i in 0..self.msix_num {
+        // The GSIs and irqfds stay registered while MSI-X is disabled, so re-enabling it only
+        // allocates the vectors that are still missing and then refreshes every route.
+        while self.irq_vec.len() < self.msix_num as usize {
             let irqfd = Event::new().unwrap();
             self.msi_device_socket
                 .send(&VmIrqRequest::AllocateOneMsi {
@@ -254,8 +255,9 @@ impl MsixConfig {
                 irqfd,
                 gsi: irq_num,
             });
-
-            self.add_msi_route(i, irq_num)?;
+        }
+        for (i, irq) in self.irq_vec.iter().enumerate() {
+            self.add_msi_route(i as u16, irq.gsi)?;
         }
         Ok(())
     }
@@ -277,6 +279,11 @@ impl MsixConfig {
         let index: usize = (offset / MSIX_TABLE_ENTRIES_MODULO) as usize;
         let modulo_offset = offset % MSIX_TABLE_ENTRIES_MODULO;
 
+        if index >= self.table_entries.len() {
+            error!("invalid MSI-X table index {}", index);
+            return;
+        }
+
         match data.len() {
             4 => {
                 let value = match modulo_offset {
@@ -328,6 +335,11 @@ impl MsixConfig {
         let index: usize = (offset / MSIX_TABLE_ENTRIES_MODULO) as usize;
         let modulo_offset = offset % MSIX_TABLE_ENTRIES_MODULO;
 
+        if index >= self.table_entries.len() {
+            error!("invalid MSI-X table index {}", index);
+            return MsixStatus::NothingToDo;
+        }
+
         // Store the value of the entry before modification
         let old_entry = self.table_entries[index].clone();
 
@@ -365,9 +377,10 @@ impl MsixConfig {
                 || old_entry.msg_addr_hi != new_entry.msg_addr_hi
                 || old_entry.msg_data != new_entry.msg_data)
         {
-            let irq_num = self.irq_vec[index].gsi;
-            if let Err(e) = self.add_msi_route(index as u16, irq_num) {
-                error!("add_msi_route failed: {}", e);
+            if let Some(irq) = self.irq_vec.get(index) {
+                if let Err(e) = self.add_msi_route(index as u16, irq.gsi) {
+                    error!("add_msi_route failed: {}", e);
+                }
             }
         }
 
@@ -404,6 +417,11 @@ impl MsixConfig {
         let index: usize = (offset / MSIX_PBA_ENTRIES_MODULO) as usize;
         let modulo_offset = offset % MSIX_PBA_ENTRIES_MODULO;
 
+        if index >= self.pba_entries.len() {
+            error!("invalid PBA index {}", index);
+            return;
+        }
+
         match data.len() {
             4 => {
                 let value: u32 = match modulo_offset {
@@ -489,7 +507,14 @@ impl MsixConfig {
     /// If the vector is unmasked, writing to irqfd which wakes up KVM to
     /// inject virtual interrupt to the guest.
     pub fn trigger(&mut self, vector: u16) {
-        if self.table_entries[vector as usize].masked() || self.masked() {
+        let masked = match self.table_entries.get(vector as usize) {
+            Some(entry) => entry.masked(),
+            None => {
+                error!("invalid MSI-X vector {}", vector);
+                return;
+            }
+        };
+        if masked || self.masked() {
             self.set_pba_bit(vector, true);
         } else if let Some(irq) = self.irq_vec.get(vector as usize) {
             irq.irqfd.write(1).unwrap();
@@ -578,3 +603,60 @@ impl MsixCap {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use vm_control::VmIrqResponseSocket;
+
+    // Answers MSI allocation and routing requests until the device side is dropped, returning the
+    // number of GSIs that were allocated.
+    fn serve_irq_requests(sock: VmIrqResponseSocket) -> thread::JoinHandle<u32> {
+        thread::spawn(move || {
+            let mut next_gsi = 0;
+            while let Ok(request) = sock.recv() {
+                let response = match request {
+                    VmIrqRequest::AllocateOneMsi { .. } => {
+                        next_gsi += 1;
+                        VmIrqResponse::AllocateOneMsi { gsi: next_gsi }
+                    }
+                    VmIrqRequest::AddMsiRoute { .. } => VmIrqResponse::Ok,
+                };
+                sock.send(&response).unwrap();
+            }
+            next_gsi
+        })
+    }
+
+    #[test]
+    fn reenable_reuses_vectors() {
+        let (device_sock, host_sock) = msg_socket::pair::<VmIrqRequest, VmIrqResponse>().unwrap();
+        let server = serve_irq_requests(host_sock);
+        let mut msix = MsixConfig::new(2, device_sock);
+
+        let enable = MSIX_ENABLE_BIT.to_le_bytes();
+        msix.write_msix_capability(2, &enable);
+        assert!(msix.enabled());
+        msix.write_msix_capability(2, &0u16.to_le_bytes());
+        assert!(!msix.enabled());
+        msix.write_msix_capability(2, &enable);
+        assert!(msix.enabled());
+
+        drop(msix);
+        assert_eq!(server.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn out_of_range_access() {
+        let (device_sock, _host_sock) = msg_socket::pair::<VmIrqRequest, VmIrqResponse>().unwrap();
+        let mut msix = MsixConfig::new(2, device_sock);
+
+        let mut data = [0xffu8; 4];
+        msix.read_msix_table(2 * MSIX_TABLE_ENTRIES_MODULO, &mut data);
+        assert_eq!(data, [0xff; 4]);
+        msix.write_msix_table(2 * MSIX_TABLE_ENTRIES_MODULO, &[1, 0, 0, 0]);
+        msix.read_pba_entries(MSIX_PBA_ENTRIES_MODULO, &mut data);
+        msix.trigger(2);
+    }
+}
@@ -13,12 +13,12 @@ const NUM_CONFIGURATION_REGISTERS: usize = 64;
 
 const STATUS_REG: usize = 1;
 const STATUS_REG_CAPABILITIES_USED_MASK: u32 = 0x0010_0000;
-const BAR0_REG: usize = 4;
+pub const BAR0_REG: usize = 4;
 const BAR_IO_ADDR_MASK: u32 = 0xffff_fffc;
 const BAR_IO_MIN_SIZE: u64 = 4;
-const BAR_MEM_ADDR_MASK: u32 = 0xffff_fff0;
+pub const BAR_MEM_ADDR_MASK: u32 = 0xffff_fff0;
 const BAR_MEM_MIN_SIZE: u64 = 16;
-const NUM_BAR_REGS: usize = 6;
+pub const NUM_BAR_REGS: usize = 6;
 const CAPABILITY_LIST_HEAD_OFFSET: usize = 0x34;
 const FIRST_CAPABILITY_OFFSET: usize = 0x40;
 const CAPABILITY_MAX_OFFSET: usize = 192;
@@ -103,6 +103,24 @@ impl PciSubclass for PciMultimediaSubclass {
     }
 }
 
+/// Subclasses of the NetworkController class.
+#[allow(dead_code)]
+#[derive(Copy, Clone)]
+pub enum PciNetworkSubclass {
+    EthernetController = 0x00,
+    TokenRingController = 0x01,
+    FddiController = 0x02,
+    AtmController = 0x03,
+    IsdnController = 0x04,
+    Other = 0x80,
+}
+
+impl PciSubclass for PciNetworkSubclass {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
 /// Subclasses of the BridgeDevice
 #[allow(dead_code)]
 #[derive(Copy, Clone)]
@@ -143,6 +161,21 @@ impl PciSubclass for PciSerialBusSubClass {
     }
 }
 
+/// Subclasses of the MemoryController class.
+#[allow(dead_code)]
+#[derive(Copy, Clone)]
+pub enum PciMemorySubclass {
+    RamController = 0x00,
+    FlashController = 0x01,
+    Other = 0x80,
+}
+
+impl PciSubclass for PciMemorySubclass {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
 /// A PCI class programming interface. Each combination of `PciClassCode` and
 /// `PciSubclass` can specify a set of register-level programming interfaces.
 /// This trait is implemented by each programming interface.
@@ -307,15 +340,21 @@ impl PciConfiguration {
         match header_type {
             PciHeaderType::Device => {
                 registers[3] = 0x0000_0000; // Header type 0 (device)
+                registers[11] = u32::from(subsystem_id) << 16 | u32::from(subsystem_vendor_id);
                 writable_bits[15] = 0x0000_00ff; // Interrupt line (r/w)
             }
             PciHeaderType::Bridge => {
                 registers[3] = 0x0001_0000; // Header type 1 (bridge)
-                writable_bits[9] = 0xfff0_fff0; // Memory base and limit
+                writable_bits[6] = 0x00ff_ffff; // Primary, secondary and subordinate bus number
+                writable_bits[7] = 0x0000_f0f0; // I/O base and limit
+                writable_bits[8] = 0xfff0_fff0; // Memory base and limit
+                writable_bits[9] = 0xfff0_fff0; // Prefetchable memory base and limit
+                writable_bits[10] = 0xffff_ffff; // Prefetchable base upper 32 bits
+                writable_bits[11] = 0xffff_ffff; // Prefetchable limit upper 32 bits
+                writable_bits[12] = 0xffff_ffff; // I/O base and limit upper 16 bits
                 writable_bits[15] = 0xffff_00ff; // Bridge control (r/w), interrupt line (r/w)
             }
         };
-        registers[11] = u32::from(subsystem_id) << 16 | u32::from(subsystem_vendor_id);
 
         PciConfiguration {
             registers,
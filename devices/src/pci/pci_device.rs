@@ -7,10 +7,11 @@ use std::fmt::{self, Display};
 use base::{Event, RawDescriptor};
 use hypervisor::Datamatch;
 use resources::{Error as SystemAllocatorFaliure, SystemAllocator};
+use vm_control::QueueStats;
 
 use crate::pci::pci_configuration;
 use crate::pci::{PciAddress, PciInterruptPin};
-use crate::{BusAccessInfo, BusDevice};
+use crate::{BusAccessInfo, BusDevice, Suspendable};
 
 #[derive(Debug)]
 pub enum Error {
@@ -46,7 +47,7 @@ impl Display for Error {
     }
 }
 
-pub trait PciDevice: Send {
+pub trait PciDevice: Send + Suspendable {
     /// Returns a label suitable for debug output.
     fn debug_label(&self) -> String;
     /// Assign a unique bus, device and function number to this device.
@@ -112,6 +113,12 @@ pub trait PciDevice: Send {
     fn write_bar(&mut self, addr: u64, data: &[u8]);
     /// Invoked when the device is sandboxed.
     fn on_device_sandboxed(&mut self) {}
+
+    /// Returns the counters of each of the device's virtio queues, in queue index order. Empty
+    /// for devices without virtio queues.
+    fn queue_stats(&self) -> Vec<QueueStats> {
+        Vec::new()
+    }
 }
 
 impl<T: PciDevice> BusDevice for T {
@@ -142,6 +149,10 @@ impl<T: PciDevice> BusDevice for T {
     fn on_sandboxed(&mut self) {
         self.on_device_sandboxed();
     }
+
+    fn queue_stats(&self) -> Vec<QueueStats> {
+        PciDevice::queue_stats(self)
+    }
 }
 
 impl<T: PciDevice + ?Sized> PciDevice for Box<T> {
@@ -192,4 +203,7 @@ impl<T: PciDevice + ?Sized> PciDevice for Box<T> {
     fn on_device_sandboxed(&mut self) {
         (**self).on_device_sandboxed()
     }
+    fn queue_stats(&self) -> Vec<QueueStats> {
+        (**self).queue_stats()
+    }
 }
@@ -7,14 +7,23 @@ use std::convert::TryInto;
 use std::fmt::{self, Display};
 use std::sync::Arc;
 
-use base::RawDescriptor;
+use base::{error, Event, RawDescriptor};
+use hypervisor::{Datamatch, IoEventAddress, Vm};
 use sync::Mutex;
+use vm_control::VirtioDeviceStats;
 
+use crate::bus;
 use crate::pci::pci_configuration::{
-    PciBridgeSubclass, PciClassCode, PciConfiguration, PciHeaderType,
+    PciBridgeSubclass, PciClassCode, PciConfiguration, PciHeaderType, BAR0_REG, BAR_MEM_ADDR_MASK,
+    NUM_BAR_REGS,
 };
 use crate::pci::pci_device::PciDevice;
-use crate::{BusAccessInfo, BusDevice};
+use crate::suspendable;
+use crate::{Bus, BusAccessInfo, BusDevice, Suspendable, SuspendableError};
+
+const BAR_IO_SPACE: u32 = 0x1;
+const BAR_MEM_TYPE_MASK: u32 = 0x6;
+const BAR_MEM_TYPE_64BIT: u32 = 0x4;
 
 // A PciDevice that holds the root hub's configuration.
 struct PciRootConfiguration {
@@ -41,8 +50,15 @@ impl PciDevice for PciRootConfiguration {
     fn write_bar(&mut self, _addr: u64, _data: &[u8]) {}
 }
 
+// The root bridge only has configuration registers.
+impl Suspendable for PciRootConfiguration {
+    fn sleep(&mut self) -> suspendable::Result<()> {
+        Ok(())
+    }
+}
+
 /// PCI Device Address, AKA Bus:Device.Function
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PciAddress {
     pub bus: u8,
     pub dev: u8,  /* u5 */
@@ -55,32 +71,49 @@ impl Display for PciAddress {
     }
 }
 
+/// Number of address bits used for the register offset by the configuration access mechanism
+/// #1 and CAM, which expose the first 256 bytes of each function's configuration space.
+pub const PCI_CONFIG_REGISTER_BITS: usize = 8;
+/// Number of address bits used for the register offset by ECAM (MMCONFIG), which exposes the
+/// whole 4KiB extended configuration space of each function.
+pub const PCIE_CONFIG_REGISTER_BITS: usize = 12;
+
 impl PciAddress {
-    const BUS_OFFSET: usize = 16;
     const BUS_MASK: u32 = 0x00ff;
-    const DEVICE_OFFSET: usize = 11;
+    const DEVICE_BITS_NUM: usize = 5;
     const DEVICE_MASK: u32 = 0x1f;
-    const FUNCTION_OFFSET: usize = 8;
+    const FUNCTION_BITS_NUM: usize = 3;
     const FUNCTION_MASK: u32 = 0x07;
     const REGISTER_OFFSET: usize = 2;
-    const REGISTER_MASK: u32 = 0x3f;
 
     /// Construct PciAddress and register tuple from CONFIG_ADDRESS value.
-    pub fn from_config_address(config_address: u32) -> (Self, usize) {
-        let bus = ((config_address >> Self::BUS_OFFSET) & Self::BUS_MASK) as u8;
-        let dev = ((config_address >> Self::DEVICE_OFFSET) & Self::DEVICE_MASK) as u8;
-        let func = ((config_address >> Self::FUNCTION_OFFSET) & Self::FUNCTION_MASK) as u8;
-        let register = ((config_address >> Self::REGISTER_OFFSET) & Self::REGISTER_MASK) as usize;
+    /// `register_bits_num` is the number of low address bits holding the byte offset of the
+    /// register, `PCI_CONFIG_REGISTER_BITS` or `PCIE_CONFIG_REGISTER_BITS`.
+    pub fn from_config_address(config_address: u32, register_bits_num: usize) -> (Self, usize) {
+        let function_offset = register_bits_num;
+        let device_offset = function_offset + Self::FUNCTION_BITS_NUM;
+        let bus_offset = device_offset + Self::DEVICE_BITS_NUM;
+        let register_mask = (1u32 << (register_bits_num - Self::REGISTER_OFFSET)) - 1;
+
+        let bus = ((config_address >> bus_offset) & Self::BUS_MASK) as u8;
+        let dev = ((config_address >> device_offset) & Self::DEVICE_MASK) as u8;
+        let func = ((config_address >> function_offset) & Self::FUNCTION_MASK) as u8;
+        let register = ((config_address >> Self::REGISTER_OFFSET) & register_mask) as usize;
 
         (PciAddress { bus, dev, func }, register)
     }
 
     /// Encode PciAddress into CONFIG_ADDRESS value.
-    pub fn to_config_address(&self, register: usize) -> u32 {
-        ((Self::BUS_MASK & self.bus as u32) << Self::BUS_OFFSET)
-            | ((Self::DEVICE_MASK & self.dev as u32) << Self::DEVICE_OFFSET)
-            | ((Self::FUNCTION_MASK & self.func as u32) << Self::FUNCTION_OFFSET)
-            | ((Self::REGISTER_MASK & register as u32) << Self::REGISTER_OFFSET)
+    pub fn to_config_address(&self, register: usize, register_bits_num: usize) -> u32 {
+        let function_offset = register_bits_num;
+        let device_offset = function_offset + Self::FUNCTION_BITS_NUM;
+        let bus_offset = device_offset + Self::DEVICE_BITS_NUM;
+        let register_mask = (1u32 << (register_bits_num - Self::REGISTER_OFFSET)) - 1;
+
+        ((Self::BUS_MASK & self.bus as u32) << bus_offset)
+            | ((Self::DEVICE_MASK & self.dev as u32) << device_offset)
+            | ((Self::FUNCTION_MASK & self.func as u32) << function_offset)
+            | ((register_mask & register as u32) << Self::REGISTER_OFFSET)
     }
 
     /// Returns true if the address points to PCI root host-bridge.
@@ -96,20 +129,63 @@ impl PciAddress {
     }
 }
 
+// A memory BAR of a device and the range it currently occupies on the MMIO bus.
+struct PciBarMapping {
+    bar_num: usize,
+    is_64bit: bool,
+    base: u64,
+    len: u64,
+}
+
+// Reads the memory address programmed in BAR `bar_num` of `device`. Returns the address and
+// whether the BAR is 64-bit, or None for I/O BARs.
+fn read_mem_bar(device: &dyn BusDevice, bar_num: usize) -> Option<(u64, bool)> {
+    let reg_idx = BAR0_REG + bar_num;
+    let low = device.config_register_read(reg_idx);
+    if low & BAR_IO_SPACE != 0 {
+        return None;
+    }
+    if low & BAR_MEM_TYPE_MASK == BAR_MEM_TYPE_64BIT && bar_num + 1 < NUM_BAR_REGS {
+        let high = device.config_register_read(reg_idx + 1);
+        Some((
+            u64::from(high) << 32 | u64::from(low & BAR_MEM_ADDR_MASK),
+            true,
+        ))
+    } else {
+        Some((u64::from(low & BAR_MEM_ADDR_MASK), false))
+    }
+}
+
+// An ioevent of a device and the address it is currently registered at.
+struct PciIoEvent {
+    event: Event,
+    addr: u64,
+    datamatch: Datamatch,
+}
+
 /// Emulates the PCI Root bridge.
 pub struct PciRoot {
     /// Bus configuration for the root device.
     root_configuration: PciRootConfiguration,
     /// Devices attached to this bridge.
     devices: BTreeMap<PciAddress, Arc<Mutex<dyn BusDevice>>>,
+    /// Bus the memory BARs of the devices are registered on.
+    mmio_bus: Bus,
+    /// Memory BARs of each device, moved on `mmio_bus` when the guest reprograms them.
+    bar_mappings: BTreeMap<PciAddress, Vec<PciBarMapping>>,
+    /// VM the ioevents of the devices are registered with.
+    vm: Option<Box<dyn Vm>>,
+    /// Ioevents of each device, moved along with the BAR they lie in.
+    ioevents: BTreeMap<PciAddress, Vec<PciIoEvent>>,
 }
 
 const PCI_VENDOR_ID_INTEL: u16 = 0x8086;
 const PCI_DEVICE_ID_INTEL_82441: u16 = 0x1237;
 
 impl PciRoot {
-    /// Create an empty PCI root bus.
-    pub fn new() -> Self {
+    /// Create an empty PCI root bus whose devices' memory BARs live on `mmio_bus`. The ioevents
+    /// of the devices are moved on `vm` when their BAR moves.
+    pub fn new(mmio_bus: Bus, vm: Option<Box<dyn Vm>>) -> Self {
         PciRoot {
             root_configuration: PciRootConfiguration {
                 config: PciConfiguration::new(
@@ -124,6 +200,10 @@ impl PciRoot {
                 ),
             },
             devices: BTreeMap::new(),
+            mmio_bus,
+            bar_mappings: BTreeMap::new(),
+            vm,
+            ioevents: BTreeMap::new(),
         }
     }
 
@@ -135,6 +215,173 @@ impl PciRoot {
         }
     }
 
+    /// Registers the memory `ranges` of the device at `address` on the MMIO bus. Ranges that match
+    /// the address programmed in one of the device's BARs follow that BAR when the guest moves it.
+    pub fn add_device_mmio_ranges(
+        &mut self,
+        address: PciAddress,
+        ranges: &[(u64, u64)],
+    ) -> bus::Result<()> {
+        let device = match self.devices.get(&address) {
+            Some(d) => d.clone(),
+            None => return Ok(()),
+        };
+
+        let mut mappings = Vec::new();
+        {
+            let locked_device = device.lock();
+            let mut bar_num = 0;
+            while bar_num < NUM_BAR_REGS {
+                let (base, is_64bit) = match read_mem_bar(&*locked_device, bar_num) {
+                    Some(bar) => bar,
+                    None => {
+                        bar_num += 1;
+                        continue;
+                    }
+                };
+                if let Some(&(_, len)) = ranges.iter().find(|r| r.0 == base && base != 0) {
+                    mappings.push(PciBarMapping {
+                        bar_num,
+                        is_64bit,
+                        base,
+                        len,
+                    });
+                }
+                bar_num += if is_64bit { 2 } else { 1 };
+            }
+        }
+
+        for &(base, len) in ranges {
+            self.mmio_bus.insert(device.clone(), base, len)?;
+        }
+        self.bar_mappings.insert(address, mappings);
+        Ok(())
+    }
+
+    /// Records the ioevents the device at `address` registered with the VM, so they follow the
+    /// BAR they lie in when the guest moves it.
+    pub fn add_device_ioevents(
+        &mut self,
+        address: PciAddress,
+        ioevents: Vec<(Event, u64, Datamatch)>,
+    ) {
+        let ioevents = ioevents
+            .into_iter()
+            .map(|(event, addr, datamatch)| PciIoEvent {
+                event,
+                addr,
+                datamatch,
+            })
+            .collect();
+        self.ioevents.insert(address, ioevents);
+    }
+
+    // Moves the MMIO bus registration of a BAR after the guest wrote a new address to it. The
+    // upper half of a 64-bit BAR is written last, so the move happens on that write.
+    fn update_bar_mapping(&mut self, address: PciAddress, register: usize) {
+        let device = match self.devices.get(&address) {
+            Some(d) => d,
+            None => return,
+        };
+        let mappings = match self.bar_mappings.get_mut(&address) {
+            Some(m) => m,
+            None => return,
+        };
+        let mapping = match mappings.iter_mut().find(|m| {
+            let reg_idx = BAR0_REG + m.bar_num;
+            if m.is_64bit {
+                register == reg_idx + 1
+            } else {
+                register == reg_idx
+            }
+        }) {
+            Some(m) => m,
+            None => return,
+        };
+
+        let new_base = match read_mem_bar(&*device.lock(), mapping.bar_num) {
+            Some((base, _)) => base,
+            None => return,
+        };
+        // Writing all ones is the guest sizing the BAR, not moving it.
+        let size_mask = !(mapping.len - 1);
+        let sizing_value = if mapping.is_64bit {
+            size_mask
+        } else {
+            size_mask & u64::from(u32::max_value())
+        };
+        if new_base == mapping.base || new_base == sizing_value {
+            return;
+        }
+
+        if let Err(e) = self.mmio_bus.remove(mapping.base, mapping.len) {
+            error!(
+                "failed to remove BAR at {:#x} for {}: {}",
+                mapping.base, address, e
+            );
+            return;
+        }
+        match self.mmio_bus.insert(device.clone(), new_base, mapping.len) {
+            Ok(()) => {
+                let (old_base, len) = (mapping.base, mapping.len);
+                mapping.base = new_base;
+                self.move_ioevents(address, old_base, new_base, len);
+            }
+            Err(e) => {
+                error!(
+                    "failed to move BAR of {} to {:#x}: {}",
+                    address, new_base, e
+                );
+                if let Err(e) = self
+                    .mmio_bus
+                    .insert(device.clone(), mapping.base, mapping.len)
+                {
+                    error!("failed to restore BAR at {:#x}: {}", mapping.base, e);
+                }
+            }
+        }
+    }
+
+    // Re-registers the ioevents of the device at `address` that lie in the `len` bytes moved from
+    // `old_base` to `new_base`. An ioevent that fails to register leaves its writes to the device.
+    fn move_ioevents(&mut self, address: PciAddress, old_base: u64, new_base: u64, len: u64) {
+        let vm = match self.vm.as_mut() {
+            Some(vm) => vm,
+            None => return,
+        };
+        let ioevents = match self.ioevents.get_mut(&address) {
+            Some(ioevents) => ioevents,
+            None => return,
+        };
+        for ioevent in ioevents
+            .iter_mut()
+            .filter(|e| e.addr >= old_base && e.addr - old_base < len)
+        {
+            let new_addr = ioevent.addr - old_base + new_base;
+            if let Err(e) = vm.unregister_ioevent(
+                &ioevent.event,
+                IoEventAddress::Mmio(ioevent.addr),
+                ioevent.datamatch,
+            ) {
+                error!(
+                    "failed to unregister ioevent at {:#x} for {}: {}",
+                    ioevent.addr, address, e
+                );
+            }
+            if let Err(e) = vm.register_ioevent(
+                &ioevent.event,
+                IoEventAddress::Mmio(new_addr),
+                ioevent.datamatch,
+            ) {
+                error!(
+                    "failed to register ioevent at {:#x} for {}: {}",
+                    new_addr, address, e
+                );
+            }
+            ioevent.addr = new_addr;
+        }
+    }
+
     pub fn config_space_read(&self, address: PciAddress, register: usize) -> u32 {
         if address.is_root() {
             self.root_configuration.config_register_read(register)
@@ -160,20 +407,111 @@ impl PciRoot {
                 .config_register_write(register, offset, data);
         } else if let Some(d) = self.devices.get(&address) {
             d.lock().config_register_write(register, offset, data);
+            if register >= BAR0_REG && register < BAR0_REG + NUM_BAR_REGS {
+                self.update_bar_mapping(address, register);
+            }
+        }
+    }
+
+    /// Puts every device it can to sleep and returns the first failure. The devices that went to
+    /// sleep stay asleep until `wake_devices`, so a guest suspend quiesces as much as it can.
+    pub fn sleep_devices(&self) -> suspendable::Result<()> {
+        let mut result = Ok(());
+        for (&address, device) in &self.devices {
+            let mut device = device.lock();
+            if let Err(e) = device.sleep() {
+                error!(
+                    "failed to put to sleep PCI device {} ({}): {}",
+                    address,
+                    device.debug_label(),
+                    e
+                );
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
         }
+        result
+    }
+
+    /// Wakes every device, stopping at the first one that fails.
+    pub fn wake_devices(&self) -> suspendable::Result<()> {
+        self.for_each_device("wake", |_, device| device.wake())
+    }
+
+    /// Returns the state of every device by address. The devices must be asleep.
+    pub fn snapshot_devices(&self) -> suspendable::Result<BTreeMap<PciAddress, Vec<u8>>> {
+        let mut snapshots = BTreeMap::new();
+        self.for_each_device("snapshot", |address, device| {
+            snapshots.insert(address, device.snapshot()?);
+            Ok(())
+        })?;
+        Ok(snapshots)
+    }
+
+    /// Restores every device from the snapshots taken by `snapshot_devices`, which must cover
+    /// all of them. The devices must be asleep.
+    pub fn restore_devices(
+        &self,
+        snapshots: &BTreeMap<PciAddress, Vec<u8>>,
+    ) -> suspendable::Result<()> {
+        self.for_each_device("restore", |address, device| match snapshots.get(&address) {
+            Some(data) => device.restore(data),
+            None => Err(SuspendableError::InvalidSnapshot),
+        })
+    }
+
+    /// Returns the queue counters of every device with virtio queues, in address order.
+    pub fn virtio_stats(&self) -> Vec<VirtioDeviceStats> {
+        let mut stats = Vec::new();
+        for (address, device) in &self.devices {
+            let device = device.lock();
+            let queues = device.queue_stats();
+            if !queues.is_empty() {
+                stats.push(VirtioDeviceStats {
+                    bus: address.bus,
+                    dev: address.dev,
+                    func: address.func,
+                    label: device.debug_label().into_bytes(),
+                    queues,
+                });
+            }
+        }
+        stats
+    }
+
+    // Calls `f` on each device in address order and logs the first failure, which ends the walk.
+    fn for_each_device<F>(&self, action: &str, mut f: F) -> suspendable::Result<()>
+    where
+        F: FnMut(PciAddress, &mut dyn BusDevice) -> suspendable::Result<()>,
+    {
+        for (&address, device) in &self.devices {
+            let mut device = device.lock();
+            if let Err(e) = f(address, &mut *device) {
+                error!(
+                    "failed to {} PCI device {} ({}): {}",
+                    action,
+                    address,
+                    device.debug_label(),
+                    e
+                );
+                return Err(e);
+            }
+        }
+        Ok(())
     }
 }
 
 /// Emulates PCI configuration access mechanism #1 (I/O ports 0xcf8 and 0xcfc).
 pub struct PciConfigIo {
     /// PCI root bridge.
-    pci_root: PciRoot,
+    pci_root: Arc<Mutex<PciRoot>>,
     /// Current address to read/write from (0xcf8 register, litte endian).
     config_address: u32,
 }
 
 impl PciConfigIo {
-    pub fn new(pci_root: PciRoot) -> Self {
+    pub fn new(pci_root: Arc<Mutex<PciRoot>>) -> Self {
         PciConfigIo {
             pci_root,
             config_address: 0,
@@ -186,8 +524,9 @@ impl PciConfigIo {
             return 0xffff_ffff;
         }
 
-        let (address, register) = PciAddress::from_config_address(self.config_address);
-        self.pci_root.config_space_read(address, register)
+        let (address, register) =
+            PciAddress::from_config_address(self.config_address, PCI_CONFIG_REGISTER_BITS);
+        self.pci_root.lock().config_space_read(address, register)
     }
 
     fn config_space_write(&mut self, offset: u64, data: &[u8]) {
@@ -196,8 +535,10 @@ impl PciConfigIo {
             return;
         }
 
-        let (address, register) = PciAddress::from_config_address(self.config_address);
+        let (address, register) =
+            PciAddress::from_config_address(self.config_address, PCI_CONFIG_REGISTER_BITS);
         self.pci_root
+            .lock()
             .config_space_write(address, register, offset, data)
     }
 
@@ -258,25 +599,42 @@ impl BusDevice for PciConfigIo {
     }
 }
 
-/// Emulates PCI memory-mapped configuration access mechanism.
+// Configuration accesses only come from the guest.
+impl Suspendable for PciConfigIo {
+    fn sleep(&mut self) -> suspendable::Result<()> {
+        Ok(())
+    }
+}
+
+/// Emulates PCI memory-mapped configuration access mechanism, either CAM or ECAM depending on
+/// the number of register bits.
 pub struct PciConfigMmio {
     /// PCI root bridge.
-    pci_root: PciRoot,
+    pci_root: Arc<Mutex<PciRoot>>,
+    /// Register bit number in config address, `PCI_CONFIG_REGISTER_BITS` for CAM and
+    /// `PCIE_CONFIG_REGISTER_BITS` for ECAM.
+    register_bits_num: usize,
 }
 
 impl PciConfigMmio {
-    pub fn new(pci_root: PciRoot) -> Self {
-        PciConfigMmio { pci_root }
+    pub fn new(pci_root: Arc<Mutex<PciRoot>>, register_bits_num: usize) -> Self {
+        PciConfigMmio {
+            pci_root,
+            register_bits_num,
+        }
     }
 
     fn config_space_read(&self, config_address: u32) -> u32 {
-        let (address, register) = PciAddress::from_config_address(config_address);
-        self.pci_root.config_space_read(address, register)
+        let (address, register) =
+            PciAddress::from_config_address(config_address, self.register_bits_num);
+        self.pci_root.lock().config_space_read(address, register)
     }
 
     fn config_space_write(&mut self, config_address: u32, offset: u64, data: &[u8]) {
-        let (address, register) = PciAddress::from_config_address(config_address);
+        let (address, register) =
+            PciAddress::from_config_address(config_address, self.register_bits_num);
         self.pci_root
+            .lock()
             .config_space_write(address, register, offset, data)
     }
 }
@@ -310,3 +668,299 @@ impl BusDevice for PciConfigMmio {
         self.config_space_write(info.offset as u32, info.offset % 4, data)
     }
 }
+
+// Configuration accesses only come from the guest.
+impl Suspendable for PciConfigMmio {
+    fn sleep(&mut self) -> suspendable::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::pci_configuration::{
+        PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciMultimediaSubclass,
+    };
+    use vm_control::QueueStats;
+
+    struct TestDevice {
+        config_regs: PciConfiguration,
+        can_sleep: bool,
+        asleep: bool,
+        state: Vec<u8>,
+        queues: Vec<QueueStats>,
+    }
+
+    impl TestDevice {
+        fn new(bar_addr: u64, bar_size: u64, region_type: PciBarRegionType) -> Self {
+            let mut config_regs = PciConfiguration::new(
+                0x1234,
+                0x5678,
+                PciClassCode::MultimediaController,
+                &PciMultimediaSubclass::AudioController,
+                None,
+                PciHeaderType::Device,
+                0,
+                0,
+            );
+            config_regs
+                .add_pci_bar(
+                    PciBarConfiguration::new(
+                        0,
+                        bar_size,
+                        region_type,
+                        PciBarPrefetchable::NotPrefetchable,
+                    )
+                    .set_address(bar_addr),
+                )
+                .unwrap();
+            TestDevice {
+                config_regs,
+                can_sleep: true,
+                asleep: false,
+                state: Vec::new(),
+                queues: Vec::new(),
+            }
+        }
+    }
+
+    impl PciDevice for TestDevice {
+        fn debug_label(&self) -> String {
+            "test device".to_owned()
+        }
+        fn keep_rds(&self) -> Vec<RawDescriptor> {
+            Vec::new()
+        }
+        fn read_config_register(&self, reg_idx: usize) -> u32 {
+            self.config_regs.read_reg(reg_idx)
+        }
+        fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+            self.config_regs.write_reg(reg_idx, offset, data)
+        }
+        fn read_bar(&mut self, _addr: u64, _data: &mut [u8]) {}
+        fn write_bar(&mut self, _addr: u64, _data: &[u8]) {}
+        fn queue_stats(&self) -> Vec<QueueStats> {
+            self.queues.clone()
+        }
+    }
+
+    impl Suspendable for TestDevice {
+        fn sleep(&mut self) -> suspendable::Result<()> {
+            if !self.can_sleep {
+                return Err(SuspendableError::NotSupported);
+            }
+            self.asleep = true;
+            Ok(())
+        }
+
+        fn wake(&mut self) -> suspendable::Result<()> {
+            self.asleep = false;
+            Ok(())
+        }
+
+        fn snapshot(&mut self) -> suspendable::Result<Vec<u8>> {
+            if !self.asleep {
+                return Err(SuspendableError::NotSupported);
+            }
+            Ok(self.state.clone())
+        }
+
+        fn restore(&mut self, data: &[u8]) -> suspendable::Result<()> {
+            if !self.asleep {
+                return Err(SuspendableError::NotSupported);
+            }
+            self.state = data.to_vec();
+            Ok(())
+        }
+    }
+
+    fn setup(device: TestDevice, bar_addr: u64, bar_size: u64) -> (PciRoot, Bus, PciAddress) {
+        let bus = Bus::new();
+        let mut root = PciRoot::new(bus.clone(), None);
+        let address = PciAddress {
+            bus: 0,
+            dev: 1,
+            func: 0,
+        };
+        root.add_device(address, Arc::new(Mutex::new(device)));
+        root.add_device_mmio_ranges(address, &[(bar_addr, bar_size)])
+            .unwrap();
+        (root, bus, address)
+    }
+
+    #[test]
+    fn config_address_cam_ecam() {
+        let address = PciAddress {
+            bus: 3,
+            dev: 5,
+            func: 2,
+        };
+        let cam = address.to_config_address(0x10, PCI_CONFIG_REGISTER_BITS);
+        assert_eq!(cam, 3 << 16 | 5 << 11 | 2 << 8 | 0x10 << 2);
+        assert_eq!(
+            PciAddress::from_config_address(cam, PCI_CONFIG_REGISTER_BITS),
+            (address, 0x10)
+        );
+
+        let ecam = address.to_config_address(0x100, PCIE_CONFIG_REGISTER_BITS);
+        assert_eq!(ecam, 3 << 20 | 5 << 15 | 2 << 12 | 0x100 << 2);
+        assert_eq!(
+            PciAddress::from_config_address(ecam, PCIE_CONFIG_REGISTER_BITS),
+            (address, 0x100)
+        );
+    }
+
+    #[test]
+    fn move_32bit_bar() {
+        let device = TestDevice::new(0x1000_0000, 0x1000, PciBarRegionType::Memory32BitRegion);
+        let (mut root, bus, address) = setup(device, 0x1000_0000, 0x1000);
+        assert!(bus.read(0x1000_0000, &mut [0u8; 4]));
+
+        // Sizing the BAR must not move it.
+        root.config_space_write(address, BAR0_REG, 0, &0xffff_ffffu32.to_le_bytes());
+        assert!(bus.read(0x1000_0000, &mut [0u8; 4]));
+        root.config_space_write(address, BAR0_REG, 0, &0x1000_0000u32.to_le_bytes());
+        assert!(bus.read(0x1000_0000, &mut [0u8; 4]));
+
+        root.config_space_write(address, BAR0_REG, 0, &0x2000_0000u32.to_le_bytes());
+        assert!(!bus.read(0x1000_0000, &mut [0u8; 4]));
+        assert!(bus.read(0x2000_0000, &mut [0u8; 4]));
+    }
+
+    #[test]
+    fn move_64bit_bar() {
+        let device = TestDevice::new(
+            0x1_0000_0000,
+            0x10_0000,
+            PciBarRegionType::Memory64BitRegion,
+        );
+        let (mut root, bus, address) = setup(device, 0x1_0000_0000, 0x10_0000);
+        assert!(bus.read(0x1_0000_0000, &mut [0u8; 4]));
+
+        // The move happens once both halves of the address have been written.
+        root.config_space_write(address, BAR0_REG, 0, &0x0020_0000u32.to_le_bytes());
+        assert!(bus.read(0x1_0000_0000, &mut [0u8; 4]));
+        root.config_space_write(address, BAR0_REG + 1, 0, &0x2u32.to_le_bytes());
+        assert!(!bus.read(0x1_0000_0000, &mut [0u8; 4]));
+        assert!(bus.read(0x2_0020_0000, &mut [0u8; 4]));
+    }
+
+    #[test]
+    fn snapshot_restore_devices() {
+        let bus = Bus::new();
+        let mut root = PciRoot::new(bus, None);
+        let first = PciAddress {
+            bus: 0,
+            dev: 1,
+            func: 0,
+        };
+        let second = PciAddress {
+            bus: 0,
+            dev: 2,
+            func: 0,
+        };
+        let mut devices = Vec::new();
+        for (address, state) in &[(first, 1u8), (second, 2u8)] {
+            let mut device = TestDevice::new(0, 0x1000, PciBarRegionType::Memory32BitRegion);
+            device.state = vec![*state];
+            let device = Arc::new(Mutex::new(device));
+            root.add_device(*address, device.clone());
+            devices.push(device);
+        }
+
+        // Devices refuse to be snapshot while they are running.
+        assert!(matches!(
+            root.snapshot_devices(),
+            Err(SuspendableError::NotSupported)
+        ));
+
+        root.sleep_devices().unwrap();
+        let mut snapshots = root.snapshot_devices().unwrap();
+        assert_eq!(snapshots[&first], vec![1]);
+        assert_eq!(snapshots[&second], vec![2]);
+
+        snapshots.insert(first, vec![2]);
+        snapshots.insert(second, vec![1]);
+        root.restore_devices(&snapshots).unwrap();
+        assert_eq!(devices[0].lock().state, vec![2]);
+        assert_eq!(devices[1].lock().state, vec![1]);
+
+        snapshots.remove(&second);
+        assert!(matches!(
+            root.restore_devices(&snapshots),
+            Err(SuspendableError::InvalidSnapshot)
+        ));
+
+        root.wake_devices().unwrap();
+        assert!(matches!(
+            root.snapshot_devices(),
+            Err(SuspendableError::NotSupported)
+        ));
+    }
+
+    #[test]
+    fn sleep_devices_past_failure() {
+        let bus = Bus::new();
+        let mut root = PciRoot::new(bus, None);
+        let mut devices = Vec::new();
+        for (dev, can_sleep) in &[(1, false), (2, true)] {
+            let address = PciAddress {
+                bus: 0,
+                dev: *dev,
+                func: 0,
+            };
+            let mut device = TestDevice::new(0, 0x1000, PciBarRegionType::Memory32BitRegion);
+            device.can_sleep = *can_sleep;
+            let device = Arc::new(Mutex::new(device));
+            root.add_device(address, device.clone());
+            devices.push(device);
+        }
+
+        // The device that can't sleep doesn't keep the next one awake.
+        assert!(matches!(
+            root.sleep_devices(),
+            Err(SuspendableError::NotSupported)
+        ));
+        assert!(!devices[0].lock().asleep);
+        assert!(devices[1].lock().asleep);
+
+        root.wake_devices().unwrap();
+        assert!(!devices[1].lock().asleep);
+    }
+
+    #[test]
+    fn virtio_stats() {
+        let bus = Bus::new();
+        let mut root = PciRoot::new(bus, None);
+        let queue = QueueStats {
+            descriptors: 3,
+            bytes: 4096,
+            notifications_suppressed: 1,
+            interrupts: 2,
+        };
+        let mut device = TestDevice::new(0, 0x1000, PciBarRegionType::Memory32BitRegion);
+        device.queues = vec![queue, QueueStats::default(), queue];
+        let address = PciAddress {
+            bus: 0,
+            dev: 2,
+            func: 0,
+        };
+        root.add_device(address, Arc::new(Mutex::new(device)));
+        // Devices without virtio queues are left out.
+        let device = TestDevice::new(0, 0x1000, PciBarRegionType::Memory32BitRegion);
+        let address = PciAddress {
+            bus: 0,
+            dev: 1,
+            func: 0,
+        };
+        root.add_device(address, Arc::new(Mutex::new(device)));
+
+        let stats = root.virtio_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].dev, 2);
+        assert_eq!(stats[0].label, b"test device");
+        assert_eq!(stats[0].queues.len(), 3);
+        assert_eq!(stats[0].total(), queue + queue);
+    }
+}
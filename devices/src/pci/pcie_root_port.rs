@@ -0,0 +1,558 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::sync::Arc;
+use std::thread;
+
+use base::{error, AsRawDescriptor, Event, PollToken, RawDescriptor, WaitContext};
+use data_model::DataInit;
+use msg_socket::{MsgReceiver, MsgSender};
+use sync::Mutex;
+use vm_control::{PcieSlotControlCommand, PcieSlotControlResponseSocket, PcieSlotControlResult};
+
+use crate::pci::pci_configuration::{
+    PciBridgeSubclass, PciCapability, PciCapabilityID, PciClassCode, PciConfiguration,
+    PciHeaderType,
+};
+use crate::pci::pci_device::{self, PciDevice, Result};
+use crate::pci::PciInterruptPin;
+use crate::Suspendable;
+
+const PCI_VENDOR_ID_INTEL: u16 = 0x8086;
+const PCIE_RP_DEVICE_ID: u16 = 0x3420;
+
+const PCIE_CAP_LEN: usize = 0x3c;
+// Capability version 2, device/port type 4 (root port), slot implemented.
+const PCIE_CAP_VERSION: u16 = 0x2;
+const PCIE_CAP_TYPE_ROOT_PORT: u16 = 0x4 << 4;
+const PCIE_CAP_SLOT_IMPLEMENTED: u16 = 1 << 8;
+
+// Link capability: 2.5GT/s, x1, data link layer link active reporting capable.
+const PCIE_LINK_CAP_SPEED_2_5GT: u32 = 0x1;
+const PCIE_LINK_CAP_WIDTH_X1: u32 = 0x1 << 4;
+const PCIE_LINK_CAP_DLLLA_REPORTING: u32 = 1 << 20;
+const PCIE_LINK_STA_SPEED_2_5GT: u16 = 0x1;
+const PCIE_LINK_STA_WIDTH_X1: u16 = 0x1 << 4;
+const PCIE_LINK_STA_DLLLA: u16 = 1 << 13;
+
+// Slot capability: hot-plug surprise, hot-plug capable, no command completed support.
+const PCIE_SLOT_CAP_HOTPLUG_SURPRISE: u32 = 1 << 5;
+const PCIE_SLOT_CAP_HOTPLUG_CAPABLE: u32 = 1 << 6;
+const PCIE_SLOT_CAP_NO_CMD_COMPLETED: u32 = 1 << 18;
+const PCIE_SLOT_CAP_SLOT_NUMBER_SHIFT: u32 = 19;
+
+const PCIE_SLOT_CTL_PDC_ENABLE: u16 = 1 << 3;
+const PCIE_SLOT_CTL_HP_INT_ENABLE: u16 = 1 << 5;
+const PCIE_SLOT_CTL_DLLSC_ENABLE: u16 = 1 << 12;
+
+const PCIE_SLOT_STA_PDC: u16 = 1 << 3;
+const PCIE_SLOT_STA_PDS: u16 = 1 << 6;
+const PCIE_SLOT_STA_DLLSC: u16 = 1 << 8;
+
+/// PCI Express Capability Structure, PCIe base spec 7.5.3.
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct PcieCap {
+    // To make add_capability() happy
+    _cap_vndr: u8,
+    _cap_next: u8,
+    pcie_cap: u16,
+    dev_cap: u32,
+    dev_ctl: u16,
+    dev_sts: u16,
+    link_cap: u32,
+    link_ctl: u16,
+    link_sts: u16,
+    slot_cap: u32,
+    slot_ctl: u16,
+    slot_sts: u16,
+    root_ctl: u16,
+    root_cap: u16,
+    root_sts: u32,
+    dev_cap2: u32,
+    dev_ctl2: u16,
+    dev_sts2: u16,
+    link_cap2: u32,
+    link_ctl2: u16,
+    link_sts2: u16,
+    slot_cap2: u32,
+    slot_ctl2: u16,
+    slot_sts2: u16,
+}
+
+// It is safe to implement DataInit; all members are simple numbers and any value is valid.
+unsafe impl DataInit for PcieCap {}
+
+impl PciCapability for PcieCap {
+    fn bytes(&self) -> &[u8] {
+        self.as_slice()
+    }
+
+    fn id(&self) -> PciCapabilityID {
+        PciCapabilityID::PCIExpress
+    }
+}
+
+impl PcieCap {
+    fn new(slot_number: u8) -> Self {
+        PcieCap {
+            pcie_cap: PCIE_CAP_VERSION | PCIE_CAP_TYPE_ROOT_PORT | PCIE_CAP_SLOT_IMPLEMENTED,
+            link_cap: PCIE_LINK_CAP_SPEED_2_5GT
+                | PCIE_LINK_CAP_WIDTH_X1
+                | PCIE_LINK_CAP_DLLLA_REPORTING,
+            link_sts: PCIE_LINK_STA_SPEED_2_5GT | PCIE_LINK_STA_WIDTH_X1,
+            slot_cap: PCIE_SLOT_CAP_HOTPLUG_SURPRISE
+                | PCIE_SLOT_CAP_HOTPLUG_CAPABLE
+                | PCIE_SLOT_CAP_NO_CMD_COMPLETED
+                | u32::from(slot_number) << PCIE_SLOT_CAP_SLOT_NUMBER_SHIFT,
+            ..Default::default()
+        }
+    }
+
+    // Returns true if a slot event the guest enabled hot-plug interrupts for is still unhandled.
+    fn hotplug_interrupt_pending(&self) -> bool {
+        let slot_ctl = self.slot_ctl;
+        if slot_ctl & PCIE_SLOT_CTL_HP_INT_ENABLE == 0 {
+            return false;
+        }
+        let mut enabled_events = 0;
+        if slot_ctl & PCIE_SLOT_CTL_PDC_ENABLE != 0 {
+            enabled_events |= PCIE_SLOT_STA_PDC;
+        }
+        if slot_ctl & PCIE_SLOT_CTL_DLLSC_ENABLE != 0 {
+            enabled_events |= PCIE_SLOT_STA_DLLSC;
+        }
+        self.slot_sts & enabled_events != 0
+    }
+}
+
+// Returns the (writable, write-1-to-clear) masks of the byte at `offset` in the capability.
+fn pcie_cap_byte_masks(offset: usize) -> (u8, u8) {
+    match offset {
+        // Device control, link control, root control, device control 2, link control 2.
+        0x08 | 0x09 | 0x10 | 0x11 | 0x1c | 0x1d | 0x28 | 0x29 | 0x30 | 0x31 => (0xff, 0),
+        // Slot control.
+        0x18 => (0xff, 0),
+        0x19 => (0x1f, 0),
+        // Slot status: attention button pressed, power fault, MRL sensor changed, presence
+        // detect changed, command completed and data link layer state changed.
+        0x1a => (0, 0x1f),
+        0x1b => (0, 0x01),
+        // Root status: PME status.
+        0x22 => (0, 0x01),
+        _ => (0, 0),
+    }
+}
+
+// Carries out the slot commands from the main process, and re-asserts the level-triggered
+// hot-plug interrupt on resample while slot events are pending.
+struct Worker {
+    pcie_cap: Arc<Mutex<PcieCap>>,
+    irq_evt: Event,
+    irq_resample_evt: Event,
+    control_socket: PcieSlotControlResponseSocket,
+    kill_evt: Event,
+}
+
+impl Worker {
+    fn run(&mut self) -> base::Result<()> {
+        #[derive(PollToken)]
+        enum Token {
+            InterruptResample,
+            Control,
+            Kill,
+        }
+
+        let wait_ctx: WaitContext<Token> = WaitContext::build_with(&[
+            (&self.irq_resample_evt, Token::InterruptResample),
+            (&self.control_socket, Token::Control),
+            (&self.kill_evt, Token::Kill),
+        ])?;
+
+        loop {
+            let events = wait_ctx.wait()?;
+            if events.iter().any(|e| e.is_hungup) {
+                // The main process is gone.
+                return Ok(());
+            }
+            for event in events.iter().filter(|e| e.is_readable) {
+                match event.token {
+                    // The irqchip de-asserts the interrupt on EOI and signals the resample event,
+                    // so it has to be asserted again if the guest hasn't cleared every enabled
+                    // slot event by then.
+                    Token::InterruptResample => {
+                        self.irq_resample_evt.read()?;
+                        if self.pcie_cap.lock().hotplug_interrupt_pending() {
+                            self.irq_evt.write(1)?;
+                        }
+                    }
+                    Token::Control => {
+                        let result = match self.control_socket.recv() {
+                            Ok(PcieSlotControlCommand::SetPresence { present }) => {
+                                self.set_slot_presence(present)
+                            }
+                            Err(e) => {
+                                error!("pcie root port: failed to receive command: {}", e);
+                                continue;
+                            }
+                        };
+                        let response = match result {
+                            Ok(()) => PcieSlotControlResult::Ok,
+                            Err(e) => PcieSlotControlResult::Err(e),
+                        };
+                        if let Err(e) = self.control_socket.send(&response) {
+                            error!("pcie root port: failed to send response: {}", e);
+                        }
+                    }
+                    Token::Kill => return Ok(()),
+                }
+            }
+        }
+    }
+
+    // Updates the presence detect state of the slot, as if a device was inserted into or removed
+    // from it, and notifies the guest if it enabled hot-plug interrupts.
+    fn set_slot_presence(&self, present: bool) -> base::Result<()> {
+        let pending = {
+            let mut cap = self.pcie_cap.lock();
+            if present {
+                cap.slot_sts |= PCIE_SLOT_STA_PDS;
+                cap.link_sts |= PCIE_LINK_STA_DLLLA;
+            } else {
+                cap.slot_sts &= !PCIE_SLOT_STA_PDS;
+                cap.link_sts &= !PCIE_LINK_STA_DLLLA;
+            }
+            cap.slot_sts |= PCIE_SLOT_STA_PDC | PCIE_SLOT_STA_DLLSC;
+            cap.hotplug_interrupt_pending()
+        };
+        if pending {
+            self.irq_evt.write(1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Emulates a PCI Express root port with a hot-plug capable slot. Each root port is a PCI-to-PCI
+/// bridge whose secondary bus hosts the device in its slot.
+pub struct PcieRootPort {
+    config_regs: PciConfiguration,
+    pcie_cap: Arc<Mutex<PcieCap>>,
+    pcie_cap_offset: Option<usize>,
+    secondary_bus: u8,
+    irq_evt: Option<Event>,
+    irq_resample_evt: Option<Event>,
+    control_socket: Option<PcieSlotControlResponseSocket>,
+    kill_evt: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl PcieRootPort {
+    /// Constructs a root port whose secondary bus is `secondary_bus`, with an empty slot. Devices
+    /// are inserted into the slot and removed from it with commands on `control_socket`.
+    pub fn new(secondary_bus: u8, control_socket: PcieSlotControlResponseSocket) -> Self {
+        let mut config_regs = PciConfiguration::new(
+            PCI_VENDOR_ID_INTEL,
+            PCIE_RP_DEVICE_ID,
+            PciClassCode::BridgeDevice,
+            &PciBridgeSubclass::PciToPciBridge,
+            None,
+            PciHeaderType::Bridge,
+            0,
+            0,
+        );
+        // Primary bus 0, secondary and subordinate bus both `secondary_bus`.
+        let bus_numbers = u32::from(secondary_bus) << 16 | u32::from(secondary_bus) << 8;
+        config_regs.write_reg(6, 0, &bus_numbers.to_le_bytes());
+
+        PcieRootPort {
+            config_regs,
+            pcie_cap: Arc::new(Mutex::new(PcieCap::new(secondary_bus))),
+            pcie_cap_offset: None,
+            secondary_bus,
+            irq_evt: None,
+            irq_resample_evt: None,
+            control_socket: Some(control_socket),
+            kill_evt: None,
+            worker_thread: None,
+        }
+    }
+
+    /// Returns the bus number behind this root port.
+    pub fn secondary_bus(&self) -> u8 {
+        self.secondary_bus
+    }
+
+    // Starts the worker, in the process that will handle the device.
+    fn start_worker(&mut self) {
+        let (irq_evt, irq_resample_evt, control_socket) = match (
+            self.irq_evt.as_ref().map(Event::try_clone),
+            self.irq_resample_evt.as_ref().map(Event::try_clone),
+            self.control_socket.take(),
+        ) {
+            (Some(Ok(irq_evt)), Some(Ok(irq_resample_evt)), Some(control_socket)) => {
+                (irq_evt, irq_resample_evt, control_socket)
+            }
+            _ => {
+                error!("pcie root port: worker started without its resources");
+                return;
+            }
+        };
+        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("pcie root port: failed to create kill Event pair: {}", e);
+                return;
+            }
+        };
+        let mut worker = Worker {
+            pcie_cap: self.pcie_cap.clone(),
+            irq_evt,
+            irq_resample_evt,
+            control_socket,
+            kill_evt,
+        };
+        let worker_result = thread::Builder::new()
+            .name(format!("pcie root port {}", self.secondary_bus))
+            .spawn(move || {
+                if let Err(e) = worker.run() {
+                    error!("pcie root port worker exited with error: {}", e);
+                }
+            });
+        match worker_result {
+            Err(e) => error!("failed to spawn pcie root port worker: {}", e),
+            Ok(join_handle) => {
+                self.kill_evt = Some(self_kill_evt);
+                self.worker_thread = Some(join_handle);
+            }
+        }
+    }
+
+    // Returns the offset in the PCIe capability of register `reg_idx`, if the register is part of it.
+    fn pcie_cap_range(&self, reg_idx: usize) -> Option<usize> {
+        let cap_offset = self.pcie_cap_offset?;
+        let reg_offset = reg_idx * 4;
+        if reg_offset >= cap_offset && reg_offset < cap_offset + PCIE_CAP_LEN {
+            Some(reg_offset - cap_offset)
+        } else {
+            None
+        }
+    }
+
+    fn write_pcie_cap(&mut self, offset: usize, data: &[u8]) {
+        let mut pcie_cap = self.pcie_cap.lock();
+        let cap = pcie_cap.as_mut_slice();
+        for (i, value) in data.iter().enumerate() {
+            let byte_offset = offset + i;
+            if byte_offset >= PCIE_CAP_LEN {
+                break;
+            }
+            let (writable, rw1c) = pcie_cap_byte_masks(byte_offset);
+            let old = cap[byte_offset];
+            cap[byte_offset] = ((old & !writable) | (value & writable)) & !(value & rw1c);
+        }
+    }
+}
+
+impl Drop for PcieRootPort {
+    fn drop(&mut self) {
+        if let Some(worker_thread) = self.worker_thread.take() {
+            if let Some(kill_evt) = &self.kill_evt {
+                // Ignore the result because there is nothing we can do about it.
+                let _ = kill_evt.write(1);
+            }
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl PciDevice for PcieRootPort {
+    fn debug_label(&self) -> String {
+        format!("pcie root port {}", self.secondary_bus)
+    }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut rds = Vec::new();
+        if let Some(irq_evt) = &self.irq_evt {
+            rds.push(irq_evt.as_raw_descriptor());
+        }
+        if let Some(irq_resample_evt) = &self.irq_resample_evt {
+            rds.push(irq_resample_evt.as_raw_descriptor());
+        }
+        if let Some(control_socket) = &self.control_socket {
+            rds.push(control_socket.as_raw_descriptor());
+        }
+        rds
+    }
+
+    fn assign_irq(
+        &mut self,
+        irq_evt: Event,
+        irq_resample_evt: Event,
+        irq_num: u32,
+        irq_pin: PciInterruptPin,
+    ) {
+        self.config_regs.set_irq(irq_num as u8, irq_pin);
+        self.irq_evt = Some(irq_evt);
+        self.irq_resample_evt = Some(irq_resample_evt);
+    }
+
+    fn register_device_capabilities(&mut self) -> Result<()> {
+        let offset = self
+            .config_regs
+            .add_capability(&*self.pcie_cap.lock())
+            .map_err(pci_device::Error::CapabilitiesSetup)?;
+        self.pcie_cap_offset = Some(offset);
+        Ok(())
+    }
+
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        let data = self.config_regs.read_reg(reg_idx);
+        match self.pcie_cap_range(reg_idx) {
+            Some(offset) => {
+                let pcie_cap = self.pcie_cap.lock();
+                let cap = pcie_cap.as_slice();
+                // Keep the capability header generated by add_capability().
+                let mut value = data.to_le_bytes();
+                for (i, byte) in value.iter_mut().enumerate() {
+                    if offset + i >= 2 && offset + i < PCIE_CAP_LEN {
+                        *byte = cap[offset + i];
+                    }
+                }
+                u32::from_le_bytes(value)
+            }
+            None => data,
+        }
+    }
+
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        match self.pcie_cap_range(reg_idx) {
+            Some(cap_offset) => self.write_pcie_cap(cap_offset + offset as usize, data),
+            None => self.config_regs.write_reg(reg_idx, offset, data),
+        }
+    }
+
+    fn read_bar(&mut self, _addr: u64, _data: &mut [u8]) {}
+
+    fn write_bar(&mut self, _addr: u64, _data: &[u8]) {}
+
+    fn on_device_sandboxed(&mut self) {
+        self.start_worker();
+    }
+}
+
+impl Suspendable for PcieRootPort {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_control::PcieSlotControlRequestSocket;
+
+    fn root_port() -> (PcieRootPort, usize, PcieSlotControlRequestSocket) {
+        let (host_socket, device_socket) = msg_socket::pair().unwrap();
+        let mut port = PcieRootPort::new(1, device_socket);
+        port.register_device_capabilities().unwrap();
+        let cap_reg = port.pcie_cap_offset.unwrap() / 4;
+        (port, cap_reg, host_socket)
+    }
+
+    // Returns the interrupt and resample events of `port`, whose worker is running.
+    fn start_port(port: &mut PcieRootPort) -> (Event, Event) {
+        let irq_evt = Event::new().unwrap();
+        let irq_resample_evt = Event::new().unwrap();
+        port.assign_irq(
+            irq_evt.try_clone().unwrap(),
+            irq_resample_evt.try_clone().unwrap(),
+            5,
+            PciInterruptPin::IntA,
+        );
+        port.on_device_sandboxed();
+        (irq_evt, irq_resample_evt)
+    }
+
+    fn set_presence(host_socket: &PcieSlotControlRequestSocket, present: bool) {
+        host_socket
+            .send(&PcieSlotControlCommand::SetPresence { present })
+            .unwrap();
+        match host_socket.recv().unwrap() {
+            PcieSlotControlResult::Ok => {}
+            PcieSlotControlResult::Err(e) => panic!("failed to set slot presence: {}", e),
+        }
+    }
+
+    #[test]
+    fn bridge_header() {
+        let (port, cap_reg, _) = root_port();
+        // Header type 1.
+        assert_eq!((port.read_config_register(3) >> 16) & 0x7f, 1);
+        // Primary bus 0, secondary bus 1, subordinate bus 1.
+        assert_eq!(port.read_config_register(6) & 0x00ff_ffff, 0x0001_0100);
+        // Capability id and root port type.
+        let header = port.read_config_register(cap_reg);
+        assert_eq!(header & 0xff, PciCapabilityID::PCIExpress as u32);
+        assert_eq!(header >> 16, 0x0142);
+    }
+
+    #[test]
+    fn slot_status_write_one_to_clear() {
+        let (mut port, cap_reg, host_socket) = root_port();
+        start_port(&mut port);
+        // Slot control and status share the dword at offset 0x18.
+        let slot_reg = cap_reg + 0x18 / 4;
+
+        set_presence(&host_socket, true);
+        let slot = port.read_config_register(slot_reg) >> 16;
+        assert_eq!(
+            slot as u16,
+            PCIE_SLOT_STA_PDS | PCIE_SLOT_STA_PDC | PCIE_SLOT_STA_DLLSC
+        );
+
+        // Clearing the change bits must leave the read-only presence state intact.
+        let clear = u32::from(PCIE_SLOT_STA_PDC | PCIE_SLOT_STA_DLLSC | PCIE_SLOT_STA_PDS) << 16;
+        port.write_config_register(slot_reg, 0, &clear.to_le_bytes());
+        let slot = port.read_config_register(slot_reg) >> 16;
+        assert_eq!(slot as u16, PCIE_SLOT_STA_PDS);
+
+        set_presence(&host_socket, false);
+        let slot = port.read_config_register(slot_reg) >> 16;
+        assert_eq!(slot as u16, PCIE_SLOT_STA_PDC | PCIE_SLOT_STA_DLLSC);
+    }
+
+    #[test]
+    fn hotplug_interrupt() {
+        let (mut port, cap_reg, host_socket) = root_port();
+        let (irq_evt, _) = start_port(&mut port);
+
+        let slot_ctl = PCIE_SLOT_CTL_HP_INT_ENABLE | PCIE_SLOT_CTL_PDC_ENABLE;
+        port.write_config_register(cap_reg + 0x18 / 4, 0, &slot_ctl.to_le_bytes());
+        set_presence(&host_socket, true);
+        assert_eq!(irq_evt.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn hotplug_interrupt_resample() {
+        let (mut port, cap_reg, host_socket) = root_port();
+        let (irq_evt, irq_resample_evt) = start_port(&mut port);
+
+        let slot_ctl = PCIE_SLOT_CTL_HP_INT_ENABLE | PCIE_SLOT_CTL_PDC_ENABLE;
+        port.write_config_register(cap_reg + 0x18 / 4, 0, &slot_ctl.to_le_bytes());
+        set_presence(&host_socket, true);
+        assert_eq!(irq_evt.read().unwrap(), 1);
+
+        // The presence detect change is still pending at EOI, so the line is asserted again.
+        irq_resample_evt.write(1).unwrap();
+        assert_eq!(irq_evt.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn drop_stops_worker() {
+        let (mut port, _, host_socket) = root_port();
+        start_port(&mut port);
+        drop(port);
+        // The worker closed its end of the socket on exit.
+        assert!(host_socket
+            .send(&PcieSlotControlCommand::SetPresence { present: true })
+            .is_err());
+    }
+}
@@ -26,6 +26,7 @@ use crate::pci::pci_device::{Error as PciDeviceError, PciDevice};
 use crate::pci::{PciAddress, PciClassCode, PciInterruptPin};
 
 use crate::vfio::{VfioDevice, VfioIrqType};
+use crate::Suspendable;
 
 const PCI_VENDOR_ID: u32 = 0x0;
 const INTEL_VENDOR_ID: u16 = 0x8086;
@@ -466,6 +467,8 @@ pub struct VfioPciDevice {
     msi_cap: Option<VfioMsiCap>,
     msix_cap: Option<VfioMsixCap>,
     irq_type: Option<VfioIrqType>,
+    // SR-IOV virtual functions and some other devices have no legacy interrupt
+    intx_supported: bool,
     vm_socket_mem: VmMemoryControlRequestSocket,
     device_data: Option<DeviceData>,
 
@@ -504,7 +507,13 @@ impl VfioPciDevice {
             cap_next = config.read_config_byte(offset).into();
         }
 
-        let vendor_id = config.read_config_word(PCI_VENDOR_ID);
+        let intx_supported =
+            config.read_config_byte(PCI_INTERRUPT_PIN) > 0 && dev.irq_count(VfioIrqType::Intx) > 0;
+
+        let vendor_id = match dev.virtfn_ids() {
+            Some((vendor_id, _)) => vendor_id,
+            None => config.read_config_word(PCI_VENDOR_ID),
+        };
         let class_code = config.read_config_byte(PCI_BASE_CLASS_CODE);
 
         let is_intel_gfx = vendor_id == INTEL_VENDOR_ID
@@ -528,6 +537,7 @@ impl VfioPciDevice {
             msi_cap,
             msix_cap,
             irq_type: None,
+            intx_supported,
             vm_socket_mem: vfio_device_socket_mem,
             device_data,
             mem: Vec::new(),
@@ -561,6 +571,11 @@ impl VfioPciDevice {
     }
 
     fn enable_intx(&mut self) {
+        if !self.intx_supported {
+            self.irq_type = None;
+            return;
+        }
+
         if self.interrupt_evt.is_none() || self.interrupt_resample_evt.is_none() {
             return;
         }
@@ -595,6 +610,10 @@ impl VfioPciDevice {
     }
 
     fn disable_intx(&mut self) {
+        if !self.intx_supported {
+            return;
+        }
+
         if let Err(e) = self.device.irq_disable(VfioIrqType::Intx) {
             error!("Intx disable failed: {}", e);
         }
@@ -725,7 +744,7 @@ impl VfioPciDevice {
                     Err(_) => break,
                 };
                 match response {
-                    VmMemoryResponse::Ok => {
+                    VmMemoryResponse::RegisterMemory { .. } => {
                         // Even if vm has mapped this region, but it is in vm main process,
                         // device process doesn't has this mapping, but vfio_dma_map() need it
                         // in device process, so here map it again.
@@ -807,9 +826,7 @@ impl PciDevice for VfioPciDevice {
         self.interrupt_resample_evt = Some(irq_resample_evt);
 
         // enable INTX
-        if self.config.read_config_byte(PCI_INTERRUPT_PIN) > 0 {
-            self.enable_intx();
-        }
+        self.enable_intx();
     }
 
     fn allocate_io_bars(
@@ -962,8 +979,13 @@ impl PciDevice for VfioPciDevice {
 
         let mut config = self.config.read_config_dword(reg);
 
-        // Ignore IO bar
-        if reg >= 0x10 && reg <= 0x24 {
+        if reg == PCI_VENDOR_ID {
+            // Report the vendor and device id the PF provides for a VF.
+            if let Some((vendor_id, device_id)) = self.device.virtfn_ids() {
+                config = u32::from(device_id) << 16 | u32::from(vendor_id);
+            }
+        } else if reg >= 0x10 && reg <= 0x24 {
+            // Ignore IO bar
             for io_info in self.io_regions.iter() {
                 if io_info.bar_index * 4 + 0x10 == reg {
                     config = 0;
@@ -1075,3 +1097,5 @@ impl PciDevice for VfioPciDevice {
         }
     }
 }
+
+impl Suspendable for VfioPciDevice {}
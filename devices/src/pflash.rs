@@ -0,0 +1,376 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Emulated CFI parallel flash, used by firmware to persist its variable store.
+//!
+//! The device implements the Intel/Sharp command set (CFI primary vendor command set 0x0001) for
+//! a single x8 chip. The whole chip is emulated through MMIO, and every program or erase is
+//! written through to the backing file so the contents survive VM restarts.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+
+use base::{error, warn};
+
+use crate::suspendable;
+use crate::{BusAccessInfo, BusDevice, Suspendable};
+
+/// Size of an erase block. Backing files must be a multiple of this size.
+pub const PFLASH_BLOCK_SIZE: u64 = 0x1000;
+
+// Commands.
+const CMD_READ_ARRAY: u8 = 0xff;
+const CMD_READ_ID: u8 = 0x90;
+const CMD_CFI_QUERY: u8 = 0x98;
+const CMD_READ_STATUS: u8 = 0x70;
+const CMD_CLEAR_STATUS: u8 = 0x50;
+const CMD_PROGRAM: u8 = 0x40;
+const CMD_PROGRAM_ALT: u8 = 0x10;
+const CMD_BLOCK_ERASE: u8 = 0x20;
+const CMD_LOCK_SETUP: u8 = 0x60;
+const CMD_CONFIRM: u8 = 0xd0;
+
+// Status register bits.
+const STATUS_READY: u8 = 0x80;
+const STATUS_ERASE_ERROR: u8 = 0x20;
+const STATUS_PROGRAM_ERROR: u8 = 0x10;
+
+// Values returned in read identifier mode: Intel, 28F008SA.
+const MANUFACTURER_ID: u8 = 0x89;
+const DEVICE_ID: u8 = 0xa2;
+
+#[derive(Debug)]
+pub enum PflashError {
+    /// The backing file size is zero or not a multiple of the erase block size.
+    InvalidSize(u64),
+    /// Failed to read the backing file.
+    ReadImage(io::Error),
+}
+
+impl Display for PflashError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PflashError::*;
+
+        match self {
+            InvalidSize(size) => write!(
+                f,
+                "pflash image size {:#x} is not a non-zero multiple of {:#x}",
+                size, PFLASH_BLOCK_SIZE
+            ),
+            ReadImage(e) => write!(f, "failed to read pflash image: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PflashError {}
+
+// What reads return, or what the next write means, after the last command.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Mode {
+    ReadArray,
+    ReadStatus,
+    ReadId,
+    CfiQuery,
+    Program,
+    EraseSetup,
+    LockSetup,
+}
+
+/// A CFI flash chip backed by a file.
+pub struct Pflash {
+    file: File,
+    data: Vec<u8>,
+    cfi: Vec<u8>,
+    mode: Mode,
+    status: u8,
+}
+
+impl Pflash {
+    /// Constructs a flash chip with the contents and size of `file`, which is written back to as
+    /// the guest programs and erases the chip.
+    pub fn new(mut file: File) -> Result<Pflash, PflashError> {
+        let size = file
+            .seek(SeekFrom::End(0))
+            .map_err(PflashError::ReadImage)?;
+        if size == 0 || size % PFLASH_BLOCK_SIZE != 0 {
+            return Err(PflashError::InvalidSize(size));
+        }
+        file.seek(SeekFrom::Start(0))
+            .map_err(PflashError::ReadImage)?;
+        let mut data = vec![0u8; size as usize];
+        file.read_exact(&mut data).map_err(PflashError::ReadImage)?;
+
+        Ok(Pflash {
+            file,
+            cfi: build_cfi_table(size),
+            data,
+            mode: Mode::ReadArray,
+            status: STATUS_READY,
+        })
+    }
+
+    /// Size of the chip in bytes.
+    pub fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn program(&mut self, offset: usize, data: &[u8]) {
+        let end = offset + data.len();
+        self.data[offset..end].copy_from_slice(data);
+        if let Err(e) = self.file.write_all_at(data, offset as u64) {
+            error!("pflash: failed to write back {:#x}: {}", offset, e);
+            self.status |= STATUS_PROGRAM_ERROR;
+        }
+    }
+
+    fn erase(&mut self, offset: usize) {
+        let start = offset & !(PFLASH_BLOCK_SIZE as usize - 1);
+        let end = start + PFLASH_BLOCK_SIZE as usize;
+        let block = &mut self.data[start..end];
+        for b in block.iter_mut() {
+            *b = 0xff;
+        }
+        if let Err(e) = self.file.write_all_at(block, start as u64) {
+            error!("pflash: failed to write back block {:#x}: {}", start, e);
+            self.status |= STATUS_ERASE_ERROR;
+        }
+    }
+
+    fn command(&mut self, cmd: u8) {
+        self.mode = match cmd {
+            CMD_READ_ARRAY => Mode::ReadArray,
+            CMD_READ_ID => Mode::ReadId,
+            CMD_CFI_QUERY => Mode::CfiQuery,
+            CMD_READ_STATUS => Mode::ReadStatus,
+            CMD_CLEAR_STATUS => {
+                self.status = 0;
+                Mode::ReadArray
+            }
+            CMD_PROGRAM | CMD_PROGRAM_ALT => Mode::Program,
+            CMD_BLOCK_ERASE => Mode::EraseSetup,
+            CMD_LOCK_SETUP => Mode::LockSetup,
+            _ => {
+                warn!("pflash: unsupported command {:#x}", cmd);
+                Mode::ReadArray
+            }
+        };
+    }
+}
+
+// Builds the CFI query table for a chip of `size` bytes with a single region of uniform erase
+// blocks, laid out as it is read back in an x8 bus.
+fn build_cfi_table(size: u64) -> Vec<u8> {
+    let mut cfi = vec![0u8; 0x31];
+    cfi[0x10..0x13].copy_from_slice(b"QRY");
+    // Primary vendor command set: Intel/Sharp extended. There is no extended query table.
+    cfi[0x13] = 0x01;
+    // Vcc logic supply minimum and maximum, 4.5V and 5.5V.
+    cfi[0x1b] = 0x45;
+    cfi[0x1c] = 0x55;
+    // Typical single byte program and block erase times, 2^n us and 2^n ms.
+    cfi[0x1f] = 0x07;
+    cfi[0x21] = 0x0a;
+    // Maximum times, as multiples of the typical ones.
+    cfi[0x23] = 0x04;
+    cfi[0x25] = 0x04;
+    // Device size as a power of two.
+    cfi[0x27] = 64 - (size - 1).leading_zeros() as u8;
+    // One erase block region.
+    cfi[0x2c] = 1;
+    let blocks = size / PFLASH_BLOCK_SIZE - 1;
+    cfi[0x2d] = blocks as u8;
+    cfi[0x2e] = (blocks >> 8) as u8;
+    let block_size = PFLASH_BLOCK_SIZE / 256;
+    cfi[0x2f] = block_size as u8;
+    cfi[0x30] = (block_size >> 8) as u8;
+    cfi
+}
+
+impl BusDevice for Pflash {
+    fn debug_label(&self) -> String {
+        "pflash".to_owned()
+    }
+
+    fn read(&mut self, info: BusAccessInfo, data: &mut [u8]) {
+        let offset = info.offset as usize;
+        match self.mode {
+            Mode::ReadArray => match self.data.get(offset..offset + data.len()) {
+                Some(src) => data.copy_from_slice(src),
+                None => {
+                    for b in data.iter_mut() {
+                        *b = 0xff;
+                    }
+                }
+            },
+            Mode::ReadId => {
+                for (i, b) in data.iter_mut().enumerate() {
+                    *b = match offset + i {
+                        0 => MANUFACTURER_ID,
+                        1 => DEVICE_ID,
+                        _ => 0,
+                    };
+                }
+            }
+            Mode::CfiQuery => {
+                for (i, b) in data.iter_mut().enumerate() {
+                    *b = self.cfi.get(offset + i).copied().unwrap_or(0);
+                }
+            }
+            // Program, erase and lock operations complete immediately, after which the chip
+            // returns its status until it is put back in read array mode.
+            _ => {
+                for b in data.iter_mut() {
+                    *b = self.status;
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, info: BusAccessInfo, data: &[u8]) {
+        let offset = info.offset as usize;
+        if data.is_empty() || offset + data.len() > self.data.len() {
+            return;
+        }
+
+        match self.mode {
+            Mode::Program => {
+                self.program(offset, data);
+                self.status |= STATUS_READY;
+                self.mode = Mode::ReadStatus;
+            }
+            Mode::EraseSetup => {
+                if data[0] == CMD_CONFIRM {
+                    self.erase(offset);
+                } else {
+                    // Improper command sequence.
+                    self.status |= STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR;
+                }
+                self.status |= STATUS_READY;
+                self.mode = Mode::ReadStatus;
+            }
+            Mode::LockSetup => {
+                // Block locking is not emulated, all blocks are always unlocked.
+                self.status |= STATUS_READY;
+                self.mode = Mode::ReadStatus;
+            }
+            _ => self.command(data[0]),
+        }
+    }
+}
+
+// The flash only acts on guest accesses.
+impl Suspendable for Pflash {
+    fn sleep(&mut self) -> suspendable::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use tempfile::tempfile;
+
+    const SIZE: usize = 4 * PFLASH_BLOCK_SIZE as usize;
+
+    fn new_pflash() -> (Pflash, File) {
+        let mut file = tempfile().unwrap();
+        let contents: Vec<u8> = (0..SIZE).map(|i| i as u8).collect();
+        file.write_all(&contents).unwrap();
+        let pflash = Pflash::new(file.try_clone().unwrap()).unwrap();
+        (pflash, file)
+    }
+
+    fn read(pflash: &mut Pflash, offset: u64) -> u8 {
+        let mut data = [0u8];
+        pflash.read(
+            BusAccessInfo {
+                offset,
+                address: offset,
+                id: 0,
+            },
+            &mut data,
+        );
+        data[0]
+    }
+
+    fn write(pflash: &mut Pflash, offset: u64, value: u8) {
+        pflash.write(
+            BusAccessInfo {
+                offset,
+                address: offset,
+                id: 0,
+            },
+            &[value],
+        );
+    }
+
+    #[test]
+    fn invalid_size() {
+        let mut file = tempfile().unwrap();
+        file.write_all(&[0u8; 100]).unwrap();
+        assert!(Pflash::new(file).is_err());
+        assert!(Pflash::new(tempfile().unwrap()).is_err());
+    }
+
+    #[test]
+    fn program_and_erase_persist() {
+        let (mut pflash, file) = new_pflash();
+        assert_eq!(pflash.size(), SIZE as u64);
+        assert_eq!(read(&mut pflash, 0x1234), 0x34);
+
+        write(&mut pflash, 0x1234, CMD_PROGRAM_ALT);
+        write(&mut pflash, 0x1234, 0xaa);
+        assert_eq!(read(&mut pflash, 0x1234) & STATUS_READY, STATUS_READY);
+        write(&mut pflash, 0x1234, CMD_READ_ARRAY);
+        assert_eq!(read(&mut pflash, 0x1234), 0xaa);
+
+        write(&mut pflash, 0x2010, CMD_BLOCK_ERASE);
+        write(&mut pflash, 0x2010, CMD_CONFIRM);
+        write(&mut pflash, 0x2010, CMD_READ_ARRAY);
+        assert_eq!(read(&mut pflash, 0x2000), 0xff);
+        assert_eq!(read(&mut pflash, 0x2fff), 0xff);
+        assert_eq!(read(&mut pflash, 0x3000), 0x00);
+
+        let mut contents = vec![0u8; SIZE];
+        file.read_exact_at(&mut contents, 0).unwrap();
+        assert_eq!(contents[0x1234], 0xaa);
+        assert!(contents[0x2000..0x3000].iter().all(|&b| b == 0xff));
+        assert_eq!(contents[0x3001], 0x01);
+    }
+
+    #[test]
+    fn firmware_probe() {
+        // The sequence OVMF uses to tell flash apart from ROM and RAM.
+        let (mut pflash, _file) = new_pflash();
+        let original = read(&mut pflash, 0x10);
+        write(&mut pflash, 0x10, CMD_CLEAR_STATUS);
+        assert_eq!(read(&mut pflash, 0x10), original);
+        write(&mut pflash, 0x10, CMD_READ_STATUS);
+        assert_eq!(read(&mut pflash, 0x10), 0x00);
+        write(&mut pflash, 0x10, CMD_READ_ARRAY);
+        assert_eq!(read(&mut pflash, 0x10), original);
+    }
+
+    #[test]
+    fn cfi_query() {
+        let (mut pflash, _file) = new_pflash();
+        write(&mut pflash, 0x55, CMD_CFI_QUERY);
+        assert_eq!(read(&mut pflash, 0x10), b'Q');
+        assert_eq!(read(&mut pflash, 0x11), b'R');
+        assert_eq!(read(&mut pflash, 0x12), b'Y');
+        // 16KiB device with four 4KiB blocks.
+        assert_eq!(read(&mut pflash, 0x27), 14);
+        assert_eq!(read(&mut pflash, 0x2d), 3);
+        assert_eq!(read(&mut pflash, 0x2f), 0x10);
+        write(&mut pflash, 0, CMD_READ_ID);
+        assert_eq!(read(&mut pflash, 0), MANUFACTURER_ID);
+        write(&mut pflash, 0, CMD_READ_ARRAY);
+        assert_eq!(read(&mut pflash, 0x11), 0x11);
+    }
+}
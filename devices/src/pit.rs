@@ -28,7 +28,7 @@ use base::FakeTimer as Timer;
 use base::Timer;
 
 use crate::bus::BusAccessInfo;
-use crate::BusDevice;
+use crate::{BusDevice, Suspendable};
 
 // Bitmask for areas of standard (non-ReadBack) Control Word Format. Constant
 // names are kept the same as Intel PIT data sheet.
@@ -259,6 +259,8 @@ impl BusDevice for Pit {
     }
 }
 
+impl Suspendable for Pit {}
+
 impl Pit {
     pub fn new(interrupt_evt: Event, clock: Arc<Mutex<Clock>>) -> PitResult<Pit> {
         let mut counters = Vec::new();
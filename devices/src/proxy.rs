@@ -8,12 +8,16 @@ use std::fmt::{self, Display};
 use std::time::Duration;
 use std::{self, io};
 
-use base::{error, net::UnixSeqpacket, AsRawDescriptor, RawDescriptor};
+use base::{
+    error, net::UnixSeqpacket, signal, syslog, trace_event, AsRawDescriptor, RawDescriptor,
+};
 use libc::{self, pid_t};
 use minijail::{self, Minijail};
 use msg_socket::{MsgOnSocket, MsgReceiver, MsgSender, MsgSocket};
+use vm_control::QueueStats;
 
-use crate::{BusAccessInfo, BusDevice};
+use crate::suspendable;
+use crate::{BusAccessInfo, BusDevice, Suspendable, SuspendableError};
 
 /// Errors for proxy devices.
 #[derive(Debug)]
@@ -54,6 +58,11 @@ enum Command {
         len: u32,
         data: [u8; 4],
     },
+    Sleep,
+    Wake,
+    Snapshot,
+    Restore(Vec<u8>),
+    QueueStats,
     Shutdown,
 }
 
@@ -62,6 +71,18 @@ enum CommandResult {
     Ok,
     ReadResult([u8; 8]),
     ReadConfigResult(u32),
+    SnapshotResult(Vec<u8>),
+    QueueStatsResult(Vec<QueueStats>),
+    SuspendableError(SuspendableError),
+}
+
+impl From<suspendable::Result<()>> for CommandResult {
+    fn from(res: suspendable::Result<()>) -> CommandResult {
+        match res {
+            Ok(()) => CommandResult::Ok,
+            Err(e) => CommandResult::SuspendableError(e),
+        }
+    }
 }
 
 fn child_proc<D: BusDevice>(sock: UnixSeqpacket, device: &mut D) {
@@ -104,6 +125,16 @@ fn child_proc<D: BusDevice>(sock: UnixSeqpacket, device: &mut D) {
                 // Command::WriteConfig does not have a result.
                 Ok(())
             }
+            Command::Sleep => sock.send(&device.sleep().into()),
+            Command::Wake => sock.send(&device.wake().into()),
+            Command::Snapshot => match device.snapshot() {
+                Ok(data) => sock.send(&CommandResult::SnapshotResult(data)),
+                Err(e) => sock.send(&CommandResult::SuspendableError(e)),
+            },
+            Command::Restore(data) => sock.send(&device.restore(&data).into()),
+            Command::QueueStats => {
+                sock.send(&CommandResult::QueueStatsResult(device.queue_stats()))
+            }
             Command::Shutdown => {
                 running = false;
                 sock.send(&CommandResult::Ok)
@@ -144,10 +175,19 @@ impl ProxyDevice {
         let (child_sock, parent_sock) = UnixSeqpacket::pair().map_err(Error::Io)?;
 
         keep_rds.push(child_sock.as_raw_descriptor());
+        // Spans traced in the child go to the same output as the ones of this process.
+        trace_event::push_descriptors(&mut keep_rds);
+        let trace = trace_event!(jail, "fork");
         // Forking here is safe as long as the program is still single threaded.
         let pid = unsafe {
             match jail.fork(Some(&keep_rds)).map_err(Error::ForkingJail)? {
                 0 => {
+                    syslog::set_tag(debug_label.clone());
+                    // The main process blocks the signals it reads from a signalfd, and the mask is
+                    // inherited across fork. Nothing reads them here, so restore the default.
+                    if let Err(e) = signal::unblock_all_signals() {
+                        error!("failed to unblock signals in {}: {}", debug_label, e);
+                    }
                     device.on_sandboxed();
                     child_proc(child_sock, &mut device);
 
@@ -164,6 +204,7 @@ impl ProxyDevice {
                 p => p,
             }
         };
+        drop(trace);
 
         parent_sock
             .set_write_timeout(Some(Duration::from_millis(SOCKET_TIMEOUT_MS)))
@@ -207,6 +248,15 @@ impl ProxyDevice {
             Ok(r) => Some(r),
         }
     }
+
+    /// Send one of the `Suspendable` commands, which only answer with success or an error.
+    fn sync_send_suspendable(&self, cmd: &Command) -> suspendable::Result<()> {
+        match self.sync_send(cmd) {
+            Some(CommandResult::Ok) => Ok(()),
+            Some(CommandResult::SuspendableError(e)) => Err(e),
+            _ => Err(SuspendableError::ProxyFailed),
+        }
+    }
 }
 
 impl BusDevice for ProxyDevice {
@@ -257,6 +307,35 @@ impl BusDevice for ProxyDevice {
             data: buffer,
         });
     }
+
+    fn queue_stats(&self) -> Vec<QueueStats> {
+        match self.sync_send(&Command::QueueStats) {
+            Some(CommandResult::QueueStatsResult(stats)) => stats,
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Suspendable for ProxyDevice {
+    fn sleep(&mut self) -> suspendable::Result<()> {
+        self.sync_send_suspendable(&Command::Sleep)
+    }
+
+    fn wake(&mut self) -> suspendable::Result<()> {
+        self.sync_send_suspendable(&Command::Wake)
+    }
+
+    fn snapshot(&mut self) -> suspendable::Result<Vec<u8>> {
+        match self.sync_send(&Command::Snapshot) {
+            Some(CommandResult::SnapshotResult(data)) => Ok(data),
+            Some(CommandResult::SuspendableError(e)) => Err(e),
+            _ => Err(SuspendableError::ProxyFailed),
+        }
+    }
+
+    fn restore(&mut self, data: &[u8]) -> suspendable::Result<()> {
+        self.sync_send_suspendable(&Command::Restore(data.to_vec()))
+    }
 }
 
 impl Drop for ProxyDevice {
@@ -306,6 +385,8 @@ mod tests {
         }
     }
 
+    impl Suspendable for EchoDevice {}
+
     fn new_proxied_echo_device() -> ProxyDevice {
         let device = EchoDevice::new();
         let keep_fds: Vec<RawDescriptor> = Vec::new();
@@ -0,0 +1,159 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The parts of the SCSI command set shared by the disks emulated behind the virtio-scsi
+//! controller and the USB mass storage device: operation codes, sense data, and the parameter data
+//! of the commands that only describe a logical unit. See SPC-4 and SBC-3.
+
+// Operation codes.
+pub const TEST_UNIT_READY: u8 = 0x00;
+pub const REQUEST_SENSE: u8 = 0x03;
+pub const INQUIRY: u8 = 0x12;
+pub const MODE_SENSE_6: u8 = 0x1a;
+pub const START_STOP_UNIT: u8 = 0x1b;
+pub const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+pub const READ_CAPACITY_10: u8 = 0x25;
+pub const READ_10: u8 = 0x28;
+pub const WRITE_10: u8 = 0x2a;
+pub const VERIFY_10: u8 = 0x2f;
+pub const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+pub const MODE_SENSE_10: u8 = 0x5a;
+pub const REPORT_LUNS: u8 = 0xa0;
+
+/// Vendor identification reported by INQUIRY.
+pub const VENDOR: &[u8; 8] = b"CROSVM  ";
+const REVISION: &[u8; 4] = b"0001";
+
+/// A sense key and additional sense code, describing why a command failed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sense {
+    pub key: u8,
+    pub asc: u8,
+    pub ascq: u8,
+}
+
+impl Sense {
+    pub const NO_SENSE: Sense = Sense::new(0x00, 0x00, 0x00);
+    pub const MEDIUM_NOT_PRESENT: Sense = Sense::new(0x02, 0x3a, 0x00);
+    pub const UNRECOVERED_READ_ERROR: Sense = Sense::new(0x03, 0x11, 0x00);
+    pub const WRITE_ERROR: Sense = Sense::new(0x03, 0x0c, 0x00);
+    pub const PARAMETER_LIST_LENGTH_ERROR: Sense = Sense::new(0x05, 0x1a, 0x00);
+    pub const INVALID_OPCODE: Sense = Sense::new(0x05, 0x20, 0x00);
+    pub const LBA_OUT_OF_RANGE: Sense = Sense::new(0x05, 0x21, 0x00);
+    pub const INVALID_FIELD_IN_CDB: Sense = Sense::new(0x05, 0x24, 0x00);
+    pub const LUN_NOT_SUPPORTED: Sense = Sense::new(0x05, 0x25, 0x00);
+    pub const INVALID_FIELD_IN_PARAMETER_LIST: Sense = Sense::new(0x05, 0x26, 0x00);
+    pub const INVALID_RELEASE_OF_PERSISTENT_RESERVATION: Sense = Sense::new(0x05, 0x26, 0x04);
+    pub const WRITE_PROTECTED: Sense = Sense::new(0x07, 0x27, 0x00);
+
+    const fn new(key: u8, asc: u8, ascq: u8) -> Sense {
+        Sense { key, asc, ascq }
+    }
+
+    /// Returns the sense data in fixed format.
+    pub fn to_bytes(self) -> [u8; 18] {
+        let mut sense = [0u8; 18];
+        // Current error, fixed format.
+        sense[0] = 0x70;
+        sense[2] = self.key;
+        // Additional sense length.
+        sense[7] = 10;
+        sense[12] = self.asc;
+        sense[13] = self.ascq;
+        sense
+    }
+}
+
+/// What the standard INQUIRY data tells about a direct access block device.
+pub struct Inquiry {
+    pub product: &'static [u8; 16],
+    pub removable: bool,
+    /// Whether the unit accepts more than one command at a time.
+    pub command_queuing: bool,
+}
+
+impl Inquiry {
+    /// Returns the standard INQUIRY data.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![
+            0x00, // Direct access block device.
+            if self.removable { 0x80 } else { 0x00 },
+            0x06, // SPC-4.
+            0x12, // Hierarchical LUN addressing, response data format 2.
+            31,   // Additional length.
+            0x00,
+            0x00,
+            if self.command_queuing { 0x02 } else { 0x00 },
+        ];
+        data.extend_from_slice(VENDOR);
+        data.extend_from_slice(self.product);
+        data.extend_from_slice(REVISION);
+        data
+    }
+}
+
+/// Returns the READ CAPACITY(10) parameter data of a disk of `num_blocks` blocks of `block_size`
+/// bytes.
+pub fn read_capacity_10(num_blocks: u64, block_size: u32) -> Vec<u8> {
+    let last_lba = num_blocks.saturating_sub(1).min(u32::MAX as u64) as u32;
+    let mut data = last_lba.to_be_bytes().to_vec();
+    data.extend_from_slice(&block_size.to_be_bytes());
+    data
+}
+
+/// Returns the MODE SENSE parameter data made of `block_descriptor` and `pages`, with the header of
+/// MODE SENSE(10) if `opcode` is `MODE_SENSE_10` and of MODE SENSE(6) otherwise.
+pub fn mode_sense(opcode: u8, read_only: bool, block_descriptor: &[u8], pages: &[u8]) -> Vec<u8> {
+    let device_specific = if read_only { 0x80 } else { 0x00 };
+    let mut data = if opcode == MODE_SENSE_10 {
+        let len = (6 + block_descriptor.len() + pages.len()) as u16;
+        let mut header = len.to_be_bytes().to_vec();
+        header.extend_from_slice(&[0, device_specific, 0, 0]);
+        header.extend_from_slice(&(block_descriptor.len() as u16).to_be_bytes());
+        header
+    } else {
+        let len = (3 + block_descriptor.len() + pages.len()) as u8;
+        vec![len, 0, device_specific, block_descriptor.len() as u8]
+    };
+    data.extend_from_slice(block_descriptor);
+    data.extend_from_slice(pages);
+    data
+}
+
+/// Returns the REPORT LUNS parameter data listing `luns`.
+pub fn report_luns<I: IntoIterator<Item = u16>>(luns: I) -> Vec<u8> {
+    let mut data = vec![0u8; 8];
+    for lun in luns {
+        let mut entry = [0u8; 8];
+        if lun < 256 {
+            // Peripheral device addressing.
+            entry[1] = lun as u8;
+        } else {
+            // Flat space addressing.
+            entry[0] = 0x40 | (lun >> 8) as u8;
+            entry[1] = lun as u8;
+        }
+        data.extend_from_slice(&entry);
+    }
+    let list_len = (data.len() - 8) as u32;
+    data[..4].copy_from_slice(&list_len.to_be_bytes());
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mode_sense_headers() {
+        assert_eq!(
+            mode_sense(MODE_SENSE_6, true, &[], &[]),
+            vec![3, 0, 0x80, 0]
+        );
+        assert_eq!(
+            mode_sense(MODE_SENSE_10, false, &[1; 8], &[2; 4]),
+            vec![0, 18, 0, 0, 0, 0, 0, 8, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2]
+        );
+    }
+}
@@ -12,7 +12,7 @@ use std::thread::{self};
 use base::{error, Event, RawDescriptor, Result};
 
 use crate::bus::BusAccessInfo;
-use crate::{BusDevice, SerialDevice};
+use crate::{BusDevice, SerialDevice, Suspendable};
 
 const LOOP_SIZE: usize = 0x40;
 
@@ -374,6 +374,8 @@ impl BusDevice for Serial {
     }
 }
 
+impl Suspendable for Serial {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,89 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Quiescing devices and saving their state, the groundwork for saving and restoring a VM.
+
+use std::fmt::{self, Display};
+
+use base::RawDescriptor;
+use msg_socket::MsgOnSocket;
+
+#[derive(Debug, MsgOnSocket)]
+pub enum Error {
+    /// The snapshot passed to `restore` was not taken from this kind of device.
+    InvalidSnapshot,
+    /// The device does not support the operation.
+    NotSupported,
+    /// The process running the device did not answer.
+    ProxyFailed,
+    /// The device failed to stop or restart its workers.
+    WorkerFailed,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidSnapshot => write!(f, "snapshot does not match the device"),
+            NotSupported => write!(f, "operation is not supported by the device"),
+            ProxyFailed => write!(f, "failed to reach the device process"),
+            WorkerFailed => write!(f, "failed to stop or restart the device workers"),
+        }
+    }
+}
+
+/// Lets the VM pause a device, save its state, and bring it back.
+///
+/// The VM puts every device to sleep before its state is saved or replaced, and wakes them once it
+/// resumes. A sleeping device must not touch guest memory or inject interrupts, but has to keep
+/// the requests the guest already made so that they are carried out on `wake`. Waking a device
+/// that isn't asleep does nothing.
+///
+/// Devices have to opt in: one with workers must stop them in `sleep` and start them again in
+/// `wake`, while one that only acts on guest accesses, which stop anyway while the VCPUs are
+/// paused, can just return `Ok` from `sleep`. They can't be snapshot until they implement
+/// `snapshot` and `restore`.
+pub trait Suspendable {
+    /// Stops the device from doing any work on its own.
+    fn sleep(&mut self) -> Result<()> {
+        Err(Error::NotSupported)
+    }
+
+    /// Lets a sleeping device carry on.
+    fn wake(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the state of a sleeping device, which `restore` accepts.
+    fn snapshot(&mut self) -> Result<Vec<u8>> {
+        Err(Error::NotSupported)
+    }
+
+    /// Replaces the state of a sleeping device with a snapshot from `snapshot`.
+    fn restore(&mut self, data: &[u8]) -> Result<()> {
+        let _ = data;
+        Err(Error::NotSupported)
+    }
+}
+
+impl<T: Suspendable + ?Sized> Suspendable for Box<T> {
+    fn sleep(&mut self) -> Result<()> {
+        (**self).sleep()
+    }
+
+    fn wake(&mut self) -> Result<()> {
+        (**self).wake()
+    }
+
+    fn snapshot(&mut self) -> Result<Vec<u8>> {
+        (**self).snapshot()
+    }
+
+    fn restore(&mut self, data: &[u8]) -> Result<()> {
+        (**self).restore(data)
+    }
+}
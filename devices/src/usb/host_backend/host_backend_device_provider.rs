@@ -7,12 +7,15 @@ use std::sync::Arc;
 
 use super::error::*;
 use super::host_device::HostDevice;
+use crate::usb::mass_storage::mass_storage_device::MassStorageDevice;
 use crate::usb::xhci::usb_hub::UsbHub;
 use crate::usb::xhci::xhci_backend_device_provider::XhciBackendDeviceProvider;
 use crate::utils::AsyncJobQueue;
 use crate::utils::{EventHandler, EventLoop, FailHandle};
 use base::net::UnixSeqpacket;
-use base::{error, AsRawDescriptor, FromRawDescriptor, RawDescriptor, WatchingEvents};
+use base::{
+    error, AsRawDescriptor, FromRawDescriptor, IntoRawDescriptor, RawDescriptor, WatchingEvents,
+};
 use msg_socket::{MsgReceiver, MsgSender, MsgSocket};
 use std::collections::HashMap;
 use std::mem;
@@ -223,6 +226,40 @@ impl ProviderInner {
         }
     }
 
+    /// Create an emulated mass storage device from a disk image and connect it to the hub.
+    fn handle_attach_mass_storage(
+        &self,
+        read_only: bool,
+        fd: Option<MaybeOwnedDescriptor>,
+    ) -> UsbControlResult {
+        let disk_file = match fd {
+            Some(MaybeOwnedDescriptor::Owned(file)) => file,
+            _ => {
+                error!("missing fd in UsbControlCommand::AttachMassStorage message");
+                return UsbControlResult::FailedToOpenDevice;
+            }
+        };
+
+        // Safe because we own the descriptor and transfer its ownership to the file.
+        let raw_image = unsafe { File::from_raw_descriptor(disk_file.into_raw_descriptor()) };
+        let disk = match disk::create_disk_file(raw_image, disk::MAX_NESTING_DEPTH) {
+            Ok(d) => d,
+            Err(e) => {
+                error!("failed to open mass storage disk image: {}", e);
+                return UsbControlResult::FailedToOpenDevice;
+            }
+        };
+
+        let device = Box::new(MassStorageDevice::new(disk, read_only));
+        match self.usb_hub.connect_backend(device) {
+            Ok(port) => UsbControlResult::Ok { port },
+            Err(e) => {
+                error!("failed to connect device to hub: {}", e);
+                UsbControlResult::NoAvailablePort
+            }
+        }
+    }
+
     fn handle_detach_device(&self, port: u8) -> UsbControlResult {
         match self.usb_hub.disconnect_port(port) {
             Ok(()) => {
@@ -280,6 +317,10 @@ impl ProviderInner {
             UsbControlCommand::AttachDevice { descriptor, .. } => {
                 self.handle_attach_device(descriptor)
             }
+            UsbControlCommand::AttachMassStorage {
+                read_only,
+                descriptor,
+            } => self.handle_attach_mass_storage(read_only, descriptor),
             UsbControlCommand::DetachDevice { port } => self.handle_detach_device(port),
             UsbControlCommand::ListDevice { ports } => self.handle_list_devices(ports),
         };
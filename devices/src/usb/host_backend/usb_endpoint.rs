@@ -69,7 +69,7 @@ impl UsbEndpoint {
             .get_transfer_type()
             .map_err(Error::GetXhciTransferType)?
         {
-            XhciTransferType::Normal(buffer) => buffer,
+            XhciTransferType::Normal(buffer) | XhciTransferType::Isochronous(buffer) => buffer,
             XhciTransferType::Noop => {
                 return transfer
                     .on_transfer_complete(&TransferStatus::Completed, 0)
@@ -90,6 +90,9 @@ impl UsbEndpoint {
             EndpointType::Interrupt => {
                 self.handle_interrupt_transfer(transfer, buffer)?;
             }
+            EndpointType::Isochronous => {
+                self.handle_isochronous_transfer(transfer, buffer)?;
+            }
             _ => {
                 return transfer
                     .on_transfer_complete(&TransferStatus::Error, 0)
@@ -130,6 +133,20 @@ impl UsbEndpoint {
         self.do_handle_transfer(xhci_transfer, usb_transfer, buffer)
     }
 
+    fn handle_isochronous_transfer(
+        &self,
+        xhci_transfer: XhciTransfer,
+        buffer: ScatterGatherBuffer,
+    ) -> Result<()> {
+        let transfer_buffer = self.get_transfer_buffer(&buffer)?;
+        // Each isochronous TD covers a single service interval, so it maps onto one usbfs packet.
+        let packet_length = transfer_buffer.len() as u32;
+        let usb_transfer =
+            Transfer::new_isochronous(self.ep_addr(), transfer_buffer, &[packet_length])
+                .map_err(Error::CreateTransfer)?;
+        self.do_handle_transfer(xhci_transfer, usb_transfer, buffer)
+    }
+
     fn do_handle_transfer(
         &self,
         xhci_transfer: XhciTransfer,
@@ -175,7 +192,7 @@ impl UsbEndpoint {
                 usb_transfer.set_callback(move |t: Transfer| match callback(t) {
                     Ok(_) => {}
                     Err(e) => {
-                        error!("bulk transfer callback failed: {:?}", e);
+                        error!("out transfer callback failed: {:?}", e);
                         fail_handle.fail();
                     }
                 });
@@ -231,7 +248,7 @@ impl UsbEndpoint {
                 usb_transfer.set_callback(move |t: Transfer| match callback(t) {
                     Ok(_) => {}
                     Err(e) => {
-                        error!("bulk transfer callback {:?}", e);
+                        error!("in transfer callback failed: {:?}", e);
                         fail_handle.fail();
                     }
                 });
@@ -0,0 +1,626 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::min;
+use std::fmt::{self, Display};
+use std::mem;
+
+use super::scsi::{DataPhase, ScsiDisk};
+use crate::usb::host_backend::host_device::ControlEndpointState;
+use crate::usb::xhci::scatter_gather_buffer::{Error as BufferError, ScatterGatherBuffer};
+use crate::usb::xhci::xhci_backend_device::{BackendType, UsbDeviceAddress, XhciBackendDevice};
+use crate::usb::xhci::xhci_transfer::{
+    Error as XhciTransferError, TransferDirection, XhciTransfer, XhciTransferType,
+};
+use base::{error, warn};
+use data_model::DataInit;
+use disk::DiskFile;
+use usb_util::{
+    ConfigDescriptor, ControlRequestDataPhaseTransferDirection, ControlRequestType, Descriptor,
+    DescriptorHeader, DescriptorType, DeviceDescriptor, EndpointDescriptor, InterfaceDescriptor,
+    StandardControlRequest, TransferStatus, UsbRequestSetup,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    GetXhciTransferType(XhciTransferError),
+    TransferComplete(XhciTransferError),
+    ReadBuffer(BufferError),
+    WriteBuffer(BufferError),
+    BufferLen(BufferError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            GetXhciTransferType(e) => write!(f, "failed to get xhci transfer type: {}", e),
+            TransferComplete(e) => write!(f, "xhci transfer completed: {}", e),
+            ReadBuffer(e) => write!(f, "failed to read buffer: {}", e),
+            WriteBuffer(e) => write!(f, "failed to write buffer: {}", e),
+            BufferLen(e) => write!(f, "failed to get buffer length: {}", e),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+const VENDOR_ID: u16 = 0x18d1;
+const PRODUCT_ID: u16 = 0x5502;
+
+const BULK_IN_ENDPOINT: u8 = 1;
+const BULK_OUT_ENDPOINT: u8 = 2;
+const BULK_MAX_PACKET_SIZE: u16 = 512;
+
+const STRING_MANUFACTURER: u8 = 1;
+const STRING_PRODUCT: u8 = 2;
+const STRING_SERIAL_NUMBER: u8 = 3;
+const STRING_DESCRIPTOR_TYPE: u8 = 3;
+// US English, the only language reported in string descriptor zero.
+const LANGUAGE_ID: u16 = 0x0409;
+
+// Mass storage class, SCSI transparent command set, bulk-only transport.
+const MASS_STORAGE_CLASS: u8 = 0x08;
+const SCSI_TRANSPARENT_SUBCLASS: u8 = 0x06;
+const BULK_ONLY_PROTOCOL: u8 = 0x50;
+
+// Class specific requests, see Bulk-Only Transport 3.1 and 3.2.
+const BULK_ONLY_MASS_STORAGE_RESET: u8 = 0xff;
+const GET_MAX_LUN: u8 = 0xfe;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_FLAG_DATA_IN: u8 = 0x80;
+const CSW_STATUS_PASSED: u8 = 0;
+const CSW_STATUS_FAILED: u8 = 1;
+const CSW_STATUS_PHASE_ERROR: u8 = 2;
+
+/// Command block wrapper sent by the host on the bulk-out endpoint to start a command.
+#[derive(Copy, Clone, Default)]
+#[repr(C, packed)]
+struct CommandBlockWrapper {
+    signature: u32,
+    tag: u32,
+    data_transfer_length: u32,
+    flags: u8,
+    lun: u8,
+    cb_length: u8,
+    cb: [u8; 16],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for CommandBlockWrapper {}
+
+/// Command status wrapper returned on the bulk-in endpoint once a command completes.
+#[derive(Copy, Clone, Default)]
+#[repr(C, packed)]
+struct CommandStatusWrapper {
+    signature: u32,
+    tag: u32,
+    data_residue: u32,
+    status: u8,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for CommandStatusWrapper {}
+
+/// Progress of the current command on the bulk endpoints.
+enum BulkState {
+    /// Waiting for a command block wrapper.
+    Command,
+    /// Sending the remaining response bytes to the host.
+    DataIn(Vec<u8>),
+    /// Sending disk contents starting at `offset` to the host.
+    ReadDisk { offset: u64, remaining: usize },
+    /// Writing data received from the host to the disk starting at `offset`.
+    WriteDisk { offset: u64, remaining: usize },
+    /// Waiting to send the command status wrapper.
+    Status,
+}
+
+/// Emulated USB mass storage device using the bulk-only transport, backed by a disk image.
+pub struct MassStorageDevice {
+    scsi: ScsiDisk,
+    ctl_ep_state: ControlEndpointState,
+    control_request_setup: UsbRequestSetup,
+    executed: bool,
+    configuration: u8,
+    bulk_state: BulkState,
+    csw: CommandStatusWrapper,
+}
+
+impl MassStorageDevice {
+    /// Create a new mass storage device exposing `disk` as its only logical unit.
+    pub fn new(disk: Box<dyn DiskFile>, read_only: bool) -> MassStorageDevice {
+        MassStorageDevice {
+            scsi: ScsiDisk::new(disk, read_only),
+            ctl_ep_state: ControlEndpointState::SetupStage,
+            control_request_setup: UsbRequestSetup::new(0, 0, 0, 0, 0),
+            executed: false,
+            configuration: 0,
+            bulk_state: BulkState::Command,
+            csw: CommandStatusWrapper::default(),
+        }
+    }
+
+    fn device_descriptor() -> Vec<u8> {
+        let mut buf = Vec::new();
+        append_descriptor(
+            &mut buf,
+            &DeviceDescriptor {
+                bcdUSB: 0x0200,
+                bMaxPacketSize0: 64,
+                idVendor: VENDOR_ID,
+                idProduct: PRODUCT_ID,
+                bcdDevice: 0x0100,
+                iManufacturer: STRING_MANUFACTURER,
+                iProduct: STRING_PRODUCT,
+                iSerialNumber: STRING_SERIAL_NUMBER,
+                bNumConfigurations: 1,
+                ..Default::default()
+            },
+        );
+        buf
+    }
+
+    fn config_descriptor() -> Vec<u8> {
+        let mut buf = Vec::new();
+        append_descriptor(
+            &mut buf,
+            &ConfigDescriptor {
+                bNumInterfaces: 1,
+                bConfigurationValue: 1,
+                // Self powered.
+                bmAttributes: 0xc0,
+                ..Default::default()
+            },
+        );
+        append_descriptor(
+            &mut buf,
+            &InterfaceDescriptor {
+                bNumEndpoints: 2,
+                bInterfaceClass: MASS_STORAGE_CLASS,
+                bInterfaceSubClass: SCSI_TRANSPARENT_SUBCLASS,
+                bInterfaceProtocol: BULK_ONLY_PROTOCOL,
+                ..Default::default()
+            },
+        );
+        for &address in &[0x80 | BULK_IN_ENDPOINT, BULK_OUT_ENDPOINT] {
+            append_descriptor(
+                &mut buf,
+                &EndpointDescriptor {
+                    bEndpointAddress: address,
+                    // Bulk.
+                    bmAttributes: 0x02,
+                    wMaxPacketSize: BULK_MAX_PACKET_SIZE,
+                    bInterval: 0,
+                },
+            );
+        }
+        // Fill in wTotalLength now that the size of the whole hierarchy is known.
+        let total_len = (buf.len() as u16).to_le_bytes();
+        buf[2..4].copy_from_slice(&total_len);
+        buf
+    }
+
+    fn get_string_descriptor(index: u8) -> Option<Vec<u8>> {
+        let string = match index {
+            0 => return Some(string_descriptor(&[LANGUAGE_ID])),
+            STRING_MANUFACTURER => "crosvm",
+            STRING_PRODUCT => "crosvm USB storage",
+            // Bulk-only transport requires a serial number of at least 12 hex digits.
+            STRING_SERIAL_NUMBER => "000000000001",
+            _ => return None,
+        };
+        let units: Vec<u16> = string.encode_utf16().collect();
+        Some(string_descriptor(&units))
+    }
+
+    fn get_descriptor(&self, value: u16) -> Option<Vec<u8>> {
+        let descriptor_type = (value >> 8) as u8;
+        let index = value as u8;
+        match descriptor_type {
+            t if t == DescriptorType::Device as u8 => Some(Self::device_descriptor()),
+            t if t == DescriptorType::Configuration as u8 => Some(Self::config_descriptor()),
+            STRING_DESCRIPTOR_TYPE => Self::get_string_descriptor(index),
+            _ => None,
+        }
+    }
+
+    // Handles the control request in `self.control_request_setup`. Returns the data to send to
+    // the host for device to host requests, an empty vector for host to device requests, or
+    // `None` if the request is not supported.
+    fn handle_control_request(&mut self) -> Option<Vec<u8>> {
+        let setup = self.control_request_setup;
+        match setup.get_type() {
+            ControlRequestType::Standard => match setup.get_standard_request() {
+                Some(StandardControlRequest::GetDescriptor) => self.get_descriptor(setup.value),
+                Some(StandardControlRequest::GetConfiguration) => Some(vec![self.configuration]),
+                Some(StandardControlRequest::SetConfiguration) => {
+                    self.configuration = setup.value as u8;
+                    usb_debug!("mass storage set config {}", self.configuration);
+                    self.reset_bulk_state();
+                    Some(vec![])
+                }
+                Some(StandardControlRequest::GetStatus) => Some(vec![0, 0]),
+                Some(StandardControlRequest::GetInterface) => Some(vec![0]),
+                Some(StandardControlRequest::SetAddress)
+                | Some(StandardControlRequest::SetInterface)
+                | Some(StandardControlRequest::ClearFeature)
+                | Some(StandardControlRequest::SetFeature) => Some(vec![]),
+                _ => None,
+            },
+            ControlRequestType::Class => match setup.request {
+                // Only logical unit 0 exists.
+                GET_MAX_LUN => Some(vec![0]),
+                BULK_ONLY_MASS_STORAGE_RESET => {
+                    usb_debug!("mass storage bulk-only reset");
+                    self.reset_bulk_state();
+                    Some(vec![])
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn execute_control_transfer(
+        &mut self,
+        xhci_transfer: &XhciTransfer,
+        buffer: Option<ScatterGatherBuffer>,
+    ) -> Result<()> {
+        let (status, len) = match self.handle_control_request() {
+            Some(data) => {
+                let len = match buffer {
+                    Some(buffer)
+                        if self.control_request_setup.get_direction()
+                            == ControlRequestDataPhaseTransferDirection::DeviceToHost =>
+                    {
+                        let len = min(data.len(), self.control_request_setup.length as usize);
+                        buffer.write(&data[..len]).map_err(Error::WriteBuffer)?
+                    }
+                    _ => 0,
+                };
+                (TransferStatus::Completed, len as u32)
+            }
+            None => {
+                let request_type = self.control_request_setup.request_type;
+                let request = self.control_request_setup.request;
+                warn!(
+                    "unsupported mass storage control request type {:#x} request {:#x}",
+                    request_type, request
+                );
+                (TransferStatus::Error, 0)
+            }
+        };
+        xhci_transfer
+            .on_transfer_complete(&status, len)
+            .map_err(Error::TransferComplete)
+    }
+
+    fn handle_control_transfer(&mut self, transfer: XhciTransfer) -> Result<()> {
+        match transfer
+            .get_transfer_type()
+            .map_err(Error::GetXhciTransferType)?
+        {
+            XhciTransferType::SetupStage(setup) => {
+                if self.ctl_ep_state != ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                self.control_request_setup = setup;
+                transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+                self.ctl_ep_state = ControlEndpointState::DataStage;
+            }
+            XhciTransferType::DataStage(buffer) => {
+                if self.ctl_ep_state != ControlEndpointState::DataStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                self.execute_control_transfer(&transfer, Some(buffer))?;
+                self.executed = true;
+                self.ctl_ep_state = ControlEndpointState::StatusStage;
+            }
+            XhciTransferType::StatusStage => {
+                if self.ctl_ep_state == ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                if self.executed {
+                    transfer
+                        .on_transfer_complete(&TransferStatus::Completed, 0)
+                        .map_err(Error::TransferComplete)?;
+                } else {
+                    self.execute_control_transfer(&transfer, None)?;
+                }
+                self.executed = false;
+                self.ctl_ep_state = ControlEndpointState::SetupStage;
+            }
+            _ => {
+                error!("Non control (could be noop) transfer sent to control endpoint.");
+                transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn reset_bulk_state(&mut self) {
+        self.bulk_state = BulkState::Command;
+    }
+
+    // Parses a command block wrapper, runs the command and sets up the data and status phases.
+    fn handle_command(&mut self, data: &[u8]) {
+        let cbw = match CommandBlockWrapper::from_slice(data) {
+            Some(cbw) if cbw.signature == CBW_SIGNATURE => *cbw,
+            _ => {
+                warn!("invalid mass storage command block wrapper");
+                return;
+            }
+        };
+        let data_len = cbw.data_transfer_length as usize;
+        let data_in = cbw.flags & CBW_FLAG_DATA_IN != 0;
+        self.csw = CommandStatusWrapper {
+            signature: CSW_SIGNATURE,
+            tag: cbw.tag,
+            data_residue: cbw.data_transfer_length,
+            status: CSW_STATUS_PASSED,
+        };
+
+        let phase = if cbw.lun == 0 {
+            let cb_len = min(cbw.cb_length as usize, cbw.cb.len());
+            self.scsi.execute(&cbw.cb[..cb_len])
+        } else {
+            self.scsi.fail_unsupported_lun();
+            None
+        };
+        let phase = match phase {
+            Some(phase) => phase,
+            None => {
+                self.csw.status = CSW_STATUS_FAILED;
+                DataPhase::None
+            }
+        };
+
+        // The host always runs a data phase of `data_len` bytes in the direction it chose, so the
+        // device must follow it even when the command has less (or nothing) to transfer.
+        if data_len == 0 {
+            if phase != DataPhase::None {
+                self.csw.status = CSW_STATUS_PHASE_ERROR;
+            }
+            self.bulk_state = BulkState::Status;
+            return;
+        }
+        self.bulk_state = match phase {
+            DataPhase::In(mut response) if data_in => {
+                response.truncate(data_len);
+                BulkState::DataIn(response)
+            }
+            DataPhase::Read { offset, len } if data_in => BulkState::ReadDisk {
+                offset,
+                remaining: min(len, data_len),
+            },
+            DataPhase::Write { offset, len } if !data_in => BulkState::WriteDisk {
+                offset,
+                remaining: min(len, data_len),
+            },
+            DataPhase::None => {
+                if data_in {
+                    BulkState::DataIn(Vec::new())
+                } else {
+                    // Data sent by the host is dropped while waiting to send the status.
+                    BulkState::Status
+                }
+            }
+            _ => {
+                self.csw.status = CSW_STATUS_PHASE_ERROR;
+                if data_in {
+                    BulkState::DataIn(Vec::new())
+                } else {
+                    BulkState::Status
+                }
+            }
+        };
+    }
+
+    fn handle_bulk_out(&mut self, buffer: &ScatterGatherBuffer) -> Result<u32> {
+        let mut data = vec![0u8; buffer.len().map_err(Error::BufferLen)?];
+        buffer.read(&mut data).map_err(Error::ReadBuffer)?;
+        match mem::replace(&mut self.bulk_state, BulkState::Command) {
+            BulkState::Command => self.handle_command(&data),
+            BulkState::WriteDisk { offset, remaining } => {
+                let len = min(data.len(), remaining);
+                self.csw.data_residue = self.csw.data_residue.saturating_sub(len as u32);
+                if self.scsi.write(offset, &mut data[..len]).is_err() {
+                    self.csw.status = CSW_STATUS_FAILED;
+                    self.bulk_state = BulkState::Status;
+                } else if len == remaining {
+                    self.bulk_state = BulkState::Status;
+                } else {
+                    self.bulk_state = BulkState::WriteDisk {
+                        offset: offset + len as u64,
+                        remaining: remaining - len,
+                    };
+                }
+            }
+            state => {
+                usb_debug!("dropping unexpected mass storage bulk out data");
+                self.bulk_state = state;
+            }
+        }
+        Ok(data.len() as u32)
+    }
+
+    fn handle_bulk_in(&mut self, buffer: &ScatterGatherBuffer) -> Result<u32> {
+        let buffer_len = buffer.len().map_err(Error::BufferLen)?;
+        let len = match mem::replace(&mut self.bulk_state, BulkState::Command) {
+            BulkState::DataIn(mut response) => {
+                let len = min(buffer_len, response.len());
+                buffer.write(&response[..len]).map_err(Error::WriteBuffer)?;
+                self.csw.data_residue = self.csw.data_residue.saturating_sub(len as u32);
+                // A short packet ends the data phase.
+                self.bulk_state = if len == buffer_len && len < response.len() {
+                    BulkState::DataIn(response.split_off(len))
+                } else {
+                    BulkState::Status
+                };
+                len
+            }
+            BulkState::ReadDisk { offset, remaining } => {
+                let len = min(buffer_len, remaining);
+                let mut data = vec![0u8; len];
+                if self.scsi.read(offset, &mut data).is_err() {
+                    self.csw.status = CSW_STATUS_FAILED;
+                    self.bulk_state = BulkState::Status;
+                    0
+                } else {
+                    buffer.write(&data).map_err(Error::WriteBuffer)?;
+                    self.csw.data_residue = self.csw.data_residue.saturating_sub(len as u32);
+                    self.bulk_state = if len == remaining {
+                        BulkState::Status
+                    } else {
+                        BulkState::ReadDisk {
+                            offset: offset + len as u64,
+                            remaining: remaining - len,
+                        }
+                    };
+                    len
+                }
+            }
+            BulkState::Status => buffer
+                .write(self.csw.as_slice())
+                .map_err(Error::WriteBuffer)?,
+            BulkState::Command => {
+                warn!("mass storage bulk in transfer without a pending command");
+                0
+            }
+            state => {
+                warn!("mass storage bulk in transfer during data out phase");
+                self.bulk_state = state;
+                0
+            }
+        };
+        Ok(len as u32)
+    }
+
+    fn submit_transfer_helper(&mut self, transfer: XhciTransfer) -> Result<()> {
+        if transfer.get_endpoint_number() == 0 {
+            return self.handle_control_transfer(transfer);
+        }
+        let buffer = match transfer
+            .get_transfer_type()
+            .map_err(Error::GetXhciTransferType)?
+        {
+            XhciTransferType::Normal(buffer) => buffer,
+            XhciTransferType::Noop => {
+                return transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete);
+            }
+            _ => {
+                warn!("unexpected transfer type on mass storage bulk endpoint");
+                return transfer
+                    .on_transfer_complete(&TransferStatus::Error, 0)
+                    .map_err(Error::TransferComplete);
+            }
+        };
+        let len = match (transfer.get_endpoint_number(), transfer.get_transfer_dir()) {
+            (BULK_OUT_ENDPOINT, TransferDirection::Out) => self.handle_bulk_out(&buffer)?,
+            (BULK_IN_ENDPOINT, TransferDirection::In) => self.handle_bulk_in(&buffer)?,
+            _ => {
+                warn!("Could not find endpoint for transfer");
+                return transfer
+                    .on_transfer_complete(&TransferStatus::Error, 0)
+                    .map_err(Error::TransferComplete);
+            }
+        };
+        transfer
+            .on_transfer_complete(&TransferStatus::Completed, len)
+            .map_err(Error::TransferComplete)
+    }
+}
+
+impl XhciBackendDevice for MassStorageDevice {
+    fn get_backend_type(&self) -> BackendType {
+        BackendType::Usb2
+    }
+
+    fn get_vid(&self) -> u16 {
+        VENDOR_ID
+    }
+
+    fn get_pid(&self) -> u16 {
+        PRODUCT_ID
+    }
+
+    fn submit_transfer(&mut self, transfer: XhciTransfer) -> std::result::Result<(), ()> {
+        self.submit_transfer_helper(transfer).map_err(|e| {
+            error!("failed to submit mass storage transfer: {}", e);
+        })
+    }
+
+    fn set_address(&mut self, _address: UsbDeviceAddress) {
+        usb_debug!(
+            "Set address control transfer is received with address: {}",
+            _address
+        );
+    }
+
+    fn reset(&mut self) -> std::result::Result<(), ()> {
+        usb_debug!("resetting mass storage device");
+        self.ctl_ep_state = ControlEndpointState::SetupStage;
+        self.executed = false;
+        self.configuration = 0;
+        self.reset_bulk_state();
+        Ok(())
+    }
+}
+
+// Appends `descriptor`, preceded by the standard descriptor header, to `buf`.
+fn append_descriptor<T: Descriptor + DataInit>(buf: &mut Vec<u8>, descriptor: &T) {
+    let header = DescriptorHeader {
+        bLength: (mem::size_of::<DescriptorHeader>() + mem::size_of::<T>()) as u8,
+        bDescriptorType: T::descriptor_type() as u8,
+    };
+    buf.extend_from_slice(header.as_slice());
+    buf.extend_from_slice(descriptor.as_slice());
+}
+
+// Builds a string descriptor holding the given UTF-16 code units.
+fn string_descriptor(units: &[u16]) -> Vec<u8> {
+    let mut buf = vec![(2 + units.len() * 2) as u8, STRING_DESCRIPTOR_TYPE];
+    for unit in units {
+        buf.extend_from_slice(&unit.to_le_bytes());
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_descriptor_layout() {
+        let config = MassStorageDevice::config_descriptor();
+        // Configuration, interface and two endpoint descriptors.
+        assert_eq!(config.len(), 9 + 9 + 7 + 7);
+        assert_eq!(
+            u16::from_le_bytes([config[2], config[3]]) as usize,
+            config.len()
+        );
+        assert_eq!(&config[14..17], &[0x08, 0x06, 0x50]);
+        assert_eq!(config[20], 0x81);
+        assert_eq!(config[27], 0x02);
+    }
+
+    #[test]
+    fn wrapper_sizes() {
+        assert_eq!(mem::size_of::<CommandBlockWrapper>(), 31);
+        assert_eq!(mem::size_of::<CommandStatusWrapper>(), 13);
+    }
+}
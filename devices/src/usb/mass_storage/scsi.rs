@@ -0,0 +1,326 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use base::error;
+use data_model::VolatileSlice;
+use disk::DiskFile;
+
+use crate::scsi::{
+    mode_sense, read_capacity_10, report_luns, Inquiry, Sense, INQUIRY, MODE_SENSE_10,
+    MODE_SENSE_6, PREVENT_ALLOW_MEDIUM_REMOVAL, READ_10, READ_CAPACITY_10, REPORT_LUNS,
+    REQUEST_SENSE, START_STOP_UNIT, SYNCHRONIZE_CACHE_10, TEST_UNIT_READY, VERIFY_10, WRITE_10,
+};
+
+/// Logical block size reported to the guest.
+pub const BLOCK_SIZE: u64 = 512;
+
+const INQUIRY_PRODUCT: &[u8; 16] = b"USB STORAGE     ";
+
+/// The data phase requested by a successfully executed command.
+#[derive(Debug, PartialEq)]
+pub enum DataPhase {
+    /// The command has no data phase.
+    None,
+    /// The given bytes should be sent to the host.
+    In(Vec<u8>),
+    /// `len` bytes starting at byte `offset` of the disk should be sent to the host.
+    Read { offset: u64, len: usize },
+    /// `len` bytes from the host should be written starting at byte `offset` of the disk.
+    Write { offset: u64, len: usize },
+}
+
+/// A single logical unit SCSI direct-access block device with removable medium.
+pub struct ScsiDisk {
+    disk: Box<dyn DiskFile>,
+    read_only: bool,
+    medium_present: bool,
+    sense: Sense,
+}
+
+impl ScsiDisk {
+    pub fn new(disk: Box<dyn DiskFile>, read_only: bool) -> ScsiDisk {
+        ScsiDisk {
+            disk,
+            read_only,
+            medium_present: true,
+            sense: Sense::NO_SENSE,
+        }
+    }
+
+    /// Executes the command descriptor block `cdb`. Returns the data phase of the command, or
+    /// `None` if the command failed, in which case the reason is reported by the next REQUEST
+    /// SENSE.
+    pub fn execute(&mut self, cdb: &[u8]) -> Option<DataPhase> {
+        match self.execute_inner(cdb) {
+            Ok(phase) => Some(phase),
+            Err(sense) => {
+                self.sense = sense;
+                None
+            }
+        }
+    }
+
+    /// Fails the current command because the host addressed a logical unit other than 0.
+    pub fn fail_unsupported_lun(&mut self) {
+        self.sense = Sense::LUN_NOT_SUPPORTED;
+    }
+
+    /// Reads disk contents at `offset` for the data phase of a READ command.
+    pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), ()> {
+        self.disk
+            .read_exact_at_volatile(VolatileSlice::new(buf), offset)
+            .map_err(|e| {
+                error!("failed to read mass storage disk: {}", e);
+                self.sense = Sense::UNRECOVERED_READ_ERROR;
+            })
+    }
+
+    /// Writes disk contents at `offset` for the data phase of a WRITE command.
+    pub fn write(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), ()> {
+        self.disk
+            .write_all_at_volatile(VolatileSlice::new(buf), offset)
+            .map_err(|e| {
+                error!("failed to write mass storage disk: {}", e);
+                self.sense = Sense::WRITE_ERROR;
+            })
+    }
+
+    fn num_blocks(&self) -> u64 {
+        match self.disk.get_len() {
+            Ok(len) => len / BLOCK_SIZE,
+            Err(e) => {
+                error!("failed to get mass storage disk length: {}", e);
+                0
+            }
+        }
+    }
+
+    fn check_medium(&self) -> Result<(), Sense> {
+        if self.medium_present {
+            Ok(())
+        } else {
+            Err(Sense::MEDIUM_NOT_PRESENT)
+        }
+    }
+
+    // Returns the byte offset and length of the block range addressed by a READ(10), WRITE(10)
+    // or VERIFY(10) command.
+    fn block_range(&self, cdb: &[u8]) -> Result<(u64, usize), Sense> {
+        if cdb.len() < 10 {
+            return Err(Sense::INVALID_FIELD_IN_CDB);
+        }
+        let lba = u32::from_be_bytes([cdb[2], cdb[3], cdb[4], cdb[5]]) as u64;
+        let blocks = u16::from_be_bytes([cdb[7], cdb[8]]) as u64;
+        if lba + blocks > self.num_blocks() {
+            return Err(Sense::LBA_OUT_OF_RANGE);
+        }
+        Ok((lba * BLOCK_SIZE, (blocks * BLOCK_SIZE) as usize))
+    }
+
+    fn inquiry(&self, cdb: &[u8]) -> Result<DataPhase, Sense> {
+        // Vital product data pages are not supported.
+        if cdb.len() < 6 || cdb[1] & 0x01 != 0 {
+            return Err(Sense::INVALID_FIELD_IN_CDB);
+        }
+        let inquiry = Inquiry {
+            product: INQUIRY_PRODUCT,
+            removable: true,
+            command_queuing: false,
+        };
+        let mut data = inquiry.to_bytes();
+        data.truncate(cdb[4] as usize);
+        Ok(DataPhase::In(data))
+    }
+
+    fn request_sense(&mut self, cdb: &[u8]) -> Result<DataPhase, Sense> {
+        if cdb.len() < 6 {
+            return Err(Sense::INVALID_FIELD_IN_CDB);
+        }
+        let mut data = self.sense.to_bytes().to_vec();
+        data.truncate(cdb[4] as usize);
+        self.sense = Sense::NO_SENSE;
+        Ok(DataPhase::In(data))
+    }
+
+    fn mode_sense(&self, cdb: &[u8]) -> Result<DataPhase, Sense> {
+        let alloc_len = if cdb[0] == MODE_SENSE_6 {
+            if cdb.len() < 6 {
+                return Err(Sense::INVALID_FIELD_IN_CDB);
+            }
+            cdb[4] as usize
+        } else {
+            if cdb.len() < 10 {
+                return Err(Sense::INVALID_FIELD_IN_CDB);
+            }
+            u16::from_be_bytes([cdb[7], cdb[8]]) as usize
+        };
+        // No mode pages are reported, only the header with the write protect bit.
+        let mut data = mode_sense(cdb[0], self.read_only, &[], &[]);
+        data.truncate(alloc_len);
+        Ok(DataPhase::In(data))
+    }
+
+    fn read_capacity(&self) -> Result<DataPhase, Sense> {
+        self.check_medium()?;
+        Ok(DataPhase::In(read_capacity_10(
+            self.num_blocks(),
+            BLOCK_SIZE as u32,
+        )))
+    }
+
+    fn start_stop_unit(&mut self, cdb: &[u8]) -> Result<DataPhase, Sense> {
+        if cdb.len() < 6 {
+            return Err(Sense::INVALID_FIELD_IN_CDB);
+        }
+        let start = cdb[4] & 0x01 != 0;
+        let load_eject = cdb[4] & 0x02 != 0;
+        if load_eject {
+            self.medium_present = start;
+        }
+        Ok(DataPhase::None)
+    }
+
+    fn execute_inner(&mut self, cdb: &[u8]) -> Result<DataPhase, Sense> {
+        let opcode = match cdb.first() {
+            Some(&opcode) => opcode,
+            None => return Err(Sense::INVALID_OPCODE),
+        };
+        match opcode {
+            TEST_UNIT_READY => {
+                self.check_medium()?;
+                Ok(DataPhase::None)
+            }
+            REQUEST_SENSE => self.request_sense(cdb),
+            INQUIRY => self.inquiry(cdb),
+            MODE_SENSE_6 | MODE_SENSE_10 => self.mode_sense(cdb),
+            START_STOP_UNIT => self.start_stop_unit(cdb),
+            PREVENT_ALLOW_MEDIUM_REMOVAL => Ok(DataPhase::None),
+            READ_CAPACITY_10 => self.read_capacity(),
+            REPORT_LUNS => {
+                if cdb.len() < 12 {
+                    return Err(Sense::INVALID_FIELD_IN_CDB);
+                }
+                // The device only has logical unit 0.
+                let alloc_len = u32::from_be_bytes([cdb[6], cdb[7], cdb[8], cdb[9]]);
+                let mut data = report_luns(Some(0));
+                data.truncate(alloc_len as usize);
+                Ok(DataPhase::In(data))
+            }
+            READ_10 => {
+                self.check_medium()?;
+                let (offset, len) = self.block_range(cdb)?;
+                Ok(DataPhase::Read { offset, len })
+            }
+            WRITE_10 => {
+                self.check_medium()?;
+                if self.read_only {
+                    return Err(Sense::WRITE_PROTECTED);
+                }
+                let (offset, len) = self.block_range(cdb)?;
+                Ok(DataPhase::Write { offset, len })
+            }
+            VERIFY_10 => {
+                self.check_medium()?;
+                self.block_range(cdb)?;
+                Ok(DataPhase::None)
+            }
+            SYNCHRONIZE_CACHE_10 => {
+                self.check_medium()?;
+                self.disk.fsync().map_err(|e| {
+                    error!("failed to sync mass storage disk: {}", e);
+                    Sense::WRITE_ERROR
+                })?;
+                Ok(DataPhase::None)
+            }
+            _ => Err(Sense::INVALID_OPCODE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempfile;
+
+    fn scsi_disk(blocks: u64, read_only: bool) -> ScsiDisk {
+        let f = tempfile().unwrap();
+        f.set_len(blocks * BLOCK_SIZE).unwrap();
+        ScsiDisk::new(Box::new(f), read_only)
+    }
+
+    fn sense(disk: &mut ScsiDisk) -> Sense {
+        match disk.execute(&[REQUEST_SENSE, 0, 0, 0, 18, 0]) {
+            Some(DataPhase::In(data)) => Sense {
+                key: data[2],
+                asc: data[12],
+                ascq: data[13],
+            },
+            r => panic!("unexpected request sense result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn read_capacity() {
+        let mut disk = scsi_disk(8, false);
+        assert_eq!(
+            disk.execute(&[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Some(DataPhase::In(vec![0, 0, 0, 7, 0, 0, 2, 0]))
+        );
+    }
+
+    #[test]
+    fn read_write_range() {
+        let mut disk = scsi_disk(8, false);
+        assert_eq!(
+            disk.execute(&[READ_10, 0, 0, 0, 0, 2, 0, 0, 4, 0]),
+            Some(DataPhase::Read {
+                offset: 2 * BLOCK_SIZE,
+                len: 4 * BLOCK_SIZE as usize
+            })
+        );
+        assert_eq!(disk.execute(&[WRITE_10, 0, 0, 0, 0, 7, 0, 0, 2, 0]), None);
+        assert_eq!(sense(&mut disk), Sense::LBA_OUT_OF_RANGE);
+        assert_eq!(sense(&mut disk), Sense::NO_SENSE);
+
+        let mut data = [0xa5u8; BLOCK_SIZE as usize];
+        disk.write(BLOCK_SIZE, &mut data).unwrap();
+        let mut read_back = [0u8; BLOCK_SIZE as usize];
+        disk.read(BLOCK_SIZE, &mut read_back).unwrap();
+        assert_eq!(&data[..], &read_back[..]);
+    }
+
+    #[test]
+    fn write_protected() {
+        let mut disk = scsi_disk(8, true);
+        assert_eq!(disk.execute(&[WRITE_10, 0, 0, 0, 0, 0, 0, 0, 1, 0]), None);
+        assert_eq!(sense(&mut disk), Sense::WRITE_PROTECTED);
+        assert_eq!(
+            disk.execute(&[MODE_SENSE_6, 0, 0x3f, 0, 4, 0]),
+            Some(DataPhase::In(vec![3, 0, 0x80, 0]))
+        );
+    }
+
+    #[test]
+    fn eject() {
+        let mut disk = scsi_disk(8, false);
+        assert_eq!(
+            disk.execute(&[TEST_UNIT_READY, 0, 0, 0, 0, 0]),
+            Some(DataPhase::None)
+        );
+        assert_eq!(
+            disk.execute(&[START_STOP_UNIT, 0, 0, 0, 0x02, 0]),
+            Some(DataPhase::None)
+        );
+        assert_eq!(disk.execute(&[TEST_UNIT_READY, 0, 0, 0, 0, 0]), None);
+        assert_eq!(sense(&mut disk), Sense::MEDIUM_NOT_PRESENT);
+        assert_eq!(
+            disk.execute(&[START_STOP_UNIT, 0, 0, 0, 0x03, 0]),
+            Some(DataPhase::None)
+        );
+        assert_eq!(
+            disk.execute(&[TEST_UNIT_READY, 0, 0, 0, 0, 0]),
+            Some(DataPhase::None)
+        );
+    }
+}
@@ -5,4 +5,5 @@
 #[macro_use]
 mod log;
 pub mod host_backend;
+pub mod mass_storage;
 pub mod xhci;
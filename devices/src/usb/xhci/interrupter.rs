@@ -8,9 +8,12 @@ use super::xhci_abi::{
     TrbCast, TrbCompletionCode, TrbType,
 };
 use super::xhci_regs::*;
+use crate::pci::MsixConfig;
 use crate::register_space::Register;
 use base::{Error as SysError, Event};
 use std::fmt::{self, Display};
+use std::sync::Arc;
+use sync::Mutex;
 use vm_memory::{GuestAddress, GuestMemory};
 
 #[derive(Debug)]
@@ -41,6 +44,7 @@ impl Display for Error {
 /// through interrupter.
 pub struct Interrupter {
     interrupt_evt: Event,
+    msix_config: Option<Arc<Mutex<MsixConfig>>>,
     usbsts: Register<u32>,
     iman: Register<u32>,
     erdp: Register<u64>,
@@ -52,10 +56,17 @@ pub struct Interrupter {
 }
 
 impl Interrupter {
-    /// Create a new interrupter.
-    pub fn new(mem: GuestMemory, irq_evt: Event, regs: &XhciRegs) -> Self {
+    /// Create a new interrupter. If `msix_config` is given and the guest enables MSI-X, interrupts
+    /// are delivered through MSI-X vector 0 instead of `irq_evt`.
+    pub fn new(
+        mem: GuestMemory,
+        irq_evt: Event,
+        msix_config: Option<Arc<Mutex<MsixConfig>>>,
+        regs: &XhciRegs,
+    ) -> Self {
         Interrupter {
             interrupt_evt: irq_evt,
+            msix_config,
             usbsts: regs.usbsts.clone(),
             iman: regs.iman.clone(),
             erdp: regs.erdp.clone(),
@@ -184,6 +195,14 @@ impl Interrupter {
         self.usbsts.set_bits(USB_STS_EVENT_INTERRUPT);
         self.iman.set_bits(IMAN_INTERRUPT_PENDING);
         self.erdp.set_bits(ERDP_EVENT_HANDLER_BUSY);
+        if let Some(msix_config) = &self.msix_config {
+            let mut msix_config = msix_config.lock();
+            if msix_config.enabled() {
+                // There is only one interrupter, so it always uses the first vector.
+                msix_config.trigger(0);
+                return Ok(());
+            }
+        }
         self.interrupt_evt.write(1).map_err(Error::SendInterrupt)
     }
 
@@ -10,6 +10,7 @@ use super::ring_buffer_stop_cb::RingBufferStopCallback;
 use super::usb_hub::UsbHub;
 use super::xhci_backend_device_provider::XhciBackendDeviceProvider;
 use super::xhci_regs::*;
+use crate::pci::MsixConfig;
 use crate::usb::host_backend::host_backend_device_provider::HostBackendDeviceProvider;
 use crate::utils::{Error as UtilsError, EventLoop, FailHandle};
 use base::{error, Event};
@@ -86,12 +87,18 @@ impl Xhci {
         device_provider: HostBackendDeviceProvider,
         irq_evt: Event,
         irq_resample_evt: Event,
+        msix_config: Option<Arc<Mutex<MsixConfig>>>,
         regs: XhciRegs,
     ) -> Result<Arc<Self>> {
         let (event_loop, join_handle) =
             EventLoop::start("xhci".to_string(), Some(fail_handle.clone()))
                 .map_err(Error::StartEventLoop)?;
-        let interrupter = Arc::new(Mutex::new(Interrupter::new(mem.clone(), irq_evt, &regs)));
+        let interrupter = Arc::new(Mutex::new(Interrupter::new(
+            mem.clone(),
+            irq_evt,
+            msix_config,
+            &regs,
+        )));
         let event_loop = Arc::new(event_loop);
         let intr_resample_handler =
             IntrResampleHandler::start(&event_loop, interrupter.clone(), irq_resample_evt)
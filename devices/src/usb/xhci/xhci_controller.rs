@@ -3,8 +3,9 @@
 // found in the LICENSE file.
 
 use crate::pci::{
-    PciAddress, PciBarConfiguration, PciClassCode, PciConfiguration, PciDevice, PciDeviceError,
-    PciHeaderType, PciInterruptPin, PciProgrammingInterface, PciSerialBusSubClass,
+    MsixCap, MsixConfig, PciAddress, PciBarConfiguration, PciClassCode, PciConfiguration,
+    PciDevice, PciDeviceError, PciHeaderType, PciInterruptPin, PciProgrammingInterface,
+    PciSerialBusSubClass,
 };
 use crate::register_space::{Register, RegisterSpace};
 use crate::usb::host_backend::host_backend_device_provider::HostBackendDeviceProvider;
@@ -12,15 +13,31 @@ use crate::usb::xhci::xhci::Xhci;
 use crate::usb::xhci::xhci_backend_device_provider::XhciBackendDeviceProvider;
 use crate::usb::xhci::xhci_regs::{init_xhci_mmio_space_and_regs, XhciRegs};
 use crate::utils::FailHandle;
+use crate::Suspendable;
 use base::{error, Event, RawDescriptor};
 use resources::{Alloc, MmioType, SystemAllocator};
 use std::mem;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use sync::Mutex;
+use vm_control::VmIrqRequestSocket;
 use vm_memory::GuestMemory;
 
+// The controller doesn't pose as a real part, whose quirks the guest drivers would apply to it.
+// They bind to it by its class code.
+const PCI_VENDOR_ID_GOOGLE: u16 = 0x1ae0;
+const XHCI_DEVICE_ID: u16 = 0x0100;
+
 const XHCI_BAR0_SIZE: u64 = 0x10000;
 
+// The MSI-X table and PBA live in BAR0, in the gap between the runtime registers and the extended
+// capabilities. A single vector serves the single interrupter.
+const XHCI_MSIX_VECTORS: u16 = 1;
+const XHCI_MSIX_TABLE_OFFSET: u64 = 0x8000;
+const XHCI_MSIX_TABLE_SIZE: u64 = XHCI_MSIX_VECTORS as u64 * 16;
+const XHCI_MSIX_PBA_OFFSET: u64 = 0x9000;
+const XHCI_MSIX_PBA_SIZE: u64 = 8;
+
 #[derive(Clone, Copy)]
 enum UsbControllerProgrammingInterface {
     Usb3HostController = 0x30,
@@ -97,14 +114,20 @@ pub struct XhciController {
     pci_address: Option<PciAddress>,
     mem: GuestMemory,
     state: XhciControllerState,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    msix_cap_reg_idx: Option<usize>,
 }
 
 impl XhciController {
     /// Create new xhci controller.
-    pub fn new(mem: GuestMemory, usb_provider: HostBackendDeviceProvider) -> Self {
+    pub fn new(
+        mem: GuestMemory,
+        usb_provider: HostBackendDeviceProvider,
+        msi_device_socket: VmIrqRequestSocket,
+    ) -> Self {
         let config_regs = PciConfiguration::new(
-            0x01b73, // fresco logic, (google = 0x1ae0)
-            0x1000,  // fresco logic pdk. This chip has broken msi. See kernel xhci-pci.c
+            PCI_VENDOR_ID_GOOGLE,
+            XHCI_DEVICE_ID,
             PciClassCode::SerialBusController,
             &PciSerialBusSubClass::USB,
             Some(&UsbControllerProgrammingInterface::Usb3HostController),
@@ -119,6 +142,11 @@ impl XhciController {
             state: XhciControllerState::Created {
                 device_provider: usb_provider,
             },
+            msix_config: Arc::new(Mutex::new(MsixConfig::new(
+                XHCI_MSIX_VECTORS,
+                msi_device_socket,
+            ))),
+            msix_cap_reg_idx: None,
         }
     }
 
@@ -138,6 +166,7 @@ impl XhciController {
                     device_provider,
                     irq_evt,
                     irq_resample_evt,
+                    Some(self.msix_config.clone()),
                     regs,
                 ) {
                     Ok(xhci) => Some(xhci),
@@ -172,7 +201,11 @@ impl PciDevice for XhciController {
 
     fn keep_rds(&self) -> Vec<RawDescriptor> {
         match &self.state {
-            XhciControllerState::Created { device_provider } => device_provider.keep_rds(),
+            XhciControllerState::Created { device_provider } => {
+                let mut rds = device_provider.keep_rds();
+                rds.push(self.msix_config.lock().get_msi_socket());
+                rds
+            }
             _ => {
                 error!("xhci controller is in a wrong state");
                 vec![]
@@ -231,14 +264,37 @@ impl PciDevice for XhciController {
         self.config_regs
             .add_pci_bar(bar0_config)
             .map_err(|e| PciDeviceError::IoRegistrationFailed(bar0_addr, e))?;
+
+        let msix_cap = MsixCap::new(
+            0,
+            XHCI_MSIX_VECTORS,
+            XHCI_MSIX_TABLE_OFFSET as u32,
+            0,
+            XHCI_MSIX_PBA_OFFSET as u32,
+        );
+        let msix_offset = self
+            .config_regs
+            .add_capability(&msix_cap)
+            .map_err(PciDeviceError::CapabilitiesSetup)?;
+        self.msix_cap_reg_idx = Some(msix_offset / 4);
+
         Ok(vec![(bar0_addr, XHCI_BAR0_SIZE)])
     }
 
     fn read_config_register(&self, reg_idx: usize) -> u32 {
-        self.config_regs.read_reg(reg_idx)
+        let data = self.config_regs.read_reg(reg_idx);
+        match self.msix_cap_reg_idx {
+            Some(idx) if idx == reg_idx => self.msix_config.lock().read_msix_capability(data),
+            _ => data,
+        }
     }
 
     fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        if let Some(idx) = self.msix_cap_reg_idx {
+            if idx == reg_idx {
+                self.msix_config.lock().write_msix_capability(offset, data);
+            }
+        }
         (&mut self.config_regs).write_reg(reg_idx, offset, data)
     }
 
@@ -247,10 +303,24 @@ impl PciDevice for XhciController {
         if addr < bar0 || addr > bar0 + XHCI_BAR0_SIZE {
             return;
         }
+        let offset = addr - bar0;
+        if (XHCI_MSIX_TABLE_OFFSET..XHCI_MSIX_TABLE_OFFSET + XHCI_MSIX_TABLE_SIZE).contains(&offset)
+        {
+            self.msix_config
+                .lock()
+                .read_msix_table(offset - XHCI_MSIX_TABLE_OFFSET, data);
+            return;
+        }
+        if (XHCI_MSIX_PBA_OFFSET..XHCI_MSIX_PBA_OFFSET + XHCI_MSIX_PBA_SIZE).contains(&offset) {
+            self.msix_config
+                .lock()
+                .read_pba_entries(offset - XHCI_MSIX_PBA_OFFSET, data);
+            return;
+        }
         match &self.state {
             XhciControllerState::Initialized { mmio, .. } => {
                 // Read bar would still work even if it's already failed.
-                mmio.read(addr - bar0, data);
+                mmio.read(offset, data);
             }
             _ => {
                 error!("xhci controller is in a wrong state");
@@ -263,12 +333,26 @@ impl PciDevice for XhciController {
         if addr < bar0 || addr > bar0 + XHCI_BAR0_SIZE {
             return;
         }
+        let offset = addr - bar0;
+        if (XHCI_MSIX_TABLE_OFFSET..XHCI_MSIX_TABLE_OFFSET + XHCI_MSIX_TABLE_SIZE).contains(&offset)
+        {
+            self.msix_config
+                .lock()
+                .write_msix_table(offset - XHCI_MSIX_TABLE_OFFSET, data);
+            return;
+        }
+        if (XHCI_MSIX_PBA_OFFSET..XHCI_MSIX_PBA_OFFSET + XHCI_MSIX_PBA_SIZE).contains(&offset) {
+            self.msix_config
+                .lock()
+                .write_pba_entries(offset - XHCI_MSIX_PBA_OFFSET, data);
+            return;
+        }
         match &self.state {
             XhciControllerState::Initialized {
                 mmio, fail_handle, ..
             } => {
                 if !fail_handle.failed() {
-                    mmio.write(addr - bar0, data);
+                    mmio.write(offset, data);
                 }
             }
             _ => {
@@ -281,3 +365,5 @@ impl PciDevice for XhciController {
         self.init_when_forked();
     }
 }
+
+impl Suspendable for XhciController {}
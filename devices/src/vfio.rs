@@ -6,7 +6,7 @@ use data_model::vec_with_array_field;
 use std::collections::HashMap;
 use std::ffi::CString;
 use std::fmt;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io;
 use std::mem;
 use std::os::unix::prelude::FileExt;
@@ -46,6 +46,8 @@ pub enum VfioError {
     VfioIrqDisable(Error),
     VfioIrqUnmask(Error),
     VfioIrqMask(Error),
+    SriovReadNumVfs(io::Error),
+    SriovVfNotFound(u16, u16),
 }
 
 impl fmt::Display for VfioError {
@@ -71,6 +73,8 @@ impl fmt::Display for VfioError {
             VfioError::VfioIrqDisable(e) => write!(f, "failed to disable vfio deviece's irq: {}", e),
             VfioError::VfioIrqUnmask(e) => write!(f, "failed to unmask vfio deviece's irq: {}", e),
             VfioError::VfioIrqMask(e) => write!(f, "failed to mask vfio deviece's irq: {}", e),
+            VfioError::SriovReadNumVfs(e) => write!(f, "failed to read the number of enabled SR-IOV VFs: {}", e),
+            VfioError::SriovVfNotFound(index, num_vfs) => write!(f, "SR-IOV VF {} doesn't exist, {} VFs are enabled", index, num_vfs),
         }
     }
 }
@@ -79,6 +83,12 @@ fn get_error() -> Error {
     Error::last()
 }
 
+// Parses a sysfs id file such as "0x8086\n".
+fn read_sysfs_id(path: &Path) -> Option<u16> {
+    let id = fs::read_to_string(path).ok()?;
+    u16::from_str_radix(id.trim().trim_start_matches("0x"), 16).ok()
+}
+
 /// VfioContainer contain multi VfioGroup, and delegate an IOMMU domain table
 pub struct VfioContainer {
     container: File,
@@ -320,6 +330,31 @@ impl AsRawDescriptor for VfioGroup {
     }
 }
 
+/// Returns the number of SR-IOV virtual functions currently enabled on the physical function at
+/// `pf_sysfs_path`. VFs are enabled on the host by writing to the PF's `sriov_numvfs` file.
+pub fn sriov_num_vfs(pf_sysfs_path: &Path) -> Result<u16, VfioError> {
+    let num_vfs = fs::read_to_string(pf_sysfs_path.join("sriov_numvfs"))
+        .map_err(VfioError::SriovReadNumVfs)?;
+    num_vfs
+        .trim()
+        .parse::<u16>()
+        .map_err(|e| VfioError::SriovReadNumVfs(io::Error::new(io::ErrorKind::InvalidData, e)))
+}
+
+/// Returns the sysfs path of SR-IOV virtual function `index` of the physical function at
+/// `pf_sysfs_path`, which can then be passed to `VfioDevice::new`.
+pub fn sriov_vf_path(pf_sysfs_path: &Path, index: u16) -> Result<PathBuf, VfioError> {
+    let num_vfs = sriov_num_vfs(pf_sysfs_path)?;
+    if index >= num_vfs {
+        return Err(VfioError::SriovVfNotFound(index, num_vfs));
+    }
+    // virtfnN is a symlink to the VF's own device directory, whose name is the VF's address.
+    pf_sysfs_path
+        .join(format!("virtfn{}", index))
+        .canonicalize()
+        .map_err(|_| VfioError::InvalidPath)
+}
+
 /// Vfio Irq type used to enable/disable/mask/unmask vfio irq
 pub enum VfioIrqType {
     Intx,
@@ -346,6 +381,8 @@ pub struct VfioDevice {
     group_descriptor: RawDescriptor,
     // vec for vfio device's regions
     regions: Vec<VfioRegion>,
+    // vendor and device id of an SR-IOV virtual function, provided by its physical function
+    virtfn_ids: Option<(u16, u16)>,
 }
 
 impl VfioDevice {
@@ -358,6 +395,10 @@ impl VfioDevice {
         guest_mem: &GuestMemory,
         container: Arc<Mutex<VfioContainer>>,
     ) -> Result<Self, VfioError> {
+        // Resolve symlinks such as a PF's virtfnN, the device is looked up by its own name.
+        let sysfspath = &sysfspath
+            .canonicalize()
+            .map_err(|_| VfioError::InvalidPath)?;
         let mut uuid_path = PathBuf::new();
         uuid_path.push(sysfspath);
         uuid_path.push("iommu_group");
@@ -372,14 +413,53 @@ impl VfioDevice {
         let new_dev = group.get_device(sysfspath)?;
         let dev_regions = Self::get_regions(&new_dev)?;
 
+        // A VF's config space reads 0xffff as vendor and device id, the real ids are kept by
+        // its PF and exposed in sysfs.
+        let virtfn_ids = if sysfspath.join("physfn").exists() {
+            let vendor = read_sysfs_id(&sysfspath.join("vendor"));
+            let device = read_sysfs_id(&sysfspath.join("device"));
+            vendor.and_then(|v| device.map(|d| (v, d)))
+        } else {
+            None
+        };
+
         Ok(VfioDevice {
             dev: new_dev,
             container,
             group_descriptor: group.as_raw_descriptor(),
             regions: dev_regions,
+            virtfn_ids,
         })
     }
 
+    /// Returns the vendor and device id if this device is an SR-IOV virtual function.
+    pub fn virtfn_ids(&self) -> Option<(u16, u16)> {
+        self.virtfn_ids
+    }
+
+    /// Returns the number of interrupts of `irq_type` the device supports. SR-IOV virtual
+    /// functions, for example, have no INTx.
+    pub fn irq_count(&self, irq_type: VfioIrqType) -> u32 {
+        let mut irq_info = vfio_irq_info {
+            argsz: mem::size_of::<vfio_irq_info>() as u32,
+            flags: 0,
+            index: match irq_type {
+                VfioIrqType::Intx => VFIO_PCI_INTX_IRQ_INDEX,
+                VfioIrqType::Msi => VFIO_PCI_MSI_IRQ_INDEX,
+                VfioIrqType::Msix => VFIO_PCI_MSIX_IRQ_INDEX,
+            },
+            count: 0,
+        };
+        // Safe as we are the owner of self and irq_info which are valid value,
+        // and we verify the return value.
+        let ret = unsafe { ioctl_with_mut_ref(self, VFIO_DEVICE_GET_IRQ_INFO(), &mut irq_info) };
+        if ret < 0 {
+            warn!("failed to get vfio device's irq info: {}", get_error());
+            return 0;
+        }
+        irq_info.count
+    }
+
     /// Enable vfio device's irq and associate Irqfd Event with device.
     /// When MSIx is enabled, multi vectors will be supported, so descriptors is vector and the vector
     /// length is the num of MSIx vectors
@@ -0,0 +1,1399 @@
+// Copyright 2017 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use base::{
+    self, error, info, warn, AsRawDescriptor, Error as SysError, Event, PollToken, RawDescriptor,
+    WaitContext,
+};
+use data_model::{DataInit, Le16, Le32, Le64};
+use libc::EINVAL;
+use msg_socket::{MsgError, MsgReceiver, MsgSender};
+use vm_control::{
+    BalloonControlCommand, BalloonControlResponseSocket, BalloonControlResult, BalloonDiagnostics,
+    BalloonEvent, BalloonEventSendSocket, BalloonStats,
+};
+use vm_memory::{GuestAddress, GuestMemory};
+
+use super::{copy_config, DescriptorError, Interrupt, Queue, Reader, VirtioDevice, TYPE_BALLOON};
+use crate::suspendable;
+use crate::{Suspendable, SuspendableError};
+
+#[cfg(any(test, feature = "balloon-testing"))]
+pub mod testing;
+
+#[derive(Debug)]
+pub enum BalloonError {
+    /// Request to adjust memory size can't provide the number of pages requested.
+    NotEnoughPages,
+    /// Failure wriitng the config notification event.
+    WritingConfigEvent(base::Error),
+}
+pub type Result<T> = std::result::Result<T, BalloonError>;
+
+impl Display for BalloonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::BalloonError::*;
+
+        match self {
+            NotEnoughPages => write!(f, "not enough pages"),
+            WritingConfigEvent(e) => write!(f, "failed to write config event: {}", e),
+        }
+    }
+}
+
+// Balloon has five virt IO queues: Inflate, Deflate, Stats, Free Page Hint, and Free Page
+// Reporting.
+const QUEUE_SIZE: u16 = 128;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE];
+
+const VIRTIO_BALLOON_PFN_SHIFT: u32 = 12;
+
+// The feature bitmap for virtio balloon
+const VIRTIO_BALLOON_F_MUST_TELL_HOST: u32 = 0; // Tell before reclaiming pages
+const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1; // Stats reporting enabled
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 2; // Deflate balloon on OOM
+const VIRTIO_BALLOON_F_FREE_PAGE_HINT: u32 = 3; // VQ to report free pages
+const VIRTIO_BALLOON_F_PAGE_POISON: u32 = 4; // Guest is using page poisoning
+const VIRTIO_BALLOON_F_PAGE_REPORTING: u32 = 5; // VQ to hand over free pages
+
+// Special values of free_page_hint_cmd_id. Any other value starts a hint run with that id.
+const VIRTIO_BALLOON_CMD_ID_STOP: u32 = 0; // Driver stops reporting, keeping the hinted pages
+const VIRTIO_BALLOON_CMD_ID_DONE: u32 = 1; // Driver gives the hinted pages back to the guest
+const FIRST_HINT_CMD_ID: u32 = VIRTIO_BALLOON_CMD_ID_DONE + 1;
+
+// The worker stops listening to the command socket after this many receive errors in a row,
+// rather than spinning on a socket that keeps failing.
+const MAX_COMMAND_RECV_FAILURES: u32 = 8;
+
+// virtio_balloon_config is the ballon device configuration space defined by the virtio spec.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_balloon_config {
+    num_pages: Le32,
+    actual: Le32,
+    free_page_hint_cmd_id: Le32,
+    poison_val: Le32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_balloon_config {}
+
+// The state of the balloon saved by `snapshot`.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct BalloonSnapshot {
+    acked_features: Le64,
+    num_pages: Le32,
+    actual_pages: Le32,
+    free_page_hint_cmd_id: Le32,
+    poison_val: Le32,
+}
+
+// It is safe to implement DataInit; all members are simple numbers and any value is valid.
+unsafe impl DataInit for BalloonSnapshot {}
+
+// BalloonConfig is modified by the worker and read from the device thread.
+#[derive(Default)]
+struct BalloonConfig {
+    num_pages: AtomicUsize,
+    actual_pages: AtomicUsize,
+    free_page_hint_cmd_id: AtomicU32,
+    // The value the guest fills free pages with, if it negotiated VIRTIO_BALLOON_F_PAGE_POISON.
+    poison_val: AtomicU32,
+}
+
+// The constants defining stats types in virtio_baloon_stat
+const VIRTIO_BALLOON_S_SWAP_IN: u16 = 0;
+const VIRTIO_BALLOON_S_SWAP_OUT: u16 = 1;
+const VIRTIO_BALLOON_S_MAJFLT: u16 = 2;
+const VIRTIO_BALLOON_S_MINFLT: u16 = 3;
+const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+const VIRTIO_BALLOON_S_CACHES: u16 = 7;
+const VIRTIO_BALLOON_S_HTLB_PGALLOC: u16 = 8;
+const VIRTIO_BALLOON_S_HTLB_PGFAIL: u16 = 9;
+
+// BalloonStat is used to deserialize stats from the stats_queue.
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct BalloonStat {
+    tag: Le16,
+    val: Le64,
+}
+// Safe because it only has data.
+unsafe impl DataInit for BalloonStat {}
+
+impl BalloonStat {
+    fn update_stats(&self, stats: &mut BalloonStats) {
+        let val = Some(self.val.to_native());
+        match self.tag.to_native() {
+            VIRTIO_BALLOON_S_SWAP_IN => stats.swap_in = val,
+            VIRTIO_BALLOON_S_SWAP_OUT => stats.swap_out = val,
+            VIRTIO_BALLOON_S_MAJFLT => stats.major_faults = val,
+            VIRTIO_BALLOON_S_MINFLT => stats.minor_faults = val,
+            VIRTIO_BALLOON_S_MEMFREE => stats.free_memory = val,
+            VIRTIO_BALLOON_S_MEMTOT => stats.total_memory = val,
+            VIRTIO_BALLOON_S_AVAIL => stats.available_memory = val,
+            VIRTIO_BALLOON_S_CACHES => stats.disk_caches = val,
+            VIRTIO_BALLOON_S_HTLB_PGALLOC => stats.hugetlb_allocations = val,
+            VIRTIO_BALLOON_S_HTLB_PGFAIL => stats.hugetlb_failures = val,
+            _ => (),
+        }
+    }
+}
+
+/// Guest memory as seen by the balloon. Besides holding the virtqueues, it gives the pages the
+/// guest puts in the balloon back to the host.
+pub trait BalloonMemory: Send + 'static {
+    /// Returns the memory the virtqueues and their buffers live in.
+    fn guest_memory(&self) -> &GuestMemory;
+
+    /// Tells the host that the `count` bytes at `addr` are no longer needed by the guest.
+    fn dont_need_range(&self, addr: GuestAddress, count: u64) -> vm_memory::Result<()>;
+
+    /// Returns the size of the host pages backing the guest memory at `addr`. `dont_need_range`
+    /// only gives memory back to the host in whole pages of this size.
+    fn backing_page_size(&self, addr: GuestAddress) -> u64;
+}
+
+impl BalloonMemory for GuestMemory {
+    fn guest_memory(&self) -> &GuestMemory {
+        self
+    }
+
+    fn backing_page_size(&self, addr: GuestAddress) -> u64 {
+        GuestMemory::backing_page_size(self, addr).unwrap_or(1 << VIRTIO_BALLOON_PFN_SHIFT)
+    }
+
+    fn dont_need_range(&self, addr: GuestAddress, count: u64) -> vm_memory::Result<()> {
+        self.remove_range(addr, count)
+    }
+}
+
+// Releases the `num_pages` pages starting at `first_pfn` in a single call if it can, and page by
+// page if they don't all lie in one memory region.
+fn release_pages<M: BalloonMemory>(mem: &M, first_pfn: u64, num_pages: u64) {
+    let page_size = 1 << VIRTIO_BALLOON_PFN_SHIFT;
+    let guest_address = GuestAddress(first_pfn << VIRTIO_BALLOON_PFN_SHIFT);
+    if mem
+        .dont_need_range(guest_address, num_pages * page_size)
+        .is_ok()
+    {
+        return;
+    }
+    if num_pages == 1 {
+        warn!("Marking pages unused failed; addr={}", guest_address);
+        return;
+    }
+    for pfn in first_pfn..first_pfn + num_pages {
+        release_pages(mem, pfn, 1);
+    }
+}
+
+struct HugePage {
+    size: u64,
+    // One bit per balloon page in the huge page, set while that page is in the balloon.
+    inflated: Vec<u64>,
+    num_inflated: u64,
+    // Whether the huge page was given back to the host since it was last fully inflated.
+    released: bool,
+}
+
+// Tracks the balloon pages that lie in host huge pages. Giving back part of a huge page doesn't
+// free any host memory, so a huge page is only released once the guest has put every balloon page
+// in it into the balloon.
+#[derive(Default)]
+struct HugePages {
+    // Keyed by the guest address of each huge page that has pages in the balloon.
+    pages: BTreeMap<u64, HugePage>,
+}
+
+impl HugePages {
+    // Records that the balloon page `pfn`, which lies in a host page of `page_size` bytes, is in the
+    // balloon. Returns the address of the huge page if that completed it, so it can be released.
+    fn inflate(&mut self, pfn: u64, page_size: u64) -> Option<GuestAddress> {
+        let addr = pfn << VIRTIO_BALLOON_PFN_SHIFT;
+        let base = addr & !(page_size - 1);
+        let num_pages = page_size >> VIRTIO_BALLOON_PFN_SHIFT;
+        let page = self.pages.entry(base).or_insert_with(|| HugePage {
+            size: page_size,
+            inflated: vec![0; ((num_pages + 63) / 64) as usize],
+            num_inflated: 0,
+            released: false,
+        });
+        let bit = (addr - base) >> VIRTIO_BALLOON_PFN_SHIFT;
+        let (word, mask) = ((bit / 64) as usize, 1 << (bit % 64));
+        if page.inflated[word] & mask == 0 {
+            page.inflated[word] |= mask;
+            page.num_inflated += 1;
+        }
+        if page.num_inflated == num_pages && !page.released {
+            page.released = true;
+            return Some(GuestAddress(base));
+        }
+        None
+    }
+
+    // Records that the balloon page `pfn` was taken back out of the balloon.
+    fn deflate(&mut self, pfn: u64) {
+        let addr = pfn << VIRTIO_BALLOON_PFN_SHIFT;
+        let base = match self.pages.range_mut(..=addr).next_back() {
+            Some((&base, page)) if addr - base < page.size => {
+                let bit = (addr - base) >> VIRTIO_BALLOON_PFN_SHIFT;
+                let (word, mask) = ((bit / 64) as usize, 1 << (bit % 64));
+                if page.inflated[word] & mask != 0 {
+                    page.inflated[word] &= !mask;
+                    page.num_inflated -= 1;
+                    // The guest touching the page faults the whole huge page back in.
+                    page.released = false;
+                }
+                if page.num_inflated > 0 {
+                    return;
+                }
+                base
+            }
+            _ => return,
+        };
+        self.pages.remove(&base);
+    }
+}
+
+struct Worker<M: BalloonMemory = GuestMemory> {
+    interrupt: Interrupt,
+    mem: M,
+    inflate_queue: Queue,
+    deflate_queue: Queue,
+    stats_queue: Queue,
+    stats_desc_index: Option<u16>,
+    // Whether the host is waiting for the stats the driver will put in its next buffer.
+    stats_requested: bool,
+    latest_stats: BalloonStats,
+    free_page_queue: Queue,
+    reporting_queue: Queue,
+    // The id of the hint run started by the host, if one is in progress.
+    hint_cmd_id: Option<u32>,
+    // Whether the driver acknowledged `hint_cmd_id`, so that the hints it sends belong to the run.
+    hinting: bool,
+    hinted_bytes: u64,
+    next_hint_cmd_id: u32,
+    huge_pages: HugePages,
+    config: Arc<BalloonConfig>,
+    command_socket: BalloonControlResponseSocket,
+    // Receive errors on the command socket since the last command received.
+    command_recv_failures: u32,
+    diagnostics: BalloonDiagnostics,
+}
+
+impl<M: BalloonMemory> Worker<M> {
+    fn process_inflate_deflate(&mut self, inflate: bool) -> bool {
+        let queue = if inflate {
+            &mut self.inflate_queue
+        } else {
+            &mut self.deflate_queue
+        };
+
+        let mem = self.mem.guest_memory();
+        // Descriptors are only given back once the pages in them are released, so that pages
+        // from several descriptors can be released together.
+        let mut used_descs = Vec::new();
+        // The first PFN and the number of pages of the run of contiguous pages not yet released.
+        let mut run: Option<(u64, u64)> = None;
+        while let Some(avail_desc) = queue.pop(mem) {
+            let index = avail_desc.index;
+
+            // Deflated pages only matter to huge pages that aren't fully inflated yet.
+            if inflate || !self.huge_pages.pages.is_empty() {
+                let mut reader = match Reader::new(mem.clone(), avail_desc) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        error!("balloon: failed to create reader: {}", e);
+                        used_descs.push(index);
+                        continue;
+                    }
+                };
+                let pfns = match reader.read_remaining_objs::<Le32>() {
+                    Ok(pfns) => pfns,
+                    Err(e) => {
+                        error!("error while reading unused pages: {}", e);
+                        used_descs.push(index);
+                        continue;
+                    }
+                };
+                for pfn in pfns {
+                    let pfn = u64::from(pfn.to_native());
+                    if !inflate {
+                        self.huge_pages.deflate(pfn);
+                        continue;
+                    }
+                    let page_size = self
+                        .mem
+                        .backing_page_size(GuestAddress(pfn << VIRTIO_BALLOON_PFN_SHIFT));
+                    if page_size > 1 << VIRTIO_BALLOON_PFN_SHIFT {
+                        if let Some(addr) = self.huge_pages.inflate(pfn, page_size) {
+                            if self.mem.dont_need_range(addr, page_size).is_err() {
+                                warn!("Marking huge page unused failed; addr={}", addr);
+                            }
+                        }
+                        continue;
+                    }
+                    match run {
+                        Some((first_pfn, ref mut num_pages)) if first_pfn + *num_pages == pfn => {
+                            *num_pages += 1
+                        }
+                        _ => {
+                            if let Some((first_pfn, num_pages)) = run.replace((pfn, 1)) {
+                                release_pages(&self.mem, first_pfn, num_pages);
+                            }
+                        }
+                    }
+                }
+            }
+            used_descs.push(index);
+        }
+        if let Some((first_pfn, num_pages)) = run {
+            release_pages(&self.mem, first_pfn, num_pages);
+        }
+
+        for &index in &used_descs {
+            queue.add_used(mem, index, 0);
+        }
+        !used_descs.is_empty()
+    }
+
+    fn process_stats(&mut self) {
+        let mem = self.mem.guest_memory();
+        let queue = &mut self.stats_queue;
+        while let Some(stats_desc) = queue.pop(mem) {
+            if let Some(prev_desc) = self.stats_desc_index {
+                // We shouldn't ever have an extra buffer if the driver follows
+                // the protocol, but return it if we find one.
+                warn!("balloon: driver is not compliant, more than one stats buffer received");
+                queue.add_used(mem, prev_desc, 0);
+            }
+            self.stats_desc_index = Some(stats_desc.index);
+            let mut reader = match Reader::new(mem.clone(), stats_desc) {
+                Ok(r) => r,
+                Err(e) => {
+                    error!("balloon: failed to create reader: {}", e);
+                    continue;
+                }
+            };
+            let mut stats: BalloonStats = Default::default();
+            match reader.read_remaining_objs::<BalloonStat>() {
+                Ok(stats_read) => {
+                    for stat in stats_read {
+                        stat.update_stats(&mut stats);
+                    }
+                }
+                Err(e) => error!("error while reading stats: {}", e),
+            }
+            self.latest_stats = stats;
+        }
+
+        // The driver hands over its first buffer unprompted when it sets up the queue; only
+        // buffers returned in response to a request are answered.
+        if self.stats_requested && self.stats_desc_index.is_some() {
+            self.stats_requested = false;
+            self.send_stats();
+        }
+    }
+
+    fn send_stats(&mut self) {
+        let actual_pages = self.config.actual_pages.load(Ordering::Relaxed) as u64;
+        let result = BalloonControlResult::Stats {
+            balloon_actual: actual_pages << VIRTIO_BALLOON_PFN_SHIFT,
+            stats: self.latest_stats.clone(),
+        };
+        self.send_result(&result);
+    }
+
+    fn send_result(&mut self, result: &BalloonControlResult) {
+        if let Err(e) = self.command_socket.send(result) {
+            warn!("failed to send balloon command result: {}", e);
+            self.diagnostics.command_send_errors += 1;
+        }
+    }
+
+    fn request_stats(&mut self) {
+        // Pick up a buffer the driver made available before its queue event was handled.
+        self.process_stats();
+
+        match self.stats_desc_index.take() {
+            Some(index) => {
+                // Returning the buffer asks the driver to fill in a new one.
+                self.stats_queue.add_used(self.mem.guest_memory(), index, 0);
+                self.stats_queue
+                    .trigger_interrupt(self.mem.guest_memory(), &self.interrupt);
+                self.stats_requested = true;
+            }
+            // Until the driver hands over a buffer, answer with the most recent stats so the host
+            // isn't left waiting.
+            None => self.send_stats(),
+        }
+    }
+
+    fn process_free_page_hints(&mut self) -> bool {
+        let mem = self.mem.guest_memory();
+        let discard = self.can_discard_free_pages();
+        let queue = &mut self.free_page_queue;
+        let mut needs_interrupt = false;
+        let mut run_finished = false;
+        while let Some(avail_desc) = queue.pop(mem) {
+            let index = avail_desc.index;
+
+            if avail_desc.is_write_only() {
+                // The buffers the device may write to are the free pages themselves. Hints sent
+                // after the run was stopped or before the driver acknowledged it are left alone.
+                if self.hinting && discard {
+                    for desc in avail_desc.into_iter() {
+                        if self
+                            .mem
+                            .dont_need_range(desc.addr, u64::from(desc.len))
+                            .is_err()
+                        {
+                            warn!("Marking hinted pages unused failed; addr={}", desc.addr);
+                            continue;
+                        }
+                        self.hinted_bytes += u64::from(desc.len);
+                    }
+                }
+            } else {
+                // A buffer the device reads holds the command id the driver is reporting for.
+                let cmd_id = Reader::new(mem.clone(), avail_desc)
+                    .and_then(|mut r| r.read_obj::<Le32>().map_err(DescriptorError::IoError));
+                match cmd_id.map(Le32::to_native) {
+                    Ok(VIRTIO_BALLOON_CMD_ID_STOP) => {
+                        run_finished |= self.hinting;
+                        self.hinting = false;
+                    }
+                    Ok(cmd_id) if Some(cmd_id) == self.hint_cmd_id => self.hinting = true,
+                    Ok(cmd_id) => warn!("balloon: ignoring stale free page hint id {}", cmd_id),
+                    Err(e) => error!("balloon: failed to read free page hint id: {}", e),
+                }
+            }
+            queue.add_used(mem, index, 0);
+            needs_interrupt = true;
+        }
+
+        if run_finished {
+            self.stop_free_page_hinting();
+        }
+
+        needs_interrupt
+    }
+
+    fn process_reported_pages(&mut self) -> bool {
+        let mem = self.mem.guest_memory();
+        let discard = self.can_discard_free_pages();
+        let queue = &mut self.reporting_queue;
+        let mut needs_interrupt = false;
+        while let Some(avail_desc) = queue.pop(mem) {
+            let index = avail_desc.index;
+            // Each buffer is a range of free pages, which the driver keeps out of use until the
+            // device gives the buffer back.
+            if discard {
+                for desc in avail_desc.into_iter() {
+                    if self
+                        .mem
+                        .dont_need_range(desc.addr, u64::from(desc.len))
+                        .is_err()
+                    {
+                        warn!("Marking reported pages unused failed; addr={}", desc.addr);
+                    }
+                }
+            }
+            queue.add_used(mem, index, 0);
+            needs_interrupt = true;
+        }
+
+        needs_interrupt
+    }
+
+    // Free pages the guest filled with a poison value must keep it, so they can only be given back
+    // to the host if they read as zero afterwards. Inflated pages are not affected, because the
+    // driver poisons them again when it frees them on deflate.
+    fn can_discard_free_pages(&self) -> bool {
+        self.config.poison_val.load(Ordering::Relaxed) == 0
+    }
+
+    fn start_free_page_hinting(&mut self) {
+        let cmd_id = self.next_hint_cmd_id;
+        self.next_hint_cmd_id = cmd_id.checked_add(1).unwrap_or(FIRST_HINT_CMD_ID);
+        self.hint_cmd_id = Some(cmd_id);
+        self.hinting = false;
+        self.hinted_bytes = 0;
+        info!("balloon: starting free page hint run {}", cmd_id);
+        self.set_hint_cmd_id(cmd_id);
+    }
+
+    fn stop_free_page_hinting(&mut self) {
+        if let Some(cmd_id) = self.hint_cmd_id.take() {
+            info!(
+                "balloon: free page hint run {} released {} bytes",
+                cmd_id, self.hinted_bytes
+            );
+        }
+        self.hinting = false;
+        // The driver holds on to the hinted pages until it is told the run is done.
+        self.set_hint_cmd_id(VIRTIO_BALLOON_CMD_ID_DONE);
+    }
+
+    fn set_hint_cmd_id(&self, cmd_id: u32) {
+        self.config
+            .free_page_hint_cmd_id
+            .store(cmd_id, Ordering::Relaxed);
+        self.interrupt.signal_config_changed();
+    }
+
+    fn adjust(&mut self, num_bytes: u64) -> BalloonControlResult {
+        // The target is a 32-bit page count in the config space, and a balloon larger than guest
+        // memory could never be filled.
+        let num_pages = num_bytes >> VIRTIO_BALLOON_PFN_SHIFT;
+        if num_bytes > self.mem.guest_memory().memory_size() || num_pages > u64::from(u32::MAX) {
+            warn!("balloon: refusing to grow to {} bytes", num_bytes);
+            return BalloonControlResult::Err(SysError::new(EINVAL));
+        }
+        info!("ballon config changed to consume {} pages", num_pages);
+
+        self.config
+            .num_pages
+            .store(num_pages as usize, Ordering::Relaxed);
+        self.interrupt.signal_config_changed();
+        BalloonControlResult::Ok
+    }
+
+    fn handle_command(&mut self, command: BalloonControlCommand) {
+        let result = match command {
+            BalloonControlCommand::Adjust { num_bytes } => self.adjust(num_bytes),
+            BalloonControlCommand::Stats => {
+                // The stats are sent once the driver has filled in a buffer with them.
+                self.request_stats();
+                return;
+            }
+            BalloonControlCommand::Size => {
+                let num_pages = self.config.num_pages.load(Ordering::Relaxed) as u64;
+                let actual_pages = self.config.actual_pages.load(Ordering::Relaxed) as u64;
+                BalloonControlResult::Size {
+                    num_bytes: num_pages << VIRTIO_BALLOON_PFN_SHIFT,
+                    actual_bytes: actual_pages << VIRTIO_BALLOON_PFN_SHIFT,
+                }
+            }
+            BalloonControlCommand::FreePageHinting { start: true } => {
+                self.start_free_page_hinting();
+                BalloonControlResult::Ok
+            }
+            BalloonControlCommand::FreePageHinting { start: false } => {
+                self.stop_free_page_hinting();
+                BalloonControlResult::Ok
+            }
+            BalloonControlCommand::Diagnostics => {
+                BalloonControlResult::Diagnostics(self.diagnostics)
+            }
+        };
+        self.send_result(&result);
+    }
+
+    // Receives and handles a command. Returns false once the command socket is beyond use.
+    fn recv_command(&mut self) -> bool {
+        let e = match self.command_socket.recv() {
+            Ok(command) => {
+                self.command_recv_failures = 0;
+                self.handle_command(command);
+                return true;
+            }
+            Err(e) => e,
+        };
+
+        match e {
+            // The command was read off the socket, so the next one can still be received.
+            MsgError::InvalidType
+            | MsgError::BadRecvSize { .. }
+            | MsgError::ExpectDescriptor
+            | MsgError::NotExpectDescriptor
+            | MsgError::WrongDescriptorBufferSize
+            | MsgError::WrongMsgBufferSize => {
+                warn!("received an invalid balloon command: {}", e);
+                self.diagnostics.invalid_commands += 1;
+                true
+            }
+            // The main process closed its end.
+            MsgError::RecvZero => false,
+            _ => {
+                self.diagnostics.command_recv_errors += 1;
+                self.command_recv_failures += 1;
+                if self.command_recv_failures < MAX_COMMAND_RECV_FAILURES {
+                    warn!("failed to receive balloon command: {}", e);
+                    true
+                } else {
+                    error!("balloon command socket keeps failing, ignoring it: {}", e);
+                    false
+                }
+            }
+        }
+    }
+
+    fn run(&mut self, mut queue_evts: Vec<Event>, kill_evt: Event) {
+        #[derive(PartialEq, PollToken)]
+        enum Token {
+            Inflate,
+            Deflate,
+            Stats,
+            FreePageHint,
+            Reporting,
+            CommandSocket,
+            InterruptResample,
+            Kill,
+        }
+
+        let inflate_queue_evt = queue_evts.remove(0);
+        let deflate_queue_evt = queue_evts.remove(0);
+        let stats_queue_evt = queue_evts.remove(0);
+        let free_page_queue_evt = queue_evts.remove(0);
+        let reporting_queue_evt = queue_evts.remove(0);
+
+        let wait_ctx: WaitContext<Token> = match WaitContext::build_with(&[
+            (&inflate_queue_evt, Token::Inflate),
+            (&deflate_queue_evt, Token::Deflate),
+            (&stats_queue_evt, Token::Stats),
+            (&free_page_queue_evt, Token::FreePageHint),
+            (&reporting_queue_evt, Token::Reporting),
+            (&self.command_socket, Token::CommandSocket),
+            (self.interrupt.get_resample_evt(), Token::InterruptResample),
+            (&kill_evt, Token::Kill),
+        ]) {
+            Ok(pc) => pc,
+            Err(e) => {
+                error!("failed creating WaitContext: {}", e);
+                self.interrupt.signal_needs_reset();
+                return;
+            }
+        };
+
+        'wait: loop {
+            let events = match wait_ctx.wait() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed polling for events: {}", e);
+                    break;
+                }
+            };
+
+            let mut needs_interrupt_inflate = false;
+            let mut needs_interrupt_deflate = false;
+            let mut needs_interrupt_free_page = false;
+            let mut needs_interrupt_reporting = false;
+            for event in events.iter().filter(|e| e.is_readable) {
+                match event.token {
+                    Token::Inflate => {
+                        if let Err(e) = inflate_queue_evt.read() {
+                            error!("failed reading inflate queue Event: {}", e);
+                            break 'wait;
+                        }
+                        needs_interrupt_inflate |= self.process_inflate_deflate(true);
+                    }
+                    Token::Deflate => {
+                        if let Err(e) = deflate_queue_evt.read() {
+                            error!("failed reading deflate queue Event: {}", e);
+                            break 'wait;
+                        }
+                        needs_interrupt_deflate |= self.process_inflate_deflate(false);
+                    }
+                    Token::Stats => {
+                        if let Err(e) = stats_queue_evt.read() {
+                            error!("failed reading stats queue Event: {}", e);
+                            break 'wait;
+                        }
+                        self.process_stats();
+                    }
+                    Token::FreePageHint => {
+                        if let Err(e) = free_page_queue_evt.read() {
+                            error!("failed reading free page hint queue Event: {}", e);
+                            break 'wait;
+                        }
+                        needs_interrupt_free_page |= self.process_free_page_hints();
+                    }
+                    Token::Reporting => {
+                        if let Err(e) = reporting_queue_evt.read() {
+                            error!("failed reading free page reporting queue Event: {}", e);
+                            break 'wait;
+                        }
+                        needs_interrupt_reporting |= self.process_reported_pages();
+                    }
+                    Token::CommandSocket => {
+                        // The socket is polled again once the device is reset and activated.
+                        if !self.recv_command() {
+                            let _ = wait_ctx.delete(&self.command_socket);
+                        }
+                    }
+                    Token::InterruptResample => {
+                        self.interrupt.interrupt_resample();
+                    }
+                    Token::Kill => return,
+                }
+            }
+            for event in events.iter().filter(|e| e.is_hungup) {
+                if event.token == Token::CommandSocket && !event.is_readable {
+                    // If this call fails, the command socket was already removed from the
+                    // WaitContext.
+                    let _ = wait_ctx.delete(&self.command_socket);
+                }
+            }
+
+            if needs_interrupt_inflate {
+                self.inflate_queue
+                    .trigger_interrupt(self.mem.guest_memory(), &self.interrupt);
+            }
+
+            if needs_interrupt_deflate {
+                self.deflate_queue
+                    .trigger_interrupt(self.mem.guest_memory(), &self.interrupt);
+            }
+
+            if needs_interrupt_free_page {
+                self.free_page_queue
+                    .trigger_interrupt(self.mem.guest_memory(), &self.interrupt);
+            }
+
+            if needs_interrupt_reporting {
+                self.reporting_queue
+                    .trigger_interrupt(self.mem.guest_memory(), &self.interrupt);
+            }
+        }
+
+        // Only errors get here. Resetting the device starts a new worker with the same state.
+        self.interrupt.signal_needs_reset();
+    }
+}
+
+/// Virtio device for memory balloon inflation/deflation.
+pub struct Balloon {
+    command_socket: Option<BalloonControlResponseSocket>,
+    // Tells the main process when the guest changes the balloon's size.
+    event_socket: BalloonEventSendSocket,
+    config: Arc<BalloonConfig>,
+    // Carried over from one worker to the next across device resets.
+    diagnostics: BalloonDiagnostics,
+    features: u64,
+    acked_features: u64,
+    kill_evt: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<Worker>>,
+}
+
+impl Balloon {
+    /// Create a new virtio balloon device.
+    pub fn new(
+        base_features: u64,
+        command_socket: BalloonControlResponseSocket,
+        event_socket: BalloonEventSendSocket,
+    ) -> Result<Balloon> {
+        Ok(Balloon {
+            command_socket: Some(command_socket),
+            event_socket,
+            config: Arc::new(BalloonConfig {
+                num_pages: AtomicUsize::new(0),
+                actual_pages: AtomicUsize::new(0),
+                free_page_hint_cmd_id: AtomicU32::new(VIRTIO_BALLOON_CMD_ID_STOP),
+                poison_val: AtomicU32::new(0),
+            }),
+            diagnostics: Default::default(),
+            kill_evt: None,
+            worker_thread: None,
+            acked_features: 0,
+            features: base_features
+                | 1 << VIRTIO_BALLOON_F_MUST_TELL_HOST
+                | 1 << VIRTIO_BALLOON_F_STATS_VQ
+                | 1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM
+                | 1 << VIRTIO_BALLOON_F_FREE_PAGE_HINT
+                | 1 << VIRTIO_BALLOON_F_PAGE_POISON
+                | 1 << VIRTIO_BALLOON_F_PAGE_REPORTING,
+        })
+    }
+
+    fn get_config(&self) -> virtio_balloon_config {
+        let num_pages = self.config.num_pages.load(Ordering::Relaxed) as u32;
+        let actual_pages = self.config.actual_pages.load(Ordering::Relaxed) as u32;
+        let free_page_hint_cmd_id = self.config.free_page_hint_cmd_id.load(Ordering::Relaxed);
+        let poison_val = self.config.poison_val.load(Ordering::Relaxed);
+        virtio_balloon_config {
+            num_pages: num_pages.into(),
+            actual: actual_pages.into(),
+            free_page_hint_cmd_id: free_page_hint_cmd_id.into(),
+            poison_val: poison_val.into(),
+        }
+    }
+
+    // Records the size of the balloon, telling the main process if it changed.
+    fn set_actual_pages(&self, actual_pages: usize) {
+        let prev_actual_pages = self
+            .config
+            .actual_pages
+            .swap(actual_pages, Ordering::Relaxed);
+        if actual_pages != prev_actual_pages {
+            let event = BalloonEvent::ActualChanged {
+                actual_bytes: (actual_pages as u64) << VIRTIO_BALLOON_PFN_SHIFT,
+            };
+            if let Err(e) = self.event_socket.send(&event) {
+                warn!("failed to send balloon event: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for Balloon {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do with a failure.
+            let _ = kill_evt.write(1);
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl VirtioDevice for Balloon {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        vec![
+            self.command_socket.as_ref().unwrap().as_raw_descriptor(),
+            self.event_socket.as_raw_descriptor(),
+        ]
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_BALLOON
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        copy_config(data, 0, self.get_config().as_slice(), offset);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let mut config = self.get_config();
+        copy_config(config.as_mut_slice(), offset, data, 0);
+        self.set_actual_pages(config.actual.to_native() as usize);
+        if self.acked_features & (1 << VIRTIO_BALLOON_F_PAGE_POISON) != 0 {
+            self.config
+                .poison_val
+                .store(config.poison_val.to_native(), Ordering::Relaxed);
+        }
+    }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.acked_features |= value & self.features;
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        queue_evts: Vec<Event>,
+    ) {
+        if queues.len() != QUEUE_SIZES.len() || queue_evts.len() != QUEUE_SIZES.len() {
+            return;
+        }
+
+        // The driver only sets up the queues of the features it acked, and numbers them in the
+        // order of QUEUE_SIZES. The queues it leaves out go to the worker unused.
+        let mut queues: VecDeque<(Queue, Event)> = queues.into_iter().zip(queue_evts).collect();
+        let mut take_queue = |feature: Option<u32>| match feature {
+            Some(bit) if self.acked_features & (1 << bit) == 0 => queues.pop_back().unwrap(),
+            _ => queues.pop_front().unwrap(),
+        };
+        let (inflate_queue, inflate_evt) = take_queue(None);
+        let (deflate_queue, deflate_evt) = take_queue(None);
+        let (stats_queue, stats_evt) = take_queue(Some(VIRTIO_BALLOON_F_STATS_VQ));
+        let (free_page_queue, free_page_evt) = take_queue(Some(VIRTIO_BALLOON_F_FREE_PAGE_HINT));
+        let (reporting_queue, reporting_evt) = take_queue(Some(VIRTIO_BALLOON_F_PAGE_REPORTING));
+        let queue_evts = vec![
+            inflate_evt,
+            deflate_evt,
+            stats_evt,
+            free_page_evt,
+            reporting_evt,
+        ];
+
+        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed to create kill Event pair: {}", e);
+                return;
+            }
+        };
+        self.kill_evt = Some(self_kill_evt);
+
+        let config = self.config.clone();
+        let command_socket = self.command_socket.take().unwrap();
+        let diagnostics = self.diagnostics;
+        let worker_result = thread::Builder::new()
+            .name("virtio_balloon".to_string())
+            .spawn(move || {
+                let mut worker = Worker {
+                    interrupt,
+                    mem,
+                    inflate_queue,
+                    deflate_queue,
+                    stats_queue,
+                    stats_desc_index: None,
+                    stats_requested: false,
+                    latest_stats: Default::default(),
+                    free_page_queue,
+                    reporting_queue,
+                    hint_cmd_id: None,
+                    hinting: false,
+                    hinted_bytes: 0,
+                    next_hint_cmd_id: FIRST_HINT_CMD_ID,
+                    huge_pages: Default::default(),
+                    command_socket,
+                    command_recv_failures: 0,
+                    diagnostics,
+                    config,
+                };
+                worker.run(queue_evts, kill_evt);
+                worker
+            });
+
+        match worker_result {
+            Err(e) => {
+                error!("failed to spawn virtio_balloon worker: {}", e);
+            }
+            Ok(join_handle) => {
+                self.worker_thread = Some(join_handle);
+            }
+        }
+    }
+
+    fn reset(&mut self) -> bool {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            if kill_evt.write(1).is_err() {
+                error!("{}: failed to notify the kill event", self.debug_label());
+                return false;
+            }
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            match worker_thread.join() {
+                Err(_) => {
+                    error!("{}: failed to get back resources", self.debug_label());
+                    return false;
+                }
+                Ok(worker) => {
+                    self.command_socket = Some(worker.command_socket);
+                    self.diagnostics = worker.diagnostics;
+                    // The pages in the balloon went back to the guest along with its driver. The
+                    // target size stays, so the next driver inflates the balloon again.
+                    self.acked_features = 0;
+                    self.set_actual_pages(0);
+                    self.config
+                        .free_page_hint_cmd_id
+                        .store(VIRTIO_BALLOON_CMD_ID_STOP, Ordering::Relaxed);
+                    self.config.poison_val.store(0, Ordering::Relaxed);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+// The worker only acts on queue notifications from the paused guest and on commands from the main
+// process, which doesn't send any while it saves or restores the VM, so sleeping needs no work.
+impl Suspendable for Balloon {
+    fn sleep(&mut self) -> suspendable::Result<()> {
+        Ok(())
+    }
+
+    fn snapshot(&mut self) -> suspendable::Result<Vec<u8>> {
+        let snapshot = BalloonSnapshot {
+            acked_features: self.acked_features.into(),
+            num_pages: (self.config.num_pages.load(Ordering::Relaxed) as u32).into(),
+            actual_pages: (self.config.actual_pages.load(Ordering::Relaxed) as u32).into(),
+            free_page_hint_cmd_id: self
+                .config
+                .free_page_hint_cmd_id
+                .load(Ordering::Relaxed)
+                .into(),
+            poison_val: self.config.poison_val.load(Ordering::Relaxed).into(),
+        };
+        Ok(snapshot.as_slice().to_vec())
+    }
+
+    fn restore(&mut self, data: &[u8]) -> suspendable::Result<()> {
+        let mut data = data;
+        let snapshot = BalloonSnapshot::from_reader(&mut data)
+            .map_err(|_| SuspendableError::InvalidSnapshot)?;
+        let acked_features = snapshot.acked_features.to_native();
+        // The snapshot can't have acked features this device doesn't offer.
+        if !data.is_empty() || acked_features & !self.features != 0 {
+            return Err(SuspendableError::InvalidSnapshot);
+        }
+
+        self.acked_features = acked_features;
+        self.config
+            .num_pages
+            .store(snapshot.num_pages.to_native() as usize, Ordering::Relaxed);
+        self.config.actual_pages.store(
+            snapshot.actual_pages.to_native() as usize,
+            Ordering::Relaxed,
+        );
+        self.config.free_page_hint_cmd_id.store(
+            snapshot.free_page_hint_cmd_id.to_native(),
+            Ordering::Relaxed,
+        );
+        self.config
+            .poison_val
+            .store(snapshot.poison_val.to_native(), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{pfn_to_addr, FakeBalloonGuest, FAKE_GUEST_MEMORY_SIZE};
+    use super::*;
+
+    fn recv_stats(guest: &FakeBalloonGuest) -> (BalloonStats, u64) {
+        match guest.host_socket().recv() {
+            Ok(BalloonControlResult::Stats {
+                stats,
+                balloon_actual,
+            }) => (stats, balloon_actual),
+            Ok(r) => panic!("unexpected result instead of stats: {:?}", r),
+            Err(e) => panic!("failed to receive stats: {}", e),
+        }
+    }
+
+    fn send_command(
+        guest: &FakeBalloonGuest,
+        command: BalloonControlCommand,
+    ) -> BalloonControlResult {
+        guest.host_socket().send(&command).unwrap();
+        guest.host_socket().recv().unwrap()
+    }
+
+    fn new_balloon(base_features: u64) -> Balloon {
+        let (_, command_socket) = msg_socket::pair().unwrap();
+        let (event_socket, _) = msg_socket::pair().unwrap();
+        Balloon::new(base_features, command_socket, event_socket).unwrap()
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut balloon = new_balloon(1 << 32);
+        balloon.ack_features(1 << VIRTIO_BALLOON_F_STATS_VQ | 1 << VIRTIO_BALLOON_F_PAGE_POISON);
+        balloon.config.num_pages.store(0x100, Ordering::Relaxed);
+        balloon.config.actual_pages.store(0x80, Ordering::Relaxed);
+        balloon.config.poison_val.store(0xaa, Ordering::Relaxed);
+        let snapshot = balloon.snapshot().unwrap();
+
+        let mut restored = new_balloon(1 << 32);
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.acked_features, balloon.acked_features);
+        assert_eq!(
+            restored.get_config().as_slice(),
+            balloon.get_config().as_slice()
+        );
+
+        // Neither features the device doesn't offer nor a truncated snapshot can be restored.
+        balloon.ack_features(1 << 32);
+        let snapshot = balloon.snapshot().unwrap();
+        assert!(new_balloon(0).restore(&snapshot).is_err());
+        assert!(new_balloon(1 << 32).restore(&snapshot[1..]).is_err());
+    }
+
+    #[test]
+    fn inflate_releases_pages() {
+        let mut guest = FakeBalloonGuest::new();
+        guest.inflate(&[0x40, 0x41, 0x80]);
+        assert_eq!(
+            guest.memory().released(),
+            vec![(pfn_to_addr(0x40), 0x2000), (pfn_to_addr(0x80), 0x1000)]
+        );
+
+        // Pages outside of guest memory are skipped.
+        guest.inflate(&[0xffff, 0x81]);
+        assert_eq!(guest.memory().released().len(), 3);
+        assert_eq!(guest.memory().released()[2], (pfn_to_addr(0x81), 0x1000));
+    }
+
+    #[test]
+    fn inflate_batches_contiguous_pages() {
+        let mut guest = FakeBalloonGuest::new();
+        let pfns: Vec<u32> = (0x40..0x80).collect();
+        guest.inflate(&pfns);
+        assert_eq!(
+            guest.memory().released(),
+            vec![(pfn_to_addr(0x40), 0x40000)]
+        );
+
+        // Runs carry on across the buffers the device finds available together.
+        guest.inflate_buffers(&[&[0x80, 0x81], &[0x82, 0x83], &[0x90]]);
+        assert_eq!(
+            guest.memory().released()[1..],
+            [(pfn_to_addr(0x80), 0x4000), (pfn_to_addr(0x90), 0x1000)]
+        );
+    }
+
+    #[test]
+    fn inflate_run_past_guest_memory() {
+        let mut guest = FakeBalloonGuest::new();
+        // The run is released page by page when part of it isn't guest memory.
+        let last_pfn = (FAKE_GUEST_MEMORY_SIZE >> VIRTIO_BALLOON_PFN_SHIFT) as u32 - 1;
+        guest.inflate(&[last_pfn - 1, last_pfn, last_pfn + 1]);
+        assert_eq!(
+            guest.memory().released(),
+            vec![
+                (pfn_to_addr(last_pfn - 1), 0x1000),
+                (pfn_to_addr(last_pfn), 0x1000),
+            ]
+        );
+    }
+
+    #[test]
+    fn inflate_releases_whole_huge_pages() {
+        let mut guest = FakeBalloonGuest::new();
+        // Each host page holds 8 balloon pages.
+        guest.memory().set_backing_page_size(0x8000);
+        guest.inflate(&[0x40, 0x41, 0x42, 0x43]);
+        guest.inflate(&[0x44, 0x45, 0x46, 0x48]);
+        assert!(guest.memory().released().is_empty());
+        guest.inflate(&[0x47]);
+        assert_eq!(guest.memory().released(), vec![(pfn_to_addr(0x40), 0x8000)]);
+
+        // A page taken back out has to be put back in before the huge page is released again.
+        guest.deflate(&[0x41]);
+        guest.inflate(&[0x40]);
+        assert_eq!(guest.memory().released().len(), 1);
+        guest.inflate(&[0x41]);
+        assert_eq!(guest.memory().released()[1], (pfn_to_addr(0x40), 0x8000));
+    }
+
+    #[test]
+    fn deflate_keeps_pages() {
+        let mut guest = FakeBalloonGuest::new();
+        guest.deflate(&[0x40, 0x41]);
+        assert!(guest.memory().released().is_empty());
+    }
+
+    #[test]
+    fn adjust_changes_config() {
+        let mut guest = FakeBalloonGuest::new();
+        let command = BalloonControlCommand::Adjust {
+            num_bytes: 16 << VIRTIO_BALLOON_PFN_SHIFT,
+        };
+        assert!(matches!(
+            send_command(&guest, command),
+            BalloonControlResult::Ok
+        ));
+        guest.wait_config_changed();
+        assert_eq!(guest.num_pages(), 16);
+    }
+
+    #[test]
+    fn adjust_beyond_guest_memory() {
+        let guest = FakeBalloonGuest::new();
+        let command = BalloonControlCommand::Adjust {
+            num_bytes: FAKE_GUEST_MEMORY_SIZE + 1,
+        };
+        assert!(matches!(
+            send_command(&guest, command),
+            BalloonControlResult::Err(_)
+        ));
+        assert_eq!(guest.num_pages(), 0);
+    }
+
+    #[test]
+    fn size_reports_target_and_actual() {
+        let guest = FakeBalloonGuest::new();
+        let command = BalloonControlCommand::Adjust {
+            num_bytes: 16 << VIRTIO_BALLOON_PFN_SHIFT,
+        };
+        send_command(&guest, command);
+        guest.set_actual_pages(4);
+        match send_command(&guest, BalloonControlCommand::Size) {
+            BalloonControlResult::Size {
+                num_bytes,
+                actual_bytes,
+            } => {
+                assert_eq!(num_bytes, 16 << VIRTIO_BALLOON_PFN_SHIFT);
+                assert_eq!(actual_bytes, 4 << VIRTIO_BALLOON_PFN_SHIFT);
+            }
+            r => panic!("unexpected result instead of size: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn invalid_commands_counted() {
+        let guest = FakeBalloonGuest::new();
+        // A message too short to be a command doesn't keep the device from serving the next one.
+        guest.host_socket().as_ref().send(&[0xff]).unwrap();
+        match send_command(&guest, BalloonControlCommand::Diagnostics) {
+            BalloonControlResult::Diagnostics(diagnostics) => assert_eq!(
+                diagnostics,
+                BalloonDiagnostics {
+                    invalid_commands: 1,
+                    ..Default::default()
+                }
+            ),
+            r => panic!("unexpected result instead of diagnostics: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn stats_without_buffer() {
+        let guest = FakeBalloonGuest::new();
+        guest.set_actual_pages(2);
+        guest
+            .host_socket()
+            .send(&BalloonControlCommand::Stats)
+            .unwrap();
+        let (stats, balloon_actual) = recv_stats(&guest);
+        assert_eq!(stats.free_memory, None);
+        assert_eq!(balloon_actual, 2 << VIRTIO_BALLOON_PFN_SHIFT);
+    }
+
+    #[test]
+    fn stats_on_request() {
+        let mut guest = FakeBalloonGuest::new();
+
+        // The driver hands over a stats buffer as soon as the queue is set up, which the device
+        // keeps until the host asks for stats.
+        guest.provide_stats(&[(VIRTIO_BALLOON_S_MEMFREE, 0x1000)]);
+
+        guest
+            .host_socket()
+            .send(&BalloonControlCommand::Stats)
+            .unwrap();
+        guest.wait_stats_request();
+        guest.provide_stats(&[
+            (VIRTIO_BALLOON_S_MEMFREE, 0x2000),
+            (VIRTIO_BALLOON_S_MEMTOT, 0x8000),
+        ]);
+        let (stats, _) = recv_stats(&guest);
+        assert_eq!(stats.free_memory, Some(0x2000));
+        assert_eq!(stats.total_memory, Some(0x8000));
+        assert_eq!(stats.swap_in, None);
+    }
+
+    fn start_hinting(guest: &mut FakeBalloonGuest) -> u32 {
+        let command = BalloonControlCommand::FreePageHinting { start: true };
+        assert!(matches!(
+            send_command(guest, command),
+            BalloonControlResult::Ok
+        ));
+        guest.wait_config_changed();
+        guest.hint_cmd_id()
+    }
+
+    #[test]
+    fn free_page_hinting_releases_pages() {
+        let mut guest = FakeBalloonGuest::new();
+        assert_eq!(guest.hint_cmd_id(), VIRTIO_BALLOON_CMD_ID_STOP);
+
+        let cmd_id = start_hinting(&mut guest);
+        assert_eq!(cmd_id, FIRST_HINT_CMD_ID);
+        guest.send_hint_cmd_id(cmd_id);
+        guest.hint_free_pages(pfn_to_addr(0x80), 0x4000);
+        guest.hint_free_pages(pfn_to_addr(0xa0), 0x1000);
+        assert_eq!(
+            guest.memory().released(),
+            vec![(pfn_to_addr(0x80), 0x4000), (pfn_to_addr(0xa0), 0x1000)]
+        );
+
+        // Once the driver is through, the device lets it have the hinted pages back.
+        guest.send_hint_cmd_id(VIRTIO_BALLOON_CMD_ID_STOP);
+        guest.wait_config_changed();
+        assert_eq!(guest.hint_cmd_id(), VIRTIO_BALLOON_CMD_ID_DONE);
+
+        // Hints after the run ended are ignored, and the next run gets a new id.
+        guest.hint_free_pages(pfn_to_addr(0xc0), 0x1000);
+        assert_eq!(guest.memory().released().len(), 2);
+        assert_eq!(start_hinting(&mut guest), cmd_id + 1);
+    }
+
+    #[test]
+    fn reported_pages_released() {
+        let mut guest = FakeBalloonGuest::new();
+        guest.report_free_pages(pfn_to_addr(0x80), 0x8000);
+        guest.report_free_pages(pfn_to_addr(0xc0), 0x1000);
+        assert_eq!(
+            guest.memory().released(),
+            vec![(pfn_to_addr(0x80), 0x8000), (pfn_to_addr(0xc0), 0x1000)]
+        );
+    }
+
+    #[test]
+    fn poisoned_free_pages_kept() {
+        let mut guest = FakeBalloonGuest::new();
+        guest.set_poison_val(0xaaaa_aaaa);
+
+        // Dropping free pages would lose the poison value the guest expects to find in them.
+        guest.report_free_pages(pfn_to_addr(0x80), 0x1000);
+        let cmd_id = start_hinting(&mut guest);
+        guest.send_hint_cmd_id(cmd_id);
+        guest.hint_free_pages(pfn_to_addr(0xa0), 0x1000);
+        assert!(guest.memory().released().is_empty());
+
+        // Inflated pages are poisoned again when the guest gets them back.
+        guest.inflate(&[0x40]);
+        assert_eq!(guest.memory().released(), vec![(pfn_to_addr(0x40), 4096)]);
+    }
+
+    #[test]
+    fn free_page_hinting_stopped_by_host() {
+        let mut guest = FakeBalloonGuest::new();
+        let cmd_id = start_hinting(&mut guest);
+
+        // Hints reported for an older run don't count.
+        guest.send_hint_cmd_id(cmd_id + 1);
+        guest.hint_free_pages(pfn_to_addr(0x80), 0x1000);
+        assert!(guest.memory().released().is_empty());
+
+        guest.send_hint_cmd_id(cmd_id);
+        send_command(
+            &guest,
+            BalloonControlCommand::FreePageHinting { start: false },
+        );
+        guest.wait_config_changed();
+        assert_eq!(guest.hint_cmd_id(), VIRTIO_BALLOON_CMD_ID_DONE);
+        guest.hint_free_pages(pfn_to_addr(0x80), 0x1000);
+        assert!(guest.memory().released().is_empty());
+    }
+
+    #[test]
+    fn actual_size_changes_reported() {
+        let (_host_socket, device_socket) = msg_socket::pair().unwrap();
+        let (event_recv_socket, event_send_socket) = msg_socket::pair().unwrap();
+        let mut balloon = Balloon::new(0, device_socket, event_send_socket).unwrap();
+        let actual_offset = 4;
+
+        balloon.write_config(actual_offset, &3u32.to_le_bytes());
+        // Writing the same size again is not a change.
+        balloon.write_config(actual_offset, &3u32.to_le_bytes());
+        balloon.write_config(actual_offset, &1u32.to_le_bytes());
+
+        let recv_actual_bytes = || match event_recv_socket.recv() {
+            Ok(BalloonEvent::ActualChanged { actual_bytes }) => actual_bytes,
+            Err(e) => panic!("failed to receive balloon event: {}", e),
+        };
+        assert_eq!(recv_actual_bytes(), 3 << VIRTIO_BALLOON_PFN_SHIFT);
+        assert_eq!(recv_actual_bytes(), 1 << VIRTIO_BALLOON_PFN_SHIFT);
+    }
+}
@@ -0,0 +1,428 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Pieces for exercising the balloon device without booting a kernel.
+//!
+//! `FakeBalloonGuest` plays the part of the guest's balloon driver. It lays out the inflate,
+//! deflate, stats, free page hint, and free page reporting queues in its own guest memory, fills
+//! them the way a driver would, and runs the device worker against a `FakeGuestMemory` that
+//! records the ranges given back to the host instead of dropping them. Tests outside this crate
+//! can use it by enabling the `balloon-testing` feature in their dev-dependencies.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use base::Event;
+use data_model::{DataInit, Le16, Le32, Le64};
+use msg_socket::pair;
+use sync::Mutex;
+use vm_control::BalloonControlRequestSocket;
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
+
+use super::{
+    BalloonConfig, BalloonMemory, BalloonStat, Worker, FIRST_HINT_CMD_ID, QUEUE_SIZE,
+    VIRTIO_BALLOON_PFN_SHIFT,
+};
+use crate::virtio::{
+    Interrupt, Queue, INTERRUPT_STATUS_CONFIG_CHANGED, INTERRUPT_STATUS_USED_RING,
+    VIRTIO_MSI_NO_VECTOR,
+};
+
+// Flags of a descriptor the device may write to.
+const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+/// Size of the memory given to the fake guest.
+pub const FAKE_GUEST_MEMORY_SIZE: u64 = 0x100000;
+
+// Each queue gets 0x2000 bytes for its rings and 0x10000 bytes for its buffers, one 0x200 byte
+// buffer per descriptor.
+const RINGS_BASE: u64 = 0;
+const RINGS_SIZE: u64 = 0x2000;
+const AVAIL_RING_OFFSET: u64 = 0x800;
+const USED_RING_OFFSET: u64 = 0x1000;
+const BUFFERS_BASE: u64 = 0x10000;
+const BUFFERS_SIZE: u64 = 0x10000;
+const BUFFER_SIZE: u64 = 0x200;
+
+const INFLATE_QUEUE: usize = 0;
+const DEFLATE_QUEUE: usize = 1;
+const STATS_QUEUE: usize = 2;
+const FREE_PAGE_QUEUE: usize = 3;
+const REPORTING_QUEUE: usize = 4;
+const NUM_QUEUES: usize = 5;
+
+/// Guest memory that records the ranges the balloon releases instead of releasing them.
+#[derive(Clone)]
+pub struct FakeGuestMemory {
+    mem: GuestMemory,
+    released: Arc<Mutex<Vec<(GuestAddress, u64)>>>,
+    page_size: Arc<AtomicU64>,
+}
+
+impl FakeGuestMemory {
+    /// Wraps `mem`, which holds the virtqueues.
+    pub fn new(mem: GuestMemory) -> FakeGuestMemory {
+        FakeGuestMemory {
+            mem,
+            released: Arc::new(Mutex::new(Vec::new())),
+            page_size: Arc::new(AtomicU64::new(1 << VIRTIO_BALLOON_PFN_SHIFT)),
+        }
+    }
+
+    /// Pretends the memory is backed by host pages of `page_size` bytes, such as huge pages.
+    pub fn set_backing_page_size(&self, page_size: u64) {
+        self.page_size.store(page_size, Ordering::Relaxed);
+    }
+
+    /// Returns the ranges passed to `dont_need_range` so far, in the order they were released.
+    pub fn released(&self) -> Vec<(GuestAddress, u64)> {
+        self.released.lock().clone()
+    }
+}
+
+impl BalloonMemory for FakeGuestMemory {
+    fn guest_memory(&self) -> &GuestMemory {
+        &self.mem
+    }
+
+    fn dont_need_range(&self, addr: GuestAddress, count: u64) -> vm_memory::Result<()> {
+        // Like `GuestMemory::remove_range`, fail unless the whole range is in guest memory.
+        let last = addr
+            .checked_add(count.saturating_sub(1))
+            .ok_or(GuestMemoryError::InvalidGuestAddress(addr))?;
+        if !self.mem.address_in_range(addr) || !self.mem.address_in_range(last) {
+            return Err(GuestMemoryError::InvalidGuestAddress(addr));
+        }
+        self.released.lock().push((addr, count));
+        Ok(())
+    }
+
+    fn backing_page_size(&self, _addr: GuestAddress) -> u64 {
+        self.page_size.load(Ordering::Relaxed)
+    }
+}
+
+/// A synthetic balloon driver running against the device worker in a separate thread.
+pub struct FakeBalloonGuest {
+    mem: FakeGuestMemory,
+    config: Arc<BalloonConfig>,
+    queues: Vec<Queue>,
+    queue_evts: Vec<Event>,
+    // Number of buffers made available on each queue.
+    avail_idx: [u16; NUM_QUEUES],
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_evt: Event,
+    // Interrupt status bits read from the device but not yet waited for.
+    pending_status: usize,
+    host_socket: BalloonControlRequestSocket,
+    kill_evt: Event,
+    worker_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl FakeBalloonGuest {
+    /// Sets up the queues and starts the device worker.
+    pub fn new() -> FakeBalloonGuest {
+        let mem = GuestMemory::new(&[(GuestAddress(0), FAKE_GUEST_MEMORY_SIZE)])
+            .expect("failed to create guest memory");
+        let mem = FakeGuestMemory::new(mem);
+
+        let queues: Vec<Queue> = (0..NUM_QUEUES)
+            .map(|i| {
+                let rings = RINGS_BASE + i as u64 * RINGS_SIZE;
+                let mut queue = Queue::new(QUEUE_SIZE);
+                queue.ready = true;
+                queue.desc_table = GuestAddress(rings);
+                queue.avail_ring = GuestAddress(rings + AVAIL_RING_OFFSET);
+                queue.used_ring = GuestAddress(rings + USED_RING_OFFSET);
+                queue
+            })
+            .collect();
+        let queue_evts: Vec<Event> = (0..NUM_QUEUES)
+            .map(|_| Event::new().expect("failed to create queue event"))
+            .collect();
+
+        let interrupt_status = Arc::new(AtomicUsize::new(0));
+        let interrupt_evt = Event::new().expect("failed to create interrupt event");
+        let interrupt = Interrupt::new(
+            interrupt_status.clone(),
+            interrupt_evt.try_clone().expect("failed to clone event"),
+            Event::new().expect("failed to create resample event"),
+            None,
+            VIRTIO_MSI_NO_VECTOR,
+        );
+
+        let (host_socket, device_socket) = pair().expect("failed to create balloon sockets");
+        let config = Arc::new(BalloonConfig::default());
+        let kill_evt = Event::new().expect("failed to create kill event");
+
+        let mut worker = Worker {
+            interrupt,
+            mem: mem.clone(),
+            inflate_queue: queues[INFLATE_QUEUE].clone(),
+            deflate_queue: queues[DEFLATE_QUEUE].clone(),
+            stats_queue: queues[STATS_QUEUE].clone(),
+            stats_desc_index: None,
+            stats_requested: false,
+            latest_stats: Default::default(),
+            free_page_queue: queues[FREE_PAGE_QUEUE].clone(),
+            reporting_queue: queues[REPORTING_QUEUE].clone(),
+            hint_cmd_id: None,
+            hinting: false,
+            hinted_bytes: 0,
+            next_hint_cmd_id: FIRST_HINT_CMD_ID,
+            huge_pages: Default::default(),
+            config: config.clone(),
+            command_socket: device_socket,
+            command_recv_failures: 0,
+            diagnostics: Default::default(),
+        };
+        let worker_evts: Vec<Event> = queue_evts
+            .iter()
+            .map(|e| e.try_clone().expect("failed to clone event"))
+            .collect();
+        let worker_kill_evt = kill_evt.try_clone().expect("failed to clone event");
+        let worker_thread = thread::Builder::new()
+            .name("fake_balloon".to_string())
+            .spawn(move || worker.run(worker_evts, worker_kill_evt))
+            .expect("failed to spawn balloon worker");
+
+        FakeBalloonGuest {
+            mem,
+            config,
+            queues,
+            queue_evts,
+            avail_idx: [0; NUM_QUEUES],
+            interrupt_status,
+            interrupt_evt,
+            pending_status: 0,
+            host_socket,
+            kill_evt,
+            worker_thread: Some(worker_thread),
+        }
+    }
+
+    /// Returns the host end of the balloon control socket.
+    pub fn host_socket(&self) -> &BalloonControlRequestSocket {
+        &self.host_socket
+    }
+
+    /// Returns the guest memory shared with the device.
+    pub fn memory(&self) -> &FakeGuestMemory {
+        &self.mem
+    }
+
+    /// Returns the balloon size requested by the host, in pages.
+    pub fn num_pages(&self) -> usize {
+        self.config.num_pages.load(Ordering::Relaxed)
+    }
+
+    /// Reports the number of pages in the balloon, as the driver does through the config space.
+    pub fn set_actual_pages(&self, pages: usize) {
+        self.config.actual_pages.store(pages, Ordering::Relaxed);
+    }
+
+    /// Puts `pfns` in the balloon and waits for the device to process them.
+    pub fn inflate(&mut self, pfns: &[u32]) {
+        self.send_pfns(INFLATE_QUEUE, pfns);
+    }
+
+    /// Puts the `pfns` of each buffer in the balloon, making all of the buffers available before
+    /// notifying the device, and waits for the device to process them.
+    pub fn inflate_buffers(&mut self, buffers: &[&[u32]]) {
+        for pfns in buffers {
+            let (buffer, len) = self.write_pfns(INFLATE_QUEUE, pfns);
+            self.add_buffer(INFLATE_QUEUE, buffer, len, 0);
+        }
+        self.publish(INFLATE_QUEUE);
+        self.wait_used(INFLATE_QUEUE);
+    }
+
+    /// Takes `pfns` out of the balloon and waits for the device to process them.
+    pub fn deflate(&mut self, pfns: &[u32]) {
+        self.send_pfns(DEFLATE_QUEUE, pfns);
+    }
+
+    /// Hands the device a stats buffer holding the given `(tag, value)` pairs. The device answers
+    /// on the host socket, so this doesn't wait.
+    pub fn provide_stats(&mut self, stats: &[(u16, u64)]) {
+        let buffer = self.next_buffer(STATS_QUEUE);
+        let stat_size = std::mem::size_of::<BalloonStat>() as u64;
+        assert!(stats.len() as u64 * stat_size <= BUFFER_SIZE);
+        for (i, &(tag, val)) in stats.iter().enumerate() {
+            let stat = BalloonStat {
+                tag: Le16::from(tag),
+                val: Le64::from(val),
+            };
+            self.write(buffer.unchecked_add(i as u64 * stat_size), stat);
+        }
+        self.make_available(STATS_QUEUE, buffer, stats.len() as u64 * stat_size, 0);
+    }
+
+    /// Waits for the device to give back the stats buffer, which it does when the host asks for
+    /// new stats.
+    pub fn wait_stats_request(&mut self) {
+        self.wait_used(STATS_QUEUE);
+    }
+
+    /// Returns the free page hint command id the device last put in the config space.
+    pub fn hint_cmd_id(&self) -> u32 {
+        self.config.free_page_hint_cmd_id.load(Ordering::Relaxed)
+    }
+
+    /// Tells the device which command id the free pages sent next are reported for, or that
+    /// reporting stopped, and waits for the device to process it.
+    pub fn send_hint_cmd_id(&mut self, cmd_id: u32) {
+        let buffer = self.next_buffer(FREE_PAGE_QUEUE);
+        self.write(buffer, Le32::from(cmd_id));
+        self.make_available(FREE_PAGE_QUEUE, buffer, 4, 0);
+        self.wait_used(FREE_PAGE_QUEUE);
+    }
+
+    /// Reports the `len` bytes at `addr` as free and waits for the device to process them.
+    pub fn hint_free_pages(&mut self, addr: GuestAddress, len: u64) {
+        self.make_available(FREE_PAGE_QUEUE, addr, len, VIRTQ_DESC_F_WRITE);
+        self.wait_used(FREE_PAGE_QUEUE);
+    }
+
+    /// Hands the `len` bytes at `addr` to the device as free and waits for it to give them back.
+    pub fn report_free_pages(&mut self, addr: GuestAddress, len: u64) {
+        self.make_available(REPORTING_QUEUE, addr, len, VIRTQ_DESC_F_WRITE);
+        self.wait_used(REPORTING_QUEUE);
+    }
+
+    /// Sets the value free pages are filled with, as a driver using page poisoning does through
+    /// the config space.
+    pub fn set_poison_val(&self, poison_val: u32) {
+        self.config.poison_val.store(poison_val, Ordering::Relaxed);
+    }
+
+    /// Waits for the device to signal a config change.
+    pub fn wait_config_changed(&mut self) {
+        self.wait_interrupt(INTERRUPT_STATUS_CONFIG_CHANGED);
+    }
+
+    fn send_pfns(&mut self, queue: usize, pfns: &[u32]) {
+        let (buffer, len) = self.write_pfns(queue, pfns);
+        self.make_available(queue, buffer, len, 0);
+        self.wait_used(queue);
+    }
+
+    // Writes `pfns` to the next buffer of `queue` and returns its address and length.
+    fn write_pfns(&self, queue: usize, pfns: &[u32]) -> (GuestAddress, u64) {
+        let buffer = self.next_buffer(queue);
+        let pfn_size = std::mem::size_of::<Le32>() as u64;
+        assert!(pfns.len() as u64 * pfn_size <= BUFFER_SIZE);
+        for (i, &pfn) in pfns.iter().enumerate() {
+            self.write(buffer.unchecked_add(i as u64 * pfn_size), Le32::from(pfn));
+        }
+        (buffer, pfns.len() as u64 * pfn_size)
+    }
+
+    fn desc_index(&self, queue: usize) -> u16 {
+        self.avail_idx[queue] % QUEUE_SIZE
+    }
+
+    fn next_buffer(&self, queue: usize) -> GuestAddress {
+        GuestAddress(
+            BUFFERS_BASE
+                + queue as u64 * BUFFERS_SIZE
+                + self.desc_index(queue) as u64 * BUFFER_SIZE,
+        )
+    }
+
+    fn write<T: DataInit>(&self, addr: GuestAddress, val: T) {
+        self.mem
+            .guest_memory()
+            .write_obj_at_addr(val, addr)
+            .expect("failed to write guest memory");
+    }
+
+    fn make_available(&mut self, queue: usize, buffer: GuestAddress, len: u64, flags: u16) {
+        self.add_buffer(queue, buffer, len, flags);
+        self.publish(queue);
+    }
+
+    // Puts a buffer on the available ring of `queue` without telling the device about it.
+    fn add_buffer(&mut self, queue: usize, buffer: GuestAddress, len: u64, flags: u16) {
+        let desc_index = self.desc_index(queue);
+        let q = &self.queues[queue];
+
+        let desc = q.desc_table.unchecked_add(desc_index as u64 * 16);
+        self.write(desc, Le64::from(buffer.offset()));
+        self.write(desc.unchecked_add(8), Le32::from(len as u32));
+        // A single descriptor, which the device only reads unless `flags` says otherwise.
+        self.write(desc.unchecked_add(12), Le16::from(flags));
+        self.write(desc.unchecked_add(14), Le16::from(0));
+
+        let ring_entry = q.avail_ring.unchecked_add(4 + desc_index as u64 * 2);
+        self.write(ring_entry, Le16::from(desc_index));
+        self.avail_idx[queue] = self.avail_idx[queue].wrapping_add(1);
+    }
+
+    // Lets the device see the buffers added to `queue` so far.
+    fn publish(&mut self, queue: usize) {
+        let avail_ring = self.queues[queue].avail_ring;
+        self.write(
+            avail_ring.unchecked_add(2),
+            Le16::from(self.avail_idx[queue]),
+        );
+        self.queue_evts[queue]
+            .write(1)
+            .expect("failed to notify queue");
+    }
+
+    fn used_idx(&self, queue: usize) -> u16 {
+        let idx: Le16 = self
+            .mem
+            .guest_memory()
+            .read_obj_from_addr(self.queues[queue].used_ring.unchecked_add(2))
+            .expect("failed to read used ring");
+        idx.to_native()
+    }
+
+    // Waits until the device has used every buffer made available on `queue`.
+    fn wait_used(&mut self, queue: usize) {
+        while self.used_idx(queue) != self.avail_idx[queue] {
+            self.wait_interrupt(INTERRUPT_STATUS_USED_RING);
+        }
+    }
+
+    fn wait_interrupt(&mut self, status: u32) {
+        let status = status as usize;
+        loop {
+            // Acknowledge everything pending, as the driver does by reading the ISR, so that the
+            // device injects the next interrupt.
+            self.pending_status |= self.interrupt_status.swap(0, Ordering::SeqCst);
+            if self.pending_status & status != 0 {
+                self.pending_status &= !status;
+                return;
+            }
+            self.interrupt_evt
+                .read()
+                .expect("failed to wait for interrupt");
+        }
+    }
+}
+
+impl Default for FakeBalloonGuest {
+    fn default() -> Self {
+        FakeBalloonGuest::new()
+    }
+}
+
+impl Drop for FakeBalloonGuest {
+    fn drop(&mut self) {
+        // Ignore the result because there is nothing we can do with a failure.
+        let _ = self.kill_evt.write(1);
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+/// Returns the guest physical address of `pfn` as the balloon sees it.
+pub fn pfn_to_addr(pfn: u32) -> GuestAddress {
+    GuestAddress(u64::from(pfn) << VIRTIO_BALLOON_PFN_SHIFT)
+}
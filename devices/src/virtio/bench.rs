@@ -0,0 +1,744 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Microbenchmarks for the block and net datapaths that run without a guest.
+//!
+//! `QueueDriver` plays the part of the guest's virtio driver for any `VirtioDevice`. It activates
+//! the device against its own guest memory, puts descriptor chains on the queues, and waits for
+//! the device to use them. `bench_block` and `bench_net_tx` keep a fixed number of synthetic
+//! requests in flight through it for a while and measure how long each took to complete.
+
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::mem::size_of;
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base::{Error as SysError, Event};
+use data_model::{DataInit, Le16, Le32, Le64};
+use disk::DiskFile;
+use net_util::TapT;
+use virtio_sys::virtio_net::virtio_net_hdr_v1;
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
+
+use super::{
+    base_features, Block, BlockConfig, Interrupt, Net, NetError, Queue, QueueStats, VirtioDevice,
+    INTERRUPT_STATUS_USED_RING, VIRTIO_BLK_S_OK, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+    VIRTIO_MSI_NO_VECTOR,
+};
+
+const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+const PAGE_SIZE: u64 = 0x1000;
+// Data buffers are aligned to this so that they can be used for direct I/O.
+const BUFFER_ALIGNMENT: u64 = 0x200;
+
+const SECTOR_SHIFT: u64 = 9;
+// The request header is followed by the data and a status byte.
+const BLOCK_HEADER_SIZE: u64 = 16;
+const BLOCK_DESCRIPTORS_PER_REQUEST: usize = 3;
+
+// The rx queue comes first and is left empty, since nothing is received.
+const NET_TX_QUEUE: usize = 1;
+// A locally administered unicast address the host won't pick up frames for.
+const NET_DEST_MAC: [u8; 6] = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+// IEEE 802 local experimental ethertype, which the host stack drops.
+const NET_ETHERTYPE: [u8; 2] = [0x88, 0xb5];
+const ETH_HEADER_SIZE: usize = 14;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create the block device.
+    CreateBlock(SysError),
+    /// Failed to create an event.
+    CreateEvent(SysError),
+    /// Failed to create the guest memory.
+    CreateGuestMemory(GuestMemoryError),
+    /// Failed to create the net device.
+    CreateNet(NetError),
+    /// The guest memory is too small for the buffers asked for.
+    GuestMemoryFull,
+    /// Failed to access the guest memory.
+    GuestMemoryAccess(GuestMemoryError),
+    /// The request size is not usable with the device.
+    InvalidRequestSize(u64),
+    /// Failed to notify the device of new buffers.
+    NotifyQueue(SysError),
+    /// There aren't enough free descriptors on the queue for the chain.
+    QueueFull,
+    /// The queue depth is zero or does not fit in the queue.
+    QueueDepth(usize),
+    /// Failed to wait for an interrupt from the device.
+    ReadInterrupt(SysError),
+    /// The device completed a request with an error status.
+    RequestFailed(u8),
+    /// The device used a descriptor chain that was not made available.
+    UnexpectedUsedDescriptor(u16),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            CreateBlock(e) => write!(f, "failed to create block device: {}", e),
+            CreateEvent(e) => write!(f, "failed to create event: {}", e),
+            CreateGuestMemory(e) => write!(f, "failed to create guest memory: {}", e),
+            CreateNet(e) => write!(f, "failed to create net device: {}", e),
+            GuestMemoryFull => write!(f, "out of guest memory for buffers"),
+            GuestMemoryAccess(e) => write!(f, "failed to access guest memory: {}", e),
+            InvalidRequestSize(s) => write!(f, "invalid request size {}", s),
+            NotifyQueue(e) => write!(f, "failed to notify queue: {}", e),
+            QueueFull => write!(f, "no free descriptors on the queue"),
+            QueueDepth(d) => write!(f, "queue depth {} does not fit in the queue", d),
+            ReadInterrupt(e) => write!(f, "failed to wait for interrupt: {}", e),
+            RequestFailed(s) => write!(f, "request failed with status {}", s),
+            UnexpectedUsedDescriptor(i) => write!(f, "device used unknown descriptor {}", i),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// A buffer in a descriptor chain.
+#[derive(Copy, Clone, Debug)]
+pub struct ChainBuffer {
+    pub addr: GuestAddress,
+    pub len: u32,
+    /// Whether the device writes to the buffer rather than reading from it.
+    pub writable: bool,
+}
+
+impl ChainBuffer {
+    /// A buffer the device reads from.
+    pub fn readable(addr: GuestAddress, len: u32) -> ChainBuffer {
+        ChainBuffer {
+            addr,
+            len,
+            writable: false,
+        }
+    }
+
+    /// A buffer the device writes to.
+    pub fn writable(addr: GuestAddress, len: u32) -> ChainBuffer {
+        ChainBuffer {
+            addr,
+            len,
+            writable: true,
+        }
+    }
+}
+
+// Offsets of the rings of a queue of `size` entries from the start of its descriptor table, and
+// the space the rings take up.
+fn ring_layout(size: u16) -> (u64, u64, u64) {
+    let size = u64::from(size);
+    let avail_offset = 16 * size;
+    let used_offset = align(avail_offset + 6 + 2 * size, PAGE_SIZE);
+    let total = align(used_offset + 6 + 8 * size, PAGE_SIZE);
+    (avail_offset, used_offset, total)
+}
+
+// `alignment` must be a power of two.
+fn align(val: u64, alignment: u64) -> u64 {
+    (val + alignment - 1) & !(alignment - 1)
+}
+
+/// Drives the queues of a virtio device the way a guest driver does.
+pub struct QueueDriver {
+    mem: GuestMemory,
+    queues: Vec<Queue>,
+    queue_evts: Vec<Event>,
+    // Free descriptor indices of each queue.
+    free_descs: Vec<Vec<u16>>,
+    // The descriptors of each chain made available and not yet used, indexed by queue and head.
+    chains: Vec<HashMap<u16, Vec<u16>>>,
+    avail_idx: Vec<u16>,
+    last_used_idx: Vec<u16>,
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_evt: Event,
+    next_buffer: u64,
+    mem_size: u64,
+}
+
+impl QueueDriver {
+    /// Acks every feature `device` offers and activates it with the largest queues it supports.
+    /// `buffer_size` bytes of guest memory are left for the buffers handed out by `alloc`.
+    pub fn new(device: &mut dyn VirtioDevice, buffer_size: u64) -> Result<QueueDriver> {
+        let sizes = device.queue_max_sizes().to_vec();
+        let rings_size: u64 = sizes.iter().map(|&s| ring_layout(s).2).sum();
+        let mem_size = rings_size + align(buffer_size, PAGE_SIZE);
+        let mem =
+            GuestMemory::new(&[(GuestAddress(0), mem_size)]).map_err(Error::CreateGuestMemory)?;
+
+        let mut queues = Vec::with_capacity(sizes.len());
+        let mut rings = 0;
+        for &size in &sizes {
+            let (avail_offset, used_offset, total) = ring_layout(size);
+            let mut queue = Queue::new(size);
+            queue.ready = true;
+            queue.desc_table = GuestAddress(rings);
+            queue.avail_ring = GuestAddress(rings + avail_offset);
+            queue.used_ring = GuestAddress(rings + used_offset);
+            queues.push(queue);
+            rings += total;
+        }
+        let queue_evts = sizes
+            .iter()
+            .map(|_| Event::new().map_err(Error::CreateEvent))
+            .collect::<Result<Vec<Event>>>()?;
+        let device_evts = queue_evts
+            .iter()
+            .map(|e| e.try_clone().map_err(Error::CreateEvent))
+            .collect::<Result<Vec<Event>>>()?;
+
+        let interrupt_status = Arc::new(AtomicUsize::new(0));
+        let interrupt_evt = Event::new().map_err(Error::CreateEvent)?;
+        let interrupt = Interrupt::new(
+            interrupt_status.clone(),
+            interrupt_evt.try_clone().map_err(Error::CreateEvent)?,
+            Event::new().map_err(Error::CreateEvent)?,
+            None,
+            VIRTIO_MSI_NO_VECTOR,
+        );
+
+        device.ack_features(device.features());
+        device.activate(mem.clone(), interrupt, queues.clone(), device_evts);
+
+        Ok(QueueDriver {
+            mem,
+            free_descs: sizes.iter().map(|&s| (0..s).rev().collect()).collect(),
+            chains: sizes.iter().map(|_| HashMap::new()).collect(),
+            avail_idx: vec![0; sizes.len()],
+            last_used_idx: vec![0; sizes.len()],
+            queues,
+            queue_evts,
+            interrupt_status,
+            interrupt_evt,
+            next_buffer: rings,
+            mem_size,
+        })
+    }
+
+    /// Returns the guest memory shared with the device.
+    pub fn memory(&self) -> &GuestMemory {
+        &self.mem
+    }
+
+    /// Returns the counters the device keeps for `queue`.
+    pub fn queue_stats(&self, queue: usize) -> QueueStats {
+        self.queues[queue].stats()
+    }
+
+    /// Sets aside `len` bytes of guest memory for buffers.
+    pub fn alloc(&mut self, len: u64) -> Result<GuestAddress> {
+        let addr = align(self.next_buffer, BUFFER_ALIGNMENT);
+        let end = addr.checked_add(len).ok_or(Error::GuestMemoryFull)?;
+        if end > self.mem_size {
+            return Err(Error::GuestMemoryFull);
+        }
+        self.next_buffer = end;
+        Ok(GuestAddress(addr))
+    }
+
+    /// Puts a chain of `buffers` on the available ring of `queue` and returns the index of its
+    /// head descriptor. The device isn't told about it until `notify` is called.
+    pub fn add_chain(&mut self, queue: usize, buffers: &[ChainBuffer]) -> Result<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_descs[queue].len() {
+            return Err(Error::QueueFull);
+        }
+        let free = &mut self.free_descs[queue];
+        let descs = free.split_off(free.len() - buffers.len());
+
+        let desc_table = self.queues[queue].desc_table;
+        for (i, (buffer, &index)) in buffers.iter().zip(descs.iter()).enumerate() {
+            let mut flags = 0;
+            if buffer.writable {
+                flags |= VIRTQ_DESC_F_WRITE;
+            }
+            let next = descs.get(i + 1).copied();
+            if next.is_some() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            let desc = desc_table.unchecked_add(u64::from(index) * 16);
+            self.write(desc, Le64::from(buffer.addr.offset()))?;
+            self.write(desc.unchecked_add(8), Le32::from(buffer.len))?;
+            self.write(desc.unchecked_add(12), Le16::from(flags))?;
+            self.write(desc.unchecked_add(14), Le16::from(next.unwrap_or(0)))?;
+        }
+
+        let head = descs[0];
+        let q = &self.queues[queue];
+        let avail_ring = q.avail_ring;
+        let slot = self.avail_idx[queue] % q.size;
+        self.write(
+            avail_ring.unchecked_add(4 + u64::from(slot) * 2),
+            Le16::from(head),
+        )?;
+        let avail_idx = self.avail_idx[queue].wrapping_add(1);
+        self.write(avail_ring.unchecked_add(2), Le16::from(avail_idx))?;
+        self.avail_idx[queue] = avail_idx;
+        self.chains[queue].insert(head, descs);
+        Ok(head)
+    }
+
+    /// Tells the device there are new buffers on `queue`.
+    pub fn notify(&self, queue: usize) -> Result<()> {
+        self.queue_evts[queue].write(1).map_err(Error::NotifyQueue)
+    }
+
+    /// Waits for the device to use at least one chain on `queue` and returns the head index and
+    /// written length of every chain it used since the last call.
+    pub fn wait_used(&mut self, queue: usize) -> Result<Vec<(u16, u32)>> {
+        loop {
+            let used = self.take_used(queue)?;
+            if !used.is_empty() {
+                return Ok(used);
+            }
+            // Acknowledge the interrupt, as the driver does by reading the ISR, so that the device
+            // injects the next one, and check the ring again if one was already pending.
+            let status = self.interrupt_status.swap(0, Ordering::SeqCst);
+            if status & INTERRUPT_STATUS_USED_RING as usize == 0 {
+                self.interrupt_evt.read().map_err(Error::ReadInterrupt)?;
+            }
+        }
+    }
+
+    fn take_used(&mut self, queue: usize) -> Result<Vec<(u16, u32)>> {
+        let used_ring = self.queues[queue].used_ring;
+        let size = self.queues[queue].size;
+        let used_idx: Le16 = self.read(used_ring.unchecked_add(2))?;
+        let used_idx = used_idx.to_native();
+
+        let mut used = Vec::new();
+        while self.last_used_idx[queue] != used_idx {
+            let slot = self.last_used_idx[queue] % size;
+            let elem = used_ring.unchecked_add(4 + u64::from(slot) * 8);
+            let id: Le32 = self.read(elem)?;
+            let len: Le32 = self.read(elem.unchecked_add(4))?;
+            let head = id.to_native() as u16;
+            let descs = self.chains[queue]
+                .remove(&head)
+                .ok_or(Error::UnexpectedUsedDescriptor(head))?;
+            self.free_descs[queue].extend(descs.into_iter().rev());
+            used.push((head, len.to_native()));
+            self.last_used_idx[queue] = self.last_used_idx[queue].wrapping_add(1);
+        }
+        Ok(used)
+    }
+
+    fn write<T: DataInit>(&self, addr: GuestAddress, val: T) -> Result<()> {
+        self.mem
+            .write_obj_at_addr(val, addr)
+            .map_err(Error::GuestMemoryAccess)
+    }
+
+    fn read<T: DataInit>(&self, addr: GuestAddress) -> Result<T> {
+        self.mem
+            .read_obj_from_addr(addr)
+            .map_err(Error::GuestMemoryAccess)
+    }
+}
+
+/// How long and how hard to load a device.
+#[derive(Clone, Debug)]
+pub struct BenchParameters {
+    /// Size in bytes of the data each request carries.
+    pub request_size: u64,
+    /// Number of requests kept in flight.
+    pub queue_depth: usize,
+    /// How long to keep submitting requests for.
+    pub duration: Duration,
+}
+
+impl Default for BenchParameters {
+    fn default() -> Self {
+        BenchParameters {
+            request_size: 4096,
+            queue_depth: 16,
+            duration: Duration::from_secs(10),
+        }
+    }
+}
+
+/// What a benchmark measured.
+#[derive(Clone, Debug, Default)]
+pub struct BenchResult {
+    /// Number of requests completed.
+    pub requests: u64,
+    /// Bytes of data carried by the completed requests.
+    pub bytes: u64,
+    /// Time from the first request being submitted to the last completing.
+    pub elapsed: Duration,
+    /// Time each request took to complete, from shortest to longest.
+    pub latencies: Vec<Duration>,
+    /// What the device counted on the queue the requests went through.
+    pub queue_stats: QueueStats,
+}
+
+impl BenchResult {
+    /// Returns the latency below which `percent` of the requests completed.
+    pub fn latency_percentile(&self, percent: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::default();
+        }
+        let rank = (percent / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[min(max(rank, 1), self.latencies.len()) - 1]
+    }
+
+    fn mean_latency(&self) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::default();
+        }
+        self.latencies.iter().sum::<Duration>() / self.latencies.len() as u32
+    }
+}
+
+impl Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        let (iops, mib_per_sec) = if secs > 0.0 {
+            (
+                self.requests as f64 / secs,
+                self.bytes as f64 / secs / (1 << 20) as f64,
+            )
+        } else {
+            (0.0, 0.0)
+        };
+        writeln!(
+            f,
+            "{} requests in {:.2}s: {:.0} requests/s, {:.2} MiB/s",
+            self.requests, secs, iops, mib_per_sec
+        )?;
+        writeln!(
+            f,
+            "latency: mean {:?}, p50 {:?}, p99 {:?}, max {:?}",
+            self.mean_latency(),
+            self.latency_percentile(50.0),
+            self.latency_percentile(99.0),
+            self.latencies.last().copied().unwrap_or_default()
+        )?;
+        write!(
+            f,
+            "interrupts: {}, notifications suppressed: {}",
+            self.queue_stats.interrupts, self.queue_stats.notifications_suppressed
+        )
+    }
+}
+
+// Puts the requests of one benchmark on a queue. Each of the requests in flight has a slot of its
+// own buffers.
+trait Workload {
+    // Makes the request of `slot` available and returns the head of its chain.
+    fn submit(&mut self, driver: &mut QueueDriver, slot: usize) -> Result<u16>;
+
+    // Checks the outcome of the request of `slot` once the device used it.
+    fn complete(&mut self, _driver: &mut QueueDriver, _slot: usize) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Keeps `params.queue_depth` requests from `workload` in flight on `queue` until
+// `params.duration` is up, then waits for the last of them.
+fn run_workload<W: Workload>(
+    driver: &mut QueueDriver,
+    queue: usize,
+    workload: &mut W,
+    params: &BenchParameters,
+) -> Result<BenchResult> {
+    let stats_before = driver.queue_stats(queue);
+    let mut result = BenchResult::default();
+    let mut in_flight = HashMap::new();
+
+    let start = Instant::now();
+    for slot in 0..params.queue_depth {
+        let head = workload.submit(driver, slot)?;
+        in_flight.insert(head, (slot, Instant::now()));
+    }
+    driver.notify(queue)?;
+
+    while !in_flight.is_empty() {
+        let used = driver.wait_used(queue)?;
+        let now = Instant::now();
+        let submitting = now.duration_since(start) < params.duration;
+        for (head, _len) in used {
+            let (slot, submitted) = in_flight
+                .remove(&head)
+                .ok_or(Error::UnexpectedUsedDescriptor(head))?;
+            workload.complete(driver, slot)?;
+            result.requests += 1;
+            result.bytes += params.request_size;
+            result.latencies.push(now.duration_since(submitted));
+            if submitting {
+                let head = workload.submit(driver, slot)?;
+                in_flight.insert(head, (slot, Instant::now()));
+            }
+        }
+        if submitting {
+            driver.notify(queue)?;
+        }
+    }
+
+    result.elapsed = start.elapsed();
+    result.latencies.sort_unstable();
+    let stats = driver.queue_stats(queue);
+    result.queue_stats = QueueStats {
+        descriptors: stats.descriptors - stats_before.descriptors,
+        bytes: stats.bytes - stats_before.bytes,
+        notifications_suppressed: stats.notifications_suppressed
+            - stats_before.notifications_suppressed,
+        interrupts: stats.interrupts - stats_before.interrupts,
+    };
+    Ok(result)
+}
+
+/// The kind of requests `bench_block` sends.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BlockBenchOp {
+    Read,
+    Write,
+}
+
+struct BlockWorkload {
+    op: BlockBenchOp,
+    request_size: u64,
+    // Requests go to consecutive offsets, wrapping around at the end of the disk.
+    num_requests: u64,
+    next_request: u64,
+    // The header, data, and status buffers of each slot.
+    slots: Vec<(GuestAddress, GuestAddress, GuestAddress)>,
+}
+
+impl Workload for BlockWorkload {
+    fn submit(&mut self, driver: &mut QueueDriver, slot: usize) -> Result<u16> {
+        let (header, data, status) = self.slots[slot];
+        let (req_type, data_buffer) = match self.op {
+            BlockBenchOp::Read => (
+                VIRTIO_BLK_T_IN,
+                ChainBuffer::writable(data, self.request_size as u32),
+            ),
+            BlockBenchOp::Write => (
+                VIRTIO_BLK_T_OUT,
+                ChainBuffer::readable(data, self.request_size as u32),
+            ),
+        };
+        let sector = (self.next_request * self.request_size) >> SECTOR_SHIFT;
+        self.next_request = (self.next_request + 1) % self.num_requests;
+
+        driver.write(header, Le32::from(req_type))?;
+        driver.write(header.unchecked_add(4), Le32::from(0))?;
+        driver.write(header.unchecked_add(8), Le64::from(sector))?;
+        driver.write(status, 0xffu8)?;
+        driver.add_chain(
+            0,
+            &[
+                ChainBuffer::readable(header, BLOCK_HEADER_SIZE as u32),
+                data_buffer,
+                ChainBuffer::writable(status, 1),
+            ],
+        )
+    }
+
+    fn complete(&mut self, driver: &mut QueueDriver, slot: usize) -> Result<()> {
+        let status: u8 = driver.read(self.slots[slot].2)?;
+        if status != VIRTIO_BLK_S_OK {
+            return Err(Error::RequestFailed(status));
+        }
+        Ok(())
+    }
+}
+
+/// Reads or writes `disk` through a virtio block device and measures how fast the requests
+/// complete. Requests of `params.request_size` bytes go to consecutive offsets from the start of
+/// the disk.
+///
+/// Writing overwrites the contents of the disk.
+pub fn bench_block(
+    disk: Box<dyn DiskFile>,
+    op: BlockBenchOp,
+    busy_poll: Option<Duration>,
+    interrupt_interval: Option<Duration>,
+    params: &BenchParameters,
+) -> Result<BenchResult> {
+    let mut block = Block::new(
+        base_features(false),
+        disk,
+        None,
+        BlockConfig {
+            read_only: op == BlockBenchOp::Read,
+            sparse: false,
+            block_size: 1 << SECTOR_SHIFT,
+            busy_poll,
+            interrupt_interval,
+            ..Default::default()
+        },
+        None,
+        None,
+    )
+    .map_err(Error::CreateBlock)?;
+
+    // The capacity in sectors leads the config space.
+    let mut capacity = [0u8; 8];
+    block.read_config(0, &mut capacity);
+    let disk_size = u64::from_le_bytes(capacity) << SECTOR_SHIFT;
+    let request_size = params.request_size;
+    if request_size == 0
+        || request_size > u64::from(u32::MAX)
+        || align(request_size, 1 << SECTOR_SHIFT) != request_size
+        || request_size > disk_size
+    {
+        return Err(Error::InvalidRequestSize(request_size));
+    }
+
+    let queue_size = usize::from(block.queue_max_sizes()[0]);
+    let depth = params.queue_depth;
+    if depth == 0 || depth * BLOCK_DESCRIPTORS_PER_REQUEST > queue_size {
+        return Err(Error::QueueDepth(depth));
+    }
+
+    let slot_size = align(BLOCK_HEADER_SIZE, BUFFER_ALIGNMENT)
+        + align(request_size, BUFFER_ALIGNMENT)
+        + BUFFER_ALIGNMENT;
+    let mut driver = QueueDriver::new(&mut block, slot_size * depth as u64)?;
+    let slots = (0..depth)
+        .map(|_| {
+            Ok((
+                driver.alloc(BLOCK_HEADER_SIZE)?,
+                driver.alloc(request_size)?,
+                driver.alloc(1)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut workload = BlockWorkload {
+        op,
+        request_size,
+        num_requests: disk_size / request_size,
+        next_request: 0,
+        slots,
+    };
+    run_workload(&mut driver, 0, &mut workload, params)
+}
+
+struct NetTxWorkload {
+    frame_len: u32,
+    frames: Vec<GuestAddress>,
+}
+
+impl Workload for NetTxWorkload {
+    fn submit(&mut self, driver: &mut QueueDriver, slot: usize) -> Result<u16> {
+        driver.add_chain(
+            NET_TX_QUEUE,
+            &[ChainBuffer::readable(self.frames[slot], self.frame_len)],
+        )
+    }
+}
+
+/// Sends frames through a virtio net device transmitting on `tap` and measures how fast the
+/// device goes through them. Each frame carries `params.request_size` bytes of payload, and is
+/// addressed so that the host drops it.
+pub fn bench_net_tx<T: 'static + TapT>(
+    tap: T,
+    interrupt_interval: Option<Duration>,
+    params: &BenchParameters,
+) -> Result<BenchResult> {
+    let payload_size = params.request_size as usize;
+    let vnet_hdr_size = size_of::<virtio_net_hdr_v1>();
+    let frame_len = vnet_hdr_size + ETH_HEADER_SIZE + payload_size;
+    if payload_size == 0 || frame_len > u32::MAX as usize {
+        return Err(Error::InvalidRequestSize(params.request_size));
+    }
+
+    let mut net = Net::from(base_features(false), tap, 1, interrupt_interval, None)
+        .map_err(Error::CreateNet)?;
+
+    let depth = params.queue_depth;
+    if depth == 0 || depth > usize::from(net.queue_max_sizes()[NET_TX_QUEUE]) {
+        return Err(Error::QueueDepth(depth));
+    }
+
+    let mut driver = QueueDriver::new(
+        &mut net,
+        align(frame_len as u64, BUFFER_ALIGNMENT) * depth as u64,
+    )?;
+    let mut frames = Vec::with_capacity(depth);
+    for _ in 0..depth {
+        // The vnet header stays zeroed: no offloads are asked for.
+        let frame = driver.alloc(frame_len as u64)?;
+        let eth = frame.unchecked_add(vnet_hdr_size as u64);
+        driver
+            .memory()
+            .write_all_at_addr(&NET_DEST_MAC, eth)
+            .map_err(Error::GuestMemoryAccess)?;
+        driver
+            .memory()
+            .write_all_at_addr(&NET_ETHERTYPE, eth.unchecked_add(12))
+            .map_err(Error::GuestMemoryAccess)?;
+        frames.push(frame);
+    }
+
+    let mut workload = NetTxWorkload {
+        frame_len: frame_len as u32,
+        frames,
+    };
+    run_workload(&mut driver, NET_TX_QUEUE, &mut workload, params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempfile;
+
+    fn params(queue_depth: usize) -> BenchParameters {
+        BenchParameters {
+            request_size: 4096,
+            queue_depth,
+            duration: Duration::from_millis(50),
+        }
+    }
+
+    #[test]
+    fn block_read_write() {
+        let f = tempfile().unwrap();
+        f.set_len(0x100000).unwrap();
+        for &op in &[BlockBenchOp::Write, BlockBenchOp::Read] {
+            let disk = Box::new(f.try_clone().unwrap());
+            let result = bench_block(disk, op, None, None, &params(4)).unwrap();
+            assert!(result.requests > 0);
+            assert_eq!(result.bytes, result.requests * 4096);
+            assert_eq!(result.latencies.len() as u64, result.requests);
+            assert_eq!(result.queue_stats.descriptors, result.requests);
+        }
+    }
+
+    #[test]
+    fn block_queue_depth_checked() {
+        let f = tempfile().unwrap();
+        f.set_len(0x100000).unwrap();
+        for &depth in &[0, 1000] {
+            let disk = Box::new(f.try_clone().unwrap());
+            match bench_block(disk, BlockBenchOp::Read, None, None, &params(depth)) {
+                Err(Error::QueueDepth(d)) => assert_eq!(d, depth),
+                r => panic!("unexpected result {:?}", r.map(|r| r.requests)),
+            }
+        }
+    }
+
+    #[test]
+    fn percentiles() {
+        let result = BenchResult {
+            latencies: (1..=100).map(Duration::from_micros).collect(),
+            ..Default::default()
+        };
+        assert_eq!(result.latency_percentile(50.0), Duration::from_micros(50));
+        assert_eq!(result.latency_percentile(99.0), Duration::from_micros(99));
+        assert_eq!(result.latency_percentile(0.0), Duration::from_micros(1));
+        assert_eq!(result.mean_latency(), Duration::from_nanos(50500));
+    }
+}
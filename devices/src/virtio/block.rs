@@ -2,36 +2,50 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::cell::{Cell, RefCell};
 use std::cmp::{max, min};
+use std::convert::TryFrom;
 use std::fmt::{self, Display};
+use std::fs::File;
+use std::future::Future;
 use std::io::{self, Write};
 use std::mem::size_of;
+use std::pin::Pin;
 use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::u32;
 
 use base::Error as SysError;
 use base::Result as SysResult;
 use base::{
-    error, info, iov_max, warn, AsRawDescriptor, Event, PollToken, RawDescriptor, Timer,
-    WaitContext,
+    error, info, iov_max, trace_event, warn, AsRawDescriptor, Event, PollToken, RawDescriptor,
+    Timer, TimerFd, WaitContext,
 };
+use cros_async::{async_from, AsyncError, EventAsync, IoSourceExt};
 use data_model::{DataInit, Le16, Le32, Le64};
-use disk::DiskFile;
-use msg_socket::{MsgReceiver, MsgSender};
+use disk::{AsyncDisk, DiskFile, SingleFileDisk};
+use futures::future::{self, select, select_all, Either, FusedFuture};
+use futures::stream::FuturesUnordered;
+use futures::{pin_mut, FutureExt, StreamExt};
+use msg_socket::{MsgError, MsgReceiver, MsgSender};
 use sync::Mutex;
 use vm_control::{DiskControlCommand, DiskControlResponseSocket, DiskControlResult};
 use vm_memory::GuestMemory;
 
 use super::{
-    copy_config, DescriptorChain, DescriptorError, Interrupt, Queue, Reader, VirtioDevice, Writer,
-    TYPE_BLOCK,
+    copy_config, BusyPoll, CoalescingInterval, DescriptorChain, DescriptorError, Interrupt,
+    InterruptCoalescer, IoKind, IoStats, IoThrottle, Queue, Reader, ThrottleLimits, VirtioDevice,
+    Writer, ZoneError, ZoneOp, ZonedDisk, TYPE_BLOCK, VIRTIO_F_RING_PACKED,
 };
+use crate::suspendable;
+use crate::{Suspendable, SuspendableError};
 
 const QUEUE_SIZE: u16 = 256;
-const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+// Maximum number of requests per queue with disk I/O in flight when the worker runs on io_uring.
+const MAX_IN_FLIGHT: usize = 32;
 const SECTOR_SHIFT: u8 = 9;
 const SECTOR_SIZE: u64 = 0x01 << SECTOR_SHIFT;
 const MAX_DISCARD_SECTORS: u32 = u32::MAX;
@@ -43,6 +57,9 @@ const MAX_WRITE_ZEROES_SEG: u32 = 32;
 // but this should probably be based on cluster size for qcow.
 const DISCARD_SECTOR_ALIGNMENT: u32 = 128;
 
+// Delay after a write when the file is auto-flushed.
+const FLUSH_DELAY: Duration = Duration::from_secs(60);
+
 const ID_LEN: usize = 20;
 
 /// Virtio block device identifier.
@@ -50,23 +67,39 @@ const ID_LEN: usize = 20;
 /// in which case the \0 terminator is omitted.
 pub type BlockId = [u8; ID_LEN];
 
-const VIRTIO_BLK_T_IN: u32 = 0;
-const VIRTIO_BLK_T_OUT: u32 = 1;
+pub(crate) const VIRTIO_BLK_T_IN: u32 = 0;
+pub(crate) const VIRTIO_BLK_T_OUT: u32 = 1;
 const VIRTIO_BLK_T_FLUSH: u32 = 4;
 const VIRTIO_BLK_T_GET_ID: u32 = 8;
 const VIRTIO_BLK_T_DISCARD: u32 = 11;
 const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
-
-const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_T_ZONE_APPEND: u32 = 15;
+const VIRTIO_BLK_T_ZONE_REPORT: u32 = 16;
+const VIRTIO_BLK_T_ZONE_OPEN: u32 = 18;
+const VIRTIO_BLK_T_ZONE_CLOSE: u32 = 20;
+const VIRTIO_BLK_T_ZONE_FINISH: u32 = 22;
+const VIRTIO_BLK_T_ZONE_RESET: u32 = 24;
+const VIRTIO_BLK_T_ZONE_RESET_ALL: u32 = 26;
+
+pub(crate) const VIRTIO_BLK_S_OK: u8 = 0;
 const VIRTIO_BLK_S_IOERR: u8 = 1;
 const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+const VIRTIO_BLK_S_ZONE_INVALID_CMD: u8 = 3;
+const VIRTIO_BLK_S_ZONE_UNALIGNED_WP: u8 = 4;
+const VIRTIO_BLK_S_ZONE_OPEN_RESOURCE: u8 = 5;
+const VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE: u8 = 6;
 
 const VIRTIO_BLK_F_SEG_MAX: u32 = 2;
 const VIRTIO_BLK_F_RO: u32 = 5;
 const VIRTIO_BLK_F_BLK_SIZE: u32 = 6;
 const VIRTIO_BLK_F_FLUSH: u32 = 9;
+const VIRTIO_BLK_F_MQ: u32 = 12;
 const VIRTIO_BLK_F_DISCARD: u32 = 13;
 const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
+const VIRTIO_BLK_F_ZONED: u32 = 17;
+
+// The zoned model of a device whose zones have to be written sequentially.
+const VIRTIO_BLK_Z_HM: u8 = 1;
 
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
@@ -91,6 +124,21 @@ struct virtio_blk_topology {
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for virtio_blk_topology {}
 
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_blk_zoned_characteristics {
+    zone_sectors: Le32,
+    max_open_zones: Le32,
+    max_active_zones: Le32,
+    max_append_sectors: Le32,
+    write_granularity: Le32,
+    model: u8,
+    unused2: [u8; 3],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_blk_zoned_characteristics {}
+
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 struct virtio_blk_config {
@@ -101,7 +149,8 @@ struct virtio_blk_config {
     blk_size: Le32,
     topology: virtio_blk_topology,
     writeback: u8,
-    unused0: [u8; 3],
+    unused0: u8,
+    num_queues: Le16,
     max_discard_sectors: Le32,
     max_discard_seg: Le32,
     discard_sector_alignment: Le32,
@@ -109,6 +158,10 @@ struct virtio_blk_config {
     max_write_zeroes_seg: Le32,
     write_zeroes_may_unmap: u8,
     unused1: [u8; 3],
+    max_secure_erase_sectors: Le32,
+    max_secure_erase_seg: Le32,
+    secure_erase_sector_alignment: Le32,
+    zoned: virtio_blk_zoned_characteristics,
 }
 
 // Safe because it only has data and has no implicit padding.
@@ -138,6 +191,33 @@ const VIRTIO_BLK_DISCARD_WRITE_ZEROES_FLAG_UNMAP: u32 = 1 << 0;
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for virtio_blk_discard_write_zeroes {}
 
+// The reserved bytes are split up since `DataInit` is only implemented for arrays of up to 32.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_blk_zone_report {
+    nr_zones: Le64,
+    reserved0: [u8; 32],
+    reserved1: [u8; 24],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_blk_zone_report {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_blk_zone_descriptor {
+    z_cap: Le64,
+    z_start: Le64,
+    z_wp: Le64,
+    z_type: u8,
+    z_state: u8,
+    reserved0: [u8; 6],
+    reserved1: [u8; 32],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_blk_zone_descriptor {}
+
 #[derive(Debug)]
 enum ExecuteError {
     CopyId(io::Error),
@@ -146,17 +226,28 @@ enum ExecuteError {
     WriteStatus(io::Error),
     /// Error arming the flush timer.
     Flush(io::Error),
+    FlushAsync(disk::Error),
     ReadIo {
         length: usize,
         sector: u64,
         desc_error: io::Error,
     },
+    ReadIoAsync {
+        length: usize,
+        sector: u64,
+        disk_error: disk::Error,
+    },
     Timer(SysError),
     WriteIo {
         length: usize,
         sector: u64,
         desc_error: io::Error,
     },
+    WriteIoAsync {
+        length: usize,
+        sector: u64,
+        disk_error: disk::Error,
+    },
     DiscardWriteZeroes {
         ioerr: Option<io::Error>,
         sector: u64,
@@ -169,6 +260,8 @@ enum ExecuteError {
     OutOfRange,
     MissingStatus,
     Unsupported(u32),
+    WriteZoneReport(io::Error),
+    Zone(ZoneError),
 }
 
 impl Display for ExecuteError {
@@ -181,6 +274,7 @@ impl Display for ExecuteError {
             Read(e) => write!(f, "failed to read message: {}", e),
             WriteStatus(e) => write!(f, "failed to write request status: {}", e),
             Flush(e) => write!(f, "failed to flush: {}", e),
+            FlushAsync(e) => write!(f, "failed to flush: {}", e),
             ReadIo {
                 length,
                 sector,
@@ -190,6 +284,15 @@ impl Display for ExecuteError {
                 "io error reading {} bytes from sector {}: {}",
                 length, sector, desc_error,
             ),
+            ReadIoAsync {
+                length,
+                sector,
+                disk_error,
+            } => write!(
+                f,
+                "io error reading {} bytes from sector {}: {}",
+                length, sector, disk_error,
+            ),
             Timer(e) => write!(f, "{}", e),
             WriteIo {
                 length,
@@ -200,6 +303,15 @@ impl Display for ExecuteError {
                 "io error writing {} bytes to sector {}: {}",
                 length, sector, desc_error,
             ),
+            WriteIoAsync {
+                length,
+                sector,
+                disk_error,
+            } => write!(
+                f,
+                "io error writing {} bytes to sector {}: {}",
+                length, sector, disk_error,
+            ),
             DiscardWriteZeroes {
                 ioerr: Some(ioerr),
                 sector,
@@ -224,6 +336,8 @@ impl Display for ExecuteError {
             OutOfRange => write!(f, "out of range"),
             MissingStatus => write!(f, "not enough space in descriptor chain to write status"),
             Unsupported(n) => write!(f, "unsupported ({})", n),
+            WriteZoneReport(e) => write!(f, "failed to write zone report: {}", e),
+            Zone(e) => write!(f, "{}", e),
         }
     }
 }
@@ -236,27 +350,708 @@ impl ExecuteError {
             ExecuteError::Read(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::WriteStatus(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Flush(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::FlushAsync(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::ReadIo { .. } => VIRTIO_BLK_S_IOERR,
+            ExecuteError::ReadIoAsync { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::Timer(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::WriteIo { .. } => VIRTIO_BLK_S_IOERR,
+            ExecuteError::WriteIoAsync { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::DiscardWriteZeroes { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::ReadOnly { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::OutOfRange { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::MissingStatus => VIRTIO_BLK_S_IOERR,
             ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
+            ExecuteError::WriteZoneReport(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Zone(ZoneError::ActiveResource) => VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE,
+            ExecuteError::Zone(ZoneError::InvalidCommand) => VIRTIO_BLK_S_ZONE_INVALID_CMD,
+            ExecuteError::Zone(ZoneError::Io(_)) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Zone(ZoneError::OpenResource) => VIRTIO_BLK_S_ZONE_OPEN_RESOURCE,
+            ExecuteError::Zone(ZoneError::UnalignedWritePointer) => VIRTIO_BLK_S_ZONE_UNALIGNED_WP,
+        }
+    }
+}
+
+// Errors that stop a worker running on io_uring.
+#[derive(Debug)]
+enum AsyncWorkerError {
+    /// Creating an async source failed.
+    CreateAsyncSource(AsyncError),
+    /// Converting the disk image for async access failed.
+    CreateAsyncDisk(disk::Error),
+    /// Creating a timer failed.
+    CreateTimer(SysError),
+    /// Cloning an event failed.
+    CloneEvent(SysError),
+    /// Arming the interrupt coalescing timer failed.
+    ArmTimer(SysError),
+    /// Flushing the disk when the flush timer expired failed.
+    Flush(disk::Error),
+    /// Reading an event or timer failed.
+    ReadEvent(AsyncError),
+    /// Receiving a control request failed.
+    ReadControl(MsgError),
+    /// Replying to a control request failed.
+    WriteControl(MsgError),
+    /// Running the executor failed.
+    RunExecutor(cros_async::Error),
+}
+
+impl Display for AsyncWorkerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::AsyncWorkerError::*;
+
+        match self {
+            CreateAsyncSource(e) => write!(f, "failed to create async source: {}", e),
+            CreateAsyncDisk(e) => write!(f, "failed to open disk image for async access: {}", e),
+            CreateTimer(e) => write!(f, "failed to create timer: {}", e),
+            CloneEvent(e) => write!(f, "failed to clone event: {}", e),
+            ArmTimer(e) => write!(f, "failed to arm interrupt coalescing timer: {}", e),
+            Flush(e) => write!(f, "failed to flush the disk: {}", e),
+            ReadEvent(e) => write!(f, "failed to read event: {}", e),
+            ReadControl(e) => write!(f, "failed to receive control request: {}", e),
+            WriteControl(e) => write!(f, "failed to reply to control request: {}", e),
+            RunExecutor(e) => write!(f, "failed to run executor: {}", e),
+        }
+    }
+}
+
+// What a request needs from the worker running on io_uring that executes it.
+struct AsyncRequestContext<'a> {
+    mem: &'a GuestMemory,
+    disk: &'a dyn AsyncDisk,
+    // Discard and write zeroes requests go through the shared disk image, like on the threaded
+    // worker.
+    sync_disk: &'a Mutex<Box<dyn DiskFile>>,
+    disk_size: &'a Mutex<u64>,
+    read_only: bool,
+    sparse: &'a AtomicBool,
+    write_through: bool,
+    coalesce_flushes: bool,
+    id: Option<BlockId>,
+    stats: &'a IoStats,
+    flush_timer: &'a dyn IoSourceExt<TimerFd>,
+    flush_timer_armed: &'a Cell<bool>,
+}
+
+// What completes when a future in flight on io_uring does.
+enum Completion {
+    // The request with this descriptor index, and the length to add to the used ring.
+    Request(u16, usize),
+    // A batch of coalesced flushes.
+    Flushes(Vec<(u16, usize)>),
+}
+
+fn resize_disk(
+    disk_image: &Mutex<Box<dyn DiskFile>>,
+    disk_size: &Mutex<u64>,
+    sparse: &AtomicBool,
+    read_only: bool,
+    new_size: u64,
+) -> DiskControlResult {
+    if read_only {
+        error!("Attempted to resize read-only block device");
+        return DiskControlResult::Err(SysError::new(libc::EROFS));
+    }
+
+    info!("Resizing block device to {} bytes", new_size);
+
+    let mut disk_image = disk_image.lock();
+    if let Err(e) = disk_image.resize(new_size) {
+        error!("Resizing disk failed! {}", e);
+        // Shrinking a disk that holds data past the new size fails with EINVAL.
+        return DiskControlResult::Err(SysError::new(e.raw_os_error().unwrap_or(libc::EIO)));
+    }
+
+    // Allocate new space if the disk image is not sparse.
+    if let Err(e) = disk_image.allocate(0, new_size) {
+        error!("Allocating disk space after resize failed! {}", e);
+        return DiskControlResult::Err(SysError::new(libc::EIO));
+    }
+
+    sparse.store(false, Ordering::Relaxed);
+
+    if let Ok(new_disk_size) = disk_image.get_len() {
+        let mut disk_size = disk_size.lock();
+        *disk_size = new_disk_size;
+    }
+    DiskControlResult::Ok
+}
+
+// Check that a request accesses only data within the disk's current size.
+// All parameters are in units of bytes.
+fn check_range(io_start: u64, io_length: u64, disk_size: u64) -> result::Result<(), ExecuteError> {
+    let io_end = io_start
+        .checked_add(io_length)
+        .ok_or(ExecuteError::OutOfRange)?;
+    if io_end > disk_size {
+        Err(ExecuteError::OutOfRange)
+    } else {
+        Ok(())
+    }
+}
+
+// Returns the number of bytes of data a request reads or writes, which is what it costs under a
+// bandwidth limit.
+fn request_data_len(avail_desc: &DescriptorChain) -> u64 {
+    let len: u64 = avail_desc
+        .clone()
+        .into_iter()
+        .map(|desc| u64::from(desc.len))
+        .sum();
+    len.saturating_sub(size_of::<virtio_blk_req_header>() as u64 + 1)
+}
+
+// Returns the bytes of the disk covered by the segments of a discard or write zeroes request.
+fn segments_len(reader: &mut Reader) -> u64 {
+    let mut len = 0u64;
+    while reader.available_bytes() >= size_of::<virtio_blk_discard_write_zeroes>() {
+        let seg: virtio_blk_discard_write_zeroes = match reader.read_obj() {
+            Ok(seg) => seg,
+            Err(_) => break,
+        };
+        len = len.saturating_add(u64::from(seg.num_sectors.to_native()) << SECTOR_SHIFT);
+    }
+    len
+}
+
+// Returns the kind of request `avail_desc` makes, and the bytes of the disk it covers, for the
+// device's statistics.
+fn request_kind(avail_desc: &DescriptorChain, mem: &GuestMemory) -> (IoKind, u64) {
+    let mut reader = match Reader::new(mem.clone(), avail_desc.clone()) {
+        Ok(reader) => reader,
+        Err(_) => return (IoKind::Other, 0),
+    };
+    let req_header: virtio_blk_req_header = match reader.read_obj() {
+        Ok(req_header) => req_header,
+        Err(_) => return (IoKind::Other, 0),
+    };
+    match req_header.req_type.to_native() {
+        VIRTIO_BLK_T_IN => (IoKind::Read, request_data_len(avail_desc)),
+        VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_ZONE_APPEND => {
+            (IoKind::Write, request_data_len(avail_desc))
+        }
+        VIRTIO_BLK_T_FLUSH => (IoKind::Flush, 0),
+        VIRTIO_BLK_T_DISCARD => (IoKind::Discard, segments_len(&mut reader)),
+        VIRTIO_BLK_T_WRITE_ZEROES => (IoKind::WriteZeroes, segments_len(&mut reader)),
+        _ => (IoKind::Other, 0),
+    }
+}
+
+// A flush request held back so that it completes with the other flushes made around the same
+// time, after a single sync of the disk.
+struct PendingFlush {
+    desc_index: u16,
+    len: usize,
+    status_writer: Writer,
+    started: Instant,
+}
+
+impl PendingFlush {
+    // Returns the request as a pending flush if it is a flush request.
+    fn new(avail_desc: &DescriptorChain, mem: &GuestMemory) -> Option<PendingFlush> {
+        let mut reader = Reader::new(mem.clone(), avail_desc.clone()).ok()?;
+        let req_header: virtio_blk_req_header = reader.read_obj().ok()?;
+        if req_header.req_type.to_native() != VIRTIO_BLK_T_FLUSH {
+            return None;
+        }
+        let mut writer = Writer::new(mem.clone(), avail_desc.clone()).ok()?;
+        let len = writer.available_bytes();
+        let status_writer = writer.split_at(len.checked_sub(1)?);
+        Some(PendingFlush {
+            desc_index: avail_desc.index,
+            len,
+            status_writer,
+            started: Instant::now(),
+        })
+    }
+
+    // Writes the status of the shared sync, and returns the descriptor index and length to add
+    // to the used ring.
+    fn complete(mut self, status: u8) -> (u16, usize) {
+        if let Err(e) = self.status_writer.write_all(&[status]) {
+            error!("block: failed to complete flush: {}", e);
+            return (self.desc_index, 0);
+        }
+        (self.desc_index, self.len)
+    }
+}
+
+// A read or write request that may be executed together with the requests for the sectors around
+// it, as a single host I/O.
+struct MergeableRequest {
+    avail_desc: DescriptorChain,
+    req_type: u32,
+    // The range of the disk read or written, in bytes.
+    offset: u64,
+    data_len: u64,
+    started: Instant,
+}
+
+impl MergeableRequest {
+    // Returns the request as a mergeable request if it is a read, or a write to a writable disk.
+    fn new(
+        avail_desc: &DescriptorChain,
+        read_only: bool,
+        mem: &GuestMemory,
+    ) -> Option<MergeableRequest> {
+        let mut reader = Reader::new(mem.clone(), avail_desc.clone()).ok()?;
+        let req_header: virtio_blk_req_header = reader.read_obj().ok()?;
+        let req_type = req_header.req_type.to_native();
+        let data_len = match req_type {
+            VIRTIO_BLK_T_IN => Writer::new(mem.clone(), avail_desc.clone())
+                .ok()?
+                .available_bytes()
+                .checked_sub(1)?,
+            VIRTIO_BLK_T_OUT if !read_only => reader.available_bytes(),
+            _ => return None,
+        };
+        let offset = req_header
+            .sector
+            .to_native()
+            .checked_shl(u32::from(SECTOR_SHIFT))?;
+        Some(MergeableRequest {
+            avail_desc: avail_desc.clone(),
+            req_type,
+            offset,
+            data_len: data_len as u64,
+            started: Instant::now(),
+        })
+    }
+
+    // Returns true if `request` can be executed together with `merged`: the requests have the
+    // same type, `request` starts where `merged` ends, and together they transfer no more than
+    // `max_size` bytes.
+    fn can_merge(merged: &[MergeableRequest], request: &MergeableRequest, max_size: u64) -> bool {
+        let last = match merged.last() {
+            Some(last) => last,
+            None => return true,
+        };
+        let merged_len: u64 = merged.iter().map(|r| r.data_len).sum();
+        last.req_type == request.req_type
+            && last.offset.checked_add(last.data_len) == Some(request.offset)
+            && merged_len.saturating_add(request.data_len) <= max_size
+    }
+
+    fn kind(&self) -> IoKind {
+        if self.req_type == VIRTIO_BLK_T_IN {
+            IoKind::Read
+        } else {
+            IoKind::Write
+        }
+    }
+}
+
+// Executes the segments of a discard or write zeroes request on `disk`.
+fn discard_write_zeroes(
+    reader: &mut Reader,
+    req_type: u32,
+    sparse: bool,
+    disk: &mut dyn DiskFile,
+    disk_size: u64,
+) -> result::Result<(), ExecuteError> {
+    if req_type == VIRTIO_BLK_T_DISCARD && !sparse {
+        // Discard is a hint; if this is a non-sparse disk, just ignore it.
+        return Ok(());
+    }
+
+    while reader.available_bytes() >= size_of::<virtio_blk_discard_write_zeroes>() {
+        let seg: virtio_blk_discard_write_zeroes = reader.read_obj().map_err(ExecuteError::Read)?;
+
+        let sector = seg.sector.to_native();
+        let num_sectors = seg.num_sectors.to_native();
+        let flags = seg.flags.to_native();
+
+        let valid_flags = if req_type == VIRTIO_BLK_T_WRITE_ZEROES {
+            VIRTIO_BLK_DISCARD_WRITE_ZEROES_FLAG_UNMAP
+        } else {
+            0
+        };
+
+        if (flags & !valid_flags) != 0 {
+            return Err(ExecuteError::DiscardWriteZeroes {
+                ioerr: None,
+                sector,
+                num_sectors,
+                flags,
+            });
+        }
+
+        let offset = sector
+            .checked_shl(u32::from(SECTOR_SHIFT))
+            .ok_or(ExecuteError::OutOfRange)?;
+        let length = u64::from(num_sectors)
+            .checked_shl(u32::from(SECTOR_SHIFT))
+            .ok_or(ExecuteError::OutOfRange)?;
+        check_range(offset, length, disk_size)?;
+
+        if req_type == VIRTIO_BLK_T_DISCARD {
+            // Since Discard is just a hint and some filesystems may not implement
+            // FALLOC_FL_PUNCH_HOLE, ignore punch_hole errors.
+            let _ = disk.punch_hole(offset, length);
+        } else {
+            // With UNMAP, the guest allows the range to be deallocated, and a hole
+            // reads back as zeroes. Fall back to writing zeroes if it can't be punched.
+            let unmapped = sparse
+                && flags & VIRTIO_BLK_DISCARD_WRITE_ZEROES_FLAG_UNMAP != 0
+                && disk.punch_hole(offset, length).is_ok();
+            if !unmapped {
+                disk.write_zeroes_all_at(offset, length as usize)
+                    .map_err(|e| ExecuteError::DiscardWriteZeroes {
+                        ioerr: Some(e),
+                        sector,
+                        num_sectors,
+                        flags,
+                    })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Writes a report of the zones of `zoned`, starting with the one `sector` is in, filling as much of
+// `writer` as it can.
+fn report_zones(
+    writer: &mut Writer,
+    zoned: &ZonedDisk,
+    sector: u64,
+) -> result::Result<(), ExecuteError> {
+    let max_zones = writer
+        .available_bytes()
+        .saturating_sub(size_of::<virtio_blk_zone_report>())
+        / size_of::<virtio_blk_zone_descriptor>();
+    let zones = zoned
+        .report(sector, max_zones)
+        .map_err(ExecuteError::Zone)?;
+
+    let report = virtio_blk_zone_report {
+        nr_zones: Le64::from(zones.len() as u64),
+        ..Default::default()
+    };
+    writer
+        .write_obj(report)
+        .map_err(ExecuteError::WriteZoneReport)?;
+    for zone in zones {
+        let desc = virtio_blk_zone_descriptor {
+            z_cap: Le64::from(zone.capacity),
+            z_start: Le64::from(zone.start),
+            z_wp: Le64::from(zone.write_pointer),
+            z_type: zone.zone_type,
+            z_state: zone.condition,
+            ..Default::default()
+        };
+        writer
+            .write_obj(desc)
+            .map_err(ExecuteError::WriteZoneReport)?;
+    }
+    Ok(())
+}
+
+fn async_event(event: &Event) -> result::Result<EventAsync, AsyncWorkerError> {
+    let event = event.try_clone().map_err(AsyncWorkerError::CloneEvent)?;
+    EventAsync::try_from(event.0).map_err(AsyncWorkerError::CreateAsyncSource)
+}
+
+// Arms `timer` to expire once the interrupt held back by `coalescer` is due.
+fn arm_coalescing_timer(
+    coalescer: &InterruptCoalescer,
+    timer: &dyn IoSourceExt<TimerFd>,
+) -> result::Result<(), AsyncWorkerError> {
+    if let Some(timeout) = coalescer.timeout() {
+        // A zero duration would disarm the timer instead.
+        timer
+            .as_source()
+            .reset(max(timeout, Duration::from_nanos(1)), None)
+            .map_err(AsyncWorkerError::ArmTimer)?;
+    }
+    Ok(())
+}
+
+// Keeps up to `MAX_IN_FLIGHT` requests from `queue` in flight. Requests are returned to the guest
+// as they complete, which may not be the order they were made in, and the guest is signaled once
+// for each batch of completed requests unless `coalescer` holds the interrupt back. Requests are
+// left in the queue until `throttle` allows them to start.
+//
+// If flushes are coalesced, the flushes made while a sync is in flight wait for it to complete, and
+// then share the next sync.
+//
+// Once `kill` completes, no more requests are taken from the queue, and the function returns when
+// the ones in flight have been returned to the guest.
+async fn process_queue_async(
+    interrupt: &Interrupt,
+    queue: &RefCell<&mut Queue>,
+    queue_evt: &EventAsync,
+    coalescer: &RefCell<&mut InterruptCoalescer>,
+    coalescing_timer: &dyn IoSourceExt<TimerFd>,
+    throttle: &IoThrottle,
+    throttle_timer: &dyn IoSourceExt<TimerFd>,
+    ctx: &AsyncRequestContext<'_>,
+    kill: impl Future<Output = result::Result<(), AsyncWorkerError>>,
+) -> result::Result<(), AsyncWorkerError> {
+    let mem = ctx.mem;
+    let mut in_flight = FuturesUnordered::new();
+    let mut flushes = Vec::new();
+    let mut flushing = false;
+    let kill = kill.fuse();
+    pin_mut!(kill);
+    loop {
+        let mut throttled = false;
+        while !kill.is_terminated() && in_flight.len() < MAX_IN_FLIGHT {
+            let avail_desc = match queue.borrow_mut().peek(mem) {
+                Some(avail_desc) => avail_desc,
+                None => break,
+            };
+            if let Some(wait) = throttle.admit(request_data_len(&avail_desc)) {
+                throttle_timer
+                    .as_source()
+                    .reset(max(wait, Duration::from_nanos(1)), None)
+                    .map_err(AsyncWorkerError::ArmTimer)?;
+                throttled = true;
+                break;
+            }
+            queue.borrow_mut().pop_peeked(mem);
+            ctx.stats.start();
+            if ctx.coalesce_flushes {
+                if let Some(flush) = PendingFlush::new(&avail_desc, mem) {
+                    flushes.push(flush);
+                    continue;
+                }
+            }
+            let (kind, bytes) = request_kind(&avail_desc, mem);
+            let started = Instant::now();
+            in_flight.push(Either::Left(async move {
+                let desc_index = avail_desc.index;
+                let len = match Worker::process_one_request_async(avail_desc, ctx).await {
+                    Ok(len) => len,
+                    Err(e) => {
+                        error!("block: failed to handle request: {}", e);
+                        0
+                    }
+                };
+                ctx.stats.complete(kind, bytes, started.elapsed());
+                Completion::Request(desc_index, len)
+            }));
+        }
+        if !flushes.is_empty() && !flushing {
+            let batch = std::mem::take(&mut flushes);
+            flushing = true;
+            in_flight.push(Either::Right(async move {
+                let status = match Block::flush_disk_async(ctx).await {
+                    Ok(()) => VIRTIO_BLK_S_OK,
+                    Err(e) => {
+                        error!("failed executing disk request: {}", e);
+                        e.status()
+                    }
+                };
+                let completed = batch
+                    .into_iter()
+                    .map(|f| {
+                        ctx.stats.complete(IoKind::Flush, 0, f.started.elapsed());
+                        f.complete(status)
+                    })
+                    .collect();
+                Completion::Flushes(completed)
+            }));
+        }
+
+        if in_flight.is_empty() && kill.is_terminated() {
+            return Ok(());
+        }
+
+        // Wait for the guest to make more requests available, for the throttle to allow the next
+        // one to start, or for the worker to be killed. Once killed, only the requests in flight
+        // are waited for.
+        let wake = async {
+            if kill.is_terminated() {
+                return future::pending().await;
+            }
+            let next = async {
+                if throttled {
+                    throttle_timer.read_u64().await
+                } else {
+                    queue_evt.next_val().await
+                }
+            };
+            pin_mut!(next);
+            match select(next, kill.as_mut()).await {
+                Either::Left((res, _)) => res.map(|_| ()).map_err(AsyncWorkerError::ReadEvent),
+                Either::Right((res, _)) => res,
+            }
+        };
+        if in_flight.is_empty() {
+            wake.await?;
+            continue;
+        }
+
+        // Otherwise wait for a request to complete too.
+        pin_mut!(wake);
+        let mut completed = match select(in_flight.next(), wake).await {
+            Either::Left((completed, _)) => completed,
+            Either::Right((res, _)) => {
+                res?;
+                continue;
+            }
+        };
+
+        let mut queue = queue.borrow_mut();
+        while let Some(completion) = completed {
+            match completion {
+                Completion::Request(desc_index, len) => queue.add_used(mem, desc_index, len as u32),
+                Completion::Flushes(batch) => {
+                    for (desc_index, len) in batch {
+                        queue.add_used(mem, desc_index, len as u32);
+                    }
+                    flushing = false;
+                }
+            }
+            completed = in_flight.next().now_or_never().flatten();
+        }
+        let mut coalescer = coalescer.borrow_mut();
+        if coalescer.signal() {
+            queue.trigger_interrupt(mem, interrupt);
+        } else {
+            arm_coalescing_timer(&coalescer, coalescing_timer)?;
+        }
+    }
+}
+
+// Delivers the interrupts for `queue` that `coalescer` held back once they are due.
+async fn flush_coalesced_interrupts(
+    interrupt: &Interrupt,
+    mem: &GuestMemory,
+    queue: &RefCell<&mut Queue>,
+    coalescer: &RefCell<&mut InterruptCoalescer>,
+    coalescing_timer: &dyn IoSourceExt<TimerFd>,
+) -> result::Result<(), AsyncWorkerError> {
+    loop {
+        coalescing_timer
+            .read_u64()
+            .await
+            .map_err(AsyncWorkerError::ReadEvent)?;
+        let mut coalescer = coalescer.borrow_mut();
+        if coalescer.take_due() {
+            queue.borrow_mut().trigger_interrupt(mem, interrupt);
+        } else {
+            arm_coalescing_timer(&coalescer, coalescing_timer)?;
+        }
+    }
+}
+
+// Flushes the disk each time the flush timer armed by a write expires.
+async fn flush_on_timer(
+    disk: &dyn AsyncDisk,
+    flush_timer: &dyn IoSourceExt<TimerFd>,
+) -> result::Result<(), AsyncWorkerError> {
+    loop {
+        flush_timer
+            .read_u64()
+            .await
+            .map_err(AsyncWorkerError::ReadEvent)?;
+        disk.fsync().await.map_err(AsyncWorkerError::Flush)?;
+    }
+}
+
+async fn handle_irq_resample(
+    interrupt: &Interrupt,
+    resample_evt: &EventAsync,
+) -> result::Result<(), AsyncWorkerError> {
+    loop {
+        resample_evt
+            .next_val()
+            .await
+            .map_err(AsyncWorkerError::ReadEvent)?;
+        interrupt.interrupt_resample();
+    }
+}
+
+async fn handle_control_requests(
+    interrupt: &Interrupt,
+    control_socket: &DiskControlResponseSocket,
+    coalescer: &RefCell<&mut InterruptCoalescer>,
+    throttle: &IoThrottle,
+    ctx: &AsyncRequestContext<'_>,
+) -> result::Result<(), AsyncWorkerError> {
+    let mut receiver = control_socket
+        .async_receiver()
+        .map_err(AsyncWorkerError::ReadControl)?;
+    loop {
+        let command = receiver
+            .next()
+            .await
+            .map_err(AsyncWorkerError::ReadControl)?;
+        let mut needs_config_interrupt = false;
+        let resp = match command {
+            DiskControlCommand::Resize { new_size } => {
+                let resize_resp = resize_disk(
+                    ctx.sync_disk,
+                    ctx.disk_size,
+                    ctx.sparse,
+                    ctx.read_only,
+                    new_size,
+                );
+                if let DiskControlResult::Ok = resize_resp {
+                    needs_config_interrupt = true;
+                }
+                resize_resp
+            }
+            DiskControlCommand::SetInterruptCoalescing { min_interval_us } => {
+                coalescer
+                    .borrow()
+                    .set_interval(Duration::from_micros(min_interval_us));
+                DiskControlResult::Ok
+            }
+            DiskControlCommand::SetThrottle {
+                iops,
+                iops_burst,
+                bytes_per_sec,
+                bytes_burst,
+            } => {
+                throttle.set_limits(ThrottleLimits {
+                    iops,
+                    iops_burst,
+                    bytes_per_sec,
+                    bytes_burst,
+                });
+                DiskControlResult::Ok
+            }
+            DiskControlCommand::GetStats => DiskControlResult::Stats(ctx.stats.stats()),
+        };
+        control_socket
+            .send(&resp)
+            .map_err(AsyncWorkerError::WriteControl)?;
+        if needs_config_interrupt {
+            interrupt.signal_config_changed();
         }
     }
 }
 
+// Serves one of the device's queues. The workers of all the queues share the disk image.
 struct Worker {
-    interrupt: Interrupt,
-    queues: Vec<Queue>,
+    interrupt: Arc<Interrupt>,
+    queue: Queue,
     mem: GuestMemory,
-    disk_image: Box<dyn DiskFile>,
+    disk_image: Arc<Mutex<Box<dyn DiskFile>>>,
+    // The file of a raw disk image, which the worker accesses through io_uring if it is set.
+    raw_image: Option<File>,
     disk_size: Arc<Mutex<u64>>,
     read_only: bool,
-    sparse: bool,
+    sparse: Arc<AtomicBool>,
+    write_through: bool,
+    // Whether the flushes made together complete after a single sync.
+    coalesce_flushes: bool,
+    // The most bytes that reads or writes of contiguous sectors are merged into, or zero to
+    // execute each request on its own.
+    max_merge_size: u64,
     id: Option<BlockId>,
+    zoned: Option<ZonedDisk>,
+    busy_poll: Option<BusyPoll>,
+    coalescer: InterruptCoalescer,
+    throttle: IoThrottle,
+    stats: IoStats,
+    // When the request at the head of the queue may start, if the throttle held it back.
+    throttled_until: Option<Instant>,
+    // Only the worker of the first queue waits for interrupt resamples and serves the control
+    // socket, since the rest of the workers share them.
+    first_queue: bool,
     control_socket: Option<DiskControlResponseSocket>,
 }
 
@@ -265,9 +1060,11 @@ impl Worker {
         avail_desc: DescriptorChain,
         read_only: bool,
         sparse: bool,
+        write_through: bool,
         disk: &mut dyn DiskFile,
         disk_size: u64,
         id: Option<BlockId>,
+        zoned: Option<&ZonedDisk>,
         flush_timer: &mut Timer,
         flush_timer_armed: &mut bool,
         mem: &GuestMemory,
@@ -290,9 +1087,11 @@ impl Worker {
             &mut writer,
             read_only,
             sparse,
+            write_through,
             disk,
             disk_size,
             id,
+            zoned,
             flush_timer,
             flush_timer_armed,
         ) {
@@ -309,27 +1108,95 @@ impl Worker {
         Ok(available_bytes)
     }
 
-    fn process_queue(
-        &mut self,
-        queue_index: usize,
-        flush_timer: &mut Timer,
-        flush_timer_armed: &mut bool,
-    ) {
-        let queue = &mut self.queues[queue_index];
+    // Like `process_one_request`, with reads, writes and flushes going through the async disk of
+    // a worker running on io_uring.
+    async fn process_one_request_async(
+        avail_desc: DescriptorChain,
+        ctx: &AsyncRequestContext<'_>,
+    ) -> result::Result<usize, ExecuteError> {
+        let mut reader =
+            Reader::new(ctx.mem.clone(), avail_desc.clone()).map_err(ExecuteError::Descriptor)?;
+        let mut writer =
+            Writer::new(ctx.mem.clone(), avail_desc).map_err(ExecuteError::Descriptor)?;
+
+        // The last byte of the buffer is virtio_blk_req::status.
+        let available_bytes = writer.available_bytes();
+        let status_offset = available_bytes
+            .checked_sub(1)
+            .ok_or(ExecuteError::MissingStatus)?;
+        let mut status_writer = writer.split_at(status_offset);
 
-        let disk_size = self.disk_size.lock();
+        let status = match Block::execute_request_async(&mut reader, &mut writer, ctx).await {
+            Ok(()) => VIRTIO_BLK_S_OK,
+            Err(e) => {
+                error!("failed executing disk request: {}", e);
+                e.status()
+            }
+        };
+
+        status_writer
+            .write_all(&[status])
+            .map_err(ExecuteError::WriteStatus)?;
+        Ok(available_bytes)
+    }
+
+    fn process_queue(&mut self, flush_timer: &mut Timer, flush_timer_armed: &mut bool) {
+        let _trace = trace_event!(virtio, "block_process_queue");
+
+        let disk_size = *self.disk_size.lock();
+        let sparse = self.sparse.load(Ordering::Relaxed);
+
+        self.throttled_until = None;
+        // Flushes are only coalesced within one pass over the queue. The disk is synced once they
+        // have all been taken from it.
+        let mut flushes = Vec::new();
+        // Reads or writes of contiguous sectors, waiting to be executed together.
+        let mut merged = Vec::new();
+        while let Some(avail_desc) = self.queue.peek(&self.mem) {
+            if let Some(wait) = self.throttle.admit(request_data_len(&avail_desc)) {
+                self.throttled_until = Some(Instant::now() + wait);
+                break;
+            }
+            self.queue.pop_peeked(&self.mem);
+            self.stats.start();
+            if self.coalesce_flushes && !self.read_only {
+                if let Some(flush) = PendingFlush::new(&avail_desc, &self.mem) {
+                    flushes.push(flush);
+                    continue;
+                }
+            }
+            if self.max_merge_size != 0 && self.zoned.is_none() {
+                if let Some(request) = MergeableRequest::new(&avail_desc, self.read_only, &self.mem)
+                {
+                    if !MergeableRequest::can_merge(&merged, &request, self.max_merge_size) {
+                        self.complete_merged(
+                            &mut merged,
+                            disk_size,
+                            flush_timer,
+                            flush_timer_armed,
+                        );
+                    }
+                    merged.push(request);
+                    continue;
+                }
+            }
+            // Requests are completed in the order they were made.
+            self.complete_merged(&mut merged, disk_size, flush_timer, flush_timer_armed);
 
-        while let Some(avail_desc) = queue.pop(&self.mem) {
-            queue.set_notify(&self.mem, false);
+            self.queue.set_notify(&self.mem, false);
             let desc_index = avail_desc.index;
+            let (kind, bytes) = request_kind(&avail_desc, &self.mem);
+            let started = Instant::now();
 
             let len = match Worker::process_one_request(
                 avail_desc,
                 self.read_only,
-                self.sparse,
-                &mut *self.disk_image,
-                *disk_size,
+                sparse,
+                self.write_through,
+                &mut **self.disk_image.lock(),
+                disk_size,
                 self.id,
+                self.zoned.as_ref(),
                 flush_timer,
                 flush_timer_armed,
                 &self.mem,
@@ -340,55 +1207,277 @@ impl Worker {
                     0
                 }
             };
+            self.stats.complete(kind, bytes, started.elapsed());
 
-            queue.add_used(&self.mem, desc_index, len as u32);
-            queue.trigger_interrupt(&self.mem, &self.interrupt);
-            queue.set_notify(&self.mem, true);
-        }
-    }
-
-    fn resize(&mut self, new_size: u64) -> DiskControlResult {
-        if self.read_only {
-            error!("Attempted to resize read-only block device");
-            return DiskControlResult::Err(SysError::new(libc::EROFS));
+            self.queue.add_used(&self.mem, desc_index, len as u32);
+            if self.coalescer.signal() {
+                self.queue.trigger_interrupt(&self.mem, &self.interrupt);
+            }
+            self.queue.set_notify(&self.mem, true);
         }
+        self.complete_merged(&mut merged, disk_size, flush_timer, flush_timer_armed);
 
-        info!("Resizing block device to {} bytes", new_size);
-
-        if let Err(e) = self.disk_image.set_len(new_size) {
-            error!("Resizing disk failed! {}", e);
-            return DiskControlResult::Err(SysError::new(libc::EIO));
+        if flushes.is_empty() {
+            return;
         }
-
-        // Allocate new space if the disk image is not sparse.
-        if let Err(e) = self.disk_image.allocate(0, new_size) {
-            error!("Allocating disk space after resize failed! {}", e);
-            return DiskControlResult::Err(SysError::new(libc::EIO));
+        let status = match Block::flush_disk(
+            &mut **self.disk_image.lock(),
+            self.write_through,
+            flush_timer,
+            flush_timer_armed,
+        ) {
+            Ok(()) => VIRTIO_BLK_S_OK,
+            Err(e) => {
+                error!("failed executing disk request: {}", e);
+                e.status()
+            }
+        };
+        for flush in flushes {
+            self.stats
+                .complete(IoKind::Flush, 0, flush.started.elapsed());
+            let (desc_index, len) = flush.complete(status);
+            self.queue.add_used(&self.mem, desc_index, len as u32);
         }
-
-        self.sparse = false;
-
-        if let Ok(new_disk_size) = self.disk_image.get_len() {
-            let mut disk_size = self.disk_size.lock();
-            *disk_size = new_disk_size;
+        if self.coalescer.signal() {
+            self.queue.trigger_interrupt(&self.mem, &self.interrupt);
         }
-        DiskControlResult::Ok
     }
 
-    fn run(&mut self, queue_evt: Event, kill_evt: Event) {
-        #[derive(PollToken)]
-        enum Token {
-            FlushTimer,
-            QueueAvailable,
-            ControlRequest,
-            InterruptResample,
-            Kill,
-        }
-
+    // Executes the requests in `merged` and returns them to the guest, leaving `merged` empty.
+    // If the merged I/O fails, the requests are executed again one at a time, so that each one
+    // gets its own status.
+    fn complete_merged(
+        &mut self,
+        merged: &mut Vec<MergeableRequest>,
+        disk_size: u64,
+        flush_timer: &mut Timer,
+        flush_timer_armed: &mut bool,
+    ) {
+        if merged.is_empty() {
+            return;
+        }
+        let sparse = self.sparse.load(Ordering::Relaxed);
+        let lens = match Block::execute_merged(
+            merged,
+            &mut **self.disk_image.lock(),
+            disk_size,
+            self.write_through,
+            flush_timer,
+            flush_timer_armed,
+            &self.mem,
+        ) {
+            Ok(lens) => lens,
+            Err(e) => {
+                warn!(
+                    "block: failed merged request, executing its parts separately: {}",
+                    e
+                );
+                merged
+                    .iter()
+                    .map(|request| {
+                        match Worker::process_one_request(
+                            request.avail_desc.clone(),
+                            self.read_only,
+                            sparse,
+                            self.write_through,
+                            &mut **self.disk_image.lock(),
+                            disk_size,
+                            self.id,
+                            None,
+                            flush_timer,
+                            flush_timer_armed,
+                            &self.mem,
+                        ) {
+                            Ok(len) => len,
+                            Err(e) => {
+                                error!("block: failed to handle request: {}", e);
+                                0
+                            }
+                        }
+                    })
+                    .collect()
+            }
+        };
+
+        for (request, len) in merged.drain(..).zip(lens) {
+            self.stats
+                .complete(request.kind(), request.data_len, request.started.elapsed());
+            self.queue
+                .add_used(&self.mem, request.avail_desc.index, len as u32);
+        }
+        if self.coalescer.signal() {
+            self.queue.trigger_interrupt(&self.mem, &self.interrupt);
+        }
+    }
+
+    // Keeps processing the queue for as long as the driver makes new requests available within the
+    // busy-poll window, with guest notifications disabled so the driver doesn't need to kick us.
+    fn busy_poll_queue(&mut self, flush_timer: &mut Timer, flush_timer_armed: &mut bool) {
+        let mut poller = match self.busy_poll.take() {
+            Some(poller) => poller,
+            None => return,
+        };
+
+        self.queue.set_notify(&self.mem, false);
+        // There's no point polling for requests that the throttle holds back.
+        while self.throttled_until.is_none() {
+            let queue = &self.queue;
+            let mem = &self.mem;
+            if !poller.poll(|| queue.has_available(mem)) {
+                break;
+            }
+            self.process_queue(flush_timer, flush_timer_armed);
+        }
+        self.queue.set_notify(&self.mem, true);
+
+        // Requests made available before notifications were re-enabled didn't kick the queue
+        // event, so pick them up now.
+        self.process_queue(flush_timer, flush_timer_armed);
+        self.busy_poll = Some(poller);
+    }
+
+    // Runs the worker on io_uring, keeping up to `MAX_IN_FLIGHT` requests in flight instead of
+    // completing each one before taking the next from the queue. `raw_image` is the file of the
+    // raw disk image, used for reads, writes and flushes.
+    fn run_async(
+        &mut self,
+        raw_image: File,
+        queue_evt: &Event,
+        kill_evt: &Event,
+    ) -> result::Result<(), AsyncWorkerError> {
+        let flush_timer = Timer::new().map_err(AsyncWorkerError::CreateTimer)?;
+        let coalescing_timer = Timer::new().map_err(AsyncWorkerError::CreateTimer)?;
+        let throttle_timer = Timer::new().map_err(AsyncWorkerError::CreateTimer)?;
+        let flush_timer_armed = Cell::new(false);
+
+        let interrupt = &*self.interrupt;
+        let mem = &self.mem;
+        let queue = RefCell::new(&mut self.queue);
+        let coalescer = RefCell::new(&mut self.coalescer);
+        let throttle = &self.throttle;
+        let stats = &self.stats;
+        let sync_disk = &*self.disk_image;
+        let disk_size = &*self.disk_size;
+        let read_only = self.read_only;
+        let sparse = &*self.sparse;
+        let write_through = self.write_through;
+        let coalesce_flushes = self.coalesce_flushes && !self.read_only;
+        let id = self.id;
+        let first_queue = self.first_queue;
+        let control_socket = self.control_socket.as_ref();
+
+        let fut = async move {
+            let disk =
+                SingleFileDisk::try_from(raw_image).map_err(AsyncWorkerError::CreateAsyncDisk)?;
+            let flush_timer =
+                async_from(flush_timer.0).map_err(AsyncWorkerError::CreateAsyncSource)?;
+            let coalescing_timer =
+                async_from(coalescing_timer.0).map_err(AsyncWorkerError::CreateAsyncSource)?;
+            let throttle_timer =
+                async_from(throttle_timer.0).map_err(AsyncWorkerError::CreateAsyncSource)?;
+            let queue_evt = async_event(queue_evt)?;
+            // The kill event is only waited on and never read, so that every worker sees it.
+            let kill_evt = kill_evt.try_clone().map_err(AsyncWorkerError::CloneEvent)?;
+            let kill_evt = async_from(kill_evt.0).map_err(AsyncWorkerError::CreateAsyncSource)?;
+            let resample_evt = if first_queue {
+                Some(async_event(interrupt.get_resample_evt())?)
+            } else {
+                None
+            };
+
+            let ctx = AsyncRequestContext {
+                mem,
+                disk: &disk,
+                sync_disk,
+                disk_size,
+                read_only,
+                sparse,
+                write_through,
+                coalesce_flushes,
+                id,
+                stats,
+                flush_timer: &*flush_timer,
+                flush_timer_armed: &flush_timer_armed,
+            };
+            let kill = async {
+                kill_evt
+                    .wait_readable()
+                    .await
+                    .map_err(AsyncWorkerError::ReadEvent)
+            };
+            // Deliver the interrupt held back when the worker was last stopped, if any.
+            arm_coalescing_timer(&coalescer.borrow(), &*coalescing_timer)?;
+
+            let mut futures: Vec<
+                Pin<Box<dyn Future<Output = result::Result<(), AsyncWorkerError>> + '_>>,
+            > = vec![
+                Box::pin(process_queue_async(
+                    interrupt,
+                    &queue,
+                    &queue_evt,
+                    &coalescer,
+                    &*coalescing_timer,
+                    throttle,
+                    &*throttle_timer,
+                    &ctx,
+                    kill,
+                )),
+                Box::pin(flush_coalesced_interrupts(
+                    interrupt,
+                    mem,
+                    &queue,
+                    &coalescer,
+                    &*coalescing_timer,
+                )),
+                Box::pin(flush_on_timer(&disk, &*flush_timer)),
+            ];
+            if let Some(resample_evt) = &resample_evt {
+                futures.push(Box::pin(handle_irq_resample(interrupt, resample_evt)));
+            }
+            if let Some(control_socket) = control_socket {
+                futures.push(Box::pin(handle_control_requests(
+                    interrupt,
+                    control_socket,
+                    &coalescer,
+                    throttle,
+                    &ctx,
+                )));
+            }
+
+            let (result, _, _) = select_all(futures).await;
+            result
+        };
+
+        cros_async::run_one_uring(Box::pin(fut)).map_err(AsyncWorkerError::RunExecutor)?
+    }
+
+    // Returns how long the worker can wait for events before it has to deliver an interrupt held
+    // back by coalescing or start a request held back by the throttle.
+    fn timeout(&self) -> Option<Duration> {
+        let throttle_timeout = self
+            .throttled_until
+            .map(|t| t.saturating_duration_since(Instant::now()));
+        match (self.coalescer.timeout(), throttle_timeout) {
+            (Some(a), Some(b)) => Some(min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn run(&mut self, queue_evt: &Event, kill_evt: &Event) {
+        #[derive(PollToken)]
+        enum Token {
+            FlushTimer,
+            QueueAvailable,
+            ControlRequest,
+            InterruptResample,
+            Kill,
+        }
+
         let mut flush_timer = match Timer::new() {
             Ok(t) => t,
             Err(e) => {
                 error!("Failed to create the flush timer: {}", e);
+                self.interrupt.signal_needs_reset();
                 return;
             }
         };
@@ -396,11 +1485,13 @@ impl Worker {
 
         let wait_ctx: WaitContext<Token> = match WaitContext::build_with(&[
             (&flush_timer, Token::FlushTimer),
-            (&queue_evt, Token::QueueAvailable),
-            (self.interrupt.get_resample_evt(), Token::InterruptResample),
-            (&kill_evt, Token::Kill),
+            (queue_evt, Token::QueueAvailable),
+            (kill_evt, Token::Kill),
         ])
         .and_then(|pc| {
+            if self.first_queue {
+                pc.add(self.interrupt.get_resample_evt(), Token::InterruptResample)?
+            }
             if let Some(control_socket) = self.control_socket.as_ref() {
                 pc.add(control_socket, Token::ControlRequest)?
             }
@@ -409,12 +1500,19 @@ impl Worker {
             Ok(pc) => pc,
             Err(e) => {
                 error!("failed creating WaitContext: {}", e);
+                self.interrupt.signal_needs_reset();
                 return;
             }
         };
 
         'wait: loop {
-            let events = match wait_ctx.wait() {
+            // Wake up in time to deliver any interrupt held back by coalescing, or to start a
+            // request held back by the throttle.
+            let events = match self.timeout() {
+                Some(timeout) => wait_ctx.wait_timeout(timeout),
+                None => wait_ctx.wait(),
+            };
+            let events = match events {
                 Ok(v) => v,
                 Err(e) => {
                     error!("failed polling for events: {}", e);
@@ -426,7 +1524,7 @@ impl Worker {
             for event in events.iter().filter(|e| e.is_readable) {
                 match event.token {
                     Token::FlushTimer => {
-                        if let Err(e) = self.disk_image.fsync() {
+                        if let Err(e) = self.disk_image.lock().fsync() {
                             error!("Failed to flush the disk: {}", e);
                             break 'wait;
                         }
@@ -440,7 +1538,8 @@ impl Worker {
                             error!("failed reading queue Event: {}", e);
                             break 'wait;
                         }
-                        self.process_queue(0, &mut flush_timer, &mut flush_timer_armed);
+                        self.process_queue(&mut flush_timer, &mut flush_timer_armed);
+                        self.busy_poll_queue(&mut flush_timer, &mut flush_timer_armed);
                     }
                     Token::ControlRequest => {
                         let control_socket = match self.control_socket.as_ref() {
@@ -459,13 +1558,45 @@ impl Worker {
                         };
 
                         let resp = match req {
+                            DiskControlCommand::Resize { .. } if self.zoned.is_some() => {
+                                error!("Attempted to resize zoned block device");
+                                DiskControlResult::Err(SysError::new(libc::ENOTSUP))
+                            }
                             DiskControlCommand::Resize { new_size } => {
-                                let resize_resp = self.resize(new_size);
+                                let resize_resp = resize_disk(
+                                    &self.disk_image,
+                                    &self.disk_size,
+                                    &self.sparse,
+                                    self.read_only,
+                                    new_size,
+                                );
                                 if let DiskControlResult::Ok = resize_resp {
                                     needs_config_interrupt = true;
                                 }
                                 resize_resp
                             }
+                            DiskControlCommand::SetInterruptCoalescing { min_interval_us } => {
+                                self.coalescer
+                                    .set_interval(Duration::from_micros(min_interval_us));
+                                DiskControlResult::Ok
+                            }
+                            DiskControlCommand::SetThrottle {
+                                iops,
+                                iops_burst,
+                                bytes_per_sec,
+                                bytes_burst,
+                            } => {
+                                self.throttle.set_limits(ThrottleLimits {
+                                    iops,
+                                    iops_burst,
+                                    bytes_per_sec,
+                                    bytes_burst,
+                                });
+                                DiskControlResult::Ok
+                            }
+                            DiskControlCommand::GetStats => {
+                                DiskControlResult::Stats(self.stats.stats())
+                            }
                         };
 
                         // We already know there is Some control_socket used to recv a request.
@@ -477,12 +1608,99 @@ impl Worker {
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
-                    Token::Kill => break 'wait,
+                    Token::Kill => return,
                 }
             }
             if needs_config_interrupt {
                 self.interrupt.signal_config_changed();
             }
+            if self.coalescer.take_due() {
+                self.queue.trigger_interrupt(&self.mem, &self.interrupt);
+            }
+            if let Some(throttled_until) = self.throttled_until {
+                if throttled_until <= Instant::now() {
+                    self.process_queue(&mut flush_timer, &mut flush_timer_armed);
+                }
+            }
+        }
+
+        // Only errors get here. Resetting the device starts a new worker.
+        self.interrupt.signal_needs_reset();
+    }
+}
+
+// Starts a thread running `worker` for the queue at `index`, which gives back the worker and
+// `queue_evt` once `kill_evt` is signaled.
+fn spawn_worker(
+    index: usize,
+    mut worker: Worker,
+    queue_evt: Event,
+    kill_evt: Event,
+) -> io::Result<thread::JoinHandle<(Worker, Event)>> {
+    thread::Builder::new()
+        .name(format!("virtio_blk worker {}", index))
+        .spawn(move || {
+            // The worker keeps its raw disk image, to run on it again once restarted.
+            match worker.raw_image.as_ref().map(File::try_clone) {
+                Some(Ok(raw_image)) => {
+                    if let Err(e) = worker.run_async(raw_image, &queue_evt, &kill_evt) {
+                        error!("block worker thread exited with error: {}", e);
+                        worker.interrupt.signal_needs_reset();
+                    }
+                }
+                Some(Err(e)) => {
+                    error!("failed to clone raw disk image: {}", e);
+                    worker.interrupt.signal_needs_reset();
+                }
+                None => worker.run(&queue_evt, &kill_evt),
+            }
+            (worker, queue_evt)
+        })
+}
+
+/// The options of a virtio block device.
+#[derive(Clone, Debug)]
+pub struct BlockConfig {
+    pub read_only: bool,
+    /// Whether discard requests are passed on to the disk.
+    pub sparse: bool,
+    /// Sync each write to storage with `fdatasync` before it completes, and use `fdatasync`
+    /// rather than `fsync` for guest flushes.
+    pub write_through: bool,
+    /// Complete the guest flushes a worker finds in its queue together, or that arrive while it is
+    /// syncing the disk for earlier ones, after a single sync.
+    pub flush_coalescing: bool,
+    /// The most bytes that the reads or writes of contiguous sectors a worker finds in its queue
+    /// together are merged into, or zero to not merge them. Requests are only merged by threaded
+    /// workers, so merging keeps the workers off io_uring.
+    pub max_merge_size: u64,
+    pub block_size: u32,
+    pub id: Option<BlockId>,
+    /// Number of queues, each served by its own worker thread.
+    pub num_queues: u16,
+    /// How long a worker busy-polls its queue after processing requests before waiting for the
+    /// next queue notification.
+    pub busy_poll: Option<Duration>,
+    /// Minimum time between used-ring interrupts, or `None` to interrupt after every request.
+    pub interrupt_interval: Option<Duration>,
+    /// Rate limits on the requests of all the queues.
+    pub throttle_limits: ThrottleLimits,
+}
+
+impl Default for BlockConfig {
+    fn default() -> BlockConfig {
+        BlockConfig {
+            read_only: false,
+            sparse: true,
+            write_through: false,
+            flush_coalescing: true,
+            max_merge_size: 0,
+            block_size: SECTOR_SIZE as u32,
+            id: None,
+            num_queues: 1,
+            busy_poll: None,
+            interrupt_interval: None,
+            throttle_limits: ThrottleLimits::default(),
         }
     }
 }
@@ -490,45 +1708,105 @@ impl Worker {
 /// Virtio device for exposing block level read/write operations on a host file.
 pub struct Block {
     kill_evt: Option<Event>,
-    worker_thread: Option<thread::JoinHandle<Worker>>,
+    // One per queue, in queue order. Each gives back its worker and queue event when it exits.
+    worker_threads: Vec<thread::JoinHandle<(Worker, Event)>>,
+    // The workers stopped by `sleep`, in queue order, to be restarted by `wake`.
+    sleeping_workers: Vec<(Worker, Event)>,
     disk_image: Option<Box<dyn DiskFile>>,
+    raw_image: Option<File>,
     disk_size: Arc<Mutex<u64>>,
     avail_features: u64,
+    queue_sizes: Vec<u16>,
     read_only: bool,
-    sparse: bool,
+    sparse: Arc<AtomicBool>,
+    write_through: bool,
+    flush_coalescing: bool,
+    max_merge_size: u64,
     seg_max: u32,
     block_size: u32,
     id: Option<BlockId>,
+    zoned: Option<ZonedDisk>,
+    busy_poll: Option<Duration>,
+    interrupt_interval: CoalescingInterval,
+    throttle: IoThrottle,
+    stats: IoStats,
     control_socket: Option<DiskControlResponseSocket>,
 }
 
-fn build_config_space(disk_size: u64, seg_max: u32, block_size: u32) -> virtio_blk_config {
+fn build_config_space(
+    disk_size: u64,
+    seg_max: u32,
+    block_size: u32,
+    num_queues: u16,
+    zoned: Option<&ZonedDisk>,
+) -> virtio_blk_config {
+    let zoned = match zoned {
+        Some(zoned) => {
+            let zone_sectors = min(zoned.zone_sectors(), u64::from(u32::MAX)) as u32;
+            virtio_blk_zoned_characteristics {
+                zone_sectors: Le32::from(zone_sectors),
+                max_open_zones: Le32::from(zoned.max_open_zones()),
+                max_active_zones: Le32::from(zoned.max_active_zones()),
+                max_append_sectors: Le32::from(zone_sectors),
+                write_granularity: Le32::from(block_size),
+                model: VIRTIO_BLK_Z_HM,
+                ..Default::default()
+            }
+        }
+        None => Default::default(),
+    };
     virtio_blk_config {
         // If the image is not a multiple of the sector size, the tail bits are not exposed.
         capacity: Le64::from(disk_size >> SECTOR_SHIFT),
         seg_max: Le32::from(seg_max),
         blk_size: Le32::from(block_size),
+        num_queues: Le16::from(num_queues),
         max_discard_sectors: Le32::from(MAX_DISCARD_SECTORS),
         discard_sector_alignment: Le32::from(DISCARD_SECTOR_ALIGNMENT),
         max_write_zeroes_sectors: Le32::from(MAX_WRITE_ZEROES_SECTORS),
         write_zeroes_may_unmap: 1,
         max_discard_seg: Le32::from(MAX_DISCARD_SEG),
         max_write_zeroes_seg: Le32::from(MAX_WRITE_ZEROES_SEG),
+        zoned,
         ..Default::default()
     }
 }
 
 impl Block {
     /// Create a new virtio block device that operates on the given DiskFile.
+    ///
+    /// If `disk_image` is a raw image, `raw_image` can be a duplicate of its file. The workers then
+    /// run on io_uring when it is available and `busy_poll` isn't set, and keep several requests in
+    /// flight at once.
+    ///
+    /// If `zoned` is set, the device is a host-managed zoned device with its zones. Writes to a
+    /// zoned device have to be checked against the write pointers one at a time, so its workers
+    /// never run on io_uring, and it doesn't support discard and write zeroes requests.
     pub fn new(
         base_features: u64,
         disk_image: Box<dyn DiskFile>,
-        read_only: bool,
-        sparse: bool,
-        block_size: u32,
-        id: Option<BlockId>,
+        raw_image: Option<File>,
+        config: BlockConfig,
+        zoned: Option<ZonedDisk>,
         control_socket: Option<DiskControlResponseSocket>,
     ) -> SysResult<Block> {
+        let BlockConfig {
+            read_only,
+            sparse,
+            write_through,
+            flush_coalescing,
+            max_merge_size,
+            block_size,
+            id,
+            num_queues,
+            busy_poll,
+            interrupt_interval,
+            throttle_limits,
+        } = config;
+        if num_queues == 0 {
+            error!("A block device needs at least one queue.");
+            return Err(SysError::new(libc::EINVAL));
+        }
         if block_size % SECTOR_SIZE as u32 != 0 {
             error!(
                 "Block size {} is not a multiple of {}.",
@@ -546,17 +1824,24 @@ impl Block {
         }
 
         let mut avail_features: u64 = base_features;
+        avail_features |= 1 << VIRTIO_F_RING_PACKED;
         avail_features |= 1 << VIRTIO_BLK_F_FLUSH;
         if read_only {
             avail_features |= 1 << VIRTIO_BLK_F_RO;
-        } else {
+        } else if zoned.is_none() {
             if sparse {
                 avail_features |= 1 << VIRTIO_BLK_F_DISCARD;
             }
             avail_features |= 1 << VIRTIO_BLK_F_WRITE_ZEROES;
         }
+        if zoned.is_some() {
+            avail_features |= 1 << VIRTIO_BLK_F_ZONED;
+        }
         avail_features |= 1 << VIRTIO_BLK_F_SEG_MAX;
         avail_features |= 1 << VIRTIO_BLK_F_BLK_SIZE;
+        if num_queues > 1 {
+            avail_features |= 1 << VIRTIO_BLK_F_MQ;
+        }
 
         let seg_max = min(max(iov_max(), 1), u32::max_value() as usize) as u32;
 
@@ -567,19 +1852,200 @@ impl Block {
 
         Ok(Block {
             kill_evt: None,
-            worker_thread: None,
+            worker_threads: Vec::new(),
+            sleeping_workers: Vec::new(),
             disk_image: Some(disk_image),
+            raw_image: if zoned.is_some() { None } else { raw_image },
             disk_size: Arc::new(Mutex::new(disk_size)),
             avail_features,
+            queue_sizes: vec![QUEUE_SIZE; num_queues as usize],
             read_only,
-            sparse,
+            sparse: Arc::new(AtomicBool::new(sparse)),
+            write_through,
+            flush_coalescing,
+            max_merge_size,
             seg_max,
             block_size,
             id,
+            zoned,
+            busy_poll,
+            interrupt_interval: CoalescingInterval::new(interrupt_interval.unwrap_or_default()),
+            throttle: IoThrottle::new(throttle_limits),
+            stats: IoStats::new(),
             control_socket,
         })
     }
 
+    // Creates the event that stops the workers, keeping one end of it, and returns the other end
+    // for the workers to wait on.
+    fn create_kill_evt(&mut self) -> SysResult<Event> {
+        let kill_evt = Event::new()?;
+        self.kill_evt = Some(kill_evt.try_clone()?);
+        Ok(kill_evt)
+    }
+
+    // Syncs the disk for a guest flush.
+    fn flush_disk(
+        disk: &mut dyn DiskFile,
+        write_through: bool,
+        flush_timer: &mut Timer,
+        flush_timer_armed: &mut bool,
+    ) -> result::Result<(), ExecuteError> {
+        if write_through {
+            // Writes already reached storage, so only their metadata may be left.
+            disk.fdatasync().map_err(ExecuteError::Flush)?;
+        } else {
+            disk.fsync().map_err(ExecuteError::Flush)?;
+            flush_timer.clear().map_err(ExecuteError::Timer)?;
+            *flush_timer_armed = false;
+        }
+        Ok(())
+    }
+
+    // Like `flush_disk`, for the async disk of a worker running on io_uring.
+    async fn flush_disk_async(ctx: &AsyncRequestContext<'_>) -> result::Result<(), ExecuteError> {
+        ctx.disk.fsync().await.map_err(ExecuteError::FlushAsync)?;
+        ctx.flush_timer
+            .as_source()
+            .clear()
+            .map_err(ExecuteError::Timer)?;
+        ctx.flush_timer_armed.set(false);
+        Ok(())
+    }
+
+    // Makes a write durable: right away if `write_through` is set, or otherwise by arming the
+    // flush timer.
+    fn finish_write(
+        disk: &mut dyn DiskFile,
+        write_through: bool,
+        flush_timer: &mut Timer,
+        flush_timer_armed: &mut bool,
+    ) -> result::Result<(), ExecuteError> {
+        if write_through {
+            disk.fdatasync().map_err(ExecuteError::Flush)?;
+        } else if !*flush_timer_armed {
+            flush_timer
+                .reset(FLUSH_DELAY, None)
+                .map_err(ExecuteError::Timer)?;
+            *flush_timer_armed = true;
+        }
+        Ok(())
+    }
+
+    // Executes `requests`, reads or writes of contiguous ranges of the disk in order, with as few
+    // vectored reads or writes as the host allows. Returns the length to add to the used ring for
+    // each request.
+    fn execute_merged(
+        requests: &[MergeableRequest],
+        disk: &mut dyn DiskFile,
+        disk_size: u64,
+        write_through: bool,
+        flush_timer: &mut Timer,
+        flush_timer_armed: &mut bool,
+        mem: &GuestMemory,
+    ) -> result::Result<Vec<usize>, ExecuteError> {
+        let offset = requests[0].offset;
+        let sector = offset >> SECTOR_SHIFT;
+        let total: u64 = requests.iter().map(|r| r.data_len).sum();
+        check_range(offset, total, disk_size)?;
+
+        let mut lens = Vec::with_capacity(requests.len());
+        let mut status_writers = Vec::with_capacity(requests.len());
+        let mut done = 0;
+        if requests[0].req_type == VIRTIO_BLK_T_IN {
+            let mut writers = Vec::with_capacity(requests.len());
+            for request in requests {
+                let mut writer = Writer::new(mem.clone(), request.avail_desc.clone())
+                    .map_err(ExecuteError::Descriptor)?;
+                let len = writer.available_bytes();
+                status_writers.push(writer.split_at(len - 1));
+                lens.push(len);
+                writers.push(writer);
+            }
+            let mut current = 0;
+            while done < total {
+                let result = {
+                    let iovs: Vec<_> = writers[current..]
+                        .iter()
+                        .flat_map(|w| w.get_remaining())
+                        .take(iov_max())
+                        .collect();
+                    disk.read_vectored_at_volatile(&iovs, offset + done)
+                };
+                let count = match result {
+                    Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                    r => r,
+                }
+                .map_err(|desc_error| ExecuteError::ReadIo {
+                    length: total as usize,
+                    sector,
+                    desc_error,
+                })?;
+                let mut rem = count;
+                while rem > 0 {
+                    let consumed = min(rem, writers[current].available_bytes());
+                    writers[current].consume_bytes(consumed);
+                    rem -= consumed;
+                    if writers[current].available_bytes() == 0 {
+                        current += 1;
+                    }
+                }
+                done += count as u64;
+            }
+        } else {
+            let mut readers = Vec::with_capacity(requests.len());
+            for request in requests {
+                let mut reader = Reader::new(mem.clone(), request.avail_desc.clone())
+                    .map_err(ExecuteError::Descriptor)?;
+                reader.consume(size_of::<virtio_blk_req_header>());
+                let mut writer = Writer::new(mem.clone(), request.avail_desc.clone())
+                    .map_err(ExecuteError::Descriptor)?;
+                let len = writer.available_bytes();
+                status_writers.push(writer.split_at(len.saturating_sub(1)));
+                lens.push(len);
+                readers.push(reader);
+            }
+            let mut current = 0;
+            while done < total {
+                let result = {
+                    let iovs: Vec<_> = readers[current..]
+                        .iter()
+                        .flat_map(|r| r.get_remaining())
+                        .take(iov_max())
+                        .collect();
+                    disk.write_vectored_at_volatile(&iovs, offset + done)
+                };
+                let count = match result {
+                    Ok(0) => Err(io::Error::from(io::ErrorKind::WriteZero)),
+                    r => r,
+                }
+                .map_err(|desc_error| ExecuteError::WriteIo {
+                    length: total as usize,
+                    sector,
+                    desc_error,
+                })?;
+                let mut rem = count;
+                while rem > 0 {
+                    let consumed = min(rem, readers[current].available_bytes());
+                    readers[current].consume(consumed);
+                    rem -= consumed;
+                    if readers[current].available_bytes() == 0 {
+                        current += 1;
+                    }
+                }
+                done += count as u64;
+            }
+            Block::finish_write(disk, write_through, flush_timer, flush_timer_armed)?;
+        }
+
+        for status_writer in &mut status_writers {
+            status_writer
+                .write_all(&[VIRTIO_BLK_S_OK])
+                .map_err(ExecuteError::WriteStatus)?;
+        }
+        Ok(lens)
+    }
+
     // Execute a single block device request.
     // `writer` includes the data region only; the status byte is not included.
     // It is up to the caller to convert the result of this function into a status byte
@@ -589,9 +2055,11 @@ impl Block {
         writer: &mut Writer,
         read_only: bool,
         sparse: bool,
+        write_through: bool,
         disk: &mut dyn DiskFile,
         disk_size: u64,
         id: Option<BlockId>,
+        zoned: Option<&ZonedDisk>,
         flush_timer: &mut Timer,
         flush_timer_armed: &mut bool,
     ) -> result::Result<(), ExecuteError> {
@@ -599,31 +2067,19 @@ impl Block {
 
         let req_type = req_header.req_type.to_native();
         let sector = req_header.sector.to_native();
-        // Delay after a write when the file is auto-flushed.
-        let flush_delay = Duration::from_secs(60);
 
-        if read_only && req_type != VIRTIO_BLK_T_IN && req_type != VIRTIO_BLK_T_GET_ID {
+        if read_only
+            && req_type != VIRTIO_BLK_T_IN
+            && req_type != VIRTIO_BLK_T_GET_ID
+            && req_type != VIRTIO_BLK_T_ZONE_REPORT
+        {
             return Err(ExecuteError::ReadOnly {
                 request_type: req_type,
             });
         }
 
-        /// Check that a request accesses only data within the disk's current size.
-        /// All parameters are in units of bytes.
-        fn check_range(
-            io_start: u64,
-            io_length: u64,
-            disk_size: u64,
-        ) -> result::Result<(), ExecuteError> {
-            let io_end = io_start
-                .checked_add(io_length)
-                .ok_or(ExecuteError::OutOfRange)?;
-            if io_end > disk_size {
-                Err(ExecuteError::OutOfRange)
-            } else {
-                Ok(())
-            }
-        }
+        // Zoned requests are only supported by zoned devices.
+        let zoned_only = || zoned.ok_or(ExecuteError::Unsupported(req_type));
 
         match req_type {
             VIRTIO_BLK_T_IN => {
@@ -640,85 +2096,147 @@ impl Block {
                         desc_error,
                     })?;
             }
-            VIRTIO_BLK_T_OUT => {
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_ZONE_APPEND => {
                 let data_len = reader.available_bytes();
+                let sectors = data_len as u64 >> SECTOR_SHIFT;
+                // Appends go to the write pointer of the zone that starts at `sector`.
+                let sector = if req_type == VIRTIO_BLK_T_ZONE_APPEND {
+                    zoned_only()?
+                        .append(sector, sectors)
+                        .map_err(ExecuteError::Zone)?
+                } else {
+                    if let Some(zoned) = zoned {
+                        zoned.write(sector, sectors).map_err(ExecuteError::Zone)?;
+                    }
+                    sector
+                };
                 let offset = sector
                     .checked_shl(u32::from(SECTOR_SHIFT))
                     .ok_or(ExecuteError::OutOfRange)?;
                 check_range(offset, data_len as u64, disk_size)?;
                 reader
-                    .read_exact_to_at(disk, data_len, offset)
+                    .read_exact_to_at(&mut *disk, data_len, offset)
                     .map_err(|desc_error| ExecuteError::WriteIo {
                         length: data_len,
                         sector,
                         desc_error,
                     })?;
-                if !*flush_timer_armed {
-                    flush_timer
-                        .reset(flush_delay, None)
-                        .map_err(ExecuteError::Timer)?;
-                    *flush_timer_armed = true;
+                Block::finish_write(disk, write_through, flush_timer, flush_timer_armed)?;
+                if req_type == VIRTIO_BLK_T_ZONE_APPEND {
+                    // The sector the data was written to comes before the status.
+                    writer
+                        .write_obj(Le64::from(sector))
+                        .map_err(ExecuteError::WriteStatus)?;
                 }
             }
             VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
-                if req_type == VIRTIO_BLK_T_DISCARD && !sparse {
-                    // Discard is a hint; if this is a non-sparse disk, just ignore it.
-                    return Ok(());
+                discard_write_zeroes(reader, req_type, sparse, disk, disk_size)?;
+                if write_through {
+                    disk.fdatasync().map_err(ExecuteError::Flush)?;
                 }
+            }
+            VIRTIO_BLK_T_FLUSH => {
+                Block::flush_disk(disk, write_through, flush_timer, flush_timer_armed)?;
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                if let Some(id) = id {
+                    writer.write_all(&id).map_err(ExecuteError::CopyId)?;
+                } else {
+                    return Err(ExecuteError::Unsupported(req_type));
+                }
+            }
+            VIRTIO_BLK_T_ZONE_REPORT => report_zones(writer, zoned_only()?, sector)?,
+            VIRTIO_BLK_T_ZONE_OPEN => zoned_only()?
+                .manage(ZoneOp::Open, sector)
+                .map_err(ExecuteError::Zone)?,
+            VIRTIO_BLK_T_ZONE_CLOSE => zoned_only()?
+                .manage(ZoneOp::Close, sector)
+                .map_err(ExecuteError::Zone)?,
+            VIRTIO_BLK_T_ZONE_FINISH => zoned_only()?
+                .manage(ZoneOp::Finish, sector)
+                .map_err(ExecuteError::Zone)?,
+            VIRTIO_BLK_T_ZONE_RESET => zoned_only()?
+                .manage(ZoneOp::Reset, sector)
+                .map_err(ExecuteError::Zone)?,
+            VIRTIO_BLK_T_ZONE_RESET_ALL => zoned_only()?.reset_all().map_err(ExecuteError::Zone)?,
+            t => return Err(ExecuteError::Unsupported(t)),
+        };
+        Ok(())
+    }
 
-                while reader.available_bytes() >= size_of::<virtio_blk_discard_write_zeroes>() {
-                    let seg: virtio_blk_discard_write_zeroes =
-                        reader.read_obj().map_err(ExecuteError::Read)?;
-
-                    let sector = seg.sector.to_native();
-                    let num_sectors = seg.num_sectors.to_native();
-                    let flags = seg.flags.to_native();
+    // Like `execute_request`, with reads, writes and flushes going through the async disk of a
+    // worker running on io_uring.
+    async fn execute_request_async(
+        reader: &mut Reader,
+        writer: &mut Writer,
+        ctx: &AsyncRequestContext<'_>,
+    ) -> result::Result<(), ExecuteError> {
+        let req_header: virtio_blk_req_header = reader.read_obj().map_err(ExecuteError::Read)?;
 
-                    let valid_flags = if req_type == VIRTIO_BLK_T_WRITE_ZEROES {
-                        VIRTIO_BLK_DISCARD_WRITE_ZEROES_FLAG_UNMAP
-                    } else {
-                        0
-                    };
+        let req_type = req_header.req_type.to_native();
+        let sector = req_header.sector.to_native();
 
-                    if (flags & !valid_flags) != 0 {
-                        return Err(ExecuteError::DiscardWriteZeroes {
-                            ioerr: None,
-                            sector,
-                            num_sectors,
-                            flags,
-                        });
-                    }
+        if ctx.read_only && req_type != VIRTIO_BLK_T_IN && req_type != VIRTIO_BLK_T_GET_ID {
+            return Err(ExecuteError::ReadOnly {
+                request_type: req_type,
+            });
+        }
 
-                    let offset = sector
-                        .checked_shl(u32::from(SECTOR_SHIFT))
-                        .ok_or(ExecuteError::OutOfRange)?;
-                    let length = u64::from(num_sectors)
-                        .checked_shl(u32::from(SECTOR_SHIFT))
-                        .ok_or(ExecuteError::OutOfRange)?;
-                    check_range(offset, length, disk_size)?;
-
-                    if req_type == VIRTIO_BLK_T_DISCARD {
-                        // Since Discard is just a hint and some filesystems may not implement
-                        // FALLOC_FL_PUNCH_HOLE, ignore punch_hole errors.
-                        let _ = disk.punch_hole(offset, length);
-                    } else {
-                        disk.write_zeroes_all_at(offset, length as usize)
-                            .map_err(|e| ExecuteError::DiscardWriteZeroes {
-                                ioerr: Some(e),
-                                sector,
-                                num_sectors,
-                                flags,
-                            })?;
-                    }
+        let disk_size = *ctx.disk_size.lock();
+        match req_type {
+            VIRTIO_BLK_T_IN => {
+                let data_len = writer.available_bytes();
+                let offset = sector
+                    .checked_shl(u32::from(SECTOR_SHIFT))
+                    .ok_or(ExecuteError::OutOfRange)?;
+                check_range(offset, data_len as u64, disk_size)?;
+                writer
+                    .write_all_from_at_fut(ctx.disk, data_len, offset)
+                    .await
+                    .map_err(|disk_error| ExecuteError::ReadIoAsync {
+                        length: data_len,
+                        sector,
+                        disk_error,
+                    })?;
+            }
+            VIRTIO_BLK_T_OUT => {
+                let data_len = reader.available_bytes();
+                let offset = sector
+                    .checked_shl(u32::from(SECTOR_SHIFT))
+                    .ok_or(ExecuteError::OutOfRange)?;
+                check_range(offset, data_len as u64, disk_size)?;
+                reader
+                    .read_exact_to_at_fut(ctx.disk, data_len, offset)
+                    .await
+                    .map_err(|disk_error| ExecuteError::WriteIoAsync {
+                        length: data_len,
+                        sector,
+                        disk_error,
+                    })?;
+                if ctx.write_through {
+                    // The async disk can only fsync, which syncs the data along with the rest.
+                    ctx.disk.fsync().await.map_err(ExecuteError::FlushAsync)?;
+                } else if !ctx.flush_timer_armed.get() {
+                    ctx.flush_timer
+                        .as_source()
+                        .reset(FLUSH_DELAY, None)
+                        .map_err(ExecuteError::Timer)?;
+                    ctx.flush_timer_armed.set(true);
+                }
+            }
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
+                let sparse = ctx.sparse.load(Ordering::Relaxed);
+                let mut sync_disk = ctx.sync_disk.lock();
+                discard_write_zeroes(reader, req_type, sparse, &mut **sync_disk, disk_size)?;
+                if ctx.write_through {
+                    sync_disk.fdatasync().map_err(ExecuteError::Flush)?;
                 }
             }
             VIRTIO_BLK_T_FLUSH => {
-                disk.fsync().map_err(ExecuteError::Flush)?;
-                flush_timer.clear().map_err(ExecuteError::Timer)?;
-                *flush_timer_armed = false;
+                Block::flush_disk_async(ctx).await?;
             }
             VIRTIO_BLK_T_GET_ID => {
-                if let Some(id) = id {
+                if let Some(id) = ctx.id {
                     writer.write_all(&id).map_err(ExecuteError::CopyId)?;
                 } else {
                     return Err(ExecuteError::Unsupported(req_type));
@@ -737,7 +2255,7 @@ impl Drop for Block {
             let _ = kill_evt.write(1);
         }
 
-        if let Some(worker_thread) = self.worker_thread.take() {
+        for worker_thread in self.worker_threads.drain(..) {
             let _ = worker_thread.join();
         }
     }
@@ -751,6 +2269,14 @@ impl VirtioDevice for Block {
             keep_rds.extend(disk_image.as_raw_descriptors());
         }
 
+        if let Some(raw_image) = &self.raw_image {
+            keep_rds.push(raw_image.as_raw_descriptor());
+        }
+
+        if let Some(zoned) = &self.zoned {
+            keep_rds.push(zoned.as_raw_descriptor());
+        }
+
         if let Some(control_socket) = &self.control_socket {
             keep_rds.push(control_socket.as_raw_descriptor());
         }
@@ -767,13 +2293,19 @@ impl VirtioDevice for Block {
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        &self.queue_sizes
     }
 
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         let config_space = {
             let disk_size = self.disk_size.lock();
-            build_config_space(*disk_size, self.seg_max, self.block_size)
+            build_config_space(
+                *disk_size,
+                self.seg_max,
+                self.block_size,
+                self.queue_sizes.len() as u16,
+                self.zoned.as_ref(),
+            )
         };
         copy_config(data, 0, config_space.as_slice(), offset);
     }
@@ -783,53 +2315,82 @@ impl VirtioDevice for Block {
         mem: GuestMemory,
         interrupt: Interrupt,
         queues: Vec<Queue>,
-        mut queue_evts: Vec<Event>,
+        queue_evts: Vec<Event>,
     ) {
-        if queues.len() != 1 || queue_evts.len() != 1 {
+        if queues.len() != self.queue_sizes.len() || queue_evts.len() != self.queue_sizes.len() {
             return;
         }
 
-        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
-            Ok(v) => v,
+        let kill_evt = match self.create_kill_evt() {
+            Ok(evt) => evt,
             Err(e) => {
                 error!("failed creating kill Event pair: {}", e);
                 return;
             }
         };
-        self.kill_evt = Some(self_kill_evt);
 
-        let read_only = self.read_only;
-        let sparse = self.sparse;
-        let disk_size = self.disk_size.clone();
+        let disk_image = match self.disk_image.take() {
+            Some(disk_image) => Arc::new(Mutex::new(disk_image)),
+            None => return,
+        };
+        let interrupt = Arc::new(interrupt);
         let id = self.id.take();
-        if let Some(disk_image) = self.disk_image.take() {
-            let control_socket = self.control_socket.take();
-            let worker_result =
-                thread::Builder::new()
-                    .name("virtio_blk".to_string())
-                    .spawn(move || {
-                        let mut worker = Worker {
-                            interrupt,
-                            queues,
-                            mem,
-                            disk_image,
-                            disk_size,
-                            read_only,
-                            sparse,
-                            id,
-                            control_socket,
-                        };
-                        worker.run(queue_evts.remove(0), kill_evt);
-                        worker
-                    });
-
-            match worker_result {
+        let use_uring =
+            cros_async::uring_available() && self.busy_poll.is_none() && self.max_merge_size == 0;
+        for (i, (queue, queue_evt)) in queues.into_iter().zip(queue_evts).enumerate() {
+            // The workers wait on the same kill event, which they never read, so that all of them
+            // see it.
+            let kill_evt = match kill_evt.try_clone() {
+                Ok(evt) => evt,
+                Err(e) => {
+                    error!("failed to clone kill Event: {}", e);
+                    return;
+                }
+            };
+            // Each worker submits its requests to its own io_uring, through its own file.
+            let raw_image = match &self.raw_image {
+                Some(raw_image) if use_uring => match raw_image.try_clone() {
+                    Ok(raw_image) => Some(raw_image),
+                    Err(e) => {
+                        error!("failed to clone raw disk image: {}", e);
+                        return;
+                    }
+                },
+                _ => None,
+            };
+            let worker = Worker {
+                interrupt: interrupt.clone(),
+                queue,
+                mem: mem.clone(),
+                disk_image: disk_image.clone(),
+                raw_image,
+                disk_size: self.disk_size.clone(),
+                read_only: self.read_only,
+                sparse: self.sparse.clone(),
+                write_through: self.write_through,
+                coalesce_flushes: self.flush_coalescing,
+                max_merge_size: self.max_merge_size,
+                id,
+                zoned: self.zoned.clone(),
+                busy_poll: self.busy_poll.map(BusyPoll::new),
+                coalescer: InterruptCoalescer::new(self.interrupt_interval.clone()),
+                throttle: self.throttle.clone(),
+                stats: self.stats.clone(),
+                throttled_until: None,
+                first_queue: i == 0,
+                control_socket: if i == 0 {
+                    self.control_socket.take()
+                } else {
+                    None
+                },
+            };
+            match spawn_worker(i, worker, queue_evt, kill_evt) {
                 Err(e) => {
                     error!("failed to spawn virtio_blk worker: {}", e);
                     return;
                 }
                 Ok(join_handle) => {
-                    self.worker_thread = Some(join_handle);
+                    self.worker_threads.push(join_handle);
                 }
             }
         }
@@ -843,31 +2404,113 @@ impl VirtioDevice for Block {
             }
         }
 
-        if let Some(worker_thread) = self.worker_thread.take() {
+        let debug_label = self.debug_label();
+        let mut workers = std::mem::take(&mut self.sleeping_workers);
+        for worker_thread in self.worker_threads.drain(..) {
             match worker_thread.join() {
                 Err(_) => {
-                    error!("{}: failed to get back resources", self.debug_label());
+                    error!("{}: failed to get back resources", debug_label);
                     return false;
                 }
-                Ok(worker) => {
-                    self.disk_image = Some(worker.disk_image);
-                    self.control_socket = worker.control_socket;
-                    return true;
+                Ok(worker) => workers.push(worker),
+            }
+        }
+        if workers.is_empty() {
+            return false;
+        }
+        let mut disk_image = None;
+        for (worker, _queue_evt) in workers {
+            if worker.control_socket.is_some() {
+                self.control_socket = worker.control_socket;
+            }
+            disk_image = Some(worker.disk_image);
+        }
+        // All the other workers dropped their reference to the disk image when they were joined.
+        match disk_image.map(Arc::try_unwrap) {
+            Some(Ok(disk_image)) => {
+                self.disk_image = Some(disk_image.into_inner());
+                true
+            }
+            _ => {
+                error!("{}: failed to get back the disk image", debug_label);
+                false
+            }
+        }
+    }
+}
+
+// Sleeping stops the workers, which complete the requests they already took from their queue
+// first, and waking starts them again on the same queues.
+impl Suspendable for Block {
+    fn sleep(&mut self) -> suspendable::Result<()> {
+        if self.worker_threads.is_empty() {
+            return Ok(());
+        }
+        let debug_label = self.debug_label();
+        if let Some(kill_evt) = self.kill_evt.take() {
+            if let Err(e) = kill_evt.write(1) {
+                error!("{}: failed to notify the kill event: {}", debug_label, e);
+                return Err(SuspendableError::WorkerFailed);
+            }
+        }
+        for worker_thread in self.worker_threads.drain(..) {
+            match worker_thread.join() {
+                Ok(worker) => self.sleeping_workers.push(worker),
+                Err(_) => {
+                    error!("{}: failed to stop the worker", debug_label);
+                    return Err(SuspendableError::WorkerFailed);
+                }
+            }
+        }
+        // The flush timer of the workers is gone, so sync the writes it was waiting for now.
+        if let Some((worker, _)) = self.sleeping_workers.first() {
+            if !self.read_only {
+                if let Err(e) = worker.disk_image.lock().fsync() {
+                    error!("{}: failed to flush the disk: {}", debug_label, e);
+                    return Err(SuspendableError::WorkerFailed);
                 }
             }
         }
-        false
+        Ok(())
+    }
+
+    fn wake(&mut self) -> suspendable::Result<()> {
+        if self.sleeping_workers.is_empty() {
+            return Ok(());
+        }
+        let debug_label = self.debug_label();
+        let kill_evt = self.create_kill_evt().map_err(|e| {
+            error!("{}: failed creating kill Event pair: {}", debug_label, e);
+            SuspendableError::WorkerFailed
+        })?;
+        let workers = std::mem::take(&mut self.sleeping_workers);
+        for (i, (worker, queue_evt)) in workers.into_iter().enumerate() {
+            let kill_evt = kill_evt.try_clone().map_err(|e| {
+                error!("{}: failed to clone kill Event: {}", debug_label, e);
+                SuspendableError::WorkerFailed
+            })?;
+            let join_handle = spawn_worker(i, worker, queue_evt, kill_evt).map_err(|e| {
+                error!("{}: failed to spawn virtio_blk worker: {}", debug_label, e);
+                SuspendableError::WorkerFailed
+            })?;
+            self.worker_threads.push(join_handle);
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::mem::size_of_val;
+    use std::os::unix::fs::{FileExt, MetadataExt};
     use tempfile::tempfile;
     use vm_memory::GuestAddress;
 
+    use std::sync::atomic::AtomicUsize;
+
     use crate::virtio::base_features;
     use crate::virtio::descriptor_utils::{create_descriptor_chain, DescriptorType};
+    use crate::virtio::VIRTIO_MSI_NO_VECTOR;
 
     use super::*;
 
@@ -877,7 +2520,19 @@ mod tests {
         f.set_len(0x1000).unwrap();
 
         let features = base_features(false);
-        let b = Block::new(features, Box::new(f), true, false, 512, None, None).unwrap();
+        let b = Block::new(
+            features,
+            Box::new(f),
+            None,
+            BlockConfig {
+                read_only: true,
+                sparse: false,
+                ..Default::default()
+            },
+            None,
+            None,
+        )
+        .unwrap();
         let mut num_sectors = [0u8; 4];
         b.read_config(0, &mut num_sectors);
         // size is 0x1000, so num_sectors is 8 (4096/512).
@@ -894,8 +2549,21 @@ mod tests {
         f.set_len(0x1000).unwrap();
 
         let features = base_features(false);
-        let b = Block::new(features, Box::new(f), true, false, 4096, None, None).unwrap();
-        let mut blk_size = [0u8; 4];
+        let b = Block::new(
+            features,
+            Box::new(f),
+            None,
+            BlockConfig {
+                read_only: true,
+                sparse: false,
+                block_size: 4096,
+                ..Default::default()
+            },
+            None,
+            None,
+        )
+        .unwrap();
+        let mut blk_size = [0u8; 4];
         b.read_config(20, &mut blk_size);
         // blk_size should be 4096 (0x1000).
         assert_eq!([0x00, 0x10, 0x00, 0x00], blk_size);
@@ -907,35 +2575,89 @@ mod tests {
         {
             let f = tempfile().unwrap();
             let features = base_features(false);
-            let b = Block::new(features, Box::new(f), false, true, 512, None, None).unwrap();
+            let b = Block::new(
+                features,
+                Box::new(f),
+                None,
+                BlockConfig::default(),
+                None,
+                None,
+            )
+            .unwrap();
             // writable device should set VIRTIO_BLK_F_FLUSH + VIRTIO_BLK_F_DISCARD
             // + VIRTIO_BLK_F_WRITE_ZEROES + VIRTIO_F_VERSION_1 + VIRTIO_BLK_F_BLK_SIZE
-            // + VIRTIO_BLK_F_SEG_MAX
-            assert_eq!(0x100006244, b.features());
+            // + VIRTIO_BLK_F_SEG_MAX + VIRTIO_RING_F_EVENT_IDX + VIRTIO_F_RING_PACKED
+            assert_eq!(0x520006244, b.features());
         }
 
         // read-write block device, non-sparse
         {
             let f = tempfile().unwrap();
             let features = base_features(false);
-            let b = Block::new(features, Box::new(f), false, false, 512, None, None).unwrap();
+            let b = Block::new(
+                features,
+                Box::new(f),
+                None,
+                BlockConfig {
+                    sparse: false,
+                    ..Default::default()
+                },
+                None,
+                None,
+            )
+            .unwrap();
             // writable device should set VIRTIO_BLK_F_FLUSH
             // + VIRTIO_BLK_F_WRITE_ZEROES + VIRTIO_F_VERSION_1 + VIRTIO_BLK_F_BLK_SIZE
-            // + VIRTIO_BLK_F_SEG_MAX
-            assert_eq!(0x100004244, b.features());
+            // + VIRTIO_BLK_F_SEG_MAX + VIRTIO_RING_F_EVENT_IDX + VIRTIO_F_RING_PACKED
+            assert_eq!(0x520004244, b.features());
         }
 
         // read-only block device
         {
             let f = tempfile().unwrap();
             let features = base_features(false);
-            let b = Block::new(features, Box::new(f), true, true, 512, None, None).unwrap();
+            let b = Block::new(
+                features,
+                Box::new(f),
+                None,
+                BlockConfig {
+                    read_only: true,
+                    ..Default::default()
+                },
+                None,
+                None,
+            )
+            .unwrap();
             // read-only device should set VIRTIO_BLK_F_FLUSH and VIRTIO_BLK_F_RO
             // + VIRTIO_F_VERSION_1 + VIRTIO_BLK_F_BLK_SIZE + VIRTIO_BLK_F_SEG_MAX
-            assert_eq!(0x100000264, b.features());
+            // + VIRTIO_RING_F_EVENT_IDX + VIRTIO_F_RING_PACKED
+            assert_eq!(0x520000264, b.features());
         }
     }
 
+    #[test]
+    fn multiple_queues() {
+        let f = tempfile().unwrap();
+        let features = base_features(false);
+        let b = Block::new(
+            features,
+            Box::new(f),
+            None,
+            BlockConfig {
+                num_queues: 4,
+                ..Default::default()
+            },
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(b.queue_max_sizes(), &[QUEUE_SIZE; 4]);
+        assert_ne!(b.features() & (1 << VIRTIO_BLK_F_MQ), 0);
+        let mut num_queues = [0u8; 2];
+        b.read_config(34, &mut num_queues);
+        assert_eq!([0x04, 0x00], num_queues);
+    }
+
     #[test]
     fn read_last_sector() {
         let mut f = tempfile().unwrap();
@@ -976,9 +2698,11 @@ mod tests {
             avail_desc,
             false,
             true,
+            false,
             &mut f,
             disk_size,
             None,
+            None,
             &mut flush_timer,
             &mut flush_timer_armed,
             &mem,
@@ -1030,9 +2754,11 @@ mod tests {
             avail_desc,
             false,
             true,
+            false,
             &mut f,
             disk_size,
             None,
+            None,
             &mut flush_timer,
             &mut flush_timer_armed,
             &mem,
@@ -1044,6 +2770,70 @@ mod tests {
         assert_eq!(status, VIRTIO_BLK_S_IOERR);
     }
 
+    #[test]
+    fn write_read_only() {
+        let mut f = tempfile().unwrap();
+        let disk_size = 0x1000;
+        f.set_len(disk_size).unwrap();
+
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
+            .expect("Creating guest memory failed.");
+
+        let req_hdr = virtio_blk_req_header {
+            req_type: Le32::from(VIRTIO_BLK_T_OUT),
+            reserved: Le32::from(0),
+            sector: Le64::from(0),
+        };
+        mem.write_obj_at_addr(req_hdr, GuestAddress(0x1000))
+            .expect("writing req failed");
+        let data_addr = GuestAddress(0x1000 + size_of_val(&req_hdr) as u64);
+        mem.write_all_at_addr(&[0xffu8; 512], data_addr)
+            .expect("writing data failed");
+
+        let avail_desc = create_descriptor_chain(
+            &mem,
+            GuestAddress(0x100),  // Place descriptor chain at 0x100.
+            GuestAddress(0x1000), // Describe buffer at 0x1000.
+            vec![
+                // Request header
+                (DescriptorType::Readable, size_of_val(&req_hdr) as u32),
+                // I/O buffer (1 sector of data)
+                (DescriptorType::Readable, 512),
+                // Request status
+                (DescriptorType::Writable, 1),
+            ],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+
+        let mut flush_timer = Timer::new().expect("failed to create flush_timer");
+        let mut flush_timer_armed = false;
+
+        Worker::process_one_request(
+            avail_desc,
+            true,
+            true,
+            false,
+            &mut f,
+            disk_size,
+            None,
+            None,
+            &mut flush_timer,
+            &mut flush_timer_armed,
+            &mem,
+        )
+        .expect("execute failed");
+
+        let status_offset = data_addr.unchecked_add(512);
+        let status = mem.read_obj_from_addr::<u8>(status_offset).unwrap();
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+
+        // Nothing reached the disk.
+        let mut contents = vec![0xffu8; 512];
+        f.read_exact_at(&mut contents, 0).unwrap();
+        assert!(contents.iter().all(|&b| b == 0));
+    }
+
     #[test]
     fn get_id() {
         let mut f = tempfile().unwrap();
@@ -1086,9 +2876,11 @@ mod tests {
             avail_desc,
             false,
             true,
+            false,
             &mut f,
             disk_size,
             Some(*id),
+            None,
             &mut flush_timer,
             &mut flush_timer_armed,
             &mem,
@@ -1103,4 +2895,447 @@ mod tests {
         let returned_id = mem.read_obj_from_addr::<[u8; 20]>(id_offset).unwrap();
         assert_eq!(returned_id, *id);
     }
+
+    #[test]
+    fn write_zeroes_unmap() {
+        let mut f = tempfile().unwrap();
+        let disk_size = 0x10000;
+        f.write_all(&vec![0xffu8; disk_size as usize]).unwrap();
+        f.sync_all().unwrap();
+        let allocated_blocks = f.metadata().unwrap().blocks();
+
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
+            .expect("Creating guest memory failed.");
+
+        let req_hdr = virtio_blk_req_header {
+            req_type: Le32::from(VIRTIO_BLK_T_WRITE_ZEROES),
+            reserved: Le32::from(0),
+            sector: Le64::from(0),
+        };
+        mem.write_obj_at_addr(req_hdr, GuestAddress(0x1000))
+            .expect("writing req failed");
+        let seg = virtio_blk_discard_write_zeroes {
+            sector: Le64::from(0),
+            num_sectors: Le32::from((disk_size >> SECTOR_SHIFT) as u32),
+            flags: Le32::from(VIRTIO_BLK_DISCARD_WRITE_ZEROES_FLAG_UNMAP),
+        };
+        let seg_addr = GuestAddress(0x1000 + size_of_val(&req_hdr) as u64);
+        mem.write_obj_at_addr(seg, seg_addr)
+            .expect("writing segment failed");
+
+        let avail_desc = create_descriptor_chain(
+            &mem,
+            GuestAddress(0x100),  // Place descriptor chain at 0x100.
+            GuestAddress(0x1000), // Describe buffer at 0x1000.
+            vec![
+                // Request header
+                (DescriptorType::Readable, size_of_val(&req_hdr) as u32),
+                // Segment to zero
+                (DescriptorType::Readable, size_of_val(&seg) as u32),
+                // Request status
+                (DescriptorType::Writable, 1),
+            ],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+
+        let mut flush_timer = Timer::new().expect("failed to create flush_timer");
+        let mut flush_timer_armed = false;
+
+        Worker::process_one_request(
+            avail_desc,
+            false,
+            true,
+            false,
+            &mut f,
+            disk_size,
+            None,
+            None,
+            &mut flush_timer,
+            &mut flush_timer_armed,
+            &mem,
+        )
+        .expect("execute failed");
+
+        let status_offset = seg_addr.unchecked_add(size_of_val(&seg) as u64);
+        let status = mem.read_obj_from_addr::<u8>(status_offset).unwrap();
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+
+        // The range reads back as zeroes, and its storage was given back to the host.
+        let mut contents = vec![0xffu8; disk_size as usize];
+        f.read_exact_at(&mut contents, 0).unwrap();
+        assert!(contents.iter().all(|&b| b == 0));
+        assert!(f.metadata().unwrap().blocks() < allocated_blocks);
+    }
+
+    #[test]
+    fn pending_flush() {
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
+            .expect("Creating guest memory failed.");
+
+        let mut req_hdr = virtio_blk_req_header {
+            req_type: Le32::from(VIRTIO_BLK_T_FLUSH),
+            reserved: Le32::from(0),
+            sector: Le64::from(0),
+        };
+        mem.write_obj_at_addr(req_hdr, GuestAddress(0x1000))
+            .expect("writing req failed");
+        let status_addr = GuestAddress(0x1000 + size_of_val(&req_hdr) as u64);
+        mem.write_obj_at_addr(0xffu8, status_addr)
+            .expect("writing status failed");
+
+        let descriptors = vec![
+            // Request header
+            (DescriptorType::Readable, size_of_val(&req_hdr) as u32),
+            // Request status
+            (DescriptorType::Writable, 1),
+        ];
+        let avail_desc = create_descriptor_chain(
+            &mem,
+            GuestAddress(0x100),  // Place descriptor chain at 0x100.
+            GuestAddress(0x1000), // Describe buffer at 0x1000.
+            descriptors.clone(),
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+
+        // The flush only completes once the status of the shared sync is known.
+        let flush = PendingFlush::new(&avail_desc, &mem).expect("flush not recognized");
+        assert_eq!(mem.read_obj_from_addr::<u8>(status_addr).unwrap(), 0xff);
+        assert_eq!(flush.complete(VIRTIO_BLK_S_OK), (avail_desc.index, 1));
+        assert_eq!(
+            mem.read_obj_from_addr::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+
+        // Other requests are executed as usual.
+        req_hdr.req_type = Le32::from(VIRTIO_BLK_T_GET_ID);
+        mem.write_obj_at_addr(req_hdr, GuestAddress(0x1000))
+            .expect("writing req failed");
+        let avail_desc = create_descriptor_chain(
+            &mem,
+            GuestAddress(0x100),
+            GuestAddress(0x1000),
+            descriptors,
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        assert!(PendingFlush::new(&avail_desc, &mem).is_none());
+    }
+
+    #[test]
+    fn merged_write_and_read() {
+        let mut f = tempfile().unwrap();
+        let disk_size = 0x1000;
+        f.set_len(disk_size).unwrap();
+
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
+            .expect("Creating guest memory failed.");
+        let hdr_len = size_of::<virtio_blk_req_header>() as u64;
+        let data_addr = |i: u64| GuestAddress(0x1000 * (i + 1) + hdr_len);
+        let status_addr = |i: u64| GuestAddress(0x1000 * (i + 1) + hdr_len + 512);
+
+        // Requests for sectors 1 and 2, with their buffers at 0x1000 and 0x2000.
+        let requests = |req_type: u32, data: DescriptorType| -> Vec<MergeableRequest> {
+            (0..2)
+                .map(|i| {
+                    let req_hdr = virtio_blk_req_header {
+                        req_type: Le32::from(req_type),
+                        reserved: Le32::from(0),
+                        sector: Le64::from(1 + i),
+                    };
+                    mem.write_obj_at_addr(req_hdr, GuestAddress(0x1000 * (i + 1)))
+                        .expect("writing req failed");
+                    let avail_desc = create_descriptor_chain(
+                        &mem,
+                        GuestAddress(0x100 * (i + 1)),
+                        GuestAddress(0x1000 * (i + 1)),
+                        vec![
+                            (DescriptorType::Readable, hdr_len as u32),
+                            (data, 512),
+                            (DescriptorType::Writable, 1),
+                        ],
+                        0,
+                    )
+                    .expect("create_descriptor_chain failed");
+                    MergeableRequest::new(&avail_desc, false, &mem).expect("not mergeable")
+                })
+                .collect()
+        };
+
+        let mut flush_timer = Timer::new().expect("failed to create flush_timer");
+        let mut flush_timer_armed = false;
+
+        let writes = requests(VIRTIO_BLK_T_OUT, DescriptorType::Readable);
+        assert!(MergeableRequest::can_merge(&writes[..1], &writes[1], 1024));
+        assert!(!MergeableRequest::can_merge(&writes[..1], &writes[1], 1023));
+        assert!(!MergeableRequest::can_merge(&writes[1..], &writes[0], 1024));
+        mem.write_all_at_addr(&[0x11; 512], data_addr(0)).unwrap();
+        mem.write_all_at_addr(&[0x22; 512], data_addr(1)).unwrap();
+        let lens = Block::execute_merged(
+            &writes,
+            &mut f,
+            disk_size,
+            false,
+            &mut flush_timer,
+            &mut flush_timer_armed,
+            &mem,
+        )
+        .expect("merged write failed");
+        assert_eq!(lens, vec![1, 1]);
+        assert!(flush_timer_armed);
+        let mut contents = [0u8; 1024];
+        f.read_exact_at(&mut contents, 512).unwrap();
+        assert!(contents[..512].iter().all(|&b| b == 0x11));
+        assert!(contents[512..].iter().all(|&b| b == 0x22));
+
+        let reads = requests(VIRTIO_BLK_T_IN, DescriptorType::Writable);
+        assert!(!MergeableRequest::can_merge(&writes[..1], &reads[1], 1024));
+        mem.write_all_at_addr(&[0; 512], data_addr(0)).unwrap();
+        mem.write_all_at_addr(&[0; 512], data_addr(1)).unwrap();
+        let lens = Block::execute_merged(
+            &reads,
+            &mut f,
+            disk_size,
+            false,
+            &mut flush_timer,
+            &mut flush_timer_armed,
+            &mem,
+        )
+        .expect("merged read failed");
+        assert_eq!(lens, vec![513, 513]);
+        for (i, expected) in [0x11u8, 0x22].iter().enumerate() {
+            let mut data = [0u8; 512];
+            mem.read_exact_at_addr(&mut data, data_addr(i as u64))
+                .unwrap();
+            assert!(data.iter().all(|b| b == expected));
+            let status = mem.read_obj_from_addr::<u8>(status_addr(i as u64)).unwrap();
+            assert_eq!(status, VIRTIO_BLK_S_OK);
+        }
+    }
+
+    #[test]
+    fn zoned_append_and_report() {
+        let mut f = tempfile().unwrap();
+        let zone_size = 0x10000;
+        let disk_size = 4 * zone_size;
+        f.set_len(disk_size).unwrap();
+        let zoned = ZonedDisk::emulated(f.try_clone().unwrap(), zone_size, 0, 0).unwrap();
+
+        let b = Block::new(
+            base_features(false),
+            Box::new(f.try_clone().unwrap()),
+            None,
+            BlockConfig::default(),
+            Some(zoned.clone()),
+            None,
+        )
+        .unwrap();
+        assert_ne!(b.features() & (1 << VIRTIO_BLK_F_ZONED), 0);
+        assert_eq!(b.features() & (1 << VIRTIO_BLK_F_DISCARD), 0);
+        let mut zone_sectors = [0u8; 4];
+        b.read_config(72, &mut zone_sectors);
+        assert_eq!(u32::from_le_bytes(zone_sectors), 128);
+
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
+            .expect("Creating guest memory failed.");
+        let mut flush_timer = Timer::new().expect("failed to create flush_timer");
+        let mut flush_timer_armed = false;
+
+        // Append a sector to the second zone, twice.
+        for i in 0..2 {
+            let req_hdr = virtio_blk_req_header {
+                req_type: Le32::from(VIRTIO_BLK_T_ZONE_APPEND),
+                reserved: Le32::from(0),
+                sector: Le64::from(128),
+            };
+            mem.write_obj_at_addr(req_hdr, GuestAddress(0x1000))
+                .expect("writing req failed");
+            let avail_desc = create_descriptor_chain(
+                &mem,
+                GuestAddress(0x100),  // Place descriptor chain at 0x100.
+                GuestAddress(0x1000), // Describe buffer at 0x1000.
+                vec![
+                    // Request header
+                    (DescriptorType::Readable, size_of_val(&req_hdr) as u32),
+                    // Data to append
+                    (DescriptorType::Readable, 512),
+                    // Append sector and status
+                    (DescriptorType::Writable, 16),
+                ],
+                0,
+            )
+            .expect("create_descriptor_chain failed");
+
+            Worker::process_one_request(
+                avail_desc,
+                false,
+                true,
+                false,
+                &mut f,
+                disk_size,
+                None,
+                Some(&zoned),
+                &mut flush_timer,
+                &mut flush_timer_armed,
+                &mem,
+            )
+            .expect("execute failed");
+
+            let in_hdr = GuestAddress(0x1000 + size_of_val(&req_hdr) as u64 + 512);
+            let append_sector: Le64 = mem.read_obj_from_addr(in_hdr).unwrap();
+            assert_eq!(append_sector.to_native(), 128 + i);
+            let status: u8 = mem.read_obj_from_addr(in_hdr.unchecked_add(15)).unwrap();
+            assert_eq!(status, VIRTIO_BLK_S_OK);
+        }
+
+        // Report the zones from the second one on.
+        let req_hdr = virtio_blk_req_header {
+            req_type: Le32::from(VIRTIO_BLK_T_ZONE_REPORT),
+            reserved: Le32::from(0),
+            sector: Le64::from(128),
+        };
+        mem.write_obj_at_addr(req_hdr, GuestAddress(0x1000))
+            .expect("writing req failed");
+        let report_len =
+            size_of::<virtio_blk_zone_report>() + 8 * size_of::<virtio_blk_zone_descriptor>();
+        let avail_desc = create_descriptor_chain(
+            &mem,
+            GuestAddress(0x100),  // Place descriptor chain at 0x100.
+            GuestAddress(0x1000), // Describe buffer at 0x1000.
+            vec![
+                // Request header
+                (DescriptorType::Readable, size_of_val(&req_hdr) as u32),
+                // Zone report
+                (DescriptorType::Writable, report_len as u32),
+                // Request status
+                (DescriptorType::Writable, 1),
+            ],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+
+        Worker::process_one_request(
+            avail_desc,
+            false,
+            true,
+            false,
+            &mut f,
+            disk_size,
+            None,
+            Some(&zoned),
+            &mut flush_timer,
+            &mut flush_timer_armed,
+            &mem,
+        )
+        .expect("execute failed");
+
+        let report_addr = GuestAddress(0x1000 + size_of_val(&req_hdr) as u64);
+        let report: virtio_blk_zone_report = mem.read_obj_from_addr(report_addr).unwrap();
+        assert_eq!(report.nr_zones.to_native(), 3);
+        let desc_addr = report_addr.unchecked_add(size_of::<virtio_blk_zone_report>() as u64);
+        let desc: virtio_blk_zone_descriptor = mem.read_obj_from_addr(desc_addr).unwrap();
+        assert_eq!(desc.z_start.to_native(), 128);
+        assert_eq!(desc.z_wp.to_native(), 130);
+        let status: u8 = mem
+            .read_obj_from_addr(report_addr.unchecked_add(report_len as u64))
+            .unwrap();
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+    }
+
+    #[test]
+    fn sleep_and_wake() {
+        let f = tempfile().unwrap();
+        f.set_len(0x1000).unwrap();
+
+        let features = base_features(false);
+        let mut b = Block::new(
+            features,
+            Box::new(f),
+            None,
+            BlockConfig {
+                read_only: true,
+                sparse: false,
+                ..Default::default()
+            },
+            None,
+            None,
+        )
+        .unwrap();
+
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 0x10000)])
+            .expect("Creating guest memory failed.");
+        let mut queue = Queue::new(16);
+        queue.ready = true;
+        queue.desc_table = GuestAddress(0x0);
+        queue.avail_ring = GuestAddress(0x1000);
+        queue.used_ring = GuestAddress(0x2000);
+        let queue_evt = Event::new().unwrap();
+        b.activate(
+            mem.clone(),
+            Interrupt::new(
+                Arc::new(AtomicUsize::new(0)),
+                Event::new().unwrap(),
+                Event::new().unwrap(),
+                None,
+                VIRTIO_MSI_NO_VECTOR,
+            ),
+            vec![queue],
+            vec![queue_evt.try_clone().unwrap()],
+        );
+        b.sleep().expect("sleep failed");
+
+        // Make a read available while the device sleeps.
+        let req_hdr = virtio_blk_req_header {
+            req_type: Le32::from(VIRTIO_BLK_T_IN),
+            reserved: Le32::from(0),
+            sector: Le64::from(0),
+        };
+        mem.write_obj_at_addr(req_hdr, GuestAddress(0x3000))
+            .expect("writing req failed");
+        create_descriptor_chain(
+            &mem,
+            GuestAddress(0x0),
+            GuestAddress(0x3000),
+            vec![
+                (DescriptorType::Readable, size_of_val(&req_hdr) as u32),
+                (DescriptorType::Writable, 512),
+                (DescriptorType::Writable, 1),
+            ],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        mem.write_obj_at_addr(Le16::from(0), GuestAddress(0x1000 + 4))
+            .unwrap();
+        mem.write_obj_at_addr(Le16::from(1), GuestAddress(0x1000 + 2))
+            .unwrap();
+        queue_evt.write(1).unwrap();
+
+        let used_idx = GuestAddress(0x2000 + 2);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            mem.read_obj_from_addr::<Le16>(used_idx)
+                .unwrap()
+                .to_native(),
+            0
+        );
+
+        b.wake().expect("wake failed");
+        let start = Instant::now();
+        while mem
+            .read_obj_from_addr::<Le16>(used_idx)
+            .unwrap()
+            .to_native()
+            == 0
+        {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "read not completed after waking"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert!(b.sleep().is_ok());
+        assert!(b.reset());
+    }
 }
@@ -0,0 +1,85 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::{max, min};
+use std::sync::atomic::spin_loop_hint;
+use std::time::{Duration, Instant};
+
+// Shortest polling window; keeps the cost of polling an idle queue negligible.
+const MIN_POLL_WINDOW: Duration = Duration::from_micros(1);
+
+/// Adaptive busy-polling of a virtio queue.
+///
+/// After processing a queue, a device worker can spin waiting for the driver to make more
+/// descriptors available instead of going back to waiting on the queue event, avoiding the cost
+/// of a guest notification and a wakeup for each request. The polling window doubles, up to the
+/// configured maximum, every time new work shows up within it and halves every time it expires
+/// empty-handed, so idle queues quickly stop burning CPU.
+pub struct BusyPoll {
+    max_window: Duration,
+    window: Duration,
+}
+
+impl BusyPoll {
+    /// Creates a poller whose window never exceeds `max_window`.
+    pub fn new(max_window: Duration) -> BusyPoll {
+        BusyPoll {
+            max_window,
+            window: max_window,
+        }
+    }
+
+    /// Returns the current polling window.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Spins until `has_work` returns true or the current window expires, then adapts the window.
+    /// Returns true if work became available.
+    pub fn poll<F: FnMut() -> bool>(&mut self, mut has_work: F) -> bool {
+        let start = Instant::now();
+        loop {
+            if has_work() {
+                self.window = min(self.window * 2, self.max_window);
+                return true;
+            }
+            if start.elapsed() >= self.window {
+                self.window = max(self.window / 2, min(MIN_POLL_WINDOW, self.max_window));
+                return false;
+            }
+            spin_loop_hint();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_adapts() {
+        let max_window = Duration::from_micros(64);
+        let mut poller = BusyPoll::new(max_window);
+
+        assert!(!poller.poll(|| false));
+        assert_eq!(poller.window(), Duration::from_micros(32));
+        assert!(!poller.poll(|| false));
+        assert_eq!(poller.window(), Duration::from_micros(16));
+
+        assert!(poller.poll(|| true));
+        assert_eq!(poller.window(), Duration::from_micros(32));
+        assert!(poller.poll(|| true));
+        assert!(poller.poll(|| true));
+        assert_eq!(poller.window(), max_window);
+    }
+
+    #[test]
+    fn window_floor() {
+        let mut poller = BusyPoll::new(Duration::from_micros(4));
+        for _ in 0..8 {
+            assert!(!poller.poll(|| false));
+        }
+        assert_eq!(poller.window(), MIN_POLL_WINDOW);
+    }
+}
@@ -4,16 +4,21 @@
 
 use std::io::{self, Read, Write};
 use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::Arc;
 use std::thread;
 
-use base::{error, Event, PollToken, RawDescriptor, WaitContext};
+use base::{error, AsRawDescriptor, Event, PollToken, RawDescriptor, WaitContext};
 use data_model::{DataInit, Le16, Le32};
+use msg_socket::MsgReceiver;
+use sync::Mutex;
+use vm_control::{ConsoleResizeRecvSocket, ConsoleSize};
 use vm_memory::GuestMemory;
 
 use super::{
     base_features, copy_config, Interrupt, Queue, Reader, VirtioDevice, Writer, TYPE_CONSOLE,
 };
 use crate::SerialDevice;
+use crate::Suspendable;
 
 const QUEUE_SIZE: u16 = 256;
 
@@ -21,6 +26,9 @@ const QUEUE_SIZE: u16 = 256;
 // If VIRTIO_CONSOLE_F_MULTIPORT is implemented, more queues will be needed.
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE];
 
+// The device reports the size of the terminal in its config.
+const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
+
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 struct virtio_console_config {
@@ -38,6 +46,8 @@ struct Worker {
     interrupt: Interrupt,
     input: Option<Box<dyn io::Read + Send>>,
     output: Option<Box<dyn io::Write + Send>>,
+    resize_socket: Option<ConsoleResizeRecvSocket>,
+    size: Arc<Mutex<ConsoleSize>>,
 }
 
 fn write_output(output: &mut Box<dyn io::Write>, data: &[u8]) -> io::Result<()> {
@@ -89,7 +99,7 @@ impl Worker {
         }
 
         if needs_interrupt {
-            self.interrupt.signal_used_queue(transmit_queue.vector);
+            transmit_queue.trigger_interrupt(&self.mem, &self.interrupt);
         }
     }
 
@@ -189,7 +199,7 @@ impl Worker {
             if bytes_written > 0 {
                 receive_queue.pop_peeked(&self.mem);
                 receive_queue.add_used(&self.mem, desc_index, bytes_written);
-                self.interrupt.signal_used_queue(receive_queue.vector);
+                receive_queue.trigger_interrupt(&self.mem, &self.interrupt);
             }
 
             if disconnected {
@@ -204,12 +214,28 @@ impl Worker {
         }
     }
 
+    // Takes the new size of the terminal and has the driver read it.
+    fn handle_resize(&mut self) {
+        let resize_socket = match &self.resize_socket {
+            Some(socket) => socket,
+            None => return,
+        };
+        match resize_socket.recv() {
+            Ok(size) => {
+                *self.size.lock() = size;
+                self.interrupt.signal_config_changed();
+            }
+            Err(e) => error!("console: failed to receive the terminal size: {}", e),
+        }
+    }
+
     fn run(&mut self, mut queues: Vec<Queue>, mut queue_evts: Vec<Event>, kill_evt: Event) {
-        #[derive(PollToken)]
+        #[derive(PartialEq, PollToken)]
         enum Token {
             ReceiveQueueAvailable,
             TransmitQueueAvailable,
             InputAvailable,
+            Resize,
             InterruptResample,
             Kill,
         }
@@ -224,6 +250,7 @@ impl Worker {
             Ok(evt) => evt,
             Err(e) => {
                 error!("failed creating Event: {}", e);
+                self.interrupt.signal_needs_reset();
                 return;
             }
         };
@@ -245,9 +272,17 @@ impl Worker {
             Ok(pc) => pc,
             Err(e) => {
                 error!("failed creating WaitContext: {}", e);
+                self.interrupt.signal_needs_reset();
                 return;
             }
         };
+        if let Some(resize_socket) = &self.resize_socket {
+            if let Err(e) = wait_ctx.add(resize_socket, Token::Resize) {
+                error!("failed adding resize socket to WaitContext: {}", e);
+                self.interrupt.signal_needs_reset();
+                return;
+            }
+        }
 
         let mut output: Box<dyn io::Write> = match self.output.take() {
             Some(o) => o,
@@ -286,13 +321,25 @@ impl Worker {
                         }
                         self.handle_input(&mut in_channel, &mut receive_queue);
                     }
+                    Token::Resize => self.handle_resize(),
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
-                    Token::Kill => break 'wait,
+                    Token::Kill => return,
+                }
+            }
+            for event in events.iter().filter(|e| e.is_hungup) {
+                if event.token == Token::Resize && !event.is_readable {
+                    // The main process went away, so the size won't change anymore.
+                    if let Some(resize_socket) = &self.resize_socket {
+                        let _ = wait_ctx.delete(resize_socket);
+                    }
                 }
             }
         }
+
+        // Only errors get here. Resetting the device starts a new worker.
+        self.interrupt.signal_needs_reset();
     }
 }
 
@@ -304,6 +351,19 @@ pub struct Console {
     input: Option<Box<dyn io::Read + Send>>,
     output: Option<Box<dyn io::Write + Send>>,
     keep_rds: Vec<RawDescriptor>,
+    resize_socket: Option<ConsoleResizeRecvSocket>,
+    size: Arc<Mutex<ConsoleSize>>,
+}
+
+impl Console {
+    /// Reports `size` as the size of the terminal the console is attached to, and the sizes
+    /// received on `resize_socket` whenever the terminal is resized.
+    pub fn set_resize_socket(&mut self, resize_socket: ConsoleResizeRecvSocket, size: ConsoleSize) {
+        self.base_features |= 1 << VIRTIO_CONSOLE_F_SIZE;
+        self.keep_rds.push(resize_socket.as_raw_descriptor());
+        self.resize_socket = Some(resize_socket);
+        *self.size.lock() = size;
+    }
 }
 
 impl SerialDevice for Console {
@@ -321,6 +381,8 @@ impl SerialDevice for Console {
             input,
             output,
             keep_rds,
+            resize_socket: None,
+            size: Arc::new(Mutex::new(ConsoleSize::default())),
         }
     }
 }
@@ -356,7 +418,10 @@ impl VirtioDevice for Console {
     }
 
     fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let size = *self.size.lock();
         let config = virtio_console_config {
+            cols: size.cols.into(),
+            rows: size.rows.into(),
             max_nr_ports: 1.into(),
             ..Default::default()
         };
@@ -385,6 +450,8 @@ impl VirtioDevice for Console {
 
         let input = self.input.take();
         let output = self.output.take();
+        let resize_socket = self.resize_socket.take();
+        let size = self.size.clone();
 
         let worker_result = thread::Builder::new()
             .name("virtio_console".to_string())
@@ -394,6 +461,8 @@ impl VirtioDevice for Console {
                     interrupt,
                     input,
                     output,
+                    resize_socket,
+                    size,
                 };
                 worker.run(queues, queue_evts, kill_evt);
                 worker
@@ -427,6 +496,7 @@ impl VirtioDevice for Console {
                 Ok(worker) => {
                     self.input = worker.input;
                     self.output = worker.output;
+                    self.resize_socket = worker.resize_socket;
                     return true;
                 }
             }
@@ -434,3 +504,5 @@ impl VirtioDevice for Console {
         false
     }
 }
+
+impl Suspendable for Console {}
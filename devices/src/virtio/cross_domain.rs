@@ -0,0 +1,234 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Implements a cross-domain channel device, which lets the guest exchange file descriptors with
+//! host services other than the wayland compositor, such as a clipboard, a file picker or a dmabuf
+//! importer.
+//!
+//! The device speaks the virtio wayland protocol and reuses its implementation: the guest opens a
+//! connection to a service with `VIRTIO_WL_CMD_VFD_NEW_CTX_NAMED`, then sends and receives shared
+//! memory, pipes and dmabufs over it like it would over a wayland context. Unlike virtio-wl, there
+//! is no default connection. The guest can only reach the services named when the device was
+//! created, and it finds their names in the device configuration, after the virtio-wl fields.
+
+use std::collections::BTreeMap as Map;
+use std::fmt::{self, Display};
+use std::mem::size_of;
+use std::path::PathBuf;
+
+use base::{Event, RawDescriptor};
+use data_model::{DataInit, Le32};
+use vm_control::VmMemoryControlRequestSocket;
+use vm_memory::GuestMemory;
+
+use super::resource_bridge::ResourceRequestSocket;
+use super::wl::{WlConfig, CONTEXT_NAME_SIZE};
+use super::{copy_config, Interrupt, Queue, VirtioDevice, Wl, TYPE_CROSS_DOMAIN};
+use crate::Suspendable;
+
+/// The most services a single device can offer, bounded by the size of the configuration space.
+pub const CROSS_DOMAIN_MAX_SERVICES: usize = 16;
+
+#[derive(Debug)]
+pub enum CrossDomainError {
+    /// The device was given no services to offer.
+    NoServices,
+    /// The device was given more services than fit in its configuration.
+    TooManyServices(usize),
+    /// A service name is empty, too long, or contains a NUL byte.
+    InvalidServiceName(String),
+    /// Failed to create the underlying virtio wayland device.
+    CreateDevice(base::Error),
+}
+
+pub type Result<T> = std::result::Result<T, CrossDomainError>;
+
+impl Display for CrossDomainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CrossDomainError::*;
+
+        match self {
+            NoServices => write!(f, "no cross-domain services were given"),
+            TooManyServices(n) => write!(
+                f,
+                "{} cross-domain services given, at most {} are supported",
+                n, CROSS_DOMAIN_MAX_SERVICES
+            ),
+            InvalidServiceName(name) => write!(
+                f,
+                "invalid cross-domain service name '{}': it must be 1 to {} bytes without NUL",
+                name, CONTEXT_NAME_SIZE
+            ),
+            CreateDevice(e) => write!(f, "failed to create the cross-domain device: {}", e),
+        }
+    }
+}
+
+// The list of services, which follows the virtio-wl fields in the device configuration. Names
+// shorter than `CONTEXT_NAME_SIZE` are NUL terminated.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct ServicesConfig {
+    num_services: Le32,
+    padding: Le32,
+    names: [[u8; CONTEXT_NAME_SIZE]; CROSS_DOMAIN_MAX_SERVICES],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for ServicesConfig {}
+
+/// Virtio device for sharing file descriptors with a fixed set of named host services.
+pub struct CrossDomain {
+    wl: Wl,
+    services: ServicesConfig,
+}
+
+impl CrossDomain {
+    /// Creates a device offering the unix sockets in `services` to the guest, under their keys.
+    pub fn new(
+        base_features: u64,
+        services: Map<String, PathBuf>,
+        vm_socket: VmMemoryControlRequestSocket,
+        resource_bridge: Option<ResourceRequestSocket>,
+    ) -> Result<CrossDomain> {
+        if services.is_empty() {
+            return Err(CrossDomainError::NoServices);
+        }
+        if services.len() > CROSS_DOMAIN_MAX_SERVICES {
+            return Err(CrossDomainError::TooManyServices(services.len()));
+        }
+
+        let mut config = ServicesConfig {
+            num_services: Le32::from(services.len() as u32),
+            ..Default::default()
+        };
+        for (name, slot) in services.keys().zip(config.names.iter_mut()) {
+            let bytes = name.as_bytes();
+            // An empty name would be the default wayland connection, which this device doesn't
+            // have.
+            if bytes.is_empty() || bytes.len() > CONTEXT_NAME_SIZE || bytes.contains(&0) {
+                return Err(CrossDomainError::InvalidServiceName(name.clone()));
+            }
+            slot[..bytes.len()].copy_from_slice(bytes);
+        }
+
+        let wl = Wl::new(base_features, services, vm_socket, resource_bridge)
+            .map_err(CrossDomainError::CreateDevice)?;
+        Ok(CrossDomain {
+            wl,
+            services: config,
+        })
+    }
+}
+
+impl VirtioDevice for CrossDomain {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        self.wl.keep_rds()
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_CROSS_DOMAIN
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        self.wl.queue_max_sizes()
+    }
+
+    fn features(&self) -> u64 {
+        self.wl.features()
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.wl.ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        self.wl.read_config(offset, data);
+
+        let services_offset = size_of::<WlConfig>() as u64;
+        if offset < services_offset {
+            copy_config(data, services_offset - offset, self.services.as_slice(), 0);
+        } else {
+            copy_config(data, 0, self.services.as_slice(), offset - services_offset);
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        queue_evts: Vec<Event>,
+    ) {
+        self.wl.activate(mem, interrupt, queues, queue_evts)
+    }
+}
+
+impl Suspendable for CrossDomain {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn services(names: &[&str]) -> Map<String, PathBuf> {
+        names
+            .iter()
+            .map(|name| (name.to_string(), PathBuf::from(format!("/run/{}", name))))
+            .collect()
+    }
+
+    fn new_device(names: &[&str]) -> Result<CrossDomain> {
+        let (vm_socket, _vm_responder) = msg_socket::pair().unwrap();
+        CrossDomain::new(0, services(names), vm_socket, None)
+    }
+
+    #[test]
+    fn config_lists_services() {
+        let dev = new_device(&["clipboard", "dmabuf", "file-picker"]).unwrap();
+
+        let services_offset = size_of::<WlConfig>();
+        let mut config = vec![0xffu8; services_offset + size_of::<ServicesConfig>()];
+        dev.read_config(0, &mut config);
+
+        // The shared memory area hasn't been used yet.
+        assert_eq!(&config[..services_offset], &[0u8; 8]);
+        let services = ServicesConfig::from_slice(&config[services_offset..]).unwrap();
+        assert_eq!(services.num_services.to_native(), 3);
+        assert_eq!(&services.names[0][..10], b"clipboard\0");
+        assert_eq!(&services.names[1][..7], b"dmabuf\0");
+        assert_eq!(&services.names[2][..12], b"file-picker\0");
+        assert_eq!(services.names[3], [0u8; CONTEXT_NAME_SIZE]);
+
+        // Reads that start inside the services list see the same bytes.
+        let mut name = [0u8; 6];
+        dev.read_config((services_offset + 8 + CONTEXT_NAME_SIZE) as u64, &mut name);
+        assert_eq!(&name, b"dmabuf");
+    }
+
+    #[test]
+    fn invalid_services() {
+        assert!(matches!(new_device(&[]), Err(CrossDomainError::NoServices)));
+        assert!(matches!(
+            new_device(&[""]),
+            Err(CrossDomainError::InvalidServiceName(_))
+        ));
+        assert!(matches!(
+            new_device(&["a-name-that-is-much-too-long-for-the-guest"]),
+            Err(CrossDomainError::InvalidServiceName(_))
+        ));
+        assert!(matches!(
+            new_device(&["nul\0"]),
+            Err(CrossDomainError::InvalidServiceName(_))
+        ));
+
+        let names: Vec<String> = (0..=CROSS_DOMAIN_MAX_SERVICES)
+            .map(|i| format!("service{}", i))
+            .collect();
+        let names: Vec<&str> = names.iter().map(|s| s.as_str()).collect();
+        assert!(matches!(
+            new_device(&names),
+            Err(CrossDomainError::TooManyServices(17))
+        ));
+    }
+}
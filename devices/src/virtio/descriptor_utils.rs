@@ -13,9 +13,10 @@ use std::mem::{size_of, MaybeUninit};
 use std::ptr::copy_nonoverlapping;
 use std::rc::Rc;
 use std::result;
+use std::slice;
 
 use base::{FileReadWriteAtVolatile, FileReadWriteVolatile};
-use cros_async::MemRegion;
+use cros_async::{AsyncResult, MemRegion, ReadAsync, WriteAsync};
 use data_model::{DataInit, Le16, Le32, Le64, VolatileMemoryError, VolatileSlice};
 use disk::AsyncDisk;
 use vm_memory::{GuestAddress, GuestMemory};
@@ -287,6 +288,37 @@ impl Reader {
         Ok(unsafe { obj.assume_init() })
     }
 
+    /// Reads `count` objects from the descriptor chain buffer. Unlike `iter`, which copies out one
+    /// object at a time, this copies out of each descriptor once. Fails without reading anything if
+    /// fewer than `count` objects are left.
+    pub fn read_objs<T: DataInit>(&mut self, count: usize) -> io::Result<Vec<T>> {
+        let len = count
+            .checked_mul(size_of::<T>())
+            .filter(|&len| len <= self.available_bytes())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "not enough objects left in the descriptor chain",
+                )
+            })?;
+        let mut objs = Vec::with_capacity(count);
+
+        // Safe because `objs` has room for `count` objects, which is `len` bytes.
+        let buf = unsafe { slice::from_raw_parts_mut(objs.as_mut_ptr() as *mut u8, len) };
+        self.read_exact(buf)?;
+
+        // Safe because all of the `count` objects were just read, and any type that implements
+        // `DataInit` can be considered initialized even if it is filled with random data.
+        unsafe { objs.set_len(count) };
+        Ok(objs)
+    }
+
+    /// Reads every whole object left in the descriptor chain buffer, like `read_objs`. Trailing
+    /// bytes too few to make up an object are left unread.
+    pub fn read_remaining_objs<T: DataInit>(&mut self) -> io::Result<Vec<T>> {
+        self.read_objs(self.available_bytes() / size_of::<T>())
+    }
+
     /// Reads objects by consuming all the remaining data in the descriptor chain buffer and returns
     /// them as a collection. Returns an error if the size of the remaining data is indivisible by
     /// the size of an object of type `T`.
@@ -405,6 +437,32 @@ impl Reader {
         Ok(written)
     }
 
+    /// Reads data from the descriptor chain buffer into a stream such as a tap device in a single
+    /// write. Returns the number of bytes read from the descriptor chain buffer.
+    pub async fn read_to_fut<F: WriteAsync + ?Sized>(
+        &mut self,
+        dst: &F,
+        count: usize,
+    ) -> AsyncResult<usize> {
+        self.read_to_file_at_fut(dst, count, 0).await
+    }
+
+    /// Reads data from the descriptor chain buffer into a file at offset `off` in a single write.
+    /// Returns the number of bytes read from the descriptor chain buffer.
+    pub async fn read_to_file_at_fut<F: WriteAsync + ?Sized>(
+        &mut self,
+        dst: &F,
+        count: usize,
+        off: u64,
+    ) -> AsyncResult<usize> {
+        let mem_regions = self.regions.get_remaining_regions_with_count(count);
+        let written = dst
+            .write_from_mem(off, Rc::new(self.mem.clone()), &mem_regions)
+            .await?;
+        self.regions.consume(written);
+        Ok(written)
+    }
+
     /// Reads exactly `count` bytes from the chain to the disk asynchronously or returns an error if
     /// not enough data can be read.
     pub async fn read_exact_to_at_fut<F: AsyncDisk + ?Sized>(
@@ -468,12 +526,18 @@ impl io::Read for Reader {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let mut rem = buf;
         let mut total = 0;
-        for b in self.regions.get_remaining(&self.mem) {
+        // Only the regions that are read from are looked up in guest memory, so that reading small
+        // objects doesn't cost the whole chain.
+        for region in self.regions.get_remaining_regions() {
             if rem.len() == 0 {
                 break;
             }
 
-            let count = cmp::min(rem.len(), b.size());
+            let count = cmp::min(rem.len(), region.len);
+            let b = self
+                .mem
+                .get_slice_at_addr(GuestAddress(region.offset), count)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
 
             // Safe because we have already verified that `b` points to valid memory.
             unsafe {
@@ -543,6 +607,17 @@ impl Writer {
         self.write_all(val.as_slice())
     }
 
+    /// Writes `objs` to the descriptor chain buffer. Unlike `write_iter`, which copies in one object
+    /// at a time, this copies into each descriptor once.
+    pub fn write_objs<T: DataInit>(&mut self, objs: &[T]) -> io::Result<()> {
+        // Safe because types that implement `DataInit` have no padding, so all of the bytes of
+        // `objs` are initialized.
+        let buf = unsafe {
+            slice::from_raw_parts(objs.as_ptr() as *const u8, objs.len() * size_of::<T>())
+        };
+        self.write_all(buf)
+    }
+
     /// Writes all objects produced by `iter` into the descriptor chain buffer. Unlike `consume`,
     /// this doesn't require the values to be stored in an intermediate collection first. It also
     /// allows callers to choose which elements in a collection to write, for example by using the
@@ -573,7 +648,7 @@ impl Writer {
     ) -> io::Result<usize> {
         let iovs = self.regions.get_remaining_with_count(&self.mem, count);
         let read = src.read_vectored_volatile(&iovs[..])?;
-        self.regions.consume(read);
+        self.consume_written(read);
         Ok(read)
     }
 
@@ -589,7 +664,7 @@ impl Writer {
     ) -> io::Result<usize> {
         let iovs = self.regions.get_remaining_with_count(&self.mem, count);
         let read = src.read_vectored_at_volatile(&iovs[..], off)?;
-        self.regions.consume(read);
+        self.consume_written(read);
         Ok(read)
     }
 
@@ -653,7 +728,33 @@ impl Writer {
         let read = src
             .read_to_mem(off, Rc::new(self.mem.clone()), &regions)
             .await?;
-        self.regions.consume(read);
+        self.consume_written(read);
+        Ok(read)
+    }
+
+    /// Writes data to the descriptor chain buffer from a stream such as a tap device in a single
+    /// read. Returns the number of bytes written to the descriptor chain buffer.
+    pub async fn write_from_fut<F: ReadAsync + ?Sized>(
+        &mut self,
+        src: &F,
+        count: usize,
+    ) -> AsyncResult<usize> {
+        self.write_from_file_at_fut(src, count, 0).await
+    }
+
+    /// Writes data to the descriptor chain buffer from a file at offset `off` in a single read.
+    /// Returns the number of bytes written to the descriptor chain buffer.
+    pub async fn write_from_file_at_fut<F: ReadAsync + ?Sized>(
+        &mut self,
+        src: &F,
+        count: usize,
+        off: u64,
+    ) -> AsyncResult<usize> {
+        let regions = self.regions.get_remaining_regions_with_count(count);
+        let read = src
+            .read_to_mem(off, Rc::new(self.mem.clone()), &regions)
+            .await?;
+        self.consume_written(read);
         Ok(read)
     }
 
@@ -682,6 +783,34 @@ impl Writer {
         self.regions.bytes_consumed()
     }
 
+    /// Returns a `&[VolatileSlice]` that represents all the remaining space in this `Writer`.
+    /// Calling this method does not actually advance the `Writer` and callers should call
+    /// `consume_bytes` once they have written to the slices.
+    pub fn get_remaining(&self) -> Vec<VolatileSlice> {
+        self.regions.get_remaining(&self.mem)
+    }
+
+    /// Advances the `Writer` past `amt` bytes written to the slices returned by `get_remaining`.
+    /// If `amt` is larger than the remaining space in this `Writer`, then all of it is consumed.
+    pub fn consume_bytes(&mut self, amt: usize) {
+        self.consume_written(amt)
+    }
+
+    // Advances past `count` bytes that were just written to guest memory, recording them in the
+    // guest memory's dirty log.
+    fn consume_written(&mut self, count: usize) {
+        let mut rem = count;
+        for region in self.regions.get_remaining_regions() {
+            if rem == 0 {
+                break;
+            }
+            let len = cmp::min(rem, region.len);
+            self.mem.mark_dirty(GuestAddress(region.offset), len as u64);
+            rem -= len;
+        }
+        self.regions.consume(count);
+    }
+
     /// Splits this `Writer` into two at the given offset in the `DescriptorChain` buffer. After the
     /// split, `self` will be able to write up to `offset` bytes while the returned `Writer` can
     /// write up to `available_bytes() - offset` bytes. If `offset > self.available_bytes()`, then
@@ -698,12 +827,16 @@ impl io::Write for Writer {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let mut rem = buf;
         let mut total = 0;
-        for b in self.regions.get_remaining(&self.mem) {
+        for region in self.regions.get_remaining_regions() {
             if rem.len() == 0 {
                 break;
             }
 
-            let count = cmp::min(rem.len(), b.size());
+            let count = cmp::min(rem.len(), region.len);
+            let b = self
+                .mem
+                .get_slice_at_addr(GuestAddress(region.offset), count)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
             // Safe because we have already verified that `vs` points to valid memory.
             unsafe {
                 copy_nonoverlapping(rem.as_ptr(), b.as_mut_ptr(), count);
@@ -712,7 +845,7 @@ impl io::Write for Writer {
             total += count;
         }
 
-        self.regions.consume(total);
+        self.consume_written(total);
         Ok(total)
     }
 
@@ -782,7 +915,7 @@ pub fn create_descriptor_chain(
         );
     }
 
-    DescriptorChain::checked_new(memory, descriptor_array_addr, 0x100, 0, 0)
+    DescriptorChain::checked_new(memory, None, descriptor_array_addr, 0x100, 0, 0)
         .ok_or(Error::InvalidChain)
 }
 
@@ -1075,6 +1208,45 @@ mod tests {
         }
     }
 
+    #[test]
+    fn read_write_objs() {
+        use DescriptorType::*;
+
+        let memory_start_addr = GuestAddress(0x0);
+        let memory = GuestMemory::new(&vec![(memory_start_addr, 0x10000)]).unwrap();
+        let vs: Vec<Le32> = (0..8u32).map(|v| (v * 0x01010101).into()).collect();
+
+        // The objects straddle the descriptors.
+        let write_chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x100),
+            vec![(Writable, 7), (Writable, 13), (Writable, 14)],
+            16,
+        )
+        .expect("create_descriptor_chain failed");
+        let mut writer = Writer::new(memory.clone(), write_chain).expect("failed to create Writer");
+        writer.write_objs(&vs).expect("failed to write objects");
+        assert_eq!(writer.available_bytes(), 2);
+
+        let read_chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x100),
+            vec![(Readable, 7), (Readable, 13), (Readable, 14)],
+            16,
+        )
+        .expect("create_descriptor_chain failed");
+        let mut reader = Reader::new(memory.clone(), read_chain).expect("failed to create Reader");
+        reader
+            .read_objs::<Le32>(9)
+            .expect_err("read more objects than are left");
+        assert_eq!(reader.available_bytes(), 34);
+        assert_eq!(reader.read_objs::<Le32>(2).unwrap(), &vs[..2]);
+        assert_eq!(reader.read_remaining_objs::<Le32>().unwrap(), &vs[2..]);
+        assert_eq!(reader.available_bytes(), 2);
+    }
+
     #[test]
     fn reader_unexpected_eof() {
         use DescriptorType::*;
@@ -1301,6 +1473,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn write_marks_dirty() {
+        use DescriptorType::*;
+
+        let memory_start_addr = GuestAddress(0x0);
+        let mut memory = GuestMemory::new(&vec![(memory_start_addr, 0x10000)]).unwrap();
+
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x100),
+            vec![(Writable, 16), (Writable, 16)],
+            0x2000 - 16,
+        )
+        .expect("create_descriptor_chain failed");
+        memory.enable_dirty_log().unwrap();
+        let mut writer = Writer::new(memory.clone(), chain).expect("failed to create Writer");
+
+        writer
+            .write_all(&[0xde; 20])
+            .expect("failed to write to buffer");
+
+        let page_size = base::pagesize() as u64;
+        let expected = 1 << (0x100 / page_size) | 1 << (0x2100 / page_size);
+        assert_eq!(memory.dirty_log().unwrap().take_bitmap()[0], expected);
+    }
+
     #[test]
     fn consume_collect() {
         use DescriptorType::*;
@@ -1525,4 +1724,59 @@ mod tests {
         assert_eq!(writer.available_bytes(), 128);
         assert_eq!(writer.bytes_written(), 384);
     }
+
+    #[test]
+    fn stream_round_trip() {
+        // Stream reads and writes ignore the file offset, which only io_uring supports.
+        if !cros_async::uring_available() {
+            return;
+        }
+        cros_async::run_one_uring(Box::pin(stream_round_trip_async())).unwrap()
+    }
+    async fn stream_round_trip_async() {
+        use DescriptorType::*;
+
+        let memory_start_addr = GuestAddress(0x0);
+        let memory = GuestMemory::new(&vec![(memory_start_addr, 0x10000)]).unwrap();
+        memory
+            .write_all_at_addr(&[0x55u8; 256], GuestAddress(0x1000))
+            .unwrap();
+
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x1000),
+            vec![(Readable, 128), (Readable, 128)],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        let mut reader = Reader::new(memory.clone(), chain).expect("failed to create Reader");
+
+        let (rx, tx) = base::pipe(true).unwrap();
+        let rx = cros_async::async_from(rx).unwrap();
+        let tx = cros_async::async_from(tx).unwrap();
+
+        assert_eq!(reader.read_to_fut(&*tx, 256).await.unwrap(), 256);
+        assert_eq!(reader.available_bytes(), 0);
+
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x4000),
+            vec![(Writable, 64), (Writable, 512)],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        let mut writer = Writer::new(memory.clone(), chain).expect("failed to create Writer");
+
+        let count = writer.available_bytes();
+        assert_eq!(writer.write_from_fut(&*rx, count).await.unwrap(), 256);
+        assert_eq!(writer.bytes_written(), 256);
+
+        let mut buf = [0u8; 256];
+        memory
+            .read_exact_at_addr(&mut buf, GuestAddress(0x4000))
+            .unwrap();
+        assert!(buf.iter().all(|&b| b == 0x55));
+    }
 }
@@ -0,0 +1,303 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Translates the I/O virtual addresses (IOVAs) that a driver gives a device into guest physical
+//! addresses. This applies when the device sits behind an IOMMU, where it may only reach the guest
+//! memory that the driver mapped for it.
+
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::sync::Arc;
+
+use sync::Mutex;
+use vm_memory::GuestAddress;
+
+#[derive(Debug, PartialEq)]
+pub enum DmaError {
+    /// The range maps to guest memory that isn't contiguous.
+    Discontiguous { iova: u64, len: u64 },
+    /// The mapping is empty or wraps around the end of the address space.
+    InvalidMapping { iova: u64, size: u64 },
+    /// The range is mapped, but not with the access the device needs.
+    NoAccess {
+        iova: u64,
+        len: u64,
+        access: DmaAccess,
+    },
+    /// Part of the range isn't mapped.
+    NotMapped { iova: u64, len: u64 },
+    /// The mapping overlaps one that already exists.
+    Overlap { iova: u64, size: u64 },
+    /// The range to unmap only covers part of a mapping.
+    PartialUnmap { iova: u64, size: u64 },
+}
+
+impl Display for DmaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DmaError::*;
+
+        match self {
+            Discontiguous { iova, len } => write!(
+                f,
+                "iova range {:#x}+{:#x} maps to discontiguous guest memory",
+                iova, len
+            ),
+            InvalidMapping { iova, size } => {
+                write!(f, "invalid iova mapping {:#x}+{:#x}", iova, size)
+            }
+            NoAccess { iova, len, access } => write!(
+                f,
+                "iova range {:#x}+{:#x} is not mapped for {:?} access",
+                iova, len, access
+            ),
+            NotMapped { iova, len } => write!(f, "iova range {:#x}+{:#x} is not mapped", iova, len),
+            Overlap { iova, size } => write!(
+                f,
+                "iova mapping {:#x}+{:#x} overlaps an existing mapping",
+                iova, size
+            ),
+            PartialUnmap { iova, size } => write!(
+                f,
+                "unmapping iova range {:#x}+{:#x} would split a mapping",
+                iova, size
+            ),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, DmaError>;
+
+/// The access a device makes to guest memory through a DMA mapping.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DmaAccess {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl DmaAccess {
+    fn allowed(self, mapping: &DmaMapping) -> bool {
+        match self {
+            DmaAccess::Read => mapping.read,
+            DmaAccess::Write => mapping.write,
+            DmaAccess::ReadWrite => mapping.read && mapping.write,
+        }
+    }
+}
+
+struct DmaMapping {
+    gpa: GuestAddress,
+    size: u64,
+    read: bool,
+    write: bool,
+}
+
+/// The I/O virtual address space of a device, made of the mappings the driver set up through the
+/// IOMMU.
+///
+/// Clones share the same mappings, so the IOMMU can change the mappings while a device's queues
+/// are translating addresses with them.
+#[derive(Clone, Default)]
+pub struct DmaMap {
+    // Keyed by the first IOVA of each mapping.
+    mappings: Arc<Mutex<BTreeMap<u64, DmaMapping>>>,
+}
+
+impl DmaMap {
+    /// Creates an address space with nothing mapped.
+    pub fn new() -> DmaMap {
+        Default::default()
+    }
+
+    /// Maps the `size` bytes at `iova` to guest memory at `gpa`, with the given permissions.
+    pub fn map(
+        &self,
+        iova: u64,
+        gpa: GuestAddress,
+        size: u64,
+        read: bool,
+        write: bool,
+    ) -> Result<()> {
+        let end = match iova.checked_add(size) {
+            Some(end) if size > 0 && gpa.checked_add(size).is_some() => end,
+            _ => return Err(DmaError::InvalidMapping { iova, size }),
+        };
+
+        let mut mappings = self.mappings.lock();
+        if let Some((start, mapping)) = mappings.range(..end).next_back() {
+            if start + mapping.size > iova {
+                return Err(DmaError::Overlap { iova, size });
+            }
+        }
+        mappings.insert(
+            iova,
+            DmaMapping {
+                gpa,
+                size,
+                read,
+                write,
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes the mappings inside the `size` bytes at `iova`. Mappings may not be partially
+    /// unmapped.
+    pub fn unmap(&self, iova: u64, size: u64) -> Result<()> {
+        let end = iova.saturating_add(size);
+        let mut mappings = self.mappings.lock();
+        let inside: Vec<u64> = mappings.range(iova..end).map(|(&start, _)| start).collect();
+        let split_first = mappings
+            .range(..iova)
+            .next_back()
+            .map_or(false, |(start, mapping)| start + mapping.size > iova);
+        let split_last = inside
+            .last()
+            .map_or(false, |start| start + mappings[start].size > end);
+        if split_first || split_last {
+            return Err(DmaError::PartialUnmap { iova, size });
+        }
+
+        for start in inside {
+            mappings.remove(&start);
+        }
+        Ok(())
+    }
+
+    /// Returns the guest physical address of the `len` bytes at `iova`, which must be mapped with
+    /// `access` to contiguous guest memory.
+    pub fn translate(&self, iova: u64, len: u64, access: DmaAccess) -> Result<GuestAddress> {
+        let end = iova
+            .checked_add(len)
+            .ok_or(DmaError::NotMapped { iova, len })?;
+
+        let mappings = self.mappings.lock();
+        let mut first_gpa = None;
+        let mut next_gpa = None;
+        let mut cur = iova;
+        // An empty range still needs its start to be mapped.
+        while cur < end || first_gpa.is_none() {
+            let (start, mapping) = mappings
+                .range(..=cur)
+                .next_back()
+                .filter(|(start, mapping)| cur - **start < mapping.size)
+                .ok_or(DmaError::NotMapped { iova, len })?;
+            if !access.allowed(mapping) {
+                return Err(DmaError::NoAccess { iova, len, access });
+            }
+
+            let gpa = mapping.gpa.unchecked_add(cur - start);
+            if next_gpa.map_or(false, |next| next != gpa) {
+                return Err(DmaError::Discontiguous { iova, len });
+            }
+            first_gpa.get_or_insert(gpa);
+
+            let chunk = min(end, start + mapping.size) - cur;
+            if chunk == 0 {
+                break;
+            }
+            cur += chunk;
+            next_gpa = Some(gpa.unchecked_add(chunk));
+        }
+        // The loop runs at least once, so the first address is known.
+        Ok(first_gpa.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate_inside_mapping() {
+        let dma_map = DmaMap::new();
+        dma_map
+            .map(0x1000, GuestAddress(0x8000), 0x2000, true, false)
+            .unwrap();
+        assert_eq!(
+            dma_map.translate(0x1800, 0x100, DmaAccess::Read),
+            Ok(GuestAddress(0x8800))
+        );
+        assert_eq!(
+            dma_map.translate(0x1800, 0, DmaAccess::Read),
+            Ok(GuestAddress(0x8800))
+        );
+        assert_eq!(
+            dma_map.translate(0x1800, 0x100, DmaAccess::Write),
+            Err(DmaError::NoAccess {
+                iova: 0x1800,
+                len: 0x100,
+                access: DmaAccess::Write
+            })
+        );
+        assert_eq!(
+            dma_map.translate(0x2f00, 0x200, DmaAccess::Read),
+            Err(DmaError::NotMapped {
+                iova: 0x2f00,
+                len: 0x200
+            })
+        );
+    }
+
+    #[test]
+    fn translate_across_mappings() {
+        let dma_map = DmaMap::new();
+        dma_map
+            .map(0x1000, GuestAddress(0x8000), 0x1000, true, true)
+            .unwrap();
+        dma_map
+            .map(0x2000, GuestAddress(0x9000), 0x1000, true, true)
+            .unwrap();
+        dma_map
+            .map(0x3000, GuestAddress(0x4000), 0x1000, true, true)
+            .unwrap();
+        assert_eq!(
+            dma_map.translate(0x1f00, 0x200, DmaAccess::ReadWrite),
+            Ok(GuestAddress(0x8f00))
+        );
+        assert_eq!(
+            dma_map.translate(0x2f00, 0x200, DmaAccess::ReadWrite),
+            Err(DmaError::Discontiguous {
+                iova: 0x2f00,
+                len: 0x200
+            })
+        );
+    }
+
+    #[test]
+    fn map_and_unmap() {
+        let dma_map = DmaMap::new();
+        dma_map
+            .map(0x1000, GuestAddress(0x8000), 0x2000, true, true)
+            .unwrap();
+        assert_eq!(
+            dma_map.map(0x2000, GuestAddress(0x8000), 0x2000, true, true),
+            Err(DmaError::Overlap {
+                iova: 0x2000,
+                size: 0x2000
+            })
+        );
+        assert_eq!(
+            dma_map.map(0x4000, GuestAddress(0x8000), 0, true, true),
+            Err(DmaError::InvalidMapping {
+                iova: 0x4000,
+                size: 0
+            })
+        );
+        assert_eq!(
+            dma_map.unmap(0x1000, 0x1000),
+            Err(DmaError::PartialUnmap {
+                iova: 0x1000,
+                size: 0x1000
+            })
+        );
+
+        dma_map.unmap(0, 0x4000).unwrap();
+        assert!(dma_map.translate(0x1000, 0x10, DmaAccess::Read).is_err());
+        dma_map
+            .map(0x2000, GuestAddress(0x8000), 0x2000, true, true)
+            .unwrap();
+    }
+}
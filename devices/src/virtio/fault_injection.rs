@@ -0,0 +1,487 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A virtio device that misbehaves on request, to exercise the error paths of guest drivers and
+//! of crosvm's own virtio code.
+//!
+//! The device has a single queue and answers each request by copying the readable part of its
+//! descriptor chain into the writable part. The faults it introduces on top of that are set with
+//! `FaultInjectionCommand`s from the control socket.
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base::{
+    error, info, warn, AsRawDescriptor, Event, PollToken, RawDescriptor, Timer, WaitContext,
+};
+use msg_socket::{MsgReceiver, MsgSender};
+use vm_control::{
+    FaultInjectionCommand, FaultInjectionConfig, FaultInjectionResponseSocket,
+    FaultInjectionResult, FaultInjectionStats,
+};
+use vm_memory::GuestMemory;
+
+use super::{
+    DescriptorChain, DescriptorError, Interrupt, Queue, Reader, VirtioDevice, Writer,
+    TYPE_FAULT_INJECTION,
+};
+use crate::Suspendable;
+
+const QUEUE_SIZE: u16 = 256;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+
+#[derive(Debug)]
+enum Error {
+    /// Invalid virtio descriptor chain.
+    Descriptor(DescriptorError),
+    /// Failed to read from virtqueue.
+    ReadQueue(io::Error),
+    /// Failed to write to virtqueue.
+    WriteQueue(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Descriptor(e) => write!(f, "virtio descriptor error: {}", e),
+            ReadQueue(e) => write!(f, "failed to read from virtqueue: {}", e),
+            WriteQueue(e) => write!(f, "failed to write to virtqueue: {}", e),
+        }
+    }
+}
+
+type Result<T> = ::std::result::Result<T, Error>;
+
+// Returns true if the `count`th event is one of every `every`, which is never if `every` is zero.
+fn hits(count: u64, every: u64) -> bool {
+    every != 0 && count % every == 0
+}
+
+// Decides which requests and interrupts the configured faults hit, and counts them.
+#[derive(Default)]
+struct Faults {
+    config: FaultInjectionConfig,
+    stats: FaultInjectionStats,
+    interrupts: u64,
+}
+
+impl Faults {
+    // Returns how long to hold the next request back, if at all, and whether to corrupt it.
+    fn next_request(&mut self) -> (Option<Duration>, bool) {
+        self.stats.requests += 1;
+        let delay = match self.config.used_delay_ms {
+            0 => None,
+            ms => {
+                self.stats.delayed += 1;
+                Some(Duration::from_millis(ms))
+            }
+        };
+        let corrupt = hits(self.stats.requests, self.config.corrupt_every);
+        if corrupt {
+            self.stats.corrupted += 1;
+        }
+        (delay, corrupt)
+    }
+
+    // Returns false if the next interrupt should be dropped.
+    fn next_interrupt(&mut self) -> bool {
+        self.interrupts += 1;
+        if hits(self.interrupts, self.config.drop_interrupt_every) {
+            self.stats.dropped_interrupts += 1;
+            return false;
+        }
+        true
+    }
+}
+
+// A request that was served but is held back from the used ring until `deadline`.
+#[derive(Clone, Copy)]
+struct HeldRequest {
+    deadline: Instant,
+    index: u16,
+    len: u32,
+}
+
+struct Worker {
+    interrupt: Interrupt,
+    mem: GuestMemory,
+    queue: Queue,
+    command_socket: FaultInjectionResponseSocket,
+    faults: Faults,
+    // Held requests are returned in the order they arrived.
+    held: VecDeque<HeldRequest>,
+    release_timer: Timer,
+}
+
+impl Worker {
+    // Copies what the driver gave into the buffers it left for the answer. Returns the number of
+    // bytes written and the size of those buffers.
+    fn handle_request(&mut self, avail_desc: DescriptorChain) -> Result<(usize, usize)> {
+        let mut reader =
+            Reader::new(self.mem.clone(), avail_desc.clone()).map_err(Error::Descriptor)?;
+        let mut writer = Writer::new(self.mem.clone(), avail_desc).map_err(Error::Descriptor)?;
+        let writable = writer.available_bytes();
+
+        let mut data = vec![0; min(reader.available_bytes(), writable)];
+        reader.read_exact(&mut data).map_err(Error::ReadQueue)?;
+        writer.write_all(&data).map_err(Error::WriteQueue)?;
+
+        Ok((writer.bytes_written(), writable))
+    }
+
+    fn process_queue(&mut self) -> bool {
+        let mut needs_interrupt = false;
+        while let Some(avail_desc) = self.queue.pop(&self.mem) {
+            let index = avail_desc.index;
+
+            let (written, writable) = match self.handle_request(avail_desc) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("fault injection: unable to handle request: {}", e);
+                    (0, 0)
+                }
+            };
+            let (delay, corrupt) = self.faults.next_request();
+            // A driver must not trust the used length to be within the buffers it gave.
+            let len = if corrupt { writable + 1 } else { written };
+            match delay {
+                Some(delay) => self.held.push_back(HeldRequest {
+                    deadline: Instant::now() + delay,
+                    index,
+                    len: len as u32,
+                }),
+                None => {
+                    self.queue.add_used(&self.mem, index, len as u32);
+                    needs_interrupt = true;
+                }
+            }
+        }
+
+        needs_interrupt
+    }
+
+    // Returns the held requests whose delay has passed, and arms the release timer for the next
+    // one.
+    fn release_held(&mut self) -> bool {
+        let now = Instant::now();
+        let mut needs_interrupt = false;
+        while let Some(&held) = self.held.front() {
+            if held.deadline > now {
+                // The timer is never reset to zero, which would disarm it.
+                if let Err(e) = self.release_timer.reset(held.deadline - now, None) {
+                    error!("fault injection: failed to arm release timer: {}", e);
+                }
+                break;
+            }
+            self.held.pop_front();
+            self.queue.add_used(&self.mem, held.index, held.len);
+            needs_interrupt = true;
+        }
+
+        needs_interrupt
+    }
+
+    fn handle_command(&mut self, command: FaultInjectionCommand) {
+        let result = match command {
+            FaultInjectionCommand::Configure(config) => {
+                info!("fault injection: introducing faults {}", config);
+                self.faults.config = config;
+                FaultInjectionResult::Ok
+            }
+            FaultInjectionCommand::Status => FaultInjectionResult::Status {
+                config: self.faults.config,
+                stats: self.faults.stats,
+            },
+        };
+        if let Err(e) = self.command_socket.send(&result) {
+            warn!("failed to send fault injection command result: {}", e);
+        }
+    }
+
+    fn run(&mut self, queue_evt: Event, kill_evt: Event) {
+        #[derive(PartialEq, PollToken)]
+        enum Token {
+            QueueAvailable,
+            ReleaseTimer,
+            CommandSocket,
+            InterruptResample,
+            Kill,
+        }
+
+        let wait_ctx: WaitContext<Token> = match WaitContext::build_with(&[
+            (&queue_evt, Token::QueueAvailable),
+            (&self.release_timer, Token::ReleaseTimer),
+            (&self.command_socket, Token::CommandSocket),
+            (self.interrupt.get_resample_evt(), Token::InterruptResample),
+            (&kill_evt, Token::Kill),
+        ]) {
+            Ok(pc) => pc,
+            Err(e) => {
+                error!("failed creating WaitContext: {}", e);
+                self.interrupt.signal_needs_reset();
+                return;
+            }
+        };
+
+        'wait: loop {
+            let events = match wait_ctx.wait() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed polling for events: {}", e);
+                    break;
+                }
+            };
+
+            let mut needs_interrupt = false;
+            for event in events.iter().filter(|e| e.is_readable) {
+                match event.token {
+                    Token::QueueAvailable => {
+                        if let Err(e) = queue_evt.read() {
+                            error!("failed reading queue Event: {}", e);
+                            break 'wait;
+                        }
+                        needs_interrupt |= self.process_queue();
+                    }
+                    Token::ReleaseTimer => {
+                        if let Err(e) = self.release_timer.wait() {
+                            error!("failed reading release timer: {}", e);
+                            break 'wait;
+                        }
+                    }
+                    Token::CommandSocket => {
+                        if let Ok(req) = self.command_socket.recv() {
+                            self.handle_command(req);
+                        }
+                    }
+                    Token::InterruptResample => {
+                        self.interrupt.interrupt_resample();
+                    }
+                    Token::Kill => return,
+                }
+            }
+            for event in events.iter().filter(|e| e.is_hungup) {
+                if event.token == Token::CommandSocket && !event.is_readable {
+                    // If this call fails, the command socket was already removed from the
+                    // WaitContext.
+                    let _ = wait_ctx.delete(&self.command_socket);
+                }
+            }
+            needs_interrupt |= self.release_held();
+            if needs_interrupt && self.faults.next_interrupt() {
+                self.queue.trigger_interrupt(&self.mem, &self.interrupt);
+            }
+        }
+
+        // Only errors get here. Resetting the device starts a new worker.
+        self.interrupt.signal_needs_reset();
+    }
+}
+
+/// Virtio device that echoes requests back to the guest, while delaying, corrupting or failing to
+/// signal them as set from the control socket.
+pub struct FaultInjection {
+    kill_evt: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<Worker>>,
+    base_features: u64,
+    faults: Faults,
+    command_socket: Option<FaultInjectionResponseSocket>,
+}
+
+impl FaultInjection {
+    /// Create a new fault injection device that introduces no faults until told so on
+    /// `command_socket`.
+    pub fn new(base_features: u64, command_socket: FaultInjectionResponseSocket) -> FaultInjection {
+        FaultInjection {
+            kill_evt: None,
+            worker_thread: None,
+            base_features,
+            faults: Faults::default(),
+            command_socket: Some(command_socket),
+        }
+    }
+}
+
+impl Drop for FaultInjection {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl VirtioDevice for FaultInjection {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
+        if let Some(command_socket) = &self.command_socket {
+            keep_rds.push(command_socket.as_raw_descriptor());
+        }
+        keep_rds
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_FAULT_INJECTION
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.base_features
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<Event>,
+    ) {
+        if queues.len() != 1 || queue_evts.len() != 1 {
+            return;
+        }
+
+        let queue = queues.remove(0);
+        let queue_evt = queue_evts.remove(0);
+
+        let command_socket = match self.command_socket.take() {
+            Some(command_socket) => command_socket,
+            None => return,
+        };
+
+        let release_timer = match Timer::new() {
+            Ok(timer) => timer,
+            Err(e) => {
+                error!("failed creating release timer: {}", e);
+                self.command_socket = Some(command_socket);
+                return;
+            }
+        };
+
+        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed creating kill Event pair: {}", e);
+                self.command_socket = Some(command_socket);
+                return;
+            }
+        };
+        self.kill_evt = Some(self_kill_evt);
+
+        let faults = std::mem::take(&mut self.faults);
+        let worker_result = thread::Builder::new()
+            .name("virtio_fault_injection".to_string())
+            .spawn(move || {
+                let mut worker = Worker {
+                    interrupt,
+                    mem,
+                    queue,
+                    command_socket,
+                    faults,
+                    held: VecDeque::new(),
+                    release_timer,
+                };
+                worker.run(queue_evt, kill_evt);
+                worker
+            });
+
+        match worker_result {
+            Err(e) => {
+                error!("failed to spawn virtio_fault_injection worker: {}", e);
+            }
+            Ok(join_handle) => {
+                self.worker_thread = Some(join_handle);
+            }
+        }
+    }
+
+    fn reset(&mut self) -> bool {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            if kill_evt.write(1).is_err() {
+                error!("{}: failed to notify the kill event", self.debug_label());
+                return false;
+            }
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            match worker_thread.join() {
+                Err(_) => {
+                    error!("{}: failed to get back resources", self.debug_label());
+                    return false;
+                }
+                Ok(worker) => {
+                    // Held requests are dropped with the queue, but the faults stay configured
+                    // for the next driver.
+                    self.faults = worker.faults;
+                    self.command_socket = Some(worker.command_socket);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl Suspendable for FaultInjection {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_faults_by_default() {
+        let mut faults = Faults::default();
+        for _ in 0..10 {
+            assert_eq!(faults.next_request(), (None, false));
+            assert!(faults.next_interrupt());
+        }
+        assert_eq!(
+            faults.stats,
+            FaultInjectionStats {
+                requests: 10,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn faults_hit_every_nth() {
+        let mut faults = Faults {
+            config: FaultInjectionConfig {
+                used_delay_ms: 5,
+                corrupt_every: 3,
+                drop_interrupt_every: 2,
+            },
+            ..Default::default()
+        };
+
+        let delay = Some(Duration::from_millis(5));
+        assert_eq!(faults.next_request(), (delay, false));
+        assert_eq!(faults.next_request(), (delay, false));
+        assert_eq!(faults.next_request(), (delay, true));
+        assert!(faults.next_interrupt());
+        assert!(!faults.next_interrupt());
+        assert!(faults.next_interrupt());
+        assert_eq!(
+            faults.stats,
+            FaultInjectionStats {
+                requests: 3,
+                delayed: 3,
+                corrupted: 1,
+                dropped_interrupts: 1,
+            }
+        );
+    }
+}
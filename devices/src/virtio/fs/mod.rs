@@ -13,10 +13,11 @@ use data_model::{DataInit, Le32};
 use vm_memory::GuestMemory;
 
 use crate::virtio::{copy_config, DescriptorError, Interrupt, Queue, VirtioDevice, TYPE_FS};
+use crate::Suspendable;
 
 mod multikey;
 pub mod passthrough;
-mod read_dir;
+pub(crate) mod read_dir;
 mod worker;
 
 use fuse::Server;
@@ -233,8 +234,13 @@ impl VirtioDevice for Fs {
             let worker_result = thread::Builder::new()
                 .name(format!("virtio-fs worker {}", idx))
                 .spawn(move || {
-                    let mut worker = Worker::new(mem, queue, server, irq);
-                    worker.run(evt, kill_evt, watch_resample_event)
+                    let mut worker = Worker::new(mem, queue, server, irq.clone());
+                    let result = worker.run(evt, kill_evt, watch_resample_event);
+                    // The error is logged when the worker is joined.
+                    if result.is_err() {
+                        irq.signal_needs_reset();
+                    }
+                    result
                 });
 
             if watch_resample_event {
@@ -253,6 +259,8 @@ impl VirtioDevice for Fs {
     }
 }
 
+impl Suspendable for Fs {}
+
 impl Drop for Fs {
     fn drop(&mut self) {
         self.stop_workers()
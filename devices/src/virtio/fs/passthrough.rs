@@ -323,6 +323,96 @@ fn ebadf() -> io::Error {
     io::Error::from_raw_os_error(libc::EBADF)
 }
 
+// Counts how much of something the guest used against an optional limit. Nothing is ever given
+// back once used, so deleting files doesn't make room for more.
+struct Quota {
+    limit: Option<u64>,
+    used: AtomicU64,
+}
+
+impl Quota {
+    fn new(limit: Option<u64>) -> Quota {
+        Quota {
+            limit,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    // Takes `amount` out of the quota, failing with ENOSPC if not all of it is left.
+    fn charge(&self, amount: u64) -> io::Result<QuotaCharge> {
+        self.take(amount, false)
+    }
+
+    // Takes as much of `amount` as is left in the quota, failing with ENOSPC if nothing is left.
+    fn charge_up_to(&self, amount: u64) -> io::Result<QuotaCharge> {
+        self.take(amount, true)
+    }
+
+    fn take(&self, amount: u64, partial: bool) -> io::Result<QuotaCharge> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => {
+                return Ok(QuotaCharge {
+                    quota: self,
+                    amount,
+                })
+            }
+        };
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let left = limit.saturating_sub(used);
+            let taken = if partial {
+                cmp::min(amount, left)
+            } else {
+                amount
+            };
+            if taken > left || (taken == 0 && amount > 0) {
+                return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+            }
+            match self.used.compare_exchange_weak(
+                used,
+                used + taken,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    return Ok(QuotaCharge {
+                        quota: self,
+                        amount: taken,
+                    })
+                }
+                Err(current) => used = current,
+            }
+        }
+    }
+}
+
+// Part of a quota taken for an operation. Whatever isn't kept is given back when this is dropped,
+// so failed operations don't count.
+struct QuotaCharge<'a> {
+    quota: &'a Quota,
+    amount: u64,
+}
+
+impl<'a> QuotaCharge<'a> {
+    fn amount(&self) -> u64 {
+        self.amount
+    }
+
+    // Keeps `used` out of the charge, and gives back the rest.
+    fn keep(mut self, used: u64) {
+        self.amount -= cmp::min(used, self.amount);
+    }
+}
+
+impl<'a> Drop for QuotaCharge<'a> {
+    fn drop(&mut self) {
+        if self.quota.limit.is_some() {
+            self.quota.used.fetch_sub(self.amount, Ordering::Relaxed);
+        }
+    }
+}
+
 fn stat(f: &File) -> io::Result<libc::stat64> {
     let mut st = MaybeUninit::<libc::stat64>::zeroed();
 
@@ -436,6 +526,18 @@ pub struct Config {
     ///
     /// The default value for this option is `false`.
     pub ascii_casefold: bool,
+
+    /// The most bytes the guest can write to files, counted over the life of the file system.
+    /// Writes past the limit fail with `ENOSPC`, so the guest can't fill up the host file system.
+    ///
+    /// The default value for this option is `None`, which means there is no limit.
+    pub max_bytes_written: Option<u64>,
+
+    /// The most files, directories, device nodes and symlinks the guest can create, counted over
+    /// the life of the file system. Creating more fails with `ENOSPC`.
+    ///
+    /// The default value for this option is `None`, which means there is no limit.
+    pub max_inodes_created: Option<u64>,
 }
 
 impl Default for Config {
@@ -447,6 +549,8 @@ impl Default for Config {
             writeback: false,
             rewrite_security_xattrs: false,
             ascii_casefold: false,
+            max_bytes_written: None,
+            max_inodes_created: None,
         }
     }
 }
@@ -483,6 +587,11 @@ pub struct PassthroughFs {
     // process-wide CWD, we cannot allow more than one thread to do it at the same time.
     chdir_mutex: Mutex<()>,
 
+    // What the guest added to the shared directory so far, limited by `cfg.max_bytes_written` and
+    // `cfg.max_inodes_created`.
+    bytes_written: Quota,
+    inodes_created: Quota,
+
     cfg: Config,
 }
 
@@ -517,6 +626,8 @@ impl PassthroughFs {
 
             writeback: AtomicBool::new(false),
             chdir_mutex: Mutex::new(()),
+            bytes_written: Quota::new(cfg.max_bytes_written),
+            inodes_created: Quota::new(cfg.max_inodes_created),
             cfg,
         })
     }
@@ -1305,6 +1416,7 @@ impl FileSystem for PassthroughFs {
         // visible in the filesystem with the requested name but incorrect metadata. The only thing
         // left would be a empty hidden directory with a random name.
         let data = self.find_inode(parent)?;
+        let charge = self.inodes_created.charge(1)?;
 
         let _ctx = security_ctx
             .filter(|ctx| ctx.to_bytes() != UNLABELED)
@@ -1354,6 +1466,7 @@ impl FileSystem for PassthroughFs {
         // Now that we've moved the directory make sure we don't try to delete the now non-existent
         // `tmpdir`.
         tmpdir.into_inner();
+        charge.keep(1);
 
         self.do_lookup(&data, name)
     }
@@ -1424,6 +1537,7 @@ impl FileSystem for PassthroughFs {
         // To ensure that the file is created atomically with the proper uid/gid we use `O_TMPFILE`
         // + `linkat` as described in the `open(2)` manpage.
         let data = self.find_inode(parent)?;
+        let charge = self.inodes_created.charge(1)?;
 
         let _ctx = security_ctx
             .filter(|ctx| ctx.to_bytes() != UNLABELED)
@@ -1498,6 +1612,7 @@ impl FileSystem for PassthroughFs {
         if ret < 0 {
             return Err(io::Error::last_os_error());
         }
+        charge.keep(1);
 
         // We no longer need the tmpfile.
         mem::drop(tmpfile);
@@ -1555,9 +1670,12 @@ impl FileSystem for PassthroughFs {
         // setgid bits from the file if it was written to by someone other than the owner.
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
         let data = self.find_handle(handle, inode)?;
+        let charge = self.bytes_written.charge_up_to(size.into())?;
 
         let mut f = data.file.lock();
-        r.read_to(&mut f, size as usize, offset)
+        let written = r.read_to(&mut f, charge.amount() as usize, offset)?;
+        charge.keep(written as u64);
+        Ok(written)
     }
 
     fn getattr(
@@ -1745,6 +1863,7 @@ impl FileSystem for PassthroughFs {
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
 
         let data = self.find_inode(parent)?;
+        let charge = self.inodes_created.charge(1)?;
 
         // The presence of a default posix acl xattr in the parent directory completely changes the
         // meaning of the mode parameter so only apply the umask if it doesn't have one.
@@ -1770,6 +1889,7 @@ impl FileSystem for PassthroughFs {
         if res < 0 {
             Err(io::Error::last_os_error())
         } else {
+            charge.keep(1);
             self.do_lookup(&data, name)
         }
     }
@@ -1813,6 +1933,7 @@ impl FileSystem for PassthroughFs {
         security_ctx: Option<&CStr>,
     ) -> io::Result<Entry> {
         let data = self.find_inode(parent)?;
+        let charge = self.inodes_created.charge(1)?;
 
         let _ctx = security_ctx
             .filter(|ctx| ctx.to_bytes() != UNLABELED)
@@ -1830,6 +1951,7 @@ impl FileSystem for PassthroughFs {
             )
         };
         if res == 0 {
+            charge.keep(1);
             self.do_lookup(&data, name)
         } else {
             Err(io::Error::last_os_error())
@@ -2158,6 +2280,12 @@ impl FileSystem for PassthroughFs {
         length: u64,
     ) -> io::Result<()> {
         let data = self.find_handle(handle, inode)?;
+        // Everything but punching holes can allocate up to `length` bytes.
+        let charge = if mode as libc::c_int & libc::FALLOC_FL_PUNCH_HOLE == 0 {
+            self.bytes_written.charge(length)?
+        } else {
+            self.bytes_written.charge(0)?
+        };
 
         let fd = data.file.lock().as_raw_descriptor();
         // Safe because this doesn't modify any memory and we check the return value.
@@ -2170,6 +2298,7 @@ impl FileSystem for PassthroughFs {
             )
         };
         if res == 0 {
+            charge.keep(length);
             Ok(())
         } else {
             Err(io::Error::last_os_error())
@@ -2246,6 +2375,7 @@ impl FileSystem for PassthroughFs {
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
         let src_data = self.find_handle(handle_src, inode_src)?;
         let dst_data = self.find_handle(handle_dst, inode_dst)?;
+        let charge = self.bytes_written.charge_up_to(length)?;
 
         let src = src_data.file.lock().as_raw_descriptor();
         let dst = dst_data.file.lock().as_raw_descriptor();
@@ -2257,12 +2387,13 @@ impl FileSystem for PassthroughFs {
                 &offset_src,
                 dst,
                 &offset_dst,
-                length,
+                charge.amount(),
                 flags,
             )
         };
 
         if res >= 0 {
+            charge.keep(res as u64);
             Ok(res as usize)
         } else {
             Err(io::Error::last_os_error())
@@ -2278,6 +2409,32 @@ mod tests {
     use std::os::unix::ffi::OsStringExt;
     use std::path::PathBuf;
 
+    #[test]
+    fn quota() {
+        let quota = Quota::new(Some(10));
+
+        // Failed operations give back their charge.
+        drop(quota.charge(4).unwrap());
+        quota.charge(10).unwrap().keep(4);
+        quota.charge_up_to(2).unwrap().keep(1);
+
+        // Only 5 are left, which partial charges can still use.
+        assert_eq!(
+            quota.charge(6).err().and_then(|e| e.raw_os_error()),
+            Some(libc::ENOSPC)
+        );
+        let charge = quota.charge_up_to(8).unwrap();
+        assert_eq!(charge.amount(), 5);
+        charge.keep(5);
+        assert_eq!(
+            quota.charge_up_to(1).err().and_then(|e| e.raw_os_error()),
+            Some(libc::ENOSPC)
+        );
+
+        let unlimited = Quota::new(None);
+        assert_eq!(unlimited.charge_up_to(u64::MAX).unwrap().amount(), u64::MAX);
+    }
+
     #[test]
     fn create_temp_dir() {
         let testdir = CString::new(env::temp_dir().into_os_string().into_vec())
@@ -45,6 +45,7 @@ use super::{
     copy_config, resource_bridge::*, DescriptorChain, Interrupt, Queue, Reader, VirtioDevice,
     Writer, TYPE_GPU,
 };
+use crate::Suspendable;
 
 use super::{PciCapabilityType, VirtioPciShmCap};
 
@@ -710,6 +711,7 @@ impl Worker {
             Ok(pc) => pc,
             Err(e) => {
                 error!("failed creating WaitContext: {}", e);
+                self.interrupt.signal_needs_reset();
                 return;
             }
         };
@@ -730,7 +732,7 @@ impl Worker {
 
         // Declare this outside the loop so we don't keep allocating and freeing the vector.
         let mut process_resource_bridge = Vec::with_capacity(self.resource_bridges.len());
-        'wait: loop {
+        loop {
             // If there are outstanding fences, wake up early to poll them.
             let duration = if !self.state.fence_descriptors.is_empty() {
                 Duration::from_millis(FENCE_POLL_MS)
@@ -788,9 +790,7 @@ impl Worker {
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
-                    Token::Kill => {
-                        break 'wait;
-                    }
+                    Token::Kill => return,
                 }
             }
 
@@ -826,13 +826,18 @@ impl Worker {
             }
 
             if signal_used_ctrl {
-                self.interrupt.signal_used_queue(self.ctrl_queue.vector);
+                self.ctrl_queue
+                    .trigger_interrupt(&self.mem, &self.interrupt);
             }
 
             if signal_used_cursor {
-                self.interrupt.signal_used_queue(self.cursor_queue.vector);
+                self.cursor_queue
+                    .trigger_interrupt(&self.mem, &self.interrupt);
             }
         }
+
+        // Only errors get here. Resetting the device starts a new worker.
+        self.interrupt.signal_needs_reset();
     }
 }
 
@@ -1087,7 +1092,10 @@ impl VirtioDevice for Gpu {
                             external_blob,
                         ) {
                             Some(backend) => backend,
-                            None => return,
+                            None => {
+                                interrupt.signal_needs_reset();
+                                return;
+                            }
                         };
 
                         Worker {
@@ -1143,3 +1151,5 @@ impl VirtioDevice for Gpu {
         ))]
     }
 }
+
+impl Suspendable for Gpu {}
@@ -19,6 +19,7 @@ use super::{
     copy_config, DescriptorChain, DescriptorError, Interrupt, Queue, Reader, VirtioDevice, Writer,
     TYPE_INPUT,
 };
+use crate::Suspendable;
 use linux_input_sys::{virtio_input_event, InputEventDecoder};
 use std::collections::BTreeMap;
 use std::fmt::{self, Display};
@@ -448,6 +449,7 @@ impl<T: EventSource> Worker<T> {
     fn run(&mut self, event_queue_evt: Event, status_queue_evt: Event, kill_evt: Event) {
         if let Err(e) = self.event_source.init() {
             error!("failed initializing event source: {}", e);
+            self.interrupt.signal_needs_reset();
             return;
         }
 
@@ -469,6 +471,7 @@ impl<T: EventSource> Worker<T> {
             Ok(wait_ctx) => wait_ctx,
             Err(e) => {
                 error!("failed creating WaitContext: {}", e);
+                self.interrupt.signal_needs_reset();
                 return;
             }
         };
@@ -478,6 +481,7 @@ impl<T: EventSource> Worker<T> {
                 Ok(wait_events) => wait_events,
                 Err(e) => {
                     error!("failed polling for events: {}", e);
+                    self.interrupt.signal_needs_reset();
                     break;
                 }
             };
@@ -488,6 +492,7 @@ impl<T: EventSource> Worker<T> {
                     Token::EventQAvailable => {
                         if let Err(e) = event_queue_evt.read() {
                             error!("failed reading event queue Event: {}", e);
+                            self.interrupt.signal_needs_reset();
                             break 'wait;
                         }
                         needs_interrupt |= self.send_events();
@@ -495,6 +500,7 @@ impl<T: EventSource> Worker<T> {
                     Token::StatusQAvailable => {
                         if let Err(e) = status_queue_evt.read() {
                             error!("failed reading status queue Event: {}", e);
+                            self.interrupt.signal_needs_reset();
                             break 'wait;
                         }
                         match self.process_status_queue() {
@@ -516,7 +522,8 @@ impl<T: EventSource> Worker<T> {
                 }
             }
             if needs_interrupt {
-                self.interrupt.signal_used_queue(self.event_queue.vector);
+                self.event_queue
+                    .trigger_interrupt(&self.guest_memory, &self.interrupt);
             }
         }
 
@@ -660,6 +667,8 @@ where
     }
 }
 
+impl<T> Suspendable for Input<T> where T: 'static + EventSource + Send {}
+
 /// Creates a new virtio input device from an event device node
 pub fn new_evdev<T>(source: T, virtio_features: u64) -> Result<Input<EvdevEventSource<T>>>
 where
@@ -5,7 +5,7 @@
 use super::{INTERRUPT_STATUS_CONFIG_CHANGED, INTERRUPT_STATUS_USED_RING, VIRTIO_MSI_NO_VECTOR};
 use crate::pci::MsixConfig;
 use base::Event;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use sync::Mutex;
 
@@ -15,6 +15,7 @@ pub struct Interrupt {
     interrupt_resample_evt: Event,
     pub msix_config: Option<Arc<Mutex<MsixConfig>>>,
     config_msix_vector: u16,
+    needs_reset: Arc<AtomicBool>,
 }
 
 impl Interrupt {
@@ -31,6 +32,7 @@ impl Interrupt {
             interrupt_resample_evt,
             msix_config,
             config_msix_vector,
+            needs_reset: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -72,6 +74,21 @@ impl Interrupt {
         self.signal(self.config_msix_vector, INTERRUPT_STATUS_CONFIG_CHANGED)
     }
 
+    /// Tell the driver that the device stopped working and has to be reset.
+    ///
+    /// The transport sets DEVICE_NEEDS_RESET in the device status the next time the driver looks
+    /// at it, and the config change interrupt makes the driver look. Only the first call signals.
+    pub fn signal_needs_reset(&self) {
+        if !self.needs_reset.swap(true, Ordering::SeqCst) {
+            self.signal_config_changed();
+        }
+    }
+
+    /// Return the flag set by `signal_needs_reset`, shared with every clone of this interrupt.
+    pub fn needs_reset(&self) -> Arc<AtomicBool> {
+        self.needs_reset.clone()
+    }
+
     /// Handle interrupt resampling event, reading the value from the event and doing the resample.
     pub fn interrupt_resample(&self) {
         let _ = self.interrupt_resample_evt.read();
@@ -92,3 +109,31 @@ impl Interrupt {
         &self.interrupt_resample_evt
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn needs_reset_signals_once() {
+        let interrupt_status = Arc::new(AtomicUsize::new(0));
+        let interrupt = Interrupt::new(
+            interrupt_status.clone(),
+            Event::new().unwrap(),
+            Event::new().unwrap(),
+            None,
+            VIRTIO_MSI_NO_VECTOR,
+        );
+        interrupt.signal_needs_reset();
+        assert!(interrupt.needs_reset().load(Ordering::SeqCst));
+        assert_eq!(
+            interrupt_status.load(Ordering::SeqCst),
+            INTERRUPT_STATUS_CONFIG_CHANGED as usize
+        );
+
+        // The driver acknowledges the interrupt, which isn't raised again.
+        interrupt_status.store(0, Ordering::SeqCst);
+        interrupt.signal_needs_reset();
+        assert_eq!(interrupt_status.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The minimum time between used-ring interrupts of a device.
+///
+/// Clones share the same value, so a device can hand one to each of its workers and still change
+/// the interval while they run. An interval of zero disables coalescing.
+#[derive(Clone, Default)]
+pub struct CoalescingInterval(Arc<AtomicU64>);
+
+impl CoalescingInterval {
+    pub fn new(interval: Duration) -> CoalescingInterval {
+        let ci = CoalescingInterval::default();
+        ci.set(interval);
+        ci
+    }
+
+    pub fn set(&self, interval: Duration) {
+        let micros = interval.as_micros().min(u64::MAX as u128) as u64;
+        self.0.store(micros, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Duration {
+        Duration::from_micros(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Interrupt coalescing for a virtio queue.
+///
+/// Rather than interrupting the guest every time buffers are returned to the used ring, a device
+/// worker asks the coalescer first. An interrupt that would come sooner than the configured
+/// interval after the previous one is held back, and every completion until the interval expires
+/// is delivered with a single interrupt once it does. The worker is expected to wait no longer
+/// than `timeout` and then call `take_due` so that held back interrupts aren't lost.
+pub struct InterruptCoalescer {
+    interval: CoalescingInterval,
+    last_interrupt: Option<Instant>,
+    pending: bool,
+}
+
+impl InterruptCoalescer {
+    pub fn new(interval: CoalescingInterval) -> InterruptCoalescer {
+        InterruptCoalescer {
+            interval,
+            last_interrupt: None,
+            pending: false,
+        }
+    }
+
+    /// Changes the interval for this coalescer and every other one sharing its interval.
+    pub fn set_interval(&self, interval: Duration) {
+        self.interval.set(interval);
+    }
+
+    /// Called after buffers were added to the used ring. Returns true if the guest should be
+    /// interrupted now; otherwise the interrupt is held back until `timeout` expires.
+    pub fn signal(&mut self) -> bool {
+        self.signal_at(Instant::now())
+    }
+
+    /// Returns how long until a held back interrupt is due, or `None` if nothing is held back.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_at(Instant::now())
+    }
+
+    /// Returns true if a held back interrupt is due and the guest should be interrupted now.
+    pub fn take_due(&mut self) -> bool {
+        self.take_due_at(Instant::now())
+    }
+
+    fn signal_at(&mut self, now: Instant) -> bool {
+        if let Some(last) = self.last_interrupt {
+            if now.duration_since(last) < self.interval.get() {
+                self.pending = true;
+                return false;
+            }
+        }
+        self.last_interrupt = Some(now);
+        self.pending = false;
+        true
+    }
+
+    fn timeout_at(&self, now: Instant) -> Option<Duration> {
+        if !self.pending {
+            return None;
+        }
+        let deadline = self.last_interrupt? + self.interval.get();
+        Some(deadline.saturating_duration_since(now))
+    }
+
+    fn take_due_at(&mut self, now: Instant) -> bool {
+        if self.timeout_at(now) != Some(Duration::from_secs(0)) {
+            return false;
+        }
+        self.last_interrupt = Some(now);
+        self.pending = false;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled() {
+        let mut coalescer = InterruptCoalescer::new(CoalescingInterval::default());
+        let now = Instant::now();
+        assert!(coalescer.signal_at(now));
+        assert!(coalescer.signal_at(now));
+        assert_eq!(coalescer.timeout_at(now), None);
+        assert!(!coalescer.take_due_at(now));
+    }
+
+    #[test]
+    fn holds_back_interrupts() {
+        let interval = Duration::from_micros(100);
+        let mut coalescer = InterruptCoalescer::new(CoalescingInterval::new(interval));
+        let start = Instant::now();
+
+        assert!(coalescer.signal_at(start));
+        assert_eq!(coalescer.timeout_at(start), None);
+
+        let t = start + Duration::from_micros(40);
+        assert!(!coalescer.signal_at(t));
+        assert!(!coalescer.signal_at(t));
+        assert_eq!(coalescer.timeout_at(t), Some(Duration::from_micros(60)));
+        assert!(!coalescer.take_due_at(t));
+
+        let t = start + interval;
+        assert!(coalescer.take_due_at(t));
+        assert_eq!(coalescer.timeout_at(t), None);
+        assert!(!coalescer.take_due_at(t + interval));
+
+        assert!(coalescer.signal_at(t + interval));
+    }
+
+    #[test]
+    fn shared_interval() {
+        let interval = CoalescingInterval::new(Duration::from_millis(1));
+        let mut coalescer = InterruptCoalescer::new(interval.clone());
+        let start = Instant::now();
+
+        assert!(coalescer.signal_at(start));
+        assert!(!coalescer.signal_at(start));
+
+        interval.set(Duration::from_secs(0));
+        assert_eq!(coalescer.timeout_at(start), Some(Duration::from_secs(0)));
+        assert!(coalescer.take_due_at(start));
+        assert!(coalescer.signal_at(start));
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use sync::Mutex;
+use vm_control::{DiskStats, DISK_LATENCY_BUCKETS};
+
+/// The kinds of block device requests that are counted separately.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IoKind {
+    Read,
+    Write,
+    Flush,
+    Discard,
+    WriteZeroes,
+    Other,
+}
+
+// Returns the `DiskStats::latency_us` bucket for a request that took `latency`.
+fn latency_bucket(latency: Duration) -> usize {
+    let micros = latency.as_micros().max(1);
+    let log2 = (127 - micros.leading_zeros()) as usize;
+    log2.min(DISK_LATENCY_BUCKETS - 1)
+}
+
+/// Counters of a block device's requests.
+///
+/// Clones share the same counters, so the workers of a device's queues add to them together, and
+/// they can be read while the workers run.
+#[derive(Clone, Default)]
+pub struct IoStats(Arc<Mutex<DiskStats>>);
+
+impl IoStats {
+    pub fn new() -> IoStats {
+        Default::default()
+    }
+
+    /// Called when a request is taken from a queue.
+    pub fn start(&self) {
+        let mut stats = self.0.lock();
+        stats.in_flight += 1;
+        stats.max_in_flight = stats.max_in_flight.max(stats.in_flight);
+    }
+
+    /// Called when a request of `kind` covering `bytes` of the disk completes, `latency` after it
+    /// was taken from its queue.
+    pub fn complete(&self, kind: IoKind, bytes: u64, latency: Duration) {
+        let mut stats = self.0.lock();
+        stats.in_flight = stats.in_flight.saturating_sub(1);
+        match kind {
+            IoKind::Read => {
+                stats.reads += 1;
+                stats.read_bytes += bytes;
+            }
+            IoKind::Write => {
+                stats.writes += 1;
+                stats.written_bytes += bytes;
+            }
+            IoKind::Flush => stats.flushes += 1,
+            IoKind::Discard => {
+                stats.discards += 1;
+                stats.discarded_bytes += bytes;
+            }
+            IoKind::WriteZeroes => {
+                stats.write_zeroes += 1;
+                stats.zeroed_bytes += bytes;
+            }
+            IoKind::Other => {}
+        }
+        stats.latency_us[latency_bucket(latency)] += 1;
+    }
+
+    /// Returns a copy of the counters.
+    pub fn stats(&self) -> DiskStats {
+        *self.0.lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        assert_eq!(latency_bucket(Duration::from_nanos(10)), 0);
+        assert_eq!(latency_bucket(Duration::from_micros(1)), 0);
+        assert_eq!(latency_bucket(Duration::from_micros(2)), 1);
+        assert_eq!(latency_bucket(Duration::from_micros(1000)), 9);
+        assert_eq!(latency_bucket(Duration::from_micros(1024)), 10);
+        assert_eq!(
+            latency_bucket(Duration::from_secs(3600)),
+            DISK_LATENCY_BUCKETS - 1
+        );
+    }
+
+    #[test]
+    fn counts() {
+        let stats = IoStats::new();
+        let clone = stats.clone();
+        stats.start();
+        clone.start();
+        stats.complete(IoKind::Read, 4096, Duration::from_micros(100));
+        stats.start();
+        clone.complete(IoKind::Discard, 1 << 20, Duration::from_micros(3));
+        stats.complete(IoKind::Flush, 0, Duration::from_micros(3));
+
+        let s = clone.stats();
+        assert_eq!(s.reads, 1);
+        assert_eq!(s.read_bytes, 4096);
+        assert_eq!(s.discards, 1);
+        assert_eq!(s.discarded_bytes, 1 << 20);
+        assert_eq!(s.flushes, 1);
+        assert_eq!(s.writes, 0);
+        assert_eq!(s.in_flight, 0);
+        assert_eq!(s.max_in_flight, 2);
+        assert_eq!(s.latency_us[6], 1);
+        assert_eq!(s.latency_us[1], 2);
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use sync::Mutex;
+
+/// Limits on the rate of a block device's requests. A rate of zero is unlimited.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ThrottleLimits {
+    /// Requests per second.
+    pub iops: u64,
+    /// Requests that can be made back to back after the device has been idle, or zero for one
+    /// second's worth.
+    pub iops_burst: u64,
+    /// Bytes of data read or written per second.
+    pub bytes_per_sec: u64,
+    /// Bytes that can be read or written back to back after the device has been idle, or zero for
+    /// one second's worth.
+    pub bytes_burst: u64,
+}
+
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+
+struct TokenBucket {
+    rate: u64,
+    capacity: u64,
+    // Counted in billionths of a token so that refills are exact. Goes negative after a request
+    // that costs more than the capacity.
+    nanotokens: i128,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst: u64) -> TokenBucket {
+        let capacity = if burst == 0 { rate } else { burst };
+        TokenBucket {
+            rate,
+            capacity,
+            nanotokens: capacity as i128 * NANOS_PER_SEC,
+        }
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        if self.rate != 0 {
+            let nanotokens = self.nanotokens + elapsed.as_nanos() as i128 * self.rate as i128;
+            self.nanotokens = nanotokens.min(self.capacity as i128 * NANOS_PER_SEC);
+        }
+    }
+
+    // Returns how long until `cost` tokens are available. A cost over the capacity only has to
+    // wait for a full bucket, and leaves it in debt.
+    fn wait(&self, cost: u64) -> Duration {
+        let needed = cost.min(self.capacity) as i128 * NANOS_PER_SEC;
+        if self.rate == 0 || self.nanotokens >= needed {
+            return Duration::from_secs(0);
+        }
+        let rate = self.rate as i128;
+        let nanos = (needed - self.nanotokens + rate - 1) / rate;
+        Duration::from_nanos(nanos.min(u64::MAX as i128) as u64)
+    }
+
+    // Keeps the tokens of the bucket this one replaces, so that changing the limits doesn't hand
+    // out a fresh burst.
+    fn carry_over(&mut self, old: &TokenBucket) {
+        if old.rate != 0 {
+            self.nanotokens = old.nanotokens.min(self.capacity as i128 * NANOS_PER_SEC);
+        }
+    }
+
+    fn take(&mut self, cost: u64) {
+        if self.rate != 0 {
+            self.nanotokens -= cost as i128 * NANOS_PER_SEC;
+        }
+    }
+}
+
+struct ThrottleState {
+    limits: ThrottleLimits,
+    ops: TokenBucket,
+    bytes: TokenBucket,
+    last_refill: Instant,
+}
+
+impl ThrottleState {
+    fn new(limits: ThrottleLimits, now: Instant) -> ThrottleState {
+        ThrottleState {
+            limits,
+            ops: TokenBucket::new(limits.iops, limits.iops_burst),
+            bytes: TokenBucket::new(limits.bytes_per_sec, limits.bytes_burst),
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.ops.refill(elapsed);
+        self.bytes.refill(elapsed);
+        self.last_refill = now;
+    }
+}
+
+/// Token-bucket rate limiting of a block device's requests.
+///
+/// Clones share the same buckets, so the workers of a device's queues stay within the limits
+/// together, and the limits can be changed while they run. A worker asks the throttle before
+/// starting each request, and leaves the request in the queue until it's allowed to start.
+#[derive(Clone)]
+pub struct IoThrottle(Arc<Mutex<ThrottleState>>);
+
+impl IoThrottle {
+    pub fn new(limits: ThrottleLimits) -> IoThrottle {
+        IoThrottle(Arc::new(Mutex::new(ThrottleState::new(
+            limits,
+            Instant::now(),
+        ))))
+    }
+
+    /// Changes the limits for this throttle and every clone of it.
+    pub fn set_limits(&self, limits: ThrottleLimits) {
+        self.set_limits_at(Instant::now(), limits)
+    }
+
+    pub fn limits(&self) -> ThrottleLimits {
+        self.0.lock().limits
+    }
+
+    /// Called before starting a request that reads or writes `bytes` of data. Returns `None` if
+    /// the request can start now, and has been accounted for; otherwise returns how long to wait
+    /// before asking again.
+    pub fn admit(&self, bytes: u64) -> Option<Duration> {
+        self.admit_at(Instant::now(), bytes)
+    }
+
+    fn set_limits_at(&self, now: Instant, limits: ThrottleLimits) {
+        let mut state = self.0.lock();
+        state.refill(now);
+        let mut new_state = ThrottleState::new(limits, now);
+        new_state.ops.carry_over(&state.ops);
+        new_state.bytes.carry_over(&state.bytes);
+        *state = new_state;
+    }
+
+    fn admit_at(&self, now: Instant, bytes: u64) -> Option<Duration> {
+        let mut state = self.0.lock();
+        state.refill(now);
+        let wait = state.ops.wait(1).max(state.bytes.wait(bytes));
+        if wait > Duration::from_secs(0) {
+            return Some(wait);
+        }
+        state.ops.take(1);
+        state.bytes.take(bytes);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited() {
+        let throttle = IoThrottle::new(ThrottleLimits::default());
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert_eq!(throttle.admit_at(now, 1 << 30), None);
+        }
+    }
+
+    #[test]
+    fn limits_iops() {
+        let throttle = IoThrottle::new(ThrottleLimits {
+            iops: 10,
+            iops_burst: 2,
+            ..Default::default()
+        });
+        let start = Instant::now();
+
+        assert_eq!(throttle.admit_at(start, 0), None);
+        assert_eq!(throttle.admit_at(start, 0), None);
+        assert_eq!(
+            throttle.admit_at(start, 0),
+            Some(Duration::from_millis(100))
+        );
+
+        let t = start + Duration::from_millis(50);
+        assert_eq!(throttle.admit_at(t, 0), Some(Duration::from_millis(50)));
+
+        let t = start + Duration::from_millis(100);
+        assert_eq!(throttle.admit_at(t, 0), None);
+        assert!(throttle.admit_at(t, 0).is_some());
+
+        // The bucket holds no more than the burst after a long idle period.
+        let t = t + Duration::from_secs(10);
+        assert_eq!(throttle.admit_at(t, 0), None);
+        assert_eq!(throttle.admit_at(t, 0), None);
+        assert!(throttle.admit_at(t, 0).is_some());
+    }
+
+    #[test]
+    fn limits_bandwidth() {
+        let throttle = IoThrottle::new(ThrottleLimits {
+            bytes_per_sec: 1000,
+            ..Default::default()
+        });
+        let start = Instant::now();
+
+        assert_eq!(throttle.admit_at(start, 600), None);
+        assert_eq!(
+            throttle.admit_at(start, 600),
+            Some(Duration::from_millis(200))
+        );
+        assert_eq!(throttle.admit_at(start, 400), None);
+
+        // A request larger than the burst waits for a full bucket, and the debt it leaves holds
+        // back the requests after it.
+        let t = start + Duration::from_secs(1);
+        assert_eq!(throttle.admit_at(t, 3000), None);
+        let t = t + Duration::from_secs(1);
+        assert_eq!(throttle.admit_at(t, 1), Some(Duration::from_millis(1001)));
+    }
+
+    #[test]
+    fn change_limits() {
+        let throttle = IoThrottle::new(ThrottleLimits::default());
+        let clone = throttle.clone();
+        let start = Instant::now();
+
+        let limits = ThrottleLimits {
+            iops: 1,
+            ..Default::default()
+        };
+        clone.set_limits_at(start, limits);
+        assert_eq!(throttle.limits(), limits);
+        assert_eq!(throttle.admit_at(start, 0), None);
+        assert_eq!(throttle.admit_at(start, 0), Some(Duration::from_secs(1)));
+
+        // Raising the limit doesn't refill the bucket.
+        clone.set_limits_at(
+            start,
+            ThrottleLimits {
+                iops: 2,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            throttle.admit_at(start, 0),
+            Some(Duration::from_millis(500))
+        );
+
+        clone.set_limits_at(start, ThrottleLimits::default());
+        assert_eq!(throttle.admit_at(start, 0), None);
+    }
+}
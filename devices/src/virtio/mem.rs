@@ -0,0 +1,700 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fmt::{self, Display};
+use std::io;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use base::{
+    error, info, warn, AsRawDescriptor, Error as SysError, Event, MappedRegion, MemoryMapping,
+    MemoryMappingUnix, PollToken, RawDescriptor, WaitContext,
+};
+use data_model::{DataInit, Le16, Le64};
+use libc::EINVAL;
+use msg_socket::{MsgReceiver, MsgSender};
+use vm_control::{MemControlCommand, MemControlResponseSocket, MemControlResult};
+use vm_memory::{GuestAddress, GuestMemory};
+
+use super::{
+    copy_config, DescriptorChain, DescriptorError, Interrupt, Queue, Reader, VirtioDevice, Writer,
+    TYPE_MEM,
+};
+use crate::Suspendable;
+
+const QUEUE_SIZE: u16 = 128;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+
+const VIRTIO_MEM_REQ_PLUG: u16 = 0;
+const VIRTIO_MEM_REQ_UNPLUG: u16 = 1;
+const VIRTIO_MEM_REQ_UNPLUG_ALL: u16 = 2;
+const VIRTIO_MEM_REQ_STATE: u16 = 3;
+
+const VIRTIO_MEM_RESP_ACK: u16 = 0;
+const VIRTIO_MEM_RESP_NACK: u16 = 1;
+const VIRTIO_MEM_RESP_ERROR: u16 = 3;
+
+const VIRTIO_MEM_STATE_PLUGGED: u16 = 0;
+const VIRTIO_MEM_STATE_UNPLUGGED: u16 = 1;
+const VIRTIO_MEM_STATE_MIXED: u16 = 2;
+
+/// The size of the blocks the guest plugs and unplugs memory in. Linux needs this to be at least
+/// the size of a huge page.
+pub const VIRTIO_MEM_BLOCK_SIZE: u64 = 2 * 1024 * 1024;
+
+// virtio_mem_config is the virtio-mem device configuration space defined by the virtio spec.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_mem_config {
+    block_size: Le64,
+    node_id: Le16,
+    padding: [u8; 6],
+    addr: Le64,
+    region_size: Le64,
+    usable_region_size: Le64,
+    plugged_size: Le64,
+    requested_size: Le64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_mem_config {}
+
+// The plug, unplug and state requests all name a range of blocks. An unplug all request leaves
+// the range out, but the driver still sends the whole struct.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_mem_req {
+    type_: Le16,
+    padding: [Le16; 3],
+    addr: Le64,
+    nb_blocks: Le16,
+    padding_1: [Le16; 3],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_mem_req {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_mem_resp {
+    type_: Le16,
+    padding: [Le16; 3],
+    state: Le16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_mem_resp {}
+
+#[derive(Debug)]
+enum Error {
+    /// Invalid virtio descriptor chain.
+    Descriptor(DescriptorError),
+    /// Failed to read from virtqueue.
+    ReadQueue(io::Error),
+    /// Failed to write to virtqueue.
+    WriteQueue(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Descriptor(e) => write!(f, "virtio descriptor error: {}", e),
+            ReadQueue(e) => write!(f, "failed to read from virtqueue: {}", e),
+            WriteQueue(e) => write!(f, "failed to write to virtqueue: {}", e),
+        }
+    }
+}
+
+impl ::std::error::Error for Error {}
+
+type Result<T> = ::std::result::Result<T, Error>;
+
+// MemConfig is modified by the worker and read from the device thread.
+#[derive(Default)]
+struct MemConfig {
+    plugged_size: AtomicU64,
+    requested_size: AtomicU64,
+}
+
+// The blocks of the device's memory region and which of them the guest has plugged.
+struct MemoryBlocks {
+    // The device's own mapping of the region, through which unplugged blocks are given back to
+    // the host.
+    mapping: MemoryMapping,
+    addr: u64,
+    plugged: Vec<bool>,
+    config: Arc<MemConfig>,
+}
+
+impl MemoryBlocks {
+    fn region_size(&self) -> u64 {
+        self.plugged.len() as u64 * VIRTIO_MEM_BLOCK_SIZE
+    }
+
+    // Returns the indices of the `nb_blocks` blocks starting at `addr`, or `None` if they are not
+    // all in the region.
+    fn blocks(&self, addr: u64, nb_blocks: u16) -> Option<Range<usize>> {
+        let offset = addr.checked_sub(self.addr)?;
+        if offset % VIRTIO_MEM_BLOCK_SIZE != 0 || nb_blocks == 0 {
+            return None;
+        }
+        let first = (offset / VIRTIO_MEM_BLOCK_SIZE) as usize;
+        let end = first.checked_add(usize::from(nb_blocks))?;
+        if end > self.plugged.len() {
+            return None;
+        }
+        Some(first..end)
+    }
+
+    fn plug(&mut self, addr: u64, nb_blocks: u16) -> u16 {
+        let blocks = match self.blocks(addr, nb_blocks) {
+            Some(b) => b,
+            None => return VIRTIO_MEM_RESP_ERROR,
+        };
+        if self.plugged[blocks.clone()].iter().any(|&p| p) {
+            return VIRTIO_MEM_RESP_ERROR;
+        }
+        // The guest may only grow up to the size the host asked for.
+        let size = u64::from(nb_blocks) * VIRTIO_MEM_BLOCK_SIZE;
+        let plugged_size = self.config.plugged_size.load(Ordering::Relaxed);
+        if plugged_size + size > self.config.requested_size.load(Ordering::Relaxed) {
+            return VIRTIO_MEM_RESP_NACK;
+        }
+
+        // The blocks need no setting up, the host backs them with memory once the guest uses them.
+        for plugged in &mut self.plugged[blocks] {
+            *plugged = true;
+        }
+        self.config
+            .plugged_size
+            .store(plugged_size + size, Ordering::Relaxed);
+        VIRTIO_MEM_RESP_ACK
+    }
+
+    fn unplug(&mut self, addr: u64, nb_blocks: u16) -> u16 {
+        let blocks = match self.blocks(addr, nb_blocks) {
+            Some(b) => b,
+            None => return VIRTIO_MEM_RESP_ERROR,
+        };
+        if !self.plugged[blocks.clone()].iter().all(|&p| p) {
+            return VIRTIO_MEM_RESP_ERROR;
+        }
+        if !self.discard(blocks.clone()) {
+            return VIRTIO_MEM_RESP_ERROR;
+        }
+
+        for plugged in &mut self.plugged[blocks] {
+            *plugged = false;
+        }
+        let size = u64::from(nb_blocks) * VIRTIO_MEM_BLOCK_SIZE;
+        self.config.plugged_size.fetch_sub(size, Ordering::Relaxed);
+        VIRTIO_MEM_RESP_ACK
+    }
+
+    fn unplug_all(&mut self) -> u16 {
+        if !self.discard(0..self.plugged.len()) {
+            return VIRTIO_MEM_RESP_ERROR;
+        }
+        for plugged in &mut self.plugged {
+            *plugged = false;
+        }
+        self.config.plugged_size.store(0, Ordering::Relaxed);
+        VIRTIO_MEM_RESP_ACK
+    }
+
+    fn state(&self, addr: u64, nb_blocks: u16) -> (u16, u16) {
+        let blocks = match self.blocks(addr, nb_blocks) {
+            Some(b) => b,
+            None => return (VIRTIO_MEM_RESP_ERROR, 0),
+        };
+        let blocks = &self.plugged[blocks];
+        let state = if blocks.iter().all(|&p| p) {
+            VIRTIO_MEM_STATE_PLUGGED
+        } else if blocks.iter().all(|&p| !p) {
+            VIRTIO_MEM_STATE_UNPLUGGED
+        } else {
+            VIRTIO_MEM_STATE_MIXED
+        };
+        (VIRTIO_MEM_RESP_ACK, state)
+    }
+
+    // Gives the memory backing `blocks` back to the host, so that the guest reads zeroes from
+    // them if it plugs them again.
+    fn discard(&self, blocks: Range<usize>) -> bool {
+        let offset = blocks.start as u64 * VIRTIO_MEM_BLOCK_SIZE;
+        let size = blocks.len() as u64 * VIRTIO_MEM_BLOCK_SIZE;
+        match self.mapping.remove_range(offset as usize, size as usize) {
+            Ok(()) => true,
+            Err(e) => {
+                error!(
+                    "virtio-mem: failed to discard {:#x} bytes at {:#x}: {}",
+                    size,
+                    self.addr + offset,
+                    e
+                );
+                false
+            }
+        }
+    }
+}
+
+struct Worker {
+    interrupt: Interrupt,
+    mem: GuestMemory,
+    queue: Queue,
+    blocks: MemoryBlocks,
+    command_socket: MemControlResponseSocket,
+}
+
+impl Worker {
+    fn execute_request(&mut self, request: virtio_mem_req) -> virtio_mem_resp {
+        let addr = request.addr.to_native();
+        let nb_blocks = request.nb_blocks.to_native();
+        let (type_, state) = match request.type_.to_native() {
+            VIRTIO_MEM_REQ_PLUG => (self.blocks.plug(addr, nb_blocks), 0),
+            VIRTIO_MEM_REQ_UNPLUG => (self.blocks.unplug(addr, nb_blocks), 0),
+            VIRTIO_MEM_REQ_UNPLUG_ALL => (self.blocks.unplug_all(), 0),
+            VIRTIO_MEM_REQ_STATE => self.blocks.state(addr, nb_blocks),
+            t => {
+                error!("virtio-mem: unknown request type: {}", t);
+                (VIRTIO_MEM_RESP_ERROR, 0)
+            }
+        };
+        virtio_mem_resp {
+            type_: type_.into(),
+            state: state.into(),
+            ..Default::default()
+        }
+    }
+
+    fn handle_request(&mut self, avail_desc: DescriptorChain) -> Result<usize> {
+        let mut reader =
+            Reader::new(self.mem.clone(), avail_desc.clone()).map_err(Error::Descriptor)?;
+        let mut writer = Writer::new(self.mem.clone(), avail_desc).map_err(Error::Descriptor)?;
+
+        let request: virtio_mem_req = reader.read_obj().map_err(Error::ReadQueue)?;
+        let response = self.execute_request(request);
+        writer.write_obj(response).map_err(Error::WriteQueue)?;
+
+        Ok(writer.bytes_written())
+    }
+
+    fn process_queue(&mut self) -> bool {
+        let mut needs_interrupt = false;
+        while let Some(avail_desc) = self.queue.pop(&self.mem) {
+            let index = avail_desc.index;
+
+            let bytes_written = match self.handle_request(avail_desc) {
+                Ok(count) => count,
+                Err(e) => {
+                    error!("virtio-mem: unable to handle request: {}", e);
+                    0
+                }
+            };
+            self.queue.add_used(&self.mem, index, bytes_written as u32);
+            needs_interrupt = true;
+        }
+
+        needs_interrupt
+    }
+
+    fn resize(&mut self, requested_bytes: u64) -> MemControlResult {
+        if requested_bytes % VIRTIO_MEM_BLOCK_SIZE != 0
+            || requested_bytes > self.blocks.region_size()
+        {
+            warn!(
+                "virtio-mem: refusing to resize to {} bytes",
+                requested_bytes
+            );
+            return MemControlResult::Err(SysError::new(EINVAL));
+        }
+        info!("virtio-mem: requesting {} bytes", requested_bytes);
+
+        self.blocks
+            .config
+            .requested_size
+            .store(requested_bytes, Ordering::Relaxed);
+        self.interrupt.signal_config_changed();
+        MemControlResult::Ok
+    }
+
+    fn handle_command(&mut self, command: MemControlCommand) {
+        let result = match command {
+            MemControlCommand::Resize { requested_bytes } => self.resize(requested_bytes),
+            MemControlCommand::Size => MemControlResult::Size {
+                plugged_bytes: self.blocks.config.plugged_size.load(Ordering::Relaxed),
+                requested_bytes: self.blocks.config.requested_size.load(Ordering::Relaxed),
+                region_bytes: self.blocks.region_size(),
+            },
+        };
+        if let Err(e) = self.command_socket.send(&result) {
+            warn!("failed to send virtio-mem command result: {}", e);
+        }
+    }
+
+    fn run(&mut self, queue_evt: Event, kill_evt: Event) {
+        #[derive(PartialEq, PollToken)]
+        enum Token {
+            QueueAvailable,
+            CommandSocket,
+            InterruptResample,
+            Kill,
+        }
+
+        let wait_ctx: WaitContext<Token> = match WaitContext::build_with(&[
+            (&queue_evt, Token::QueueAvailable),
+            (&self.command_socket, Token::CommandSocket),
+            (self.interrupt.get_resample_evt(), Token::InterruptResample),
+            (&kill_evt, Token::Kill),
+        ]) {
+            Ok(pc) => pc,
+            Err(e) => {
+                error!("failed creating WaitContext: {}", e);
+                self.interrupt.signal_needs_reset();
+                return;
+            }
+        };
+
+        'wait: loop {
+            let events = match wait_ctx.wait() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed polling for events: {}", e);
+                    break;
+                }
+            };
+
+            let mut needs_interrupt = false;
+            for event in events.iter().filter(|e| e.is_readable) {
+                match event.token {
+                    Token::QueueAvailable => {
+                        if let Err(e) = queue_evt.read() {
+                            error!("failed reading queue Event: {}", e);
+                            break 'wait;
+                        }
+                        needs_interrupt |= self.process_queue();
+                    }
+                    Token::CommandSocket => {
+                        if let Ok(req) = self.command_socket.recv() {
+                            self.handle_command(req);
+                        }
+                    }
+                    Token::InterruptResample => {
+                        self.interrupt.interrupt_resample();
+                    }
+                    Token::Kill => return,
+                }
+            }
+            for event in events.iter().filter(|e| e.is_hungup) {
+                if event.token == Token::CommandSocket && !event.is_readable {
+                    // If this call fails, the command socket was already removed from the
+                    // WaitContext.
+                    let _ = wait_ctx.delete(&self.command_socket);
+                }
+            }
+            if needs_interrupt {
+                self.queue.trigger_interrupt(&self.mem, &self.interrupt);
+            }
+        }
+
+        // Only errors get here. Resetting the device starts a new worker.
+        self.interrupt.signal_needs_reset();
+    }
+}
+
+/// Virtio device for plugging memory into the guest and unplugging it, a block at a time.
+///
+/// The device owns a region of guest physical memory outside of the guest's boot memory, which
+/// the VM maps to the same memory as `mapping`. The host sets how much of it the guest should
+/// use, and the guest driver plugs and unplugs blocks to match.
+pub struct Mem {
+    kill_evt: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<Worker>>,
+    base_features: u64,
+    addr: u64,
+    region_size: u64,
+    config: Arc<MemConfig>,
+    blocks: Option<MemoryBlocks>,
+    command_socket: Option<MemControlResponseSocket>,
+}
+
+impl Mem {
+    /// Create a new virtio-mem device for the region at `addr`, which is `mapping` in the device.
+    /// The region size must be a multiple of `VIRTIO_MEM_BLOCK_SIZE`.
+    pub fn new(
+        base_features: u64,
+        addr: GuestAddress,
+        mapping: MemoryMapping,
+        command_socket: MemControlResponseSocket,
+    ) -> base::Result<Mem> {
+        let region_size = mapping.size() as u64;
+        if region_size == 0 || region_size % VIRTIO_MEM_BLOCK_SIZE != 0 {
+            return Err(SysError::new(EINVAL));
+        }
+
+        let config = Arc::new(MemConfig::default());
+        Ok(Mem {
+            kill_evt: None,
+            worker_thread: None,
+            base_features,
+            addr: addr.offset(),
+            region_size,
+            config: config.clone(),
+            blocks: Some(MemoryBlocks {
+                mapping,
+                addr: addr.offset(),
+                plugged: vec![false; (region_size / VIRTIO_MEM_BLOCK_SIZE) as usize],
+                config,
+            }),
+            command_socket: Some(command_socket),
+        })
+    }
+}
+
+impl Drop for Mem {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl VirtioDevice for Mem {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
+        if let Some(command_socket) = &self.command_socket {
+            keep_rds.push(command_socket.as_raw_descriptor());
+        }
+        keep_rds
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_MEM
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.base_features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = virtio_mem_config {
+            block_size: VIRTIO_MEM_BLOCK_SIZE.into(),
+            addr: self.addr.into(),
+            region_size: self.region_size.into(),
+            usable_region_size: self.region_size.into(),
+            plugged_size: self.config.plugged_size.load(Ordering::Relaxed).into(),
+            requested_size: self.config.requested_size.load(Ordering::Relaxed).into(),
+            ..Default::default()
+        };
+        copy_config(data, 0, config.as_slice(), offset);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<Event>,
+    ) {
+        if queues.len() != 1 || queue_evts.len() != 1 {
+            return;
+        }
+
+        let queue = queues.remove(0);
+        let queue_evt = queue_evts.remove(0);
+
+        let (blocks, command_socket) = match (self.blocks.take(), self.command_socket.take()) {
+            (Some(blocks), Some(command_socket)) => (blocks, command_socket),
+            _ => return,
+        };
+
+        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed creating kill Event pair: {}", e);
+                return;
+            }
+        };
+        self.kill_evt = Some(self_kill_evt);
+
+        let worker_result =
+            thread::Builder::new()
+                .name("virtio_mem".to_string())
+                .spawn(move || {
+                    let mut worker = Worker {
+                        interrupt,
+                        mem,
+                        queue,
+                        blocks,
+                        command_socket,
+                    };
+                    worker.run(queue_evt, kill_evt);
+                    worker
+                });
+
+        match worker_result {
+            Err(e) => {
+                error!("failed to spawn virtio_mem worker: {}", e);
+            }
+            Ok(join_handle) => {
+                self.worker_thread = Some(join_handle);
+            }
+        }
+    }
+
+    fn reset(&mut self) -> bool {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            if kill_evt.write(1).is_err() {
+                error!("{}: failed to notify the kill event", self.debug_label());
+                return false;
+            }
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            match worker_thread.join() {
+                Err(_) => {
+                    error!("{}: failed to get back resources", self.debug_label());
+                    return false;
+                }
+                Ok(mut worker) => {
+                    // The driver starts over with no memory plugged.
+                    worker.blocks.unplug_all();
+                    self.blocks = Some(worker.blocks);
+                    self.command_socket = Some(worker.command_socket);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl Suspendable for Mem {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::MemoryMappingBuilder;
+
+    const NUM_BLOCKS: usize = 8;
+    const REGION_ADDR: u64 = 0x1_0000_0000;
+
+    fn new_blocks() -> MemoryBlocks {
+        let size = NUM_BLOCKS * VIRTIO_MEM_BLOCK_SIZE as usize;
+        MemoryBlocks {
+            mapping: MemoryMappingBuilder::new(size).build().unwrap(),
+            addr: REGION_ADDR,
+            plugged: vec![false; NUM_BLOCKS],
+            config: Arc::new(MemConfig::default()),
+        }
+    }
+
+    fn block_addr(index: u64) -> u64 {
+        REGION_ADDR + index * VIRTIO_MEM_BLOCK_SIZE
+    }
+
+    fn plugged_size(blocks: &MemoryBlocks) -> u64 {
+        blocks.config.plugged_size.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn plug_up_to_requested_size() {
+        let mut blocks = new_blocks();
+        assert_eq!(blocks.plug(block_addr(0), 1), VIRTIO_MEM_RESP_NACK);
+
+        blocks
+            .config
+            .requested_size
+            .store(3 * VIRTIO_MEM_BLOCK_SIZE, Ordering::Relaxed);
+        assert_eq!(blocks.plug(block_addr(2), 2), VIRTIO_MEM_RESP_ACK);
+        assert_eq!(plugged_size(&blocks), 2 * VIRTIO_MEM_BLOCK_SIZE);
+        assert_eq!(blocks.plug(block_addr(4), 2), VIRTIO_MEM_RESP_NACK);
+        assert_eq!(blocks.plug(block_addr(4), 1), VIRTIO_MEM_RESP_ACK);
+        assert_eq!(plugged_size(&blocks), 3 * VIRTIO_MEM_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn plug_invalid_ranges() {
+        let mut blocks = new_blocks();
+        blocks
+            .config
+            .requested_size
+            .store(blocks.region_size(), Ordering::Relaxed);
+
+        assert_eq!(
+            blocks.plug(block_addr(0) + 0x1000, 1),
+            VIRTIO_MEM_RESP_ERROR
+        );
+        assert_eq!(
+            blocks.plug(REGION_ADDR - VIRTIO_MEM_BLOCK_SIZE, 1),
+            VIRTIO_MEM_RESP_ERROR
+        );
+        assert_eq!(blocks.plug(block_addr(7), 2), VIRTIO_MEM_RESP_ERROR);
+        assert_eq!(blocks.plug(block_addr(0), 0), VIRTIO_MEM_RESP_ERROR);
+
+        // Blocks can't be plugged twice.
+        assert_eq!(blocks.plug(block_addr(1), 1), VIRTIO_MEM_RESP_ACK);
+        assert_eq!(blocks.plug(block_addr(0), 2), VIRTIO_MEM_RESP_ERROR);
+        assert_eq!(plugged_size(&blocks), VIRTIO_MEM_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn unplug_discards_memory() {
+        let mut blocks = new_blocks();
+        blocks
+            .config
+            .requested_size
+            .store(blocks.region_size(), Ordering::Relaxed);
+        assert_eq!(blocks.plug(block_addr(0), 2), VIRTIO_MEM_RESP_ACK);
+        blocks.mapping.write_obj(0x55aa_u16, 0).unwrap();
+
+        // Only plugged blocks can be unplugged.
+        assert_eq!(blocks.unplug(block_addr(1), 2), VIRTIO_MEM_RESP_ERROR);
+        assert_eq!(blocks.unplug(block_addr(0), 2), VIRTIO_MEM_RESP_ACK);
+        assert_eq!(plugged_size(&blocks), 0);
+        assert_eq!(blocks.mapping.read_obj::<u16>(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn block_states() {
+        let mut blocks = new_blocks();
+        blocks
+            .config
+            .requested_size
+            .store(blocks.region_size(), Ordering::Relaxed);
+        assert_eq!(blocks.plug(block_addr(2), 2), VIRTIO_MEM_RESP_ACK);
+
+        let ack = |state| (VIRTIO_MEM_RESP_ACK, state);
+        assert_eq!(
+            blocks.state(block_addr(2), 2),
+            ack(VIRTIO_MEM_STATE_PLUGGED)
+        );
+        assert_eq!(
+            blocks.state(block_addr(0), 2),
+            ack(VIRTIO_MEM_STATE_UNPLUGGED)
+        );
+        assert_eq!(blocks.state(block_addr(1), 2), ack(VIRTIO_MEM_STATE_MIXED));
+        assert_eq!(blocks.state(block_addr(8), 1).0, VIRTIO_MEM_RESP_ERROR);
+
+        assert_eq!(blocks.unplug_all(), VIRTIO_MEM_RESP_ACK);
+        assert_eq!(
+            blocks.state(block_addr(0), 8),
+            ack(VIRTIO_MEM_STATE_UNPLUGGED)
+        );
+        assert_eq!(plugged_size(&blocks), 0);
+    }
+}
@@ -6,15 +6,28 @@
 
 mod balloon;
 mod block;
+mod busy_poll;
 mod console;
+mod cross_domain;
 mod descriptor_utils;
+mod dma;
+#[cfg(feature = "fault-injection")]
+mod fault_injection;
 mod input;
 mod interrupt;
+mod interrupt_coalescing;
+mod io_stats;
+mod io_throttle;
+mod mem;
 mod net;
 mod p9;
+mod packet_capture;
 mod pmem;
 mod queue;
 mod rng;
+mod rx_filter;
+mod rx_hash;
+mod telemetry;
 #[cfg(feature = "tpm")]
 mod tpm;
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
@@ -22,44 +35,66 @@ mod video;
 mod virtio_device;
 mod virtio_pci_common_config;
 mod virtio_pci_device;
+mod vsock;
 mod wl;
+mod zoned;
 
+pub mod bench;
 pub mod fs;
 #[cfg(feature = "gpu")]
 pub mod gpu;
 pub mod resource_bridge;
+pub mod scsi;
 pub mod vhost;
 
 pub use self::balloon::*;
 pub use self::block::*;
+pub use self::busy_poll::*;
 pub use self::console::*;
+pub use self::cross_domain::*;
 pub use self::descriptor_utils::Error as DescriptorError;
 pub use self::descriptor_utils::*;
+pub use self::dma::*;
+#[cfg(feature = "fault-injection")]
+pub use self::fault_injection::*;
 #[cfg(feature = "gpu")]
 pub use self::gpu::*;
 pub use self::input::*;
 pub use self::interrupt::*;
+pub use self::interrupt_coalescing::*;
+pub use self::io_stats::*;
+pub use self::io_throttle::*;
+pub use self::mem::*;
 pub use self::net::*;
 pub use self::p9::*;
+pub use self::packet_capture::*;
 pub use self::pmem::*;
 pub use self::queue::*;
 pub use self::rng::*;
+pub use self::rx_filter::*;
+pub use self::rx_hash::*;
+pub use self::telemetry::*;
 #[cfg(feature = "tpm")]
 pub use self::tpm::*;
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
 pub use self::video::*;
 pub use self::virtio_device::*;
 pub use self::virtio_pci_device::*;
+pub use self::vsock::*;
 pub use self::wl::*;
+pub use self::zoned::*;
 
 use std::cmp;
 use std::convert::TryFrom;
 
+use virtio_sys::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+
 const DEVICE_RESET: u32 = 0x0;
 const DEVICE_ACKNOWLEDGE: u32 = 0x01;
 const DEVICE_DRIVER: u32 = 0x02;
 const DEVICE_DRIVER_OK: u32 = 0x04;
 const DEVICE_FEATURES_OK: u32 = 0x08;
+const DEVICE_NEEDS_RESET: u32 = 0x40;
 const DEVICE_FAILED: u32 = 0x80;
 
 // Types taken from linux/virtio_ids.h
@@ -78,6 +113,7 @@ const TYPE_INPUT: u32 = 18;
 const TYPE_VSOCK: u32 = 19;
 const TYPE_CRYPTO: u32 = 20;
 const TYPE_IOMMU: u32 = 23;
+const TYPE_MEM: u32 = 24;
 const TYPE_FS: u32 = 26;
 const TYPE_PMEM: u32 = 27;
 const TYPE_VIDEO_ENC: u32 = 30;
@@ -86,9 +122,12 @@ const TYPE_VIDEO_DEC: u32 = 31;
 const MAX_VIRTIO_DEVICE_ID: u32 = 63;
 const TYPE_WL: u32 = MAX_VIRTIO_DEVICE_ID;
 const TYPE_TPM: u32 = MAX_VIRTIO_DEVICE_ID - 1;
+const TYPE_FAULT_INJECTION: u32 = MAX_VIRTIO_DEVICE_ID - 2;
+const TYPE_CROSS_DOMAIN: u32 = MAX_VIRTIO_DEVICE_ID - 3;
 
 const VIRTIO_F_VERSION_1: u32 = 32;
 const VIRTIO_F_ACCESS_PLATFORM: u32 = 33;
+const VIRTIO_F_RING_PACKED: u32 = 34;
 
 const INTERRUPT_STATUS_USED_RING: u32 = 0x1;
 const INTERRUPT_STATUS_CONFIG_CHANGED: u32 = 0x2;
@@ -117,10 +156,13 @@ pub fn type_to_str(type_: u32) -> Option<&'static str> {
         TYPE_VSOCK => "vsock",
         TYPE_CRYPTO => "crypto",
         TYPE_IOMMU => "iommu",
+        TYPE_MEM => "mem",
         TYPE_FS => "fs",
         TYPE_PMEM => "pmem",
         TYPE_WL => "wl",
         TYPE_TPM => "tpm",
+        TYPE_FAULT_INJECTION => "fault-injection",
+        TYPE_CROSS_DOMAIN => "cross-domain",
         TYPE_VIDEO_DEC => "video-decoder",
         TYPE_VIDEO_ENC => "video-encoder",
         _ => return None,
@@ -152,8 +194,11 @@ pub fn copy_config(dst: &mut [u8], dst_offset: u64, src: &[u8], src_offset: u64)
 }
 
 /// Returns the set of reserved base features common to all virtio devices.
+///
+/// Every device's queues honor event indices, so the driver only kicks a queue the device is
+/// waiting on and the device only interrupts when the driver asked for it.
 pub fn base_features(protected_vm: bool) -> u64 {
-    let mut features: u64 = 1 << VIRTIO_F_VERSION_1;
+    let mut features: u64 = 1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_RING_F_EVENT_IDX;
 
     if protected_vm {
         features |= 1 << VIRTIO_F_ACCESS_PLATFORM;
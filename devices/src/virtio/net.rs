@@ -2,40 +2,84 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::cell::RefCell;
+use std::cmp::{max, min};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::fmt::{self, Display};
+use std::fs::File;
+use std::future::Future;
 use std::io::{self, Write};
 use std::mem;
 use std::net::Ipv4Addr;
 use std::os::raw::c_uint;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::pin::Pin;
 use std::result;
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 use base::Error as SysError;
-use base::{error, warn, AsRawDescriptor, Event, EventType, PollToken, RawDescriptor, WaitContext};
-use data_model::{DataInit, Le16, Le64};
+use base::{
+    add_fd_flags, clear_fd_flags, error, trace_event, warn, AsRawDescriptor, Event, EventType,
+    FromRawDescriptor, PollToken, RawDescriptor, Timer, WaitContext,
+};
+use cros_async::{async_from, AsyncError, EventAsync, IoPriority, IoSourceExt, TimerAsync};
+use data_model::{DataInit, Le16, Le32, Le64};
+use futures::future::{select, select_all, Either};
+use futures::stream::FuturesOrdered;
+use futures::{pin_mut, FutureExt, StreamExt};
+use msg_socket::{MsgError, MsgReceiver, MsgSender};
 use net_util::{Error as TapError, MacAddress, TapT};
 use virtio_sys::virtio_net;
 use virtio_sys::virtio_net::{
     virtio_net_hdr_v1, VIRTIO_NET_CTRL_GUEST_OFFLOADS, VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET,
-    VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_ERR, VIRTIO_NET_OK,
+    VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET, VIRTIO_NET_CTRL_MAC_TABLE_SET,
+    VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_HASH_CONFIG, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET,
+    VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_ALLMULTI, VIRTIO_NET_CTRL_RX_PROMISC,
+    VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_ADD, VIRTIO_NET_CTRL_VLAN_DEL, VIRTIO_NET_ERR,
+    VIRTIO_NET_OK,
 };
+use vm_control::{NetControlCommand, NetControlResponseSocket, NetControlResult};
 use vm_memory::GuestMemory;
 
 use super::{
-    copy_config, DescriptorError, Interrupt, Queue, Reader, VirtioDevice, Writer, TYPE_NET,
+    copy_config, CaptureDirection, CoalescingInterval, DescriptorChain, DescriptorError, Interrupt,
+    InterruptCoalescer, PacketCapture, Queue, Reader, RxFilter, RxHash, VirtioDevice, Writer,
+    ETH_ALEN, RX_FILTER_HEADER_LEN, RX_HASH_HEADER_LEN, RX_HASH_MAX_KEY_LEN,
+    RX_HASH_SUPPORTED_TYPES, TYPE_NET, VIRTIO_F_RING_PACKED,
 };
+use crate::suspendable;
+use crate::{Suspendable, SuspendableError};
 
 const QUEUE_SIZE: u16 = 256;
+// Maximum number of descriptor chains per queue with a tap read or write in flight when the worker
+// runs on io_uring.
+const MAX_IN_FLIGHT: usize = 16;
 
 #[derive(Debug)]
 pub enum NetError {
+    /// Arming the interrupt coalescing timer failed.
+    ArmTimer(SysError),
+    /// Creating an event failed.
+    CreateEvent(SysError),
     /// Creating kill event failed.
     CreateKillEvent(SysError),
+    /// Creating the interrupt coalescing timer failed.
+    CreateTimer(SysError),
     /// Creating WaitContext failed.
     CreateWaitContext(SysError),
     /// Cloning kill event failed.
     CloneKillEvent(SysError),
+    /// Cloning an event for the async worker failed.
+    CloneEvent(SysError),
+    /// Duplicating the tap descriptor failed.
+    CloneTap(SysError),
+    /// Duplicating the interrupt coalescing timer descriptor failed.
+    CloneTimer(SysError),
+    /// Creating an async source for the tap or an event failed.
+    CreateAsyncSource(AsyncError),
     /// Descriptor chain was invalid.
     DescriptorChain(DescriptorError),
     /// Removing read event from the tap fd events failed.
@@ -48,8 +92,18 @@ pub enum NetError {
     ReadCtrlData(io::Error),
     /// Error reading header from control queue.
     ReadCtrlHeader(io::Error),
+    /// Error receiving a request on the control socket.
+    ReadControl(MsgError),
+    /// Error reading an event in the async worker.
+    ReadEvent(AsyncError),
+    /// Error reading a frame from the tap in the async worker.
+    ReadTap(AsyncError),
+    /// Running the async worker failed.
+    RunExecutor(cros_async::Error),
     /// There are no more available descriptors to receive into.
     RxDescriptorsExhausted,
+    /// Writing the header of a packet capture failed.
+    StartCapture(io::Error),
     /// Open tap device failed.
     TapOpen(TapError),
     /// Setting tap IP failed.
@@ -60,6 +114,10 @@ pub enum NetError {
     TapSetMacAddress(TapError),
     /// Setting tap interface offload flags failed.
     TapSetOffload(TapError),
+    /// Switching the tap to blocking mode failed.
+    TapSetBlocking(SysError),
+    /// Switching the tap to non-blocking mode failed.
+    TapSetNonBlocking(SysError),
     /// Setting vnet header size failed.
     TapSetVnetHdrSize(TapError),
     /// Enabling tap interface failed.
@@ -68,6 +126,8 @@ pub enum NetError {
     TapValidate(String),
     /// Failed writing an ack in response to a control message.
     WriteAck(io::Error),
+    /// Error sending a response on the control socket.
+    WriteControl(MsgError),
     /// Writing to a buffer in the guest failed.
     WriteBuffer(io::Error),
 }
@@ -77,25 +137,40 @@ impl Display for NetError {
         use self::NetError::*;
 
         match self {
+            ArmTimer(e) => write!(f, "failed to arm interrupt coalescing timer: {}", e),
+            CreateEvent(e) => write!(f, "failed to create event: {}", e),
             CreateKillEvent(e) => write!(f, "failed to create kill event: {}", e),
+            CreateTimer(e) => write!(f, "failed to create interrupt coalescing timer: {}", e),
             CreateWaitContext(e) => write!(f, "failed to create wait context: {}", e),
             CloneKillEvent(e) => write!(f, "failed to clone kill event: {}", e),
+            CloneEvent(e) => write!(f, "failed to clone event: {}", e),
+            CloneTap(e) => write!(f, "failed to duplicate tap descriptor: {}", e),
+            CloneTimer(e) => write!(f, "failed to duplicate timer descriptor: {}", e),
+            CreateAsyncSource(e) => write!(f, "failed to create async source: {}", e),
             DescriptorChain(e) => write!(f, "failed to valildate descriptor chain: {}", e),
             WaitContextDisableTap(e) => write!(f, "failed to disable EPOLLIN on tap fd: {}", e),
             WaitContextEnableTap(e) => write!(f, "failed to enable EPOLLIN on tap fd: {}", e),
             WaitError(e) => write!(f, "error while waiting for events: {}", e),
             ReadCtrlData(e) => write!(f, "failed to read control message data: {}", e),
             ReadCtrlHeader(e) => write!(f, "failed to read control message header: {}", e),
+            ReadControl(e) => write!(f, "failed to receive control request: {}", e),
+            ReadEvent(e) => write!(f, "failed to read event: {}", e),
+            ReadTap(e) => write!(f, "failed to read frame from tap: {}", e),
+            RunExecutor(e) => write!(f, "failed to run async executor: {}", e),
             RxDescriptorsExhausted => write!(f, "no rx descriptors available"),
+            StartCapture(e) => write!(f, "failed to start packet capture: {}", e),
             TapOpen(e) => write!(f, "failed to open tap device: {}", e),
             TapSetIp(e) => write!(f, "failed to set tap IP: {}", e),
             TapSetNetmask(e) => write!(f, "failed to set tap netmask: {}", e),
             TapSetMacAddress(e) => write!(f, "failed to set tap mac address: {}", e),
             TapSetOffload(e) => write!(f, "failed to set tap interface offload flags: {}", e),
+            TapSetBlocking(e) => write!(f, "failed to set tap to blocking mode: {}", e),
+            TapSetNonBlocking(e) => write!(f, "failed to set tap to non-blocking mode: {}", e),
             TapSetVnetHdrSize(e) => write!(f, "failed to set vnet header size: {}", e),
             TapEnable(e) => write!(f, "failed to enable tap interface: {}", e),
             TapValidate(s) => write!(f, "failed to validate tap interface: {}", s),
             WriteAck(e) => write!(f, "failed to write control message ack: {}", e),
+            WriteControl(e) => write!(f, "failed to send control response: {}", e),
             WriteBuffer(e) => write!(f, "failed to write to guest buffer: {}", e),
         }
     }
@@ -111,11 +186,36 @@ pub struct virtio_net_ctrl_hdr {
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for virtio_net_ctrl_hdr {}
 
+// The features that say which offloads the driver can receive frames with, which it may change
+// later with VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET.
+const GUEST_OFFLOAD_FEATURES: u64 = 1 << virtio_net::VIRTIO_NET_F_GUEST_CSUM
+    | 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO4
+    | 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO6
+    | 1 << virtio_net::VIRTIO_NET_F_GUEST_ECN
+    | 1 << virtio_net::VIRTIO_NET_F_GUEST_UFO;
+
+// Offset of `num_buffers` in `virtio_net_hdr_v1`.
+const NUM_BUFFERS_OFFSET: usize = 10;
+// Frames start with a `virtio_net_hdr_v1_hash` instead of a `virtio_net_hdr_v1` once
+// VIRTIO_NET_F_HASH_REPORT is negotiated. It adds `hash_value`, `hash_report` and padding.
+const HASH_HDR_LEN: usize = 20;
+const HASH_VALUE_OFFSET: usize = 12;
+
+// Returns the length of the virtio-net header of every frame with the negotiated `features`.
+fn vnet_hdr_len(features: u64) -> usize {
+    if features & 1 << virtio_net::VIRTIO_NET_F_HASH_REPORT != 0 {
+        HASH_HDR_LEN
+    } else {
+        mem::size_of::<virtio_net_hdr_v1>()
+    }
+}
+
 fn virtio_features_to_tap_offload(features: u64) -> c_uint {
-    let mut tap_offloads: c_uint = 0;
-    if features & (1 << virtio_net::VIRTIO_NET_F_GUEST_CSUM) != 0 {
-        tap_offloads |= net_sys::TUN_F_CSUM;
+    // The tap rejects segmentation offloads without checksum offload, and so does the spec.
+    if features & (1 << virtio_net::VIRTIO_NET_F_GUEST_CSUM) == 0 {
+        return 0;
     }
+    let mut tap_offloads = net_sys::TUN_F_CSUM;
     if features & (1 << virtio_net::VIRTIO_NET_F_GUEST_TSO4) != 0 {
         tap_offloads |= net_sys::TUN_F_TSO4;
     }
@@ -139,11 +239,639 @@ struct VirtioNetConfig {
     status: Le16,
     max_vq_pairs: Le16,
     mtu: Le16,
+    speed: Le32,
+    duplex: u8,
+    rss_max_key_size: u8,
+    // Only meant for VIRTIO_NET_F_RSS, which isn't offered, but some Linux drivers size the
+    // indirection table of their VIRTIO_NET_CTRL_MQ_HASH_CONFIG commands with it.
+    rss_max_indirection_table_length: Le16,
+    supported_hash_types: Le32,
 }
 
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for VirtioNetConfig {}
 
+const SPEED_UNKNOWN: u32 = 0xffff_ffff;
+const DUPLEX_HALF: u8 = 0;
+const DUPLEX_FULL: u8 = 1;
+const DUPLEX_UNKNOWN: u8 = 0xff;
+
+/// Duplex mode of a network link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Duplex {
+    Half,
+    Full,
+}
+
+/// Properties of its link a virtio-net device advertises to the guest. Those left unset aren't
+/// advertised, or are reported as unknown.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NetLink {
+    /// Largest packet the guest may send, without the ethernet header.
+    pub mtu: Option<u16>,
+    /// Speed in Mbit/s.
+    pub speed: Option<u32>,
+    pub duplex: Option<Duplex>,
+}
+
+impl NetLink {
+    fn features(&self) -> u64 {
+        let mut features = 0;
+        if self.mtu.is_some() {
+            features |= 1 << virtio_net::VIRTIO_NET_F_MTU;
+        }
+        if self.speed.is_some() || self.duplex.is_some() {
+            features |= 1 << virtio_net::VIRTIO_NET_F_SPEED_DUPLEX;
+        }
+        features
+    }
+
+    fn apply(&self, config: &mut VirtioNetConfig) {
+        config.mtu = Le16::from(self.mtu.unwrap_or(0));
+        config.speed = Le32::from(self.speed.unwrap_or(SPEED_UNKNOWN));
+        config.duplex = match self.duplex {
+            Some(Duplex::Half) => DUPLEX_HALF,
+            Some(Duplex::Full) => DUPLEX_FULL,
+            None => DUPLEX_UNKNOWN,
+        };
+    }
+}
+
+// Handles to the taps of every queue pair of a multiqueue device, used by the worker serving the
+// control queue to attach the queues of the pairs the driver uses and detach the rest, so that the
+// tap interface only steers flows to queues the driver posts receive buffers on.
+struct QueuePairs<T: TapT> {
+    taps: Vec<T>,
+    attached: Vec<bool>,
+}
+
+impl<T> QueuePairs<T>
+where
+    T: TapT,
+{
+    fn new(taps: &[T]) -> Result<QueuePairs<T>, NetError> {
+        let taps = taps
+            .iter()
+            .map(|tap| {
+                tap.try_clone()
+                    .map_err(|e| NetError::CloneTap(e.sys_error()))
+            })
+            .collect::<Result<Vec<T>, NetError>>()?;
+        // Queues are attached to the interface when they are created.
+        let attached = vec![true; taps.len()];
+        Ok(QueuePairs { taps, attached })
+    }
+
+    fn max_pairs(&self) -> u16 {
+        self.taps.len() as u16
+    }
+
+    // Attaches the queues of the first `pairs` queue pairs and detaches the others.
+    fn set_active(&mut self, pairs: u16) -> Result<(), TapError> {
+        for (i, tap) in self.taps.iter().enumerate() {
+            let enabled = i < pairs as usize;
+            if self.attached[i] != enabled {
+                tap.set_queue_enabled(enabled)?;
+                self.attached[i] = enabled;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Reads a `virtio_net_ctrl_mac` table. Returns None if it claims more entries than the request
+// holds.
+fn read_mac_table(reader: &mut Reader) -> Result<Option<Vec<[u8; ETH_ALEN]>>, NetError> {
+    let entries: Le32 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+    let entries = entries.to_native() as usize;
+    if entries > reader.available_bytes() / ETH_ALEN {
+        return Ok(None);
+    }
+    (0..entries)
+        .map(|_| reader.read_obj().map_err(NetError::ReadCtrlData))
+        .collect::<Result<Vec<_>, NetError>>()
+        .map(Some)
+}
+
+// Applies a VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_MAC or VIRTIO_NET_CTRL_VLAN command to
+// `rx_filter`. Returns whether the command was valid for the negotiated features.
+fn process_ctrl_rx_filter(
+    ctrl_hdr: virtio_net_ctrl_hdr,
+    reader: &mut Reader,
+    acked_features: u64,
+    rx_filter: &RxFilter,
+) -> Result<bool, NetError> {
+    let has_feature = |feature: c_uint| acked_features & 1 << feature != 0;
+    match (ctrl_hdr.class as c_uint, ctrl_hdr.cmd as c_uint) {
+        (VIRTIO_NET_CTRL_RX, cmd) if has_feature(virtio_net::VIRTIO_NET_F_CTRL_RX) => {
+            let on: u8 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+            match cmd {
+                VIRTIO_NET_CTRL_RX_PROMISC => rx_filter.set_promiscuous(on != 0),
+                VIRTIO_NET_CTRL_RX_ALLMULTI => rx_filter.set_all_multicast(on != 0),
+                _ => return Ok(false),
+            }
+        }
+        (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET)
+            if has_feature(virtio_net::VIRTIO_NET_F_CTRL_RX) =>
+        {
+            let unicast = read_mac_table(reader)?;
+            let multicast = read_mac_table(reader)?;
+            match (unicast, multicast) {
+                (Some(unicast), Some(multicast)) => rx_filter.set_mac_table(&unicast, &multicast),
+                _ => return Ok(false),
+            }
+        }
+        (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET)
+            if has_feature(virtio_net::VIRTIO_NET_F_CTRL_MAC_ADDR) =>
+        {
+            let mac: [u8; ETH_ALEN] = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+            rx_filter.set_mac(mac);
+        }
+        (VIRTIO_NET_CTRL_VLAN, cmd) => {
+            let vid: Le16 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+            // Both fail if VIRTIO_NET_F_CTRL_VLAN wasn't negotiated.
+            return Ok(match cmd {
+                VIRTIO_NET_CTRL_VLAN_ADD => rx_filter.add_vlan(vid.to_native()),
+                VIRTIO_NET_CTRL_VLAN_DEL => rx_filter.del_vlan(vid.to_native()),
+                _ => false,
+            });
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+// Reads the start of what was received into the writable buffers of `desc_chain` into `buf`.
+// Returns the number of bytes read.
+fn read_received(mem: &GuestMemory, desc_chain: DescriptorChain, buf: &mut [u8]) -> usize {
+    let mut read = 0;
+    for desc in desc_chain.into_iter().writable() {
+        let len = min(desc.len as usize, buf.len() - read);
+        match mem.read_at_addr(&mut buf[read..read + len], desc.addr) {
+            Ok(count) => read += count,
+            Err(_) => break,
+        }
+        if read == buf.len() {
+            break;
+        }
+    }
+    read
+}
+
+// Returns whether `rx_filter` lets through the frame of `len` bytes, including its virtio-net
+// header of `hdr_len` bytes, that was just received into `desc_chain`.
+fn rx_frame_accepted(
+    mem: &GuestMemory,
+    desc_chain: DescriptorChain,
+    len: usize,
+    hdr_len: usize,
+    rx_filter: &RxFilter,
+) -> bool {
+    if rx_filter.is_promiscuous() {
+        return true;
+    }
+    let mut head = [0u8; HASH_HDR_LEN + RX_FILTER_HEADER_LEN];
+    let read = read_received(mem, desc_chain, &mut head[..hdr_len + RX_FILTER_HEADER_LEN]).min(len);
+    if read <= hdr_len {
+        return true;
+    }
+    rx_filter.accepts(&head[hdr_len..read])
+}
+
+// Adds the frame of `len` bytes, including its virtio-net header of `hdr_len` bytes, that was just
+// received into `desc_chain` to `capture`.
+fn capture_received(
+    mem: &GuestMemory,
+    desc_chain: DescriptorChain,
+    len: usize,
+    hdr_len: usize,
+    capture: &PacketCapture,
+) {
+    if !capture.is_active() {
+        return;
+    }
+    let mut frame = vec![0u8; len];
+    let read = read_received(mem, desc_chain, &mut frame);
+    if read > hdr_len {
+        capture.write_frame(&frame[hdr_len..read], CaptureDirection::Inbound);
+    }
+}
+
+// Adds the frame the driver is sending in `desc_chain`, after a virtio-net header of `hdr_len`
+// bytes, to `capture`.
+fn capture_sent(
+    mem: &GuestMemory,
+    desc_chain: DescriptorChain,
+    hdr_len: usize,
+    capture: &PacketCapture,
+) {
+    if !capture.is_active() {
+        return;
+    }
+    if let Ok(mut reader) = Reader::new(mem.clone(), desc_chain) {
+        reader.consume(hdr_len);
+        if let Ok(frame) = reader.read_remaining_objs::<u8>() {
+            capture.write_frame(&frame, CaptureDirection::Outbound);
+        }
+    }
+}
+
+// The tap leaves `num_buffers` in the virtio-net header of a received frame alone. Without
+// VIRTIO_NET_F_MRG_RXBUF every frame takes up exactly one buffer, so set it to 1.
+fn set_num_buffers(mem: &GuestMemory, desc_chain: DescriptorChain) {
+    if let Ok(mut writer) = Writer::new(mem.clone(), desc_chain) {
+        writer.consume_bytes(NUM_BUFFERS_OFFSET);
+        if let Err(e) = writer.write_obj(Le16::from(1)) {
+            warn!("net: rx: failed to set num_buffers: {}", e);
+        }
+    }
+}
+
+// The tap leaves the hash fields of a `virtio_net_hdr_v1_hash` alone as well. Fills them in for the
+// frame of `len` bytes that was just received into `desc_chain`.
+fn set_rx_hash(mem: &GuestMemory, desc_chain: DescriptorChain, len: usize, rx_hash: &RxHash) {
+    let mut head = [0u8; HASH_HDR_LEN + RX_HASH_HEADER_LEN];
+    let read = read_received(mem, desc_chain.clone(), &mut head).min(len);
+    let (value, report) = rx_hash.hash(&head[min(HASH_HDR_LEN, read)..read]);
+    let mut fields = [0u8; HASH_HDR_LEN - HASH_VALUE_OFFSET];
+    fields[..4].copy_from_slice(&value.to_le_bytes());
+    fields[4..6].copy_from_slice(&report.to_le_bytes());
+    if let Ok(mut writer) = Writer::new(mem.clone(), desc_chain) {
+        writer.consume_bytes(HASH_VALUE_OFFSET);
+        if let Err(e) = writer.write_all(&fields) {
+            warn!("net: rx: failed to set hash: {}", e);
+        }
+    }
+}
+
+// Reads a `virtio_net_hash_config` and applies it to `rx_hash`. Returns whether it was valid.
+fn process_ctrl_hash_config(reader: &mut Reader, rx_hash: &RxHash) -> Result<bool, NetError> {
+    let hash_types: Le32 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+    // Reserved, where VIRTIO_NET_CTRL_MQ_RSS_CONFIG has its indirection table.
+    reader.consume(4 * mem::size_of::<Le16>());
+    let key_len: u8 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+    if key_len as usize > reader.available_bytes() {
+        return Ok(false);
+    }
+    let key = (0..key_len)
+        .map(|_| reader.read_obj().map_err(NetError::ReadCtrlData))
+        .collect::<Result<Vec<u8>, NetError>>()?;
+    Ok(rx_hash.configure(hash_types.to_native(), &key))
+}
+
+fn process_ctrl<T: TapT>(
+    interrupt: &Interrupt,
+    mem: &GuestMemory,
+    ctrl_queue: &mut Queue,
+    tap: &T,
+    acked_features: u64,
+    mut queue_pairs: Option<&mut QueuePairs<T>>,
+    rx_filter: &RxFilter,
+    rx_hash: Option<&RxHash>,
+) -> Result<(), NetError> {
+    while let Some(desc_chain) = ctrl_queue.pop(mem) {
+        let index = desc_chain.index;
+
+        let mut reader =
+            Reader::new(mem.clone(), desc_chain.clone()).map_err(NetError::DescriptorChain)?;
+        let mut writer = Writer::new(mem.clone(), desc_chain).map_err(NetError::DescriptorChain)?;
+        let ctrl_hdr: virtio_net_ctrl_hdr = reader.read_obj().map_err(NetError::ReadCtrlHeader)?;
+
+        match ctrl_hdr.class as c_uint {
+            VIRTIO_NET_CTRL_GUEST_OFFLOADS => {
+                if ctrl_hdr.cmd != VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET as u8 {
+                    error!(
+                        "invalid cmd for VIRTIO_NET_CTRL_GUEST_OFFLOADS: {}",
+                        ctrl_hdr.cmd
+                    );
+                    let ack = VIRTIO_NET_ERR as u8;
+                    writer.write_all(&[ack]).map_err(NetError::WriteAck)?;
+                    ctrl_queue.add_used(mem, index, 0);
+                    continue;
+                }
+                let offloads: Le64 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+                let offloads: u64 = offloads.into();
+                // Only offloads negotiated as features may be turned on.
+                let ack = if offloads & !(acked_features & GUEST_OFFLOAD_FEATURES) != 0 {
+                    error!("net: guest offloads {:#x} weren't negotiated", offloads);
+                    VIRTIO_NET_ERR as u8
+                } else {
+                    match tap.set_offload(virtio_features_to_tap_offload(offloads)) {
+                        Ok(()) => VIRTIO_NET_OK as u8,
+                        Err(e) => {
+                            error!("net: failed to set tap offload flags: {}", e);
+                            VIRTIO_NET_ERR as u8
+                        }
+                    }
+                };
+                writer.write_all(&[ack]).map_err(NetError::WriteAck)?;
+            }
+            VIRTIO_NET_CTRL_MQ => {
+                if ctrl_hdr.cmd == VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET as u8 {
+                    let pairs: Le16 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+                    let pairs = pairs.to_native();
+                    let queue_pairs = match queue_pairs.as_deref_mut() {
+                        Some(q) if acked_features & 1 << virtio_net::VIRTIO_NET_F_MQ != 0 => q,
+                        _ => {
+                            error!("net: VQ_PAIRS_SET without multiqueue support");
+                            let ack = VIRTIO_NET_ERR as u8;
+                            writer.write_all(&[ack]).map_err(NetError::WriteAck)?;
+                            ctrl_queue.add_used(mem, index, 0);
+                            continue;
+                        }
+                    };
+                    let max_pairs = queue_pairs.max_pairs();
+                    if pairs < 1 || pairs > max_pairs {
+                        error!(
+                            "net: invalid VQ_PAIRS_SET cmd, driver request pairs: {}, device vq pairs: {}",
+                            pairs, max_pairs
+                        );
+                        let ack = VIRTIO_NET_ERR as u8;
+                        writer.write_all(&[ack]).map_err(NetError::WriteAck)?;
+                        ctrl_queue.add_used(mem, index, 0);
+                        continue;
+                    }
+                    let ack = match queue_pairs.set_active(pairs) {
+                        Ok(()) => VIRTIO_NET_OK as u8,
+                        Err(e) => {
+                            error!("net: failed to use {} queue pairs: {}", pairs, e);
+                            VIRTIO_NET_ERR as u8
+                        }
+                    };
+                    writer.write_all(&[ack]).map_err(NetError::WriteAck)?;
+                } else if ctrl_hdr.cmd == VIRTIO_NET_CTRL_MQ_HASH_CONFIG as u8 {
+                    // Only accepted if VIRTIO_NET_F_HASH_REPORT was negotiated.
+                    let ack = match rx_hash {
+                        Some(rx_hash) if process_ctrl_hash_config(&mut reader, rx_hash)? => {
+                            VIRTIO_NET_OK as u8
+                        }
+                        _ => {
+                            error!("net: invalid HASH_CONFIG cmd");
+                            VIRTIO_NET_ERR as u8
+                        }
+                    };
+                    writer.write_all(&[ack]).map_err(NetError::WriteAck)?;
+                }
+            }
+            VIRTIO_NET_CTRL_RX | VIRTIO_NET_CTRL_MAC | VIRTIO_NET_CTRL_VLAN => {
+                let ack =
+                    if process_ctrl_rx_filter(ctrl_hdr, &mut reader, acked_features, rx_filter)? {
+                        VIRTIO_NET_OK as u8
+                    } else {
+                        error!(
+                            "net: invalid rx filter cmd {} of class {}",
+                            ctrl_hdr.cmd, ctrl_hdr.class
+                        );
+                        VIRTIO_NET_ERR as u8
+                    };
+                writer.write_all(&[ack]).map_err(NetError::WriteAck)?;
+            }
+            _ => warn!(
+                "unimplemented class for VIRTIO_NET_CTRL_GUEST_OFFLOADS: {}",
+                ctrl_hdr.class
+            ),
+        }
+
+        ctrl_queue.add_used(mem, index, 0);
+    }
+
+    ctrl_queue.trigger_interrupt(mem, interrupt);
+    Ok(())
+}
+
+fn handle_control_command(
+    coalescer: &InterruptCoalescer,
+    capture: &PacketCapture,
+    command: NetControlCommand,
+) -> NetControlResult {
+    match command {
+        NetControlCommand::SetInterruptCoalescing { min_interval_us } => {
+            coalescer.set_interval(Duration::from_micros(min_interval_us));
+            NetControlResult::Ok
+        }
+        NetControlCommand::StartCapture { file } => match capture.start(file) {
+            Ok(()) => NetControlResult::Ok,
+            Err(e) => {
+                error!("net: failed to start packet capture: {}", e);
+                NetControlResult::Err(SysError::new(e.raw_os_error().unwrap_or(libc::EIO)))
+            }
+        },
+        NetControlCommand::StopCapture => {
+            capture.stop();
+            NetControlResult::Ok
+        }
+    }
+}
+
+// The interrupt coalescer of one queue of the async worker, along with the timer that wakes the
+// worker up to deliver the interrupts it holds back.
+struct AsyncCoalescer<'a> {
+    coalescer: &'a mut InterruptCoalescer,
+    timer: Timer,
+}
+
+impl<'a> AsyncCoalescer<'a> {
+    fn new(coalescer: &'a mut InterruptCoalescer) -> Result<AsyncCoalescer<'a>, NetError> {
+        let mut async_coalescer = AsyncCoalescer {
+            coalescer,
+            timer: Timer::new().map_err(NetError::CreateTimer)?,
+        };
+        // Deliver the interrupt held back when the worker was last stopped, if any.
+        async_coalescer.arm_timer()?;
+        Ok(async_coalescer)
+    }
+
+    // Returns an async handle to the timer, which expires when a held back interrupt is due.
+    fn timer_async(&self) -> Result<TimerAsync, NetError> {
+        // Safe because we own the timer descriptor and check the result.
+        let fd = unsafe { libc::dup(self.timer.as_raw_descriptor()) };
+        if fd < 0 {
+            return Err(NetError::CloneTimer(SysError::last()));
+        }
+        // Safe because `fd` was just duplicated and nothing else owns it.
+        let timer = unsafe { Timer::from_raw_descriptor(fd) };
+        TimerAsync::try_from(timer.0).map_err(NetError::CreateAsyncSource)
+    }
+
+    fn signal(&mut self) -> Result<bool, NetError> {
+        if self.coalescer.signal() {
+            return Ok(true);
+        }
+        self.arm_timer()?;
+        Ok(false)
+    }
+
+    fn take_due(&mut self) -> Result<bool, NetError> {
+        if self.coalescer.take_due() {
+            return Ok(true);
+        }
+        self.arm_timer()?;
+        Ok(false)
+    }
+
+    fn arm_timer(&mut self) -> Result<(), NetError> {
+        if let Some(timeout) = self.coalescer.timeout() {
+            // A zero duration would disarm the timer instead.
+            self.timer
+                .reset(max(timeout, Duration::from_nanos(1)), None)
+                .map_err(NetError::ArmTimer)?;
+        }
+        Ok(())
+    }
+}
+
+// Keeps up to `MAX_IN_FLIGHT` descriptor chains from `queue` in flight, starting a transfer on each
+// with `start`. Chains are returned to the guest in the order they were taken from the queue, and
+// the guest is signaled once for each batch of completed transfers, unless `coalescer` holds the
+// interrupt back. The indices of the chains in flight are kept in `pending`, oldest first.
+async fn process_queue_async<S, F>(
+    interrupt: &Interrupt,
+    mem: &GuestMemory,
+    queue: &RefCell<&mut Queue>,
+    queue_evt: &EventAsync,
+    coalescer: &RefCell<AsyncCoalescer<'_>>,
+    pending: &RefCell<VecDeque<u16>>,
+    mut start: S,
+) -> Result<(), NetError>
+where
+    S: FnMut(DescriptorChain) -> F,
+    F: Future<Output = Result<(u16, u32), NetError>>,
+{
+    let mut in_flight = FuturesOrdered::new();
+    loop {
+        while in_flight.len() < MAX_IN_FLIGHT {
+            let desc_chain = match queue.borrow_mut().pop(mem) {
+                Some(desc_chain) => desc_chain,
+                None => break,
+            };
+            pending.borrow_mut().push_back(desc_chain.index);
+            in_flight.push(start(desc_chain));
+        }
+
+        if in_flight.is_empty() {
+            queue_evt.next_val().await.map_err(NetError::ReadEvent)?;
+            continue;
+        }
+
+        // Wait for the oldest transfer to finish, or for the guest to make more buffers available
+        // while there is room for them.
+        let next_val = queue_evt.next_val();
+        pin_mut!(next_val);
+        let mut completed = match select(in_flight.next(), next_val).await {
+            Either::Left((completed, _)) => completed,
+            Either::Right((res, _)) => {
+                res.map_err(NetError::ReadEvent)?;
+                continue;
+            }
+        };
+
+        let mut queue = queue.borrow_mut();
+        while let Some(result) = completed {
+            let (index, len) = result?;
+            pending.borrow_mut().pop_front();
+            queue.add_used(mem, index, len);
+            completed = in_flight.next().now_or_never().flatten();
+        }
+        if coalescer.borrow_mut().signal()? {
+            queue.trigger_interrupt(mem, interrupt);
+        }
+    }
+}
+
+// Gives the chains whose transfer was dropped back to the guest unused, so that they are not lost
+// to a worker that is started again. A frame being received into one of them is dropped, like one
+// arriving while the worker is stopped.
+fn return_pending(
+    interrupt: &Interrupt,
+    mem: &GuestMemory,
+    queue: &mut Queue,
+    pending: &mut VecDeque<u16>,
+) {
+    if pending.is_empty() {
+        return;
+    }
+    for index in pending.drain(..) {
+        queue.add_used(mem, index, 0);
+    }
+    queue.trigger_interrupt(mem, interrupt);
+}
+
+// Delivers the interrupts for `vector` that `coalescer` held back once they are due.
+async fn flush_coalesced_interrupts(
+    interrupt: &Interrupt,
+    vector: u16,
+    coalescer: &RefCell<AsyncCoalescer<'_>>,
+    timer: &TimerAsync,
+) -> Result<(), NetError> {
+    loop {
+        timer.next_val().await.map_err(NetError::ReadEvent)?;
+        if coalescer.borrow_mut().take_due()? {
+            interrupt.signal_used_queue(vector);
+        }
+    }
+}
+
+async fn handle_control_requests(
+    control_socket: &NetControlResponseSocket,
+    coalescer: &RefCell<AsyncCoalescer<'_>>,
+    capture: &PacketCapture,
+) -> Result<(), NetError> {
+    let mut receiver = control_socket
+        .async_receiver()
+        .map_err(NetError::ReadControl)?;
+    loop {
+        let command = receiver.next().await.map_err(NetError::ReadControl)?;
+        let result = handle_control_command(coalescer.borrow().coalescer, capture, command);
+        control_socket
+            .send(&result)
+            .map_err(NetError::WriteControl)?;
+    }
+}
+
+async fn process_ctrl_async<T: TapT>(
+    interrupt: &Interrupt,
+    mem: &GuestMemory,
+    ctrl_queue: &mut Queue,
+    ctrl_queue_evt: &EventAsync,
+    tap: &T,
+    acked_features: u64,
+    mut queue_pairs: Option<&mut QueuePairs<T>>,
+    rx_filter: &RxFilter,
+    rx_hash: Option<&RxHash>,
+) -> Result<(), NetError> {
+    loop {
+        ctrl_queue_evt
+            .next_val()
+            .await
+            .map_err(NetError::ReadEvent)?;
+        process_ctrl(
+            interrupt,
+            mem,
+            ctrl_queue,
+            tap,
+            acked_features,
+            queue_pairs.as_deref_mut(),
+            rx_filter,
+            rx_hash,
+        )?;
+    }
+}
+
+async fn handle_irq_resample(
+    interrupt: &Interrupt,
+    resample_evt: &EventAsync,
+) -> Result<(), NetError> {
+    loop {
+        resample_evt.next_val().await.map_err(NetError::ReadEvent)?;
+        interrupt.do_interrupt_resample();
+    }
+}
+
+fn async_event(event: &Event) -> Result<EventAsync, NetError> {
+    let event = event.try_clone().map_err(NetError::CloneEvent)?;
+    EventAsync::try_from(event.0).map_err(NetError::CreateAsyncSource)
+}
+
 struct Worker<T: TapT> {
     interrupt: Arc<Interrupt>,
     mem: GuestMemory,
@@ -152,8 +880,15 @@ struct Worker<T: TapT> {
     ctrl_queue: Option<Queue>,
     tap: T,
     acked_features: u64,
-    vq_pairs: u16,
+    queue_pairs: Option<QueuePairs<T>>,
+    rx_filter: RxFilter,
+    // Set if VIRTIO_NET_F_HASH_REPORT was negotiated.
+    rx_hash: Option<RxHash>,
+    capture: PacketCapture,
     kill_evt: Event,
+    rx_coalescer: InterruptCoalescer,
+    tx_coalescer: InterruptCoalescer,
+    control_socket: Option<NetControlResponseSocket>,
 }
 
 impl<T> Worker<T>
@@ -161,6 +896,8 @@ where
     T: TapT,
 {
     fn process_rx(&mut self) -> result::Result<(), NetError> {
+        let _trace = trace_event!(virtio, "net_process_rx");
+        let hdr_len = vnet_hdr_len(self.acked_features);
         let mut needs_interrupt = false;
         let mut exhausted_queue = false;
 
@@ -175,7 +912,7 @@ where
             };
 
             let index = desc_chain.index;
-            let bytes_written = match Writer::new(self.mem.clone(), desc_chain) {
+            let bytes_written = match Writer::new(self.mem.clone(), desc_chain.clone()) {
                 Ok(mut writer) => {
                     match writer.write_from(&mut self.tap, writer.available_bytes()) {
                         Ok(_) => {}
@@ -201,15 +938,40 @@ where
                 }
             };
 
-            if bytes_written > 0 {
+            // A frame the driver filtered out is dropped, and the buffer is used for the next one.
+            if bytes_written > 0
+                && rx_frame_accepted(
+                    &self.mem,
+                    desc_chain.clone(),
+                    bytes_written as usize,
+                    hdr_len,
+                    &self.rx_filter,
+                )
+            {
+                capture_received(
+                    &self.mem,
+                    desc_chain.clone(),
+                    bytes_written as usize,
+                    hdr_len,
+                    &self.capture,
+                );
+                if let Some(rx_hash) = &self.rx_hash {
+                    set_rx_hash(
+                        &self.mem,
+                        desc_chain.clone(),
+                        bytes_written as usize,
+                        rx_hash,
+                    );
+                }
+                set_num_buffers(&self.mem, desc_chain);
                 self.rx_queue.pop_peeked(&self.mem);
                 self.rx_queue.add_used(&self.mem, index, bytes_written);
                 needs_interrupt = true;
             }
         }
 
-        if needs_interrupt {
-            self.interrupt.signal_used_queue(self.rx_queue.vector);
+        if needs_interrupt && self.rx_coalescer.signal() {
+            self.rx_queue.trigger_interrupt(&self.mem, &self.interrupt);
         }
 
         if exhausted_queue {
@@ -220,8 +982,11 @@ where
     }
 
     fn process_tx(&mut self) {
+        let _trace = trace_event!(virtio, "net_process_tx");
+        let hdr_len = vnet_hdr_len(self.acked_features);
         while let Some(desc_chain) = self.tx_queue.pop(&self.mem) {
             let index = desc_chain.index;
+            capture_sent(&self.mem, desc_chain.clone(), hdr_len, &self.capture);
 
             match Reader::new(self.mem.clone(), desc_chain) {
                 Ok(mut reader) => {
@@ -246,7 +1011,9 @@ where
             self.tx_queue.add_used(&self.mem, index, 0);
         }
 
-        self.interrupt.signal_used_queue(self.tx_queue.vector);
+        if self.tx_coalescer.signal() {
+            self.tx_queue.trigger_interrupt(&self.mem, &self.interrupt);
+        }
     }
 
     fn process_ctrl(&mut self) -> Result<(), NetError> {
@@ -254,73 +1021,249 @@ where
             Some(queue) => queue,
             None => return Ok(()),
         };
+        process_ctrl(
+            &self.interrupt,
+            &self.mem,
+            ctrl_queue,
+            &self.tap,
+            self.acked_features,
+            self.queue_pairs.as_mut(),
+            &self.rx_filter,
+            self.rx_hash.as_ref(),
+        )
+    }
 
-        while let Some(desc_chain) = ctrl_queue.pop(&self.mem) {
-            let index = desc_chain.index;
-
-            let mut reader = Reader::new(self.mem.clone(), desc_chain.clone())
-                .map_err(NetError::DescriptorChain)?;
-            let mut writer =
-                Writer::new(self.mem.clone(), desc_chain).map_err(NetError::DescriptorChain)?;
-            let ctrl_hdr: virtio_net_ctrl_hdr =
-                reader.read_obj().map_err(NetError::ReadCtrlHeader)?;
-
-            match ctrl_hdr.class as c_uint {
-                VIRTIO_NET_CTRL_GUEST_OFFLOADS => {
-                    if ctrl_hdr.cmd != VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET as u8 {
-                        error!(
-                            "invalid cmd for VIRTIO_NET_CTRL_GUEST_OFFLOADS: {}",
-                            ctrl_hdr.cmd
-                        );
-                        let ack = VIRTIO_NET_ERR as u8;
-                        writer.write_all(&[ack]).map_err(NetError::WriteAck)?;
-                        ctrl_queue.add_used(&self.mem, index, 0);
-                        continue;
+    // Runs the worker on io_uring, keeping up to `MAX_IN_FLIGHT` tap reads and writes in flight
+    // per queue instead of handling one frame per wakeup.
+    fn run_async(
+        &mut self,
+        rx_queue_evt: &Event,
+        tx_queue_evt: &Event,
+        ctrl_queue_evt: Option<&Event>,
+    ) -> Result<(), NetError> {
+        // Safe because we own the tap descriptor and check the result.
+        let fd = unsafe { libc::dup(self.tap.as_raw_descriptor()) };
+        if fd < 0 {
+            return Err(NetError::CloneTap(SysError::last()));
+        }
+        // Safe because `fd` was just duplicated and nothing else owns it.
+        let tap_file = unsafe { File::from_raw_fd(fd) };
+        // io_uring fails reads on a non-blocking file with EAGAIN instead of waiting for a frame.
+        // Only the synchronous worker relies on the tap being non-blocking, and it is only used
+        // when io_uring isn't available.
+        clear_fd_flags(tap_file.as_raw_fd(), libc::O_NONBLOCK).map_err(NetError::TapSetBlocking)?;
+
+        let interrupt = &*self.interrupt;
+        let mem = &self.mem;
+        let rx_vector = self.rx_queue.vector;
+        let tx_vector = self.tx_queue.vector;
+        let rx_queue = RefCell::new(&mut self.rx_queue);
+        let tx_queue = RefCell::new(&mut self.tx_queue);
+        let rx_pending = RefCell::new(VecDeque::new());
+        let tx_pending = RefCell::new(VecDeque::new());
+        let ctrl_queue = self.ctrl_queue.as_mut();
+        let sync_tap = &self.tap;
+        let kill_evt = &self.kill_evt;
+        let acked_features = self.acked_features;
+        let queue_pairs = self.queue_pairs.as_mut();
+        let rx_filter = &self.rx_filter;
+        let rx_hash = self.rx_hash.as_ref();
+        let hdr_len = vnet_hdr_len(acked_features);
+        let capture = &self.capture;
+        let rx_coalescer = RefCell::new(AsyncCoalescer::new(&mut self.rx_coalescer)?);
+        let tx_coalescer = RefCell::new(AsyncCoalescer::new(&mut self.tx_coalescer)?);
+        let control_socket = self.control_socket.as_ref();
+
+        let fut = async move {
+            let mut tap = async_from(tap_file).map_err(NetError::CreateAsyncSource)?;
+            tap.set_priority(IoPriority::Low);
+            let tap: &dyn IoSourceExt<File> = &*tap;
+            let rx_queue_evt = async_event(rx_queue_evt)?;
+            let tx_queue_evt = async_event(tx_queue_evt)?;
+            let kill_evt = async_event(kill_evt)?;
+            let ctrl_queue_evt = ctrl_queue_evt.map(async_event).transpose()?;
+            let resample_evt = match ctrl_queue_evt {
+                Some(_) => Some(async_event(interrupt.get_resample_evt())?),
+                None => None,
+            };
+            let rx_timer = rx_coalescer.borrow().timer_async()?;
+            let tx_timer = tx_coalescer.borrow().timer_async()?;
+            let rx_coalescer = &rx_coalescer;
+            let tx_coalescer = &tx_coalescer;
+
+            let rx = process_queue_async(
+                interrupt,
+                mem,
+                &rx_queue,
+                &rx_queue_evt,
+                rx_coalescer,
+                &rx_pending,
+                |desc_chain| {
+                    let mem = mem.clone();
+                    async move {
+                        let index = desc_chain.index;
+                        loop {
+                            let bytes_written = match Writer::new(mem.clone(), desc_chain.clone()) {
+                                Ok(mut writer) => {
+                                    let count = writer.available_bytes();
+                                    match writer.write_from_fut(tap, count).await {
+                                        Ok(_) => writer.bytes_written() as u32,
+                                        Err(e) => return Err(NetError::ReadTap(e)),
+                                    }
+                                }
+                                Err(e) => {
+                                    error!("net: failed to create Writer: {}", e);
+                                    0
+                                }
+                            };
+                            // Receive the next frame into the same buffer if the driver filtered
+                            // this one out.
+                            if bytes_written == 0 {
+                                return Ok((index, 0));
+                            }
+                            if rx_frame_accepted(
+                                &mem,
+                                desc_chain.clone(),
+                                bytes_written as usize,
+                                hdr_len,
+                                rx_filter,
+                            ) {
+                                capture_received(
+                                    &mem,
+                                    desc_chain.clone(),
+                                    bytes_written as usize,
+                                    hdr_len,
+                                    capture,
+                                );
+                                if let Some(rx_hash) = rx_hash {
+                                    set_rx_hash(
+                                        &mem,
+                                        desc_chain.clone(),
+                                        bytes_written as usize,
+                                        rx_hash,
+                                    );
+                                }
+                                set_num_buffers(&mem, desc_chain);
+                                return Ok((index, bytes_written));
+                            }
+                        }
                     }
-                    let offloads: Le64 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
-                    let tap_offloads = virtio_features_to_tap_offload(offloads.into());
-                    self.tap
-                        .set_offload(tap_offloads)
-                        .map_err(NetError::TapSetOffload)?;
-                    let ack = VIRTIO_NET_OK as u8;
-                    writer.write_all(&[ack]).map_err(NetError::WriteAck)?;
-                }
-                VIRTIO_NET_CTRL_MQ => {
-                    if ctrl_hdr.cmd == VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET as u8 {
-                        let pairs: Le16 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
-                        // Simple handle it now
-                        if self.acked_features & 1 << virtio_net::VIRTIO_NET_F_MQ == 0
-                            || pairs.to_native() != self.vq_pairs
-                        {
-                            error!("Invalid VQ_PAIRS_SET cmd, driver request pairs: {}, device vq pairs: {}",
-                                   pairs.to_native(), self.vq_pairs);
-                            let ack = VIRTIO_NET_ERR as u8;
-                            writer.write_all(&[ack]).map_err(NetError::WriteAck)?;
-                            ctrl_queue.add_used(&self.mem, index, 0);
-                            continue;
+                },
+            );
+
+            let tx = process_queue_async(
+                interrupt,
+                mem,
+                &tx_queue,
+                &tx_queue_evt,
+                tx_coalescer,
+                &tx_pending,
+                |desc_chain| {
+                    let mem = mem.clone();
+                    async move {
+                        let index = desc_chain.index;
+                        capture_sent(&mem, desc_chain.clone(), hdr_len, capture);
+                        match Reader::new(mem, desc_chain) {
+                            Ok(mut reader) => {
+                                let expected_count = reader.available_bytes();
+                                match reader.read_to_fut(tap, expected_count).await {
+                                    Ok(count) => {
+                                        // Tap writes must be done in one call. If the entire frame
+                                        // was not written, it's an error.
+                                        if count != expected_count {
+                                            error!(
+                                                "net: tx: wrote only {} bytes of {} byte frame",
+                                                count, expected_count
+                                            );
+                                        }
+                                    }
+                                    Err(e) => {
+                                        error!("net: tx: failed to write frame to tap: {}", e)
+                                    }
+                                }
+                            }
+                            Err(e) => error!("net: failed to create Reader: {}", e),
                         }
-                        let ack = VIRTIO_NET_OK as u8;
-                        writer.write_all(&[ack]).map_err(NetError::WriteAck)?;
+                        Ok((index, 0))
                     }
-                }
-                _ => warn!(
-                    "unimplemented class for VIRTIO_NET_CTRL_GUEST_OFFLOADS: {}",
-                    ctrl_hdr.class
-                ),
+                },
+            );
+
+            let kill = async {
+                kill_evt
+                    .next_val()
+                    .await
+                    .map(|_| ())
+                    .map_err(NetError::ReadEvent)
+            };
+
+            let mut futures: Vec<Pin<Box<dyn Future<Output = Result<(), NetError>> + '_>>> = vec![
+                Box::pin(rx),
+                Box::pin(tx),
+                Box::pin(kill),
+                Box::pin(flush_coalesced_interrupts(
+                    interrupt,
+                    rx_vector,
+                    rx_coalescer,
+                    &rx_timer,
+                )),
+                Box::pin(flush_coalesced_interrupts(
+                    interrupt,
+                    tx_vector,
+                    tx_coalescer,
+                    &tx_timer,
+                )),
+            ];
+            if let (Some(ctrl_queue), Some(ctrl_queue_evt), Some(resample_evt)) =
+                (ctrl_queue, &ctrl_queue_evt, &resample_evt)
+            {
+                futures.push(Box::pin(process_ctrl_async(
+                    interrupt,
+                    mem,
+                    ctrl_queue,
+                    ctrl_queue_evt,
+                    sync_tap,
+                    acked_features,
+                    queue_pairs,
+                    rx_filter,
+                    rx_hash,
+                )));
+                // Let the control queue's worker handle interrupt resampling also.
+                futures.push(Box::pin(handle_irq_resample(interrupt, resample_evt)));
+            }
+            if let Some(control_socket) = control_socket {
+                futures.push(Box::pin(handle_control_requests(
+                    control_socket,
+                    rx_coalescer,
+                    capture,
+                )));
             }
 
-            ctrl_queue.add_used(&self.mem, index, 0);
-        }
+            let (result, _, _) = select_all(futures).await;
+            // The transfers still in flight were dropped along with the other futures.
+            return_pending(
+                interrupt,
+                mem,
+                &mut rx_queue.borrow_mut(),
+                &mut rx_pending.borrow_mut(),
+            );
+            return_pending(
+                interrupt,
+                mem,
+                &mut tx_queue.borrow_mut(),
+                &mut tx_pending.borrow_mut(),
+            );
+            result
+        };
 
-        self.interrupt.signal_used_queue(ctrl_queue.vector);
-        Ok(())
+        cros_async::run_one_uring(Box::pin(fut)).map_err(NetError::RunExecutor)?
     }
 
     fn run(
         &mut self,
-        rx_queue_evt: Event,
-        tx_queue_evt: Event,
-        ctrl_queue_evt: Option<Event>,
+        rx_queue_evt: &Event,
+        tx_queue_evt: &Event,
+        ctrl_queue_evt: Option<&Event>,
     ) -> Result<(), NetError> {
         #[derive(PollToken)]
         enum Token {
@@ -334,19 +1277,21 @@ where
             CtrlQueue,
             // Check if any interrupts need to be re-asserted.
             InterruptResample,
+            // A request was made on the control socket.
+            ControlRequest,
             // crosvm has requested the device to shut down.
             Kill,
         }
 
         let wait_ctx: WaitContext<Token> = WaitContext::build_with(&[
             (&self.tap, Token::RxTap),
-            (&rx_queue_evt, Token::RxQueue),
-            (&tx_queue_evt, Token::TxQueue),
+            (rx_queue_evt, Token::RxQueue),
+            (tx_queue_evt, Token::TxQueue),
             (&self.kill_evt, Token::Kill),
         ])
         .map_err(NetError::CreateWaitContext)?;
 
-        if let Some(ctrl_evt) = &ctrl_queue_evt {
+        if let Some(ctrl_evt) = ctrl_queue_evt {
             wait_ctx
                 .add(ctrl_evt, Token::CtrlQueue)
                 .map_err(NetError::CreateWaitContext)?;
@@ -355,10 +1300,24 @@ where
                 .add(self.interrupt.get_resample_evt(), Token::InterruptResample)
                 .map_err(NetError::CreateWaitContext)?;
         }
+        if let Some(control_socket) = &self.control_socket {
+            wait_ctx
+                .add(control_socket, Token::ControlRequest)
+                .map_err(NetError::CreateWaitContext)?;
+        }
 
         let mut tap_polling_enabled = true;
         'wait: loop {
-            let events = wait_ctx.wait().map_err(NetError::WaitError)?;
+            // Wake up in time to deliver any interrupt held back by coalescing.
+            let timeout = match (self.rx_coalescer.timeout(), self.tx_coalescer.timeout()) {
+                (Some(rx), Some(tx)) => Some(min(rx, tx)),
+                (rx, tx) => rx.or(tx),
+            };
+            let events = match timeout {
+                Some(timeout) => wait_ctx.wait_timeout(timeout),
+                None => wait_ctx.wait(),
+            }
+            .map_err(NetError::WaitError)?;
             for event in events.iter().filter(|e| e.is_readable) {
                 match event.token {
                     Token::RxTap => match self.process_rx() {
@@ -391,7 +1350,7 @@ where
                         self.process_tx();
                     }
                     Token::CtrlQueue => {
-                        if let Some(ctrl_evt) = &ctrl_queue_evt {
+                        if let Some(ctrl_evt) = ctrl_queue_evt {
                             if let Err(e) = ctrl_evt.read() {
                                 error!("net: error reading ctrl queue Event: {}", e);
                                 break 'wait;
@@ -407,25 +1366,88 @@ where
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
+                    Token::ControlRequest => {
+                        let control_socket = match &self.control_socket {
+                            Some(cs) => cs,
+                            None => break 'wait,
+                        };
+                        let command = match control_socket.recv() {
+                            Ok(command) => command,
+                            Err(e) => {
+                                error!("net: control socket failed recv: {}", e);
+                                break 'wait;
+                            }
+                        };
+                        let result =
+                            handle_control_command(&self.rx_coalescer, &self.capture, command);
+                        if let Err(e) = control_socket.send(&result) {
+                            error!("net: control socket failed send: {}", e);
+                            break 'wait;
+                        }
+                    }
                     Token::Kill => {
                         let _ = self.kill_evt.read();
                         break 'wait;
                     }
                 }
             }
+            if self.rx_coalescer.take_due() {
+                self.rx_queue.trigger_interrupt(&self.mem, &self.interrupt);
+            }
+            if self.tx_coalescer.take_due() {
+                self.tx_queue.trigger_interrupt(&self.mem, &self.interrupt);
+            }
         }
         Ok(())
     }
 }
 
+// The events the guest notifies the queues of a worker with.
+struct WorkerQueueEvts {
+    rx: Event,
+    tx: Event,
+    ctrl: Option<Event>,
+}
+
+// Starts a thread running `worker`, the one for queue pair `index`, which gives back the worker
+// and `queue_evts` once it is killed.
+fn spawn_worker<T: 'static + TapT>(
+    index: usize,
+    mut worker: Worker<T>,
+    queue_evts: WorkerQueueEvts,
+) -> io::Result<thread::JoinHandle<(Worker<T>, WorkerQueueEvts)>> {
+    thread::Builder::new()
+        .name(format!("virtio_net worker {}", index))
+        .spawn(move || {
+            let result = if cros_async::uring_available() {
+                worker.run_async(&queue_evts.rx, &queue_evts.tx, queue_evts.ctrl.as_ref())
+            } else {
+                worker.run(&queue_evts.rx, &queue_evts.tx, queue_evts.ctrl.as_ref())
+            };
+            if let Err(e) = result {
+                error!("net worker thread exited with error: {}", e);
+                worker.interrupt.signal_needs_reset();
+            }
+            (worker, queue_evts)
+        })
+}
+
 pub struct Net<T: TapT> {
     queue_sizes: Box<[u16]>,
     workers_kill_evt: Vec<Event>,
     kill_evts: Vec<Event>,
-    worker_threads: Vec<thread::JoinHandle<Worker<T>>>,
+    // Each gives back its worker and the events of its queues when it exits.
+    worker_threads: Vec<thread::JoinHandle<(Worker<T>, WorkerQueueEvts)>>,
+    // The workers stopped by `sleep`, in order, to be restarted by `wake`.
+    sleeping_workers: Vec<(Worker<T>, WorkerQueueEvts)>,
     taps: Vec<T>,
+    queue_pairs: Option<QueuePairs<T>>,
     avail_features: u64,
     acked_features: u64,
+    interrupt_interval: CoalescingInterval,
+    control_socket: Option<NetControlResponseSocket>,
+    capture: PacketCapture,
+    link: NetLink,
 }
 
 impl<T> Net<T>
@@ -440,22 +1462,32 @@ where
         netmask: Ipv4Addr,
         mac_addr: MacAddress,
         vq_pairs: u16,
+        interrupt_interval: Option<Duration>,
+        control_socket: Option<NetControlResponseSocket>,
     ) -> Result<Net<T>, NetError> {
         let multi_queue = if vq_pairs > 1 { true } else { false };
-        let tap: T = T::new(true, multi_queue).map_err(NetError::TapOpen)?;
-        tap.set_ip_addr(ip_addr).map_err(NetError::TapSetIp)?;
-        tap.set_netmask(netmask).map_err(NetError::TapSetNetmask)?;
-        tap.set_mac_address(mac_addr)
-            .map_err(NetError::TapSetMacAddress)?;
-
-        tap.enable().map_err(NetError::TapEnable)?;
-
-        Net::from(base_features, tap, vq_pairs)
+        let tap: T = create_tap(ip_addr, netmask, mac_addr, multi_queue)?;
+        Net::from(
+            base_features,
+            tap,
+            vq_pairs,
+            interrupt_interval,
+            control_socket,
+        )
     }
 
     /// Creates a new virtio network device from a tap device that has already been
     /// configured.
-    pub fn from(base_features: u64, tap: T, vq_pairs: u16) -> Result<Net<T>, NetError> {
+    ///
+    /// If `interrupt_interval` is set, used-ring interrupts are coalesced so that the guest is
+    /// interrupted at most once per interval for each queue.
+    pub fn from(
+        base_features: u64,
+        tap: T,
+        vq_pairs: u16,
+        interrupt_interval: Option<Duration>,
+        control_socket: Option<NetControlResponseSocket>,
+    ) -> Result<Net<T>, NetError> {
         let taps = tap.into_mq_taps(vq_pairs).map_err(NetError::TapOpen)?;
 
         // This would also validate a tap created by Self::new(), but that's a good thing as it
@@ -465,19 +1497,60 @@ where
             validate_and_configure_tap(tap, vq_pairs)?;
         }
 
+        Net::with_taps(base_features, taps, interrupt_interval, control_socket)
+    }
+
+    /// Creates a new virtio network device with a queue pair for each of `taps`, which are
+    /// already open queues of the same tap or macvtap interface. This lets a more privileged
+    /// process set up a multiqueue device that crosvm couldn't open the queues of itself.
+    pub fn from_taps(
+        base_features: u64,
+        taps: Vec<T>,
+        interrupt_interval: Option<Duration>,
+        control_socket: Option<NetControlResponseSocket>,
+    ) -> Result<Net<T>, NetError> {
+        // The queues of a macvtap don't have IFF_MULTI_QUEUE set, and a tun interface can only
+        // have more than one if it does, so don't check for it.
+        for tap in &taps {
+            validate_and_configure_tap(tap, 1)?;
+        }
+
+        Net::with_taps(base_features, taps, interrupt_interval, control_socket)
+    }
+
+    fn with_taps(
+        base_features: u64,
+        taps: Vec<T>,
+        interrupt_interval: Option<Duration>,
+        control_socket: Option<NetControlResponseSocket>,
+    ) -> Result<Net<T>, NetError> {
+        let vq_pairs = taps.len() as u16;
+
         let mut avail_features = base_features
+            | 1 << VIRTIO_F_RING_PACKED
             | 1 << virtio_net::VIRTIO_NET_F_GUEST_CSUM
             | 1 << virtio_net::VIRTIO_NET_F_CSUM
             | 1 << virtio_net::VIRTIO_NET_F_CTRL_VQ
+            | 1 << virtio_net::VIRTIO_NET_F_CTRL_RX
+            | 1 << virtio_net::VIRTIO_NET_F_CTRL_VLAN
+            | 1 << virtio_net::VIRTIO_NET_F_CTRL_MAC_ADDR
             | 1 << virtio_net::VIRTIO_NET_F_CTRL_GUEST_OFFLOADS
             | 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO4
+            | 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO6
+            | 1 << virtio_net::VIRTIO_NET_F_GUEST_ECN
             | 1 << virtio_net::VIRTIO_NET_F_GUEST_UFO
             | 1 << virtio_net::VIRTIO_NET_F_HOST_TSO4
-            | 1 << virtio_net::VIRTIO_NET_F_HOST_UFO;
+            | 1 << virtio_net::VIRTIO_NET_F_HOST_TSO6
+            | 1 << virtio_net::VIRTIO_NET_F_HOST_ECN
+            | 1 << virtio_net::VIRTIO_NET_F_HOST_UFO
+            | 1 << virtio_net::VIRTIO_NET_F_HASH_REPORT;
 
-        if vq_pairs > 1 {
+        let queue_pairs = if vq_pairs > 1 {
             avail_features |= 1 << virtio_net::VIRTIO_NET_F_MQ;
-        }
+            Some(QueuePairs::new(&taps)?)
+        } else {
+            None
+        };
 
         let mut kill_evts: Vec<Event> = Vec::new();
         let mut workers_kill_evt: Vec<Event> = Vec::new();
@@ -493,28 +1566,73 @@ where
             workers_kill_evt,
             kill_evts,
             worker_threads: Vec::new(),
+            sleeping_workers: Vec::new(),
             taps,
+            queue_pairs,
             avail_features,
             acked_features: 0u64,
+            interrupt_interval: CoalescingInterval::new(interrupt_interval.unwrap_or_default()),
+            control_socket,
+            capture: PacketCapture::new(),
+            link: Default::default(),
         })
     }
 
+    /// Advertises the MTU, speed and duplex mode of `link` to the guest. Only takes effect for
+    /// drivers that haven't read the device features yet.
+    pub fn set_link(&mut self, link: NetLink) {
+        self.avail_features &= !self.link.features();
+        self.avail_features |= link.features();
+        self.link = link;
+    }
+
+    /// Writes a copy of every frame the device sends or receives to `file`, in the pcapng format.
+    /// The capture can also be started and stopped later through the control socket.
+    pub fn start_capture(&self, file: File) -> Result<(), NetError> {
+        self.capture.start(file).map_err(NetError::StartCapture)
+    }
+
     fn build_config(&self) -> VirtioNetConfig {
         let vq_pairs = self.queue_sizes.len() as u16 / 2;
 
-        VirtioNetConfig {
+        let mut config = VirtioNetConfig {
             max_vq_pairs: Le16::from(vq_pairs),
+            rss_max_key_size: RX_HASH_MAX_KEY_LEN as u8,
+            rss_max_indirection_table_length: Le16::from(1),
+            supported_hash_types: Le32::from(RX_HASH_SUPPORTED_TYPES),
             // Other field has meaningful value when the corresponding feature
             // is enabled, but all these features aren't supported now.
             // So set them to default.
             ..Default::default()
-        }
+        };
+        self.link.apply(&mut config);
+        config
     }
 }
 
-// Ensure that the tap interface has the correct flags and sets the offload and VNET header size
-// to the appropriate values.
-fn validate_and_configure_tap<T: TapT>(tap: &T, vq_pairs: u16) -> Result<(), NetError> {
+/// Opens a tap device with a vnet header and brings it up with the given IP address, netmask and
+/// MAC address.
+pub fn create_tap<T: TapT>(
+    ip_addr: Ipv4Addr,
+    netmask: Ipv4Addr,
+    mac_addr: MacAddress,
+    multi_queue: bool,
+) -> Result<T, NetError> {
+    let tap: T = T::new(true, multi_queue).map_err(NetError::TapOpen)?;
+    tap.set_ip_addr(ip_addr).map_err(NetError::TapSetIp)?;
+    tap.set_netmask(netmask).map_err(NetError::TapSetNetmask)?;
+    tap.set_mac_address(mac_addr)
+        .map_err(NetError::TapSetMacAddress)?;
+
+    tap.enable().map_err(NetError::TapEnable)?;
+
+    Ok(tap)
+}
+
+/// Checks that `tap` was opened with the flags the network devices rely on, sets its vnet header
+/// size to that of `virtio_net_hdr_v1` and makes it non-blocking. Taps passed in by descriptor may
+/// have been opened in blocking mode.
+pub fn validate_and_configure_tap<T: TapT>(tap: &T, vq_pairs: u16) -> Result<(), NetError> {
     let flags = tap.if_flags();
     let mut required_flags = vec![
         (net_sys::IFF_TAP, "IFF_TAP"),
@@ -547,6 +1665,7 @@ fn validate_and_configure_tap<T: TapT>(tap: &T, vq_pairs: u16) -> Result<(), Net
     let vnet_hdr_size = mem::size_of::<virtio_net_hdr_v1>() as i32;
     tap.set_vnet_hdr_size(vnet_hdr_size)
         .map_err(NetError::TapSetVnetHdrSize)?;
+    add_fd_flags(tap.as_raw_descriptor(), libc::O_NONBLOCK).map_err(NetError::TapSetNonBlocking)?;
 
     Ok(())
 }
@@ -584,6 +1703,11 @@ where
         for tap in &self.taps {
             keep_rds.push(tap.as_raw_descriptor());
         }
+        if let Some(queue_pairs) = &self.queue_pairs {
+            for tap in &queue_pairs.taps {
+                keep_rds.push(tap.as_raw_descriptor());
+            }
+        }
 
         for worker_kill_evt in &self.workers_kill_evt {
             keep_rds.push(worker_kill_evt.as_raw_descriptor());
@@ -591,6 +1715,12 @@ where
         for kill_evt in &self.kill_evts {
             keep_rds.push(kill_evt.as_raw_descriptor());
         }
+        if let Some(control_socket) = &self.control_socket {
+            keep_rds.push(control_socket.as_raw_descriptor());
+        }
+        if let Some(capture_file) = self.capture.file_descriptor() {
+            keep_rds.push(capture_file);
+        }
 
         keep_rds
     }
@@ -665,6 +1795,28 @@ where
             );
             return;
         }
+        // Until the driver sets the number of queue pairs it uses, it only uses the first one.
+        if let Some(queue_pairs) = self.queue_pairs.as_mut() {
+            if let Err(e) = queue_pairs.set_active(1) {
+                warn!("net: failed to detach unused tap queues: {}", e);
+            }
+        }
+        // The tap skips the header of frames the driver sends and leaves room for it in those it
+        // receives, so it needs to know how long the header is.
+        let hdr_len = vnet_hdr_len(self.acked_features);
+        for tap in &self.taps {
+            if let Err(e) = tap.set_vnet_hdr_size(hdr_len as i32) {
+                error!("net: failed to set tap vnet header size: {}", e);
+                return;
+            }
+        }
+        let rx_filter =
+            RxFilter::new(self.acked_features & 1 << virtio_net::VIRTIO_NET_F_CTRL_VLAN != 0);
+        let rx_hash = if self.acked_features & 1 << virtio_net::VIRTIO_NET_F_HASH_REPORT != 0 {
+            Some(RxHash::new())
+        } else {
+            None
+        };
         let interrupt_arc = Arc::new(interrupt);
         for i in 0..vq_pairs {
             let tap = self.taps.remove(0);
@@ -680,7 +1832,6 @@ where
             } else {
                 None
             };
-            let pairs = vq_pairs as u16;
             let rx_queue_evt = queue_evts.remove(0);
             let tx_queue_evt = queue_evts.remove(0);
             let ctrl_queue_evt = if i == 0 {
@@ -688,28 +1839,45 @@ where
             } else {
                 None
             };
-            let worker_result = thread::Builder::new()
-                .name(format!("virtio_net worker {}", i))
-                .spawn(move || {
-                    let mut worker = Worker {
-                        interrupt,
-                        mem: memory,
-                        rx_queue,
-                        tx_queue,
-                        ctrl_queue,
-                        tap,
-                        acked_features,
-                        vq_pairs: pairs,
-                        kill_evt,
-                    };
-                    let result = worker.run(rx_queue_evt, tx_queue_evt, ctrl_queue_evt);
-                    if let Err(e) = result {
-                        error!("net worker thread exited with error: {}", e);
-                    }
-                    worker
-                });
-
-            match worker_result {
+            // The first worker also serves the control socket.
+            let control_socket = if i == 0 {
+                self.control_socket.take()
+            } else {
+                None
+            };
+            let queue_pairs = if i == 0 {
+                self.queue_pairs.take()
+            } else {
+                None
+            };
+            let rx_filter = rx_filter.clone();
+            let rx_hash = rx_hash.clone();
+            let capture = self.capture.clone();
+            let rx_coalescer = InterruptCoalescer::new(self.interrupt_interval.clone());
+            let tx_coalescer = InterruptCoalescer::new(self.interrupt_interval.clone());
+            let worker = Worker {
+                interrupt,
+                mem: memory,
+                rx_queue,
+                tx_queue,
+                ctrl_queue,
+                tap,
+                acked_features,
+                queue_pairs,
+                rx_filter,
+                rx_hash,
+                capture,
+                kill_evt,
+                rx_coalescer,
+                tx_coalescer,
+                control_socket,
+            };
+            let queue_evts = WorkerQueueEvts {
+                rx: rx_queue_evt,
+                tx: tx_queue_evt,
+                ctrl: ctrl_queue_evt,
+            };
+            match spawn_worker(i, worker, queue_evts) {
                 Err(e) => {
                     error!("failed to spawn virtio_net worker: {}", e);
                     return;
@@ -720,19 +1888,15 @@ where
     }
 
     fn reset(&mut self) -> bool {
-        let len = self.kill_evts.len();
-        for i in 0..len {
-            // Only kill the child if it claimed its event.
-            if self.workers_kill_evt.get(i).is_none() {
-                if let Some(kill_evt) = self.kill_evts.get(i) {
-                    if kill_evt.write(1).is_err() {
-                        error!("{}: failed to notify the kill event", self.debug_label());
-                        return false;
-                    }
-                }
+        // Sleeping workers aren't waiting on their kill event, which has to stay clear for them.
+        for kill_evt in self.kill_evts.iter().take(self.worker_threads.len()) {
+            if kill_evt.write(1).is_err() {
+                error!("{}: failed to notify the kill event", self.debug_label());
+                return false;
             }
         }
 
+        let mut workers = mem::take(&mut self.sleeping_workers);
         let len = self.worker_threads.len();
         for _ in 0..len {
             match self.worker_threads.remove(0).join() {
@@ -740,13 +1904,107 @@ where
                     error!("{}: failed to get back resources", self.debug_label());
                     return false;
                 }
-                Ok(worker) => {
-                    self.taps.push(worker.tap);
-                    self.workers_kill_evt.push(worker.kill_evt);
-                }
+                Ok(worker) => workers.push(worker),
+            }
+        }
+        for (worker, _queue_evts) in workers {
+            self.taps.push(worker.tap);
+            self.workers_kill_evt.push(worker.kill_evt);
+            if worker.control_socket.is_some() {
+                self.control_socket = worker.control_socket;
+            }
+            if worker.queue_pairs.is_some() {
+                self.queue_pairs = worker.queue_pairs;
             }
         }
 
+        self.acked_features = 0;
         return true;
     }
 }
+
+// Sleeping stops the workers and waking starts them again on the same queues. Frames the tap
+// receives in between wait in the tap's queue, or are dropped once it is full.
+impl<T> Suspendable for Net<T>
+where
+    T: 'static + TapT,
+{
+    fn sleep(&mut self) -> suspendable::Result<()> {
+        let debug_label = self.debug_label();
+        for kill_evt in self.kill_evts.iter().take(self.worker_threads.len()) {
+            if let Err(e) = kill_evt.write(1) {
+                error!("{}: failed to notify the kill event: {}", debug_label, e);
+                return Err(SuspendableError::WorkerFailed);
+            }
+        }
+        for worker_thread in self.worker_threads.drain(..) {
+            match worker_thread.join() {
+                Ok(worker) => self.sleeping_workers.push(worker),
+                Err(_) => {
+                    error!("{}: failed to stop the worker", debug_label);
+                    return Err(SuspendableError::WorkerFailed);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn wake(&mut self) -> suspendable::Result<()> {
+        let debug_label = self.debug_label();
+        let workers = mem::take(&mut self.sleeping_workers);
+        for (i, (worker, queue_evts)) in workers.into_iter().enumerate() {
+            let join_handle = spawn_worker(i, worker, queue_evts).map_err(|e| {
+                error!("{}: failed to spawn virtio_net worker: {}", debug_label, e);
+                SuspendableError::WorkerFailed
+            })?;
+            self.worker_threads.push(join_handle);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tap_offloads() {
+        assert_eq!(virtio_features_to_tap_offload(0), 0);
+        // Segmentation offloads need checksum offload.
+        assert_eq!(
+            virtio_features_to_tap_offload(1 << virtio_net::VIRTIO_NET_F_GUEST_TSO4),
+            0
+        );
+        assert_eq!(
+            virtio_features_to_tap_offload(GUEST_OFFLOAD_FEATURES),
+            net_sys::TUN_F_CSUM
+                | net_sys::TUN_F_TSO4
+                | net_sys::TUN_F_TSO6
+                | net_sys::TUN_F_TSO_ECN
+                | net_sys::TUN_F_UFO
+        );
+    }
+
+    #[test]
+    fn link_config() {
+        let mut config = VirtioNetConfig::default();
+        assert_eq!(config.as_slice().len(), 24);
+        NetLink::default().apply(&mut config);
+        assert_eq!(NetLink::default().features(), 0);
+        assert_eq!(&config.as_slice()[12..17], &[0xff; 5]);
+
+        let link = NetLink {
+            mtu: Some(9000),
+            speed: Some(10000),
+            duplex: Some(Duplex::Full),
+        };
+        link.apply(&mut config);
+        assert_eq!(&config.as_slice()[10..12], &9000u16.to_le_bytes());
+        assert_eq!(&config.as_slice()[12..16], &10000u32.to_le_bytes());
+        assert_eq!(config.as_slice()[16], DUPLEX_FULL);
+        assert_eq!(
+            link.features(),
+            1 << virtio_net::VIRTIO_NET_F_MTU | 1 << virtio_net::VIRTIO_NET_F_SPEED_DUPLEX
+        );
+    }
+}
@@ -2,18 +2,32 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::convert::TryFrom;
 use std::fmt::{self, Display};
+use std::future::Future;
 use std::io::{self, Write};
 use std::mem;
+use std::pin::Pin;
 use std::result;
 use std::thread;
 
-use base::{error, warn, Error as SysError, Event, PollToken, RawDescriptor, WaitContext};
+use base::{error, warn, Error as SysError, Event, RawDescriptor};
+use cros_async::{AsyncError, EventAsync};
+use futures::future::{select, select_all, Either};
+use futures::stream::FuturesUnordered;
+use futures::{pin_mut, FutureExt, StreamExt};
 use vm_memory::GuestMemory;
 
 use super::{
     copy_config, DescriptorError, Interrupt, Queue, Reader, VirtioDevice, Writer, TYPE_9P,
 };
+use crate::Suspendable;
+
+mod protocol;
+mod server;
+
+pub use self::server::P9Config;
+use self::server::Server;
 
 const QUEUE_SIZE: u16 = 128;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
@@ -26,14 +40,16 @@ const VIRTIO_9P_MOUNT_TAG: u8 = 0;
 pub enum P9Error {
     /// The tag for the 9P device was too large to fit in the config space.
     TagTooLong(usize),
-    /// Creating WaitContext failed.
-    CreateWaitContext(SysError),
+    /// Failed to clone an Event.
+    CloneEvent(SysError),
+    /// Failed to create an async source.
+    CreateAsyncSource(AsyncError),
     /// Failed to create a 9p server.
     CreateServer(io::Error),
-    /// Error while polling for events.
-    WaitError(SysError),
     /// Error while reading from the virtio queue's Event.
-    ReadQueueEvent(SysError),
+    ReadQueueEvent(AsyncError),
+    /// Failed to run the async executor.
+    RunExecutor(cros_async::Error),
     /// A request is missing readable descriptors.
     NoReadableDescriptors,
     /// A request is missing writable descriptors.
@@ -59,10 +75,11 @@ impl Display for P9Error {
                 len,
                 ::std::u16::MAX
             ),
-            CreateWaitContext(err) => write!(f, "failed to create WaitContext: {}", err),
-            WaitError(err) => write!(f, "failed to wait for events: {}", err),
+            CloneEvent(err) => write!(f, "failed to clone event: {}", err),
+            CreateAsyncSource(err) => write!(f, "failed to create async source: {}", err),
             CreateServer(err) => write!(f, "failed to create 9p server: {}", err),
             ReadQueueEvent(err) => write!(f, "failed to read from virtio queue Event: {}", err),
+            RunExecutor(err) => write!(f, "failed to run async executor: {}", err),
             NoReadableDescriptors => write!(f, "request does not have any readable descriptors"),
             NoWritableDescriptors => write!(f, "request does not have any writable descriptors"),
             SignalUsedQueue(err) => write!(f, "failed to signal used queue: {}", err),
@@ -76,74 +93,118 @@ impl Display for P9Error {
 
 pub type P9Result<T> = result::Result<T, P9Error>;
 
-struct Worker {
-    interrupt: Interrupt,
-    mem: GuestMemory,
-    queue: Queue,
-    server: p9::Server,
-}
-
-impl Worker {
-    fn process_queue(&mut self) -> P9Result<()> {
-        while let Some(avail_desc) = self.queue.pop(&self.mem) {
-            let mut reader = Reader::new(self.mem.clone(), avail_desc.clone())
-                .map_err(P9Error::InvalidDescriptorChain)?;
-            let mut writer = Writer::new(self.mem.clone(), avail_desc.clone())
+// Handles every request on `queue` in its own future, so that a request waiting on I/O doesn't
+// hold back the ones after it. Replies are returned to the guest in the order they complete, and
+// the guest is signaled once for each batch of completed requests.
+async fn process_queue_async(
+    interrupt: &Interrupt,
+    mem: &GuestMemory,
+    queue: &mut Queue,
+    queue_evt: &EventAsync,
+    server: &Server,
+) -> P9Result<()> {
+    let mut in_flight = FuturesUnordered::new();
+    loop {
+        while let Some(avail_desc) = queue.pop(mem) {
+            let index = avail_desc.index;
+            let reader = Reader::new(mem.clone(), avail_desc.clone())
                 .map_err(P9Error::InvalidDescriptorChain)?;
+            let writer =
+                Writer::new(mem.clone(), avail_desc).map_err(P9Error::InvalidDescriptorChain)?;
+            in_flight.push(async move {
+                let len = server.handle_message(reader, writer).await?;
+                Ok((index, len))
+            });
+        }
 
-            self.server
-                .handle_message(&mut reader, &mut writer)
-                .map_err(P9Error::Internal)?;
-
-            self.queue
-                .add_used(&self.mem, avail_desc.index, writer.bytes_written() as u32);
+        if in_flight.is_empty() {
+            queue_evt
+                .next_val()
+                .await
+                .map_err(P9Error::ReadQueueEvent)?;
+            continue;
         }
 
-        self.interrupt.signal_used_queue(self.queue.vector);
+        // Wait for a request to finish, or for the guest to send more.
+        let next_val = queue_evt.next_val();
+        pin_mut!(next_val);
+        let mut completed = match select(in_flight.next(), next_val).await {
+            Either::Left((completed, _)) => completed,
+            Either::Right((res, _)) => {
+                res.map_err(P9Error::ReadQueueEvent)?;
+                continue;
+            }
+        };
+
+        while let Some(result) = completed {
+            let (index, len) = result.map_err(P9Error::Internal)?;
+            queue.add_used(mem, index, len);
+            completed = in_flight.next().now_or_never().flatten();
+        }
+        queue.trigger_interrupt(mem, interrupt);
+    }
+}
 
-        Ok(())
+async fn handle_irq_resample(interrupt: &Interrupt, resample_evt: &EventAsync) -> P9Result<()> {
+    loop {
+        resample_evt
+            .next_val()
+            .await
+            .map_err(P9Error::ReadQueueEvent)?;
+        interrupt.do_interrupt_resample();
     }
+}
 
+fn async_event(event: &Event) -> P9Result<EventAsync> {
+    let event = event.try_clone().map_err(P9Error::CloneEvent)?;
+    EventAsync::try_from(event.0).map_err(P9Error::CreateAsyncSource)
+}
+
+struct Worker {
+    interrupt: Interrupt,
+    mem: GuestMemory,
+    queue: Queue,
+    server: Server,
+}
+
+impl Worker {
     fn run(&mut self, queue_evt: Event, kill_evt: Event) -> P9Result<()> {
-        #[derive(PollToken)]
-        enum Token {
-            // A request is ready on the queue.
-            QueueReady,
-            // Check if any interrupts need to be re-asserted.
-            InterruptResample,
-            // The parent thread requested an exit.
-            Kill,
-        }
+        let queue_evt = async_event(&queue_evt)?;
+        let resample_evt = async_event(self.interrupt.get_resample_evt())?;
+        let kill_evt = async_event(&kill_evt)?;
+
+        let interrupt = &self.interrupt;
+        let queue = process_queue_async(
+            interrupt,
+            &self.mem,
+            &mut self.queue,
+            &queue_evt,
+            &self.server,
+        );
+        let resample = handle_irq_resample(interrupt, &resample_evt);
+        let kill = async {
+            kill_evt
+                .next_val()
+                .await
+                .map(|_| ())
+                .map_err(P9Error::ReadQueueEvent)
+        };
 
-        let wait_ctx: WaitContext<Token> = WaitContext::build_with(&[
-            (&queue_evt, Token::QueueReady),
-            (self.interrupt.get_resample_evt(), Token::InterruptResample),
-            (&kill_evt, Token::Kill),
-        ])
-        .map_err(P9Error::CreateWaitContext)?;
-
-        loop {
-            let events = wait_ctx.wait().map_err(P9Error::WaitError)?;
-            for event in events.iter().filter(|e| e.is_readable) {
-                match event.token {
-                    Token::QueueReady => {
-                        queue_evt.read().map_err(P9Error::ReadQueueEvent)?;
-                        self.process_queue()?;
-                    }
-                    Token::InterruptResample => {
-                        self.interrupt.interrupt_resample();
-                    }
-                    Token::Kill => return Ok(()),
-                }
-            }
-        }
+        let futures: Vec<Pin<Box<dyn Future<Output = P9Result<()>> + '_>>> =
+            vec![Box::pin(queue), Box::pin(resample), Box::pin(kill)];
+        let fut = async {
+            let (result, _, _) = select_all(futures).await;
+            result
+        };
+
+        cros_async::run_one(Box::pin(fut)).map_err(P9Error::RunExecutor)?
     }
 }
 
 /// Virtio device for sharing specific directories on the host system with the guest VM.
 pub struct P9 {
     config: Vec<u8>,
-    server: Option<p9::Server>,
+    server: Option<Server>,
     kill_evt: Option<Event>,
     avail_features: u64,
     acked_features: u64,
@@ -151,7 +212,7 @@ pub struct P9 {
 }
 
 impl P9 {
-    pub fn new(base_features: u64, tag: &str, p9_cfg: p9::Config) -> P9Result<P9> {
+    pub fn new(base_features: u64, tag: &str, p9_cfg: P9Config) -> P9Result<P9> {
         if tag.len() > ::std::u16::MAX as usize {
             return Err(P9Error::TagTooLong(tag.len()));
         }
@@ -163,7 +224,7 @@ impl P9 {
 
         cfg.write_all(tag.as_bytes()).map_err(P9Error::Internal)?;
 
-        let server = p9::Server::with_config(p9_cfg).map_err(P9Error::CreateServer)?;
+        let server = Server::new(p9_cfg).map_err(P9Error::CreateServer)?;
         Ok(P9 {
             config: cfg,
             server: Some(server),
@@ -179,7 +240,7 @@ impl VirtioDevice for P9 {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
         self.server
             .as_ref()
-            .map(p9::Server::keep_fds)
+            .map(Server::keep_rds)
             .unwrap_or_else(Vec::new)
     }
 
@@ -245,7 +306,12 @@ impl VirtioDevice for P9 {
                             server,
                         };
 
-                        worker.run(queue_evts.remove(0), kill_evt)
+                        let result = worker.run(queue_evts.remove(0), kill_evt);
+                        // The error is logged when the worker is joined.
+                        if result.is_err() {
+                            worker.interrupt.signal_needs_reset();
+                        }
+                        result
                     });
 
             match worker_result {
@@ -256,6 +322,8 @@ impl VirtioDevice for P9 {
     }
 }
 
+impl Suspendable for P9 {}
+
 impl Drop for P9 {
     fn drop(&mut self) {
         if let Some(kill_evt) = self.kill_evt.take() {
@@ -0,0 +1,662 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The wire format of the 9P2000.L messages served by the 9p device.
+//!
+//! Every message starts with a `Header`, followed by the fields of the message in order. Integers
+//! are little-endian, strings are a 16-bit length followed by that many bytes, and lists are a
+//! 16-bit count followed by that many elements.
+
+use std::ffi::CString;
+use std::io::{self, Read, Write};
+use std::mem;
+
+pub const RLERROR: u8 = 7;
+pub const TSTATFS: u8 = 8;
+pub const TLOPEN: u8 = 12;
+pub const TLCREATE: u8 = 14;
+pub const TSYMLINK: u8 = 16;
+pub const TMKNOD: u8 = 18;
+pub const TRENAME: u8 = 20;
+pub const TREADLINK: u8 = 22;
+pub const TGETATTR: u8 = 24;
+pub const TSETATTR: u8 = 26;
+pub const TXATTRWALK: u8 = 30;
+pub const TXATTRCREATE: u8 = 32;
+pub const TREADDIR: u8 = 40;
+pub const TFSYNC: u8 = 50;
+pub const TLOCK: u8 = 52;
+pub const TGETLOCK: u8 = 54;
+pub const TLINK: u8 = 70;
+pub const TMKDIR: u8 = 72;
+pub const TRENAMEAT: u8 = 74;
+pub const TUNLINKAT: u8 = 76;
+pub const TVERSION: u8 = 100;
+pub const TAUTH: u8 = 102;
+pub const TATTACH: u8 = 104;
+pub const TFLUSH: u8 = 108;
+pub const TWALK: u8 = 110;
+pub const TREAD: u8 = 116;
+pub const TWRITE: u8 = 118;
+pub const TCLUNK: u8 = 120;
+pub const TREMOVE: u8 = 122;
+
+/// The length of the `Header` at the start of every message.
+pub const HEADER_LEN: u32 = 7;
+
+/// The fid value meaning "no fid", such as the afid of a `Tattach` without authentication.
+pub const NOFID: u32 = u32::MAX;
+
+/// The maximum number of names in a `Twalk`.
+pub const MAX_WALK_NAMES: usize = 16;
+
+// Types of the file a qid refers to.
+pub const QTDIR: u8 = 0x80;
+pub const QTSYMLINK: u8 = 0x02;
+pub const QTFILE: u8 = 0x00;
+
+// Flags of `Tlopen` and `Tlcreate` that are passed on to the host. These are the values of the x86
+// Linux open flags, which the client uses whatever the architecture of either side.
+pub const P9_ACCMODE: u32 = 0o3;
+pub const P9_EXCL: u32 = 0o200;
+pub const P9_TRUNC: u32 = 0o1000;
+pub const P9_APPEND: u32 = 0o2000;
+pub const P9_DSYNC: u32 = 0o10000;
+pub const P9_DIRECTORY: u32 = 0o200000;
+pub const P9_NOATIME: u32 = 0o1000000;
+pub const P9_SYNC: u32 = 0o4000000;
+
+// The attributes of `Tgetattr` that are filled in from a `stat`.
+pub const P9_GETATTR_BASIC: u64 = 0x0000_07ff;
+
+// The attributes a `Tsetattr` changes.
+pub const P9_SETATTR_MODE: u32 = 0x0000_0001;
+pub const P9_SETATTR_UID: u32 = 0x0000_0002;
+pub const P9_SETATTR_GID: u32 = 0x0000_0004;
+pub const P9_SETATTR_SIZE: u32 = 0x0000_0008;
+pub const P9_SETATTR_ATIME: u32 = 0x0000_0010;
+pub const P9_SETATTR_MTIME: u32 = 0x0000_0020;
+pub const P9_SETATTR_ATIME_SET: u32 = 0x0000_0080;
+pub const P9_SETATTR_MTIME_SET: u32 = 0x0000_0100;
+
+// Results of `Tlock` and lock types of `Tgetlock`.
+pub const P9_LOCK_SUCCESS: u8 = 0;
+pub const P9_LOCK_TYPE_UNLCK: u8 = 2;
+
+// Flag of `Tunlinkat`.
+pub const P9_AT_REMOVEDIR: u32 = 0x200;
+
+/// A value that can be encoded to and decoded from the 9p wire format.
+pub trait WireFormat: Sized {
+    /// Returns the number of bytes `self` takes when encoded.
+    fn byte_size(&self) -> u32;
+
+    /// Encodes `self` into `writer`.
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()>;
+
+    /// Decodes a value from `reader`.
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self>;
+}
+
+macro_rules! uint_wire_format {
+    ($($ty:ty),*) => {
+        $(
+            impl WireFormat for $ty {
+                fn byte_size(&self) -> u32 {
+                    mem::size_of::<$ty>() as u32
+                }
+
+                fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+                    writer.write_all(&self.to_le_bytes())
+                }
+
+                fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+                    let mut buf = [0u8; mem::size_of::<$ty>()];
+                    reader.read_exact(&mut buf)?;
+                    Ok(<$ty>::from_le_bytes(buf))
+                }
+            }
+        )*
+    };
+}
+
+uint_wire_format!(u8, u16, u32, u64);
+
+// The body of the replies that only report success.
+impl WireFormat for () {
+    fn byte_size(&self) -> u32 {
+        0
+    }
+
+    fn encode<W: Write>(&self, _writer: &mut W) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn decode<R: Read>(_reader: &mut R) -> io::Result<Self> {
+        Ok(())
+    }
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn encode_bytes<W: Write>(bytes: &[u8], writer: &mut W) -> io::Result<()> {
+    if bytes.len() > u16::MAX as usize {
+        return Err(invalid_data("string is too long"));
+    }
+    (bytes.len() as u16).encode(writer)?;
+    writer.write_all(bytes)
+}
+
+fn decode_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = u16::decode(reader)?;
+    let mut bytes = vec![0u8; len as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+impl WireFormat for String {
+    fn byte_size(&self) -> u32 {
+        mem::size_of::<u16>() as u32 + self.len() as u32
+    }
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        encode_bytes(self.as_bytes(), writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        String::from_utf8(decode_bytes(reader)?).map_err(invalid_data)
+    }
+}
+
+// File names are decoded straight into C strings, ready to be passed to system calls. A name with
+// a nul byte in it can't name any file.
+impl WireFormat for CString {
+    fn byte_size(&self) -> u32 {
+        mem::size_of::<u16>() as u32 + self.as_bytes().len() as u32
+    }
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        encode_bytes(self.as_bytes(), writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        CString::new(decode_bytes(reader)?).map_err(invalid_data)
+    }
+}
+
+impl<T: WireFormat> WireFormat for Vec<T> {
+    fn byte_size(&self) -> u32 {
+        mem::size_of::<u16>() as u32 + self.iter().map(WireFormat::byte_size).sum::<u32>()
+    }
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.len() > u16::MAX as usize {
+            return Err(invalid_data("list is too long"));
+        }
+        (self.len() as u16).encode(writer)?;
+        for elem in self {
+            elem.encode(writer)?;
+        }
+        Ok(())
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let len = u16::decode(reader)?;
+        (0..len).map(|_| T::decode(reader)).collect()
+    }
+}
+
+/// Opaque bytes with a 32-bit length, such as the entries of an `Rreaddir`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Data(pub Vec<u8>);
+
+impl WireFormat for Data {
+    fn byte_size(&self) -> u32 {
+        mem::size_of::<u32>() as u32 + self.0.len() as u32
+    }
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (self.0.len() as u32).encode(writer)?;
+        writer.write_all(&self.0)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let len = u32::decode(reader)?;
+        let mut bytes = Vec::new();
+        reader.take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != len as usize {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        Ok(Data(bytes))
+    }
+}
+
+// Declares a struct whose fields are encoded one after the other, in order.
+macro_rules! wire_struct {
+    ($(#[$attr:meta])* pub struct $name:ident { $($field:ident: $ty:ty,)* }) => {
+        $(#[$attr])*
+        #[derive(Debug, Default, PartialEq)]
+        pub struct $name {
+            $(pub $field: $ty,)*
+        }
+
+        impl WireFormat for $name {
+            fn byte_size(&self) -> u32 {
+                0 $(+ self.$field.byte_size())*
+            }
+
+            fn encode<W: Write>(&self, _writer: &mut W) -> io::Result<()> {
+                $(self.$field.encode(_writer)?;)*
+                Ok(())
+            }
+
+            fn decode<R: Read>(_reader: &mut R) -> io::Result<Self> {
+                Ok($name {
+                    $($field: WireFormat::decode(_reader)?,)*
+                })
+            }
+        }
+    };
+}
+
+wire_struct! {
+    /// The start of every message. `size` includes the header itself.
+    pub struct Header {
+        size: u32,
+        ty: u8,
+        tag: u16,
+    }
+}
+
+wire_struct! {
+    /// The server's unique identification of a file.
+    pub struct Qid {
+        ty: u8,
+        version: u32,
+        path: u64,
+    }
+}
+
+wire_struct! {
+    pub struct Tversion {
+        msize: u32,
+        version: String,
+    }
+}
+
+wire_struct! {
+    pub struct Rversion {
+        msize: u32,
+        version: String,
+    }
+}
+
+wire_struct! {
+    pub struct Tflush {
+        oldtag: u16,
+    }
+}
+
+wire_struct! {
+    pub struct Twalk {
+        fid: u32,
+        newfid: u32,
+        wnames: Vec<CString>,
+    }
+}
+
+wire_struct! {
+    pub struct Rwalk {
+        wqids: Vec<Qid>,
+    }
+}
+
+wire_struct! {
+    /// Followed in the reply by a count and that many bytes of data, which are read straight into
+    /// the guest's buffers.
+    pub struct Tread {
+        fid: u32,
+        offset: u64,
+        count: u32,
+    }
+}
+
+wire_struct! {
+    /// Followed by `count` bytes of data, which are written straight from the guest's buffers.
+    pub struct Twrite {
+        fid: u32,
+        offset: u64,
+        count: u32,
+    }
+}
+
+wire_struct! {
+    pub struct Rwrite {
+        count: u32,
+    }
+}
+
+wire_struct! {
+    pub struct Tclunk {
+        fid: u32,
+    }
+}
+
+wire_struct! {
+    pub struct Tremove {
+        fid: u32,
+    }
+}
+
+wire_struct! {
+    pub struct Tattach {
+        fid: u32,
+        afid: u32,
+        uname: String,
+        aname: String,
+        n_uname: u32,
+    }
+}
+
+wire_struct! {
+    pub struct Rattach {
+        qid: Qid,
+    }
+}
+
+wire_struct! {
+    pub struct Rlerror {
+        ecode: u32,
+    }
+}
+
+wire_struct! {
+    pub struct Tstatfs {
+        fid: u32,
+    }
+}
+
+wire_struct! {
+    pub struct Rstatfs {
+        ty: u32,
+        bsize: u32,
+        blocks: u64,
+        bfree: u64,
+        bavail: u64,
+        files: u64,
+        ffree: u64,
+        fsid: u64,
+        namelen: u32,
+    }
+}
+
+wire_struct! {
+    pub struct Tlopen {
+        fid: u32,
+        flags: u32,
+    }
+}
+
+wire_struct! {
+    /// The reply to both `Tlopen` and `Tlcreate`.
+    pub struct Rlopen {
+        qid: Qid,
+        iounit: u32,
+    }
+}
+
+wire_struct! {
+    pub struct Tlcreate {
+        fid: u32,
+        name: CString,
+        flags: u32,
+        mode: u32,
+        gid: u32,
+    }
+}
+
+wire_struct! {
+    pub struct Treadlink {
+        fid: u32,
+    }
+}
+
+wire_struct! {
+    pub struct Tsymlink {
+        fid: u32,
+        name: CString,
+        symtgt: CString,
+        gid: u32,
+    }
+}
+
+wire_struct! {
+    pub struct Tmknod {
+        dfid: u32,
+        name: CString,
+        mode: u32,
+        major: u32,
+        minor: u32,
+        gid: u32,
+    }
+}
+
+wire_struct! {
+    pub struct Rreadlink {
+        target: CString,
+    }
+}
+
+wire_struct! {
+    pub struct Tgetattr {
+        fid: u32,
+        request_mask: u64,
+    }
+}
+
+wire_struct! {
+    pub struct Rgetattr {
+        valid: u64,
+        qid: Qid,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        nlink: u64,
+        rdev: u64,
+        size: u64,
+        blksize: u64,
+        blocks: u64,
+        atime_sec: u64,
+        atime_nsec: u64,
+        mtime_sec: u64,
+        mtime_nsec: u64,
+        ctime_sec: u64,
+        ctime_nsec: u64,
+        btime_sec: u64,
+        btime_nsec: u64,
+        gen: u64,
+        data_version: u64,
+    }
+}
+
+wire_struct! {
+    pub struct Tsetattr {
+        fid: u32,
+        valid: u32,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        size: u64,
+        atime_sec: u64,
+        atime_nsec: u64,
+        mtime_sec: u64,
+        mtime_nsec: u64,
+    }
+}
+
+wire_struct! {
+    pub struct Treaddir {
+        fid: u32,
+        offset: u64,
+        count: u32,
+    }
+}
+
+wire_struct! {
+    pub struct Rreaddir {
+        data: Data,
+    }
+}
+
+wire_struct! {
+    /// An entry in the data of an `Rreaddir`.
+    pub struct Dirent {
+        qid: Qid,
+        offset: u64,
+        ty: u8,
+        name: CString,
+    }
+}
+
+wire_struct! {
+    pub struct Tfsync {
+        fid: u32,
+        datasync: u32,
+    }
+}
+
+wire_struct! {
+    pub struct Tlock {
+        fid: u32,
+        ty: u8,
+        flags: u32,
+        start: u64,
+        length: u64,
+        proc_id: u32,
+        client_id: String,
+    }
+}
+
+wire_struct! {
+    pub struct Rlock {
+        status: u8,
+    }
+}
+
+wire_struct! {
+    pub struct Tgetlock {
+        fid: u32,
+        ty: u8,
+        start: u64,
+        length: u64,
+        proc_id: u32,
+        client_id: String,
+    }
+}
+
+wire_struct! {
+    pub struct Rgetlock {
+        ty: u8,
+        start: u64,
+        length: u64,
+        proc_id: u32,
+        client_id: String,
+    }
+}
+
+wire_struct! {
+    pub struct Tlink {
+        dfid: u32,
+        fid: u32,
+        name: CString,
+    }
+}
+
+wire_struct! {
+    pub struct Tmkdir {
+        dfid: u32,
+        name: CString,
+        mode: u32,
+        gid: u32,
+    }
+}
+
+wire_struct! {
+    /// The reply to the messages creating a file other than `Tlcreate`.
+    pub struct Rmkdir {
+        qid: Qid,
+    }
+}
+
+wire_struct! {
+    pub struct Trenameat {
+        olddirfid: u32,
+        oldname: CString,
+        newdirfid: u32,
+        newname: CString,
+    }
+}
+
+wire_struct! {
+    pub struct Tunlinkat {
+        dirfd: u32,
+        name: CString,
+        flags: u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode<T: WireFormat>(val: &T) -> Vec<u8> {
+        let mut buf = Vec::new();
+        val.encode(&mut buf).unwrap();
+        assert_eq!(buf.len(), val.byte_size() as usize);
+        buf
+    }
+
+    #[test]
+    fn integers_are_little_endian() {
+        assert_eq!(encode(&0x1234u16), [0x34, 0x12]);
+        assert_eq!(encode(&0x1234_5678u32), [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(u64::decode(&mut &encode(&u64::MAX)[..]).unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn walk_round_trip() {
+        let walk = Twalk {
+            fid: 1,
+            newfid: 2,
+            wnames: vec![CString::new("dir").unwrap(), CString::new("file").unwrap()],
+        };
+        let buf = encode(&walk);
+        assert_eq!(&buf[8..10], [2, 0]);
+        assert_eq!(&buf[10..15], [3, 0, b'd', b'i', b'r']);
+        assert_eq!(Twalk::decode(&mut &buf[..]).unwrap(), walk);
+    }
+
+    #[test]
+    fn name_with_nul_is_invalid() {
+        let buf = [3, 0, b'a', 0, b'b'];
+        let err = CString::decode(&mut &buf[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_message_fails() {
+        let buf = encode(&Tread {
+            fid: 1,
+            offset: 0,
+            count: 4096,
+        });
+        let err = Tread::decode(&mut &buf[..buf.len() - 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,1432 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A 9P2000.L server for the directory shared with the guest.
+//!
+//! Each request is handled by its own future, so that a request waiting on file I/O doesn't hold
+//! back the requests behind it. Reads, writes and syncs of open files go through the async
+//! executor, straight between the file and the guest's buffers. Everything else is a quick system
+//! call on an `O_PATH` descriptor and is done inline.
+
+use std::cell::{Cell, RefCell};
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::mem::{self, MaybeUninit};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use base::{AsRawDescriptor, FromRawDescriptor, RawDescriptor};
+use cros_async::async_from;
+use fuse::filesystem::DirectoryIterator;
+use futures::channel::oneshot;
+
+use super::protocol::*;
+use crate::virtio::fs::read_dir::ReadDir;
+use crate::virtio::{Reader, Writer};
+
+// The largest message size the server agrees to. The Linux virtio transport never sends more than
+// this in one message.
+const MAX_MESSAGE_SIZE: u32 = 512 * 1024;
+
+// The length of the header of an `Rread`, which is followed by the data read.
+const RREAD_HEADER_LEN: u32 = HEADER_LEN + mem::size_of::<u32>() as u32;
+
+const EMPTY_CSTR: &[u8] = b"\0";
+const PROC_CSTR: &[u8] = b"/proc\0";
+
+/// Configuration of the 9p server.
+#[derive(Clone, Debug)]
+pub struct P9Config {
+    /// The directory shared with the guest.
+    pub root: Box<Path>,
+    /// Whether names that aren't found are looked up again ignoring ASCII case.
+    pub ascii_casefold: bool,
+}
+
+impl Default for P9Config {
+    fn default() -> Self {
+        P9Config {
+            root: Path::new("/").into(),
+            ascii_casefold: false,
+        }
+    }
+}
+
+fn ebadf() -> io::Error {
+    io::Error::from_raw_os_error(libc::EBADF)
+}
+
+fn einval() -> io::Error {
+    io::Error::from_raw_os_error(libc::EINVAL)
+}
+
+fn eopnotsupp() -> io::Error {
+    io::Error::from_raw_os_error(libc::EOPNOTSUPP)
+}
+
+// Returns the errno reported to the guest for `e`.
+fn errno(e: &io::Error) -> u32 {
+    match e.raw_os_error() {
+        Some(errno) => errno as u32,
+        None => match e.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => libc::EINVAL as u32,
+            _ => libc::EIO as u32,
+        },
+    }
+}
+
+fn stat_at(dir: &File, name: &CStr) -> io::Result<libc::stat64> {
+    let mut st = MaybeUninit::<libc::stat64>::zeroed();
+
+    // Safe because the kernel will only write data in `st` and we check the return value.
+    let res = unsafe {
+        libc::fstatat64(
+            dir.as_raw_descriptor(),
+            name.as_ptr(),
+            st.as_mut_ptr(),
+            libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because the kernel guarantees that the struct is now fully initialized.
+    Ok(unsafe { st.assume_init() })
+}
+
+fn stat(f: &File) -> io::Result<libc::stat64> {
+    // Safe because this is a constant value and a valid C string.
+    let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+    stat_at(f, empty)
+}
+
+// Opens `name` in `dir` with `flags`, returning the new descriptor.
+fn open_at(dir: RawDescriptor, name: &CStr, flags: i32, mode: u32) -> io::Result<File> {
+    // Safe because this doesn't modify any memory and we check the return value.
+    let fd = unsafe { libc::openat(dir, name.as_ptr(), flags | libc::O_CLOEXEC, mode) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because we just opened this descriptor.
+    Ok(unsafe { File::from_raw_descriptor(fd) })
+}
+
+// Returns an `O_PATH` descriptor for `name` in `dir`, not following it if it is a symlink.
+fn open_path(dir: &File, name: &CStr) -> io::Result<File> {
+    open_at(
+        dir.as_raw_descriptor(),
+        name,
+        libc::O_PATH | libc::O_NOFOLLOW,
+        0,
+    )
+}
+
+// Checks that `name` is a single component of a path, so that it can't reach outside of the
+// directory it is looked up in.
+fn check_name(name: &CStr) -> io::Result<()> {
+    match name.to_bytes() {
+        b"" | b"." | b".." => Err(einval()),
+        bytes if bytes.contains(&b'/') => Err(einval()),
+        _ => Ok(()),
+    }
+}
+
+fn qid(st: &libc::stat64) -> Qid {
+    let ty = match st.st_mode & libc::S_IFMT {
+        libc::S_IFDIR => QTDIR,
+        libc::S_IFLNK => QTSYMLINK,
+        _ => QTFILE,
+    };
+    Qid {
+        ty,
+        version: st.st_mtime as u32,
+        path: st.st_ino,
+    }
+}
+
+// Converts the flags of a `Tlopen` or `Tlcreate` to host open flags. Flags that don't make sense
+// for a file opened by the server, such as `O_NOCTTY` or `O_NONBLOCK`, are dropped.
+fn open_flags(flags: u32) -> i32 {
+    const FLAGS: &[(u32, i32)] = &[
+        (P9_EXCL, libc::O_EXCL),
+        (P9_TRUNC, libc::O_TRUNC),
+        (P9_APPEND, libc::O_APPEND),
+        (P9_DSYNC, libc::O_DSYNC),
+        (P9_DIRECTORY, libc::O_DIRECTORY),
+        (P9_NOATIME, libc::O_NOATIME),
+        (P9_SYNC, libc::O_SYNC),
+    ];
+
+    let mut host_flags = match flags & P9_ACCMODE {
+        1 => libc::O_WRONLY,
+        2 => libc::O_RDWR,
+        _ => libc::O_RDONLY,
+    };
+    for &(p9_flag, host_flag) in FLAGS {
+        if flags & p9_flag != 0 {
+            host_flags |= host_flag;
+        }
+    }
+    host_flags
+}
+
+// Writes a message with `body` to `writer`, returning the length of the message.
+fn write_message<T: WireFormat>(
+    writer: &mut Writer,
+    ty: u8,
+    tag: u16,
+    body: &T,
+) -> io::Result<u32> {
+    let size = HEADER_LEN + body.byte_size();
+    Header { size, ty, tag }.encode(writer)?;
+    body.encode(writer)?;
+    Ok(size)
+}
+
+// Writes the reply to the request of type `ty`: `result` on success, an `Rlerror` otherwise.
+fn write_reply<T: WireFormat>(
+    writer: &mut Writer,
+    ty: u8,
+    tag: u16,
+    result: io::Result<T>,
+) -> io::Result<u32> {
+    match result {
+        Ok(body) => write_message(writer, ty + 1, tag, &body),
+        Err(e) => write_message(writer, RLERROR, tag, &Rlerror { ecode: errno(&e) }),
+    }
+}
+
+struct Fid {
+    // An `O_PATH` descriptor for the file.
+    path: File,
+    // The file opened by `Tlopen` or `Tlcreate`. Requests doing I/O on it hold a reference of their
+    // own, so the fid can be clunked while they are in flight.
+    file: Option<Arc<File>>,
+}
+
+/// Serves the directory in its `P9Config` to the guest.
+pub struct Server {
+    fids: RefCell<BTreeMap<u32, Fid>>,
+    // The tags of the requests being handled, each with the `Tflush`es waiting for it to finish.
+    in_flight: RefCell<BTreeMap<u16, Vec<oneshot::Sender<()>>>>,
+    msize: Cell<u32>,
+    // The device and inode of the root directory, which ".." doesn't leave.
+    root: Cell<Option<(u64, u64)>>,
+    // `/proc`, opened before the device is jailed, to reopen `O_PATH` descriptors through
+    // `self/fd`.
+    proc: File,
+    cfg: P9Config,
+}
+
+impl Server {
+    pub fn new(cfg: P9Config) -> io::Result<Server> {
+        // Safe because this is a constant value and a valid C string.
+        let proc_cstr = unsafe { CStr::from_bytes_with_nul_unchecked(PROC_CSTR) };
+        let proc = open_at(
+            libc::AT_FDCWD,
+            proc_cstr,
+            libc::O_PATH | libc::O_NOFOLLOW,
+            0,
+        )?;
+
+        Ok(Server {
+            fids: RefCell::new(BTreeMap::new()),
+            in_flight: RefCell::new(BTreeMap::new()),
+            msize: Cell::new(MAX_MESSAGE_SIZE),
+            root: Cell::new(None),
+            proc,
+            cfg,
+        })
+    }
+
+    pub fn keep_rds(&self) -> Vec<RawDescriptor> {
+        vec![self.proc.as_raw_descriptor()]
+    }
+
+    /// Handles the request in `reader` and writes the reply to `writer`. Returns the length of the
+    /// reply. Several requests can be handled at the same time, and finish in any order.
+    pub async fn handle_message(&self, mut reader: Reader, mut writer: Writer) -> io::Result<u32> {
+        let Header { ty, tag, .. } = Header::decode(&mut reader)?;
+
+        self.in_flight.borrow_mut().insert(tag, Vec::new());
+        let result = self.dispatch(ty, tag, &mut reader, &mut writer).await;
+        // A `Tflush` for this request is only answered after it.
+        if let Some(flushes) = self.in_flight.borrow_mut().remove(&tag) {
+            for flush in flushes {
+                let _ = flush.send(());
+            }
+        }
+        result
+    }
+
+    async fn dispatch(
+        &self,
+        ty: u8,
+        tag: u16,
+        reader: &mut Reader,
+        writer: &mut Writer,
+    ) -> io::Result<u32> {
+        match ty {
+            TREAD => return self.read(tag, reader, writer).await,
+            TWRITE => {
+                let result = match Twrite::decode(reader) {
+                    Ok(msg) => self.write(msg, reader).await,
+                    Err(e) => Err(e),
+                };
+                return write_reply(writer, ty, tag, result);
+            }
+            TFSYNC => {
+                let result = match Tfsync::decode(reader) {
+                    Ok(msg) => self.fsync(msg).await,
+                    Err(e) => Err(e),
+                };
+                return write_reply(writer, ty, tag, result);
+            }
+            TFLUSH => {
+                let result = match Tflush::decode(reader) {
+                    Ok(msg) => self.flush(tag, msg).await,
+                    Err(e) => Err(e),
+                };
+                return write_reply(writer, ty, tag, result);
+            }
+            _ => {}
+        }
+
+        match ty {
+            TVERSION => write_reply(
+                writer,
+                ty,
+                tag,
+                Tversion::decode(reader).map(|m| self.version(m)),
+            ),
+            TAUTH => write_reply::<()>(writer, ty, tag, Err(eopnotsupp())),
+            TATTACH => write_reply(
+                writer,
+                ty,
+                tag,
+                Tattach::decode(reader).and_then(|m| self.attach(m)),
+            ),
+            TWALK => write_reply(
+                writer,
+                ty,
+                tag,
+                Twalk::decode(reader).and_then(|m| self.walk(m)),
+            ),
+            TCLUNK => write_reply(
+                writer,
+                ty,
+                tag,
+                Tclunk::decode(reader).and_then(|m| self.clunk(m.fid)),
+            ),
+            TREMOVE => write_reply(
+                writer,
+                ty,
+                tag,
+                Tremove::decode(reader).and_then(|m| self.remove(m)),
+            ),
+            TSTATFS => write_reply(
+                writer,
+                ty,
+                tag,
+                Tstatfs::decode(reader).and_then(|m| self.statfs(m)),
+            ),
+            TLOPEN => write_reply(
+                writer,
+                ty,
+                tag,
+                Tlopen::decode(reader).and_then(|m| self.lopen(m)),
+            ),
+            TLCREATE => write_reply(
+                writer,
+                ty,
+                tag,
+                Tlcreate::decode(reader).and_then(|m| self.lcreate(m)),
+            ),
+            TSYMLINK => write_reply(
+                writer,
+                ty,
+                tag,
+                Tsymlink::decode(reader).and_then(|m| self.symlink(m)),
+            ),
+            TMKNOD => write_reply(
+                writer,
+                ty,
+                tag,
+                Tmknod::decode(reader).and_then(|m| self.mknod(m)),
+            ),
+            TRENAME => write_reply::<()>(writer, ty, tag, Err(eopnotsupp())),
+            TREADLINK => write_reply(
+                writer,
+                ty,
+                tag,
+                Treadlink::decode(reader).and_then(|m| self.readlink(m)),
+            ),
+            TGETATTR => write_reply(
+                writer,
+                ty,
+                tag,
+                Tgetattr::decode(reader).and_then(|m| self.getattr(m)),
+            ),
+            TSETATTR => write_reply(
+                writer,
+                ty,
+                tag,
+                Tsetattr::decode(reader).and_then(|m| self.setattr(m)),
+            ),
+            TXATTRWALK | TXATTRCREATE => write_reply::<()>(writer, ty, tag, Err(eopnotsupp())),
+            TREADDIR => write_reply(
+                writer,
+                ty,
+                tag,
+                Treaddir::decode(reader).and_then(|m| self.readdir(m)),
+            ),
+            TLOCK => write_reply(
+                writer,
+                ty,
+                tag,
+                Tlock::decode(reader).map(|_| Rlock {
+                    status: P9_LOCK_SUCCESS,
+                }),
+            ),
+            TGETLOCK => write_reply(
+                writer,
+                ty,
+                tag,
+                Tgetlock::decode(reader).map(|m| self.getlock(m)),
+            ),
+            TLINK => write_reply(
+                writer,
+                ty,
+                tag,
+                Tlink::decode(reader).and_then(|m| self.link(m)),
+            ),
+            TMKDIR => write_reply(
+                writer,
+                ty,
+                tag,
+                Tmkdir::decode(reader).and_then(|m| self.mkdir(m)),
+            ),
+            TRENAMEAT => write_reply(
+                writer,
+                ty,
+                tag,
+                Trenameat::decode(reader).and_then(|m| self.renameat(m)),
+            ),
+            TUNLINKAT => write_reply(
+                writer,
+                ty,
+                tag,
+                Tunlinkat::decode(reader).and_then(|m| self.unlinkat(m)),
+            ),
+            _ => write_reply::<()>(writer, ty, tag, Err(eopnotsupp())),
+        }
+    }
+
+    // Runs `f` on `fid`, failing with EBADF if the guest didn't create it.
+    fn with_fid<T, F>(&self, fid: u32, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&Fid) -> io::Result<T>,
+    {
+        f(self.fids.borrow().get(&fid).ok_or_else(ebadf)?)
+    }
+
+    // Returns the file that `fid` was opened as.
+    fn open_file(&self, fid: u32) -> io::Result<Arc<File>> {
+        self.with_fid(fid, |fid| fid.file.clone().ok_or_else(ebadf))
+    }
+
+    // Returns the path of `f` relative to `self.proc`.
+    fn proc_path(f: &File) -> CString {
+        // Safe to unwrap because the formatted number has no nul bytes.
+        CString::new(format!("self/fd/{}", f.as_raw_descriptor())).unwrap()
+    }
+
+    // Opens the file that the `O_PATH` descriptor `path` refers to with `flags`.
+    fn reopen(&self, path: &File, flags: i32) -> io::Result<File> {
+        open_at(
+            self.proc.as_raw_descriptor(),
+            &Self::proc_path(path),
+            flags & !libc::O_NOFOLLOW,
+            0,
+        )
+    }
+
+    fn is_root(&self, dir: &File) -> io::Result<bool> {
+        let st = stat(dir)?;
+        Ok(self.root.get() == Some((st.st_dev, st.st_ino)))
+    }
+
+    // Looks up `name` in `dir`, returning an `O_PATH` descriptor for it.
+    fn lookup(&self, dir: &File, name: &CStr) -> io::Result<(File, libc::stat64)> {
+        let file = if name.to_bytes() == b".." && self.is_root(dir)? {
+            // The guest can't walk out of the shared directory.
+            dir.try_clone()?
+        } else {
+            if name.to_bytes() != b".." {
+                check_name(name)?;
+            }
+            match open_path(dir, name) {
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) && self.cfg.ascii_casefold => {
+                    self.ascii_casefold_lookup(dir, name).map_err(|_| e)?
+                }
+                res => res?,
+            }
+        };
+        let st = stat(&file)?;
+        Ok((file, st))
+    }
+
+    // Looks for an entry of `dir` whose name only differs from `name` in ASCII case.
+    fn ascii_casefold_lookup(&self, dir: &File, name: &CStr) -> io::Result<File> {
+        let dir = self.reopen(dir, libc::O_RDONLY | libc::O_DIRECTORY)?;
+        let mut buf = [0u8; 1024];
+        let mut offset = 0;
+        loop {
+            let mut read_dir = ReadDir::new(&dir, offset, &mut buf[..])?;
+            if read_dir.remaining() == 0 {
+                break;
+            }
+
+            while let Some(entry) = read_dir.next() {
+                offset = entry.offset as libc::off64_t;
+                if name.to_bytes().eq_ignore_ascii_case(entry.name.to_bytes()) {
+                    return open_path(&dir, entry.name);
+                }
+            }
+        }
+        Err(io::Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    fn version(&self, msg: Tversion) -> Rversion {
+        // A new session starts, so the fids of the previous one are gone.
+        self.fids.borrow_mut().clear();
+
+        let msize = min(msg.msize, MAX_MESSAGE_SIZE);
+        self.msize.set(msize);
+        let version = if msg.version.starts_with("9P2000.L") {
+            "9P2000.L"
+        } else {
+            "unknown"
+        };
+        Rversion {
+            msize,
+            version: version.to_string(),
+        }
+    }
+
+    fn attach(&self, msg: Tattach) -> io::Result<Rattach> {
+        // There is no authentication, so there are no fids to attach with.
+        if msg.afid != NOFID {
+            return Err(ebadf());
+        }
+        let mut fids = self.fids.borrow_mut();
+        if fids.contains_key(&msg.fid) {
+            return Err(ebadf());
+        }
+
+        let root = CString::new(self.cfg.root.as_os_str().as_bytes()).map_err(|_| einval())?;
+        let path = open_at(libc::AT_FDCWD, &root, libc::O_PATH | libc::O_DIRECTORY, 0)?;
+        let st = stat(&path)?;
+        self.root.set(Some((st.st_dev, st.st_ino)));
+        fids.insert(msg.fid, Fid { path, file: None });
+        Ok(Rattach { qid: qid(&st) })
+    }
+
+    fn walk(&self, msg: Twalk) -> io::Result<Rwalk> {
+        if msg.wnames.len() > MAX_WALK_NAMES {
+            return Err(einval());
+        }
+
+        let mut fids = self.fids.borrow_mut();
+        if msg.newfid != msg.fid && fids.contains_key(&msg.newfid) {
+            return Err(ebadf());
+        }
+        let fid = fids.get(&msg.fid).ok_or_else(ebadf)?;
+
+        let mut wqids = Vec::with_capacity(msg.wnames.len());
+        let mut path = None;
+        for name in &msg.wnames {
+            match self.lookup(path.as_ref().unwrap_or(&fid.path), name) {
+                Ok((file, st)) => {
+                    wqids.push(qid(&st));
+                    path = Some(file);
+                }
+                Err(e) if wqids.is_empty() => return Err(e),
+                // The guest sees how far the walk went from the qids, and `newfid` isn't created.
+                Err(_) => return Ok(Rwalk { wqids }),
+            }
+        }
+
+        let path = match path {
+            Some(path) => path,
+            None => fid.path.try_clone()?,
+        };
+        fids.insert(msg.newfid, Fid { path, file: None });
+        Ok(Rwalk { wqids })
+    }
+
+    fn clunk(&self, fid: u32) -> io::Result<()> {
+        self.fids
+            .borrow_mut()
+            .remove(&fid)
+            .map(|_| ())
+            .ok_or_else(ebadf)
+    }
+
+    // Files are removed with `Tunlinkat`, which the client falls back from when this fails.
+    fn remove(&self, msg: Tremove) -> io::Result<()> {
+        self.clunk(msg.fid)?;
+        Err(eopnotsupp())
+    }
+
+    fn statfs(&self, msg: Tstatfs) -> io::Result<Rstatfs> {
+        self.with_fid(msg.fid, |fid| {
+            let mut buf = MaybeUninit::<libc::statfs64>::zeroed();
+
+            // Safe because the kernel will only write data in `buf` and we check the return value.
+            let res = unsafe { libc::fstatfs64(fid.path.as_raw_descriptor(), buf.as_mut_ptr()) };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Safe because the kernel guarantees that the struct is now fully initialized.
+            let buf = unsafe { buf.assume_init() };
+            Ok(Rstatfs {
+                ty: buf.f_type as u32,
+                bsize: buf.f_bsize as u32,
+                blocks: buf.f_blocks,
+                bfree: buf.f_bfree,
+                bavail: buf.f_bavail,
+                files: buf.f_files,
+                ffree: buf.f_ffree,
+                // Safe because `fsid_t` is two plain integers.
+                fsid: unsafe { mem::transmute::<libc::fsid_t, u64>(buf.f_fsid) },
+                namelen: buf.f_namelen as u32,
+            })
+        })
+    }
+
+    fn lopen(&self, msg: Tlopen) -> io::Result<Rlopen> {
+        let mut fids = self.fids.borrow_mut();
+        let fid = fids.get_mut(&msg.fid).ok_or_else(ebadf)?;
+        let file = self.reopen(&fid.path, open_flags(msg.flags))?;
+        let st = stat(&fid.path)?;
+        fid.file = Some(Arc::new(file));
+        Ok(Rlopen {
+            qid: qid(&st),
+            iounit: 0,
+        })
+    }
+
+    fn lcreate(&self, msg: Tlcreate) -> io::Result<Rlopen> {
+        check_name(&msg.name)?;
+        let mut fids = self.fids.borrow_mut();
+        let fid = fids.get_mut(&msg.fid).ok_or_else(ebadf)?;
+
+        let file = open_at(
+            fid.path.as_raw_descriptor(),
+            &msg.name,
+            open_flags(msg.flags) | libc::O_CREAT | libc::O_NOFOLLOW,
+            msg.mode & 0o7777,
+        )?;
+        let path = open_path(&fid.path, &msg.name)?;
+        let st = stat(&path)?;
+        // The fid now refers to the new file.
+        fid.path = path;
+        fid.file = Some(Arc::new(file));
+        Ok(Rlopen {
+            qid: qid(&st),
+            iounit: 0,
+        })
+    }
+
+    fn symlink(&self, msg: Tsymlink) -> io::Result<Rmkdir> {
+        check_name(&msg.name)?;
+        self.with_fid(msg.fid, |dir| {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe {
+                libc::symlinkat(
+                    msg.symtgt.as_ptr(),
+                    dir.path.as_raw_descriptor(),
+                    msg.name.as_ptr(),
+                )
+            };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Rmkdir {
+                qid: qid(&stat_at(&dir.path, &msg.name)?),
+            })
+        })
+    }
+
+    fn mknod(&self, msg: Tmknod) -> io::Result<Rmkdir> {
+        check_name(&msg.name)?;
+        // Only files that don't give access to a host device can be created.
+        match msg.mode & libc::S_IFMT {
+            libc::S_IFREG | libc::S_IFIFO | libc::S_IFSOCK => {}
+            _ => return Err(io::Error::from_raw_os_error(libc::EPERM)),
+        }
+        self.with_fid(msg.dfid, |dir| {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe {
+                libc::mknodat(
+                    dir.path.as_raw_descriptor(),
+                    msg.name.as_ptr(),
+                    msg.mode & (libc::S_IFMT | 0o7777),
+                    0,
+                )
+            };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Rmkdir {
+                qid: qid(&stat_at(&dir.path, &msg.name)?),
+            })
+        })
+    }
+
+    fn readlink(&self, msg: Treadlink) -> io::Result<Rreadlink> {
+        self.with_fid(msg.fid, |fid| {
+            let mut buf = vec![0u8; libc::PATH_MAX as usize];
+            // Safe because this is a constant value and a valid C string.
+            let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+
+            // Safe because the kernel will only write `buf.len()` bytes in `buf` and we check the
+            // return value.
+            let res = unsafe {
+                libc::readlinkat(
+                    fid.path.as_raw_descriptor(),
+                    empty.as_ptr(),
+                    buf.as_mut_ptr() as *mut libc::c_char,
+                    buf.len(),
+                )
+            };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            buf.truncate(res as usize);
+            Ok(Rreadlink {
+                target: CString::new(buf).map_err(|_| einval())?,
+            })
+        })
+    }
+
+    // `st_nlink` is a `u64` on x86_64 and a `u32` elsewhere.
+    #[allow(clippy::useless_conversion)]
+    fn getattr(&self, msg: Tgetattr) -> io::Result<Rgetattr> {
+        let st = self.with_fid(msg.fid, |fid| stat(&fid.path))?;
+        Ok(Rgetattr {
+            valid: P9_GETATTR_BASIC,
+            qid: qid(&st),
+            mode: st.st_mode,
+            uid: st.st_uid,
+            gid: st.st_gid,
+            nlink: u64::from(st.st_nlink),
+            rdev: st.st_rdev,
+            size: st.st_size as u64,
+            blksize: st.st_blksize as u64,
+            blocks: st.st_blocks as u64,
+            atime_sec: st.st_atime as u64,
+            atime_nsec: st.st_atime_nsec as u64,
+            mtime_sec: st.st_mtime as u64,
+            mtime_nsec: st.st_mtime_nsec as u64,
+            ctime_sec: st.st_ctime as u64,
+            ctime_nsec: st.st_ctime_nsec as u64,
+            ..Default::default()
+        })
+    }
+
+    fn setattr(&self, msg: Tsetattr) -> io::Result<()> {
+        self.with_fid(msg.fid, |fid| {
+            let proc_path = Self::proc_path(&fid.path);
+
+            if msg.valid & P9_SETATTR_MODE != 0 {
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res = unsafe {
+                    libc::fchmodat(
+                        self.proc.as_raw_descriptor(),
+                        proc_path.as_ptr(),
+                        msg.mode & 0o7777,
+                        0,
+                    )
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            if msg.valid & (P9_SETATTR_UID | P9_SETATTR_GID) != 0 {
+                let uid = if msg.valid & P9_SETATTR_UID != 0 {
+                    msg.uid
+                } else {
+                    u32::MAX
+                };
+                let gid = if msg.valid & P9_SETATTR_GID != 0 {
+                    msg.gid
+                } else {
+                    u32::MAX
+                };
+                // Safe because this is a constant value and a valid C string.
+                let empty = unsafe { CStr::from_bytes_with_nul_unchecked(EMPTY_CSTR) };
+
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res = unsafe {
+                    libc::fchownat(
+                        fid.path.as_raw_descriptor(),
+                        empty.as_ptr(),
+                        uid,
+                        gid,
+                        libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+                    )
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            if msg.valid & P9_SETATTR_SIZE != 0 {
+                let file = self.reopen(&fid.path, libc::O_WRONLY)?;
+
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res = unsafe {
+                    libc::ftruncate64(file.as_raw_descriptor(), msg.size as libc::off64_t)
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            if msg.valid & (P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0 {
+                let time = |set, set_given, sec, nsec| libc::timespec {
+                    tv_sec: sec as libc::time_t,
+                    tv_nsec: match (msg.valid & set != 0, msg.valid & set_given != 0) {
+                        (false, _) => libc::UTIME_OMIT,
+                        (true, false) => libc::UTIME_NOW,
+                        (true, true) => nsec as libc::c_long,
+                    },
+                };
+                let times = [
+                    time(
+                        P9_SETATTR_ATIME,
+                        P9_SETATTR_ATIME_SET,
+                        msg.atime_sec,
+                        msg.atime_nsec,
+                    ),
+                    time(
+                        P9_SETATTR_MTIME,
+                        P9_SETATTR_MTIME_SET,
+                        msg.mtime_sec,
+                        msg.mtime_nsec,
+                    ),
+                ];
+
+                // Safe because this doesn't modify any memory and we check the return value.
+                let res = unsafe {
+                    libc::utimensat(
+                        self.proc.as_raw_descriptor(),
+                        proc_path.as_ptr(),
+                        times.as_ptr(),
+                        0,
+                    )
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn readdir(&self, msg: Treaddir) -> io::Result<Rreaddir> {
+        let dir = self.open_file(msg.fid)?;
+        let count = min(msg.count, self.msize.get().saturating_sub(RREAD_HEADER_LEN)) as usize;
+
+        let mut buf = vec![0u8; count];
+        let mut read_dir = ReadDir::new(&*dir, msg.offset as libc::off64_t, &mut buf[..])?;
+        let mut data = Vec::with_capacity(count);
+        while let Some(entry) = read_dir.next() {
+            let ty = match entry.type_ as u8 {
+                libc::DT_DIR => QTDIR,
+                libc::DT_LNK => QTSYMLINK,
+                _ => QTFILE,
+            };
+            let dirent = Dirent {
+                qid: Qid {
+                    ty,
+                    version: 0,
+                    path: entry.ino,
+                },
+                offset: entry.offset,
+                ty: entry.type_ as u8,
+                name: entry.name.to_owned(),
+            };
+            // The guest asks for the entries after the last one it got next time.
+            if data.len() + dirent.byte_size() as usize > count {
+                break;
+            }
+            dirent.encode(&mut data)?;
+        }
+        Ok(Rreaddir { data: Data(data) })
+    }
+
+    // Locks are only advisory and can't be shared with the host, so every lock is granted.
+    fn getlock(&self, msg: Tgetlock) -> Rgetlock {
+        Rgetlock {
+            ty: P9_LOCK_TYPE_UNLCK,
+            start: msg.start,
+            length: msg.length,
+            proc_id: msg.proc_id,
+            client_id: msg.client_id,
+        }
+    }
+
+    fn link(&self, msg: Tlink) -> io::Result<()> {
+        check_name(&msg.name)?;
+        let fids = self.fids.borrow();
+        let dir = fids.get(&msg.dfid).ok_or_else(ebadf)?;
+        let fid = fids.get(&msg.fid).ok_or_else(ebadf)?;
+        let proc_path = Self::proc_path(&fid.path);
+
+        // Safe because this doesn't modify any memory and we check the return value. Following the
+        // `self/fd` symlink links the file itself, without needing `CAP_DAC_READ_SEARCH`.
+        let res = unsafe {
+            libc::linkat(
+                self.proc.as_raw_descriptor(),
+                proc_path.as_ptr(),
+                dir.path.as_raw_descriptor(),
+                msg.name.as_ptr(),
+                libc::AT_SYMLINK_FOLLOW,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn mkdir(&self, msg: Tmkdir) -> io::Result<Rmkdir> {
+        check_name(&msg.name)?;
+        self.with_fid(msg.dfid, |dir| {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res = unsafe {
+                libc::mkdirat(
+                    dir.path.as_raw_descriptor(),
+                    msg.name.as_ptr(),
+                    msg.mode & 0o7777,
+                )
+            };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Rmkdir {
+                qid: qid(&stat_at(&dir.path, &msg.name)?),
+            })
+        })
+    }
+
+    fn renameat(&self, msg: Trenameat) -> io::Result<()> {
+        check_name(&msg.oldname)?;
+        check_name(&msg.newname)?;
+        let fids = self.fids.borrow();
+        let olddir = fids.get(&msg.olddirfid).ok_or_else(ebadf)?;
+        let newdir = fids.get(&msg.newdirfid).ok_or_else(ebadf)?;
+
+        // Safe because this doesn't modify any memory and we check the return value.
+        let res = unsafe {
+            libc::renameat(
+                olddir.path.as_raw_descriptor(),
+                msg.oldname.as_ptr(),
+                newdir.path.as_raw_descriptor(),
+                msg.newname.as_ptr(),
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn unlinkat(&self, msg: Tunlinkat) -> io::Result<()> {
+        check_name(&msg.name)?;
+        self.with_fid(msg.dirfd, |dir| {
+            let flags = if msg.flags & P9_AT_REMOVEDIR != 0 {
+                libc::AT_REMOVEDIR
+            } else {
+                0
+            };
+
+            // Safe because this doesn't modify any memory and we check the return value.
+            let res =
+                unsafe { libc::unlinkat(dir.path.as_raw_descriptor(), msg.name.as_ptr(), flags) };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    }
+
+    // Reads straight into the guest's buffers, after the header and count of the `Rread`.
+    async fn read(&self, tag: u16, reader: &mut Reader, writer: &mut Writer) -> io::Result<u32> {
+        let mut data = writer.split_at(RREAD_HEADER_LEN as usize);
+        let result = match Tread::decode(reader) {
+            Ok(msg) => self.read_data(msg, &mut data).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(count) => {
+                let size = RREAD_HEADER_LEN + count;
+                Header {
+                    size,
+                    ty: TREAD + 1,
+                    tag,
+                }
+                .encode(writer)?;
+                count.encode(writer)?;
+                Ok(size)
+            }
+            Err(e) => write_reply::<()>(writer, TREAD, tag, Err(e)),
+        }
+    }
+
+    async fn read_data(&self, msg: Tread, data: &mut Writer) -> io::Result<u32> {
+        let file = self.open_file(msg.fid)?;
+        let count = min(msg.count, self.msize.get().saturating_sub(RREAD_HEADER_LEN));
+        let source = async_from(file)?;
+        let read = data
+            .write_from_file_at_fut(&*source, count as usize, msg.offset)
+            .await?;
+        Ok(read as u32)
+    }
+
+    // Writes straight from the guest's buffers, which follow the `Twrite`.
+    async fn write(&self, msg: Twrite, reader: &mut Reader) -> io::Result<Rwrite> {
+        let file = self.open_file(msg.fid)?;
+        let count = min(msg.count as usize, reader.available_bytes());
+        let source = async_from(file)?;
+        let written = reader
+            .read_to_file_at_fut(&*source, count, msg.offset)
+            .await?;
+        Ok(Rwrite {
+            count: written as u32,
+        })
+    }
+
+    async fn fsync(&self, msg: Tfsync) -> io::Result<()> {
+        let file = self.open_file(msg.fid)?;
+        async_from(file)?.fsync().await?;
+        Ok(())
+    }
+
+    // Answers once the request with `msg.oldtag` is answered, if it is still being handled.
+    async fn flush(&self, tag: u16, msg: Tflush) -> io::Result<()> {
+        if msg.oldtag == tag {
+            return Ok(());
+        }
+        let done = match self.in_flight.borrow_mut().get_mut(&msg.oldtag) {
+            Some(flushes) => {
+                let (sender, receiver) = oneshot::channel();
+                flushes.push(sender);
+                receiver
+            }
+            None => return Ok(()),
+        };
+        let _ = done.await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{Read, Write};
+    use std::os::unix::fs::OpenOptionsExt;
+
+    use futures::future::{select, Either};
+    use futures::pin_mut;
+    use tempfile::TempDir;
+    use vm_memory::{GuestAddress, GuestMemory};
+
+    use super::*;
+    use crate::virtio::descriptor_utils::{create_descriptor_chain, DescriptorType};
+
+    const REPLY_LEN: u32 = 0x1000;
+
+    // Places `msg` in its own descriptor chain in `mem`, numbered `slot`, followed by room for the
+    // reply. Returns the reader and writer of the chain and the address of the reply.
+    fn chain(mem: &GuestMemory, slot: u64, msg: &[u8]) -> (Reader, Writer, GuestAddress) {
+        let base = slot * 0x10000;
+        let buffers = GuestAddress(base + 0x1000);
+        mem.write_all_at_addr(msg, buffers).unwrap();
+        let desc = create_descriptor_chain(
+            mem,
+            GuestAddress(base + 0x100),
+            buffers,
+            vec![
+                (DescriptorType::Readable, msg.len() as u32),
+                (DescriptorType::Writable, REPLY_LEN),
+            ],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+        let reader = Reader::new(mem.clone(), desc.clone()).unwrap();
+        let writer = Writer::new(mem.clone(), desc).unwrap();
+        (
+            reader,
+            writer,
+            GuestAddress(base + 0x1000 + msg.len() as u64),
+        )
+    }
+
+    fn message<T: WireFormat>(ty: u8, tag: u16, body: &T, data: &[u8]) -> Vec<u8> {
+        let mut msg = Vec::new();
+        Header {
+            size: HEADER_LEN + body.byte_size() + data.len() as u32,
+            ty,
+            tag,
+        }
+        .encode(&mut msg)
+        .unwrap();
+        body.encode(&mut msg).unwrap();
+        msg.extend_from_slice(data);
+        msg
+    }
+
+    // Decodes the reply at `addr` to a request of type `ty`, which has the length `len`.
+    fn reply<R: WireFormat>(
+        mem: &GuestMemory,
+        addr: GuestAddress,
+        ty: u8,
+        len: u32,
+    ) -> io::Result<R> {
+        let mut buf = vec![0u8; len as usize];
+        mem.read_exact_at_addr(&mut buf, addr).unwrap();
+        let mut buf = &buf[..];
+        let header = Header::decode(&mut buf).unwrap();
+        assert_eq!(header.size, len);
+        if header.ty == RLERROR {
+            let Rlerror { ecode } = Rlerror::decode(&mut buf).unwrap();
+            return Err(io::Error::from_raw_os_error(ecode as i32));
+        }
+        assert_eq!(header.ty, ty + 1);
+        let body = R::decode(&mut buf).unwrap();
+        assert!(buf.is_empty());
+        Ok(body)
+    }
+
+    struct Session {
+        server: Server,
+        mem: GuestMemory,
+        // Keeps the shared directory around for the test.
+        _dir: TempDir,
+        path: Box<Path>,
+    }
+
+    impl Session {
+        // Starts a session on a new temporary directory, attached as fid 0.
+        fn new() -> Session {
+            let dir = TempDir::new().unwrap();
+            let path: Box<Path> = dir.path().into();
+            let cfg = P9Config {
+                root: path.clone(),
+                ascii_casefold: false,
+            };
+            let session = Session {
+                server: Server::new(cfg).unwrap(),
+                mem: GuestMemory::new(&[(GuestAddress(0), 0x100000)]).unwrap(),
+                _dir: dir,
+                path,
+            };
+            let version = Tversion {
+                msize: 0x10000,
+                version: "9P2000.L".to_string(),
+            };
+            session.call::<_, Rversion>(TVERSION, &version).unwrap();
+            let attach = Tattach {
+                fid: 0,
+                afid: NOFID,
+                ..Default::default()
+            };
+            session.call::<_, Rattach>(TATTACH, &attach).unwrap();
+            session
+        }
+
+        fn call_with_data<T: WireFormat, R: WireFormat>(
+            &self,
+            ty: u8,
+            body: &T,
+            data: &[u8],
+        ) -> io::Result<R> {
+            let (reader, writer, addr) = chain(&self.mem, 0, &message(ty, 1, body, data));
+            let len = cros_async::run_one(Box::pin(self.server.handle_message(reader, writer)))
+                .unwrap()
+                .unwrap();
+            reply(&self.mem, addr, ty, len)
+        }
+
+        fn call<T: WireFormat, R: WireFormat>(&self, ty: u8, body: &T) -> io::Result<R> {
+            self.call_with_data(ty, body, &[])
+        }
+
+        fn walk(&self, fid: u32, newfid: u32, names: &[&str]) -> io::Result<Rwalk> {
+            let walk = Twalk {
+                fid,
+                newfid,
+                wnames: names.iter().map(|n| CString::new(*n).unwrap()).collect(),
+            };
+            self.call(TWALK, &walk)
+        }
+
+        // Creates `name` in the root as `fid`, opened for reading and writing.
+        fn create(&self, fid: u32, name: &str) -> Rlopen {
+            self.walk(0, fid, &[]).unwrap();
+            let create = Tlcreate {
+                fid,
+                name: CString::new(name).unwrap(),
+                flags: 2,
+                mode: 0o644,
+                gid: 0,
+            };
+            self.call(TLCREATE, &create).unwrap()
+        }
+
+        fn read(&self, fid: u32, offset: u64, count: u32) -> io::Result<Vec<u8>> {
+            let read = Tread { fid, offset, count };
+            let (reader, writer, addr) = chain(&self.mem, 0, &message(TREAD, 1, &read, &[]));
+            let len = cros_async::run_one(Box::pin(self.server.handle_message(reader, writer)))
+                .unwrap()
+                .unwrap();
+            reply::<Data>(&self.mem, addr, TREAD, len).map(|data| data.0)
+        }
+    }
+
+    #[test]
+    fn version_limits_msize() {
+        let session = Session::new();
+        let version = Tversion {
+            msize: u32::MAX,
+            version: "9P2000.L".to_string(),
+        };
+        let r: Rversion = session.call(TVERSION, &version).unwrap();
+        assert_eq!(r.msize, MAX_MESSAGE_SIZE);
+        assert_eq!(r.version, "9P2000.L");
+
+        let version = Tversion {
+            msize: 0x2000,
+            version: "9P2000.u".to_string(),
+        };
+        let r: Rversion = session.call(TVERSION, &version).unwrap();
+        assert_eq!(r.msize, 0x2000);
+        assert_eq!(r.version, "unknown");
+    }
+
+    #[test]
+    fn version_clunks_fids() {
+        let session = Session::new();
+        let version = Tversion {
+            msize: 0x2000,
+            version: "9P2000.L".to_string(),
+        };
+        session.call::<_, Rversion>(TVERSION, &version).unwrap();
+        let err = session
+            .call::<_, ()>(TCLUNK, &Tclunk { fid: 0 })
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+    }
+
+    #[test]
+    fn create_write_read() {
+        let session = Session::new();
+        let created = session.create(1, "file");
+        assert_eq!(created.qid.ty, QTFILE);
+
+        let write = Twrite {
+            fid: 1,
+            offset: 3,
+            count: 5,
+        };
+        let r: Rwrite = session.call_with_data(TWRITE, &write, b"hello").unwrap();
+        assert_eq!(r.count, 5);
+        let mut contents = Vec::new();
+        File::open(session.path.join("file"))
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, b"\0\0\0hello");
+
+        assert_eq!(session.read(1, 4, 100).unwrap(), b"ello");
+        assert_eq!(session.read(1, 8, 100).unwrap(), b"");
+
+        let getattr = Tgetattr {
+            fid: 1,
+            request_mask: P9_GETATTR_BASIC,
+        };
+        let r: Rgetattr = session.call(TGETATTR, &getattr).unwrap();
+        assert_eq!(r.size, 8);
+        assert_eq!(r.mode & libc::S_IFMT, libc::S_IFREG);
+        assert_eq!(r.qid, created.qid);
+    }
+
+    #[test]
+    fn read_unopened_fid() {
+        let session = Session::new();
+        let err = session.read(0, 0, 10).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+        let err = session.read(7, 0, 10).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+    }
+
+    #[test]
+    fn walk_stays_in_root() {
+        let session = Session::new();
+        let root = session.walk(0, 1, &[".."]).unwrap();
+        let r: Rgetattr = session
+            .call(
+                TGETATTR,
+                &Tgetattr {
+                    fid: 0,
+                    request_mask: P9_GETATTR_BASIC,
+                },
+            )
+            .unwrap();
+        assert_eq!(root.wqids, vec![r.qid]);
+
+        let err = session.walk(0, 2, &["a/b"]).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn partial_walk() {
+        let session = Session::new();
+        let mkdir = Tmkdir {
+            dfid: 0,
+            name: CString::new("dir").unwrap(),
+            mode: 0o755,
+            gid: 0,
+        };
+        let dir: Rmkdir = session.call(TMKDIR, &mkdir).unwrap();
+        assert_eq!(dir.qid.ty, QTDIR);
+
+        let walked = session.walk(0, 1, &["dir", "missing"]).unwrap();
+        assert_eq!(walked.wqids, vec![dir.qid]);
+        // The new fid is only created by a complete walk.
+        let err = session
+            .call::<_, ()>(TCLUNK, &Tclunk { fid: 1 })
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+
+        let err = session.walk(0, 1, &["missing"]).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn readdir_and_unlinkat() {
+        let session = Session::new();
+        session.create(1, "file");
+        session.call::<_, ()>(TCLUNK, &Tclunk { fid: 1 }).unwrap();
+
+        session.walk(0, 2, &[]).unwrap();
+        session
+            .call::<_, Rlopen>(
+                TLOPEN,
+                &Tlopen {
+                    fid: 2,
+                    flags: P9_DIRECTORY,
+                },
+            )
+            .unwrap();
+        let readdir = Treaddir {
+            fid: 2,
+            offset: 0,
+            count: 0x1000,
+        };
+        let r: Rreaddir = session.call(TREADDIR, &readdir).unwrap();
+        let mut data = &r.data.0[..];
+        let mut names = Vec::new();
+        while !data.is_empty() {
+            names.push(Dirent::decode(&mut data).unwrap().name);
+        }
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                CString::new(".").unwrap(),
+                CString::new("..").unwrap(),
+                CString::new("file").unwrap()
+            ]
+        );
+
+        let unlink = Tunlinkat {
+            dirfd: 0,
+            name: CString::new("file").unwrap(),
+            flags: 0,
+        };
+        session.call::<_, ()>(TUNLINKAT, &unlink).unwrap();
+        assert!(!session.path.join("file").exists());
+    }
+
+    #[test]
+    fn read_does_not_block_other_requests() {
+        let session = Session::new();
+        let mknod = Tmknod {
+            dfid: 0,
+            name: CString::new("fifo").unwrap(),
+            mode: libc::S_IFIFO | 0o600,
+            ..Default::default()
+        };
+        session.call::<_, Rmkdir>(TMKNOD, &mknod).unwrap();
+        session.walk(0, 1, &["fifo"]).unwrap();
+        session
+            .call::<_, Rlopen>(TLOPEN, &Tlopen { fid: 1, flags: 2 })
+            .unwrap();
+
+        let read = Tread {
+            fid: 1,
+            offset: 0,
+            count: 16,
+        };
+        let (reader, writer, read_addr) = chain(&session.mem, 1, &message(TREAD, 1, &read, &[]));
+        let getattr = Tgetattr {
+            fid: 0,
+            request_mask: P9_GETATTR_BASIC,
+        };
+        let (reader2, writer2, getattr_addr) =
+            chain(&session.mem, 2, &message(TGETATTR, 2, &getattr, &[]));
+
+        let fut = async {
+            let read = session.server.handle_message(reader, writer);
+            let getattr = session.server.handle_message(reader2, writer2);
+            pin_mut!(read);
+            pin_mut!(getattr);
+
+            // The read waits for data in the fifo, while the getattr is answered.
+            let read = match select(read, getattr).await {
+                Either::Left(_) => panic!("read finished before the fifo was written"),
+                Either::Right((len, read)) => {
+                    reply::<Rgetattr>(&session.mem, getattr_addr, TGETATTR, len.unwrap()).unwrap();
+                    read
+                }
+            };
+
+            OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(session.path.join("fifo"))
+                .unwrap()
+                .write_all(b"data")
+                .unwrap();
+            read.await.unwrap()
+        };
+        let len = cros_async::run_one(Box::pin(fut)).unwrap();
+        let data = reply::<Data>(&session.mem, read_addr, TREAD, len).unwrap();
+        assert_eq!(data.0, b"data");
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base::{error, AsRawDescriptor, RawDescriptor};
+use sync::Mutex;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const LINKTYPE_ETHERNET: u16 = 1;
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_EPB_FLAGS: u16 = 2;
+// Length of the fixed fields of an enhanced packet block, its flags option and the end of its
+// options, around the frame data.
+const ENHANCED_PACKET_BLOCK_OVERHEAD: usize = 28 + 8 + 4 + 4;
+
+/// Which way a captured frame went, as seen from the guest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaptureDirection {
+    /// The guest received the frame.
+    Inbound,
+    /// The guest sent the frame.
+    Outbound,
+}
+
+// The section header and the description of the single interface of a capture.
+fn capture_header() -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend_from_slice(&SECTION_HEADER_BLOCK.to_le_bytes());
+    header.extend_from_slice(&28u32.to_le_bytes());
+    header.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    // Version 1.0, and a section of unspecified length.
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes());
+    header.extend_from_slice(&(-1i64).to_le_bytes());
+    header.extend_from_slice(&28u32.to_le_bytes());
+
+    header.extend_from_slice(&INTERFACE_DESCRIPTION_BLOCK.to_le_bytes());
+    header.extend_from_slice(&20u32.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes());
+    // No limit on the length of captured frames. Timestamps are in the default microseconds.
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&20u32.to_le_bytes());
+    header
+}
+
+// An enhanced packet block holding `frame`, captured `timestamp_us` microseconds after the epoch.
+fn packet_block(frame: &[u8], direction: CaptureDirection, timestamp_us: u64) -> Vec<u8> {
+    let padded_len = (frame.len() + 3) & !3;
+    let block_len = (ENHANCED_PACKET_BLOCK_OVERHEAD + padded_len) as u32;
+    let mut block = Vec::with_capacity(block_len as usize);
+    block.extend_from_slice(&ENHANCED_PACKET_BLOCK.to_le_bytes());
+    block.extend_from_slice(&block_len.to_le_bytes());
+    // Interface id.
+    block.extend_from_slice(&0u32.to_le_bytes());
+    block.extend_from_slice(&((timestamp_us >> 32) as u32).to_le_bytes());
+    block.extend_from_slice(&(timestamp_us as u32).to_le_bytes());
+    // Captured and original length.
+    block.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    block.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    block.extend_from_slice(frame);
+    block.resize(block.len() + padded_len - frame.len(), 0);
+    let flags: u32 = match direction {
+        CaptureDirection::Inbound => 1,
+        CaptureDirection::Outbound => 2,
+    };
+    block.extend_from_slice(&OPT_EPB_FLAGS.to_le_bytes());
+    block.extend_from_slice(&4u16.to_le_bytes());
+    block.extend_from_slice(&flags.to_le_bytes());
+    block.extend_from_slice(&OPT_ENDOFOPT.to_le_bytes());
+    block.extend_from_slice(&0u16.to_le_bytes());
+    block.extend_from_slice(&block_len.to_le_bytes());
+    block
+}
+
+/// A copy of the frames passing through a network device, written to a file in the pcapng format
+/// that Wireshark and tcpdump read.
+///
+/// Clones share the same capture, so that it can be started or stopped for every queue of a
+/// device at once.
+#[derive(Clone, Default)]
+pub struct PacketCapture(Arc<Mutex<Option<File>>>);
+
+impl PacketCapture {
+    /// Creates a capture that isn't running.
+    pub fn new() -> PacketCapture {
+        Default::default()
+    }
+
+    /// Starts writing frames to `file`, replacing the file of a capture that was already running.
+    pub fn start(&self, mut file: File) -> io::Result<()> {
+        file.write_all(&capture_header())?;
+        *self.0.lock() = Some(file);
+        Ok(())
+    }
+
+    /// Stops writing frames. The file is closed once the capture and all of its clones stopped
+    /// using it.
+    pub fn stop(&self) {
+        *self.0.lock() = None;
+    }
+
+    /// Returns whether frames are being captured.
+    pub fn is_active(&self) -> bool {
+        self.0.lock().is_some()
+    }
+
+    /// Returns the descriptor of the file being written, if there is one.
+    pub fn file_descriptor(&self) -> Option<RawDescriptor> {
+        self.0.lock().as_ref().map(|file| file.as_raw_descriptor())
+    }
+
+    /// Adds the ethernet frame `frame` to the capture, if it is running. If writing it fails, the
+    /// capture is stopped.
+    pub fn write_frame(&self, frame: &[u8], direction: CaptureDirection) {
+        let mut file = self.0.lock();
+        if let Some(f) = file.as_mut() {
+            let timestamp_us = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_micros() as u64)
+                .unwrap_or(0);
+            if let Err(e) = f.write_all(&packet_block(frame, direction, timestamp_us)) {
+                error!("failed to write packet capture, stopping it: {}", e);
+                *file = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom};
+    use tempfile::tempfile;
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&buf[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn packet_block_layout() {
+        let block = packet_block(&[1, 2, 3, 4, 5], CaptureDirection::Outbound, 1 << 32 | 7);
+        assert_eq!(block.len(), ENHANCED_PACKET_BLOCK_OVERHEAD + 8);
+        assert_eq!(u32_at(&block, 0), ENHANCED_PACKET_BLOCK);
+        assert_eq!(u32_at(&block, 4), block.len() as u32);
+        assert_eq!(u32_at(&block, 12), 1);
+        assert_eq!(u32_at(&block, 16), 7);
+        assert_eq!(u32_at(&block, 20), 5);
+        assert_eq!(&block[28..36], &[1, 2, 3, 4, 5, 0, 0, 0]);
+        // The flags option.
+        assert_eq!(u32_at(&block, 40), 2);
+        assert_eq!(u32_at(&block, block.len() - 4), block.len() as u32);
+    }
+
+    #[test]
+    fn start_and_stop() {
+        let file = tempfile().unwrap();
+        let mut contents = file.try_clone().unwrap();
+        let capture = PacketCapture::new();
+        capture.write_frame(&[0; 60], CaptureDirection::Inbound);
+        assert!(!capture.is_active());
+
+        capture.start(file).unwrap();
+        capture
+            .clone()
+            .write_frame(&[0; 60], CaptureDirection::Inbound);
+        capture.stop();
+        capture.write_frame(&[0; 60], CaptureDirection::Outbound);
+
+        let mut buf = Vec::new();
+        contents.seek(SeekFrom::Start(0)).unwrap();
+        contents.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf.len(), 28 + 20 + ENHANCED_PACKET_BLOCK_OVERHEAD + 60);
+        assert_eq!(u32_at(&buf, 0), SECTION_HEADER_BLOCK);
+        assert_eq!(u32_at(&buf, 8), BYTE_ORDER_MAGIC);
+        assert_eq!(u32_at(&buf, 28), INTERFACE_DESCRIPTION_BLOCK);
+        assert_eq!(u32_at(&buf, 48), ENHANCED_PACKET_BLOCK);
+    }
+}
@@ -4,25 +4,303 @@
 
 use std::cell::RefCell;
 use std::cmp::min;
+use std::fmt::{self, Display};
 use std::num::Wrapping;
 use std::rc::Rc;
-use std::sync::atomic::{fence, Ordering};
+use std::sync::atomic::{fence, AtomicU16, AtomicU64, Ordering};
+use std::sync::Arc;
 
 use base::error;
 use cros_async::{AsyncError, EventAsync};
+use data_model::{DataInit, Le16, Le32, Le64};
 use virtio_sys::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{GuestAddress, GuestMemory};
 
-use super::{Interrupt, VIRTIO_MSI_NO_VECTOR};
+pub use vm_control::QueueStats;
+
+use super::{
+    DmaAccess, DmaError, DmaMap, Interrupt, VIRTIO_F_ACCESS_PLATFORM, VIRTIO_F_RING_PACKED,
+    VIRTIO_MSI_NO_VECTOR,
+};
 
 const VIRTQ_DESC_F_NEXT: u16 = 0x1;
 const VIRTQ_DESC_F_WRITE: u16 = 0x2;
 #[allow(dead_code)]
 const VIRTQ_DESC_F_INDIRECT: u16 = 0x4;
+// The flags of a packed ring descriptor that say whether it is available or used. A descriptor is
+// available when its AVAIL bit matches the driver's wrap counter and its USED bit doesn't, and
+// used when both match the device's.
+const VIRTQ_DESC_F_AVAIL: u16 = 0x80;
+const VIRTQ_DESC_F_USED: u16 = 0x8000;
+
+// The flags of the event suppression structures of packed rings.
+const RING_EVENT_FLAGS_ENABLE: u16 = 0x0;
+const RING_EVENT_FLAGS_DISABLE: u16 = 0x1;
+const RING_EVENT_FLAGS_DESC: u16 = 0x2;
+const RING_EVENT_WRAP_COUNTER: u16 = 0x8000;
 
 const VIRTQ_USED_F_NO_NOTIFY: u16 = 0x1;
 const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 0x1;
 
+// A descriptor table entry as laid out in guest memory.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtq_desc {
+    addr: Le64,
+    len: Le32,
+    flags: Le16,
+    next: Le16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtq_desc {}
+
+// A packed ring entry as laid out in guest memory.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct pvirtq_desc {
+    addr: Le64,
+    len: Le32,
+    id: Le16,
+    flags: Le16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for pvirtq_desc {}
+
+/// A reason for the device to refuse a descriptor chain made available by the driver.
+#[derive(Debug, PartialEq)]
+pub enum DescriptorChainError {
+    /// The buffer described by a descriptor is not in guest memory.
+    BufferOutOfBounds {
+        index: u16,
+        addr: GuestAddress,
+        len: u32,
+    },
+    /// A descriptor table entry is not in guest memory.
+    EntryOutOfBounds(u16),
+    /// A descriptor index is past the end of the descriptor table.
+    IndexOutOfRange(u16),
+    /// The buffer id of a packed ring chain is not smaller than the ring size.
+    BufferIdOutOfRange(u16),
+    /// The chain starting at the given head visits more descriptors than the table holds.
+    Loop(u16),
+    /// A device-readable descriptor follows a device-writable one.
+    ReadableAfterWritable(u16),
+    /// The buffer described by a descriptor is not mapped for the device by the IOMMU.
+    Unmapped { index: u16, error: DmaError },
+}
+
+impl Display for DescriptorChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DescriptorChainError::*;
+
+        match self {
+            BufferOutOfBounds { index, addr, len } => write!(
+                f,
+                "descriptor {} buffer goes out of bounds: start:{} len:0x{:x}",
+                index, addr, len
+            ),
+            EntryOutOfBounds(index) => {
+                write!(f, "descriptor {} table entry goes out of bounds", index)
+            }
+            IndexOutOfRange(index) => write!(f, "descriptor index {} is out of range", index),
+            BufferIdOutOfRange(id) => write!(f, "buffer id {} is out of range", id),
+            Loop(head) => write!(f, "descriptor chain starting at {} loops", head),
+            ReadableAfterWritable(index) => {
+                write!(f, "readable descriptor {} follows a writable one", index)
+            }
+            Unmapped { index, error } => {
+                write!(f, "descriptor {} buffer is not mapped: {}", index, error)
+            }
+        }
+    }
+}
+
+/// A single descriptor table entry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Descriptor {
+    pub addr: GuestAddress,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+impl Descriptor {
+    /// Returns true if the descriptor is linked to a next one.
+    pub fn has_next(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_NEXT != 0
+    }
+
+    /// Returns true if the device may only write to the descriptor's buffer.
+    pub fn is_write_only(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_WRITE != 0
+    }
+}
+
+/// Reads entry `index` of the descriptor table at `desc_table`, which holds `queue_size`
+/// descriptors. Fails unless the entry and the buffer it describes are in guest memory and the
+/// index of the next descriptor, if any, is inside the table.
+///
+/// With a `dma_map`, the address of the buffer is an I/O virtual address, and the returned
+/// descriptor holds the guest physical address it is mapped to.
+pub fn read_descriptor(
+    mem: &GuestMemory,
+    dma_map: Option<&DmaMap>,
+    desc_table: GuestAddress,
+    queue_size: u16,
+    index: u16,
+) -> Result<Descriptor, DescriptorChainError> {
+    if index >= queue_size {
+        return Err(DescriptorChainError::IndexOutOfRange(index));
+    }
+    let entry = mem
+        .checked_offset(desc_table, u64::from(index) * 16)
+        .ok_or(DescriptorChainError::EntryOutOfBounds(index))?;
+    let raw: virtq_desc = mem
+        .read_obj_from_addr(entry)
+        .map_err(|_| DescriptorChainError::EntryOutOfBounds(index))?;
+    let desc = Descriptor {
+        addr: GuestAddress(raw.addr.into()),
+        len: raw.len.into(),
+        flags: raw.flags.into(),
+        next: raw.next.into(),
+    };
+    let desc = check_buffer(mem, dma_map, index, desc)?;
+    if desc.has_next() && desc.next >= queue_size {
+        return Err(DescriptorChainError::IndexOutOfRange(desc.next));
+    }
+    Ok(desc)
+}
+
+/// Reads entry `index` of the packed ring at `desc_ring`, which holds `queue_size` descriptors,
+/// and returns it with its buffer id. The `next` field of the returned descriptor is the entry
+/// that follows it in the ring. Fails unless the buffer it describes is in guest memory.
+///
+/// With a `dma_map`, the address of the buffer is an I/O virtual address, and the returned
+/// descriptor holds the guest physical address it is mapped to.
+pub fn read_packed_descriptor(
+    mem: &GuestMemory,
+    dma_map: Option<&DmaMap>,
+    desc_ring: GuestAddress,
+    queue_size: u16,
+    index: u16,
+) -> Result<(Descriptor, u16), DescriptorChainError> {
+    if index >= queue_size {
+        return Err(DescriptorChainError::IndexOutOfRange(index));
+    }
+    let entry = mem
+        .checked_offset(desc_ring, u64::from(index) * 16)
+        .ok_or(DescriptorChainError::EntryOutOfBounds(index))?;
+    let raw: pvirtq_desc = mem
+        .read_obj_from_addr(entry)
+        .map_err(|_| DescriptorChainError::EntryOutOfBounds(index))?;
+    let desc = Descriptor {
+        addr: GuestAddress(raw.addr.into()),
+        len: raw.len.into(),
+        flags: raw.flags.into(),
+        next: (index + 1) % queue_size,
+    };
+    Ok((check_buffer(mem, dma_map, index, desc)?, raw.id.into()))
+}
+
+// Translates the buffer address of descriptor `index` with `dma_map`, if any, and checks that the
+// buffer is in guest memory.
+fn check_buffer(
+    mem: &GuestMemory,
+    dma_map: Option<&DmaMap>,
+    index: u16,
+    mut desc: Descriptor,
+) -> Result<Descriptor, DescriptorChainError> {
+    if let Some(dma_map) = dma_map.filter(|_| desc.len > 0) {
+        let access = if desc.is_write_only() {
+            DmaAccess::Write
+        } else {
+            DmaAccess::Read
+        };
+        desc.addr = dma_map
+            .translate(desc.addr.offset(), u64::from(desc.len), access)
+            .map_err(|error| DescriptorChainError::Unmapped { index, error })?;
+    }
+
+    if desc.len > 0 && mem.checked_offset(desc.addr, desc.len as u64 - 1).is_none() {
+        return Err(DescriptorChainError::BufferOutOfBounds {
+            index,
+            addr: desc.addr,
+            len: desc.len,
+        });
+    }
+    Ok(desc)
+}
+
+/// Walks the descriptor chain starting at `head` in the descriptor table at `desc_table`, checking
+/// each descriptor with `read_descriptor`, and returns the number of descriptors in the chain.
+///
+/// Device-readable descriptors may not follow device-writable ones. A chain can't visit more
+/// descriptors than the table holds without coming back to one, so longer chains are rejected as
+/// loops. Only guest memory is read, which makes this usable directly from fuzzers.
+pub fn validate_descriptor_chain(
+    mem: &GuestMemory,
+    dma_map: Option<&DmaMap>,
+    desc_table: GuestAddress,
+    queue_size: u16,
+    head: u16,
+) -> Result<u16, DescriptorChainError> {
+    let mut index = head;
+    let mut count = 0;
+    let mut writable = false;
+    loop {
+        let desc = read_descriptor(mem, dma_map, desc_table, queue_size, index)?;
+        count += 1;
+        if desc.is_write_only() {
+            writable = true;
+        } else if writable {
+            return Err(DescriptorChainError::ReadableAfterWritable(index));
+        }
+        if !desc.has_next() {
+            return Ok(count);
+        }
+        if count == queue_size {
+            return Err(DescriptorChainError::Loop(head));
+        }
+        index = desc.next;
+    }
+}
+
+/// Walks the chain starting at entry `head` of the packed ring at `desc_ring` like
+/// `validate_descriptor_chain`, and returns the number of descriptors in the chain and its buffer
+/// id, which is given by its last descriptor.
+pub fn validate_packed_descriptor_chain(
+    mem: &GuestMemory,
+    dma_map: Option<&DmaMap>,
+    desc_ring: GuestAddress,
+    queue_size: u16,
+    head: u16,
+) -> Result<(u16, u16), DescriptorChainError> {
+    let mut index = head;
+    let mut count = 0;
+    let mut writable = false;
+    loop {
+        let (desc, id) = read_packed_descriptor(mem, dma_map, desc_ring, queue_size, index)?;
+        count += 1;
+        if desc.is_write_only() {
+            writable = true;
+        } else if writable {
+            return Err(DescriptorChainError::ReadableAfterWritable(index));
+        }
+        if !desc.has_next() {
+            if id >= queue_size {
+                return Err(DescriptorChainError::BufferIdOutOfRange(id));
+            }
+            return Ok((count, id));
+        }
+        if count == queue_size {
+            return Err(DescriptorChainError::Loop(head));
+        }
+        index = desc.next;
+    }
+}
+
 /// An iterator over a single descriptor chain.  Not to be confused with AvailIter,
 /// which iterates over the descriptor chain heads in a queue.
 pub struct DescIter {
@@ -58,11 +336,14 @@ impl Iterator for DescIter {
 #[derive(Clone)]
 pub struct DescriptorChain {
     mem: GuestMemory,
+    dma_map: Option<DmaMap>,
     desc_table: GuestAddress,
     queue_size: u16,
     ttl: u16, // used to prevent infinite chain cycles
+    packed: bool,
 
-    /// Index into the descriptor table
+    /// Index into the descriptor table. For packed rings, the buffer id of the chain, which is
+    /// what `Queue::add_used` takes for them.
     pub index: u16,
 
     /// Guest physical address of device specific data
@@ -82,60 +363,64 @@ pub struct DescriptorChain {
 impl DescriptorChain {
     pub(crate) fn checked_new(
         mem: &GuestMemory,
+        dma_map: Option<&DmaMap>,
         desc_table: GuestAddress,
         queue_size: u16,
         index: u16,
         required_flags: u16,
     ) -> Option<DescriptorChain> {
-        if index >= queue_size {
+        // The driver may rewrite the table at any time, so every descriptor is checked again as
+        // the chain is walked, even though `Queue::peek` validated the whole chain.
+        let desc = read_descriptor(mem, dma_map, desc_table, queue_size, index).ok()?;
+        if desc.flags & required_flags != required_flags {
             return None;
         }
 
-        let desc_head = match mem.checked_offset(desc_table, (index as u64) * 16) {
-            Some(a) => a,
-            None => return None,
-        };
-        // These reads can't fail unless Guest memory is hopelessly broken.
-        let addr = GuestAddress(mem.read_obj_from_addr::<u64>(desc_head).unwrap() as u64);
-        if mem.checked_offset(desc_head, 16).is_none() {
-            return None;
-        }
-        let len: u32 = mem.read_obj_from_addr(desc_head.unchecked_add(8)).unwrap();
-        let flags: u16 = mem.read_obj_from_addr(desc_head.unchecked_add(12)).unwrap();
-        let next: u16 = mem.read_obj_from_addr(desc_head.unchecked_add(14)).unwrap();
-        let chain = DescriptorChain {
+        Some(DescriptorChain {
             mem: mem.clone(),
+            dma_map: dma_map.cloned(),
             desc_table,
             queue_size,
             ttl: queue_size,
+            packed: false,
             index,
-            addr,
-            len,
-            flags,
-            next,
-        };
-
-        if chain.is_valid() && chain.flags & required_flags == required_flags {
-            Some(chain)
-        } else {
-            None
-        }
+            addr: desc.addr,
+            len: desc.len,
+            flags: desc.flags,
+            next: desc.next,
+        })
     }
 
-    #[allow(clippy::if_same_then_else)]
-    fn is_valid(&self) -> bool {
-        if self.len > 0
-            && self
-                .mem
-                .checked_offset(self.addr, self.len as u64 - 1u64)
-                .is_none()
-        {
-            false
-        } else if self.has_next() && self.next >= self.queue_size {
-            false
-        } else {
-            true
+    // Like `checked_new`, for the descriptor at entry `position` of a packed ring, in the chain
+    // with buffer id `id`.
+    fn checked_new_packed(
+        mem: &GuestMemory,
+        dma_map: Option<&DmaMap>,
+        desc_ring: GuestAddress,
+        queue_size: u16,
+        position: u16,
+        id: u16,
+        required_flags: u16,
+    ) -> Option<DescriptorChain> {
+        let (desc, _) =
+            read_packed_descriptor(mem, dma_map, desc_ring, queue_size, position).ok()?;
+        if desc.flags & required_flags != required_flags {
+            return None;
         }
+
+        Some(DescriptorChain {
+            mem: mem.clone(),
+            dma_map: dma_map.cloned(),
+            desc_table: desc_ring,
+            queue_size,
+            ttl: queue_size,
+            packed: true,
+            index: id,
+            addr: desc.addr,
+            len: desc.len,
+            flags: desc.flags,
+            next: desc.next,
+        })
     }
 
     /// Gets if this descriptor chain has another descriptor chain linked after it.
@@ -167,14 +452,27 @@ impl DescriptorChain {
         if self.has_next() {
             // Once we see a write-only descriptor, all subsequent descriptors must be write-only.
             let required_flags = self.flags & VIRTQ_DESC_F_WRITE;
-            DescriptorChain::checked_new(
-                &self.mem,
-                self.desc_table,
-                self.queue_size,
-                self.next,
-                required_flags,
-            )
-            .map(|mut c| {
+            let next = if self.packed {
+                DescriptorChain::checked_new_packed(
+                    &self.mem,
+                    self.dma_map.as_ref(),
+                    self.desc_table,
+                    self.queue_size,
+                    self.next,
+                    self.index,
+                    required_flags,
+                )
+            } else {
+                DescriptorChain::checked_new(
+                    &self.mem,
+                    self.dma_map.as_ref(),
+                    self.desc_table,
+                    self.queue_size,
+                    self.next,
+                    required_flags,
+                )
+            };
+            next.map(|mut c| {
                 c.ttl = self.ttl - 1;
                 c
             })
@@ -203,6 +501,24 @@ impl<'a, 'b> Iterator for AvailIter<'a, 'b> {
     }
 }
 
+// The live counters behind `QueueStats`, shared by all clones of a `Queue` so that the transport
+// can read the counters of the copy handed to the device worker.
+#[derive(Default)]
+struct QueueCounters {
+    descriptors: AtomicU64,
+    bytes: AtomicU64,
+    notifications_suppressed: AtomicU64,
+    interrupts: AtomicU64,
+}
+
+// The ring positions the device reached, shared like `QueueCounters` so that the transport can save
+// those of a sleeping device worker. Unlike the counters, they start over when the queue is reset.
+#[derive(Default)]
+struct RingPositions {
+    next_avail: AtomicU16,
+    next_used: AtomicU16,
+}
+
 #[derive(Clone)]
 /// A virtio queue's parameters.
 pub struct Queue {
@@ -218,15 +534,19 @@ pub struct Queue {
     /// MSI-X vector for the queue. Don't care for INTx
     pub vector: u16,
 
-    /// Guest physical address of the descriptor table
+    /// Guest physical address of the descriptor table, or of the ring of a packed queue
     pub desc_table: GuestAddress,
 
-    /// Guest physical address of the available ring
+    /// Guest physical address of the available ring, or of the driver event suppression
+    /// structure of a packed queue
     pub avail_ring: GuestAddress,
 
-    /// Guest physical address of the used ring
+    /// Guest physical address of the used ring, or of the device event suppression structure of
+    /// a packed queue
     pub used_ring: GuestAddress,
 
+    // For packed queues, these and `last_used` are ring positions that count two laps of the
+    // ring, the second one with the wrap counter cleared. See `packed_slot`.
     next_avail: Wrapping<u16>,
     next_used: Wrapping<u16>,
 
@@ -238,6 +558,18 @@ pub struct Queue {
     // processing requests. This is the count of how many are in flight(could be several contexts
     // handling requests in parallel). When this count is zero, notifications are re-enabled.
     notification_disable_count: usize,
+
+    // Translates the addresses the driver gives, once the rings have been translated with it.
+    dma_map: Option<DmaMap>,
+
+    // For packed queues, the number of descriptors in the chain last returned by `peek`, and in
+    // the chain of each buffer id in use. The used ring skips as many entries when a buffer is
+    // returned.
+    peeked_len: u16,
+    chain_lens: Vec<u16>,
+
+    counters: Arc<QueueCounters>,
+    positions: Arc<RingPositions>,
 }
 
 impl Queue {
@@ -256,6 +588,25 @@ impl Queue {
             features: 0,
             last_used: Wrapping(0),
             notification_disable_count: 0,
+            dma_map: None,
+            peeked_len: 0,
+            chain_lens: Vec::new(),
+            counters: Arc::new(QueueCounters::default()),
+            positions: Arc::new(RingPositions::default()),
+        }
+    }
+
+    /// Returns the counters of the work done on this queue. They are shared by all clones of the
+    /// queue and are not cleared by `reset`.
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            descriptors: self.counters.descriptors.load(Ordering::Relaxed),
+            bytes: self.counters.bytes.load(Ordering::Relaxed),
+            notifications_suppressed: self
+                .counters
+                .notifications_suppressed
+                .load(Ordering::Relaxed),
+            interrupts: self.counters.interrupts.load(Ordering::Relaxed),
         }
     }
 
@@ -265,6 +616,38 @@ impl Queue {
         min(self.size, self.max_size)
     }
 
+    /// Returns the positions the device reached in the rings, `next_avail` and `next_used`, which
+    /// `set_ring_positions` takes to carry on from there. Like `stats`, they are those of the
+    /// clone of the queue that last moved.
+    pub fn ring_positions(&self) -> (u16, u16) {
+        (
+            self.positions.next_avail.load(Ordering::Relaxed),
+            self.positions.next_used.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Carries on from ring positions returned by `ring_positions`, as if the buffers before them
+    /// had been used and the guest interrupted for them.
+    pub fn set_ring_positions(&mut self, next_avail: u16, next_used: u16) {
+        self.next_avail = Wrapping(next_avail);
+        self.next_used = Wrapping(next_used);
+        self.last_used = Wrapping(next_used);
+        self.publish_avail();
+        self.publish_used();
+    }
+
+    fn publish_avail(&self) {
+        self.positions
+            .next_avail
+            .store(self.next_avail.0, Ordering::Relaxed);
+    }
+
+    fn publish_used(&self) {
+        self.positions
+            .next_used
+            .store(self.next_used.0, Ordering::Relaxed);
+    }
+
     /// Reset queue to a clean state
     pub fn reset(&mut self) {
         self.ready = false;
@@ -277,6 +660,91 @@ impl Queue {
         self.next_used = Wrapping(0);
         self.features = 0;
         self.last_used = Wrapping(0);
+        self.dma_map = None;
+        self.peeked_len = 0;
+        self.chain_lens.clear();
+        self.positions = Arc::new(RingPositions::default());
+    }
+
+    // Whether the driver set the queue up as a packed ring.
+    fn is_packed(&self) -> bool {
+        self.features & (1u64 << VIRTIO_F_RING_PACKED) != 0
+    }
+
+    // Returns the ring entry of a packed queue position, and the wrap counter that goes with it.
+    // Positions count two laps of the ring, as the wrap counter starts set and flips with every
+    // lap.
+    fn packed_slot(&self, position: Wrapping<u16>) -> (u16, bool) {
+        let queue_size = self.actual_size();
+        (position.0 % queue_size, position.0 < queue_size)
+    }
+
+    // Returns packed queue position `position` moved `count` entries ahead.
+    fn packed_advance(&self, position: Wrapping<u16>, count: u16) -> Wrapping<u16> {
+        let laps = 2 * u32::from(self.actual_size());
+        Wrapping(((u32::from(position.0) + u32::from(count)) % laps) as u16)
+    }
+
+    // Returns the flags of entry `slot` of a packed ring.
+    fn packed_desc_flags(&self, mem: &GuestMemory, slot: u16) -> u16 {
+        let flags_addr = self.desc_table.unchecked_add(u64::from(slot) * 16 + 14);
+        mem.read_obj_from_addr(flags_addr).unwrap()
+    }
+
+    // Whether the driver made the descriptor at `next_avail` available in a packed ring.
+    fn packed_has_available(&self, mem: &GuestMemory) -> bool {
+        let (slot, wrap) = self.packed_slot(self.next_avail);
+        let flags = self.packed_desc_flags(mem, slot);
+        let avail = flags & VIRTQ_DESC_F_AVAIL != 0;
+        let used = flags & VIRTQ_DESC_F_USED != 0;
+        avail == wrap && used != wrap
+    }
+
+    /// Makes the queue translate the addresses the driver gives it with `dma_map`, if the driver
+    /// acked VIRTIO_F_ACCESS_PLATFORM. Otherwise the driver gives guest physical addresses and
+    /// nothing changes.
+    ///
+    /// The addresses of the rings are translated right away, so this must be called once the
+    /// driver has set them up and before the queue is used. The driver keeps the rings mapped for
+    /// as long as the queue is live.
+    pub fn set_dma_map(&mut self, dma_map: &DmaMap) -> Result<(), DmaError> {
+        if self.features & (1 << VIRTIO_F_ACCESS_PLATFORM) == 0 || self.dma_map.is_some() {
+            return Ok(());
+        }
+
+        let queue_size = u64::from(self.actual_size());
+        if self.is_packed() {
+            // The device writes used descriptors to the ring itself.
+            let desc_table = dma_map.translate(
+                self.desc_table.offset(),
+                16 * queue_size,
+                DmaAccess::ReadWrite,
+            )?;
+            let avail_ring = dma_map.translate(self.avail_ring.offset(), 4, DmaAccess::Read)?;
+            let used_ring = dma_map.translate(self.used_ring.offset(), 4, DmaAccess::ReadWrite)?;
+            self.desc_table = desc_table;
+            self.avail_ring = avail_ring;
+            self.used_ring = used_ring;
+            self.dma_map = Some(dma_map.clone());
+            return Ok(());
+        }
+        let desc_table =
+            dma_map.translate(self.desc_table.offset(), 16 * queue_size, DmaAccess::Read)?;
+        let avail_ring = dma_map.translate(
+            self.avail_ring.offset(),
+            6 + 2 * queue_size,
+            DmaAccess::Read,
+        )?;
+        let used_ring = dma_map.translate(
+            self.used_ring.offset(),
+            6 + 8 * queue_size,
+            DmaAccess::ReadWrite,
+        )?;
+        self.desc_table = desc_table;
+        self.avail_ring = avail_ring;
+        self.used_ring = used_ring;
+        self.dma_map = Some(dma_map.clone());
+        Ok(())
     }
 
     pub fn is_valid(&self, mem: &GuestMemory) -> bool {
@@ -284,14 +752,22 @@ impl Queue {
         let desc_table = self.desc_table;
         let desc_table_size = 16 * queue_size;
         let avail_ring = self.avail_ring;
-        let avail_ring_size = 6 + 2 * queue_size;
         let used_ring = self.used_ring;
-        let used_ring_size = 6 + 8 * queue_size;
+        // Packed rings only have the event suppression structures besides the descriptors, and
+        // don't need to be a power of 2 in size.
+        let (avail_ring_size, used_ring_size, size_ok) = if self.is_packed() {
+            (4, 4, self.size <= 0x8000)
+        } else {
+            (
+                6 + 2 * queue_size,
+                6 + 8 * queue_size,
+                self.size.is_power_of_two(),
+            )
+        };
         if !self.ready {
             error!("attempt to use virtio queue that is not marked ready");
             false
-        } else if self.size > self.max_size || self.size == 0 || (self.size & (self.size - 1)) != 0
-        {
+        } else if self.size > self.max_size || self.size == 0 || !size_ok {
             error!("virtio queue with invalid size: {}", self.size);
             false
         } else if desc_table
@@ -413,33 +889,147 @@ impl Queue {
         if !self.is_valid(mem) {
             return None;
         }
+        if self.is_packed() {
+            return self.peek_packed(mem);
+        }
 
         let queue_size = self.actual_size();
         let avail_index = self.get_avail_index(mem);
         let avail_len = avail_index - self.next_avail;
 
-        if avail_len.0 > queue_size || self.next_avail == avail_index {
+        if avail_len.0 > queue_size {
             return None;
         }
 
-        let desc_idx_addr_offset = 4 + (u64::from(self.next_avail.0 % queue_size) * 2);
-        let desc_idx_addr = mem.checked_offset(self.avail_ring, desc_idx_addr_offset)?;
+        while self.next_avail != avail_index {
+            let desc_idx_addr_offset = 4 + (u64::from(self.next_avail.0 % queue_size) * 2);
+            let desc_idx_addr = mem.checked_offset(self.avail_ring, desc_idx_addr_offset)?;
+            let descriptor_index: u16 = mem.read_obj_from_addr(desc_idx_addr).ok()?;
+
+            let dma_map = self.dma_map.as_ref();
+            match validate_descriptor_chain(
+                mem,
+                dma_map,
+                self.desc_table,
+                queue_size,
+                descriptor_index,
+            ) {
+                Ok(_) => {
+                    return DescriptorChain::checked_new(
+                        mem,
+                        dma_map,
+                        self.desc_table,
+                        queue_size,
+                        descriptor_index,
+                        0,
+                    )
+                }
+                Err(e) => {
+                    // Skip the chain instead of stopping at it so that it can't wedge the queue,
+                    // and return it unused so the driver gets its descriptors back. A head index
+                    // past the end of the table names no chain and can only be skipped.
+                    error!("virtio queue: returning malformed descriptor chain: {}", e);
+                    self.pop_peeked(mem);
+                    if descriptor_index < queue_size {
+                        self.add_used(mem, descriptor_index, 0);
+                    }
+                }
+            }
+        }
+        None
+    }
 
-        // This index is checked below in checked_new.
-        let descriptor_index: u16 = mem.read_obj_from_addr(desc_idx_addr).unwrap();
+    fn peek_packed(&mut self, mem: &GuestMemory) -> Option<DescriptorChain> {
+        let queue_size = self.actual_size();
+        if self.chain_lens.len() != usize::from(queue_size) {
+            self.chain_lens = vec![0; usize::from(queue_size)];
+        }
 
-        DescriptorChain::checked_new(mem, self.desc_table, queue_size, descriptor_index, 0)
+        while self.packed_has_available(mem) {
+            // Make sure the rest of the chain isn't read before the flags that made it available.
+            fence(Ordering::Acquire);
+
+            let (slot, _) = self.packed_slot(self.next_avail);
+            let dma_map = self.dma_map.as_ref();
+            match validate_packed_descriptor_chain(mem, dma_map, self.desc_table, queue_size, slot)
+            {
+                Ok((len, id)) => {
+                    self.peeked_len = len;
+                    self.chain_lens[usize::from(id)] = len;
+                    return DescriptorChain::checked_new_packed(
+                        mem,
+                        dma_map,
+                        self.desc_table,
+                        queue_size,
+                        slot,
+                        id,
+                        0,
+                    );
+                }
+                Err(e) => {
+                    // The driver only takes back the ring entries of a chain once it is used, so
+                    // the chain is returned unused instead of being skipped.
+                    error!("virtio queue: returning malformed descriptor chain: {}", e);
+                    let (len, id) = self.packed_chain_extent(mem, slot);
+                    self.peeked_len = len;
+                    self.pop_peeked(mem);
+                    if id < queue_size {
+                        self.chain_lens[usize::from(id)] = len;
+                        self.add_used(mem, id, 0);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // Returns the number of entries in the packed ring chain starting at `slot`, going by their
+    // flags alone, and the buffer id of its last one.
+    fn packed_chain_extent(&self, mem: &GuestMemory, slot: u16) -> (u16, u16) {
+        let queue_size = self.actual_size();
+        let mut index = slot;
+        let mut count = 1;
+        loop {
+            let entry = self.desc_table.unchecked_add(u64::from(index) * 16);
+            let desc: pvirtq_desc = mem.read_obj_from_addr(entry).unwrap();
+            if desc.flags.to_native() & VIRTQ_DESC_F_NEXT == 0 || count == queue_size {
+                return (count, desc.id.to_native());
+            }
+            index = (index + 1) % queue_size;
+            count += 1;
+        }
     }
 
     /// Remove the first available descriptor chain from the queue.
     /// This function should only be called immediately following `peek`.
     pub fn pop_peeked(&mut self, mem: &GuestMemory) {
+        if self.is_packed() {
+            self.next_avail = self.packed_advance(self.next_avail, self.peeked_len);
+            self.publish_avail();
+            return;
+        }
         self.next_avail += Wrapping(1);
-        if self.features & ((1u64) << VIRTIO_RING_F_EVENT_IDX) != 0 {
+        self.publish_avail();
+        // While notifications are disabled the stale avail_event keeps the driver from kicking.
+        if self.features & ((1u64) << VIRTIO_RING_F_EVENT_IDX) != 0
+            && self.notification_disable_count == 0
+        {
             self.set_avail_event(mem, self.next_avail);
         }
     }
 
+    /// Returns true if the driver made descriptor chains available that haven't been popped yet.
+    pub fn has_available(&self, mem: &GuestMemory) -> bool {
+        if !self.is_valid(mem) {
+            return false;
+        }
+        if self.is_packed() {
+            self.packed_has_available(mem)
+        } else {
+            self.get_avail_index(mem) != self.next_avail
+        }
+    }
+
     /// If a new DescriptorHead is available, returns one and removes it from the queue.
     pub fn pop(&mut self, mem: &GuestMemory) -> Option<DescriptorChain> {
         let descriptor_chain = self.peek(mem);
@@ -480,17 +1070,66 @@ impl Queue {
             return;
         }
 
-        let used_ring = self.used_ring;
-        let next_used = (self.next_used.0 % self.actual_size()) as usize;
-        let used_elem = used_ring.unchecked_add((4 + next_used * 8) as u64);
+        if self.is_packed() {
+            if !self.add_used_packed(mem, desc_index, len) {
+                return;
+            }
+        } else {
+            let used_ring = self.used_ring;
+            let next_used = (self.next_used.0 % self.actual_size()) as usize;
+            let used_elem = used_ring.unchecked_add((4 + next_used * 8) as u64);
+
+            // These writes can't fail as we are guaranteed to be within the descriptor ring.
+            mem.write_obj_at_addr(desc_index as u32, used_elem).unwrap();
+            mem.write_obj_at_addr(len as u32, used_elem.unchecked_add(4))
+                .unwrap();
+
+            self.next_used += Wrapping(1);
+            self.set_used_index(mem, self.next_used);
+            self.publish_used();
+        }
+
+        self.counters.descriptors.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes
+            .fetch_add(u64::from(len), Ordering::Relaxed);
+    }
+
+    // Writes a used descriptor for buffer `id` to a packed ring, and skips the ring entries its
+    // chain took. Returns false if the buffer isn't in use.
+    fn add_used_packed(&mut self, mem: &GuestMemory, id: u16, len: u32) -> bool {
+        let chain_len = match self.chain_lens.get_mut(usize::from(id)) {
+            Some(chain_len) if *chain_len > 0 => chain_len,
+            _ => {
+                error!("attempted to add unused buffer {} to used ring", id);
+                return false;
+            }
+        };
+        let count = *chain_len;
+        *chain_len = 0;
+
+        let (slot, wrap) = self.packed_slot(self.next_used);
+        let entry = self.desc_table.unchecked_add(u64::from(slot) * 16);
+        let mut flags = if wrap {
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED
+        } else {
+            0
+        };
+        if len > 0 {
+            flags |= VIRTQ_DESC_F_WRITE;
+        }
 
         // These writes can't fail as we are guaranteed to be within the descriptor ring.
-        mem.write_obj_at_addr(desc_index as u32, used_elem).unwrap();
-        mem.write_obj_at_addr(len as u32, used_elem.unchecked_add(4))
+        mem.write_obj_at_addr(len, entry.unchecked_add(8)).unwrap();
+        mem.write_obj_at_addr(id, entry.unchecked_add(12)).unwrap();
+        // The driver may read the descriptor as soon as its flags mark it used.
+        fence(Ordering::Release);
+        mem.write_obj_at_addr(flags, entry.unchecked_add(14))
             .unwrap();
 
-        self.next_used += Wrapping(1);
-        self.set_used_index(mem, self.next_used);
+        self.next_used = self.packed_advance(self.next_used, count);
+        self.publish_used();
+        true
     }
 
     /// Enable / Disable guest notify device that requests are available on
@@ -499,11 +1138,28 @@ impl Queue {
         if enable {
             self.notification_disable_count -= 1;
         } else {
+            if self.notification_disable_count == 0 {
+                self.counters
+                    .notifications_suppressed
+                    .fetch_add(1, Ordering::Relaxed);
+            }
             self.notification_disable_count += 1;
         }
 
-        if self.features & ((1u64) << VIRTIO_RING_F_EVENT_IDX) != 0 {
-            self.set_avail_event(mem, self.get_avail_index(mem));
+        if self.is_packed() {
+            let flags = if self.notification_disable_count > 0 {
+                RING_EVENT_FLAGS_DISABLE
+            } else {
+                RING_EVENT_FLAGS_ENABLE
+            };
+            mem.write_obj_at_addr(flags, self.used_ring.unchecked_add(2))
+                .unwrap();
+        } else if self.features & ((1u64) << VIRTIO_RING_F_EVENT_IDX) != 0 {
+            // There is no flag to suppress notifications with event indices. Leaving avail_event
+            // behind does, and catching it up asks for the next one.
+            if self.notification_disable_count == 0 {
+                self.set_avail_event(mem, self.get_avail_index(mem));
+            }
         } else {
             self.set_used_flag(
                 mem,
@@ -515,7 +1171,12 @@ impl Queue {
 
     // Check Whether guest enable interrupt injection or not.
     fn available_interrupt_enabled(&self, mem: &GuestMemory) -> bool {
-        if self.features & ((1u64) << VIRTIO_RING_F_EVENT_IDX) != 0 {
+        // The used index written by `add_used` must be visible before reading what the driver
+        // asked for, or a driver that just changed its mind could miss an interrupt.
+        fence(Ordering::SeqCst);
+        if self.is_packed() {
+            self.packed_interrupt_enabled(mem)
+        } else if self.features & ((1u64) << VIRTIO_RING_F_EVENT_IDX) != 0 {
             let used_event = self.get_used_event(mem);
             // if used_event >= self.last_used, driver handle interrupt quickly enough, new
             // interrupt could be injected.
@@ -527,6 +1188,32 @@ impl Queue {
         }
     }
 
+    // Checks the driver event suppression structure of a packed queue.
+    fn packed_interrupt_enabled(&self, mem: &GuestMemory) -> bool {
+        let off_wrap: u16 = mem.read_obj_from_addr(self.avail_ring).unwrap();
+        let flags: u16 = mem
+            .read_obj_from_addr(self.avail_ring.unchecked_add(2))
+            .unwrap();
+        match flags & 0x3 {
+            RING_EVENT_FLAGS_DISABLE => false,
+            RING_EVENT_FLAGS_DESC if self.features & (1u64 << VIRTIO_RING_F_EVENT_IDX) != 0 => {
+                // The driver asked for an interrupt once the descriptor at a position is used.
+                // Same as with split rings, but positions only go around two laps of the ring.
+                let queue_size = self.actual_size();
+                let laps = 2 * u32::from(queue_size);
+                let mut event = u32::from(off_wrap & !RING_EVENT_WRAP_COUNTER);
+                if off_wrap & RING_EVENT_WRAP_COUNTER == 0 {
+                    event += u32::from(queue_size);
+                }
+                let event = event % laps;
+                let new = u32::from(self.next_used.0);
+                let old = u32::from(self.last_used.0);
+                (new + 2 * laps - event - 1) % laps < (new + laps - old) % laps
+            }
+            _ => true,
+        }
+    }
+
     /// inject interrupt into guest on this queue
     /// return true: interrupt is injected into guest for this queue
     ///        false: interrupt isn't injected
@@ -534,6 +1221,7 @@ impl Queue {
         if self.available_interrupt_enabled(mem) {
             self.last_used = self.next_used;
             interrupt.signal_used_queue(self.vector);
+            self.counters.interrupts.fetch_add(1, Ordering::Relaxed);
             true
         } else {
             false
@@ -851,4 +1539,433 @@ mod tests {
         // should inject interrupt again.
         assert_eq!(queue.trigger_interrupt(&mem, &interrupt), true);
     }
+
+    #[test]
+    fn queue_stats() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&vec![(GuestAddress(0x0), GUEST_MEMORY_SIZE)]).unwrap();
+        setup_vq(&mut queue, &mem);
+        let device_queue = queue.clone();
+
+        let interrupt = Interrupt::new(
+            Arc::new(AtomicUsize::new(0)),
+            Event::new().unwrap(),
+            Event::new().unwrap(),
+            None,
+            10,
+        );
+
+        queue.set_notify(&mem, false);
+        queue.set_notify(&mem, false);
+        queue.add_used(&mem, 0x0, BUFFER_LEN);
+        queue.add_used(&mem, 0x1, 0x10);
+        queue.set_notify(&mem, true);
+        queue.set_notify(&mem, true);
+        assert!(queue.trigger_interrupt(&mem, &interrupt));
+
+        let stats = QueueStats {
+            descriptors: 2,
+            bytes: u64::from(BUFFER_LEN) + 0x10,
+            notifications_suppressed: 1,
+            interrupts: 1,
+        };
+        // Clones handed to device workers share the counters.
+        assert_eq!(device_queue.stats(), stats);
+        assert_eq!(
+            vec![stats, QueueStats::default(), stats]
+                .into_iter()
+                .sum::<QueueStats>(),
+            stats + stats
+        );
+    }
+
+    #[test]
+    fn ring_positions() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        setup_vq(&mut queue, &mem);
+
+        // The transport sees how far the clone handed to the device worker got.
+        let mut device_queue = queue.clone();
+        device_queue.add_used(&mem, 0x0, BUFFER_LEN);
+        device_queue.add_used(&mem, 0x1, BUFFER_LEN);
+        assert_eq!(queue.ring_positions(), (0, 2));
+
+        let mut restored = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        setup_vq(&mut restored, &mem);
+        restored.set_ring_positions(0, 2);
+        restored.add_used(&mem, 0x2, BUFFER_LEN);
+        let used_idx: u16 = mem
+            .read_obj_from_addr(GuestAddress(USED_OFFSET + 2))
+            .unwrap();
+        assert_eq!(used_idx, 3);
+        assert_eq!(restored.ring_positions(), (0, 3));
+
+        queue.reset();
+        assert_eq!(queue.ring_positions(), (0, 0));
+    }
+
+    fn write_desc(mem: &GuestMemory, index: u16, addr: u64, len: u32, flags: u16, next: u16) {
+        let desc = Desc {
+            addr: Le64::from(addr),
+            len: Le32::from(len),
+            flags: Le16::from(flags),
+            next: Le16::from(next),
+        };
+        mem.write_obj_at_addr(desc, GuestAddress(DESC_OFFSET + u64::from(index) * 16))
+            .unwrap();
+    }
+
+    fn validate(mem: &GuestMemory, head: u16) -> Result<u16, DescriptorChainError> {
+        validate_descriptor_chain(
+            mem,
+            None,
+            GuestAddress(DESC_OFFSET),
+            QUEUE_SIZE as u16,
+            head,
+        )
+    }
+
+    #[test]
+    fn validate_chain() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        write_desc(&mem, 0, BUFFER_OFFSET, BUFFER_LEN, VIRTQ_DESC_F_NEXT, 3);
+        write_desc(&mem, 3, BUFFER_OFFSET, BUFFER_LEN, VIRTQ_DESC_F_WRITE, 0);
+        assert_eq!(validate(&mem, 0), Ok(2));
+        assert_eq!(validate(&mem, 3), Ok(1));
+        assert_eq!(
+            validate(&mem, QUEUE_SIZE as u16),
+            Err(DescriptorChainError::IndexOutOfRange(QUEUE_SIZE as u16))
+        );
+    }
+
+    #[test]
+    fn validate_chain_loop() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        write_desc(&mem, 0, BUFFER_OFFSET, BUFFER_LEN, VIRTQ_DESC_F_NEXT, 0);
+        assert_eq!(validate(&mem, 0), Err(DescriptorChainError::Loop(0)));
+
+        write_desc(&mem, 0, BUFFER_OFFSET, BUFFER_LEN, VIRTQ_DESC_F_NEXT, 1);
+        write_desc(&mem, 1, BUFFER_OFFSET, BUFFER_LEN, VIRTQ_DESC_F_NEXT, 0);
+        assert_eq!(validate(&mem, 1), Err(DescriptorChainError::Loop(1)));
+    }
+
+    #[test]
+    fn validate_chain_out_of_bounds() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        write_desc(&mem, 0, GUEST_MEMORY_SIZE - 0x10, 0x20, 0, 0);
+        assert_eq!(
+            validate(&mem, 0),
+            Err(DescriptorChainError::BufferOutOfBounds {
+                index: 0,
+                addr: GuestAddress(GUEST_MEMORY_SIZE - 0x10),
+                len: 0x20,
+            })
+        );
+
+        write_desc(&mem, 0, BUFFER_OFFSET, BUFFER_LEN, VIRTQ_DESC_F_NEXT, 0x20);
+        assert_eq!(
+            validate(&mem, 0),
+            Err(DescriptorChainError::IndexOutOfRange(0x20))
+        );
+
+        let table = GuestAddress(GUEST_MEMORY_SIZE - 0x10);
+        assert_eq!(
+            validate_descriptor_chain(&mem, None, table, QUEUE_SIZE as u16, 1),
+            Err(DescriptorChainError::EntryOutOfBounds(1))
+        );
+    }
+
+    #[test]
+    fn validate_chain_readable_after_writable() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        write_desc(
+            &mem,
+            0,
+            BUFFER_OFFSET,
+            BUFFER_LEN,
+            VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            1,
+        );
+        write_desc(&mem, 1, BUFFER_OFFSET, BUFFER_LEN, 0, 0);
+        assert_eq!(
+            validate(&mem, 0),
+            Err(DescriptorChainError::ReadableAfterWritable(1))
+        );
+    }
+
+    #[test]
+    fn queue_event_idx_notify_disabled() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        setup_vq(&mut queue, &mem);
+        queue.ready = true;
+
+        // Calculating the address of avail_event within Used structure
+        let avail_event_offset: u64 =
+            unsafe { &(*(::std::ptr::null::<Used>())).avail_event as *const _ as u64 };
+        let avail_event_address = GuestAddress(USED_OFFSET + avail_event_offset);
+        let avail_event = || {
+            mem.read_obj_from_addr::<Le16>(avail_event_address)
+                .unwrap()
+                .to_native()
+        };
+
+        write_desc(&mem, 0, BUFFER_OFFSET, BUFFER_LEN, 0, 0);
+        let mut avail = Avail::default();
+        avail.idx = Le16::from(3);
+        mem.write_obj_at_addr(avail, GuestAddress(AVAIL_OFFSET))
+            .unwrap();
+
+        // Every chain taken asks the driver to kick for the next one.
+        queue.pop(&mem).unwrap();
+        assert_eq!(avail_event(), 1);
+
+        // While notifications are disabled, avail_event stays behind so the driver doesn't kick.
+        queue.set_notify(&mem, false);
+        queue.pop(&mem).unwrap();
+        assert_eq!(avail_event(), 1);
+
+        queue.set_notify(&mem, true);
+        assert_eq!(avail_event(), 3);
+        queue.pop(&mem).unwrap();
+        assert_eq!(avail_event(), 3);
+    }
+
+    fn write_packed_desc(mem: &GuestMemory, slot: u16, len: u32, id: u16, flags: u16) {
+        let desc = pvirtq_desc {
+            addr: Le64::from(BUFFER_OFFSET),
+            len: Le32::from(len),
+            id: Le16::from(id),
+            flags: Le16::from(flags),
+        };
+        mem.write_obj_at_addr(desc, GuestAddress(DESC_OFFSET + u64::from(slot) * 16))
+            .unwrap();
+    }
+
+    fn read_packed_desc(mem: &GuestMemory, slot: u16) -> (u32, u16, u16) {
+        let desc: pvirtq_desc = mem
+            .read_obj_from_addr(GuestAddress(DESC_OFFSET + u64::from(slot) * 16))
+            .unwrap();
+        (desc.len.into(), desc.id.into(), desc.flags.into())
+    }
+
+    fn setup_packed_vq(queue: &mut Queue, mem: &GuestMemory, features: u64) {
+        // Both event suppression structures start out enabling notifications.
+        mem.write_obj_at_addr(0u32, GuestAddress(AVAIL_OFFSET))
+            .unwrap();
+        mem.write_obj_at_addr(0u32, GuestAddress(USED_OFFSET))
+            .unwrap();
+        queue.desc_table = GuestAddress(DESC_OFFSET);
+        queue.avail_ring = GuestAddress(AVAIL_OFFSET);
+        queue.used_ring = GuestAddress(USED_OFFSET);
+        queue.ack_features(1 << VIRTIO_F_RING_PACKED | features);
+        queue.ready = true;
+    }
+
+    #[test]
+    fn packed_ring_wraps() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        // Packed rings needn't be a power of 2 in size.
+        queue.size = 3;
+        setup_packed_vq(&mut queue, &mem, 0);
+
+        // Buffer 1 is a readable and a writable descriptor.
+        write_packed_desc(
+            &mem,
+            0,
+            BUFFER_LEN,
+            0,
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_NEXT,
+        );
+        write_packed_desc(
+            &mem,
+            1,
+            BUFFER_LEN,
+            1,
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_WRITE,
+        );
+        let chain = queue.pop(&mem).unwrap();
+        assert_eq!(chain.index, 1);
+        assert!(chain.is_read_only());
+        let next = chain.next_descriptor().unwrap();
+        assert_eq!(next.index, 1);
+        assert!(next.is_write_only());
+        assert!(next.next_descriptor().is_none());
+        assert!(queue.pop(&mem).is_none());
+
+        // The used descriptor takes the place of the head of the chain.
+        queue.add_used(&mem, 1, 0x10);
+        assert_eq!(
+            read_packed_desc(&mem, 0),
+            (
+                0x10,
+                1,
+                VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED | VIRTQ_DESC_F_WRITE
+            )
+        );
+
+        // Buffer 0 takes the last entry and the first one of the second lap, where the driver's
+        // wrap counter is cleared.
+        write_packed_desc(
+            &mem,
+            2,
+            BUFFER_LEN,
+            0,
+            VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_NEXT,
+        );
+        write_packed_desc(&mem, 0, BUFFER_LEN, 0, VIRTQ_DESC_F_USED);
+        let chain = queue.pop(&mem).unwrap();
+        assert_eq!(chain.index, 0);
+        assert!(chain.next_descriptor().is_some());
+        assert!(!queue.has_available(&mem));
+
+        queue.add_used(&mem, 0, 0);
+        assert_eq!(
+            read_packed_desc(&mem, 2),
+            (0, 0, VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED)
+        );
+        // A buffer that isn't in use can't be returned.
+        queue.add_used(&mem, 0, 0);
+        assert_eq!(queue.next_used, Wrapping(4));
+        assert_eq!(queue.next_avail, Wrapping(4));
+        assert_eq!(queue.stats().descriptors, 2);
+    }
+
+    #[test]
+    fn packed_ring_event_suppression() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        setup_packed_vq(&mut queue, &mem, 1 << VIRTIO_RING_F_EVENT_IDX);
+
+        let interrupt = Interrupt::new(
+            Arc::new(AtomicUsize::new(0)),
+            Event::new().unwrap(),
+            Event::new().unwrap(),
+            None,
+            10,
+        );
+        let use_buffer = |queue: &mut Queue, slot: u16| {
+            write_packed_desc(&mem, slot, BUFFER_LEN, slot, VIRTQ_DESC_F_AVAIL);
+            let chain = queue.pop(&mem).unwrap();
+            queue.add_used(&mem, chain.index, 0);
+        };
+
+        queue.set_notify(&mem, false);
+        let device_flags: u16 = mem
+            .read_obj_from_addr(GuestAddress(USED_OFFSET + 2))
+            .unwrap();
+        assert_eq!(device_flags, RING_EVENT_FLAGS_DISABLE);
+        queue.set_notify(&mem, true);
+        let device_flags: u16 = mem
+            .read_obj_from_addr(GuestAddress(USED_OFFSET + 2))
+            .unwrap();
+        assert_eq!(device_flags, RING_EVENT_FLAGS_ENABLE);
+
+        mem.write_obj_at_addr(RING_EVENT_FLAGS_DISABLE, GuestAddress(AVAIL_OFFSET + 2))
+            .unwrap();
+        use_buffer(&mut queue, 0);
+        assert_eq!(queue.trigger_interrupt(&mem, &interrupt), false);
+
+        // The driver asks for an interrupt once entry 2 of the first lap is used.
+        mem.write_obj_at_addr(2 | RING_EVENT_WRAP_COUNTER, GuestAddress(AVAIL_OFFSET))
+            .unwrap();
+        mem.write_obj_at_addr(RING_EVENT_FLAGS_DESC, GuestAddress(AVAIL_OFFSET + 2))
+            .unwrap();
+        use_buffer(&mut queue, 1);
+        assert_eq!(queue.trigger_interrupt(&mem, &interrupt), false);
+        use_buffer(&mut queue, 2);
+        assert_eq!(queue.trigger_interrupt(&mem, &interrupt), true);
+        use_buffer(&mut queue, 3);
+        assert_eq!(queue.trigger_interrupt(&mem, &interrupt), false);
+
+        mem.write_obj_at_addr(RING_EVENT_FLAGS_ENABLE, GuestAddress(AVAIL_OFFSET + 2))
+            .unwrap();
+        assert_eq!(queue.trigger_interrupt(&mem, &interrupt), true);
+    }
+
+    #[test]
+    fn pop_returns_malformed_chain() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        setup_vq(&mut queue, &mem);
+        queue.ready = true;
+
+        write_desc(&mem, 0, BUFFER_OFFSET, BUFFER_LEN, VIRTQ_DESC_F_NEXT, 0);
+        write_desc(&mem, 1, BUFFER_OFFSET, BUFFER_LEN, 0, 0);
+        let mut avail = Avail::default();
+        avail.ring[0] = Le16::from(0);
+        avail.ring[1] = Le16::from(1);
+        avail.idx = Le16::from(2);
+        mem.write_obj_at_addr(avail, GuestAddress(AVAIL_OFFSET))
+            .unwrap();
+
+        let chain = queue.pop(&mem).unwrap();
+        assert_eq!(chain.index, 1);
+        assert!(queue.pop(&mem).is_none());
+        assert_eq!(queue.next_avail, Wrapping(2));
+
+        // The malformed chain was returned unused.
+        let used: Used = mem.read_obj_from_addr(GuestAddress(USED_OFFSET)).unwrap();
+        assert_eq!(used.idx.to_native(), 1);
+        assert_eq!(used.used_elem_ring[0].id.to_native(), 0);
+        assert_eq!(used.used_elem_ring[0].len.to_native(), 0);
+
+        // A head past the end of the descriptor table is skipped without being used.
+        avail.ring[2] = Le16::from(QUEUE_SIZE as u16);
+        avail.idx = Le16::from(3);
+        mem.write_obj_at_addr(avail, GuestAddress(AVAIL_OFFSET))
+            .unwrap();
+        assert!(queue.pop(&mem).is_none());
+        assert_eq!(queue.next_avail, Wrapping(3));
+        let used: Used = mem.read_obj_from_addr(GuestAddress(USED_OFFSET)).unwrap();
+        assert_eq!(used.idx.to_native(), 1);
+    }
+
+    #[test]
+    fn pop_translates_iovas() {
+        const RING_IOVA: u64 = 0x10_0000;
+        const BUFFER_IOVA: u64 = 0x20_0000;
+
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        setup_vq(&mut queue, &mem);
+        queue.desc_table = GuestAddress(RING_IOVA + DESC_OFFSET);
+        queue.avail_ring = GuestAddress(RING_IOVA + AVAIL_OFFSET);
+        queue.used_ring = GuestAddress(RING_IOVA + USED_OFFSET);
+        queue.ack_features(1 << VIRTIO_F_ACCESS_PLATFORM);
+        queue.ready = true;
+
+        let dma_map = DmaMap::new();
+        dma_map
+            .map(RING_IOVA, GuestAddress(0), 0x1000, true, true)
+            .unwrap();
+        dma_map
+            .map(
+                BUFFER_IOVA,
+                GuestAddress(BUFFER_OFFSET),
+                u64::from(BUFFER_LEN),
+                true,
+                false,
+            )
+            .unwrap();
+        queue.set_dma_map(&dma_map).unwrap();
+        assert_eq!(queue.desc_table, GuestAddress(DESC_OFFSET));
+
+        write_desc(&mem, 0, BUFFER_IOVA, BUFFER_LEN, 0, 0);
+        // The device may not write to a buffer the driver only mapped for reading.
+        write_desc(&mem, 1, BUFFER_IOVA, BUFFER_LEN, VIRTQ_DESC_F_WRITE, 0);
+        let mut avail = Avail::default();
+        avail.ring[0] = Le16::from(1);
+        avail.ring[1] = Le16::from(0);
+        avail.idx = Le16::from(2);
+        mem.write_obj_at_addr(avail, GuestAddress(AVAIL_OFFSET))
+            .unwrap();
+
+        let chain = queue.pop(&mem).unwrap();
+        assert_eq!(chain.index, 0);
+        assert_eq!(chain.addr, GuestAddress(BUFFER_OFFSET));
+        assert!(queue.pop(&mem).is_none());
+    }
 }
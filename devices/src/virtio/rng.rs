@@ -10,7 +10,8 @@ use std::thread;
 use base::{error, warn, AsRawDescriptor, Event, PollToken, RawDescriptor, WaitContext};
 use vm_memory::GuestMemory;
 
-use super::{Interrupt, Queue, VirtioDevice, Writer, TYPE_RNG};
+use super::{Interrupt, Queue, VirtioDevice, Writer, TYPE_RNG, VIRTIO_F_RING_PACKED};
+use crate::Suspendable;
 
 const QUEUE_SIZE: u16 = 256;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
@@ -81,6 +82,7 @@ impl Worker {
             Ok(pc) => pc,
             Err(e) => {
                 error!("failed creating WaitContext: {}", e);
+                self.interrupt.signal_needs_reset();
                 return;
             }
         };
@@ -107,13 +109,16 @@ impl Worker {
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
-                    Token::Kill => break 'wait,
+                    Token::Kill => return,
                 }
             }
             if needs_interrupt {
-                self.interrupt.signal_used_queue(self.queue.vector);
+                self.queue.trigger_interrupt(&self.mem, &self.interrupt);
             }
         }
+
+        // Only errors get here. Resetting the device starts a new worker.
+        self.interrupt.signal_needs_reset();
     }
 }
 
@@ -133,7 +138,7 @@ impl Rng {
             kill_evt: None,
             worker_thread: None,
             random_file: Some(random_file),
-            virtio_features,
+            virtio_features: virtio_features | 1 << VIRTIO_F_RING_PACKED,
         })
     }
 }
@@ -246,3 +251,5 @@ impl VirtioDevice for Rng {
         false
     }
 }
+
+impl Suspendable for Rng {}
@@ -0,0 +1,211 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use sync::Mutex;
+
+/// Length of an ethernet address.
+pub const ETH_ALEN: usize = 6;
+/// Length of the part of an ethernet frame that `RxFilter::accepts` looks at: the addresses and an
+/// 802.1Q tag, if there is one.
+pub const RX_FILTER_HEADER_LEN: usize = 2 * ETH_ALEN + 4;
+
+const ETH_P_8021Q: u16 = 0x8100;
+const VLAN_VID_MASK: u16 = 0xfff;
+const BROADCAST: [u8; ETH_ALEN] = [0xff; ETH_ALEN];
+
+struct RxFilterState {
+    promiscuous: bool,
+    all_multicast: bool,
+    mac: Option<[u8; ETH_ALEN]>,
+    unicast: HashSet<[u8; ETH_ALEN]>,
+    multicast: HashSet<[u8; ETH_ALEN]>,
+    // None if the driver didn't ask for VLAN filtering.
+    vlans: Option<HashSet<u16>>,
+}
+
+/// The receive filter a virtio-net driver programs through the control queue.
+///
+/// Until the driver turns it off, the filter is promiscuous, so drivers that never touch it see
+/// every frame. Clones share the same filter, so the worker serving the control queue can change
+/// it for the workers of every receive queue.
+#[derive(Clone)]
+pub struct RxFilter(Arc<Mutex<RxFilterState>>);
+
+impl RxFilter {
+    /// Creates a promiscuous filter. Tagged frames are only let through for VLANs that were added
+    /// if `vlan_filtering` is set.
+    pub fn new(vlan_filtering: bool) -> RxFilter {
+        RxFilter(Arc::new(Mutex::new(RxFilterState {
+            promiscuous: true,
+            all_multicast: false,
+            mac: None,
+            unicast: HashSet::new(),
+            multicast: HashSet::new(),
+            vlans: if vlan_filtering {
+                Some(HashSet::new())
+            } else {
+                None
+            },
+        })))
+    }
+
+    pub fn is_promiscuous(&self) -> bool {
+        self.0.lock().promiscuous
+    }
+
+    pub fn set_promiscuous(&self, promiscuous: bool) {
+        self.0.lock().promiscuous = promiscuous;
+    }
+
+    pub fn set_all_multicast(&self, all_multicast: bool) {
+        self.0.lock().all_multicast = all_multicast;
+    }
+
+    /// Sets the primary address of the device. Unicast frames are let through for any address
+    /// until it is set.
+    pub fn set_mac(&self, mac: [u8; ETH_ALEN]) {
+        self.0.lock().mac = Some(mac);
+    }
+
+    /// Replaces the additional unicast and the multicast addresses to let through.
+    pub fn set_mac_table(&self, unicast: &[[u8; ETH_ALEN]], multicast: &[[u8; ETH_ALEN]]) {
+        let mut state = self.0.lock();
+        state.unicast = unicast.iter().cloned().collect();
+        state.multicast = multicast.iter().cloned().collect();
+    }
+
+    /// Lets through frames tagged with VLAN `vid`. Returns false if `vid` isn't a valid VLAN id
+    /// or VLAN filtering is off.
+    pub fn add_vlan(&self, vid: u16) -> bool {
+        if vid > VLAN_VID_MASK {
+            return false;
+        }
+        match &mut self.0.lock().vlans {
+            Some(vlans) => {
+                vlans.insert(vid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops letting through frames tagged with VLAN `vid`. Returns false if `vid` isn't a valid
+    /// VLAN id or VLAN filtering is off.
+    pub fn del_vlan(&self, vid: u16) -> bool {
+        if vid > VLAN_VID_MASK {
+            return false;
+        }
+        match &mut self.0.lock().vlans {
+            Some(vlans) => {
+                vlans.remove(&vid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns whether the ethernet frame starting with `head` should be given to the driver.
+    /// `head` holds up to the first `RX_FILTER_HEADER_LEN` bytes of the frame.
+    pub fn accepts(&self, head: &[u8]) -> bool {
+        let state = self.0.lock();
+        if state.promiscuous || head.len() < ETH_ALEN {
+            return true;
+        }
+
+        if let Some(vlans) = &state.vlans {
+            if head.len() >= RX_FILTER_HEADER_LEN
+                && u16::from_be_bytes([head[12], head[13]]) == ETH_P_8021Q
+            {
+                let vid = u16::from_be_bytes([head[14], head[15]]) & VLAN_VID_MASK;
+                if !vlans.contains(&vid) {
+                    return false;
+                }
+            }
+        }
+
+        let mut dest = [0u8; ETH_ALEN];
+        dest.copy_from_slice(&head[..ETH_ALEN]);
+        if dest[0] & 1 != 0 {
+            dest == BROADCAST || state.all_multicast || state.multicast.contains(&dest)
+        } else {
+            match state.mac {
+                Some(mac) => mac == dest || state.unicast.contains(&dest),
+                None => true,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; ETH_ALEN] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    const OTHER: [u8; ETH_ALEN] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x57];
+    const MULTICAST: [u8; ETH_ALEN] = [0x01, 0x00, 0x5e, 0x00, 0x00, 0x01];
+
+    fn frame(dest: [u8; ETH_ALEN], vid: Option<u16>) -> Vec<u8> {
+        let mut frame = dest.to_vec();
+        frame.extend_from_slice(&OTHER);
+        match vid {
+            Some(vid) => {
+                frame.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+                frame.extend_from_slice(&vid.to_be_bytes());
+            }
+            None => frame.extend_from_slice(&[0x08, 0x00, 0x45, 0x00]),
+        }
+        frame
+    }
+
+    #[test]
+    fn promiscuous_by_default() {
+        let filter = RxFilter::new(true);
+        assert!(filter.accepts(&frame(OTHER, None)));
+        assert!(filter.accepts(&frame(MULTICAST, Some(5))));
+    }
+
+    #[test]
+    fn addresses() {
+        let filter = RxFilter::new(false);
+        filter.set_promiscuous(false);
+        // Any unicast address goes through until the device's own is known.
+        assert!(filter.accepts(&frame(OTHER, None)));
+
+        filter.set_mac(MAC);
+        assert!(filter.accepts(&frame(MAC, None)));
+        assert!(!filter.accepts(&frame(OTHER, None)));
+        assert!(filter.accepts(&frame(BROADCAST, None)));
+        assert!(!filter.accepts(&frame(MULTICAST, None)));
+
+        filter.set_mac_table(&[OTHER], &[MULTICAST]);
+        assert!(filter.accepts(&frame(OTHER, None)));
+        assert!(filter.accepts(&frame(MULTICAST, None)));
+
+        filter.set_mac_table(&[], &[]);
+        filter.set_all_multicast(true);
+        assert!(!filter.accepts(&frame(OTHER, None)));
+        assert!(filter.accepts(&frame(MULTICAST, None)));
+    }
+
+    #[test]
+    fn vlans() {
+        let filter = RxFilter::new(true);
+        filter.set_promiscuous(false);
+        assert!(filter.accepts(&frame(MAC, None)));
+        assert!(!filter.accepts(&frame(MAC, Some(5))));
+        assert!(filter.add_vlan(5));
+        assert!(filter.accepts(&frame(MAC, Some(5))));
+        assert!(filter.del_vlan(5));
+        assert!(!filter.accepts(&frame(MAC, Some(5))));
+        assert!(!filter.add_vlan(4096));
+
+        let filter = RxFilter::new(false);
+        filter.set_promiscuous(false);
+        assert!(filter.accepts(&frame(MAC, Some(5))));
+        assert!(!filter.add_vlan(5));
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::sync::Arc;
+
+use sync::Mutex;
+use virtio_sys::virtio_net::{
+    VIRTIO_NET_HASH_REPORT_IPv4, VIRTIO_NET_HASH_REPORT_IPv6, VIRTIO_NET_HASH_REPORT_TCPv4,
+    VIRTIO_NET_HASH_REPORT_TCPv6, VIRTIO_NET_HASH_REPORT_UDPv4, VIRTIO_NET_HASH_REPORT_UDPv6,
+    VIRTIO_NET_RSS_HASH_TYPE_IPv4, VIRTIO_NET_RSS_HASH_TYPE_IPv6, VIRTIO_NET_RSS_HASH_TYPE_TCPv4,
+    VIRTIO_NET_RSS_HASH_TYPE_TCPv6, VIRTIO_NET_RSS_HASH_TYPE_UDPv4, VIRTIO_NET_RSS_HASH_TYPE_UDPv6,
+    VIRTIO_NET_HASH_REPORT_NONE,
+};
+
+use super::ETH_ALEN;
+
+/// Longest hash key a driver may set, which is long enough to hash the addresses and ports of an
+/// IPv6 packet.
+pub const RX_HASH_MAX_KEY_LEN: usize = 40;
+/// The hash types `RxHash` can compute. Packets with IPv6 extension headers are hashed on their
+/// addresses only.
+pub const RX_HASH_SUPPORTED_TYPES: u32 = VIRTIO_NET_RSS_HASH_TYPE_IPv4
+    | VIRTIO_NET_RSS_HASH_TYPE_TCPv4
+    | VIRTIO_NET_RSS_HASH_TYPE_UDPv4
+    | VIRTIO_NET_RSS_HASH_TYPE_IPv6
+    | VIRTIO_NET_RSS_HASH_TYPE_TCPv6
+    | VIRTIO_NET_RSS_HASH_TYPE_UDPv6;
+/// Length of the part of an ethernet frame that `RxHash::hash` looks at: the ethernet header with
+/// an 802.1Q tag, an IPv4 header with the most options, and the ports that follow it.
+pub const RX_HASH_HEADER_LEN: usize = 2 * ETH_ALEN + 4 + 2 + 60 + 4;
+
+const ETH_P_8021Q: u16 = 0x8100;
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+// The more fragments flag and the fragment offset of an IPv4 header.
+const IPV4_FRAGMENT_MASK: u16 = 0x3fff;
+
+#[derive(Default)]
+struct RxHashConfig {
+    hash_types: u32,
+    key: Vec<u8>,
+}
+
+/// The hash of received packets a virtio-net driver asks for with VIRTIO_NET_CTRL_MQ_HASH_CONFIG,
+/// which the device reports in the virtio-net header of each frame.
+///
+/// Nothing is hashed until the driver sets the hash types. Clones share the same configuration,
+/// so the worker serving the control queue can change it for the workers of every receive queue.
+#[derive(Clone, Default)]
+pub struct RxHash(Arc<Mutex<RxHashConfig>>);
+
+// The Toeplitz hash of `input` with `key`, as used by RSS. Bits past the end of the key count as
+// zeros.
+fn toeplitz(key: &[u8], input: &[u8]) -> u32 {
+    let key_bit = |i: usize| key.get(i / 8).map_or(0, |b| (b >> (7 - i % 8)) & 1) as u32;
+    let mut window = (0..32).fold(0, |window, i| window << 1 | key_bit(i));
+    let mut hash = 0;
+    for (i, byte) in input.iter().enumerate() {
+        for bit in 0..8 {
+            if byte & (0x80 >> bit) != 0 {
+                hash ^= window;
+            }
+            window = window << 1 | key_bit(32 + i * 8 + bit);
+        }
+    }
+    hash
+}
+
+fn be16(buf: &[u8], offset: usize) -> Option<u16> {
+    let bytes = buf.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+impl RxHash {
+    /// Creates a configuration that hashes nothing.
+    pub fn new() -> RxHash {
+        Default::default()
+    }
+
+    /// Hashes packets of `hash_types` with `key` from now on. Returns false, leaving the
+    /// configuration alone, if a hash type isn't supported or the key is too long.
+    pub fn configure(&self, hash_types: u32, key: &[u8]) -> bool {
+        if hash_types & !RX_HASH_SUPPORTED_TYPES != 0 || key.len() > RX_HASH_MAX_KEY_LEN {
+            return false;
+        }
+        let mut config = self.0.lock();
+        config.hash_types = hash_types;
+        config.key = key.to_vec();
+        true
+    }
+
+    /// Returns the hash of the ethernet frame starting with `frame`, which needs to hold at least
+    /// `RX_HASH_HEADER_LEN` bytes of it, and the type of hash it is as a VIRTIO_NET_HASH_REPORT_*
+    /// value. The hash is zero for frames that weren't hashed.
+    pub fn hash(&self, frame: &[u8]) -> (u32, u16) {
+        let config = self.0.lock();
+        let has_type = |hash_type: u32| config.hash_types & hash_type != 0;
+
+        let mut ethertype_offset = 2 * ETH_ALEN;
+        if be16(frame, ethertype_offset) == Some(ETH_P_8021Q) {
+            ethertype_offset += 4;
+        }
+        let l3 = frame.get(ethertype_offset + 2..).unwrap_or_default();
+
+        // The addresses to hash, the protocol and offset of the transport header unless the
+        // addresses are all that can be hashed, and the hash types and reports of the network layer
+        // and of TCP and UDP.
+        let (addrs, l4, l3_report, l4_types) = match be16(frame, ethertype_offset) {
+            Some(ETH_P_IP) if l3.len() >= 20 && l3[0] >> 4 == 4 => {
+                let header_len = (l3[0] & 0xf) as usize * 4;
+                let fragment = be16(l3, 6).unwrap_or(0) & IPV4_FRAGMENT_MASK != 0;
+                let l4 = if header_len >= 20 && !fragment {
+                    Some((l3[9], header_len))
+                } else {
+                    None
+                };
+                (
+                    &l3[12..20],
+                    l4,
+                    (VIRTIO_NET_RSS_HASH_TYPE_IPv4, VIRTIO_NET_HASH_REPORT_IPv4),
+                    [
+                        (VIRTIO_NET_RSS_HASH_TYPE_TCPv4, VIRTIO_NET_HASH_REPORT_TCPv4),
+                        (VIRTIO_NET_RSS_HASH_TYPE_UDPv4, VIRTIO_NET_HASH_REPORT_UDPv4),
+                    ],
+                )
+            }
+            Some(ETH_P_IPV6) if l3.len() >= 40 && l3[0] >> 4 == 6 => (
+                &l3[8..40],
+                Some((l3[6], 40)),
+                (VIRTIO_NET_RSS_HASH_TYPE_IPv6, VIRTIO_NET_HASH_REPORT_IPv6),
+                [
+                    (VIRTIO_NET_RSS_HASH_TYPE_TCPv6, VIRTIO_NET_HASH_REPORT_TCPv6),
+                    (VIRTIO_NET_RSS_HASH_TYPE_UDPv6, VIRTIO_NET_HASH_REPORT_UDPv6),
+                ],
+            ),
+            _ => return (0, VIRTIO_NET_HASH_REPORT_NONE as u16),
+        };
+
+        let mut input = addrs.to_vec();
+        let l4_type = match l4 {
+            Some((IPPROTO_TCP, offset)) => Some((l4_types[0], offset)),
+            Some((IPPROTO_UDP, offset)) => Some((l4_types[1], offset)),
+            _ => None,
+        };
+        if let Some(((hash_type, report), offset)) = l4_type {
+            if let (true, Some(ports)) = (has_type(hash_type), l3.get(offset..offset + 4)) {
+                input.extend_from_slice(ports);
+                return (toeplitz(&config.key, &input), report as u16);
+            }
+        }
+        let (hash_type, report) = l3_report;
+        if has_type(hash_type) {
+            (toeplitz(&config.key, &input), report as u16)
+        } else {
+            (0, VIRTIO_NET_HASH_REPORT_NONE as u16)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The key and packets of the verification suite in Microsoft's RSS documentation.
+    const KEY: [u8; RX_HASH_MAX_KEY_LEN] = [
+        0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3, 0x8f,
+        0xb0, 0xd0, 0xca, 0x2b, 0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3, 0x80, 0x30,
+        0xf2, 0x0c, 0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac, 0x01, 0xfa,
+    ];
+
+    fn ipv4_frame(protocol: u8, fragment: u16) -> Vec<u8> {
+        let mut frame = vec![0u8; 2 * ETH_ALEN];
+        frame.extend_from_slice(&ETH_P_IP.to_be_bytes());
+        let mut ip = [0u8; 20];
+        ip[0] = 0x45;
+        ip[6..8].copy_from_slice(&fragment.to_be_bytes());
+        ip[9] = protocol;
+        ip[12..16].copy_from_slice(&[66, 9, 149, 187]);
+        ip[16..20].copy_from_slice(&[161, 142, 100, 80]);
+        frame.extend_from_slice(&ip);
+        frame.extend_from_slice(&2794u16.to_be_bytes());
+        frame.extend_from_slice(&1766u16.to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn hash_ipv4() {
+        let rx_hash = RxHash::new();
+        let frame = ipv4_frame(IPPROTO_TCP, 0);
+        assert_eq!(
+            rx_hash.hash(&frame),
+            (0, VIRTIO_NET_HASH_REPORT_NONE as u16)
+        );
+
+        assert!(rx_hash.configure(RX_HASH_SUPPORTED_TYPES, &KEY));
+        assert_eq!(
+            rx_hash.hash(&frame),
+            (0x51cc_c178, VIRTIO_NET_HASH_REPORT_TCPv4 as u16)
+        );
+        // Fragments are only hashed on their addresses.
+        assert_eq!(
+            rx_hash.hash(&ipv4_frame(IPPROTO_TCP, 0x2000)),
+            (0x323e_8fc2, VIRTIO_NET_HASH_REPORT_IPv4 as u16)
+        );
+
+        assert!(rx_hash.configure(VIRTIO_NET_RSS_HASH_TYPE_IPv4, &KEY));
+        assert_eq!(
+            rx_hash.hash(&frame),
+            (0x323e_8fc2, VIRTIO_NET_HASH_REPORT_IPv4 as u16)
+        );
+    }
+
+    #[test]
+    fn hash_ipv6() {
+        let mut frame = vec![0u8; 2 * ETH_ALEN];
+        frame.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+        frame.extend_from_slice(&[0, 1]);
+        frame.extend_from_slice(&ETH_P_IPV6.to_be_bytes());
+        let mut ip = [0u8; 40];
+        ip[0] = 0x60;
+        ip[6] = IPPROTO_UDP;
+        // 3ffe:2501:200:1fff::7 to 3ffe:2501:200:3::1.
+        ip[8..16].copy_from_slice(&[0x3f, 0xfe, 0x25, 0x01, 0x02, 0x00, 0x1f, 0xff]);
+        ip[23] = 7;
+        ip[24..32].copy_from_slice(&[0x3f, 0xfe, 0x25, 0x01, 0x02, 0x00, 0x00, 0x03]);
+        ip[39] = 1;
+        frame.extend_from_slice(&ip);
+        frame.extend_from_slice(&2794u16.to_be_bytes());
+        frame.extend_from_slice(&1766u16.to_be_bytes());
+
+        let rx_hash = RxHash::new();
+        assert!(rx_hash.configure(RX_HASH_SUPPORTED_TYPES, &KEY));
+        assert_eq!(
+            rx_hash.hash(&frame),
+            (0x4020_7d3d, VIRTIO_NET_HASH_REPORT_UDPv6 as u16)
+        );
+        assert!(rx_hash.configure(VIRTIO_NET_RSS_HASH_TYPE_IPv6, &KEY));
+        assert_eq!(
+            rx_hash.hash(&frame),
+            (0x2cc1_8cd5, VIRTIO_NET_HASH_REPORT_IPv6 as u16)
+        );
+    }
+
+    #[test]
+    fn configure_rejects() {
+        let rx_hash = RxHash::new();
+        assert!(!rx_hash.configure(1 << 6, &KEY));
+        assert!(!rx_hash.configure(RX_HASH_SUPPORTED_TYPES, &[0; RX_HASH_MAX_KEY_LEN + 1]));
+        // Not hashing anything is fine.
+        assert!(rx_hash.configure(0, &[]));
+        assert_eq!(
+            rx_hash.hash(&ipv4_frame(IPPROTO_UDP, 0)),
+            (0, VIRTIO_NET_HASH_REPORT_NONE as u16)
+        );
+    }
+}
@@ -0,0 +1,890 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::io::{self, Read};
+
+use base::{error, RawDescriptor};
+use base::{Error as SysError, Result as SysResult};
+use data_model::VolatileSlice;
+use disk::DiskFile;
+
+use super::{write_data_in, CommandResult, LogicalUnit};
+use crate::scsi::{
+    mode_sense, read_capacity_10, Inquiry, Sense, INQUIRY, MODE_SENSE_10, MODE_SENSE_6,
+    PREVENT_ALLOW_MEDIUM_REMOVAL, READ_10, READ_CAPACITY_10, REQUEST_SENSE, START_STOP_UNIT,
+    SYNCHRONIZE_CACHE_10, TEST_UNIT_READY, VENDOR, VERIFY_10, WRITE_10,
+};
+use crate::virtio::{Reader, Writer};
+
+// Operation codes only the virtio-scsi disk handles.
+const READ_6: u8 = 0x08;
+const WRITE_6: u8 = 0x0a;
+const WRITE_SAME_10: u8 = 0x41;
+const UNMAP: u8 = 0x42;
+const PERSISTENT_RESERVE_IN: u8 = 0x5e;
+const PERSISTENT_RESERVE_OUT: u8 = 0x5f;
+const READ_16: u8 = 0x88;
+const WRITE_16: u8 = 0x8a;
+const VERIFY_16: u8 = 0x8f;
+const SYNCHRONIZE_CACHE_16: u8 = 0x91;
+const WRITE_SAME_16: u8 = 0x93;
+const SERVICE_ACTION_IN_16: u8 = 0x9e;
+
+// Service action of SERVICE ACTION IN(16).
+const READ_CAPACITY_16: u8 = 0x10;
+
+// Service actions of PERSISTENT RESERVE IN.
+const PR_READ_KEYS: u8 = 0x00;
+const PR_READ_RESERVATION: u8 = 0x01;
+const PR_REPORT_CAPABILITIES: u8 = 0x02;
+
+// Service actions of PERSISTENT RESERVE OUT.
+const PR_REGISTER: u8 = 0x00;
+const PR_RESERVE: u8 = 0x01;
+const PR_RELEASE: u8 = 0x02;
+const PR_CLEAR: u8 = 0x03;
+const PR_PREEMPT: u8 = 0x04;
+const PR_PREEMPT_AND_ABORT: u8 = 0x05;
+const PR_REGISTER_AND_IGNORE_EXISTING_KEY: u8 = 0x06;
+
+// Length of the parameter list of PERSISTENT RESERVE OUT.
+const PR_OUT_PARAMETER_LEN: usize = 24;
+
+// Mode pages.
+const MODE_PAGE_CACHING: u8 = 0x08;
+const MODE_PAGE_CONTROL: u8 = 0x0a;
+const MODE_PAGE_ALL: u8 = 0x3f;
+
+// Vital product data pages.
+const VPD_SUPPORTED_PAGES: u8 = 0x00;
+const VPD_UNIT_SERIAL_NUMBER: u8 = 0x80;
+const VPD_DEVICE_IDENTIFICATION: u8 = 0x83;
+const VPD_BLOCK_LIMITS: u8 = 0xb0;
+const VPD_LOGICAL_BLOCK_PROVISIONING: u8 = 0xb2;
+
+const PRODUCT: &[u8; 16] = b"CROSVM HARDDISK ";
+
+// The most blocks a single UNMAP descriptor or WRITE SAME command can cover.
+const MAX_UNMAP_BLOCKS: u32 = 0x10_0000;
+const MAX_UNMAP_DESCRIPTORS: u32 = 256;
+
+// Persistent reservations of the unit. The guest is the only initiator, so a reservation never
+// blocks its own commands, but registrations and reservations are tracked so that clustering
+// software sees the behavior it expects.
+#[derive(Default)]
+struct Reservations {
+    generation: u32,
+    key: Option<u64>,
+    // The type of the reservation held by the registrant, if any.
+    reservation: Option<u8>,
+}
+
+impl Reservations {
+    fn execute_out(&mut self, cdb: &[u8], params: &[u8; PR_OUT_PARAMETER_LEN]) -> CommandResult {
+        let service_action = cdb[1] & 0x1f;
+        let scope = cdb[2] >> 4;
+        let type_ = cdb[2] & 0x0f;
+        let key = be_u64(&params[0..8]);
+        let sa_key = be_u64(&params[8..16]);
+
+        // Only registrations that stay with this unit are supported.
+        let (spec_i_pt, all_tg_pt) = (params[20] & 0x08 != 0, params[20] & 0x04 != 0);
+        if spec_i_pt || all_tg_pt {
+            return CommandResult::check_condition(Sense::INVALID_FIELD_IN_PARAMETER_LIST);
+        }
+
+        if service_action == PR_REGISTER || service_action == PR_REGISTER_AND_IGNORE_EXISTING_KEY {
+            if service_action == PR_REGISTER && key != self.key.unwrap_or(0) {
+                return CommandResult::reservation_conflict();
+            }
+            if sa_key == 0 {
+                self.key = None;
+                self.reservation = None;
+            } else {
+                self.key = Some(sa_key);
+            }
+            self.generation = self.generation.wrapping_add(1);
+            return CommandResult::good();
+        }
+
+        match self.key {
+            Some(registered) if registered == key => {}
+            _ => return CommandResult::reservation_conflict(),
+        }
+        let reserve = match service_action {
+            PR_RESERVE | PR_PREEMPT | PR_PREEMPT_AND_ABORT => true,
+            PR_RELEASE => false,
+            PR_CLEAR => {
+                self.key = None;
+                self.reservation = None;
+                self.generation = self.generation.wrapping_add(1);
+                return CommandResult::good();
+            }
+            _ => return CommandResult::check_condition(Sense::INVALID_FIELD_IN_CDB),
+        };
+        if scope != 0 || !valid_reservation_type(type_) {
+            return CommandResult::check_condition(Sense::INVALID_FIELD_IN_CDB);
+        }
+
+        if reserve {
+            if service_action != PR_RESERVE {
+                // The only registrant this could preempt is the initiator itself.
+                if sa_key != key {
+                    return CommandResult::reservation_conflict();
+                }
+                self.generation = self.generation.wrapping_add(1);
+            } else if self.reservation.map_or(false, |t| t != type_) {
+                return CommandResult::reservation_conflict();
+            }
+            self.reservation = Some(type_);
+        } else {
+            match self.reservation {
+                Some(t) if t != type_ => {
+                    return CommandResult::check_condition(
+                        Sense::INVALID_RELEASE_OF_PERSISTENT_RESERVATION,
+                    )
+                }
+                _ => self.reservation = None,
+            }
+        }
+        CommandResult::good()
+    }
+
+    fn execute_in(&self, service_action: u8) -> Option<Vec<u8>> {
+        let mut data = Vec::new();
+        match service_action {
+            PR_READ_KEYS => {
+                data.extend_from_slice(&self.generation.to_be_bytes());
+                let keys: Vec<u64> = self.key.into_iter().collect();
+                data.extend_from_slice(&(keys.len() as u32 * 8).to_be_bytes());
+                for key in keys {
+                    data.extend_from_slice(&key.to_be_bytes());
+                }
+            }
+            PR_READ_RESERVATION => {
+                data.extend_from_slice(&self.generation.to_be_bytes());
+                match (self.key, self.reservation) {
+                    (Some(key), Some(type_)) => {
+                        data.extend_from_slice(&16u32.to_be_bytes());
+                        data.extend_from_slice(&key.to_be_bytes());
+                        data.extend_from_slice(&[0, 0, 0, 0, 0, type_, 0, 0]);
+                    }
+                    _ => data.extend_from_slice(&0u32.to_be_bytes()),
+                }
+            }
+            PR_REPORT_CAPABILITIES => {
+                // Length, no capabilities, a valid type mask, and the mask of every type in
+                // `valid_reservation_type`.
+                data.extend_from_slice(&[0, 8, 0, 0x80, 0xea, 0x01, 0, 0]);
+            }
+            _ => return None,
+        }
+        Some(data)
+    }
+}
+
+fn valid_reservation_type(type_: u8) -> bool {
+    // Write exclusive, exclusive access, and their registrants only and all registrants variants.
+    matches!(type_, 1 | 3 | 5 | 6 | 7 | 8)
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    u64::from(be_u32(&bytes[0..4])) << 32 | u64::from(be_u32(&bytes[4..8]))
+}
+
+/// A disk emulated on a disk image, attached to a virtio-scsi controller.
+pub struct ScsiDisk {
+    disk_image: Box<dyn DiskFile>,
+    read_only: bool,
+    sparse: bool,
+    write_through: bool,
+    block_size: u32,
+    num_blocks: u64,
+    serial: Vec<u8>,
+    reservations: Reservations,
+}
+
+impl ScsiDisk {
+    /// Creates a disk with blocks of `block_size` bytes on `disk_image`. If `write_through` is
+    /// set, each write is flushed to the image before it completes. The disk's serial number is
+    /// `id`, without the NUL bytes it's padded with.
+    pub fn new(
+        disk_image: Box<dyn DiskFile>,
+        read_only: bool,
+        sparse: bool,
+        write_through: bool,
+        block_size: u32,
+        id: Option<[u8; 20]>,
+    ) -> SysResult<ScsiDisk> {
+        if block_size < 512 || !block_size.is_power_of_two() {
+            error!(
+                "scsi: block size {} is not a power of two of at least 512",
+                block_size
+            );
+            return Err(SysError::new(libc::EINVAL));
+        }
+        let disk_size = disk_image.get_len()?;
+        let serial = id
+            .map(|id| id.iter().copied().take_while(|&b| b != 0).collect())
+            .unwrap_or_default();
+
+        Ok(ScsiDisk {
+            disk_image,
+            read_only,
+            sparse,
+            write_through,
+            block_size,
+            num_blocks: disk_size / block_size as u64,
+            serial,
+            reservations: Reservations::default(),
+        })
+    }
+
+    fn inquiry(&self, cdb: &[u8], writer: &mut Writer) -> io::Result<CommandResult> {
+        let allocation_length = u16::from_be_bytes([cdb[3], cdb[4]]) as usize;
+        let evpd = cdb[1] & 0x01 != 0;
+        let page = cdb[2];
+        if !evpd {
+            if page != 0 {
+                return Ok(CommandResult::check_condition(Sense::INVALID_FIELD_IN_CDB));
+            }
+            let inquiry = Inquiry {
+                product: PRODUCT,
+                removable: false,
+                command_queuing: true,
+            };
+            write_data_in(writer, &inquiry.to_bytes(), allocation_length)?;
+            return Ok(CommandResult::good());
+        }
+
+        let mut page_data = match page {
+            VPD_SUPPORTED_PAGES => vec![
+                VPD_SUPPORTED_PAGES,
+                VPD_UNIT_SERIAL_NUMBER,
+                VPD_DEVICE_IDENTIFICATION,
+                VPD_BLOCK_LIMITS,
+                VPD_LOGICAL_BLOCK_PROVISIONING,
+            ],
+            VPD_UNIT_SERIAL_NUMBER => self.serial.clone(),
+            VPD_DEVICE_IDENTIFICATION => {
+                // A T10 vendor ID based designator, in ASCII, identifying the logical unit.
+                let mut designator = VENDOR.to_vec();
+                designator.extend_from_slice(PRODUCT);
+                designator.extend_from_slice(&self.serial);
+                let mut data = vec![0x02, 0x01, 0x00, designator.len() as u8];
+                data.extend_from_slice(&designator);
+                data
+            }
+            VPD_BLOCK_LIMITS => {
+                let mut data = vec![0u8; 0x3c];
+                // WRITE SAME with a number of blocks of zero isn't supported.
+                data[0] = 0x01;
+                if self.sparse {
+                    data[16..20].copy_from_slice(&MAX_UNMAP_BLOCKS.to_be_bytes());
+                    data[20..24].copy_from_slice(&MAX_UNMAP_DESCRIPTORS.to_be_bytes());
+                }
+                data[32..40].copy_from_slice(&u64::from(MAX_UNMAP_BLOCKS).to_be_bytes());
+                data
+            }
+            VPD_LOGICAL_BLOCK_PROVISIONING => {
+                let mut data = vec![0u8; 4];
+                if self.sparse {
+                    // UNMAP and WRITE SAME with the unmap bit are supported, and unmapped blocks
+                    // read as zeroes, since the disk is thin provisioned.
+                    data[1] = 0x80 | 0x40 | 0x04;
+                    data[2] = 0x02;
+                }
+                data
+            }
+            _ => return Ok(CommandResult::check_condition(Sense::INVALID_FIELD_IN_CDB)),
+        };
+
+        let mut data = vec![0x00, page];
+        data.extend_from_slice(&(page_data.len() as u16).to_be_bytes());
+        data.append(&mut page_data);
+        write_data_in(writer, &data, allocation_length)?;
+        Ok(CommandResult::good())
+    }
+
+    fn mode_sense(&self, cdb: &[u8], writer: &mut Writer) -> io::Result<CommandResult> {
+        let ten = cdb[0] == MODE_SENSE_10;
+        let dbd = cdb[1] & 0x08 != 0;
+        let page_control = cdb[2] >> 6;
+        let page = cdb[2] & 0x3f;
+        let allocation_length = if ten {
+            u16::from_be_bytes([cdb[7], cdb[8]]) as usize
+        } else {
+            cdb[4] as usize
+        };
+        // Saved values aren't supported.
+        if page_control == 3 {
+            return Ok(CommandResult::check_condition(Sense::INVALID_FIELD_IN_CDB));
+        }
+        // None of the parameters can be changed.
+        let changeable = page_control == 1;
+
+        let mut pages = Vec::new();
+        if page == MODE_PAGE_CACHING || page == MODE_PAGE_ALL {
+            let mut caching = [0u8; 20];
+            caching[0] = MODE_PAGE_CACHING;
+            caching[1] = 18;
+            if !changeable && !self.write_through {
+                // Write cache enabled.
+                caching[2] = 0x04;
+            }
+            pages.extend_from_slice(&caching);
+        }
+        if page == MODE_PAGE_CONTROL || page == MODE_PAGE_ALL {
+            let mut control = [0u8; 12];
+            control[0] = MODE_PAGE_CONTROL;
+            control[1] = 10;
+            if !changeable {
+                // Commands can be reordered.
+                control[3] = 0x10;
+            }
+            pages.extend_from_slice(&control);
+        }
+        if pages.is_empty() {
+            return Ok(CommandResult::check_condition(Sense::INVALID_FIELD_IN_CDB));
+        }
+
+        let mut block_descriptor = Vec::new();
+        if !dbd {
+            let num_blocks = self.num_blocks.min(0xff_ffff) as u32;
+            block_descriptor.extend_from_slice(&num_blocks.to_be_bytes());
+            block_descriptor.extend_from_slice(&self.block_size.to_be_bytes());
+        }
+
+        let data = mode_sense(cdb[0], self.read_only, &block_descriptor, &pages);
+        write_data_in(writer, &data, allocation_length)?;
+        Ok(CommandResult::good())
+    }
+
+    fn check_range(&self, lba: u64, blocks: u64) -> Option<CommandResult> {
+        match lba.checked_add(blocks) {
+            Some(end) if end <= self.num_blocks => None,
+            _ => Some(CommandResult::check_condition(Sense::LBA_OUT_OF_RANGE)),
+        }
+    }
+
+    fn read(&mut self, lba: u64, blocks: u64, writer: &mut Writer) -> CommandResult {
+        if let Some(result) = self.check_range(lba, blocks) {
+            return result;
+        }
+        let len = (blocks * self.block_size as u64).min(writer.available_bytes() as u64);
+        let offset = lba * self.block_size as u64;
+        match writer.write_all_from_at(&mut *self.disk_image, len as usize, offset) {
+            Ok(()) => CommandResult::good(),
+            Err(e) => {
+                error!("scsi: failed to read {} bytes at {}: {}", len, offset, e);
+                CommandResult::check_condition(Sense::UNRECOVERED_READ_ERROR)
+            }
+        }
+    }
+
+    fn write(&mut self, lba: u64, blocks: u64, fua: bool, reader: &mut Reader) -> CommandResult {
+        if self.read_only {
+            return CommandResult::check_condition(Sense::WRITE_PROTECTED);
+        }
+        if let Some(result) = self.check_range(lba, blocks) {
+            return result;
+        }
+        let len = (blocks * self.block_size as u64).min(reader.available_bytes() as u64);
+        let offset = lba * self.block_size as u64;
+        let result = reader
+            .read_exact_to_at(&mut *self.disk_image, len as usize, offset)
+            .and_then(|()| {
+                if fua || self.write_through {
+                    self.disk_image.fdatasync()
+                } else {
+                    Ok(())
+                }
+            });
+        match result {
+            Ok(()) => CommandResult::good(),
+            Err(e) => {
+                error!("scsi: failed to write {} bytes at {}: {}", len, offset, e);
+                CommandResult::check_condition(Sense::WRITE_ERROR)
+            }
+        }
+    }
+
+    // Unmaps, or zeroes if unmapping isn't allowed, `blocks` blocks at `lba`.
+    fn unmap_blocks(&mut self, lba: u64, blocks: u64, unmap: bool) -> io::Result<()> {
+        let offset = lba * self.block_size as u64;
+        let len = blocks * self.block_size as u64;
+        if unmap && self.sparse && self.disk_image.punch_hole(offset, len).is_ok() {
+            return Ok(());
+        }
+        self.disk_image.write_zeroes_all_at(offset, len as usize)
+    }
+
+    fn write_same(
+        &mut self,
+        lba: u64,
+        blocks: u64,
+        unmap: bool,
+        reader: &mut Reader,
+    ) -> CommandResult {
+        if self.read_only {
+            return CommandResult::check_condition(Sense::WRITE_PROTECTED);
+        }
+        if blocks == 0 || blocks > MAX_UNMAP_BLOCKS as u64 {
+            return CommandResult::check_condition(Sense::INVALID_FIELD_IN_CDB);
+        }
+        if let Some(result) = self.check_range(lba, blocks) {
+            return result;
+        }
+        let mut block = vec![0u8; self.block_size as usize];
+        if reader.read_exact(&mut block).is_err() {
+            return CommandResult::check_condition(Sense::PARAMETER_LIST_LENGTH_ERROR);
+        }
+
+        let result = if block.iter().all(|&b| b == 0) {
+            self.unmap_blocks(lba, blocks, unmap)
+        } else {
+            let block_size = self.block_size as u64;
+            let disk_image = &mut self.disk_image;
+            (0..blocks).try_for_each(|i| {
+                disk_image
+                    .write_all_at_volatile(VolatileSlice::new(&mut block), (lba + i) * block_size)
+            })
+        };
+        match result.and_then(|()| {
+            if self.write_through {
+                self.disk_image.fdatasync()
+            } else {
+                Ok(())
+            }
+        }) {
+            Ok(()) => CommandResult::good(),
+            Err(e) => {
+                error!("scsi: failed to write same at block {}: {}", lba, e);
+                CommandResult::check_condition(Sense::WRITE_ERROR)
+            }
+        }
+    }
+
+    fn unmap(&mut self, cdb: &[u8], reader: &mut Reader) -> CommandResult {
+        if self.read_only {
+            return CommandResult::check_condition(Sense::WRITE_PROTECTED);
+        }
+        let param_len = u16::from_be_bytes([cdb[7], cdb[8]]) as usize;
+        if param_len == 0 {
+            return CommandResult::good();
+        }
+        let mut params = vec![0u8; param_len];
+        if param_len < 8 || reader.read_exact(&mut params).is_err() {
+            return CommandResult::check_condition(Sense::PARAMETER_LIST_LENGTH_ERROR);
+        }
+        let descriptors_len = u16::from_be_bytes([params[2], params[3]]) as usize;
+        let descriptors = match params.get(8..8 + descriptors_len) {
+            Some(d) => d.to_vec(),
+            None => return CommandResult::check_condition(Sense::PARAMETER_LIST_LENGTH_ERROR),
+        };
+
+        for descriptor in descriptors.chunks_exact(16) {
+            let lba = be_u64(&descriptor[0..8]);
+            let blocks = be_u32(&descriptor[8..12]);
+            if blocks > MAX_UNMAP_BLOCKS {
+                return CommandResult::check_condition(Sense::INVALID_FIELD_IN_PARAMETER_LIST);
+            }
+            if let Some(result) = self.check_range(lba, blocks as u64) {
+                return result;
+            }
+            // Unmapping is only a hint, so blocks of disks that aren't sparse are left as is.
+            if self.sparse {
+                let offset = lba * self.block_size as u64;
+                let _ = self
+                    .disk_image
+                    .punch_hole(offset, blocks as u64 * self.block_size as u64);
+            }
+        }
+        CommandResult::good()
+    }
+
+    fn persistent_reserve_out(&mut self, cdb: &[u8], reader: &mut Reader) -> CommandResult {
+        let param_len = be_u32(&cdb[5..9]) as usize;
+        if param_len != PR_OUT_PARAMETER_LEN {
+            return CommandResult::check_condition(Sense::PARAMETER_LIST_LENGTH_ERROR);
+        }
+        let mut params = [0u8; PR_OUT_PARAMETER_LEN];
+        if reader.read_exact(&mut params).is_err() {
+            return CommandResult::check_condition(Sense::PARAMETER_LIST_LENGTH_ERROR);
+        }
+        self.reservations.execute_out(cdb, &params)
+    }
+}
+
+impl LogicalUnit for ScsiDisk {
+    fn execute(
+        &mut self,
+        cdb: &[u8],
+        reader: &mut Reader,
+        writer: &mut Writer,
+    ) -> io::Result<CommandResult> {
+        let fua = cdb[1] & 0x08 != 0;
+        let result = match cdb[0] {
+            TEST_UNIT_READY | START_STOP_UNIT | PREVENT_ALLOW_MEDIUM_REMOVAL => {
+                CommandResult::good()
+            }
+            REQUEST_SENSE => {
+                write_data_in(writer, &Sense::NO_SENSE.to_bytes(), cdb[4] as usize)?;
+                CommandResult::good()
+            }
+            INQUIRY => return self.inquiry(cdb, writer),
+            MODE_SENSE_6 | MODE_SENSE_10 => return self.mode_sense(cdb, writer),
+            READ_CAPACITY_10 => {
+                let data = read_capacity_10(self.num_blocks, self.block_size);
+                write_data_in(writer, &data, data.len())?;
+                CommandResult::good()
+            }
+            SERVICE_ACTION_IN_16 if cdb[1] & 0x1f == READ_CAPACITY_16 => {
+                let allocation_length = be_u32(&cdb[10..14]);
+                let mut data = vec![0u8; 32];
+                data[0..8].copy_from_slice(&self.num_blocks.saturating_sub(1).to_be_bytes());
+                data[8..12].copy_from_slice(&self.block_size.to_be_bytes());
+                if self.sparse {
+                    // Thin provisioned, with unmapped blocks reading as zeroes.
+                    data[14] = 0x80 | 0x40;
+                }
+                write_data_in(writer, &data, allocation_length as usize)?;
+                CommandResult::good()
+            }
+            READ_6 => {
+                let lba = u32::from_be_bytes([0, cdb[1] & 0x1f, cdb[2], cdb[3]]);
+                // A transfer length of zero means 256 blocks.
+                let blocks = if cdb[4] == 0 { 256 } else { cdb[4] as u64 };
+                self.read(lba as u64, blocks, writer)
+            }
+            READ_10 => {
+                let lba = be_u32(&cdb[2..6]);
+                let blocks = u16::from_be_bytes([cdb[7], cdb[8]]);
+                self.read(lba as u64, blocks as u64, writer)
+            }
+            READ_16 => {
+                let lba = be_u64(&cdb[2..10]);
+                let blocks = be_u32(&cdb[10..14]);
+                self.read(lba, blocks as u64, writer)
+            }
+            WRITE_6 => {
+                let lba = u32::from_be_bytes([0, cdb[1] & 0x1f, cdb[2], cdb[3]]);
+                let blocks = if cdb[4] == 0 { 256 } else { cdb[4] as u64 };
+                self.write(lba as u64, blocks, false, reader)
+            }
+            WRITE_10 => {
+                let lba = be_u32(&cdb[2..6]);
+                let blocks = u16::from_be_bytes([cdb[7], cdb[8]]);
+                self.write(lba as u64, blocks as u64, fua, reader)
+            }
+            WRITE_16 => {
+                let lba = be_u64(&cdb[2..10]);
+                let blocks = be_u32(&cdb[10..14]);
+                self.write(lba, blocks as u64, fua, reader)
+            }
+            VERIFY_10 => {
+                let lba = be_u32(&cdb[2..6]);
+                let blocks = u16::from_be_bytes([cdb[7], cdb[8]]);
+                self.check_range(lba as u64, blocks as u64)
+                    .unwrap_or_else(CommandResult::good)
+            }
+            VERIFY_16 => {
+                let lba = be_u64(&cdb[2..10]);
+                let blocks = be_u32(&cdb[10..14]);
+                self.check_range(lba, blocks as u64)
+                    .unwrap_or_else(CommandResult::good)
+            }
+            SYNCHRONIZE_CACHE_10 | SYNCHRONIZE_CACHE_16 => match self.disk_image.fsync() {
+                Ok(()) => CommandResult::good(),
+                Err(e) => {
+                    error!("scsi: failed to flush disk image: {}", e);
+                    CommandResult::check_condition(Sense::WRITE_ERROR)
+                }
+            },
+            WRITE_SAME_10 => {
+                let lba = be_u32(&cdb[2..6]);
+                let blocks = u16::from_be_bytes([cdb[7], cdb[8]]);
+                self.write_same(lba as u64, blocks as u64, cdb[1] & 0x08 != 0, reader)
+            }
+            WRITE_SAME_16 => {
+                let lba = be_u64(&cdb[2..10]);
+                let blocks = be_u32(&cdb[10..14]);
+                self.write_same(lba, blocks as u64, cdb[1] & 0x08 != 0, reader)
+            }
+            UNMAP => self.unmap(cdb, reader),
+            PERSISTENT_RESERVE_IN => {
+                let allocation_length = u16::from_be_bytes([cdb[7], cdb[8]]);
+                match self.reservations.execute_in(cdb[1] & 0x1f) {
+                    Some(data) => {
+                        write_data_in(writer, &data, allocation_length as usize)?;
+                        CommandResult::good()
+                    }
+                    None => CommandResult::check_condition(Sense::INVALID_FIELD_IN_CDB),
+                }
+            }
+            PERSISTENT_RESERVE_OUT => self.persistent_reserve_out(cdb, reader),
+            _ => CommandResult::check_condition(Sense::INVALID_OPCODE),
+        };
+        Ok(result)
+    }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        self.disk_image.as_raw_descriptors()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+
+    use tempfile::tempfile;
+    use vm_memory::{GuestAddress, GuestMemory};
+
+    use crate::virtio::descriptor_utils::{create_descriptor_chain, DescriptorType};
+
+    // Runs `cdb` on `disk` with `data_out` as its data-out buffer, returning the result and the
+    // data-in buffer.
+    fn run(disk: &mut ScsiDisk, cdb: &[u8], data_out: &[u8]) -> (CommandResult, Vec<u8>) {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut descriptors = Vec::new();
+        if !data_out.is_empty() {
+            mem.write_all_at_addr(data_out, GuestAddress(0x1000))
+                .unwrap();
+            descriptors.push((DescriptorType::Readable, data_out.len() as u32));
+        }
+        descriptors.push((DescriptorType::Writable, 0x1000));
+        let chain = create_descriptor_chain(
+            &mem,
+            GuestAddress(0x100),
+            GuestAddress(0x1000),
+            descriptors,
+            0,
+        )
+        .unwrap();
+        let mut reader = Reader::new(mem.clone(), chain.clone()).unwrap();
+        let mut writer = Writer::new(mem.clone(), chain).unwrap();
+
+        let result = disk.execute(cdb, &mut reader, &mut writer).unwrap();
+        let mut data = vec![0u8; writer.bytes_written()];
+        let data_in_addr = GuestAddress(0x1000 + data_out.len() as u64);
+        mem.read_exact_at_addr(&mut data, data_in_addr).unwrap();
+        (result, data)
+    }
+
+    fn disk(read_only: bool) -> (std::fs::File, ScsiDisk) {
+        let f = tempfile().unwrap();
+        f.set_len(0x10000).unwrap();
+        let disk = ScsiDisk::new(
+            Box::new(f.try_clone().unwrap()),
+            read_only,
+            true,
+            false,
+            512,
+            Some(*b"serial\0\0\0\0\0\0\0\0\0\0\0\0\0\0"),
+        )
+        .unwrap();
+        (f, disk)
+    }
+
+    #[test]
+    fn inquiry() {
+        let (_, mut disk) = disk(true);
+        let (result, data) = run(&mut disk, &[INQUIRY, 0, 0, 0, 96, 0], &[]);
+        assert_eq!(result, CommandResult::good());
+        assert_eq!(data.len(), 36);
+        assert_eq!(&data[8..16], VENDOR);
+
+        let (result, data) = run(
+            &mut disk,
+            &[INQUIRY, 1, VPD_UNIT_SERIAL_NUMBER, 0, 96, 0],
+            &[],
+        );
+        assert_eq!(result, CommandResult::good());
+        assert_eq!(&data[..], b"\x00\x80\x00\x06serial");
+
+        // The allocation length truncates the data.
+        let (_, data) = run(&mut disk, &[INQUIRY, 1, VPD_SUPPORTED_PAGES, 0, 6, 0], &[]);
+        assert_eq!(&data[..], &[0x00, 0x00, 0x00, 0x05, 0x00, 0x80]);
+
+        let (result, _) = run(&mut disk, &[INQUIRY, 1, 0x89, 0, 96, 0], &[]);
+        assert_eq!(
+            result,
+            CommandResult::check_condition(Sense::INVALID_FIELD_IN_CDB)
+        );
+    }
+
+    #[test]
+    fn read_capacity() {
+        let (_, mut disk) = disk(true);
+        let (_, data) = run(
+            &mut disk,
+            &[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            &[],
+        );
+        assert_eq!(&data[..], &[0, 0, 0, 127, 0, 0, 2, 0]);
+
+        let mut cdb = [0u8; 16];
+        cdb[0] = SERVICE_ACTION_IN_16;
+        cdb[1] = READ_CAPACITY_16;
+        cdb[13] = 32;
+        let (_, data) = run(&mut disk, &cdb, &[]);
+        assert_eq!(data.len(), 32);
+        assert_eq!(&data[..12], &[0, 0, 0, 0, 0, 0, 0, 127, 0, 0, 2, 0]);
+        assert_eq!(data[14], 0xc0);
+    }
+
+    #[test]
+    fn read_write() {
+        let (f, mut disk) = disk(false);
+        f.write_all_at(&[0x55; 512], 512).unwrap();
+
+        let (result, data) = run(&mut disk, &[READ_10, 0, 0, 0, 0, 1, 0, 0, 1, 0], &[]);
+        assert_eq!(result, CommandResult::good());
+        assert_eq!(data, vec![0x55; 512]);
+
+        let (result, _) = run(
+            &mut disk,
+            &[WRITE_10, 0, 0, 0, 0, 2, 0, 0, 1, 0],
+            &[0xaa; 512],
+        );
+        assert_eq!(result, CommandResult::good());
+        let mut buf = [0u8; 512];
+        f.read_exact_at(&mut buf, 1024).unwrap();
+        assert_eq!(&buf[..], &[0xaa; 512][..]);
+
+        let (result, _) = run(&mut disk, &[READ_10, 0, 0, 0, 0, 127, 0, 0, 2, 0], &[]);
+        assert_eq!(
+            result,
+            CommandResult::check_condition(Sense::LBA_OUT_OF_RANGE)
+        );
+    }
+
+    #[test]
+    fn write_protected() {
+        let (_, mut disk) = disk(true);
+        let (result, _) = run(&mut disk, &[WRITE_6, 0, 0, 0, 1, 0], &[0xaa; 512]);
+        assert_eq!(
+            result,
+            CommandResult::check_condition(Sense::WRITE_PROTECTED)
+        );
+
+        let (_, data) = run(
+            &mut disk,
+            &[MODE_SENSE_6, 0x08, MODE_PAGE_ALL, 0, 0xff, 0],
+            &[],
+        );
+        // Header, caching page and control page.
+        assert_eq!(data.len(), 4 + 20 + 12);
+        assert_eq!(data[2], 0x80);
+        assert_eq!(data[4], MODE_PAGE_CACHING);
+        assert_eq!(data[6], 0x04);
+    }
+
+    #[test]
+    fn unmap() {
+        let (f, mut disk) = disk(false);
+        f.write_all_at(&[0x55; 1024], 4096).unwrap();
+
+        let mut params = vec![0, 22, 0, 16, 0, 0, 0, 0];
+        params.extend_from_slice(&8u64.to_be_bytes());
+        params.extend_from_slice(&2u32.to_be_bytes());
+        params.extend_from_slice(&[0; 4]);
+        let (result, _) = run(
+            &mut disk,
+            &[UNMAP, 0, 0, 0, 0, 0, 0, 0, params.len() as u8, 0],
+            &params,
+        );
+        assert_eq!(result, CommandResult::good());
+        let mut buf = [0xffu8; 1024];
+        f.read_exact_at(&mut buf, 4096).unwrap();
+        assert_eq!(&buf[..], &[0; 1024][..]);
+    }
+
+    fn pr_out(disk: &mut ScsiDisk, action: u8, type_: u8, key: u64, sa_key: u64) -> CommandResult {
+        let mut params = [0u8; PR_OUT_PARAMETER_LEN];
+        params[0..8].copy_from_slice(&key.to_be_bytes());
+        params[8..16].copy_from_slice(&sa_key.to_be_bytes());
+        let cdb = [
+            PERSISTENT_RESERVE_OUT,
+            action,
+            type_,
+            0,
+            0,
+            0,
+            0,
+            0,
+            PR_OUT_PARAMETER_LEN as u8,
+            0,
+        ];
+        run(disk, &cdb, &params).0
+    }
+
+    #[test]
+    fn persistent_reservations() {
+        let (_, mut disk) = disk(false);
+        let read_reservation = [
+            PERSISTENT_RESERVE_IN,
+            PR_READ_RESERVATION,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            24,
+            0,
+        ];
+
+        // Reserving needs a registration.
+        assert_eq!(
+            pr_out(&mut disk, PR_RESERVE, 1, 0, 0),
+            CommandResult::reservation_conflict()
+        );
+        assert_eq!(
+            pr_out(&mut disk, PR_REGISTER, 0, 0, 0x1234),
+            CommandResult::good()
+        );
+        assert_eq!(
+            pr_out(&mut disk, PR_RESERVE, 1, 0x4321, 0),
+            CommandResult::reservation_conflict()
+        );
+        assert_eq!(
+            pr_out(&mut disk, PR_RESERVE, 1, 0x1234, 0),
+            CommandResult::good()
+        );
+
+        let (_, data) = run(&mut disk, &read_reservation, &[]);
+        let mut expected = vec![0, 0, 0, 1, 0, 0, 0, 16];
+        expected.extend_from_slice(&0x1234u64.to_be_bytes());
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(data, expected);
+
+        assert_eq!(
+            pr_out(&mut disk, PR_RELEASE, 3, 0x1234, 0),
+            CommandResult::check_condition(Sense::INVALID_RELEASE_OF_PERSISTENT_RESERVATION)
+        );
+        assert_eq!(
+            pr_out(&mut disk, PR_RELEASE, 1, 0x1234, 0),
+            CommandResult::good()
+        );
+        let (_, data) = run(&mut disk, &read_reservation, &[]);
+        assert_eq!(data, vec![0, 0, 0, 1, 0, 0, 0, 0]);
+
+        assert_eq!(
+            pr_out(&mut disk, PR_CLEAR, 0, 0x1234, 0),
+            CommandResult::good()
+        );
+        let (_, data) = run(
+            &mut disk,
+            &[PERSISTENT_RESERVE_IN, PR_READ_KEYS, 0, 0, 0, 0, 0, 0, 24, 0],
+            &[],
+        );
+        assert_eq!(data, vec![0, 0, 0, 2, 0, 0, 0, 0]);
+    }
+}
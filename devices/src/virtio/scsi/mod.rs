@@ -0,0 +1,725 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A virtio-scsi controller whose logical units are emulated in userspace.
+//!
+//! Each logical unit is either a disk emulated on top of any image the block device supports, or
+//! a SCSI device of the host that commands are passed through to with `SG_IO`.
+
+mod disk;
+mod passthrough;
+
+pub use self::disk::ScsiDisk;
+pub use self::passthrough::ScsiPassthrough;
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::thread;
+
+use base::{error, Event, PollToken, RawDescriptor, WaitContext};
+use data_model::{DataInit, Le16, Le32, Le64};
+use vm_memory::GuestMemory;
+
+use super::{
+    copy_config, DescriptorChain, DescriptorError, Interrupt, Queue, Reader, VirtioDevice, Writer,
+    TYPE_SCSI,
+};
+use crate::scsi::{report_luns, Sense, INQUIRY, REPORT_LUNS, REQUEST_SENSE};
+use crate::Suspendable;
+
+const QUEUE_SIZE: u16 = 128;
+// The control and event queues, then a single request queue.
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE];
+
+const SENSE_SIZE: usize = 96;
+const CDB_SIZE: usize = 32;
+// Size of struct virtio_scsi_event.
+const EVENT_INFO_SIZE: u32 = 16;
+const MAX_TARGET: u8 = 255;
+/// The highest LUN that can be addressed on a target.
+pub const MAX_LUN: u16 = 16383;
+
+// Control queue request types.
+const VIRTIO_SCSI_T_TMF: u32 = 0;
+const VIRTIO_SCSI_T_AN_QUERY: u32 = 1;
+const VIRTIO_SCSI_T_AN_SUBSCRIBE: u32 = 2;
+
+// Response codes.
+const VIRTIO_SCSI_S_OK: u8 = 0;
+const VIRTIO_SCSI_S_FUNCTION_COMPLETE: u8 = 0;
+const VIRTIO_SCSI_S_BAD_TARGET: u8 = 3;
+const VIRTIO_SCSI_S_FAILURE: u8 = 9;
+
+// SCSI status codes.
+const GOOD: u8 = 0x00;
+const CHECK_CONDITION: u8 = 0x02;
+const RESERVATION_CONFLICT: u8 = 0x18;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_scsi_config {
+    num_queues: Le32,
+    seg_max: Le32,
+    max_sectors: Le32,
+    cmd_per_lun: Le32,
+    event_info_size: Le32,
+    sense_size: Le32,
+    cdb_size: Le32,
+    max_channel: Le16,
+    max_target: Le16,
+    max_lun: Le32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_scsi_config {}
+
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct virtio_scsi_cmd_req {
+    lun: [u8; 8],
+    tag: Le64,
+    task_attr: u8,
+    prio: u8,
+    crn: u8,
+    cdb: [u8; CDB_SIZE],
+}
+
+// Safe because it only has data and, being packed, has no implicit padding.
+unsafe impl DataInit for virtio_scsi_cmd_req {}
+
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct virtio_scsi_cmd_resp {
+    sense_len: Le32,
+    resid: Le32,
+    status_qualifier: Le16,
+    status: u8,
+    response: u8,
+    sense: [u8; SENSE_SIZE],
+}
+
+// Safe because it only has data and, being packed, has no implicit padding.
+unsafe impl DataInit for virtio_scsi_cmd_resp {}
+
+impl virtio_scsi_cmd_resp {
+    fn new(response: u8) -> virtio_scsi_cmd_resp {
+        virtio_scsi_cmd_resp {
+            sense_len: Le32::from(0),
+            resid: Le32::from(0),
+            status_qualifier: Le16::from(0),
+            status: GOOD,
+            response,
+            sense: [0; SENSE_SIZE],
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct virtio_scsi_ctrl_tmf_req {
+    type_: Le32,
+    subtype: Le32,
+    lun: [u8; 8],
+    tag: Le64,
+}
+
+// Safe because it only has data and, being packed, has no implicit padding.
+unsafe impl DataInit for virtio_scsi_ctrl_tmf_req {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct virtio_scsi_ctrl_an_resp {
+    event_actual: Le32,
+    response: u8,
+}
+
+// Safe because it only has data and, being packed, has no implicit padding.
+unsafe impl DataInit for virtio_scsi_ctrl_an_resp {}
+
+/// The status a logical unit completed a command with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommandResult {
+    pub status: u8,
+    pub sense: Vec<u8>,
+}
+
+impl CommandResult {
+    pub fn good() -> CommandResult {
+        CommandResult {
+            status: GOOD,
+            sense: Vec::new(),
+        }
+    }
+
+    pub fn check_condition(sense: Sense) -> CommandResult {
+        CommandResult {
+            status: CHECK_CONDITION,
+            sense: sense.to_bytes().to_vec(),
+        }
+    }
+
+    pub fn reservation_conflict() -> CommandResult {
+        CommandResult {
+            status: RESERVATION_CONFLICT,
+            sense: Vec::new(),
+        }
+    }
+}
+
+/// A SCSI logical unit attached to the controller.
+pub trait LogicalUnit: Send {
+    /// Executes the command in `cdb`, reading its data-out buffer from `reader` and writing its
+    /// data-in buffer to `writer`. Only fails if the command couldn't be delivered at all; errors
+    /// of the unit are reported through the returned status.
+    fn execute(
+        &mut self,
+        cdb: &[u8],
+        reader: &mut Reader,
+        writer: &mut Writer,
+    ) -> io::Result<CommandResult>;
+
+    /// Returns the descriptors the unit needs in the device's jail.
+    fn keep_rds(&self) -> Vec<RawDescriptor>;
+}
+
+/// A logical unit and the address it's attached to the controller at.
+pub struct ScsiLun {
+    pub target: u8,
+    pub lun: u16,
+    pub unit: Box<dyn LogicalUnit>,
+}
+
+#[derive(Debug)]
+enum Error {
+    /// Invalid virtio descriptor chain.
+    Descriptor(DescriptorError),
+    /// Failed to read from virtqueue.
+    ReadQueue(io::Error),
+    /// Unknown control queue request type.
+    UnknownControlRequest(u32),
+    /// Failed to write to virtqueue.
+    WriteQueue(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Descriptor(e) => write!(f, "virtio descriptor error: {}", e),
+            ReadQueue(e) => write!(f, "failed to read from virtqueue: {}", e),
+            UnknownControlRequest(t) => write!(f, "unknown control request type: {}", t),
+            WriteQueue(e) => write!(f, "failed to write to virtqueue: {}", e),
+        }
+    }
+}
+
+impl ::std::error::Error for Error {}
+
+type Result<T> = ::std::result::Result<T, Error>;
+
+// Decodes the target and LUN of the single level LUN structure virtio-scsi addresses units with.
+fn parse_lun(lun: &[u8; 8]) -> Option<(u8, u16)> {
+    if lun[0] != 1 {
+        return None;
+    }
+    Some((lun[1], u16::from(lun[2] & 0x3f) << 8 | u16::from(lun[3])))
+}
+
+// Writes `data` to `writer`, truncated to the allocation length of the command.
+fn write_data_in(writer: &mut Writer, data: &[u8], allocation_length: usize) -> io::Result<()> {
+    let len = data
+        .len()
+        .min(allocation_length)
+        .min(writer.available_bytes());
+    writer.write_all(&data[..len])
+}
+
+type LunMap = BTreeMap<(u8, u16), Box<dyn LogicalUnit>>;
+
+fn target_exists(luns: &LunMap, target: u8) -> bool {
+    target_luns(luns, target).next().is_some()
+}
+
+// Returns the LUNs of the units attached to `target`.
+fn target_luns(luns: &LunMap, target: u8) -> impl Iterator<Item = u16> + '_ {
+    luns.range((target, 0)..=(target, MAX_LUN))
+        .map(|(&(_, lun), _)| lun)
+}
+
+// Executes the commands the controller answers for a target instead of one of its units: REPORT
+// LUNS, and the commands to a LUN that has no unit.
+fn execute_for_target(
+    luns: &LunMap,
+    target: u8,
+    cdb: &[u8],
+    writer: &mut Writer,
+) -> io::Result<CommandResult> {
+    match cdb[0] {
+        REPORT_LUNS => {
+            let allocation_length = u32::from_be_bytes([cdb[6], cdb[7], cdb[8], cdb[9]]);
+            write_data_in(
+                writer,
+                &report_luns(target_luns(luns, target)),
+                allocation_length as usize,
+            )?;
+            Ok(CommandResult::good())
+        }
+        INQUIRY => {
+            let allocation_length = u16::from_be_bytes([cdb[3], cdb[4]]);
+            let mut data = [0u8; 36];
+            // Peripheral qualifier 3 and type 0x1f: no unit can be attached at this LUN.
+            data[0] = 0x7f;
+            data[2] = 0x06;
+            data[3] = 0x02;
+            data[4] = 31;
+            write_data_in(writer, &data, allocation_length as usize)?;
+            Ok(CommandResult::good())
+        }
+        REQUEST_SENSE => {
+            write_data_in(
+                writer,
+                &Sense::LUN_NOT_SUPPORTED.to_bytes(),
+                cdb[4] as usize,
+            )?;
+            Ok(CommandResult::good())
+        }
+        _ => Ok(CommandResult::check_condition(Sense::LUN_NOT_SUPPORTED)),
+    }
+}
+
+fn execute_command(
+    luns: &mut LunMap,
+    req: &virtio_scsi_cmd_req,
+    reader: &mut Reader,
+    writer: &mut Writer,
+) -> virtio_scsi_cmd_resp {
+    let (target, lun) = match parse_lun(&req.lun) {
+        Some(addr) if target_exists(luns, addr.0) => addr,
+        _ => return virtio_scsi_cmd_resp::new(VIRTIO_SCSI_S_BAD_TARGET),
+    };
+    let cdb = &req.cdb[..];
+    let result = match luns.get_mut(&(target, lun)) {
+        Some(unit) if cdb[0] != REPORT_LUNS => unit.execute(cdb, reader, writer),
+        _ => execute_for_target(luns, target, cdb, writer),
+    };
+    let result = match result {
+        Ok(r) => r,
+        Err(e) => {
+            error!("scsi: failed to execute command {:#x}: {}", cdb[0], e);
+            return virtio_scsi_cmd_resp::new(VIRTIO_SCSI_S_FAILURE);
+        }
+    };
+
+    let mut resp = virtio_scsi_cmd_resp::new(VIRTIO_SCSI_S_OK);
+    resp.status = result.status;
+    let sense_len = result.sense.len().min(SENSE_SIZE);
+    resp.sense[..sense_len].copy_from_slice(&result.sense[..sense_len]);
+    resp.sense_len = Le32::from(sense_len as u32);
+    // Only one of the buffers is left over, as a command transfers data in one direction.
+    let resid = reader.available_bytes() + writer.available_bytes();
+    resp.resid = Le32::from(resid as u32);
+    resp
+}
+
+fn process_request(
+    mem: &GuestMemory,
+    luns: &mut LunMap,
+    avail_desc: DescriptorChain,
+) -> Result<usize> {
+    let mut reader = Reader::new(mem.clone(), avail_desc.clone()).map_err(Error::Descriptor)?;
+    let mut writer = Writer::new(mem.clone(), avail_desc).map_err(Error::Descriptor)?;
+
+    let req: virtio_scsi_cmd_req = reader.read_obj().map_err(Error::ReadQueue)?;
+    let mut data_writer = writer.split_at(size_of::<virtio_scsi_cmd_resp>());
+    let resp = execute_command(luns, &req, &mut reader, &mut data_writer);
+    writer.write_obj(resp).map_err(Error::WriteQueue)?;
+
+    Ok(writer.bytes_written() + data_writer.bytes_written())
+}
+
+fn process_control_request(
+    mem: &GuestMemory,
+    luns: &LunMap,
+    avail_desc: DescriptorChain,
+) -> Result<usize> {
+    let mut reader = Reader::new(mem.clone(), avail_desc.clone()).map_err(Error::Descriptor)?;
+    let mut writer = Writer::new(mem.clone(), avail_desc).map_err(Error::Descriptor)?;
+
+    let type_: Le32 = reader.clone().read_obj().map_err(Error::ReadQueue)?;
+    match type_.to_native() {
+        VIRTIO_SCSI_T_TMF => {
+            let req: virtio_scsi_ctrl_tmf_req = reader.read_obj().map_err(Error::ReadQueue)?;
+            // Commands complete before the next request is read, so there are never any tasks
+            // left to abort or reset.
+            let response = match parse_lun(&req.lun) {
+                Some((target, _)) if target_exists(luns, target) => VIRTIO_SCSI_S_FUNCTION_COMPLETE,
+                _ => VIRTIO_SCSI_S_BAD_TARGET,
+            };
+            writer.write_obj(response).map_err(Error::WriteQueue)?;
+        }
+        VIRTIO_SCSI_T_AN_QUERY | VIRTIO_SCSI_T_AN_SUBSCRIBE => {
+            // No asynchronous notifications are supported.
+            let resp = virtio_scsi_ctrl_an_resp {
+                event_actual: Le32::from(0),
+                response: VIRTIO_SCSI_S_OK,
+            };
+            writer.write_obj(resp).map_err(Error::WriteQueue)?;
+        }
+        t => return Err(Error::UnknownControlRequest(t)),
+    }
+
+    Ok(writer.bytes_written())
+}
+
+struct Worker {
+    interrupt: Interrupt,
+    mem: GuestMemory,
+    luns: LunMap,
+}
+
+impl Worker {
+    fn process_queue(&mut self, queue: &mut Queue, control: bool) -> bool {
+        let mut needs_interrupt = false;
+        while let Some(avail_desc) = queue.pop(&self.mem) {
+            let avail_desc_index = avail_desc.index;
+
+            let result = if control {
+                process_control_request(&self.mem, &self.luns, avail_desc)
+            } else {
+                process_request(&self.mem, &mut self.luns, avail_desc)
+            };
+            let bytes_written = match result {
+                Ok(count) => count,
+                Err(e) => {
+                    error!("scsi: unable to handle request: {}", e);
+                    0
+                }
+            };
+            queue.add_used(&self.mem, avail_desc_index, bytes_written as u32);
+            needs_interrupt = true;
+        }
+
+        needs_interrupt
+    }
+
+    fn run(&mut self, mut queues: Vec<Queue>, queue_evts: Vec<Event>, kill_evt: Event) {
+        #[derive(PollToken)]
+        enum Token {
+            ControlQueue,
+            EventQueue,
+            RequestQueue,
+            InterruptResample,
+            Kill,
+        }
+
+        let wait_ctx: WaitContext<Token> = match WaitContext::build_with(&[
+            (&queue_evts[0], Token::ControlQueue),
+            (&queue_evts[1], Token::EventQueue),
+            (&queue_evts[2], Token::RequestQueue),
+            (self.interrupt.get_resample_evt(), Token::InterruptResample),
+            (&kill_evt, Token::Kill),
+        ]) {
+            Ok(pc) => pc,
+            Err(e) => {
+                error!("failed creating WaitContext: {}", e);
+                self.interrupt.signal_needs_reset();
+                return;
+            }
+        };
+
+        'wait: loop {
+            let events = match wait_ctx.wait() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed polling for events: {}", e);
+                    break;
+                }
+            };
+
+            for event in events.iter().filter(|e| e.is_readable) {
+                let index = match event.token {
+                    Token::ControlQueue => 0,
+                    // The buffers of the event queue are left there, since no events are sent.
+                    Token::EventQueue => 1,
+                    Token::RequestQueue => 2,
+                    Token::InterruptResample => {
+                        self.interrupt.interrupt_resample();
+                        continue;
+                    }
+                    Token::Kill => return,
+                };
+                if let Err(e) = queue_evts[index].read() {
+                    error!("failed reading queue Event: {}", e);
+                    break 'wait;
+                }
+                let queue = &mut queues[index];
+                if index != 1 && self.process_queue(queue, index == 0) {
+                    queue.trigger_interrupt(&self.mem, &self.interrupt);
+                }
+            }
+        }
+
+        // Only errors get here. Resetting the device starts a new worker.
+        self.interrupt.signal_needs_reset();
+    }
+}
+
+/// Virtio SCSI controller emulated in userspace, with a single request queue.
+///
+/// The controller answers REPORT LUNS for its targets, and the commands to LUNs that have no
+/// unit, and passes the rest to the unit they address.
+pub struct Scsi {
+    kill_evt: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<()>>,
+    base_features: u64,
+    luns: Option<LunMap>,
+}
+
+impl Scsi {
+    /// Creates a controller with the units in `luns`. A unit at the same address as an earlier
+    /// one replaces it.
+    pub fn new(base_features: u64, luns: Vec<ScsiLun>) -> Scsi {
+        Scsi {
+            kill_evt: None,
+            worker_thread: None,
+            base_features,
+            luns: Some(
+                luns.into_iter()
+                    .map(|l| ((l.target, l.lun), l.unit))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl Drop for Scsi {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl VirtioDevice for Scsi {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        self.luns
+            .iter()
+            .flat_map(|luns| luns.values())
+            .flat_map(|unit| unit.keep_rds())
+            .collect()
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_SCSI
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.base_features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = virtio_scsi_config {
+            num_queues: Le32::from(1),
+            // Two descriptors of each chain are taken by the request and response headers.
+            seg_max: Le32::from(QUEUE_SIZE as u32 - 2),
+            max_sectors: Le32::from(0xffff),
+            cmd_per_lun: Le32::from(QUEUE_SIZE as u32),
+            event_info_size: Le32::from(EVENT_INFO_SIZE),
+            sense_size: Le32::from(SENSE_SIZE as u32),
+            cdb_size: Le32::from(CDB_SIZE as u32),
+            max_channel: Le16::from(0),
+            max_target: Le16::from(MAX_TARGET as u16),
+            max_lun: Le32::from(MAX_LUN as u32),
+        };
+        copy_config(data, 0, config.as_slice(), offset);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        queue_evts: Vec<Event>,
+    ) {
+        if queues.len() != QUEUE_SIZES.len() || queue_evts.len() != QUEUE_SIZES.len() {
+            return;
+        }
+
+        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed creating kill Event pair: {}", e);
+                return;
+            }
+        };
+        self.kill_evt = Some(self_kill_evt);
+
+        if let Some(luns) = self.luns.take() {
+            let worker_result = thread::Builder::new()
+                .name("virtio_scsi".to_string())
+                .spawn(move || {
+                    let mut worker = Worker {
+                        interrupt,
+                        mem,
+                        luns,
+                    };
+                    worker.run(queues, queue_evts, kill_evt);
+                });
+
+            match worker_result {
+                Err(e) => {
+                    error!("failed to spawn virtio_scsi worker: {}", e);
+                    return;
+                }
+                Ok(join_handle) => {
+                    self.worker_thread = Some(join_handle);
+                }
+            }
+        }
+    }
+}
+
+impl Suspendable for Scsi {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::GuestAddress;
+
+    use crate::virtio::descriptor_utils::{create_descriptor_chain, DescriptorType};
+
+    struct NullUnit;
+
+    impl LogicalUnit for NullUnit {
+        fn execute(
+            &mut self,
+            _cdb: &[u8],
+            _reader: &mut Reader,
+            _writer: &mut Writer,
+        ) -> io::Result<CommandResult> {
+            Ok(CommandResult::check_condition(Sense::INVALID_OPCODE))
+        }
+
+        fn keep_rds(&self) -> Vec<RawDescriptor> {
+            Vec::new()
+        }
+    }
+
+    fn lun_bytes(target: u8, lun: u16) -> [u8; 8] {
+        [1, target, 0x40 | (lun >> 8) as u8, lun as u8, 0, 0, 0, 0]
+    }
+
+    // Runs `cdb` on the unit at `target` and `lun`, returning the response and the data-in buffer.
+    fn run_command(
+        luns: &mut LunMap,
+        target: u8,
+        lun: u16,
+        cdb: &[u8],
+    ) -> (virtio_scsi_cmd_resp, Vec<u8>) {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut req = virtio_scsi_cmd_req {
+            lun: lun_bytes(target, lun),
+            tag: Le64::from(0),
+            task_attr: 0,
+            prio: 0,
+            crn: 0,
+            cdb: [0; CDB_SIZE],
+        };
+        req.cdb[..cdb.len()].copy_from_slice(cdb);
+        mem.write_obj_at_addr(req, GuestAddress(0x1000)).unwrap();
+
+        let resp_size = size_of::<virtio_scsi_cmd_resp>();
+        let avail_desc = create_descriptor_chain(
+            &mem,
+            GuestAddress(0x100),
+            GuestAddress(0x1000),
+            vec![
+                (
+                    DescriptorType::Readable,
+                    size_of::<virtio_scsi_cmd_req>() as u32,
+                ),
+                (DescriptorType::Writable, resp_size as u32),
+                (DescriptorType::Writable, 512),
+            ],
+            0,
+        )
+        .unwrap();
+        let written = process_request(&mem, luns, avail_desc).unwrap();
+
+        let resp_addr = GuestAddress(0x1000 + size_of::<virtio_scsi_cmd_req>() as u64);
+        let resp: virtio_scsi_cmd_resp = mem.read_obj_from_addr(resp_addr).unwrap();
+        let mut data = vec![0u8; written - resp_size];
+        mem.read_exact_at_addr(&mut data, resp_addr.unchecked_add(resp_size as u64))
+            .unwrap();
+        (resp, data)
+    }
+
+    fn units(addrs: &[(u8, u16)]) -> LunMap {
+        addrs
+            .iter()
+            .map(|&addr| (addr, Box::new(NullUnit) as Box<dyn LogicalUnit>))
+            .collect()
+    }
+
+    #[test]
+    fn parse_luns() {
+        assert_eq!(parse_lun(&lun_bytes(3, 0)), Some((3, 0)));
+        assert_eq!(parse_lun(&lun_bytes(0, 300)), Some((0, 300)));
+        assert_eq!(parse_lun(&[1, 2, 0, 5, 0, 0, 0, 0]), Some((2, 5)));
+        assert_eq!(parse_lun(&[0, 2, 0x40, 5, 0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn report_luns() {
+        let mut luns = units(&[(0, 0), (0, 300), (1, 7)]);
+        let (resp, data) = run_command(&mut luns, 0, 0, &[REPORT_LUNS, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+        assert_eq!(resp.response, VIRTIO_SCSI_S_OK);
+        assert_eq!(resp.status, GOOD);
+        assert_eq!(
+            data,
+            vec![
+                0, 0, 0, 16, 0, 0, 0, 0, // header
+                0, 0, 0, 0, 0, 0, 0, 0, // LUN 0
+                0x41, 0x2c, 0, 0, 0, 0, 0, 0, // LUN 300
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_units() {
+        let mut luns = units(&[(0, 1)]);
+
+        let (resp, _) = run_command(&mut luns, 1, 0, &[INQUIRY, 0, 0, 0, 36, 0]);
+        assert_eq!(resp.response, VIRTIO_SCSI_S_BAD_TARGET);
+
+        let (resp, data) = run_command(&mut luns, 0, 0, &[INQUIRY, 0, 0, 0, 36, 0]);
+        assert_eq!(resp.response, VIRTIO_SCSI_S_OK);
+        assert_eq!(resp.status, GOOD);
+        assert_eq!(data.len(), 36);
+        assert_eq!(data[0], 0x7f);
+
+        let (resp, _) = run_command(&mut luns, 0, 0, &[0x00, 0, 0, 0, 0, 0]);
+        assert_eq!(resp.status, CHECK_CONDITION);
+        assert_eq!(resp.sense[..18], Sense::LUN_NOT_SUPPORTED.to_bytes()[..]);
+
+        // The unit's own commands go to the unit.
+        let (resp, _) = run_command(&mut luns, 0, 1, &[0x00, 0, 0, 0, 0, 0]);
+        assert_eq!(resp.status, CHECK_CONDITION);
+        assert_eq!(resp.sense[12], Sense::INVALID_OPCODE.asc);
+        // The whole data-in buffer is left over.
+        assert_eq!({ resp.resid }.to_native(), 512);
+    }
+}
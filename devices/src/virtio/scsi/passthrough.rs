@@ -0,0 +1,194 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::raw::{c_int, c_uchar, c_uint, c_ushort, c_void};
+use std::ptr::null_mut;
+
+use base::{errno_result, ioctl_with_mut_ref, AsRawDescriptor, IoctlNr, RawDescriptor};
+use base::{Error as SysError, Result as SysResult};
+
+use super::{CommandResult, LogicalUnit, Sense, SENSE_SIZE};
+use crate::virtio::{Reader, Writer};
+
+const SG_GET_VERSION_NUM: IoctlNr = 0x2282;
+const SG_IO: IoctlNr = 0x2285;
+
+const SG_DXFER_NONE: c_int = -1;
+const SG_DXFER_TO_DEV: c_int = -2;
+const SG_DXFER_FROM_DEV: c_int = -3;
+
+// The version of the SCSI generic driver that added SG_IO.
+const SG_IO_MIN_VERSION: c_int = 30000;
+// Commands that take longer than this are aborted by the host.
+const COMMAND_TIMEOUT_MS: c_uint = 60_000;
+
+// The driver status of a command that returned sense data. Any other driver status is an error.
+const DRIVER_SENSE: c_ushort = 0x08;
+
+#[repr(C)]
+struct sg_io_hdr {
+    interface_id: c_int,
+    dxfer_direction: c_int,
+    cmd_len: c_uchar,
+    mx_sb_len: c_uchar,
+    iovec_count: c_ushort,
+    dxfer_len: c_uint,
+    dxferp: *mut c_void,
+    cmdp: *mut c_uchar,
+    sbp: *mut c_uchar,
+    timeout: c_uint,
+    flags: c_uint,
+    pack_id: c_int,
+    usr_ptr: *mut c_void,
+    status: c_uchar,
+    masked_status: c_uchar,
+    msg_status: c_uchar,
+    sb_len_wr: c_uchar,
+    host_status: c_ushort,
+    driver_status: c_ushort,
+    resid: c_int,
+    duration: c_uint,
+    info: c_uint,
+}
+
+// Returns the length of the command descriptor block `cdb` starts with, from its group code.
+fn cdb_len(cdb: &[u8]) -> usize {
+    match cdb[0] >> 5 {
+        0 => 6,
+        1 | 2 => 10,
+        4 => 16,
+        5 => 12,
+        // Variable length and vendor specific commands use the whole block.
+        _ => cdb.len(),
+    }
+}
+
+/// A SCSI device of the host, attached to a virtio-scsi controller by passing the commands to it
+/// with `SG_IO`.
+///
+/// The device can be a SCSI generic device like `/dev/sg0`, or a block device of a SCSI disk like
+/// `/dev/sda`. The host kernel only passes commands that could change the device's firmware or
+/// state for other users to a block device if the process has `CAP_SYS_RAWIO`.
+pub struct ScsiPassthrough {
+    device: File,
+}
+
+impl ScsiPassthrough {
+    pub fn new(device: File) -> SysResult<ScsiPassthrough> {
+        let mut version: c_int = 0;
+        // Safe because the kernel only writes an int to `version`, and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(&device, SG_GET_VERSION_NUM, &mut version) };
+        if ret < 0 {
+            return errno_result();
+        }
+        if version < SG_IO_MIN_VERSION {
+            return Err(SysError::new(libc::ENOTSUP));
+        }
+        Ok(ScsiPassthrough { device })
+    }
+}
+
+impl LogicalUnit for ScsiPassthrough {
+    fn execute(
+        &mut self,
+        cdb: &[u8],
+        reader: &mut Reader,
+        writer: &mut Writer,
+    ) -> io::Result<CommandResult> {
+        let mut cdb = cdb[..cdb_len(cdb)].to_vec();
+        let (direction, mut data) = match (reader.available_bytes(), writer.available_bytes()) {
+            (0, 0) => (SG_DXFER_NONE, Vec::new()),
+            (0, len) => (SG_DXFER_FROM_DEV, vec![0u8; len]),
+            (len, 0) => {
+                let mut data = vec![0u8; len];
+                reader.read_exact(&mut data)?;
+                (SG_DXFER_TO_DEV, data)
+            }
+            // SG_IO can't transfer data both ways.
+            _ => return Ok(CommandResult::check_condition(Sense::INVALID_FIELD_IN_CDB)),
+        };
+        let mut sense = [0u8; SENSE_SIZE];
+
+        let mut hdr = sg_io_hdr {
+            interface_id: 'S' as c_int,
+            dxfer_direction: direction,
+            cmd_len: cdb.len() as c_uchar,
+            mx_sb_len: sense.len() as c_uchar,
+            iovec_count: 0,
+            dxfer_len: data.len() as c_uint,
+            dxferp: if data.is_empty() {
+                null_mut()
+            } else {
+                data.as_mut_ptr() as *mut c_void
+            },
+            cmdp: cdb.as_mut_ptr(),
+            sbp: sense.as_mut_ptr(),
+            timeout: COMMAND_TIMEOUT_MS,
+            flags: 0,
+            pack_id: 0,
+            usr_ptr: null_mut(),
+            status: 0,
+            masked_status: 0,
+            msg_status: 0,
+            sb_len_wr: 0,
+            host_status: 0,
+            driver_status: 0,
+            resid: 0,
+            duration: 0,
+            info: 0,
+        };
+        // Safe because the buffers `hdr` points to outlive the call and are as long as it says, and
+        // we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(&self.device, SG_IO, &mut hdr) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if hdr.host_status != 0 || hdr.driver_status & !DRIVER_SENSE != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "host status {:#x}, driver status {:#x}",
+                    hdr.host_status, hdr.driver_status
+                ),
+            ));
+        }
+
+        if direction == SG_DXFER_FROM_DEV {
+            let transferred = data.len() - (hdr.resid.max(0) as usize).min(data.len());
+            writer.write_all(&data[..transferred])?;
+        }
+        Ok(CommandResult {
+            status: hdr.status,
+            sense: sense[..hdr.sb_len_wr as usize].to_vec(),
+        })
+    }
+
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        vec![self.device.as_raw_descriptor()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cdb_lengths() {
+        let mut cdb = [0u8; 32];
+        for &(opcode, len) in &[(0x12, 6), (0x28, 10), (0x5e, 10), (0x88, 16), (0xa0, 12)] {
+            cdb[0] = opcode;
+            assert_eq!(cdb_len(&cdb), len);
+        }
+        cdb[0] = 0x7f;
+        assert_eq!(cdb_len(&cdb), 32);
+    }
+
+    #[test]
+    fn not_sg_device() {
+        let f = tempfile::tempfile().unwrap();
+        assert!(ScsiPassthrough::new(f).is_err());
+    }
+}
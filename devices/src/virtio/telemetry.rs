@@ -0,0 +1,174 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The guest telemetry port, a virtio console the guest writes records about its health to.
+//!
+//! Each record is a line of fields separated by spaces, the first naming the kind of record:
+//!
+//! * `boot MILESTONE UPTIME_MS`: the guest reached a boot milestone, e.g. `boot userspace 2311`.
+//! * `memory_pressure SOME FULL`: the `avg10` values of `/proc/pressure/memory`, in percent with
+//!   up to two decimals, e.g. `memory_pressure 4.12 0.50`.
+//! * `oom_kill PID NAME`: the OOM killer killed a process.
+//!
+//! Fields after the ones a record needs are ignored, and so are lines that aren't records this
+//! host understands, so that guests can send records that only newer hosts know about.
+
+use std::io;
+
+use base::error;
+use msg_socket::MsgSender;
+use vm_control::{GuestTelemetryEvent, GuestTelemetrySendSocket};
+
+/// The longest line that is parsed as a record. The rest of longer lines is dropped.
+const MAX_RECORD_LEN: usize = 256;
+
+// Parses a decimal number of percent with up to two decimals into hundredths of a percent.
+fn parse_hundredths(s: &str) -> Option<u32> {
+    let (int, frac) = match s.find('.') {
+        Some(dot) => (&s[..dot], &s[dot + 1..]),
+        None => (s, ""),
+    };
+    if frac.len() > 2 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let int: u32 = int.parse().ok()?;
+    let frac: u32 = format!("{:0<2}", frac).parse().ok()?;
+    int.checked_mul(100)?.checked_add(frac)
+}
+
+/// Parses a `line` the guest wrote, without its newline, into the event it records.
+pub fn parse_record(line: &[u8]) -> Option<GuestTelemetryEvent> {
+    let mut fields = std::str::from_utf8(line).ok()?.split_whitespace();
+    let event = match fields.next()? {
+        "boot" => GuestTelemetryEvent::BootMilestone {
+            name: fields.next()?.as_bytes().to_vec(),
+            uptime_ms: fields.next()?.parse().ok()?,
+        },
+        "memory_pressure" => GuestTelemetryEvent::MemoryPressure {
+            some_avg10: parse_hundredths(fields.next()?)?,
+            full_avg10: parse_hundredths(fields.next()?)?,
+        },
+        "oom_kill" => GuestTelemetryEvent::OomKill {
+            pid: fields.next()?.parse().ok()?,
+            name: fields.next()?.as_bytes().to_vec(),
+        },
+        _ => return None,
+    };
+    Some(event)
+}
+
+/// The output of the telemetry port's console. Parses the records the guest writes and sends them
+/// to the main process.
+pub struct TelemetryOutput {
+    socket: GuestTelemetrySendSocket,
+    line: Vec<u8>,
+    // Set while dropping the rest of a line that was too long.
+    overlong: bool,
+}
+
+impl TelemetryOutput {
+    pub fn new(socket: GuestTelemetrySendSocket) -> TelemetryOutput {
+        TelemetryOutput {
+            socket,
+            line: Vec::with_capacity(MAX_RECORD_LEN),
+            overlong: false,
+        }
+    }
+
+    fn end_line(&mut self) {
+        if !self.overlong {
+            if let Some(event) = parse_record(&self.line) {
+                if let Err(e) = self.socket.send(&event) {
+                    error!("failed to send guest telemetry: {}", e);
+                }
+            }
+        }
+        self.line.clear();
+        self.overlong = false;
+    }
+}
+
+impl io::Write for TelemetryOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if byte == b'\n' {
+                self.end_line();
+            } else if self.line.len() < MAX_RECORD_LEN {
+                self.line.push(byte);
+            } else {
+                self.overlong = true;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use msg_socket::MsgReceiver;
+
+    #[test]
+    fn parse_records() {
+        assert_eq!(
+            parse_record(b"boot userspace 2311"),
+            Some(GuestTelemetryEvent::BootMilestone {
+                name: b"userspace".to_vec(),
+                uptime_ms: 2311,
+            })
+        );
+        assert_eq!(
+            parse_record(b"memory_pressure 4.12 0.5 extra"),
+            Some(GuestTelemetryEvent::MemoryPressure {
+                some_avg10: 412,
+                full_avg10: 50,
+            })
+        );
+        assert_eq!(
+            parse_record(b"oom_kill 42 chrome"),
+            Some(GuestTelemetryEvent::OomKill {
+                pid: 42,
+                name: b"chrome".to_vec(),
+            })
+        );
+        assert_eq!(parse_record(b"memory_pressure 4.123 0"), None);
+        assert_eq!(parse_record(b"boot userspace"), None);
+        assert_eq!(parse_record(b"unknown 1"), None);
+        assert_eq!(parse_record(b""), None);
+    }
+
+    #[test]
+    fn output_splits_lines() {
+        let (host, device) = msg_socket::pair::<(), GuestTelemetryEvent>().unwrap();
+        let mut output = TelemetryOutput::new(device);
+        output.write_all(b"boot kernel 1").unwrap();
+        output.write_all(b"00\nnot a record\n").unwrap();
+        // Records are not parsed from the start of overlong lines.
+        let mut overlong = b"oom_kill 2 ".to_vec();
+        overlong.resize(MAX_RECORD_LEN + 1, b'x');
+        overlong.extend_from_slice(b"\noom_kill 1 init\n");
+        output.write_all(&overlong).unwrap();
+
+        assert_eq!(
+            host.recv().unwrap(),
+            GuestTelemetryEvent::BootMilestone {
+                name: b"kernel".to_vec(),
+                uptime_ms: 100,
+            }
+        );
+        assert_eq!(
+            host.recv().unwrap(),
+            GuestTelemetryEvent::OomKill {
+                pid: 1,
+                name: b"init".to_vec(),
+            }
+        );
+    }
+}
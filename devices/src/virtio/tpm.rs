@@ -16,6 +16,7 @@ use vm_memory::GuestMemory;
 use super::{
     DescriptorChain, DescriptorError, Interrupt, Queue, Reader, VirtioDevice, Writer, TYPE_TPM,
 };
+use crate::Suspendable;
 
 // A single queue of size 2. The guest kernel driver will enqueue a single
 // descriptor chain containing one command buffer and one response buffer at a
@@ -118,6 +119,7 @@ impl Worker {
             Ok(pc) => pc,
             Err(e) => {
                 error!("vtpm failed creating WaitContext: {}", e);
+                self.interrupt.signal_needs_reset();
                 return;
             }
         };
@@ -144,13 +146,16 @@ impl Worker {
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
-                    Token::Kill => break 'wait,
+                    Token::Kill => return,
                 }
             }
             if needs_interrupt == NeedsInterrupt::Yes {
-                self.interrupt.signal_used_queue(self.queue.vector);
+                self.queue.trigger_interrupt(&self.mem, &self.interrupt);
             }
         }
+
+        // Only errors get here. Resetting the device starts a new worker.
+        self.interrupt.signal_needs_reset();
     }
 }
 
@@ -252,6 +257,8 @@ impl VirtioDevice for Tpm {
     }
 }
 
+impl Suspendable for Tpm {}
+
 #[derive(PartialEq)]
 enum NeedsInterrupt {
     Yes,
@@ -10,14 +10,21 @@ use base::Error as SysError;
 use net_util::Error as TapError;
 use remain::sorted;
 use vhost::Error as VhostError;
+use vm_memory::GuestMemoryError;
 
 mod control_socket;
 mod net;
+mod scsi;
+mod user_blk;
+mod user_net;
 mod vsock;
 mod worker;
 
 pub use self::control_socket::*;
 pub use self::net::Net;
+pub use self::scsi::Scsi;
+pub use self::user_blk::VhostUserBlock;
+pub use self::user_net::VhostUserNet;
 pub use self::vsock::Vsock;
 
 #[sorted]
@@ -29,6 +36,8 @@ pub enum Error {
     CreateKillEvent(SysError),
     /// Creating wait context failed.
     CreateWaitContext(SysError),
+    /// Failed to read the index of a used ring.
+    ReadUsedIndex(GuestMemoryError),
     /// Enabling tap interface failed.
     TapEnable(TapError),
     /// Open tap device failed.
@@ -43,6 +52,8 @@ pub enum Error {
     TapSetOffload(TapError),
     /// Setting vnet header size failed.
     TapSetVnetHdrSize(TapError),
+    /// Validating tap interface failed.
+    TapValidate(String),
     /// Get features failed.
     VhostGetFeatures(VhostError),
     /// Failed to create vhost event.
@@ -53,6 +64,14 @@ pub enum Error {
     VhostNetSetBackend(VhostError),
     /// Failed to open vhost device.
     VhostOpen(VhostError),
+    /// The host kernel's vhost-scsi ABI version is newer than supported.
+    VhostScsiAbiVersion(i32),
+    /// Failed to clear vhost-scsi endpoint.
+    VhostScsiClearEndpoint(VhostError),
+    /// Failed to get vhost-scsi ABI version.
+    VhostScsiGetAbiVersion(VhostError),
+    /// Failed to set vhost-scsi endpoint.
+    VhostScsiSetEndpoint(VhostError),
     /// Set features failed.
     VhostSetFeatures(VhostError),
     /// Set mem table failed.
@@ -69,6 +88,12 @@ pub enum Error {
     VhostSetVringKick(VhostError),
     /// Set vring num failed.
     VhostSetVringNum(VhostError),
+    /// Failed to connect to the vhost-user backend.
+    VhostUserConnect(VhostError),
+    /// Failed to read the config space from the vhost-user backend.
+    VhostUserGetConfig(VhostError),
+    /// Failed to enable or disable a vring of the vhost-user backend.
+    VhostUserSetVringEnable(VhostError),
     /// Failed to set CID for guest.
     VhostVsockSetCid(VhostError),
     /// Failed to start vhost-vsock driver.
@@ -89,6 +114,7 @@ impl Display for Error {
             CloneKillEvent(e) => write!(f, "failed to clone kill event: {}", e),
             CreateKillEvent(e) => write!(f, "failed to create kill event: {}", e),
             CreateWaitContext(e) => write!(f, "failed to create poll context: {}", e),
+            ReadUsedIndex(e) => write!(f, "failed to read used ring index: {}", e),
             TapEnable(e) => write!(f, "failed to enable tap interface: {}", e),
             TapOpen(e) => write!(f, "failed to open tap device: {}", e),
             TapSetIp(e) => write!(f, "failed to set tap IP: {}", e),
@@ -96,11 +122,16 @@ impl Display for Error {
             TapSetNetmask(e) => write!(f, "failed to set tap netmask: {}", e),
             TapSetOffload(e) => write!(f, "failed to set tap interface offload flags: {}", e),
             TapSetVnetHdrSize(e) => write!(f, "failed to set vnet header size: {}", e),
+            TapValidate(s) => write!(f, "failed to validate tap interface: {}", s),
             VhostGetFeatures(e) => write!(f, "failed to get features: {}", e),
             VhostIrqCreate(e) => write!(f, "failed to create vhost event: {}", e),
             VhostIrqRead(e) => write!(f, "failed to read vhost event: {}", e),
             VhostNetSetBackend(e) => write!(f, "net set backend failed: {}", e),
             VhostOpen(e) => write!(f, "failed to open vhost device: {}", e),
+            VhostScsiAbiVersion(v) => write!(f, "unsupported vhost-scsi ABI version {}", v),
+            VhostScsiClearEndpoint(e) => write!(f, "failed to clear vhost-scsi endpoint: {}", e),
+            VhostScsiGetAbiVersion(e) => write!(f, "failed to get vhost-scsi ABI version: {}", e),
+            VhostScsiSetEndpoint(e) => write!(f, "failed to set vhost-scsi endpoint: {}", e),
             VhostSetFeatures(e) => write!(f, "failed to set features: {}", e),
             VhostSetMemTable(e) => write!(f, "failed to set mem table: {}", e),
             VhostSetOwner(e) => write!(f, "failed to set owner: {}", e),
@@ -109,6 +140,9 @@ impl Display for Error {
             VhostSetVringCall(e) => write!(f, "failed to set vring call: {}", e),
             VhostSetVringKick(e) => write!(f, "failed to set vring kick: {}", e),
             VhostSetVringNum(e) => write!(f, "failed to set vring num: {}", e),
+            VhostUserConnect(e) => write!(f, "failed to connect to vhost-user backend: {}", e),
+            VhostUserGetConfig(e) => write!(f, "failed to get vhost-user config: {}", e),
+            VhostUserSetVringEnable(e) => write!(f, "failed to set vring enable: {}", e),
             VhostVsockSetCid(e) => write!(f, "failed to set CID for guest: {}", e),
             VhostVsockStart(e) => write!(f, "failed to start vhost-vsock driver: {}", e),
             WaitError(e) => write!(f, "failed waiting for events: {}", e),
@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::io;
 use std::mem;
 use std::net::Ipv4Addr;
 use std::thread;
@@ -17,17 +18,59 @@ use super::control_socket::*;
 use super::worker::Worker;
 use super::{Error, Result};
 use crate::pci::MsixStatus;
+use crate::suspendable;
 use crate::virtio::{Interrupt, Queue, VirtioDevice, TYPE_NET};
+use crate::{Suspendable, SuspendableError};
 use msg_socket::{MsgReceiver, MsgSender};
 
 const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 2;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
 
+// Starts a thread running `worker` with `tap` as the backend of its queues, which gives back the
+// worker, the tap and `queue_evts` once it is killed.
+fn spawn_worker<T, U>(
+    mut worker: Worker<U>,
+    tap: T,
+    queue_evts: Vec<Event>,
+) -> io::Result<thread::JoinHandle<(Worker<U>, T, Vec<Event>)>>
+where
+    T: TapT + 'static,
+    U: VhostNetT<T> + 'static,
+{
+    thread::Builder::new()
+        .name("vhost_net".to_string())
+        .spawn(move || {
+            let activate_vqs = |handle: &U| -> Result<()> {
+                for idx in 0..NUM_QUEUES {
+                    handle
+                        .set_backend(idx, Some(&tap))
+                        .map_err(Error::VhostNetSetBackend)?;
+                }
+                Ok(())
+            };
+            let cleanup_vqs = |handle: &U| -> Result<()> {
+                for idx in 0..NUM_QUEUES {
+                    handle
+                        .set_backend(idx, None)
+                        .map_err(Error::VhostNetSetBackend)?;
+                }
+                Ok(())
+            };
+            let result = worker.run(&queue_evts, QUEUE_SIZES, activate_vqs, cleanup_vqs);
+            if let Err(e) = result {
+                error!("net worker thread exited with error: {}", e);
+            }
+            (worker, tap, queue_evts)
+        })
+}
+
 pub struct Net<T: TapT, U: VhostNetT<T>> {
     workers_kill_evt: Option<Event>,
     kill_evt: Event,
-    worker_thread: Option<thread::JoinHandle<(Worker<U>, T)>>,
+    worker_thread: Option<thread::JoinHandle<(Worker<U>, T, Vec<Event>)>>,
+    // The worker stopped by `sleep`, to be restarted by `wake`.
+    sleeping_worker: Option<(Worker<U>, T, Vec<Event>)>,
     tap: Option<T>,
     vhost_net_handle: Option<U>,
     vhost_interrupt: Option<Vec<Event>>,
@@ -51,13 +94,41 @@ where
         mac_addr: MacAddress,
         mem: &GuestMemory,
     ) -> Result<Net<T, U>> {
-        let kill_evt = Event::new().map_err(Error::CreateKillEvent)?;
-
         let tap: T = T::new(true, false).map_err(Error::TapOpen)?;
         tap.set_ip_addr(ip_addr).map_err(Error::TapSetIp)?;
         tap.set_netmask(netmask).map_err(Error::TapSetNetmask)?;
         tap.set_mac_address(mac_addr)
             .map_err(Error::TapSetMacAddress)?;
+        tap.enable().map_err(Error::TapEnable)?;
+
+        Net::with_tap(base_features, tap, mem)
+    }
+
+    /// Creates a new virtio network device from a tap device that has already been configured,
+    /// such as one passed in by descriptor.
+    pub fn from(base_features: u64, tap: T, mem: &GuestMemory) -> Result<Net<T, U>> {
+        let flags = tap.if_flags();
+        let missing_flags = [
+            (net_sys::IFF_TAP, "IFF_TAP"),
+            (net_sys::IFF_NO_PI, "IFF_NO_PI"),
+            (net_sys::IFF_VNET_HDR, "IFF_VNET_HDR"),
+        ]
+        .iter()
+        .filter(|(value, _)| value & flags == 0)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>();
+        if !missing_flags.is_empty() {
+            return Err(Error::TapValidate(format!(
+                "Missing flags: {:?}",
+                missing_flags
+            )));
+        }
+
+        Net::with_tap(base_features, tap, mem)
+    }
+
+    fn with_tap(base_features: u64, tap: T, mem: &GuestMemory) -> Result<Net<T, U>> {
+        let kill_evt = Event::new().map_err(Error::CreateKillEvent)?;
 
         // Set offload flags to match the virtio features below.
         tap.set_offload(
@@ -70,7 +141,6 @@ where
         tap.set_vnet_hdr_size(vnet_hdr_size)
             .map_err(Error::TapSetVnetHdrSize)?;
 
-        tap.enable().map_err(Error::TapEnable)?;
         let vhost_net_handle = U::new(mem).map_err(Error::VhostOpen)?;
 
         let avail_features = base_features
@@ -96,6 +166,7 @@ where
             workers_kill_evt: Some(kill_evt.try_clone().map_err(Error::CloneKillEvent)?),
             kill_evt,
             worker_thread: None,
+            sleeping_worker: None,
             tap: Some(tap),
             vhost_net_handle: Some(vhost_net_handle),
             vhost_interrupt: Some(vhost_interrupt),
@@ -211,43 +282,16 @@ where
                         } else {
                             None
                         };
-                        let worker_result = thread::Builder::new()
-                            .name("vhost_net".to_string())
-                            .spawn(move || {
-                                let mut worker = Worker::new(
-                                    queues,
-                                    vhost_net_handle,
-                                    vhost_interrupt,
-                                    interrupt,
-                                    acked_features,
-                                    kill_evt,
-                                    socket,
-                                );
-                                let activate_vqs = |handle: &U| -> Result<()> {
-                                    for idx in 0..NUM_QUEUES {
-                                        handle
-                                            .set_backend(idx, Some(&tap))
-                                            .map_err(Error::VhostNetSetBackend)?;
-                                    }
-                                    Ok(())
-                                };
-                                let cleanup_vqs = |handle: &U| -> Result<()> {
-                                    for idx in 0..NUM_QUEUES {
-                                        handle
-                                            .set_backend(idx, None)
-                                            .map_err(Error::VhostNetSetBackend)?;
-                                    }
-                                    Ok(())
-                                };
-                                let result =
-                                    worker.run(queue_evts, QUEUE_SIZES, activate_vqs, cleanup_vqs);
-                                if let Err(e) = result {
-                                    error!("net worker thread exited with error: {}", e);
-                                }
-                                (worker, tap)
-                            });
-
-                        match worker_result {
+                        let worker = Worker::new(
+                            queues,
+                            vhost_net_handle,
+                            vhost_interrupt,
+                            interrupt,
+                            acked_features,
+                            kill_evt,
+                            socket,
+                        );
+                        match spawn_worker(worker, tap, queue_evts) {
                             Err(e) => {
                                 error!("failed to spawn vhost_net worker: {}", e);
                                 return;
@@ -317,29 +361,88 @@ where
     }
 
     fn reset(&mut self) -> bool {
-        // Only kill the child if it claimed its event.
-        if self.workers_kill_evt.is_none() && self.kill_evt.write(1).is_err() {
+        // A sleeping worker isn't waiting on its kill event, which has to stay clear for it.
+        if self.worker_thread.is_some() && self.kill_evt.write(1).is_err() {
             error!("{}: failed to notify the kill event", self.debug_label());
             return false;
         }
 
-        if let Some(worker_thread) = self.worker_thread.take() {
-            match worker_thread.join() {
+        let worker = match self.worker_thread.take() {
+            Some(worker_thread) => match worker_thread.join() {
                 Err(_) => {
                     error!("{}: failed to get back resources", self.debug_label());
                     return false;
                 }
-                Ok((worker, tap)) => {
-                    self.vhost_net_handle = Some(worker.vhost_handle);
-                    self.tap = Some(tap);
-                    self.vhost_interrupt = Some(worker.vhost_interrupt);
-                    self.workers_kill_evt = Some(worker.kill_evt);
-                    self.response_socket = worker.response_socket;
-                    return true;
-                }
+                Ok(worker) => worker,
+            },
+            None => match self.sleeping_worker.take() {
+                Some(worker) => worker,
+                None => return false,
+            },
+        };
+        let (worker, tap, _queue_evts) = worker;
+        self.vhost_net_handle = Some(worker.vhost_handle);
+        self.tap = Some(tap);
+        self.vhost_interrupt = Some(worker.vhost_interrupt);
+        self.workers_kill_evt = Some(worker.kill_evt);
+        self.response_socket = worker.response_socket;
+        self.acked_features = 0;
+        true
+    }
+}
+
+// Sleeping stops the worker, which takes the tap off the queues, and waking starts it again from
+// the last buffers the kernel used in each queue.
+impl<T, U> Suspendable for Net<T, U>
+where
+    T: TapT + 'static,
+    U: VhostNetT<T> + 'static,
+{
+    fn sleep(&mut self) -> suspendable::Result<()> {
+        let worker_thread = match self.worker_thread.take() {
+            Some(worker_thread) => worker_thread,
+            None => return Ok(()),
+        };
+        if let Err(e) = self.kill_evt.write(1) {
+            error!(
+                "{}: failed to notify the kill event: {}",
+                self.debug_label(),
+                e
+            );
+            self.worker_thread = Some(worker_thread);
+            return Err(SuspendableError::WorkerFailed);
+        }
+        match worker_thread.join() {
+            Ok(worker) => {
+                self.sleeping_worker = Some(worker);
+                Ok(())
+            }
+            Err(_) => {
+                error!("{}: failed to stop the worker", self.debug_label());
+                Err(SuspendableError::WorkerFailed)
+            }
+        }
+    }
+
+    fn wake(&mut self) -> suspendable::Result<()> {
+        let (worker, tap, queue_evts) = match self.sleeping_worker.take() {
+            Some(worker) => worker,
+            None => return Ok(()),
+        };
+        match spawn_worker(worker, tap, queue_evts) {
+            Ok(join_handle) => {
+                self.worker_thread = Some(join_handle);
+                Ok(())
+            }
+            Err(e) => {
+                error!(
+                    "{}: failed to spawn vhost_net worker: {}",
+                    self.debug_label(),
+                    e
+                );
+                Err(SuspendableError::WorkerFailed)
             }
         }
-        false
     }
 }
 
@@ -379,6 +482,17 @@ pub mod tests {
         create_net_common();
     }
 
+    #[test]
+    fn from_unconfigured_tap() {
+        let guest_memory = create_guest_memory().unwrap();
+        let tap = FakeTap::new(true, false).unwrap();
+        // The fake tap only reports IFF_TAP.
+        match Net::<FakeTap, FakeNet<FakeTap>>::from(base_features(false), tap, &guest_memory) {
+            Err(Error::TapValidate(_)) => {}
+            _ => panic!("tap without IFF_VNET_HDR was accepted"),
+        }
+    }
+
     #[test]
     fn keep_rds() {
         let net = create_net_common();
@@ -418,4 +532,30 @@ pub mod tests {
             vec![Event::new().unwrap()],
         );
     }
+
+    #[test]
+    fn sleep_and_wake() {
+        let mut net = create_net_common();
+        let guest_memory = create_guest_memory().unwrap();
+        net.activate(
+            guest_memory,
+            Interrupt::new(
+                Arc::new(AtomicUsize::new(0)),
+                Event::new().unwrap(),
+                Event::new().unwrap(),
+                None,
+                VIRTIO_MSI_NO_VECTOR,
+            ),
+            vec![Queue::new(QUEUE_SIZE), Queue::new(QUEUE_SIZE)],
+            vec![Event::new().unwrap(), Event::new().unwrap()],
+        );
+        net.sleep().unwrap();
+        assert!(net.worker_thread.is_none());
+        net.wake().unwrap();
+        assert!(net.sleeping_worker.is_none());
+        net.sleep().unwrap();
+        // The resources come back from the sleeping worker too.
+        assert!(net.reset());
+        assert!(net.tap.is_some());
+    }
 }
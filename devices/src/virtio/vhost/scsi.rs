@@ -0,0 +1,300 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::thread;
+
+use data_model::{DataInit, Le16, Le32};
+
+use base::{error, warn, AsRawDescriptor, Event, RawDescriptor};
+use vhost::Vhost;
+use vhost::{Scsi as VhostScsiHandle, VHOST_SCSI_ABI_VERSION};
+use vm_memory::GuestMemory;
+
+use super::worker::Worker;
+use super::{Error, Result};
+use crate::virtio::{copy_config, Interrupt, Queue, VirtioDevice, TYPE_SCSI};
+use crate::Suspendable;
+
+const QUEUE_SIZE: u16 = 128;
+// The control and event queues come before the request queues.
+const NUM_FIXED_QUEUES: usize = 2;
+
+// Sizes the vhost-scsi driver in the host kernel is built with. The guest can't change them.
+const SENSE_SIZE: u32 = 96;
+const CDB_SIZE: u32 = 32;
+// Size of struct virtio_scsi_event.
+const EVENT_INFO_SIZE: u32 = 16;
+const MAX_TARGET: u16 = 255;
+const MAX_LUN: u32 = 16383;
+
+// Feature bits.
+const VIRTIO_SCSI_F_HOTPLUG: u32 = 1;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_scsi_config {
+    num_queues: Le32,
+    seg_max: Le32,
+    max_sectors: Le32,
+    cmd_per_lun: Le32,
+    event_info_size: Le32,
+    sense_size: Le32,
+    cdb_size: Le32,
+    max_channel: Le16,
+    max_target: Le16,
+    max_lun: Le32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_scsi_config {}
+
+fn build_config_space(num_queues: u16) -> virtio_scsi_config {
+    virtio_scsi_config {
+        num_queues: Le32::from(num_queues as u32),
+        // Two descriptors of each chain are taken by the request and response headers.
+        seg_max: Le32::from(QUEUE_SIZE as u32 - 2),
+        max_sectors: Le32::from(0xffff),
+        cmd_per_lun: Le32::from(QUEUE_SIZE as u32),
+        event_info_size: Le32::from(EVENT_INFO_SIZE),
+        sense_size: Le32::from(SENSE_SIZE),
+        cdb_size: Le32::from(CDB_SIZE),
+        max_channel: Le16::from(0),
+        max_target: Le16::from(MAX_TARGET),
+        max_lun: Le32::from(MAX_LUN),
+    }
+}
+
+/// Virtio SCSI device whose request queues are served by a LIO target in the host kernel through
+/// vhost-scsi. SCSI commands, including persistent reservations, are passed to the target as-is.
+pub struct Scsi {
+    worker_kill_evt: Option<Event>,
+    kill_evt: Option<Event>,
+    vhost_handle: Option<VhostScsiHandle>,
+    wwpn: String,
+    tpgt: u16,
+    config: virtio_scsi_config,
+    queue_sizes: Vec<u16>,
+    interrupts: Option<Vec<Event>>,
+    avail_features: u64,
+    acked_features: u64,
+}
+
+impl Scsi {
+    /// Create a new virtio-scsi device bound to target portal group `tpgt` of the vhost target
+    /// `wwpn`, with `num_queues` request queues.
+    pub fn new(
+        base_features: u64,
+        wwpn: &str,
+        tpgt: u16,
+        num_queues: u16,
+        mem: &GuestMemory,
+    ) -> Result<Scsi> {
+        let kill_evt = Event::new().map_err(Error::CreateKillEvent)?;
+        let handle = VhostScsiHandle::new(mem).map_err(Error::VhostOpen)?;
+
+        let abi_version = handle
+            .get_abi_version()
+            .map_err(Error::VhostScsiGetAbiVersion)?;
+        if abi_version > VHOST_SCSI_ABI_VERSION {
+            return Err(Error::VhostScsiAbiVersion(abi_version));
+        }
+
+        let avail_features = base_features
+            | 1 << virtio_sys::vhost::VIRTIO_F_NOTIFY_ON_EMPTY
+            | 1 << virtio_sys::vhost::VIRTIO_RING_F_INDIRECT_DESC
+            | 1 << virtio_sys::vhost::VIRTIO_RING_F_EVENT_IDX
+            | 1 << virtio_sys::vhost::VIRTIO_F_ANY_LAYOUT
+            | 1 << VIRTIO_SCSI_F_HOTPLUG;
+
+        let num_vqs = NUM_FIXED_QUEUES + num_queues as usize;
+        let mut interrupts = Vec::new();
+        for _ in 0..num_vqs {
+            interrupts.push(Event::new().map_err(Error::VhostIrqCreate)?);
+        }
+
+        Ok(Scsi {
+            worker_kill_evt: Some(kill_evt.try_clone().map_err(Error::CloneKillEvent)?),
+            kill_evt: Some(kill_evt),
+            vhost_handle: Some(handle),
+            wwpn: wwpn.to_owned(),
+            tpgt,
+            config: build_config_space(num_queues),
+            queue_sizes: vec![QUEUE_SIZE; num_vqs],
+            interrupts: Some(interrupts),
+            avail_features,
+            acked_features: 0,
+        })
+    }
+}
+
+impl Drop for Scsi {
+    fn drop(&mut self) {
+        // Only kill the child if it claimed its event.
+        if self.worker_kill_evt.is_none() {
+            if let Some(kill_evt) = &self.kill_evt {
+                // Ignore the result because there is nothing we can do about it.
+                let _ = kill_evt.write(1);
+            }
+        }
+    }
+}
+
+impl VirtioDevice for Scsi {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
+
+        if let Some(handle) = &self.vhost_handle {
+            keep_rds.push(handle.as_raw_descriptor());
+        }
+
+        if let Some(interrupt) = &self.interrupts {
+            for vhost_int in interrupt.iter() {
+                keep_rds.push(vhost_int.as_raw_descriptor());
+            }
+        }
+
+        if let Some(worker_kill_evt) = &self.worker_kill_evt {
+            keep_rds.push(worker_kill_evt.as_raw_descriptor());
+        }
+
+        keep_rds
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_SCSI
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        copy_config(data, 0, self.config.as_slice(), offset);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // Only sense_size and cdb_size are writable, and the host kernel only supports the
+        // defaults, so the config space is left unchanged.
+        let mut config = self.config;
+        copy_config(config.as_mut_slice(), offset, data, 0);
+        if config.sense_size.to_native() != SENSE_SIZE || config.cdb_size.to_native() != CDB_SIZE {
+            warn!(
+                "vhost-scsi: guest tried to change sense size to {} and cdb size to {}",
+                config.sense_size.to_native(),
+                config.cdb_size.to_native()
+            );
+        }
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("vhost-scsi: virtio-scsi got unknown feature ack: {:x}", v);
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn activate(
+        &mut self,
+        _: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        queue_evts: Vec<Event>,
+    ) {
+        let num_vqs = self.queue_sizes.len();
+        if queues.len() != num_vqs || queue_evts.len() != num_vqs {
+            error!(
+                "vhost-scsi: expected {} queues, got {}",
+                num_vqs,
+                queues.len()
+            );
+            return;
+        }
+
+        if let Some(vhost_handle) = self.vhost_handle.take() {
+            if let Some(interrupts) = self.interrupts.take() {
+                if let Some(kill_evt) = self.worker_kill_evt.take() {
+                    let acked_features = self.acked_features;
+                    let queue_sizes = self.queue_sizes.clone();
+                    let wwpn = self.wwpn.clone();
+                    let tpgt = self.tpgt;
+                    let worker_result = thread::Builder::new()
+                        .name("vhost_scsi".to_string())
+                        .spawn(move || {
+                            // Unlike vsock, the kernel driver also services the control and event
+                            // queues, so all of them are handed to vhost.
+                            let mut worker = Worker::new(
+                                queues,
+                                vhost_handle,
+                                interrupts,
+                                interrupt,
+                                acked_features,
+                                kill_evt,
+                                None,
+                            );
+                            let activate_vqs = |handle: &VhostScsiHandle| -> Result<()> {
+                                handle
+                                    .set_endpoint(&wwpn, tpgt)
+                                    .map_err(Error::VhostScsiSetEndpoint)
+                            };
+                            let cleanup_vqs = |handle: &VhostScsiHandle| -> Result<()> {
+                                handle
+                                    .clear_endpoint(&wwpn, tpgt)
+                                    .map_err(Error::VhostScsiClearEndpoint)
+                            };
+                            let result =
+                                worker.run(&queue_evts, &queue_sizes, activate_vqs, cleanup_vqs);
+                            if let Err(e) = result {
+                                error!("vhost-scsi worker thread exited with error: {:?}", e);
+                            }
+                        });
+
+                    if let Err(e) = worker_result {
+                        error!("failed to spawn vhost_scsi worker: {}", e);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_device_sandboxed(&mut self) {
+        // Errors are only logged here. If the owner could not be set, the remaining vhost setup
+        // in activate will fail and stop the worker.
+        if let Some(vhost_handle) = &self.vhost_handle {
+            if let Err(e) = vhost_handle.set_owner() {
+                error!("{}: failed to set owner: {:?}", self.debug_label(), e);
+            }
+        }
+    }
+}
+
+impl Suspendable for Scsi {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_space() {
+        let config = build_config_space(4);
+        let bytes = config.as_slice();
+        assert_eq!(bytes.len(), 36);
+        assert_eq!(&bytes[0..4], &4u32.to_le_bytes());
+        assert_eq!(&bytes[20..24], &SENSE_SIZE.to_le_bytes());
+        assert_eq!(&bytes[24..28], &CDB_SIZE.to_le_bytes());
+        assert_eq!(&bytes[30..32], &MAX_TARGET.to_le_bytes());
+        assert_eq!(&bytes[32..36], &MAX_LUN.to_le_bytes());
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::path::Path;
+use std::thread;
+
+use base::{error, warn, AsRawDescriptor, Event, RawDescriptor};
+use vhost::{Vhost, VhostUser};
+use vm_memory::GuestMemory;
+
+use super::worker::Worker;
+use super::{Error, Result};
+use crate::virtio::{copy_config, Interrupt, Queue, VirtioDevice, TYPE_BLOCK};
+use crate::Suspendable;
+
+const QUEUE_SIZE: u16 = 128;
+
+// Size of the config space up to and including the write zeroes fields.
+const CONFIG_SIZE: u32 = 60;
+// Offset of num_queues in the config space.
+const NUM_QUEUES_OFFSET: usize = 34;
+
+// Feature bits.
+const VIRTIO_BLK_F_SIZE_MAX: u32 = 1;
+const VIRTIO_BLK_F_SEG_MAX: u32 = 2;
+const VIRTIO_BLK_F_GEOMETRY: u32 = 4;
+const VIRTIO_BLK_F_RO: u32 = 5;
+const VIRTIO_BLK_F_BLK_SIZE: u32 = 6;
+const VIRTIO_BLK_F_FLUSH: u32 = 9;
+const VIRTIO_BLK_F_TOPOLOGY: u32 = 10;
+const VIRTIO_BLK_F_MQ: u32 = 12;
+const VIRTIO_BLK_F_DISCARD: u32 = 13;
+const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
+
+// The block and ring features passed through from the backend. The writeback config field is not
+// forwarded to the backend, so VIRTIO_BLK_F_CONFIG_WCE is left out.
+const SUPPORTED_FEATURES: u64 = 1 << VIRTIO_BLK_F_SIZE_MAX
+    | 1 << VIRTIO_BLK_F_SEG_MAX
+    | 1 << VIRTIO_BLK_F_GEOMETRY
+    | 1 << VIRTIO_BLK_F_RO
+    | 1 << VIRTIO_BLK_F_BLK_SIZE
+    | 1 << VIRTIO_BLK_F_FLUSH
+    | 1 << VIRTIO_BLK_F_TOPOLOGY
+    | 1 << VIRTIO_BLK_F_MQ
+    | 1 << VIRTIO_BLK_F_DISCARD
+    | 1 << VIRTIO_BLK_F_WRITE_ZEROES
+    | 1 << virtio_sys::vhost::VIRTIO_F_NOTIFY_ON_EMPTY
+    | 1 << virtio_sys::vhost::VIRTIO_RING_F_INDIRECT_DESC
+    | 1 << virtio_sys::vhost::VIRTIO_RING_F_EVENT_IDX
+    | 1 << virtio_sys::vhost::VIRTIO_F_ANY_LAYOUT;
+
+// Returns the number of request queues the backend serves.
+fn num_queues(features: u64, config: &[u8]) -> usize {
+    if features & 1 << VIRTIO_BLK_F_MQ == 0 {
+        return 1;
+    }
+    let num_queues = u16::from_le_bytes([config[NUM_QUEUES_OFFSET], config[NUM_QUEUES_OFFSET + 1]]);
+    (num_queues as usize).max(1)
+}
+
+/// Virtio block device whose queues are served by a vhost-user backend in another process, such
+/// as SPDK. The config space is read from the backend once, when the device is created.
+pub struct VhostUserBlock {
+    worker_kill_evt: Option<Event>,
+    kill_evt: Option<Event>,
+    vhost_handle: Option<VhostUser>,
+    config: Vec<u8>,
+    queue_sizes: Vec<u16>,
+    interrupts: Option<Vec<Event>>,
+    avail_features: u64,
+    acked_features: u64,
+}
+
+impl VhostUserBlock {
+    /// Create a new virtio block device backed by the vhost-user backend listening on `socket`.
+    pub fn new<P: AsRef<Path>>(
+        base_features: u64,
+        socket: P,
+        mem: &GuestMemory,
+    ) -> Result<VhostUserBlock> {
+        let kill_evt = Event::new().map_err(Error::CreateKillEvent)?;
+        let handle = VhostUser::connect(socket, mem).map_err(Error::VhostUserConnect)?;
+        handle.set_owner().map_err(Error::VhostSetOwner)?;
+
+        let backend_features = handle.get_features().map_err(Error::VhostGetFeatures)?;
+        let avail_features = backend_features & (base_features | SUPPORTED_FEATURES);
+        let config = handle
+            .get_config(0, CONFIG_SIZE)
+            .map_err(Error::VhostUserGetConfig)?;
+
+        let num_vqs = num_queues(avail_features, &config);
+        let mut interrupts = Vec::new();
+        for _ in 0..num_vqs {
+            interrupts.push(Event::new().map_err(Error::VhostIrqCreate)?);
+        }
+
+        Ok(VhostUserBlock {
+            worker_kill_evt: Some(kill_evt.try_clone().map_err(Error::CloneKillEvent)?),
+            kill_evt: Some(kill_evt),
+            vhost_handle: Some(handle),
+            config,
+            queue_sizes: vec![QUEUE_SIZE; num_vqs],
+            interrupts: Some(interrupts),
+            avail_features,
+            acked_features: 0,
+        })
+    }
+}
+
+impl Drop for VhostUserBlock {
+    fn drop(&mut self) {
+        // Only kill the child if it claimed its event.
+        if self.worker_kill_evt.is_none() {
+            if let Some(kill_evt) = &self.kill_evt {
+                // Ignore the result because there is nothing we can do about it.
+                let _ = kill_evt.write(1);
+            }
+        }
+    }
+}
+
+impl VirtioDevice for VhostUserBlock {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
+
+        if let Some(handle) = &self.vhost_handle {
+            keep_rds.push(handle.as_raw_descriptor());
+            // The guest memory is sent to the backend when the device is activated.
+            let (_, shms) = handle.mem().shared_regions();
+            for shm in shms {
+                keep_rds.push(shm.as_raw_descriptor());
+            }
+        }
+
+        if let Some(interrupt) = &self.interrupts {
+            for vhost_int in interrupt.iter() {
+                keep_rds.push(vhost_int.as_raw_descriptor());
+            }
+        }
+
+        if let Some(worker_kill_evt) = &self.worker_kill_evt {
+            keep_rds.push(worker_kill_evt.as_raw_descriptor());
+        }
+
+        keep_rds
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_BLOCK
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        copy_config(data, 0, &self.config, offset);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // The only writable field is writeback, which is not offered.
+        warn!(
+            "vhost-user-blk: guest tried to write {} bytes of config at {}",
+            data.len(),
+            offset
+        );
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!(
+                "vhost-user-blk: virtio-blk got unknown feature ack: {:x}",
+                v
+            );
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn activate(
+        &mut self,
+        _: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        queue_evts: Vec<Event>,
+    ) {
+        let num_vqs = self.queue_sizes.len();
+        if queues.len() != num_vqs || queue_evts.len() != num_vqs {
+            error!(
+                "vhost-user-blk: expected {} queues, got {}",
+                num_vqs,
+                queues.len()
+            );
+            return;
+        }
+
+        if let Some(vhost_handle) = self.vhost_handle.take() {
+            if let Some(interrupts) = self.interrupts.take() {
+                if let Some(kill_evt) = self.worker_kill_evt.take() {
+                    let acked_features = self.acked_features;
+                    let queue_sizes = self.queue_sizes.clone();
+                    let worker_result = thread::Builder::new()
+                        .name("vhost_user_blk".to_string())
+                        .spawn(move || {
+                            let mut worker = Worker::new(
+                                queues,
+                                vhost_handle,
+                                interrupts,
+                                interrupt,
+                                acked_features,
+                                kill_evt,
+                                None,
+                            );
+                            let activate_vqs = |handle: &VhostUser| -> Result<()> {
+                                for index in 0..num_vqs {
+                                    handle
+                                        .set_vring_enable(index, true)
+                                        .map_err(Error::VhostUserSetVringEnable)?;
+                                }
+                                Ok(())
+                            };
+                            let cleanup_vqs = |handle: &VhostUser| -> Result<()> {
+                                for index in 0..num_vqs {
+                                    handle
+                                        .set_vring_enable(index, false)
+                                        .map_err(Error::VhostUserSetVringEnable)?;
+                                }
+                                Ok(())
+                            };
+                            let result =
+                                worker.run(&queue_evts, &queue_sizes, activate_vqs, cleanup_vqs);
+                            if let Err(e) = result {
+                                error!("vhost-user-blk worker thread exited with error: {:?}", e);
+                            }
+                        });
+
+                    if let Err(e) = worker_result {
+                        error!("failed to spawn vhost_user_blk worker: {}", e);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Suspendable for VhostUserBlock {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_from_config() {
+        let mut config = vec![0u8; CONFIG_SIZE as usize];
+        config[NUM_QUEUES_OFFSET..NUM_QUEUES_OFFSET + 2].copy_from_slice(&4u16.to_le_bytes());
+        assert_eq!(num_queues(1 << VIRTIO_BLK_F_MQ, &config), 4);
+        assert_eq!(num_queues(0, &config), 1);
+        config[NUM_QUEUES_OFFSET] = 0;
+        assert_eq!(num_queues(1 << VIRTIO_BLK_F_MQ, &config), 1);
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use base::{error, warn, AsRawDescriptor, Event, RawDescriptor};
+use data_model::{DataInit, Le16};
+use net_util::MacAddress;
+use vhost::{Vhost, VhostUser};
+use virtio_sys::virtio_net;
+use vm_memory::GuestMemory;
+
+use super::worker::Worker;
+use super::{Error, Result};
+use crate::virtio::{copy_config, Interrupt, Queue, VirtioDevice, TYPE_NET};
+use crate::Suspendable;
+
+const QUEUE_SIZE: u16 = 256;
+// One receive and one transmit queue. The backend has no control queue to serve.
+const NUM_QUEUES: usize = 2;
+
+// The net and ring features passed through from the backend. VIRTIO_NET_F_MAC is offered by the
+// frontend itself, as the address comes from the command line rather than the backend.
+const SUPPORTED_FEATURES: u64 = 1 << virtio_net::VIRTIO_NET_F_CSUM
+    | 1 << virtio_net::VIRTIO_NET_F_GUEST_CSUM
+    | 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO4
+    | 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO6
+    | 1 << virtio_net::VIRTIO_NET_F_GUEST_ECN
+    | 1 << virtio_net::VIRTIO_NET_F_GUEST_UFO
+    | 1 << virtio_net::VIRTIO_NET_F_HOST_TSO4
+    | 1 << virtio_net::VIRTIO_NET_F_HOST_TSO6
+    | 1 << virtio_net::VIRTIO_NET_F_HOST_ECN
+    | 1 << virtio_net::VIRTIO_NET_F_HOST_UFO
+    | 1 << virtio_net::VIRTIO_NET_F_MRG_RXBUF
+    | 1 << virtio_sys::vhost::VIRTIO_F_NOTIFY_ON_EMPTY
+    | 1 << virtio_sys::vhost::VIRTIO_RING_F_INDIRECT_DESC
+    | 1 << virtio_sys::vhost::VIRTIO_RING_F_EVENT_IDX
+    | 1 << virtio_sys::vhost::VIRTIO_F_ANY_LAYOUT;
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct VirtioNetConfig {
+    mac: [u8; 6],
+    status: Le16,
+    max_vq_pairs: Le16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for VirtioNetConfig {}
+
+fn build_config(mac: Option<MacAddress>) -> VirtioNetConfig {
+    VirtioNetConfig {
+        mac: mac.map(|mac| mac.octets()).unwrap_or_default(),
+        max_vq_pairs: Le16::from(1),
+        ..Default::default()
+    }
+}
+
+/// Virtio network device whose datapath is handled by a vhost-user backend in another process,
+/// such as a DPDK or Open vSwitch switch. If the backend goes away, the device waits for it to
+/// come back on the same socket and picks up where it left off.
+pub struct VhostUserNet {
+    worker_kill_evt: Option<Event>,
+    kill_evt: Option<Event>,
+    vhost_handle: Option<VhostUser>,
+    socket: PathBuf,
+    config: VirtioNetConfig,
+    queue_sizes: Vec<u16>,
+    interrupts: Option<Vec<Event>>,
+    avail_features: u64,
+    acked_features: u64,
+}
+
+impl VhostUserNet {
+    /// Create a new virtio network device backed by the vhost-user backend listening on `socket`.
+    /// If `mac` is set, it is offered to the guest as the address of the device.
+    pub fn new<P: AsRef<Path>>(
+        base_features: u64,
+        socket: P,
+        mac: Option<MacAddress>,
+        mem: &GuestMemory,
+    ) -> Result<VhostUserNet> {
+        let kill_evt = Event::new().map_err(Error::CreateKillEvent)?;
+        let handle = VhostUser::connect(&socket, mem).map_err(Error::VhostUserConnect)?;
+        handle.set_owner().map_err(Error::VhostSetOwner)?;
+
+        let backend_features = handle.get_features().map_err(Error::VhostGetFeatures)?;
+        let mut avail_features = backend_features & (base_features | SUPPORTED_FEATURES);
+        if mac.is_some() {
+            avail_features |= 1 << virtio_net::VIRTIO_NET_F_MAC;
+        }
+
+        let mut interrupts = Vec::new();
+        for _ in 0..NUM_QUEUES {
+            interrupts.push(Event::new().map_err(Error::VhostIrqCreate)?);
+        }
+
+        Ok(VhostUserNet {
+            worker_kill_evt: Some(kill_evt.try_clone().map_err(Error::CloneKillEvent)?),
+            kill_evt: Some(kill_evt),
+            vhost_handle: Some(handle),
+            socket: socket.as_ref().to_path_buf(),
+            config: build_config(mac),
+            queue_sizes: vec![QUEUE_SIZE; NUM_QUEUES],
+            interrupts: Some(interrupts),
+            avail_features,
+            acked_features: 0,
+        })
+    }
+}
+
+impl Drop for VhostUserNet {
+    fn drop(&mut self) {
+        // Only kill the child if it claimed its event.
+        if self.worker_kill_evt.is_none() {
+            if let Some(kill_evt) = &self.kill_evt {
+                // Ignore the result because there is nothing we can do about it.
+                let _ = kill_evt.write(1);
+            }
+        }
+    }
+}
+
+impl VirtioDevice for VhostUserNet {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
+
+        if let Some(handle) = &self.vhost_handle {
+            keep_rds.push(handle.as_raw_descriptor());
+            // The guest memory is sent to the backend when the device is activated, and again
+            // whenever the backend reconnects.
+            let (_, shms) = handle.mem().shared_regions();
+            for shm in shms {
+                keep_rds.push(shm.as_raw_descriptor());
+            }
+        }
+
+        if let Some(interrupt) = &self.interrupts {
+            for vhost_int in interrupt.iter() {
+                keep_rds.push(vhost_int.as_raw_descriptor());
+            }
+        }
+
+        if let Some(worker_kill_evt) = &self.worker_kill_evt {
+            keep_rds.push(worker_kill_evt.as_raw_descriptor());
+        }
+
+        keep_rds
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_NET
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        copy_config(data, 0, self.config.as_slice(), offset);
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!(
+                "vhost-user-net: virtio-net got unknown feature ack: {:x}",
+                v
+            );
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        queues: Vec<Queue>,
+        queue_evts: Vec<Event>,
+    ) {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!(
+                "vhost-user-net: expected {} queues, got {}",
+                NUM_QUEUES,
+                queues.len()
+            );
+            return;
+        }
+
+        if let Some(vhost_handle) = self.vhost_handle.take() {
+            if let Some(interrupts) = self.interrupts.take() {
+                if let Some(kill_evt) = self.worker_kill_evt.take() {
+                    let acked_features = self.acked_features;
+                    let queue_sizes = self.queue_sizes.clone();
+                    let socket = self.socket.clone();
+                    let worker_result = thread::Builder::new()
+                        .name("vhost_user_net".to_string())
+                        .spawn(move || {
+                            let mut worker = Worker::new(
+                                queues,
+                                vhost_handle,
+                                interrupts,
+                                interrupt,
+                                acked_features,
+                                kill_evt,
+                                None,
+                            );
+                            worker.set_reconnect(move || {
+                                let handle = VhostUser::connect(&socket, &mem)
+                                    .map_err(Error::VhostUserConnect)?;
+                                handle.set_owner().map_err(Error::VhostSetOwner)?;
+                                Ok(handle)
+                            });
+                            let activate_vqs = |handle: &VhostUser| -> Result<()> {
+                                for index in 0..NUM_QUEUES {
+                                    handle
+                                        .set_vring_enable(index, true)
+                                        .map_err(Error::VhostUserSetVringEnable)?;
+                                }
+                                Ok(())
+                            };
+                            let cleanup_vqs = |handle: &VhostUser| -> Result<()> {
+                                for index in 0..NUM_QUEUES {
+                                    handle
+                                        .set_vring_enable(index, false)
+                                        .map_err(Error::VhostUserSetVringEnable)?;
+                                }
+                                Ok(())
+                            };
+                            let result =
+                                worker.run(&queue_evts, &queue_sizes, activate_vqs, cleanup_vqs);
+                            if let Err(e) = result {
+                                error!("vhost-user-net worker thread exited with error: {:?}", e);
+                            }
+                        });
+
+                    if let Err(e) = worker_result {
+                        error!("failed to spawn vhost_user_net worker: {}", e);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Suspendable for VhostUserNet {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_mac() {
+        let mac: MacAddress = "52:54:00:12:34:56".parse().unwrap();
+        let config = build_config(Some(mac));
+        assert_eq!(&config.as_slice()[..6], &mac.octets());
+        assert_eq!(config.max_vq_pairs.to_native(), 1);
+        assert_eq!(build_config(None).mac, [0u8; 6]);
+    }
+}
@@ -14,6 +14,7 @@ use vm_memory::GuestMemory;
 use super::worker::Worker;
 use super::{Error, Result};
 use crate::virtio::{copy_config, Interrupt, Queue, VirtioDevice, TYPE_VSOCK};
+use crate::Suspendable;
 
 const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 3;
@@ -178,7 +179,7 @@ impl VirtioDevice for Vsock {
                             };
                             let cleanup_vqs = |_handle: &VhostVsockHandle| -> Result<()> { Ok(()) };
                             let result =
-                                worker.run(queue_evts, QUEUE_SIZES, activate_vqs, cleanup_vqs);
+                                worker.run(&queue_evts, QUEUE_SIZES, activate_vqs, cleanup_vqs);
                             if let Err(e) = result {
                                 error!("vsock worker thread exited with error: {:?}", e);
                             }
@@ -206,6 +207,8 @@ impl VirtioDevice for Vsock {
     }
 }
 
+impl Suspendable for Vsock {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -3,8 +3,9 @@
 // found in the LICENSE file.
 
 use std::os::raw::c_ulonglong;
+use std::time::Duration;
 
-use base::{error, Error as SysError, Event, PollToken, WaitContext};
+use base::{error, info, Error as SysError, Event, PollToken, WaitContext};
 use vhost::Vhost;
 
 use super::control_socket::{VhostDevRequest, VhostDevResponse, VhostDevResponseSocket};
@@ -22,8 +23,14 @@ pub struct Worker<T: Vhost> {
     acked_features: u64,
     pub kill_evt: Event,
     pub response_socket: Option<VhostDevResponseSocket>,
+    reconnect: Option<Box<dyn FnMut() -> Result<T> + Send>>,
+    // Whether the worker ran before, leaving buffers the backend used in the rings.
+    started: bool,
 }
 
+// How long to wait between attempts to reconnect to a backend that hung up.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
 impl<T: Vhost> Worker<T> {
     pub fn new(
         queues: Vec<Queue>,
@@ -42,60 +49,56 @@ impl<T: Vhost> Worker<T> {
             acked_features,
             kill_evt,
             response_socket,
+            reconnect: None,
+            started: false,
         }
     }
 
+    /// Watch the backend for hangups, and when it hangs up, call `reconnect` until it returns a
+    /// handle to the restarted backend. The queues are then set up again on the new handle, from
+    /// the last buffers the old backend returned to the guest. Only for backends whose descriptor
+    /// can be polled, such as a vhost-user socket.
+    pub fn set_reconnect<F>(&mut self, reconnect: F)
+    where
+        F: FnMut() -> Result<T> + Send + 'static,
+    {
+        self.reconnect = Some(Box::new(reconnect));
+    }
+
+    /// Sets up the queues on the backend and services them until the kill event fires. A worker
+    /// that is run again resumes from the last buffers the backend used in each queue. If it
+    /// fails, the driver is asked to reset the device.
     pub fn run<F1, F2>(
         &mut self,
-        queue_evts: Vec<Event>,
+        queue_evts: &[Event],
         queue_sizes: &[u16],
         activate_vqs: F1,
         cleanup_vqs: F2,
     ) -> Result<()>
     where
-        F1: FnOnce(&T) -> Result<()>,
+        F1: Fn(&T) -> Result<()>,
         F2: FnOnce(&T) -> Result<()>,
     {
-        let avail_features = self
-            .vhost_handle
-            .get_features()
-            .map_err(Error::VhostGetFeatures)?;
-
-        let features: c_ulonglong = self.acked_features & avail_features;
-        self.vhost_handle
-            .set_features(features)
-            .map_err(Error::VhostSetFeatures)?;
-
-        self.vhost_handle
-            .set_mem_table()
-            .map_err(Error::VhostSetMemTable)?;
-
-        for (queue_index, queue) in self.queues.iter().enumerate() {
-            self.vhost_handle
-                .set_vring_num(queue_index, queue.max_size)
-                .map_err(Error::VhostSetVringNum)?;
-
-            self.vhost_handle
-                .set_vring_addr(
-                    queue_sizes[queue_index],
-                    queue.actual_size(),
-                    queue_index,
-                    0,
-                    queue.desc_table,
-                    queue.used_ring,
-                    queue.avail_ring,
-                    None,
-                )
-                .map_err(Error::VhostSetVringAddr)?;
-            self.vhost_handle
-                .set_vring_base(queue_index, 0)
-                .map_err(Error::VhostSetVringBase)?;
-            self.set_vring_call_for_entry(queue_index, queue.vector as usize)?;
-            self.vhost_handle
-                .set_vring_kick(queue_index, &queue_evts[queue_index])
-                .map_err(Error::VhostSetVringKick)?;
+        let result = self.run_until_killed(queue_evts, queue_sizes, activate_vqs, cleanup_vqs);
+        if result.is_err() {
+            self.interrupt.signal_needs_reset();
         }
+        result
+    }
 
+    fn run_until_killed<F1, F2>(
+        &mut self,
+        queue_evts: &[Event],
+        queue_sizes: &[u16],
+        activate_vqs: F1,
+        cleanup_vqs: F2,
+    ) -> Result<()>
+    where
+        F1: Fn(&T) -> Result<()>,
+        F2: FnOnce(&T) -> Result<()>,
+    {
+        self.set_up_vrings(queue_evts, queue_sizes, self.started)?;
+        self.started = true;
         activate_vqs(&self.vhost_handle)?;
 
         #[derive(PollToken)]
@@ -104,6 +107,7 @@ impl<T: Vhost> Worker<T> {
             InterruptResample,
             Kill,
             ControlNotify,
+            BackendHangup,
         }
 
         let wait_ctx: WaitContext<Token> = WaitContext::build_with(&[
@@ -122,12 +126,32 @@ impl<T: Vhost> Worker<T> {
                 .add(socket, Token::ControlNotify)
                 .map_err(Error::CreateWaitContext)?;
         }
+        if self.reconnect.is_some() {
+            // The backend only sends on its socket in reply to a request, so it becomes readable
+            // when the backend hangs up.
+            wait_ctx
+                .add(&self.vhost_handle, Token::BackendHangup)
+                .map_err(Error::CreateWaitContext)?;
+        }
 
         'wait: loop {
             let events = wait_ctx.wait().map_err(Error::WaitError)?;
 
             for event in events.iter().filter(|e| e.is_readable) {
                 match event.token {
+                    Token::BackendHangup => {
+                        wait_ctx
+                            .delete(&self.vhost_handle)
+                            .map_err(Error::CreateWaitContext)?;
+                        if !self.reconnect_backend(queue_evts, queue_sizes)? {
+                            // Killed while waiting for the backend.
+                            return Ok(());
+                        }
+                        activate_vqs(&self.vhost_handle)?;
+                        wait_ctx
+                            .add(&self.vhost_handle, Token::BackendHangup)
+                            .map_err(Error::CreateWaitContext)?;
+                    }
                     Token::VhostIrqi { index } => {
                         self.vhost_interrupt[index]
                             .read()
@@ -195,6 +219,102 @@ impl<T: Vhost> Worker<T> {
         Ok(())
     }
 
+    // Negotiates the features with the backend, sends it the memory table, and sets up every
+    // queue. If `resume` is set, the backend starts from the last buffers used in each queue
+    // instead of the start of the rings.
+    fn set_up_vrings(&self, queue_evts: &[Event], queue_sizes: &[u16], resume: bool) -> Result<()> {
+        let avail_features = self
+            .vhost_handle
+            .get_features()
+            .map_err(Error::VhostGetFeatures)?;
+
+        let features: c_ulonglong = self.acked_features & avail_features;
+        self.vhost_handle
+            .set_features(features)
+            .map_err(Error::VhostSetFeatures)?;
+
+        self.vhost_handle
+            .set_mem_table()
+            .map_err(Error::VhostSetMemTable)?;
+
+        for (queue_index, queue) in self.queues.iter().enumerate() {
+            self.vhost_handle
+                .set_vring_num(queue_index, queue.max_size)
+                .map_err(Error::VhostSetVringNum)?;
+
+            self.vhost_handle
+                .set_vring_addr(
+                    queue_sizes[queue_index],
+                    queue.actual_size(),
+                    queue_index,
+                    0,
+                    queue.desc_table,
+                    queue.used_ring,
+                    queue.avail_ring,
+                    None,
+                )
+                .map_err(Error::VhostSetVringAddr)?;
+            let base = if resume {
+                // The index of the used ring follows its flags.
+                self.vhost_handle
+                    .mem()
+                    .read_obj_from_addr::<u16>(queue.used_ring.unchecked_add(2))
+                    .map_err(Error::ReadUsedIndex)?
+            } else {
+                0
+            };
+            self.vhost_handle
+                .set_vring_base(queue_index, base)
+                .map_err(Error::VhostSetVringBase)?;
+            self.set_vring_call_for_entry(queue_index, queue.vector as usize)?;
+            self.vhost_handle
+                .set_vring_kick(queue_index, &queue_evts[queue_index])
+                .map_err(Error::VhostSetVringKick)?;
+        }
+        Ok(())
+    }
+
+    // Replaces the handle of a backend that hung up with one from the reconnect callback, retrying
+    // until it succeeds. Returns false if the kill event fired first.
+    fn reconnect_backend(&mut self, queue_evts: &[Event], queue_sizes: &[u16]) -> Result<bool> {
+        error!("vhost backend hung up, reconnecting");
+        #[derive(PollToken)]
+        enum Token {
+            Kill,
+        }
+
+        let wait_ctx: WaitContext<Token> =
+            WaitContext::build_with(&[(&self.kill_evt, Token::Kill)])
+                .map_err(Error::CreateWaitContext)?;
+        loop {
+            let events = wait_ctx
+                .wait_timeout(RECONNECT_INTERVAL)
+                .map_err(Error::WaitError)?;
+            if !events.is_empty() {
+                let _ = self.kill_evt.read();
+                return Ok(false);
+            }
+            let reconnect = match self.reconnect.as_mut() {
+                Some(reconnect) => reconnect,
+                None => return Ok(false),
+            };
+            match reconnect() {
+                Ok(handle) => self.vhost_handle = handle,
+                Err(e) => {
+                    error!("failed to reconnect to vhost backend: {}", e);
+                    continue;
+                }
+            }
+            match self.set_up_vrings(queue_evts, queue_sizes, true) {
+                Ok(()) => {
+                    info!("reconnected to vhost backend");
+                    return Ok(true);
+                }
+                Err(e) => error!("failed to set up reconnected vhost backend: {}", e),
+            }
+        }
+    }
+
     fn set_vring_call_for_entry(&self, queue_index: usize, vector: usize) -> Result<()> {
         // No response_socket means it doesn't have any control related
         // with the msix. Due to this, cannot use the direct irq fd but
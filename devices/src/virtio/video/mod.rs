@@ -17,6 +17,7 @@ use vm_memory::GuestMemory;
 use crate::virtio::resource_bridge::ResourceRequestSocket;
 use crate::virtio::virtio_device::VirtioDevice;
 use crate::virtio::{self, copy_config, DescriptorError, Interrupt};
+use crate::Suspendable;
 
 #[macro_use]
 mod macros;
@@ -222,12 +223,14 @@ impl VirtioDevice for VideoDevice {
                         Ok(vda) => vda,
                         Err(e) => {
                             error!("Failed to initialize vda: {}", e);
+                            worker.interrupt.signal_needs_reset();
                             return;
                         }
                     };
                     let device = decoder::Decoder::new(&vda);
                     if let Err(e) = worker.run(cmd_queue, event_queue, device) {
                         error!("Failed to start decoder worker: {}", e);
+                        worker.interrupt.signal_needs_reset();
                     };
                     // Don't return any information since the return value is never checked.
                 }),
@@ -238,6 +241,7 @@ impl VirtioDevice for VideoDevice {
                         Ok(vea) => vea,
                         Err(e) => {
                             error!("Failed to initialize vea: {}", e);
+                            worker.interrupt.signal_needs_reset();
                             return;
                         }
                     };
@@ -245,11 +249,13 @@ impl VirtioDevice for VideoDevice {
                         Ok(d) => d,
                         Err(e) => {
                             error!("Failed to create encoder device: {}", e);
+                            worker.interrupt.signal_needs_reset();
                             return;
                         }
                     };
                     if let Err(e) = worker.run(cmd_queue, event_queue, device) {
                         error!("Failed to start encoder worker: {}", e);
+                        worker.interrupt.signal_needs_reset();
                     }
                 }),
         };
@@ -262,3 +268,5 @@ impl VirtioDevice for VideoDevice {
         }
     }
 }
+
+impl Suspendable for VideoDevice {}
@@ -55,7 +55,7 @@ impl Worker {
             }
             cmd_queue.add_used(&self.mem, desc_index, writer.bytes_written() as u32);
         }
-        self.interrupt.signal_used_queue(cmd_queue.vector);
+        cmd_queue.trigger_interrupt(&self.mem, &self.interrupt);
         Ok(())
     }
 
@@ -73,7 +73,7 @@ impl Worker {
             .write(&mut writer)
             .map_err(|error| Error::WriteEventFailure { event, error })?;
         event_queue.add_used(&self.mem, desc_index, writer.bytes_written() as u32);
-        self.interrupt.signal_used_queue(event_queue.vector);
+        event_queue.trigger_interrupt(&self.mem, &self.interrupt);
         Ok(())
     }
 
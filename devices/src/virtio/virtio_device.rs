@@ -7,6 +7,7 @@ use vm_memory::GuestMemory;
 
 use super::*;
 use crate::pci::{MsixStatus, PciAddress, PciBarConfiguration, PciCapability};
+use crate::Suspendable;
 
 /// Trait for virtio devices to be driven by a virtio transport.
 ///
@@ -15,7 +16,10 @@ use crate::pci::{MsixStatus, PciAddress, PciBarConfiguration, PciCapability};
 /// and all the events, memory, and queues for device operation will be moved into the device.
 /// Optionally, a virtio device can implement device reset in which it returns said resources and
 /// resets its internal.
-pub trait VirtioDevice: Send {
+///
+/// The transport puts the device to sleep while the VM is paused and saves or restores its state
+/// through `Suspendable`.
+pub trait VirtioDevice: Send + Suspendable {
     /// Returns a label suitable for debug output.
     fn debug_label(&self) -> String {
         match type_to_str(self.device_type()) {
@@ -69,6 +73,10 @@ pub trait VirtioDevice: Send {
     /// not able to reset the virtio device, or the virtio device model doesn't
     /// implement the reset method, a false value is returned to indicate
     /// the reset is not successful. Otherwise a true value should be returned.
+    ///
+    /// A device that was reset must stop its workers, take back the resources `activate` handed
+    /// them, and forget the features the driver acked, so that the driver can negotiate features
+    /// and activate the device again.
     fn reset(&mut self) -> bool {
         false
     }
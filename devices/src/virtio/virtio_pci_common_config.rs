@@ -38,6 +38,8 @@ pub struct VirtioPciCommonConfig {
     pub driver_feature_select: u32,
     pub queue_select: u16,
     pub msix_config: u16,
+    // The features the driver acked, including any the device doesn't offer.
+    pub driver_features: u64,
 }
 
 impl VirtioPciCommonConfig {
@@ -186,8 +188,14 @@ impl VirtioPciCommonConfig {
             0x08 => self.driver_feature_select = value,
             0x0c => {
                 if self.driver_feature_select < 2 {
-                    let features: u64 = (value as u64) << (self.driver_feature_select * 32);
+                    let shift = self.driver_feature_select * 32;
+                    let features: u64 = (value as u64) << shift;
+                    self.driver_features =
+                        (self.driver_features & !(0xffff_ffff << shift)) | features;
                     device.ack_features(features);
+                    // Queues only take the features the device offered, so that a driver can't
+                    // set up packed rings on a device that didn't offer them, say.
+                    let features = features & device.features();
                     for queue in queues.iter_mut() {
                         queue.ack_features(features);
                     }
@@ -246,6 +254,8 @@ mod tests {
     use base::{Event, RawDescriptor};
     use vm_memory::GuestMemory;
 
+    use crate::Suspendable;
+
     struct DummyDevice(u32);
     const QUEUE_SIZE: u16 = 256;
     const QUEUE_SIZES: &'static [u16] = &[QUEUE_SIZE];
@@ -273,6 +283,8 @@ mod tests {
         }
     }
 
+    impl Suspendable for DummyDevice {}
+
     #[test]
     fn write_base_regs() {
         let mut regs = VirtioPciCommonConfig {
@@ -282,6 +294,7 @@ mod tests {
             driver_feature_select: 0x0,
             queue_select: 0xff,
             msix_config: 0x00,
+            driver_features: 0,
         };
 
         let dev = &mut DummyDevice(0) as &mut dyn VirtioDevice;
@@ -315,6 +328,13 @@ mod tests {
         regs.read(0x08, &mut read_back, &mut queues, dev);
         assert_eq!(u32::from_le_bytes(read_back), 0x0403_0201);
 
+        // Each page of acked features is recorded, even bits the device doesn't offer.
+        regs.write(0x08, &[0, 0, 0, 0], &mut queues, dev);
+        regs.write(0x0c, &[0xff, 0, 0, 0], &mut queues, dev);
+        regs.write(0x08, &[1, 0, 0, 0], &mut queues, dev);
+        regs.write(0x0c, &[1, 0, 0, 0], &mut queues, dev);
+        assert_eq!(regs.driver_features, 0x1_0000_00ff);
+
         // 'queue_select' can be read and written.
         regs.write(0x16, &[0xaa, 0x55], &mut queues, dev);
         let mut read_back = vec![0x00, 0x00];
@@ -2,16 +2,17 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use sync::Mutex;
 
-use base::{warn, AsRawDescriptor, Event, RawDescriptor, Result};
-use data_model::{DataInit, Le32};
+use base::{error, warn, AsRawDescriptor, Event, RawDescriptor, Result};
+use data_model::{DataInit, Le16, Le32, Le64};
 use hypervisor::Datamatch;
 use libc::ERANGE;
+use msg_socket::MsgSender;
 use resources::{Alloc, MmioType, SystemAllocator};
-use vm_memory::GuestMemory;
+use vm_memory::{GuestAddress, GuestMemory};
 
 use super::*;
 use crate::pci::{
@@ -19,7 +20,9 @@ use crate::pci::{
     PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciDisplaySubclass, PciHeaderType,
     PciInterruptPin, PciSubclass,
 };
-use vm_control::VmIrqRequestSocket;
+use crate::suspendable;
+use crate::{Suspendable, SuspendableError};
+use vm_control::{VirtioDeviceId, VirtioEvent, VirtioEventSendSocket, VmIrqRequestSocket};
 
 use self::virtio_pci_common_config::VirtioPciCommonConfig;
 
@@ -194,6 +197,41 @@ const NOTIFY_OFF_MULTIPLIER: u32 = 4; // A dword per notification address.
 const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
 const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040; // Add to device type to get device ID.
 
+// Transport state saved by `snapshot`, followed by one `VirtioPciQueueSnapshot` per queue and
+// then the state of the device.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct VirtioPciSnapshot {
+    device_feature_select: Le32,
+    driver_feature_select: Le32,
+    queue_select: Le16,
+    msix_config: Le16,
+    num_queues: Le16,
+    driver_status: u8,
+    config_generation: u8,
+    driver_features: Le64,
+}
+
+// It is safe to implement DataInit; all members are simple numbers and any value is valid.
+unsafe impl DataInit for VirtioPciSnapshot {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct VirtioPciQueueSnapshot {
+    desc_table: Le64,
+    avail_ring: Le64,
+    used_ring: Le64,
+    size: Le16,
+    vector: Le16,
+    next_avail: Le16,
+    next_used: Le16,
+    ready: u8,
+    padding: [u8; 7],
+}
+
+// It is safe to implement DataInit; all members are simple numbers and any value is valid.
+unsafe impl DataInit for VirtioPciQueueSnapshot {}
+
 /// Implements the
 /// [PCI](http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-650001)
 /// transport for virtio devices.
@@ -203,6 +241,8 @@ pub struct VirtioPciDevice {
 
     device: Box<dyn VirtioDevice>,
     device_activated: bool,
+    // Set by the device's workers when they stop, through the interrupt given on activation.
+    needs_reset: Arc<AtomicBool>,
 
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: Option<Event>,
@@ -214,14 +254,18 @@ pub struct VirtioPciDevice {
     msix_config: Arc<Mutex<MsixConfig>>,
     msix_cap_reg_idx: Option<usize>,
     common_config: VirtioPciCommonConfig,
+    dma_map: Option<DmaMap>,
+    event_socket: VirtioEventSendSocket,
 }
 
 impl VirtioPciDevice {
-    /// Constructs a new PCI transport for the given virtio device.
+    /// Constructs a new PCI transport for the given virtio device. Drivers that fail to set up the
+    /// device are reported on `event_socket`.
     pub fn new(
         mem: GuestMemory,
         device: Box<dyn VirtioDevice>,
         msi_device_socket: VmIrqRequestSocket,
+        event_socket: VirtioEventSendSocket,
     ) -> Result<Self> {
         let mut queue_evts = Vec::new();
         for _ in device.queue_max_sizes() {
@@ -268,6 +312,7 @@ impl VirtioPciDevice {
             pci_address: None,
             device,
             device_activated: false,
+            needs_reset: Arc::new(AtomicBool::new(false)),
             interrupt_status: Arc::new(AtomicUsize::new(0)),
             interrupt_evt: None,
             interrupt_resample_evt: None,
@@ -284,10 +329,20 @@ impl VirtioPciDevice {
                 driver_feature_select: 0,
                 queue_select: 0,
                 msix_config: VIRTIO_MSI_NO_VECTOR,
+                driver_features: 0,
             },
+            dma_map: None,
+            event_socket,
         })
     }
 
+    /// Puts the device behind an IOMMU, which maps the I/O virtual addresses the driver gives the
+    /// device to guest memory with `dma_map`. Only drivers that ack VIRTIO_F_ACCESS_PLATFORM give
+    /// I/O virtual addresses.
+    pub fn set_dma_map(&mut self, dma_map: DmaMap) {
+        self.dma_map = Some(dma_map);
+    }
+
     fn is_driver_ready(&self) -> bool {
         let ready_bits =
             (DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK | DEVICE_FEATURES_OK) as u8;
@@ -295,11 +350,96 @@ impl VirtioPciDevice {
             && self.common_config.driver_status & DEVICE_FAILED as u8 == 0
     }
 
+    fn virtio_device_id(&self) -> VirtioDeviceId {
+        let (bus, dev, func) = match self.pci_address {
+            Some(address) => (address.bus, address.dev, address.func),
+            None => (0, 0, 0),
+        };
+        VirtioDeviceId {
+            device_type: self.device.device_type(),
+            bus,
+            dev,
+            func,
+        }
+    }
+
+    // Reports a driver that acked features the device doesn't offer, or that gave up on the
+    // device, rather than leaving the device silently unusable.
+    fn check_driver_status(&mut self, prev_driver_status: u8) {
+        let newly_set = self.common_config.driver_status & !prev_driver_status;
+        let offered = self.device.features();
+        let acked = self.common_config.driver_features;
+        let id = self.virtio_device_id();
+        let event = if newly_set & DEVICE_FEATURES_OK as u8 != 0 && acked & !offered != 0 {
+            // The driver reads the status back, and gives up on the device once it sees that
+            // FEATURES_OK didn't stick.
+            self.common_config.driver_status &= !(DEVICE_FEATURES_OK as u8);
+            warn!(
+                "{} rejected unsupported features {:#x} (offered {:#x}, acked {:#x})",
+                self.debug_label(),
+                acked & !offered,
+                offered,
+                acked
+            );
+            VirtioEvent::FeaturesRejected { id, offered, acked }
+        } else if newly_set & DEVICE_FAILED as u8 != 0 {
+            error!(
+                "{} driver failed (offered {:#x}, acked {:#x})",
+                self.debug_label(),
+                offered,
+                acked
+            );
+            VirtioEvent::DriverFailed { id, offered, acked }
+        } else {
+            return;
+        };
+        if let Err(e) = self.event_socket.send(&event) {
+            error!("{} failed to send virtio event: {}", self.debug_label(), e);
+        }
+    }
+
+    // Shows the driver that the device's workers stopped, so that it resets the device and gets
+    // new ones, and reports the device to the main process.
+    fn check_needs_reset(&mut self) {
+        if !self.needs_reset.load(Ordering::SeqCst)
+            || self.common_config.driver_status & DEVICE_NEEDS_RESET as u8 != 0
+        {
+            return;
+        }
+        self.common_config.driver_status |= DEVICE_NEEDS_RESET as u8;
+        error!("{} stopped working and needs a reset", self.debug_label());
+        let event = VirtioEvent::NeedsReset {
+            id: self.virtio_device_id(),
+        };
+        if let Err(e) = self.event_socket.send(&event) {
+            error!("{} failed to send virtio event: {}", self.debug_label(), e);
+        }
+    }
+
     /// Determines if the driver has requested the device reset itself
     fn is_reset_requested(&self) -> bool {
         self.common_config.driver_status == DEVICE_RESET as u8
     }
 
+    // Translates the ring addresses of the ready queues, if they are I/O virtual addresses.
+    fn translate_queues(&mut self) -> bool {
+        let dma_map = match &self.dma_map {
+            Some(dma_map) => dma_map,
+            None => return true,
+        };
+        let debug_label = self.debug_label();
+        for (index, queue) in self.queues.iter_mut().enumerate().filter(|(_, q)| q.ready) {
+            if let Err(e) = queue.set_dma_map(dma_map) {
+                warn!(
+                    "{} queue {} rings are not mapped: {}",
+                    debug_label, index, e
+                );
+                return false;
+            }
+        }
+        true
+    }
+
     fn are_queues_valid(&self) -> bool {
         if let Some(mem) = self.mem.as_ref() {
             // All queues marked as ready must be valid.
@@ -312,6 +452,90 @@ impl VirtioPciDevice {
         }
     }
 
+    // Hands the queues to the device, which starts its workers. Leaves `device_activated` unset if
+    // the interrupt or queue events can't be cloned.
+    fn activate(&mut self) {
+        if let Some(interrupt_evt) = self.interrupt_evt.take() {
+            self.interrupt_evt = match interrupt_evt.try_clone() {
+                Ok(evt) => Some(evt),
+                Err(e) => {
+                    warn!(
+                        "{} failed to clone interrupt_evt: {}",
+                        self.debug_label(),
+                        e
+                    );
+                    None
+                }
+            };
+            if let Some(interrupt_resample_evt) = self.interrupt_resample_evt.take() {
+                self.interrupt_resample_evt = match interrupt_resample_evt.try_clone() {
+                    Ok(evt) => Some(evt),
+                    Err(e) => {
+                        warn!(
+                            "{} failed to clone interrupt_resample_evt: {}",
+                            self.debug_label(),
+                            e
+                        );
+                        None
+                    }
+                };
+                if let Some(mem) = self.mem.take() {
+                    self.mem = Some(mem.clone());
+                    let interrupt = Interrupt::new(
+                        self.interrupt_status.clone(),
+                        interrupt_evt,
+                        interrupt_resample_evt,
+                        Some(self.msix_config.clone()),
+                        self.common_config.msix_config,
+                    );
+
+                    match self.clone_queue_evts() {
+                        Ok(queue_evts) => {
+                            self.needs_reset = interrupt.needs_reset();
+                            self.device
+                                .activate(mem, interrupt, self.queues.clone(), queue_evts);
+                            self.device_activated = true;
+                        }
+                        Err(e) => {
+                            warn!(
+                                "{} not activate due to failed to clone queue_evts: {}",
+                                self.debug_label(),
+                                e
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Brings the device back to the state it had before the driver first set it up, so that a new
+    // driver (or the same one, reloaded) can negotiate features and activate it again.
+    fn reset_device(&mut self) {
+        if self.device_activated {
+            // The device's workers still own copies of the queues, so the driver can't set it up
+            // again unless they stop.
+            if !self.device.reset() {
+                warn!(
+                    "{} does not support reset, the driver can't set it up again",
+                    self.debug_label()
+                );
+                return;
+            }
+            self.device_activated = false;
+            self.needs_reset.store(false, Ordering::SeqCst);
+        }
+
+        // Requests the driver made before the reset are dropped along with the queues.
+        self.queues.iter_mut().for_each(Queue::reset);
+        self.common_config.queue_select = 0;
+        self.common_config.device_feature_select = 0;
+        self.common_config.driver_feature_select = 0;
+        self.common_config.msix_config = VIRTIO_MSI_NO_VECTOR;
+        self.common_config.driver_features = 0;
+        self.interrupt_status.store(0, Ordering::SeqCst);
+    }
+
     fn add_settings_pci_capabilities(
         &mut self,
         settings_bar: u8,
@@ -406,6 +630,7 @@ impl PciDevice for VirtioPciDevice {
         }
         let descriptor = self.msix_config.lock().get_msi_socket();
         rds.push(descriptor);
+        rds.push(self.event_socket.as_raw_descriptor());
         rds
     }
 
@@ -513,6 +738,8 @@ impl PciDevice for VirtioPciDevice {
     fn ioevents(&self) -> Vec<(&Event, u64, Datamatch)> {
         let bar0 = self.config_regs.get_bar_addr(self.settings_bar as usize);
         let notify_base = bar0 + NOTIFICATION_BAR_OFFSET;
+        // The driver notifies a queue by writing its 16-bit index at the queue's notify address
+        // (virtio spec 4.1.4.4), so only that exact write is handled in the kernel.
         self.queue_evts
             .iter()
             .enumerate()
@@ -520,7 +747,7 @@ impl PciDevice for VirtioPciDevice {
                 (
                     event,
                     notify_base + i as u64 * NOTIFY_OFF_MULTIPLIER as u64,
-                    Datamatch::AnyLength,
+                    Datamatch::U16(Some(i as u16)),
                 )
             })
             .collect()
@@ -553,6 +780,7 @@ impl PciDevice for VirtioPciDevice {
     // is written such that the value of the const may be changed independently.
     #[allow(clippy::absurd_extreme_comparisons)]
     fn read_bar(&mut self, addr: u64, data: &mut [u8]) {
+        self.check_needs_reset();
         // The driver is only allowed to do aligned, properly sized access.
         let bar0 = self.config_regs.get_bar_addr(self.settings_bar as usize);
         let offset = addr - bar0;
@@ -602,8 +830,10 @@ impl PciDevice for VirtioPciDevice {
 
     #[allow(clippy::absurd_extreme_comparisons)]
     fn write_bar(&mut self, addr: u64, data: &[u8]) {
+        self.check_needs_reset();
         let bar0 = self.config_regs.get_bar_addr(self.settings_bar as usize);
         let offset = addr - bar0;
+        let prev_driver_status = self.common_config.driver_status;
         match offset {
             o if COMMON_CONFIG_BAR_OFFSET <= o
                 && o < COMMON_CONFIG_BAR_OFFSET + COMMON_CONFIG_SIZE =>
@@ -629,7 +859,16 @@ impl PciDevice for VirtioPciDevice {
             o if NOTIFICATION_BAR_OFFSET <= o
                 && o < NOTIFICATION_BAR_OFFSET + NOTIFICATION_SIZE =>
             {
-                // Handled with ioevents.
+                // Normally handled with ioevents. Notifications only get here when the guest moved
+                // the BAR away from the address the ioevents were registered at, or wrote
+                // something other than the 16-bit queue index the ioevents match on.
+                let queue_index =
+                    ((o - NOTIFICATION_BAR_OFFSET) / NOTIFY_OFF_MULTIPLIER as u64) as usize;
+                if let Some(queue_evt) = self.queue_evts.get(queue_index) {
+                    if let Err(e) = queue_evt.write(1) {
+                        error!("{} failed to notify queue: {}", self.debug_label(), e);
+                    }
+                }
             }
             o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_SIZE => {
                 let behavior = self
@@ -647,75 +886,140 @@ impl PciDevice for VirtioPciDevice {
             _ => (),
         };
 
-        if !self.device_activated && self.is_driver_ready() && self.are_queues_valid() {
-            if let Some(interrupt_evt) = self.interrupt_evt.take() {
-                self.interrupt_evt = match interrupt_evt.try_clone() {
-                    Ok(evt) => Some(evt),
-                    Err(e) => {
-                        warn!(
-                            "{} failed to clone interrupt_evt: {}",
-                            self.debug_label(),
-                            e
-                        );
-                        None
-                    }
-                };
-                if let Some(interrupt_resample_evt) = self.interrupt_resample_evt.take() {
-                    self.interrupt_resample_evt = match interrupt_resample_evt.try_clone() {
-                        Ok(evt) => Some(evt),
-                        Err(e) => {
-                            warn!(
-                                "{} failed to clone interrupt_resample_evt: {}",
-                                self.debug_label(),
-                                e
-                            );
-                            None
-                        }
-                    };
-                    if let Some(mem) = self.mem.take() {
-                        self.mem = Some(mem.clone());
-                        let interrupt = Interrupt::new(
-                            self.interrupt_status.clone(),
-                            interrupt_evt,
-                            interrupt_resample_evt,
-                            Some(self.msix_config.clone()),
-                            self.common_config.msix_config,
-                        );
+        if self.common_config.driver_status != prev_driver_status {
+            self.check_driver_status(prev_driver_status);
+        }
 
-                        match self.clone_queue_evts() {
-                            Ok(queue_evts) => {
-                                self.device.activate(
-                                    mem,
-                                    interrupt,
-                                    self.queues.clone(),
-                                    queue_evts,
-                                );
-                                self.device_activated = true;
-                            }
-                            Err(e) => {
-                                warn!(
-                                    "{} not activate due to failed to clone queue_evts: {}",
-                                    self.debug_label(),
-                                    e
-                                );
-                            }
-                        }
-                    }
-                }
-            }
+        if !self.device_activated
+            && self.is_driver_ready()
+            && self.translate_queues()
+            && self.are_queues_valid()
+        {
+            self.activate();
         }
 
         // Device has been reset by the driver
-        if self.device_activated && self.is_reset_requested() && self.device.reset() {
-            self.device_activated = false;
-            // reset queues
-            self.queues.iter_mut().for_each(Queue::reset);
-            // select queue 0 by default
-            self.common_config.queue_select = 0;
+        if self.common_config.driver_status != prev_driver_status && self.is_reset_requested() {
+            self.reset_device();
         }
     }
 
     fn on_device_sandboxed(&mut self) {
         self.device.on_device_sandboxed();
     }
+
+    fn queue_stats(&self) -> Vec<QueueStats> {
+        self.queues.iter().map(Queue::stats).collect()
+    }
+}
+
+impl Suspendable for VirtioPciDevice {
+    fn sleep(&mut self) -> suspendable::Result<()> {
+        // The device has no workers until the driver sets it up.
+        if !self.device_activated {
+            return Ok(());
+        }
+        self.device.sleep()
+    }
+
+    fn wake(&mut self) -> suspendable::Result<()> {
+        if !self.device_activated {
+            return Ok(());
+        }
+        self.device.wake()
+    }
+
+    fn snapshot(&mut self) -> suspendable::Result<Vec<u8>> {
+        let device = self.device.snapshot()?;
+        let header = VirtioPciSnapshot {
+            device_feature_select: self.common_config.device_feature_select.into(),
+            driver_feature_select: self.common_config.driver_feature_select.into(),
+            queue_select: self.common_config.queue_select.into(),
+            msix_config: self.common_config.msix_config.into(),
+            num_queues: (self.queues.len() as u16).into(),
+            driver_status: self.common_config.driver_status,
+            config_generation: self.common_config.config_generation,
+            driver_features: self.common_config.driver_features.into(),
+        };
+
+        let mut data = header.as_slice().to_vec();
+        for queue in &self.queues {
+            let (next_avail, next_used) = queue.ring_positions();
+            let queue = VirtioPciQueueSnapshot {
+                desc_table: queue.desc_table.offset().into(),
+                avail_ring: queue.avail_ring.offset().into(),
+                used_ring: queue.used_ring.offset().into(),
+                size: queue.size.into(),
+                vector: queue.vector.into(),
+                next_avail: next_avail.into(),
+                next_used: next_used.into(),
+                ready: queue.ready as u8,
+                padding: [0; 7],
+            };
+            data.extend_from_slice(queue.as_slice());
+        }
+        data.extend_from_slice(&device);
+        Ok(data)
+    }
+
+    fn restore(&mut self, data: &[u8]) -> suspendable::Result<()> {
+        // The device worker owns the queues once activated, so only a device the driver hasn't
+        // set up yet can take on the restored ones.
+        if self.device_activated {
+            return Err(SuspendableError::NotSupported);
+        }
+
+        let mut data = data;
+        let header = VirtioPciSnapshot::from_reader(&mut data)
+            .map_err(|_| SuspendableError::InvalidSnapshot)?;
+        if header.num_queues.to_native() as usize != self.queues.len() {
+            return Err(SuspendableError::InvalidSnapshot);
+        }
+        let queues = self
+            .queues
+            .iter()
+            .map(|_| VirtioPciQueueSnapshot::from_reader(&mut data))
+            .collect::<std::io::Result<Vec<_>>>()
+            .map_err(|_| SuspendableError::InvalidSnapshot)?;
+        self.device.restore(data)?;
+
+        let driver_features = header.driver_features.to_native();
+        // Queues only take the features the device offered, as when the driver acks them.
+        let queue_features = driver_features & self.device.features();
+        for (queue, snapshot) in self.queues.iter_mut().zip(queues) {
+            queue.reset();
+            queue.ack_features(queue_features);
+            queue.set_ring_positions(
+                snapshot.next_avail.to_native(),
+                snapshot.next_used.to_native(),
+            );
+            queue.desc_table = GuestAddress(snapshot.desc_table.to_native());
+            queue.avail_ring = GuestAddress(snapshot.avail_ring.to_native());
+            queue.used_ring = GuestAddress(snapshot.used_ring.to_native());
+            queue.size = snapshot.size.to_native();
+            queue.vector = snapshot.vector.to_native();
+            queue.ready = snapshot.ready != 0;
+        }
+        self.common_config.device_feature_select = header.device_feature_select.to_native();
+        self.common_config.driver_feature_select = header.driver_feature_select.to_native();
+        self.common_config.queue_select = header.queue_select.to_native();
+        self.common_config.msix_config = header.msix_config.to_native();
+        self.common_config.driver_status = header.driver_status;
+        self.common_config.config_generation = header.config_generation;
+        self.common_config.driver_features = driver_features;
+
+        // A device the driver had set up goes back to work from where its queues were, but stays
+        // asleep until the VM wakes it.
+        if self.is_driver_ready() {
+            if !self.translate_queues() || !self.are_queues_valid() {
+                return Err(SuspendableError::InvalidSnapshot);
+            }
+            self.activate();
+            if !self.device_activated {
+                return Err(SuspendableError::WorkerFailed);
+            }
+            self.device.sleep()?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,1126 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A virtio-vsock device for hosts without vhost-vsock. Instead of handing the queues to the
+//! kernel, crosvm runs the stream protocol itself and bridges each connection to a Unix domain
+//! socket on the host.
+
+use std::cmp::min;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::thread;
+
+use base::{error, warn, AsRawDescriptor, Event, EventType, PollToken, RawDescriptor, WaitContext};
+use data_model::{DataInit, Le64};
+use vm_memory::GuestMemory;
+
+use super::{copy_config, Interrupt, Queue, Reader, VirtioDevice, Writer, TYPE_VSOCK};
+use crate::Suspendable;
+
+const QUEUE_SIZE: u16 = 256;
+// The receive, transmit and event queues. Nothing is sent on the event queue, as the transport is
+// never reset under the guest.
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; 3];
+
+// The CID the guest reaches the host at.
+const VSOCK_HOST_CID: u64 = 2;
+
+// Size of `struct virtio_vsock_hdr`.
+const HDR_LEN: usize = 44;
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+// The sender of a shutdown won't receive or won't send any more data.
+const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 1;
+const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 2;
+const VIRTIO_VSOCK_SHUTDOWN_BOTH: u32 = VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND;
+
+// Data the guest sent on a connection that its socket didn't take yet is buffered, up to this
+// much. It is the credit every connection gives the guest.
+const CONN_BUF_ALLOC: u32 = 256 * 1024;
+// The guest is sent a credit update once this much of the buffer of a connection was freed since
+// it last heard about it.
+const CREDIT_UPDATE_THRESHOLD: u32 = CONN_BUF_ALLOC / 4;
+// Largest amount of data sent to the guest in one packet.
+const MAX_PKT_DATA_LEN: usize = 64 * 1024;
+// The guest's packets are left in the transmit queue while this many replies to earlier ones are
+// waiting for receive buffers, so that a guest that doesn't receive can't grow them without limit.
+const MAX_PENDING_CONTROL: usize = QUEUE_SIZE as usize;
+// The host ports of connections made from the host are allocated from here up.
+const FIRST_HOST_PORT: u32 = 1024;
+
+#[derive(Debug)]
+pub enum VsockError {
+    /// Failed to create the socket for the connections to a guest port.
+    BindSocket(PathBuf, io::Error),
+}
+
+impl Display for VsockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::VsockError::*;
+
+        match self {
+            BindSocket(path, e) => write!(f, "failed to bind socket {}: {}", path.display(), e),
+        }
+    }
+}
+
+// `struct virtio_vsock_hdr`, which starts every packet.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct PacketHeader {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    type_: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl PacketHeader {
+    fn from_bytes(bytes: &[u8; HDR_LEN]) -> PacketHeader {
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let u32_at = |offset: usize| {
+            let mut field = [0u8; 4];
+            field.copy_from_slice(&bytes[offset..offset + 4]);
+            u32::from_le_bytes(field)
+        };
+        let u64_at = |offset: usize| {
+            let mut field = [0u8; 8];
+            field.copy_from_slice(&bytes[offset..offset + 8]);
+            u64::from_le_bytes(field)
+        };
+        PacketHeader {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            type_: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; HDR_LEN] {
+        let mut bytes = [0u8; HDR_LEN];
+        bytes[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.len.to_le_bytes());
+        bytes[28..30].copy_from_slice(&self.type_.to_le_bytes());
+        bytes[30..32].copy_from_slice(&self.op.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        bytes[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+        bytes
+    }
+}
+
+// Reads a packet the guest sent, and the data that follows its header.
+fn read_packet(reader: &mut Reader) -> io::Result<(PacketHeader, Vec<u8>)> {
+    let mut bytes = [0u8; HDR_LEN];
+    reader.read_exact(&mut bytes)?;
+    let hdr = PacketHeader::from_bytes(&bytes);
+    if hdr.len as usize > reader.available_bytes() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "packet is shorter than its header says",
+        ));
+    }
+    let mut data = vec![0u8; hdr.len as usize];
+    reader.read_exact(&mut data)?;
+    Ok((hdr, data))
+}
+
+#[derive(PollToken)]
+enum Token {
+    // The guest made buffers available to receive packets into.
+    RxQueue,
+    // The guest sent packets.
+    TxQueue,
+    // A connection was made to the socket of the `index`th guest port.
+    Listener { index: usize },
+    // The socket of connection `id` is ready.
+    Stream { id: u32 },
+    InterruptResample,
+    Kill,
+}
+
+// A stream connection between a port of the guest and a host socket.
+struct Connection {
+    host_port: u32,
+    guest_port: u32,
+    stream: UnixStream,
+    // Whether the guest accepted a connection made from the host. Connections the guest makes are
+    // established once their socket is connected.
+    established: bool,
+    // Data from the guest that the socket didn't take yet.
+    pending: Vec<u8>,
+    // How much data from the guest was written to the socket, and how much of that the guest was
+    // told about.
+    fwd_cnt: u32,
+    reported_fwd_cnt: u32,
+    // The receive buffer of the guest, how much of what it was sent it consumed, and how much it
+    // was sent.
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    tx_cnt: u32,
+    // The VIRTIO_VSOCK_SHUTDOWN_* flags the guest sent.
+    peer_shutdown: u32,
+    // Whether the socket was found closed, and the guest told so.
+    closed: bool,
+    // Whether the socket has data for the guest. The socket isn't polled for reading until it
+    // runs out.
+    readable: bool,
+    write_shutdown: bool,
+    // Whether the socket is in the wait context.
+    polled: bool,
+}
+
+impl Connection {
+    fn new(host_port: u32, guest_port: u32, stream: UnixStream, established: bool) -> Connection {
+        Connection {
+            host_port,
+            guest_port,
+            stream,
+            established,
+            pending: Vec::new(),
+            fwd_cnt: 0,
+            reported_fwd_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            tx_cnt: 0,
+            peer_shutdown: 0,
+            closed: false,
+            readable: false,
+            write_shutdown: false,
+            polled: false,
+        }
+    }
+
+    // How much more data the guest can take.
+    fn peer_credit(&self) -> u32 {
+        let in_flight = self.tx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight)
+    }
+
+    // The events to wait for on the socket, if any.
+    fn event_type(&self) -> Option<EventType> {
+        let read = self.established
+            && !self.readable
+            && !self.closed
+            && self.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV == 0;
+        let write = !self.pending.is_empty();
+        match (read, write) {
+            (true, true) => Some(EventType::ReadWrite),
+            (true, false) => Some(EventType::Read),
+            (false, true) => Some(EventType::Write),
+            (false, false) => None,
+        }
+    }
+
+    // Writes as much of the pending data as the socket takes.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(count) => {
+                    self.pending.drain(..count);
+                    self.fwd_cnt = self.fwd_cnt.wrapping_add(count as u32);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // Fills in the credit the guest has on this connection.
+    fn stamp_credit(&mut self, hdr: &mut PacketHeader) {
+        hdr.buf_alloc = CONN_BUF_ALLOC;
+        hdr.fwd_cnt = self.fwd_cnt;
+        self.reported_fwd_cnt = self.fwd_cnt;
+    }
+}
+
+// The connections between the guest and host sockets, and the packets for the guest they produce.
+struct Bridge {
+    cid: u64,
+    host_ports: BTreeMap<u32, PathBuf>,
+    wait_ctx: WaitContext<Token>,
+    connections: BTreeMap<u32, Connection>,
+    // The id of the connection for each pair of host and guest ports.
+    ids: BTreeMap<(u32, u32), u32>,
+    next_id: u32,
+    next_host_port: u32,
+    // Packets for the guest that carry no data.
+    control: VecDeque<PacketHeader>,
+    // Connections whose sockets have data for the guest, in the order they are served.
+    readable: VecDeque<u32>,
+}
+
+impl Bridge {
+    fn new(cid: u64, host_ports: BTreeMap<u32, PathBuf>, wait_ctx: WaitContext<Token>) -> Bridge {
+        Bridge {
+            cid,
+            host_ports,
+            wait_ctx,
+            connections: BTreeMap::new(),
+            ids: BTreeMap::new(),
+            next_id: 0,
+            next_host_port: FIRST_HOST_PORT,
+            control: VecDeque::new(),
+            readable: VecDeque::new(),
+        }
+    }
+
+    fn queue_control(&mut self, host_port: u32, guest_port: u32, op: u16, flags: u32) {
+        self.control.push_back(PacketHeader {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: self.cid,
+            src_port: host_port,
+            dst_port: guest_port,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            flags,
+            ..Default::default()
+        });
+    }
+
+    // Whether the packets the guest sends should wait until it received the pending replies.
+    fn control_full(&self) -> bool {
+        self.control.len() >= MAX_PENDING_CONTROL
+    }
+
+    fn add_connection(&mut self, conn: Connection) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.ids.insert((conn.host_port, conn.guest_port), id);
+        self.connections.insert(id, conn);
+        self.update_polling(id);
+        id
+    }
+
+    // Closes connection `id` and tells the guest so.
+    fn reset(&mut self, id: u32) {
+        if let Some(conn) = self.connections.remove(&id) {
+            if conn.polled {
+                let _ = self.wait_ctx.delete(&conn.stream);
+            }
+            self.ids.remove(&(conn.host_port, conn.guest_port));
+            self.queue_control(conn.host_port, conn.guest_port, VIRTIO_VSOCK_OP_RST, 0);
+        }
+    }
+
+    // Waits for the events connection `id` needs next on its socket.
+    fn update_polling(&mut self, id: u32) {
+        let conn = match self.connections.get_mut(&id) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let token = Token::Stream { id };
+        let result = match (conn.event_type(), conn.polled) {
+            (Some(event_type), true) => self.wait_ctx.modify(&conn.stream, event_type, token),
+            (Some(event_type), false) => {
+                self.wait_ctx.add_for_event(&conn.stream, event_type, token)
+            }
+            // Hangups can't be masked, so a socket that isn't waited on is taken out entirely.
+            (None, true) => self.wait_ctx.delete(&conn.stream),
+            (None, false) => return,
+        };
+        match result {
+            Ok(()) => conn.polled = conn.event_type().is_some(),
+            Err(e) => {
+                error!("vsock: failed to wait on connection socket: {}", e);
+                self.reset(id);
+            }
+        }
+    }
+
+    // Acts on the state of connection `id` after it changed.
+    fn settle(&mut self, id: u32) {
+        let conn = match self.connections.get_mut(&id) {
+            Some(conn) => conn,
+            None => return,
+        };
+        if conn.pending.is_empty() {
+            if conn.peer_shutdown == VIRTIO_VSOCK_SHUTDOWN_BOTH {
+                // The guest is done with the connection. It waits for a reset to release it.
+                self.reset(id);
+                return;
+            }
+            if conn.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0 && !conn.write_shutdown {
+                let _ = conn.stream.shutdown(Shutdown::Write);
+                conn.write_shutdown = true;
+            }
+        }
+        if conn.fwd_cnt.wrapping_sub(conn.reported_fwd_cnt) >= CREDIT_UPDATE_THRESHOLD {
+            // Don't queue another one until this one was sent.
+            conn.reported_fwd_cnt = conn.fwd_cnt;
+            let (host_port, guest_port) = (conn.host_port, conn.guest_port);
+            self.queue_control(host_port, guest_port, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0);
+        }
+        self.update_polling(id);
+    }
+
+    // Connects the guest to the socket of `host_port`.
+    fn connect(&mut self, hdr: &PacketHeader) {
+        let (host_port, guest_port) = (hdr.dst_port, hdr.src_port);
+        let path = match self.host_ports.get(&host_port) {
+            Some(path) => path,
+            None => {
+                self.queue_control(host_port, guest_port, VIRTIO_VSOCK_OP_RST, 0);
+                return;
+            }
+        };
+        let stream = match UnixStream::connect(path).and_then(|stream| {
+            stream.set_nonblocking(true)?;
+            Ok(stream)
+        }) {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(
+                    "vsock: failed to connect port {} to {}: {}",
+                    host_port,
+                    path.display(),
+                    e
+                );
+                self.queue_control(host_port, guest_port, VIRTIO_VSOCK_OP_RST, 0);
+                return;
+            }
+        };
+        let mut conn = Connection::new(host_port, guest_port, stream, true);
+        conn.peer_buf_alloc = hdr.buf_alloc;
+        conn.peer_fwd_cnt = hdr.fwd_cnt;
+        self.add_connection(conn);
+        self.queue_control(host_port, guest_port, VIRTIO_VSOCK_OP_RESPONSE, 0);
+    }
+
+    /// Handles a packet the guest sent, with `data` following its header.
+    fn handle_packet(&mut self, hdr: PacketHeader, data: &[u8]) {
+        let (host_port, guest_port) = (hdr.dst_port, hdr.src_port);
+        if hdr.src_cid != self.cid
+            || hdr.dst_cid != VSOCK_HOST_CID
+            || hdr.type_ != VIRTIO_VSOCK_TYPE_STREAM
+        {
+            warn!(
+                "vsock: dropping packet of type {} from cid {} to cid {}",
+                hdr.type_, hdr.src_cid, hdr.dst_cid
+            );
+            if hdr.op != VIRTIO_VSOCK_OP_RST {
+                self.queue_control(host_port, guest_port, VIRTIO_VSOCK_OP_RST, 0);
+            }
+            return;
+        }
+
+        let id = match (self.ids.get(&(host_port, guest_port)).copied(), hdr.op) {
+            (None, VIRTIO_VSOCK_OP_REQUEST) => return self.connect(&hdr),
+            (Some(id), _) => id,
+            (None, VIRTIO_VSOCK_OP_RST) => return,
+            (None, _) => {
+                self.queue_control(host_port, guest_port, VIRTIO_VSOCK_OP_RST, 0);
+                return;
+            }
+        };
+        let conn = match self.connections.get_mut(&id) {
+            Some(conn) => conn,
+            None => return,
+        };
+        // Every packet tells how much the guest can take.
+        conn.peer_buf_alloc = hdr.buf_alloc;
+        conn.peer_fwd_cnt = hdr.fwd_cnt;
+
+        let valid = match hdr.op {
+            VIRTIO_VSOCK_OP_RESPONSE if !conn.established => {
+                conn.established = true;
+                true
+            }
+            VIRTIO_VSOCK_OP_RW
+                if conn.established && conn.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND == 0 =>
+            {
+                // The guest may only send as much as it has credit for.
+                if conn.pending.len() + data.len() > CONN_BUF_ALLOC as usize {
+                    warn!("vsock: guest overran the buffer of port {}", host_port);
+                    false
+                } else {
+                    conn.pending.extend_from_slice(data);
+                    match conn.flush() {
+                        Ok(()) => true,
+                        Err(e) => {
+                            warn!("vsock: failed to write to port {}: {}", host_port, e);
+                            false
+                        }
+                    }
+                }
+            }
+            VIRTIO_VSOCK_OP_CREDIT_UPDATE => true,
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+                self.queue_control(host_port, guest_port, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0);
+                true
+            }
+            VIRTIO_VSOCK_OP_SHUTDOWN => {
+                conn.peer_shutdown |= hdr.flags & VIRTIO_VSOCK_SHUTDOWN_BOTH;
+                true
+            }
+            VIRTIO_VSOCK_OP_RST => {
+                // The guest closed the connection, or refused one made from the host.
+                if let Some(conn) = self.connections.remove(&id) {
+                    if conn.polled {
+                        let _ = self.wait_ctx.delete(&conn.stream);
+                    }
+                    self.ids.remove(&(host_port, guest_port));
+                }
+                return;
+            }
+            _ => false,
+        };
+        if valid {
+            self.settle(id);
+        } else {
+            self.reset(id);
+        }
+    }
+
+    /// Bridges the connections waiting on `listener` to `guest_port` of the guest.
+    fn accept(&mut self, listener: &UnixListener, guest_port: u32) {
+        loop {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error!("vsock: failed to accept connection: {}", e);
+                    break;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(true) {
+                error!("vsock: failed to make connection non-blocking: {}", e);
+                continue;
+            }
+            let host_port = self.allocate_host_port(guest_port);
+            // The socket isn't read until the guest accepts the connection.
+            self.add_connection(Connection::new(host_port, guest_port, stream, false));
+            self.queue_control(host_port, guest_port, VIRTIO_VSOCK_OP_REQUEST, 0);
+        }
+    }
+
+    fn allocate_host_port(&mut self, guest_port: u32) -> u32 {
+        loop {
+            let port = self.next_host_port;
+            self.next_host_port = port.checked_add(1).unwrap_or(FIRST_HOST_PORT);
+            if !self.ids.contains_key(&(port, guest_port)) && !self.host_ports.contains_key(&port) {
+                return port;
+            }
+        }
+    }
+
+    /// Handles the events on the socket of connection `id`.
+    fn socket_ready(&mut self, id: u32, readable: bool, writable: bool) {
+        let conn = match self.connections.get_mut(&id) {
+            Some(conn) => conn,
+            None => return,
+        };
+        if writable {
+            if let Err(e) = conn.flush() {
+                warn!("vsock: failed to write to port {}: {}", conn.host_port, e);
+                self.reset(id);
+                return;
+            }
+        }
+        if readable && !conn.readable {
+            conn.readable = true;
+            self.readable.push_back(id);
+        }
+        self.settle(id);
+    }
+
+    /// Returns the next packet for the guest and the data that follows its header, which is at
+    /// most `max_data` bytes long.
+    fn next_packet(&mut self, max_data: usize) -> Option<(PacketHeader, Vec<u8>)> {
+        if let Some(mut hdr) = self.control.pop_front() {
+            if let Some(id) = self.ids.get(&(hdr.src_port, hdr.dst_port)) {
+                if let Some(conn) = self.connections.get_mut(id) {
+                    conn.stamp_credit(&mut hdr);
+                }
+            }
+            return Some((hdr, Vec::new()));
+        }
+        if max_data == 0 {
+            return None;
+        }
+
+        // Serve the sockets with data in turn.
+        for _ in 0..self.readable.len() {
+            let id = self.readable.pop_front()?;
+            let conn = match self.connections.get_mut(&id) {
+                Some(conn) => conn,
+                None => continue,
+            };
+            if conn.closed || conn.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV != 0 {
+                conn.readable = false;
+                continue;
+            }
+            let credit = conn.peer_credit() as usize;
+            if credit == 0 {
+                // Wait for the guest to make room.
+                self.readable.push_back(id);
+                continue;
+            }
+
+            let mut data = vec![0u8; min(min(credit, max_data), MAX_PKT_DATA_LEN)];
+            let mut hdr = PacketHeader {
+                src_cid: VSOCK_HOST_CID,
+                dst_cid: self.cid,
+                src_port: conn.host_port,
+                dst_port: conn.guest_port,
+                type_: VIRTIO_VSOCK_TYPE_STREAM,
+                ..Default::default()
+            };
+            match conn.stream.read(&mut data) {
+                Ok(0) => {
+                    // The host side won't send any more.
+                    conn.closed = true;
+                    conn.readable = false;
+                    conn.stamp_credit(&mut hdr);
+                    hdr.op = VIRTIO_VSOCK_OP_SHUTDOWN;
+                    hdr.flags = VIRTIO_VSOCK_SHUTDOWN_SEND;
+                    self.update_polling(id);
+                    return Some((hdr, Vec::new()));
+                }
+                Ok(count) => {
+                    data.truncate(count);
+                    conn.tx_cnt = conn.tx_cnt.wrapping_add(count as u32);
+                    conn.stamp_credit(&mut hdr);
+                    hdr.op = VIRTIO_VSOCK_OP_RW;
+                    hdr.len = count as u32;
+                    self.readable.push_back(id);
+                    return Some((hdr, data));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    conn.readable = false;
+                    self.update_polling(id);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                    self.readable.push_back(id);
+                }
+                Err(e) => {
+                    warn!("vsock: failed to read from port {}: {}", conn.host_port, e);
+                    self.reset(id);
+                    return self.next_packet(max_data);
+                }
+            }
+        }
+        None
+    }
+}
+
+struct Worker {
+    interrupt: Interrupt,
+    mem: GuestMemory,
+    rx_queue: Queue,
+    tx_queue: Queue,
+    cid: u64,
+    host_ports: BTreeMap<u32, PathBuf>,
+    listeners: Vec<(u32, UnixListener)>,
+}
+
+impl Worker {
+    fn process_rx(&mut self, bridge: &mut Bridge) -> bool {
+        let mut needs_interrupt = false;
+        while let Some(desc_chain) = self.rx_queue.peek(&self.mem) {
+            let index = desc_chain.index;
+            let mut writer = match Writer::new(self.mem.clone(), desc_chain) {
+                Ok(writer) => writer,
+                Err(e) => {
+                    error!("vsock: failed to create Writer: {}", e);
+                    self.rx_queue.pop_peeked(&self.mem);
+                    self.rx_queue.add_used(&self.mem, index, 0);
+                    needs_interrupt = true;
+                    continue;
+                }
+            };
+            let max_data = writer.available_bytes().saturating_sub(HDR_LEN);
+            if writer.available_bytes() < HDR_LEN {
+                error!("vsock: rx buffer is too small for a packet header");
+                self.rx_queue.pop_peeked(&self.mem);
+                self.rx_queue.add_used(&self.mem, index, 0);
+                needs_interrupt = true;
+                continue;
+            }
+            let (hdr, data) = match bridge.next_packet(max_data) {
+                Some(packet) => packet,
+                None => break,
+            };
+            if let Err(e) = writer
+                .write_all(&hdr.to_bytes())
+                .and_then(|()| writer.write_all(&data))
+            {
+                error!("vsock: failed to write packet: {}", e);
+            }
+            self.rx_queue.pop_peeked(&self.mem);
+            self.rx_queue
+                .add_used(&self.mem, index, writer.bytes_written() as u32);
+            needs_interrupt = true;
+        }
+        needs_interrupt
+    }
+
+    fn process_tx(&mut self, bridge: &mut Bridge) -> bool {
+        let mut needs_interrupt = false;
+        while !bridge.control_full() {
+            let desc_chain = match self.tx_queue.pop(&self.mem) {
+                Some(desc_chain) => desc_chain,
+                None => break,
+            };
+            let index = desc_chain.index;
+            match Reader::new(self.mem.clone(), desc_chain) {
+                Ok(mut reader) => match read_packet(&mut reader) {
+                    Ok((hdr, data)) => bridge.handle_packet(hdr, &data),
+                    Err(e) => error!("vsock: failed to read packet: {}", e),
+                },
+                Err(e) => error!("vsock: failed to create Reader: {}", e),
+            }
+            self.tx_queue.add_used(&self.mem, index, 0);
+            needs_interrupt = true;
+        }
+        needs_interrupt
+    }
+
+    fn run(&mut self, rx_queue_evt: Event, tx_queue_evt: Event, kill_evt: Event) {
+        let wait_ctx: WaitContext<Token> = match WaitContext::build_with(&[
+            (&rx_queue_evt, Token::RxQueue),
+            (&tx_queue_evt, Token::TxQueue),
+            (self.interrupt.get_resample_evt(), Token::InterruptResample),
+            (&kill_evt, Token::Kill),
+        ]) {
+            Ok(wait_ctx) => wait_ctx,
+            Err(e) => {
+                error!("failed creating WaitContext: {}", e);
+                self.interrupt.signal_needs_reset();
+                return;
+            }
+        };
+        for (index, (_, listener)) in self.listeners.iter().enumerate() {
+            if let Err(e) = wait_ctx.add(listener, Token::Listener { index }) {
+                error!("vsock: failed to wait on socket: {}", e);
+                self.interrupt.signal_needs_reset();
+                return;
+            }
+        }
+        let mut bridge = Bridge::new(self.cid, self.host_ports.clone(), wait_ctx);
+
+        'wait: loop {
+            let events = match bridge.wait_ctx.wait() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed polling for events: {}", e);
+                    break;
+                }
+            };
+
+            let mut needs_tx_interrupt = false;
+            for event in events.iter() {
+                match event.token {
+                    Token::RxQueue => {
+                        if let Err(e) = rx_queue_evt.read() {
+                            error!("vsock: error reading rx queue Event: {}", e);
+                            break 'wait;
+                        }
+                    }
+                    Token::TxQueue => {
+                        if let Err(e) = tx_queue_evt.read() {
+                            error!("vsock: error reading tx queue Event: {}", e);
+                            break 'wait;
+                        }
+                        needs_tx_interrupt |= self.process_tx(&mut bridge);
+                    }
+                    Token::Listener { index } => {
+                        if let Some((guest_port, listener)) = self.listeners.get(index) {
+                            bridge.accept(listener, *guest_port);
+                        }
+                    }
+                    Token::Stream { id } => bridge.socket_ready(
+                        id,
+                        event.is_readable || event.is_hungup,
+                        event.is_writable,
+                    ),
+                    Token::InterruptResample => {
+                        self.interrupt.interrupt_resample();
+                    }
+                    Token::Kill => return,
+                }
+            }
+            // Whatever happened may have left packets for the guest. Sending them may make room for
+            // the replies to the packets left in the transmit queue.
+            let mut needs_rx_interrupt = false;
+            loop {
+                needs_rx_interrupt |= self.process_rx(&mut bridge);
+                if !self.process_tx(&mut bridge) {
+                    break;
+                }
+                needs_tx_interrupt = true;
+            }
+            if needs_tx_interrupt {
+                self.tx_queue.trigger_interrupt(&self.mem, &self.interrupt);
+            }
+            if needs_rx_interrupt {
+                self.rx_queue.trigger_interrupt(&self.mem, &self.interrupt);
+            }
+        }
+
+        // Only errors get here. Resetting the device starts a new worker.
+        self.interrupt.signal_needs_reset();
+    }
+}
+
+/// Virtio device for stream sockets between the guest and the host, implemented in crosvm rather
+/// than with vhost-vsock. Every connection is bridged to a Unix domain socket on the host.
+pub struct Vsock {
+    kill_evt: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<Worker>>,
+    cid: u64,
+    host_ports: BTreeMap<u32, PathBuf>,
+    listeners: Option<Vec<(u32, UnixListener)>>,
+    avail_features: u64,
+}
+
+impl Vsock {
+    /// Creates a vsock device for a guest with context ID `cid`.
+    ///
+    /// Connections the guest makes to a port of the host in `host_ports` are bridged to a new
+    /// connection to the socket at its path. A socket is created at each path of `guest_ports`,
+    /// and connections made to it are bridged to that port of the guest.
+    pub fn new(
+        base_features: u64,
+        cid: u64,
+        host_ports: BTreeMap<u32, PathBuf>,
+        guest_ports: &BTreeMap<u32, PathBuf>,
+    ) -> Result<Vsock, VsockError> {
+        let listeners = guest_ports
+            .iter()
+            .map(|(port, path)| {
+                let listener = UnixListener::bind(path)
+                    .and_then(|listener| {
+                        listener.set_nonblocking(true)?;
+                        Ok(listener)
+                    })
+                    .map_err(|e| VsockError::BindSocket(path.clone(), e))?;
+                Ok((*port, listener))
+            })
+            .collect::<Result<Vec<_>, VsockError>>()?;
+
+        Ok(Vsock {
+            kill_evt: None,
+            worker_thread: None,
+            cid,
+            host_ports,
+            listeners: Some(listeners),
+            avail_features: base_features,
+        })
+    }
+}
+
+impl Drop for Vsock {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl VirtioDevice for Vsock {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
+
+        if let Some(listeners) = &self.listeners {
+            for (_, listener) in listeners {
+                keep_rds.push(listener.as_raw_descriptor());
+            }
+        }
+
+        keep_rds
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_VSOCK
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let cid = Le64::from(self.cid);
+        copy_config(data, 0, DataInit::as_slice(&cid), offset);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<Event>,
+    ) {
+        if queues.len() != QUEUE_SIZES.len() || queue_evts.len() != QUEUE_SIZES.len() {
+            error!(
+                "vsock: expected {} queues, got {}",
+                QUEUE_SIZES.len(),
+                queues.len()
+            );
+            return;
+        }
+
+        let (self_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e))) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed to create kill Event pair: {}", e);
+                return;
+            }
+        };
+        self.kill_evt = Some(self_kill_evt);
+
+        let rx_queue = queues.remove(0);
+        let tx_queue = queues.remove(0);
+        let rx_queue_evt = queue_evts.remove(0);
+        let tx_queue_evt = queue_evts.remove(0);
+        let cid = self.cid;
+        let host_ports = self.host_ports.clone();
+
+        if let Some(listeners) = self.listeners.take() {
+            let worker_result = thread::Builder::new()
+                .name("virtio_vsock".to_string())
+                .spawn(move || {
+                    let mut worker = Worker {
+                        interrupt,
+                        mem,
+                        rx_queue,
+                        tx_queue,
+                        cid,
+                        host_ports,
+                        listeners,
+                    };
+                    worker.run(rx_queue_evt, tx_queue_evt, kill_evt);
+                    worker
+                });
+
+            match worker_result {
+                Err(e) => {
+                    error!("failed to spawn virtio_vsock worker: {}", e);
+                    return;
+                }
+                Ok(join_handle) => {
+                    self.worker_thread = Some(join_handle);
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) -> bool {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            if kill_evt.write(1).is_err() {
+                error!("{}: failed to notify the kill event", self.debug_label());
+                return false;
+            }
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            match worker_thread.join() {
+                Err(_) => {
+                    error!("{}: failed to get back resources", self.debug_label());
+                    return false;
+                }
+                Ok(worker) => {
+                    // Connections end with the worker, but the sockets for the guest's ports stay.
+                    self.listeners = Some(worker.listeners);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl Suspendable for Vsock {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const GUEST_CID: u64 = 3;
+
+    fn guest_packet(host_port: u32, guest_port: u32, op: u16) -> PacketHeader {
+        PacketHeader {
+            src_cid: GUEST_CID,
+            dst_cid: VSOCK_HOST_CID,
+            src_port: guest_port,
+            dst_port: host_port,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            buf_alloc: 4,
+            ..Default::default()
+        }
+    }
+
+    fn bridge(host_ports: BTreeMap<u32, PathBuf>) -> Bridge {
+        Bridge::new(GUEST_CID, host_ports, WaitContext::new().unwrap())
+    }
+
+    #[test]
+    fn header_bytes() {
+        let hdr = PacketHeader {
+            src_cid: 2,
+            dst_cid: 3,
+            src_port: 1024,
+            dst_port: 80,
+            len: 5,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op: VIRTIO_VSOCK_OP_RW,
+            flags: 0,
+            buf_alloc: CONN_BUF_ALLOC,
+            fwd_cnt: 7,
+        };
+        let bytes = hdr.to_bytes();
+        assert_eq!(&bytes[16..20], &1024u32.to_le_bytes());
+        assert_eq!(&bytes[30..32], &VIRTIO_VSOCK_OP_RW.to_le_bytes());
+        assert_eq!(PacketHeader::from_bytes(&bytes), hdr);
+    }
+
+    #[test]
+    fn connect_to_host_port() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("service");
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut host_ports = BTreeMap::new();
+        host_ports.insert(80, path);
+        let mut bridge = bridge(host_ports);
+
+        bridge.handle_packet(guest_packet(80, 5000, VIRTIO_VSOCK_OP_REQUEST), &[]);
+        let (hdr, _) = bridge.next_packet(1024).unwrap();
+        assert_eq!(hdr.op, VIRTIO_VSOCK_OP_RESPONSE);
+        assert_eq!(
+            (hdr.src_port, hdr.dst_port, hdr.dst_cid),
+            (80, 5000, GUEST_CID)
+        );
+        assert_eq!(hdr.buf_alloc, CONN_BUF_ALLOC);
+        let (mut service, _) = listener.accept().unwrap();
+
+        let mut rw = guest_packet(80, 5000, VIRTIO_VSOCK_OP_RW);
+        rw.len = 5;
+        bridge.handle_packet(rw, b"hello");
+        let mut buf = [0u8; 5];
+        service.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // The guest has room for 4 bytes.
+        service.write_all(b"world").unwrap();
+        let id = bridge.ids[&(80, 5000)];
+        bridge.socket_ready(id, true, false);
+        let (hdr, data) = bridge.next_packet(1024).unwrap();
+        assert_eq!((hdr.op, hdr.len, hdr.fwd_cnt), (VIRTIO_VSOCK_OP_RW, 4, 5));
+        assert_eq!(&data, b"worl");
+        assert!(bridge.next_packet(1024).is_none());
+        let mut update = guest_packet(80, 5000, VIRTIO_VSOCK_OP_CREDIT_UPDATE);
+        update.fwd_cnt = 4;
+        bridge.handle_packet(update, &[]);
+        let (_, data) = bridge.next_packet(1024).unwrap();
+        assert_eq!(&data, b"d");
+
+        drop(service);
+        let (hdr, _) = bridge.next_packet(1024).unwrap();
+        assert_eq!(
+            (hdr.op, hdr.flags),
+            (VIRTIO_VSOCK_OP_SHUTDOWN, VIRTIO_VSOCK_SHUTDOWN_SEND)
+        );
+        bridge.handle_packet(guest_packet(80, 5000, VIRTIO_VSOCK_OP_RST), &[]);
+        assert!(bridge.connections.is_empty());
+        assert!(bridge.next_packet(1024).is_none());
+    }
+
+    #[test]
+    fn refused_connections() {
+        let dir = TempDir::new().unwrap();
+        let mut host_ports = BTreeMap::new();
+        // Nothing listens at the path.
+        host_ports.insert(80, dir.path().join("service"));
+        let mut bridge = bridge(host_ports);
+
+        bridge.handle_packet(guest_packet(80, 5000, VIRTIO_VSOCK_OP_REQUEST), &[]);
+        bridge.handle_packet(guest_packet(81, 5000, VIRTIO_VSOCK_OP_REQUEST), &[]);
+        for _ in 0..2 {
+            let (hdr, _) = bridge.next_packet(1024).unwrap();
+            assert_eq!(hdr.op, VIRTIO_VSOCK_OP_RST);
+        }
+        assert!(bridge.connections.is_empty());
+    }
+
+    #[test]
+    fn control_full() {
+        let mut bridge = bridge(BTreeMap::new());
+        for guest_port in 0..MAX_PENDING_CONTROL as u32 {
+            assert!(!bridge.control_full());
+            bridge.handle_packet(guest_packet(80, guest_port, VIRTIO_VSOCK_OP_REQUEST), &[]);
+        }
+        assert!(bridge.control_full());
+        bridge.next_packet(0).unwrap();
+        assert!(!bridge.control_full());
+    }
+
+    #[test]
+    fn connect_to_guest_port() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("guest");
+        let listener = UnixListener::bind(&path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let mut bridge = bridge(BTreeMap::new());
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        bridge.accept(&listener, 22);
+        let (hdr, _) = bridge.next_packet(1024).unwrap();
+        assert_eq!(hdr.op, VIRTIO_VSOCK_OP_REQUEST);
+        assert_eq!((hdr.src_port, hdr.dst_port), (FIRST_HOST_PORT, 22));
+
+        let response = guest_packet(FIRST_HOST_PORT, 22, VIRTIO_VSOCK_OP_RESPONSE);
+        bridge.handle_packet(response, &[]);
+        let mut rw = guest_packet(FIRST_HOST_PORT, 22, VIRTIO_VSOCK_OP_RW);
+        rw.len = 2;
+        bridge.handle_packet(rw, b"hi");
+        let mut buf = [0u8; 2];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+
+        // The guest closing the connection closes the client's.
+        let mut shutdown = guest_packet(FIRST_HOST_PORT, 22, VIRTIO_VSOCK_OP_SHUTDOWN);
+        shutdown.flags = VIRTIO_VSOCK_SHUTDOWN_BOTH;
+        bridge.handle_packet(shutdown, &[]);
+        let (hdr, _) = bridge.next_packet(1024).unwrap();
+        assert_eq!(hdr.op, VIRTIO_VSOCK_OP_RST);
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -29,6 +29,7 @@
 //! event, available descriptors on the `in` or `out` queue, and incoming data on any vfd's socket.
 
 use std::cell::RefCell;
+use std::cmp::max;
 use std::collections::btree_map::Entry;
 use std::collections::{BTreeMap as Map, BTreeSet as Set, VecDeque};
 use std::convert::From;
@@ -36,13 +37,15 @@ use std::error::Error as StdError;
 use std::fmt::{self, Display};
 use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::mem::size_of;
+use std::mem::{self, size_of};
 #[cfg(feature = "wl-dmabuf")]
 use std::os::raw::{c_uint, c_ulonglong};
 use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
@@ -55,22 +58,26 @@ use data_model::*;
 #[cfg(feature = "wl-dmabuf")]
 use base::ioctl_iow_nr;
 use base::{
-    error, pipe, round_up_to_page_size, warn, AsRawDescriptor, Error, Event, FileFlags,
-    FromRawDescriptor, PollToken, RawDescriptor, Result, ScmSocket, SharedMemory, SharedMemoryUnix,
-    WaitContext,
+    error, pagesize, pipe, round_up_to_page_size, warn, AsRawDescriptor, Error, Event, FileFlags,
+    FromRawDescriptor, MemfdSeals, PollToken, RawDescriptor, Result, ScmSocket, SharedMemory,
+    SharedMemoryUnix, WaitContext,
 };
 #[cfg(feature = "gpu")]
 use base::{IntoRawDescriptor, SafeDescriptor};
 use msg_socket::{MsgError, MsgReceiver, MsgSender};
 #[cfg(feature = "wl-dmabuf")]
 use resources::GpuMemoryDesc;
+use resources::{AddressAllocator, Alloc, Error as AllocatorError};
 use vm_memory::{GuestMemory, GuestMemoryError};
 
 #[cfg(feature = "wl-dmabuf")]
 use base::ioctl_with_ref;
 
 use super::resource_bridge::*;
-use super::{DescriptorChain, Interrupt, Queue, Reader, VirtioDevice, Writer, TYPE_WL};
+use super::{
+    copy_config, DescriptorChain, Interrupt, Queue, Reader, VirtioDevice, Writer, TYPE_WL,
+};
+use crate::Suspendable;
 use vm_control::{
     MaybeOwnedDescriptor, MemSlot, VmMemoryControlRequestSocket, VmMemoryRequest, VmMemoryResponse,
 };
@@ -106,6 +113,17 @@ const VIRTIO_WL_VFD_MAP: u32 = 0x2;
 const VIRTIO_WL_VFD_CONTROL: u32 = 0x4;
 const VIRTIO_WL_F_TRANS_FLAGS: u32 = 0x01;
 const VIRTIO_WL_F_SEND_FENCES: u32 = 0x02;
+const VIRTIO_WL_F_SHMEM_AREA: u32 = 0x03;
+
+// The shared memory area grows by a chunk of this size, or of the allocation that didn't fit,
+// whichever is larger.
+const SHMEM_AREA_CHUNK_SIZE: u64 = 1 << 28;
+// The shared memory area doesn't grow beyond this size.
+const SHMEM_AREA_MAX_SIZE: u64 = 1 << 34;
+
+// Size of the name field of VIRTIO_WL_CMD_VFD_NEW_CTX_NAMED, the longest socket name a guest can
+// ask for.
+pub(super) const CONTEXT_NAME_SIZE: usize = 32;
 
 const QUEUE_SIZE: u16 = 16;
 const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE];
@@ -284,6 +302,9 @@ enum WlError {
     WriteResponse(io::Error),
     InvalidString(std::str::Utf8Error),
     UnknownSocketName(String),
+    ShmemAreaFull,
+    GrowShmemArea(Error),
+    ShmemAreaAllocator(AllocatorError),
 }
 
 impl Display for WlError {
@@ -313,6 +334,11 @@ impl Display for WlError {
             WriteResponse(e) => write!(f, "failed to write response: {}", e),
             InvalidString(e) => write!(f, "invalid string: {}", e),
             UnknownSocketName(name) => write!(f, "unknown socket name: {}", name),
+            ShmemAreaFull => write!(f, "the shared memory area reached its maximum size"),
+            GrowShmemArea(e) => write!(f, "failed to grow the shared memory area: {}", e),
+            ShmemAreaAllocator(e) => {
+                write!(f, "failed to allocate in the shared memory area: {}", e)
+            }
         }
     }
 }
@@ -353,6 +379,123 @@ impl VmRequester {
     }
 }
 
+// The guest physical address space that shared memory VFDs are mapped into when the guest acks
+// VIRTIO_WL_F_SHMEM_AREA. It starts out empty and grows by a chunk, reserved from the VM, whenever
+// an allocation doesn't fit in the chunks it already has.
+#[derive(Clone)]
+struct ShmemArea {
+    inner: Rc<RefCell<ShmemAreaInner>>,
+}
+
+struct ShmemAreaInner {
+    vm: VmRequester,
+    chunks: Vec<AddressAllocator>,
+    // The size of all the chunks, which the device reports in its config space.
+    size: Arc<AtomicU64>,
+    next_alloc: usize,
+    grown: bool,
+}
+
+impl ShmemArea {
+    fn new(vm: VmRequester, size: Arc<AtomicU64>) -> ShmemArea {
+        size.store(0, Ordering::Relaxed);
+        ShmemArea {
+            inner: Rc::new(RefCell::new(ShmemAreaInner {
+                vm,
+                chunks: Vec::new(),
+                size,
+                next_alloc: 0,
+                grown: false,
+            })),
+        }
+    }
+
+    // Finds room for `size` bytes, growing the area if there is none. Returns the allocation and
+    // the guest physical address it starts at.
+    fn allocate(&self, size: u64) -> WlResult<(Alloc, u64)> {
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+        // These only need to be unique within the chunks of this area.
+        let alloc = Alloc::Anon(inner.next_alloc);
+        inner.next_alloc += 1;
+
+        for chunk in &mut inner.chunks {
+            if let Ok(gpa) = chunk.allocate(size, alloc, "virtio_wl_alloc".to_string()) {
+                return Ok((alloc, gpa));
+            }
+        }
+
+        let area_size = inner.size.load(Ordering::Relaxed);
+        let chunk_size = max(
+            SHMEM_AREA_CHUNK_SIZE,
+            (size + SHMEM_AREA_CHUNK_SIZE - 1) & !(SHMEM_AREA_CHUNK_SIZE - 1),
+        );
+        if area_size + chunk_size > SHMEM_AREA_MAX_SIZE {
+            return Err(WlError::ShmemAreaFull);
+        }
+        let response = inner.vm.request(VmMemoryRequest::AllocateAddressSpace {
+            alloc: Alloc::WlShmemArea(inner.chunks.len()),
+            size: chunk_size,
+        })?;
+        let base = match response {
+            VmMemoryResponse::AddressSpace { gpa } => gpa,
+            VmMemoryResponse::Err(e) => return Err(WlError::GrowShmemArea(e)),
+            _ => return Err(WlError::VmBadResponse),
+        };
+        let mut chunk = AddressAllocator::new(base, chunk_size, Some(pagesize() as u64))
+            .map_err(WlError::ShmemAreaAllocator)?;
+        let gpa = chunk
+            .allocate(size, alloc, "virtio_wl_alloc".to_string())
+            .map_err(WlError::ShmemAreaAllocator)?;
+        inner.chunks.push(chunk);
+        inner.size.store(area_size + chunk_size, Ordering::Relaxed);
+        inner.grown = true;
+        Ok((alloc, gpa))
+    }
+
+    fn release(&self, alloc: Alloc) {
+        for chunk in &mut self.inner.borrow_mut().chunks {
+            if chunk.release(alloc).is_ok() {
+                return;
+            }
+        }
+    }
+
+    // Returns whether the area grew since the last call, so that the guest can be told.
+    fn take_grown(&self) -> bool {
+        mem::replace(&mut self.inner.borrow_mut().grown, false)
+    }
+}
+
+impl Drop for ShmemAreaInner {
+    fn drop(&mut self) {
+        // A device that is activated again starts with an empty area.
+        for index in 0..self.chunks.len() {
+            match self
+                .vm
+                .request(VmMemoryRequest::ReleaseAddressSpace(Alloc::WlShmemArea(
+                    index,
+                ))) {
+                Ok(VmMemoryResponse::Ok) => {}
+                Ok(_) => error!("failed to release shared memory area chunk {}", index),
+                Err(e) => error!(
+                    "failed to release shared memory area chunk {}: {}",
+                    index, e
+                ),
+            }
+        }
+        self.size.store(0, Ordering::Relaxed);
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub(super) struct WlConfig {
+    shmem_size: Le64,
+}
+
+unsafe impl DataInit for WlConfig {}
+
 #[repr(C)]
 #[derive(Copy, Clone, Default)]
 struct CtrlHeader {
@@ -380,7 +523,7 @@ struct CtrlVfdNewCtxNamed {
     flags: Le32, // Ignored.
     pfn: Le64,   // Ignored.
     size: Le32,  // Ignored.
-    name: [u8; 32],
+    name: [u8; CONTEXT_NAME_SIZE],
 }
 
 unsafe impl DataInit for CtrlVfdNewCtxNamed {}
@@ -560,6 +703,7 @@ struct WlVfd {
     remote_pipe: Option<File>,
     local_pipe: Option<(u32 /* flags */, File)>,
     slot: Option<(MemSlot, u64 /* pfn */, VmRequester)>,
+    shmem_area: Option<(ShmemArea, Alloc)>,
     #[cfg(feature = "wl-dmabuf")]
     is_dmabuf: bool,
 }
@@ -591,26 +735,63 @@ impl WlVfd {
         Ok(vfd)
     }
 
-    fn allocate(vm: VmRequester, size: u64) -> WlResult<WlVfd> {
-        let size_page_aligned = round_up_to_page_size(size as usize) as u64;
-        let vfd_shm =
-            SharedMemory::named("virtwl_alloc", size_page_aligned).map_err(WlError::NewAlloc)?;
+    // Maps the first `size` bytes of `descriptor` into the guest, inside `shmem_area` if there is
+    // one, and records the mapping in this VFD.
+    fn register(
+        &mut self,
+        vm: VmRequester,
+        shmem_area: Option<&ShmemArea>,
+        descriptor: &dyn AsRawDescriptor,
+        size: u64,
+    ) -> WlResult<()> {
+        let descriptor = MaybeOwnedDescriptor::Borrowed(descriptor.as_raw_descriptor());
+        let (request, area_alloc) = match shmem_area {
+            Some(area) => {
+                let (alloc, gpa) = area.allocate(size)?;
+                let request = VmMemoryRequest::RegisterMmapMemory {
+                    descriptor,
+                    size: size as usize,
+                    offset: 0,
+                    gpa,
+                };
+                (request, Some((area.clone(), alloc)))
+            }
+            None => (
+                VmMemoryRequest::RegisterMemory(descriptor, size as usize),
+                None,
+            ),
+        };
 
-        let register_response = vm.request(VmMemoryRequest::RegisterMemory(
-            MaybeOwnedDescriptor::Borrowed(vfd_shm.as_raw_descriptor()),
-            vfd_shm.size() as usize,
-        ))?;
-        match register_response {
-            VmMemoryResponse::RegisterMemory { pfn, slot } => {
-                let mut vfd = WlVfd::default();
-                vfd.guest_shared_memory = Some((vfd_shm.size(), vfd_shm));
-                vfd.slot = Some((slot, pfn, vm));
-                Ok(vfd)
+        match vm.request(request) {
+            Ok(VmMemoryResponse::RegisterMemory { pfn, slot }) => {
+                self.slot = Some((slot, pfn, vm));
+                self.shmem_area = area_alloc;
+                Ok(())
+            }
+            response => {
+                if let Some((area, alloc)) = area_alloc {
+                    area.release(alloc);
+                }
+                response.and(Err(WlError::VmBadResponse))
             }
-            _ => Err(WlError::VmBadResponse),
         }
     }
 
+    fn allocate(vm: VmRequester, shmem_area: Option<&ShmemArea>, size: u64) -> WlResult<WlVfd> {
+        let size_page_aligned = round_up_to_page_size(size as usize) as u64;
+        let mut vfd_shm =
+            SharedMemory::named("virtwl_alloc", size_page_aligned).map_err(WlError::NewAlloc)?;
+        // The guest maps the whole allocation, so it must never shrink underneath that mapping.
+        let mut seals = MemfdSeals::new();
+        seals.set_shrink_seal();
+        vfd_shm.add_seals(seals).map_err(WlError::NewAlloc)?;
+
+        let mut vfd = WlVfd::default();
+        vfd.register(vm, shmem_area, &vfd_shm, vfd_shm.size())?;
+        vfd.guest_shared_memory = Some((vfd_shm.size(), vfd_shm));
+        Ok(vfd)
+    }
+
     #[cfg(feature = "wl-dmabuf")]
     fn dmabuf(
         vm: VmRequester,
@@ -681,7 +862,11 @@ impl WlVfd {
         Ok(vfd)
     }
 
-    fn from_file(vm: VmRequester, mut descriptor: File) -> WlResult<WlVfd> {
+    fn from_file(
+        vm: VmRequester,
+        shmem_area: Option<&ShmemArea>,
+        mut descriptor: File,
+    ) -> WlResult<WlVfd> {
         // We need to determine if the given file is more like shared memory or a pipe/socket. A
         // quick and easy check is to seek to the end of the file. If it works we assume it's not a
         // pipe/socket because those have no end. We can even use that seek location as an indicator
@@ -690,24 +875,13 @@ impl WlVfd {
         match descriptor.seek(SeekFrom::End(0)) {
             Ok(fd_size) => {
                 let size = round_up_to_page_size(fd_size as usize) as u64;
-                let register_response = vm.request(VmMemoryRequest::RegisterMemory(
-                    MaybeOwnedDescriptor::Borrowed(descriptor.as_raw_descriptor()),
-                    size as usize,
-                ))?;
-
-                match register_response {
-                    VmMemoryResponse::RegisterMemory { pfn, slot } => {
-                        let mut vfd = WlVfd::default();
-                        vfd.guest_shared_memory = Some((
-                            size,
-                            SharedMemory::from_file(descriptor)
-                                .map_err(WlError::FromSharedMemory)?,
-                        ));
-                        vfd.slot = Some((slot, pfn, vm));
-                        Ok(vfd)
-                    }
-                    _ => Err(WlError::VmBadResponse),
-                }
+                let mut vfd = WlVfd::default();
+                vfd.register(vm, shmem_area, &descriptor, size)?;
+                vfd.guest_shared_memory = Some((
+                    size,
+                    SharedMemory::from_file(descriptor).map_err(WlError::FromSharedMemory)?,
+                ));
+                Ok(vfd)
             }
             _ => {
                 let flags = match FileFlags::from_file(&descriptor) {
@@ -845,6 +1019,9 @@ impl WlVfd {
         if let Some((slot, _, vm)) = self.slot.take() {
             vm.request(VmMemoryRequest::UnregisterMemory(slot))?;
         }
+        if let Some((area, alloc)) = self.shmem_area.take() {
+            area.release(alloc);
+        }
         self.socket = None;
         self.remote_pipe = None;
         self.local_pipe = None;
@@ -868,6 +1045,7 @@ enum WlRecv {
 struct WlState {
     wayland_paths: Map<String, PathBuf>,
     vm: VmRequester,
+    shmem_area: Option<ShmemArea>,
     resource_bridge: Option<ResourceRequestSocket>,
     use_transition_flags: bool,
     wait_ctx: WaitContext<u32>,
@@ -888,11 +1066,14 @@ impl WlState {
         vm_socket: VmMemoryControlRequestSocket,
         use_transition_flags: bool,
         use_send_vfd_v2: bool,
+        shmem_size: Option<Arc<AtomicU64>>,
         resource_bridge: Option<ResourceRequestSocket>,
     ) -> WlState {
+        let vm = VmRequester::new(vm_socket);
         WlState {
             wayland_paths,
-            vm: VmRequester::new(vm_socket),
+            shmem_area: shmem_size.map(|size| ShmemArea::new(vm.clone(), size)),
+            vm,
             resource_bridge,
             wait_ctx: WaitContext::new().expect("failed to create WaitContext"),
             use_transition_flags,
@@ -962,7 +1143,12 @@ impl WlState {
 
         match self.vfds.entry(id) {
             Entry::Vacant(entry) => {
-                let vfd = WlVfd::allocate(self.vm.clone(), size as u64)?;
+                let vfd =
+                    match WlVfd::allocate(self.vm.clone(), self.shmem_area.as_ref(), size as u64) {
+                        Ok(vfd) => vfd,
+                        Err(WlError::ShmemAreaFull) => return Ok(WlResp::OutOfMemory),
+                        Err(e) => return Err(e),
+                    };
                 let resp = WlResp::VfdNew {
                     id,
                     flags,
@@ -1049,6 +1235,13 @@ impl WlState {
         }
     }
 
+    fn shmem_area_grown(&self) -> bool {
+        match &self.shmem_area {
+            Some(area) => area.take_grown(),
+            None => false,
+        }
+    }
+
     fn process_wait_context(&mut self) {
         let events = match self.wait_ctx.wait_timeout(Duration::from_secs(0)) {
             Ok(v) => v.to_owned(),
@@ -1271,7 +1464,7 @@ impl WlState {
             return Ok(());
         }
         for file in self.in_file_queue.drain(..) {
-            let vfd = WlVfd::from_file(self.vm.clone(), file)?;
+            let vfd = WlVfd::from_file(self.vm.clone(), self.shmem_area.as_ref(), file)?;
             if let Some(wait_descriptor) = vfd.wait_descriptor() {
                 self.wait_ctx
                     .add(wait_descriptor, self.next_vfd_id)
@@ -1480,6 +1673,7 @@ impl Worker {
         vm_socket: VmMemoryControlRequestSocket,
         use_transition_flags: bool,
         use_send_vfd_v2: bool,
+        shmem_size: Option<Arc<AtomicU64>>,
         resource_bridge: Option<ResourceRequestSocket>,
     ) -> Worker {
         Worker {
@@ -1492,6 +1686,7 @@ impl Worker {
                 vm_socket,
                 use_transition_flags,
                 use_send_vfd_v2,
+                shmem_size,
                 resource_bridge,
             ),
         }
@@ -1521,11 +1716,12 @@ impl Worker {
             Ok(pc) => pc,
             Err(e) => {
                 error!("failed creating WaitContext: {}", e);
+                self.interrupt.signal_needs_reset();
                 return;
             }
         };
 
-        'wait: loop {
+        loop {
             let mut signal_used_in = false;
             let mut signal_used_out = false;
             let events = match wait_ctx.wait() {
@@ -1602,7 +1798,7 @@ impl Worker {
                             }
                         }
                     }
-                    Token::Kill => break 'wait,
+                    Token::Kill => return,
                     Token::State => self.state.process_wait_context(),
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
@@ -1646,14 +1842,22 @@ impl Worker {
                 }
             }
 
+            // Allocations, and VFDs received from the host, can both grow the area.
+            if self.state.shmem_area_grown() {
+                self.interrupt.signal_config_changed();
+            }
+
             if signal_used_in {
-                self.interrupt.signal_used_queue(self.in_queue.vector);
+                self.in_queue.trigger_interrupt(&self.mem, &self.interrupt);
             }
 
             if signal_used_out {
-                self.interrupt.signal_used_queue(self.out_queue.vector);
+                self.out_queue.trigger_interrupt(&self.mem, &self.interrupt);
             }
         }
+
+        // Only errors get here. Resetting the device starts a new worker.
+        self.interrupt.signal_needs_reset();
     }
 }
 
@@ -1665,6 +1869,8 @@ pub struct Wl {
     resource_bridge: Option<ResourceRequestSocket>,
     use_transition_flags: bool,
     use_send_vfd_v2: bool,
+    use_shmem_area: bool,
+    shmem_size: Arc<AtomicU64>,
     base_features: u64,
 }
 
@@ -1683,6 +1889,8 @@ impl Wl {
             resource_bridge,
             use_transition_flags: false,
             use_send_vfd_v2: false,
+            use_shmem_area: false,
+            shmem_size: Arc::new(AtomicU64::new(0)),
             base_features,
         })
     }
@@ -1724,7 +1932,10 @@ impl VirtioDevice for Wl {
     }
 
     fn features(&self) -> u64 {
-        self.base_features | 1 << VIRTIO_WL_F_TRANS_FLAGS | 1 << VIRTIO_WL_F_SEND_FENCES
+        self.base_features
+            | 1 << VIRTIO_WL_F_TRANS_FLAGS
+            | 1 << VIRTIO_WL_F_SEND_FENCES
+            | 1 << VIRTIO_WL_F_SHMEM_AREA
     }
 
     fn ack_features(&mut self, value: u64) {
@@ -1734,6 +1945,16 @@ impl VirtioDevice for Wl {
         if value & (1 << VIRTIO_WL_F_SEND_FENCES) != 0 {
             self.use_send_vfd_v2 = true;
         }
+        if value & (1 << VIRTIO_WL_F_SHMEM_AREA) != 0 {
+            self.use_shmem_area = true;
+        }
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = WlConfig {
+            shmem_size: Le64::from(self.shmem_size.load(Ordering::Relaxed)),
+        };
+        copy_config(data, 0, config.as_slice(), offset);
     }
 
     fn activate(
@@ -1760,6 +1981,11 @@ impl VirtioDevice for Wl {
             let wayland_paths = self.wayland_paths.clone();
             let use_transition_flags = self.use_transition_flags;
             let use_send_vfd_v2 = self.use_send_vfd_v2;
+            let shmem_size = if self.use_shmem_area {
+                Some(self.shmem_size.clone())
+            } else {
+                None
+            };
             let resource_bridge = self.resource_bridge.take();
             let worker_result =
                 thread::Builder::new()
@@ -1774,6 +2000,7 @@ impl VirtioDevice for Wl {
                             vm_socket,
                             use_transition_flags,
                             use_send_vfd_v2,
+                            shmem_size,
                             resource_bridge,
                         )
                         .run(queue_evts, kill_evt);
@@ -1791,3 +2018,128 @@ impl VirtioDevice for Wl {
         }
     }
 }
+
+impl Suspendable for Wl {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_control::VmMemoryControlResponseSocket;
+
+    // Answers the memory requests of the device like the VM would, without mapping anything.
+    fn fake_vm(socket: VmMemoryControlResponseSocket) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut mmio = AddressAllocator::new(1 << 32, 1 << 36, Some(0x1000)).unwrap();
+            let mut next_slot = 0;
+            while let Ok(request) = socket.recv() {
+                let response = match request {
+                    VmMemoryRequest::AllocateAddressSpace { alloc, size } => {
+                        let gpa = mmio.allocate(size, alloc, String::new()).unwrap();
+                        VmMemoryResponse::AddressSpace { gpa }
+                    }
+                    VmMemoryRequest::ReleaseAddressSpace(alloc) => {
+                        mmio.release(alloc).unwrap();
+                        VmMemoryResponse::Ok
+                    }
+                    VmMemoryRequest::RegisterMmapMemory { gpa, .. } => {
+                        next_slot += 1;
+                        VmMemoryResponse::RegisterMemory {
+                            pfn: gpa >> 12,
+                            slot: next_slot,
+                        }
+                    }
+                    VmMemoryRequest::UnregisterMemory(_) => VmMemoryResponse::Ok,
+                    r => panic!("unexpected request: {:?}", r),
+                };
+                socket.send(&response).unwrap();
+            }
+        })
+    }
+
+    fn new_alloc_pfn(state: &mut WlState, id: u32, size: u32) -> u64 {
+        match state.new_alloc(id, 0, size).unwrap() {
+            WlResp::VfdNew { pfn, .. } => pfn,
+            _ => panic!("allocation {} failed", id),
+        }
+    }
+
+    #[test]
+    fn shmem_area_grows() {
+        let (vm_socket, vm_responder) = msg_socket::pair().unwrap();
+        let vm = fake_vm(vm_responder);
+        let shmem_size = Arc::new(AtomicU64::new(0));
+        let mut state = WlState::new(
+            Map::new(),
+            vm_socket,
+            false,
+            false,
+            Some(shmem_size.clone()),
+            None,
+        );
+
+        let first = new_alloc_pfn(&mut state, 1, 0x1000);
+        assert!(state.shmem_area_grown());
+        assert_eq!(shmem_size.load(Ordering::Relaxed), SHMEM_AREA_CHUNK_SIZE);
+
+        let second = new_alloc_pfn(&mut state, 2, 0x1000);
+        assert!(!state.shmem_area_grown());
+        assert_eq!(second, first + 1);
+
+        // An allocation that doesn't fit adds a chunk large enough for it.
+        new_alloc_pfn(&mut state, 3, SHMEM_AREA_CHUNK_SIZE as u32);
+        assert!(state.shmem_area_grown());
+        assert_eq!(
+            shmem_size.load(Ordering::Relaxed),
+            SHMEM_AREA_CHUNK_SIZE * 2
+        );
+
+        // Closing a VFD frees its room in the area.
+        state.close(2).unwrap();
+        assert_eq!(new_alloc_pfn(&mut state, 4, 0x1000), second);
+        assert!(!state.shmem_area_grown());
+
+        // The area stops growing at its maximum size.
+        for id in 5..8 {
+            new_alloc_pfn(&mut state, id, 0xffff_f000);
+        }
+        match state.new_alloc(8, 0, 0xffff_f000).unwrap() {
+            WlResp::OutOfMemory => {}
+            _ => panic!("allocation beyond the maximum area size succeeded"),
+        }
+
+        // Dropping the state releases the chunks, which the fake VM checks.
+        drop(state);
+        assert_eq!(shmem_size.load(Ordering::Relaxed), 0);
+        vm.join().unwrap();
+    }
+
+    #[test]
+    fn named_contexts() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("clipboard");
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let mut paths = Map::new();
+        paths.insert("clipboard".to_string(), path);
+
+        let (vm_socket, _vm_responder) = msg_socket::pair().unwrap();
+        let mut state = WlState::new(paths, vm_socket, true, true, None, None);
+
+        match state.new_context(1, "clipboard").unwrap() {
+            WlResp::VfdNew { id: 1, .. } => {}
+            _ => panic!("failed to open a context to a named socket"),
+        }
+        listener.accept().unwrap();
+
+        // Only the sockets the device was given can be reached, so without an unnamed one there
+        // is no default context.
+        assert!(matches!(
+            state.new_context(2, ""),
+            Err(WlError::UnknownSocketName(_))
+        ));
+        assert!(matches!(
+            state.new_context(3, "file-picker"),
+            Err(WlError::UnknownSocketName(_))
+        ));
+    }
+}
@@ -0,0 +1,776 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io;
+use std::mem::size_of;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::sync::Arc;
+
+use base::{
+    ioctl_ior_nr, ioctl_iow_nr, ioctl_iowr_nr, ioctl_with_mut_ptr, ioctl_with_mut_ref,
+    ioctl_with_ref, AsRawDescriptor, IoctlNr, PunchHole, RawDescriptor, SeekHole,
+};
+use sync::Mutex;
+
+const SECTOR_SHIFT: u64 = 9;
+
+// Zone type, numbered as in both the virtio spec and the host kernel's zone reports.
+const ZONE_TYPE_SEQWRITE_REQUIRED: u8 = 2;
+
+// Zone conditions, numbered as in both the virtio spec and the host kernel's zone reports.
+const ZONE_COND_EMPTY: u8 = 1;
+const ZONE_COND_IMPLICIT_OPEN: u8 = 2;
+const ZONE_COND_EXPLICIT_OPEN: u8 = 3;
+const ZONE_COND_CLOSED: u8 = 4;
+const ZONE_COND_FULL: u8 = 14;
+
+const BLKZONED: u32 = 0x12;
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct blk_zone {
+    start: u64,
+    len: u64,
+    wp: u64,
+    type_: u8,
+    cond: u8,
+    non_seq: u8,
+    reset: u8,
+    resv: [u8; 4],
+    capacity: u64,
+    reserved: [u8; 24],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct blk_zone_report {
+    sector: u64,
+    nr_zones: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct blk_zone_range {
+    sector: u64,
+    nr_sectors: u64,
+}
+
+// Set in `blk_zone_report::flags` by kernels that report zone capacities.
+const BLK_ZONE_REP_CAPACITY: u32 = 1 << 0;
+
+ioctl_iowr_nr!(BLKREPORTZONE, BLKZONED, 130, blk_zone_report);
+ioctl_iow_nr!(BLKRESETZONE, BLKZONED, 131, blk_zone_range);
+ioctl_ior_nr!(BLKGETZONESZ, BLKZONED, 132, u32);
+ioctl_ior_nr!(BLKGETNRZONES, BLKZONED, 133, u32);
+ioctl_iow_nr!(BLKOPENZONE, BLKZONED, 134, blk_zone_range);
+ioctl_iow_nr!(BLKCLOSEZONE, BLKZONED, 135, blk_zone_range);
+ioctl_iow_nr!(BLKFINISHZONE, BLKZONED, 136, blk_zone_range);
+
+/// How a block device is split into zones.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ZonedOption {
+    /// The disk is a zoned block device of the host, and the guest sees its zones.
+    Host,
+    /// The disk is a regular file, split into sequential write required zones of `zone_size`
+    /// bytes. A limit of zero is unlimited.
+    Emulated {
+        zone_size: u64,
+        max_open_zones: u32,
+        max_active_zones: u32,
+    },
+}
+
+#[derive(Debug)]
+pub enum ZoneError {
+    /// Activating a zone would go over the limit on active zones.
+    ActiveResource,
+    /// The command can't be applied to the zone in its current condition, or doesn't address a
+    /// zone the way it has to.
+    InvalidCommand,
+    /// Accessing the disk failed.
+    Io(io::Error),
+    /// Opening a zone would go over the limit on open zones.
+    OpenResource,
+    /// A write doesn't start at the write pointer of its zone.
+    UnalignedWritePointer,
+}
+
+impl Display for ZoneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ZoneError::*;
+
+        match self {
+            ActiveResource => write!(f, "too many active zones"),
+            InvalidCommand => write!(f, "invalid command for the zone"),
+            Io(e) => write!(f, "zone management failed: {}", e),
+            OpenResource => write!(f, "too many open zones"),
+            UnalignedWritePointer => write!(f, "write not at the zone's write pointer"),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, ZoneError>;
+
+/// Commands that change the condition of a zone.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ZoneOp {
+    Open,
+    Close,
+    Finish,
+    Reset,
+}
+
+/// A zone as reported to the guest. Positions are in 512-byte sectors.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ZoneDescriptor {
+    pub start: u64,
+    pub capacity: u64,
+    pub write_pointer: u64,
+    pub zone_type: u8,
+    pub condition: u8,
+}
+
+#[derive(Copy, Clone)]
+struct Zone {
+    write_pointer: u64,
+    condition: u8,
+}
+
+fn is_open(condition: u8) -> bool {
+    condition == ZONE_COND_IMPLICIT_OPEN || condition == ZONE_COND_EXPLICIT_OPEN
+}
+
+fn is_active(condition: u8) -> bool {
+    is_open(condition) || condition == ZONE_COND_CLOSED
+}
+
+// Sequential write required zones over a regular file. The write pointers are kept in memory, and
+// the file records them between runs: resetting a zone punches it out of the file, so a zone's
+// write pointer is found again after the last data in it.
+struct EmulatedZones {
+    file: File,
+    zone_sectors: u64,
+    max_open_zones: u32,
+    max_active_zones: u32,
+    zones: Vec<Zone>,
+}
+
+impl EmulatedZones {
+    fn new(
+        mut file: File,
+        zone_size: u64,
+        max_open_zones: u32,
+        max_active_zones: u32,
+    ) -> io::Result<EmulatedZones> {
+        let metadata = file.metadata()?;
+        let disk_size = metadata.len();
+        if !metadata.is_file()
+            || zone_size == 0
+            || !zone_size.is_power_of_two()
+            || zone_size % (1 << SECTOR_SHIFT) != 0
+            || disk_size % zone_size != 0
+        {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let zone_sectors = zone_size >> SECTOR_SHIFT;
+
+        let mut zones = Vec::new();
+        for start in (0..disk_size).step_by(zone_size as usize) {
+            let end = start + zone_size;
+            // The data extents of the file are at least as fine as its blocks, so a write pointer
+            // found this way may be rounded up to the end of a block.
+            let mut data_end = start;
+            let mut pos = start;
+            while let Some(data) = file.seek_data(pos)? {
+                if data >= end {
+                    break;
+                }
+                let hole = file.seek_hole(data)?.unwrap_or(end);
+                data_end = hole.min(end);
+                pos = hole;
+            }
+            let write_pointer = (data_end + (1 << SECTOR_SHIFT) - 1) >> SECTOR_SHIFT;
+            let condition = if data_end == start {
+                ZONE_COND_EMPTY
+            } else if data_end == end {
+                ZONE_COND_FULL
+            } else {
+                ZONE_COND_CLOSED
+            };
+            zones.push(Zone {
+                write_pointer,
+                condition,
+            });
+        }
+
+        Ok(EmulatedZones {
+            file,
+            zone_sectors,
+            max_open_zones,
+            max_active_zones,
+            zones,
+        })
+    }
+
+    fn zone_start(&self, index: usize) -> u64 {
+        index as u64 * self.zone_sectors
+    }
+
+    fn zone_index(&self, sector: u64) -> Result<usize> {
+        let index = (sector / self.zone_sectors) as usize;
+        if index < self.zones.len() {
+            Ok(index)
+        } else {
+            Err(ZoneError::InvalidCommand)
+        }
+    }
+
+    fn count(&self, f: fn(u8) -> bool) -> u32 {
+        self.zones.iter().filter(|z| f(z.condition)).count() as u32
+    }
+
+    // Checks that a zone in condition `from` can be opened, closing an implicitly open zone to
+    // make room if the limit on open zones has been reached and `implicit` is set.
+    fn make_room_to_open(&mut self, from: u8, implicit: bool) -> Result<()> {
+        if is_open(from) {
+            return Ok(());
+        }
+        if !is_active(from)
+            && self.max_active_zones != 0
+            && self.count(is_active) >= self.max_active_zones
+        {
+            return Err(ZoneError::ActiveResource);
+        }
+        if self.max_open_zones != 0 && self.count(is_open) >= self.max_open_zones {
+            let victim = self
+                .zones
+                .iter()
+                .position(|z| z.condition == ZONE_COND_IMPLICIT_OPEN);
+            match victim {
+                Some(index) if implicit => self.zones[index].condition = ZONE_COND_CLOSED,
+                _ => return Err(ZoneError::OpenResource),
+            }
+        }
+        Ok(())
+    }
+
+    // Moves the write pointer of zone `index` past a write of `sectors` sectors at it, and returns
+    // the sector the write starts at.
+    fn advance(&mut self, index: usize, sectors: u64) -> Result<u64> {
+        let start = self.zone_start(index);
+        let zone = self.zones[index];
+        if zone.condition == ZONE_COND_FULL {
+            return Err(ZoneError::InvalidCommand);
+        }
+        if zone.write_pointer + sectors > start + self.zone_sectors {
+            return Err(ZoneError::InvalidCommand);
+        }
+        self.make_room_to_open(zone.condition, true)?;
+
+        let zone = &mut self.zones[index];
+        let sector = zone.write_pointer;
+        zone.write_pointer += sectors;
+        if zone.write_pointer == start + self.zone_sectors {
+            zone.condition = ZONE_COND_FULL;
+        } else if !is_open(zone.condition) {
+            zone.condition = ZONE_COND_IMPLICIT_OPEN;
+        }
+        Ok(sector)
+    }
+
+    fn write(&mut self, sector: u64, sectors: u64) -> Result<()> {
+        let index = self.zone_index(sector)?;
+        if sector != self.zones[index].write_pointer {
+            return Err(ZoneError::UnalignedWritePointer);
+        }
+        self.advance(index, sectors).map(|_| ())
+    }
+
+    fn append(&mut self, sector: u64, sectors: u64) -> Result<u64> {
+        let index = self.zone_index(sector)?;
+        if sector != self.zone_start(index) {
+            return Err(ZoneError::InvalidCommand);
+        }
+        self.advance(index, sectors)
+    }
+
+    fn reset(&mut self, index: usize) -> Result<()> {
+        let start = self.zone_start(index);
+        if self.zones[index].write_pointer != start {
+            self.file
+                .punch_hole(
+                    start << SECTOR_SHIFT,
+                    (self.zones[index].write_pointer - start) << SECTOR_SHIFT,
+                )
+                .map_err(ZoneError::Io)?;
+        }
+        self.zones[index] = Zone {
+            write_pointer: start,
+            condition: ZONE_COND_EMPTY,
+        };
+        Ok(())
+    }
+
+    fn manage(&mut self, op: ZoneOp, sector: u64) -> Result<()> {
+        let index = self.zone_index(sector)?;
+        let start = self.zone_start(index);
+        if sector != start {
+            return Err(ZoneError::InvalidCommand);
+        }
+        let condition = self.zones[index].condition;
+        match op {
+            ZoneOp::Open => {
+                if condition == ZONE_COND_FULL {
+                    return Err(ZoneError::InvalidCommand);
+                }
+                self.make_room_to_open(condition, false)?;
+                self.zones[index].condition = ZONE_COND_EXPLICIT_OPEN;
+            }
+            ZoneOp::Close => match condition {
+                ZONE_COND_IMPLICIT_OPEN | ZONE_COND_EXPLICIT_OPEN => {
+                    // A zone that was opened without being written to goes back to empty.
+                    self.zones[index].condition = if self.zones[index].write_pointer == start {
+                        ZONE_COND_EMPTY
+                    } else {
+                        ZONE_COND_CLOSED
+                    };
+                }
+                ZONE_COND_CLOSED => {}
+                _ => return Err(ZoneError::InvalidCommand),
+            },
+            ZoneOp::Finish => {
+                self.zones[index] = Zone {
+                    write_pointer: start + self.zone_sectors,
+                    condition: ZONE_COND_FULL,
+                };
+            }
+            ZoneOp::Reset => self.reset(index)?,
+        }
+        Ok(())
+    }
+
+    fn reset_all(&mut self) -> Result<()> {
+        for index in 0..self.zones.len() {
+            if self.zones[index].condition != ZONE_COND_EMPTY {
+                self.reset(index)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn report(&self, sector: u64, max_zones: usize) -> Result<Vec<ZoneDescriptor>> {
+        let first = self.zone_index(sector)?;
+        Ok(self.zones[first..]
+            .iter()
+            .take(max_zones)
+            .enumerate()
+            .map(|(i, zone)| ZoneDescriptor {
+                start: self.zone_start(first + i),
+                capacity: self.zone_sectors,
+                write_pointer: zone.write_pointer,
+                zone_type: ZONE_TYPE_SEQWRITE_REQUIRED,
+                condition: zone.condition,
+            })
+            .collect())
+    }
+}
+
+// Converts an error from the host's zone management ioctls to the error the guest sees.
+fn host_zone_error(e: io::Error) -> ZoneError {
+    match e.raw_os_error() {
+        Some(libc::EINVAL) => ZoneError::InvalidCommand,
+        Some(libc::ETOOMANYREFS) => ZoneError::OpenResource,
+        Some(libc::EOVERFLOW) => ZoneError::ActiveResource,
+        _ => ZoneError::Io(e),
+    }
+}
+
+// Reads a limit on zones from the queue attributes of the host block device `device` is, or
+// returns zero if it has none.
+fn read_zone_limit(device: &File, name: &str) -> u32 {
+    let rdev = match device.metadata() {
+        Ok(m) => m.rdev(),
+        Err(_) => return 0,
+    };
+    let major = ((rdev >> 32) & 0xffff_f000) | ((rdev >> 8) & 0xfff);
+    let minor = ((rdev >> 12) & 0xffff_ff00) | (rdev & 0xff);
+    fs::read_to_string(format!("/sys/dev/block/{}:{}/queue/{}", major, minor, name))
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+// The zones of a host zoned block device, which keeps track of them itself.
+struct HostZones {
+    device: File,
+    zone_sectors: u64,
+    nr_zones: u32,
+    max_open_zones: u32,
+    max_active_zones: u32,
+}
+
+impl HostZones {
+    fn new(device: File) -> io::Result<HostZones> {
+        if !device.metadata()?.file_type().is_block_device() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTBLK));
+        }
+        let mut zone_sectors: u32 = 0;
+        // Safe because the kernel only writes a u32 to `zone_sectors`, and we check the return
+        // value.
+        let ret = unsafe { ioctl_with_mut_ref(&device, BLKGETZONESZ(), &mut zone_sectors) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Block devices that aren't zoned have a zone size of zero.
+        if zone_sectors == 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+        }
+        let mut nr_zones: u32 = 0;
+        // Safe because the kernel only writes a u32 to `nr_zones`, and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(&device, BLKGETNRZONES(), &mut nr_zones) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let max_open_zones = read_zone_limit(&device, "max_open_zones");
+        let max_active_zones = read_zone_limit(&device, "max_active_zones");
+        Ok(HostZones {
+            device,
+            zone_sectors: u64::from(zone_sectors),
+            nr_zones,
+            max_open_zones,
+            max_active_zones,
+        })
+    }
+
+    fn report(&self, sector: u64, max_zones: usize) -> Result<Vec<ZoneDescriptor>> {
+        let max_zones = max_zones.min(self.nr_zones as usize);
+        if max_zones == 0 {
+            return Ok(Vec::new());
+        }
+        // The report header is followed by the zones, so allocate it as zones to keep the
+        // alignment right.
+        let header_zones =
+            (size_of::<blk_zone_report>() + size_of::<blk_zone>() - 1) / size_of::<blk_zone>();
+        let mut buf = vec![blk_zone::default(); header_zones + max_zones];
+        let report = buf.as_mut_ptr() as *mut blk_zone_report;
+        // Safe because `buf` is big enough for the header and `max_zones` zones, and is aligned
+        // for both.
+        unsafe {
+            (*report).sector = sector;
+            (*report).nr_zones = max_zones as u32;
+        }
+        // Safe because the kernel writes no more than the `nr_zones` zones the header says there
+        // is room for, and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ptr(&self.device, BLKREPORTZONE(), report) };
+        if ret < 0 {
+            return Err(host_zone_error(io::Error::last_os_error()));
+        }
+        // Safe because the header is initialized, and the kernel updated it.
+        let (nr_zones, flags) = unsafe { ((*report).nr_zones as usize, (*report).flags) };
+        // Safe because the kernel wrote `nr_zones` zones right after the header, within `buf`.
+        let zones = unsafe {
+            std::slice::from_raw_parts(
+                (report as *const u8).add(size_of::<blk_zone_report>()) as *const blk_zone,
+                nr_zones.min(max_zones),
+            )
+        };
+        Ok(zones
+            .iter()
+            .map(|zone| ZoneDescriptor {
+                start: zone.start,
+                capacity: if flags & BLK_ZONE_REP_CAPACITY != 0 {
+                    zone.capacity
+                } else {
+                    zone.len
+                },
+                write_pointer: zone.wp,
+                zone_type: zone.type_,
+                condition: zone.cond,
+            })
+            .collect())
+    }
+
+    fn zone_range(&self, op: IoctlNr, sector: u64, nr_sectors: u64) -> Result<()> {
+        let range = blk_zone_range { sector, nr_sectors };
+        // Safe because the kernel only reads `range`, and we check the return value.
+        let ret = unsafe { ioctl_with_ref(&self.device, op, &range) };
+        if ret < 0 {
+            return Err(host_zone_error(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    fn manage(&self, op: ZoneOp, sector: u64) -> Result<()> {
+        let nr = match op {
+            ZoneOp::Open => BLKOPENZONE(),
+            ZoneOp::Close => BLKCLOSEZONE(),
+            ZoneOp::Finish => BLKFINISHZONE(),
+            ZoneOp::Reset => BLKRESETZONE(),
+        };
+        self.zone_range(nr, sector, self.zone_sectors)
+    }
+
+    fn reset_all(&self) -> Result<()> {
+        // The kernel resets all the zones at once when the range covers the whole device.
+        self.zone_range(
+            BLKRESETZONE(),
+            0,
+            u64::from(self.nr_zones) * self.zone_sectors,
+        )
+    }
+
+    // The device checks writes itself, but an append has to be turned into a write at the zone's
+    // write pointer.
+    fn append(&self, sector: u64, sectors: u64) -> Result<u64> {
+        let zone = match self.report(sector, 1)?.pop() {
+            Some(zone) => zone,
+            None => return Err(ZoneError::InvalidCommand),
+        };
+        if zone.start != sector
+            || zone.zone_type != ZONE_TYPE_SEQWRITE_REQUIRED
+            || zone.write_pointer + sectors > zone.start + zone.capacity
+        {
+            return Err(ZoneError::InvalidCommand);
+        }
+        Ok(zone.write_pointer)
+    }
+}
+
+enum Zones {
+    Emulated(EmulatedZones),
+    Host(HostZones),
+}
+
+/// The zones of a zoned block device.
+///
+/// Clones share the same zones, so that the workers of a device's queues all see the same write
+/// pointers. Each write to a sequential zone has to be checked with `write` before it's made, so
+/// that the emulated write pointers follow it; the caller has to keep writes from racing each
+/// other.
+#[derive(Clone)]
+pub struct ZonedDisk(Arc<Mutex<Zones>>);
+
+impl ZonedDisk {
+    /// Splits the regular file `file` into zones of `zone_size` bytes, which must be a power of
+    /// two that the size of the file is a multiple of.
+    pub fn emulated(
+        file: File,
+        zone_size: u64,
+        max_open_zones: u32,
+        max_active_zones: u32,
+    ) -> io::Result<ZonedDisk> {
+        let zones = EmulatedZones::new(file, zone_size, max_open_zones, max_active_zones)?;
+        Ok(ZonedDisk(Arc::new(Mutex::new(Zones::Emulated(zones)))))
+    }
+
+    /// Passes the zones of the host zoned block device `device` through to the guest.
+    pub fn host(device: File) -> io::Result<ZonedDisk> {
+        let zones = HostZones::new(device)?;
+        Ok(ZonedDisk(Arc::new(Mutex::new(Zones::Host(zones)))))
+    }
+
+    /// Returns the size of each zone in sectors.
+    pub fn zone_sectors(&self) -> u64 {
+        match &*self.0.lock() {
+            Zones::Emulated(z) => z.zone_sectors,
+            Zones::Host(z) => z.zone_sectors,
+        }
+    }
+
+    /// Returns the maximum number of open zones, or zero if there is no limit.
+    pub fn max_open_zones(&self) -> u32 {
+        match &*self.0.lock() {
+            Zones::Emulated(z) => z.max_open_zones,
+            Zones::Host(z) => z.max_open_zones,
+        }
+    }
+
+    /// Returns the maximum number of active zones, or zero if there is no limit.
+    pub fn max_active_zones(&self) -> u32 {
+        match &*self.0.lock() {
+            Zones::Emulated(z) => z.max_active_zones,
+            Zones::Host(z) => z.max_active_zones,
+        }
+    }
+
+    /// Checks that a write of `sectors` sectors may start at `sector`, and accounts for it.
+    pub fn write(&self, sector: u64, sectors: u64) -> Result<()> {
+        match &mut *self.0.lock() {
+            Zones::Emulated(z) => z.write(sector, sectors),
+            Zones::Host(_) => Ok(()),
+        }
+    }
+
+    /// Accounts for appending `sectors` sectors to the zone starting at `sector`, and returns the
+    /// sector the data goes to.
+    pub fn append(&self, sector: u64, sectors: u64) -> Result<u64> {
+        match &mut *self.0.lock() {
+            Zones::Emulated(z) => z.append(sector, sectors),
+            Zones::Host(z) => z.append(sector, sectors),
+        }
+    }
+
+    /// Applies `op` to the zone starting at `sector`.
+    pub fn manage(&self, op: ZoneOp, sector: u64) -> Result<()> {
+        match &mut *self.0.lock() {
+            Zones::Emulated(z) => z.manage(op, sector),
+            Zones::Host(z) => z.manage(op, sector),
+        }
+    }
+
+    /// Resets every zone of the device.
+    pub fn reset_all(&self) -> Result<()> {
+        match &mut *self.0.lock() {
+            Zones::Emulated(z) => z.reset_all(),
+            Zones::Host(z) => z.reset_all(),
+        }
+    }
+
+    /// Returns up to `max_zones` zones, starting with the one `sector` is in.
+    pub fn report(&self, sector: u64, max_zones: usize) -> Result<Vec<ZoneDescriptor>> {
+        match &*self.0.lock() {
+            Zones::Emulated(z) => z.report(sector, max_zones),
+            Zones::Host(z) => z.report(sector, max_zones),
+        }
+    }
+
+    pub fn as_raw_descriptor(&self) -> RawDescriptor {
+        match &*self.0.lock() {
+            Zones::Emulated(z) => z.file.as_raw_descriptor(),
+            Zones::Host(z) => z.device.as_raw_descriptor(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+    use tempfile::tempfile;
+
+    const ZONE_SIZE: u64 = 64 * 1024;
+    const ZONE_SECTORS: u64 = ZONE_SIZE >> SECTOR_SHIFT;
+
+    fn zoned_file(zones: u64, max_open: u32, max_active: u32) -> ZonedDisk {
+        let f = tempfile().unwrap();
+        f.set_len(zones * ZONE_SIZE).unwrap();
+        ZonedDisk::emulated(f, ZONE_SIZE, max_open, max_active).unwrap()
+    }
+
+    fn condition(zoned: &ZonedDisk, zone: u64) -> u8 {
+        zoned.report(zone * ZONE_SECTORS, 1).unwrap()[0].condition
+    }
+
+    #[test]
+    fn invalid_layout() {
+        let f = tempfile().unwrap();
+        f.set_len(ZONE_SIZE * 3 / 2).unwrap();
+        assert!(ZonedDisk::emulated(f.try_clone().unwrap(), ZONE_SIZE, 0, 0).is_err());
+        f.set_len(ZONE_SIZE * 3).unwrap();
+        assert!(ZonedDisk::emulated(f.try_clone().unwrap(), ZONE_SIZE * 3, 0, 0).is_err());
+        assert!(ZonedDisk::emulated(f, ZONE_SIZE, 0, 0).is_ok());
+    }
+
+    #[test]
+    fn sequential_writes() {
+        let zoned = zoned_file(4, 0, 0);
+        let zones = zoned.report(0, 16).unwrap();
+        assert_eq!(zones.len(), 4);
+        assert_eq!(zones[2].start, 2 * ZONE_SECTORS);
+        assert!(zones.iter().all(|z| z.condition == ZONE_COND_EMPTY));
+
+        zoned.write(0, 8).unwrap();
+        assert_eq!(condition(&zoned, 0), ZONE_COND_IMPLICIT_OPEN);
+        match zoned.write(0, 8) {
+            Err(ZoneError::UnalignedWritePointer) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        zoned.write(8, ZONE_SECTORS - 8).unwrap();
+        assert_eq!(condition(&zoned, 0), ZONE_COND_FULL);
+        match zoned.write(ZONE_SECTORS - 8, 8) {
+            Err(ZoneError::UnalignedWritePointer) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // Writes can't cross into the next zone.
+        zoned.write(ZONE_SECTORS, ZONE_SECTORS - 8).unwrap();
+        match zoned.write(2 * ZONE_SECTORS - 8, 16) {
+            Err(ZoneError::InvalidCommand) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn append() {
+        let zoned = zoned_file(2, 0, 0);
+        assert_eq!(zoned.append(ZONE_SECTORS, 8).unwrap(), ZONE_SECTORS);
+        assert_eq!(zoned.append(ZONE_SECTORS, 8).unwrap(), ZONE_SECTORS + 8);
+        assert!(zoned.append(ZONE_SECTORS + 8, 8).is_err());
+        let zone = zoned.report(ZONE_SECTORS, 1).unwrap()[0];
+        assert_eq!(zone.write_pointer, ZONE_SECTORS + 16);
+    }
+
+    #[test]
+    fn open_limits() {
+        let zoned = zoned_file(4, 2, 3);
+        zoned.manage(ZoneOp::Open, 0).unwrap();
+        zoned.write(ZONE_SECTORS, 8).unwrap();
+        // The implicitly opened zone is closed to make room for another one.
+        zoned.write(2 * ZONE_SECTORS, 8).unwrap();
+        assert_eq!(condition(&zoned, 0), ZONE_COND_EXPLICIT_OPEN);
+        assert_eq!(condition(&zoned, 1), ZONE_COND_CLOSED);
+        assert_eq!(condition(&zoned, 2), ZONE_COND_IMPLICIT_OPEN);
+
+        // Three zones are active, so the last one can't be opened.
+        match zoned.write(3 * ZONE_SECTORS, 8) {
+            Err(ZoneError::ActiveResource) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        zoned.manage(ZoneOp::Finish, 2 * ZONE_SECTORS).unwrap();
+        zoned.manage(ZoneOp::Open, 3 * ZONE_SECTORS).unwrap();
+        // Explicitly open zones are never closed to make room.
+        match zoned.manage(ZoneOp::Open, ZONE_SECTORS) {
+            Err(ZoneError::OpenResource) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        zoned.manage(ZoneOp::Close, 0).unwrap();
+        assert_eq!(condition(&zoned, 0), ZONE_COND_EMPTY);
+        zoned.manage(ZoneOp::Open, ZONE_SECTORS).unwrap();
+    }
+
+    #[test]
+    fn reset_and_recover() {
+        let f = tempfile().unwrap();
+        f.set_len(3 * ZONE_SIZE).unwrap();
+        let zoned = ZonedDisk::emulated(f.try_clone().unwrap(), ZONE_SIZE, 0, 0).unwrap();
+        zoned.write(0, ZONE_SECTORS).unwrap();
+        f.write_all_at(&[1u8; ZONE_SIZE as usize], 0).unwrap();
+        zoned.write(ZONE_SECTORS, 8).unwrap();
+        f.write_all_at(&[1u8; 4096], ZONE_SIZE).unwrap();
+        zoned.manage(ZoneOp::Reset, 0).unwrap();
+        assert_eq!(condition(&zoned, 0), ZONE_COND_EMPTY);
+        let mut buf = [1u8; 512];
+        f.read_exact_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // The zones are found where they were left when the file is opened again.
+        let zoned = ZonedDisk::emulated(f, ZONE_SIZE, 0, 0).unwrap();
+        let zones = zoned.report(0, 3).unwrap();
+        assert_eq!(zones[0].condition, ZONE_COND_EMPTY);
+        assert_eq!(zones[1].condition, ZONE_COND_CLOSED);
+        assert_eq!(zones[1].write_pointer, ZONE_SECTORS + 8);
+        assert_eq!(zones[2].condition, ZONE_COND_EMPTY);
+
+        zoned.reset_all().unwrap();
+        assert_eq!(condition(&zoned, 1), ZONE_COND_EMPTY);
+    }
+
+    #[test]
+    fn not_host_zoned() {
+        assert!(ZonedDisk::host(tempfile().unwrap()).is_err());
+    }
+}
@@ -10,7 +10,7 @@ use std::fs::File;
 use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
 use std::mem;
 
-use crate::DiskGetLen;
+use crate::{DiskGetLen, DiskResize};
 use base::{
     AsRawDescriptor, FileAllocate, FileReadWriteAtVolatile, FileSetLen, FileSync, PunchHole,
     RawDescriptor, WriteZeroesAt,
@@ -114,30 +114,44 @@ fn parse_chunk<T: Read + Seek>(
         .map_err(Error::ReadSpecificationError)?;
     let chunk_header =
         ChunkHeader::from_reader(&mut input).map_err(Error::ReadSpecificationError)?;
+    let total_sz = chunk_header.total_sz.to_native() as u64;
+    if total_sz < chunk_hdr_size {
+        return Err(Error::InvalidSpecification(format!(
+            "Chunk size {} is smaller than its header",
+            total_sz
+        )));
+    }
+    // Newer revisions of the format may have larger chunk headers, and the data follows them.
+    let data_offset = current_offset + chunk_hdr_size;
+    let data_size = total_sz - chunk_hdr_size;
+    input
+        .seek(SeekFrom::Start(data_offset))
+        .map_err(Error::ReadSpecificationError)?;
+    let expanded_size = chunk_header.chunk_sz.to_native() as u64 * blk_sz;
     let chunk = match chunk_header.chunk_type.to_native() {
         CHUNK_TYPE_RAW => {
-            input
-                .seek(SeekFrom::Current(
-                    chunk_header.total_sz.to_native() as i64 - chunk_hdr_size as i64,
-                ))
-                .map_err(Error::ReadSpecificationError)?;
-            Chunk::Raw(current_offset + chunk_hdr_size as u64)
+            if data_size != expanded_size {
+                return Err(Error::InvalidSpecification(format!(
+                    "Raw chunk had {} bytes of data for {} bytes of output",
+                    data_size, expanded_size
+                )));
+            }
+            Some(Chunk::Raw(data_offset))
         }
         CHUNK_TYPE_FILL => {
-            if chunk_header.total_sz == chunk_hdr_size as u32 {
+            if data_size == 0 {
                 return Err(Error::InvalidSpecification(format!(
                     "Fill chunk did not have any data to fill"
                 )));
             }
-            let fill_size = chunk_header.total_sz.to_native() as u64 - chunk_hdr_size as u64;
-            let mut fill_bytes = vec![0u8; fill_size as usize];
+            let mut fill_bytes = vec![0u8; data_size as usize];
             input
                 .read_exact(&mut fill_bytes)
                 .map_err(Error::ReadSpecificationError)?;
-            Chunk::Fill(fill_bytes)
+            Some(Chunk::Fill(fill_bytes))
         }
-        CHUNK_TYPE_DONT_CARE => Chunk::DontCare,
-        CHUNK_TYPE_CRC32 => return Ok(None), // TODO(schuffelen): Validate crc32s in input
+        CHUNK_TYPE_DONT_CARE => Some(Chunk::DontCare),
+        CHUNK_TYPE_CRC32 => None, // TODO(schuffelen): Validate crc32s in input
         unknown_type => {
             return Err(Error::InvalidSpecification(format!(
                 "Chunk had invalid type, was {:x}",
@@ -145,8 +159,11 @@ fn parse_chunk<T: Read + Seek>(
             )))
         }
     };
-    let expanded_size = chunk_header.chunk_sz.to_native() as u64 * blk_sz;
-    Ok(Some(ChunkWithSize {
+    // Skip the data of the chunk, which a CRC32 or don't care chunk may also have.
+    input
+        .seek(SeekFrom::Start(current_offset + total_sz))
+        .map_err(Error::ReadSpecificationError)?;
+    Ok(chunk.map(|chunk| ChunkWithSize {
         chunk,
         expanded_size,
     }))
@@ -170,15 +187,25 @@ impl AndroidSparse {
                 MAJOR_VERSION,
                 sparse_header.major_version.to_native(),
             )));
+        } else if (sparse_header.file_hdr_sz.to_native() as usize) < mem::size_of::<SparseHeader>()
+        {
+            return Err(Error::InvalidSpecification(format!(
+                "File header size does not fit file header struct, expected >={}, was {}",
+                mem::size_of::<SparseHeader>(),
+                sparse_header.file_hdr_sz.to_native()
+            )));
         } else if (sparse_header.chunk_hdr_size.to_native() as usize)
             < mem::size_of::<ChunkHeader>()
         {
             return Err(Error::InvalidSpecification(format!(
                 "Chunk header size does not fit chunk header struct, expected >={}, was {}",
-                sparse_header.chunk_hdr_size.to_native(),
-                mem::size_of::<ChunkHeader>()
+                mem::size_of::<ChunkHeader>(),
+                sparse_header.chunk_hdr_size.to_native()
             )));
         }
+        // The first chunk follows the file header, which may be larger in newer revisions.
+        file.seek(SeekFrom::Start(sparse_header.file_hdr_sz.to_native() as u64))
+            .map_err(Error::ReadSpecificationError)?;
         let header_size = sparse_header.chunk_hdr_size.to_native() as u64;
         let block_size = sparse_header.blk_sz.to_native() as u64;
         let chunks = (0..sparse_header.total_chunks.to_native())
@@ -236,6 +263,15 @@ impl FileSetLen for AndroidSparse {
     }
 }
 
+impl DiskResize for AndroidSparse {
+    fn resize(&mut self, _new_size: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "unsupported operation",
+        ))
+    }
+}
+
 impl FileSync for AndroidSparse {
     fn fsync(&mut self) -> io::Result<()> {
         Ok(())
@@ -426,6 +462,51 @@ mod tests {
         AndroidSparse::from_parts(file, size, chunks).expect("Could not create image")
     }
 
+    #[test]
+    fn read_image_from_file() {
+        const BLOCK_SIZE: u32 = 8;
+        let chunks: Vec<(u16, u32, &[u8])> = vec![
+            (CHUNK_TYPE_RAW, 1, b"abcdefgh"),
+            (CHUNK_TYPE_CRC32, 0, &[0u8; 4]),
+            (CHUNK_TYPE_FILL, 2, &[1, 2, 3, 4]),
+            (CHUNK_TYPE_DONT_CARE, 1, &[]),
+        ];
+        let header = SparseHeader {
+            magic: SPARSE_HEADER_MAGIC.into(),
+            major_version: MAJOR_VERSION.into(),
+            minor_version: 0.into(),
+            file_hdr_sz: (mem::size_of::<SparseHeader>() as u16).into(),
+            chunk_hdr_size: (CHUNK_SIZE as u16).into(),
+            blk_sz: BLOCK_SIZE.into(),
+            total_blks: 4.into(),
+            total_chunks: (chunks.len() as u32).into(),
+            image_checksum: 0.into(),
+        };
+        let mut file = tempfile().expect("failed to create tempfile");
+        file.write_all(header.as_slice()).unwrap();
+        for (chunk_type, chunk_sz, data) in chunks {
+            let chunk_header = ChunkHeader {
+                chunk_type: chunk_type.into(),
+                reserved1: 0,
+                chunk_sz: chunk_sz.into(),
+                total_sz: ((CHUNK_SIZE + data.len()) as u32).into(),
+            };
+            file.write_all(chunk_header.as_slice()).unwrap();
+            file.write_all(data).unwrap();
+        }
+
+        let mut image = AndroidSparse::from_file(file).expect("Could not parse image");
+        assert_eq!(image.get_len().unwrap(), 4 * BLOCK_SIZE as u64);
+        let mut input_memory = [55u8; 32];
+        image
+            .read_exact_at_volatile(VolatileSlice::new(&mut input_memory[..]), 0)
+            .expect("Could not read");
+        let mut expected = b"abcdefgh".to_vec();
+        expected.extend([1, 2, 3, 4].iter().cycle().take(16));
+        expected.extend_from_slice(&[0u8; 8]);
+        assert_eq!(&expected[..], &input_memory[..]);
+    }
+
     #[test]
     fn read_dontcare() {
         let chunks = vec![ChunkWithSize {
@@ -5,15 +5,21 @@
 use std::cmp::{max, min};
 use std::fmt::{self, Display};
 use std::fs::{File, OpenOptions};
-use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::ops::Range;
+use std::path::{Path, PathBuf};
 
-use crate::{create_disk_file, DiskFile, DiskGetLen, ImageType};
+use crate::gpt::{
+    self, GptHeader, PartitionEntry, GPT_BEGINNING_SIZE, GPT_END_SIZE, LINUX_FILESYSTEM_GUID,
+    MAX_PARTITIONS, MAX_PARTITION_NAME_LEN, SECTOR_SIZE,
+};
+use crate::{create_disk_file, DiskFile, DiskGetLen, DiskResize, ImageType, MAX_NESTING_DEPTH};
 use base::{
     AsRawDescriptors, FileAllocate, FileReadWriteAtVolatile, FileSetLen, FileSync, PunchHole,
     RawDescriptor, WriteZeroesAt,
 };
 use data_model::VolatileSlice;
+use protobuf::Message;
 use protos::cdisk_spec;
 use remain::sorted;
 
@@ -21,13 +27,19 @@ use remain::sorted;
 #[derive(Debug)]
 pub enum Error {
     DiskError(Box<crate::Error>),
+    GetLength(io::Error, String),
     InvalidMagicHeader,
     InvalidProto(protobuf::ProtobufError),
     InvalidSpecification(String),
     OpenFile(io::Error, String),
+    PartitionLabelTooLong(String),
+    RandomGuid(io::Error),
     ReadSpecificationError(io::Error),
+    TooManyPartitions(usize),
     UnknownVersion(u64),
     UnsupportedComponent(ImageType),
+    WriteFile(io::Error, String),
+    WriteProto(protobuf::ProtobufError),
 }
 
 impl Display for Error {
@@ -38,13 +50,27 @@ impl Display for Error {
         #[sorted]
         match self {
             DiskError(e) => write!(f, "failed to use underlying disk: \"{}\"", e),
+            GetLength(e, p) => write!(f, "failed to get length of \"{}\": \"{}\"", p, e),
             InvalidMagicHeader => write!(f, "invalid magic header for composite disk format"),
             InvalidProto(e) => write!(f, "failed to parse specification proto: \"{}\"", e),
             InvalidSpecification(s) => write!(f, "invalid specification: \"{}\"", s),
             OpenFile(e, p) => write!(f, "failed to open component file \"{}\": \"{}\"", p, e),
+            PartitionLabelTooLong(l) => write!(
+                f,
+                "partition label \"{}\" is longer than {} characters",
+                l, MAX_PARTITION_NAME_LEN
+            ),
+            RandomGuid(e) => write!(f, "failed to generate a GUID: \"{}\"", e),
             ReadSpecificationError(e) => write!(f, "failed to read specification: \"{}\"", e),
+            TooManyPartitions(n) => write!(
+                f,
+                "{} partitions given, at most {} are supported",
+                n, MAX_PARTITIONS
+            ),
             UnknownVersion(v) => write!(f, "unknown version {} in specification", v),
             UnsupportedComponent(c) => write!(f, "unsupported component disk type \"{:?}\"", c),
+            WriteFile(e, p) => write!(f, "failed to write \"{}\": \"{}\"", p, e),
+            WriteProto(e) => write!(f, "failed to write specification proto: \"{}\"", e),
         }
     }
 }
@@ -117,7 +143,8 @@ impl CompositeDiskFile {
     /// Set up a composite disk by reading the specification from a file. The file must consist of
     /// the CDISK_MAGIC string followed by one binary instance of the CompositeDisk protocol
     /// buffer. Returns an error if it could not read the file or if the specification was invalid.
-    pub fn from_file(mut file: File) -> Result<CompositeDiskFile> {
+    /// The component disks are opened with `create_disk_file` and `max_nesting_depth`.
+    pub fn from_file(mut file: File, max_nesting_depth: u32) -> Result<CompositeDiskFile> {
         file.seek(SeekFrom::Start(0))
             .map_err(Error::ReadSpecificationError)?;
         let mut magic_space = [0u8; CDISK_MAGIC_LEN];
@@ -144,7 +171,8 @@ impl CompositeDiskFile {
                     .open(disk.get_file_path())
                     .map_err(|e| Error::OpenFile(e, disk.get_file_path().to_string()))?;
                 Ok(ComponentDiskPart {
-                    file: create_disk_file(file).map_err(|e| Error::DiskError(Box::new(e)))?,
+                    file: create_disk_file(file, max_nesting_depth)
+                        .map_err(|e| Error::DiskError(Box::new(e)))?,
                     offset: disk.get_offset(),
                     length: 0, // Assigned later
                 })
@@ -212,6 +240,162 @@ impl CompositeDiskFile {
     }
 }
 
+/// A partition of a composite disk created by `create_composite_disk`.
+#[derive(Clone, Debug)]
+pub struct PartitionInfo {
+    /// The name of the partition in the partition table.
+    pub label: String,
+    /// The disk image holding the partition.
+    pub path: PathBuf,
+    /// Whether the guest may write to the partition.
+    pub writable: bool,
+}
+
+/// Partitions start at multiples of this many bytes, as partitioning tools usually align them.
+const PARTITION_ALIGNMENT: u64 = 4096;
+
+/// The component used for the gaps between partitions, which read as zeroes.
+const ZERO_FILLER_PATH: &str = "/dev/zero";
+
+fn align_to(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) / alignment * alignment
+}
+
+fn path_str(path: &Path) -> Result<String> {
+    path.to_str().map(|p| p.to_string()).ok_or_else(|| {
+        Error::InvalidSpecification(format!("path {} is not valid UTF-8", path.display()))
+    })
+}
+
+fn component_disk(path: &str, offset: u64, writable: bool) -> cdisk_spec::ComponentDisk {
+    let mut component = cdisk_spec::ComponentDisk::new();
+    component.set_file_path(path.to_string());
+    component.set_offset(offset);
+    component.set_read_write_capability(if writable {
+        cdisk_spec::ReadWriteCapability::READ_WRITE
+    } else {
+        cdisk_spec::ReadWriteCapability::READ_ONLY
+    });
+    component
+}
+
+// Writes `write` to a new file at `path`, replacing any existing one.
+fn write_file<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut File) -> io::Result<()>,
+{
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .and_then(|mut file| write(&mut file))
+        .map_err(|e| Error::WriteFile(e, path.display().to_string()))
+}
+
+/// Creates a composite disk with a GUID partition table that holds `partitions` in order, and
+/// writes its specification to `output_composite`. The protective MBR, the partition table and
+/// its primary header are written to a new file at `header_path`, and the backup partition table
+/// and header to a new file at `footer_path`. Both are components of the disk, as are the
+/// partitions, which are referred to by the paths given, so they must all stay in place for the
+/// composite disk to be used.
+pub fn create_composite_disk(
+    partitions: &[PartitionInfo],
+    header_path: &Path,
+    footer_path: &Path,
+    output_composite: &mut File,
+) -> Result<()> {
+    if partitions.len() > MAX_PARTITIONS {
+        return Err(Error::TooManyPartitions(partitions.len()));
+    }
+
+    let header_size = align_to(GPT_BEGINNING_SIZE, PARTITION_ALIGNMENT);
+    let mut components = vec![component_disk(&path_str(header_path)?, 0, false)];
+    let mut entries = Vec::new();
+    let mut offset = header_size;
+    for partition in partitions {
+        let partition_name: Vec<u16> = partition.label.encode_utf16().collect();
+        if partition_name.len() > MAX_PARTITION_NAME_LEN {
+            return Err(Error::PartitionLabelTooLong(partition.label.clone()));
+        }
+        let path = path_str(&partition.path)?;
+        let file = File::open(&partition.path).map_err(|e| Error::OpenFile(e, path.clone()))?;
+        let size = create_disk_file(file, MAX_NESTING_DEPTH)
+            .map_err(|e| Error::DiskError(Box::new(e)))?
+            .get_len()
+            .map_err(|e| Error::GetLength(e, path.clone()))?;
+        if size == 0 {
+            return Err(Error::InvalidSpecification(format!(
+                "partition {} is empty",
+                path
+            )));
+        }
+
+        components.push(component_disk(&path, offset, partition.writable));
+        let end = align_to(offset + size, PARTITION_ALIGNMENT);
+        if end > offset + size {
+            components.push(component_disk(ZERO_FILLER_PATH, offset + size, false));
+        }
+        entries.push(PartitionEntry {
+            partition_type_guid: LINUX_FILESYSTEM_GUID,
+            unique_partition_guid: gpt::random_guid().map_err(Error::RandomGuid)?,
+            first_lba: offset / SECTOR_SIZE,
+            last_lba: end / SECTOR_SIZE - 1,
+            attributes: 0,
+            partition_name,
+        });
+        offset = end;
+    }
+
+    let footer_offset = offset;
+    let disk_size = footer_offset + GPT_END_SIZE;
+    let last_lba = disk_size / SECTOR_SIZE - 1;
+    let disk_guid = gpt::random_guid().map_err(Error::RandomGuid)?;
+    let entries = gpt::partition_entries(&entries);
+    let primary_header = GptHeader {
+        current_lba: 1,
+        backup_lba: last_lba,
+        first_usable_lba: GPT_BEGINNING_SIZE / SECTOR_SIZE,
+        last_usable_lba: footer_offset / SECTOR_SIZE - 1,
+        disk_guid,
+        partition_entries_lba: 2,
+    };
+    let backup_header = GptHeader {
+        current_lba: last_lba,
+        backup_lba: 1,
+        partition_entries_lba: footer_offset / SECTOR_SIZE,
+        ..primary_header
+    };
+
+    write_file(header_path, |file| {
+        gpt::write_protective_mbr(file, disk_size)?;
+        primary_header.write(file, &entries)?;
+        file.write_all(&entries)?;
+        // The header component reaches up to the first partition.
+        file.set_len(header_size)
+    })?;
+    write_file(footer_path, |file| {
+        file.write_all(&entries)?;
+        backup_header.write(file, &entries)
+    })?;
+    components.push(component_disk(
+        &path_str(footer_path)?,
+        footer_offset,
+        false,
+    ));
+
+    let mut composite = cdisk_spec::CompositeDisk::new();
+    composite.set_version(1);
+    composite.set_length(disk_size);
+    composite.set_component_disks(components.into());
+    output_composite
+        .write_all(CDISK_MAGIC.as_bytes())
+        .map_err(|e| Error::WriteFile(e, "composite disk".to_string()))?;
+    composite
+        .write_to_writer(output_composite)
+        .map_err(Error::WriteProto)
+}
+
 impl DiskGetLen for CompositeDiskFile {
     fn get_len(&self) -> io::Result<u64> {
         Ok(self.length())
@@ -224,6 +408,12 @@ impl FileSetLen for CompositeDiskFile {
     }
 }
 
+impl DiskResize for CompositeDiskFile {
+    fn resize(&mut self, _new_size: u64) -> io::Result<()> {
+        Err(io::Error::new(ErrorKind::Other, "unsupported operation"))
+    }
+}
+
 impl FileSync for CompositeDiskFile {
     fn fsync(&mut self) -> io::Result<()> {
         for disk in self.component_disks.iter_mut() {
@@ -344,6 +534,7 @@ mod tests {
     use super::*;
     use base::{AsRawDescriptor, SharedMemory};
     use data_model::VolatileMemory;
+    use tempfile::{tempfile, TempDir};
 
     #[test]
     fn block_duplicate_offset_disks() {
@@ -560,4 +751,58 @@ mod tests {
         }
         assert!(input_memory.into_iter().eq(output_memory.into_iter()));
     }
+
+    #[test]
+    fn create_composite() {
+        let dir = TempDir::new().unwrap();
+        let partition1 = dir.path().join("partition1");
+        let partition2 = dir.path().join("partition2");
+        // The first partition is padded to the alignment of the second.
+        std::fs::write(&partition1, &[0x11u8; 1000][..]).unwrap();
+        std::fs::write(&partition2, &[0x22u8; 4096][..]).unwrap();
+        let partitions = [
+            PartitionInfo {
+                label: "system".to_string(),
+                path: partition1,
+                writable: false,
+            },
+            PartitionInfo {
+                label: "data".to_string(),
+                path: partition2,
+                writable: true,
+            },
+        ];
+        let mut spec = tempfile().unwrap();
+        create_composite_disk(
+            &partitions,
+            &dir.path().join("header"),
+            &dir.path().join("footer"),
+            &mut spec,
+        )
+        .unwrap();
+
+        let mut composite = CompositeDiskFile::from_file(spec, MAX_NESTING_DEPTH).unwrap();
+        let header_size = 5 * PARTITION_ALIGNMENT;
+        let footer_offset = header_size + 2 * PARTITION_ALIGNMENT;
+        assert_eq!(composite.get_len().unwrap(), footer_offset + GPT_END_SIZE);
+
+        let mut buf = [0u8; 8];
+        let read_at = |composite: &mut CompositeDiskFile, buf: &mut [u8], offset| {
+            composite
+                .read_exact_at_volatile(VolatileSlice::new(buf), offset)
+                .unwrap()
+        };
+        read_at(&mut composite, &mut buf, SECTOR_SIZE);
+        assert_eq!(&buf, b"EFI PART");
+        read_at(&mut composite, &mut buf, header_size + 996);
+        assert_eq!(buf, [0x11, 0x11, 0x11, 0x11, 0, 0, 0, 0]);
+        read_at(&mut composite, &mut buf, header_size + PARTITION_ALIGNMENT);
+        assert_eq!(buf, [0x22; 8]);
+        read_at(
+            &mut composite,
+            &mut buf,
+            footer_offset + GPT_END_SIZE - SECTOR_SIZE,
+        );
+        assert_eq!(&buf, b"EFI PART");
+    }
 }
@@ -0,0 +1,338 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::{max, min};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+
+use base::{
+    add_fd_flags, fallocate, pagesize, AsRawDescriptor, AsRawDescriptors, FallocateMode,
+    FileAllocate, FileReadWriteAtVolatile, FileSetLen, FileSync, PunchHole, RawDescriptor,
+    WriteZeroesAt,
+};
+use data_model::VolatileSlice;
+
+use crate::{detect_image_type, DiskGetLen, DiskResize, Error, ImageType, Result};
+
+// Unaligned I/O is bounced through a buffer of this size, so it takes a call for each chunk.
+const BOUNCE_SIZE: usize = 0x10000;
+// The smallest logical block size of any storage.
+const MIN_ALIGNMENT: usize = 512;
+
+/// A raw disk image accessed with `O_DIRECT`, bypassing the host page cache.
+///
+/// Direct I/O needs the file offset, the length and the address of each buffer to be aligned to
+/// the logical block size of the storage under the file. I/O that is aligned goes straight to the
+/// file. The rest goes through a bounce buffer, a chunk at a time, reading the blocks that a write
+/// only covers part of so that it can write them back whole.
+#[derive(Debug)]
+pub struct DirectFile {
+    file: File,
+    alignment: usize,
+    // Over-allocated by `alignment` so that an aligned buffer of `BOUNCE_SIZE` fits in it.
+    bounce: Vec<u8>,
+}
+
+impl DirectFile {
+    /// Switches `file` to direct I/O. Only raw images are supported, since the other formats read
+    /// and write their metadata at arbitrary offsets.
+    pub fn new(file: File) -> Result<DirectFile> {
+        if detect_image_type(&file)? != ImageType::Raw {
+            return Err(Error::DirectIoNotRaw);
+        }
+        // The preferred I/O size of a file is a multiple of the logical block size, but there's no
+        // point aligning to more than a page.
+        let blksize = file.metadata().map_err(Error::DirectIo)?.blksize() as usize;
+        let alignment = max(min(blksize, pagesize()), MIN_ALIGNMENT);
+        if !alignment.is_power_of_two() {
+            return Err(Error::DirectIo(io::Error::from_raw_os_error(libc::EINVAL)));
+        }
+        add_fd_flags(file.as_raw_fd(), libc::O_DIRECT)
+            .map_err(|e| Error::DirectIo(io::Error::from_raw_os_error(e.errno())))?;
+        Ok(DirectFile {
+            file,
+            alignment,
+            bounce: vec![0; BOUNCE_SIZE + alignment],
+        })
+    }
+
+    fn is_aligned(&self, slice: &VolatileSlice, offset: u64) -> bool {
+        let mask = self.alignment - 1;
+        slice.as_mut_ptr() as usize & mask == 0
+            && slice.size() & mask == 0
+            && offset as usize & mask == 0
+    }
+
+    fn align_down(&self, offset: u64) -> u64 {
+        offset & !(self.alignment as u64 - 1)
+    }
+
+    // Returns the aligned range of the file, from `start` to `end`, that the bounce buffer covers
+    // for I/O of `len` bytes at `offset`.
+    fn bounce_range(&self, offset: u64, len: usize) -> (u64, u64) {
+        let start = self.align_down(offset);
+        let end = self.align_down(offset + len as u64 + self.alignment as u64 - 1);
+        (start, min(end, start + BOUNCE_SIZE as u64))
+    }
+
+    // Reads the block at `offset` of the file into `block_index` of the bounce buffer. The part of
+    // the block past the end of the file reads as zeroes.
+    fn read_block(&mut self, offset: u64, block_index: usize) -> io::Result<()> {
+        let alignment = self.alignment;
+        let bounce = aligned_bounce(&mut self.bounce, alignment);
+        let block =
+            VolatileSlice::new(&mut bounce[block_index * alignment..(block_index + 1) * alignment]);
+        block.write_bytes(0);
+        let mut done = 0;
+        while done < alignment {
+            match self
+                .file
+                .read_at_volatile(block.offset(done).unwrap(), offset + done as u64)
+            {
+                Ok(0) => break,
+                Ok(n) => done += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_bounced(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        let (start, end) = self.bounce_range(offset, slice.size());
+        let bounce = aligned_bounce(&mut self.bounce, self.alignment);
+        let bounce = VolatileSlice::new(&mut bounce[..(end - start) as usize]);
+        let read = self.file.read_at_volatile(bounce, start)?;
+        let skip = (offset - start) as usize;
+        if read <= skip {
+            return Ok(0);
+        }
+        let count = min(slice.size(), read - skip);
+        bounce
+            .sub_slice(skip, count)
+            .unwrap()
+            .copy_to_volatile_slice(slice);
+        Ok(count)
+    }
+
+    fn write_bounced(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        let (start, end) = self.bounce_range(offset, slice.size());
+        let len = (end - start) as usize;
+        let skip = (offset - start) as usize;
+        let count = min(slice.size(), len - skip);
+
+        // Read the blocks at either end that the write only covers part of. They are the same
+        // block if the write is within one.
+        let blocks = len / self.alignment;
+        if skip != 0 {
+            self.read_block(start, 0)?;
+        }
+        if (skip + count) % self.alignment != 0 && (blocks > 1 || skip == 0) {
+            self.read_block(end - self.alignment as u64, blocks - 1)?;
+        }
+
+        let bounce = aligned_bounce(&mut self.bounce, self.alignment);
+        let bounce = VolatileSlice::new(&mut bounce[..len]);
+        slice
+            .sub_slice(0, count)
+            .unwrap()
+            .copy_to_volatile_slice(bounce.sub_slice(skip, count).unwrap());
+        self.file.write_all_at_volatile(bounce, start)?;
+        Ok(count)
+    }
+}
+
+// Returns the part of `bounce` that is aligned to `alignment` and `BOUNCE_SIZE` long.
+fn aligned_bounce(bounce: &mut [u8], alignment: usize) -> &mut [u8] {
+    let misalignment = bounce.as_ptr() as usize & (alignment - 1);
+    let start = if misalignment == 0 {
+        0
+    } else {
+        alignment - misalignment
+    };
+    &mut bounce[start..start + BOUNCE_SIZE]
+}
+
+impl FileReadWriteAtVolatile for DirectFile {
+    fn read_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        if self.is_aligned(&slice, offset) {
+            self.file.read_at_volatile(slice, offset)
+        } else {
+            self.read_bounced(slice, offset)
+        }
+    }
+
+    fn read_vectored_at_volatile(
+        &mut self,
+        bufs: &[VolatileSlice],
+        offset: u64,
+    ) -> io::Result<usize> {
+        if bufs.iter().all(|b| self.is_aligned(b, offset)) {
+            self.file.read_vectored_at_volatile(bufs, offset)
+        } else {
+            match bufs.iter().find(|b| b.size() > 0) {
+                Some(&slice) => self.read_at_volatile(slice, offset),
+                None => Ok(0),
+            }
+        }
+    }
+
+    fn write_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
+        if self.is_aligned(&slice, offset) {
+            self.file.write_at_volatile(slice, offset)
+        } else {
+            self.write_bounced(slice, offset)
+        }
+    }
+
+    fn write_vectored_at_volatile(
+        &mut self,
+        bufs: &[VolatileSlice],
+        offset: u64,
+    ) -> io::Result<usize> {
+        if bufs.iter().all(|b| self.is_aligned(b, offset)) {
+            self.file.write_vectored_at_volatile(bufs, offset)
+        } else {
+            match bufs.iter().find(|b| b.size() > 0) {
+                Some(&slice) => self.write_at_volatile(slice, offset),
+                None => Ok(0),
+            }
+        }
+    }
+}
+
+impl WriteZeroesAt for DirectFile {
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+        if fallocate(
+            &self.file,
+            FallocateMode::ZeroRange,
+            true,
+            offset,
+            length as u64,
+        )
+        .is_ok()
+        {
+            return Ok(length);
+        }
+        // Fall back to writing zeroes, which `File` would do from an unaligned buffer.
+        let mut zeroes = vec![0u8; min(length, BOUNCE_SIZE)];
+        self.write_at_volatile(VolatileSlice::new(&mut zeroes), offset)
+    }
+}
+
+impl FileSync for DirectFile {
+    fn fsync(&mut self) -> io::Result<()> {
+        self.file.fsync()
+    }
+
+    fn fdatasync(&mut self) -> io::Result<()> {
+        self.file.fdatasync()
+    }
+}
+
+impl FileSetLen for DirectFile {
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+}
+
+impl DiskGetLen for DirectFile {
+    fn get_len(&self) -> io::Result<u64> {
+        self.file.get_len()
+    }
+}
+
+impl DiskResize for DirectFile {
+    fn resize(&mut self, new_size: u64) -> io::Result<()> {
+        self.file.resize(new_size)
+    }
+}
+
+impl PunchHole for DirectFile {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.file.punch_hole(offset, length)
+    }
+}
+
+impl FileAllocate for DirectFile {
+    fn allocate(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.file.allocate(offset, len)
+    }
+}
+
+impl AsRawDescriptors for DirectFile {
+    fn as_raw_descriptors(&self) -> Vec<RawDescriptor> {
+        vec![self.file.as_raw_descriptor()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+    use tempfile::tempfile;
+
+    fn direct_file(contents: &[u8]) -> Option<DirectFile> {
+        let f = tempfile().unwrap();
+        f.write_all_at(contents, 0).unwrap();
+        // Not every filesystem the tests could run on supports O_DIRECT.
+        DirectFile::new(f).ok()
+    }
+
+    #[test]
+    fn unaligned_read() {
+        let contents: Vec<u8> = (0..0x3000).map(|i| i as u8).collect();
+        let mut f = match direct_file(&contents) {
+            Some(f) => f,
+            None => return,
+        };
+
+        let mut buf = vec![0u8; 0x1001];
+        f.read_exact_at_volatile(VolatileSlice::new(&mut buf[1..]), 0x7ff)
+            .unwrap();
+        assert_eq!(&buf[1..], &contents[0x7ff..0x17ff]);
+
+        // Reads stop at the end of the file.
+        let read = f
+            .read_at_volatile(VolatileSlice::new(&mut buf), 0x2f00)
+            .unwrap();
+        assert_eq!(read, 0x100);
+        assert_eq!(&buf[..0x100], &contents[0x2f00..]);
+    }
+
+    #[test]
+    fn unaligned_write() {
+        let contents = vec![0x55u8; 0x3000];
+        let mut f = match direct_file(&contents) {
+            Some(f) => f,
+            None => return,
+        };
+
+        let mut data = vec![0xaau8; 0x1235];
+        f.write_all_at_volatile(VolatileSlice::new(&mut data[1..]), 0x7ff)
+            .unwrap();
+
+        let mut expected = contents;
+        expected[0x7ff..0x7ff + 0x1234].copy_from_slice(&data[1..]);
+        // Read back through the bounce buffer, since the file can only be read with aligned
+        // buffers.
+        let mut actual = vec![0u8; 0x3001];
+        f.read_exact_at_volatile(VolatileSlice::new(&mut actual[1..]), 0)
+            .unwrap();
+        let actual = &actual[1..];
+        assert_eq!(actual, &expected[..]);
+    }
+
+    #[test]
+    fn not_raw() {
+        let f = tempfile().unwrap();
+        f.write_all_at(&crate::QCOW_MAGIC.to_be_bytes(), 0).unwrap();
+        match DirectFile::new(f) {
+            Err(Error::DirectIoNotRaw) => {}
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+    }
+}
@@ -19,16 +19,27 @@ use remain::sorted;
 use vm_memory::GuestMemory;
 
 mod qcow;
-pub use qcow::{QcowFile, QCOW_MAGIC};
+pub use qcow::{QcowCacheSize, QcowCheckResult, QcowFile, QcowSnapshot, QCOW_MAGIC};
 
 #[cfg(feature = "composite-disk")]
 mod composite;
 #[cfg(feature = "composite-disk")]
+pub use composite::{create_composite_disk, PartitionInfo};
+#[cfg(feature = "composite-disk")]
 use composite::{CompositeDiskFile, CDISK_MAGIC, CDISK_MAGIC_LEN};
+#[cfg(feature = "composite-disk")]
+mod gpt;
 
 mod android_sparse;
 use android_sparse::{AndroidSparse, SPARSE_HEADER_MAGIC};
 
+mod direct;
+pub use direct::DirectFile;
+
+/// The default limit on how many levels of images `create_disk_file` opens for a disk, such as a
+/// chain of qcow backing files.
+pub const MAX_NESTING_DEPTH: u32 = 10;
+
 #[sorted]
 #[derive(Debug)]
 pub enum Error {
@@ -38,8 +49,11 @@ pub enum Error {
     #[cfg(feature = "composite-disk")]
     CreateCompositeDisk(composite::Error),
     CreateSingleFileDisk(cros_async::AsyncError),
+    DirectIo(io::Error),
+    DirectIoNotRaw,
     Fallocate(cros_async::AsyncError),
     Fsync(cros_async::AsyncError),
+    MaxNestingDepthExceeded,
     QcowError(qcow::Error),
     ReadingData(io::Error),
     ReadingHeader(io::Error),
@@ -70,11 +84,28 @@ impl DiskGetLen for File {
     }
 }
 
+/// A trait for changing the size of a disk image while it is in use.
+pub trait DiskResize {
+    /// Changes the size of the disk to `new_size` bytes. Shrinking fails with `EINVAL` if the
+    /// disk may hold data past `new_size`, so no data is ever dropped.
+    fn resize(&mut self, new_size: u64) -> io::Result<()>;
+}
+
+impl DiskResize for File {
+    fn resize(&mut self, new_size: u64) -> io::Result<()> {
+        if new_size < self.get_len()? && self.seek_data(new_size)?.is_some() {
+            return Err(io::Error::from_raw_os_error(EINVAL));
+        }
+        self.set_len(new_size)
+    }
+}
+
 /// The prerequisites necessary to support a block device.
 #[rustfmt::skip] // rustfmt won't wrap the long list of trait bounds.
 pub trait DiskFile:
     FileSetLen
     + DiskGetLen
+    + DiskResize
     + FileSync
     + FileReadWriteAtVolatile
     + PunchHole
@@ -88,6 +119,7 @@ pub trait DiskFile:
 impl<
         D: FileSetLen
             + DiskGetLen
+            + DiskResize
             + FileSync
             + PunchHole
             + FileReadWriteAtVolatile
@@ -129,8 +161,11 @@ impl Display for Error {
             #[cfg(feature = "composite-disk")]
             CreateCompositeDisk(e) => write!(f, "failure in composite disk: {}", e),
             CreateSingleFileDisk(e) => write!(f, "failure creating single file disk: {}", e),
+            DirectIo(e) => write!(f, "failed to set up direct I/O: {}", e),
+            DirectIoNotRaw => write!(f, "direct I/O is only supported on raw images"),
             Fallocate(e) => write!(f, "failure with fallocate: {}", e),
             Fsync(e) => write!(f, "failure with fsync: {}", e),
+            MaxNestingDepthExceeded => write!(f, "maximum disk nesting depth exceeded"),
             QcowError(e) => write!(f, "failure in qcow: {}", e),
             ReadingData(e) => write!(f, "failed to read data: {}", e),
             ReadingHeader(e) => write!(f, "failed to read header: {}", e),
@@ -145,6 +180,38 @@ impl Display for Error {
     }
 }
 
+/// How the host caches the data of a disk image.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Writes go to the host page cache, and reach storage when the guest flushes them with an
+    /// `fsync`.
+    Writeback,
+    /// Writes go through the host page cache, and reach storage with an `fdatasync` before they
+    /// complete.
+    Writethrough,
+    /// Reads and writes bypass the host page cache with `O_DIRECT`, and writes reach storage with
+    /// an `fdatasync` before they complete. Only raw images can be accessed this way.
+    DirectSync,
+}
+
+impl CacheMode {
+    /// Returns true if the image is accessed with `O_DIRECT`; see `DirectFile`.
+    pub fn direct_io(self) -> bool {
+        self == CacheMode::DirectSync
+    }
+
+    /// Returns true if each write is synced to storage before it completes.
+    pub fn write_through(self) -> bool {
+        self != CacheMode::Writeback
+    }
+}
+
+impl Default for CacheMode {
+    fn default() -> CacheMode {
+        CacheMode::Writeback
+    }
+}
+
 /// The variants of image files on the host that can be used as virtual disks.
 #[derive(Debug, PartialEq, Eq)]
 pub enum ImageType {
@@ -173,7 +240,17 @@ where
         let nread = reader
             .read(&mut buf[..this_count])
             .map_err(Error::ReadingData)?;
-        writer.write(&buf[..nread]).map_err(Error::WritingData)?;
+        // The destination starts out sparse, so runs of zeroes in allocated source ranges are
+        // skipped rather than written out.
+        if buf[..nread].iter().all(|&b| b == 0) {
+            writer
+                .seek(SeekFrom::Current(nread as i64))
+                .map_err(Error::SeekingFile)?;
+        } else {
+            writer
+                .write_all(&buf[..nread])
+                .map_err(Error::WritingData)?;
+        }
         read_count += nread as u64;
         if nread == 0 || read_count == size {
             break;
@@ -317,18 +394,39 @@ pub fn create_async_disk_file(raw_image: File) -> Result<Box<dyn ToAsyncDisk>> {
 }
 
 /// Inspect the image file type and create an appropriate disk file to match it.
-pub fn create_disk_file(raw_image: File) -> Result<Box<dyn DiskFile>> {
+///
+/// Images can refer to other images, like the backing file of a qcow image or the components of a
+/// composite disk. `max_nesting_depth` limits how many levels of images are opened, including
+/// `raw_image` itself, so that a chain that loops back on itself can't be opened forever.
+pub fn create_disk_file(raw_image: File, max_nesting_depth: u32) -> Result<Box<dyn DiskFile>> {
+    create_disk_file_with_cache_size(raw_image, max_nesting_depth, QcowCacheSize::default())
+}
+
+/// Like `create_disk_file`, but a qcow image and its backing files keep up to `qcow_cache_size`
+/// of their tables in memory.
+pub fn create_disk_file_with_cache_size(
+    raw_image: File,
+    max_nesting_depth: u32,
+    qcow_cache_size: QcowCacheSize,
+) -> Result<Box<dyn DiskFile>> {
+    if max_nesting_depth == 0 {
+        return Err(Error::MaxNestingDepthExceeded);
+    }
+    let max_nesting_depth = max_nesting_depth - 1;
     let image_type = detect_image_type(&raw_image)?;
     Ok(match image_type {
         ImageType::Raw => Box::new(raw_image) as Box<dyn DiskFile>,
-        ImageType::Qcow2 => {
-            Box::new(QcowFile::from(raw_image).map_err(Error::QcowError)?) as Box<dyn DiskFile>
-        }
+        ImageType::Qcow2 => Box::new(
+            QcowFile::from_with_cache_size(raw_image, max_nesting_depth, qcow_cache_size)
+                .map_err(Error::QcowError)?,
+        ) as Box<dyn DiskFile>,
         #[cfg(feature = "composite-disk")]
         ImageType::CompositeDisk => {
             // Valid composite disk header present
-            Box::new(CompositeDiskFile::from_file(raw_image).map_err(Error::CreateCompositeDisk)?)
-                as Box<dyn DiskFile>
+            Box::new(
+                CompositeDiskFile::from_file(raw_image, max_nesting_depth)
+                    .map_err(Error::CreateCompositeDisk)?,
+            ) as Box<dyn DiskFile>
         }
         #[cfg(not(feature = "composite-disk"))]
         ImageType::CompositeDisk => return Err(Error::UnknownType),
@@ -372,7 +470,7 @@ pub trait AsyncDisk: DiskGetLen + FileSetLen + FileAllocate {
     async fn write_zeroes_at(&self, file_offset: u64, length: u64) -> Result<()>;
 }
 
-use cros_async::IoSourceExt;
+use cros_async::{IoPriority, IoSourceExt};
 
 /// A disk backed by a single file that implements `AsyncDisk` for access.
 pub struct SingleFileDisk {
@@ -382,9 +480,13 @@ pub struct SingleFileDisk {
 impl TryFrom<File> for SingleFileDisk {
     type Error = Error;
     fn try_from(inner: File) -> Result<Self> {
+        // Disk transfers are bulk I/O and yield to more latency-sensitive sources.
         cros_async::async_from(inner)
             .map_err(Error::CreateSingleFileDisk)
-            .map(|inner| SingleFileDisk { inner })
+            .map(|mut inner| {
+                inner.set_priority(IoPriority::Low);
+                SingleFileDisk { inner }
+            })
     }
 }
 
@@ -491,6 +593,7 @@ mod tests {
 
     use cros_async::MemRegion;
     use futures::pin_mut;
+    use tempfile::tempfile;
     use vm_memory::{GuestAddress, GuestMemory};
 
     #[test]
@@ -534,4 +637,29 @@ mod tests {
         pin_mut!(fut);
         cros_async::run_one(fut).unwrap();
     }
+
+    #[test]
+    fn convert_round_trip_sparse() {
+        const SIZE: u64 = 4 * 1024 * 1024;
+        let mut raw = tempfile().unwrap();
+        raw.set_len(SIZE).unwrap();
+        raw.write_all(&[0x55; 4096]).unwrap();
+        // Allocated but zero-filled, so it should come out as a hole.
+        raw.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+        raw.write_all(&[0; 65536]).unwrap();
+
+        let qcow = tempfile().unwrap();
+        convert(raw, qcow.try_clone().unwrap(), ImageType::Qcow2).unwrap();
+        assert_eq!(detect_image_type(&qcow).unwrap(), ImageType::Qcow2);
+
+        let mut out = tempfile().unwrap();
+        convert(qcow, out.try_clone().unwrap(), ImageType::Raw).unwrap();
+        assert_eq!(out.seek(SeekFrom::End(0)).unwrap(), SIZE);
+
+        let mut buf = [0u8; 4096];
+        out.seek(SeekFrom::Start(0)).unwrap();
+        out.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0x55));
+        assert_eq!(out.seek_data(65536).unwrap(), None);
+    }
 }
@@ -0,0 +1,193 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Generation of the GUID partition table of a composite disk, described in chapter 5 of the UEFI
+//! specification.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+pub const SECTOR_SIZE: u64 = 512;
+
+/// The number of partition entries in the table, the minimum the specification allows.
+pub const MAX_PARTITIONS: usize = 128;
+const PARTITION_ENTRY_SIZE: usize = 128;
+const PARTITION_ENTRIES_SECTORS: u64 = (MAX_PARTITIONS * PARTITION_ENTRY_SIZE) as u64 / SECTOR_SIZE;
+/// The maximum length of a partition name, in UTF-16 code units.
+pub const MAX_PARTITION_NAME_LEN: usize = 36;
+
+/// The size of the protective MBR, the primary GPT header and the partition entries at the start
+/// of the disk.
+pub const GPT_BEGINNING_SIZE: u64 = SECTOR_SIZE * (2 + PARTITION_ENTRIES_SECTORS);
+/// The size of the partition entries and the backup GPT header at the end of the disk.
+pub const GPT_END_SIZE: u64 = SECTOR_SIZE * (PARTITION_ENTRIES_SECTORS + 1);
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_REVISION: u32 = 0x0001_0000;
+const GPT_HEADER_SIZE: u32 = 92;
+
+/// A GUID as stored on disk, with its first three fields little endian.
+pub type Guid = [u8; 16];
+
+/// The partition type GUID of Linux filesystem data, 0FC63DAF-8483-4772-8E79-3D69D8477DE4.
+pub const LINUX_FILESYSTEM_GUID: Guid = [
+    0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4,
+];
+
+/// Returns a random (version 4) GUID.
+pub fn random_guid() -> io::Result<Guid> {
+    let mut guid = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut guid)?;
+    // The version is in the high bits of the little endian third field, and the variant in the
+    // high bits of the fourth.
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+    Ok(guid)
+}
+
+// The CRC32 used by GPT headers, the same as zlib's.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// An entry of the partition table.
+pub struct PartitionEntry {
+    pub partition_type_guid: Guid,
+    pub unique_partition_guid: Guid,
+    pub first_lba: u64,
+    /// The last sector of the partition, inclusive.
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub partition_name: Vec<u16>,
+}
+
+impl PartitionEntry {
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(&self.partition_type_guid);
+        out.extend_from_slice(&self.unique_partition_guid);
+        out.extend_from_slice(&self.first_lba.to_le_bytes());
+        out.extend_from_slice(&self.last_lba.to_le_bytes());
+        out.extend_from_slice(&self.attributes.to_le_bytes());
+        for unit in self.partition_name.iter().take(MAX_PARTITION_NAME_LEN) {
+            out.extend_from_slice(&unit.to_le_bytes());
+        }
+        out.resize(start + PARTITION_ENTRY_SIZE, 0);
+    }
+}
+
+/// Returns the partition table holding `partitions`, including the unused entries.
+pub fn partition_entries(partitions: &[PartitionEntry]) -> Vec<u8> {
+    let mut entries = Vec::with_capacity(MAX_PARTITIONS * PARTITION_ENTRY_SIZE);
+    for partition in partitions {
+        partition.write_bytes(&mut entries);
+    }
+    entries.resize(MAX_PARTITIONS * PARTITION_ENTRY_SIZE, 0);
+    entries
+}
+
+/// Writes a protective MBR for a disk of `disk_size` bytes, which makes the whole disk look like
+/// it's in use to tools that don't know about GPT.
+pub fn write_protective_mbr(file: &mut impl Write, disk_size: u64) -> io::Result<()> {
+    let mut mbr = [0u8; SECTOR_SIZE as usize];
+    // The only partition record covers the disk, or as much of it as fits, after the MBR.
+    let record = &mut mbr[446..462];
+    record[1..4].copy_from_slice(&[0x00, 0x02, 0x00]); // CHS of LBA 1
+    record[4] = 0xee; // GPT protective
+    record[5..8].copy_from_slice(&[0xff, 0xff, 0xff]); // CHS past what it can hold
+    record[8..12].copy_from_slice(&1u32.to_le_bytes());
+    let size_in_lba = (disk_size / SECTOR_SIZE - 1).min(u64::from(u32::max_value())) as u32;
+    record[12..16].copy_from_slice(&size_in_lba.to_le_bytes());
+    mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+    file.write_all(&mbr)
+}
+
+/// A GPT header. There is a primary one after the MBR and a backup at the end of the disk.
+pub struct GptHeader {
+    pub current_lba: u64,
+    pub backup_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: Guid,
+    pub partition_entries_lba: u64,
+}
+
+impl GptHeader {
+    /// Writes the header, one sector long, for the partition table `entries`.
+    pub fn write(&self, file: &mut impl Write, entries: &[u8]) -> io::Result<()> {
+        let mut header = Vec::with_capacity(SECTOR_SIZE as usize);
+        header.extend_from_slice(GPT_SIGNATURE);
+        header.extend_from_slice(&GPT_REVISION.to_le_bytes());
+        header.extend_from_slice(&GPT_HEADER_SIZE.to_le_bytes());
+        header.extend_from_slice(&[0u8; 4]); // header CRC32, computed below
+        header.extend_from_slice(&[0u8; 4]); // reserved
+        header.extend_from_slice(&self.current_lba.to_le_bytes());
+        header.extend_from_slice(&self.backup_lba.to_le_bytes());
+        header.extend_from_slice(&self.first_usable_lba.to_le_bytes());
+        header.extend_from_slice(&self.last_usable_lba.to_le_bytes());
+        header.extend_from_slice(&self.disk_guid);
+        header.extend_from_slice(&self.partition_entries_lba.to_le_bytes());
+        header.extend_from_slice(&(MAX_PARTITIONS as u32).to_le_bytes());
+        header.extend_from_slice(&(PARTITION_ENTRY_SIZE as u32).to_le_bytes());
+        header.extend_from_slice(&crc32(entries).to_le_bytes());
+        let header_crc = crc32(&header);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        header.resize(SECTOR_SIZE as usize, 0);
+        file.write_all(&header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn header_layout() {
+        let entries = partition_entries(&[PartitionEntry {
+            partition_type_guid: LINUX_FILESYSTEM_GUID,
+            unique_partition_guid: [1; 16],
+            first_lba: 40,
+            last_lba: 47,
+            attributes: 0,
+            partition_name: "a".encode_utf16().collect(),
+        }]);
+        assert_eq!(
+            entries.len() as u64,
+            PARTITION_ENTRIES_SECTORS * SECTOR_SIZE
+        );
+        assert_eq!(&entries[32..40], &40u64.to_le_bytes());
+        assert_eq!(&entries[56..58], &[b'a', 0]);
+
+        let mut header = Vec::new();
+        GptHeader {
+            current_lba: 1,
+            backup_lba: 99,
+            first_usable_lba: 34,
+            last_usable_lba: 66,
+            disk_guid: [2; 16],
+            partition_entries_lba: 2,
+        }
+        .write(&mut header, &entries)
+        .unwrap();
+        assert_eq!(header.len() as u64, SECTOR_SIZE);
+        assert_eq!(&header[0..8], GPT_SIGNATURE);
+        // The header CRC covers the header with the CRC field zeroed.
+        let crc = u32::from_le_bytes([header[16], header[17], header[18], header[19]]);
+        header[16..20].copy_from_slice(&[0; 4]);
+        assert_eq!(crc, crc32(&header[..GPT_HEADER_SIZE as usize]));
+    }
+}
@@ -0,0 +1,378 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fmt;
+use std::fs::File;
+use std::mem::size_of;
+
+use base::warn;
+
+use crate::qcow::compression::{is_compressed, CompressedCluster};
+use crate::qcow::qcow_raw_file::QcowRawFile;
+use crate::qcow::snapshot::read_snapshot_table;
+use crate::qcow::{
+    div_round_up_u64, Error, QcowFile, QcowHeader, Result, L1_TABLE_OFFSET_MASK,
+    L2_TABLE_OFFSET_MASK, MAX_CLUSTER_BITS, MAX_RAM_POINTER_TABLE_SIZE, MIN_CLUSTER_BITS,
+};
+
+/// Problems found by `QcowFile::check`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QcowCheckResult {
+    /// L1 or L2 table entries that point outside the file, are misaligned, or point to a cluster
+    /// that is already in use by other metadata. These can't be repaired.
+    pub corruptions: u64,
+    /// Clusters in use whose refcount is wrong.
+    pub refcount_errors: u64,
+    /// Clusters with a non-zero refcount that nothing refers to. These only waste space, and
+    /// some are left behind in normal operation when refcount blocks are replaced.
+    pub leaked_clusters: u64,
+    /// True if the refcount errors and leaked clusters were repaired.
+    pub repaired: bool,
+}
+
+impl QcowCheckResult {
+    /// Returns true if no problems were found.
+    pub fn is_clean(&self) -> bool {
+        self.corruptions == 0 && self.refcount_errors == 0 && self.leaked_clusters == 0
+    }
+}
+
+// The number of references to each cluster of the file found by walking its metadata.
+struct ClusterRefs {
+    refs: Vec<u16>,
+    // Clusters that may be shared between the image and its snapshots: L2 tables and data.
+    shared: Vec<bool>,
+    cluster_size: u64,
+    corruptions: u64,
+}
+
+impl ClusterRefs {
+    // Adds a reference from `what` to the cluster at `address`. Returns false, after counting the
+    // corruption, if the reference is invalid.
+    fn add(&mut self, address: u64, what: fmt::Arguments) -> bool {
+        self.add_ref(address, what, false)
+    }
+
+    // Like `add`, for clusters that are reference counted once for each L1 table that leads to
+    // them.
+    fn add_shared(&mut self, address: u64, what: fmt::Arguments) -> bool {
+        self.add_ref(address, what, true)
+    }
+
+    fn add_ref(&mut self, address: u64, what: fmt::Arguments, shared: bool) -> bool {
+        let index = address / self.cluster_size;
+        if address % self.cluster_size != 0 || index >= self.refs.len() as u64 {
+            warn!("{} points to invalid offset {:#x}", what, address);
+            self.corruptions += 1;
+            return false;
+        }
+        let index = index as usize;
+        let refcount = &mut self.refs[index];
+        *refcount = refcount.saturating_add(1);
+        if *refcount > 1 && !(shared && self.shared[index]) {
+            warn!(
+                "{} points to cluster {:#x} which is already in use",
+                what, address
+            );
+            self.corruptions += 1;
+            return false;
+        }
+        self.shared[index] = shared;
+        true
+    }
+
+    // Adds references to the clusters of the L1 table of `owner` and to the L2 tables and data
+    // clusters it leads to.
+    fn add_l1_table(
+        &mut self,
+        raw_file: &mut QcowRawFile,
+        l1_table_offset: u64,
+        l1_size: u32,
+        owner: &str,
+    ) -> Result<()> {
+        let l1_clusters = div_round_up_u64(
+            u64::from(l1_size) * size_of::<u64>() as u64,
+            self.cluster_size,
+        );
+        for i in 0..l1_clusters {
+            self.add(
+                l1_table_offset + i * self.cluster_size,
+                format_args!("L1 table of {}", owner),
+            );
+        }
+
+        let l1_table = raw_file
+            .read_pointer_table(
+                l1_table_offset,
+                u64::from(l1_size),
+                Some(L1_TABLE_OFFSET_MASK),
+            )
+            .map_err(Error::ReadingPointers)?;
+        for (l1_index, &l2_addr) in l1_table.iter().enumerate() {
+            if l2_addr == 0
+                || !self.add_shared(l2_addr, format_args!("L1 entry {} of {}", l1_index, owner))
+            {
+                continue;
+            }
+            let l2_table = raw_file
+                .read_pointer_cluster(l2_addr, None)
+                .map_err(Error::ReadingPointers)?;
+            for (l2_index, &entry) in l2_table.iter().enumerate() {
+                if is_compressed(entry) {
+                    // Compressed clusters share host clusters, so those are counted as shared.
+                    let compressed =
+                        CompressedCluster::from_l2_entry(entry, self.cluster_size.trailing_zeros());
+                    for host_cluster in compressed.host_clusters(self.cluster_size) {
+                        self.add_shared(
+                            host_cluster,
+                            format_args!(
+                                "compressed L2 entry {} of L1 entry {} of {}",
+                                l2_index, l1_index, owner
+                            ),
+                        );
+                    }
+                    continue;
+                }
+                let data_addr = entry & L2_TABLE_OFFSET_MASK;
+                if data_addr != 0 {
+                    self.add_shared(
+                        data_addr,
+                        format_args!(
+                            "L2 entry {} of L1 entry {} of {}",
+                            l2_index, l1_index, owner
+                        ),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl QcowFile {
+    /// Checks the metadata of the qcow2 image in `file` for consistency, without trusting the
+    /// refcounts as `QcowFile::from` does. Every cluster reachable from the header, the L1 and L2
+    /// tables, the snapshot table and the refcount table must have a refcount equal to the number
+    /// of references to it, and every other cluster must have a refcount of zero. Only L2 tables
+    /// and data clusters may be referenced more than once, by the image and its snapshots.
+    ///
+    /// If `repair` is true and the only problems are refcount errors or leaked clusters, the
+    /// refcounts are rebuilt from the L1 and L2 tables and leaked clusters at the end of the file
+    /// are truncated. Corrupt L1 or L2 tables are never modified.
+    pub fn check(mut file: File, repair: bool) -> Result<QcowCheckResult> {
+        let header = QcowHeader::new(&mut file)?;
+        if header.version != 3 {
+            return Err(Error::UnsupportedVersion(header.version));
+        }
+        if header.cluster_bits < MIN_CLUSTER_BITS || header.cluster_bits > MAX_CLUSTER_BITS {
+            return Err(Error::InvalidClusterSize);
+        }
+        if header.refcount_order != 4 {
+            return Err(Error::UnsupportedRefcountOrder);
+        }
+        if u64::from(header.l1_size) > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(Error::InvalidL1TableSize(header.l1_size));
+        }
+        let cluster_size = 0x01u64 << header.cluster_bits;
+        let pointers_per_cluster = cluster_size / size_of::<u64>() as u64;
+        let refcount_table_entries =
+            u64::from(header.refcount_table_clusters) * pointers_per_cluster;
+        if refcount_table_entries > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(Error::RefcountTableTooLarge);
+        }
+
+        let file_size = file.metadata().map_err(Error::GettingFileSize)?.len();
+        let file_clusters = div_round_up_u64(file_size, cluster_size);
+        if file_clusters > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(Error::FileTooBig(file_size));
+        }
+        let mut raw_file =
+            QcowRawFile::from(file, cluster_size).ok_or(Error::InvalidClusterSize)?;
+
+        let mut refs = ClusterRefs {
+            refs: vec![0; file_clusters as usize],
+            shared: vec![false; file_clusters as usize],
+            cluster_size,
+            corruptions: 0,
+        };
+
+        refs.add(0, format_args!("header"));
+        for i in 0..u64::from(header.refcount_table_clusters) {
+            refs.add(
+                header.refcount_table_offset + i * cluster_size,
+                format_args!("refcount table"),
+            );
+        }
+
+        refs.add_l1_table(
+            &mut raw_file,
+            header.l1_table_offset,
+            header.l1_size,
+            "the image",
+        )?;
+
+        let (snapshots, snapshot_table_size) =
+            read_snapshot_table(raw_file.file(), &header).map_err(Error::ReadingSnapshots)?;
+        for i in 0..div_round_up_u64(snapshot_table_size, cluster_size) {
+            refs.add(
+                header.snapshots_offset + i * cluster_size,
+                format_args!("snapshot table"),
+            );
+        }
+        for snapshot in &snapshots {
+            refs.add_l1_table(
+                &mut raw_file,
+                snapshot.l1_table_offset,
+                snapshot.l1_size,
+                &format!("snapshot {}", snapshot.name),
+            )?;
+        }
+
+        let refcount_block_entries = cluster_size / size_of::<u16>() as u64;
+        let ref_table = raw_file
+            .read_pointer_table(header.refcount_table_offset, refcount_table_entries, None)
+            .map_err(Error::ReadingPointers)?;
+        let mut refcounts = vec![0u16; file_clusters as usize];
+        for (i, &refblock_addr) in ref_table.iter().enumerate() {
+            if refblock_addr == 0
+                || !refs.add(refblock_addr, format_args!("refcount table entry {}", i))
+            {
+                continue;
+            }
+            let refblock = raw_file
+                .read_refcount_block(refblock_addr)
+                .map_err(Error::ReadingRefCounts)?;
+            let first_cluster = i as u64 * refcount_block_entries;
+            for (cluster, &refcount) in (first_cluster..file_clusters).zip(refblock.iter()) {
+                refcounts[cluster as usize] = refcount;
+            }
+        }
+
+        let mut result = QcowCheckResult {
+            corruptions: refs.corruptions,
+            ..Default::default()
+        };
+        for (cluster, (&expected, &actual)) in refs.refs.iter().zip(refcounts.iter()).enumerate() {
+            let address = cluster as u64 * cluster_size;
+            if expected == 0 && actual != 0 {
+                warn!("leaked cluster {:#x} has refcount {}", address, actual);
+                result.leaked_clusters += 1;
+            } else if expected != actual {
+                warn!("cluster {:#x} in use has refcount {}", address, actual);
+                result.refcount_errors += 1;
+            }
+        }
+
+        if repair && result.corruptions == 0 && !result.is_clean() {
+            // Drop leaked clusters past the last cluster in use, which the rebuilt refcounts
+            // would otherwise not cover.
+            let end_cluster = refs.refs.iter().rposition(|&r| r != 0).map_or(0, |c| c + 1);
+            raw_file
+                .file_mut()
+                .set_len(end_cluster as u64 * cluster_size)
+                .map_err(Error::RebuildingRefCounts)?;
+            QcowFile::rebuild_refcounts(&mut raw_file, header)?;
+            raw_file
+                .file_mut()
+                .sync_all()
+                .map_err(Error::RebuildingRefCounts)?;
+            result.repaired = true;
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use tempfile::tempfile;
+
+    // Creates an image with one data cluster written and returns the file and the offset of the
+    // L2 entry pointing to the data cluster.
+    fn image_with_data() -> (File, u64) {
+        let file = tempfile().unwrap();
+        let mut qcow = QcowFile::new(file.try_clone().unwrap(), 0x10_0000).unwrap();
+        qcow.write_all(&[0x55u8; 512]).unwrap();
+        let l2_addr = qcow.l1_table()[0];
+        drop(qcow);
+        (file, l2_addr)
+    }
+
+    #[test]
+    fn check_written_image() {
+        let (file, _) = image_with_data();
+        let result = QcowFile::check(file, false).unwrap();
+        assert_eq!(result.corruptions, 0);
+        assert_eq!(result.refcount_errors, 0);
+        assert!(!result.repaired);
+    }
+
+    #[test]
+    fn check_and_repair_leak() {
+        let (mut file, l2_addr) = image_with_data();
+        let leaked = QcowFile::check(file.try_clone().unwrap(), false)
+            .unwrap()
+            .leaked_clusters;
+
+        // Drop the reference to the data cluster, leaking it.
+        file.seek(SeekFrom::Start(l2_addr)).unwrap();
+        file.write_all(&[0u8; 8]).unwrap();
+
+        let result = QcowFile::check(file.try_clone().unwrap(), false).unwrap();
+        assert_eq!(result.leaked_clusters, leaked + 1);
+        assert_eq!(result.corruptions, 0);
+        assert_eq!(result.refcount_errors, 0);
+
+        let result = QcowFile::check(file.try_clone().unwrap(), true).unwrap();
+        assert!(result.repaired);
+        assert!(QcowFile::check(file.try_clone().unwrap(), false)
+            .unwrap()
+            .is_clean());
+
+        // The image is still usable and reads back zeros where the data was.
+        let mut qcow = QcowFile::from(file).unwrap();
+        let mut buf = [0xffu8; 512];
+        qcow.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn check_and_repair_refcount_error() {
+        let (mut file, _) = image_with_data();
+
+        // Clear the refcount of the header cluster, the first entry of the first refcount block.
+        let header = QcowHeader::new(&mut file).unwrap();
+        let mut refblock_addr = [0u8; 8];
+        file.seek(SeekFrom::Start(header.refcount_table_offset))
+            .unwrap();
+        file.read_exact(&mut refblock_addr).unwrap();
+        file.seek(SeekFrom::Start(u64::from_be_bytes(refblock_addr)))
+            .unwrap();
+        file.write_all(&[0u8; 2]).unwrap();
+
+        let result = QcowFile::check(file.try_clone().unwrap(), false).unwrap();
+        assert_eq!(result.refcount_errors, 1);
+        assert_eq!(result.corruptions, 0);
+
+        let result = QcowFile::check(file.try_clone().unwrap(), true).unwrap();
+        assert!(result.repaired);
+        assert!(QcowFile::check(file, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn check_invalid_l2_entry() {
+        let (mut file, l2_addr) = image_with_data();
+
+        // Point the data cluster past the end of the file.
+        file.seek(SeekFrom::Start(l2_addr)).unwrap();
+        file.write_all(&(0x1000_0000u64 | (1 << 63)).to_be_bytes())
+            .unwrap();
+
+        let result = QcowFile::check(file.try_clone().unwrap(), true).unwrap();
+        assert_eq!(result.corruptions, 1);
+        assert!(!result.repaired);
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Reading of compressed clusters, as written by `qemu-img convert -c`.
+//!
+//! The L2 entry of a compressed cluster holds the byte offset of its compressed data in the file
+//! and the number of 512 byte sectors the data spans. Several compressed clusters may share a
+//! host cluster, which is reference counted once for each of them.
+
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::FileExt;
+
+use flate2::{Decompress, FlushDecompress};
+
+use crate::qcow::{Error, Result, COMPRESSED_FLAG};
+
+// Value of the header's `compression_type` field for deflate, the only compression supported.
+pub const COMPRESSION_TYPE_ZLIB: u8 = 0;
+
+const COMPRESSED_SECTOR_SIZE: u64 = 512;
+
+/// Checks that clusters compressed with `compression_type` can be read.
+pub fn check_compression_type(compression_type: u8) -> Result<()> {
+    match compression_type {
+        COMPRESSION_TYPE_ZLIB => Ok(()),
+        t => Err(Error::UnsupportedCompressionType(t)),
+    }
+}
+
+/// Returns true if the L2 table `entry` describes a compressed cluster.
+pub fn is_compressed(entry: u64) -> bool {
+    entry & COMPRESSED_FLAG != 0
+}
+
+/// The location of the data of a compressed cluster in the file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompressedCluster {
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl CompressedCluster {
+    /// Decodes the L2 table `entry` of a compressed cluster of an image with `cluster_bits`.
+    pub fn from_l2_entry(entry: u64, cluster_bits: u32) -> CompressedCluster {
+        let offset_bits = 62 - (cluster_bits - 8);
+        let offset = entry & ((1 << offset_bits) - 1);
+        let additional_sectors = (entry & ((1 << 62) - 1)) >> offset_bits;
+        // The data ends with the last of the sectors following the one it starts in.
+        let end = (offset & !(COMPRESSED_SECTOR_SIZE - 1))
+            + (additional_sectors + 1) * COMPRESSED_SECTOR_SIZE;
+        CompressedCluster {
+            offset,
+            size: end - offset,
+        }
+    }
+
+    /// Returns the offsets of the host clusters that hold the compressed data.
+    pub fn host_clusters(&self, cluster_size: u64) -> impl Iterator<Item = u64> {
+        let first = self.offset / cluster_size;
+        let last = (self.offset + self.size - 1) / cluster_size;
+        (first..=last).map(move |i| i * cluster_size)
+    }
+
+    /// Reads the compressed data from `file` and decompresses it into `cluster`.
+    pub fn read(&self, file: &File, compression_type: u8, cluster: &mut [u8]) -> io::Result<()> {
+        let mut compressed = vec![0u8; self.size as usize];
+        // The sector count is rounded up, so the data of the last cluster of the file may end
+        // before its last sector does.
+        let mut nread = 0;
+        while nread < compressed.len() {
+            match file.read_at(&mut compressed[nread..], self.offset + nread as u64) {
+                Ok(0) => break,
+                Ok(n) => nread += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        compressed.truncate(nread);
+
+        match compression_type {
+            COMPRESSION_TYPE_ZLIB => inflate(&compressed, cluster),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "unsupported compression type",
+            )),
+        }
+    }
+}
+
+// Decompresses raw deflate data, without a zlib header, that must fill `cluster`.
+fn inflate(compressed: &[u8], cluster: &mut [u8]) -> io::Result<()> {
+    let mut decompress = Decompress::new(false);
+    decompress
+        .decompress(compressed, cluster, FlushDecompress::Finish)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    if decompress.total_out() != cluster.len() as u64 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "compressed cluster is too short",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_l2_entry() {
+        // 64k clusters leave 54 bits for the offset.
+        let entry = COMPRESSED_FLAG | (2 << 54) | 0x1_0300;
+        let cluster = CompressedCluster::from_l2_entry(entry, 16);
+        assert_eq!(
+            cluster,
+            CompressedCluster {
+                offset: 0x1_0300,
+                size: 0x1_0800 - 0x1_0300,
+            }
+        );
+        assert_eq!(
+            cluster.host_clusters(0x1_0000).collect::<Vec<_>>(),
+            vec![0x1_0000]
+        );
+    }
+
+    #[test]
+    fn host_clusters_span() {
+        let cluster = CompressedCluster {
+            offset: 0xff00,
+            size: 0x200,
+        };
+        assert_eq!(
+            cluster.host_clusters(0x1_0000).collect::<Vec<_>>(),
+            vec![0, 0x1_0000]
+        );
+    }
+}
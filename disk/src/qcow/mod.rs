@@ -2,8 +2,11 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod check;
+mod compression;
 mod qcow_raw_file;
 mod refcount;
+mod snapshot;
 mod vec_cache;
 
 use base::{
@@ -11,28 +14,38 @@ use base::{
     FileReadWriteVolatile, FileSetLen, FileSync, PunchHole, RawDescriptor, SeekHole, WriteZeroesAt,
 };
 use data_model::{VolatileMemory, VolatileSlice};
-use libc::{EINVAL, ENOSPC, ENOTSUP};
+use libc::{EINVAL, ENOSPC};
 use remain::sorted;
 
 use std::cmp::{max, min};
+use std::convert::TryFrom;
 use std::fmt::{self, Display};
 use std::fs::{File, OpenOptions};
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
+use std::os::unix::fs::FileExt;
 use std::str;
 
+pub use crate::qcow::check::QcowCheckResult;
+use crate::qcow::compression::{check_compression_type, is_compressed, CompressedCluster};
 use crate::qcow::qcow_raw_file::QcowRawFile;
 use crate::qcow::refcount::RefCount;
+pub use crate::qcow::snapshot::QcowSnapshot;
 use crate::qcow::vec_cache::{CacheMap, Cacheable, VecCache};
-use crate::{create_disk_file, DiskFile, DiskGetLen};
+use crate::{
+    create_disk_file, create_disk_file_with_cache_size, DiskFile, DiskGetLen, DiskResize,
+    MAX_NESTING_DEPTH,
+};
 
 #[sorted]
 #[derive(Debug)]
 pub enum Error {
+    ApplyingSnapshot(io::Error),
     BackingFileIo(io::Error),
     BackingFileOpen(Box<crate::Error>),
     BackingFileTooLong(usize),
-    CompressedBlocksNotSupported,
+    CreatingSnapshot(io::Error),
+    DeletingSnapshot(io::Error),
     EvictingCache(io::Error),
     FileTooBig(u64),
     GettingFileSize(io::Error),
@@ -49,20 +62,27 @@ pub enum Error {
     InvalidRefcountTableSize(u64),
     NoFreeClusters,
     NoRefcountClusters,
+    NoSuchSnapshot(String),
     NotEnoughSpaceForRefcounts,
     OpeningFile(io::Error),
     ReadingHeader(io::Error),
     ReadingPointers(io::Error),
     ReadingRefCountBlock(refcount::Error),
     ReadingRefCounts(io::Error),
+    ReadingSnapshots(io::Error),
     RebuildingRefCounts(io::Error),
     RefcountTableOffEnd,
     RefcountTableTooLarge,
     SeekingFile(io::Error),
     SettingRefcountRefcount(io::Error),
     SizeTooSmallForNumberOfClusters,
+    SnapshotExists(String),
+    SnapshotL1TableTooLarge(u32),
+    SnapshotNameTooLong(usize),
     TooManyL1Entries(u64),
     TooManyRefcounts(u64),
+    TooManySnapshots,
+    UnsupportedCompressionType(u8),
     UnsupportedRefcountOrder,
     UnsupportedVersion(u32),
     WritingHeader(io::Error),
@@ -77,12 +97,14 @@ impl Display for Error {
 
         #[sorted]
         match self {
+            ApplyingSnapshot(e) => write!(f, "failed to apply snapshot: {}", e),
             BackingFileIo(e) => write!(f, "backing file io error: {}", e),
             BackingFileOpen(e) => write!(f, "backing file open error: {}", *e),
             BackingFileTooLong(len) => {
                 write!(f, "backing file name is too long: {} bytes over", len)
             }
-            CompressedBlocksNotSupported => write!(f, "compressed blocks not supported"),
+            CreatingSnapshot(e) => write!(f, "failed to create snapshot: {}", e),
+            DeletingSnapshot(e) => write!(f, "failed to delete snapshot: {}", e),
             EvictingCache(e) => write!(f, "failed to evict cache: {}", e),
             FileTooBig(size) => write!(
                 f,
@@ -103,20 +125,31 @@ impl Display for Error {
             InvalidRefcountTableSize(size) => write!(f, "invalid refcount table size: {}", size),
             NoFreeClusters => write!(f, "no free clusters"),
             NoRefcountClusters => write!(f, "no refcount clusters"),
+            NoSuchSnapshot(name) => write!(f, "no snapshot named {}", name),
             NotEnoughSpaceForRefcounts => write!(f, "not enough space for refcounts"),
             OpeningFile(e) => write!(f, "failed to open file: {}", e),
             ReadingHeader(e) => write!(f, "failed to read header: {}", e),
             ReadingPointers(e) => write!(f, "failed to read pointers: {}", e),
             ReadingRefCountBlock(e) => write!(f, "failed to read ref count block: {}", e),
             ReadingRefCounts(e) => write!(f, "failed to read ref counts: {}", e),
+            ReadingSnapshots(e) => write!(f, "failed to read snapshots: {}", e),
             RebuildingRefCounts(e) => write!(f, "failed to rebuild ref counts: {}", e),
             RefcountTableOffEnd => write!(f, "refcount table offset past file end"),
             RefcountTableTooLarge => write!(f, "too many clusters specified for refcount table"),
             SeekingFile(e) => write!(f, "failed to seek file: {}", e),
             SettingRefcountRefcount(e) => write!(f, "failed to set refcount refcount: {}", e),
             SizeTooSmallForNumberOfClusters => write!(f, "size too small for number of clusters"),
+            SnapshotExists(name) => write!(f, "snapshot {} already exists", name),
+            SnapshotL1TableTooLarge(size) => write!(
+                f,
+                "snapshot L1 table size {} doesn't fit in the image's L1 table",
+                size
+            ),
+            SnapshotNameTooLong(len) => write!(f, "snapshot name is too long: {} bytes", len),
             TooManyL1Entries(count) => write!(f, "l1 entry table too large: {}", count),
             TooManyRefcounts(count) => write!(f, "ref count table too large: {}", count),
+            TooManySnapshots => write!(f, "too many snapshots"),
+            UnsupportedCompressionType(t) => write!(f, "unsupported compression type: {}", t),
             UnsupportedRefcountOrder => write!(f, "unsupported refcount order"),
             UnsupportedVersion(v) => write!(f, "unsupported version: {}", v),
             WritingHeader(e) => write!(f, "failed to write header: {}", e),
@@ -143,6 +176,9 @@ const MAX_RAM_POINTER_TABLE_SIZE: u64 = 35_000_000;
 const DEFAULT_REFCOUNT_ORDER: u32 = 4;
 
 const V3_BARE_HEADER_SIZE: u32 = 104;
+// Offsets of the header fields that change when the image is resized.
+const HEADER_SIZE_OFFSET: u64 = 24;
+const HEADER_L1_SIZE_OFFSET: u64 = 36;
 
 // bits 0-8 and 56-63 are reserved.
 const L1_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
@@ -186,6 +222,8 @@ pub struct QcowHeader {
     pub autoclear_features: u64,
     pub refcount_order: u32,
     pub header_size: u32,
+    // Only present if `header_size` leaves room for it, zlib otherwise.
+    pub compression_type: u8,
 
     // Post-header entries
     pub backing_file_path: Option<String>,
@@ -247,8 +285,15 @@ impl QcowHeader {
             autoclear_features: read_u64_from_file(f)?,
             refcount_order: read_u32_from_file(f)?,
             header_size: read_u32_from_file(f)?,
+            compression_type: 0,
             backing_file_path: None,
         };
+        if header.header_size > V3_BARE_HEADER_SIZE {
+            let mut compression_type = [0u8; 1];
+            f.read_exact(&mut compression_type)
+                .map_err(Error::ReadingHeader)?;
+            header.compression_type = compression_type[0];
+        }
         if header.backing_file_size > MAX_BACKING_FILE_SIZE {
             return Err(Error::BackingFileTooLong(header.backing_file_size as usize));
         }
@@ -320,6 +365,7 @@ impl QcowHeader {
             autoclear_features: 0,
             refcount_order: DEFAULT_REFCOUNT_ORDER,
             header_size: V3_BARE_HEADER_SIZE,
+            compression_type: 0,
             backing_file_path: backing_file.map(|x| String::from(x)),
         })
     }
@@ -385,6 +431,26 @@ fn max_refcount_clusters(refcount_order: u32, cluster_size: u32, num_clusters: u
     for_data + for_refcounts
 }
 
+/// How many L2 tables and refcount blocks a `QcowFile` keeps in memory. Each is one cluster.
+///
+/// Looking up a cluster that isn't cached reads its table from the image, so a cache that covers
+/// the disk's working set avoids extra reads on random access. Tables are written back when
+/// they're evicted and when the file is synced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QcowCacheSize {
+    pub l2_tables: usize,
+    pub refcount_blocks: usize,
+}
+
+impl Default for QcowCacheSize {
+    fn default() -> Self {
+        QcowCacheSize {
+            l2_tables: 100,
+            refcount_blocks: 50,
+        }
+    }
+}
+
 /// Represents a qcow2 file. This is a sparse file format maintained by the qemu project.
 /// Full documentation of the format can be found in the qemu repository.
 ///
@@ -415,11 +481,52 @@ pub struct QcowFile {
     // removal of references to them have been synced to disk.
     avail_clusters: Vec<u64>,
     backing_file: Option<Box<dyn DiskFile>>,
+    snapshots: Vec<QcowSnapshot>,
+    snapshot_table_size: u64, // Size of the snapshot table in bytes.
+    cache_size: QcowCacheSize,
+    // The L2 entry and data of the last compressed cluster read, which is usually read in parts.
+    decompressed_cluster: Option<(u64, Vec<u8>)>,
+}
+
+// Where the data at a guest address is stored, as found by `file_offset_read`.
+enum DataLocation {
+    // The cluster isn't allocated in this file.
+    Unallocated,
+    // At the given offset of the file.
+    Offset(u64),
+    // In the compressed cluster described by the given L2 entry.
+    Compressed(u64),
+}
+
+// Where `read_cb` finds the data for part of a read.
+enum ReadSource<'a> {
+    // At the given offset of a file.
+    File(&'a mut dyn DiskFile, u64),
+    // In memory.
+    Buffer(&'a [u8]),
+    // The data reads as zeroes.
+    Zeroes,
 }
 
 impl QcowFile {
     /// Creates a QcowFile from `file`. File must be a valid qcow2 image.
-    pub fn from(mut file: File) -> Result<QcowFile> {
+    pub fn from(file: File) -> Result<QcowFile> {
+        QcowFile::from_with_nesting_depth(file, MAX_NESTING_DEPTH)
+    }
+
+    /// Creates a QcowFile from `file`, opening its chain of backing files up to
+    /// `max_nesting_depth` levels deep. File must be a valid qcow2 image.
+    pub fn from_with_nesting_depth(file: File, max_nesting_depth: u32) -> Result<QcowFile> {
+        QcowFile::from_with_cache_size(file, max_nesting_depth, QcowCacheSize::default())
+    }
+
+    /// Like `from_with_nesting_depth`, but caches up to `cache_size` tables of this file and of
+    /// each of its backing files.
+    pub fn from_with_cache_size(
+        mut file: File,
+        max_nesting_depth: u32,
+        cache_size: QcowCacheSize,
+    ) -> Result<QcowFile> {
         let header = QcowHeader::new(&mut file)?;
 
         // Only v3 files are supported.
@@ -443,14 +550,17 @@ impl QcowFile {
             return Err(Error::FileTooBig(header.size));
         }
 
+        check_compression_type(header.compression_type)?;
+
         let backing_file = if let Some(backing_file_path) = header.backing_file_path.as_ref() {
             let path = backing_file_path.clone();
             let backing_raw_file = OpenOptions::new()
                 .read(true)
                 .open(path)
                 .map_err(Error::BackingFileIo)?;
-            let backing_file = create_disk_file(backing_raw_file)
-                .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
+            let backing_file =
+                create_disk_file_with_cache_size(backing_raw_file, max_nesting_depth, cache_size)
+                    .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
             Some(backing_file)
         } else {
             None
@@ -541,22 +651,31 @@ impl QcowFile {
             refcount_clusters,
             refcount_block_entries,
             cluster_size,
+            cache_size.refcount_blocks,
         )
         .map_err(Error::ReadingRefCounts)?;
 
         let l2_entries = cluster_size / size_of::<u64>() as u64;
 
+        let (snapshots, snapshot_table_size) =
+            snapshot::read_snapshot_table(raw_file.file(), &header)
+                .map_err(Error::ReadingSnapshots)?;
+
         let mut qcow = QcowFile {
             raw_file,
             header,
             l1_table,
             l2_entries,
-            l2_cache: CacheMap::new(100),
+            l2_cache: CacheMap::new(cache_size.l2_tables),
             refcounts,
             current_offset: 0,
             unref_clusters: Vec::new(),
             avail_clusters: Vec::new(),
             backing_file,
+            snapshots,
+            snapshot_table_size,
+            cache_size,
+            decompressed_cluster: None,
         };
 
         // Check that the L1 and refcount tables fit in a 64bit address space.
@@ -586,8 +705,8 @@ impl QcowFile {
             .read(true)
             .open(backing_file_name)
             .map_err(Error::BackingFileIo)?;
-        let backing_file =
-            create_disk_file(backing_raw_file).map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
+        let backing_file = create_disk_file(backing_raw_file, MAX_NESTING_DEPTH)
+            .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
         let size = backing_file.get_len().map_err(Error::BackingFileIo)?;
         let header = QcowHeader::create_for_size_and_path(size, Some(backing_file_name))?;
         let mut result = QcowFile::new_from_header(file, header)?;
@@ -648,15 +767,12 @@ impl QcowFile {
                 Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)
                     .map_err(Error::ReadingPointers)?,
             );
+            let l2_flags = self.l2_entry_flags();
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache
                 .insert(l1_index, table, |index, evicted| {
-                    raw_file.write_pointer_table(
-                        l1_table[index],
-                        evicted.get_values(),
-                        CLUSTER_USED_FLAG,
-                    )
+                    raw_file.write_pointer_table(l1_table[index], evicted.get_values(), l2_flags)
                 })
                 .map_err(Error::EvictingCache)?;
         }
@@ -743,11 +859,10 @@ impl QcowFile {
         // Add references to the L1 table clusters.
         fn set_l1_refcounts(
             refcounts: &mut [u16],
-            header: QcowHeader,
+            l1_table_offset: u64,
+            l1_clusters: u64,
             cluster_size: u64,
         ) -> Result<()> {
-            let l1_clusters = div_round_up_u64(header.l1_size as u64, cluster_size);
-            let l1_table_offset = header.l1_table_offset;
             for i in 0..l1_clusters {
                 add_ref(refcounts, cluster_size, l1_table_offset + i * cluster_size)?;
             }
@@ -757,18 +872,19 @@ impl QcowFile {
         // Traverse the L1 and L2 tables to find all reachable data clusters.
         fn set_data_refcounts(
             refcounts: &mut [u16],
-            header: QcowHeader,
+            l1_table_offset: u64,
+            l1_size: u32,
             cluster_size: u64,
             raw_file: &mut QcowRawFile,
         ) -> Result<()> {
             let l1_table = raw_file
                 .read_pointer_table(
-                    header.l1_table_offset,
-                    header.l1_size as u64,
+                    l1_table_offset,
+                    u64::from(l1_size),
                     Some(L1_TABLE_OFFSET_MASK),
                 )
                 .map_err(Error::ReadingPointers)?;
-            for l1_index in 0..header.l1_size as usize {
+            for l1_index in 0..l1_size as usize {
                 let l2_addr_disk = *l1_table.get(l1_index).ok_or(Error::InvalidIndex)?;
                 if l2_addr_disk != 0 {
                     // Add a reference to the L2 table cluster itself.
@@ -779,12 +895,23 @@ impl QcowFile {
                         .read_pointer_table(
                             l2_addr_disk,
                             cluster_size / size_of::<u64>() as u64,
-                            Some(L2_TABLE_OFFSET_MASK),
+                            None,
                         )
                         .map_err(Error::ReadingPointers)?;
-                    for data_cluster_addr in l2_table {
-                        if data_cluster_addr != 0 {
-                            add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                    for entry in l2_table {
+                        if is_compressed(entry) {
+                            let compressed = CompressedCluster::from_l2_entry(
+                                entry,
+                                cluster_size.trailing_zeros(),
+                            );
+                            for host_cluster in compressed.host_clusters(cluster_size) {
+                                add_ref(refcounts, cluster_size, host_cluster)?;
+                            }
+                        } else {
+                            let data_cluster_addr = entry & L2_TABLE_OFFSET_MASK;
+                            if data_cluster_addr != 0 {
+                                add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                            }
                         }
                     }
                 }
@@ -793,6 +920,45 @@ impl QcowFile {
             Ok(())
         }
 
+        // Add references to the snapshot table and the L1 tables of the snapshots, and to the
+        // clusters the snapshots share with the image once for each snapshot.
+        fn set_snapshot_refcounts(
+            refcounts: &mut [u16],
+            header: &QcowHeader,
+            cluster_size: u64,
+            raw_file: &mut QcowRawFile,
+        ) -> Result<()> {
+            let (snapshots, table_size) = snapshot::read_snapshot_table(raw_file.file(), header)
+                .map_err(Error::ReadingSnapshots)?;
+            for i in 0..div_round_up_u64(table_size, cluster_size) {
+                add_ref(
+                    refcounts,
+                    cluster_size,
+                    header.snapshots_offset + i * cluster_size,
+                )?;
+            }
+            for snapshot in snapshots {
+                let l1_clusters = div_round_up_u64(
+                    u64::from(snapshot.l1_size) * size_of::<u64>() as u64,
+                    cluster_size,
+                );
+                set_l1_refcounts(
+                    refcounts,
+                    snapshot.l1_table_offset,
+                    l1_clusters,
+                    cluster_size,
+                )?;
+                set_data_refcounts(
+                    refcounts,
+                    snapshot.l1_table_offset,
+                    snapshot.l1_size,
+                    cluster_size,
+                    raw_file,
+                )?;
+            }
+            Ok(())
+        }
+
         // Add references to the top-level refcount table clusters.
         fn set_refcount_table_refcounts(
             refcounts: &mut [u16],
@@ -942,8 +1108,20 @@ impl QcowFile {
 
         // Find all references clusters and rebuild refcounts.
         set_header_refcount(&mut refcounts, cluster_size)?;
-        set_l1_refcounts(&mut refcounts, header.clone(), cluster_size)?;
-        set_data_refcounts(&mut refcounts, header.clone(), cluster_size, raw_file)?;
+        set_l1_refcounts(
+            &mut refcounts,
+            header.l1_table_offset,
+            div_round_up_u64(u64::from(header.l1_size), cluster_size),
+            cluster_size,
+        )?;
+        set_data_refcounts(
+            &mut refcounts,
+            header.l1_table_offset,
+            header.l1_size,
+            cluster_size,
+            raw_file,
+        )?;
+        set_snapshot_refcounts(&mut refcounts, &header, cluster_size, raw_file)?;
         set_refcount_table_refcounts(&mut refcounts, header.clone(), cluster_size)?;
 
         // Allocate clusters to store the new reference count blocks.
@@ -1000,9 +1178,9 @@ impl QcowFile {
         (address / self.raw_file.cluster_size()) % self.l2_entries
     }
 
-    // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters have
-    // yet to be allocated, return None.
-    fn file_offset_read(&mut self, address: u64) -> std::io::Result<Option<u64>> {
+    // Gets the location of the given guest address in the host file. If L1, L2, or data clusters
+    // have yet to be allocated, return Unallocated.
+    fn file_offset_read(&mut self, address: u64) -> std::io::Result<DataLocation> {
         if address >= self.virtual_size() as u64 {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -1015,7 +1193,7 @@ impl QcowFile {
 
         if l2_addr_disk == 0 {
             // Reading from an unallocated cluster will return zeros.
-            return Ok(None);
+            return Ok(DataLocation::Unallocated);
         }
 
         let l2_index = self.l2_table_index(address) as usize;
@@ -1025,22 +1203,24 @@ impl QcowFile {
             let table =
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
 
+            let l2_flags = self.l2_entry_flags();
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
+                raw_file.write_pointer_table(l1_table[index], evicted.get_values(), l2_flags)
             })?;
         };
 
         let cluster_addr = self.l2_cache.get(&l1_index).unwrap()[l2_index];
         if cluster_addr == 0 {
-            return Ok(None);
+            return Ok(DataLocation::Unallocated);
         }
-        Ok(Some(cluster_addr + self.raw_file.cluster_offset(address)))
+        if is_compressed(cluster_addr) {
+            return Ok(DataLocation::Compressed(cluster_addr));
+        }
+        Ok(DataLocation::Offset(
+            cluster_addr + self.raw_file.cluster_offset(address),
+        ))
     }
 
     // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters need
@@ -1072,25 +1252,29 @@ impl QcowFile {
             } else {
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?)
             };
+            let l2_flags = self.l2_entry_flags();
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache.insert(l1_index, l2_table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
+                raw_file.write_pointer_table(l1_table[index], evicted.get_values(), l2_flags)
             })?;
         }
 
-        let cluster_addr = match self.l2_cache.get(&l1_index).unwrap()[l2_index] {
+        let l2_entry = self.l2_cache.get(&l1_index).unwrap()[l2_index];
+        let cluster_addr = match l2_entry {
             0 => {
                 let initial_data = if let Some(backing) = self.backing_file.as_mut() {
                     let cluster_size = self.raw_file.cluster_size();
                     let cluster_begin = address - (address % cluster_size);
                     let mut cluster_data = vec![0u8; cluster_size as usize];
-                    let volatile_slice = VolatileSlice::new(&mut cluster_data);
-                    backing.read_exact_at_volatile(volatile_slice, cluster_begin)?;
+                    // The backing file may be smaller than the image, and reads as zeroes past its
+                    // end.
+                    let backing_len = backing.get_len()?;
+                    if cluster_begin < backing_len {
+                        let len = min(cluster_size, backing_len - cluster_begin) as usize;
+                        let volatile_slice = VolatileSlice::new(&mut cluster_data[..len]);
+                        backing.read_exact_at_volatile(volatile_slice, cluster_begin)?;
+                    }
                     Some(cluster_data)
                 } else {
                     None
@@ -1100,6 +1284,23 @@ impl QcowFile {
                 self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
                 cluster_addr
             }
+            // Compressed clusters are never written in place, the write goes to a decompressed copy.
+            a if is_compressed(a) => {
+                let cluster_data = self.decompress_cluster(a)?.to_vec();
+                let cluster_addr = self.append_data_cluster(Some(cluster_data))?;
+                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                self.add_data_refcount(a, -1)?;
+                cluster_addr
+            }
+            // A snapshot shares the cluster, so the write goes to a copy of it.
+            a if !self.snapshots.is_empty() && self.cluster_refcount(a)? > 1 => {
+                let mut cluster_data = vec![0u8; self.raw_file.cluster_size() as usize];
+                self.raw_file.file().read_exact_at(&mut cluster_data, a)?;
+                let cluster_addr = self.append_data_cluster(Some(cluster_data))?;
+                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                self.add_cluster_refcount(a, -1)?;
+                cluster_addr
+            }
             a => a,
         };
 
@@ -1126,8 +1327,12 @@ impl QcowFile {
             // The index must be valid from when it was insterted.
             let addr = self.l1_table[l1_index];
             if addr != 0 {
-                self.unref_clusters.push(addr);
-                set_refcounts.push((addr, 0));
+                // Snapshots may still refer to the old table.
+                let refcount = self.cluster_refcount(addr)?.saturating_sub(1);
+                if refcount == 0 {
+                    self.unref_clusters.push(addr);
+                }
+                set_refcounts.push((addr, refcount));
             }
 
             // Allocate a new cluster to store the L2 table and update the L1 table to point
@@ -1201,14 +1406,11 @@ impl QcowFile {
             // Not in the cache.
             let table =
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
+            let l2_flags = self.l2_entry_flags();
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
+                raw_file.write_pointer_table(l1_table[index], evicted.get_values(), l2_flags)
             })?;
         }
 
@@ -1273,14 +1475,11 @@ impl QcowFile {
             // Not in the cache.
             let table =
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
+            let l2_flags = self.l2_entry_flags();
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
+                raw_file.write_pointer_table(l1_table[index], evicted.get_values(), l2_flags)
             })?;
         }
 
@@ -1290,11 +1489,15 @@ impl QcowFile {
             return Ok(());
         }
 
+        if is_compressed(cluster_addr) {
+            // Other compressed clusters may share the host clusters, so they're only
+            // dereferenced.
+            self.remove_cluster_addr(l1_index, l2_index)?;
+            return self.add_data_refcount(cluster_addr, -1);
+        }
+
         // Decrement the refcount.
-        let refcount = self
-            .refcounts
-            .get_cluster_refcount(&mut self.raw_file, cluster_addr)
-            .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+        let refcount = self.cluster_refcount(cluster_addr)?;
         if refcount == 0 {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -1302,10 +1505,7 @@ impl QcowFile {
         let new_refcount = refcount - 1;
         let mut newly_unref = self.set_cluster_refcount(cluster_addr, new_refcount)?;
         self.unref_clusters.append(&mut newly_unref);
-
-        // Rewrite the L2 entry to remove the cluster mapping.
-        // unwrap is safe as we just checked/inserted this entry.
-        self.l2_cache.get_mut(&l1_index).unwrap()[l2_index] = 0;
+        self.remove_cluster_addr(l1_index, l2_index)?;
 
         if new_refcount == 0 {
             let cluster_size = self.raw_file.cluster_size();
@@ -1321,6 +1521,25 @@ impl QcowFile {
         Ok(())
     }
 
+    // Removes the mapping of the data cluster at `l2_index` of the L2 table at `l1_index`, which
+    // must be cached.
+    fn remove_cluster_addr(&mut self, l1_index: usize, l2_index: usize) -> std::io::Result<()> {
+        if self.snapshots.is_empty() {
+            // Rewrite the L2 entry to remove the cluster mapping.
+            // unwrap is safe as the caller checked/inserted this entry.
+            self.l2_cache.get_mut(&l1_index).unwrap()[l2_index] = 0;
+        } else {
+            // A snapshot may share the L2 table, so the entry is removed from a copy of it.
+            let mut set_refcounts = Vec::new();
+            self.update_cluster_addr(l1_index, l2_index, 0, &mut set_refcounts)?;
+            for (addr, count) in set_refcounts {
+                let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+                self.unref_clusters.append(&mut newly_unref);
+            }
+        }
+        Ok(())
+    }
+
     // Fill a range of `length` bytes starting at `address` with zeroes.
     // Any future reads of this range will return all zeroes.
     // If there is no backing file, this will deallocate cluster storage when possible.
@@ -1343,9 +1562,13 @@ impl QcowFile {
                     // show through.
                     Some(self.file_offset_write(curr_addr)?)
                 } else {
-                    // Any space in unallocated clusters can be left alone, since
-                    // unallocated clusters already read back as zeroes.
-                    self.file_offset_read(curr_addr)?
+                    match self.file_offset_read(curr_addr)? {
+                        // Any space in unallocated clusters can be left alone, since
+                        // unallocated clusters already read back as zeroes.
+                        DataLocation::Unallocated => None,
+                        DataLocation::Offset(offset) => Some(offset),
+                        DataLocation::Compressed(_) => Some(self.file_offset_write(curr_addr)?),
+                    }
                 };
                 if let Some(offset) = offset {
                     // Partial cluster - zero it out.
@@ -1360,19 +1583,86 @@ impl QcowFile {
         Ok(())
     }
 
-    // Reads an L2 cluster from the disk, returning an error if the file can't be read or if any
-    // cluster is compressed.
+    // Reads an L2 cluster from the disk, returning an error if the file can't be read. Entries of
+    // compressed clusters are kept whole, with the compressed flag, as they don't hold a cluster
+    // address.
     fn read_l2_cluster(raw_file: &mut QcowRawFile, cluster_addr: u64) -> std::io::Result<Vec<u64>> {
         let file_values = raw_file.read_pointer_cluster(cluster_addr, None)?;
-        if file_values.iter().any(|entry| entry & COMPRESSED_FLAG != 0) {
-            return Err(std::io::Error::from_raw_os_error(ENOTSUP));
-        }
         Ok(file_values
             .iter()
-            .map(|entry| *entry & L2_TABLE_OFFSET_MASK)
+            .map(|&entry| {
+                if is_compressed(entry) {
+                    entry & !CLUSTER_USED_FLAG
+                } else {
+                    entry & L2_TABLE_OFFSET_MASK
+                }
+            })
             .collect())
     }
 
+    // Returns the data of the compressed cluster described by the L2 table `entry`.
+    fn decompress_cluster(&mut self, entry: u64) -> std::io::Result<&[u8]> {
+        let cached = match &self.decompressed_cluster {
+            Some((cached_entry, _)) => *cached_entry == entry,
+            None => false,
+        };
+        if !cached {
+            let mut data = vec![0u8; self.raw_file.cluster_size() as usize];
+            CompressedCluster::from_l2_entry(entry, self.header.cluster_bits).read(
+                self.raw_file.file(),
+                self.header.compression_type,
+                &mut data,
+            )?;
+            self.decompressed_cluster = Some((entry, data));
+        }
+        // unwrap is safe as the cluster was just cached.
+        Ok(&self.decompressed_cluster.as_ref().unwrap().1)
+    }
+
+    // Adds `addend` to the refcounts of the clusters holding the data of the L2 table `entry`.
+    fn add_data_refcount(&mut self, entry: u64, addend: i32) -> std::io::Result<()> {
+        if !is_compressed(entry) {
+            return self.add_cluster_refcount(entry, addend);
+        }
+        let cluster_size = self.raw_file.cluster_size();
+        let compressed = CompressedCluster::from_l2_entry(entry, self.header.cluster_bits);
+        for host_cluster in compressed.host_clusters(cluster_size) {
+            self.add_cluster_refcount(host_cluster, addend)?;
+        }
+        Ok(())
+    }
+
+    // Gets the refcount of the cluster with the given address.
+    fn cluster_refcount(&mut self, address: u64) -> std::io::Result<u16> {
+        self.refcounts
+            .get_cluster_refcount(&mut self.raw_file, address)
+            .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))
+    }
+
+    // Adds `addend` to the refcount of the cluster with the given address. The cluster is freed
+    // once nothing refers to it.
+    fn add_cluster_refcount(&mut self, address: u64, addend: i32) -> std::io::Result<()> {
+        let refcount = i32::from(self.cluster_refcount(address)?) + addend;
+        let refcount =
+            u16::try_from(refcount).map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+        let mut newly_unref = self.set_cluster_refcount(address, refcount)?;
+        self.unref_clusters.append(&mut newly_unref);
+        if refcount == 0 {
+            self.unref_clusters.push(address);
+        }
+        Ok(())
+    }
+
+    // The flags written with the entries of L2 tables. Entries are only marked as not shared while
+    // the image has no snapshots, as any of them may be shared with one.
+    fn l2_entry_flags(&self) -> u64 {
+        if self.snapshots.is_empty() {
+            CLUSTER_USED_FLAG
+        } else {
+            0
+        }
+    }
+
     // Set the refcount for a cluster with the given address.
     // Returns a list of any refblocks that can be reused, this happens when a refblock is moved,
     // the old location can be reused.
@@ -1430,17 +1720,100 @@ impl QcowFile {
         Ok(unref_clusters)
     }
 
+    // Changes the virtual size to `new_size`. The L1 and refcount tables can't be moved, so growing
+    // fails with ENOSPC once either would outgrow the clusters allocated for it. Shrinking keeps
+    // the L1 table as it is, and fails with EINVAL if any cluster past `new_size` holds data.
+    fn resize_image(&mut self, new_size: u64) -> std::io::Result<()> {
+        let old_size = self.virtual_size();
+        if new_size > MAX_QCOW_FILE_SIZE {
+            return Err(std::io::Error::from_raw_os_error(EINVAL));
+        }
+        if new_size <= old_size {
+            // Data from the backing file could show up past the end of the image, so it is never
+            // known to be safe to drop.
+            if new_size < old_size
+                && (self.backing_file.is_some()
+                    || self.find_allocated_cluster(new_size, true)?.is_some())
+            {
+                return Err(std::io::Error::from_raw_os_error(EINVAL));
+            }
+            self.header.size = new_size;
+            return self.write_resized_header();
+        }
+
+        let cluster_size = self.raw_file.cluster_size();
+        let pointers_per_cluster = cluster_size / size_of::<u64>() as u64;
+        let num_clusters = div_round_up_u64(new_size, cluster_size);
+        let num_l2_clusters = div_round_up_u64(num_clusters, self.l2_entries);
+        // The L1 table can grow into the rest of its last cluster.
+        let l1_clusters = div_round_up_u64(u64::from(self.header.l1_size), pointers_per_cluster);
+        if num_l2_clusters > l1_clusters * pointers_per_cluster {
+            return Err(std::io::Error::from_raw_os_error(ENOSPC));
+        }
+        let refcount_clusters = self.refcount_clusters_for_size(new_size)?;
+
+        // Write out everything cached so the tables can be reloaded at their new size.
+        self.sync_caches()?;
+        let mut l1_table = self.l1_table.get_values().to_vec();
+        l1_table.resize(num_l2_clusters as usize, 0);
+        self.raw_file
+            .write_pointer_table(self.header.l1_table_offset, &l1_table, 0)?;
+        self.l1_table = VecCache::from_vec(l1_table);
+        self.refcounts = RefCount::new(
+            &mut self.raw_file,
+            self.header.refcount_table_offset,
+            refcount_clusters,
+            self.refcounts.refcounts_per_block(),
+            cluster_size,
+            self.cache_size.refcount_blocks,
+        )?;
+
+        self.header.l1_size = num_l2_clusters as u32;
+        self.header.size = new_size;
+        self.write_resized_header()
+    }
+
+    // Returns the number of refcount blocks needed to cover an image of `size` bytes, and never
+    // fewer than are in use. Fails with ENOSPC if the refcount table can't hold that many.
+    fn refcount_clusters_for_size(&self, size: u64) -> std::io::Result<u64> {
+        let cluster_size = self.raw_file.cluster_size();
+        let pointers_per_cluster = cluster_size / size_of::<u64>() as u64;
+        let num_clusters = div_round_up_u64(size, cluster_size);
+        let num_l2_clusters = div_round_up_u64(num_clusters, self.l2_entries);
+        let l1_clusters = div_round_up_u64(u64::from(self.header.l1_size), pointers_per_cluster);
+        let header_clusters = div_round_up_u64(size_of::<QcowHeader>() as u64, cluster_size);
+        let refcount_clusters = max(
+            max_refcount_clusters(
+                self.header.refcount_order,
+                cluster_size as u32,
+                (num_clusters + l1_clusters + num_l2_clusters + header_clusters) as u32,
+            ),
+            self.refcounts.ref_table().len() as u64,
+        );
+        if refcount_clusters > u64::from(self.header.refcount_table_clusters) * pointers_per_cluster
+        {
+            return Err(std::io::Error::from_raw_os_error(ENOSPC));
+        }
+        Ok(refcount_clusters)
+    }
+
+    // Writes the size and L1 table size from `header` back to the image.
+    fn write_resized_header(&mut self) -> std::io::Result<()> {
+        let file = self.raw_file.file_mut();
+        file.write_all_at(&self.header.size.to_be_bytes(), HEADER_SIZE_OFFSET)?;
+        file.write_all_at(&self.header.l1_size.to_be_bytes(), HEADER_L1_SIZE_OFFSET)?;
+        file.sync_data()
+    }
+
     fn sync_caches(&mut self) -> std::io::Result<()> {
         // Write out all dirty L2 tables.
+        let l2_flags = self.l2_entry_flags();
         for (l1_index, l2_table) in self.l2_cache.iter_mut().filter(|(_k, v)| v.dirty()) {
             // The index must be valid from when we insterted it.
             let addr = self.l1_table[*l1_index];
             if addr != 0 {
-                self.raw_file.write_pointer_table(
-                    addr,
-                    l2_table.get_values(),
-                    CLUSTER_USED_FLAG,
-                )?;
+                self.raw_file
+                    .write_pointer_table(addr, l2_table.get_values(), l2_flags)?;
             } else {
                 return Err(std::io::Error::from_raw_os_error(EINVAL));
             }
@@ -1471,12 +1844,10 @@ impl QcowFile {
     }
 
     // Reads `count` bytes starting at `address`, calling `cb` repeatedly with the data source,
-    // number of bytes read so far, offset to read from, and number of bytes to read from the file
-    // in that invocation. If None is given to `cb` in place of the backing file, the `cb` should
-    // infer zeros would have been read.
+    // number of bytes read so far, and number of bytes to read from the source in that invocation.
     fn read_cb<F>(&mut self, address: u64, count: usize, mut cb: F) -> std::io::Result<usize>
     where
-        F: FnMut(Option<&mut dyn DiskFile>, usize, u64, usize) -> std::io::Result<()>,
+        F: FnMut(ReadSource, usize, usize) -> std::io::Result<()>,
     {
         let read_count: usize = self.limit_range_file(address, count);
 
@@ -1486,12 +1857,47 @@ impl QcowFile {
             let file_offset = self.file_offset_read(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, read_count - nread);
 
-            if let Some(offset) = file_offset {
-                cb(Some(self.raw_file.file_mut()), nread, offset, count)?;
-            } else if let Some(backing) = self.backing_file.as_mut() {
-                cb(Some(backing.as_mut()), nread, curr_addr, count)?;
-            } else {
-                cb(None, nread, 0, count)?;
+            match file_offset {
+                DataLocation::Offset(offset) => {
+                    cb(
+                        ReadSource::File(self.raw_file.file_mut(), offset),
+                        nread,
+                        count,
+                    )?;
+                }
+                DataLocation::Compressed(entry) => {
+                    let cluster_offset = self.raw_file.cluster_offset(curr_addr) as usize;
+                    let data = self.decompress_cluster(entry)?;
+                    cb(
+                        ReadSource::Buffer(&data[cluster_offset..cluster_offset + count]),
+                        nread,
+                        count,
+                    )?;
+                }
+                DataLocation::Unallocated => {
+                    if let Some(backing) = self.backing_file.as_mut() {
+                        // Past the end of the backing file, unallocated clusters read as zeroes.
+                        let backing_len = backing.get_len()?;
+                        let backing_count =
+                            min(count as u64, backing_len.saturating_sub(curr_addr)) as usize;
+                        if backing_count > 0 {
+                            cb(
+                                ReadSource::File(backing.as_mut(), curr_addr),
+                                nread,
+                                backing_count,
+                            )?;
+                        }
+                        if backing_count < count {
+                            cb(
+                                ReadSource::Zeroes,
+                                nread + backing_count,
+                                count - backing_count,
+                            )?;
+                        }
+                    } else {
+                        cb(ReadSource::Zeroes, nread, count)?;
+                    }
+                }
             }
 
             nread += count;
@@ -1546,20 +1952,21 @@ impl Read for QcowFile {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let len = buf.len();
         let slice = VolatileSlice::new(buf);
-        let read_count = self.read_cb(
-            self.current_offset,
-            len,
-            |file, already_read, offset, count| {
+        let read_count =
+            self.read_cb(self.current_offset, len, |source, already_read, count| {
                 let sub_slice = slice.get_slice(already_read, count).unwrap();
-                match file {
-                    Some(f) => f.read_exact_at_volatile(sub_slice, offset),
-                    None => {
+                match source {
+                    ReadSource::File(f, offset) => f.read_exact_at_volatile(sub_slice, offset),
+                    ReadSource::Buffer(data) => {
+                        sub_slice.copy_from(data);
+                        Ok(())
+                    }
+                    ReadSource::Zeroes => {
                         sub_slice.write_bytes(0);
                         Ok(())
                     }
                 }
-            },
-        )?;
+            })?;
         self.current_offset += read_count as u64;
         Ok(read_count)
     }
@@ -1616,20 +2023,21 @@ impl Write for QcowFile {
 
 impl FileReadWriteVolatile for QcowFile {
     fn read_volatile(&mut self, slice: VolatileSlice) -> io::Result<usize> {
-        let read_count = self.read_cb(
-            self.current_offset,
-            slice.size(),
-            |file, read, offset, count| {
+        let read_count =
+            self.read_cb(self.current_offset, slice.size(), |source, read, count| {
                 let sub_slice = slice.get_slice(read, count).unwrap();
-                match file {
-                    Some(f) => f.read_exact_at_volatile(sub_slice, offset),
-                    None => {
+                match source {
+                    ReadSource::File(f, offset) => f.read_exact_at_volatile(sub_slice, offset),
+                    ReadSource::Buffer(data) => {
+                        sub_slice.copy_from(data);
+                        Ok(())
+                    }
+                    ReadSource::Zeroes => {
                         sub_slice.write_bytes(0);
                         Ok(())
                     }
                 }
-            },
-        )?;
+            })?;
         self.current_offset += read_count as u64;
         Ok(read_count)
     }
@@ -1647,11 +2055,15 @@ impl FileReadWriteVolatile for QcowFile {
 
 impl FileReadWriteAtVolatile for QcowFile {
     fn read_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
-        self.read_cb(offset, slice.size(), |file, read, offset, count| {
+        self.read_cb(offset, slice.size(), |source, read, count| {
             let sub_slice = slice.get_slice(read, count).unwrap();
-            match file {
-                Some(f) => f.read_exact_at_volatile(sub_slice, offset),
-                None => {
+            match source {
+                ReadSource::File(f, offset) => f.read_exact_at_volatile(sub_slice, offset),
+                ReadSource::Buffer(data) => {
+                    sub_slice.copy_from(data);
+                    Ok(())
+                }
+                ReadSource::Zeroes => {
                     sub_slice.write_bytes(0);
                     Ok(())
                 }
@@ -1682,6 +2094,12 @@ impl FileSetLen for QcowFile {
     }
 }
 
+impl DiskResize for QcowFile {
+    fn resize(&mut self, new_size: u64) -> io::Result<()> {
+        self.resize_image(new_size)
+    }
+}
+
 impl DiskGetLen for QcowFile {
     fn get_len(&self) -> io::Result<u64> {
         Ok(self.virtual_size())
@@ -1770,8 +2188,9 @@ fn div_round_up_u32(dividend: u32, divisor: u32) -> u32 {
 mod tests {
     use super::*;
     use base::WriteZeroes;
+    use flate2::{Compress, Compression, FlushCompress};
     use std::io::{Read, Seek, SeekFrom, Write};
-    use tempfile::tempfile;
+    use tempfile::{tempfile, TempDir};
 
     fn valid_header() -> Vec<u8> {
         vec![
@@ -1995,6 +2414,30 @@ mod tests {
         });
     }
 
+    #[test]
+    fn write_read_small_cache() {
+        with_basic_file(&valid_header(), |disk_file: File| {
+            let cache_size = QcowCacheSize {
+                l2_tables: 1,
+                refcount_blocks: 1,
+            };
+            let mut q =
+                QcowFile::from_with_cache_size(disk_file, MAX_NESTING_DEPTH, cache_size).unwrap();
+            // Each write goes through a different L2 table, evicting the one before it.
+            let offsets = [0u64, 0x4000_0000, 0x8000_0000];
+            for (i, &offset) in offsets.iter().enumerate() {
+                q.seek(SeekFrom::Start(offset)).expect("Failed to seek.");
+                q.write(&[i as u8 + 1; 4]).expect("Failed to write.");
+            }
+            for (i, &offset) in offsets.iter().enumerate() {
+                let mut buf = [0u8; 4];
+                q.seek(SeekFrom::Start(offset)).expect("Failed to seek.");
+                q.read(&mut buf).expect("Failed to read.");
+                assert_eq!(buf, [i as u8 + 1; 4]);
+            }
+        });
+    }
+
     #[test]
     fn write_read_start_backing() {
         let disk_file = basic_file(&valid_header());
@@ -2011,6 +2454,68 @@ mod tests {
         assert_eq!(&buf, b"test");
     }
 
+    #[test]
+    fn backing_file_chain() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.path().join("base.img");
+        let mid_path = dir.path().join("mid.qcow2");
+        let top_path = dir.path().join("top.qcow2");
+        let create = |path: &std::path::Path| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(path)
+                .unwrap()
+        };
+
+        let mut base = create(&base_path);
+        base.write_all(b"base").unwrap();
+        base.set_len(0x10000).unwrap();
+        QcowFile::new_from_backing(create(&mid_path), base_path.to_str().unwrap()).unwrap();
+        QcowFile::new_from_backing(create(&top_path), mid_path.to_str().unwrap()).unwrap();
+
+        // The top image has two levels of backing files below it.
+        let open_top = |max_nesting_depth| {
+            QcowFile::from_with_nesting_depth(create(&top_path), max_nesting_depth)
+        };
+        assert!(matches!(open_top(1), Err(Error::BackingFileOpen(_))));
+        let mut top = open_top(2).unwrap();
+
+        // Reads fall through both layers, and writes only go to the top one.
+        let mut buf = [0u8; 4];
+        top.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"base");
+        top.seek(SeekFrom::Start(0)).unwrap();
+        top.write_all(b"top!").unwrap();
+        top.seek(SeekFrom::Start(0)).unwrap();
+        top.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"top!");
+        let mut mid = QcowFile::from(create(&mid_path)).unwrap();
+        mid.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"base");
+    }
+
+    #[test]
+    fn backing_file_loop() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("loop.qcow2");
+        let mut disk_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .unwrap();
+        let header = QcowHeader::create_for_size_and_path(0x10000, path.to_str())
+            .expect("Failed to create header.");
+        header.write_to(&mut disk_file).unwrap();
+        disk_file.seek(SeekFrom::Start(0)).unwrap();
+        assert!(matches!(
+            QcowFile::from(disk_file),
+            Err(Error::BackingFileOpen(_))
+        ));
+    }
+
     #[test]
     fn write_read_start_backing_overlap() {
         let disk_file = basic_file(&valid_header());
@@ -2091,6 +2596,198 @@ mod tests {
         });
     }
 
+    #[test]
+    fn resize() {
+        let file = tempfile().unwrap();
+        let mut q = QcowFile::new(file.try_clone().unwrap(), 0x10_0000).unwrap();
+
+        // Growing needs more L1 entries, which still fit in the L1 table's cluster.
+        q.resize(0x1_0000_0000).unwrap();
+        assert_eq!(q.get_len().unwrap(), 0x1_0000_0000);
+        q.seek(SeekFrom::Start(0x8000_0000)).unwrap();
+        q.write_all(b"grown").unwrap();
+
+        // Shrinking would drop the data written above.
+        assert_eq!(
+            q.resize(0x10_0000).unwrap_err().raw_os_error(),
+            Some(EINVAL)
+        );
+        q.resize(0x8010_0000).unwrap();
+        drop(q);
+
+        // The new size is kept in the image.
+        let mut q = QcowFile::from(file).unwrap();
+        assert_eq!(q.get_len().unwrap(), 0x8010_0000);
+        let mut buf = [0u8; 5];
+        q.seek(SeekFrom::Start(0x8000_0000)).unwrap();
+        q.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"grown");
+    }
+
+    #[test]
+    fn snapshot_apply() {
+        let file = tempfile().unwrap();
+        let mut q = QcowFile::new(file.try_clone().unwrap(), 0x10_0000).unwrap();
+        q.write_all(b"before").unwrap();
+        q.seek(SeekFrom::Start(0x1_0000)).unwrap();
+        q.write_all(&[0x55u8; 0x1_0000]).unwrap();
+        q.create_snapshot("first").unwrap();
+        assert!(matches!(
+            q.create_snapshot("first"),
+            Err(Error::SnapshotExists(_))
+        ));
+
+        // Writes after the snapshot go to copies of the clusters it shares.
+        q.seek(SeekFrom::Start(0)).unwrap();
+        q.write_all(b"after").unwrap();
+        q.seek(SeekFrom::Start(0x1_0000)).unwrap();
+        q.write_zeroes_all(0x1_0000).unwrap();
+        q.seek(SeekFrom::Start(0x2_0000)).unwrap();
+        q.write_all(b"new").unwrap();
+        let mut buf = [0u8; 5];
+        q.seek(SeekFrom::Start(0)).unwrap();
+        q.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"after");
+
+        q.apply_snapshot("first").unwrap();
+        let mut buf = [0u8; 6];
+        q.seek(SeekFrom::Start(0)).unwrap();
+        q.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"before");
+        let mut buf = [0u8; 0x1_0000];
+        q.seek(SeekFrom::Start(0x1_0000)).unwrap();
+        q.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0x55));
+        q.seek(SeekFrom::Start(0x2_0000)).unwrap();
+        q.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        assert!(matches!(
+            q.apply_snapshot("second"),
+            Err(Error::NoSuchSnapshot(_))
+        ));
+        drop(q);
+
+        let result = QcowFile::check(file, false).unwrap();
+        assert_eq!(result.corruptions, 0);
+        assert_eq!(result.refcount_errors, 0);
+    }
+
+    #[test]
+    fn snapshot_delete() {
+        let file = tempfile().unwrap();
+        let mut q = QcowFile::new(file.try_clone().unwrap(), 0x10_0000).unwrap();
+        q.write_all(b"before").unwrap();
+        q.create_snapshot("first").unwrap();
+        drop(q);
+
+        // The snapshot is kept in the image.
+        let mut q = QcowFile::from(file.try_clone().unwrap()).unwrap();
+        assert_eq!(q.snapshots().len(), 1);
+        assert_eq!(q.snapshots()[0].id, "1");
+        assert_eq!(q.snapshots()[0].name, "first");
+        assert_eq!(q.snapshots()[0].disk_size, 0x10_0000);
+        q.seek(SeekFrom::Start(0)).unwrap();
+        q.write_all(b"after").unwrap();
+        let data_cluster = q.l2_table(0).unwrap().unwrap()[0];
+
+        // Deleting the snapshot frees what only it refers to, and leaves the image as it is.
+        q.delete_snapshot("first").unwrap();
+        assert!(q.snapshots().is_empty());
+        let mut buf = [0u8; 5];
+        q.seek(SeekFrom::Start(0)).unwrap();
+        q.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"after");
+        assert_eq!(q.l2_table(0).unwrap().unwrap()[0], data_cluster);
+        drop(q);
+
+        let result = QcowFile::check(file.try_clone().unwrap(), false).unwrap();
+        assert_eq!(result.corruptions, 0);
+        assert_eq!(result.refcount_errors, 0);
+        let q = QcowFile::from(file).unwrap();
+        assert!(q.snapshots().is_empty());
+    }
+
+    // Creates an image whose first cluster is allocated and whose second cluster is compressed and
+    // holds `data`, with lazy refcounts so they are rebuilt when it's opened.
+    fn with_compressed_cluster<F>(data: &[u8], mut testfn: F)
+    where
+        F: FnMut(QcowFile),
+    {
+        let file = tempfile().expect("failed to create tempfile");
+        let mut raw = file.try_clone().unwrap();
+        {
+            let mut q = QcowFile::new(file, 0x10_0000).unwrap();
+            q.write_all(&[1u8; 4]).expect("Failed to write.");
+        }
+        let header = QcowHeader::new(&mut raw).unwrap();
+
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut compressed = vec![0u8; data.len() + 1024];
+        compress
+            .compress(data, &mut compressed, FlushCompress::Finish)
+            .expect("Failed to compress.");
+        compressed.truncate(compress.total_out() as usize);
+        // Compressed data doesn't start on a sector boundary.
+        let offset = raw.metadata().unwrap().len() + 0x300;
+        raw.write_all_at(&compressed, offset).unwrap();
+
+        let mut l1_entry = [0u8; 8];
+        raw.read_exact_at(&mut l1_entry, header.l1_table_offset)
+            .unwrap();
+        let l2_addr = u64::from_be_bytes(l1_entry) & L1_TABLE_OFFSET_MASK;
+        let additional_sectors = (offset + compressed.len() as u64 - 1) / 512 - offset / 512;
+        let entry =
+            COMPRESSED_FLAG | additional_sectors << (62 - (header.cluster_bits - 8)) | offset;
+        raw.write_all_at(&entry.to_be_bytes(), l2_addr + size_of::<u64>() as u64)
+            .unwrap();
+        raw.write_all_at(
+            &COMPATIBLE_FEATURES_LAZY_REFCOUNTS.to_be_bytes(),
+            80, // compatible_features
+        )
+        .unwrap();
+
+        testfn(QcowFile::from(raw).unwrap());
+    }
+
+    fn compressible_cluster() -> Vec<u8> {
+        (0..0x1_0000u32).map(|i| (i / 100) as u8).collect()
+    }
+
+    #[test]
+    fn read_compressed_cluster() {
+        let data = compressible_cluster();
+        with_compressed_cluster(&data, |mut q| {
+            let mut buf = vec![0u8; data.len()];
+            q.seek(SeekFrom::Start(0x1_0000)).expect("Failed to seek.");
+            // Read in two parts, the second one from the decompressed cluster.
+            q.read_exact(&mut buf[..0x1000]).expect("Failed to read.");
+            q.read_exact(&mut buf[0x1000..]).expect("Failed to read.");
+            assert!(buf == data);
+
+            let mut buf = [0u8; 4];
+            q.seek(SeekFrom::Start(0)).expect("Failed to seek.");
+            q.read_exact(&mut buf).expect("Failed to read.");
+            assert_eq!(buf, [1u8; 4]);
+        });
+    }
+
+    #[test]
+    fn write_compressed_cluster() {
+        let data = compressible_cluster();
+        let mut expected = data.clone();
+        expected[8..12].copy_from_slice(&[0xaa; 4]);
+        with_compressed_cluster(&data, |mut q| {
+            q.seek(SeekFrom::Start(0x1_0008)).expect("Failed to seek.");
+            q.write_all(&[0xaa; 4]).expect("Failed to write.");
+            let mut buf = vec![0u8; data.len()];
+            q.seek(SeekFrom::Start(0x1_0000)).expect("Failed to seek.");
+            q.read_exact(&mut buf).expect("Failed to read.");
+            assert!(buf == expected);
+            // The write went to a copy of the cluster.
+            assert!(!is_compressed(q.l2_table(0).unwrap().unwrap()[1]));
+        });
+    }
+
     #[test]
     fn write_zeroes_backing() {
         let disk_file = basic_file(&valid_header());
@@ -9,6 +9,8 @@ use std::mem::size_of;
 use base::{FileReadWriteAtVolatile, WriteZeroes};
 use data_model::VolatileSlice;
 
+use crate::qcow::compression::is_compressed;
+
 /// A qcow file. Allows reading/writing clusters and appending clusters.
 #[derive(Debug)]
 pub struct QcowRawFile {
@@ -58,8 +60,8 @@ impl QcowRawFile {
     }
 
     /// Writes `table` of u64 pointers to `offset` in the file.
-    /// `non_zero_flags` will be ORed with all non-zero values in `table`.
-    /// writing.
+    /// `non_zero_flags` will be ORed with all non-zero values in `table`, except for the entries of
+    /// compressed clusters, which are written as they are.
     pub fn write_pointer_table(
         &mut self,
         offset: u64,
@@ -69,8 +71,8 @@ impl QcowRawFile {
         self.file.seek(SeekFrom::Start(offset))?;
         let mut buffer = BufWriter::with_capacity(table.len() * size_of::<u64>(), &self.file);
         for addr in table {
-            let val = if *addr == 0 {
-                0
+            let val = if *addr == 0 || is_compressed(*addr) {
+                *addr
             } else {
                 *addr | non_zero_flags
             };
@@ -61,13 +61,15 @@ impl RefCount {
     /// Creates a `RefCount` from `file`, reading the refcount table from `refcount_table_offset`.
     /// `refcount_table_entries` specifies the number of refcount blocks used by this image.
     /// `refcount_block_entries` indicates the number of refcounts in each refcount block.
-    /// Each refcount table entry points to a refcount block.
+    /// Each refcount table entry points to a refcount block. Up to `cached_blocks` refcount blocks
+    /// are kept in memory.
     pub fn new(
         raw_file: &mut QcowRawFile,
         refcount_table_offset: u64,
         refcount_table_entries: u64,
         refcount_block_entries: u64,
         cluster_size: u64,
+        cached_blocks: usize,
     ) -> io::Result<RefCount> {
         let ref_table = VecCache::from_vec(raw_file.read_pointer_table(
             refcount_table_offset,
@@ -79,7 +81,7 @@ impl RefCount {
         Ok(RefCount {
             ref_table,
             refcount_table_offset,
-            refblock_cache: CacheMap::new(50),
+            refblock_cache: CacheMap::new(cached_blocks),
             refcount_block_entries,
             cluster_size,
             max_valid_cluster_offset,
@@ -0,0 +1,441 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::max;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libc::ENOSPC;
+
+use crate::qcow::refcount::RefCount;
+use crate::qcow::vec_cache::{CacheMap, VecCache};
+use crate::qcow::{
+    div_round_up_u64, Error, QcowFile, QcowHeader, Result, L1_TABLE_OFFSET_MASK,
+    MAX_RAM_POINTER_TABLE_SIZE,
+};
+
+// Each entry of the snapshot table starts with this many bytes of fixed fields, followed by the
+// extra data, the id and the name, and is padded to a multiple of 8 bytes.
+const SNAPSHOT_ENTRY_FIXED_SIZE: usize = 40;
+// The extra data of version 3 images holds at least the 64-bit VM state size and the disk size.
+const SNAPSHOT_EXTRA_DATA_SIZE: usize = 16;
+// Limits from the specification, which keep a corrupt table from using up memory.
+const MAX_SNAPSHOTS: usize = 65536;
+const MAX_SNAPSHOT_EXTRA_DATA_SIZE: usize = 1024;
+const MAX_SNAPSHOT_TABLE_SIZE: u64 = 64 * 1024 * 1024;
+
+// The number of snapshots and the offset of the snapshot table are next to each other in the
+// header.
+const HEADER_NB_SNAPSHOTS_OFFSET: u64 = 60;
+
+/// An internal snapshot of a qcow2 image: a copy of the L1 table as it was when the snapshot was
+/// taken. The snapshot shares its L2 tables and data clusters with the image, which copies them
+/// before writing to them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QcowSnapshot {
+    /// Unique id of the snapshot, a decimal number by convention.
+    pub id: String,
+    pub name: String,
+    pub l1_table_offset: u64,
+    pub l1_size: u32,
+    /// Time the snapshot was taken, since the epoch.
+    pub date_sec: u32,
+    pub date_nsec: u32,
+    /// Guest time when the snapshot was taken.
+    pub vm_clock_nsec: u64,
+    /// Size of the VM state saved with the snapshot. crosvm never saves VM state.
+    pub vm_state_size: u64,
+    /// Virtual size of the disk when the snapshot was taken.
+    pub disk_size: u64,
+}
+
+impl QcowSnapshot {
+    // The size of the table entry for this snapshot, including padding.
+    fn entry_size(&self) -> usize {
+        let size =
+            SNAPSHOT_ENTRY_FIXED_SIZE + SNAPSHOT_EXTRA_DATA_SIZE + self.id.len() + self.name.len();
+        (size + 7) & !7
+    }
+
+    // Appends the table entry for this snapshot to `table`. Extra data past the disk size, such
+    // as the instruction count some writers add, isn't kept.
+    fn write_entry(&self, table: &mut Vec<u8>) {
+        let start = table.len();
+        table.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        table.extend_from_slice(&self.l1_size.to_be_bytes());
+        table.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+        table.extend_from_slice(&(self.name.len() as u16).to_be_bytes());
+        table.extend_from_slice(&self.date_sec.to_be_bytes());
+        table.extend_from_slice(&self.date_nsec.to_be_bytes());
+        table.extend_from_slice(&self.vm_clock_nsec.to_be_bytes());
+        // The 32-bit VM state size is only read by old versions, and zero when it doesn't fit.
+        let vm_state_size = u32::try_from(self.vm_state_size).unwrap_or(0);
+        table.extend_from_slice(&vm_state_size.to_be_bytes());
+        table.extend_from_slice(&(SNAPSHOT_EXTRA_DATA_SIZE as u32).to_be_bytes());
+        table.extend_from_slice(&self.vm_state_size.to_be_bytes());
+        table.extend_from_slice(&self.disk_size.to_be_bytes());
+        table.extend_from_slice(self.id.as_bytes());
+        table.extend_from_slice(self.name.as_bytes());
+        table.resize(start + self.entry_size(), 0);
+    }
+}
+
+fn invalid_table(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid snapshot table: {}", what),
+    )
+}
+
+// Reads a big-endian integer from the start of `bytes`.
+fn be_u16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    let mut value = [0u8; 4];
+    value.copy_from_slice(&bytes[..4]);
+    u32::from_be_bytes(value)
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(value)
+}
+
+/// Reads the snapshot table of the image in `file`, returning the snapshots and the size of the
+/// table in bytes.
+pub fn read_snapshot_table(
+    file: &File,
+    header: &QcowHeader,
+) -> io::Result<(Vec<QcowSnapshot>, u64)> {
+    if header.nb_snapshots as usize > MAX_SNAPSHOTS {
+        return Err(invalid_table("too many snapshots"));
+    }
+    let mut snapshots = Vec::with_capacity(header.nb_snapshots as usize);
+    let mut offset = 0u64;
+    for _ in 0..header.nb_snapshots {
+        let mut fixed = [0u8; SNAPSHOT_ENTRY_FIXED_SIZE];
+        file.read_exact_at(&mut fixed, header.snapshots_offset + offset)?;
+        let id_size = be_u16(&fixed[12..]) as usize;
+        let name_size = be_u16(&fixed[14..]) as usize;
+        let extra_data_size = be_u32(&fixed[36..]) as usize;
+        if extra_data_size > MAX_SNAPSHOT_EXTRA_DATA_SIZE {
+            return Err(invalid_table("too much extra data"));
+        }
+
+        let mut rest = vec![0u8; extra_data_size + id_size + name_size];
+        file.read_exact_at(
+            &mut rest,
+            header.snapshots_offset + offset + SNAPSHOT_ENTRY_FIXED_SIZE as u64,
+        )?;
+        let (extra_data, strings) = rest.split_at(extra_data_size);
+        let (id, name) = strings.split_at(id_size);
+        let l1_size = be_u32(&fixed[8..]);
+        if u64::from(l1_size) > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(invalid_table("L1 table too large"));
+        }
+        // Without the extra data, the snapshot has the size of the image.
+        let (vm_state_size, disk_size) = if extra_data.len() >= SNAPSHOT_EXTRA_DATA_SIZE {
+            (be_u64(extra_data), be_u64(&extra_data[8..]))
+        } else {
+            (u64::from(be_u32(&fixed[32..])), header.size)
+        };
+        snapshots.push(QcowSnapshot {
+            id: String::from_utf8_lossy(id).into_owned(),
+            name: String::from_utf8_lossy(name).into_owned(),
+            l1_table_offset: be_u64(&fixed) & L1_TABLE_OFFSET_MASK,
+            l1_size,
+            date_sec: be_u32(&fixed[16..]),
+            date_nsec: be_u32(&fixed[20..]),
+            vm_clock_nsec: be_u64(&fixed[24..]),
+            vm_state_size,
+            disk_size,
+        });
+
+        let entry_size = SNAPSHOT_ENTRY_FIXED_SIZE + rest.len();
+        offset += ((entry_size + 7) & !7) as u64;
+        if offset > MAX_SNAPSHOT_TABLE_SIZE {
+            return Err(invalid_table("table too large"));
+        }
+    }
+    Ok((snapshots, offset))
+}
+
+impl QcowFile {
+    /// Returns the internal snapshots of the image.
+    pub fn snapshots(&self) -> &[QcowSnapshot] {
+        &self.snapshots
+    }
+
+    /// Takes an internal snapshot of the image named `name`. The snapshot shares all the clusters
+    /// of the image, which are copied the next time they are written.
+    pub fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        if self.snapshots.iter().any(|s| s.name == name) {
+            return Err(Error::SnapshotExists(name.to_owned()));
+        }
+        if name.len() > u16::MAX as usize {
+            return Err(Error::SnapshotNameTooLong(name.len()));
+        }
+        if self.snapshots.len() >= MAX_SNAPSHOTS {
+            return Err(Error::TooManySnapshots);
+        }
+
+        let id = self
+            .snapshots
+            .iter()
+            .filter_map(|s| s.id.parse::<u64>().ok())
+            .max()
+            .map_or(1, |id| id + 1)
+            .to_string();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let l1_size = self.header.l1_size;
+        let l1_table_offset = self
+            .copy_active_l1_table()
+            .map_err(Error::CreatingSnapshot)?;
+        let mut snapshots = self.snapshots.clone();
+        snapshots.push(QcowSnapshot {
+            id,
+            name: name.to_owned(),
+            l1_table_offset,
+            l1_size,
+            date_sec: now.as_secs() as u32,
+            date_nsec: now.subsec_nanos(),
+            vm_clock_nsec: 0,
+            vm_state_size: 0,
+            disk_size: self.header.size,
+        });
+        self.write_snapshot_table(snapshots)
+            .map_err(Error::CreatingSnapshot)
+    }
+
+    /// Reverts the image to the snapshot named `name`, dropping everything written since. The
+    /// snapshot is kept. This fails if the snapshot's L1 table doesn't fit in the clusters of the
+    /// image's L1 table, which can't be moved.
+    pub fn apply_snapshot(&mut self, name: &str) -> Result<()> {
+        let snapshot = self
+            .snapshots
+            .iter()
+            .find(|s| s.name == name)
+            .cloned()
+            .ok_or_else(|| Error::NoSuchSnapshot(name.to_owned()))?;
+
+        let cluster_size = self.raw_file.cluster_size();
+        let pointers_per_cluster = cluster_size / size_of::<u64>() as u64;
+        let l1_clusters = div_round_up_u64(u64::from(self.header.l1_size), pointers_per_cluster);
+        if u64::from(snapshot.l1_size) > l1_clusters * pointers_per_cluster {
+            return Err(Error::SnapshotL1TableTooLarge(snapshot.l1_size));
+        }
+        let num_clusters = div_round_up_u64(snapshot.disk_size, cluster_size);
+        let num_l2_clusters = div_round_up_u64(num_clusters, self.l2_entries);
+        if num_l2_clusters > u64::from(snapshot.l1_size) {
+            return Err(Error::InvalidL1TableSize(snapshot.l1_size));
+        }
+        let refcount_clusters = self
+            .refcount_clusters_for_size(snapshot.disk_size)
+            .map_err(Error::ApplyingSnapshot)?;
+
+        self.swap_active_l1_table(&snapshot, num_l2_clusters, refcount_clusters)
+            .map_err(Error::ApplyingSnapshot)
+    }
+
+    /// Deletes the snapshot named `name`, freeing the clusters only it refers to.
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<()> {
+        let index = self
+            .snapshots
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| Error::NoSuchSnapshot(name.to_owned()))?;
+        let mut snapshots = self.snapshots.clone();
+        let snapshot = snapshots.remove(index);
+        self.drop_snapshot(snapshots, &snapshot)
+            .map_err(Error::DeletingSnapshot)
+    }
+
+    // Copies the active L1 table to new clusters, taking a reference to every cluster it leads
+    // to. Returns the offset of the copy.
+    fn copy_active_l1_table(&mut self) -> io::Result<u64> {
+        self.sync_caches()?;
+        let l1_table = self.raw_file.read_pointer_table(
+            self.header.l1_table_offset,
+            u64::from(self.header.l1_size),
+            Some(L1_TABLE_OFFSET_MASK),
+        )?;
+        self.add_l1_table_refcounts(&l1_table, 1)?;
+        let l1_table_offset = self.append_table_clusters(table_bytes(l1_table.len()))?;
+        self.raw_file
+            .write_pointer_table(l1_table_offset, &l1_table, 0)?;
+        Ok(l1_table_offset)
+    }
+
+    // Makes the L1 table of `snapshot` the active one, with `num_l2_clusters` entries in use and
+    // room for `refcount_clusters` refcount blocks.
+    fn swap_active_l1_table(
+        &mut self,
+        snapshot: &QcowSnapshot,
+        num_l2_clusters: u64,
+        refcount_clusters: u64,
+    ) -> io::Result<()> {
+        self.sync_caches()?;
+        let old_l1_table = self.raw_file.read_pointer_table(
+            self.header.l1_table_offset,
+            u64::from(self.header.l1_size),
+            Some(L1_TABLE_OFFSET_MASK),
+        )?;
+        let mut l1_table = self.raw_file.read_pointer_table(
+            snapshot.l1_table_offset,
+            u64::from(snapshot.l1_size),
+            Some(L1_TABLE_OFFSET_MASK),
+        )?;
+        if refcount_clusters > self.refcounts.ref_table().len() as u64 {
+            let refcounts_per_block = self.refcounts.refcounts_per_block();
+            let cluster_size = self.raw_file.cluster_size();
+            self.refcounts = RefCount::new(
+                &mut self.raw_file,
+                self.header.refcount_table_offset,
+                refcount_clusters,
+                refcounts_per_block,
+                cluster_size,
+                self.cache_size.refcount_blocks,
+            )?;
+        }
+        // Take the new references first so that nothing the snapshot uses is freed on the way.
+        self.add_l1_table_refcounts(&l1_table, 1)?;
+        self.sync_caches()?;
+
+        // Zero the entries of the old table past the end of the new one.
+        let mut on_disk = l1_table.clone();
+        on_disk.resize(max(on_disk.len(), old_l1_table.len()), 0);
+        self.raw_file
+            .write_pointer_table(self.header.l1_table_offset, &on_disk, 0)?;
+        self.header.size = snapshot.disk_size;
+        self.header.l1_size = snapshot.l1_size;
+        self.write_resized_header()?;
+        l1_table.truncate(num_l2_clusters as usize);
+        self.l1_table = VecCache::from_vec(l1_table);
+        // Everything cached was written out above and belongs to the old table.
+        self.l2_cache = CacheMap::new(self.cache_size.l2_tables);
+
+        self.add_l1_table_refcounts(&old_l1_table, -1)?;
+        self.sync_caches()
+    }
+
+    // Removes `snapshot` from the image, leaving `snapshots`, and drops its references.
+    fn drop_snapshot(
+        &mut self,
+        snapshots: Vec<QcowSnapshot>,
+        snapshot: &QcowSnapshot,
+    ) -> io::Result<()> {
+        self.sync_caches()?;
+        // The snapshot is gone from the table before any of its clusters is freed, so a crash on
+        // the way only leaks clusters.
+        self.write_snapshot_table(snapshots)?;
+        let l1_table = self.raw_file.read_pointer_table(
+            snapshot.l1_table_offset,
+            u64::from(snapshot.l1_size),
+            Some(L1_TABLE_OFFSET_MASK),
+        )?;
+        self.add_l1_table_refcounts(&l1_table, -1)?;
+        self.free_table_clusters(snapshot.l1_table_offset, table_bytes(l1_table.len()))?;
+        self.sync_caches()
+    }
+
+    // Adds `addend` to the refcount of every L2 table in `l1_table` and of every data cluster
+    // those point to, as when an L1 table referring to them is added or removed. Tables that
+    // become shared lose their copied flags, so that other writers, which trust the flags, also
+    // copy the clusters before writing.
+    fn add_l1_table_refcounts(&mut self, l1_table: &[u64], addend: i32) -> io::Result<()> {
+        for &l2_addr in l1_table.iter().filter(|&&addr| addr != 0) {
+            let l2_table = Self::read_l2_cluster(&mut self.raw_file, l2_addr)?;
+            for &entry in l2_table.iter().filter(|&&entry| entry != 0) {
+                self.add_data_refcount(entry, addend)?;
+            }
+            if addend > 0 {
+                self.raw_file.write_pointer_table(l2_addr, &l2_table, 0)?;
+            }
+            self.add_cluster_refcount(l2_addr, addend)?;
+        }
+        Ok(())
+    }
+
+    // Allocates contiguous clusters at the end of the file for a table of `size` bytes. Returns
+    // the offset of the first one.
+    fn append_table_clusters(&mut self, size: u64) -> io::Result<u64> {
+        let cluster_size = self.raw_file.cluster_size();
+        let max_valid_cluster_offset = self.refcounts.max_valid_cluster_offset();
+        let mut first_cluster = None;
+        for _ in 0..div_round_up_u64(size, cluster_size) {
+            let cluster = self
+                .raw_file
+                .add_cluster_end(max_valid_cluster_offset)?
+                .ok_or_else(|| io::Error::from_raw_os_error(ENOSPC))?;
+            first_cluster.get_or_insert(cluster);
+        }
+        let first_cluster = match first_cluster {
+            Some(cluster) => cluster,
+            None => return Ok(0),
+        };
+        // Refcount blocks are allocated after the table, which stays contiguous.
+        for i in 0..div_round_up_u64(size, cluster_size) {
+            self.add_cluster_refcount(first_cluster + i * cluster_size, 1)?;
+        }
+        Ok(first_cluster)
+    }
+
+    // Drops the references to the clusters of a table of `size` bytes at `offset`.
+    fn free_table_clusters(&mut self, offset: u64, size: u64) -> io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        for i in 0..div_round_up_u64(size, cluster_size) {
+            self.add_cluster_refcount(offset + i * cluster_size, -1)?;
+        }
+        Ok(())
+    }
+
+    // Writes `snapshots` as the new snapshot table of the image and frees the old table.
+    fn write_snapshot_table(&mut self, snapshots: Vec<QcowSnapshot>) -> io::Result<()> {
+        let mut table = Vec::new();
+        for snapshot in &snapshots {
+            snapshot.write_entry(&mut table);
+        }
+        let offset = if table.is_empty() {
+            0
+        } else {
+            let offset = self.append_table_clusters(table.len() as u64)?;
+            self.raw_file.file_mut().write_all_at(&table, offset)?;
+            offset
+        };
+        // The new table and its refcounts must be on disk before the header points to it.
+        self.sync_caches()?;
+
+        let mut header_fields = Vec::with_capacity(12);
+        header_fields.extend_from_slice(&(snapshots.len() as u32).to_be_bytes());
+        header_fields.extend_from_slice(&offset.to_be_bytes());
+        let file = self.raw_file.file_mut();
+        file.write_all_at(&header_fields, HEADER_NB_SNAPSHOTS_OFFSET)?;
+        file.sync_data()?;
+
+        let old_offset = self.header.snapshots_offset;
+        let old_size = self.snapshot_table_size;
+        self.header.nb_snapshots = snapshots.len() as u32;
+        self.header.snapshots_offset = offset;
+        self.snapshot_table_size = table.len() as u64;
+        self.snapshots = snapshots;
+        if old_size != 0 {
+            self.free_table_clusters(old_offset, old_size)?;
+        }
+        Ok(())
+    }
+}
+
+// The size in bytes of a pointer table with `entries` entries.
+fn table_bytes(entries: usize) -> u64 {
+    (entries * size_of::<u64>()) as u64
+}
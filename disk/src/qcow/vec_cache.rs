@@ -2,7 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::collections::hash_map::IterMut;
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::io;
 use std::ops::{Index, IndexMut};
@@ -83,17 +83,29 @@ impl<T: 'static + Copy + Default> IndexMut<usize> for VecCache<T> {
     }
 }
 
+#[derive(Debug)]
+struct CacheEntry<T> {
+    value: T,
+    // Value of the cache's clock the last time the entry was looked up.
+    last_used: Cell<u64>,
+}
+
+/// Holds up to `capacity` items, evicting the least recently used one to make room for more.
 #[derive(Debug)]
 pub struct CacheMap<T: Cacheable> {
     capacity: usize,
-    map: HashMap<usize, T>,
+    map: HashMap<usize, CacheEntry<T>>,
+    clock: Cell<u64>,
 }
 
 impl<T: Cacheable> CacheMap<T> {
+    /// Creates a cache that holds up to `capacity` items, and at least one.
     pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
         CacheMap {
             capacity,
             map: HashMap::with_capacity(capacity),
+            clock: Cell::new(0),
         }
     }
 
@@ -102,15 +114,19 @@ impl<T: Cacheable> CacheMap<T> {
     }
 
     pub fn get(&self, index: &usize) -> Option<&T> {
-        self.map.get(index)
+        let entry = self.map.get(index)?;
+        touch(&self.clock, entry);
+        Some(&entry.value)
     }
 
     pub fn get_mut(&mut self, index: &usize) -> Option<&mut T> {
-        self.map.get_mut(index)
+        let entry = self.map.get_mut(index)?;
+        touch(&self.clock, entry);
+        Some(&mut entry.value)
     }
 
-    pub fn iter_mut(&mut self) -> IterMut<usize, T> {
-        self.map.iter_mut()
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&usize, &mut T)> {
+        self.map.iter_mut().map(|(k, entry)| (k, &mut entry.value))
     }
 
     // Check if the refblock cache is full and we need to evict.
@@ -118,20 +134,35 @@ impl<T: Cacheable> CacheMap<T> {
     where
         F: FnOnce(usize, T) -> io::Result<()>,
     {
-        if self.map.len() == self.capacity {
-            // TODO(dgreid) - smarter eviction strategy.
-            let to_evict = *self.map.iter().next().unwrap().0;
+        if self.map.len() >= self.capacity && !self.map.contains_key(&index) {
+            let to_evict = *self
+                .map
+                .iter()
+                .min_by_key(|(_k, entry)| entry.last_used.get())
+                .unwrap()
+                .0;
             if let Some(evicted) = self.map.remove(&to_evict) {
-                if evicted.dirty() {
-                    write_callback(to_evict, evicted)?;
+                if evicted.value.dirty() {
+                    write_callback(to_evict, evicted.value)?;
                 }
             }
         }
-        self.map.insert(index, block);
+        let entry = CacheEntry {
+            value: block,
+            last_used: Cell::new(0),
+        };
+        touch(&self.clock, &entry);
+        self.map.insert(index, entry);
         Ok(())
     }
 }
 
+// Marks `entry` as the most recently used one in the cache with `clock`.
+fn touch<T>(clock: &Cell<u64>, entry: &CacheEntry<T>) {
+    clock.set(clock.get() + 1);
+    entry.last_used.set(clock.get());
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -182,4 +213,27 @@ mod tests {
         assert_eq!(num_items, 3);
         assert!(cache.contains_key(&3));
     }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = CacheMap::<NumCache>::new(3);
+        for i in 0..3 {
+            cache.insert(i, NumCache(i as u64), |_, _| Ok(())).unwrap();
+        }
+        // Looking up 0 and 1 leaves 2 as the least recently used.
+        assert!(cache.get(&0).is_some());
+        assert!(cache.get_mut(&1).is_some());
+
+        let mut evicted = None;
+        cache
+            .insert(3, NumCache(3), |index, _| {
+                evicted = Some(index);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(evicted, Some(2));
+        assert!(cache.contains_key(&0));
+        assert!(cache.contains_key(&1));
+        assert!(cache.contains_key(&3));
+    }
 }
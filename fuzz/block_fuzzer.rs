@@ -11,7 +11,7 @@ use std::sync::Arc;
 
 use base::Event;
 use cros_fuzz::fuzz_target;
-use devices::virtio::{base_features, Block, Interrupt, Queue, VirtioDevice};
+use devices::virtio::{base_features, Block, BlockConfig, Interrupt, Queue, VirtioDevice};
 use tempfile;
 use vm_memory::{GuestAddress, GuestMemory};
 
@@ -80,8 +80,15 @@ fuzz_target!(|bytes| {
     let features = base_features(false);
 
     let disk_file = tempfile::tempfile().unwrap();
-    let mut block =
-        Block::new(features, Box::new(disk_file), false, true, 512, None, None).unwrap();
+    let mut block = Block::new(
+        features,
+        Box::new(disk_file),
+        None,
+        BlockConfig::default(),
+        None,
+        None,
+    )
+    .unwrap();
 
     block.activate(
         mem,
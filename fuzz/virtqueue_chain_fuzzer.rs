@@ -0,0 +1,64 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+#![no_main]
+
+use cros_fuzz::fuzz_target;
+use cros_fuzz::rand::FuzzRng;
+use data_model::Le16;
+use devices::virtio::{validate_descriptor_chain, Queue};
+use rand::{Rng, RngCore};
+use vm_memory::{GuestAddress, GuestMemory};
+
+const MAX_QUEUE_SIZE: u16 = 256;
+const DESC_SIZE: u64 = 16;
+const MEM_SIZE: u64 = 1024 * 1024;
+
+// The rings live at the top of guest memory, out of the way of the fuzzed descriptor table.
+const AVAIL_RING: GuestAddress = GuestAddress(MEM_SIZE - 0x2000);
+const USED_RING: GuestAddress = GuestAddress(MEM_SIZE - 0x1000);
+
+thread_local! {
+    static GUEST_MEM: GuestMemory = GuestMemory::new(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut rng = FuzzRng::new(data);
+    let queue_size: u16 = 1 << rng.gen_range(0, 9);
+    let head: u16 = rng.gen();
+
+    let mut q = Queue::new(MAX_QUEUE_SIZE);
+    q.size = queue_size;
+    q.ready = true;
+    q.desc_table =
+        GuestAddress(rng.gen_range(0, AVAIL_RING.offset() - MAX_QUEUE_SIZE as u64 * DESC_SIZE));
+    q.avail_ring = AVAIL_RING;
+    q.used_ring = USED_RING;
+
+    GUEST_MEM.with(|mem| {
+        let mut buf = vec![0u8; queue_size as usize * DESC_SIZE as usize];
+        rng.fill_bytes(&mut buf[..]);
+        mem.write_all_at_addr(&buf[..], q.desc_table).unwrap();
+
+        // Make the fuzzed head the only available chain.
+        mem.write_obj_at_addr(Le16::from(0), AVAIL_RING).unwrap();
+        mem.write_obj_at_addr(Le16::from(1), AVAIL_RING.unchecked_add(2))
+            .unwrap();
+        mem.write_obj_at_addr(Le16::from(head), AVAIL_RING.unchecked_add(4))
+            .unwrap();
+
+        // The queue must only hand out chains that pass validation, and walking one must yield
+        // exactly the descriptors that were validated.
+        let validated = validate_descriptor_chain(mem, None, q.desc_table, queue_size, head);
+        match (q.pop(mem), validated) {
+            (Some(chain), Ok(len)) => assert_eq!(chain.into_iter().count(), len as usize),
+            (None, Err(_)) => {}
+            (chain, validated) => panic!(
+                "queue returned chain {:?} for validation result {:?}",
+                chain.map(|c| c.index),
+                validated
+            ),
+        }
+    });
+});
@@ -30,12 +30,12 @@ use base::{
     block_signal, errno_result, error, ioctl, ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val,
     pagesize, signal, unblock_signal, AsRawDescriptor, Error, Event, FromRawDescriptor,
     MappedRegion, MemoryMapping, MemoryMappingBuilder, MmapError, RawDescriptor, Result,
-    SafeDescriptor,
+    SafeDescriptor, SharedMemory,
 };
 use data_model::vec_with_array_field;
 use kvm_sys::*;
 use sync::Mutex;
-use vm_memory::{GuestAddress, GuestMemory};
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
 
 use crate::{
     ClockState, Datamatch, DeviceKind, Hypervisor, HypervisorCap, IoEventAddress, IrqRoute,
@@ -152,6 +152,8 @@ pub struct KvmVm {
     mem_regions: Arc<Mutex<BTreeMap<MemSlot, Box<dyn MappedRegion>>>>,
     /// A min heap of MemSlot numbers that were used and then removed and can now be re-used
     mem_slot_gaps: Arc<Mutex<BinaryHeap<Reverse<MemSlot>>>>,
+    /// Where the regions added with `add_guest_memory_region` start, keyed by their slot
+    guest_mem_slots: BTreeMap<MemSlot, GuestAddress>,
 }
 
 impl KvmVm {
@@ -186,6 +188,7 @@ impl KvmVm {
             guest_mem,
             mem_regions: Arc::new(Mutex::new(BTreeMap::new())),
             mem_slot_gaps: Arc::new(Mutex::new(BinaryHeap::new())),
+            guest_mem_slots: BTreeMap::new(),
         })
     }
 
@@ -384,6 +387,7 @@ impl Vm for KvmVm {
             guest_mem: self.guest_mem.clone(),
             mem_regions: self.mem_regions.clone(),
             mem_slot_gaps: self.mem_slot_gaps.clone(),
+            guest_mem_slots: self.guest_mem_slots.clone(),
         })
     }
 
@@ -424,7 +428,12 @@ impl Vm for KvmVm {
         let mut gaps = self.mem_slot_gaps.lock();
         let slot = match gaps.pop() {
             Some(gap) => gap.0,
-            None => (regions.len() + self.guest_mem.num_regions() as usize) as MemSlot,
+            // The slots below the ones in `regions` belong to the regions `new` was given.
+            None => {
+                let boot_regions =
+                    self.guest_mem.num_regions() as usize - self.guest_mem_slots.len();
+                (regions.len() + boot_regions) as MemSlot
+            }
         };
 
         // Safe because we check that the given guest address is valid and has no overlaps. We also
@@ -477,6 +486,47 @@ impl Vm for KvmVm {
         Ok(regions.remove(&slot).unwrap())
     }
 
+    fn add_guest_memory_region(
+        &mut self,
+        guest_addr: GuestAddress,
+        shm: SharedMemory,
+    ) -> Result<MemSlot> {
+        let size = usize::try_from(shm.size()).map_err(|_| Error::new(EOVERFLOW))?;
+        let mapping = MemoryMappingBuilder::new(size)
+            .from_descriptor(&shm)
+            .build()
+            .map_err(|err| match err {
+                MmapError::SystemCallFailed(e) => e,
+                _ => Error::new(EINVAL),
+            })?;
+        let guest_mem = self
+            .guest_mem
+            .add_region(guest_addr, shm)
+            .map_err(|err| match err {
+                GuestMemoryError::MemoryRegionOverlap => Error::new(ENOSPC),
+                _ => Error::new(EINVAL),
+            })?;
+        let slot = self.add_memory_region(guest_addr, Box::new(mapping), false, false)?;
+        self.guest_mem = guest_mem;
+        self.guest_mem_slots.insert(slot, guest_addr);
+        Ok(slot)
+    }
+
+    fn remove_guest_memory_region(&mut self, slot: MemSlot) -> Result<()> {
+        let guest_addr = *self
+            .guest_mem_slots
+            .get(&slot)
+            .ok_or_else(|| Error::new(ENOENT))?;
+        let guest_mem = self
+            .guest_mem
+            .remove_region(guest_addr)
+            .map_err(|_| Error::new(ENOENT))?;
+        self.remove_memory_region(slot)?;
+        self.guest_mem = guest_mem;
+        self.guest_mem_slots.remove(&slot);
+        Ok(())
+    }
+
     fn create_device(&self, kind: DeviceKind) -> Result<SafeDescriptor> {
         let device = if let Some(dev) = self.get_device_params_arch(kind) {
             dev
@@ -1209,6 +1259,44 @@ mod tests {
         assert_eq!(removed_mem.as_ptr(), mem_ptr);
     }
 
+    #[test]
+    fn add_remove_guest_memory() {
+        let kvm = Kvm::new().unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut vm = KvmVm::new(&kvm, gm).unwrap();
+        let shm = SharedMemory::anon(0x1000).unwrap();
+        let slot = vm
+            .add_guest_memory_region(GuestAddress(0x4000), shm)
+            .unwrap();
+        assert_eq!(vm.get_memory().num_regions(), 2);
+        vm.get_memory()
+            .write_obj_at_addr(67u8, GuestAddress(0x4000))
+            .unwrap();
+
+        // Other memory regions don't reuse the slot.
+        let mem = MemoryMappingBuilder::new(0x1000).build().unwrap();
+        let other_slot = vm
+            .add_memory_region(GuestAddress(0x8000), Box::new(mem), false, false)
+            .unwrap();
+        assert_ne!(other_slot, slot);
+
+        vm.remove_guest_memory_region(slot).unwrap();
+        assert_eq!(vm.get_memory().num_regions(), 1);
+        assert!(!vm.get_memory().address_in_range(GuestAddress(0x4000)));
+        assert!(vm.remove_guest_memory_region(slot).is_err());
+
+        let shm = SharedMemory::anon(0x1000).unwrap();
+        let readded_slot = vm
+            .add_guest_memory_region(GuestAddress(0x4000), shm)
+            .unwrap();
+        assert_eq!(readded_slot, slot);
+        let mem = MemoryMappingBuilder::new(0x1000).build().unwrap();
+        let new_slot = vm
+            .add_memory_region(GuestAddress(0xc000), Box::new(mem), false, false)
+            .unwrap();
+        assert!(new_slot != slot && new_slot != other_slot);
+    }
+
     #[test]
     fn remove_invalid_memory() {
         let kvm = Kvm::new().unwrap();
@@ -12,7 +12,7 @@ pub mod x86_64;
 
 use std::os::raw::c_int;
 
-use base::{Event, MappedRegion, RawDescriptor, Result, SafeDescriptor};
+use base::{Event, MappedRegion, RawDescriptor, Result, SafeDescriptor, SharedMemory};
 use msg_socket::MsgOnSocket;
 use vm_memory::{GuestAddress, GuestMemory};
 
@@ -89,6 +89,24 @@ pub trait Vm: Send {
     /// Removes and drops the `UserMemoryRegion` that was previously added at the given slot.
     fn remove_memory_region(&mut self, slot: MemSlot) -> Result<Box<dyn MappedRegion>>;
 
+    /// Adds `shm` to the guest memory at `guest_addr` and maps it into the VM's address space.
+    ///
+    /// Unlike `add_memory_region`, the region also becomes part of `get_memory`, so it is included
+    /// in the `GuestMemory` handed to devices created afterwards. Devices already holding a
+    /// `GuestMemory` keep their older snapshot, and other clones of this `Vm` are not updated.
+    ///
+    /// The slot that was assigned the region is returned on success and can be given to
+    /// `Vm::remove_guest_memory_region`.
+    fn add_guest_memory_region(
+        &mut self,
+        guest_addr: GuestAddress,
+        shm: SharedMemory,
+    ) -> Result<MemSlot>;
+
+    /// Removes a region added with `add_guest_memory_region` from both the VM's address space and
+    /// the guest memory.
+    fn remove_guest_memory_region(&mut self, slot: MemSlot) -> Result<()>;
+
     /// Creates an emulated device.
     fn create_device(&self, kind: DeviceKind) -> Result<SafeDescriptor>;
 
@@ -266,7 +284,7 @@ pub enum IoEventAddress {
 }
 
 /// Used in `Vm::register_ioevent` to indicate a size and optionally value to match.
-#[derive(PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub enum Datamatch {
     AnyLength,
     U8(Option<u8>),
@@ -14,7 +14,7 @@ use std::pin::Pin;
 use std::ptr::null_mut;
 use std::sync::atomic::{AtomicU32, Ordering};
 
-use sys_util::{MappedRegion, MemoryMapping, Protection, WatchingEvents};
+use sys_util::{trace_event, MappedRegion, MemoryMapping, Protection, WatchingEvents};
 
 use crate::bindings::*;
 use crate::syscalls::*;
@@ -478,6 +478,7 @@ impl URingContext {
         self.in_flight += self.added;
         self.stats.total_ops = self.stats.total_ops.wrapping_add(self.added as u64);
         if self.added > 0 {
+            let _trace = trace_event!(uring, "submit");
             self.stats.total_enter_calls = self.stats.total_enter_calls.wrapping_add(1);
             unsafe {
                 // Safe because the only memory modified is in the completion queue.
@@ -502,6 +503,7 @@ impl URingContext {
         self.in_flight -= completed;
         self.stats.total_ops = self.stats.total_ops.wrapping_add(self.added as u64);
         if self.in_flight > 0 || self.added > 0 {
+            let _trace = trace_event!(uring, "submit_and_wait");
             unsafe {
                 self.stats.total_enter_calls = self.stats.total_enter_calls.wrapping_add(1);
                 // Safe because the only memory modified is in the completion queue.
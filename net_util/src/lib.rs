@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::ffi::{CStr, CString};
 use std::fmt::{self, Display};
 use std::fs::File;
 use std::io::{Read, Result as IoResult, Write};
@@ -14,6 +15,7 @@ use std::str::FromStr;
 
 use libc::EPERM;
 
+use base::netlink::{self, NetlinkSocket};
 use base::Error as SysError;
 use base::FileReadWriteVolatile;
 use base::{
@@ -21,6 +23,10 @@ use base::{
     FromRawDescriptor, IoctlNr, RawDescriptor,
 };
 
+pub mod user_net;
+
+pub use base::netlink::MacvtapMode;
+
 #[derive(Debug)]
 pub enum Error {
     /// Failed to create a socket.
@@ -31,6 +37,14 @@ pub enum Error {
     CreateTap(SysError),
     /// ioctl failed.
     IoctlError(SysError),
+    /// Couldn't duplicate the tap descriptor.
+    CloneTap(SysError),
+    /// Failed to wait for user-mode networking events.
+    UserNetWait(SysError),
+    /// An rtnetlink request failed.
+    Netlink(SysError),
+    /// Couldn't open the character device of a macvtap interface.
+    OpenMacvtap(SysError),
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -43,6 +57,10 @@ impl Display for Error {
             OpenTun(e) => write!(f, "failed to open /dev/net/tun: {}", e),
             CreateTap(e) => write!(f, "failed to create tap interface: {}", e),
             IoctlError(e) => write!(f, "ioctl failed: {}", e),
+            CloneTap(e) => write!(f, "failed to clone tap: {}", e),
+            UserNetWait(e) => write!(f, "failed to wait for user-mode networking events: {}", e),
+            Netlink(e) => write!(f, "rtnetlink request failed: {}", e),
+            OpenMacvtap(e) => write!(f, "failed to open macvtap device: {}", e),
         }
     }
 }
@@ -54,6 +72,10 @@ impl Error {
             Error::OpenTun(e) => e,
             Error::CreateTap(e) => e,
             Error::IoctlError(e) => e,
+            Error::CloneTap(e) => e,
+            Error::UserNetWait(e) => e,
+            Error::Netlink(e) => e,
+            Error::OpenMacvtap(e) => e,
         }
     }
 }
@@ -122,6 +144,15 @@ pub struct MacAddress {
 }
 
 impl MacAddress {
+    /// Creates an Ethernet mac address from its octets.
+    pub fn from_octets(addr: [u8; 6usize]) -> MacAddress {
+        MacAddress {
+            family: net_sys::ARPHRD_ETHER,
+            addr,
+            __pad: [0; 8usize],
+        }
+    }
+
     pub fn octets(&self) -> [u8; 6usize] {
         self.addr
     }
@@ -136,11 +167,7 @@ impl FromStr for MacAddress {
             return Err(MacAddressError::InvalidNumOctets(octets.len()));
         }
 
-        let mut result = MacAddress {
-            family: net_sys::ARPHRD_ETHER,
-            addr: [0; 6usize],
-            __pad: [0; 8usize],
-        };
+        let mut result = MacAddress::from_octets([0; 6usize]);
 
         for (i, octet) in octets.iter().enumerate() {
             result.addr[i] = u8::from_str_radix(octet, 16).map_err(MacAddressError::ParseOctet)?;
@@ -227,6 +254,86 @@ impl Tap {
             if_flags: unsafe { ifreq.ifr_ifru.ifru_flags },
         })
     }
+
+    // Returns the interface index of the tap, which rtnetlink requests refer to it by.
+    fn link_index(&self) -> Result<u32> {
+        // Safe because the kernel nul-terminates the interface name it returns, and `if_name` is
+        // zeroed past the name we gave it otherwise.
+        let name = unsafe { CStr::from_ptr(self.if_name.as_ptr()) };
+        let name = name
+            .to_str()
+            .map_err(|_| Error::Netlink(SysError::new(libc::EINVAL)))?;
+        netlink::link_index(name).map_err(Error::Netlink)
+    }
+
+    /// Enslaves the tap to the bridge named `bridge`, creating the bridge and bringing it up if
+    /// it doesn't exist yet. A bridge created here outlives the tap, so that other VMs can join
+    /// it.
+    pub fn add_to_bridge(&self, bridge: &str) -> Result<()> {
+        let mut socket = NetlinkSocket::new().map_err(Error::CreateSocket)?;
+        let bridge_index = match netlink::link_index(bridge) {
+            Ok(index) => index,
+            Err(e) if e.errno() == libc::ENODEV => {
+                socket.create_bridge(bridge).map_err(Error::Netlink)?;
+                let index = netlink::link_index(bridge).map_err(Error::Netlink)?;
+                socket.set_link_up(index, true).map_err(Error::Netlink)?;
+                index
+            }
+            Err(e) => return Err(Error::Netlink(e)),
+        };
+        socket
+            .set_master(self.link_index()?, Some(bridge_index))
+            .map_err(Error::Netlink)
+    }
+}
+
+/// A macvtap interface created by crosvm on top of a host interface. The interface is deleted
+/// when this is dropped, which closes the queues still open on it.
+pub struct Macvtap {
+    index: u32,
+}
+
+impl Macvtap {
+    /// Creates a macvtap interface named `name` on top of the host interface `parent`, brings it
+    /// up, and opens its character device, `/dev/tapN` where N is the new interface's index. The
+    /// device node is created by udev.
+    pub fn new(name: &str, parent: &str, mode: MacvtapMode) -> Result<(Macvtap, Tap)> {
+        let mut socket = NetlinkSocket::new().map_err(Error::CreateSocket)?;
+        let parent = netlink::link_index(parent).map_err(Error::Netlink)?;
+        socket
+            .create_macvtap(name, parent, mode)
+            .map_err(Error::Netlink)?;
+        let macvtap = Macvtap {
+            index: netlink::link_index(name).map_err(Error::Netlink)?,
+        };
+        socket
+            .set_link_up(macvtap.index, true)
+            .map_err(Error::Netlink)?;
+
+        let path = CString::new(format!("/dev/tap{}", macvtap.index)).unwrap();
+        // Safe because `path` is a valid nul-terminated string and we check the result.
+        let fd = unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::OpenMacvtap(SysError::last()));
+        }
+        // Safe because we own the fd we just opened.
+        let tap = unsafe { Tap::from_raw_descriptor(fd)? };
+        Ok((macvtap, tap))
+    }
+}
+
+impl Drop for Macvtap {
+    fn drop(&mut self) {
+        // Nothing can be done about a failure but leave the interface behind.
+        if let Ok(mut socket) = NetlinkSocket::new() {
+            let _ = socket.delete_link(self.index);
+        }
+    }
 }
 
 pub trait TapT: FileReadWriteVolatile + Read + Write + AsRawDescriptor + Send + Sized {
@@ -240,6 +347,13 @@ pub trait TapT: FileReadWriteVolatile + Read + Write + AsRawDescriptor + Send +
     /// origin tap.
     fn into_mq_taps(self, vq_pairs: u16) -> Result<Vec<Self>>;
 
+    /// Create another handle to the same queue of the tap interface.
+    fn try_clone(&self) -> Result<Self>;
+
+    /// Attach this queue to a multiqueue tap interface, or detach it so that the interface stops
+    /// delivering frames to it.
+    fn set_queue_enabled(&self, enabled: bool) -> Result<()>;
+
     /// Get the host-side IP address for the tap interface.
     fn ip_addr(&self) -> Result<net::Ipv4Addr>;
 
@@ -321,6 +435,34 @@ impl TapT for Tap {
         Ok(taps)
     }
 
+    fn try_clone(&self) -> Result<Tap> {
+        Ok(Tap {
+            tap_file: self
+                .tap_file
+                .try_clone()
+                .map_err(|e| Error::CloneTap(e.into()))?,
+            if_name: self.if_name,
+            if_flags: self.if_flags,
+        })
+    }
+
+    fn set_queue_enabled(&self, enabled: bool) -> Result<()> {
+        let mut ifreq: net_sys::ifreq = Default::default();
+        ifreq.ifr_ifru.ifru_flags = if enabled {
+            net_sys::IFF_ATTACH_QUEUE
+        } else {
+            net_sys::IFF_DETACH_QUEUE
+        } as c_short;
+
+        // ioctl is safe. Called with a valid tap fd, and we check the return.
+        let ret = unsafe { ioctl_with_ref(&self.tap_file, net_sys::TUNSETQUEUE(), &ifreq) };
+        if ret < 0 {
+            return Err(Error::IoctlError(SysError::last()));
+        }
+
+        Ok(())
+    }
+
     fn ip_addr(&self) -> Result<net::Ipv4Addr> {
         let sock = create_socket()?;
         let mut ifreq = self.get_ifreq();
@@ -418,25 +560,10 @@ impl TapT for Tap {
     }
 
     fn set_mac_address(&self, mac_addr: MacAddress) -> Result<()> {
-        let sock = create_socket()?;
-
-        let mut ifreq = self.get_ifreq();
-
-        // We only access one field of the ifru union, hence this is safe.
-        unsafe {
-            // This is safe since the MacAddress struct is already sized to match the C sockaddr
-            // struct.
-            ifreq.ifr_ifru.ifru_hwaddr = std::mem::transmute(mac_addr);
-        }
-
-        // ioctl is safe. Called with a valid sock fd, and we check the return.
-        let ret =
-            unsafe { ioctl_with_ref(&sock, net_sys::sockios::SIOCSIFHWADDR as IoctlNr, &ifreq) };
-        if ret < 0 {
-            return Err(Error::IoctlError(SysError::last()));
-        }
-
-        Ok(())
+        let mut socket = NetlinkSocket::new().map_err(Error::CreateSocket)?;
+        socket
+            .set_mac_address(self.link_index()?, mac_addr.octets())
+            .map_err(Error::Netlink)
     }
 
     fn set_offload(&self, flags: c_uint) -> Result<()> {
@@ -451,20 +578,10 @@ impl TapT for Tap {
     }
 
     fn enable(&self) -> Result<()> {
-        let sock = create_socket()?;
-
-        let mut ifreq = self.get_ifreq();
-        ifreq.ifr_ifru.ifru_flags =
-            (net_sys::net_device_flags_IFF_UP | net_sys::net_device_flags_IFF_RUNNING) as i16;
-
-        // ioctl is safe. Called with a valid sock fd, and we check the return.
-        let ret =
-            unsafe { ioctl_with_ref(&sock, net_sys::sockios::SIOCSIFFLAGS as IoctlNr, &ifreq) };
-        if ret < 0 {
-            return Err(Error::IoctlError(SysError::last()));
-        }
-
-        Ok(())
+        let mut socket = NetlinkSocket::new().map_err(Error::CreateSocket)?;
+        socket
+            .set_link_up(self.link_index()?, true)
+            .map_err(Error::Netlink)
     }
 
     fn set_vnet_hdr_size(&self, size: c_int) -> Result<()> {
@@ -556,6 +673,19 @@ pub mod fakes {
             Ok(Vec::new())
         }
 
+        fn try_clone(&self) -> Result<FakeTap> {
+            Ok(FakeTap {
+                tap_file: self
+                    .tap_file
+                    .try_clone()
+                    .map_err(|e| Error::CloneTap(e.into()))?,
+            })
+        }
+
+        fn set_queue_enabled(&self, _: bool) -> Result<()> {
+            Ok(())
+        }
+
         fn ip_addr(&self) -> Result<net::Ipv4Addr> {
             Ok(net::Ipv4Addr::new(1, 2, 3, 4))
         }
@@ -695,6 +825,9 @@ mod tests {
             // We won't have permission in test environments; allow that
             Ok(_t) => {}
             Err(Error::IoctlError(e)) if e.errno() == EPERM => {}
+            Err(Error::Netlink(e)) if e.errno() == EPERM => {}
+            // Without permission the tap isn't created, so it has no interface to configure.
+            Err(Error::Netlink(e)) if e.errno() == libc::ENODEV => {}
             Err(e) => panic!("Unexpected Error:\n{}", e),
         }
     }
@@ -0,0 +1,159 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A DHCP server that only ever hands out one address.
+
+use std::net::Ipv4Addr;
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+// Offset of the options, after the fixed BOOTP fields and the magic cookie.
+const OPTIONS_OFFSET: usize = 240;
+// BOOTP relays and old clients expect messages of at least this size.
+const MIN_MESSAGE_LEN: usize = 300;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+
+const LEASE_TIME_SECS: u32 = 24 * 60 * 60;
+
+/// The configuration handed out to the guest.
+pub struct Lease {
+    pub client: Ipv4Addr,
+    pub server: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub router: Ipv4Addr,
+    pub dns: Ipv4Addr,
+}
+
+fn message_type(options: &[u8]) -> Option<u8> {
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            OPT_END => break,
+            OPT_PAD => i += 1,
+            code => {
+                let len = *options.get(i + 1)? as usize;
+                let value = options.get(i + 2..i + 2 + len)?;
+                if code == OPT_MESSAGE_TYPE && len == 1 {
+                    return Some(value[0]);
+                }
+                i += 2 + len;
+            }
+        }
+    }
+    None
+}
+
+/// Returns the reply to the DHCP message `request`, or None if the server doesn't answer it.
+/// Discovers are offered the lease and requests are acknowledged with it, whatever address they
+/// ask for.
+pub fn reply(request: &[u8], lease: &Lease) -> Option<Vec<u8>> {
+    if request.len() < OPTIONS_OFFSET
+        || request[0] != BOOTREQUEST
+        || request[236..OPTIONS_OFFSET] != MAGIC_COOKIE
+    {
+        return None;
+    }
+    let reply_type = match message_type(&request[OPTIONS_OFFSET..])? {
+        DHCPDISCOVER => DHCPOFFER,
+        DHCPREQUEST => DHCPACK,
+        _ => return None,
+    };
+
+    let mut reply = vec![0u8; OPTIONS_OFFSET];
+    reply[0] = BOOTREPLY;
+    // Hardware type and address length.
+    reply[1..3].copy_from_slice(&request[1..3]);
+    // Transaction id, and the flags asking for a broadcast reply.
+    reply[4..8].copy_from_slice(&request[4..8]);
+    reply[10..12].copy_from_slice(&request[10..12]);
+    reply[16..20].copy_from_slice(&lease.client.octets());
+    reply[20..24].copy_from_slice(&lease.server.octets());
+    // Client hardware address.
+    reply[28..44].copy_from_slice(&request[28..44]);
+    reply[236..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+
+    reply.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, reply_type]);
+    reply.extend_from_slice(&[OPT_SERVER_ID, 4]);
+    reply.extend_from_slice(&lease.server.octets());
+    reply.extend_from_slice(&[OPT_LEASE_TIME, 4]);
+    reply.extend_from_slice(&LEASE_TIME_SECS.to_be_bytes());
+    reply.extend_from_slice(&[OPT_SUBNET_MASK, 4]);
+    reply.extend_from_slice(&lease.netmask.octets());
+    reply.extend_from_slice(&[OPT_ROUTER, 4]);
+    reply.extend_from_slice(&lease.router.octets());
+    reply.extend_from_slice(&[OPT_DNS, 4]);
+    reply.extend_from_slice(&lease.dns.octets());
+    reply.push(OPT_END);
+    if reply.len() < MIN_MESSAGE_LEN {
+        reply.resize(MIN_MESSAGE_LEN, OPT_PAD);
+    }
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lease() -> Lease {
+        Lease {
+            client: Ipv4Addr::new(10, 0, 2, 15),
+            server: Ipv4Addr::new(10, 0, 2, 2),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            router: Ipv4Addr::new(10, 0, 2, 2),
+            dns: Ipv4Addr::new(10, 0, 2, 3),
+        }
+    }
+
+    fn request(message_type: u8) -> Vec<u8> {
+        let mut request = vec![0u8; OPTIONS_OFFSET];
+        request[0] = BOOTREQUEST;
+        request[1] = 1;
+        request[2] = 6;
+        request[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        request[28..34].copy_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        request[236..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+        // A parameter request list before the message type.
+        request.extend_from_slice(&[55, 2, OPT_ROUTER, OPT_DNS]);
+        request.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, message_type, OPT_END]);
+        request
+    }
+
+    #[test]
+    fn offer_and_ack() {
+        let offer = reply(&request(DHCPDISCOVER), &lease()).unwrap();
+        assert_eq!(offer[0], BOOTREPLY);
+        assert_eq!(&offer[4..8], &[1, 2, 3, 4]);
+        assert_eq!(&offer[16..20], &[10, 0, 2, 15]);
+        assert_eq!(&offer[28..34], &[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        assert_eq!(message_type(&offer[OPTIONS_OFFSET..]), Some(DHCPOFFER));
+        assert!(offer.len() >= MIN_MESSAGE_LEN);
+
+        let ack = reply(&request(DHCPREQUEST), &lease()).unwrap();
+        assert_eq!(message_type(&ack[OPTIONS_OFFSET..]), Some(DHCPACK));
+    }
+
+    #[test]
+    fn ignored_messages() {
+        // DHCPRELEASE.
+        assert!(reply(&request(7), &lease()).is_none());
+        assert!(reply(&request(DHCPDISCOVER)[..100], &lease()).is_none());
+    }
+}
@@ -0,0 +1,767 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! User-mode networking, for guests whose host can't give them a tap device.
+//!
+//! The guest is put alone on a private network, behind a NAT that is implemented entirely in the
+//! crosvm process: a DHCP server hands it its address, DNS queries are forwarded to the host's
+//! name server, and TCP connections and UDP flows are relayed through ordinary host sockets, so no
+//! privileges are needed. Like QEMU's user networking, the gateway's address reaches the host's
+//! loopback interface, and ICMP is not forwarded.
+//!
+//! The virtio-net device sees a `UserTap`, which behaves like a tap device with a vnet header. The
+//! frames written to it are handled by a `Stack` running on another thread, outside of the
+//! device's sandbox.
+
+mod dhcp;
+mod packet;
+mod tcp;
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, Read, Result as IoResult, Write};
+use std::net::{self, Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::os::raw::*;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base::net::UnixSeqpacket;
+use base::{
+    add_fd_flags, error, volatile_impl, warn, AsRawDescriptor, Descriptor, Error as SysError,
+    Event, EventType, FileReadWriteVolatile, PollToken, RawDescriptor, WaitContext,
+};
+
+use self::dhcp::Lease;
+use self::packet::*;
+use self::tcp::TcpConn;
+use crate::{Error, MacAddress, Result, TapT};
+
+/// Network the guest is put on.
+pub const NETWORK: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 0);
+pub const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+/// Address of the gateway. Connections to it reach the host's loopback interface.
+pub const GATEWAY_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+/// Address of the name server, which forwards queries to the host's.
+pub const DNS_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+/// Address the guest is given by DHCP.
+pub const GUEST_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+
+const GATEWAY_MAC: [u8; ETH_ALEN] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const DNS_PORT: u16 = 53;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+// The size of the vnet header until the device sets it, as for a tap device.
+const DEFAULT_VNET_HDR_SIZE: usize = 10;
+// Largest frame the guest can send, with its vnet header and any segmentation offloads.
+const MAX_FRAME_SIZE: usize = 65562;
+// Frames waiting for room on the socket to the device. Any more are dropped, like a NIC with a
+// full receive queue does.
+const MAX_QUEUED_FRAMES: usize = 1024;
+// How often retransmissions and idle flows are checked.
+const TICK: Duration = Duration::from_millis(100);
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_UDP_PAYLOAD: usize = 65507;
+
+/// The device end of user-mode networking. Frames written to it are handled by the `Stack` it was
+/// created with, and frames from the stack are read from it.
+pub struct UserTap {
+    socket: UnixSeqpacket,
+    vnet_hdr_size: Arc<AtomicUsize>,
+}
+
+/// Creates a `UserTap` for a virtio-net device and the `Stack` that handles its frames.
+pub fn pair() -> Result<(UserTap, Stack)> {
+    let (tap_socket, stack_socket) =
+        UnixSeqpacket::pair().map_err(|e| Error::CreateSocket(e.into()))?;
+    for socket in &[&tap_socket, &stack_socket] {
+        add_fd_flags(socket.as_raw_descriptor(), libc::O_NONBLOCK).map_err(Error::CreateSocket)?;
+    }
+    let vnet_hdr_size = Arc::new(AtomicUsize::new(DEFAULT_VNET_HDR_SIZE));
+
+    let nameserver = match fs::read_to_string(RESOLV_CONF) {
+        Ok(contents) => parse_nameserver(&contents),
+        Err(e) => {
+            warn!("user-net: failed to read {}: {}", RESOLV_CONF, e);
+            None
+        }
+    };
+    if nameserver.is_none() {
+        warn!("user-net: no IPv4 name server found, DNS is unavailable to the guest");
+    }
+
+    let tap = UserTap {
+        socket: tap_socket,
+        vnet_hdr_size: vnet_hdr_size.clone(),
+    };
+    let stack = Stack {
+        socket: stack_socket,
+        vnet_hdr_size,
+        nameserver,
+        guest_mac: None,
+        to_guest: VecDeque::new(),
+        next_ip_id: 0,
+        next_id: 0,
+        udp_flows: HashMap::new(),
+        udp_ids: HashMap::new(),
+        tcp_conns: HashMap::new(),
+        tcp_ids: HashMap::new(),
+    };
+    Ok((tap, stack))
+}
+
+// Returns the first IPv4 name server in the contents of a resolv.conf.
+fn parse_nameserver(resolv_conf: &str) -> Option<Ipv4Addr> {
+    resolv_conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("nameserver") => words.next()?.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+fn unsupported() -> Error {
+    Error::IoctlError(SysError::new(libc::EOPNOTSUPP))
+}
+
+impl TapT for UserTap {
+    /// A user-mode tap only exists together with its stack, so use `pair()` instead.
+    fn new(_vnet_hdr: bool, _multi_vq: bool) -> Result<UserTap> {
+        Err(Error::CreateTap(SysError::new(libc::EOPNOTSUPP)))
+    }
+
+    fn into_mq_taps(self, vq_pairs: u16) -> Result<Vec<UserTap>> {
+        if vq_pairs > 1 {
+            return Err(Error::CreateTap(SysError::new(libc::EOPNOTSUPP)));
+        }
+        Ok(vec![self])
+    }
+
+    fn try_clone(&self) -> Result<UserTap> {
+        Ok(UserTap {
+            socket: self
+                .socket
+                .try_clone()
+                .map_err(|e| Error::CloneTap(e.into()))?,
+            vnet_hdr_size: self.vnet_hdr_size.clone(),
+        })
+    }
+
+    fn set_queue_enabled(&self, _enabled: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn ip_addr(&self) -> Result<net::Ipv4Addr> {
+        Ok(GATEWAY_ADDR)
+    }
+
+    fn set_ip_addr(&self, _ip_addr: net::Ipv4Addr) -> Result<()> {
+        Err(unsupported())
+    }
+
+    fn netmask(&self) -> Result<net::Ipv4Addr> {
+        Ok(NETMASK)
+    }
+
+    fn set_netmask(&self, _netmask: net::Ipv4Addr) -> Result<()> {
+        Err(unsupported())
+    }
+
+    fn mac_address(&self) -> Result<MacAddress> {
+        Ok(MacAddress::from_octets(GATEWAY_MAC))
+    }
+
+    fn set_mac_address(&self, _mac_addr: MacAddress) -> Result<()> {
+        Err(unsupported())
+    }
+
+    fn set_offload(&self, _flags: c_uint) -> Result<()> {
+        // The stack terminates the guest's connections, so it takes segments of any size and
+        // doesn't check their checksums. It never sends offloaded frames itself.
+        Ok(())
+    }
+
+    fn enable(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_vnet_hdr_size(&self, size: c_int) -> Result<()> {
+        if size < DEFAULT_VNET_HDR_SIZE as c_int {
+            return Err(Error::IoctlError(SysError::new(libc::EINVAL)));
+        }
+        self.vnet_hdr_size.store(size as usize, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn get_ifreq(&self) -> net_sys::ifreq {
+        Default::default()
+    }
+
+    fn if_flags(&self) -> u32 {
+        net_sys::IFF_TAP | net_sys::IFF_NO_PI | net_sys::IFF_VNET_HDR
+    }
+}
+
+impl Read for UserTap {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.socket.recv(buf)
+    }
+}
+
+impl Write for UserTap {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.socket.send(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for UserTap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_descriptor()
+    }
+}
+
+impl AsRawDescriptor for UserTap {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.socket.as_raw_descriptor()
+    }
+}
+
+volatile_impl!(UserTap);
+
+// A UDP flow from a guest port to a remote address, relayed through a connected host socket.
+struct UdpFlow {
+    socket: UdpSocket,
+    guest: SocketAddrV4,
+    remote: SocketAddrV4,
+    last_used: Instant,
+}
+
+#[derive(PollToken)]
+enum Token {
+    Device,
+    Kill,
+    Udp { id: usize },
+    Tcp { id: usize },
+}
+
+/// The network stack behind a `UserTap`.
+pub struct Stack {
+    socket: UnixSeqpacket,
+    vnet_hdr_size: Arc<AtomicUsize>,
+    nameserver: Option<Ipv4Addr>,
+    // Learned from the frames the guest sends.
+    guest_mac: Option<[u8; ETH_ALEN]>,
+    // Frames for the device that didn't fit in the socket yet, vnet header included.
+    to_guest: VecDeque<Vec<u8>>,
+    next_ip_id: u16,
+    // Identifies the flows and connections in wait tokens.
+    next_id: usize,
+    udp_flows: HashMap<usize, UdpFlow>,
+    udp_ids: HashMap<(SocketAddrV4, SocketAddrV4), usize>,
+    tcp_conns: HashMap<usize, TcpConn>,
+    tcp_ids: HashMap<(SocketAddrV4, SocketAddrV4), usize>,
+}
+
+impl Stack {
+    /// Handles the device's frames and the host's sockets until `kill_evt` is signaled.
+    pub fn run(&mut self, kill_evt: &Event) -> Result<()> {
+        let wait_ctx: WaitContext<Token> =
+            WaitContext::build_with(&[(&self.socket, Token::Device), (kill_evt, Token::Kill)])
+                .map_err(Error::UserNetWait)?;
+
+        let mut next_tick = Instant::now() + TICK;
+        'wait: loop {
+            let timeout = next_tick.saturating_duration_since(Instant::now());
+            let events = wait_ctx.wait_timeout(timeout).map_err(Error::UserNetWait)?;
+            for event in events.iter() {
+                match event.token {
+                    Token::Device => {
+                        if event.is_writable {
+                            self.flush_to_guest();
+                        }
+                        if event.is_readable {
+                            self.handle_device_frames(&wait_ctx);
+                        }
+                    }
+                    Token::Kill => break 'wait,
+                    Token::Udp { id } => self.handle_udp_ready(id),
+                    Token::Tcp { id } => {
+                        let mut out = Vec::new();
+                        if let Some(conn) = self.tcp_conns.get_mut(&id) {
+                            conn.host_ready(&mut out);
+                        }
+                        self.finish_tcp(&wait_ctx, id, out);
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            if now >= next_tick {
+                self.tick(&wait_ctx, now);
+                next_tick = now + TICK;
+            }
+
+            let device_events = if self.to_guest.is_empty() {
+                EventType::Read
+            } else {
+                EventType::ReadWrite
+            };
+            wait_ctx
+                .modify(&self.socket, device_events, Token::Device)
+                .map_err(Error::UserNetWait)?;
+        }
+        Ok(())
+    }
+
+    fn handle_device_frames(&mut self, wait_ctx: &WaitContext<Token>) {
+        let mut buf = vec![0u8; MAX_FRAME_SIZE];
+        loop {
+            let len = match self.socket.recv(&mut buf) {
+                Ok(0) => return,
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("user-net: failed to read frame from device: {}", e);
+                    return;
+                }
+            };
+            let vnet_hdr_size = self.vnet_hdr_size.load(Ordering::Relaxed);
+            if len > vnet_hdr_size {
+                self.handle_guest_frame(wait_ctx, &buf[vnet_hdr_size..len]);
+            }
+        }
+    }
+
+    fn handle_guest_frame(&mut self, wait_ctx: &WaitContext<Token>, frame: &[u8]) {
+        let (src_mac, ethertype, payload) = match parse_eth(frame) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+        // Only unicast addresses can be replied to.
+        if src_mac[0] & 1 == 0 {
+            self.guest_mac = Some(src_mac);
+        }
+
+        match ethertype {
+            ETH_P_ARP => {
+                if let Some(request) = parse_arp_request(payload) {
+                    if request.target_ip == GATEWAY_ADDR || request.target_ip == DNS_ADDR {
+                        let reply = arp_reply(&request, GATEWAY_MAC);
+                        self.send_frame(request.sender_mac, ETH_P_ARP, &reply);
+                    }
+                }
+            }
+            ETH_P_IP => {
+                if let Some(packet) = parse_ipv4(payload) {
+                    match packet.protocol {
+                        IPPROTO_UDP => self.handle_guest_udp(wait_ctx, &packet),
+                        IPPROTO_TCP => self.handle_guest_tcp(wait_ctx, &packet),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Returns the host address to relay traffic the guest sends to `dst` to.
+    fn host_addr(&self, dst: SocketAddrV4) -> Option<SocketAddrV4> {
+        let ip = *dst.ip();
+        if ip == GATEWAY_ADDR {
+            Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, dst.port()))
+        } else if ip == DNS_ADDR {
+            match (self.nameserver, dst.port()) {
+                (Some(nameserver), DNS_PORT) => Some(SocketAddrV4::new(nameserver, DNS_PORT)),
+                _ => None,
+            }
+        } else if in_network(ip)
+            || ip.is_unspecified()
+            || ip.is_loopback()
+            || ip.is_broadcast()
+            || ip.is_multicast()
+        {
+            None
+        } else {
+            Some(dst)
+        }
+    }
+
+    fn handle_guest_udp(&mut self, wait_ctx: &WaitContext<Token>, packet: &Ipv4Packet) {
+        let datagram = match parse_udp(packet.payload) {
+            Some(datagram) => datagram,
+            None => return,
+        };
+        if datagram.dst_port == dhcp::SERVER_PORT {
+            self.handle_dhcp(datagram.payload);
+            return;
+        }
+        if !is_guest_addr(packet.src) {
+            return;
+        }
+
+        let guest = SocketAddrV4::new(packet.src, datagram.src_port);
+        let remote = SocketAddrV4::new(packet.dst, datagram.dst_port);
+        let id = match self.udp_ids.get(&(guest, remote)) {
+            Some(&id) => id,
+            None => {
+                let host = match self.host_addr(remote) {
+                    Some(host) => host,
+                    None => return,
+                };
+                let socket = match connect_udp(host) {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        warn!("user-net: failed to open UDP socket to {}: {}", host, e);
+                        return;
+                    }
+                };
+                let id = self.new_id();
+                if let Err(e) = wait_ctx.add(&socket, Token::Udp { id }) {
+                    error!("user-net: failed to watch UDP socket: {}", e);
+                    return;
+                }
+                self.udp_flows.insert(
+                    id,
+                    UdpFlow {
+                        socket,
+                        guest,
+                        remote,
+                        last_used: Instant::now(),
+                    },
+                );
+                self.udp_ids.insert((guest, remote), id);
+                id
+            }
+        };
+
+        let flow = self.udp_flows.get_mut(&id).unwrap();
+        flow.last_used = Instant::now();
+        // Like a router, drop what can't be sent right away.
+        if let Err(e) = flow.socket.send(datagram.payload) {
+            if e.kind() != io::ErrorKind::WouldBlock {
+                warn!("user-net: failed to send UDP datagram to {}: {}", remote, e);
+            }
+        }
+    }
+
+    fn handle_udp_ready(&mut self, id: usize) {
+        let flow = match self.udp_flows.get_mut(&id) {
+            Some(flow) => flow,
+            None => return,
+        };
+        flow.last_used = Instant::now();
+        let (remote, guest) = (flow.remote, flow.guest);
+
+        let mut datagrams = Vec::new();
+        let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+        loop {
+            match flow.socket.recv(&mut buf) {
+                Ok(len) => datagrams.push(udp_datagram(remote, guest, &buf[..len])),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // Errors queued by ICMP messages from the remote end are dropped, like the
+                // messages themselves.
+                Err(_) => {}
+            }
+        }
+        for datagram in datagrams {
+            self.send_ipv4(*remote.ip(), *guest.ip(), IPPROTO_UDP, &datagram);
+        }
+    }
+
+    fn handle_dhcp(&mut self, request: &[u8]) {
+        let lease = Lease {
+            client: GUEST_ADDR,
+            server: GATEWAY_ADDR,
+            netmask: NETMASK,
+            router: GATEWAY_ADDR,
+            dns: DNS_ADDR,
+        };
+        if let Some(reply) = dhcp::reply(request, &lease) {
+            // The guest doesn't have its address yet, so broadcast the reply.
+            let datagram = udp_datagram(
+                SocketAddrV4::new(GATEWAY_ADDR, dhcp::SERVER_PORT),
+                SocketAddrV4::new(Ipv4Addr::BROADCAST, dhcp::CLIENT_PORT),
+                &reply,
+            );
+            self.send_ipv4(GATEWAY_ADDR, Ipv4Addr::BROADCAST, IPPROTO_UDP, &datagram);
+        }
+    }
+
+    fn handle_guest_tcp(&mut self, wait_ctx: &WaitContext<Token>, packet: &Ipv4Packet) {
+        let segment = match parse_tcp(packet.payload) {
+            Some(segment) => segment,
+            None => return,
+        };
+        if !is_guest_addr(packet.src) {
+            return;
+        }
+        let guest = SocketAddrV4::new(packet.src, segment.src_port);
+        let remote = SocketAddrV4::new(packet.dst, segment.dst_port);
+
+        if let Some(&id) = self.tcp_ids.get(&(guest, remote)) {
+            let mut out = Vec::new();
+            if let Some(conn) = self.tcp_conns.get_mut(&id) {
+                conn.guest_segment(&segment, &mut out);
+            }
+            self.finish_tcp(wait_ctx, id, out);
+            return;
+        }
+
+        if segment.flags & (TCP_SYN | TCP_ACK | TCP_RST) == TCP_SYN {
+            let conn = self
+                .host_addr(remote)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))
+                .and_then(|host| TcpConn::new(host, guest, remote, &segment));
+            match conn {
+                Ok(conn) => {
+                    let id = self.new_id();
+                    self.tcp_conns.insert(id, conn);
+                    self.tcp_ids.insert((guest, remote), id);
+                    self.finish_tcp(wait_ctx, id, Vec::new());
+                    return;
+                }
+                Err(e) => warn!("user-net: failed to connect to {}: {}", remote, e),
+            }
+        }
+        // Refuse segments that don't belong to any connection, as a host would.
+        if segment.flags & TCP_RST == 0 {
+            let seq = if segment.flags & TCP_ACK != 0 {
+                segment.ack
+            } else {
+                0
+            };
+            let mut ack = segment.seq.wrapping_add(segment.payload.len() as u32);
+            if segment.flags & (TCP_SYN | TCP_FIN) != 0 {
+                ack = ack.wrapping_add(1);
+            }
+            let header = TcpHeader {
+                src: remote,
+                dst: guest,
+                seq,
+                ack,
+                flags: TCP_RST | TCP_ACK,
+                window: 0,
+                mss: None,
+            };
+            let rst = tcp_segment(&header, &[]);
+            self.send_ipv4(packet.dst, packet.src, IPPROTO_TCP, &rst);
+        }
+    }
+
+    // Sends the segments a connection produced, then drops it if it's over, or updates the events
+    // its host socket is watched for.
+    fn finish_tcp(&mut self, wait_ctx: &WaitContext<Token>, id: usize, out: Vec<Vec<u8>>) {
+        let (src, dst, closed, interest) = match self.tcp_conns.get(&id) {
+            Some(conn) => (
+                *conn.remote().ip(),
+                *conn.guest().ip(),
+                conn.is_closed(),
+                conn.interest(),
+            ),
+            None => return,
+        };
+        for segment in out {
+            self.send_ipv4(src, dst, IPPROTO_TCP, &segment);
+        }
+
+        let conn = &self.tcp_conns[&id];
+        let descriptor = Descriptor(conn.stream().as_raw_fd());
+        // Whether or not it was watched, stop watching the socket, so that errors and hangups
+        // aren't reported while the connection isn't waiting for anything.
+        let _ = wait_ctx.delete(&descriptor);
+        if closed {
+            let key = (conn.guest(), conn.remote());
+            self.tcp_ids.remove(&key);
+            self.tcp_conns.remove(&id);
+            return;
+        }
+        if !matches!(interest, EventType::None) {
+            if let Err(e) = wait_ctx.add_for_event(&descriptor, interest, Token::Tcp { id }) {
+                error!("user-net: failed to watch TCP socket: {}", e);
+            }
+        }
+    }
+
+    // Retransmits what the guest didn't acknowledge in time, and forgets idle UDP flows.
+    fn tick(&mut self, wait_ctx: &WaitContext<Token>, now: Instant) {
+        let ids: Vec<usize> = self.tcp_conns.keys().cloned().collect();
+        for id in ids {
+            let mut out = Vec::new();
+            if let Some(conn) = self.tcp_conns.get_mut(&id) {
+                conn.timeout(now, &mut out);
+            }
+            if !out.is_empty() {
+                self.finish_tcp(wait_ctx, id, out);
+            }
+        }
+
+        let udp_ids = &mut self.udp_ids;
+        self.udp_flows.retain(|_, flow| {
+            let idle = now.saturating_duration_since(flow.last_used) > UDP_IDLE_TIMEOUT;
+            if idle {
+                let _ = wait_ctx.delete(&flow.socket);
+                udp_ids.remove(&(flow.guest, flow.remote));
+            }
+            !idle
+        });
+    }
+
+    fn new_id(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        id
+    }
+
+    fn send_ipv4(&mut self, src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, l4: &[u8]) {
+        let id = self.next_ip_id;
+        self.next_ip_id = self.next_ip_id.wrapping_add(1);
+        let dst_mac = match self.guest_mac {
+            Some(mac) if !dst.is_broadcast() => mac,
+            _ => BROADCAST_MAC,
+        };
+        for packet in ipv4_packets(src, dst, protocol, id, l4) {
+            self.send_frame(dst_mac, ETH_P_IP, &packet);
+        }
+    }
+
+    fn send_frame(&mut self, dst_mac: [u8; ETH_ALEN], ethertype: u16, payload: &[u8]) {
+        if self.to_guest.len() >= MAX_QUEUED_FRAMES {
+            return;
+        }
+        // An all zero header asks for no offloads.
+        let mut frame = vec![0u8; self.vnet_hdr_size.load(Ordering::Relaxed)];
+        frame.extend_from_slice(&eth_frame(dst_mac, GATEWAY_MAC, ethertype, payload));
+        self.to_guest.push_back(frame);
+        self.flush_to_guest();
+    }
+
+    fn flush_to_guest(&mut self) {
+        while let Some(frame) = self.to_guest.front() {
+            match self.socket.send(frame) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => error!("user-net: failed to send frame to device: {}", e),
+            }
+            self.to_guest.pop_front();
+        }
+    }
+}
+
+fn in_network(ip: Ipv4Addr) -> bool {
+    u32::from(ip) & u32::from(NETMASK) == u32::from(NETWORK)
+}
+
+// Returns whether the guest may use `ip` as its source address.
+fn is_guest_addr(ip: Ipv4Addr) -> bool {
+    in_network(ip) && ip != GATEWAY_ADDR && ip != DNS_ADDR && ip != NETWORK
+}
+
+fn connect_udp(host: SocketAddrV4) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(host)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nameserver() {
+        let resolv_conf = "# Generated\nsearch example.com\nnameserver fe80::1\n\
+                           nameserver 192.168.1.1\nnameserver 8.8.8.8\n";
+        assert_eq!(
+            parse_nameserver(resolv_conf),
+            Some(Ipv4Addr::new(192, 168, 1, 1))
+        );
+        assert_eq!(parse_nameserver("search example.com\n"), None);
+    }
+
+    #[test]
+    fn host_addresses() {
+        let (_, stack) = pair().unwrap();
+        let addr = |a, b, c, d, port| SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), port);
+        assert_eq!(
+            stack.host_addr(addr(10, 0, 2, 2, 8080)),
+            Some(addr(127, 0, 0, 1, 8080))
+        );
+        assert_eq!(
+            stack.host_addr(addr(93, 184, 216, 34, 443)),
+            Some(addr(93, 184, 216, 34, 443))
+        );
+        assert_eq!(stack.host_addr(addr(10, 0, 2, 3, 80)), None);
+        assert_eq!(stack.host_addr(addr(10, 0, 2, 7, 80)), None);
+        assert_eq!(stack.host_addr(addr(224, 0, 0, 251, 5353)), None);
+        assert!(is_guest_addr(GUEST_ADDR));
+        assert!(!is_guest_addr(GATEWAY_ADDR));
+    }
+
+    #[test]
+    fn arp_and_dhcp() {
+        let (mut tap, mut stack) = pair().unwrap();
+        let hdr = vec![0u8; DEFAULT_VNET_HDR_SIZE];
+        let guest_mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let wait_ctx: WaitContext<Token> = WaitContext::new().unwrap();
+
+        // Who has the gateway's address?
+        let mut arp = vec![0, 1, 8, 0, 6, 4, 0, 1];
+        arp.extend_from_slice(&guest_mac);
+        arp.extend_from_slice(&GUEST_ADDR.octets());
+        arp.extend_from_slice(&[0; ETH_ALEN]);
+        arp.extend_from_slice(&GATEWAY_ADDR.octets());
+        let frame = eth_frame(BROADCAST_MAC, guest_mac, ETH_P_ARP, &arp);
+        tap.write_all(&[&hdr[..], &frame[..]].concat()).unwrap();
+        stack.handle_device_frames(&wait_ctx);
+
+        let mut buf = vec![0u8; MAX_FRAME_SIZE];
+        let len = tap.read(&mut buf).unwrap();
+        let (src, ethertype, reply) = parse_eth(&buf[hdr.len()..len]).unwrap();
+        assert_eq!(src, GATEWAY_MAC);
+        assert_eq!(ethertype, ETH_P_ARP);
+        assert_eq!(&reply[8..14], &GATEWAY_MAC);
+        assert_eq!(&reply[14..18], &GATEWAY_ADDR.octets());
+
+        // A DHCPDISCOVER.
+        let mut discover = vec![0u8; 240];
+        discover[0] = 1;
+        discover[1] = 1;
+        discover[2] = 6;
+        discover[28..34].copy_from_slice(&guest_mac);
+        discover[236..240].copy_from_slice(&[99, 130, 83, 99]);
+        discover.extend_from_slice(&[53, 1, 1, 255]);
+        let datagram = udp_datagram(
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, dhcp::CLIENT_PORT),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, dhcp::SERVER_PORT),
+            &discover,
+        );
+        let packet = &ipv4_packets(
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::BROADCAST,
+            IPPROTO_UDP,
+            0,
+            &datagram,
+        )[0];
+        let frame = eth_frame(BROADCAST_MAC, guest_mac, ETH_P_IP, packet);
+        tap.write_all(&[&hdr[..], &frame[..]].concat()).unwrap();
+        stack.handle_device_frames(&wait_ctx);
+
+        let len = tap.read(&mut buf).unwrap();
+        let (_, ethertype, payload) = parse_eth(&buf[hdr.len()..len]).unwrap();
+        assert_eq!(ethertype, ETH_P_IP);
+        let packet = parse_ipv4(payload).unwrap();
+        let datagram = parse_udp(packet.payload).unwrap();
+        assert_eq!(datagram.dst_port, dhcp::CLIENT_PORT);
+        // The offered address.
+        assert_eq!(&datagram.payload[16..20], &GUEST_ADDR.octets());
+    }
+}
@@ -0,0 +1,397 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Parsing of the frames sent by the guest and building of the ones sent to it.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+pub const ETH_ALEN: usize = 6;
+pub const ETH_HLEN: usize = 14;
+pub const ETH_P_IP: u16 = 0x0800;
+pub const ETH_P_ARP: u16 = 0x0806;
+pub const BROADCAST_MAC: [u8; ETH_ALEN] = [0xff; ETH_ALEN];
+
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+
+/// Largest IPv4 packet sent to the guest.
+pub const MTU: usize = 1500;
+
+const IPV4_HLEN: usize = 20;
+const UDP_HLEN: usize = 8;
+const TCP_HLEN: usize = 20;
+const ARP_LEN: usize = 28;
+
+const IP_MF: u16 = 0x2000;
+const IP_OFFSET_MASK: u16 = 0x1fff;
+const IP_TTL: u8 = 64;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+
+fn be16(data: &[u8]) -> u16 {
+    u16::from_be_bytes([data[0], data[1]])
+}
+
+fn be32(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn sum16(data: &[u8]) -> u32 {
+    let mut sum = 0u32;
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += be16(chunk) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Computes the internet checksum of `data`.
+pub fn checksum(data: &[u8]) -> u16 {
+    fold(sum16(data))
+}
+
+// Computes the checksum of a TCP or UDP packet, including the IPv4 pseudo header.
+fn l4_checksum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, l4: &[u8]) -> u16 {
+    let sum =
+        sum16(&src.octets()) + sum16(&dst.octets()) + protocol as u32 + l4.len() as u32 + sum16(l4);
+    fold(sum)
+}
+
+/// Splits an ethernet frame into its source address, ethertype and payload.
+pub fn parse_eth(frame: &[u8]) -> Option<([u8; ETH_ALEN], u16, &[u8])> {
+    if frame.len() < ETH_HLEN {
+        return None;
+    }
+    let mut src = [0u8; ETH_ALEN];
+    src.copy_from_slice(&frame[ETH_ALEN..2 * ETH_ALEN]);
+    Some((src, be16(&frame[12..]), &frame[ETH_HLEN..]))
+}
+
+/// Builds an ethernet frame carrying `payload`.
+pub fn eth_frame(
+    dst: [u8; ETH_ALEN],
+    src: [u8; ETH_ALEN],
+    ethertype: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ETH_HLEN + payload.len());
+    frame.extend_from_slice(&dst);
+    frame.extend_from_slice(&src);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// An ARP request for the address of `target_ip`.
+pub struct ArpRequest {
+    pub sender_mac: [u8; ETH_ALEN],
+    pub sender_ip: Ipv4Addr,
+    pub target_ip: Ipv4Addr,
+}
+
+pub fn parse_arp_request(data: &[u8]) -> Option<ArpRequest> {
+    // Ethernet hardware addresses, IPv4 protocol addresses, and a request.
+    if data.len() < ARP_LEN || data[..8] != [0, 1, 8, 0, 6, 4, 0, 1] {
+        return None;
+    }
+    let mut sender_mac = [0u8; ETH_ALEN];
+    sender_mac.copy_from_slice(&data[8..14]);
+    Some(ArpRequest {
+        sender_mac,
+        sender_ip: Ipv4Addr::from(be32(&data[14..])),
+        target_ip: Ipv4Addr::from(be32(&data[24..])),
+    })
+}
+
+/// Builds the reply telling the sender of `request` that `mac` has its target address.
+pub fn arp_reply(request: &ArpRequest, mac: [u8; ETH_ALEN]) -> Vec<u8> {
+    let mut reply = Vec::with_capacity(ARP_LEN);
+    reply.extend_from_slice(&[0, 1, 8, 0, 6, 4, 0, 2]);
+    reply.extend_from_slice(&mac);
+    reply.extend_from_slice(&request.target_ip.octets());
+    reply.extend_from_slice(&request.sender_mac);
+    reply.extend_from_slice(&request.sender_ip.octets());
+    reply
+}
+
+/// An IPv4 packet sent by the guest. Fragments are not reassembled, so they aren't parsed.
+pub struct Ipv4Packet<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub payload: &'a [u8],
+}
+
+pub fn parse_ipv4(data: &[u8]) -> Option<Ipv4Packet> {
+    if data.len() < IPV4_HLEN || data[0] >> 4 != 4 {
+        return None;
+    }
+    let header_len = (data[0] & 0xf) as usize * 4;
+    let total_len = be16(&data[2..]) as usize;
+    if header_len < IPV4_HLEN || total_len < header_len || total_len > data.len() {
+        return None;
+    }
+    if be16(&data[6..]) & (IP_MF | IP_OFFSET_MASK) != 0 {
+        return None;
+    }
+    Some(Ipv4Packet {
+        src: Ipv4Addr::from(be32(&data[12..])),
+        dst: Ipv4Addr::from(be32(&data[16..])),
+        protocol: data[9],
+        payload: &data[header_len..total_len],
+    })
+}
+
+/// Builds the IPv4 packets carrying `l4` from `src` to `dst`, fragmenting it if it doesn't fit in
+/// the MTU.
+pub fn ipv4_packets(
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    id: u16,
+    l4: &[u8],
+) -> Vec<Vec<u8>> {
+    // Every fragment but the last carries a multiple of 8 bytes.
+    let max_fragment = (MTU - IPV4_HLEN) & !7;
+    let mut packets = Vec::new();
+    let mut offset = 0;
+    loop {
+        let len = (l4.len() - offset).min(max_fragment);
+        let more = offset + len < l4.len();
+        let mut flags_offset = (offset / 8) as u16;
+        if more {
+            flags_offset |= IP_MF;
+        }
+
+        let mut packet = Vec::with_capacity(IPV4_HLEN + len);
+        packet.extend_from_slice(&[0x45, 0]);
+        packet.extend_from_slice(&((IPV4_HLEN + len) as u16).to_be_bytes());
+        packet.extend_from_slice(&id.to_be_bytes());
+        packet.extend_from_slice(&flags_offset.to_be_bytes());
+        packet.extend_from_slice(&[IP_TTL, protocol, 0, 0]);
+        packet.extend_from_slice(&src.octets());
+        packet.extend_from_slice(&dst.octets());
+        let sum = checksum(&packet);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+        packet.extend_from_slice(&l4[offset..offset + len]);
+        packets.push(packet);
+
+        offset += len;
+        if !more {
+            return packets;
+        }
+    }
+}
+
+pub struct UdpDatagram<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: &'a [u8],
+}
+
+pub fn parse_udp(data: &[u8]) -> Option<UdpDatagram> {
+    if data.len() < UDP_HLEN {
+        return None;
+    }
+    let len = be16(&data[4..]) as usize;
+    if len < UDP_HLEN || len > data.len() {
+        return None;
+    }
+    Some(UdpDatagram {
+        src_port: be16(data),
+        dst_port: be16(&data[2..]),
+        payload: &data[UDP_HLEN..len],
+    })
+}
+
+/// Builds a UDP datagram from `src` to `dst`, checksum included.
+pub fn udp_datagram(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(UDP_HLEN + payload.len());
+    datagram.extend_from_slice(&src.port().to_be_bytes());
+    datagram.extend_from_slice(&dst.port().to_be_bytes());
+    datagram.extend_from_slice(&((UDP_HLEN + payload.len()) as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(payload);
+    let sum = match l4_checksum(*src.ip(), *dst.ip(), IPPROTO_UDP, &datagram) {
+        // A checksum of zero means there is none.
+        0 => 0xffff,
+        sum => sum,
+    };
+    datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+    datagram
+}
+
+pub struct TcpSegment<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    /// The maximum segment size option, only sent with SYN.
+    pub mss: Option<u16>,
+    pub payload: &'a [u8],
+}
+
+pub fn parse_tcp(data: &[u8]) -> Option<TcpSegment> {
+    if data.len() < TCP_HLEN {
+        return None;
+    }
+    let header_len = (data[12] >> 4) as usize * 4;
+    if header_len < TCP_HLEN || header_len > data.len() {
+        return None;
+    }
+
+    let mut mss = None;
+    let options = &data[TCP_HLEN..header_len];
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            TCP_OPT_END => break,
+            TCP_OPT_NOP => i += 1,
+            kind => {
+                let len = match options.get(i + 1) {
+                    Some(&len) if len >= 2 && i + len as usize <= options.len() => len as usize,
+                    _ => break,
+                };
+                if kind == TCP_OPT_MSS && len == 4 {
+                    mss = Some(be16(&options[i + 2..]));
+                }
+                i += len;
+            }
+        }
+    }
+
+    Some(TcpSegment {
+        src_port: be16(data),
+        dst_port: be16(&data[2..]),
+        seq: be32(&data[4..]),
+        ack: be32(&data[8..]),
+        flags: data[13],
+        window: be16(&data[14..]),
+        mss,
+        payload: &data[header_len..],
+    })
+}
+
+/// The header of a TCP segment sent to the guest.
+pub struct TcpHeader {
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub mss: Option<u16>,
+}
+
+/// Builds a TCP segment with `header` and `payload`, checksum included.
+pub fn tcp_segment(header: &TcpHeader, payload: &[u8]) -> Vec<u8> {
+    let options_len = if header.mss.is_some() { 4 } else { 0 };
+    let header_len = TCP_HLEN + options_len;
+    let mut segment = Vec::with_capacity(header_len + payload.len());
+    segment.extend_from_slice(&header.src.port().to_be_bytes());
+    segment.extend_from_slice(&header.dst.port().to_be_bytes());
+    segment.extend_from_slice(&header.seq.to_be_bytes());
+    segment.extend_from_slice(&header.ack.to_be_bytes());
+    segment.extend_from_slice(&[((header_len / 4) as u8) << 4, header.flags]);
+    segment.extend_from_slice(&header.window.to_be_bytes());
+    // Checksum and urgent pointer.
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(mss) = header.mss {
+        segment.extend_from_slice(&[TCP_OPT_MSS, 4]);
+        segment.extend_from_slice(&mss.to_be_bytes());
+    }
+    segment.extend_from_slice(payload);
+    let sum = l4_checksum(*header.src.ip(), *header.dst.ip(), IPPROTO_TCP, &segment);
+    segment[16..18].copy_from_slice(&sum.to_be_bytes());
+    segment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv4_header_checksum() {
+        let packets = ipv4_packets(
+            Ipv4Addr::new(10, 0, 2, 2),
+            Ipv4Addr::new(10, 0, 2, 15),
+            IPPROTO_UDP,
+            1,
+            &[0u8; 8],
+        );
+        assert_eq!(packets.len(), 1);
+        // A header with its checksum filled in sums to zero.
+        assert_eq!(checksum(&packets[0][..IPV4_HLEN]), 0);
+        let packet = parse_ipv4(&packets[0]).unwrap();
+        assert_eq!(packet.dst, Ipv4Addr::new(10, 0, 2, 15));
+        assert_eq!(packet.payload.len(), 8);
+    }
+
+    #[test]
+    fn ipv4_fragments() {
+        let l4 = vec![0u8; 4000];
+        let packets = ipv4_packets(
+            Ipv4Addr::new(10, 0, 2, 3),
+            Ipv4Addr::new(10, 0, 2, 15),
+            IPPROTO_UDP,
+            2,
+            &l4,
+        );
+        assert_eq!(packets.len(), 3);
+        assert!(packets.iter().all(|p| p.len() <= MTU));
+        assert_eq!(
+            packets.iter().map(|p| p.len() - IPV4_HLEN).sum::<usize>(),
+            l4.len()
+        );
+        // The guest's own fragments are never parsed.
+        assert!(parse_ipv4(&packets[0]).is_none());
+        assert!(parse_ipv4(&packets[2]).is_none());
+    }
+
+    #[test]
+    fn tcp_round_trip() {
+        let src = SocketAddrV4::new(Ipv4Addr::new(93, 184, 216, 34), 80);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 15), 40000);
+        let header = TcpHeader {
+            src,
+            dst,
+            seq: 1000,
+            ack: 2000,
+            flags: TCP_SYN | TCP_ACK,
+            window: 65535,
+            mss: Some(1460),
+        };
+        let segment = tcp_segment(&header, b"hi");
+        assert_eq!(l4_checksum(*src.ip(), *dst.ip(), IPPROTO_TCP, &segment), 0);
+
+        let parsed = parse_tcp(&segment).unwrap();
+        assert_eq!(parsed.src_port, 80);
+        assert_eq!(parsed.dst_port, 40000);
+        assert_eq!(parsed.seq, 1000);
+        assert_eq!(parsed.ack, 2000);
+        assert_eq!(parsed.flags, TCP_SYN | TCP_ACK);
+        assert_eq!(parsed.mss, Some(1460));
+        assert_eq!(parsed.payload, b"hi");
+    }
+}
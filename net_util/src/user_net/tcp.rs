@@ -0,0 +1,556 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Guest TCP connections, terminated in crosvm and relayed to host sockets.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddrV4, TcpStream};
+use std::os::unix::io::FromRawFd;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base::EventType;
+
+use super::packet::*;
+
+// Bytes received from the guest and not yet written to the host socket. Window scaling isn't
+// negotiated, so the window can't be any larger.
+const RECV_BUF_SIZE: usize = 65535;
+// Bytes read from the host socket and not yet acknowledged by the guest.
+const SEND_BUF_SIZE: usize = 256 * 1024;
+// Segments sent to the guest fit in the MTU, without IP or TCP options.
+const MAX_MSS: usize = MTU - 40;
+// The segment size to use if the guest doesn't say.
+const DEFAULT_MSS: usize = 536;
+// The link to the guest doesn't lose segments unless its receive queue is full, so a fixed
+// timeout is good enough.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_RETRANSMITS: u32 = 10;
+
+// Returns whether sequence number `a` comes after `b`.
+fn seq_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+// Starts connecting a non-blocking socket to `addr`.
+fn connect(addr: SocketAddrV4) -> io::Result<TcpStream> {
+    // Safe because we check the return value.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_INET,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because the socket was just created and nothing else owns it.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let sin = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // Safe because `sin` is a valid address of the given size, and we check the return value.
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &sin as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(e);
+        }
+    }
+    Ok(stream)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    // Waiting for the host socket to connect before answering the guest's SYN.
+    Connecting,
+    // Waiting for the guest to acknowledge our SYN.
+    SynReceived,
+    Established,
+    Closed,
+}
+
+/// A connection from the guest, relayed to a host socket.
+///
+/// The guest's segments are acknowledged as soon as they are buffered, and the host's data is sent
+/// to the guest as its window allows. Lost segments are resent from the oldest unacknowledged
+/// one.
+pub struct TcpConn {
+    stream: TcpStream,
+    // The guest's end of the connection.
+    guest: SocketAddrV4,
+    // The address the guest connected to.
+    remote: SocketAddrV4,
+    state: State,
+
+    // Next sequence number expected from the guest.
+    rcv_nxt: u32,
+    recv_buf: Vec<u8>,
+    // The window last advertised to the guest.
+    rcv_wnd: usize,
+    guest_fin: bool,
+    host_shutdown: bool,
+
+    // Oldest sequence number sent and not acknowledged. `send_buf` starts with it.
+    snd_una: u32,
+    // Next sequence number to send.
+    snd_nxt: u32,
+    // Highest sequence number sent so far.
+    snd_max: u32,
+    snd_wnd: usize,
+    mss: usize,
+    send_buf: VecDeque<u8>,
+    host_eof: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+
+    retransmit_at: Option<Instant>,
+    retransmits: u32,
+}
+
+impl TcpConn {
+    /// Starts relaying the connection the guest opened with `syn` from `guest` to `remote`, by
+    /// connecting to `host`.
+    pub fn new(
+        host: SocketAddrV4,
+        guest: SocketAddrV4,
+        remote: SocketAddrV4,
+        syn: &TcpSegment,
+    ) -> io::Result<TcpConn> {
+        let stream = connect(host)?;
+        // The sequence numbers only need to differ between connections to the same port.
+        let iss = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u32)
+            .unwrap_or(0);
+        let mss = syn
+            .mss
+            .map(|mss| mss as usize)
+            .unwrap_or(DEFAULT_MSS)
+            .min(MAX_MSS);
+        Ok(TcpConn {
+            stream,
+            guest,
+            remote,
+            state: State::Connecting,
+            rcv_nxt: syn.seq.wrapping_add(1),
+            recv_buf: Vec::new(),
+            rcv_wnd: RECV_BUF_SIZE,
+            guest_fin: false,
+            host_shutdown: false,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_max: iss,
+            snd_wnd: syn.window as usize,
+            mss,
+            send_buf: VecDeque::new(),
+            host_eof: false,
+            fin_sent: false,
+            fin_acked: false,
+            retransmit_at: None,
+            retransmits: 0,
+        })
+    }
+
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    pub fn guest(&self) -> SocketAddrV4 {
+        self.guest
+    }
+
+    pub fn remote(&self) -> SocketAddrV4 {
+        self.remote
+    }
+
+    /// Returns the events of the host socket the connection is waiting for.
+    pub fn interest(&self) -> EventType {
+        match self.state {
+            State::Connecting => EventType::Write,
+            State::Established => {
+                let read = !self.host_eof && self.send_buf.len() < SEND_BUF_SIZE;
+                let write = !self.recv_buf.is_empty();
+                match (read, write) {
+                    (true, true) => EventType::ReadWrite,
+                    (true, false) => EventType::Read,
+                    (false, true) => EventType::Write,
+                    (false, false) => EventType::None,
+                }
+            }
+            State::SynReceived | State::Closed => EventType::None,
+        }
+    }
+
+    /// Returns whether the connection is over and can be dropped.
+    pub fn is_closed(&self) -> bool {
+        self.state == State::Closed
+            || (self.guest_fin && self.host_shutdown && self.host_eof && self.fin_acked)
+    }
+
+    fn segment(&self, seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let header = TcpHeader {
+            src: self.remote,
+            dst: self.guest,
+            seq,
+            ack: self.rcv_nxt,
+            flags,
+            window: self.rcv_wnd as u16,
+            mss: if flags & TCP_SYN != 0 {
+                Some(MAX_MSS as u16)
+            } else {
+                None
+            },
+        };
+        tcp_segment(&header, payload)
+    }
+
+    fn send_syn_ack(&mut self, out: &mut Vec<Vec<u8>>) {
+        out.push(self.segment(self.snd_una, TCP_SYN | TCP_ACK, &[]));
+        self.snd_nxt = self.snd_una.wrapping_add(1);
+        self.snd_max = self.snd_nxt;
+        self.retransmit_at = Some(Instant::now() + RETRANSMIT_TIMEOUT);
+    }
+
+    fn send_ack(&mut self, out: &mut Vec<Vec<u8>>) {
+        self.rcv_wnd = RECV_BUF_SIZE - self.recv_buf.len();
+        out.push(self.segment(self.snd_nxt, TCP_ACK, &[]));
+    }
+
+    /// Aborts the connection, telling the guest.
+    fn reset(&mut self, out: &mut Vec<Vec<u8>>) {
+        out.push(self.segment(self.snd_nxt, TCP_RST | TCP_ACK, &[]));
+        self.state = State::Closed;
+    }
+
+    // Sends as much of the host's data as the guest's window allows, followed by a FIN once the
+    // host closed its end.
+    fn transmit(&mut self, out: &mut Vec<Vec<u8>>) {
+        if self.state != State::Established {
+            return;
+        }
+        loop {
+            let sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            if sent < self.send_buf.len() {
+                let len = self
+                    .mss
+                    .min(self.send_buf.len() - sent)
+                    .min(self.snd_wnd.saturating_sub(sent));
+                if len == 0 {
+                    break;
+                }
+                let payload: Vec<u8> = self.send_buf.iter().skip(sent).take(len).cloned().collect();
+                self.rcv_wnd = RECV_BUF_SIZE - self.recv_buf.len();
+                out.push(self.segment(self.snd_nxt, TCP_ACK | TCP_PSH, &payload));
+                self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+            } else if self.host_eof && !self.fin_sent {
+                self.rcv_wnd = RECV_BUF_SIZE - self.recv_buf.len();
+                out.push(self.segment(self.snd_nxt, TCP_FIN | TCP_ACK, &[]));
+                self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                self.fin_sent = true;
+            } else {
+                break;
+            }
+            if seq_after(self.snd_nxt, self.snd_max) {
+                self.snd_max = self.snd_nxt;
+            }
+            if self.retransmit_at.is_none() {
+                self.retransmit_at = Some(Instant::now() + RETRANSMIT_TIMEOUT);
+            }
+        }
+    }
+
+    fn process_ack(&mut self, ack: u32) {
+        let acked = ack.wrapping_sub(self.snd_una) as usize;
+        let outstanding = self.snd_max.wrapping_sub(self.snd_una) as usize;
+        if acked == 0 || acked > outstanding {
+            return;
+        }
+        let data_acked = acked.min(self.send_buf.len());
+        self.send_buf.drain(..data_acked);
+        if acked > data_acked {
+            self.fin_acked = true;
+        }
+        self.snd_una = ack;
+        if seq_after(ack, self.snd_nxt) {
+            self.snd_nxt = ack;
+        }
+        self.retransmits = 0;
+        self.retransmit_at = if self.snd_una == self.snd_max {
+            None
+        } else {
+            Some(Instant::now() + RETRANSMIT_TIMEOUT)
+        };
+    }
+
+    fn read_from_host(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 16 * 1024];
+        while !self.host_eof && self.send_buf.len() < SEND_BUF_SIZE {
+            let len = buf.len().min(SEND_BUF_SIZE - self.send_buf.len());
+            match self.stream.read(&mut buf[..len]) {
+                Ok(0) => self.host_eof = true,
+                Ok(count) => self.send_buf.extend(&buf[..count]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // Writes what the guest sent to the host socket, and passes on the guest's FIN once it's all
+    // written. Returns whether the receive window grew.
+    fn write_to_host(&mut self) -> io::Result<bool> {
+        let mut written = 0;
+        while written < self.recv_buf.len() {
+            match self.stream.write(&self.recv_buf[written..]) {
+                Ok(count) => written += count,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.recv_buf.drain(..written);
+        if self.guest_fin && self.recv_buf.is_empty() && !self.host_shutdown {
+            self.stream.shutdown(Shutdown::Write)?;
+            self.host_shutdown = true;
+        }
+        Ok(written > 0)
+    }
+
+    /// Handles the host socket becoming ready for the events in `interest()`.
+    pub fn host_ready(&mut self, out: &mut Vec<Vec<u8>>) {
+        match self.state {
+            State::Connecting => match self.stream.take_error() {
+                // Still connecting if there is no peer yet.
+                Ok(None) if self.stream.peer_addr().is_err() => {}
+                Ok(None) => {
+                    self.state = State::SynReceived;
+                    self.send_syn_ack(out);
+                }
+                _ => self.reset(out),
+            },
+            State::Established => {
+                let result = self.read_from_host().and_then(|_| self.write_to_host());
+                match result {
+                    Ok(window_grew) => {
+                        // Let the guest know if it held back for lack of window.
+                        if window_grew && self.rcv_wnd < self.mss {
+                            self.send_ack(out);
+                        }
+                        self.transmit(out);
+                    }
+                    Err(_) => self.reset(out),
+                }
+            }
+            State::SynReceived | State::Closed => {}
+        }
+    }
+
+    /// Handles a segment the guest sent on this connection.
+    pub fn guest_segment(&mut self, segment: &TcpSegment, out: &mut Vec<Vec<u8>>) {
+        if segment.flags & TCP_RST != 0 {
+            self.state = State::Closed;
+            return;
+        }
+        match self.state {
+            State::Connecting | State::Closed => return,
+            State::SynReceived => {
+                if segment.flags & TCP_SYN != 0 {
+                    // Our SYN-ACK was lost.
+                    self.send_syn_ack(out);
+                    return;
+                }
+                if segment.flags & TCP_ACK == 0 || segment.ack != self.snd_nxt {
+                    return;
+                }
+                self.state = State::Established;
+                self.snd_una = self.snd_nxt;
+                self.retransmit_at = None;
+                self.retransmits = 0;
+            }
+            State::Established => {
+                if segment.flags & TCP_SYN != 0 {
+                    self.send_ack(out);
+                    return;
+                }
+                if segment.flags & TCP_ACK != 0 {
+                    self.process_ack(segment.ack);
+                }
+            }
+        }
+        self.snd_wnd = segment.window as usize;
+
+        let fin = segment.flags & TCP_FIN != 0;
+        if !segment.payload.is_empty() || fin {
+            // Only take the next segment in order, and as much of it as fits. The guest resends
+            // the rest.
+            if segment.seq == self.rcv_nxt && !self.guest_fin {
+                let room = RECV_BUF_SIZE - self.recv_buf.len();
+                let len = segment.payload.len().min(room);
+                self.recv_buf.extend_from_slice(&segment.payload[..len]);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+                if fin && len == segment.payload.len() {
+                    self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                    self.guest_fin = true;
+                }
+            }
+            if self.write_to_host().is_err() {
+                self.reset(out);
+                return;
+            }
+            self.send_ack(out);
+        }
+        self.transmit(out);
+    }
+
+    /// Resends what the guest hasn't acknowledged if it has been too long.
+    pub fn timeout(&mut self, now: Instant, out: &mut Vec<Vec<u8>>) {
+        match self.retransmit_at {
+            Some(deadline) if deadline <= now => {}
+            _ => return,
+        }
+        self.retransmits += 1;
+        if self.retransmits > MAX_RETRANSMITS {
+            self.reset(out);
+            return;
+        }
+        self.retransmit_at = None;
+        match self.state {
+            State::SynReceived => self.send_syn_ack(out),
+            State::Established => {
+                self.snd_nxt = self.snd_una;
+                if !self.fin_acked {
+                    self.fin_sent = false;
+                }
+                self.transmit(out);
+            }
+            State::Connecting | State::Closed => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread::sleep;
+
+    fn guest() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 15), 40000)
+    }
+
+    fn guest_segment(seq: u32, ack: u32, flags: u8, payload: &[u8]) -> TcpSegment {
+        TcpSegment {
+            src_port: guest().port(),
+            dst_port: 0,
+            seq,
+            ack,
+            flags,
+            window: 65535,
+            mss: Some(1460),
+            payload,
+        }
+    }
+
+    fn wait_for_host(conn: &mut TcpConn, out: &mut Vec<Vec<u8>>) {
+        for _ in 0..100 {
+            conn.host_ready(out);
+            if !out.is_empty() {
+                return;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        panic!("host socket never became ready");
+    }
+
+    #[test]
+    fn relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+        let syn = guest_segment(100, 0, TCP_SYN, &[]);
+        let mut conn = TcpConn::new(host, guest(), host, &syn).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+
+        let mut out = Vec::new();
+        wait_for_host(&mut conn, &mut out);
+        let syn_ack = parse_tcp(&out[0]).unwrap();
+        assert_eq!(syn_ack.flags, TCP_SYN | TCP_ACK);
+        assert_eq!(syn_ack.ack, 101);
+        let seq = syn_ack.seq.wrapping_add(1);
+
+        out.clear();
+        conn.guest_segment(&guest_segment(101, seq, TCP_ACK, b"ping"), &mut out);
+        let ack = parse_tcp(&out[0]).unwrap();
+        assert_eq!(ack.ack, 105);
+        let mut buf = [0u8; 4];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        peer.write_all(b"pong").unwrap();
+        drop(peer);
+        out.clear();
+        for _ in 0..100 {
+            conn.host_ready(&mut out);
+            if out
+                .iter()
+                .any(|s| parse_tcp(s).unwrap().flags & TCP_FIN != 0)
+            {
+                break;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        let data = parse_tcp(&out[0]).unwrap();
+        assert_eq!(data.seq, seq);
+        assert_eq!(data.payload, b"pong");
+        let fin = parse_tcp(out.last().unwrap()).unwrap();
+        assert_eq!(fin.flags & TCP_FIN, TCP_FIN);
+        assert_eq!(fin.seq, seq.wrapping_add(4));
+
+        out.clear();
+        conn.guest_segment(
+            &guest_segment(105, seq.wrapping_add(5), TCP_ACK | TCP_FIN, &[]),
+            &mut out,
+        );
+        assert_eq!(parse_tcp(&out[0]).unwrap().ack, 106);
+        assert!(conn.is_closed());
+    }
+
+    #[test]
+    fn refused() {
+        // Grab a port nothing listens on.
+        let host = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            match listener.local_addr().unwrap() {
+                std::net::SocketAddr::V4(addr) => addr,
+                _ => unreachable!(),
+            }
+        };
+        let syn = guest_segment(100, 0, TCP_SYN, &[]);
+        let mut conn = TcpConn::new(host, guest(), host, &syn).unwrap();
+        let mut out = Vec::new();
+        wait_for_host(&mut conn, &mut out);
+        let rst = parse_tcp(&out[0]).unwrap();
+        assert_eq!(rst.flags & TCP_RST, TCP_RST);
+        assert_eq!(rst.ack, 101);
+        assert!(conn.is_closed());
+    }
+}
@@ -37,8 +37,12 @@ pub enum Alloc {
     GpuRenderNode,
     /// Pmem device region with associated device index.
     PmemDevice(usize),
+    /// Memory region plugged into the guest by the virtio-mem device.
+    VirtioMem,
     /// pstore region.
     Pstore,
+    /// A chunk of the virtio-wl shared memory area, with the index of the chunk.
+    WlShmemArea(usize),
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -0,0 +1,272 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Sizes the balloon from the memory pressure on the host, so that the guest gives memory back
+//! while the host is short of it and gets it again once the host recovers. The balloon can also be
+//! deflated when the guest itself runs short of memory.
+
+use std::cmp::{max, min};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use vm_control::BalloonStats;
+
+use crate::{AutoBalloonParameters, DeflateOnPressureParameters};
+
+/// The file the kernel reports system wide memory pressure in.
+pub const DEFAULT_PSI_PATH: &str = "/proc/pressure/memory";
+
+/// Number of adjustments it takes the balloon to go from its smallest to its largest size.
+const BALLOON_STEPS: u64 = 10;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Reads the share of the last 10 seconds, in percent, during which some task was stalled waiting
+/// for memory. `path` is a pressure stall information file, such as `/proc/pressure/memory` or the
+/// `memory.pressure` file of a cgroup.
+pub fn read_memory_pressure<P: AsRef<Path>>(path: P) -> io::Result<f64> {
+    parse_memory_pressure(BufReader::new(File::open(path)?))
+}
+
+fn parse_memory_pressure<R: BufRead>(reader: R) -> io::Result<f64> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    for line in reader.lines() {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+        if fields.next() != Some("some") {
+            continue;
+        }
+        for field in fields {
+            let mut kv = field.splitn(2, '=');
+            if kv.next() == Some("avg10") {
+                return kv
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .ok_or_else(|| invalid("invalid avg10 value"));
+            }
+        }
+    }
+    Err(invalid("missing `some avg10` pressure"))
+}
+
+/// Decides how large the balloon should be as the host memory pressure changes.
+///
+/// Pressure at or above the high threshold grows the balloon by a step, and pressure at or below
+/// the low one shrinks it by a step. Between the two the balloon is left alone, which keeps it
+/// from swinging back and forth around a single threshold.
+pub struct BalloonPolicy {
+    psi_path: PathBuf,
+    low_pressure: f64,
+    high_pressure: f64,
+    // Balloon sizes in bytes that leave the guest with its most and least memory.
+    min_balloon: u64,
+    max_balloon: u64,
+    step: u64,
+    balloon: u64,
+}
+
+impl BalloonPolicy {
+    /// Creates a policy for a guest with `guest_mem` bytes of memory. The balloon starts out at the
+    /// size that leaves the guest with as much memory as `params` allows.
+    pub fn new(params: &AutoBalloonParameters, guest_mem: u64) -> BalloonPolicy {
+        let max_guest_mem = params
+            .max_mem
+            .map_or(guest_mem, |mb| min(mb << 20, guest_mem));
+        let min_guest_mem = params
+            .min_mem
+            .map_or(guest_mem / 2, |mb| min(mb << 20, max_guest_mem));
+        let min_balloon = guest_mem - max_guest_mem;
+        let max_balloon = guest_mem - min_guest_mem;
+        BalloonPolicy {
+            psi_path: params.psi_path.clone(),
+            low_pressure: params.low_pressure,
+            high_pressure: params.high_pressure,
+            min_balloon,
+            max_balloon,
+            step: max((max_balloon - min_balloon) / BALLOON_STEPS, PAGE_SIZE),
+            balloon: min_balloon,
+        }
+    }
+
+    /// The pressure stall information file to read the host memory pressure from.
+    pub fn psi_path(&self) -> &Path {
+        &self.psi_path
+    }
+
+    /// The balloon size in bytes the policy last decided on.
+    pub fn balloon_size(&self) -> u64 {
+        self.balloon
+    }
+
+    /// Returns the new balloon size in bytes for the host memory `pressure`, or `None` if the
+    /// balloon should stay as it is.
+    pub fn update(&mut self, pressure: f64) -> Option<u64> {
+        let balloon = if pressure >= self.high_pressure {
+            min(self.balloon.saturating_add(self.step), self.max_balloon)
+        } else if pressure <= self.low_pressure {
+            max(self.balloon.saturating_sub(self.step), self.min_balloon)
+        } else {
+            self.balloon
+        };
+        if balloon == self.balloon {
+            return None;
+        }
+        self.balloon = balloon;
+        Some(balloon)
+    }
+
+    /// Records that the balloon was deflated to `num_bytes` for another reason, so that it grows
+    /// back from there step by step.
+    pub fn deflated_to(&mut self, num_bytes: u64) {
+        self.balloon = max(min(self.balloon, num_bytes), self.min_balloon);
+    }
+}
+
+/// Deflates the balloon when the guest runs short of memory.
+///
+/// This is meant for guests that don't honor `VIRTIO_BALLOON_F_DEFLATE_ON_OOM` well, which would
+/// rather kill their own tasks than take memory back from the balloon.
+pub struct DeflatePolicy {
+    min_available: u64,
+    step: u64,
+}
+
+impl DeflatePolicy {
+    pub fn new(params: &DeflateOnPressureParameters) -> DeflatePolicy {
+        DeflatePolicy {
+            min_available: params.min_available << 20,
+            step: params.step << 20,
+        }
+    }
+
+    /// Returns the balloon size in bytes to deflate to for the guest memory `stats`, or `None` if
+    /// the guest has enough memory. `balloon_actual` is the current size of the balloon.
+    pub fn update(&self, stats: &BalloonStats, balloon_actual: u64) -> Option<u64> {
+        // Older guests don't report their available memory, which free memory and the page cache
+        // approximate.
+        let available = match stats.available_memory {
+            Some(available) => available,
+            None => stats.free_memory? + stats.disk_caches.unwrap_or(0),
+        };
+        if available >= self.min_available || balloon_actual == 0 {
+            return None;
+        }
+        let deflate = max(self.min_available - available, self.step);
+        Some(balloon_actual.saturating_sub(deflate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST_MEM: u64 = 1 << 30;
+
+    fn params(min_mem: Option<u64>, max_mem: Option<u64>) -> AutoBalloonParameters {
+        AutoBalloonParameters {
+            min_mem,
+            max_mem,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_pressure() {
+        let psi = "some avg10=12.50 avg60=3.00 avg300=1.00 total=12345\n\
+                   full avg10=4.00 avg60=1.00 avg300=0.50 total=2345\n";
+        assert_eq!(parse_memory_pressure(psi.as_bytes()).unwrap(), 12.5);
+    }
+
+    #[test]
+    fn parse_pressure_invalid() {
+        assert!(parse_memory_pressure("".as_bytes()).is_err());
+        assert!(parse_memory_pressure("full avg10=1.00\n".as_bytes()).is_err());
+        assert!(parse_memory_pressure("some avg10=high\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn default_bounds() {
+        let mut policy = BalloonPolicy::new(&params(None, None), GUEST_MEM);
+        assert_eq!(policy.balloon_size(), 0);
+
+        // The balloon grows under pressure until the guest is left with half of its memory.
+        let mut size = 0;
+        while let Some(s) = policy.update(50.0) {
+            assert!(s > size);
+            size = s;
+        }
+        assert_eq!(size, GUEST_MEM / 2);
+
+        // Without pressure the guest gets all of its memory back.
+        while let Some(s) = policy.update(0.0) {
+            size = s;
+        }
+        assert_eq!(size, 0);
+    }
+
+    #[test]
+    fn configured_bounds() {
+        let mut policy = BalloonPolicy::new(&params(Some(256), Some(768)), GUEST_MEM);
+        assert_eq!(policy.balloon_size(), 256 << 20);
+        while policy.update(50.0).is_some() {}
+        assert_eq!(policy.balloon_size(), 768 << 20);
+        while policy.update(0.0).is_some() {}
+        assert_eq!(policy.balloon_size(), 256 << 20);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut policy = BalloonPolicy::new(&params(None, None), GUEST_MEM);
+        let low = policy.low_pressure;
+        let high = policy.high_pressure;
+        let grown = policy.update(high).unwrap();
+
+        // Pressure between the thresholds keeps the balloon where it is.
+        assert_eq!(policy.update((low + high) / 2.0), None);
+        assert_eq!(policy.balloon_size(), grown);
+        assert_eq!(policy.update(low), Some(0));
+    }
+
+    #[test]
+    fn deflate_on_pressure() {
+        let policy = DeflatePolicy::new(&DeflateOnPressureParameters {
+            min_available: 128,
+            step: 64,
+        });
+        let stats = |available_memory, free_memory| BalloonStats {
+            available_memory,
+            free_memory,
+            ..Default::default()
+        };
+
+        // The guest has enough memory.
+        assert_eq!(
+            policy.update(&stats(Some(256 << 20), None), 512 << 20),
+            None
+        );
+        // Deflating gives back at least a step, or all the guest is missing.
+        assert_eq!(
+            policy.update(&stats(Some(100 << 20), None), 512 << 20),
+            Some(448 << 20)
+        );
+        assert_eq!(
+            policy.update(&stats(None, Some(28 << 20)), 512 << 20),
+            Some(412 << 20)
+        );
+        assert_eq!(policy.update(&stats(Some(0), None), 64 << 20), Some(0));
+        // There is nothing left to give back, or nothing to decide from.
+        assert_eq!(policy.update(&stats(Some(0), None), 0), None);
+        assert_eq!(policy.update(&stats(None, None), 512 << 20), None);
+    }
+
+    #[test]
+    fn deflated_auto_balloon_grows_back() {
+        let mut policy = BalloonPolicy::new(&params(None, None), GUEST_MEM);
+        while policy.update(50.0).is_some() {}
+        policy.deflated_to(0);
+        assert_eq!(policy.balloon_size(), 0);
+        assert_eq!(policy.update(50.0), Some(policy.step));
+    }
+}
@@ -6,8 +6,10 @@
 //! configs.
 
 pub mod argument;
+pub mod balloon_policy;
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
 pub mod gdb;
+pub mod ivshmem_broker;
 #[path = "linux.rs"]
 pub mod platform;
 #[cfg(feature = "plugin")]
@@ -18,13 +20,16 @@ use std::net;
 use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
-use arch::{Pstore, SerialHardware, SerialParameters, VcpuAffinity};
+use arch::{ArchLayout, Pstore, PvFeatures, SerialHardware, SerialParameters, VcpuAffinity};
 use devices::virtio::fs::passthrough;
 #[cfg(feature = "gpu")]
 use devices::virtio::gpu::GpuParameters;
+use devices::virtio::{NetLink, ThrottleLimits, ZonedOption};
 #[cfg(feature = "audio")]
 use devices::Ac97Parameters;
+use disk::{CacheMode, QcowCacheSize};
 use libc::{getegid, geteuid};
 use vm_control::BatteryType;
 
@@ -46,10 +51,82 @@ pub enum Executable {
 /// This is based on the virtio-block ID length limit.
 pub const DISK_ID_LEN: usize = 20;
 
+/// Maximum number of PCIe root ports.
+///
+/// Each root port takes one secondary bus, and buses 1 to 31 are reachable through the PCIe
+/// extended configuration window.
+pub const MAX_PCIE_ROOT_PORTS: u8 = 31;
+
 pub struct DiskOption {
     pub path: PathBuf,
     pub read_only: bool,
     pub sparse: bool,
+    /// How the host caches the disk's data.
+    pub cache_mode: CacheMode,
+    /// Complete the guest flushes made together after a single sync of the disk.
+    pub flush_coalescing: bool,
+    /// The most bytes that guest reads or writes of contiguous sectors are merged into, or zero
+    /// to not merge them.
+    pub max_merge_size: u64,
+    pub block_size: u32,
+    pub id: Option<[u8; DISK_ID_LEN]>,
+    /// Number of virtqueues, each served by its own worker thread.
+    pub num_queues: u16,
+    pub busy_poll: Option<Duration>,
+    /// Minimum time between used-ring interrupts, or `None` to interrupt after every request.
+    pub interrupt_interval: Option<Duration>,
+    /// Rate limits on the disk's requests.
+    pub throttle: ThrottleLimits,
+    /// Number of tables cached in memory if the image is a qcow file.
+    pub qcow_cache_size: QcowCacheSize,
+    /// How the disk is split into zones, if it's zoned.
+    pub zoned: Option<ZonedOption>,
+    /// Refuse to start if another process has the disk open. Only checked for writable disks.
+    pub exclusive: bool,
+}
+
+/// A LIO target exported to the guest through vhost-scsi.
+#[derive(Debug)]
+pub struct VhostScsiOption {
+    /// World wide port name of the vhost target.
+    pub wwpn: String,
+    /// Target portal group tag under `wwpn`.
+    pub tpgt: u16,
+    /// Number of request queues.
+    pub num_queues: u16,
+}
+
+/// A network device whose datapath is handled by a vhost-user backend, such as a DPDK or Open
+/// vSwitch switch.
+pub struct VhostUserNetOption {
+    /// Socket the backend listens on.
+    pub socket: PathBuf,
+    /// Address of the device given to the guest. The guest picks one if unset.
+    pub mac: Option<net_util::MacAddress>,
+}
+
+/// A macvtap interface crosvm creates for a network device.
+#[derive(Debug)]
+pub struct MacvtapOption {
+    /// Host interface the macvtap interface is created on.
+    pub parent: String,
+    pub mode: net_util::MacvtapMode,
+}
+
+/// A logical unit of the virtio-scsi controller.
+#[derive(Debug)]
+pub struct ScsiDiskOption {
+    pub path: PathBuf,
+    pub read_only: bool,
+    /// Target and LUN the unit is addressed by.
+    pub target: u8,
+    pub lun: u16,
+    /// Pass the guest's commands through to the host SCSI device at `path` with `SG_IO`, instead
+    /// of emulating a disk on the image at `path`.
+    pub passthrough: bool,
+    pub sparse: bool,
+    /// How the host caches the disk's data.
+    pub cache_mode: CacheMode,
     pub block_size: u32,
     pub id: Option<[u8; DISK_ID_LEN]>,
 }
@@ -153,7 +230,7 @@ pub struct SharedDir {
     pub uid_map: String,
     pub gid_map: String,
     pub fs_cfg: passthrough::Config,
-    pub p9_cfg: p9::Config,
+    pub p9_cfg: devices::virtio::P9Config,
 }
 
 impl Default for SharedDir {
@@ -170,16 +247,72 @@ impl Default for SharedDir {
     }
 }
 
+/// Options for letting crosvm size the balloon from the host memory pressure.
+#[derive(Debug)]
+pub struct AutoBalloonParameters {
+    /// Least memory in MiB the balloon leaves the guest, or half of its memory if `None`.
+    pub min_mem: Option<u64>,
+    /// Most memory in MiB the balloon leaves the guest, or all of it if `None`.
+    pub max_mem: Option<u64>,
+    /// Host memory pressure in percent at or below which the balloon shrinks.
+    pub low_pressure: f64,
+    /// Host memory pressure in percent at or above which the balloon grows.
+    pub high_pressure: f64,
+    /// Pressure stall information file the host memory pressure is read from.
+    pub psi_path: PathBuf,
+}
+
+impl Default for AutoBalloonParameters {
+    fn default() -> AutoBalloonParameters {
+        AutoBalloonParameters {
+            min_mem: None,
+            max_mem: None,
+            low_pressure: 1.0,
+            high_pressure: 10.0,
+            psi_path: PathBuf::from(balloon_policy::DEFAULT_PSI_PATH),
+        }
+    }
+}
+
+/// Options for letting crosvm deflate the balloon when the guest runs short of memory.
+#[derive(Debug)]
+pub struct DeflateOnPressureParameters {
+    /// Guest available memory in MiB below which the balloon deflates.
+    pub min_available: u64,
+    /// Least memory in MiB each deflation gives back to the guest.
+    pub step: u64,
+}
+
+impl Default for DeflateOnPressureParameters {
+    fn default() -> DeflateOnPressureParameters {
+        DeflateOnPressureParameters {
+            min_available: 128,
+            step: 64,
+        }
+    }
+}
+
 /// Aggregate of all configurable options for a running VM.
 pub struct Config {
+    /// Name that tells this VM apart from others on the host, in logs and control socket paths.
+    pub name: Option<String>,
+    /// System UUID the guest sees, in RFC 4122 byte order.
+    pub uuid: Option<[u8; 16]>,
     pub vcpu_count: Option<usize>,
     pub rt_cpus: Vec<usize>,
     pub vcpu_affinity: Option<VcpuAffinity>,
     pub no_smt: bool,
+    pub pv_features: PvFeatures,
+    pub layout: ArchLayout,
     pub memory: Option<u64>,
+    pub auto_balloon: Option<AutoBalloonParameters>,
+    pub deflate_on_pressure: Option<DeflateOnPressureParameters>,
+    /// Size in bytes of the memory region the guest can plug through a virtio-mem device.
+    pub virtio_mem_size: Option<u64>,
     pub executable_path: Option<Executable>,
     pub android_fstab: Option<PathBuf>,
     pub initrd_path: Option<PathBuf>,
+    pub pflash_path: Option<PathBuf>,
     pub params: Vec<String>,
     pub socket_path: Option<PathBuf>,
     pub plugin_root: Option<PathBuf>,
@@ -192,11 +325,40 @@ pub struct Config {
     pub netmask: Option<net::Ipv4Addr>,
     pub mac_address: Option<net_util::MacAddress>,
     pub net_vq_pairs: Option<u16>,
+    pub net_interrupt_interval: Option<Duration>,
+    /// Files to write packet captures of virtio-net devices to, by device index.
+    pub net_capture: BTreeMap<usize, PathBuf>,
+    /// Link properties advertised by virtio-net devices, by device index.
+    pub net_link: BTreeMap<usize, NetLink>,
     pub vhost_net: bool,
-    pub tap_fd: Vec<RawFd>,
+    pub vhost_scsi: Vec<VhostScsiOption>,
+    /// Sockets of the vhost-user block backends serving disks of the VM.
+    pub vhost_user_blk: Vec<PathBuf>,
+    /// Network devices served by vhost-user backends.
+    pub vhost_user_net: Vec<VhostUserNetOption>,
+    pub scsi_disks: Vec<ScsiDiskOption>,
+    pub e1000: bool,
+    /// Sockets of the ivshmem brokers whose shared memory regions are given to the VM.
+    pub ivshmem: Vec<PathBuf>,
+    /// Descriptors of configured tap or macvtap devices, one list per network device, with a
+    /// descriptor for each of its queue pairs.
+    pub tap_fd: Vec<Vec<RawFd>>,
+    /// Bridges to attach a tap that crosvm creates to, one network device each.
+    pub net_bridges: Vec<String>,
+    /// Macvtap interfaces to create, one network device each.
+    pub macvtaps: Vec<MacvtapOption>,
+    /// Whether to add a network device whose traffic is forwarded by crosvm's own user-mode
+    /// network stack.
+    pub user_net: bool,
     pub cid: Option<u64>,
+    /// Unix sockets that guest connections to these host vsock ports are bridged to.
+    pub vsock_host_ports: BTreeMap<u32, PathBuf>,
+    /// Unix sockets to create, whose connections are bridged to these guest vsock ports.
+    pub vsock_guest_ports: BTreeMap<u32, PathBuf>,
     pub wayland_socket_paths: BTreeMap<String, PathBuf>,
     pub wayland_dmabuf: bool,
+    /// Host unix sockets the guest can connect to through the cross-domain device, by name.
+    pub cross_domain_services: BTreeMap<String, PathBuf>,
     pub x_display: Option<String>,
     pub shared_dirs: Vec<SharedDir>,
     pub sandbox: bool,
@@ -205,12 +367,15 @@ pub struct Config {
     #[cfg(feature = "gpu")]
     pub gpu_parameters: Option<GpuParameters>,
     pub software_tpm: bool,
+    pub fault_injection: bool,
+    pub guest_telemetry: bool,
     pub display_window_keyboard: bool,
     pub display_window_mouse: bool,
     #[cfg(feature = "audio")]
     pub ac97_parameters: Vec<Ac97Parameters>,
     pub serial_parameters: BTreeMap<(SerialHardware, u8), SerialParameters>,
     pub syslog_tag: Option<String>,
+    pub trace_output: Option<PathBuf>,
     pub virtio_single_touch: Option<TouchDeviceOption>,
     pub virtio_trackpad: Option<TouchDeviceOption>,
     pub virtio_mouse: Option<PathBuf>,
@@ -218,6 +383,7 @@ pub struct Config {
     pub virtio_input_evdevs: Vec<PathBuf>,
     pub split_irqchip: bool,
     pub vfio: Vec<PathBuf>,
+    pub pcie_root_ports: u8,
     pub video_dec: bool,
     pub video_enc: bool,
     pub acpi_tables: Vec<PathBuf>,
@@ -230,14 +396,22 @@ pub struct Config {
 impl Default for Config {
     fn default() -> Config {
         Config {
+            name: None,
+            uuid: None,
             vcpu_count: None,
             rt_cpus: Vec::new(),
             vcpu_affinity: None,
             no_smt: false,
+            pv_features: Default::default(),
+            layout: Default::default(),
             memory: None,
+            auto_balloon: None,
+            deflate_on_pressure: None,
+            virtio_mem_size: None,
             executable_path: None,
             android_fstab: None,
             initrd_path: None,
+            pflash_path: None,
             params: Vec::new(),
             socket_path: None,
             plugin_root: None,
@@ -250,14 +424,31 @@ impl Default for Config {
             netmask: None,
             mac_address: None,
             net_vq_pairs: None,
+            net_interrupt_interval: None,
+            net_capture: BTreeMap::new(),
+            net_link: BTreeMap::new(),
             vhost_net: false,
+            vhost_scsi: Vec::new(),
+            vhost_user_blk: Vec::new(),
+            vhost_user_net: Vec::new(),
+            scsi_disks: Vec::new(),
+            e1000: false,
+            ivshmem: Vec::new(),
             tap_fd: Vec::new(),
+            net_bridges: Vec::new(),
+            macvtaps: Vec::new(),
+            user_net: false,
             cid: None,
+            vsock_host_ports: BTreeMap::new(),
+            vsock_guest_ports: BTreeMap::new(),
             #[cfg(feature = "gpu")]
             gpu_parameters: None,
             software_tpm: false,
+            fault_injection: false,
+            guest_telemetry: false,
             wayland_socket_paths: BTreeMap::new(),
             wayland_dmabuf: false,
+            cross_domain_services: BTreeMap::new(),
             x_display: None,
             display_window_keyboard: false,
             display_window_mouse: false,
@@ -269,6 +460,7 @@ impl Default for Config {
             ac97_parameters: Vec::new(),
             serial_parameters: BTreeMap::new(),
             syslog_tag: None,
+            trace_output: None,
             virtio_single_touch: None,
             virtio_trackpad: None,
             virtio_mouse: None,
@@ -276,6 +468,7 @@ impl Default for Config {
             virtio_input_evdevs: Vec::new(),
             split_irqchip: false,
             vfio: Vec::new(),
+            pcie_root_ports: 0,
             video_dec: false,
             video_enc: false,
             acpi_tables: Vec::new(),
@@ -0,0 +1,228 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The ivshmem broker shares one memory region between the VMs that connect to it, and gives each
+//! of them a peer id and the doorbells of all the peers. VMs then exchange data through the region
+//! and interrupt each other with the doorbells, without going through the network.
+//!
+//! A VM keeps its connection to the broker open while it runs. Once the connection closes, its
+//! peer id is given to the next VM that connects.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+
+use base::net::{UnixSeqpacket, UnixSeqpacketListener, UnlinkUnixSeqpacketListener};
+use base::{error, info, warn, AsRawDescriptor, Event, PollToken, SharedMemory, WaitContext};
+use msg_socket::{MsgError, MsgReceiver, MsgSender, MsgSocket};
+use vm_control::{IvshmemBrokerSocket, IvshmemPeerSocket, IvshmemSetup};
+
+#[derive(Debug)]
+pub enum Error {
+    /// Accepting a connection failed.
+    Accept(io::Error),
+    /// Binding the broker socket failed.
+    Bind(io::Error),
+    /// Duplicating the region descriptor failed.
+    CloneRegion(base::Error),
+    /// Connecting to the broker failed.
+    Connect(io::Error),
+    /// Creating a doorbell failed.
+    CreateDoorbell(base::Error),
+    /// Creating the shared memory region failed.
+    CreateRegion(base::Error),
+    /// The region size isn't a power of two of at least a page.
+    InvalidSize(u64),
+    /// Receiving the setup from the broker failed.
+    Recv(MsgError),
+    /// Sending the setup to a VM failed.
+    Send(MsgError),
+    /// Waiting for connections failed.
+    WaitContext(base::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Accept(e) => write!(f, "failed to accept connection: {}", e),
+            Bind(e) => write!(f, "failed to bind broker socket: {}", e),
+            CloneRegion(e) => write!(f, "failed to duplicate shared memory descriptor: {}", e),
+            Connect(e) => write!(f, "failed to connect to ivshmem broker: {}", e),
+            CreateDoorbell(e) => write!(f, "failed to create doorbell: {}", e),
+            CreateRegion(e) => write!(f, "failed to create shared memory: {}", e),
+            InvalidSize(size) => write!(
+                f,
+                "shared memory size {} is not a power of two of at least a page",
+                size
+            ),
+            Recv(MsgError::RecvZero) => write!(f, "the ivshmem broker has no free peer id"),
+            Recv(e) => write!(f, "failed to receive setup from ivshmem broker: {}", e),
+            Send(e) => write!(f, "failed to send setup: {}", e),
+            WaitContext(e) => write!(f, "failed to wait for connections: {}", e),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Joins the memory region shared by the broker listening at `path`. The returned socket must be
+/// kept open for as long as the VM uses the region.
+pub fn connect(path: &Path) -> Result<(IvshmemSetup, IvshmemPeerSocket)> {
+    let socket: IvshmemPeerSocket =
+        MsgSocket::new(UnixSeqpacket::connect(path).map_err(Error::Connect)?);
+    let setup = socket.recv().map_err(Error::Recv)?;
+    Ok((setup, socket))
+}
+
+pub struct Broker {
+    listener: UnlinkUnixSeqpacketListener,
+    region: SharedMemory,
+    doorbells: Vec<Event>,
+    // The connection of each peer id that is in use.
+    peers: Vec<Option<IvshmemBrokerSocket>>,
+}
+
+impl Broker {
+    /// Creates a broker listening at `path`, sharing a region of `size` bytes between up to
+    /// `num_peers` VMs.
+    pub fn new(path: &Path, size: u64, num_peers: u16) -> Result<Broker> {
+        if !size.is_power_of_two() || size < base::pagesize() as u64 {
+            return Err(Error::InvalidSize(size));
+        }
+        let region = SharedMemory::named("crosvm_ivshmem", size).map_err(Error::CreateRegion)?;
+        let doorbells = (0..num_peers)
+            .map(|_| Event::new())
+            .collect::<base::Result<Vec<_>>>()
+            .map_err(Error::CreateDoorbell)?;
+        let listener =
+            UnlinkUnixSeqpacketListener(UnixSeqpacketListener::bind(path).map_err(Error::Bind)?);
+        Ok(Broker {
+            listener,
+            region,
+            doorbells,
+            peers: (0..num_peers).map(|_| None).collect(),
+        })
+    }
+
+    /// Serves VMs until an error occurs.
+    pub fn run(&mut self) -> Result<()> {
+        #[derive(PollToken)]
+        enum Token {
+            Listener,
+            Peer { id: usize },
+        }
+
+        let wait_ctx: WaitContext<Token> =
+            WaitContext::build_with(&[(&self.listener, Token::Listener)])
+                .map_err(Error::WaitContext)?;
+        loop {
+            let events = wait_ctx.wait().map_err(Error::WaitContext)?;
+            for event in events.iter() {
+                match event.token {
+                    Token::Listener => {
+                        if let Some(id) = self.accept()? {
+                            // Unwrap is safe because the peer was just connected.
+                            let peer = self.peers[id].as_ref().unwrap();
+                            wait_ctx
+                                .add(peer, Token::Peer { id })
+                                .map_err(Error::WaitContext)?;
+                        }
+                    }
+                    // VMs never send anything, so any event means the connection closed.
+                    Token::Peer { id } => {
+                        if let Some(peer) = self.peers[id].take() {
+                            wait_ctx.delete(&peer).map_err(Error::WaitContext)?;
+                            info!("ivshmem peer {} left", id);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Hands the region and doorbells to a new VM, returning its peer id. The connection is closed
+    // right away if every peer id is in use.
+    fn accept(&mut self) -> Result<Option<usize>> {
+        let socket: IvshmemBrokerSocket =
+            MsgSocket::new(self.listener.accept().map_err(Error::Accept)?);
+        let id = match self.peers.iter().position(|peer| peer.is_none()) {
+            Some(id) => id,
+            None => {
+                warn!("ivshmem broker is full, rejecting a VM");
+                return Ok(None);
+            }
+        };
+
+        // Safe because we own the region descriptor and check the result.
+        let fd = unsafe { libc::dup(self.region.as_raw_descriptor()) };
+        if fd < 0 {
+            return Err(Error::CloneRegion(base::Error::last()));
+        }
+        let setup = IvshmemSetup {
+            peer_id: id as u16,
+            // Safe because `fd` was just duplicated and nothing else owns it.
+            region: unsafe { File::from_raw_fd(fd) },
+            doorbells: self
+                .doorbells
+                .iter()
+                .map(Event::try_clone)
+                .collect::<base::Result<Vec<_>>>()
+                .map_err(Error::CreateDoorbell)?,
+        };
+        if let Err(e) = socket.send(&setup) {
+            // The VM went away before it joined, which leaves the id free.
+            error!("{}", Error::Send(e));
+            return Ok(None);
+        }
+        info!("ivshmem peer {} joined", id);
+        self.peers[id] = Some(socket);
+        Ok(Some(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use tempfile::TempDir;
+
+    #[test]
+    fn peers_share_region() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ivshmem.sock");
+        let mut broker = Broker::new(&path, 0x10000, 2).unwrap();
+        thread::spawn(move || broker.run());
+
+        let (first, _first_socket) = connect(&path).unwrap();
+        let (second, second_socket) = connect(&path).unwrap();
+        assert_eq!(first.peer_id, 0);
+        assert_eq!(second.peer_id, 1);
+        assert_eq!(first.region.metadata().unwrap().len(), 0x10000);
+
+        // The first peer rings the second peer's doorbell.
+        first.doorbells[1].write(1).unwrap();
+        assert_eq!(second.doorbells[1].read().unwrap(), 1);
+
+        // Every id is taken until the second peer leaves.
+        assert!(matches!(
+            connect(&path),
+            Err(Error::Recv(MsgError::RecvZero))
+        ));
+        drop(second_socket);
+        loop {
+            match connect(&path) {
+                Ok((third, _)) => {
+                    assert_eq!(third.peer_id, 1);
+                    break;
+                }
+                Err(Error::Recv(MsgError::RecvZero)) => thread::yield_now(),
+                Err(e) => panic!("failed to connect: {}", e),
+            }
+        }
+    }
+}
@@ -9,7 +9,7 @@ use std::env;
 use std::error::Error as StdError;
 use std::ffi::CStr;
 use std::fmt::{self, Display};
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, stdin, Read};
 use std::iter;
 use std::mem;
@@ -17,6 +17,7 @@ use std::net::Ipv4Addr;
 #[cfg(feature = "gpu")]
 use std::num::NonZeroU8;
 use std::num::ParseIntError;
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::FromRawFd;
 use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
@@ -26,7 +27,7 @@ use std::sync::{mpsc, Arc, Barrier};
 
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use libc::{self, c_int, gid_t, uid_t};
 
@@ -35,18 +36,20 @@ use acpi_tables::sdt::SDT;
 use base::net::{UnixSeqpacket, UnixSeqpacketListener, UnlinkUnixSeqpacketListener};
 #[cfg(feature = "gpu")]
 use devices::virtio::EventDevice;
-use devices::virtio::{self, Console, VirtioDevice};
+use devices::virtio::{self, Console, VirtioDevice, ZonedOption};
 #[cfg(feature = "audio")]
 use devices::Ac97Dev;
 use devices::{
-    self, HostBackendDeviceProvider, IrqChip, IrqEventIndex, KvmKernelIrqChip, PciDevice,
-    VcpuRunState, VfioContainer, VfioDevice, VfioPciDevice, VirtioPciDevice, XhciController,
+    self, HostBackendDeviceProvider, IrqChip, IrqEventIndex, Ivshmem, IvshmemError,
+    KvmKernelIrqChip, PciDevice, PcieRootPort, SerialDevice, VcpuRunState, VfioContainer,
+    VfioDevice, VfioPciDevice, VirtioPciDevice, XhciController, E1000,
 };
 use hypervisor::kvm::{Kvm, KvmVcpu, KvmVm};
 use hypervisor::{HypervisorCap, Vcpu, VcpuExit, VcpuRunHandle, Vm, VmCap};
 use minijail::{self, Minijail};
 use msg_socket::{MsgError, MsgReceiver, MsgSender, MsgSocket};
-use net_util::{Error as NetError, MacAddress, Tap};
+use net_util::user_net::UserTap;
+use net_util::{Error as NetError, MacAddress, Macvtap, Tap, TapT};
 use remain::sorted;
 use resources::{Alloc, MmioType, SystemAllocator};
 use sync::Mutex;
@@ -54,29 +57,44 @@ use sync::Mutex;
 use base::{
     self, block_signal, clear_signal, drop_capabilities, error, flock, get_blocked_signals,
     get_group_id, get_user_id, getegid, geteuid, info, register_rt_signal_handler,
-    set_cpu_affinity, set_rt_prio_limit, set_rt_round_robin, signal, validate_raw_descriptor, warn,
-    AsRawDescriptor, Event, EventType, ExternalMapping, FlockOperation, FromRawDescriptor,
-    Killable, MemoryMappingArena, PollToken, Protection, RawDescriptor, ScopedEvent, SignalFd,
-    Terminal, Timer, WaitContext, SIGRTMIN,
+    set_cpu_affinity, set_rt_prio_limit, set_rt_round_robin, signal, trace_event,
+    validate_raw_descriptor, warn, AsRawDescriptor, Event, EventType, ExternalMapping,
+    FlockOperation, FromRawDescriptor, Killable, MemoryMapping, MemoryMappingArena,
+    MemoryMappingBuilder, PollToken, Protection, RawDescriptor, ScopedEvent, SharedMemory,
+    SignalFd, Terminal, Timer, WaitContext, SIGRTMIN,
 };
 use vm_control::{
     BalloonControlCommand, BalloonControlRequestSocket, BalloonControlResponseSocket,
-    BalloonControlResult, DiskControlCommand, DiskControlRequestSocket, DiskControlResponseSocket,
-    DiskControlResult, IrqSetup, UsbControlSocket, VcpuControl, VmControlResponseSocket,
+    BalloonControlResult, BalloonEventRecvSocket, BalloonEventSendSocket, ConsoleResizeRecvSocket,
+    ConsoleResizeSendSocket, ConsoleSize, DiskControlCommand, DiskControlRequestSocket,
+    DiskControlResponseSocket, DiskControlResult, FaultInjectionCommand,
+    FaultInjectionRequestSocket, FaultInjectionResponseSocket, FaultInjectionResult,
+    GuestTelemetry, GuestTelemetryEvent, GuestTelemetryRecvSocket, GuestTelemetrySendSocket,
+    IrqSetup, MemControlCommand, MemControlRequestSocket, MemControlResponseSocket,
+    MemControlResult, NetControlCommand, NetControlRequestSocket, NetControlResponseSocket,
+    NetControlResult, Operations, PcieSlotControlCommand, PcieSlotControlRequestSocket,
+    PcieSlotControlResponseSocket, PcieSlotControlResult, UsbControlSocket, VcpuControl,
+    VcpuExitCounters, VirtioEventRecvSocket, VirtioEventSendSocket, VmControlResponseSocket,
     VmIrqRequest, VmIrqRequestSocket, VmIrqResponse, VmIrqResponseSocket,
     VmMemoryControlRequestSocket, VmMemoryControlResponseSocket, VmMemoryRequest, VmMemoryResponse,
-    VmMsyncRequest, VmMsyncRequestSocket, VmMsyncResponse, VmMsyncResponseSocket, VmRunMode,
+    VmMsyncRequest, VmMsyncRequestSocket, VmMsyncResponse, VmMsyncResponseSocket, VmRequest,
+    VmResponse, VmRunMode,
 };
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
-use vm_control::{VcpuDebug, VcpuDebugStatus, VcpuDebugStatusMessage, VmRequest, VmResponse};
+use vm_control::{VcpuDebug, VcpuDebugStatus, VcpuDebugStatusMessage};
 use vm_memory::{GuestAddress, GuestMemory};
 
+use crate::balloon_policy::{read_memory_pressure, BalloonPolicy, DeflatePolicy};
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
 use crate::gdb::{gdb_thread, GdbStub};
-use crate::{Config, DiskOption, Executable, SharedDir, SharedDirKind, TouchDeviceOption};
+use crate::ivshmem_broker;
+use crate::{
+    Config, DiskOption, Executable, ScsiDiskOption, SharedDir, SharedDirKind, TouchDeviceOption,
+    VhostScsiOption, VhostUserNetOption,
+};
 use arch::{
-    self, LinuxArch, RunnableLinuxVm, SerialHardware, SerialParameters, VcpuAffinity,
-    VirtioDeviceStub, VmComponents, VmImage,
+    self, ArchLayout, LinuxArch, PvFeatures, RunnableLinuxVm, SerialHardware, SerialParameters,
+    VcpuAffinity, VirtioDeviceStub, VmComponents, VmImage,
 };
 
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
@@ -98,8 +116,11 @@ pub enum Error {
     AddGpuDeviceMemory(base::Error),
     AddIrqChipVcpu(base::Error),
     AddPmemDeviceMemory(base::Error),
+    AddTapToBridge(NetError),
+    AddVirtioMemMemory(base::Error),
     AllocateGpuDeviceAddress,
     AllocatePmemDeviceAddress(resources::Error),
+    AllocateVirtioMemAddress(resources::Error),
     BalloonDeviceNew(virtio::BalloonError),
     BlockDeviceNew(base::Error),
     BlockSignal(base::signal::Error),
@@ -112,7 +133,10 @@ pub enum Error {
     CreateAc97(devices::PciDeviceError),
     CreateConsole(arch::serial::Error),
     CreateDiskError(disk::Error),
+    CreateE1000(virtio::NetError),
     CreateEvent(base::Error),
+    CreateIvshmem(IvshmemError),
+    CreateMacvtap(NetError),
     CreateSignalFd(base::SignalFdError),
     CreateSocket(io::Error),
     CreateTapDevice(NetError),
@@ -121,11 +145,14 @@ pub enum Error {
     CreateUsbProvider(devices::usb::host_backend::error::Error),
     CreateVcpu(base::Error),
     CreateVfioDevice(devices::vfio::VfioError),
+    CreateVirtioMemMemory(base::Error),
     CreateWaitContext(base::Error),
+    CrossDomainDeviceNew(virtio::CrossDomainError),
     DeviceJail(minijail::Error),
     DevicePivotRoot(minijail::Error),
     Disk(PathBuf, io::Error),
     DiskImageLock(base::Error),
+    DiskInUse(PathBuf, u32),
     DropCapabilities(base::Error),
     FsDeviceNew(virtio::fs::Error),
     GetMaxOpenFiles(io::Error),
@@ -134,10 +161,14 @@ pub enum Error {
     HandleDebugCommand(<Arch as LinuxArch>::Error),
     InputDeviceNew(virtio::InputError),
     InputEventsOpen(std::io::Error),
+    InvalidCrossDomainPath,
     InvalidFdPath,
+    InvalidVsockPath,
     InvalidWaylandPath,
     IoJail(minijail::Error),
+    IvshmemBroker(PathBuf, ivshmem_broker::Error),
     LoadKernel(Box<dyn StdError>),
+    MapVirtioMemMemory(base::MmapError),
     MemoryTooLarge,
     NetDeviceNew(virtio::NetError),
     OpenAcpiTable(PathBuf, io::Error),
@@ -145,6 +176,8 @@ pub enum Error {
     OpenBios(PathBuf, io::Error),
     OpenInitrd(PathBuf, io::Error),
     OpenKernel(PathBuf, io::Error),
+    OpenNetCapture(PathBuf, io::Error),
+    OpenPflash(PathBuf, io::Error),
     OpenVinput(PathBuf, io::Error),
     P9DeviceNew(virtio::P9Error),
     ParseMaxOpenFiles(ParseIntError),
@@ -166,6 +199,7 @@ pub enum Error {
     ResetTimer(base::Error),
     RngDeviceNew(virtio::RngError),
     RunnableVcpu(base::Error),
+    ScsiDiskNew(PathBuf, base::Error),
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     SendDebugStatus(Box<mpsc::SendError<VcpuDebugStatusMessage>>),
     SettingGidMap(minijail::Error),
@@ -175,15 +209,24 @@ pub enum Error {
     SignalFd(base::SignalFdError),
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
     SpawnGdbServer(io::Error),
+    SpawnUserNet(io::Error),
     SpawnVcpu(io::Error),
     Timer(base::Error),
+    TraceOutput(PathBuf, io::Error),
+    UserNetNew(NetError),
     ValidateRawDescriptor(base::Error),
     VhostNetDeviceNew(virtio::vhost::Error),
+    VhostScsiDeviceNew(virtio::vhost::Error),
+    VhostUserBlockDeviceNew(PathBuf, virtio::vhost::Error),
+    VhostUserNetDeviceNew(PathBuf, virtio::vhost::Error),
     VhostVsockDeviceNew(virtio::vhost::Error),
+    VirtioMemDeviceNew(base::Error),
     VirtioPciDev(base::Error),
+    VsockDeviceNew(virtio::VsockError),
     WaitContextAdd(base::Error),
     WaitContextDelete(base::Error),
     WaylandDeviceNew(base::Error),
+    ZonedDisk(PathBuf, io::Error),
 }
 
 impl Display for Error {
@@ -196,10 +239,15 @@ impl Display for Error {
             AddGpuDeviceMemory(e) => write!(f, "failed to add gpu device memory: {}", e),
             AddIrqChipVcpu(e) => write!(f, "failed to add vcpu to irq chip: {}", e),
             AddPmemDeviceMemory(e) => write!(f, "failed to add pmem device memory: {}", e),
+            AddTapToBridge(e) => write!(f, "failed to add tap to bridge: {}", e),
+            AddVirtioMemMemory(e) => write!(f, "failed to add virtio-mem device memory: {}", e),
             AllocateGpuDeviceAddress => write!(f, "failed to allocate gpu device guest address"),
             AllocatePmemDeviceAddress(e) => {
                 write!(f, "failed to allocate memory for pmem device: {}", e)
             }
+            AllocateVirtioMemAddress(e) => {
+                write!(f, "failed to allocate memory for virtio-mem device: {}", e)
+            }
             BalloonDeviceNew(e) => write!(f, "failed to create balloon: {}", e),
             BlockDeviceNew(e) => write!(f, "failed to create block device: {}", e),
             BlockSignal(e) => write!(f, "failed to block signal: {}", e),
@@ -212,7 +260,10 @@ impl Display for Error {
             CreateAc97(e) => write!(f, "failed to create ac97 device: {}", e),
             CreateConsole(e) => write!(f, "failed to create console device: {}", e),
             CreateDiskError(e) => write!(f, "failed to create virtual disk: {}", e),
+            CreateE1000(e) => write!(f, "failed to create e1000 device: {}", e),
             CreateEvent(e) => write!(f, "failed to create event: {}", e),
+            CreateIvshmem(e) => write!(f, "failed to create ivshmem device: {}", e),
+            CreateMacvtap(e) => write!(f, "failed to create macvtap interface: {}", e),
             CreateSignalFd(e) => write!(f, "failed to create signalfd: {}", e),
             CreateSocket(e) => write!(f, "failed to create socket: {}", e),
             CreateTapDevice(e) => write!(f, "failed to create tap device: {}", e),
@@ -223,11 +274,19 @@ impl Display for Error {
             CreateUsbProvider(e) => write!(f, "failed to create usb provider: {}", e),
             CreateVcpu(e) => write!(f, "failed to create vcpu: {}", e),
             CreateVfioDevice(e) => write!(f, "Failed to create vfio device {}", e),
+            CreateVirtioMemMemory(e) => write!(f, "failed to create virtio-mem memory: {}", e),
             CreateWaitContext(e) => write!(f, "failed to create wait context: {}", e),
+            CrossDomainDeviceNew(e) => write!(f, "failed to create cross-domain device: {}", e),
             DeviceJail(e) => write!(f, "failed to jail device: {}", e),
             DevicePivotRoot(e) => write!(f, "failed to pivot root device: {}", e),
             Disk(p, e) => write!(f, "failed to load disk image {}: {}", p.display(), e),
             DiskImageLock(e) => write!(f, "failed to lock disk image: {}", e),
+            DiskInUse(p, pid) => write!(
+                f,
+                "disk image {} is also open in process {}",
+                p.display(),
+                pid
+            ),
             DropCapabilities(e) => write!(f, "failed to drop process capabilities: {}", e),
             FsDeviceNew(e) => write!(f, "failed to create fs device: {}", e),
             GetMaxOpenFiles(e) => write!(f, "failed to get max number of open files: {}", e),
@@ -236,10 +295,16 @@ impl Display for Error {
             HandleDebugCommand(e) => write!(f, "failed to handle a gdb command: {}", e),
             InputDeviceNew(e) => write!(f, "failed to set up input device: {}", e),
             InputEventsOpen(e) => write!(f, "failed to open event device: {}", e),
+            InvalidCrossDomainPath => {
+                write!(f, "cross-domain service socket path has no parent")
+            }
             InvalidFdPath => write!(f, "failed parsing a /proc/self/fd/*"),
+            InvalidVsockPath => write!(f, "vsock host port socket path has no parent"),
             InvalidWaylandPath => write!(f, "wayland socket path has no parent or file name"),
             IoJail(e) => write!(f, "{}", e),
+            IvshmemBroker(p, e) => write!(f, "ivshmem broker {}: {}", p.display(), e),
             LoadKernel(e) => write!(f, "failed to load kernel: {}", e),
+            MapVirtioMemMemory(e) => write!(f, "failed to map virtio-mem memory: {}", e),
             MemoryTooLarge => write!(f, "requested memory size too large"),
             NetDeviceNew(e) => write!(f, "failed to set up virtio networking: {}", e),
             OpenAcpiTable(p, e) => write!(f, "failed to open ACPI file {}: {}", p.display(), e),
@@ -252,6 +317,13 @@ impl Display for Error {
             OpenBios(p, e) => write!(f, "failed to open bios {}: {}", p.display(), e),
             OpenInitrd(p, e) => write!(f, "failed to open initrd {}: {}", p.display(), e),
             OpenKernel(p, e) => write!(f, "failed to open kernel image {}: {}", p.display(), e),
+            OpenNetCapture(p, e) => write!(
+                f,
+                "failed to open packet capture file {}: {}",
+                p.display(),
+                e
+            ),
+            OpenPflash(p, e) => write!(f, "failed to open pflash image {}: {}", p.display(), e),
             OpenVinput(p, e) => write!(f, "failed to open vinput device {}: {}", p.display(), e),
             P9DeviceNew(e) => write!(f, "failed to create 9p device: {}", e),
             ParseMaxOpenFiles(e) => write!(f, "failed to parse max number of open files: {}", e),
@@ -275,6 +347,7 @@ impl Display for Error {
             ResetTimer(e) => write!(f, "failed to reset Timer: {}", e),
             RngDeviceNew(e) => write!(f, "failed to set up rng: {}", e),
             RunnableVcpu(e) => write!(f, "failed to set thread id for vcpu: {}", e),
+            ScsiDiskNew(p, e) => write!(f, "failed to set up scsi disk {}: {}", p.display(), e),
             #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
             SendDebugStatus(e) => write!(f, "failed to send a debug status to GDB thread: {}", e),
             SettingGidMap(e) => write!(f, "error setting GID map: {}", e),
@@ -284,17 +357,36 @@ impl Display for Error {
             SignalFd(e) => write!(f, "failed to read signal fd: {}", e),
             #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
             SpawnGdbServer(e) => write!(f, "failed to spawn GDB thread: {}", e),
+            SpawnUserNet(e) => write!(f, "failed to spawn user-mode networking thread: {}", e),
             SpawnVcpu(e) => write!(f, "failed to spawn VCPU thread: {}", e),
             Timer(e) => write!(f, "failed to read timer fd: {}", e),
+            TraceOutput(p, e) => write!(f, "failed to open trace output {}: {}", p.display(), e),
+            UserNetNew(e) => write!(f, "failed to set up user-mode networking: {}", e),
             ValidateRawDescriptor(e) => write!(f, "failed to validate raw descriptor: {}", e),
             VhostNetDeviceNew(e) => write!(f, "failed to set up vhost networking: {}", e),
+            VhostScsiDeviceNew(e) => write!(f, "failed to set up vhost-scsi device: {}", e),
+            VhostUserBlockDeviceNew(p, e) => write!(
+                f,
+                "failed to set up vhost-user block device for {}: {}",
+                p.display(),
+                e
+            ),
+            VhostUserNetDeviceNew(p, e) => write!(
+                f,
+                "failed to set up vhost-user net device for {}: {}",
+                p.display(),
+                e
+            ),
             VhostVsockDeviceNew(e) => write!(f, "failed to set up virtual socket device: {}", e),
+            VirtioMemDeviceNew(e) => write!(f, "failed to create virtio-mem device: {}", e),
             VirtioPciDev(e) => write!(f, "failed to create virtio pci dev: {}", e),
+            VsockDeviceNew(e) => write!(f, "failed to create vsock device: {}", e),
             WaitContextAdd(e) => write!(f, "failed to add descriptor to wait context: {}", e),
             WaitContextDelete(e) => {
                 write!(f, "failed to remove descriptor from wait context: {}", e)
             }
             WaylandDeviceNew(e) => write!(f, "failed to create wayland device: {}", e),
+            ZonedDisk(p, e) => write!(f, "failed to set up the zones of {}: {}", p.display(), e),
         }
     }
 }
@@ -454,38 +546,144 @@ fn simple_jail(cfg: &Config, policy: &str) -> Result<Option<Minijail>> {
 
 type DeviceResult<T = VirtioDeviceStub> = std::result::Result<T, Error>;
 
-fn create_block_device(
-    cfg: &Config,
-    disk: &DiskOption,
-    disk_device_socket: DiskControlResponseSocket,
-) -> DeviceResult {
+// Opens the disk image at `path`, and locks it to prevent other crosvm instances from using it.
+fn open_disk_image(path: &Path, read_only: bool) -> Result<File> {
     // Special case '/proc/self/fd/*' paths. The FD is already open, just use it.
-    let raw_image: File = if disk.path.parent() == Some(Path::new("/proc/self/fd")) {
+    let raw_image: File = if path.parent() == Some(Path::new("/proc/self/fd")) {
         // Safe because we will validate |raw_fd|.
-        unsafe { File::from_raw_descriptor(raw_descriptor_from_path(&disk.path)?) }
+        unsafe { File::from_raw_descriptor(raw_descriptor_from_path(path)?) }
     } else {
         OpenOptions::new()
             .read(true)
-            .write(!disk.read_only)
-            .open(&disk.path)
-            .map_err(|e| Error::Disk(disk.path.to_path_buf(), e))?
+            .write(!read_only)
+            .open(path)
+            .map_err(|e| Error::Disk(path.to_path_buf(), e))?
     };
-    // Lock the disk image to prevent other crosvm instances from using it.
-    let lock_op = if disk.read_only {
+    let lock_op = if read_only {
         FlockOperation::LockShared
     } else {
         FlockOperation::LockExclusive
     };
     flock(&raw_image, lock_op, true).map_err(Error::DiskImageLock)?;
+    Ok(raw_image)
+}
+
+// Returns the ID of a process other than this one that has `file` open, if there is one. Only the
+// processes whose descriptors this process may inspect are checked.
+fn find_other_opener(file: &File) -> io::Result<Option<u32>> {
+    let metadata = file.metadata()?;
+    let own_pid = std::process::id();
+    for entry in fs::read_dir("/proc")? {
+        let entry = entry?;
+        let pid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            Some(pid) if pid != own_pid => pid,
+            _ => continue,
+        };
+        // The process may have exited since /proc was listed.
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        for fd in fds.filter_map(|fd| fd.ok()) {
+            if let Ok(fd_metadata) = fs::metadata(fd.path()) {
+                if fd_metadata.dev() == metadata.dev() && fd_metadata.ino() == metadata.ino() {
+                    return Ok(Some(pid));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn create_block_device(
+    cfg: &Config,
+    disk: &DiskOption,
+    disk_device_socket: DiskControlResponseSocket,
+) -> DeviceResult {
+    let raw_image = open_disk_image(&disk.path, disk.read_only)?;
+    // The lock only keeps out other crosvm instances, so a shared image written by anything else
+    // is caught here.
+    if disk.exclusive && !disk.read_only {
+        if let Some(pid) =
+            find_other_opener(&raw_image).map_err(|e| Error::Disk(disk.path.clone(), e))?
+        {
+            return Err(Error::DiskInUse(disk.path.clone(), pid));
+        }
+    }
 
-    let disk_file = disk::create_disk_file(raw_image).map_err(Error::CreateDiskError)?;
+    // Zones are only ever laid over raw images.
+    let zoned = match disk.zoned {
+        Some(zoned) => {
+            if disk::detect_image_type(&raw_image).map_err(Error::CreateDiskError)?
+                != disk::ImageType::Raw
+            {
+                return Err(Error::ZonedDisk(
+                    disk.path.to_path_buf(),
+                    io::Error::from_raw_os_error(libc::EINVAL),
+                ));
+            }
+            let file = raw_image
+                .try_clone()
+                .map_err(|e| Error::Disk(disk.path.to_path_buf(), e))?;
+            let zoned = match zoned {
+                ZonedOption::Host => virtio::ZonedDisk::host(file),
+                ZonedOption::Emulated {
+                    zone_size,
+                    max_open_zones,
+                    max_active_zones,
+                } => virtio::ZonedDisk::emulated(file, zone_size, max_open_zones, max_active_zones),
+            };
+            Some(zoned.map_err(|e| Error::ZonedDisk(disk.path.to_path_buf(), e))?)
+        }
+        None => None,
+    };
+    // Writes to a host zoned device bypass the page cache, since writeback could reorder them.
+    let direct_io = disk.cache_mode.direct_io() || disk.zoned == Some(ZonedOption::Host);
+
+    // Raw images can also be accessed through io_uring, unless they need the alignment handling of
+    // direct I/O.
+    let async_image = if !direct_io && disk::async_ok(&raw_image).map_err(Error::CreateDiskError)? {
+        Some(
+            raw_image
+                .try_clone()
+                .map_err(|e| Error::Disk(disk.path.to_path_buf(), e))?,
+        )
+    } else {
+        None
+    };
+    let disk_file = if direct_io {
+        Box::new(disk::DirectFile::new(raw_image).map_err(Error::CreateDiskError)?)
+            as Box<dyn disk::DiskFile>
+    } else {
+        disk::create_disk_file_with_cache_size(
+            raw_image,
+            disk::MAX_NESTING_DEPTH,
+            disk.qcow_cache_size,
+        )
+        .map_err(Error::CreateDiskError)?
+    };
     let dev = virtio::Block::new(
         virtio::base_features(cfg.protected_vm),
         disk_file,
-        disk.read_only,
-        disk.sparse,
-        disk.block_size,
-        disk.id,
+        async_image,
+        virtio::BlockConfig {
+            read_only: disk.read_only,
+            sparse: disk.sparse,
+            write_through: disk.cache_mode.write_through(),
+            flush_coalescing: disk.flush_coalescing,
+            max_merge_size: disk.max_merge_size,
+            block_size: disk.block_size,
+            id: disk.id,
+            num_queues: disk.num_queues,
+            busy_poll: disk.busy_poll,
+            interrupt_interval: disk.interrupt_interval,
+            throttle_limits: disk.throttle,
+        },
+        zoned,
         Some(disk_device_socket),
     )
     .map_err(Error::BlockDeviceNew)?;
@@ -506,6 +704,22 @@ fn create_rng_device(cfg: &Config) -> DeviceResult {
     })
 }
 
+#[cfg(feature = "fault-injection")]
+fn create_fault_injection_device(
+    cfg: &Config,
+    fault_injection_device_socket: FaultInjectionResponseSocket,
+) -> DeviceResult {
+    let dev = virtio::FaultInjection::new(
+        virtio::base_features(cfg.protected_vm),
+        fault_injection_device_socket,
+    );
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(&cfg, "fault_injection_device")?,
+    })
+}
+
 #[cfg(feature = "tpm")]
 fn create_tpm_device(cfg: &Config) -> DeviceResult {
     use base::chown;
@@ -645,9 +859,17 @@ fn create_vinput_device(cfg: &Config, dev_path: &Path) -> DeviceResult {
     })
 }
 
-fn create_balloon_device(cfg: &Config, socket: BalloonControlResponseSocket) -> DeviceResult {
-    let dev = virtio::Balloon::new(virtio::base_features(cfg.protected_vm), socket)
-        .map_err(Error::BalloonDeviceNew)?;
+fn create_balloon_device(
+    cfg: &Config,
+    socket: BalloonControlResponseSocket,
+    event_socket: BalloonEventSendSocket,
+) -> DeviceResult {
+    let dev = virtio::Balloon::new(
+        virtio::base_features(cfg.protected_vm),
+        socket,
+        event_socket,
+    )
+    .map_err(Error::BalloonDeviceNew)?;
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -655,36 +877,113 @@ fn create_balloon_device(cfg: &Config, socket: BalloonControlResponseSocket) ->
     })
 }
 
-fn create_tap_net_device(cfg: &Config, tap_fd: RawDescriptor) -> DeviceResult {
-    // Safe because we ensure that we get a unique handle to the fd.
-    let tap = unsafe {
-        Tap::from_raw_descriptor(
-            validate_raw_descriptor(tap_fd).map_err(Error::ValidateRawDescriptor)?,
-        )
-        .map_err(Error::CreateTapDevice)?
-    };
+// Sets up the link properties and packet capture asked for on the `net_index`th virtio-net
+// device.
+fn configure_net_device<T: TapT>(
+    cfg: &Config,
+    net_index: usize,
+    dev: &mut virtio::Net<T>,
+) -> Result<()> {
+    if let Some(link) = cfg.net_link.get(&net_index) {
+        dev.set_link(*link);
+    }
+    if let Some(path) = cfg.net_capture.get(&net_index) {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|e| Error::OpenNetCapture(path.clone(), e))?;
+        dev.start_capture(file).map_err(Error::NetDeviceNew)?;
+    }
+    Ok(())
+}
 
-    let mut vq_pairs = cfg.net_vq_pairs.unwrap_or(1);
-    let vcpu_count = cfg.vcpu_count.unwrap_or(1);
-    if vcpu_count < vq_pairs as usize {
-        error!("net vq pairs must be smaller than vcpu count, fall back to single queue mode");
-        vq_pairs = 1;
+fn create_tap_net_device(
+    cfg: &Config,
+    net_index: usize,
+    tap_fds: &[RawDescriptor],
+    mem: &GuestMemory,
+    net_device_socket: Option<NetControlResponseSocket>,
+) -> DeviceResult {
+    let mut taps = Vec::with_capacity(tap_fds.len());
+    for &tap_fd in tap_fds {
+        // Safe because we ensure that we get a unique handle to the fd.
+        let tap = unsafe {
+            Tap::from_raw_descriptor(
+                validate_raw_descriptor(tap_fd).map_err(Error::ValidateRawDescriptor)?,
+            )
+            .map_err(Error::CreateTapDevice)?
+        };
+        taps.push(tap);
     }
+    create_net_device_from_taps(cfg, net_index, taps, mem, net_device_socket)
+}
+
+// Creates a network device on the queues of a tap that is already set up.
+fn create_net_device_from_taps(
+    cfg: &Config,
+    net_index: usize,
+    mut taps: Vec<Tap>,
+    mem: &GuestMemory,
+    net_device_socket: Option<NetControlResponseSocket>,
+) -> DeviceResult {
     let features = virtio::base_features(cfg.protected_vm);
-    let dev = virtio::Net::from(features, tap, vq_pairs).map_err(Error::NetDeviceNew)?;
+    let dev = if taps.len() > 1 {
+        // The queues were opened for us, so they decide the number of queue pairs.
+        let mut dev = virtio::Net::from_taps(
+            features,
+            taps,
+            cfg.net_interrupt_interval,
+            net_device_socket,
+        )
+        .map_err(Error::NetDeviceNew)?;
+        configure_net_device(cfg, net_index, &mut dev)?;
+        Box::new(dev) as Box<dyn VirtioDevice>
+    } else if cfg.vhost_net {
+        let tap = taps.remove(0);
+        let dev = virtio::vhost::Net::<Tap, vhost::Net<Tap>>::from(features, tap, mem)
+            .map_err(Error::VhostNetDeviceNew)?;
+        Box::new(dev) as Box<dyn VirtioDevice>
+    } else {
+        let mut vq_pairs = cfg.net_vq_pairs.unwrap_or(1);
+        let vcpu_count = cfg.vcpu_count.unwrap_or(1);
+        if vcpu_count < vq_pairs as usize {
+            error!("net vq pairs must be smaller than vcpu count, fall back to single queue mode");
+            vq_pairs = 1;
+        }
+        let mut dev = virtio::Net::from(
+            features,
+            taps.remove(0),
+            vq_pairs,
+            cfg.net_interrupt_interval,
+            net_device_socket,
+        )
+        .map_err(Error::NetDeviceNew)?;
+        configure_net_device(cfg, net_index, &mut dev)?;
+        Box::new(dev) as Box<dyn VirtioDevice>
+    };
+
+    let policy = if cfg.vhost_net {
+        "vhost_net_device"
+    } else {
+        "net_device"
+    };
 
     Ok(VirtioDeviceStub {
-        dev: Box::new(dev),
-        jail: simple_jail(&cfg, "net_device")?,
+        dev,
+        jail: simple_jail(&cfg, policy)?,
     })
 }
 
 fn create_net_device(
     cfg: &Config,
+    net_index: usize,
     host_ip: Ipv4Addr,
     netmask: Ipv4Addr,
     mac_address: MacAddress,
     mem: &GuestMemory,
+    net_device_socket: Option<NetControlResponseSocket>,
 ) -> DeviceResult {
     let mut vq_pairs = cfg.net_vq_pairs.unwrap_or(1);
     let vcpu_count = cfg.vcpu_count.unwrap_or(1);
@@ -705,8 +1004,17 @@ fn create_net_device(
         .map_err(Error::VhostNetDeviceNew)?;
         Box::new(dev) as Box<dyn VirtioDevice>
     } else {
-        let dev = virtio::Net::<Tap>::new(features, host_ip, netmask, mac_address, vq_pairs)
-            .map_err(Error::NetDeviceNew)?;
+        let mut dev = virtio::Net::<Tap>::new(
+            features,
+            host_ip,
+            netmask,
+            mac_address,
+            vq_pairs,
+            cfg.net_interrupt_interval,
+            net_device_socket,
+        )
+        .map_err(Error::NetDeviceNew)?;
+        configure_net_device(cfg, net_index, &mut dev)?;
         Box::new(dev) as Box<dyn VirtioDevice>
     };
 
@@ -722,6 +1030,62 @@ fn create_net_device(
     })
 }
 
+fn create_user_net_device(
+    cfg: &Config,
+    net_index: usize,
+    tap: UserTap,
+    net_device_socket: Option<NetControlResponseSocket>,
+) -> DeviceResult {
+    let features = virtio::base_features(cfg.protected_vm);
+    let mut dev = virtio::Net::from(
+        features,
+        tap,
+        1,
+        cfg.net_interrupt_interval,
+        net_device_socket,
+    )
+    .map_err(Error::NetDeviceNew)?;
+    configure_net_device(cfg, net_index, &mut dev)?;
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(&cfg, "net_device")?,
+    })
+}
+
+// Returns the MAC address of the `index`th e1000 device, counting up from the address QEMU gives
+// its first NIC.
+fn e1000_guest_mac(index: u8) -> MacAddress {
+    format!("52:54:00:12:34:{:02x}", 0x56u8.wrapping_add(index))
+        .parse()
+        .expect("generated MAC address is valid")
+}
+
+fn create_e1000_devices(cfg: &Config, mem: &GuestMemory) -> DeviceResult<Vec<E1000<Tap>>> {
+    let mut devs = Vec::new();
+    for tap_fds in &cfg.tap_fd {
+        // Safe because we ensure that we get a unique handle to the fd.
+        let tap = unsafe {
+            Tap::from_raw_descriptor(
+                validate_raw_descriptor(tap_fds[0]).map_err(Error::ValidateRawDescriptor)?,
+            )
+            .map_err(Error::CreateTapDevice)?
+        };
+        let guest_mac = e1000_guest_mac(devs.len() as u8);
+        devs.push(E1000::from(mem.clone(), tap, guest_mac).map_err(Error::CreateE1000)?);
+    }
+
+    if let (Some(host_ip), Some(netmask), Some(mac_address)) =
+        (cfg.host_ip, cfg.netmask, cfg.mac_address)
+    {
+        let guest_mac = e1000_guest_mac(devs.len() as u8);
+        let dev = E1000::new(mem.clone(), host_ip, netmask, mac_address, guest_mac)
+            .map_err(Error::CreateE1000)?;
+        devs.push(dev);
+    }
+    Ok(devs)
+}
+
 #[cfg(feature = "gpu")]
 fn create_gpu_device(
     cfg: &Config,
@@ -912,6 +1276,58 @@ fn create_wayland_device(
     })
 }
 
+fn create_cross_domain_device(
+    cfg: &Config,
+    socket: VmMemoryControlRequestSocket,
+    resource_bridge: Option<virtio::resource_bridge::ResourceRequestSocket>,
+) -> DeviceResult {
+    let service_dirs = cfg
+        .cross_domain_services
+        .values()
+        .map(|path| path.parent())
+        .collect::<Option<Vec<_>>>()
+        .ok_or(Error::InvalidCrossDomainPath)?;
+
+    let features = virtio::base_features(cfg.protected_vm);
+    let dev = virtio::CrossDomain::new(
+        features,
+        cfg.cross_domain_services.clone(),
+        socket,
+        resource_bridge,
+    )
+    .map_err(Error::CrossDomainDeviceNew)?;
+
+    // The device runs the virtio-wl worker, so it gets the same sandbox.
+    let jail = match simple_jail(cfg, "wl_device")? {
+        Some(mut jail) => {
+            // Create a tmpfs in the device's root directory so that we can bind mount the service
+            // socket directories into it. The size=67108864 is size=64*1024*1024 or size=64MB.
+            jail.mount_with_data(
+                Path::new("none"),
+                Path::new("/"),
+                "tmpfs",
+                (libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC) as usize,
+                "size=67108864",
+            )?;
+
+            // Each new connection must open() the service's socket, so bind mount the directory
+            // holding it rather than the socket itself, which a restarted service would replace.
+            for dir in &service_dirs {
+                jail.mount_bind(dir, dir, true)?;
+            }
+            add_crosvm_user_to_jail(&mut jail, "cross-domain")?;
+
+            Some(jail)
+        }
+        None => None,
+    };
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail,
+    })
+}
+
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
 fn create_video_device(
     cfg: &Config,
@@ -1000,6 +1416,164 @@ fn create_vhost_vsock_device(cfg: &Config, cid: u64, mem: &GuestMemory) -> Devic
     })
 }
 
+fn create_vsock_device(cfg: &Config, cid: u64) -> DeviceResult {
+    let host_port_dirs = cfg
+        .vsock_host_ports
+        .values()
+        .map(|path| path.parent())
+        .collect::<Option<Vec<_>>>()
+        .ok_or(Error::InvalidVsockPath)?;
+
+    let features = virtio::base_features(cfg.protected_vm);
+    let dev = virtio::Vsock::new(
+        features,
+        cid,
+        cfg.vsock_host_ports.clone(),
+        &cfg.vsock_guest_ports,
+    )
+    .map_err(Error::VsockDeviceNew)?;
+
+    let jail = match simple_jail(&cfg, "vsock_device")? {
+        Some(mut jail) => {
+            // Create a tmpfs in the device's root directory so that we can bind mount the host
+            // port sockets' directories into it. The size=67108864 is size=64*1024*1024 or
+            // size=64MB.
+            jail.mount_with_data(
+                Path::new("none"),
+                Path::new("/"),
+                "tmpfs",
+                (libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC) as usize,
+                "size=67108864",
+            )?;
+
+            // Every connection the guest makes connects to the socket of its host port anew, so
+            // the sockets' directories have to be reachable from the jail.
+            for dir in &host_port_dirs {
+                jail.mount_bind(dir, dir, true)?;
+            }
+            add_crosvm_user_to_jail(&mut jail, "vsock")?;
+
+            Some(jail)
+        }
+        None => None,
+    };
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail,
+    })
+}
+
+fn create_vhost_scsi_device(
+    cfg: &Config,
+    option: &VhostScsiOption,
+    mem: &GuestMemory,
+) -> DeviceResult {
+    let features = virtio::base_features(cfg.protected_vm);
+    let dev = virtio::vhost::Scsi::new(features, &option.wwpn, option.tpgt, option.num_queues, mem)
+        .map_err(Error::VhostScsiDeviceNew)?;
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(&cfg, "vhost_scsi_device")?,
+    })
+}
+
+fn create_vhost_user_block_device(cfg: &Config, socket: &Path, mem: &GuestMemory) -> DeviceResult {
+    let features = virtio::base_features(cfg.protected_vm);
+    let dev = virtio::vhost::VhostUserBlock::new(features, socket, mem)
+        .map_err(|e| Error::VhostUserBlockDeviceNew(socket.to_path_buf(), e))?;
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(&cfg, "vhost_user_blk_device")?,
+    })
+}
+
+fn create_vhost_user_net_device(
+    cfg: &Config,
+    option: &VhostUserNetOption,
+    mem: &GuestMemory,
+) -> DeviceResult {
+    let features = virtio::base_features(cfg.protected_vm);
+    let dev = virtio::vhost::VhostUserNet::new(features, &option.socket, option.mac, mem)
+        .map_err(|e| Error::VhostUserNetDeviceNew(option.socket.clone(), e))?;
+
+    let jail = match simple_jail(&cfg, "vhost_user_net_device")? {
+        Some(mut jail) => {
+            // Create a tmpfs in the device's root directory so that we can bind mount the
+            // backend's socket directory into it. The size=67108864 is size=64*1024*1024 or
+            // size=64MB.
+            jail.mount_with_data(
+                Path::new("none"),
+                Path::new("/"),
+                "tmpfs",
+                (libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC) as usize,
+                "size=67108864",
+            )?;
+
+            // The device connects to the socket again when the backend restarts, and the backend
+            // remakes the socket when it does, so the whole directory has to be visible.
+            if let Some(dir) = option.socket.parent() {
+                jail.mount_bind(dir, dir, true)?;
+            }
+            add_crosvm_user_to_jail(&mut jail, "vhost-user-net")?;
+
+            Some(jail)
+        }
+        None => None,
+    };
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail,
+    })
+}
+
+fn create_scsi_unit(option: &ScsiDiskOption) -> Result<Box<dyn virtio::scsi::LogicalUnit>> {
+    let raw_image = open_disk_image(&option.path, option.read_only)?;
+    if option.passthrough {
+        let unit = virtio::scsi::ScsiPassthrough::new(raw_image)
+            .map_err(|e| Error::ScsiDiskNew(option.path.clone(), e))?;
+        return Ok(Box::new(unit));
+    }
+
+    let disk_file = if option.cache_mode.direct_io() {
+        Box::new(disk::DirectFile::new(raw_image).map_err(Error::CreateDiskError)?)
+            as Box<dyn disk::DiskFile>
+    } else {
+        disk::create_disk_file(raw_image, disk::MAX_NESTING_DEPTH)
+            .map_err(Error::CreateDiskError)?
+    };
+    let unit = virtio::scsi::ScsiDisk::new(
+        disk_file,
+        option.read_only,
+        option.sparse,
+        option.cache_mode.write_through(),
+        option.block_size,
+        option.id,
+    )
+    .map_err(|e| Error::ScsiDiskNew(option.path.clone(), e))?;
+    Ok(Box::new(unit))
+}
+
+fn create_scsi_device(cfg: &Config, options: &[ScsiDiskOption]) -> DeviceResult {
+    let mut luns = Vec::new();
+    for option in options {
+        luns.push(virtio::scsi::ScsiLun {
+            target: option.target,
+            lun: option.lun,
+            unit: create_scsi_unit(option)?,
+        });
+    }
+    let dev = virtio::scsi::Scsi::new(virtio::base_features(cfg.protected_vm), luns);
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(&cfg, "scsi_device")?,
+    })
+}
+
 fn create_fs_device(
     cfg: &Config,
     uid_map: &str,
@@ -1045,7 +1619,7 @@ fn create_9p_device(
     gid_map: &str,
     src: &Path,
     tag: &str,
-    mut p9_cfg: p9::Config,
+    mut p9_cfg: virtio::P9Config,
 ) -> DeviceResult {
     let max_open_files = get_max_open_files()?;
     let (jail, root) = if cfg.sandbox {
@@ -1171,12 +1745,85 @@ fn create_pmem_device(
     })
 }
 
-fn create_console_device(cfg: &Config, param: &SerialParameters) -> DeviceResult {
+// The region of a virtio-mem device: its guest address and the device's mapping of it.
+struct VirtioMemRegion {
+    address: GuestAddress,
+    mapping: MemoryMapping,
+}
+
+// Maps a virtio-mem region of `region_size` bytes into the VM and returns it along with `mem`
+// extended by the region, which every device must be given since the guest may place DMA buffers
+// in the blocks it plugs.
+fn create_virtio_mem_region(
+    vm: &mut impl Vm,
+    resources: &mut SystemAllocator,
+    region_size: u64,
+) -> DeviceResult<(GuestMemory, VirtioMemRegion)> {
+    // The VM and the device map the same memory, so that the device can give the blocks the
+    // guest unplugs back to the host.
+    let shm =
+        SharedMemory::named("virtio_mem", region_size).map_err(Error::CreateVirtioMemMemory)?;
+    let device_mapping = MemoryMappingBuilder::new(region_size as usize)
+        .from_descriptor(&shm)
+        .build()
+        .map_err(Error::MapVirtioMemMemory)?;
+
+    let mapping_address = resources
+        .mmio_allocator(MmioType::High)
+        .allocate_with_align(
+            region_size,
+            Alloc::VirtioMem,
+            "virtio_mem".to_string(),
+            // Linux adds hotplugged memory in memory blocks of up to 128 MiB.
+            128 * 1024 * 1024, /* 128 MiB */
+        )
+        .map_err(Error::AllocateVirtioMemAddress)?;
+
+    vm.add_guest_memory_region(GuestAddress(mapping_address), shm)
+        .map_err(Error::AddVirtioMemMemory)?;
+
+    let region = VirtioMemRegion {
+        address: GuestAddress(mapping_address),
+        mapping: device_mapping,
+    };
+    Ok((vm.get_memory().clone(), region))
+}
+
+fn create_mem_device(
+    cfg: &Config,
+    region: VirtioMemRegion,
+    mem_device_socket: MemControlResponseSocket,
+) -> DeviceResult {
+    let dev = virtio::Mem::new(
+        virtio::base_features(cfg.protected_vm),
+        region.address,
+        region.mapping,
+        mem_device_socket,
+    )
+    .map_err(Error::VirtioMemDeviceNew)?;
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(&cfg, "mem_device")?,
+    })
+}
+
+fn create_console_device(
+    cfg: &Config,
+    param: &SerialParameters,
+    resize_socket: Option<ConsoleResizeRecvSocket>,
+) -> DeviceResult {
     let mut keep_rds = Vec::new();
     let evt = Event::new().map_err(Error::CreateEvent)?;
-    let dev = param
+    let mut dev = param
         .create_serial_device::<Console>(cfg.protected_vm, &evt, &mut keep_rds)
         .map_err(Error::CreateConsole)?;
+    if let Some(resize_socket) = resize_socket {
+        match stdin().window_size() {
+            Ok((cols, rows)) => dev.set_resize_socket(resize_socket, ConsoleSize { cols, rows }),
+            Err(e) => info!("console is not told the terminal size: {}", e),
+        }
+    }
 
     let jail = match simple_jail(&cfg, "serial")? {
         Some(mut jail) => {
@@ -1206,8 +1853,32 @@ fn create_console_device(cfg: &Config, param: &SerialParameters) -> DeviceResult
     })
 }
 
-// gpu_device_socket is not used when GPU support is disabled.
-#[cfg_attr(not(feature = "gpu"), allow(unused_variables))]
+fn create_guest_telemetry_device(
+    cfg: &Config,
+    guest_telemetry_device_socket: GuestTelemetrySendSocket,
+) -> DeviceResult {
+    let keep_rds = vec![guest_telemetry_device_socket.as_raw_descriptor()];
+    let output = virtio::TelemetryOutput::new(guest_telemetry_device_socket);
+    let dev = Console::new(
+        cfg.protected_vm,
+        Event::new().map_err(Error::CreateEvent)?,
+        None,
+        Some(Box::new(output)),
+        keep_rds,
+    );
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(&cfg, "serial")?,
+    })
+}
+
+// gpu_device_socket is not used when GPU support is disabled, nor is
+// fault_injection_device_socket without the fault injection device.
+#[cfg_attr(
+    not(all(feature = "gpu", feature = "fault-injection")),
+    allow(unused_variables)
+)]
 fn create_virtio_devices(
     cfg: &Config,
     mem: &GuestMemory,
@@ -1215,9 +1886,18 @@ fn create_virtio_devices(
     resources: &mut SystemAllocator,
     _exit_evt: &Event,
     wayland_device_socket: VmMemoryControlRequestSocket,
+    cross_domain_device_socket: Option<VmMemoryControlRequestSocket>,
     gpu_device_socket: VmMemoryControlRequestSocket,
     balloon_device_socket: BalloonControlResponseSocket,
+    balloon_event_socket: BalloonEventSendSocket,
+    guest_telemetry_device_socket: Option<GuestTelemetrySendSocket>,
+    mut console_resize_socket: Option<ConsoleResizeRecvSocket>,
+    virtio_mem: Option<(VirtioMemRegion, MemControlResponseSocket)>,
+    fault_injection_device_socket: Option<FaultInjectionResponseSocket>,
     disk_device_sockets: &mut Vec<DiskControlResponseSocket>,
+    net_device_sockets: &mut Vec<NetControlResponseSocket>,
+    host_taps: Vec<Tap>,
+    user_net_tap: Option<UserTap>,
     pmem_device_sockets: &mut Vec<VmMsyncRequestSocket>,
     map_request: Arc<Mutex<Option<ExternalMapping>>>,
 ) -> DeviceResult<Vec<VirtioDeviceStub>> {
@@ -1228,10 +1908,25 @@ fn create_virtio_devices(
         .iter()
         .filter(|(_k, v)| v.hardware == SerialHardware::VirtioConsole)
     {
-        let dev = create_console_device(cfg, param)?;
+        // Only the console on stdin is attached to the terminal crosvm runs in.
+        let resize_socket = if param.stdin {
+            console_resize_socket.take()
+        } else {
+            None
+        };
+        let dev = create_console_device(cfg, param, resize_socket)?;
         devs.push(dev);
     }
 
+    // The telemetry port comes after the configured consoles, so that their numbering in the
+    // guest doesn't depend on it.
+    if let Some(guest_telemetry_device_socket) = guest_telemetry_device_socket {
+        devs.push(create_guest_telemetry_device(
+            cfg,
+            guest_telemetry_device_socket,
+        )?);
+    }
+
     for disk in &cfg.disks {
         let disk_device_socket = disk_device_sockets.remove(0);
         devs.push(create_block_device(cfg, disk, disk_device_socket)?);
@@ -1278,17 +1973,92 @@ fn create_virtio_devices(
         devs.push(create_vinput_device(cfg, dev_path)?);
     }
 
-    devs.push(create_balloon_device(cfg, balloon_device_socket)?);
+    devs.push(create_balloon_device(
+        cfg,
+        balloon_device_socket,
+        balloon_event_socket,
+    )?);
 
-    // We checked above that if the IP is defined, then the netmask is, too.
-    for tap_fd in &cfg.tap_fd {
-        devs.push(create_tap_net_device(cfg, *tap_fd)?);
+    if let Some((region, mem_device_socket)) = virtio_mem {
+        devs.push(create_mem_device(cfg, region, mem_device_socket)?);
     }
 
-    if let (Some(host_ip), Some(netmask), Some(mac_address)) =
-        (cfg.host_ip, cfg.netmask, cfg.mac_address)
+    #[cfg(feature = "fault-injection")]
     {
-        devs.push(create_net_device(cfg, host_ip, netmask, mac_address, mem)?);
+        if let Some(fault_injection_device_socket) = fault_injection_device_socket {
+            devs.push(create_fault_injection_device(
+                cfg,
+                fault_injection_device_socket,
+            )?);
+        }
+    }
+
+    // The e1000 devices take the place of virtio-net and are created with the other PCI devices.
+    if !cfg.e1000 {
+        // We checked above that if the IP is defined, then the netmask is, too.
+        // Devices are numbered in the order they are created here, as they are for net commands.
+        let mut net_index = 0;
+        for tap_fds in &cfg.tap_fd {
+            let net_device_socket = if cfg.vhost_net {
+                None
+            } else {
+                Some(net_device_sockets.remove(0))
+            };
+            devs.push(create_tap_net_device(
+                cfg,
+                net_index,
+                tap_fds,
+                mem,
+                net_device_socket,
+            )?);
+            net_index += 1;
+        }
+
+        for tap in host_taps {
+            let net_device_socket = if cfg.vhost_net {
+                None
+            } else {
+                Some(net_device_sockets.remove(0))
+            };
+            devs.push(create_net_device_from_taps(
+                cfg,
+                net_index,
+                vec![tap],
+                mem,
+                net_device_socket,
+            )?);
+            net_index += 1;
+        }
+
+        if let Some(tap) = user_net_tap {
+            let net_device_socket = if net_device_sockets.is_empty() {
+                None
+            } else {
+                Some(net_device_sockets.remove(0))
+            };
+            devs.push(create_user_net_device(
+                cfg,
+                net_index,
+                tap,
+                net_device_socket,
+            )?);
+            net_index += 1;
+        }
+
+        if let (Some(host_ip), Some(netmask), Some(mac_address)) =
+            (cfg.host_ip, cfg.netmask, cfg.mac_address)
+        {
+            let net_device_socket = net_device_sockets.pop();
+            devs.push(create_net_device(
+                cfg,
+                net_index,
+                host_ip,
+                netmask,
+                mac_address,
+                mem,
+                net_device_socket,
+            )?);
+        }
     }
 
     #[cfg_attr(not(feature = "gpu"), allow(unused_mut))]
@@ -1315,6 +2085,28 @@ fn create_virtio_devices(
         )?);
     }
 
+    if let Some(cross_domain_device_socket) = cross_domain_device_socket {
+        #[cfg_attr(not(feature = "gpu"), allow(unused_mut))]
+        let mut cross_domain_resource_bridge =
+            None::<virtio::resource_bridge::ResourceRequestSocket>;
+
+        #[cfg(feature = "gpu")]
+        {
+            if cfg.gpu_parameters.is_some() {
+                let (cross_domain_socket, gpu_socket) =
+                    virtio::resource_bridge::pair().map_err(Error::CreateSocket)?;
+                resource_bridges.push(gpu_socket);
+                cross_domain_resource_bridge = Some(cross_domain_socket);
+            }
+        }
+
+        devs.push(create_cross_domain_device(
+            cfg,
+            cross_domain_device_socket,
+            cross_domain_resource_bridge,
+        )?);
+    }
+
     #[cfg(feature = "video-decoder")]
     {
         if cfg.video_dec {
@@ -1393,7 +2185,27 @@ fn create_virtio_devices(
     }
 
     if let Some(cid) = cfg.cid {
-        devs.push(create_vhost_vsock_device(cfg, cid, mem)?);
+        if cfg.vsock_host_ports.is_empty() && cfg.vsock_guest_ports.is_empty() {
+            devs.push(create_vhost_vsock_device(cfg, cid, mem)?);
+        } else {
+            devs.push(create_vsock_device(cfg, cid)?);
+        }
+    }
+
+    for option in &cfg.vhost_scsi {
+        devs.push(create_vhost_scsi_device(cfg, option, mem)?);
+    }
+
+    for socket in &cfg.vhost_user_blk {
+        devs.push(create_vhost_user_block_device(cfg, socket, mem)?);
+    }
+
+    for option in &cfg.vhost_user_net {
+        devs.push(create_vhost_user_net_device(cfg, option, mem)?);
+    }
+
+    if !cfg.scsi_disks.is_empty() {
+        devs.push(create_scsi_device(cfg, &cfg.scsi_disks)?);
     }
 
     for shared_dir in &cfg.shared_dirs {
@@ -1425,13 +2237,33 @@ fn create_devices(
     exit_evt: &Event,
     control_sockets: &mut Vec<TaggedControlSocket>,
     wayland_device_socket: VmMemoryControlRequestSocket,
+    cross_domain_device_socket: Option<VmMemoryControlRequestSocket>,
     gpu_device_socket: VmMemoryControlRequestSocket,
     balloon_device_socket: BalloonControlResponseSocket,
+    balloon_event_socket: BalloonEventSendSocket,
+    virtio_event_socket: &VirtioEventSendSocket,
+    guest_telemetry_device_socket: Option<GuestTelemetrySendSocket>,
+    console_resize_socket: Option<ConsoleResizeRecvSocket>,
+    mem_device_socket: Option<MemControlResponseSocket>,
+    fault_injection_device_socket: Option<FaultInjectionResponseSocket>,
     disk_device_sockets: &mut Vec<DiskControlResponseSocket>,
+    net_device_sockets: &mut Vec<NetControlResponseSocket>,
+    pcie_slot_device_sockets: Vec<PcieSlotControlResponseSocket>,
+    host_taps: Vec<Tap>,
+    user_net_tap: Option<UserTap>,
     pmem_device_sockets: &mut Vec<VmMsyncRequestSocket>,
     usb_provider: HostBackendDeviceProvider,
     map_request: Arc<Mutex<Option<ExternalMapping>>>,
 ) -> DeviceResult<Vec<(Box<dyn PciDevice>, Option<Minijail>)>> {
+    let (mem, virtio_mem) = match (cfg.virtio_mem_size, mem_device_socket) {
+        (Some(region_size), Some(mem_device_socket)) => {
+            let (mem, region) = create_virtio_mem_region(vm, resources, region_size)?;
+            (mem, Some((region, mem_device_socket)))
+        }
+        _ => (mem.clone(), None),
+    };
+    let mem = &mem;
+
     let stubs = create_virtio_devices(
         &cfg,
         mem,
@@ -1439,9 +2271,18 @@ fn create_devices(
         resources,
         exit_evt,
         wayland_device_socket,
+        cross_domain_device_socket,
         gpu_device_socket,
         balloon_device_socket,
+        balloon_event_socket,
+        guest_telemetry_device_socket,
+        console_resize_socket,
+        virtio_mem,
+        fault_injection_device_socket,
         disk_device_sockets,
+        net_device_sockets,
+        host_taps,
+        user_net_tap,
         pmem_device_sockets,
         map_request,
     )?;
@@ -1452,12 +2293,36 @@ fn create_devices(
         let (msi_host_socket, msi_device_socket) =
             msg_socket::pair::<VmIrqResponse, VmIrqRequest>().map_err(Error::CreateSocket)?;
         control_sockets.push(TaggedControlSocket::VmIrq(msi_host_socket));
-        let dev = VirtioPciDevice::new(mem.clone(), stub.dev, msi_device_socket)
+        // Every virtio device reports to the main process on the same socket.
+        let event_socket = MsgSocket::new(
+            virtio_event_socket
+                .as_ref()
+                .try_clone()
+                .map_err(Error::CreateSocket)?,
+        );
+        let dev = VirtioPciDevice::new(mem.clone(), stub.dev, msi_device_socket, event_socket)
             .map_err(Error::VirtioPciDev)?;
         let dev = Box::new(dev) as Box<dyn PciDevice>;
         pci_devices.push((dev, stub.jail));
     }
 
+    if cfg.e1000 {
+        for dev in create_e1000_devices(cfg, mem)? {
+            pci_devices.push((Box::new(dev), simple_jail(&cfg, "e1000_device")?));
+        }
+    }
+
+    for path in &cfg.ivshmem {
+        let (setup, broker_socket) = ivshmem_broker::connect(path)
+            .map_err(|e| Error::IvshmemBroker(path.to_path_buf(), e))?;
+        let (ivshmem_host_socket, ivshmem_device_socket) =
+            msg_socket::pair::<VmMemoryResponse, VmMemoryRequest>().map_err(Error::CreateSocket)?;
+        control_sockets.push(TaggedControlSocket::VmMemory(ivshmem_host_socket));
+        let dev = Ivshmem::new(setup, broker_socket, ivshmem_device_socket)
+            .map_err(Error::CreateIvshmem)?;
+        pci_devices.push((Box::new(dev), simple_jail(&cfg, "ivshmem_device")?));
+    }
+
     #[cfg(feature = "audio")]
     for ac97_param in &cfg.ac97_parameters {
         let dev = Ac97Dev::try_new(mem.clone(), ac97_param.clone()).map_err(Error::CreateAc97)?;
@@ -1466,7 +2331,14 @@ fn create_devices(
     }
 
     // Create xhci controller.
-    let usb_controller = Box::new(XhciController::new(mem.clone(), usb_provider));
+    let (usb_msi_host_socket, usb_msi_device_socket) =
+        msg_socket::pair::<VmIrqResponse, VmIrqRequest>().map_err(Error::CreateSocket)?;
+    control_sockets.push(TaggedControlSocket::VmIrq(usb_msi_host_socket));
+    let usb_controller = Box::new(XhciController::new(
+        mem.clone(),
+        usb_provider,
+        usb_msi_device_socket,
+    ));
     pci_devices.push((usb_controller, simple_jail(&cfg, "xhci")?));
 
     if !cfg.vfio.is_empty() {
@@ -1501,6 +2373,13 @@ fn create_devices(
         }
     }
 
+    // Root ports with empty hot-plug slots, each owning the secondary bus after the previous one.
+    let secondary_buses = 1..=cfg.pcie_root_ports;
+    for (secondary_bus, control_socket) in secondary_buses.zip(pcie_slot_device_sockets) {
+        let root_port = Box::new(PcieRootPort::new(secondary_bus, control_socket));
+        pci_devices.push((root_port, simple_jail(&cfg, "pcie_root_port_device")?));
+    }
+
     Ok(pci_devices)
 }
 
@@ -1614,6 +2493,8 @@ fn runnable_vcpu<V>(
     run_rt: bool,
     vcpu_affinity: Vec<usize>,
     no_smt: bool,
+    pv_features: PvFeatures,
+    layout: ArchLayout,
     has_bios: bool,
     use_hypervisor_signals: bool,
 ) -> Result<(V, VcpuRunHandle)>
@@ -1655,6 +2536,8 @@ where
         vcpu_count,
         has_bios,
         no_smt,
+        pv_features,
+        layout,
     )
     .map_err(Error::ConfigureVcpu)?;
 
@@ -1770,6 +2653,8 @@ fn run_vcpu<V>(
     run_rt: bool,
     vcpu_affinity: Vec<usize>,
     no_smt: bool,
+    pv_features: PvFeatures,
+    layout: ArchLayout,
     start_barrier: Arc<Barrier>,
     has_bios: bool,
     io_bus: devices::Bus,
@@ -1778,6 +2663,7 @@ fn run_vcpu<V>(
     requires_pvclock_ctrl: bool,
     from_main_channel: mpsc::Receiver<VcpuControl>,
     use_hypervisor_signals: bool,
+    exit_counters: Arc<Vec<VcpuExitCounters>>,
     #[cfg(all(target_arch = "x86_64", feature = "gdb"))] to_gdb_channel: Option<
         mpsc::Sender<VcpuDebugStatusMessage>,
     >,
@@ -1803,6 +2689,8 @@ where
                 run_rt,
                 vcpu_affinity,
                 no_smt,
+                pv_features,
+                layout,
                 has_bios,
                 use_hypervisor_signals,
             );
@@ -1924,8 +2812,13 @@ where
                 }
 
                 if !interrupted_by_signal {
-                    match vcpu.run(&vcpu_run_handle) {
+                    let exit = vcpu.run(&vcpu_run_handle);
+                    if let Ok(exit) = &exit {
+                        exit_counters[cpu_id].record(exit);
+                    }
+                    match exit {
                         Ok(VcpuExit::IoIn { port, mut size }) => {
+                            let _trace = trace_event!(vcpu, "io_in");
                             let mut data = [0; 8];
                             if size > data.len() {
                                 error!("unsupported IoIn size of {} bytes", size);
@@ -1941,6 +2834,7 @@ where
                             mut size,
                             data,
                         }) => {
+                            let _trace = trace_event!(vcpu, "io_out");
                             if size > data.len() {
                                 error!("unsupported IoOut size of {} bytes", size);
                                 size = data.len();
@@ -1948,6 +2842,7 @@ where
                             io_bus.write(port as u64, &data[..size]);
                         }
                         Ok(VcpuExit::MmioRead { address, size }) => {
+                            let _trace = trace_event!(vcpu, "mmio_read");
                             let mut data = [0; 8];
                             mmio_bus.read(address, &mut data[..size]);
                             // Setting data for mmio can not fail.
@@ -1958,6 +2853,7 @@ where
                             size,
                             data,
                         }) => {
+                            let _trace = trace_event!(vcpu, "mmio_write");
                             mmio_bus.write(address, &data[..size]);
                         }
                         Ok(VcpuExit::IoapicEoi { vector }) => {
@@ -2081,7 +2977,19 @@ fn create_kvm_split_irq_chip(
     Ok(irq_chip)
 }
 
+// Starts recording trace events to `path`. Append mode keeps events written concurrently by the
+// jailed device processes from overwriting each other.
+fn start_tracing(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.set_len(0)?;
+    trace_event::init(file)
+}
+
 pub fn run_config(cfg: Config) -> Result<()> {
+    if let Some(trace_output) = &cfg.trace_output {
+        start_tracing(trace_output).map_err(|e| Error::TraceOutput(trace_output.clone(), e))?;
+    }
+
     if cfg.split_irqchip {
         #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
         {
@@ -2097,6 +3005,11 @@ pub fn run_config(cfg: Config) -> Result<()> {
     }
 }
 
+/// Signals that are masked and routed through a signalfd to the main control loop instead of being
+/// handled asynchronously: SIGCHLD for jailed device processes dying, SIGTERM for a graceful stop,
+/// and SIGWINCH for terminal resizes.
+const CONTROL_SIGNALS: &[c_int] = &[libc::SIGCHLD, libc::SIGTERM, libc::SIGWINCH];
+
 fn run_vm<V, Vcpu, I, FV, FI>(cfg: Config, create_vm: FV, create_irq_chip: FI) -> Result<()>
 where
     V: VmArch + 'static,
@@ -2120,8 +3033,8 @@ where
         HostBackendDeviceProvider::new().map_err(Error::CreateUsbProvider)?;
     // Masking signals is inherently dangerous, since this can persist across clones/execs. Do this
     // before any jailed devices have been spawned, so that we can catch any of them that fail very
-    // quickly.
-    let sigchld_fd = SignalFd::new(libc::SIGCHLD).map_err(Error::CreateSignalFd)?;
+    // quickly. The jailed devices restore an empty mask right after they fork.
+    let signal_fd = SignalFd::with_signals(CONTROL_SIGNALS).map_err(Error::CreateSignalFd)?;
 
     let initrd_image = if let Some(initrd_path) = &cfg.initrd_path {
         Some(File::open(initrd_path).map_err(|e| Error::OpenInitrd(initrd_path.clone(), e))?)
@@ -2129,6 +3042,17 @@ where
         None
     };
 
+    let pflash_image = match &cfg.pflash_path {
+        Some(pflash_path) => Some(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(pflash_path)
+                .map_err(|e| Error::OpenPflash(pflash_path.clone(), e))?,
+        ),
+        None => None,
+    };
+
     let vm_image = match cfg.executable_path {
         Some(Executable::Kernel(ref kernel_path)) => VmImage::Kernel(
             File::open(kernel_path).map_err(|e| Error::OpenKernel(kernel_path.to_path_buf(), e))?,
@@ -2160,6 +3084,8 @@ where
         vcpu_count: cfg.vcpu_count.unwrap_or(1),
         vcpu_affinity: cfg.vcpu_affinity.clone(),
         no_smt: cfg.no_smt,
+        pv_features: cfg.pv_features,
+        layout: cfg.layout,
         vm_image,
         android_fstab: cfg
             .android_fstab
@@ -2168,6 +3094,7 @@ where
             .map_or(Ok(None), |v| v.map(Some))?,
         pstore: cfg.pstore.clone(),
         initrd_image,
+        pflash_image,
         extra_kernel_params: cfg.params.clone(),
         wayland_dmabuf: cfg.wayland_dmabuf,
         acpi_sdts: cfg
@@ -2177,6 +3104,7 @@ where
             .collect::<Result<Vec<SDT>>>()?,
         rt_cpus: cfg.rt_cpus.clone(),
         protected_vm: cfg.protected_vm,
+        uuid: cfg.uuid,
         #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
         gdb: gdb_socket,
     };
@@ -2191,10 +3119,63 @@ where
     let (wayland_host_socket, wayland_device_socket) =
         msg_socket::pair::<VmMemoryResponse, VmMemoryRequest>().map_err(Error::CreateSocket)?;
     control_sockets.push(TaggedControlSocket::VmMemory(wayland_host_socket));
+    // The cross-domain device maps shared memory into the guest like the wayland device does.
+    let cross_domain_device_socket = if cfg.cross_domain_services.is_empty() {
+        None
+    } else {
+        let (host, device) =
+            msg_socket::pair::<VmMemoryResponse, VmMemoryRequest>().map_err(Error::CreateSocket)?;
+        control_sockets.push(TaggedControlSocket::VmMemory(host));
+        Some(device)
+    };
     // Balloon gets a special socket so balloon requests can be forwarded from the main process.
     let (balloon_host_socket, balloon_device_socket) =
         msg_socket::pair::<BalloonControlCommand, BalloonControlResult>()
             .map_err(Error::CreateSocket)?;
+    // The balloon device reports changes to its size on a socket of their own, so that they
+    // aren't mistaken for answers to forwarded requests.
+    let (balloon_event_host_socket, balloon_event_device_socket) =
+        msg_socket::pair::<(), _>().map_err(Error::CreateSocket)?;
+    // Virtio devices report drivers that fail to set them up.
+    let (virtio_event_host_socket, virtio_event_device_socket) =
+        msg_socket::pair::<(), _>().map_err(Error::CreateSocket)?;
+    // The guest telemetry port sends what it parses from the guest's records.
+    let (guest_telemetry_host_socket, guest_telemetry_device_socket) = if cfg.guest_telemetry {
+        let (host, device) = msg_socket::pair::<(), _>().map_err(Error::CreateSocket)?;
+        (Some(host), Some(device))
+    } else {
+        (None, None)
+    };
+    // A virtio console on stdin is told the terminal size whenever the terminal is resized.
+    let (console_resize_host_socket, console_resize_device_socket) = if cfg
+        .serial_parameters
+        .values()
+        .any(|param| param.hardware == SerialHardware::VirtioConsole && param.stdin)
+    {
+        let (host, device) = msg_socket::pair::<_, ()>().map_err(Error::CreateSocket)?;
+        (Some(host), Some(device))
+    } else {
+        (None, None)
+    };
+
+    // The virtio-mem device, like the balloon, has requests forwarded from the main process.
+    let (mem_host_socket, mem_device_socket) = match cfg.virtio_mem_size {
+        Some(_) => {
+            let (host, device) = msg_socket::pair::<MemControlCommand, MemControlResult>()
+                .map_err(Error::CreateSocket)?;
+            (Some(host), Some(device))
+        }
+        None => (None, None),
+    };
+
+    // So does the fault injection device.
+    let (fault_injection_host_socket, fault_injection_device_socket) = if cfg.fault_injection {
+        let (host, device) = msg_socket::pair::<FaultInjectionCommand, FaultInjectionResult>()
+            .map_err(Error::CreateSocket)?;
+        (Some(host), Some(device))
+    } else {
+        (None, None)
+    };
 
     // Create one control socket per disk.
     let mut disk_device_sockets = Vec::new();
@@ -2204,10 +3185,41 @@ where
         let (disk_host_socket, disk_device_socket) =
             msg_socket::pair::<DiskControlCommand, DiskControlResult>()
                 .map_err(Error::CreateSocket)?;
-        disk_host_sockets.push(disk_host_socket);
+        disk_host_sockets.push(Arc::new(Mutex::new(disk_host_socket)));
         disk_device_sockets.push(disk_device_socket);
     }
 
+    // Create one control socket per virtio-net device, unless they are backed by vhost-net.
+    let mut net_device_sockets = Vec::new();
+    let mut net_host_sockets = Vec::new();
+    let net_count = if cfg.e1000 || cfg.vhost_net {
+        0
+    } else {
+        cfg.tap_fd.len()
+            + cfg.net_bridges.len()
+            + cfg.macvtaps.len()
+            + cfg.user_net as usize
+            + cfg.host_ip.is_some() as usize
+    };
+    for _ in 0..net_count {
+        let (net_host_socket, net_device_socket) =
+            msg_socket::pair::<NetControlCommand, NetControlResult>()
+                .map_err(Error::CreateSocket)?;
+        net_host_sockets.push(net_host_socket);
+        net_device_sockets.push(net_device_socket);
+    }
+
+    // Create one control socket per PCIe root port.
+    let mut pcie_slot_device_sockets = Vec::new();
+    let mut pcie_slot_host_sockets = Vec::new();
+    for _ in 0..cfg.pcie_root_ports {
+        let (pcie_slot_host_socket, pcie_slot_device_socket) =
+            msg_socket::pair::<PcieSlotControlCommand, PcieSlotControlResult>()
+                .map_err(Error::CreateSocket)?;
+        pcie_slot_host_sockets.push(pcie_slot_host_socket);
+        pcie_slot_device_sockets.push(pcie_slot_device_socket);
+    }
+
     let mut pmem_device_sockets = Vec::new();
     let pmem_count = cfg.pmem_devices.len();
     for _ in 0..pmem_count {
@@ -2255,6 +3267,33 @@ where
         (&cfg.battery_type, None)
     };
 
+    // The user-mode network stack stays in this process, outside of the device jails, as it needs
+    // the host's network to reach the outside world.
+    let (user_net_tap, user_net_stack) = if cfg.user_net {
+        let (tap, stack) = net_util::user_net::pair().map_err(Error::UserNetNew)?;
+        (Some(tap), Some(stack))
+    } else {
+        (None, None)
+    };
+
+    // Taps on a bridge and macvtap interfaces are set up here, as the device jails can't configure
+    // the host's network. The macvtap interfaces are deleted once `_macvtaps` is dropped on exit.
+    let mut host_taps = Vec::new();
+    for bridge in &cfg.net_bridges {
+        let tap = Tap::new(true, false).map_err(Error::CreateTapDevice)?;
+        tap.add_to_bridge(bridge).map_err(Error::AddTapToBridge)?;
+        tap.enable().map_err(Error::CreateTapDevice)?;
+        host_taps.push(tap);
+    }
+    let mut _macvtaps = Vec::new();
+    for (i, option) in cfg.macvtaps.iter().enumerate() {
+        let name = format!("mvt{}_{}", std::process::id(), i);
+        let (macvtap, tap) =
+            Macvtap::new(&name, &option.parent, option.mode).map_err(Error::CreateMacvtap)?;
+        _macvtaps.push(macvtap);
+        host_taps.push(tap);
+    }
+
     let map_request: Arc<Mutex<Option<ExternalMapping>>> = Arc::new(Mutex::new(None));
 
     let linux: RunnableLinuxVm<_, Vcpu, _> = Arch::build_vm(
@@ -2271,9 +3310,20 @@ where
                 exit_evt,
                 &mut control_sockets,
                 wayland_device_socket,
+                cross_domain_device_socket,
                 gpu_device_socket,
                 balloon_device_socket,
+                balloon_event_device_socket,
+                &virtio_event_device_socket,
+                guest_telemetry_device_socket,
+                console_resize_device_socket,
+                mem_device_socket,
+                fault_injection_device_socket,
                 &mut disk_device_sockets,
+                &mut net_device_sockets,
+                pcie_slot_device_sockets,
+                host_taps,
+                user_net_tap,
                 &mut pmem_device_sockets,
                 usb_provider,
                 Arc::clone(&map_request),
@@ -2284,17 +3334,62 @@ where
     )
     .map_err(Error::BuildVm)?;
 
-    run_control(
+    let auto_balloon = cfg
+        .auto_balloon
+        .as_ref()
+        .map(|params| BalloonPolicy::new(params, linux.vm.get_memory().memory_size()));
+    let deflate_policy = cfg.deflate_on_pressure.as_ref().map(DeflatePolicy::new);
+
+    // Started only now that the jailed devices have been forked, so that they don't get a copy
+    // of the stack's sockets.
+    let user_net_thread = match user_net_stack {
+        Some(mut stack) => {
+            let kill_evt = Event::new().map_err(Error::CreateEvent)?;
+            let thread_kill_evt = kill_evt.try_clone().map_err(Error::CloneEvent)?;
+            let handle = thread::Builder::new()
+                .name("user_net".to_owned())
+                .spawn(move || {
+                    if let Err(e) = stack.run(&thread_kill_evt) {
+                        error!("user-mode networking stopped: {}", e);
+                    }
+                })
+                .map_err(Error::SpawnUserNet)?;
+            Some((kill_evt, handle))
+        }
+        None => None,
+    };
+
+    let result = run_control(
         linux,
         control_server_socket,
         control_sockets,
         balloon_host_socket,
+        balloon_event_host_socket,
+        virtio_event_host_socket,
+        guest_telemetry_host_socket,
+        console_resize_host_socket,
+        mem_host_socket,
+        fault_injection_host_socket,
         &disk_host_sockets,
+        &net_host_sockets,
+        &pcie_slot_host_sockets,
         usb_control_socket,
-        sigchld_fd,
+        signal_fd,
         cfg.sandbox,
         Arc::clone(&map_request),
-    )
+        auto_balloon,
+        deflate_policy,
+    );
+
+    if let Some((kill_evt, handle)) = user_net_thread {
+        // Ignore the result because there is nothing we can do about it.
+        let _ = kill_evt.write(1);
+        if let Err(e) = handle.join() {
+            error!("user-mode networking thread panicked: {:?}", e);
+        }
+    }
+
+    result
 }
 
 /// Signals all running VCPUs to vmexit, sends VmRunMode message to each VCPU channel, and tells
@@ -2320,26 +3415,56 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
     control_server_socket: Option<UnlinkUnixSeqpacketListener>,
     mut control_sockets: Vec<TaggedControlSocket>,
     balloon_host_socket: BalloonControlRequestSocket,
-    disk_host_sockets: &[DiskControlRequestSocket],
+    balloon_event_socket: BalloonEventRecvSocket,
+    virtio_event_socket: VirtioEventRecvSocket,
+    guest_telemetry_socket: Option<GuestTelemetryRecvSocket>,
+    console_resize_socket: Option<ConsoleResizeSendSocket>,
+    mem_host_socket: Option<MemControlRequestSocket>,
+    fault_injection_host_socket: Option<FaultInjectionRequestSocket>,
+    disk_host_sockets: &[Arc<Mutex<DiskControlRequestSocket>>],
+    net_host_sockets: &[NetControlRequestSocket],
+    pcie_slot_host_sockets: &[PcieSlotControlRequestSocket],
     usb_control_socket: UsbControlSocket,
-    sigchld_fd: SignalFd,
+    signal_fd: SignalFd,
     sandbox: bool,
     map_request: Arc<Mutex<Option<ExternalMapping>>>,
+    mut auto_balloon: Option<BalloonPolicy>,
+    deflate_policy: Option<DeflatePolicy>,
 ) -> Result<()> {
     const LOWMEM_AVAILABLE: &str = "/sys/kernel/mm/chromeos-low_mem/available";
+    // The memory pressure is averaged over 10 seconds, so checking it more often would act on the
+    // same stall more than once.
+    const AUTO_BALLOON_INTERVAL: Duration = Duration::from_secs(10);
+    // How often the guest is asked for its memory stats to check whether it runs short of memory.
+    const DEFLATE_ON_PRESSURE_INTERVAL: Duration = Duration::from_secs(1);
 
     #[derive(PollToken)]
     enum Token {
         Exit,
         Suspend,
-        ChildSignal,
+        Signal,
         IrqFd { index: IrqEventIndex },
         BalanceMemory,
+        AutoBalloon,
+        DeflateOnPressure,
         BalloonResult,
+        BalloonEvent,
+        VirtioEvent,
+        GuestTelemetry,
+        OperationUpdate,
+        RtcAlarm,
         VmControlServer,
         VmControl { index: usize },
     }
 
+    // What a control socket that no longer takes requests is kept around for.
+    enum Subscription {
+        BalloonEvents,
+        VirtioEvents,
+        GuestTelemetry,
+        Operation(u64),
+    }
+
     stdin()
         .set_raw_mode()
         .expect("failed to set terminal raw mode");
@@ -2347,9 +3472,33 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
     let wait_ctx = WaitContext::build_with(&[
         (&linux.exit_evt, Token::Exit),
         (&linux.suspend_evt, Token::Suspend),
-        (&sigchld_fd, Token::ChildSignal),
+        (&signal_fd, Token::Signal),
+        (&balloon_event_socket, Token::BalloonEvent),
+        (&virtio_event_socket, Token::VirtioEvent),
     ])
     .map_err(Error::WaitContextAdd)?;
+    // Control sockets that asked for balloon or virtio events, which are no longer read from.
+    let mut balloon_event_subscribers: Vec<VmControlResponseSocket> = Vec::new();
+    let mut virtio_event_subscribers: Vec<VmControlResponseSocket> = Vec::new();
+
+    // What the guest reported on its telemetry port, if it has one, and the control sockets
+    // following its records.
+    let mut guest_telemetry = guest_telemetry_socket
+        .as_ref()
+        .map(|_| GuestTelemetry::default());
+    let mut guest_telemetry_subscribers: Vec<VmControlResponseSocket> = Vec::new();
+    if let Some(socket) = &guest_telemetry_socket {
+        wait_ctx
+            .add(socket, Token::GuestTelemetry)
+            .map_err(Error::WaitContextAdd)?;
+    }
+
+    // Control requests that continue in the background, and the control sockets following them.
+    let mut operations = Operations::new().map_err(Error::CreateEvent)?;
+    wait_ctx
+        .add(operations.update_evt(), Token::OperationUpdate)
+        .map_err(Error::WaitContextAdd)?;
+    let mut operation_subscribers: Vec<(u64, VmControlResponseSocket)> = Vec::new();
 
     if let Some(socket_server) = &control_server_socket {
         wait_ctx
@@ -2373,9 +3522,29 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
             .map_err(Error::WaitContextAdd)?;
     }
 
+    // Resize the balloon from the host memory pressure if asked to. This replaces the balancing
+    // below, which would otherwise fight over the balloon size.
+    let mut auto_balloon_timer = Timer::new().map_err(Error::CreateTimer)?;
+    if let Some(policy) = &auto_balloon {
+        wait_ctx
+            .add(&auto_balloon_timer, Token::AutoBalloon)
+            .map_err(Error::WaitContextAdd)?;
+        auto_balloon_timer
+            .reset(AUTO_BALLOON_INTERVAL, Some(AUTO_BALLOON_INTERVAL))
+            .map_err(Error::ResetTimer)?;
+
+        let command = BalloonControlCommand::Adjust {
+            num_bytes: policy.balloon_size(),
+        };
+        if let Err(e) = balloon_host_socket.send(&command) {
+            warn!("failed to send memory value to balloon device: {}", e);
+        }
+    }
+
     // Balance available memory between guest and host every second.
     let mut balancemem_timer = Timer::new().map_err(Error::CreateTimer)?;
-    if Path::new(LOWMEM_AVAILABLE).exists() {
+    let balance_memory = auto_balloon.is_none() && Path::new(LOWMEM_AVAILABLE).exists();
+    if balance_memory {
         // Create timer request balloon stats every 1s.
         wait_ctx
             .add(&balancemem_timer, Token::BalanceMemory)
@@ -2385,15 +3554,40 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
         balancemem_timer
             .reset(balancemem_dur, Some(balancemem_int))
             .map_err(Error::ResetTimer)?;
+    } else if auto_balloon.is_none() {
+        warn!("Unable to open low mem available, maybe not a chrome os kernel");
+    }
+
+    // Deflate the balloon when the guest runs short of memory. The balancing above already asks
+    // for the guest stats often enough, so only ask for them here without it.
+    let mut deflate_timer = Timer::new().map_err(Error::CreateTimer)?;
+    if deflate_policy.is_some() && !balance_memory {
+        wait_ctx
+            .add(&deflate_timer, Token::DeflateOnPressure)
+            .map_err(Error::WaitContextAdd)?;
+        deflate_timer
+            .reset(
+                DEFLATE_ON_PRESSURE_INTERVAL,
+                Some(DEFLATE_ON_PRESSURE_INTERVAL),
+            )
+            .map_err(Error::ResetTimer)?;
+    }
 
-        // Listen for balloon statistics from the guest so we can balance.
+    // Listen for the balloon device's answers to the resizes and stats requests sent above.
+    if auto_balloon.is_some() || balance_memory || deflate_policy.is_some() {
         wait_ctx
             .add(&balloon_host_socket, Token::BalloonResult)
             .map_err(Error::WaitContextAdd)?;
-    } else {
-        warn!("Unable to open low mem available, maybe not a chrome os kernel");
     }
 
+    // Wakes the guest from suspend when the time it set in the RTC alarm is reached. The timer is
+    // only armed while the guest is suspended.
+    let mut rtc_alarm_timer = Timer::new().map_err(Error::CreateTimer)?;
+    wait_ctx
+        .add(&rtc_alarm_timer, Token::RtcAlarm)
+        .map_err(Error::WaitContextAdd)?;
+    let mut guest_suspended = false;
+
     if sandbox {
         // Before starting VCPUs, in case we started with some capabilities, drop them all.
         drop_capabilities().map_err(Error::DropCapabilities)?;
@@ -2415,6 +3609,11 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
         .get_hypervisor()
         .check_capability(&HypervisorCap::ImmediateExit);
     setup_vcpu_signal_handler::<Vcpu>(use_hypervisor_signals)?;
+    let vcpu_exit_counters: Arc<Vec<VcpuExitCounters>> = Arc::new(
+        iter::repeat_with(VcpuExitCounters::default)
+            .take(linux.vcpu_count)
+            .collect(),
+    );
 
     let vcpus: Vec<Option<_>> = match linux.vcpus.take() {
         Some(vec) => vec.into_iter().map(|vcpu| Some(vcpu)).collect(),
@@ -2436,6 +3635,8 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
             linux.rt_cpus.contains(&cpu_id),
             vcpu_affinity,
             linux.no_smt,
+            linux.pv_features,
+            linux.layout,
             vcpu_thread_barrier.clone(),
             linux.has_bios,
             linux.io_bus.clone(),
@@ -2444,6 +3645,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
             linux.vm.check_capability(VmCap::PvClockSuspend),
             from_main_channel,
             use_hypervisor_signals,
+            vcpu_exit_counters.clone(),
             #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
             to_gdb_channel.clone(),
         )?;
@@ -2486,6 +3688,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
         }
 
         let mut vm_control_indices_to_remove = Vec::new();
+        let mut vm_control_subscriptions = Vec::new();
         for event in events.iter().filter(|e| e.is_readable) {
             match event.token {
                 Token::Exit => {
@@ -2496,21 +3699,74 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                     info!("VM requested suspend");
                     linux.suspend_evt.read().unwrap();
                     kick_all_vcpus(&vcpu_handles, &linux.irq_chip, &VmRunMode::Suspending);
-                }
-                Token::ChildSignal => {
-                    // Print all available siginfo structs, then exit the loop.
-                    while let Some(siginfo) = sigchld_fd.read().map_err(Error::SignalFd)? {
-                        let pid = siginfo.ssi_pid;
-                        let pid_label = match linux.pid_debug_label_map.get(&pid) {
-                            Some(label) => format!("{} (pid {})", label, pid),
-                            None => format!("pid {}", pid),
-                        };
-                        error!(
-                            "child {} died: signo {}, status {}, code {}",
-                            pid_label, siginfo.ssi_signo, siginfo.ssi_status, siginfo.ssi_code
+                    if let Err(e) = linux.pci_root.lock().sleep_devices() {
+                        error!("failed to sleep PCI devices: {}", e);
+                    }
+                    guest_suspended = true;
+                    if let Some(wake_time) = linux.rtc_wake_alarm.get() {
+                        let now = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default();
+                        // A zero duration would disarm the timer, so wake an already expired alarm
+                        // after a millisecond.
+                        let delay = max(
+                            Duration::from_secs(wake_time)
+                                .checked_sub(now)
+                                .unwrap_or_default(),
+                            Duration::from_millis(1),
                         );
+                        info!("guest will be woken by its RTC alarm in {:?}", delay);
+                        rtc_alarm_timer
+                            .reset(delay, None)
+                            .map_err(Error::ResetTimer)?;
+                    }
+                }
+                Token::Signal => {
+                    // Handle all available siginfo structs, then exit the loop if any of them
+                    // asked for the VM to stop.
+                    let mut exit = false;
+                    while let Some(siginfo) = signal_fd.read().map_err(Error::SignalFd)? {
+                        match siginfo.ssi_signo as c_int {
+                            libc::SIGCHLD => {
+                                let pid = siginfo.ssi_pid;
+                                let pid_label = match linux.pid_debug_label_map.get(&pid) {
+                                    Some(label) => format!("{} (pid {})", label, pid),
+                                    None => format!("pid {}", pid),
+                                };
+                                error!(
+                                    "child {} died: signo {}, status {}, code {}",
+                                    pid_label,
+                                    siginfo.ssi_signo,
+                                    siginfo.ssi_status,
+                                    siginfo.ssi_code
+                                );
+                                exit = true;
+                            }
+                            libc::SIGTERM => {
+                                info!("received SIGTERM, stopping VM");
+                                exit = true;
+                            }
+                            libc::SIGWINCH => {
+                                // Only the virtio console has a notion of window size. The serial
+                                // devices use the terminal as a raw byte stream.
+                                if let Some(socket) = &console_resize_socket {
+                                    match stdin().window_size() {
+                                        Ok((cols, rows)) => {
+                                            if let Err(e) = socket.send(&ConsoleSize { cols, rows })
+                                            {
+                                                warn!("failed to send console size: {}", e);
+                                            }
+                                        }
+                                        Err(e) => warn!("failed to get terminal size: {}", e),
+                                    }
+                                }
+                            }
+                            signo => warn!("unexpected signal {} on signalfd", signo),
+                        }
+                    }
+                    if exit {
+                        break 'wait;
                     }
-                    break 'wait;
                 }
                 Token::IrqFd { index } => {
                     if let Err(e) = linux.irq_chip.service_irq_event(index) {
@@ -2524,12 +3780,75 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                         warn!("failed to send stats request to balloon device: {}", e);
                     }
                 }
+                Token::DeflateOnPressure => {
+                    deflate_timer.wait().map_err(Error::Timer)?;
+                    let command = BalloonControlCommand::Stats {};
+                    if let Err(e) = balloon_host_socket.send(&command) {
+                        warn!("failed to send stats request to balloon device: {}", e);
+                    }
+                }
+                Token::AutoBalloon => {
+                    auto_balloon_timer.wait().map_err(Error::Timer)?;
+                    if let Some(policy) = &mut auto_balloon {
+                        match read_memory_pressure(policy.psi_path()) {
+                            Ok(pressure) => {
+                                if let Some(num_bytes) = policy.update(pressure) {
+                                    info!(
+                                        "resizing balloon to {} bytes for host memory pressure {}%",
+                                        num_bytes, pressure
+                                    );
+                                    let command = BalloonControlCommand::Adjust { num_bytes };
+                                    if let Err(e) = balloon_host_socket.send(&command) {
+                                        warn!(
+                                            "failed to send memory value to balloon device: {}",
+                                            e
+                                        );
+                                    }
+                                }
+                            }
+                            Err(e) => warn!("failed to read host memory pressure: {}", e),
+                        }
+                    }
+                }
+                Token::RtcAlarm => {
+                    rtc_alarm_timer.wait().map_err(Error::Timer)?;
+                    if guest_suspended {
+                        info!("RTC alarm is waking the VM");
+                        guest_suspended = false;
+                        linux.io_bus.notify_resume();
+                        if let Err(e) = linux.pci_root.lock().wake_devices() {
+                            error!("failed to wake PCI devices: {}", e);
+                        }
+                        kick_all_vcpus(&vcpu_handles, &linux.irq_chip, &VmRunMode::Running);
+                    }
+                }
                 Token::BalloonResult => {
                     match balloon_host_socket.recv() {
                         Ok(BalloonControlResult::Stats {
                             stats,
                             balloon_actual: balloon_actual_u,
                         }) => {
+                            if let Some(num_bytes) = deflate_policy
+                                .as_ref()
+                                .and_then(|policy| policy.update(&stats, balloon_actual_u))
+                            {
+                                info!(
+                                    "deflating balloon to {} bytes for guest memory pressure",
+                                    num_bytes
+                                );
+                                if let Some(policy) = &mut auto_balloon {
+                                    policy.deflated_to(num_bytes);
+                                }
+                                let command = BalloonControlCommand::Adjust { num_bytes };
+                                if let Err(e) = balloon_host_socket.send(&command) {
+                                    warn!("failed to send memory value to balloon device: {}", e);
+                                }
+                                // The guest needs the memory more than the balancing below.
+                                continue;
+                            }
+                            if !balance_memory {
+                                continue;
+                            }
                             // Available memory is reported in MB, and we need bytes.
                             let host_available = file_to_i64(LOWMEM_AVAILABLE)
                                 .map_err(Error::ReadMemAvailable)?
@@ -2594,11 +3913,65 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                                 }
                             }
                         }
+                        Ok(BalloonControlResult::Err(e)) => {
+                            warn!("balloon device failed to resize: {}", e);
+                        }
+                        // Answers to the resize requests sent above.
+                        Ok(BalloonControlResult::Ok)
+                        | Ok(BalloonControlResult::Size { .. })
+                        | Ok(BalloonControlResult::Diagnostics(_)) => {}
                         Err(e) => {
                             error!("failed to recv BalloonControlResult: {}", e);
                         }
                     };
                 }
+                Token::BalloonEvent => match balloon_event_socket.recv() {
+                    Ok(event) => {
+                        // Subscribers that hung up are dropped when sending to them fails.
+                        let response = VmResponse::BalloonEvent(event);
+                        balloon_event_subscribers.retain(|socket| socket.send(&response).is_ok());
+                    }
+                    Err(e) => error!("failed to recv BalloonEvent: {}", e),
+                },
+                Token::VirtioEvent => match virtio_event_socket.recv() {
+                    Ok(event) => {
+                        let response = VmResponse::VirtioEvent(event);
+                        virtio_event_subscribers.retain(|socket| socket.send(&response).is_ok());
+                    }
+                    Err(e) => error!("failed to recv VirtioEvent: {}", e),
+                },
+                Token::GuestTelemetry => {
+                    if let (Some(socket), Some(telemetry)) =
+                        (&guest_telemetry_socket, &mut guest_telemetry)
+                    {
+                        match socket.recv() {
+                            Ok(event) => {
+                                if let GuestTelemetryEvent::OomKill { .. } = event {
+                                    warn!("{}", event);
+                                }
+                                telemetry.record(&event);
+                                let response = VmResponse::GuestTelemetryEvent(event);
+                                guest_telemetry_subscribers
+                                    .retain(|socket| socket.send(&response).is_ok());
+                            }
+                            Err(e) => error!("failed to recv GuestTelemetryEvent: {}", e),
+                        }
+                    }
+                }
+                Token::OperationUpdate => {
+                    if let Err(e) = operations.update_evt().read() {
+                        error!("failed to read operation update event: {}", e);
+                    }
+                    // Subscribers are dropped once their operation finished, or when sending to
+                    // them fails because they hung up.
+                    operation_subscribers.retain(|(id, socket)| match operations.state(*id) {
+                        Some(state) => {
+                            let response = VmResponse::OperationState { id: *id, state };
+                            socket.send(&response).is_ok() && !state.is_finished()
+                        }
+                        None => false,
+                    });
+                }
                 Token::VmControlServer => {
                     if let Some(socket_server) = &control_server_socket {
                         match socket_server.accept() {
@@ -2624,16 +3997,49 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                             TaggedControlSocket::Vm(socket) => match socket.recv() {
                                 Ok(request) => {
                                     let mut run_mode_opt = None;
+                                    let pci_root = &linux.pci_root;
                                     let response = request.execute(
                                         &mut run_mode_opt,
                                         &balloon_host_socket,
+                                        mem_host_socket.as_ref(),
+                                        fault_injection_host_socket.as_ref(),
                                         disk_host_sockets,
+                                        net_host_sockets,
+                                        pcie_slot_host_sockets,
                                         &usb_control_socket,
                                         &mut linux.bat_control,
+                                        &vcpu_exit_counters,
+                                        || pci_root.lock().virtio_stats(),
+                                        &linux.rtc_wake_alarm,
+                                        linux.vm.get_memory(),
+                                        guest_telemetry.as_ref(),
+                                        &mut operations,
                                     );
                                     if let Err(e) = socket.send(&response) {
                                         error!("failed to send VmResponse: {}", e);
                                     }
+                                    let subscription = match (&request, &response) {
+                                        (VmRequest::SubscribeBalloonEvents, _) => {
+                                            Some(Subscription::BalloonEvents)
+                                        }
+                                        (VmRequest::SubscribeVirtioEvents, _) => {
+                                            Some(Subscription::VirtioEvents)
+                                        }
+                                        (VmRequest::SubscribeGuestTelemetry, VmResponse::Ok) => {
+                                            Some(Subscription::GuestTelemetry)
+                                        }
+                                        (
+                                            VmRequest::SubscribeOperation { id },
+                                            VmResponse::OperationState { state, .. },
+                                        ) if !state.is_finished() => {
+                                            Some(Subscription::Operation(*id))
+                                        }
+                                        _ => None,
+                                    };
+                                    if let Some(subscription) = subscription {
+                                        vm_control_subscriptions.push((index, subscription));
+                                        vm_control_indices_to_remove.push(index);
+                                    }
                                     if let Some(run_mode) = run_mode_opt {
                                         info!("control socket changed run mode to {}", run_mode);
                                         match run_mode {
@@ -2643,12 +4049,31 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                                             other => {
                                                 if other == VmRunMode::Running {
                                                     linux.io_bus.notify_resume();
+                                                    guest_suspended = false;
+                                                    rtc_alarm_timer
+                                                        .clear()
+                                                        .map_err(Error::ResetTimer)?;
+                                                    if let Err(e) =
+                                                        linux.pci_root.lock().wake_devices()
+                                                    {
+                                                        error!("failed to wake PCI devices: {}", e);
+                                                    }
                                                 }
                                                 kick_all_vcpus(
                                                     &vcpu_handles,
                                                     &linux.irq_chip,
                                                     &other,
                                                 );
+                                                if other == VmRunMode::Suspending {
+                                                    if let Err(e) =
+                                                        linux.pci_root.lock().sleep_devices()
+                                                    {
+                                                        error!(
+                                                            "failed to sleep PCI devices: {}",
+                                                            e
+                                                        );
+                                                    }
+                                                }
                                             }
                                         }
                                     }
@@ -2750,10 +4175,33 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
             match event.token {
                 Token::Exit => {}
                 Token::Suspend => {}
-                Token::ChildSignal => {}
+                Token::Signal => {}
                 Token::IrqFd { index: _ } => {}
                 Token::BalanceMemory => {}
+                Token::AutoBalloon => {}
+                Token::DeflateOnPressure => {}
                 Token::BalloonResult => {}
+                Token::OperationUpdate => {}
+                Token::BalloonEvent => {
+                    if !event.is_readable {
+                        // The balloon device is gone and won't send any more events. If this call
+                        // fails, the socket was already removed from the WaitContext.
+                        let _ = wait_ctx.delete(&balloon_event_socket);
+                    }
+                }
+                Token::VirtioEvent => {
+                    if !event.is_readable {
+                        let _ = wait_ctx.delete(&virtio_event_socket);
+                    }
+                }
+                Token::GuestTelemetry => {
+                    if !event.is_readable {
+                        if let Some(socket) = &guest_telemetry_socket {
+                            let _ = wait_ctx.delete(socket);
+                        }
+                    }
+                }
+                Token::RtcAlarm => {}
                 Token::VmControlServer => {}
                 Token::VmControl { index } => {
                     // It's possible more data is readable and buffered while the socket is hungup,
@@ -2788,11 +4236,27 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static, I: IrqChipArch + '
                 wait_ctx.delete(socket).map_err(Error::WaitContextDelete)?;
             }
 
-            // This line implicitly drops the socket at `index` when it gets returned by
-            // `swap_remove`. After this line, the socket at `index` is not the one from
-            // `vm_control_indices_to_remove`. Because of this socket's change in index, we need to
-            // use `wait_ctx.modify` to change the associated index in its `Token::VmControl`.
-            control_sockets.swap_remove(index);
+            // The socket at `index` is dropped once it gets returned by `swap_remove`, unless it
+            // is kept to send events to. After this line, the socket at `index` is not the
+            // one from `vm_control_indices_to_remove`. Because of this socket's change in index, we
+            // need to use `wait_ctx.modify` to change the associated index in its
+            // `Token::VmControl`.
+            let socket = control_sockets.swap_remove(index);
+            if let TaggedControlSocket::Vm(socket) = socket {
+                match vm_control_subscriptions.iter().find(|(i, _)| *i == index) {
+                    Some((_, Subscription::BalloonEvents)) => {
+                        balloon_event_subscribers.push(socket)
+                    }
+                    Some((_, Subscription::VirtioEvents)) => virtio_event_subscribers.push(socket),
+                    Some((_, Subscription::GuestTelemetry)) => {
+                        guest_telemetry_subscribers.push(socket)
+                    }
+                    Some((_, Subscription::Operation(id))) => {
+                        operation_subscribers.push((*id, socket))
+                    }
+                    None => {}
+                }
+            }
             if let Some(socket) = control_sockets.get(index) {
                 wait_ctx
                     .modify(socket, EventType::Read, Token::VmControl { index })
@@ -11,6 +11,7 @@ use std::default::Default;
 use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::{BufRead, BufReader};
+use std::net::Ipv4Addr;
 use std::num::ParseIntError;
 use std::path::{Path, PathBuf};
 use std::string::String;
@@ -18,8 +19,8 @@ use std::thread::sleep;
 use std::time::Duration;
 
 use arch::{
-    set_default_serial_parameters, Pstore, SerialHardware, SerialParameters, SerialType,
-    VcpuAffinity,
+    set_default_serial_parameters, ArchLayout, Pstore, SerialHardware, SerialParameters,
+    SerialType, VcpuAffinity,
 };
 use base::{
     debug, error, getpid, info, kill_process_group, net::UnixSeqpacket, reap_child, syslog,
@@ -28,19 +29,29 @@ use base::{
 };
 use crosvm::{
     argument::{self, print_help, set_arguments, Argument},
-    platform, BindMount, Config, DiskOption, Executable, GidMap, SharedDir, TouchDeviceOption,
-    DISK_ID_LEN,
+    ivshmem_broker::Broker,
+    platform, AutoBalloonParameters, BindMount, Config, DeflateOnPressureParameters, DiskOption,
+    Executable, GidMap, MacvtapOption, ScsiDiskOption, SharedDir, SharedDirKind, TouchDeviceOption,
+    VhostScsiOption, VhostUserNetOption, DISK_ID_LEN, MAX_PCIE_ROOT_PORTS,
 };
+use devices::virtio::bench::{self, BenchParameters, BlockBenchOp};
 #[cfg(feature = "gpu")]
 use devices::virtio::gpu::{GpuMode, GpuParameters};
+use devices::virtio::{
+    create_tap, scsi, Duplex, NetLink, ThrottleLimits, ZonedOption, VIRTIO_MEM_BLOCK_SIZE,
+};
 #[cfg(feature = "audio")]
 use devices::{Ac97Backend, Ac97Parameters};
-use disk::QcowFile;
-use msg_socket::{MsgReceiver, MsgSender, MsgSocket};
+#[cfg(feature = "composite-disk")]
+use disk::{create_composite_disk, PartitionInfo};
+use disk::{CacheMode, ImageType, QcowCacheSize, QcowFile};
+use msg_socket::{MsgError, MsgReceiver, MsgSender, MsgSocket};
+use net_util::{MacAddress, MacvtapMode, Tap};
 use vm_control::{
     BalloonControlCommand, BatControlCommand, BatControlResult, BatteryType, DiskControlCommand,
-    MaybeOwnedDescriptor, UsbControlCommand, UsbControlResult, VmControlRequestSocket, VmRequest,
-    VmResponse, USB_CONTROL_MAX_PORTS,
+    FaultInjectionCommand, FaultInjectionConfig, MaybeOwnedDescriptor, MemControlCommand,
+    NetControlCommand, OperationState, PcieSlotControlCommand, UsbControlCommand, UsbControlResult,
+    VmControlRequestSocket, VmRequest, VmResponse, USB_CONTROL_MAX_PORTS,
 };
 
 fn executable_is_plugin(executable: &Option<Executable>) -> bool {
@@ -493,6 +504,15 @@ fn parse_serial_options(s: &str) -> argument::Result<SerialParameters> {
         }
     }
 
+    if serial_setting.type_.is_interactive()
+        && (serial_setting.stdin || serial_setting.input.is_some())
+    {
+        return Err(argument::Error::TooManyArguments(format!(
+            "Cannot specify stdin or input options with type={}",
+            serial_setting.type_
+        )));
+    }
+
     if serial_setting.hardware == SerialHardware::Serial && serial_setting.num > 4 {
         return Err(argument::Error::InvalidValue {
             value: serial_setting.num.to_string(),
@@ -627,1236 +647,3153 @@ fn parse_battery_options(s: Option<&str>) -> argument::Result<BatteryType> {
     Ok(battery_type)
 }
 
-fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::Result<()> {
-    match name {
-        "" => {
-            if cfg.executable_path.is_some() {
-                return Err(argument::Error::TooManyArguments(format!(
-                    "A VM executable was already specified: {:?}",
-                    cfg.executable_path
-                )));
-            }
-            let kernel_path = PathBuf::from(value.unwrap());
-            if !kernel_path.exists() {
-                return Err(argument::Error::InvalidValue {
-                    value: value.unwrap().to_owned(),
-                    expected: String::from("this kernel path does not exist"),
-                });
-            }
-            cfg.executable_path = Some(Executable::Kernel(kernel_path));
-        }
-        "android-fstab" => {
-            if cfg.android_fstab.is_some()
-                && !cfg.android_fstab.as_ref().unwrap().as_os_str().is_empty()
-            {
-                return Err(argument::Error::TooManyArguments(
-                    "expected exactly one android fstab path".to_owned(),
-                ));
-            } else {
-                let android_fstab = PathBuf::from(value.unwrap());
-                if !android_fstab.exists() {
-                    return Err(argument::Error::InvalidValue {
-                        value: value.unwrap().to_owned(),
-                        expected: String::from("this android fstab path does not exist"),
-                    });
+fn parse_auto_balloon_options(s: Option<&str>) -> argument::Result<AutoBalloonParameters> {
+    let mut params: AutoBalloonParameters = Default::default();
+
+    if let Some(s) = s {
+        let opts = s
+            .split(',')
+            .map(|frag| frag.splitn(2, '='))
+            .map(|mut kv| (kv.next().unwrap_or(""), kv.next().unwrap_or("")));
+
+        for (k, v) in opts {
+            let invalid = |expected: &str| argument::Error::InvalidValue {
+                value: v.to_string(),
+                expected: expected.to_string(),
+            };
+            match k {
+                "min_mem" => {
+                    params.min_mem = Some(
+                        v.parse()
+                            .map_err(|_| invalid("`min_mem` must be an integer"))?,
+                    )
+                }
+                "max_mem" => {
+                    params.max_mem = Some(
+                        v.parse()
+                            .map_err(|_| invalid("`max_mem` must be an integer"))?,
+                    )
+                }
+                "low" => {
+                    params.low_pressure = v
+                        .parse()
+                        .map_err(|_| invalid("`low` must be a percentage"))?
+                }
+                "high" => {
+                    params.high_pressure = v
+                        .parse()
+                        .map_err(|_| invalid("`high` must be a percentage"))?
+                }
+                "psi" => params.psi_path = PathBuf::from(v),
+                "" => {}
+                _ => {
+                    return Err(argument::Error::UnknownArgument(format!(
+                        "auto-balloon parameter {}",
+                        k
+                    )));
                 }
-                cfg.android_fstab = Some(android_fstab);
             }
         }
-        "params" => {
-            cfg.params.push(value.unwrap().to_owned());
+    }
+
+    if params.low_pressure >= params.high_pressure {
+        return Err(argument::Error::InvalidValue {
+            value: params.low_pressure.to_string(),
+            expected: String::from("`low` must be below `high`"),
+        });
+    }
+    if let (Some(min_mem), Some(max_mem)) = (params.min_mem, params.max_mem) {
+        if min_mem > max_mem {
+            return Err(argument::Error::InvalidValue {
+                value: min_mem.to_string(),
+                expected: String::from("`min_mem` must not exceed `max_mem`"),
+            });
         }
-        "cpus" => {
-            if cfg.vcpu_count.is_some() {
-                return Err(argument::Error::TooManyArguments(
-                    "`cpus` already given".to_owned(),
-                ));
-            }
-            cfg.vcpu_count =
-                Some(
-                    value
-                        .unwrap()
+    }
+
+    Ok(params)
+}
+
+fn parse_deflate_on_pressure_options(
+    s: Option<&str>,
+) -> argument::Result<DeflateOnPressureParameters> {
+    let mut params: DeflateOnPressureParameters = Default::default();
+
+    if let Some(s) = s {
+        let opts = s
+            .split(',')
+            .map(|frag| frag.splitn(2, '='))
+            .map(|mut kv| (kv.next().unwrap_or(""), kv.next().unwrap_or("")));
+
+        for (k, v) in opts {
+            let invalid = |expected: &str| argument::Error::InvalidValue {
+                value: v.to_string(),
+                expected: expected.to_string(),
+            };
+            match k {
+                "min_avail" => {
+                    params.min_available = v
                         .parse()
-                        .map_err(|_| argument::Error::InvalidValue {
-                            value: value.unwrap().to_owned(),
-                            expected: String::from("this value for `cpus` needs to be integer"),
-                        })?,
-                )
+                        .map_err(|_| invalid("`min_avail` must be an integer"))?
+                }
+                "step" => {
+                    params.step = v
+                        .parse()
+                        .map_err(|_| invalid("`step` must be an integer"))?
+                }
+                "" => {}
+                _ => {
+                    return Err(argument::Error::UnknownArgument(format!(
+                        "deflate-on-pressure parameter {}",
+                        k
+                    )));
+                }
+            }
         }
-        "cpu-affinity" => {
-            if cfg.vcpu_affinity.is_some() {
-                return Err(argument::Error::TooManyArguments(
-                    "`cpu-affinity` already given".to_owned(),
-                ));
+    }
+
+    if params.step == 0 {
+        return Err(argument::Error::InvalidValue {
+            value: params.step.to_string(),
+            expected: String::from("`step` must be at least 1"),
+        });
+    }
+
+    Ok(params)
+}
+
+// Parses a UUID in its canonical 8-4-4-4-12 hex digit form into its RFC 4122 bytes.
+fn parse_uuid(s: &str) -> argument::Result<[u8; 16]> {
+    let invalid = || argument::Error::InvalidValue {
+        value: s.to_owned(),
+        expected: String::from("a UUID like 01234567-89ab-cdef-0123-456789abcdef"),
+    };
+
+    let groups: Vec<&str> = s.split('-').collect();
+    let group_lens: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+    let digits = groups.concat();
+    if group_lens != [8, 4, 4, 4, 12] || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+
+    let mut uuid = [0u8; 16];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(uuid)
+}
+
+// Parses an interrupt coalescing setting given as either `min_interval` in microseconds or as
+// `max_rate` in interrupts per second, returning the minimum time between interrupts.
+fn parse_interrupt_coalescing(kind: &str, value: &str) -> argument::Result<Duration> {
+    let n: u64 = value.parse().map_err(|_| argument::Error::InvalidValue {
+        value: value.to_owned(),
+        expected: format!("`{}` must be an integer", kind),
+    })?;
+    match kind {
+        "min_interval" => Ok(Duration::from_micros(n)),
+        "max_rate" => {
+            if n == 0 {
+                return Err(argument::Error::InvalidValue {
+                    value: value.to_owned(),
+                    expected: String::from("`max_rate` must be greater than zero"),
+                });
             }
-            cfg.vcpu_affinity = Some(parse_cpu_affinity(value.unwrap())?);
+            Ok(Duration::from_nanos(1_000_000_000 / n))
         }
-        "no-smt" => {
-            cfg.no_smt = true;
+        _ => Err(argument::Error::InvalidValue {
+            value: kind.to_owned(),
+            expected: String::from("expected `min_interval` or `max_rate`"),
+        }),
+    }
+}
+
+// Parses a `KIND=VALUE` interrupt coalescing setting; see `parse_interrupt_coalescing`.
+fn parse_interrupt_coalescing_option(s: &str) -> argument::Result<Duration> {
+    let mut o = s.splitn(2, '=');
+    let kind = o.next().unwrap_or("");
+    let value = o.next().ok_or_else(|| argument::Error::InvalidValue {
+        value: s.to_owned(),
+        expected: String::from("expected `min_interval=MICROSECONDS` or `max_rate=PER_SECOND`"),
+    })?;
+    parse_interrupt_coalescing(kind, value)
+}
+
+// Parses one of the `iops`, `iops_burst`, `bps` or `bps_burst` disk throttling limits into
+// `limits`.
+fn parse_throttle_limit(
+    limits: &mut ThrottleLimits,
+    kind: &str,
+    value: &str,
+) -> argument::Result<()> {
+    let n = value.parse().map_err(|_| argument::Error::InvalidValue {
+        value: value.to_owned(),
+        expected: format!("`{}` must be an integer", kind),
+    })?;
+    match kind {
+        "iops" => limits.iops = n,
+        "iops_burst" => limits.iops_burst = n,
+        "bps" => limits.bytes_per_sec = n,
+        "bps_burst" => limits.bytes_burst = n,
+        _ => {
+            return Err(argument::Error::InvalidValue {
+                value: kind.to_owned(),
+                expected: String::from("expected `iops`, `iops_burst`, `bps` or `bps_burst`"),
+            })
         }
-        "rt-cpus" => {
-            if !cfg.rt_cpus.is_empty() {
-                return Err(argument::Error::TooManyArguments(
-                    "`rt-cpus` already given".to_owned(),
-                ));
-            }
-            cfg.rt_cpus = parse_cpu_set(value.unwrap())?;
+    }
+    Ok(())
+}
+
+// Parses comma-separated `KIND=VALUE` disk throttling limits; see `parse_throttle_limit`. Limits
+// that aren't given are unlimited.
+fn parse_throttle_limits(s: &str) -> argument::Result<ThrottleLimits> {
+    let mut limits = ThrottleLimits::default();
+    for opt in s.split(',') {
+        let mut o = opt.splitn(2, '=');
+        let kind = o.next().unwrap_or("");
+        let value = o.next().ok_or_else(|| argument::Error::InvalidValue {
+            value: opt.to_owned(),
+            expected: String::from("throttling limits must be of the form `kind=value`"),
+        })?;
+        parse_throttle_limit(&mut limits, kind, value)?;
+    }
+    Ok(limits)
+}
+
+fn parse_cache_mode(value: &str) -> argument::Result<CacheMode> {
+    match value {
+        "writeback" => Ok(CacheMode::Writeback),
+        "writethrough" => Ok(CacheMode::Writethrough),
+        "directsync" => Ok(CacheMode::DirectSync),
+        _ => Err(argument::Error::InvalidValue {
+            value: value.to_owned(),
+            expected: String::from("`cache` must be a cache mode"),
+        }),
+    }
+}
+
+fn parse_disk_id(value: &str) -> argument::Result<[u8; DISK_ID_LEN]> {
+    if value.len() > DISK_ID_LEN {
+        return Err(argument::Error::InvalidValue {
+            value: value.to_owned(),
+            expected: format!("`id` must be {} or fewer characters", DISK_ID_LEN),
+        });
+    }
+    let mut id = [0u8; DISK_ID_LEN];
+    // Slicing id to value's length will never panic
+    // because we checked that value will fit into id above.
+    id[..value.len()].copy_from_slice(value.as_bytes());
+    Ok(id)
+}
+
+fn parse_vsock_port(s: &str) -> argument::Result<(u32, PathBuf)> {
+    let mut components = s.splitn(2, ':');
+    let port = components.next().unwrap();
+    let path = match components.next() {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => {
+            return Err(argument::Error::InvalidValue {
+                value: s.to_owned(),
+                expected: String::from("vsock ports must be of the form `PORT:PATH`"),
+            })
         }
-        "mem" => {
-            if cfg.memory.is_some() {
-                return Err(argument::Error::TooManyArguments(
-                    "`mem` already given".to_owned(),
-                ));
+    };
+    let port = port.parse().map_err(|_| argument::Error::InvalidValue {
+        value: port.to_owned(),
+        expected: String::from("vsock port must be an unsigned 32-bit integer"),
+    })?;
+    Ok((port, path))
+}
+
+fn parse_vhost_scsi_options(s: &str) -> argument::Result<VhostScsiOption> {
+    let mut components = s.split(',');
+    let wwpn = components.next().unwrap_or("");
+    if wwpn.is_empty() {
+        return Err(argument::Error::InvalidValue {
+            value: s.to_owned(),
+            expected: String::from("a vhost-scsi target WWPN"),
+        });
+    }
+
+    let mut option = VhostScsiOption {
+        wwpn: wwpn.to_owned(),
+        tpgt: 1,
+        num_queues: 1,
+    };
+
+    for opt in components {
+        let mut o = opt.splitn(2, '=');
+        let kind = o.next().unwrap_or("");
+        let value = o.next().unwrap_or("");
+        match kind {
+            "tpgt" => {
+                option.tpgt = value.parse().map_err(|_| argument::Error::InvalidValue {
+                    value: value.to_owned(),
+                    expected: String::from("`tpgt` must be an unsigned 16-bit integer"),
+                })?;
             }
-            cfg.memory =
-                Some(
-                    value
-                        .unwrap()
-                        .parse()
-                        .map_err(|_| argument::Error::InvalidValue {
-                            value: value.unwrap().to_owned(),
-                            expected: String::from("this value for `mem` needs to be integer"),
-                        })?,
-                )
-        }
-        #[cfg(feature = "audio")]
-        "ac97" => {
-            let ac97_params = parse_ac97_options(value.unwrap())?;
-            // Add kernel parameters related to the intel8x0 driver for ac97 devices once.
-            if cfg.ac97_parameters.is_empty() {
-                // Set `inside_vm=1` to save some register read ops in the driver.
-                cfg.params.push("snd_intel8x0.inside_vm=1".to_string());
-                // Set `ac97_clock=48000` to save intel8x0_measure_ac97_clock call in the driver.
-                cfg.params.push("snd_intel8x0.ac97_clock=48000".to_string());
+            "num_queues" => {
+                option.num_queues = match value.parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => {
+                        return Err(argument::Error::InvalidValue {
+                            value: value.to_owned(),
+                            expected: String::from("`num_queues` must be a positive integer"),
+                        })
+                    }
+                };
             }
-            cfg.ac97_parameters.push(ac97_params);
-        }
-        "serial" => {
-            let serial_params = parse_serial_options(value.unwrap())?;
-            let num = serial_params.num;
-            let key = (serial_params.hardware, num);
-            if cfg.serial_parameters.contains_key(&key) {
-                return Err(argument::Error::TooManyArguments(format!(
-                    "serial hardware {} num {}",
-                    serial_params.hardware, num,
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "vhost-scsi parameter {}",
+                    kind
                 )));
             }
+        }
+    }
 
-            if serial_params.console {
-                for params in cfg.serial_parameters.values() {
-                    if params.console {
-                        return Err(argument::Error::TooManyArguments(format!(
-                            "{} device {} already set as console",
-                            params.hardware, params.num,
-                        )));
-                    }
+    Ok(option)
+}
+
+fn parse_net_link_options(s: &str) -> argument::Result<(usize, NetLink)> {
+    let mut index = 0;
+    let mut link = NetLink::default();
+    for opt in s.split(',').filter(|s| !s.is_empty()) {
+        let mut o = opt.splitn(2, '=');
+        let kind = o.next().unwrap_or("");
+        let value = o.next().unwrap_or("");
+        match kind {
+            "index" => {
+                index = value.parse().map_err(|_| argument::Error::InvalidValue {
+                    value: value.to_owned(),
+                    expected: String::from("`index` must be an unsigned integer"),
+                })?
+            }
+            "mtu" => {
+                let mtu: u16 = value.parse().map_err(|_| argument::Error::InvalidValue {
+                    value: value.to_owned(),
+                    expected: String::from("`mtu` must be an unsigned 16-bit integer"),
+                })?;
+                // The smallest MTU IPv4 works with.
+                if mtu < 68 {
+                    return Err(argument::Error::InvalidValue {
+                        value: value.to_owned(),
+                        expected: String::from("`mtu` must be at least 68"),
+                    });
                 }
+                link.mtu = Some(mtu);
             }
-
-            if serial_params.earlycon {
-                // Only SerialHardware::Serial supports earlycon= currently.
-                match serial_params.hardware {
-                    SerialHardware::Serial => {}
+            "speed" => {
+                link.speed = Some(value.parse().map_err(|_| argument::Error::InvalidValue {
+                    value: value.to_owned(),
+                    expected: String::from("`speed` must be an unsigned integer, in Mbit/s"),
+                })?)
+            }
+            "duplex" => {
+                link.duplex = Some(match value {
+                    "full" => Duplex::Full,
+                    "half" => Duplex::Half,
                     _ => {
                         return Err(argument::Error::InvalidValue {
-                            value: serial_params.hardware.to_string().to_owned(),
-                            expected: String::from("earlycon not supported for hardware"),
-                        });
-                    }
-                }
-                for params in cfg.serial_parameters.values() {
-                    if params.earlycon {
-                        return Err(argument::Error::TooManyArguments(format!(
-                            "{} device {} already set as earlycon",
-                            params.hardware, params.num,
-                        )));
+                            value: value.to_owned(),
+                            expected: String::from("`duplex` must be `full` or `half`"),
+                        })
                     }
-                }
-            }
-
-            if serial_params.stdin {
-                if let Some(previous_stdin) = cfg.serial_parameters.values().find(|sp| sp.stdin) {
-                    return Err(argument::Error::TooManyArguments(format!(
-                        "{} device {} already connected to standard input",
-                        previous_stdin.hardware, previous_stdin.num,
-                    )));
-                }
+                })
             }
-
-            cfg.serial_parameters.insert(key, serial_params);
-        }
-        "syslog-tag" => {
-            if cfg.syslog_tag.is_some() {
-                return Err(argument::Error::TooManyArguments(
-                    "`syslog-tag` already given".to_owned(),
-                ));
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "unknown net-link parameter {}",
+                    kind
+                )))
             }
-            syslog::set_proc_name(value.unwrap());
-            cfg.syslog_tag = Some(value.unwrap().to_owned());
         }
-        "root" | "rwroot" | "disk" | "rwdisk" => {
-            let param = value.unwrap();
-            let mut components = param.split(',');
-            let read_only = !name.starts_with("rw");
-            let disk_path =
-                PathBuf::from(
-                    components
-                        .next()
-                        .ok_or_else(|| argument::Error::InvalidValue {
-                            value: param.to_owned(),
-                            expected: String::from("missing disk path"),
-                        })?,
-                );
-            if !disk_path.exists() {
-                return Err(argument::Error::InvalidValue {
-                    value: param.to_owned(),
-                    expected: String::from("this disk path does not exist"),
-                });
+    }
+    Ok((index, link))
+}
+
+fn parse_vhost_user_net_options(s: &str) -> argument::Result<VhostUserNetOption> {
+    let mut components = s.split(',');
+    let socket = components.next().unwrap_or("");
+    if socket.is_empty() {
+        return Err(argument::Error::InvalidValue {
+            value: s.to_owned(),
+            expected: String::from("a vhost-user socket path"),
+        });
+    }
+
+    let mut option = VhostUserNetOption {
+        socket: PathBuf::from(socket),
+        mac: None,
+    };
+
+    for opt in components {
+        let mut o = opt.splitn(2, '=');
+        let kind = o.next().unwrap_or("");
+        let value = o.next().unwrap_or("");
+        match kind {
+            "mac" => {
+                option.mac = Some(value.parse().map_err(|_| argument::Error::InvalidValue {
+                    value: value.to_owned(),
+                    expected: String::from("`mac` must be a MAC address"),
+                })?);
             }
-            if name.ends_with("root") {
-                if cfg.disks.len() >= 26 {
-                    return Err(argument::Error::TooManyArguments(
-                        "ran out of letters for to assign to root disk".to_owned(),
-                    ));
-                }
-                cfg.params.push(format!(
-                    "root=/dev/vd{} {}",
-                    char::from(b'a' + cfg.disks.len() as u8),
-                    if read_only { "ro" } else { "rw" }
-                ));
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "vhost-user-net parameter {}",
+                    kind
+                )));
             }
+        }
+    }
 
-            let mut disk = DiskOption {
-                path: disk_path,
-                read_only,
-                sparse: true,
-                block_size: 512,
-                id: None,
-            };
+    Ok(option)
+}
 
-            for opt in components {
-                let mut o = opt.splitn(2, '=');
-                let kind = o.next().ok_or_else(|| argument::Error::InvalidValue {
-                    value: opt.to_owned(),
-                    expected: String::from("disk options must not be empty"),
-                })?;
-                let value = o.next().ok_or_else(|| argument::Error::InvalidValue {
-                    value: opt.to_owned(),
-                    expected: String::from("disk options must be of the form `kind=value`"),
-                })?;
+fn parse_macvtap_options(s: &str) -> argument::Result<MacvtapOption> {
+    let mut components = s.split(',');
+    let parent = components.next().unwrap_or("");
+    if parent.is_empty() || parent.len() >= libc::IFNAMSIZ {
+        return Err(argument::Error::InvalidValue {
+            value: s.to_owned(),
+            expected: String::from("a host interface name"),
+        });
+    }
 
-                match kind {
-                    "sparse" => {
-                        let sparse = value.parse().map_err(|_| argument::Error::InvalidValue {
+    let mut option = MacvtapOption {
+        parent: parent.to_owned(),
+        mode: MacvtapMode::Bridge,
+    };
+
+    for opt in components {
+        let mut o = opt.splitn(2, '=');
+        let kind = o.next().unwrap_or("");
+        let value = o.next().unwrap_or("");
+        match kind {
+            "mode" => {
+                option.mode = match value {
+                    "private" => MacvtapMode::Private,
+                    "vepa" => MacvtapMode::Vepa,
+                    "bridge" => MacvtapMode::Bridge,
+                    "passthru" => MacvtapMode::Passthru,
+                    _ => {
+                        return Err(argument::Error::InvalidValue {
                             value: value.to_owned(),
-                            expected: String::from("`sparse` must be a boolean"),
-                        })?;
-                        disk.sparse = sparse;
-                    }
-                    "block_size" => {
-                        let block_size =
-                            value.parse().map_err(|_| argument::Error::InvalidValue {
-                                value: value.to_owned(),
-                                expected: String::from("`block_size` must be an integer"),
-                            })?;
-                        disk.block_size = block_size;
-                    }
-                    "id" => {
-                        if value.len() > DISK_ID_LEN {
-                            return Err(argument::Error::InvalidValue {
-                                value: value.to_owned(),
-                                expected: format!(
-                                    "`id` must be {} or fewer characters",
-                                    DISK_ID_LEN
-                                ),
-                            });
-                        }
-                        let mut id = [0u8; DISK_ID_LEN];
-                        // Slicing id to value's length will never panic
-                        // because we checked that value will fit into id above.
-                        id[..value.len()].copy_from_slice(value.as_bytes());
-                        disk.id = Some(id);
+                            expected: String::from(
+                                "`mode` must be one of private, vepa, bridge or passthru",
+                            ),
+                        })
                     }
+                };
+            }
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "macvtap parameter {}",
+                    kind
+                )));
+            }
+        }
+    }
+
+    Ok(option)
+}
+
+// Parses the options of a unit of the virtio-scsi controller. A unit without a `lun` takes the
+// lowest LUN its target has free among the `existing` units.
+fn parse_scsi_disk_options(
+    s: &str,
+    read_only: bool,
+    existing: &[ScsiDiskOption],
+) -> argument::Result<ScsiDiskOption> {
+    let mut components = s.split(',');
+    let path = components.next().unwrap_or("");
+    if path.is_empty() {
+        return Err(argument::Error::InvalidValue {
+            value: s.to_owned(),
+            expected: String::from("missing disk path"),
+        });
+    }
+
+    let mut option = ScsiDiskOption {
+        path: PathBuf::from(path),
+        read_only,
+        target: 0,
+        lun: 0,
+        passthrough: false,
+        sparse: true,
+        cache_mode: CacheMode::default(),
+        block_size: 512,
+        id: None,
+    };
+    let mut lun = None;
+
+    for opt in components {
+        let mut o = opt.splitn(2, '=');
+        let kind = o.next().unwrap_or("");
+        let value = o.next().ok_or_else(|| argument::Error::InvalidValue {
+            value: opt.to_owned(),
+            expected: String::from("scsi disk options must be of the form `kind=value`"),
+        })?;
+        match kind {
+            "target" => {
+                option.target = value.parse().map_err(|_| argument::Error::InvalidValue {
+                    value: value.to_owned(),
+                    expected: String::from("`target` must be an integer from 0 to 255"),
+                })?;
+            }
+            "lun" => {
+                lun = match value.parse() {
+                    Ok(n) if n <= scsi::MAX_LUN => Some(n),
                     _ => {
                         return Err(argument::Error::InvalidValue {
-                            value: kind.to_owned(),
-                            expected: String::from("unrecognized disk option"),
-                        });
+                            value: value.to_owned(),
+                            expected: format!(
+                                "`lun` must be an integer from 0 to {}",
+                                scsi::MAX_LUN
+                            ),
+                        })
                     }
+                };
+            }
+            "passthrough" | "sparse" => {
+                let enable = value.parse().map_err(|_| argument::Error::InvalidValue {
+                    value: value.to_owned(),
+                    expected: format!("`{}` must be a boolean", kind),
+                })?;
+                if kind == "passthrough" {
+                    option.passthrough = enable;
+                } else {
+                    option.sparse = enable;
                 }
             }
+            "cache" => option.cache_mode = parse_cache_mode(value)?,
+            "block_size" => {
+                option.block_size = value.parse().map_err(|_| argument::Error::InvalidValue {
+                    value: value.to_owned(),
+                    expected: String::from("`block_size` must be an integer"),
+                })?;
+            }
+            "id" => option.id = Some(parse_disk_id(value)?),
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "scsi disk parameter {}",
+                    kind
+                )));
+            }
+        }
+    }
 
-            cfg.disks.push(disk);
+    let target = option.target;
+    let taken = |lun| existing.iter().any(|o| o.target == target && o.lun == lun);
+    option.lun = match lun {
+        Some(lun) if taken(lun) => {
+            return Err(argument::Error::InvalidValue {
+                value: s.to_owned(),
+                expected: format!("target {} already has a unit at LUN {}", target, lun),
+            })
         }
-        "pmem-device" | "rw-pmem-device" => {
-            let disk_path = PathBuf::from(value.unwrap());
-            if !disk_path.exists() {
+        Some(lun) => lun,
+        None => (0..=scsi::MAX_LUN)
+            .find(|&lun| !taken(lun))
+            .ok_or_else(|| {
+                argument::Error::TooManyArguments(format!("target {} has no free LUNs", target))
+            })?,
+    };
+
+    Ok(option)
+}
+
+fn parse_fault_injection_config(s: &str) -> argument::Result<FaultInjectionConfig> {
+    let mut config = FaultInjectionConfig::default();
+    for opt in s.split(',') {
+        let mut o = opt.splitn(2, '=');
+        let kind = o.next().unwrap_or("");
+        let value = o.next().unwrap_or("");
+        let field = match kind {
+            "used_delay_ms" => &mut config.used_delay_ms,
+            "corrupt_every" => &mut config.corrupt_every,
+            "drop_interrupt_every" => &mut config.drop_interrupt_every,
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "fault injection parameter {}",
+                    kind
+                )));
+            }
+        };
+        *field = value.parse().map_err(|_| argument::Error::InvalidValue {
+            value: value.to_owned(),
+            expected: format!("`{}` must be an unsigned integer", kind),
+        })?;
+    }
+
+    Ok(config)
+}
+
+fn parse_layout_options(s: &str) -> argument::Result<ArchLayout> {
+    let mut layout = ArchLayout::default();
+    for opt in s.split(',') {
+        let mut o = opt.splitn(2, '=');
+        let kind = o.next().unwrap_or("");
+        let value = o.next().unwrap_or("");
+        let field = match kind {
+            "ram_start" => &mut layout.ram_start,
+            "low_mmio_size" => &mut layout.low_mmio_size,
+            "high_mmio_start" => &mut layout.high_mmio_start,
+            "high_mmio_size" => &mut layout.high_mmio_size,
+            "identity_map_addr" => &mut layout.identity_map_addr,
+            _ => {
+                return Err(argument::Error::UnknownArgument(format!(
+                    "layout parameter {}",
+                    kind
+                )));
+            }
+        };
+        let parsed = if value.starts_with("0x") {
+            u64::from_str_radix(value.trim_start_matches("0x"), 16)
+        } else {
+            value.parse()
+        };
+        *field = Some(parsed.map_err(|_| argument::Error::InvalidValue {
+            value: value.to_owned(),
+            expected: format!("`{}` must be a decimal or 0x-prefixed hex address", kind),
+        })?);
+    }
+
+    Ok(layout)
+}
+
+fn set_argument(cfg: &mut Config, name: &str, value: Option<&str>) -> argument::Result<()> {
+    match name {
+        "" => {
+            if cfg.executable_path.is_some() {
+                return Err(argument::Error::TooManyArguments(format!(
+                    "A VM executable was already specified: {:?}",
+                    cfg.executable_path
+                )));
+            }
+            let kernel_path = PathBuf::from(value.unwrap());
+            if !kernel_path.exists() {
                 return Err(argument::Error::InvalidValue {
                     value: value.unwrap().to_owned(),
-                    expected: String::from("this disk path does not exist"),
+                    expected: String::from("this kernel path does not exist"),
                 });
             }
-
-            cfg.pmem_devices.push(DiskOption {
-                path: disk_path,
-                read_only: !name.starts_with("rw"),
-                sparse: false,
-                block_size: base::pagesize() as u32,
-                id: None,
-            });
+            cfg.executable_path = Some(Executable::Kernel(kernel_path));
         }
-        "pstore" => {
-            if cfg.pstore.is_some() {
+        "android-fstab" => {
+            if cfg.android_fstab.is_some()
+                && !cfg.android_fstab.as_ref().unwrap().as_os_str().is_empty()
+            {
                 return Err(argument::Error::TooManyArguments(
-                    "`pstore` already given".to_owned(),
+                    "expected exactly one android fstab path".to_owned(),
                 ));
+            } else {
+                let android_fstab = PathBuf::from(value.unwrap());
+                if !android_fstab.exists() {
+                    return Err(argument::Error::InvalidValue {
+                        value: value.unwrap().to_owned(),
+                        expected: String::from("this android fstab path does not exist"),
+                    });
+                }
+                cfg.android_fstab = Some(android_fstab);
             }
-
-            let value = value.unwrap();
-            let components: Vec<&str> = value.split(',').collect();
-            if components.len() != 2 {
-                return Err(argument::Error::InvalidValue {
-                    value: value.to_owned(),
-                    expected: String::from(
-                        "pstore must have exactly 2 components: path=<path>,size=<size>",
-                    ),
-                });
+        }
+        "params" => {
+            cfg.params.push(value.unwrap().to_owned());
+        }
+        "cpus" => {
+            if cfg.vcpu_count.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`cpus` already given".to_owned(),
+                ));
             }
-            cfg.pstore = Some(Pstore {
-                path: {
-                    if components[0].len() <= 5 || !components[0].starts_with("path=") {
-                        return Err(argument::Error::InvalidValue {
-                            value: components[0].to_owned(),
-                            expected: String::from("pstore path must follow with `path=`"),
-                        });
-                    };
-                    PathBuf::from(&components[0][5..])
-                },
-                size: {
-                    if components[1].len() <= 5 || !components[1].starts_with("size=") {
-                        return Err(argument::Error::InvalidValue {
-                            value: components[1].to_owned(),
-                            expected: String::from("pstore size must follow with `size=`"),
-                        });
-                    };
-                    components[1][5..]
-                        .parse()
-                        .map_err(|_| argument::Error::InvalidValue {
-                            value: value.to_owned(),
-                            expected: String::from("pstore size must be an integer"),
-                        })?
-                },
-            });
-        }
-        "host_ip" => {
-            if cfg.host_ip.is_some() {
-                return Err(argument::Error::TooManyArguments(
-                    "`host_ip` already given".to_owned(),
-                ));
-            }
-            cfg.host_ip =
+            cfg.vcpu_count =
                 Some(
                     value
                         .unwrap()
                         .parse()
                         .map_err(|_| argument::Error::InvalidValue {
                             value: value.unwrap().to_owned(),
-                            expected: String::from("`host_ip` needs to be in the form \"x.x.x.x\""),
+                            expected: String::from("this value for `cpus` needs to be integer"),
                         })?,
                 )
         }
-        "netmask" => {
-            if cfg.netmask.is_some() {
+        "cpu-affinity" => {
+            if cfg.vcpu_affinity.is_some() {
                 return Err(argument::Error::TooManyArguments(
-                    "`netmask` already given".to_owned(),
+                    "`cpu-affinity` already given".to_owned(),
                 ));
             }
-            cfg.netmask =
-                Some(
-                    value
-                        .unwrap()
-                        .parse()
-                        .map_err(|_| argument::Error::InvalidValue {
-                            value: value.unwrap().to_owned(),
-                            expected: String::from("`netmask` needs to be in the form \"x.x.x.x\""),
-                        })?,
-                )
+            cfg.vcpu_affinity = Some(parse_cpu_affinity(value.unwrap())?);
         }
-        "mac" => {
-            if cfg.mac_address.is_some() {
+        "no-smt" => {
+            cfg.no_smt = true;
+        }
+        "no-steal-time" => {
+            cfg.pv_features.steal_time = false;
+        }
+        "no-pv-tlb-flush" => {
+            cfg.pv_features.tlb_flush = false;
+        }
+        "no-pv-send-ipi" => {
+            cfg.pv_features.send_ipi = false;
+        }
+        "layout" => {
+            if cfg.layout != ArchLayout::default() {
                 return Err(argument::Error::TooManyArguments(
-                    "`mac` already given".to_owned(),
+                    "`layout` already given".to_owned(),
                 ));
             }
-            cfg.mac_address =
-                Some(
-                    value
-                        .unwrap()
-                        .parse()
-                        .map_err(|_| argument::Error::InvalidValue {
-                            value: value.unwrap().to_owned(),
-                            expected: String::from(
-                                "`mac` needs to be in the form \"XX:XX:XX:XX:XX:XX\"",
-                            ),
-                        })?,
-                )
+            cfg.layout = parse_layout_options(value.unwrap())?;
         }
-        "net-vq-pairs" => {
-            if cfg.net_vq_pairs.is_some() {
+        "rt-cpus" => {
+            if !cfg.rt_cpus.is_empty() {
                 return Err(argument::Error::TooManyArguments(
-                    "`net-vq-pairs` already given".to_owned(),
+                    "`rt-cpus` already given".to_owned(),
                 ));
             }
-            cfg.net_vq_pairs =
+            cfg.rt_cpus = parse_cpu_set(value.unwrap())?;
+        }
+        "auto-balloon" => {
+            if cfg.auto_balloon.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`auto-balloon` already given".to_owned(),
+                ));
+            }
+            cfg.auto_balloon = Some(parse_auto_balloon_options(value)?);
+        }
+        "deflate-on-pressure" => {
+            if cfg.deflate_on_pressure.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`deflate-on-pressure` already given".to_owned(),
+                ));
+            }
+            cfg.deflate_on_pressure = Some(parse_deflate_on_pressure_options(value)?);
+        }
+        "virtio-mem" => {
+            if cfg.virtio_mem_size.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`virtio-mem` already given".to_owned(),
+                ));
+            }
+            let invalid = || argument::Error::InvalidValue {
+                value: value.unwrap().to_owned(),
+                expected: String::from("`virtio-mem` must be a positive multiple of 2 MiB"),
+            };
+            let size_mib: u64 = value.unwrap().parse().map_err(|_| invalid())?;
+            let size = size_mib.checked_mul(1024 * 1024).ok_or_else(invalid)?;
+            if size == 0 || size % VIRTIO_MEM_BLOCK_SIZE != 0 {
+                return Err(invalid());
+            }
+            cfg.virtio_mem_size = Some(size);
+        }
+        "mem" => {
+            if cfg.memory.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`mem` already given".to_owned(),
+                ));
+            }
+            cfg.memory =
                 Some(
                     value
                         .unwrap()
                         .parse()
                         .map_err(|_| argument::Error::InvalidValue {
                             value: value.unwrap().to_owned(),
-                            expected: String::from(
-                                "this value for `net-vq-pairs` needs to be integer",
-                            ),
+                            expected: String::from("this value for `mem` needs to be integer"),
                         })?,
                 )
         }
+        #[cfg(feature = "audio")]
+        "ac97" => {
+            let ac97_params = parse_ac97_options(value.unwrap())?;
+            // Add kernel parameters related to the intel8x0 driver for ac97 devices once.
+            if cfg.ac97_parameters.is_empty() {
+                // Set `inside_vm=1` to save some register read ops in the driver.
+                cfg.params.push("snd_intel8x0.inside_vm=1".to_string());
+                // Set `ac97_clock=48000` to save intel8x0_measure_ac97_clock call in the driver.
+                cfg.params.push("snd_intel8x0.ac97_clock=48000".to_string());
+            }
+            cfg.ac97_parameters.push(ac97_params);
+        }
+        "serial" => {
+            let serial_params = parse_serial_options(value.unwrap())?;
+            let num = serial_params.num;
+            let key = (serial_params.hardware, num);
+            if cfg.serial_parameters.contains_key(&key) {
+                return Err(argument::Error::TooManyArguments(format!(
+                    "serial hardware {} num {}",
+                    serial_params.hardware, num,
+                )));
+            }
 
-        "wayland-sock" => {
-            let mut components = value.unwrap().split(',');
-            let path =
-                PathBuf::from(
-                    components
-                        .next()
-                        .ok_or_else(|| argument::Error::InvalidValue {
-                            value: value.unwrap().to_owned(),
-                            expected: String::from("missing socket path"),
-                        })?,
-                );
-            let mut name = "";
-            for c in components {
-                let mut kv = c.splitn(2, '=');
-                let (kind, value) = match (kv.next(), kv.next()) {
-                    (Some(kind), Some(value)) => (kind, value),
-                    _ => {
-                        return Err(argument::Error::InvalidValue {
-                            value: c.to_owned(),
-                            expected: String::from("option must be of the form `kind=value`"),
-                        })
+            if serial_params.console {
+                for params in cfg.serial_parameters.values() {
+                    if params.console {
+                        return Err(argument::Error::TooManyArguments(format!(
+                            "{} device {} already set as console",
+                            params.hardware, params.num,
+                        )));
                     }
-                };
-                match kind {
-                    "name" => name = value,
+                }
+            }
+
+            if serial_params.earlycon {
+                // Only SerialHardware::Serial supports earlycon= currently.
+                match serial_params.hardware {
+                    SerialHardware::Serial => {}
                     _ => {
                         return Err(argument::Error::InvalidValue {
-                            value: kind.to_owned(),
-                            expected: String::from("unrecognized option"),
-                        })
+                            value: serial_params.hardware.to_string().to_owned(),
+                            expected: String::from("earlycon not supported for hardware"),
+                        });
+                    }
+                }
+                for params in cfg.serial_parameters.values() {
+                    if params.earlycon {
+                        return Err(argument::Error::TooManyArguments(format!(
+                            "{} device {} already set as earlycon",
+                            params.hardware, params.num,
+                        )));
                     }
                 }
             }
-            if cfg.wayland_socket_paths.contains_key(name) {
-                return Err(argument::Error::TooManyArguments(format!(
-                    "wayland socket name already used: '{}'",
-                    name
-                )));
+
+            if serial_params.stdin {
+                if let Some(previous_stdin) = cfg.serial_parameters.values().find(|sp| sp.stdin) {
+                    return Err(argument::Error::TooManyArguments(format!(
+                        "{} device {} already connected to standard input",
+                        previous_stdin.hardware, previous_stdin.num,
+                    )));
+                }
             }
-            cfg.wayland_socket_paths.insert(name.to_string(), path);
+
+            cfg.serial_parameters.insert(key, serial_params);
         }
-        #[cfg(feature = "wl-dmabuf")]
-        "wayland-dmabuf" => cfg.wayland_dmabuf = true,
-        "x-display" => {
-            if cfg.x_display.is_some() {
+        "name" => {
+            if cfg.name.is_some() {
                 return Err(argument::Error::TooManyArguments(
-                    "`x-display` already given".to_owned(),
+                    "`name` already given".to_owned(),
                 ));
             }
-            cfg.x_display = Some(value.unwrap().to_owned());
-        }
-        "display-window-keyboard" => {
-            cfg.display_window_keyboard = true;
-        }
-        "display-window-mouse" => {
-            cfg.display_window_mouse = true;
+            let name = value.unwrap();
+            // The name ends up in file names, so keep it to characters that are safe there.
+            if name.is_empty()
+                || name.starts_with('.')
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+            {
+                return Err(argument::Error::InvalidValue {
+                    value: name.to_owned(),
+                    expected: String::from(
+                        "a name made of letters, digits, `-`, `_` and `.`, not starting with `.`",
+                    ),
+                });
+            }
+            // An explicit syslog tag wins over the one derived from the name.
+            if cfg.syslog_tag.is_none() {
+                syslog::set_proc_name(format!("crosvm-{}", name));
+            }
+            cfg.name = Some(name.to_owned());
         }
-        "socket" => {
-            if cfg.socket_path.is_some() {
+        "uuid" => {
+            if cfg.uuid.is_some() {
                 return Err(argument::Error::TooManyArguments(
-                    "`socket` already given".to_owned(),
+                    "`uuid` already given".to_owned(),
                 ));
             }
-            let mut socket_path = PathBuf::from(value.unwrap());
-            if socket_path.is_dir() {
-                socket_path.push(format!("crosvm-{}.sock", getpid()));
-            }
-            if socket_path.exists() {
-                return Err(argument::Error::InvalidValue {
-                    value: socket_path.to_string_lossy().into_owned(),
-                    expected: String::from("this socket path already exists"),
-                });
-            }
-            cfg.socket_path = Some(socket_path);
+            cfg.uuid = Some(parse_uuid(value.unwrap())?);
         }
-        "disable-sandbox" => {
-            cfg.sandbox = false;
+        "syslog-tag" => {
+            if cfg.syslog_tag.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`syslog-tag` already given".to_owned(),
+                ));
+            }
+            syslog::set_proc_name(value.unwrap());
+            cfg.syslog_tag = Some(value.unwrap().to_owned());
         }
-        "cid" => {
-            if cfg.cid.is_some() {
+        "trace-output" => {
+            if cfg.trace_output.is_some() {
                 return Err(argument::Error::TooManyArguments(
-                    "`cid` alread given".to_owned(),
+                    "`trace-output` already given".to_owned(),
                 ));
             }
-            cfg.cid = Some(
-                value
-                    .unwrap()
-                    .parse()
-                    .map_err(|_| argument::Error::InvalidValue {
-                        value: value.unwrap().to_owned(),
-                        expected: String::from("this value for `cid` must be an unsigned integer"),
-                    })?,
-            );
+            cfg.trace_output = Some(PathBuf::from(value.unwrap()));
         }
-        "shared-dir" => {
-            // This is formatted as multiple fields, each separated by ":". The first 2 fields are
-            // fixed (src:tag).  The rest may appear in any order:
-            //
-            // * type=TYPE - must be one of "p9" or "fs" (default: p9)
-            // * uidmap=UIDMAP - a uid map in the format "inner outer count[,inner outer count]"
-            //   (default: "0 <current euid> 1")
-            // * gidmap=GIDMAP - a gid map in the same format as uidmap
-            //   (default: "0 <current egid> 1")
-            // * timeout=TIMEOUT - a timeout value in seconds, which indicates how long attributes
-            //   and directory contents should be considered valid (default: 5)
-            // * cache=CACHE - one of "never", "always", or "auto" (default: auto)
-            // * writeback=BOOL - indicates whether writeback caching should be enabled (default: false)
+        "root" | "rwroot" | "disk" | "rwdisk" => {
             let param = value.unwrap();
-            let mut components = param.split(':');
-            let src =
+            let mut components = param.split(',');
+            let read_only = !name.starts_with("rw");
+            let disk_path =
                 PathBuf::from(
                     components
                         .next()
                         .ok_or_else(|| argument::Error::InvalidValue {
                             value: param.to_owned(),
-                            expected: String::from("missing source path for `shared-dir`"),
+                            expected: String::from("missing disk path"),
                         })?,
                 );
-            let tag = components
-                .next()
-                .ok_or_else(|| argument::Error::InvalidValue {
-                    value: param.to_owned(),
-                    expected: String::from("missing tag for `shared-dir`"),
-                })?
-                .to_owned();
-
-            if !src.is_dir() {
+            if !disk_path.exists() {
                 return Err(argument::Error::InvalidValue {
                     value: param.to_owned(),
-                    expected: String::from("source path for `shared-dir` must be a directory"),
+                    expected: String::from("this disk path does not exist"),
                 });
             }
+            if name.ends_with("root") {
+                if cfg.disks.len() >= 26 {
+                    return Err(argument::Error::TooManyArguments(
+                        "ran out of letters for to assign to root disk".to_owned(),
+                    ));
+                }
+                cfg.params.push(format!(
+                    "root=/dev/vd{} {}",
+                    char::from(b'a' + cfg.disks.len() as u8),
+                    if read_only { "ro" } else { "rw" }
+                ));
+            }
 
-            let mut shared_dir = SharedDir {
-                src,
-                tag,
-                ..Default::default()
+            let mut disk = DiskOption {
+                path: disk_path,
+                read_only,
+                sparse: true,
+                cache_mode: CacheMode::default(),
+                flush_coalescing: true,
+                max_merge_size: 0,
+                block_size: 512,
+                id: None,
+                num_queues: 1,
+                busy_poll: None,
+                interrupt_interval: None,
+                throttle: ThrottleLimits::default(),
+                qcow_cache_size: QcowCacheSize::default(),
+                zoned: None,
+                exclusive: false,
             };
+            let mut zone_size = None;
+            let mut max_open_zones = None;
+            let mut max_active_zones = None;
+
             for opt in components {
                 let mut o = opt.splitn(2, '=');
                 let kind = o.next().ok_or_else(|| argument::Error::InvalidValue {
                     value: opt.to_owned(),
-                    expected: String::from("`shared-dir` options must not be empty"),
+                    expected: String::from("disk options must not be empty"),
                 })?;
                 let value = o.next().ok_or_else(|| argument::Error::InvalidValue {
                     value: opt.to_owned(),
-                    expected: String::from("`shared-dir` options must be of the form `kind=value`"),
+                    expected: String::from("disk options must be of the form `kind=value`"),
                 })?;
 
                 match kind {
-                    "type" => {
-                        shared_dir.kind =
-                            value.parse().map_err(|_| argument::Error::InvalidValue {
-                                value: value.to_owned(),
-                                expected: String::from("`type` must be one of `fs` or `9p`"),
-                            })?
-                    }
-                    "uidmap" => shared_dir.uid_map = value.into(),
-                    "gidmap" => shared_dir.gid_map = value.into(),
-                    "timeout" => {
-                        let seconds = value.parse().map_err(|_| argument::Error::InvalidValue {
+                    "sparse" => {
+                        let sparse = value.parse().map_err(|_| argument::Error::InvalidValue {
                             value: value.to_owned(),
-                            expected: String::from("`timeout` must be an integer"),
+                            expected: String::from("`sparse` must be a boolean"),
                         })?;
-
-                        let dur = Duration::from_secs(seconds);
-                        shared_dir.fs_cfg.entry_timeout = dur.clone();
-                        shared_dir.fs_cfg.attr_timeout = dur;
+                        disk.sparse = sparse;
                     }
-                    "cache" => {
-                        let policy = value.parse().map_err(|_| argument::Error::InvalidValue {
-                            value: value.to_owned(),
-                            expected: String::from(
-                                "`cache` must be one of `never`, `always`, or `auto`",
-                            ),
-                        })?;
-                        shared_dir.fs_cfg.cache_policy = policy;
+                    "exclusive" => {
+                        disk.exclusive =
+                            value.parse().map_err(|_| argument::Error::InvalidValue {
+                                value: value.to_owned(),
+                                expected: String::from("`exclusive` must be a boolean"),
+                            })?;
                     }
-                    "writeback" => {
-                        let writeback =
+                    "cache" => disk.cache_mode = parse_cache_mode(value)?,
+                    "flush_coalescing" => {
+                        disk.flush_coalescing =
                             value.parse().map_err(|_| argument::Error::InvalidValue {
                                 value: value.to_owned(),
-                                expected: String::from("`writeback` must be a boolean"),
+                                expected: String::from("`flush_coalescing` must be a boolean"),
                             })?;
-                        shared_dir.fs_cfg.writeback = writeback;
                     }
-                    "rewrite-security-xattrs" => {
-                        let rewrite_security_xattrs =
+                    "max_merge_size" => {
+                        disk.max_merge_size =
                             value.parse().map_err(|_| argument::Error::InvalidValue {
                                 value: value.to_owned(),
-                                expected: String::from(
-                                    "`rewrite-security-xattrs` must be a boolean",
-                                ),
+                                expected: String::from("`max_merge_size` must be an integer"),
                             })?;
-                        shared_dir.fs_cfg.rewrite_security_xattrs = rewrite_security_xattrs;
                     }
-                    "ascii_casefold" => {
-                        let ascii_casefold =
+                    "block_size" => {
+                        let block_size =
                             value.parse().map_err(|_| argument::Error::InvalidValue {
                                 value: value.to_owned(),
-                                expected: String::from("`ascii_casefold` must be a boolean"),
+                                expected: String::from("`block_size` must be an integer"),
                             })?;
-                        shared_dir.fs_cfg.ascii_casefold = ascii_casefold;
-                        shared_dir.p9_cfg.ascii_casefold = ascii_casefold;
+                        disk.block_size = block_size;
+                    }
+                    "id" => disk.id = Some(parse_disk_id(value)?),
+                    "num_queues" => {
+                        disk.num_queues = match value.parse() {
+                            Ok(n) if n > 0 => n,
+                            _ => {
+                                return Err(argument::Error::InvalidValue {
+                                    value: value.to_owned(),
+                                    expected: String::from(
+                                        "`num_queues` must be a positive integer",
+                                    ),
+                                })
+                            }
+                        };
+                    }
+                    "busy_poll" => {
+                        let micros = value.parse().map_err(|_| argument::Error::InvalidValue {
+                            value: value.to_owned(),
+                            expected: String::from("`busy_poll` must be an integer"),
+                        })?;
+                        disk.busy_poll = Some(Duration::from_micros(micros));
+                    }
+                    "irq_min_interval" | "irq_max_rate" => {
+                        disk.interrupt_interval =
+                            Some(parse_interrupt_coalescing(&kind["irq_".len()..], value)?);
+                    }
+                    "iops" | "iops_burst" | "bps" | "bps_burst" => {
+                        parse_throttle_limit(&mut disk.throttle, kind, value)?;
+                    }
+                    "l2_cache" | "refcount_cache" => {
+                        let tables = match value.parse() {
+                            Ok(n) if n > 0 => n,
+                            _ => {
+                                return Err(argument::Error::InvalidValue {
+                                    value: value.to_owned(),
+                                    expected: format!("`{}` must be a positive integer", kind),
+                                })
+                            }
+                        };
+                        if kind == "l2_cache" {
+                            disk.qcow_cache_size.l2_tables = tables;
+                        } else {
+                            disk.qcow_cache_size.refcount_blocks = tables;
+                        }
+                    }
+                    "zoned" => {
+                        if value != "host" {
+                            return Err(argument::Error::InvalidValue {
+                                value: value.to_owned(),
+                                expected: String::from("`zoned` must be `host`"),
+                            });
+                        }
+                        disk.zoned = Some(ZonedOption::Host);
+                    }
+                    "zone_size" => {
+                        zone_size = match value.parse::<u64>() {
+                            Ok(n) if n.is_power_of_two() && n >= 512 => Some(n),
+                            _ => {
+                                return Err(argument::Error::InvalidValue {
+                                    value: value.to_owned(),
+                                    expected: String::from(
+                                        "`zone_size` must be a power of two of at least 512",
+                                    ),
+                                })
+                            }
+                        };
+                    }
+                    "max_open_zones" | "max_active_zones" => {
+                        let n = value.parse().map_err(|_| argument::Error::InvalidValue {
+                            value: value.to_owned(),
+                            expected: format!("`{}` must be an integer", kind),
+                        })?;
+                        if kind == "max_open_zones" {
+                            max_open_zones = Some(n);
+                        } else {
+                            max_active_zones = Some(n);
+                        }
                     }
                     _ => {
                         return Err(argument::Error::InvalidValue {
                             value: kind.to_owned(),
-                            expected: String::from("unrecognized option for `shared-dir`"),
-                        })
+                            expected: String::from("unrecognized disk option"),
+                        });
                     }
                 }
             }
-            cfg.shared_dirs.push(shared_dir);
-        }
-        "seccomp-policy-dir" => {
-            // `value` is Some because we are in this match so it's safe to unwrap.
-            cfg.seccomp_policy_dir = PathBuf::from(value.unwrap());
-        }
-        "seccomp-log-failures" => {
-            // A side-effect of this flag is to force the use of .policy files
-            // instead of .bpf files (.bpf files are expected and assumed to be
-            // compiled to fail an unpermitted action with "trap").
-            // Normally crosvm will first attempt to use a .bpf file, and if
-            // not present it will then try to use a .policy file.  It's up
-            // to the build to decide which of these files is present for
-            // crosvm to use (for CrOS the build will use .bpf files for
-            // x64 builds and .policy files for arm/arm64 builds).
-            //
-            // This flag will likely work as expected for builds that use
-            // .policy files.  For builds that only use .bpf files the initial
-            // result when using this flag is likely to be a file-not-found
-            // error (since the .policy files are not present).
-            // For .bpf builds you can either 1) manually add the .policy files,
-            // or 2) do not use this command-line parameter and instead
-            // temporarily change the build by passing "log" rather than
-            // "trap" as the "--default-action" to compile_seccomp_policy.py.
-            cfg.seccomp_log_failures = true;
-        }
-        "plugin" => {
-            if cfg.executable_path.is_some() {
-                return Err(argument::Error::TooManyArguments(format!(
-                    "A VM executable was already specified: {:?}",
-                    cfg.executable_path
-                )));
-            }
-            let plugin = PathBuf::from(value.unwrap().to_owned());
-            if plugin.is_relative() {
-                return Err(argument::Error::InvalidValue {
-                    value: plugin.to_string_lossy().into_owned(),
-                    expected: String::from("the plugin path must be an absolute path"),
-                });
-            }
-            cfg.executable_path = Some(Executable::Plugin(plugin));
-        }
-        "plugin-root" => {
-            cfg.plugin_root = Some(PathBuf::from(value.unwrap().to_owned()));
-        }
-        "plugin-mount" => {
-            let mount = parse_plugin_mount_option(value.unwrap())?;
-            cfg.plugin_mounts.push(mount);
-        }
-        "plugin-mount-file" => {
-            let file = File::open(value.unwrap()).map_err(|_| argument::Error::InvalidValue {
-                value: value.unwrap().to_owned(),
-                expected: String::from("unable to open `plugin-mount-file` file"),
-            })?;
-            let reader = BufReader::new(file);
-            for l in reader.lines() {
-                let line = l.unwrap();
-                let trimmed_line = line.splitn(2, '#').next().unwrap().trim();
-                if !trimmed_line.is_empty() {
-                    let mount = parse_plugin_mount_option(trimmed_line)?;
-                    cfg.plugin_mounts.push(mount);
+
+            // Zones are emulated over a regular file if their size is given. Otherwise only a host
+            // zoned device has them, and its limits are its own.
+            match (disk.zoned, zone_size) {
+                (Some(ZonedOption::Host), Some(_)) => {
+                    return Err(argument::Error::InvalidValue {
+                        value: param.to_owned(),
+                        expected: String::from("`zone_size` can't be given with `zoned=host`"),
+                    });
                 }
-            }
-        }
-        "plugin-gid-map" => {
-            let map = parse_plugin_gid_map_option(value.unwrap())?;
-            cfg.plugin_gid_maps.push(map);
-        }
-        "plugin-gid-map-file" => {
-            let file = File::open(value.unwrap()).map_err(|_| argument::Error::InvalidValue {
-                value: value.unwrap().to_owned(),
-                expected: String::from("unable to open `plugin-gid-map-file` file"),
-            })?;
-            let reader = BufReader::new(file);
-            for l in reader.lines() {
-                let line = l.unwrap();
-                let trimmed_line = line.splitn(2, '#').next().unwrap().trim();
-                if !trimmed_line.is_empty() {
-                    let map = parse_plugin_gid_map_option(trimmed_line)?;
-                    cfg.plugin_gid_maps.push(map);
+                (_, Some(zone_size)) => {
+                    disk.zoned = Some(ZonedOption::Emulated {
+                        zone_size,
+                        max_open_zones: max_open_zones.unwrap_or(0),
+                        max_active_zones: max_active_zones.unwrap_or(0),
+                    });
                 }
-            }
-        }
-        "vhost-net" => cfg.vhost_net = true,
-        "tap-fd" => {
-            cfg.tap_fd.push(
-                value
-                    .unwrap()
-                    .parse()
-                    .map_err(|_| argument::Error::InvalidValue {
-                        value: value.unwrap().to_owned(),
+                (_, None) if max_open_zones.is_some() || max_active_zones.is_some() => {
+                    return Err(argument::Error::InvalidValue {
+                        value: param.to_owned(),
                         expected: String::from(
-                            "this value for `tap-fd` must be an unsigned integer",
+                            "zone limits can only be given for emulated zones, with `zone_size`",
                         ),
-                    })?,
-            );
-        }
-        #[cfg(feature = "gpu")]
-        "gpu" => {
-            let params = parse_gpu_options(value)?;
-            cfg.gpu_parameters = Some(params);
+                    });
+                }
+                _ => {}
+            }
+
+            cfg.disks.push(disk);
         }
-        "software-tpm" => {
-            cfg.software_tpm = true;
+        "pmem-device" | "rw-pmem-device" => {
+            let disk_path = PathBuf::from(value.unwrap());
+            if !disk_path.exists() {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("this disk path does not exist"),
+                });
+            }
+
+            cfg.pmem_devices.push(DiskOption {
+                path: disk_path,
+                read_only: !name.starts_with("rw"),
+                sparse: false,
+                cache_mode: CacheMode::default(),
+                flush_coalescing: true,
+                max_merge_size: 0,
+                block_size: base::pagesize() as u32,
+                id: None,
+                num_queues: 1,
+                busy_poll: None,
+                interrupt_interval: None,
+                throttle: ThrottleLimits::default(),
+                qcow_cache_size: QcowCacheSize::default(),
+                zoned: None,
+                exclusive: false,
+            });
         }
-        "single-touch" => {
-            if cfg.virtio_single_touch.is_some() {
+        "pstore" => {
+            if cfg.pstore.is_some() {
                 return Err(argument::Error::TooManyArguments(
-                    "`single-touch` already given".to_owned(),
+                    "`pstore` already given".to_owned(),
                 ));
             }
-            let mut it = value.unwrap().split(':');
 
-            let mut single_touch_spec =
-                TouchDeviceOption::new(PathBuf::from(it.next().unwrap().to_owned()));
-            if let Some(width) = it.next() {
-                single_touch_spec.set_width(width.trim().parse().unwrap());
-            }
-            if let Some(height) = it.next() {
-                single_touch_spec.set_height(height.trim().parse().unwrap());
-            }
-            cfg.virtio_single_touch = Some(single_touch_spec);
+            let value = value.unwrap();
+            let components: Vec<&str> = value.split(',').collect();
+            if components.len() != 2 {
+                return Err(argument::Error::InvalidValue {
+                    value: value.to_owned(),
+                    expected: String::from(
+                        "pstore must have exactly 2 components: path=<path>,size=<size>",
+                    ),
+                });
+            }
+            cfg.pstore = Some(Pstore {
+                path: {
+                    if components[0].len() <= 5 || !components[0].starts_with("path=") {
+                        return Err(argument::Error::InvalidValue {
+                            value: components[0].to_owned(),
+                            expected: String::from("pstore path must follow with `path=`"),
+                        });
+                    };
+                    PathBuf::from(&components[0][5..])
+                },
+                size: {
+                    if components[1].len() <= 5 || !components[1].starts_with("size=") {
+                        return Err(argument::Error::InvalidValue {
+                            value: components[1].to_owned(),
+                            expected: String::from("pstore size must follow with `size=`"),
+                        });
+                    };
+                    components[1][5..]
+                        .parse()
+                        .map_err(|_| argument::Error::InvalidValue {
+                            value: value.to_owned(),
+                            expected: String::from("pstore size must be an integer"),
+                        })?
+                },
+            });
         }
-        "trackpad" => {
-            if cfg.virtio_trackpad.is_some() {
+        "host_ip" => {
+            if cfg.host_ip.is_some() {
                 return Err(argument::Error::TooManyArguments(
-                    "`trackpad` already given".to_owned(),
+                    "`host_ip` already given".to_owned(),
                 ));
             }
-            let mut it = value.unwrap().split(':');
-
-            let mut trackpad_spec =
-                TouchDeviceOption::new(PathBuf::from(it.next().unwrap().to_owned()));
-            if let Some(width) = it.next() {
-                trackpad_spec.set_width(width.trim().parse().unwrap());
-            }
-            if let Some(height) = it.next() {
-                trackpad_spec.set_height(height.trim().parse().unwrap());
+            cfg.host_ip =
+                Some(
+                    value
+                        .unwrap()
+                        .parse()
+                        .map_err(|_| argument::Error::InvalidValue {
+                            value: value.unwrap().to_owned(),
+                            expected: String::from("`host_ip` needs to be in the form \"x.x.x.x\""),
+                        })?,
+                )
+        }
+        "netmask" => {
+            if cfg.netmask.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`netmask` already given".to_owned(),
+                ));
             }
-            cfg.virtio_trackpad = Some(trackpad_spec);
+            cfg.netmask =
+                Some(
+                    value
+                        .unwrap()
+                        .parse()
+                        .map_err(|_| argument::Error::InvalidValue {
+                            value: value.unwrap().to_owned(),
+                            expected: String::from("`netmask` needs to be in the form \"x.x.x.x\""),
+                        })?,
+                )
         }
-        "mouse" => {
-            if cfg.virtio_mouse.is_some() {
+        "mac" => {
+            if cfg.mac_address.is_some() {
                 return Err(argument::Error::TooManyArguments(
-                    "`mouse` already given".to_owned(),
+                    "`mac` already given".to_owned(),
                 ));
             }
-            cfg.virtio_mouse = Some(PathBuf::from(value.unwrap().to_owned()));
+            cfg.mac_address =
+                Some(
+                    value
+                        .unwrap()
+                        .parse()
+                        .map_err(|_| argument::Error::InvalidValue {
+                            value: value.unwrap().to_owned(),
+                            expected: String::from(
+                                "`mac` needs to be in the form \"XX:XX:XX:XX:XX:XX\"",
+                            ),
+                        })?,
+                )
         }
-        "keyboard" => {
-            if cfg.virtio_keyboard.is_some() {
+        "net-vq-pairs" => {
+            if cfg.net_vq_pairs.is_some() {
                 return Err(argument::Error::TooManyArguments(
-                    "`keyboard` already given".to_owned(),
+                    "`net-vq-pairs` already given".to_owned(),
                 ));
             }
-            cfg.virtio_keyboard = Some(PathBuf::from(value.unwrap().to_owned()));
+            cfg.net_vq_pairs =
+                Some(
+                    value
+                        .unwrap()
+                        .parse()
+                        .map_err(|_| argument::Error::InvalidValue {
+                            value: value.unwrap().to_owned(),
+                            expected: String::from(
+                                "this value for `net-vq-pairs` needs to be integer",
+                            ),
+                        })?,
+                )
         }
-        "evdev" => {
-            let dev_path = PathBuf::from(value.unwrap());
-            if !dev_path.exists() {
-                return Err(argument::Error::InvalidValue {
-                    value: value.unwrap().to_owned(),
-                    expected: String::from("this input device path does not exist"),
-                });
+        "net-irq-coalescing" => {
+            if cfg.net_interrupt_interval.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`net-irq-coalescing` already given".to_owned(),
+                ));
             }
-            cfg.virtio_input_evdevs.push(dev_path);
+            cfg.net_interrupt_interval = Some(parse_interrupt_coalescing_option(value.unwrap())?);
         }
-        "split-irqchip" => {
-            cfg.split_irqchip = true;
+        "net-link" => {
+            let (index, link) = parse_net_link_options(value.unwrap())?;
+            if cfg.net_link.insert(index, link).is_some() {
+                return Err(argument::Error::TooManyArguments(format!(
+                    "`net-link` already given for net device {}",
+                    index
+                )));
+            }
         }
-        "initrd" => {
-            cfg.initrd_path = Some(PathBuf::from(value.unwrap().to_owned()));
+        "net-capture" => {
+            let mut components = value.unwrap().split(',');
+            let path = PathBuf::from(components.next().unwrap_or(""));
+            let mut index = 0;
+            for c in components {
+                let mut kv = c.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some("index"), Some(v)) => {
+                        index = v.parse().map_err(|_| argument::Error::InvalidValue {
+                            value: v.to_owned(),
+                            expected: String::from("`index` must be an unsigned integer"),
+                        })?
+                    }
+                    _ => {
+                        return Err(argument::Error::InvalidValue {
+                            value: c.to_owned(),
+                            expected: String::from("unrecognized option"),
+                        })
+                    }
+                }
+            }
+            if cfg.net_capture.insert(index, path).is_some() {
+                return Err(argument::Error::TooManyArguments(format!(
+                    "`net-capture` already given for net device {}",
+                    index
+                )));
+            }
         }
-        "bios" => {
-            if cfg.executable_path.is_some() {
+
+        "wayland-sock" => {
+            let mut components = value.unwrap().split(',');
+            let path =
+                PathBuf::from(
+                    components
+                        .next()
+                        .ok_or_else(|| argument::Error::InvalidValue {
+                            value: value.unwrap().to_owned(),
+                            expected: String::from("missing socket path"),
+                        })?,
+                );
+            let mut name = "";
+            for c in components {
+                let mut kv = c.splitn(2, '=');
+                let (kind, value) = match (kv.next(), kv.next()) {
+                    (Some(kind), Some(value)) => (kind, value),
+                    _ => {
+                        return Err(argument::Error::InvalidValue {
+                            value: c.to_owned(),
+                            expected: String::from("option must be of the form `kind=value`"),
+                        })
+                    }
+                };
+                match kind {
+                    "name" => name = value,
+                    _ => {
+                        return Err(argument::Error::InvalidValue {
+                            value: kind.to_owned(),
+                            expected: String::from("unrecognized option"),
+                        })
+                    }
+                }
+            }
+            if cfg.wayland_socket_paths.contains_key(name) {
                 return Err(argument::Error::TooManyArguments(format!(
-                    "A VM executable was already specified: {:?}",
-                    cfg.executable_path
+                    "wayland socket name already used: '{}'",
+                    name
                 )));
             }
-            cfg.executable_path = Some(Executable::Bios(PathBuf::from(value.unwrap().to_owned())));
+            cfg.wayland_socket_paths.insert(name.to_string(), path);
         }
-        "vfio" => {
-            let vfio_path = PathBuf::from(value.unwrap());
-            if !vfio_path.exists() {
-                return Err(argument::Error::InvalidValue {
-                    value: value.unwrap().to_owned(),
-                    expected: String::from("the vfio path does not exist"),
-                });
+        #[cfg(feature = "wl-dmabuf")]
+        "wayland-dmabuf" => cfg.wayland_dmabuf = true,
+        "cross-domain-service" => {
+            let mut components = value.unwrap().split(',');
+            let path =
+                PathBuf::from(
+                    components
+                        .next()
+                        .ok_or_else(|| argument::Error::InvalidValue {
+                            value: value.unwrap().to_owned(),
+                            expected: String::from("missing socket path"),
+                        })?,
+                );
+            let mut name = None;
+            for c in components {
+                let mut kv = c.splitn(2, '=');
+                let (kind, value) = match (kv.next(), kv.next()) {
+                    (Some(kind), Some(value)) => (kind, value),
+                    _ => {
+                        return Err(argument::Error::InvalidValue {
+                            value: c.to_owned(),
+                            expected: String::from("option must be of the form `kind=value`"),
+                        })
+                    }
+                };
+                match kind {
+                    "name" => name = Some(value),
+                    _ => {
+                        return Err(argument::Error::InvalidValue {
+                            value: kind.to_owned(),
+                            expected: String::from("unrecognized option"),
+                        })
+                    }
+                }
             }
-            if !vfio_path.is_dir() {
-                return Err(argument::Error::InvalidValue {
-                    value: value.unwrap().to_owned(),
-                    expected: String::from("the vfio path should be directory"),
-                });
+            let name = match name {
+                Some(name) if !name.is_empty() => name,
+                _ => {
+                    return Err(argument::Error::InvalidValue {
+                        value: value.unwrap().to_owned(),
+                        expected: String::from("cross-domain services need a `name=NAME`"),
+                    })
+                }
+            };
+            if cfg.cross_domain_services.contains_key(name) {
+                return Err(argument::Error::TooManyArguments(format!(
+                    "cross-domain service name already used: '{}'",
+                    name
+                )));
             }
-
-            cfg.vfio.push(vfio_path);
+            cfg.cross_domain_services.insert(name.to_string(), path);
         }
-        "video-decoder" => {
-            cfg.video_dec = true;
+        "x-display" => {
+            if cfg.x_display.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`x-display` already given".to_owned(),
+                ));
+            }
+            cfg.x_display = Some(value.unwrap().to_owned());
         }
-        "video-encoder" => {
-            cfg.video_enc = true;
+        "display-window-keyboard" => {
+            cfg.display_window_keyboard = true;
         }
-        "acpi-table" => {
-            let acpi_table = PathBuf::from(value.unwrap());
-            if !acpi_table.exists() {
+        "display-window-mouse" => {
+            cfg.display_window_mouse = true;
+        }
+        "socket" => {
+            if cfg.socket_path.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`socket` already given".to_owned(),
+                ));
+            }
+            // Directories are resolved once all arguments are in, as the name of the socket
+            // depends on the name of the VM.
+            cfg.socket_path = Some(PathBuf::from(value.unwrap()));
+        }
+        "disable-sandbox" => {
+            cfg.sandbox = false;
+        }
+        "cid" => {
+            if cfg.cid.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`cid` alread given".to_owned(),
+                ));
+            }
+            let cid: u64 = value
+                .unwrap()
+                .parse()
+                .map_err(|_| argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("this value for `cid` must be an unsigned integer"),
+                })?;
+            // CIDs 0 to 2 are taken by the hypervisor, local communication and the host, and
+            // vhost-vsock only takes 32-bit CIDs other than VMADDR_CID_ANY.
+            if cid <= 2 || cid >= u32::MAX as u64 {
                 return Err(argument::Error::InvalidValue {
                     value: value.unwrap().to_owned(),
-                    expected: String::from("the acpi-table path does not exist"),
+                    expected: String::from("`cid` must be greater than 2 and less than 4294967295"),
                 });
             }
-            if !acpi_table.is_file() {
+            cfg.cid = Some(cid);
+        }
+        "vsock-host-port" | "vsock-guest-port" => {
+            let (port, path) = parse_vsock_port(value.unwrap())?;
+            let ports = if name == "vsock-host-port" {
+                &mut cfg.vsock_host_ports
+            } else {
+                &mut cfg.vsock_guest_ports
+            };
+            if ports.contains_key(&port) {
+                return Err(argument::Error::TooManyArguments(format!(
+                    "`{}` already given for port {}",
+                    name, port
+                )));
+            }
+            ports.insert(port, path);
+        }
+        "shared-dir" => {
+            // This is formatted as multiple fields, each separated by ":". The first 2 fields are
+            // fixed (src:tag).  The rest may appear in any order:
+            //
+            // * type=TYPE - must be one of "p9" or "fs" (default: p9)
+            // * uidmap=UIDMAP - a uid map in the format "inner outer count[,inner outer count]"
+            //   (default: "0 <current euid> 1")
+            // * gidmap=GIDMAP - a gid map in the same format as uidmap
+            //   (default: "0 <current egid> 1")
+            // * timeout=TIMEOUT - a timeout value in seconds, which indicates how long attributes
+            //   and directory contents should be considered valid (default: 5)
+            // * cache=CACHE - one of "never", "always", or "auto" (default: auto)
+            // * writeback=BOOL - indicates whether writeback caching should be enabled (default: false)
+            // * max_bytes=BYTES - the most bytes the VM can write, only with type=fs (default: none)
+            // * max_inodes=COUNT - the most files the VM can create, only with type=fs (default: none)
+            let param = value.unwrap();
+            let mut components = param.split(':');
+            let src =
+                PathBuf::from(
+                    components
+                        .next()
+                        .ok_or_else(|| argument::Error::InvalidValue {
+                            value: param.to_owned(),
+                            expected: String::from("missing source path for `shared-dir`"),
+                        })?,
+                );
+            let tag = components
+                .next()
+                .ok_or_else(|| argument::Error::InvalidValue {
+                    value: param.to_owned(),
+                    expected: String::from("missing tag for `shared-dir`"),
+                })?
+                .to_owned();
+
+            if !src.is_dir() {
                 return Err(argument::Error::InvalidValue {
-                    value: value.unwrap().to_owned(),
-                    expected: String::from("the acpi-table path should be a file"),
+                    value: param.to_owned(),
+                    expected: String::from("source path for `shared-dir` must be a directory"),
                 });
             }
 
-            cfg.acpi_tables.push(acpi_table);
+            let mut shared_dir = SharedDir {
+                src,
+                tag,
+                ..Default::default()
+            };
+            for opt in components {
+                let mut o = opt.splitn(2, '=');
+                let kind = o.next().ok_or_else(|| argument::Error::InvalidValue {
+                    value: opt.to_owned(),
+                    expected: String::from("`shared-dir` options must not be empty"),
+                })?;
+                let value = o.next().ok_or_else(|| argument::Error::InvalidValue {
+                    value: opt.to_owned(),
+                    expected: String::from("`shared-dir` options must be of the form `kind=value`"),
+                })?;
+
+                match kind {
+                    "type" => {
+                        shared_dir.kind =
+                            value.parse().map_err(|_| argument::Error::InvalidValue {
+                                value: value.to_owned(),
+                                expected: String::from("`type` must be one of `fs` or `9p`"),
+                            })?
+                    }
+                    "uidmap" => shared_dir.uid_map = value.into(),
+                    "gidmap" => shared_dir.gid_map = value.into(),
+                    "timeout" => {
+                        let seconds = value.parse().map_err(|_| argument::Error::InvalidValue {
+                            value: value.to_owned(),
+                            expected: String::from("`timeout` must be an integer"),
+                        })?;
+
+                        let dur = Duration::from_secs(seconds);
+                        shared_dir.fs_cfg.entry_timeout = dur.clone();
+                        shared_dir.fs_cfg.attr_timeout = dur;
+                    }
+                    "cache" => {
+                        let policy = value.parse().map_err(|_| argument::Error::InvalidValue {
+                            value: value.to_owned(),
+                            expected: String::from(
+                                "`cache` must be one of `never`, `always`, or `auto`",
+                            ),
+                        })?;
+                        shared_dir.fs_cfg.cache_policy = policy;
+                    }
+                    "writeback" => {
+                        let writeback =
+                            value.parse().map_err(|_| argument::Error::InvalidValue {
+                                value: value.to_owned(),
+                                expected: String::from("`writeback` must be a boolean"),
+                            })?;
+                        shared_dir.fs_cfg.writeback = writeback;
+                    }
+                    "rewrite-security-xattrs" => {
+                        let rewrite_security_xattrs =
+                            value.parse().map_err(|_| argument::Error::InvalidValue {
+                                value: value.to_owned(),
+                                expected: String::from(
+                                    "`rewrite-security-xattrs` must be a boolean",
+                                ),
+                            })?;
+                        shared_dir.fs_cfg.rewrite_security_xattrs = rewrite_security_xattrs;
+                    }
+                    "ascii_casefold" => {
+                        let ascii_casefold =
+                            value.parse().map_err(|_| argument::Error::InvalidValue {
+                                value: value.to_owned(),
+                                expected: String::from("`ascii_casefold` must be a boolean"),
+                            })?;
+                        shared_dir.fs_cfg.ascii_casefold = ascii_casefold;
+                        shared_dir.p9_cfg.ascii_casefold = ascii_casefold;
+                    }
+                    "max_bytes" => {
+                        let max_bytes =
+                            value.parse().map_err(|_| argument::Error::InvalidValue {
+                                value: value.to_owned(),
+                                expected: String::from("`max_bytes` must be an integer"),
+                            })?;
+                        shared_dir.fs_cfg.max_bytes_written = Some(max_bytes);
+                    }
+                    "max_inodes" => {
+                        let max_inodes =
+                            value.parse().map_err(|_| argument::Error::InvalidValue {
+                                value: value.to_owned(),
+                                expected: String::from("`max_inodes` must be an integer"),
+                            })?;
+                        shared_dir.fs_cfg.max_inodes_created = Some(max_inodes);
+                    }
+                    _ => {
+                        return Err(argument::Error::InvalidValue {
+                            value: kind.to_owned(),
+                            expected: String::from("unrecognized option for `shared-dir`"),
+                        })
+                    }
+                }
+            }
+            // The 9p server has no way to enforce limits.
+            if matches!(shared_dir.kind, SharedDirKind::P9)
+                && (shared_dir.fs_cfg.max_bytes_written.is_some()
+                    || shared_dir.fs_cfg.max_inodes_created.is_some())
+            {
+                return Err(argument::Error::InvalidValue {
+                    value: param.to_owned(),
+                    expected: String::from("`max_bytes` and `max_inodes` require `type=fs`"),
+                });
+            }
+            cfg.shared_dirs.push(shared_dir);
         }
-        "protected-vm" => {
-            cfg.protected_vm = true;
-            cfg.params.push("swiotlb=force".to_string());
+        "seccomp-policy-dir" => {
+            // `value` is Some because we are in this match so it's safe to unwrap.
+            cfg.seccomp_policy_dir = PathBuf::from(value.unwrap());
         }
-        "battery" => {
-            let params = parse_battery_options(value)?;
-            cfg.battery_type = Some(params);
+        "seccomp-log-failures" => {
+            // A side-effect of this flag is to force the use of .policy files
+            // instead of .bpf files (.bpf files are expected and assumed to be
+            // compiled to fail an unpermitted action with "trap").
+            // Normally crosvm will first attempt to use a .bpf file, and if
+            // not present it will then try to use a .policy file.  It's up
+            // to the build to decide which of these files is present for
+            // crosvm to use (for CrOS the build will use .bpf files for
+            // x64 builds and .policy files for arm/arm64 builds).
+            //
+            // This flag will likely work as expected for builds that use
+            // .policy files.  For builds that only use .bpf files the initial
+            // result when using this flag is likely to be a file-not-found
+            // error (since the .policy files are not present).
+            // For .bpf builds you can either 1) manually add the .policy files,
+            // or 2) do not use this command-line parameter and instead
+            // temporarily change the build by passing "log" rather than
+            // "trap" as the "--default-action" to compile_seccomp_policy.py.
+            cfg.seccomp_log_failures = true;
         }
-        #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
-        "gdb" => {
-            let port = value
-                .unwrap()
-                .parse()
-                .map_err(|_| argument::Error::InvalidValue {
-                    value: value.unwrap().to_owned(),
-                    expected: String::from("expected a valid port number"),
-                })?;
-            cfg.gdb = Some(port);
+        "plugin" => {
+            if cfg.executable_path.is_some() {
+                return Err(argument::Error::TooManyArguments(format!(
+                    "A VM executable was already specified: {:?}",
+                    cfg.executable_path
+                )));
+            }
+            let plugin = PathBuf::from(value.unwrap().to_owned());
+            if plugin.is_relative() {
+                return Err(argument::Error::InvalidValue {
+                    value: plugin.to_string_lossy().into_owned(),
+                    expected: String::from("the plugin path must be an absolute path"),
+                });
+            }
+            cfg.executable_path = Some(Executable::Plugin(plugin));
         }
-        "help" => return Err(argument::Error::PrintHelp),
-        _ => unreachable!(),
+        "plugin-root" => {
+            cfg.plugin_root = Some(PathBuf::from(value.unwrap().to_owned()));
+        }
+        "plugin-mount" => {
+            let mount = parse_plugin_mount_option(value.unwrap())?;
+            cfg.plugin_mounts.push(mount);
+        }
+        "plugin-mount-file" => {
+            let file = File::open(value.unwrap()).map_err(|_| argument::Error::InvalidValue {
+                value: value.unwrap().to_owned(),
+                expected: String::from("unable to open `plugin-mount-file` file"),
+            })?;
+            let reader = BufReader::new(file);
+            for l in reader.lines() {
+                let line = l.unwrap();
+                let trimmed_line = line.splitn(2, '#').next().unwrap().trim();
+                if !trimmed_line.is_empty() {
+                    let mount = parse_plugin_mount_option(trimmed_line)?;
+                    cfg.plugin_mounts.push(mount);
+                }
+            }
+        }
+        "plugin-gid-map" => {
+            let map = parse_plugin_gid_map_option(value.unwrap())?;
+            cfg.plugin_gid_maps.push(map);
+        }
+        "plugin-gid-map-file" => {
+            let file = File::open(value.unwrap()).map_err(|_| argument::Error::InvalidValue {
+                value: value.unwrap().to_owned(),
+                expected: String::from("unable to open `plugin-gid-map-file` file"),
+            })?;
+            let reader = BufReader::new(file);
+            for l in reader.lines() {
+                let line = l.unwrap();
+                let trimmed_line = line.splitn(2, '#').next().unwrap().trim();
+                if !trimmed_line.is_empty() {
+                    let map = parse_plugin_gid_map_option(trimmed_line)?;
+                    cfg.plugin_gid_maps.push(map);
+                }
+            }
+        }
+        "vhost-net" => cfg.vhost_net = true,
+        "vhost-scsi" => {
+            let option = parse_vhost_scsi_options(value.unwrap())?;
+            cfg.vhost_scsi.push(option);
+        }
+        "vhost-user-blk" => cfg.vhost_user_blk.push(PathBuf::from(value.unwrap())),
+        "vhost-user-net" => {
+            let option = parse_vhost_user_net_options(value.unwrap())?;
+            cfg.vhost_user_net.push(option);
+        }
+        "scsi-disk" | "rw-scsi-disk" => {
+            let option =
+                parse_scsi_disk_options(value.unwrap(), !name.starts_with("rw"), &cfg.scsi_disks)?;
+            if !option.path.exists() {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("this disk path does not exist"),
+                });
+            }
+            cfg.scsi_disks.push(option);
+        }
+        "e1000" => cfg.e1000 = true,
+        "ivshmem" => cfg.ivshmem.push(PathBuf::from(value.unwrap())),
+        "user-net" => cfg.user_net = true,
+        "net-bridge" => {
+            let bridge = value.unwrap();
+            if bridge.is_empty() || bridge.len() >= libc::IFNAMSIZ {
+                return Err(argument::Error::InvalidValue {
+                    value: bridge.to_owned(),
+                    expected: String::from("`net-bridge` must be an interface name"),
+                });
+            }
+            cfg.net_bridges.push(bridge.to_owned());
+        }
+        "macvtap" => cfg.macvtaps.push(parse_macvtap_options(value.unwrap())?),
+        "tap-fd" => {
+            let fds = value
+                .unwrap()
+                .split(',')
+                .map(|fd| {
+                    fd.parse().map_err(|_| argument::Error::InvalidValue {
+                        value: value.unwrap().to_owned(),
+                        expected: String::from(
+                            "this value for `tap-fd` must be a comma separated list of unsigned integers",
+                        ),
+                    })
+                })
+                .collect::<argument::Result<Vec<_>>>()?;
+            cfg.tap_fd.push(fds);
+        }
+        #[cfg(feature = "gpu")]
+        "gpu" => {
+            let params = parse_gpu_options(value)?;
+            cfg.gpu_parameters = Some(params);
+        }
+        "software-tpm" => {
+            cfg.software_tpm = true;
+        }
+        #[cfg(feature = "fault-injection")]
+        "fault-injection" => {
+            cfg.fault_injection = true;
+        }
+        "guest-telemetry" => {
+            cfg.guest_telemetry = true;
+        }
+        "single-touch" => {
+            if cfg.virtio_single_touch.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`single-touch` already given".to_owned(),
+                ));
+            }
+            let mut it = value.unwrap().split(':');
+
+            let mut single_touch_spec =
+                TouchDeviceOption::new(PathBuf::from(it.next().unwrap().to_owned()));
+            if let Some(width) = it.next() {
+                single_touch_spec.set_width(width.trim().parse().unwrap());
+            }
+            if let Some(height) = it.next() {
+                single_touch_spec.set_height(height.trim().parse().unwrap());
+            }
+            cfg.virtio_single_touch = Some(single_touch_spec);
+        }
+        "trackpad" => {
+            if cfg.virtio_trackpad.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`trackpad` already given".to_owned(),
+                ));
+            }
+            let mut it = value.unwrap().split(':');
+
+            let mut trackpad_spec =
+                TouchDeviceOption::new(PathBuf::from(it.next().unwrap().to_owned()));
+            if let Some(width) = it.next() {
+                trackpad_spec.set_width(width.trim().parse().unwrap());
+            }
+            if let Some(height) = it.next() {
+                trackpad_spec.set_height(height.trim().parse().unwrap());
+            }
+            cfg.virtio_trackpad = Some(trackpad_spec);
+        }
+        "mouse" => {
+            if cfg.virtio_mouse.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`mouse` already given".to_owned(),
+                ));
+            }
+            cfg.virtio_mouse = Some(PathBuf::from(value.unwrap().to_owned()));
+        }
+        "keyboard" => {
+            if cfg.virtio_keyboard.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`keyboard` already given".to_owned(),
+                ));
+            }
+            cfg.virtio_keyboard = Some(PathBuf::from(value.unwrap().to_owned()));
+        }
+        "evdev" => {
+            let dev_path = PathBuf::from(value.unwrap());
+            if !dev_path.exists() {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("this input device path does not exist"),
+                });
+            }
+            cfg.virtio_input_evdevs.push(dev_path);
+        }
+        "split-irqchip" => {
+            cfg.split_irqchip = true;
+        }
+        "initrd" => {
+            cfg.initrd_path = Some(PathBuf::from(value.unwrap().to_owned()));
+        }
+        "bios" => {
+            if cfg.executable_path.is_some() {
+                return Err(argument::Error::TooManyArguments(format!(
+                    "A VM executable was already specified: {:?}",
+                    cfg.executable_path
+                )));
+            }
+            cfg.executable_path = Some(Executable::Bios(PathBuf::from(value.unwrap().to_owned())));
+        }
+        "pflash" => {
+            if cfg.pflash_path.is_some() {
+                return Err(argument::Error::TooManyArguments(
+                    "`pflash` already given".to_owned(),
+                ));
+            }
+            cfg.pflash_path = Some(PathBuf::from(value.unwrap().to_owned()));
+        }
+        "vfio" => {
+            let mut components = value.unwrap().split(',');
+            let mut vfio_path = PathBuf::from(components.next().unwrap_or(""));
+            if !vfio_path.exists() {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("the vfio path does not exist"),
+                });
+            }
+            if !vfio_path.is_dir() {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("the vfio path should be directory"),
+                });
+            }
+
+            for opt in components {
+                let mut kv = opt.splitn(2, '=');
+                match (kv.next().unwrap_or(""), kv.next().unwrap_or("")) {
+                    ("vf", v) => {
+                        let index: u16 = v.parse().map_err(|_| argument::Error::InvalidValue {
+                            value: v.to_owned(),
+                            expected: String::from("the vf index needs to be integer"),
+                        })?;
+                        // The path given is the SR-IOV physical function, assign one of its VFs.
+                        vfio_path =
+                            devices::vfio::sriov_vf_path(&vfio_path, index).map_err(|e| {
+                                argument::Error::InvalidValue {
+                                    value: value.unwrap().to_owned(),
+                                    expected: e.to_string(),
+                                }
+                            })?;
+                    }
+                    (k, _) => {
+                        return Err(argument::Error::UnknownArgument(format!(
+                            "vfio parameter {}",
+                            k
+                        )));
+                    }
+                }
+            }
+
+            cfg.vfio.push(vfio_path);
+        }
+        "pcie-root-ports" => {
+            let num: u8 = value
+                .unwrap()
+                .parse()
+                .map_err(|_| argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("this value for `pcie-root-ports` needs to be integer"),
+                })?;
+            if num > MAX_PCIE_ROOT_PORTS {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: format!(
+                        "`pcie-root-ports` must not be greater than {}",
+                        MAX_PCIE_ROOT_PORTS
+                    ),
+                });
+            }
+            cfg.pcie_root_ports = num;
+        }
+        "video-decoder" => {
+            cfg.video_dec = true;
+        }
+        "video-encoder" => {
+            cfg.video_enc = true;
+        }
+        "acpi-table" => {
+            let acpi_table = PathBuf::from(value.unwrap());
+            if !acpi_table.exists() {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("the acpi-table path does not exist"),
+                });
+            }
+            if !acpi_table.is_file() {
+                return Err(argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("the acpi-table path should be a file"),
+                });
+            }
+
+            cfg.acpi_tables.push(acpi_table);
+        }
+        "protected-vm" => {
+            cfg.protected_vm = true;
+            cfg.params.push("swiotlb=force".to_string());
+        }
+        "battery" => {
+            let params = parse_battery_options(value)?;
+            cfg.battery_type = Some(params);
+        }
+        #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+        "gdb" => {
+            let port = value
+                .unwrap()
+                .parse()
+                .map_err(|_| argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: String::from("expected a valid port number"),
+                })?;
+            cfg.gdb = Some(port);
+        }
+        "help" => return Err(argument::Error::PrintHelp),
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn validate_arguments(cfg: &mut Config) -> std::result::Result<(), argument::Error> {
+    if cfg.executable_path.is_none() {
+        return Err(argument::Error::ExpectedArgument("`KERNEL`".to_owned()));
+    }
+    if let Some(socket_path) = &mut cfg.socket_path {
+        if socket_path.is_dir() {
+            socket_path.push(match &cfg.name {
+                Some(name) => format!("crosvm-{}.sock", name),
+                None => format!("crosvm-{}.sock", getpid()),
+            });
+        }
+        if socket_path.exists() {
+            return Err(argument::Error::InvalidValue {
+                value: socket_path.to_string_lossy().into_owned(),
+                expected: String::from("this socket path already exists"),
+            });
+        }
+    }
+    if cfg.host_ip.is_some() || cfg.netmask.is_some() || cfg.mac_address.is_some() {
+        if cfg.host_ip.is_none() {
+            return Err(argument::Error::ExpectedArgument(
+                "`host_ip` missing from network config".to_owned(),
+            ));
+        }
+        if cfg.netmask.is_none() {
+            return Err(argument::Error::ExpectedArgument(
+                "`netmask` missing from network config".to_owned(),
+            ));
+        }
+        if cfg.mac_address.is_none() {
+            return Err(argument::Error::ExpectedArgument(
+                "`mac` missing from network config".to_owned(),
+            ));
+        }
+    }
+    if cfg.pflash_path.is_some() && !matches!(cfg.executable_path, Some(Executable::Bios(_))) {
+        return Err(argument::Error::ExpectedArgument(
+            "`pflash` requires `bios`".to_owned(),
+        ));
+    }
+    if cfg.e1000 && cfg.vhost_net {
+        return Err(argument::Error::ExpectedArgument(
+            "`e1000` can't be combined with `vhost-net`".to_owned(),
+        ));
+    }
+    if !cfg.net_capture.is_empty() && (cfg.e1000 || cfg.vhost_net) {
+        return Err(argument::Error::ExpectedArgument(
+            "`net-capture` can't be combined with `e1000` or `vhost-net`".to_owned(),
+        ));
+    }
+    if !cfg.net_link.is_empty() && (cfg.e1000 || cfg.vhost_net) {
+        return Err(argument::Error::ExpectedArgument(
+            "`net-link` can't be combined with `e1000` or `vhost-net`".to_owned(),
+        ));
+    }
+    if cfg.user_net && (cfg.e1000 || cfg.vhost_net) {
+        return Err(argument::Error::ExpectedArgument(
+            "`user-net` can't be combined with `e1000` or `vhost-net`".to_owned(),
+        ));
+    }
+    if (!cfg.net_bridges.is_empty() || !cfg.macvtaps.is_empty())
+        && (cfg.e1000 || executable_is_plugin(&cfg.executable_path))
+    {
+        return Err(argument::Error::ExpectedArgument(
+            "`net-bridge` and `macvtap` can't be combined with `e1000` or `plugin`".to_owned(),
+        ));
+    }
+    if cfg.tap_fd.iter().any(|fds| fds.len() > 1)
+        && (cfg.e1000 || cfg.vhost_net || executable_is_plugin(&cfg.executable_path))
+    {
+        return Err(argument::Error::ExpectedArgument(
+            "`tap-fd` with more than one descriptor can't be combined with `e1000`, `vhost-net` or `plugin`".to_owned(),
+        ));
+    }
+    if (!cfg.vsock_host_ports.is_empty() || !cfg.vsock_guest_ports.is_empty()) && cfg.cid.is_none()
+    {
+        return Err(argument::Error::ExpectedArgument(
+            "`vsock-host-port` and `vsock-guest-port` require `cid`".to_owned(),
+        ));
+    }
+    if let Some(path) = cfg.vsock_guest_ports.values().find(|path| path.exists()) {
+        return Err(argument::Error::InvalidValue {
+            value: path.to_string_lossy().into_owned(),
+            expected: String::from("this socket path already exists"),
+        });
+    }
+    if cfg.virtio_mem_size.is_some() && !cfg.vfio.is_empty() {
+        // VFIO pins all of guest memory for DMA, so unplugged blocks could not be given back.
+        return Err(argument::Error::ExpectedArgument(
+            "`virtio-mem` cannot be used with `vfio`".to_owned(),
+        ));
+    }
+    if cfg.plugin_root.is_some() && !executable_is_plugin(&cfg.executable_path) {
+        return Err(argument::Error::ExpectedArgument(
+            "`plugin-root` requires `plugin`".to_owned(),
+        ));
+    }
+    #[cfg(feature = "gpu")]
+    {
+        if let Some(gpu_parameters) = cfg.gpu_parameters.as_ref() {
+            let (width, height) = (gpu_parameters.display_width, gpu_parameters.display_height);
+            if let Some(virtio_single_touch) = cfg.virtio_single_touch.as_mut() {
+                virtio_single_touch.set_default_size(width, height);
+            }
+        }
+    }
+    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+    if cfg.gdb.is_some() {
+        if cfg.vcpu_count.unwrap_or(1) != 1 {
+            return Err(argument::Error::ExpectedArgument(
+                "`gdb` requires the number of vCPU to be 1".to_owned(),
+            ));
+        }
+    }
+    set_default_serial_parameters(&mut cfg.serial_parameters);
+    Ok(())
+}
+
+fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
+    let arguments =
+        &[Argument::positional("KERNEL", "bzImage of kernel to run"),
+          Argument::value("android-fstab", "PATH", "Path to Android fstab"),
+          Argument::short_value('i', "initrd", "PATH", "Initial ramdisk to load."),
+          Argument::short_value('p',
+                                "params",
+                                "PARAMS",
+                                "Extra kernel or plugin command line arguments. Can be given more than once."),
+          Argument::short_value('c', "cpus", "N", "Number of VCPUs. (default: 1)"),
+          Argument::value("cpu-affinity", "CPUSET", "Comma-separated list of CPUs or CPU ranges to run VCPUs on (e.g. 0,1-3,5)
+                              or colon-separated list of assignments of guest to host CPU assignments (e.g. 0=0:1=1:2=2) (default: no mask)"),
+          Argument::flag("no-smt", "Don't use SMT in the guest"),
+          Argument::flag("no-steal-time", "Don't let the guest account for the time its VCPUs are preempted by the host as steal time"),
+          Argument::flag("no-pv-tlb-flush", "Don't let the guest defer TLB flushes of preempted VCPUs through the hypervisor"),
+          Argument::flag("no-pv-send-ipi", "Don't let the guest send IPIs to several VCPUs with one hypercall"),
+          Argument::value("layout", "KEY=VALUE[,KEY=VALUE...]", "Comma separated overrides of the guest physical address space layout. Addresses are decimal or 0x-prefixed hex.
+                              Possible key values:
+                              ram_start=ADDR - Start of guest RAM (aarch64 only).
+                              low_mmio_size=SIZE - Size of the MMIO gap below 4G, a multiple of 256 MiB (x86_64 only, default: 0x30000000).
+                              high_mmio_start=ADDR - Start of the MMIO region above RAM.
+                              high_mmio_size=SIZE - Size of the MMIO region above RAM.
+                              identity_map_addr=ADDR - Page aligned address of the identity map and TSS (x86_64 only, default: 0xfffbc000)."),
+          Argument::value("rt-cpus", "CPUSET", "Comma-separated list of CPUs or CPU ranges to run VCPUs on. (e.g. 0,1-3,5) (default: none)"),
+          Argument::short_value('m',
+                                "mem",
+                                "N",
+                                "Amount of guest memory in MiB. (default: 256)"),
+          Argument::flag_or_value("auto-balloon",
+                                  "[min_mem=N,max_mem=N,low=PERCENT,high=PERCENT,psi=PATH]",
+                                  "Resize the balloon from the host memory pressure, giving guest memory back to the host while it is short.
+                                  Possible key values:
+                                  min_mem=N - Least guest memory in MiB (default: half of the guest memory)
+                                  max_mem=N - Most guest memory in MiB (default: all of the guest memory)
+                                  low=PERCENT - Pressure at or below which the balloon shrinks (default: 1)
+                                  high=PERCENT - Pressure at or above which the balloon grows (default: 10)
+                                  psi=PATH - Pressure stall information file to read, e.g. the memory.pressure of a cgroup (default: /proc/pressure/memory)
+                                  "),
+          Argument::flag_or_value("deflate-on-pressure",
+                                  "[min_avail=N,step=N]",
+                                  "Deflate the balloon when the memory the guest reports as available runs low, for guests that don't deflate it themselves before running out of memory.
+                                  Possible key values:
+                                  min_avail=N - Guest available memory in MiB below which the balloon deflates (default: 128)
+                                  step=N - Least memory in MiB each deflation gives back to the guest (default: 64)
+                                  "),
+          Argument::value("virtio-mem",
+                          "N",
+                          "Size in MiB of a virtio-mem region the guest can plug memory from, in addition to --mem. Must be a multiple of 2. The guest plugs what `crosvm virtio_mem` asks for."),
+          Argument::short_value('r',
+                                "root",
+                                "PATH[,key=value[,key=value[,...]]",
+                                "Path to a root disk image followed by optional comma-separated options.
+                              Like `--disk` but adds appropriate kernel command line option.
+                              See --disk for valid options."),
+          Argument::value("rwroot", "PATH[,key=value[,key=value[,...]]", "Path to a writable root disk image followed by optional comma-separated options.
+                              See --disk for valid options."),
+          Argument::short_value('d', "disk", "PATH[,key=value[,key=value[,...]]", "Path to a disk image followed by optional comma-separated options.
+                              Valid keys:
+                              sparse=BOOL - Indicates whether the disk should support the discard operation (default: true)
+                              cache=MODE - Host caching of the disk: writeback (writes reach storage when the guest flushes), writethrough (each write reaches storage before it completes) or directsync (writethrough that bypasses the host page cache with O_DIRECT; raw images only) (default: writeback)
+                              flush_coalescing=BOOL - Complete guest flushes that arrive together, or while the disk is being synced for earlier ones, after a single sync. Disable to sync once per flush, e.g. for durability testing (default: true)
+                              max_merge_size=BYTES - Merge guest reads or writes of contiguous sectors into host I/Os of up to this many bytes. Merging requests keeps the disk off io_uring (default: 0, disabled)
+                              block_size=BYTES - Set the reported block size of the disk (default: 512)
+                              id=STRING - Set the block device identifier to an ASCII string, up to 20 characters (default: no ID)
+                              num_queues=N - Number of virtqueues, each served by its own thread (default: 1)
+                              busy_poll=MICROSECONDS - Busy-poll the disk queue for up to this long after handling requests, trading CPU time for lower latency (default: disabled)
+                              irq_min_interval=MICROSECONDS - Interrupt the guest at most once per interval (default: after every request)
+                              irq_max_rate=PER_SECOND - Interrupt the guest at most this many times per second (default: unlimited)
+                              iops=N - Limit the disk to this many requests per second (default: unlimited)
+                              iops_burst=N - Number of requests allowed back to back after the disk has been idle (default: one second's worth)
+                              bps=BYTES - Limit the disk to this many bytes of data per second (default: unlimited)
+                              bps_burst=BYTES - Number of bytes allowed back to back after the disk has been idle (default: one second's worth)
+                              l2_cache=N - Number of L2 tables of a qcow image kept in memory (default: 100)
+                              refcount_cache=N - Number of refcount blocks of a qcow image kept in memory (default: 50)
+                              zoned=host - Pass the zones of a host zoned block device through to the guest, which writes to it with O_DIRECT
+                              zone_size=BYTES - Split a raw image into sequential write zones of this size, a power of two (default: not zoned)
+                              max_open_zones=N - Limit the number of open emulated zones (default: unlimited)
+                              max_active_zones=N - Limit the number of open and closed emulated zones (default: unlimited)
+                              exclusive=BOOL - Fail to start if another process has a writable disk open, e.g. to protect a shared base image (default: false)"),
+          Argument::value("rwdisk", "PATH[,key=value[,key=value[,...]]", "Path to a writable disk image followed by optional comma-separated options.
+                              See --disk for valid options."),
+          Argument::value("rw-pmem-device", "PATH", "Path to a writable disk image."),
+          Argument::value("pmem-device", "PATH", "Path to a disk image."),
+          Argument::value("pstore", "path=PATH,size=SIZE", "Path to pstore buffer backend file follewed by size."),
+          Argument::value("host_ip",
+                          "IP",
+                          "IP address to assign to host tap interface."),
+          Argument::value("netmask", "NETMASK", "Netmask for VM subnet."),
+          Argument::value("mac", "MAC", "MAC address for VM."),
+          Argument::value("net-vq-pairs", "N", "virtio net virtual queue paris. (default: 1)"),
+          Argument::value("net-irq-coalescing", "min_interval=MICROSECONDS|max_rate=PER_SECOND", "Limit how often each virtio net queue interrupts the guest. (default: after every batch of packets)"),
+          Argument::value("net-link", "[index=NET_INDEX,mtu=BYTES,speed=MBPS,duplex=full|half]", "Advertise the MTU, link speed and duplex mode of the virtio-net device NET_INDEX to the guest. Devices are counted as for --net-capture. (default index: 0)"),
+          Argument::value("net-capture", "PATH[,index=NET_INDEX]", "Write every frame the virtio-net device NET_INDEX sends or receives to PATH, in the pcapng format. Devices are counted in the order of --tap-fd, --net-bridge, --macvtap, --user-net and --host_ip. (default index: 0)"),
+          #[cfg(feature = "audio")]
+          Argument::value("ac97",
+                          "[backend=BACKEND,capture=true,capture_effect=EFFECT]",
+                          "Comma separated key=value pairs for setting up Ac97 devices. Can be given more than once .
+                          Possible key values:
+                          backend=(null, cras) - Where to route the audio device. If not provided, backend will default to null.
+                          `null` for /dev/null, and  cras for CRAS server.
+                          capture - Enable audio capture
+                          capture_effects - | separated effects to be enabled for recording. The only supported effect value now is EchoCancellation or aec."),
+          Argument::value("serial",
+                          "type=TYPE,[hardware=HW,num=NUM,path=PATH,input=PATH,console,earlycon,stdin]",
+                          "Comma separated key=value pairs for setting up serial devices. Can be given more than once.
+                          Possible key values:
+                          type=(stdout,syslog,sink,file,pty,unix-stream) - Where to route the serial device. pty allocates a pseudo terminal and logs its path, unix-stream listens for a console client on a unix socket at path.
+                          hardware=(serial,virtio-console) - Which type of serial hardware to emulate. Defaults to 8250 UART (serial).
+                          num=(1,2,3,4) - Serial Device Number. If not provided, num will default to 1.
+                          path=PATH - The path to the file to write to when type=file, or of the socket to bind when type=unix-stream
+                          input=PATH - The path to the file to read from when not stdin
+                          console - Use this serial device as the guest console. Can only be given once. Will default to first serial port if not provided.
+                          earlycon - Use this serial device as the early console. Can only be given once.
+                          stdin - Direct standard input to this serial device. Can only be given once. Will default to first serial port if not provided.
+                          "),
+          Argument::value("name", "NAME", "Name that tells the VM apart from others on the host. Logs are tagged with `crosvm-NAME`, and a control socket placed in a directory is named after it."),
+          Argument::value("uuid", "UUID", "System UUID the guest sees through SMBIOS, such as 01234567-89ab-cdef-0123-456789abcdef."),
+          Argument::value("syslog-tag", "TAG", "When logging to syslog, use the provided tag."),
+          Argument::value("trace-output", "PATH", "Record trace events of vcpu exits, virtio queue processing, io_uring submissions and device jail forks to PATH in the Chrome trace event format."),
+          Argument::value("x-display", "DISPLAY", "X11 display name to use."),
+          Argument::flag("display-window-keyboard", "Capture keyboard input from the display window."),
+          Argument::flag("display-window-mouse", "Capture keyboard input from the display window."),
+          Argument::value("wayland-sock", "PATH[,name=NAME]", "Path to the Wayland socket to use. The unnamed one is used for displaying virtual screens. Named ones are only for IPC."),
+          #[cfg(feature = "wl-dmabuf")]
+          Argument::flag("wayland-dmabuf", "Enable support for DMABufs in Wayland device."),
+          Argument::value("cross-domain-service", "PATH,name=NAME", "Path to a host service's socket that the guest can connect to by NAME through the cross-domain device, for sharing files, pipes and buffers with it. Can be given more than once."),
+          Argument::short_value('s',
+                                "socket",
+                                "PATH",
+                                "Path to put the control socket. If PATH is a directory, the socket is named after the VM, or after the process if it has no name."),
+          Argument::flag("disable-sandbox", "Run all devices in one, non-sandboxed process."),
+          Argument::value("cid", "CID", "Context ID for virtual sockets. Adds a vhost-vsock device, which lets host programs reach the guest over AF_VSOCK at this CID. Must be greater than 2."),
+          Argument::value("vsock-host-port", "PORT:PATH", "Bridge guest connections to host port PORT to the Unix socket at PATH. Replaces the vhost-vsock device with one run by crosvm, for hosts without vhost-vsock. Requires `cid`."),
+          Argument::value("vsock-guest-port", "PORT:PATH", "Create a Unix socket at PATH and bridge connections to it to guest port PORT. Replaces the vhost-vsock device with one run by crosvm, for hosts without vhost-vsock. Requires `cid`."),
+          Argument::value("shared-dir", "PATH:TAG[:type=TYPE:writeback=BOOL:timeout=SECONDS:uidmap=UIDMAP:gidmap=GIDMAP:cache=CACHE:max_bytes=BYTES:max_inodes=COUNT]",
+                          "Colon-separated options for configuring a directory to be shared with the VM.
+The first field is the directory to be shared and the second field is the tag that the VM can use to identify the device.
+The remaining fields are key=value pairs that may appear in any order.  Valid keys are:
+type=(p9, fs) - Indicates whether the directory should be shared via virtio-9p or virtio-fs (default: p9).
+uidmap=UIDMAP - The uid map to use for the device's jail in the format \"inner outer count[,inner outer count]\" (default: 0 <current euid> 1).
+gidmap=GIDMAP - The gid map to use for the device's jail in the format \"inner outer count[,inner outer count]\" (default: 0 <current egid> 1).
+cache=(never, auto, always) - Indicates whether the VM can cache the contents of the shared directory (default: auto).  When set to \"auto\" and the type is \"fs\", the VM will use close-to-open consistency for file contents.
+timeout=SECONDS - How long the VM should consider file attributes and directory entries to be valid (default: 5).  If the VM has exclusive access to the directory, then this should be a large value.  If the directory can be modified by other processes, then this should be 0.
+writeback=BOOL - Indicates whether the VM can use writeback caching (default: false).  This is only safe to do when the VM has exclusive access to the files in a directory.  Additionally, the server should have read permission for all files as the VM may issue read requests even for files that are opened write-only.
+max_bytes=BYTES - The most bytes the VM can write to files in the directory, counted while the VM runs. Further writes fail with ENOSPC (default: no limit).  Only with type=fs.
+max_inodes=COUNT - The most files, directories and links the VM can create in the directory, counted while the VM runs. Further creations fail with ENOSPC (default: no limit).  Only with type=fs.
+"),
+          Argument::value("seccomp-policy-dir", "PATH", "Path to seccomp .policy files."),
+          Argument::flag("seccomp-log-failures", "Instead of seccomp filter failures being fatal, they will be logged instead."),
+          #[cfg(feature = "plugin")]
+          Argument::value("plugin", "PATH", "Absolute path to plugin process to run under crosvm."),
+          #[cfg(feature = "plugin")]
+          Argument::value("plugin-root", "PATH", "Absolute path to a directory that will become root filesystem for the plugin process."),
+          #[cfg(feature = "plugin")]
+          Argument::value("plugin-mount", "PATH:PATH:BOOL", "Path to be mounted into the plugin's root filesystem.  Can be given more than once."),
+          #[cfg(feature = "plugin")]
+          Argument::value("plugin-mount-file", "PATH", "Path to the file listing paths be mounted into the plugin's root filesystem.  Can be given more than once."),
+          #[cfg(feature = "plugin")]
+          Argument::value("plugin-gid-map", "GID:GID:INT", "Supplemental GIDs that should be mapped in plugin jail.  Can be given more than once."),
+          #[cfg(feature = "plugin")]
+          Argument::value("plugin-gid-map-file", "PATH", "Path to the file listing supplemental GIDs that should be mapped in plugin jail.  Can be given more than once."),
+          Argument::flag("vhost-net", "Use the kernel's vhost-net driver for the datapath of the network devices, including those given with --tap-fd, so frames never pass through crosvm."),
+          Argument::value("vhost-scsi", "WWPN[,tpgt=TPGT,num_queues=N]", "Attach the LIO target WWPN to the VM as a vhost-scsi device. Can be given more than once.
+                          Possible key values:
+                          tpgt=TPGT - Target portal group tag under WWPN (default: 1).
+                          num_queues=N - Number of request queues (default: 1)."),
+          Argument::value("vhost-user-blk", "SOCKET", "Attach a disk served by the vhost-user block backend (e.g. SPDK) listening on SOCKET. Can be given more than once."),
+          Argument::value("vhost-user-net", "SOCKET[,mac=MAC]", "Attach a network device whose datapath is handled by the vhost-user backend (e.g. DPDK or Open vSwitch) listening on SOCKET. The device reconnects to SOCKET if the backend restarts. Can be given more than once.
+                          Possible key values:
+                          mac=MAC - MAC address offered to the guest."),
+          Argument::value("scsi-disk", "PATH[,key=value[,key=value[,...]]", "Attach a read-only disk image or host SCSI device to the VM's virtio-scsi controller. Can be given more than once.
+                          Possible key values:
+                          target=N - SCSI target of the unit (default: 0).
+                          lun=N - LUN of the unit on its target (default: the lowest free LUN).
+                          passthrough=BOOL - PATH is a host SCSI device, like /dev/sg0, that commands are passed through to with SG_IO, instead of a disk image (default: false).
+                          sparse=BOOL - Indicates whether the disk should support UNMAP (default: true).
+                          cache=MODE - Host caching of the disk image: writeback, writethrough or directsync (default: writeback).
+                          block_size=BYTES - Set the reported block size of the disk (default: 512).
+                          id=STRING - Set the unit serial number reported to the guest."),
+          Argument::value("rw-scsi-disk", "PATH[,key=value[,key=value[,...]]", "Attach a read-write disk image or host SCSI device to the VM's virtio-scsi controller. See --scsi-disk for possible key values."),
+          Argument::flag("e1000", "Emulate an Intel e1000 network card instead of virtio-net, for guests without virtio drivers."),
+          Argument::value("ivshmem", "PATH", "Socket of an ivshmem broker (see `crosvm ivshmem_broker`). Adds a device exposing the memory the broker shares with other VMs. Can be given more than once."),
+          Argument::flag("user-net", "Add a virtio-net device on a private 10.0.2.0/24 network, whose traffic crosvm itself forwards to the host's network. Needs no tap device or privileges. The guest gets its address over DHCP, and 10.0.2.2 reaches the host's loopback."),
+          Argument::value("tap-fd",
+                          "FD[,FD...]",
+                          "File descriptor for configured tap device. A different virtual network card will be added each time this argument is given. The descriptor can also be an opened macvtap device (/dev/tapN). Giving several descriptors, each an opened queue of the same multiqueue tap or macvtap device, adds a card with a queue pair for each."),
+          Argument::value("net-bridge", "BRIDGE", "Add a virtio-net device whose tap is attached to the host bridge BRIDGE. The bridge is created if it doesn't exist, and is left in place when crosvm exits. Can be given more than once."),
+          Argument::value("macvtap", "PARENT[,mode=private|vepa|bridge|passthru]", "Add a virtio-net device backed by a macvtap interface that crosvm creates on the host interface PARENT, and deletes when it exits. Can be given more than once. (default mode: bridge)"),
+          #[cfg(feature = "gpu")]
+          Argument::flag_or_value("gpu",
+                                  "[width=INT,height=INT]",
+                                  "(EXPERIMENTAL) Comma separated key=value pairs for setting up a virtio-gpu device
+                                  Possible key values:
+                                  backend=(2d|3d|gfxstream) - Which backend to use for virtio-gpu (determining rendering protocol)
+                                  width=INT - The width of the virtual display connected to the virtio-gpu.
+                                  height=INT - The height of the virtual display connected to the virtio-gpu.
+                                  egl[=true|=false] - If the virtio-gpu backend should use a EGL context for rendering.
+                                  glx[=true|=false] - If the virtio-gpu backend should use a GLX context for rendering.
+                                  surfaceless[=true|=false] - If the virtio-gpu backend should use a surfaceless context for rendering.
+                                  angle[=true|=false] - If the guest is using ANGLE (OpenGL on Vulkan) as its native OpenGL driver.
+                                  syncfd[=true|=false] - If the gfxstream backend should support EGL_ANDROID_native_fence_sync
+                                  vulkan[=true|=false] - If the gfxstream backend should support vulkan
+                                  "),
+          #[cfg(feature = "tpm")]
+          Argument::flag("software-tpm", "enable a software emulated trusted platform module device"),
+          #[cfg(feature = "fault-injection")]
+          Argument::flag("fault-injection", "Add a virtio test device that misbehaves as set with `crosvm fault_injection`."),
+          Argument::flag("guest-telemetry", "Add a virtio console the guest writes boot milestones, memory pressure and OOM kills to, read with `crosvm guest_telemetry`."),
+          Argument::value("evdev", "PATH", "Path to an event device node. The device will be grabbed (unusable from the host) and made available to the guest with the same configuration it shows on the host"),
+          Argument::value("single-touch", "PATH:WIDTH:HEIGHT", "Path to a socket from where to read single touch input events (such as those from a touchscreen) and write status updates to, optionally followed by width and height (defaults to 800x1280)."),
+          Argument::value("trackpad", "PATH:WIDTH:HEIGHT", "Path to a socket from where to read trackpad input events and write status updates to, optionally followed by screen width and height (defaults to 800x1280)."),
+          Argument::value("mouse", "PATH", "Path to a socket from where to read mouse input events and write status updates to."),
+          Argument::value("keyboard", "PATH", "Path to a socket from where to read keyboard input events and write status updates to."),
+          #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+          Argument::flag("split-irqchip", "(EXPERIMENTAL) enable split-irqchip support"),
+          Argument::value("bios", "PATH", "Path to BIOS/firmware ROM"),
+          Argument::value("pflash", "PATH", "Path to a writable flash image, such as a UEFI variable store, mapped directly below the BIOS. Guest writes are saved to PATH. Requires `bios`."),
+          Argument::value("vfio", "PATH[,vf=INDEX]", "Path to sysfs of pass through or mdev device. With vf, PATH is an SR-IOV physical function and its enabled virtual function INDEX is passed through instead"),
+          Argument::value("pcie-root-ports", "N", "Number of hot-plug capable PCIe root ports to create. Their slots are controlled with `crosvm pcie_slot`. (default: 0)"),
+          #[cfg(feature = "video-decoder")]
+          Argument::flag("video-decoder", "(EXPERIMENTAL) enable virtio-video decoder device"),
+          #[cfg(feature = "video-encoder")]
+          Argument::flag("video-encoder", "(EXPERIMENTAL) enable virtio-video encoder device"),
+          Argument::value("acpi-table", "PATH", "Path to user provided ACPI table"),
+          Argument::flag("protected-vm", "(EXPERIMENTAL) prevent host access to guest memory"),
+          Argument::flag_or_value("battery",
+                                  "[type=TYPE]",
+                                  "Comma separated key=value pairs for setting up battery device
+                                  Possible key values:
+                                  type=goldfish - type of battery emulation, defaults to goldfish
+                                  "),
+          Argument::value("gdb", "PORT", "(EXPERIMENTAL) gdb on the given port"),
+          Argument::short_flag('h', "help", "Print help message.")];
+
+    let mut cfg = Config::default();
+    let match_res = set_arguments(args, &arguments[..], |name, value| {
+        set_argument(&mut cfg, name, value)
+    })
+    .and_then(|_| validate_arguments(&mut cfg));
+
+    match match_res {
+        #[cfg(feature = "plugin")]
+        Ok(()) if executable_is_plugin(&cfg.executable_path) => {
+            match crosvm::plugin::run_config(cfg) {
+                Ok(_) => {
+                    info!("crosvm and plugin have exited normally");
+                    Ok(())
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    Err(())
+                }
+            }
+        }
+        Ok(()) => match platform::run_config(cfg) {
+            Ok(_) => {
+                info!("crosvm has exited normally");
+                Ok(())
+            }
+            Err(e) => {
+                error!("{}", e);
+                Err(())
+            }
+        },
+        Err(argument::Error::PrintHelp) => {
+            print_help("crosvm run", "KERNEL", &arguments[..]);
+            Ok(())
+        }
+        Err(e) => {
+            error!("{}", e);
+            Err(())
+        }
+    }
+}
+
+fn handle_request(
+    request: &VmRequest,
+    args: std::env::Args,
+) -> std::result::Result<VmResponse, ()> {
+    let mut return_result = Err(());
+    for socket_path in args {
+        match UnixSeqpacket::connect(&socket_path) {
+            Ok(s) => {
+                let socket: VmControlRequestSocket = MsgSocket::new(s);
+                if let Err(e) = socket.send(request) {
+                    error!(
+                        "failed to send request to socket at '{}': {}",
+                        socket_path, e
+                    );
+                    return_result = Err(());
+                    continue;
+                }
+                match socket.recv() {
+                    Ok(response) => return_result = Ok(response),
+                    Err(e) => {
+                        error!(
+                            "failed to send request to socket at2 '{}': {}",
+                            socket_path, e
+                        );
+                        return_result = Err(());
+                        continue;
+                    }
+                }
+            }
+            Err(e) => {
+                error!("failed to connect to socket at '{}': {}", socket_path, e);
+                return_result = Err(());
+            }
+        }
+    }
+
+    return_result
+}
+
+fn vms_request(request: &VmRequest, args: std::env::Args) -> std::result::Result<(), ()> {
+    let response = handle_request(request, args)?;
+    info!("request response was {}", response);
+    Ok(())
+}
+
+/// Prints the state of the operation `id` each time it changes, until it finishes.
+fn follow_operation(
+    socket: &VmControlRequestSocket,
+    socket_path: &str,
+    id: u64,
+) -> std::result::Result<(), ()> {
+    if let Err(e) = socket.send(&VmRequest::SubscribeOperation { id }) {
+        error!(
+            "failed to send request to socket at '{}': {}",
+            socket_path, e
+        );
+        return Err(());
+    }
+    loop {
+        match socket.recv() {
+            Ok(VmResponse::OperationState { id, state }) => {
+                println!("operation {}: {}", id, state);
+                match state {
+                    OperationState::Running { .. } => {}
+                    OperationState::Completed => return Ok(()),
+                    _ => return Err(()),
+                }
+            }
+            Ok(response) => {
+                error!("failed to follow operation {}: {}", id, response);
+                return Err(());
+            }
+            Err(MsgError::RecvZero) => {
+                error!("crosvm exited before operation {} finished", id);
+                return Err(());
+            }
+            Err(e) => {
+                error!("failed to recv response from '{}': {}", socket_path, e);
+                return Err(());
+            }
+        }
+    }
+}
+
+/// Sends `request`, which starts an operation, to the VM at `socket_path` and waits for the
+/// operation to finish.
+fn operation_request(request: &VmRequest, socket_path: &str) -> std::result::Result<(), ()> {
+    let socket: VmControlRequestSocket = match UnixSeqpacket::connect(socket_path) {
+        Ok(s) => MsgSocket::new(s),
+        Err(e) => {
+            error!("failed to connect to socket at '{}': {}", socket_path, e);
+            return Err(());
+        }
+    };
+    if let Err(e) = socket.send(request) {
+        error!(
+            "failed to send request to socket at '{}': {}",
+            socket_path, e
+        );
+        return Err(());
+    }
+    match socket.recv() {
+        Ok(VmResponse::OperationStarted { id }) => {
+            info!("request started operation {}", id);
+            follow_operation(&socket, socket_path, id)
+        }
+        Ok(response) => {
+            error!("request failed: {}", response);
+            Err(())
+        }
+        Err(e) => {
+            error!("failed to recv response from '{}': {}", socket_path, e);
+            Err(())
+        }
+    }
+}
+
+fn stop_vms(args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() == 0 {
+        print_help("crosvm stop", "VM_SOCKET...", &[]);
+        println!("Stops the crosvm instance listening on each `VM_SOCKET` given.");
+        return Err(());
+    }
+    vms_request(&VmRequest::Exit, args)
+}
+
+fn suspend_vms(args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() == 0 {
+        print_help("crosvm suspend", "VM_SOCKET...", &[]);
+        println!("Suspends the crosvm instance listening on each `VM_SOCKET` given.");
+        return Err(());
+    }
+    vms_request(&VmRequest::Suspend, args)
+}
+
+fn resume_vms(args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() == 0 {
+        print_help("crosvm resume", "VM_SOCKET...", &[]);
+        println!("Resumes the crosvm instance listening on each `VM_SOCKET` given.");
+        return Err(());
+    }
+    vms_request(&VmRequest::Resume, args)
+}
+
+fn balloon_vms(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 2 {
+        print_help("crosvm balloon", "SIZE VM_SOCKET...", &[]);
+        println!("Set the ballon size of the crosvm instance to `SIZE` bytes.");
+        return Err(());
+    }
+    let num_bytes = match args.next().unwrap().parse::<u64>() {
+        Ok(n) => n,
+        Err(_) => {
+            error!("Failed to parse number of bytes");
+            return Err(());
+        }
+    };
+
+    let command = BalloonControlCommand::Adjust { num_bytes };
+    vms_request(&VmRequest::BalloonCommand(command), args)
+}
+
+fn balloon_hint(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 2 {
+        print_help("crosvm balloon_hint", "start|stop VM_SOCKET...", &[]);
+        println!(
+            "Starts or stops the guest reporting its free pages to the host, which drops them."
+        );
+        return Err(());
+    }
+    let start = match args.next().unwrap().as_ref() {
+        "start" => true,
+        "stop" => false,
+        c => {
+            error!("invalid balloon_hint command: {:?}", c);
+            return Err(());
+        }
+    };
+
+    let command = BalloonControlCommand::FreePageHinting { start };
+    vms_request(&VmRequest::BalloonCommand(command), args)
+}
+
+fn balloon_stats(args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() != 1 {
+        print_help("crosvm balloon_stats", "VM_SOCKET", &[]);
+        println!("Prints virtio balloon statistics for a `VM_SOCKET`.");
+        return Err(());
+    }
+    let command = BalloonControlCommand::Stats {};
+    let request = &VmRequest::BalloonCommand(command);
+    let response = handle_request(request, args)?;
+    println!("{}", response);
+    Ok(())
+}
+
+fn balloon_size(args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() != 1 {
+        print_help("crosvm balloon_size", "VM_SOCKET", &[]);
+        println!("Prints the target and actual balloon size in bytes for a `VM_SOCKET`.");
+        return Err(());
     }
+    let request = &VmRequest::BalloonCommand(BalloonControlCommand::Size);
+    let response = handle_request(request, args)?;
+    println!("{}", response);
     Ok(())
 }
 
-fn validate_arguments(cfg: &mut Config) -> std::result::Result<(), argument::Error> {
-    if cfg.executable_path.is_none() {
-        return Err(argument::Error::ExpectedArgument("`KERNEL`".to_owned()));
+fn balloon_diagnostics(args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() != 1 {
+        print_help("crosvm balloon_diagnostics", "VM_SOCKET", &[]);
+        println!("Prints the errors the virtio balloon ran into for a `VM_SOCKET`.");
+        return Err(());
     }
-    if cfg.host_ip.is_some() || cfg.netmask.is_some() || cfg.mac_address.is_some() {
-        if cfg.host_ip.is_none() {
-            return Err(argument::Error::ExpectedArgument(
-                "`host_ip` missing from network config".to_owned(),
-            ));
+    let request = &VmRequest::BalloonCommand(BalloonControlCommand::Diagnostics);
+    let response = handle_request(request, args)?;
+    println!("{}", response);
+    Ok(())
+}
+
+// Subscribes to the events of the VM at `socket_path` with `request`, and prints them until the VM
+// exits.
+fn print_events(socket_path: &str, request: &VmRequest, kind: &str) -> std::result::Result<(), ()> {
+    let socket: VmControlRequestSocket = match UnixSeqpacket::connect(socket_path) {
+        Ok(s) => MsgSocket::new(s),
+        Err(e) => {
+            error!("failed to connect to socket at '{}': {}", socket_path, e);
+            return Err(());
         }
-        if cfg.netmask.is_none() {
-            return Err(argument::Error::ExpectedArgument(
-                "`netmask` missing from network config".to_owned(),
-            ));
+    };
+    if let Err(e) = socket.send(request) {
+        error!(
+            "failed to send request to socket at '{}': {}",
+            socket_path, e
+        );
+        return Err(());
+    }
+    match socket.recv() {
+        Ok(VmResponse::Ok) => {}
+        Ok(response) => {
+            error!("failed to subscribe to {} events: {}", kind, response);
+            return Err(());
         }
-        if cfg.mac_address.is_none() {
-            return Err(argument::Error::ExpectedArgument(
-                "`mac` missing from network config".to_owned(),
-            ));
+        Err(e) => {
+            error!("failed to recv response from '{}': {}", socket_path, e);
+            return Err(());
         }
     }
-    if cfg.plugin_root.is_some() && !executable_is_plugin(&cfg.executable_path) {
-        return Err(argument::Error::ExpectedArgument(
-            "`plugin-root` requires `plugin`".to_owned(),
-        ));
-    }
-    #[cfg(feature = "gpu")]
-    {
-        if let Some(gpu_parameters) = cfg.gpu_parameters.as_ref() {
-            let (width, height) = (gpu_parameters.display_width, gpu_parameters.display_height);
-            if let Some(virtio_single_touch) = cfg.virtio_single_touch.as_mut() {
-                virtio_single_touch.set_default_size(width, height);
+
+    // Events keep coming until the VM exits.
+    loop {
+        match socket.recv() {
+            Ok(response) => println!("{}", response),
+            Err(MsgError::RecvZero) => return Ok(()),
+            Err(e) => {
+                error!("failed to recv {} event: {}", kind, e);
+                return Err(());
             }
         }
     }
-    #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
-    if cfg.gdb.is_some() {
-        if cfg.vcpu_count.unwrap_or(1) != 1 {
-            return Err(argument::Error::ExpectedArgument(
-                "`gdb` requires the number of vCPU to be 1".to_owned(),
-            ));
+}
+
+fn balloon_events(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() != 1 {
+        print_help("crosvm balloon_events", "VM_SOCKET", &[]);
+        println!("Prints the actual balloon size of a `VM_SOCKET` each time the guest changes it.");
+        return Err(());
+    }
+    let socket_path = args.next().unwrap();
+    print_events(&socket_path, &VmRequest::SubscribeBalloonEvents, "balloon")
+}
+
+fn virtio_events(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() != 1 {
+        print_help("crosvm virtio_events", "VM_SOCKET", &[]);
+        println!(
+            "Prints the virtio devices of a `VM_SOCKET` that the guest drivers fail to set up, \
+             along with the features involved."
+        );
+        return Err(());
+    }
+    let socket_path = args.next().unwrap();
+    print_events(&socket_path, &VmRequest::SubscribeVirtioEvents, "virtio")
+}
+
+fn guest_telemetry(args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() != 1 {
+        print_help("crosvm guest_telemetry", "VM_SOCKET", &[]);
+        println!(
+            "Prints the boot milestones, memory pressure and OOM kills the guest of a `VM_SOCKET` \
+             reported on its telemetry port."
+        );
+        return Err(());
+    }
+    let response = handle_request(&VmRequest::GuestTelemetry, args)?;
+    println!("{}", response);
+    Ok(())
+}
+
+fn guest_telemetry_events(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() != 1 {
+        print_help("crosvm guest_telemetry_events", "VM_SOCKET", &[]);
+        println!("Prints each record the guest of a `VM_SOCKET` writes to its telemetry port.");
+        return Err(());
+    }
+    let socket_path = args.next().unwrap();
+    print_events(
+        &socket_path,
+        &VmRequest::SubscribeGuestTelemetry,
+        "guest telemetry",
+    )
+}
+
+fn virtio_mem_vms(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 2 {
+        print_help("crosvm virtio_mem", "SIZE VM_SOCKET...", &[]);
+        println!("Ask the crosvm instance to plug `SIZE` bytes of its virtio-mem region.");
+        return Err(());
+    }
+    let requested_bytes = match args.next().unwrap().parse::<u64>() {
+        Ok(n) => n,
+        Err(_) => {
+            error!("Failed to parse number of bytes");
+            return Err(());
         }
+    };
+
+    let command = MemControlCommand::Resize { requested_bytes };
+    vms_request(&VmRequest::MemCommand(command), args)
+}
+
+fn virtio_mem_size(args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() != 1 {
+        print_help("crosvm virtio_mem_size", "VM_SOCKET", &[]);
+        println!("Prints the region, requested and plugged virtio-mem sizes for a `VM_SOCKET`.");
+        return Err(());
     }
-    set_default_serial_parameters(&mut cfg.serial_parameters);
+    let request = &VmRequest::MemCommand(MemControlCommand::Size);
+    let response = handle_request(request, args)?;
+    println!("{}", response);
     Ok(())
 }
 
-fn run_vm(args: std::env::Args) -> std::result::Result<(), ()> {
-    let arguments =
-        &[Argument::positional("KERNEL", "bzImage of kernel to run"),
-          Argument::value("android-fstab", "PATH", "Path to Android fstab"),
-          Argument::short_value('i', "initrd", "PATH", "Initial ramdisk to load."),
-          Argument::short_value('p',
-                                "params",
-                                "PARAMS",
-                                "Extra kernel or plugin command line arguments. Can be given more than once."),
-          Argument::short_value('c', "cpus", "N", "Number of VCPUs. (default: 1)"),
-          Argument::value("cpu-affinity", "CPUSET", "Comma-separated list of CPUs or CPU ranges to run VCPUs on (e.g. 0,1-3,5)
-                              or colon-separated list of assignments of guest to host CPU assignments (e.g. 0=0:1=1:2=2) (default: no mask)"),
-          Argument::flag("no-smt", "Don't use SMT in the guest"),
-          Argument::value("rt-cpus", "CPUSET", "Comma-separated list of CPUs or CPU ranges to run VCPUs on. (e.g. 0,1-3,5) (default: none)"),
-          Argument::short_value('m',
-                                "mem",
-                                "N",
-                                "Amount of guest memory in MiB. (default: 256)"),
-          Argument::short_value('r',
-                                "root",
-                                "PATH[,key=value[,key=value[,...]]",
-                                "Path to a root disk image followed by optional comma-separated options.
-                              Like `--disk` but adds appropriate kernel command line option.
-                              See --disk for valid options."),
-          Argument::value("rwroot", "PATH[,key=value[,key=value[,...]]", "Path to a writable root disk image followed by optional comma-separated options.
-                              See --disk for valid options."),
-          Argument::short_value('d', "disk", "PATH[,key=value[,key=value[,...]]", "Path to a disk image followed by optional comma-separated options.
-                              Valid keys:
-                              sparse=BOOL - Indicates whether the disk should support the discard operation (default: true)
-                              block_size=BYTES - Set the reported block size of the disk (default: 512)
-                              id=STRING - Set the block device identifier to an ASCII string, up to 20 characters (default: no ID)"),
-          Argument::value("rwdisk", "PATH[,key=value[,key=value[,...]]", "Path to a writable disk image followed by optional comma-separated options.
-                              See --disk for valid options."),
-          Argument::value("rw-pmem-device", "PATH", "Path to a writable disk image."),
-          Argument::value("pmem-device", "PATH", "Path to a disk image."),
-          Argument::value("pstore", "path=PATH,size=SIZE", "Path to pstore buffer backend file follewed by size."),
-          Argument::value("host_ip",
-                          "IP",
-                          "IP address to assign to host tap interface."),
-          Argument::value("netmask", "NETMASK", "Netmask for VM subnet."),
-          Argument::value("mac", "MAC", "MAC address for VM."),
-          Argument::value("net-vq-pairs", "N", "virtio net virtual queue paris. (default: 1)"),
-          #[cfg(feature = "audio")]
-          Argument::value("ac97",
-                          "[backend=BACKEND,capture=true,capture_effect=EFFECT]",
-                          "Comma separated key=value pairs for setting up Ac97 devices. Can be given more than once .
-                          Possible key values:
-                          backend=(null, cras) - Where to route the audio device. If not provided, backend will default to null.
-                          `null` for /dev/null, and  cras for CRAS server.
-                          capture - Enable audio capture
-                          capture_effects - | separated effects to be enabled for recording. The only supported effect value now is EchoCancellation or aec."),
-          Argument::value("serial",
-                          "type=TYPE,[hardware=HW,num=NUM,path=PATH,input=PATH,console,earlycon,stdin]",
-                          "Comma separated key=value pairs for setting up serial devices. Can be given more than once.
-                          Possible key values:
-                          type=(stdout,syslog,sink,file) - Where to route the serial device
-                          hardware=(serial,virtio-console) - Which type of serial hardware to emulate. Defaults to 8250 UART (serial).
-                          num=(1,2,3,4) - Serial Device Number. If not provided, num will default to 1.
-                          path=PATH - The path to the file to write to when type=file
-                          input=PATH - The path to the file to read from when not stdin
-                          console - Use this serial device as the guest console. Can only be given once. Will default to first serial port if not provided.
-                          earlycon - Use this serial device as the early console. Can only be given once.
-                          stdin - Direct standard input to this serial device. Can only be given once. Will default to first serial port if not provided.
-                          "),
-          Argument::value("syslog-tag", "TAG", "When logging to syslog, use the provided tag."),
-          Argument::value("x-display", "DISPLAY", "X11 display name to use."),
-          Argument::flag("display-window-keyboard", "Capture keyboard input from the display window."),
-          Argument::flag("display-window-mouse", "Capture keyboard input from the display window."),
-          Argument::value("wayland-sock", "PATH[,name=NAME]", "Path to the Wayland socket to use. The unnamed one is used for displaying virtual screens. Named ones are only for IPC."),
-          #[cfg(feature = "wl-dmabuf")]
-          Argument::flag("wayland-dmabuf", "Enable support for DMABufs in Wayland device."),
-          Argument::short_value('s',
-                                "socket",
-                                "PATH",
-                                "Path to put the control socket. If PATH is a directory, a name will be generated."),
-          Argument::flag("disable-sandbox", "Run all devices in one, non-sandboxed process."),
-          Argument::value("cid", "CID", "Context ID for virtual sockets."),
-          Argument::value("shared-dir", "PATH:TAG[:type=TYPE:writeback=BOOL:timeout=SECONDS:uidmap=UIDMAP:gidmap=GIDMAP:cache=CACHE]",
-                          "Colon-separated options for configuring a directory to be shared with the VM.
-The first field is the directory to be shared and the second field is the tag that the VM can use to identify the device.
-The remaining fields are key=value pairs that may appear in any order.  Valid keys are:
-type=(p9, fs) - Indicates whether the directory should be shared via virtio-9p or virtio-fs (default: p9).
-uidmap=UIDMAP - The uid map to use for the device's jail in the format \"inner outer count[,inner outer count]\" (default: 0 <current euid> 1).
-gidmap=GIDMAP - The gid map to use for the device's jail in the format \"inner outer count[,inner outer count]\" (default: 0 <current egid> 1).
-cache=(never, auto, always) - Indicates whether the VM can cache the contents of the shared directory (default: auto).  When set to \"auto\" and the type is \"fs\", the VM will use close-to-open consistency for file contents.
-timeout=SECONDS - How long the VM should consider file attributes and directory entries to be valid (default: 5).  If the VM has exclusive access to the directory, then this should be a large value.  If the directory can be modified by other processes, then this should be 0.
-writeback=BOOL - Indicates whether the VM can use writeback caching (default: false).  This is only safe to do when the VM has exclusive access to the files in a directory.  Additionally, the server should have read permission for all files as the VM may issue read requests even for files that are opened write-only.
-"),
-          Argument::value("seccomp-policy-dir", "PATH", "Path to seccomp .policy files."),
-          Argument::flag("seccomp-log-failures", "Instead of seccomp filter failures being fatal, they will be logged instead."),
-          #[cfg(feature = "plugin")]
-          Argument::value("plugin", "PATH", "Absolute path to plugin process to run under crosvm."),
-          #[cfg(feature = "plugin")]
-          Argument::value("plugin-root", "PATH", "Absolute path to a directory that will become root filesystem for the plugin process."),
-          #[cfg(feature = "plugin")]
-          Argument::value("plugin-mount", "PATH:PATH:BOOL", "Path to be mounted into the plugin's root filesystem.  Can be given more than once."),
-          #[cfg(feature = "plugin")]
-          Argument::value("plugin-mount-file", "PATH", "Path to the file listing paths be mounted into the plugin's root filesystem.  Can be given more than once."),
-          #[cfg(feature = "plugin")]
-          Argument::value("plugin-gid-map", "GID:GID:INT", "Supplemental GIDs that should be mapped in plugin jail.  Can be given more than once."),
-          #[cfg(feature = "plugin")]
-          Argument::value("plugin-gid-map-file", "PATH", "Path to the file listing supplemental GIDs that should be mapped in plugin jail.  Can be given more than once."),
-          Argument::flag("vhost-net", "Use vhost for networking."),
-          Argument::value("tap-fd",
-                          "fd",
-                          "File descriptor for configured tap device. A different virtual network card will be added each time this argument is given."),
-          #[cfg(feature = "gpu")]
-          Argument::flag_or_value("gpu",
-                                  "[width=INT,height=INT]",
-                                  "(EXPERIMENTAL) Comma separated key=value pairs for setting up a virtio-gpu device
-                                  Possible key values:
-                                  backend=(2d|3d|gfxstream) - Which backend to use for virtio-gpu (determining rendering protocol)
-                                  width=INT - The width of the virtual display connected to the virtio-gpu.
-                                  height=INT - The height of the virtual display connected to the virtio-gpu.
-                                  egl[=true|=false] - If the virtio-gpu backend should use a EGL context for rendering.
-                                  glx[=true|=false] - If the virtio-gpu backend should use a GLX context for rendering.
-                                  surfaceless[=true|=false] - If the virtio-gpu backend should use a surfaceless context for rendering.
-                                  angle[=true|=false] - If the guest is using ANGLE (OpenGL on Vulkan) as its native OpenGL driver.
-                                  syncfd[=true|=false] - If the gfxstream backend should support EGL_ANDROID_native_fence_sync
-                                  vulkan[=true|=false] - If the gfxstream backend should support vulkan
-                                  "),
-          #[cfg(feature = "tpm")]
-          Argument::flag("software-tpm", "enable a software emulated trusted platform module device"),
-          Argument::value("evdev", "PATH", "Path to an event device node. The device will be grabbed (unusable from the host) and made available to the guest with the same configuration it shows on the host"),
-          Argument::value("single-touch", "PATH:WIDTH:HEIGHT", "Path to a socket from where to read single touch input events (such as those from a touchscreen) and write status updates to, optionally followed by width and height (defaults to 800x1280)."),
-          Argument::value("trackpad", "PATH:WIDTH:HEIGHT", "Path to a socket from where to read trackpad input events and write status updates to, optionally followed by screen width and height (defaults to 800x1280)."),
-          Argument::value("mouse", "PATH", "Path to a socket from where to read mouse input events and write status updates to."),
-          Argument::value("keyboard", "PATH", "Path to a socket from where to read keyboard input events and write status updates to."),
-          #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-          Argument::flag("split-irqchip", "(EXPERIMENTAL) enable split-irqchip support"),
-          Argument::value("bios", "PATH", "Path to BIOS/firmware ROM"),
-          Argument::value("vfio", "PATH", "Path to sysfs of pass through or mdev device"),
-          #[cfg(feature = "video-decoder")]
-          Argument::flag("video-decoder", "(EXPERIMENTAL) enable virtio-video decoder device"),
-          #[cfg(feature = "video-encoder")]
-          Argument::flag("video-encoder", "(EXPERIMENTAL) enable virtio-video encoder device"),
-          Argument::value("acpi-table", "PATH", "Path to user provided ACPI table"),
-          Argument::flag("protected-vm", "(EXPERIMENTAL) prevent host access to guest memory"),
-          Argument::flag_or_value("battery",
-                                  "[type=TYPE]",
-                                  "Comma separated key=value pairs for setting up battery device
-                                  Possible key values:
-                                  type=goldfish - type of battery emulation, defaults to goldfish
-                                  "),
-          Argument::value("gdb", "PORT", "(EXPERIMENTAL) gdb on the given port"),
-          Argument::short_flag('h', "help", "Print help message.")];
+fn fault_injection_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 2 {
+        print_help("crosvm fault_injection", "SUBCOMMAND VM_SOCKET...", &[]);
+        println!("Set the faults the fault injection test device introduces.");
+        println!("Subcommands:");
+        println!("  set used_delay_ms=N,corrupt_every=N,drop_interrupt_every=N VM_SOCKET");
+        println!("  clear VM_SOCKET");
+        println!("  status VM_SOCKET");
+        println!("Faults left out of `set` are disabled.");
+        return Err(());
+    }
+    let subcommand: &str = &args.next().unwrap();
+
+    let command = match subcommand {
+        "set" => match parse_fault_injection_config(&args.next().unwrap()) {
+            Ok(config) => FaultInjectionCommand::Configure(config),
+            Err(e) => {
+                error!("Failed to parse faults: {}", e);
+                return Err(());
+            }
+        },
+        "clear" => FaultInjectionCommand::Configure(FaultInjectionConfig::default()),
+        "status" => {
+            let request = &VmRequest::FaultInjectionCommand(FaultInjectionCommand::Status);
+            let response = handle_request(request, args)?;
+            println!("{}", response);
+            return Ok(());
+        }
+        _ => {
+            error!("Unknown fault_injection subcommand '{}'", subcommand);
+            return Err(());
+        }
+    };
 
-    let mut cfg = Config::default();
-    let match_res = set_arguments(args, &arguments[..], |name, value| {
-        set_argument(&mut cfg, name, value)
-    })
-    .and_then(|_| validate_arguments(&mut cfg));
+    vms_request(&VmRequest::FaultInjectionCommand(command), args)
+}
 
-    match match_res {
-        #[cfg(feature = "plugin")]
-        Ok(()) if executable_is_plugin(&cfg.executable_path) => {
-            match crosvm::plugin::run_config(cfg) {
-                Ok(_) => {
-                    info!("crosvm and plugin have exited normally");
-                    Ok(())
-                }
-                Err(e) => {
-                    error!("{}", e);
-                    Err(())
-                }
+fn vcpu_stats(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() != 1 && args.len() != 2 {
+        print_help("crosvm vcpu_stats", "[CPU_ID] VM_SOCKET", &[]);
+        println!("Prints the VCPU exit counts of `CPU_ID`, or of all VCPUs, for a `VM_SOCKET`.");
+        return Err(());
+    }
+    let cpu_id = if args.len() == 2 {
+        match args.next().unwrap().parse::<usize>() {
+            Ok(n) => Some(n),
+            Err(_) => {
+                error!("Failed to parse CPU_ID");
+                return Err(());
             }
         }
-        Ok(()) => match platform::run_config(cfg) {
-            Ok(_) => {
-                info!("crosvm has exited normally");
-                Ok(())
-            }
-            Err(e) => {
-                error!("{}", e);
-                Err(())
-            }
+    } else {
+        None
+    };
+    let request = &VmRequest::VcpuExitStats { cpu_id };
+    let response = handle_request(request, args)?;
+    println!("{}", response);
+    Ok(())
+}
+
+fn virtio_stats(args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() != 1 {
+        print_help("crosvm virtio_stats", "VM_SOCKET", &[]);
+        println!("Prints the queue counters of each virtio PCI device of a `VM_SOCKET`.");
+        return Err(());
+    }
+    let response = handle_request(&VmRequest::VirtioStats, args)?;
+    println!("{}", response);
+    Ok(())
+}
+
+fn rtc_wake_time(args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() != 1 {
+        print_help("crosvm rtc_wake_time", "VM_SOCKET", &[]);
+        println!("Prints the RTC alarm wake time of a `VM_SOCKET` in seconds since the epoch.");
+        return Err(());
+    }
+    let response = handle_request(&VmRequest::RtcWakeTime, args)?;
+    println!("{}", response);
+    Ok(())
+}
+
+fn log_filter(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 2 {
+        print_help("crosvm log_filter", "FILTER VM_SOCKET...", &[]);
+        println!("Sets which messages crosvm instances and their device processes log.");
+        println!("`FILTER` is a comma separated list of `PRIORITY` and `MODULE=PRIORITY` entries,");
+        println!("for example `warning,devices::virtio::net=debug`.");
+        return Err(());
+    }
+    let filter = args.next().unwrap();
+    vms_request(
+        &VmRequest::SetLogFilter {
+            filter: filter.into_bytes(),
         },
-        Err(argument::Error::PrintHelp) => {
-            print_help("crosvm run", "KERNEL", &arguments[..]);
+        args,
+    )
+}
+
+fn dump_memory(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() != 2 {
+        print_help("crosvm dump_memory", "PATH VM_SOCKET", &[]);
+        println!(
+            "Writes the guest memory of a `VM_SOCKET` to the file at `PATH`, each byte at the"
+        );
+        println!("offset of its guest physical address.");
+        return Err(());
+    }
+    let file_path = args.next().unwrap();
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&file_path)
+        .map_err(|e| {
+            error!("Failed opening dump file at '{}': {}", file_path, e);
+        })?;
+    operation_request(&VmRequest::DumpMemory { file }, &args.next().unwrap())
+}
+
+fn operation_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 3 {
+        print_help("crosvm operation", "SUBCOMMAND ID VM_SOCKET", &[]);
+        println!("Manage requests that continue in the background, such as `disk resize`.");
+        println!("Subcommands:");
+        println!("  status ID VM_SOCKET");
+        println!("  wait ID VM_SOCKET");
+        println!("  cancel ID VM_SOCKET");
+        return Err(());
+    }
+    let subcommand = args.next().unwrap();
+    let id = match args.next().unwrap().parse::<u64>() {
+        Ok(n) => n,
+        Err(_) => {
+            error!("Failed to parse operation id");
+            return Err(());
+        }
+    };
+
+    match subcommand.as_ref() {
+        "status" => {
+            let response = handle_request(&VmRequest::OperationStatus { id }, args)?;
+            println!("{}", response);
             Ok(())
         }
-        Err(e) => {
-            error!("{}", e);
+        "wait" => {
+            let socket_path = args.next().unwrap();
+            let socket: VmControlRequestSocket = match UnixSeqpacket::connect(&socket_path) {
+                Ok(s) => MsgSocket::new(s),
+                Err(e) => {
+                    error!("failed to connect to socket at '{}': {}", socket_path, e);
+                    return Err(());
+                }
+            };
+            follow_operation(&socket, &socket_path, id)
+        }
+        "cancel" => vms_request(&VmRequest::CancelOperation { id }, args),
+        _ => {
+            error!("Unknown operation subcommand '{}'", subcommand);
             Err(())
         }
     }
 }
 
-fn handle_request(
-    request: &VmRequest,
-    args: std::env::Args,
-) -> std::result::Result<VmResponse, ()> {
-    let mut return_result = Err(());
-    for socket_path in args {
-        match UnixSeqpacket::connect(&socket_path) {
-            Ok(s) => {
-                let socket: VmControlRequestSocket = MsgSocket::new(s);
-                if let Err(e) = socket.send(request) {
-                    error!(
-                        "failed to send request to socket at '{}': {}",
-                        socket_path, e
-                    );
-                    return_result = Err(());
-                    continue;
-                }
-                match socket.recv() {
-                    Ok(response) => return_result = Ok(response),
-                    Err(e) => {
-                        error!(
-                            "failed to send request to socket at2 '{}': {}",
-                            socket_path, e
-                        );
-                        return_result = Err(());
-                        continue;
+fn create_qcow2(args: std::env::Args) -> std::result::Result<(), ()> {
+    let arguments = [
+        Argument::positional("PATH", "where to create the qcow2 image"),
+        Argument::positional("[SIZE]", "the expanded size of the image"),
+        Argument::value(
+            "backing_file",
+            "path/to/file",
+            " the file to back the image",
+        ),
+    ];
+    let mut positional_index = 0;
+    let mut file_path = String::from("");
+    let mut size: Option<u64> = None;
+    let mut backing_file: Option<String> = None;
+    set_arguments(args, &arguments[..], |name, value| {
+        match (name, positional_index) {
+            ("", 0) => {
+                // NAME
+                positional_index += 1;
+                file_path = value.unwrap().to_owned();
+            }
+            ("", 1) => {
+                // [SIZE]
+                positional_index += 1;
+                size = Some(value.unwrap().parse::<u64>().map_err(|_| {
+                    argument::Error::InvalidValue {
+                        value: value.unwrap().to_owned(),
+                        expected: String::from("SIZE should be a nonnegative integer"),
                     }
-                }
+                })?);
             }
-            Err(e) => {
-                error!("failed to connect to socket at '{}': {}", socket_path, e);
-                return_result = Err(());
+            ("", _) => {
+                return Err(argument::Error::TooManyArguments(
+                    "Expected at most 2 positional arguments".to_owned(),
+                ));
             }
-        }
+            ("backing_file", _) => {
+                backing_file = value.map(|x| x.to_owned());
+            }
+            _ => unreachable!(),
+        };
+        Ok(())
+    })
+    .map_err(|e| {
+        error!("Unable to parse command line arguments: {}", e);
+    })?;
+    if file_path.is_empty() || !(size.is_some() ^ backing_file.is_some()) {
+        print_help("crosvm create_qcow2", "PATH [SIZE]", &arguments);
+        println!(
+            "Create a new QCOW2 image at `PATH` of either the specified `SIZE` in bytes or
+with a '--backing_file'."
+        );
+        return Err(());
     }
 
-    return_result
-}
+    let file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(true)
+        .open(&file_path)
+        .map_err(|e| {
+            error!("Failed opening qcow file at '{}': {}", file_path, e);
+        })?;
 
-fn vms_request(request: &VmRequest, args: std::env::Args) -> std::result::Result<(), ()> {
-    let response = handle_request(request, args)?;
-    info!("request response was {}", response);
+    match (size, backing_file) {
+        (Some(size), None) => QcowFile::new(file, size).map_err(|e| {
+            error!("Failed to create qcow file at '{}': {}", file_path, e);
+        })?,
+        (None, Some(backing_file)) => {
+            QcowFile::new_from_backing(file, &backing_file).map_err(|e| {
+                error!("Failed to create qcow file at '{}': {}", file_path, e);
+            })?
+        }
+        _ => unreachable!(),
+    };
     Ok(())
 }
 
-fn stop_vms(args: std::env::Args) -> std::result::Result<(), ()> {
-    if args.len() == 0 {
-        print_help("crosvm stop", "VM_SOCKET...", &[]);
-        println!("Stops the crosvm instance listening on each `VM_SOCKET` given.");
+fn convert_disk(args: std::env::Args) -> std::result::Result<(), ()> {
+    let arguments = [
+        Argument::positional("SRC", "the raw or qcow2 image to read"),
+        Argument::positional("DST", "where to write the converted image"),
+        Argument::value(
+            "format",
+            "raw|qcow2",
+            "format of DST (default: the other one of raw and qcow2)",
+        ),
+    ];
+    let mut src_path = String::new();
+    let mut dst_path = String::new();
+    let mut dst_type: Option<ImageType> = None;
+    set_arguments(args, &arguments[..], |name, value| {
+        match name {
+            "" => {
+                if src_path.is_empty() {
+                    src_path = value.unwrap().to_owned();
+                } else if dst_path.is_empty() {
+                    dst_path = value.unwrap().to_owned();
+                } else {
+                    return Err(argument::Error::TooManyArguments(
+                        "Expected exactly 2 positional arguments".to_owned(),
+                    ));
+                }
+            }
+            "format" => {
+                dst_type = Some(match value.unwrap() {
+                    "raw" => ImageType::Raw,
+                    "qcow2" => ImageType::Qcow2,
+                    v => {
+                        return Err(argument::Error::InvalidValue {
+                            value: v.to_owned(),
+                            expected: String::from("format must be `raw` or `qcow2`"),
+                        })
+                    }
+                });
+            }
+            _ => unreachable!(),
+        };
+        Ok(())
+    })
+    .map_err(|e| {
+        error!("Unable to parse command line arguments: {}", e);
+    })?;
+    if src_path.is_empty() || dst_path.is_empty() {
+        print_help("crosvm convert", "SRC DST", &arguments);
+        println!(
+            "Convert the disk image at `SRC` between raw and qcow2, writing it to `DST`.
+Unallocated and zeroed ranges of `SRC` are left sparse in `DST`."
+        );
         return Err(());
     }
-    vms_request(&VmRequest::Exit, args)
-}
 
-fn suspend_vms(args: std::env::Args) -> std::result::Result<(), ()> {
-    if args.len() == 0 {
-        print_help("crosvm suspend", "VM_SOCKET...", &[]);
-        println!("Suspends the crosvm instance listening on each `VM_SOCKET` given.");
-        return Err(());
-    }
-    vms_request(&VmRequest::Suspend, args)
+    let src = File::open(&src_path).map_err(|e| {
+        error!("Failed opening disk image at '{}': {}", src_path, e);
+    })?;
+    let dst_type = match dst_type {
+        Some(t) => t,
+        None => match disk::detect_image_type(&src) {
+            Ok(ImageType::Raw) => ImageType::Qcow2,
+            Ok(ImageType::Qcow2) => ImageType::Raw,
+            Ok(t) => {
+                error!("Cannot convert {:?} image at '{}'", t, src_path);
+                return Err(());
+            }
+            Err(e) => {
+                error!("Failed to inspect disk image at '{}': {}", src_path, e);
+                return Err(());
+            }
+        },
+    };
+    let dst = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(true)
+        .open(&dst_path)
+        .map_err(|e| {
+            error!("Failed opening disk image at '{}': {}", dst_path, e);
+        })?;
+
+    disk::convert(src, dst, dst_type).map_err(|e| {
+        error!("Failed to convert '{}' to '{}': {}", src_path, dst_path, e);
+    })
 }
 
-fn resume_vms(args: std::env::Args) -> std::result::Result<(), ()> {
-    if args.len() == 0 {
-        print_help("crosvm resume", "VM_SOCKET...", &[]);
-        println!("Resumes the crosvm instance listening on each `VM_SOCKET` given.");
-        return Err(());
+#[cfg(feature = "composite-disk")]
+fn create_composite(args: std::env::Args) -> std::result::Result<(), ()> {
+    let arguments = [
+        Argument::positional("PATH", "where to create the composite disk specification"),
+        Argument::positional(
+            "[LABEL:PARTITION]...",
+            "read-only partitions, named LABEL, from the disk images PARTITION",
+        ),
+        Argument::value(
+            "rw-partition",
+            "LABEL:PARTITION",
+            "A writable partition. Can be given more than once.",
+        ),
+    ];
+    fn parse_partition(value: &str, writable: bool) -> argument::Result<PartitionInfo> {
+        let mut components = value.splitn(2, ':');
+        match (components.next(), components.next()) {
+            (Some(label), Some(path)) if !label.is_empty() && !path.is_empty() => {
+                Ok(PartitionInfo {
+                    label: label.to_owned(),
+                    path: PathBuf::from(path),
+                    writable,
+                })
+            }
+            _ => Err(argument::Error::InvalidValue {
+                value: value.to_owned(),
+                expected: String::from("partitions must be given as LABEL:PARTITION"),
+            }),
+        }
     }
-    vms_request(&VmRequest::Resume, args)
-}
 
-fn balloon_vms(mut args: std::env::Args) -> std::result::Result<(), ()> {
-    if args.len() < 2 {
-        print_help("crosvm balloon", "SIZE VM_SOCKET...", &[]);
-        println!("Set the ballon size of the crosvm instance to `SIZE` bytes.");
-        return Err(());
-    }
-    let num_bytes = match args.next().unwrap().parse::<u64>() {
-        Ok(n) => n,
-        Err(_) => {
-            error!("Failed to parse number of bytes");
+    let mut composite_path = None;
+    let mut partitions = Vec::new();
+    set_arguments(args, &arguments[..], |name, value| {
+        match name {
+            "" if composite_path.is_none() => {
+                composite_path = Some(PathBuf::from(value.unwrap()));
+            }
+            "" => partitions.push(parse_partition(value.unwrap(), false)?),
+            "rw-partition" => partitions.push(parse_partition(value.unwrap(), true)?),
+            _ => unreachable!(),
+        };
+        Ok(())
+    })
+    .map_err(|e| {
+        error!("Unable to parse command line arguments: {}", e);
+    })?;
+    let composite_path = match composite_path {
+        Some(path) if !partitions.is_empty() => path,
+        _ => {
+            print_help(
+                "crosvm create_composite",
+                "PATH [LABEL:PARTITION]...",
+                &arguments,
+            );
+            println!(
+                "Create a composite disk specification at `PATH` for a disk with a GUID partition
+table that holds the partitions in the order given. The partition table is kept in `PATH.header`
+and `PATH.footer`. Attach the disk with `crosvm run --disk PATH` or `--rwdisk PATH`."
+            );
             return Err(());
         }
     };
 
-    let command = BalloonControlCommand::Adjust { num_bytes };
-    vms_request(&VmRequest::BalloonCommand(command), args)
-}
-
-fn balloon_stats(args: std::env::Args) -> std::result::Result<(), ()> {
-    if args.len() != 1 {
-        print_help("crosvm balloon_stats", "VM_SOCKET", &[]);
-        println!("Prints virtio balloon statistics for a `VM_SOCKET`.");
-        return Err(());
+    // The specification refers to the components by path, which must not depend on where crosvm
+    // runs from.
+    for partition in &mut partitions {
+        partition.path = partition.path.canonicalize().map_err(|e| {
+            error!(
+                "Failed to find partition '{}': {}",
+                partition.path.display(),
+                e
+            );
+        })?;
     }
-    let command = BalloonControlCommand::Stats {};
-    let request = &VmRequest::BalloonCommand(command);
-    let response = handle_request(request, args)?;
-    println!("{}", response);
-    Ok(())
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&composite_path)
+        .map_err(|e| {
+            error!(
+                "Failed opening composite disk file at '{}': {}",
+                composite_path.display(),
+                e
+            );
+        })?;
+    let composite_path = composite_path.canonicalize().map_err(|e| {
+        error!(
+            "Failed to find composite disk file at '{}': {}",
+            composite_path.display(),
+            e
+        );
+    })?;
+    let header_path = PathBuf::from(format!("{}.header", composite_path.display()));
+    let footer_path = PathBuf::from(format!("{}.footer", composite_path.display()));
+    create_composite_disk(&partitions, &header_path, &footer_path, &mut file).map_err(|e| {
+        error!(
+            "Failed to create composite disk at '{}': {}",
+            composite_path.display(),
+            e
+        );
+    })
 }
 
-fn create_qcow2(args: std::env::Args) -> std::result::Result<(), ()> {
+fn ivshmem_broker_cmd(args: std::env::Args) -> std::result::Result<(), ()> {
     let arguments = [
-        Argument::positional("PATH", "where to create the qcow2 image"),
-        Argument::positional("[SIZE]", "the expanded size of the image"),
+        Argument::positional("PATH", "where to create the broker socket"),
         Argument::value(
-            "backing_file",
-            "path/to/file",
-            " the file to back the image",
+            "size",
+            "BYTES",
+            "size of the shared memory, a power of two (default: 4194304)",
+        ),
+        Argument::value(
+            "peers",
+            "N",
+            "maximum number of VMs sharing the memory at once (default: 2)",
         ),
     ];
-    let mut positional_index = 0;
-    let mut file_path = String::from("");
-    let mut size: Option<u64> = None;
-    let mut backing_file: Option<String> = None;
+    let mut path = None;
+    let mut size = 4 << 20;
+    let mut num_peers = 2;
     set_arguments(args, &arguments[..], |name, value| {
-        match (name, positional_index) {
-            ("", 0) => {
-                // NAME
-                positional_index += 1;
-                file_path = value.unwrap().to_owned();
+        match name {
+            "" => {
+                if path.is_some() {
+                    return Err(argument::Error::TooManyArguments(
+                        "expected exactly one socket path".to_owned(),
+                    ));
+                }
+                path = value.map(PathBuf::from);
             }
-            ("", 1) => {
-                // [SIZE]
-                positional_index += 1;
-                size = Some(value.unwrap().parse::<u64>().map_err(|_| {
-                    argument::Error::InvalidValue {
+            "size" => {
+                size = value
+                    .unwrap()
+                    .parse()
+                    .map_err(|_| argument::Error::InvalidValue {
                         value: value.unwrap().to_owned(),
-                        expected: String::from("SIZE should be a nonnegative integer"),
-                    }
-                })?);
-            }
-            ("", _) => {
-                return Err(argument::Error::TooManyArguments(
-                    "Expected at most 2 positional arguments".to_owned(),
-                ));
+                        expected: String::from("`size` must be an integer"),
+                    })?;
             }
-            ("backing_file", _) => {
-                backing_file = value.map(|x| x.to_owned());
+            "peers" => {
+                num_peers = match value.unwrap().parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => {
+                        return Err(argument::Error::InvalidValue {
+                            value: value.unwrap().to_owned(),
+                            expected: String::from("`peers` must be a positive integer"),
+                        })
+                    }
+                };
             }
             _ => unreachable!(),
         };
@@ -1865,37 +3802,136 @@ fn create_qcow2(args: std::env::Args) -> std::result::Result<(), ()> {
     .map_err(|e| {
         error!("Unable to parse command line arguments: {}", e);
     })?;
-    if file_path.is_empty() || !(size.is_some() ^ backing_file.is_some()) {
-        print_help("crosvm create_qcow2", "PATH [SIZE]", &arguments);
-        println!(
-            "Create a new QCOW2 image at `PATH` of either the specified `SIZE` in bytes or
-with a '--backing_file'."
+    let path = match path {
+        Some(path) => path,
+        None => {
+            print_help("crosvm ivshmem_broker", "PATH", &arguments);
+            println!("Share memory between the VMs started with `--ivshmem PATH`.");
+            return Err(());
+        }
+    };
+
+    let mut broker = Broker::new(&path, size, num_peers).map_err(|e| {
+        error!(
+            "Failed to start ivshmem broker at {}: {}",
+            path.display(),
+            e
         );
+    })?;
+    broker.run().map_err(|e| {
+        error!("ivshmem broker failed: {}", e);
+    })
+}
+
+fn check_qcow2(args: std::env::Args) -> std::result::Result<(), ()> {
+    let arguments = [
+        Argument::positional("PATH", "the qcow2 image to check"),
+        Argument::flag("repair", "rebuild the refcounts if they are inconsistent"),
+    ];
+    let mut file_path = String::from("");
+    let mut repair = false;
+    set_arguments(args, &arguments[..], |name, value| {
+        match name {
+            "" => {
+                if !file_path.is_empty() {
+                    return Err(argument::Error::TooManyArguments(
+                        "Expected 1 positional argument".to_owned(),
+                    ));
+                }
+                file_path = value.unwrap().to_owned();
+            }
+            "repair" => repair = true,
+            _ => unreachable!(),
+        };
+        Ok(())
+    })
+    .map_err(|e| {
+        error!("Unable to parse command line arguments: {}", e);
+    })?;
+    if file_path.is_empty() {
+        print_help("crosvm disk check", "PATH", &arguments);
+        println!("Check the metadata of the QCOW2 image at `PATH` for consistency.");
         return Err(());
     }
 
     let file = OpenOptions::new()
-        .create(true)
         .read(true)
-        .write(true)
-        .truncate(true)
+        .write(repair)
         .open(&file_path)
         .map_err(|e| {
             error!("Failed opening qcow file at '{}': {}", file_path, e);
         })?;
+    let result = QcowFile::check(file, repair).map_err(|e| {
+        error!("Failed to check qcow file at '{}': {}", file_path, e);
+    })?;
 
-    match (size, backing_file) {
-        (Some(size), None) => QcowFile::new(file, size).map_err(|e| {
-            error!("Failed to create qcow file at '{}': {}", file_path, e);
-        })?,
-        (None, Some(backing_file)) => {
-            QcowFile::new_from_backing(file, &backing_file).map_err(|e| {
-                error!("Failed to create qcow file at '{}': {}", file_path, e);
-            })?
+    println!("{} corrupt table entries", result.corruptions);
+    println!("{} refcount errors", result.refcount_errors);
+    println!("{} leaked clusters", result.leaked_clusters);
+    if result.repaired {
+        println!("refcounts repaired");
+    }
+    if result.corruptions != 0 || (result.refcount_errors != 0 && !result.repaired) {
+        return Err(());
+    }
+    Ok(())
+}
+
+fn snapshot_qcow2(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    let usage = || {
+        print_help("crosvm disk snapshot", "create|apply|delete NAME PATH", &[]);
+        println!("       crosvm disk snapshot list PATH");
+        println!(
+            "Manage the internal snapshots of the QCOW2 image at `PATH`, which must not be in use."
+        );
+    };
+    let subcommand = args.next().unwrap_or_default();
+    let (name, file_path) = match (subcommand.as_str(), args.next(), args.next(), args.next()) {
+        ("list", Some(file_path), None, None) => (None, file_path),
+        ("create", Some(name), Some(file_path), None)
+        | ("apply", Some(name), Some(file_path), None)
+        | ("delete", Some(name), Some(file_path), None) => (Some(name), file_path),
+        _ => {
+            usage();
+            return Err(());
+        }
+    };
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(name.is_some())
+        .open(&file_path)
+        .map_err(|e| {
+            error!("Failed opening qcow file at '{}': {}", file_path, e);
+        })?;
+    let mut qcow = QcowFile::from(file).map_err(|e| {
+        error!("Failed to open qcow file at '{}': {}", file_path, e);
+    })?;
+    let name = match name {
+        Some(name) => name,
+        None => {
+            println!("{:<8} {:<32} {:>16}", "ID", "NAME", "DISK SIZE");
+            for snapshot in qcow.snapshots() {
+                println!(
+                    "{:<8} {:<32} {:>16}",
+                    snapshot.id, snapshot.name, snapshot.disk_size
+                );
+            }
+            return Ok(());
         }
+    };
+    let result = match subcommand.as_str() {
+        "create" => qcow.create_snapshot(&name),
+        "apply" => qcow.apply_snapshot(&name),
+        "delete" => qcow.delete_snapshot(&name),
         _ => unreachable!(),
     };
-    Ok(())
+    result.map_err(|e| {
+        error!(
+            "Failed to {} snapshot '{}' of '{}': {}",
+            subcommand, name, file_path, e
+        );
+    })
 }
 
 fn disk_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
@@ -1904,9 +3940,21 @@ fn disk_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
         println!("Manage attached virtual disk devices.");
         println!("Subcommands:");
         println!("  resize DISK_INDEX NEW_SIZE VM_SOCKET");
+        println!("  irq-coalescing DISK_INDEX min_interval=MICROSECONDS|max_rate=N VM_SOCKET");
+        println!("  throttle DISK_INDEX iops=N,iops_burst=N,bps=BYTES,bps_burst=BYTES VM_SOCKET");
+        println!("  stats DISK_INDEX VM_SOCKET");
+        println!("  check [--repair] PATH");
+        println!("  snapshot create|apply|delete NAME PATH");
+        println!("  snapshot list PATH");
         return Err(());
     }
     let subcommand: &str = &args.next().unwrap();
+    if subcommand == "check" {
+        return check_qcow2(args);
+    }
+    if subcommand == "snapshot" {
+        return snapshot_qcow2(args);
+    }
 
     let request = match subcommand {
         "resize" => {
@@ -1926,18 +3974,199 @@ fn disk_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
                 }
             };
 
-            VmRequest::DiskCommand {
-                disk_index,
-                command: DiskControlCommand::Resize { new_size },
-            }
+            // Resizing continues in the background, so wait for it to finish.
+            let request = VmRequest::DiskCommand {
+                disk_index,
+                command: DiskControlCommand::Resize { new_size },
+            };
+            return match args.next() {
+                Some(socket_path) => operation_request(&request, &socket_path),
+                None => {
+                    error!("Missing VM_SOCKET");
+                    Err(())
+                }
+            };
+        }
+        "irq-coalescing" => {
+            let disk_index = match args.next().unwrap().parse::<usize>() {
+                Ok(n) => n,
+                Err(_) => {
+                    error!("Failed to parse disk index");
+                    return Err(());
+                }
+            };
+
+            let interval = match parse_interrupt_coalescing_option(&args.next().unwrap()) {
+                Ok(interval) => interval,
+                Err(e) => {
+                    error!("Failed to parse interrupt coalescing setting: {}", e);
+                    return Err(());
+                }
+            };
+
+            VmRequest::DiskCommand {
+                disk_index,
+                command: DiskControlCommand::SetInterruptCoalescing {
+                    min_interval_us: interval.as_micros() as u64,
+                },
+            }
+        }
+        "throttle" => {
+            let disk_index = match args.next().unwrap().parse::<usize>() {
+                Ok(n) => n,
+                Err(_) => {
+                    error!("Failed to parse disk index");
+                    return Err(());
+                }
+            };
+
+            let limits = match parse_throttle_limits(&args.next().unwrap_or_default()) {
+                Ok(limits) => limits,
+                Err(e) => {
+                    error!("Failed to parse throttling limits: {}", e);
+                    return Err(());
+                }
+            };
+
+            VmRequest::DiskCommand {
+                disk_index,
+                command: DiskControlCommand::SetThrottle {
+                    iops: limits.iops,
+                    iops_burst: limits.iops_burst,
+                    bytes_per_sec: limits.bytes_per_sec,
+                    bytes_burst: limits.bytes_burst,
+                },
+            }
+        }
+        "stats" => {
+            let disk_index = match args.next().unwrap().parse::<usize>() {
+                Ok(n) => n,
+                Err(_) => {
+                    error!("Failed to parse disk index");
+                    return Err(());
+                }
+            };
+
+            let request = VmRequest::DiskCommand {
+                disk_index,
+                command: DiskControlCommand::GetStats,
+            };
+            let response = handle_request(&request, args)?;
+            println!("{}", response);
+            return Ok(());
+        }
+        _ => {
+            error!("Unknown disk subcommand '{}'", subcommand);
+            return Err(());
+        }
+    };
+
+    vms_request(&request, args)
+}
+
+fn net_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 3 {
+        print_help("crosvm net", "SUBCOMMAND VM_SOCKET...", &[]);
+        println!("Manage attached virtual network devices.");
+        println!("Subcommands:");
+        println!("  irq-coalescing NET_INDEX min_interval=MICROSECONDS|max_rate=N VM_SOCKET");
+        println!("  capture NET_INDEX PATH VM_SOCKET");
+        println!("  capture-stop NET_INDEX VM_SOCKET");
+        return Err(());
+    }
+    let subcommand: &str = &args.next().unwrap();
+    let net_index = match args.next().unwrap().parse::<usize>() {
+        Ok(n) => n,
+        Err(_) => {
+            error!("Failed to parse net device index");
+            return Err(());
+        }
+    };
+
+    let command = match subcommand {
+        "irq-coalescing" | "capture" if args.len() < 2 => {
+            error!("Missing arguments for net subcommand '{}'", subcommand);
+            return Err(());
+        }
+        "irq-coalescing" => {
+            let interval = match parse_interrupt_coalescing_option(&args.next().unwrap()) {
+                Ok(interval) => interval,
+                Err(e) => {
+                    error!("Failed to parse interrupt coalescing setting: {}", e);
+                    return Err(());
+                }
+            };
+
+            NetControlCommand::SetInterruptCoalescing {
+                min_interval_us: interval.as_micros() as u64,
+            }
+        }
+        "capture" => {
+            let file_path = args.next().unwrap();
+            // The device process can't open files itself, so the file is opened here and passed
+            // to it.
+            let file = match OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&file_path)
+            {
+                Ok(f) => f,
+                Err(e) => {
+                    error!("Failed opening capture file at '{}': {}", file_path, e);
+                    return Err(());
+                }
+            };
+            NetControlCommand::StartCapture { file }
+        }
+        "capture-stop" => NetControlCommand::StopCapture,
+        _ => {
+            error!("Unknown net subcommand '{}'", subcommand);
+            return Err(());
+        }
+    };
+
+    vms_request(&VmRequest::NetCommand { net_index, command }, args)
+}
+
+fn pcie_slot_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    if args.len() < 3 {
+        print_help(
+            "crosvm pcie_slot",
+            "SUBCOMMAND PORT_INDEX VM_SOCKET...",
+            &[],
+        );
+        println!("Hot-plug the slots of the PCIe root ports.");
+        println!("Subcommands:");
+        println!("  insert PORT_INDEX VM_SOCKET");
+        println!("  remove PORT_INDEX VM_SOCKET");
+        return Err(());
+    }
+    let subcommand: &str = &args.next().unwrap();
+    let port_index = match args.next().unwrap().parse::<usize>() {
+        Ok(n) => n,
+        Err(_) => {
+            error!("Failed to parse root port index");
+            return Err(());
         }
+    };
+
+    let command = match subcommand {
+        "insert" => PcieSlotControlCommand::SetPresence { present: true },
+        "remove" => PcieSlotControlCommand::SetPresence { present: false },
         _ => {
-            error!("Unknown disk subcommand '{}'", subcommand);
+            error!("Unknown pcie_slot subcommand '{}'", subcommand);
             return Err(());
         }
     };
 
-    vms_request(&request, args)
+    vms_request(
+        &VmRequest::PcieSlotCommand {
+            port_index,
+            command,
+        },
+        args,
+    )
 }
 
 enum ModifyUsbError {
@@ -2067,6 +4296,39 @@ fn usb_attach(mut args: std::env::Args) -> ModifyUsbResult<UsbControlResult> {
     }
 }
 
+fn usb_attach_storage(mut args: std::env::Args) -> ModifyUsbResult<UsbControlResult> {
+    let mut val = args.next().ok_or(ModifyUsbError::ArgMissing("DISK_PATH"))?;
+    let read_only = val == "--read-only";
+    if read_only {
+        val = args.next().ok_or(ModifyUsbError::ArgMissing("DISK_PATH"))?;
+    }
+    let disk_path = PathBuf::from(val);
+    let disk_file = if disk_path.parent() == Some(Path::new("/proc/self/fd")) {
+        // Special case '/proc/self/fd/*' paths. The FD is already open, just use it.
+        // Safe because we will validate |raw_fd|.
+        unsafe { File::from_raw_descriptor(raw_descriptor_from_path(&disk_path)?) }
+    } else {
+        OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(&disk_path)
+            .map_err(|_| ModifyUsbError::UsbControl(UsbControlResult::FailedToOpenDevice))?
+    };
+
+    let request = VmRequest::UsbCommand(UsbControlCommand::AttachMassStorage {
+        read_only,
+        // Safe because we are transferring ownership to the rawdescriptor
+        descriptor: Some(MaybeOwnedDescriptor::Owned(unsafe {
+            SafeDescriptor::from_raw_descriptor(disk_file.into_raw_descriptor())
+        })),
+    });
+    let response = handle_request(&request, args).map_err(|_| ModifyUsbError::SocketFailed)?;
+    match response {
+        VmResponse::UsbResponse(usb_resp) => Ok(usb_resp),
+        r => Err(ModifyUsbError::UnexpectedResponse(r)),
+    }
+}
+
 fn usb_detach(mut args: std::env::Args) -> ModifyUsbResult<UsbControlResult> {
     let port: u8 = args
         .next()
@@ -2098,7 +4360,7 @@ fn usb_list(args: std::env::Args) -> ModifyUsbResult<UsbControlResult> {
 fn modify_usb(mut args: std::env::Args) -> std::result::Result<(), ()> {
     if args.len() < 2 {
         print_help("crosvm usb",
-                   "[attach BUS_ID:ADDR:VENDOR_ID:PRODUCT_ID [USB_DEVICE_PATH|-] | detach PORT | list] VM_SOCKET...", &[]);
+                   "[attach BUS_ID:ADDR:VENDOR_ID:PRODUCT_ID [USB_DEVICE_PATH|-] | attach_storage [--read-only] DISK_PATH | detach PORT | list] VM_SOCKET...", &[]);
         return Err(());
     }
 
@@ -2106,6 +4368,7 @@ fn modify_usb(mut args: std::env::Args) -> std::result::Result<(), ()> {
     let command = args.next().unwrap();
     let result = match command.as_ref() {
         "attach" => usb_attach(args),
+        "attach_storage" => usb_attach_storage(args),
         "detach" => usb_detach(args),
         "list" => usb_list(args),
         other => Err(ModifyUsbError::UnknownCommand(other.to_owned())),
@@ -2122,15 +4385,238 @@ fn modify_usb(mut args: std::env::Args) -> std::result::Result<(), ()> {
     }
 }
 
+// Options shared by the `crosvm bench` devices, parsed by `parse_bench_argument`.
+fn bench_common_arguments() -> Vec<Argument> {
+    vec![
+        Argument::value(
+            "size",
+            "BYTES",
+            "Size of the data carried by each request (default: 4096)",
+        ),
+        Argument::value(
+            "depth",
+            "N",
+            "Number of requests kept in flight (default: 16)",
+        ),
+        Argument::value("seconds", "N", "How long to run for (default: 10)"),
+        Argument::value(
+            "irq_min_interval",
+            "MICROSECONDS",
+            "Interrupt the driver at most once per interval (default: after every request)",
+        ),
+        Argument::value(
+            "irq_max_rate",
+            "PER_SECOND",
+            "Interrupt the driver at most this many times per second (default: unlimited)",
+        ),
+    ]
+}
+
+// Parses an option shared by the `crosvm bench` devices. Returns false if `name` isn't one of
+// them.
+fn parse_bench_argument(
+    params: &mut BenchParameters,
+    interrupt_interval: &mut Option<Duration>,
+    name: &str,
+    value: Option<&str>,
+) -> argument::Result<bool> {
+    let parse_u64 = |value: Option<&str>| -> argument::Result<u64> {
+        value
+            .unwrap()
+            .parse()
+            .map_err(|_| argument::Error::InvalidValue {
+                value: value.unwrap().to_owned(),
+                expected: format!("`{}` must be an integer", name),
+            })
+    };
+    match name {
+        "size" => params.request_size = parse_u64(value)?,
+        "depth" => params.queue_depth = parse_u64(value)? as usize,
+        "seconds" => params.duration = Duration::from_secs(parse_u64(value)?),
+        "irq_min_interval" | "irq_max_rate" => {
+            *interrupt_interval = Some(parse_interrupt_coalescing(
+                &name["irq_".len()..],
+                value.unwrap(),
+            )?);
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn bench_cmd(mut args: std::env::Args) -> std::result::Result<(), ()> {
+    let device = args.next().unwrap_or_default();
+    let mut params = BenchParameters::default();
+    let mut interrupt_interval = None;
+    let result = match device.as_str() {
+        "block" => {
+            let mut arguments = vec![
+                Argument::positional("PATH", "disk image to read or write"),
+                Argument::flag(
+                    "write",
+                    "Write to the disk, overwriting its contents, instead of reading",
+                ),
+                Argument::value(
+                    "busy_poll",
+                    "MICROSECONDS",
+                    "Busy-poll the queue for this long after handling requests (default: disabled)",
+                ),
+            ];
+            arguments.extend(bench_common_arguments());
+            let mut path = None;
+            let mut op = BlockBenchOp::Read;
+            let mut busy_poll = None;
+            set_arguments(args, &arguments, |name, value| {
+                if parse_bench_argument(&mut params, &mut interrupt_interval, name, value)? {
+                    return Ok(());
+                }
+                match name {
+                    "" => {
+                        if path.is_some() {
+                            return Err(argument::Error::TooManyArguments(
+                                "expected exactly one disk image".to_owned(),
+                            ));
+                        }
+                        path = value.map(PathBuf::from);
+                    }
+                    "write" => op = BlockBenchOp::Write,
+                    "busy_poll" => {
+                        let micros =
+                            value
+                                .unwrap()
+                                .parse()
+                                .map_err(|_| argument::Error::InvalidValue {
+                                    value: value.unwrap().to_owned(),
+                                    expected: String::from("`busy_poll` must be an integer"),
+                                })?;
+                        busy_poll = Some(Duration::from_micros(micros));
+                    }
+                    _ => unreachable!(),
+                }
+                Ok(())
+            })
+            .map_err(|e| {
+                error!("Unable to parse command line arguments: {}", e);
+            })?;
+            let path = match path {
+                Some(path) => path,
+                None => {
+                    print_help("crosvm bench block", "PATH", &arguments);
+                    return Err(());
+                }
+            };
+
+            let raw_image = OpenOptions::new()
+                .read(true)
+                .write(op == BlockBenchOp::Write)
+                .open(&path)
+                .map_err(|e| {
+                    error!("Failed to open disk image '{}': {}", path.display(), e);
+                })?;
+            let disk = disk::create_disk_file(raw_image, disk::MAX_NESTING_DEPTH).map_err(|e| {
+                error!("Failed to open disk image '{}': {}", path.display(), e);
+            })?;
+            bench::bench_block(disk, op, busy_poll, interrupt_interval, &params)
+        }
+        "net" => {
+            let mut arguments = vec![
+                Argument::value(
+                    "host_ip",
+                    "IP",
+                    "IP address to assign to the host side of the tap",
+                ),
+                Argument::value("netmask", "NETMASK", "Netmask of the tap's subnet"),
+                Argument::value("mac", "MAC", "MAC address of the tap"),
+            ];
+            arguments.extend(bench_common_arguments());
+            let mut host_ip: Option<Ipv4Addr> = None;
+            let mut netmask: Option<Ipv4Addr> = None;
+            let mut mac: Option<MacAddress> = None;
+            set_arguments(args, &arguments, |name, value| {
+                if parse_bench_argument(&mut params, &mut interrupt_interval, name, value)? {
+                    return Ok(());
+                }
+                let invalid = |expected: &str| argument::Error::InvalidValue {
+                    value: value.unwrap().to_owned(),
+                    expected: expected.to_owned(),
+                };
+                match name {
+                    "host_ip" => {
+                        host_ip = Some(value.unwrap().parse().map_err(|_| {
+                            invalid("`host_ip` needs to be in the form \"x.x.x.x\"")
+                        })?)
+                    }
+                    "netmask" => {
+                        netmask = Some(value.unwrap().parse().map_err(|_| {
+                            invalid("`netmask` needs to be in the form \"x.x.x.x\"")
+                        })?)
+                    }
+                    "mac" => {
+                        mac = Some(value.unwrap().parse().map_err(|_| {
+                            invalid("`mac` needs to be in the form \"XX:XX:XX:XX:XX:XX\"")
+                        })?)
+                    }
+                    _ => unreachable!(),
+                }
+                Ok(())
+            })
+            .map_err(|e| {
+                error!("Unable to parse command line arguments: {}", e);
+            })?;
+            let (host_ip, netmask, mac) = match (host_ip, netmask, mac) {
+                (Some(host_ip), Some(netmask), Some(mac)) => (host_ip, netmask, mac),
+                _ => {
+                    print_help("crosvm bench net", "", &arguments);
+                    println!("`host_ip`, `netmask`, and `mac` are required.");
+                    return Err(());
+                }
+            };
+
+            let tap = create_tap::<Tap>(host_ip, netmask, mac, false).map_err(|e| {
+                error!("Failed to create tap device: {}", e);
+            })?;
+            bench::bench_net_tx(tap, interrupt_interval, &params)
+        }
+        _ => {
+            print_help("crosvm bench", "block|net [OPTIONS]", &[]);
+            println!("Measures the throughput and latency of a device's datapath without a guest.");
+            println!("  block - Reads or writes a disk image through a virtio block device.");
+            println!("  net   - Sends frames out of a new tap through a virtio net device.");
+            println!("Run `crosvm bench DEVICE` for the options of each device.");
+            return Err(());
+        }
+    };
+
+    match result {
+        Ok(result) => {
+            println!("{}", result);
+            Ok(())
+        }
+        Err(e) => {
+            error!("Benchmark failed: {}", e);
+            Err(())
+        }
+    }
+}
+
 fn print_usage() {
     print_help("crosvm", "[stop|run]", &[]);
     println!("Commands:");
     println!("    stop - Stops crosvm instances via their control sockets.");
     println!("    run  - Start a new crosvm instance.");
     println!("    create_qcow2  - Create a new qcow2 disk image file.");
+    println!("    convert - Convert a disk image between raw and qcow2, keeping it sparse.");
+    #[cfg(feature = "composite-disk")]
+    println!("    create_composite  - Create a new composite disk with a GUID partition table.");
     println!("    disk - Manage attached virtual disk devices.");
+    println!("    fault_injection - Make the fault injection test device misbehave.");
+    println!("    ivshmem_broker - Share memory between VMs.");
+    println!("    net - Manage attached virtual network devices.");
+    println!("    operation - Manage requests that continue in the background.");
+    println!("    pcie_slot - Hot-plug the slots of the PCIe root ports.");
     println!("    usb - Manage attached virtual USB devices.");
     println!("    version - Show package version.");
+    println!("    bench - Measure device datapath performance without a guest.");
 }
 
 fn pkg_version() -> std::result::Result<(), ()> {
@@ -2220,10 +4706,33 @@ fn crosvm_main() -> std::result::Result<(), ()> {
         Some("run") => run_vm(args),
         Some("balloon") => balloon_vms(args),
         Some("balloon_stats") => balloon_stats(args),
+        Some("balloon_hint") => balloon_hint(args),
+        Some("balloon_size") => balloon_size(args),
+        Some("balloon_events") => balloon_events(args),
+        Some("virtio_events") => virtio_events(args),
+        Some("guest_telemetry") => guest_telemetry(args),
+        Some("guest_telemetry_events") => guest_telemetry_events(args),
+        Some("balloon_diagnostics") => balloon_diagnostics(args),
+        Some("virtio_mem") => virtio_mem_vms(args),
+        Some("virtio_mem_size") => virtio_mem_size(args),
+        Some("vcpu_stats") => vcpu_stats(args),
+        Some("virtio_stats") => virtio_stats(args),
+        Some("rtc_wake_time") => rtc_wake_time(args),
+        Some("log_filter") => log_filter(args),
+        Some("dump_memory") => dump_memory(args),
+        Some("operation") => operation_cmd(args),
         Some("create_qcow2") => create_qcow2(args),
+        Some("convert") => convert_disk(args),
+        #[cfg(feature = "composite-disk")]
+        Some("create_composite") => create_composite(args),
+        Some("ivshmem_broker") => ivshmem_broker_cmd(args),
         Some("disk") => disk_cmd(args),
+        Some("fault_injection") => fault_injection_cmd(args),
+        Some("net") => net_cmd(args),
+        Some("pcie_slot") => pcie_slot_cmd(args),
         Some("usb") => modify_usb(args),
         Some("version") => pkg_version(),
+        Some("bench") => bench_cmd(args),
         Some("battery") => modify_battery(args),
         Some(c) => {
             println!("invalid subcommand: {:?}", c);
@@ -2430,6 +4939,247 @@ mod tests {
             .expect_err("should fail to parse a second serial port connected to stdin");
     }
 
+    #[test]
+    fn parse_serial_interactive_valid() {
+        parse_serial_options("type=pty,num=2").expect("parse should have succeded");
+        parse_serial_options("type=unix-stream,num=3,path=/run/com3.sock")
+            .expect("parse should have succeded");
+    }
+
+    #[test]
+    fn parse_serial_interactive_invalid_stdin() {
+        parse_serial_options("type=pty,num=1,stdin=true").expect_err("parse should have failed");
+        parse_serial_options("stdin=true,type=unix-stream,path=/run/com1.sock")
+            .expect_err("parse should have failed");
+    }
+
+    #[test]
+    fn parse_vhost_scsi_valid() {
+        let option = parse_vhost_scsi_options("naa.5001405c0ffee000,tpgt=2,num_queues=4")
+            .expect("parse should have succeded");
+        assert_eq!(option.wwpn, "naa.5001405c0ffee000");
+        assert_eq!(option.tpgt, 2);
+        assert_eq!(option.num_queues, 4);
+
+        let option =
+            parse_vhost_scsi_options("naa.5001405c0ffee000").expect("parse should have succeded");
+        assert_eq!(option.tpgt, 1);
+        assert_eq!(option.num_queues, 1);
+    }
+
+    #[test]
+    fn parse_vhost_scsi_invalid() {
+        parse_vhost_scsi_options("").expect_err("parse should have failed");
+        parse_vhost_scsi_options("naa.1,num_queues=0").expect_err("parse should have failed");
+        parse_vhost_scsi_options("naa.1,tpgt=70000").expect_err("parse should have failed");
+        parse_vhost_scsi_options("naa.1,lun=1").expect_err("parse should have failed");
+    }
+
+    #[test]
+    fn parse_net_link() {
+        let (index, link) = parse_net_link_options("index=1,mtu=9000,speed=10000,duplex=full")
+            .expect("parse should have succeded");
+        assert_eq!(index, 1);
+        assert_eq!(
+            link,
+            NetLink {
+                mtu: Some(9000),
+                speed: Some(10000),
+                duplex: Some(Duplex::Full),
+            }
+        );
+
+        let (index, link) = parse_net_link_options("mtu=1400").expect("parse should have succeded");
+        assert_eq!(index, 0);
+        assert_eq!(link.speed, None);
+
+        parse_net_link_options("mtu=60").expect_err("parse should have failed");
+        parse_net_link_options("duplex=auto").expect_err("parse should have failed");
+        parse_net_link_options("vlan=3").expect_err("parse should have failed");
+    }
+
+    #[test]
+    fn parse_macvtap() {
+        let option = parse_macvtap_options("eth0,mode=vepa").expect("parse should have succeded");
+        assert_eq!(option.parent, "eth0");
+        assert_eq!(option.mode, MacvtapMode::Vepa);
+        let option = parse_macvtap_options("eth0").expect("parse should have succeded");
+        assert_eq!(option.mode, MacvtapMode::Bridge);
+
+        parse_macvtap_options("").expect_err("parse should have failed");
+        parse_macvtap_options("eth0,mode=auto").expect_err("parse should have failed");
+        parse_macvtap_options("eth0,vlan=3").expect_err("parse should have failed");
+    }
+
+    #[test]
+    fn parse_cid() {
+        let mut config = Config::default();
+        set_argument(&mut config, "cid", Some("2")).expect_err("parse should fail");
+        set_argument(&mut config, "cid", Some("4294967295")).expect_err("parse should fail");
+        set_argument(&mut config, "cid", Some("3")).expect("parse should succeed");
+        assert_eq!(config.cid, Some(3));
+    }
+
+    #[test]
+    fn parse_vsock_ports() {
+        let mut config = Config::default();
+        set_argument(&mut config, "vsock-host-port", Some("80:/run/web.sock"))
+            .expect("parse should succeed");
+        set_argument(&mut config, "vsock-guest-port", Some("80:/run/ssh.sock"))
+            .expect("parse should succeed");
+        assert_eq!(
+            config.vsock_host_ports.get(&80),
+            Some(&PathBuf::from("/run/web.sock"))
+        );
+        assert_eq!(
+            config.vsock_guest_ports.get(&80),
+            Some(&PathBuf::from("/run/ssh.sock"))
+        );
+
+        set_argument(&mut config, "vsock-host-port", Some("80:/run/other.sock"))
+            .expect_err("parse should fail");
+        set_argument(&mut config, "vsock-host-port", Some("81")).expect_err("parse should fail");
+        set_argument(&mut config, "vsock-host-port", Some("81:")).expect_err("parse should fail");
+        set_argument(&mut config, "vsock-host-port", Some("-1:/run/web.sock"))
+            .expect_err("parse should fail");
+    }
+
+    #[test]
+    fn parse_cross_domain_services() {
+        let mut config = Config::default();
+        set_argument(
+            &mut config,
+            "cross-domain-service",
+            Some("/run/clipboard.sock,name=clipboard"),
+        )
+        .expect("parse should succeed");
+        assert_eq!(
+            config.cross_domain_services.get("clipboard"),
+            Some(&PathBuf::from("/run/clipboard.sock"))
+        );
+
+        set_argument(
+            &mut config,
+            "cross-domain-service",
+            Some("/run/other.sock,name=clipboard"),
+        )
+        .expect_err("parse should fail");
+        set_argument(
+            &mut config,
+            "cross-domain-service",
+            Some("/run/picker.sock"),
+        )
+        .expect_err("parse should fail");
+        set_argument(
+            &mut config,
+            "cross-domain-service",
+            Some("/run/picker.sock,name="),
+        )
+        .expect_err("parse should fail");
+    }
+
+    #[test]
+    fn vsock_ports_require_cid() {
+        let mut config = Config::default();
+        config
+            .executable_path
+            .replace(Executable::Kernel(PathBuf::from("kernel")));
+        set_argument(&mut config, "vsock-host-port", Some("80:/run/web.sock"))
+            .expect("parse should succeed");
+        validate_arguments(&mut config).expect_err("validation should fail");
+        set_argument(&mut config, "cid", Some("3")).expect("parse should succeed");
+        validate_arguments(&mut config).expect("validation should succeed");
+    }
+
+    #[test]
+    fn parse_tap_fd() {
+        let mut config = Config::default();
+        set_argument(&mut config, "tap-fd", Some("3")).expect("parse should succeed");
+        set_argument(&mut config, "tap-fd", Some("4,5,6")).expect("parse should succeed");
+        assert_eq!(config.tap_fd, vec![vec![3], vec![4, 5, 6]]);
+
+        set_argument(&mut config, "tap-fd", Some("4,")).expect_err("parse should fail");
+        set_argument(&mut config, "tap-fd", Some("tap0")).expect_err("parse should fail");
+    }
+
+    #[test]
+    fn parse_scsi_disk_valid() {
+        let first = parse_scsi_disk_options("/dev/sg0,passthrough=true", false, &[])
+            .expect("parse should have succeded");
+        assert_eq!(first.path, PathBuf::from("/dev/sg0"));
+        assert!(first.passthrough);
+        assert!(!first.read_only);
+        assert_eq!((first.target, first.lun), (0, 0));
+
+        let existing = [first];
+        let second = parse_scsi_disk_options("disk.img,cache=writethrough,id=abc", true, &existing)
+            .expect("parse should have succeded");
+        assert_eq!((second.target, second.lun), (0, 1));
+        assert_eq!(second.cache_mode, CacheMode::Writethrough);
+        assert!(second.id.is_some());
+
+        let other_target = parse_scsi_disk_options("disk.img,target=3,lun=300", true, &existing)
+            .expect("parse should have succeded");
+        assert_eq!((other_target.target, other_target.lun), (3, 300));
+    }
+
+    #[test]
+    fn parse_scsi_disk_invalid() {
+        parse_scsi_disk_options("", true, &[]).expect_err("parse should have failed");
+        parse_scsi_disk_options("disk.img,lun=16384", true, &[])
+            .expect_err("parse should have failed");
+        parse_scsi_disk_options("disk.img,target=256", true, &[])
+            .expect_err("parse should have failed");
+        parse_scsi_disk_options("disk.img,tpgt=1", true, &[])
+            .expect_err("parse should have failed");
+
+        let existing = [parse_scsi_disk_options("disk.img", true, &[]).unwrap()];
+        parse_scsi_disk_options("other.img,lun=0", true, &existing)
+            .expect_err("parse should have failed");
+    }
+
+    #[test]
+    fn parse_fault_injection_valid() {
+        let config = parse_fault_injection_config("used_delay_ms=20,drop_interrupt_every=4")
+            .expect("parse should have succeded");
+        assert_eq!(
+            config,
+            FaultInjectionConfig {
+                used_delay_ms: 20,
+                corrupt_every: 0,
+                drop_interrupt_every: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_fault_injection_invalid() {
+        parse_fault_injection_config("corrupt_every=-1").expect_err("parse should have failed");
+        parse_fault_injection_config("used_delay_ms").expect_err("parse should have failed");
+        parse_fault_injection_config("drop_every=2").expect_err("parse should have failed");
+    }
+
+    #[test]
+    fn parse_layout_valid() {
+        let layout = parse_layout_options("low_mmio_size=0x40000000,high_mmio_size=1073741824")
+            .expect("parse should have succeded");
+        assert_eq!(
+            layout,
+            ArchLayout {
+                low_mmio_size: Some(0x4000_0000),
+                high_mmio_size: Some(0x4000_0000),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_layout_invalid() {
+        parse_layout_options("ram_start=0xg").expect_err("parse should have failed");
+        parse_layout_options("high_mmio_start").expect_err("parse should have failed");
+        parse_layout_options("mmio_size=0x1000").expect_err("parse should have failed");
+    }
+
     #[test]
     fn parse_plugin_mount_valid() {
         let mut config = Config::default();
@@ -2692,6 +5442,96 @@ mod tests {
         assert!(parse_gpu_options(Some("syncfd=true,backend=3d")).is_err());
     }
 
+    #[test]
+    fn parse_auto_balloon_defaults() {
+        let params = parse_auto_balloon_options(None).expect("parse should have succeded");
+        assert_eq!(params.min_mem, None);
+        assert_eq!(params.max_mem, None);
+        assert_eq!(params.psi_path, PathBuf::from("/proc/pressure/memory"));
+    }
+
+    #[test]
+    fn parse_auto_balloon_all_options() {
+        let params = parse_auto_balloon_options(Some(
+            "min_mem=512,max_mem=2048,low=0.5,high=20,psi=/sys/fs/cgroup/vm/memory.pressure",
+        ))
+        .expect("parse should have succeded");
+        assert_eq!(params.min_mem, Some(512));
+        assert_eq!(params.max_mem, Some(2048));
+        assert_eq!(params.low_pressure, 0.5);
+        assert_eq!(params.high_pressure, 20.0);
+        assert_eq!(
+            params.psi_path,
+            PathBuf::from("/sys/fs/cgroup/vm/memory.pressure")
+        );
+    }
+
+    #[test]
+    fn parse_auto_balloon_invalid() {
+        parse_auto_balloon_options(Some("min=512")).expect_err("parse should have failed");
+        parse_auto_balloon_options(Some("min_mem=lots")).expect_err("parse should have failed");
+        parse_auto_balloon_options(Some("low=10,high=5")).expect_err("parse should have failed");
+        parse_auto_balloon_options(Some("min_mem=2048,max_mem=512"))
+            .expect_err("parse should have failed");
+    }
+
+    #[test]
+    fn parse_deflate_on_pressure() {
+        let params = parse_deflate_on_pressure_options(None).expect("parse should have succeded");
+        assert_eq!(params.min_available, 128);
+        assert_eq!(params.step, 64);
+
+        let params = parse_deflate_on_pressure_options(Some("min_avail=256,step=32"))
+            .expect("parse should have succeded");
+        assert_eq!(params.min_available, 256);
+        assert_eq!(params.step, 32);
+
+        parse_deflate_on_pressure_options(Some("step=0")).expect_err("parse should have failed");
+        parse_deflate_on_pressure_options(Some("min=1")).expect_err("parse should have failed");
+    }
+
+    #[test]
+    fn parse_uuid_valid() {
+        let uuid =
+            parse_uuid("01234567-89ab-cdef-0123-456789ABCDEF").expect("parse should have succeded");
+        assert_eq!(
+            uuid,
+            [
+                0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab,
+                0xcd, 0xef
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_uuid_invalid() {
+        parse_uuid("0123456789abcdef0123456789abcdef").expect_err("parse should have failed");
+        parse_uuid("01234567-89ab-cdef-0123-456789abcde").expect_err("parse should have failed");
+        parse_uuid("01234567-89ab-cdef-0123-456789abcdeg").expect_err("parse should have failed");
+        parse_uuid("+1234567-89ab-cdef-0123-456789abcdef").expect_err("parse should have failed");
+    }
+
+    #[test]
+    fn parse_throttle_limits_valid() {
+        assert_eq!(
+            parse_throttle_limits("iops=100,bps=1048576,bps_burst=4194304")
+                .expect("parse should have succeeded"),
+            ThrottleLimits {
+                iops: 100,
+                iops_burst: 0,
+                bytes_per_sec: 1048576,
+                bytes_burst: 4194304,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_throttle_limits_invalid() {
+        parse_throttle_limits("iops=fast").expect_err("parse should have failed");
+        parse_throttle_limits("iops").expect_err("parse should have failed");
+        parse_throttle_limits("bandwidth=1").expect_err("parse should have failed");
+    }
+
     #[test]
     fn parse_battery_vaild() {
         parse_battery_options(Some("type=goldfish")).expect("parse should have succeded");
@@ -674,7 +674,7 @@ pub fn run_config(cfg: Config) -> Result<()> {
             }
         }
     }
-    for tap_fd in cfg.tap_fd {
+    for tap_fd in cfg.tap_fd.into_iter().flatten() {
         // Safe because we ensure that we get a unique handle to the fd.
         let tap = unsafe {
             Tap::from_raw_descriptor(validate_raw_descriptor(tap_fd).map_err(Error::ValidateTapFd)?)
@@ -4,8 +4,8 @@
 
 //! Sync primitive types whose methods panic rather than returning error in case of poison.
 //!
-//! The Mutex/Condvar type in this crates wraps the standard library versions and mirrors the same
-//! methods, except that they panic where the standard library would return an Error. This API
+//! The Mutex/Condvar/RwLock types in this crate wrap the standard library versions and mirror the
+//! same methods, except that they panic where the standard library would return an Error. This API
 //! codifies our error handling strategy around poisoned mutexes in crosvm.
 //!
 //! - Crosvm releases are built with panic=abort so poisoning never occurs. A panic while a mutex is
@@ -23,6 +23,8 @@
 
 mod condvar;
 mod mutex;
+mod rwlock;
 
 pub use crate::condvar::Condvar;
 pub use crate::mutex::{Mutex, WouldBlock};
+pub use crate::rwlock::RwLock;
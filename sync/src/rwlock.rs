@@ -0,0 +1,70 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! RwLock type whose methods panic rather than returning error in case of
+//! poison.
+//!
+//! The RwLock type in this module wraps the standard library RwLock and
+//! mirrors the same methods, except that they panic where the standard
+//! library would return a PoisonError, for the same reasons as `sync::Mutex`.
+
+use std::fmt::{self, Debug};
+use std::sync::{RwLock as StdRwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A reader-writer lock, allowing any number of readers or a single writer at a time.
+#[derive(Default)]
+pub struct RwLock<T: ?Sized> {
+    std: StdRwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    /// Creates a new instance of an RwLock which is unlocked.
+    pub fn new(value: T) -> RwLock<T> {
+        RwLock {
+            std: StdRwLock::new(value),
+        }
+    }
+
+    /// Consumes this lock, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        match self.std.into_inner() {
+            Ok(value) => value,
+            Err(_) => panic!("rwlock is poisoned"),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Locks this lock with shared read access, blocking the current thread
+    /// until it can be acquired.
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        match self.std.read() {
+            Ok(guard) => guard,
+            Err(_) => panic!("rwlock is poisoned"),
+        }
+    }
+
+    /// Locks this lock with exclusive write access, blocking the current
+    /// thread until it can be acquired.
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        match self.std.write() {
+            Ok(guard) => guard,
+            Err(_) => panic!("rwlock is poisoned"),
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    pub fn get_mut(&mut self) -> &mut T {
+        match self.std.get_mut() {
+            Ok(value) => value,
+            Err(_) => panic!("rwlock is poisoned"),
+        }
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for RwLock<T> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        Debug::fmt(&self.std, formatter)
+    }
+}
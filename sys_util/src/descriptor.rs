@@ -8,7 +8,7 @@ use std::mem;
 use std::net::UdpSocket;
 use std::ops::Drop;
 use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
-use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
 
 use crate::net::UnlinkUnixSeqpacketListener;
 use crate::{errno_result, PollToken, Result};
@@ -198,6 +198,12 @@ impl AsRawDescriptor for UnixStream {
     }
 }
 
+impl AsRawDescriptor for UnixListener {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.as_raw_fd()
+    }
+}
+
 impl AsRawDescriptor for UnixDatagram {
     fn as_raw_descriptor(&self) -> RawDescriptor {
         self.as_raw_fd()
@@ -17,12 +17,22 @@ use crate::{fallocate, FallocateMode};
 pub trait FileSync {
     // Flush buffers related to this file to disk.
     fn fsync(&mut self) -> Result<()>;
+
+    // Flush the data of this file to disk, along with only the metadata needed to read it back.
+    // This is the moral equivalent of `fdatasync()`, and falls back to `fsync` by default.
+    fn fdatasync(&mut self) -> Result<()> {
+        self.fsync()
+    }
 }
 
 impl FileSync for File {
     fn fsync(&mut self) -> Result<()> {
         self.sync_all()
     }
+
+    fn fdatasync(&mut self) -> Result<()> {
+        self.sync_data()
+    }
 }
 
 /// A trait for setting the size of a file.
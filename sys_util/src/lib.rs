@@ -19,6 +19,8 @@ pub mod handle_eintr;
 pub mod ioctl;
 #[macro_use]
 pub mod syslog;
+#[macro_use]
+pub mod trace_event;
 mod capabilities;
 mod clock;
 mod descriptor;
@@ -30,6 +32,7 @@ pub mod file_traits;
 mod fork;
 mod mmap;
 pub mod net;
+pub mod netlink;
 mod passwd;
 mod poll;
 mod priority;
@@ -93,7 +96,7 @@ use libc::{
     F_SETFL, O_CLOEXEC, SIGKILL, WNOHANG, _SC_IOV_MAX, _SC_PAGESIZE,
 };
 
-use syscall_defines::linux::LinuxSyscall::SYS_getpid;
+use syscall_defines::linux::LinuxSyscall::{SYS_getpid, SYS_gettid};
 
 /// Used to mark types as !Sync.
 pub type UnsyncMarker = std::marker::PhantomData<Cell<usize>>;
@@ -126,6 +129,13 @@ pub fn getpid() -> pid_t {
     unsafe { syscall(SYS_getpid as c_long) as pid_t }
 }
 
+/// Safe wrapper for `gettid(2)`.
+#[inline(always)]
+pub fn gettid() -> pid_t {
+    // Safe because this syscall can never fail and we give it a valid syscall number.
+    unsafe { syscall(SYS_gettid as c_long) as pid_t }
+}
+
 /// Safe wrapper for `geteuid(2)`.
 #[inline(always)]
 pub fn geteuid() -> uid_t {
@@ -0,0 +1,463 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A minimal rtnetlink (`NETLINK_ROUTE`) client for configuring host network interfaces.
+//!
+//! This covers the handful of operations crosvm needs to set up guest networking without shelling
+//! out to `ip(8)`: creating bridges and macvtap interfaces, attaching interfaces to a bridge,
+//! bringing links up and down, and assigning IPv4 or IPv6 addresses.
+
+use std::ffi::CString;
+use std::mem::size_of;
+use std::net::IpAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use data_model::DataInit;
+use libc::{c_int, c_uint, c_ushort};
+
+use crate::{
+    errno_result, AsRawDescriptor, Error, FromRawDescriptor, RawDescriptor, Result, SafeDescriptor,
+};
+
+const NLMSG_ALIGNTO: usize = 4;
+const NLA_F_NESTED: u16 = 1 << 15;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_NEWADDR: u16 = 20;
+
+const IFLA_ADDRESS: u16 = 1;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MTU: u16 = 4;
+const IFLA_LINK: u16 = 5;
+const IFLA_MASTER: u16 = 10;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_INFO_KIND: u16 = 1;
+const IFLA_INFO_DATA: u16 = 2;
+const IFLA_MACVLAN_MODE: u16 = 1;
+
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+
+// The kernel's `struct nlmsghdr`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct NlMsgHdr {
+    len: u32,
+    type_: u16,
+    flags: u16,
+    seq: u32,
+    pid: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for NlMsgHdr {}
+
+// The kernel's `struct nlmsgerr`, minus the echoed request header that follows it.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct NlMsgErr {
+    error: i32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for NlMsgErr {}
+
+// The kernel's `struct nlattr`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct NlAttr {
+    len: u16,
+    type_: u16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for NlAttr {}
+
+// The kernel's `struct ifinfomsg`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct IfInfoMsg {
+    family: u8,
+    pad: u8,
+    type_: c_ushort,
+    index: c_int,
+    flags: c_uint,
+    change: c_uint,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for IfInfoMsg {}
+
+// The kernel's `struct ifaddrmsg`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct IfAddrMsg {
+    family: u8,
+    prefixlen: u8,
+    flags: u8,
+    scope: u8,
+    index: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for IfAddrMsg {}
+
+fn nlmsg_align(len: usize) -> usize {
+    (len + NLMSG_ALIGNTO - 1) & !(NLMSG_ALIGNTO - 1)
+}
+
+/// The forwarding mode of a macvtap interface, mirroring `enum macvlan_mode`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MacvtapMode {
+    Private = 1,
+    Vepa = 2,
+    Bridge = 4,
+    Passthru = 8,
+}
+
+/// Builds a single rtnetlink request message.
+struct NetlinkMessage {
+    buf: Vec<u8>,
+    nests: Vec<usize>,
+}
+
+impl NetlinkMessage {
+    fn new<T: DataInit>(type_: u16, flags: u16, payload: T) -> NetlinkMessage {
+        let mut msg = NetlinkMessage {
+            buf: Vec::new(),
+            nests: Vec::new(),
+        };
+        let hdr = NlMsgHdr {
+            type_,
+            flags: flags | libc::NLM_F_REQUEST as u16 | libc::NLM_F_ACK as u16,
+            ..Default::default()
+        };
+        msg.push_aligned(hdr.as_slice());
+        msg.push_aligned(payload.as_slice());
+        msg
+    }
+
+    fn push_aligned(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        self.buf.resize(nlmsg_align(self.buf.len()), 0);
+    }
+
+    fn attr(&mut self, type_: u16, data: &[u8]) -> &mut Self {
+        let attr = NlAttr {
+            len: (size_of::<NlAttr>() + data.len()) as u16,
+            type_,
+        };
+        self.buf.extend_from_slice(attr.as_slice());
+        self.push_aligned(data);
+        self
+    }
+
+    fn attr_u32(&mut self, type_: u16, val: u32) -> &mut Self {
+        self.attr(type_, &val.to_ne_bytes())
+    }
+
+    fn attr_str(&mut self, type_: u16, val: &CString) -> &mut Self {
+        self.attr(type_, val.as_bytes_with_nul())
+    }
+
+    fn begin_nested(&mut self, type_: u16) -> &mut Self {
+        self.nests.push(self.buf.len());
+        let attr = NlAttr {
+            len: 0,
+            type_: type_ | NLA_F_NESTED,
+        };
+        self.buf.extend_from_slice(attr.as_slice());
+        self
+    }
+
+    fn end_nested(&mut self) -> &mut Self {
+        let start = self
+            .nests
+            .pop()
+            .expect("unbalanced netlink attribute nesting");
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+        self
+    }
+
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// A `NETLINK_ROUTE` socket for configuring network links and addresses.
+///
+/// Every request is sent with `NLM_F_ACK` and waits for the kernel's acknowledgement, so a
+/// successful return means the change has been applied.
+pub struct NetlinkSocket {
+    socket: SafeDescriptor,
+    seq: u32,
+}
+
+impl NetlinkSocket {
+    /// Opens a new rtnetlink socket. Most requests require `CAP_NET_ADMIN`.
+    pub fn new() -> Result<NetlinkSocket> {
+        // Safe because we check the return value and take ownership of the new fd.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return errno_result();
+        }
+
+        Ok(NetlinkSocket {
+            // Safe because we own the fd we just created.
+            socket: unsafe { SafeDescriptor::from_raw_descriptor(fd) },
+            seq: 0,
+        })
+    }
+
+    fn request(&mut self, msg: NetlinkMessage) -> Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+        let buf = msg.finish(self.seq);
+
+        // Safe because the buffer is valid for its whole length and we check the return value.
+        let ret = unsafe {
+            libc::send(
+                self.socket.as_raw_fd(),
+                buf.as_ptr() as *const _,
+                buf.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return errno_result();
+        }
+
+        let mut resp = [0u8; 4096];
+        loop {
+            // Safe because the kernel writes at most `resp.len()` bytes and we check the return.
+            let ret = handle_eintr_errno!(unsafe {
+                libc::recv(
+                    self.socket.as_raw_fd(),
+                    resp.as_mut_ptr() as *mut _,
+                    resp.len(),
+                    0,
+                )
+            });
+            if ret < 0 {
+                return errno_result();
+            }
+            let resp = &resp[..ret as usize];
+
+            let mut offset = 0;
+            while offset + size_of::<NlMsgHdr>() <= resp.len() {
+                let hdr = NlMsgHdr::from_slice(&resp[offset..offset + size_of::<NlMsgHdr>()])
+                    .copied()
+                    .ok_or_else(|| Error::new(libc::EINVAL))?;
+                let msg_len = hdr.len as usize;
+                if msg_len < size_of::<NlMsgHdr>() || offset + msg_len > resp.len() {
+                    return Err(Error::new(libc::EINVAL));
+                }
+                if hdr.seq == self.seq && hdr.type_ == libc::NLMSG_ERROR as u16 {
+                    let err_start = offset + size_of::<NlMsgHdr>();
+                    let err = NlMsgErr::from_slice(
+                        resp.get(err_start..err_start + size_of::<NlMsgErr>())
+                            .ok_or_else(|| Error::new(libc::EINVAL))?,
+                    )
+                    .copied()
+                    .ok_or_else(|| Error::new(libc::EINVAL))?;
+                    // An error code of zero is the acknowledgement of success.
+                    return if err.error == 0 {
+                        Ok(())
+                    } else {
+                        Err(Error::new(-err.error))
+                    };
+                }
+                offset += nlmsg_align(msg_len);
+            }
+        }
+    }
+
+    /// Creates a new bridge named `name`.
+    pub fn create_bridge(&mut self, name: &str) -> Result<()> {
+        let name = to_ifname(name)?;
+        let mut msg = NetlinkMessage::new(
+            RTM_NEWLINK,
+            (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16,
+            IfInfoMsg::default(),
+        );
+        msg.attr_str(IFLA_IFNAME, &name)
+            .begin_nested(IFLA_LINKINFO)
+            .attr(IFLA_INFO_KIND, b"bridge")
+            .end_nested();
+        self.request(msg)
+    }
+
+    /// Creates a new macvtap interface named `name` on top of the link with index `parent`. The
+    /// matching character device is `/dev/tapN`, where N is the new link's index.
+    pub fn create_macvtap(&mut self, name: &str, parent: u32, mode: MacvtapMode) -> Result<()> {
+        let name = to_ifname(name)?;
+        let mut msg = NetlinkMessage::new(
+            RTM_NEWLINK,
+            (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16,
+            IfInfoMsg::default(),
+        );
+        msg.attr_str(IFLA_IFNAME, &name)
+            .attr_u32(IFLA_LINK, parent)
+            .begin_nested(IFLA_LINKINFO)
+            .attr(IFLA_INFO_KIND, b"macvtap")
+            .begin_nested(IFLA_INFO_DATA)
+            .attr_u32(IFLA_MACVLAN_MODE, mode as u32)
+            .end_nested()
+            .end_nested();
+        self.request(msg)
+    }
+
+    /// Deletes the link with the given interface index.
+    pub fn delete_link(&mut self, index: u32) -> Result<()> {
+        let msg = NetlinkMessage::new(RTM_DELLINK, 0, if_info(index));
+        self.request(msg)
+    }
+
+    /// Brings the link with the given interface index up or down.
+    pub fn set_link_up(&mut self, index: u32, up: bool) -> Result<()> {
+        let mut info = if_info(index);
+        info.flags = if up { libc::IFF_UP as c_uint } else { 0 };
+        info.change = libc::IFF_UP as c_uint;
+        let msg = NetlinkMessage::new(RTM_NEWLINK, 0, info);
+        self.request(msg)
+    }
+
+    /// Sets the MTU of the link with the given interface index.
+    pub fn set_mtu(&mut self, index: u32, mtu: u32) -> Result<()> {
+        let mut msg = NetlinkMessage::new(RTM_NEWLINK, 0, if_info(index));
+        msg.attr_u32(IFLA_MTU, mtu);
+        self.request(msg)
+    }
+
+    /// Sets the hardware address of the link with the given interface index.
+    pub fn set_mac_address(&mut self, index: u32, mac: [u8; 6]) -> Result<()> {
+        let mut msg = NetlinkMessage::new(RTM_NEWLINK, 0, if_info(index));
+        msg.attr(IFLA_ADDRESS, &mac);
+        self.request(msg)
+    }
+
+    /// Enslaves the link `index` to the bridge `master`, or releases it if `master` is `None`.
+    pub fn set_master(&mut self, index: u32, master: Option<u32>) -> Result<()> {
+        let mut msg = NetlinkMessage::new(RTM_NEWLINK, 0, if_info(index));
+        msg.attr_u32(IFLA_MASTER, master.unwrap_or(0));
+        self.request(msg)
+    }
+
+    /// Assigns `addr` with the given prefix length to the link with the given interface index.
+    pub fn add_address(&mut self, index: u32, addr: IpAddr, prefix_len: u8) -> Result<()> {
+        let (family, max_prefix, octets) = match addr {
+            IpAddr::V4(a) => (libc::AF_INET, 32, a.octets().to_vec()),
+            IpAddr::V6(a) => (libc::AF_INET6, 128, a.octets().to_vec()),
+        };
+        if prefix_len > max_prefix {
+            return Err(Error::new(libc::EINVAL));
+        }
+        let info = IfAddrMsg {
+            family: family as u8,
+            prefixlen: prefix_len,
+            index,
+            ..Default::default()
+        };
+        let mut msg = NetlinkMessage::new(
+            RTM_NEWADDR,
+            (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16,
+            info,
+        );
+        msg.attr(IFA_LOCAL, &octets).attr(IFA_ADDRESS, &octets);
+        self.request(msg)
+    }
+}
+
+impl AsRawDescriptor for NetlinkSocket {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.socket.as_raw_descriptor()
+    }
+}
+
+impl AsRawFd for NetlinkSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+fn if_info(index: u32) -> IfInfoMsg {
+    IfInfoMsg {
+        family: libc::AF_UNSPEC as u8,
+        index: index as c_int,
+        ..Default::default()
+    }
+}
+
+fn to_ifname(name: &str) -> Result<CString> {
+    if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+        return Err(Error::new(libc::EINVAL));
+    }
+    CString::new(name).map_err(|_| Error::new(libc::EINVAL))
+}
+
+/// Returns the interface index of the network interface named `name`.
+pub fn link_index(name: &str) -> Result<u32> {
+    let name = to_ifname(name)?;
+    // Safe because `name` is a valid nul-terminated string and we check the return value.
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        errno_result()
+    } else {
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_layout() {
+        let mut msg = NetlinkMessage::new(RTM_NEWLINK, 0, if_info(7));
+        msg.attr_u32(IFLA_MTU, 1500)
+            .begin_nested(IFLA_LINKINFO)
+            .attr(IFLA_INFO_KIND, b"bridge")
+            .end_nested();
+        let buf = msg.finish(3);
+
+        let hdr = NlMsgHdr::from_slice(&buf[..16]).unwrap();
+        assert_eq!(hdr.len as usize, buf.len());
+        assert_eq!(hdr.type_, RTM_NEWLINK);
+        assert_eq!(hdr.seq, 3);
+        assert_eq!(buf.len() % NLMSG_ALIGNTO, 0);
+
+        // Header, ifinfomsg, then the MTU attribute.
+        let mtu_start = 16 + size_of::<IfInfoMsg>();
+        let attr = NlAttr::from_slice(&buf[mtu_start..mtu_start + 4]).unwrap();
+        assert_eq!(attr.len, 8);
+        assert_eq!(attr.type_, IFLA_MTU);
+
+        // The nested attribute covers its own header plus the aligned "bridge" kind.
+        let nest = NlAttr::from_slice(&buf[mtu_start + 8..mtu_start + 12]).unwrap();
+        assert_eq!(nest.type_, IFLA_LINKINFO | NLA_F_NESTED);
+        assert_eq!(nest.len, 4 + 4 + 8);
+    }
+
+    #[test]
+    fn invalid_ifname() {
+        assert!(to_ifname("").is_err());
+        assert!(to_ifname("a_name_that_is_too_long").is_err());
+        assert!(to_ifname("br0").is_ok());
+    }
+
+    #[test]
+    fn loopback_index() {
+        assert!(link_index("lo").unwrap() > 0);
+    }
+}
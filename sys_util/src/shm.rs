@@ -386,6 +386,25 @@ mod tests {
         shm.add_seals(seals).unwrap_err();
     }
 
+    #[test]
+    fn resize_sealed() {
+        if !kernel_has_memfd() {
+            return;
+        }
+        let mut shm = SharedMemory::anon().expect("failed to create shared memory");
+        shm.set_size(8192)
+            .expect("failed to set shared memory size");
+        let mut seals = MemfdSeals::new();
+        seals.set_shrink_seal();
+        shm.add_seals(seals).expect("failed to add seals");
+
+        // Growing is still allowed, but shrinking must be rejected without changing the size.
+        shm.set_size(16384).expect("failed to grow shared memory");
+        assert_eq!(shm.size(), 16384);
+        shm.set_size(4096).unwrap_err();
+        assert_eq!(shm.size(), 16384);
+    }
+
     #[test]
     fn mmap_page() {
         if !kernel_has_memfd() {
@@ -5,7 +5,7 @@
 use libc::{
     c_int, pthread_kill, pthread_sigmask, pthread_t, sigaction, sigaddset, sigemptyset, siginfo_t,
     sigismember, sigpending, sigset_t, sigtimedwait, timespec, EAGAIN, EINTR, EINVAL, SA_RESTART,
-    SIG_BLOCK, SIG_UNBLOCK,
+    SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK,
 };
 
 use std::cmp::Ordering;
@@ -219,6 +219,18 @@ pub fn unblock_signal(num: c_int) -> SignalResult<()> {
     Ok(())
 }
 
+/// Unmasks every signal, leaving the current thread with an empty signal mask.
+pub fn unblock_all_signals() -> SignalResult<()> {
+    let sigset = create_sigset(&[]).map_err(Error::CreateSigset)?;
+
+    // Safe - return value is checked.
+    let ret = unsafe { pthread_sigmask(SIG_SETMASK, &sigset, null_mut()) };
+    if ret < 0 {
+        return Err(Error::UnblockSignal(errno::Error::last()));
+    }
+    Ok(())
+}
+
 /// Clears pending signal.
 pub fn clear_signal(num: c_int) -> SignalResult<()> {
     let sigset = create_sigset(&[num]).map_err(Error::CreateSigset)?;
@@ -60,10 +60,11 @@ pub type Result<T> = result::Result<T, Error>;
 /// A safe wrapper around a Linux signalfd (man 2 signalfd).
 ///
 /// A signalfd can be used for non-synchronous signals (such as SIGCHLD) so that
-/// signals can be processed without the use of a signal handler.
+/// signals can be processed without the use of a signal handler. A single signalfd may watch
+/// several signals, in which case `ssi_signo` of each read siginfo tells them apart.
 pub struct SignalFd {
     signalfd: File,
-    signal: c_int,
+    signals: Vec<c_int>,
 }
 
 impl SignalFd {
@@ -73,7 +74,13 @@ impl SignalFd {
     /// **exec** so the user of SignalFd should think long and hard about
     /// when to mask signals.
     pub fn new(signal: c_int) -> Result<SignalFd> {
-        let sigset = signal::create_sigset(&[signal]).map_err(Error::CreateSigset)?;
+        SignalFd::with_signals(&[signal])
+    }
+
+    /// Creates a new SignalFd that is readable when any of `signals` is pending, blocking the
+    /// normal handlers for all of them. The same caveats as `SignalFd::new` apply to each signal.
+    pub fn with_signals(signals: &[c_int]) -> Result<SignalFd> {
+        let sigset = signal::create_sigset(signals).map_err(Error::CreateSigset)?;
 
         // This is safe as we check the return value and know that fd is valid.
         let fd = unsafe { signalfd(-1, &sigset, SFD_CLOEXEC | SFD_NONBLOCK) };
@@ -81,17 +88,30 @@ impl SignalFd {
             return Err(Error::CreateSignalFd(errno::Error::last()));
         }
 
-        // Mask out the normal handler for the signal.
-        signal::block_signal(signal).map_err(Error::CreateBlockSignal)?;
-
         // This is safe because we checked fd for success and know the
         // kernel gave us an fd that we own.
-        unsafe {
-            Ok(SignalFd {
-                signalfd: File::from_raw_fd(fd),
-                signal,
-            })
+        let signalfd = unsafe { File::from_raw_fd(fd) };
+
+        // Mask out the normal handler for each signal.
+        for (i, &signal) in signals.iter().enumerate() {
+            if let Err(e) = signal::block_signal(signal) {
+                // Don't leave the signals blocked so far masked without a SignalFd to read them.
+                for &blocked in &signals[..i] {
+                    let _ = signal::unblock_signal(blocked);
+                }
+                return Err(Error::CreateBlockSignal(e));
+            }
         }
+
+        Ok(SignalFd {
+            signalfd,
+            signals: signals.to_vec(),
+        })
+    }
+
+    /// Returns the signals this SignalFd was created to watch.
+    pub fn signals(&self) -> &[c_int] {
+        &self.signals
     }
 
     /// Read a siginfo struct from the signalfd, if available.
@@ -143,10 +163,11 @@ impl AsRawDescriptor for SignalFd {
 impl Drop for SignalFd {
     fn drop(&mut self) {
         // This is thread-safe and safe in the sense that we're doing what
-        // was promised - unmasking the signal when we go out of scope.
-        let res = signal::unblock_signal(self.signal);
-        if let Err(e) = res {
-            error!("signalfd failed to unblock signal {}: {}", self.signal, e);
+        // was promised - unmasking the signals when we go out of scope.
+        for &signal in &self.signals {
+            if let Err(e) = signal::unblock_signal(signal) {
+                error!("signalfd failed to unblock signal {}: {}", signal, e);
+            }
         }
     }
 }
@@ -177,6 +198,21 @@ mod tests {
         assert_eq!(siginfo.ssi_signo, sigid as u32);
     }
 
+    #[test]
+    fn read_multiple() {
+        let first = SIGRTMIN() + 3;
+        let second = SIGRTMIN() + 4;
+        let sigrt_fd = SignalFd::with_signals(&[first, second]).unwrap();
+        assert_eq!(sigrt_fd.signals(), &[first, second]);
+
+        let ret = unsafe { raise(second) };
+        assert_eq!(ret, 0);
+
+        let siginfo = sigrt_fd.read().unwrap().unwrap();
+        assert_eq!(siginfo.ssi_signo, second as u32);
+        assert!(sigrt_fd.read().unwrap().is_none());
+    }
+
     #[test]
     fn drop() {
         let sigid = SIGRTMIN() + 2;
@@ -197,4 +233,24 @@ mod tests {
             assert_eq!(sigismember(&sigset, sigid), 0);
         }
     }
+
+    #[test]
+    fn block_failure_unblocks_earlier_signals() {
+        let first = SIGRTMIN() + 5;
+        let second = SIGRTMIN() + 6;
+
+        // Already blocking the second signal makes blocking it again fail.
+        let second_fd = SignalFd::new(second).unwrap();
+        assert!(SignalFd::with_signals(&[first, second]).is_err());
+
+        // The first signal should not be left masked.
+        unsafe {
+            let mut sigset: sigset_t = mem::zeroed();
+            pthread_sigmask(0, null(), &mut sigset as *mut sigset_t);
+            assert_eq!(sigismember(&sigset, first), 0);
+            assert_eq!(sigismember(&sigset, second), 1);
+        }
+
+        mem::drop(second_fd);
+    }
 }
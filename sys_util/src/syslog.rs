@@ -19,9 +19,14 @@
 //! warn!("this is your {} warning", "final");
 //! error!("something went horribly wrong: {}", "out of RAMs");
 //! ```
+//!
+//! Which messages are recorded can be changed at any time with `syslog::set_filter()`, by
+//! priority and by the module they come from. The filter is shared with the processes forked
+//! after `syslog::init()`, so changing it in the main process also changes it in the sandboxed
+//! device processes.
 
 use crate::target_os::syslog::PlatformSyslog;
-use crate::RawDescriptor;
+use crate::{MappedRegion, MemoryMapping, RawDescriptor};
 use std::env;
 use std::ffi::{OsStr, OsString};
 use std::fmt::{self, Display};
@@ -30,6 +35,8 @@ use std::io;
 use std::io::{stderr, Cursor, Write};
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{fence, AtomicU32, Ordering};
 use std::sync::{MutexGuard, Once};
 
 use sync::Mutex;
@@ -37,7 +44,7 @@ use sync::Mutex;
 /// The priority (i.e. severity) of a syslog message.
 ///
 /// See syslog man pages for information on their semantics.
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Priority {
     Emergency = 0,
     Alert = 1,
@@ -68,6 +75,26 @@ impl Display for Priority {
     }
 }
 
+impl FromStr for Priority {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use self::Priority::*;
+
+        Ok(match s {
+            "emergency" => Emergency,
+            "alert" => Alert,
+            "critical" => Critical,
+            "error" => Error,
+            "warning" | "warn" => Warning,
+            "notice" => Notice,
+            "info" => Info,
+            "debug" => Debug,
+            _ => return Err(self::Error::InvalidFilter(s.to_owned())),
+        })
+    }
+}
+
 /// The facility of a syslog message.
 ///
 /// See syslog man pages for information on their semantics.
@@ -92,9 +119,11 @@ pub enum Facility {
     Local7 = 23 << 3,
 }
 
-/// Errors returned by `syslog::init()`.
+/// Errors returned by `syslog::init()` and `syslog::set_filter()`.
 #[derive(Debug)]
 pub enum Error {
+    /// The log filter names an unknown priority.
+    InvalidFilter(String),
     /// Initialization was never attempted.
     NeverInitialized,
     /// Initialization has previously failed and can not be retried.
@@ -107,6 +136,8 @@ pub enum Error {
     GetLowestFd(io::Error),
     // The guess of libc's file descriptor for the syslog connection was invalid.
     InvalidFd,
+    /// The log filter is longer than `MAX_FILTER_LEN`.
+    FilterTooLong,
 }
 
 impl Display for Error {
@@ -114,12 +145,14 @@ impl Display for Error {
         use self::Error::*;
 
         match self {
+            InvalidFilter(s) => write!(f, "invalid log filter entry: {:?}", s),
             NeverInitialized => write!(f, "initialization was never attempted"),
             Poisoned => write!(f, "initialization previously failed and cannot be retried"),
             Socket(e) => write!(f, "failed to create socket: {}", e),
             Connect(e) => write!(f, "failed to connect socket: {}", e),
             GetLowestFd(e) => write!(f, "failed to get lowest file descriptor: {}", e),
             InvalidFd => write!(f, "guess of fd for syslog connection was invalid"),
+            FilterTooLong => write!(f, "log filter is longer than {} bytes", MAX_FILTER_LEN),
         }
     }
 }
@@ -133,11 +166,141 @@ fn get_proc_name() -> Option<String> {
         .and_then(Result::ok)
 }
 
+/// The longest log filter `set_filter` accepts, in bytes.
+pub const MAX_FILTER_LEN: usize = 1024;
+
+// The shared filter memory holds a generation count, which is odd while the filter is being
+// replaced, followed by the length of the filter and the filter itself.
+const FILTER_GENERATION_OFFSET: usize = 0;
+const FILTER_LEN_OFFSET: usize = 4;
+const FILTER_OFFSET: usize = 8;
+
+/// Decides which messages are recorded from their priority and the module they come from.
+///
+/// A filter is a comma separated list of entries. An entry `module=priority` sets the least severe
+/// priority recorded from `module` and the modules inside it, and the most specific module
+/// applies. An entry without a module sets the priority for all other messages. For example,
+/// `warning,devices::virtio::net=debug` records everything from the virtio-net device but only
+/// warnings and worse from anywhere else.
+#[derive(Debug, PartialEq)]
+pub struct LogFilter {
+    default: Priority,
+    modules: Vec<(String, Priority)>,
+}
+
+impl Default for LogFilter {
+    fn default() -> LogFilter {
+        LogFilter {
+            default: Priority::Debug,
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl LogFilter {
+    /// Returns whether a message of priority `pri` from `module_path` should be recorded.
+    pub fn enabled(&self, module_path: Option<&str>, pri: Priority) -> bool {
+        let mut level = self.default;
+        if let Some(module_path) = module_path {
+            let mut longest = 0;
+            for (module, module_level) in &self.modules {
+                let matches = module_path == module
+                    || (module_path.starts_with(module.as_str())
+                        && module_path[module.len()..].starts_with("::"));
+                if matches && module.len() > longest {
+                    longest = module.len();
+                    level = *module_level;
+                }
+            }
+        }
+        pri as u8 <= level as u8
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = LogFilter::default();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut kv = entry.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(module), Some(pri)) if !module.is_empty() => filter
+                    .modules
+                    .push((module.to_owned(), pri.trim().parse()?)),
+                (Some(pri), None) => filter.default = pri.parse()?,
+                _ => return Err(Error::InvalidFilter(entry.to_owned())),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+// Memory shared with every process forked after `init`, holding the current filter.
+struct SharedFilter {
+    mem: MemoryMapping,
+    // The generation of the filter this process last read or wrote.
+    generation: u32,
+}
+
+impl SharedFilter {
+    fn new() -> Option<SharedFilter> {
+        Some(SharedFilter {
+            mem: MemoryMapping::new(FILTER_OFFSET + MAX_FILTER_LEN).ok()?,
+            generation: 0,
+        })
+    }
+
+    fn shared_generation(&self) -> &AtomicU32 {
+        // Safe because the mapping is page aligned, large enough, and lives as long as `self`. The
+        // generation is only ever accessed atomically.
+        unsafe { &*(self.mem.as_ptr().add(FILTER_GENERATION_OFFSET) as *const AtomicU32) }
+    }
+
+    // Returns the filter another process set since this one last looked, if there is one.
+    fn read(&mut self) -> Option<LogFilter> {
+        let generation = self.shared_generation().load(Ordering::Acquire);
+        // Until the writer is done, keep using the last filter.
+        if generation == self.generation || generation % 2 == 1 {
+            return None;
+        }
+
+        let mut buf = [0u8; MAX_FILTER_LEN];
+        let len = (self.mem.read_obj::<u32>(FILTER_LEN_OFFSET).ok()? as usize).min(MAX_FILTER_LEN);
+        self.mem.read_slice(&mut buf[..len], FILTER_OFFSET).ok()?;
+        fence(Ordering::Acquire);
+        if self.shared_generation().load(Ordering::Relaxed) != generation {
+            return None;
+        }
+
+        self.generation = generation;
+        std::str::from_utf8(&buf[..len])
+            .ok()
+            .and_then(|s| s.parse().ok())
+    }
+
+    fn write(&mut self, spec: &str) {
+        let generation = self.shared_generation();
+        let next = generation.load(Ordering::Relaxed).wrapping_add(1);
+        generation.store(next, Ordering::Relaxed);
+        fence(Ordering::Release);
+        // Neither write can fail, because the mapping is large enough for any filter.
+        let _ = self.mem.write_obj(spec.len() as u32, FILTER_LEN_OFFSET);
+        let _ = self.mem.write_slice(spec.as_bytes(), FILTER_OFFSET);
+        generation.store(next.wrapping_add(1), Ordering::Release);
+        self.generation = next.wrapping_add(1);
+    }
+}
+
 struct State {
     stderr: bool,
     file: Option<File>,
     proc_name: Option<String>,
+    tag: Option<String>,
     syslog: PlatformSyslog,
+    filter: LogFilter,
+    // Without the shared memory, filters only apply to the process that sets them.
+    shared_filter: Option<SharedFilter>,
 }
 
 impl State {
@@ -146,9 +309,23 @@ impl State {
             stderr: true,
             file: None,
             proc_name: get_proc_name(),
+            tag: None,
             syslog: PlatformSyslog::new()?,
+            filter: LogFilter::default(),
+            shared_filter: SharedFilter::new(),
         })
     }
+
+    fn set_filter(&mut self, spec: &str) -> Result<(), Error> {
+        if spec.len() > MAX_FILTER_LEN {
+            return Err(Error::FilterTooLong);
+        }
+        self.filter = spec.parse()?;
+        if let Some(shared_filter) = &mut self.shared_filter {
+            shared_filter.write(spec);
+        }
+        Ok(())
+    }
 }
 
 static STATE_ONCE: Once = Once::new();
@@ -217,6 +394,25 @@ pub fn set_proc_name<T: Into<String>>(proc_name: T) {
     state.proc_name = Some(proc_name.into());
 }
 
+/// Sets a tag that is added to every message recorded by this process, such as the name of the
+/// device a sandboxed device process runs.
+///
+/// Does nothing if syslog was never initialized.
+pub fn set_tag<T: Into<String>>(tag: T) {
+    let mut state = lock!();
+    state.tag = Some(tag.into());
+}
+
+/// Replaces the filter deciding which messages are recorded, in this process and in every process
+/// forked from it after `init`. See `LogFilter` for the syntax of `filter`.
+///
+/// Returns an error if syslog was never initialized or `filter` is invalid, in which case the
+/// current filter is kept.
+pub fn set_filter(filter: &str) -> Result<(), Error> {
+    let mut state = lock()?;
+    state.set_filter(filter)
+}
+
 pub(crate) trait Syslog {
     fn new() -> Result<Self, Error>
     where
@@ -324,6 +520,22 @@ pub fn push_descriptors(descriptors: &mut Vec<RawDescriptor>) {
 /// * `file_line` - Optional tuple of the name of the file that generated the
 ///                 log and the line number within that file.
 /// * `args` - The log's message to record, in the form of `format_args!()`  return value
+pub fn log(pri: Priority, fac: Facility, file_line: Option<(&str, u32)>, args: fmt::Arguments) {
+    log_module(None, pri, fac, file_line, args)
+}
+
+/// Records a log message with the given details, if the log filter lets messages of its priority
+/// from `module_path` through.
+///
+/// Note that this will fail silently if syslog was not initialized.
+///
+/// # Arguments
+/// * `module_path` - The path of the module that generated the log, as given by `module_path!()`.
+/// * `pri` - The `Priority` (i.e. severity) of the log message.
+/// * `fac` - The `Facility` of the log message. Usually `Facility::User` should be used.
+/// * `file_line` - Optional tuple of the name of the file that generated the
+///                 log and the line number within that file.
+/// * `args` - The log's message to record, in the form of `format_args!()`  return value
 ///
 /// # Examples
 ///
@@ -333,13 +545,49 @@ pub fn push_descriptors(descriptors: &mut Vec<RawDescriptor>) {
 /// #     println!("failed to initiailize syslog: {}", e);
 /// #     return;
 /// # }
-/// syslog::log(syslog::Priority::Error,
-///             syslog::Facility::User,
-///             Some((file!(), line!())),
-///             format_args!("hello syslog"));
+/// syslog::log_module(Some(module_path!()),
+///                    syslog::Priority::Error,
+///                    syslog::Facility::User,
+///                    Some((file!(), line!())),
+///                    format_args!("hello syslog"));
 /// ```
-pub fn log(pri: Priority, fac: Facility, file_line: Option<(&str, u32)>, args: fmt::Arguments) {
+pub fn log_module(
+    module_path: Option<&str>,
+    pri: Priority,
+    fac: Facility,
+    file_line: Option<(&str, u32)>,
+    args: fmt::Arguments,
+) {
     let mut state = lock!();
+    if let Some(filter) = state.shared_filter.as_mut().and_then(SharedFilter::read) {
+        state.filter = filter;
+    }
+    if !state.filter.enabled(module_path, pri) {
+        return;
+    }
+
+    match state.tag.take() {
+        Some(tag) => {
+            record(
+                &mut state,
+                pri,
+                fac,
+                file_line,
+                format_args!("[{}] {}", tag, args),
+            );
+            state.tag = Some(tag);
+        }
+        None => record(&mut state, pri, fac, file_line, args),
+    }
+}
+
+fn record(
+    state: &mut State,
+    pri: Priority,
+    fac: Facility,
+    file_line: Option<(&str, u32)>,
+    args: fmt::Arguments,
+) {
     let mut buf = [0u8; 1024];
 
     state.syslog.log(
@@ -376,7 +624,7 @@ pub fn log(pri: Priority, fac: Facility, file_line: Option<(&str, u32)>, args: f
 #[macro_export]
 macro_rules! log {
     ($pri:expr, $($args:tt)+) => ({
-        $crate::syslog::log($pri, $crate::syslog::Facility::User, Some((file!(), line!())), format_args!($($args)+))
+        $crate::syslog::log_module(Some(module_path!()), $pri, $crate::syslog::Facility::User, Some((file!(), line!())), format_args!($($args)+))
     })
 }
 
@@ -545,6 +793,56 @@ mod tests {
         debug!("this is debug info {:?}", Some("helpful stuff"));
     }
 
+    #[test]
+    fn parse_filter() {
+        let filter: LogFilter = "warning, devices::virtio::net=debug,devices=error"
+            .parse()
+            .unwrap();
+        assert_eq!(filter.default, Priority::Warning);
+        assert_eq!(
+            filter.modules,
+            vec![
+                ("devices::virtio::net".to_owned(), Priority::Debug),
+                ("devices".to_owned(), Priority::Error),
+            ]
+        );
+        assert_eq!("".parse::<LogFilter>().unwrap(), LogFilter::default());
+        assert!("loud".parse::<LogFilter>().is_err());
+        assert!("=info".parse::<LogFilter>().is_err());
+        assert!("devices=loud".parse::<LogFilter>().is_err());
+    }
+
+    #[test]
+    fn filter_most_specific_module() {
+        let filter: LogFilter = "warning,devices::virtio=info,devices::virtio::net=debug"
+            .parse()
+            .unwrap();
+        assert!(filter.enabled(Some("devices::virtio::net"), Priority::Debug));
+        assert!(filter.enabled(Some("devices::virtio::net::worker"), Priority::Debug));
+        assert!(!filter.enabled(Some("devices::virtio::block"), Priority::Debug));
+        assert!(filter.enabled(Some("devices::virtio::block"), Priority::Info));
+        // Only whole module names match.
+        assert!(!filter.enabled(Some("devices::virtio_extra"), Priority::Info));
+        assert!(!filter.enabled(None, Priority::Info));
+        assert!(filter.enabled(None, Priority::Error));
+    }
+
+    #[test]
+    fn shared_filter() {
+        let mut writer = SharedFilter::new().unwrap();
+        writer.write("error,devices=info");
+        // The writer doesn't read back its own filter.
+        assert_eq!(writer.read(), None);
+
+        // A forked process sees the same memory, but hasn't read the filter yet.
+        let mut reader = SharedFilter {
+            mem: writer.mem,
+            generation: 0,
+        };
+        assert_eq!(reader.read(), Some("error,devices=info".parse().unwrap()));
+        assert_eq!(reader.read(), None);
+    }
+
     #[test]
     fn syslogger_char() {
         init().unwrap();
@@ -2,16 +2,23 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::ffi::{CStr, OsStr};
+use std::fs::{File, OpenOptions};
 use std::io::Stdin;
 use std::mem::zeroed;
-use std::os::unix::io::RawFd;
+use std::os::raw::c_char;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
 
 use libc::{
-    isatty, read, tcgetattr, tcsetattr, termios, ECHO, ICANON, ISIG, O_NONBLOCK, STDIN_FILENO,
-    TCSANOW,
+    cfmakeraw, grantpt, ioctl, isatty, posix_openpt, ptsname_r, read, tcgetattr, tcsetattr,
+    termios, unlockpt, winsize, ECHO, ICANON, ISIG, O_CLOEXEC, O_NOCTTY, O_NONBLOCK, O_RDWR,
+    STDIN_FILENO, TCSANOW, TIOCGWINSZ,
 };
 
-use crate::{add_fd_flags, clear_fd_flags, errno_result, Result};
+use crate::{add_fd_flags, clear_fd_flags, errno_result, Error, Result};
 
 fn modify_mode<F: FnOnce(&mut termios)>(fd: RawFd, f: F) -> Result<()> {
     // Safe because we check the return value of isatty.
@@ -86,6 +93,18 @@ pub unsafe trait Terminal {
             clear_fd_flags(self.tty_fd(), O_NONBLOCK)
         }
     }
+
+    /// Gets the size of the terminal window, in columns and rows.
+    fn window_size(&self) -> Result<(u16, u16)> {
+        // Safe because winsize is plain data, the kernel writes no more than its size, and we
+        // check the return value.
+        let mut ws: winsize = unsafe { zeroed() };
+        let ret = unsafe { ioctl(self.tty_fd(), TIOCGWINSZ, &mut ws as *mut winsize) };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok((ws.ws_col, ws.ws_row))
+    }
 }
 
 // Safe because we return a genuine terminal fd that never changes and shares our lifetime.
@@ -94,3 +113,131 @@ unsafe impl Terminal for Stdin {
         STDIN_FILENO
     }
 }
+
+/// A pseudoterminal pair.
+///
+/// The terminal side is kept open for as long as the `Pty` is, so that reads from the controlling
+/// side block instead of failing while no other process has the terminal open.
+pub struct Pty {
+    controller: File,
+    terminal: File,
+    path: PathBuf,
+}
+
+impl Pty {
+    /// Allocates a new pseudoterminal and puts its terminal side in raw mode.
+    pub fn new() -> Result<Pty> {
+        // Safe because posix_openpt doesn't take any pointers and we check the return value.
+        let fd = unsafe { posix_openpt(O_RDWR | O_NOCTTY | O_CLOEXEC) };
+        if fd < 0 {
+            return errno_result();
+        }
+        // Safe because we own the fd that was just returned.
+        let controller = unsafe { File::from_raw_fd(fd) };
+
+        // Safe because these only operate on the valid fd and we check the return values.
+        if unsafe { grantpt(fd) } < 0 || unsafe { unlockpt(fd) } < 0 {
+            return errno_result();
+        }
+
+        let mut name = [0 as c_char; 128];
+        // Safe because ptsname_r writes at most `name.len()` bytes and we check the return value.
+        let ret = unsafe { ptsname_r(fd, name.as_mut_ptr(), name.len()) };
+        if ret != 0 {
+            return Err(Error::new(ret));
+        }
+        // Safe because ptsname_r succeeded, so `name` holds a nul terminated string.
+        let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+        let path = PathBuf::from(OsStr::from_bytes(name.to_bytes()));
+
+        let terminal = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(O_NOCTTY | O_CLOEXEC)
+            .open(&path)?;
+        // Without raw mode, the guest's output would be echoed back to it as input.
+        // Safe because cfmakeraw only modifies the termios it is given.
+        modify_mode(terminal.as_raw_fd(), |t| unsafe { cfmakeraw(t) })?;
+
+        Ok(Pty {
+            controller,
+            terminal,
+            path,
+        })
+    }
+
+    /// Returns the path of the terminal side, for other processes to open.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the controlling side, which exchanges data with the process that has the terminal
+    /// open.
+    pub fn controller(&self) -> &File {
+        &self.controller
+    }
+
+    /// Returns the terminal side opened by this `Pty`.
+    pub fn terminal(&self) -> &File {
+        &self.terminal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn pty_round_trip() {
+        let pty = Pty::new().unwrap();
+        assert!(pty.path().starts_with("/dev/pts"));
+
+        let mut terminal = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(pty.path())
+            .unwrap();
+        let mut controller = pty.controller();
+        // Raw mode passes bytes through untranslated and without echo.
+        terminal.write_all(b"abc\r").unwrap();
+        let mut buf = [0u8; 4];
+        controller.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"abc\r");
+
+        controller.write_all(b"xyz\n").unwrap();
+        terminal.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"xyz\n");
+    }
+
+    struct PtyTerminal(File);
+
+    // Safe because the fd is the open terminal side of a pty, owned by the struct.
+    unsafe impl Terminal for PtyTerminal {
+        fn tty_fd(&self) -> RawFd {
+            self.0.as_raw_fd()
+        }
+    }
+
+    #[test]
+    fn window_size() {
+        let pty = Pty::new().unwrap();
+        let terminal = PtyTerminal(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(pty.path())
+                .unwrap(),
+        );
+        let ws = winsize {
+            ws_row: 24,
+            ws_col: 80,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        // Safe because the kernel only reads the winsize.
+        let ret = unsafe { ioctl(terminal.tty_fd(), libc::TIOCSWINSZ, &ws as *const winsize) };
+        assert_eq!(ret, 0);
+        assert_eq!(terminal.window_size().unwrap(), (80, 24));
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Lightweight tracing of timed spans in the Chrome trace event format.
+//!
+//! Tracing is disabled, and `trace_event!` spans cost a single atomic load, until `init` is given
+//! an output file. Each span is then written to the file as one complete ("X") event when it ends.
+//! The file is shared with child processes as long as its descriptor is kept open across jails
+//! (see `push_descriptors`), so the spans of jailed device processes land in the same trace. The
+//! output is a JSON array without its closing bracket, which `chrome://tracing` and Perfetto
+//! accept as is.
+//!
+//! # Examples
+//!
+//! ```
+//! use sys_util::trace_event;
+//!
+//! fn process_queue() {
+//!     let _trace = trace_event!(virtio, "process_queue");
+//!     // The span ends when `_trace` is dropped.
+//! }
+//! ```
+
+use std::fs::File;
+use std::io::{self, Cursor, Write};
+use std::mem::MaybeUninit;
+use std::os::unix::io::IntoRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use libc::{clock_gettime, close, dup2, timespec, CLOCK_MONOTONIC};
+
+use crate::{getpid, gettid, RawDescriptor};
+
+// Descriptor of the trace output file, or -1 if tracing is disabled.
+static TRACE_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// Starts writing trace events to `file`, replacing any previous output file.
+///
+/// The file should be opened in append mode so that events written concurrently by several
+/// processes don't overwrite each other.
+pub fn init(mut file: File) -> io::Result<()> {
+    file.write_all(b"[\n")?;
+    let fd = file.into_raw_fd();
+    let old_fd = match TRACE_FD.compare_exchange(-1, fd, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => return Ok(()),
+        Err(old_fd) => old_fd,
+    };
+    // Spans ending on other threads may be writing to the old descriptor right now, so it is never
+    // closed. Instead `dup2` atomically makes it refer to the new file.
+    // Safe because we own both descriptors, and `fd` isn't used after it is closed.
+    let ret = unsafe { dup2(fd, old_fd) };
+    let res = if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    };
+    unsafe { close(fd) };
+    res
+}
+
+/// Returns true if trace events are being recorded.
+#[inline(always)]
+pub fn enabled() -> bool {
+    TRACE_FD.load(Ordering::Relaxed) >= 0
+}
+
+/// Retrieves the descriptor of the trace output file, if tracing is enabled, so it can be kept
+/// open in jailed child processes.
+pub fn push_descriptors(descriptors: &mut Vec<RawDescriptor>) {
+    let fd = TRACE_FD.load(Ordering::Acquire);
+    if fd >= 0 {
+        descriptors.push(fd);
+    }
+}
+
+// Returns the current CLOCK_MONOTONIC time in microseconds, the time base shared by all processes.
+fn now_us() -> u64 {
+    let mut ts = MaybeUninit::<timespec>::uninit();
+    // Safe because the kernel only writes to `ts` and CLOCK_MONOTONIC is always available.
+    let ts = unsafe {
+        clock_gettime(CLOCK_MONOTONIC, ts.as_mut_ptr());
+        ts.assume_init()
+    };
+    ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000
+}
+
+/// A span recorded as a trace event when it is dropped. Created by `trace_event!`.
+pub struct TraceEventScope {
+    category: &'static str,
+    name: &'static str,
+    start_us: Option<u64>,
+}
+
+impl TraceEventScope {
+    /// Starts a span, unless tracing is disabled.
+    pub fn new(category: &'static str, name: &'static str) -> TraceEventScope {
+        TraceEventScope {
+            category,
+            name,
+            start_us: if enabled() { Some(now_us()) } else { None },
+        }
+    }
+}
+
+impl Drop for TraceEventScope {
+    fn drop(&mut self) {
+        let start_us = match self.start_us {
+            Some(start_us) => start_us,
+            None => return,
+        };
+        let fd = TRACE_FD.load(Ordering::Acquire);
+        if fd < 0 {
+            return;
+        }
+
+        let mut buf = [0u8; 256];
+        let mut cursor = Cursor::new(&mut buf[..]);
+        let res = writeln!(
+            &mut cursor,
+            "{{\"cat\":{:?},\"name\":{:?},\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":{},\"tid\":{}}},",
+            self.category,
+            self.name,
+            start_us,
+            now_us().saturating_sub(start_us),
+            getpid(),
+            gettid(),
+        );
+        if res.is_ok() {
+            let len = cursor.position() as usize;
+            // Safe because `buf` outlives the call and we only write `len` initialized bytes from
+            // it. A single write keeps events from concurrent writers from interleaving.
+            unsafe { libc::write(fd, buf.as_ptr() as *const libc::c_void, len) };
+        }
+    }
+}
+
+/// Starts a span named `$name` in `$category` that lasts until the returned guard is dropped.
+///
+/// The guard must be bound to a named variable, e.g. `let _trace = trace_event!(...)`; binding
+/// it to `_` ends the span immediately.
+#[macro_export]
+macro_rules! trace_event {
+    ($category:ident, $name:expr) => {
+        $crate::trace_event::TraceEventScope::new(stringify!($category), $name)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::{Read, Seek, SeekFrom};
+
+    #[test]
+    fn trace_to_file() {
+        assert!(TraceEventScope::new("test", "disabled").start_us.is_none());
+
+        let mut output = tempfile::tempfile().unwrap();
+        init(output.try_clone().unwrap()).unwrap();
+        assert!(enabled());
+        {
+            let _trace = trace_event!(test, "span");
+        }
+        init(OpenOptions::new().write(true).open("/dev/null").unwrap()).unwrap();
+
+        let mut contents = String::new();
+        output.seek(SeekFrom::Start(0)).unwrap();
+        output.read_to_string(&mut contents).unwrap();
+        let mut lines = contents.lines();
+        assert_eq!(lines.next(), Some("["));
+        let event = lines.next().unwrap();
+        assert!(event.starts_with("{\"cat\":\"test\",\"name\":\"span\",\"ph\":\"X\",\"ts\":"));
+        assert!(event.ends_with("},"));
+        assert_eq!(lines.next(), None);
+    }
+}
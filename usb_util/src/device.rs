@@ -383,10 +383,29 @@ impl Transfer {
         Self::new(usb_sys::USBDEVFS_URB_TYPE_BULK, endpoint, buffer, &[])
     }
 
-    /// Create an isochronous transfer.
-    pub fn new_isochronous(endpoint: u8, buffer: Vec<u8>) -> Result<Transfer> {
-        // TODO(dverkamp): allow user to specify iso descriptors
-        Self::new(usb_sys::USBDEVFS_URB_TYPE_ISO, endpoint, buffer, &[])
+    /// Create an isochronous transfer made of one packet per entry in `packet_lengths`, which
+    /// should add up to the length of `buffer`. The transfer is scheduled as soon as possible.
+    pub fn new_isochronous(
+        endpoint: u8,
+        buffer: Vec<u8>,
+        packet_lengths: &[u32],
+    ) -> Result<Transfer> {
+        let iso_packets: Vec<usb_sys::usbdevfs_iso_packet_desc> = packet_lengths
+            .iter()
+            .map(|&length| usb_sys::usbdevfs_iso_packet_desc {
+                length,
+                ..Default::default()
+            })
+            .collect();
+        let mut transfer = Self::new(
+            usb_sys::USBDEVFS_URB_TYPE_ISO,
+            endpoint,
+            buffer,
+            &iso_packets,
+        )?;
+        transfer.urb_mut().flags |= usb_sys::USBDEVFS_URB_ISO_ASAP;
+        transfer.urb_mut().number_of_packets_or_stream_id = iso_packets.len() as u32;
+        Ok(transfer)
     }
 
     /// Get the status of a completed transfer.
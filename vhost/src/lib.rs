@@ -3,10 +3,14 @@
 // found in the LICENSE file.
 
 pub mod net;
+mod scsi;
+mod user;
 mod vsock;
 
 pub use crate::net::Net;
 pub use crate::net::NetT;
+pub use crate::scsi::{Scsi, VHOST_SCSI_ABI_VERSION};
+pub use crate::user::*;
 pub use crate::vsock::Vsock;
 
 use std::alloc::Layout;
@@ -17,7 +21,7 @@ use std::ptr::null;
 
 use assertions::const_assert;
 use base::{ioctl, ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_ref};
-use base::{AsRawDescriptor, Event, LayoutAllocation};
+use base::{AsRawDescriptor, Error as SysError, Event, LayoutAllocation};
 use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
 
 #[derive(Debug)]
@@ -36,6 +40,20 @@ pub enum Error {
     AvailAddress(GuestMemoryError),
     /// Invalid log address.
     LogAddress(GuestMemoryError),
+    /// The vhost-scsi target name does not fit in the ioctl argument.
+    InvalidScsiWwpn,
+    /// Failed to connect to the vhost-user backend.
+    VhostUserConnect(IoError),
+    /// The vhost-user backend sent a malformed reply.
+    VhostUserInvalidReply,
+    /// The vhost-user backend failed a request.
+    VhostUserRequestFailed(u32, u64),
+    /// Failed to send or receive a vhost-user message.
+    VhostUserSocket(SysError),
+    /// The guest memory has more regions than a vhost-user backend accepts.
+    VhostUserTooManyRegions(usize),
+    /// The vhost-user backend doesn't support a request.
+    VhostUserUnsupported(&'static str),
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -51,6 +69,21 @@ impl Display for Error {
             UsedAddress(e) => write!(f, "invalid used address: {}", e),
             AvailAddress(e) => write!(f, "invalid available address: {}", e),
             LogAddress(e) => write!(f, "invalid log address: {}", e),
+            InvalidScsiWwpn => write!(f, "invalid vhost-scsi target name"),
+            VhostUserConnect(e) => write!(f, "failed to connect to vhost-user backend: {}", e),
+            VhostUserInvalidReply => write!(f, "invalid reply from vhost-user backend"),
+            VhostUserRequestFailed(request, status) => write!(
+                f,
+                "vhost-user backend failed request {} with status {}",
+                request, status
+            ),
+            VhostUserSocket(e) => write!(f, "vhost-user socket error: {}", e),
+            VhostUserTooManyRegions(n) => {
+                write!(f, "too many guest memory regions for vhost-user: {}", n)
+            }
+            VhostUserUnsupported(request) => {
+                write!(f, "vhost-user backend does not support {}", request)
+            }
         }
     }
 }
@@ -59,6 +92,53 @@ fn ioctl_result<T>() -> Result<T> {
     Err(Error::IoctlError(IoError::last_os_error()))
 }
 
+// Checks that a vring fits in guest memory and translates its addresses to the host addresses
+// vhost expects.
+#[allow(clippy::too_many_arguments)]
+fn vring_addr<T: Vhost>(
+    vhost: &T,
+    queue_max_size: u16,
+    queue_size: u16,
+    queue_index: usize,
+    flags: u32,
+    desc_addr: GuestAddress,
+    used_addr: GuestAddress,
+    avail_addr: GuestAddress,
+    log_addr: Option<GuestAddress>,
+) -> Result<virtio_sys::vhost_vring_addr> {
+    // TODO(smbarber): Refactor out virtio from crosvm so we can
+    // validate a Queue struct directly.
+    if !vhost.is_valid(queue_max_size, queue_size, desc_addr, used_addr, avail_addr) {
+        return Err(Error::InvalidQueue);
+    }
+
+    let desc_addr = vhost
+        .mem()
+        .get_host_address(desc_addr)
+        .map_err(Error::DescriptorTableAddress)?;
+    let used_addr = vhost
+        .mem()
+        .get_host_address(used_addr)
+        .map_err(Error::UsedAddress)?;
+    let avail_addr = vhost
+        .mem()
+        .get_host_address(avail_addr)
+        .map_err(Error::AvailAddress)?;
+    let log_addr = match log_addr {
+        None => null(),
+        Some(a) => vhost.mem().get_host_address(a).map_err(Error::LogAddress)?,
+    };
+
+    Ok(virtio_sys::vhost_vring_addr {
+        index: queue_index as u32,
+        flags,
+        desc_user_addr: desc_addr as u64,
+        used_user_addr: used_addr as u64,
+        avail_user_addr: avail_addr as u64,
+        log_guest_addr: log_addr as u64,
+    })
+}
+
 /// An interface for setting up vhost-based virtio devices.  Vhost-based devices are different
 /// from regular virtio devices because the host kernel takes care of handling all the data
 /// transfer.  The device itself only needs to deal with setting up the kernel driver and
@@ -232,37 +312,17 @@ pub trait Vhost: AsRawDescriptor + std::marker::Sized {
         avail_addr: GuestAddress,
         log_addr: Option<GuestAddress>,
     ) -> Result<()> {
-        // TODO(smbarber): Refactor out virtio from crosvm so we can
-        // validate a Queue struct directly.
-        if !self.is_valid(queue_max_size, queue_size, desc_addr, used_addr, avail_addr) {
-            return Err(Error::InvalidQueue);
-        }
-
-        let desc_addr = self
-            .mem()
-            .get_host_address(desc_addr)
-            .map_err(Error::DescriptorTableAddress)?;
-        let used_addr = self
-            .mem()
-            .get_host_address(used_addr)
-            .map_err(Error::UsedAddress)?;
-        let avail_addr = self
-            .mem()
-            .get_host_address(avail_addr)
-            .map_err(Error::AvailAddress)?;
-        let log_addr = match log_addr {
-            None => null(),
-            Some(a) => self.mem().get_host_address(a).map_err(Error::LogAddress)?,
-        };
-
-        let vring_addr = virtio_sys::vhost_vring_addr {
-            index: queue_index as u32,
+        let vring_addr = vring_addr(
+            self,
+            queue_max_size,
+            queue_size,
+            queue_index,
             flags,
-            desc_user_addr: desc_addr as u64,
-            used_user_addr: used_addr as u64,
-            avail_user_addr: avail_addr as u64,
-            log_guest_addr: log_addr as u64,
-        };
+            desc_addr,
+            used_addr,
+            avail_addr,
+            log_addr,
+        )?;
 
         // This ioctl is called on a valid vhost_net fd and has its
         // return value checked.
@@ -0,0 +1,128 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fs::{File, OpenOptions};
+use std::os::raw::{c_char, c_int};
+use std::os::unix::fs::OpenOptionsExt;
+
+use base::{ioctl_with_mut_ref, ioctl_with_ref, AsRawDescriptor, RawDescriptor};
+use virtio_sys::{
+    vhost_scsi_target, VHOST_SCSI_CLEAR_ENDPOINT, VHOST_SCSI_GET_ABI_VERSION,
+    VHOST_SCSI_SET_ENDPOINT,
+};
+use vm_memory::GuestMemory;
+
+use super::{ioctl_result, Error, Result, Vhost};
+
+static DEVICE: &str = "/dev/vhost-scsi";
+
+/// The vhost-scsi ABI version understood by this crate.
+pub const VHOST_SCSI_ABI_VERSION: c_int = 1;
+
+/// Handle for running VHOST_SCSI ioctls.
+pub struct Scsi {
+    descriptor: File,
+    mem: GuestMemory,
+}
+
+impl Scsi {
+    /// Open a handle to a new VHOST_SCSI instance.
+    pub fn new(mem: &GuestMemory) -> Result<Scsi> {
+        Ok(Scsi {
+            descriptor: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
+                .open(DEVICE)
+                .map_err(Error::VhostOpen)?,
+            mem: mem.clone(),
+        })
+    }
+
+    /// Get the vhost-scsi ABI version implemented by the host kernel.
+    pub fn get_abi_version(&self) -> Result<c_int> {
+        let mut version: c_int = 0;
+        // This ioctl is called on a valid vhost-scsi fd and has its return value checked.
+        let ret = unsafe {
+            ioctl_with_mut_ref(&self.descriptor, VHOST_SCSI_GET_ABI_VERSION(), &mut version)
+        };
+        if ret < 0 {
+            return ioctl_result();
+        }
+        Ok(version)
+    }
+
+    /// Bind the virtqueues to the target portal group `tpgt` of the LIO target `wwpn`. Commands
+    /// the guest submits are then handled directly by the kernel target.
+    ///
+    /// # Arguments
+    /// * `wwpn` - World wide port name of the vhost target, e.g. "naa.5001405c0ffee000".
+    /// * `tpgt` - Target portal group tag under `wwpn`.
+    pub fn set_endpoint(&self, wwpn: &str, tpgt: u16) -> Result<()> {
+        let target = scsi_target(wwpn, tpgt)?;
+        // This ioctl is called on a valid vhost-scsi fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(&self.descriptor, VHOST_SCSI_SET_ENDPOINT(), &target) };
+        if ret < 0 {
+            return ioctl_result();
+        }
+        Ok(())
+    }
+
+    /// Unbind the virtqueues from the target set with `set_endpoint`.
+    pub fn clear_endpoint(&self, wwpn: &str, tpgt: u16) -> Result<()> {
+        let target = scsi_target(wwpn, tpgt)?;
+        // This ioctl is called on a valid vhost-scsi fd and has its return value checked.
+        let ret = unsafe { ioctl_with_ref(&self.descriptor, VHOST_SCSI_CLEAR_ENDPOINT(), &target) };
+        if ret < 0 {
+            return ioctl_result();
+        }
+        Ok(())
+    }
+}
+
+fn scsi_target(wwpn: &str, tpgt: u16) -> Result<vhost_scsi_target> {
+    let mut target = vhost_scsi_target {
+        abi_version: VHOST_SCSI_ABI_VERSION,
+        vhost_tpgt: tpgt,
+        ..Default::default()
+    };
+    // The name must leave room for the terminating nul.
+    if wwpn.len() >= target.vhost_wwpn.len() || wwpn.as_bytes().contains(&0) {
+        return Err(Error::InvalidScsiWwpn);
+    }
+    for (dst, &src) in target.vhost_wwpn.iter_mut().zip(wwpn.as_bytes()) {
+        *dst = src as c_char;
+    }
+    Ok(target)
+}
+
+impl Vhost for Scsi {
+    fn mem(&self) -> &GuestMemory {
+        &self.mem
+    }
+}
+
+impl AsRawDescriptor for Scsi {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.descriptor.as_raw_descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_name() {
+        let target = scsi_target("naa.5001405c0ffee000", 3).unwrap();
+        assert_eq!(target.abi_version, VHOST_SCSI_ABI_VERSION);
+        assert_eq!(target.vhost_tpgt, 3);
+        assert_eq!(target.vhost_wwpn[0], b'n' as c_char);
+        assert_eq!(target.vhost_wwpn[19], b'0' as c_char);
+        assert_eq!(target.vhost_wwpn[20], 0);
+
+        assert!(scsi_target(&"n".repeat(224), 1).is_err());
+        assert!(scsi_target("naa.1\0", 1).is_err());
+    }
+}
@@ -0,0 +1,459 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::io::{IoSlice, Read};
+use std::mem::size_of;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use base::{AsRawDescriptor, Event, RawDescriptor, ScmSocket};
+use data_model::DataInit;
+use vm_memory::{GuestAddress, GuestMemory};
+
+use super::{vring_addr, Error, Result, Vhost};
+
+// Requests of the vhost-user protocol sent by the frontend.
+const GET_FEATURES: u32 = 1;
+const SET_FEATURES: u32 = 2;
+const SET_OWNER: u32 = 3;
+const SET_MEM_TABLE: u32 = 5;
+const SET_VRING_NUM: u32 = 8;
+const SET_VRING_ADDR: u32 = 9;
+const SET_VRING_BASE: u32 = 10;
+const SET_VRING_KICK: u32 = 12;
+const SET_VRING_CALL: u32 = 13;
+const GET_PROTOCOL_FEATURES: u32 = 15;
+const SET_PROTOCOL_FEATURES: u32 = 16;
+const SET_VRING_ENABLE: u32 = 18;
+const GET_CONFIG: u32 = 24;
+
+// Flags of the message header.
+const VERSION: u32 = 0x1;
+const FLAG_REPLY: u32 = 0x4;
+const FLAG_NEED_REPLY: u32 = 0x8;
+
+/// The virtio feature bit a backend sets if it supports the vhost-user protocol features.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u32 = 30;
+
+/// Protocol feature bits understood by this frontend.
+pub const VHOST_USER_PROTOCOL_F_MQ: u32 = 0;
+pub const VHOST_USER_PROTOCOL_F_REPLY_ACK: u32 = 3;
+pub const VHOST_USER_PROTOCOL_F_CONFIG: u32 = 9;
+const SUPPORTED_PROTOCOL_FEATURES: u64 = 1 << VHOST_USER_PROTOCOL_F_MQ
+    | 1 << VHOST_USER_PROTOCOL_F_REPLY_ACK
+    | 1 << VHOST_USER_PROTOCOL_F_CONFIG;
+
+// The most memory regions a backend has to accept in SET_MEM_TABLE.
+const MAX_MEM_REGIONS: usize = 8;
+// The largest config space GET_CONFIG can transfer.
+const MAX_CONFIG_SIZE: usize = 256;
+// Set in the payload of SET_VRING_KICK and SET_VRING_CALL if no descriptor is sent.
+const VRING_NOFD_MASK: u64 = 0x100;
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct MsgHeader {
+    request: u32,
+    flags: u32,
+    size: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for MsgHeader {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct VringState {
+    index: u32,
+    num: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for VringState {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct MemoryRegion {
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+    mmap_offset: u64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for MemoryRegion {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct ConfigHeader {
+    offset: u32,
+    size: u32,
+    flags: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for ConfigHeader {}
+
+/// Handle for a device backend in another process, e.g. SPDK, reached over a vhost-user socket.
+///
+/// The backend maps the guest memory from the shared memory crosvm sends it, and is kicked and
+/// calls the guest through the events of each queue, like the vhost drivers of the host kernel.
+pub struct VhostUser {
+    socket: UnixStream,
+    mem: GuestMemory,
+    features: u64,
+    protocol_features: u64,
+}
+
+impl VhostUser {
+    /// Connect to the backend listening on the socket at `path` and negotiate the protocol
+    /// features both sides support.
+    pub fn connect<P: AsRef<Path>>(path: P, mem: &GuestMemory) -> Result<VhostUser> {
+        let socket = UnixStream::connect(path).map_err(Error::VhostUserConnect)?;
+        VhostUser::from_socket(socket, mem)
+    }
+
+    fn from_socket(socket: UnixStream, mem: &GuestMemory) -> Result<VhostUser> {
+        let mut handle = VhostUser {
+            socket,
+            mem: mem.clone(),
+            features: 0,
+            protocol_features: 0,
+        };
+        handle.features = handle.get_u64(GET_FEATURES)?;
+        if handle.features & 1 << VHOST_USER_F_PROTOCOL_FEATURES != 0 {
+            let protocol_features =
+                handle.get_u64(GET_PROTOCOL_FEATURES)? & SUPPORTED_PROTOCOL_FEATURES;
+            handle.send(SET_PROTOCOL_FEATURES, protocol_features.as_slice(), &[])?;
+            handle.protocol_features = protocol_features;
+        }
+        Ok(handle)
+    }
+
+    /// Returns true if the backend agreed to use protocol feature `bit`.
+    pub fn has_protocol_feature(&self, bit: u32) -> bool {
+        self.protocol_features & 1 << bit != 0
+    }
+
+    /// Read `size` bytes of the device's config space, starting at `offset`. At most 256 bytes
+    /// can be read at once.
+    pub fn get_config(&self, offset: u32, size: u32) -> Result<Vec<u8>> {
+        if !self.has_protocol_feature(VHOST_USER_PROTOCOL_F_CONFIG) {
+            return Err(Error::VhostUserUnsupported("GET_CONFIG"));
+        }
+        let header = ConfigHeader {
+            offset,
+            size,
+            flags: 0,
+        };
+        let mut payload = header.as_slice().to_vec();
+        payload.resize(payload.len() + size as usize, 0);
+        self.send_request(GET_CONFIG, &payload, &[], false)?;
+
+        let reply = self.recv_reply(GET_CONFIG)?;
+        let data = reply
+            .get(size_of::<ConfigHeader>()..)
+            .filter(|data| data.len() == size as usize)
+            .ok_or(Error::VhostUserInvalidReply)?;
+        Ok(data.to_vec())
+    }
+
+    /// Start or stop the backend processing queue `queue_index`. Once the protocol features are
+    /// negotiated, the backend only processes a queue after it's enabled.
+    pub fn set_vring_enable(&self, queue_index: usize, enable: bool) -> Result<()> {
+        if self.features & 1 << VHOST_USER_F_PROTOCOL_FEATURES == 0 {
+            return Ok(());
+        }
+        let state = VringState {
+            index: queue_index as u32,
+            num: enable as u32,
+        };
+        self.send(SET_VRING_ENABLE, state.as_slice(), &[])
+    }
+
+    fn get_u64(&self, request: u32) -> Result<u64> {
+        self.send_request(request, &[], &[], false)?;
+        let reply = self.recv_reply(request)?;
+        u64::from_slice(&reply)
+            .copied()
+            .ok_or(Error::VhostUserInvalidReply)
+    }
+
+    // Sends a request that has no reply of its own, and waits for the backend to acknowledge it
+    // if it supports that.
+    fn send(&self, request: u32, payload: &[u8], fds: &[RawDescriptor]) -> Result<()> {
+        let need_reply = self.has_protocol_feature(VHOST_USER_PROTOCOL_F_REPLY_ACK);
+        self.send_request(request, payload, fds, need_reply)?;
+        if need_reply {
+            let reply = self.recv_reply(request)?;
+            match u64::from_slice(&reply).copied() {
+                Some(0) => {}
+                Some(status) => return Err(Error::VhostUserRequestFailed(request, status)),
+                None => return Err(Error::VhostUserInvalidReply),
+            }
+        }
+        Ok(())
+    }
+
+    fn send_request(
+        &self,
+        request: u32,
+        payload: &[u8],
+        fds: &[RawDescriptor],
+        need_reply: bool,
+    ) -> Result<()> {
+        let header = MsgHeader {
+            request,
+            flags: if need_reply {
+                VERSION | FLAG_NEED_REPLY
+            } else {
+                VERSION
+            },
+            size: payload.len() as u32,
+        };
+        let bufs = [IoSlice::new(header.as_slice()), IoSlice::new(payload)];
+        let len = self
+            .socket
+            .send_with_fds(&bufs, fds)
+            .map_err(Error::VhostUserSocket)?;
+        if len != size_of::<MsgHeader>() + payload.len() {
+            return Err(Error::VhostUserInvalidReply);
+        }
+        Ok(())
+    }
+
+    fn recv_reply(&self, request: u32) -> Result<Vec<u8>> {
+        let mut socket = &self.socket;
+        let mut header = MsgHeader::default();
+        socket
+            .read_exact(header.as_mut_slice())
+            .map_err(|e| Error::VhostUserSocket(e.into()))?;
+        if header.request != request
+            || header.flags & FLAG_REPLY == 0
+            || header.size as usize > size_of::<ConfigHeader>() + MAX_CONFIG_SIZE
+        {
+            return Err(Error::VhostUserInvalidReply);
+        }
+        let mut payload = vec![0u8; header.size as usize];
+        socket
+            .read_exact(&mut payload)
+            .map_err(|e| Error::VhostUserSocket(e.into()))?;
+        Ok(payload)
+    }
+
+    fn send_vring_fd(&self, request: u32, queue_index: usize, event: &Event) -> Result<()> {
+        let index = queue_index as u64 & !VRING_NOFD_MASK;
+        self.send(request, index.as_slice(), &[event.as_raw_descriptor()])
+    }
+}
+
+impl Vhost for VhostUser {
+    fn mem(&self) -> &GuestMemory {
+        &self.mem
+    }
+
+    fn set_owner(&self) -> Result<()> {
+        self.send(SET_OWNER, &[], &[])
+    }
+
+    fn get_features(&self) -> Result<u64> {
+        self.get_u64(GET_FEATURES)
+    }
+
+    fn set_features(&self, features: u64) -> Result<()> {
+        // The protocol features stay in effect only if this bit is acked along with the device's
+        // features.
+        let features = features | self.features & 1 << VHOST_USER_F_PROTOCOL_FEATURES;
+        self.send(SET_FEATURES, features.as_slice(), &[])
+    }
+
+    fn set_mem_table(&self) -> Result<()> {
+        let (shared_regions, shms) = self.mem.shared_regions();
+        if shared_regions.len() > MAX_MEM_REGIONS {
+            return Err(Error::VhostUserTooManyRegions(shared_regions.len()));
+        }
+
+        let mut regions = Vec::with_capacity(shared_regions.len());
+        let mut fds = Vec::with_capacity(shared_regions.len());
+        // The backend translates the vring addresses, which are crosvm's own addresses, with
+        // userspace_addr.
+        let _ = self
+            .mem
+            .with_regions::<_, ()>(|index, guest_addr, size, host_addr, _| {
+                let shared = &shared_regions[index];
+                regions.push(MemoryRegion {
+                    guest_phys_addr: guest_addr.offset(),
+                    memory_size: size as u64,
+                    userspace_addr: host_addr as u64,
+                    mmap_offset: shared.shm_offset,
+                });
+                fds.push(shms[shared.shm_index].as_raw_descriptor());
+                Ok(())
+            });
+
+        let mut payload = (regions.len() as u32).as_slice().to_vec();
+        // Padding before the regions.
+        payload.extend_from_slice(&[0u8; 4]);
+        for region in &regions {
+            payload.extend_from_slice(region.as_slice());
+        }
+        self.send(SET_MEM_TABLE, &payload, &fds)
+    }
+
+    fn set_vring_num(&self, queue_index: usize, num: u16) -> Result<()> {
+        let state = VringState {
+            index: queue_index as u32,
+            num: num as u32,
+        };
+        self.send(SET_VRING_NUM, state.as_slice(), &[])
+    }
+
+    fn set_vring_addr(
+        &self,
+        queue_max_size: u16,
+        queue_size: u16,
+        queue_index: usize,
+        flags: u32,
+        desc_addr: GuestAddress,
+        used_addr: GuestAddress,
+        avail_addr: GuestAddress,
+        log_addr: Option<GuestAddress>,
+    ) -> Result<()> {
+        let vring_addr = vring_addr(
+            self,
+            queue_max_size,
+            queue_size,
+            queue_index,
+            flags,
+            desc_addr,
+            used_addr,
+            avail_addr,
+            log_addr,
+        )?;
+        // Same layout as struct vhost_vring_addr.
+        let mut payload = Vec::with_capacity(40);
+        payload.extend_from_slice(&vring_addr.index.to_ne_bytes());
+        payload.extend_from_slice(&vring_addr.flags.to_ne_bytes());
+        payload.extend_from_slice(&vring_addr.desc_user_addr.to_ne_bytes());
+        payload.extend_from_slice(&vring_addr.used_user_addr.to_ne_bytes());
+        payload.extend_from_slice(&vring_addr.avail_user_addr.to_ne_bytes());
+        payload.extend_from_slice(&vring_addr.log_guest_addr.to_ne_bytes());
+        self.send(SET_VRING_ADDR, &payload, &[])
+    }
+
+    fn set_vring_base(&self, queue_index: usize, num: u16) -> Result<()> {
+        let state = VringState {
+            index: queue_index as u32,
+            num: num as u32,
+        };
+        self.send(SET_VRING_BASE, state.as_slice(), &[])
+    }
+
+    fn set_vring_call(&self, queue_index: usize, event: &Event) -> Result<()> {
+        self.send_vring_fd(SET_VRING_CALL, queue_index, event)
+    }
+
+    fn set_vring_kick(&self, queue_index: usize, event: &Event) -> Result<()> {
+        self.send_vring_fd(SET_VRING_KICK, queue_index, event)
+    }
+}
+
+impl AsRawDescriptor for VhostUser {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.socket.as_raw_descriptor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::thread;
+
+    // Reads one request from the frontend and returns its header, payload and the number of
+    // descriptors sent with it.
+    fn recv_request(backend: &mut UnixStream) -> (MsgHeader, Vec<u8>, usize) {
+        let mut header = MsgHeader::default();
+        let mut fds = [0; MAX_MEM_REGIONS];
+        let (len, nfds) = backend
+            .recv_with_fds(header.as_mut_slice(), &mut fds)
+            .unwrap();
+        assert_eq!(len, size_of::<MsgHeader>());
+        let mut payload = vec![0u8; header.size as usize];
+        backend.read_exact(&mut payload).unwrap();
+        for &fd in &fds[..nfds] {
+            // Safe because the descriptors were just received and nothing else owns them.
+            unsafe { libc::close(fd) };
+        }
+        (header, payload, nfds)
+    }
+
+    fn reply(backend: &mut UnixStream, request: u32, payload: &[u8]) {
+        let header = MsgHeader {
+            request,
+            flags: VERSION | FLAG_REPLY,
+            size: payload.len() as u32,
+        };
+        backend.write_all(header.as_slice()).unwrap();
+        backend.write_all(payload).unwrap();
+    }
+
+    #[test]
+    fn negotiate_and_set_mem_table() {
+        let mem = GuestMemory::new(&[
+            (GuestAddress(0), 0x10000),
+            (GuestAddress(0x100000), 0x10000),
+        ])
+        .unwrap();
+        let (frontend, mut backend) = UnixStream::pair().unwrap();
+        let backend_thread = thread::spawn(move || {
+            let (header, _, _) = recv_request(&mut backend);
+            assert_eq!(header.request, GET_FEATURES);
+            reply(
+                &mut backend,
+                GET_FEATURES,
+                (1u64 << VHOST_USER_F_PROTOCOL_FEATURES).as_slice(),
+            );
+
+            let (header, _, _) = recv_request(&mut backend);
+            assert_eq!(header.request, GET_PROTOCOL_FEATURES);
+            // Offer everything; the frontend must only take what it supports.
+            reply(&mut backend, GET_PROTOCOL_FEATURES, (!0u64).as_slice());
+
+            let (header, payload, _) = recv_request(&mut backend);
+            assert_eq!(header.request, SET_PROTOCOL_FEATURES);
+            assert_eq!(header.flags & FLAG_NEED_REPLY, 0);
+            assert_eq!(
+                u64::from_slice(&payload).copied(),
+                Some(SUPPORTED_PROTOCOL_FEATURES)
+            );
+
+            let (header, payload, nfds) = recv_request(&mut backend);
+            assert_eq!(header.request, SET_MEM_TABLE);
+            assert_ne!(header.flags & FLAG_NEED_REPLY, 0);
+            assert_eq!(nfds, 2);
+            assert_eq!(&payload[0..4], &2u32.to_ne_bytes());
+            let second = MemoryRegion::from_slice(&payload[40..72]).unwrap();
+            assert_eq!(second.guest_phys_addr, 0x100000);
+            assert_eq!(second.memory_size, 0x10000);
+            assert_eq!(second.mmap_offset, 0x10000);
+            reply(&mut backend, SET_MEM_TABLE, 0u64.as_slice());
+
+            let (header, _, nfds) = recv_request(&mut backend);
+            assert_eq!(header.request, SET_VRING_KICK);
+            assert_eq!(nfds, 1);
+            reply(&mut backend, SET_VRING_KICK, 1u64.as_slice());
+        });
+
+        let handle = VhostUser::from_socket(frontend, &mem).unwrap();
+        assert!(handle.has_protocol_feature(VHOST_USER_PROTOCOL_F_CONFIG));
+        handle.set_mem_table().unwrap();
+        match handle.set_vring_kick(0, &Event::new().unwrap()) {
+            Err(Error::VhostUserRequestFailed(SET_VRING_KICK, 1)) => {}
+            r => panic!("unexpected result of a failed request: {:?}", r.err()),
+        }
+        backend_thread.join().unwrap();
+    }
+}
@@ -174,6 +174,9 @@ pub const VIRTIO_NET_F_CTRL_RX_EXTRA: ::std::os::raw::c_uint = 20;
 pub const VIRTIO_NET_F_GUEST_ANNOUNCE: ::std::os::raw::c_uint = 21;
 pub const VIRTIO_NET_F_MQ: ::std::os::raw::c_uint = 22;
 pub const VIRTIO_NET_F_CTRL_MAC_ADDR: ::std::os::raw::c_uint = 23;
+pub const VIRTIO_NET_F_HASH_REPORT: ::std::os::raw::c_uint = 57;
+pub const VIRTIO_NET_F_RSS: ::std::os::raw::c_uint = 60;
+pub const VIRTIO_NET_F_SPEED_DUPLEX: ::std::os::raw::c_uint = 63;
 pub const VIRTIO_NET_F_GSO: ::std::os::raw::c_uint = 6;
 pub const VIRTIO_NET_S_LINK_UP: ::std::os::raw::c_uint = 1;
 pub const VIRTIO_NET_S_ANNOUNCE: ::std::os::raw::c_uint = 2;
@@ -205,6 +208,27 @@ pub const VIRTIO_NET_CTRL_MQ: ::std::os::raw::c_uint = 4;
 pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: ::std::os::raw::c_uint = 0;
 pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN: ::std::os::raw::c_uint = 1;
 pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX: ::std::os::raw::c_uint = 32768;
+pub const VIRTIO_NET_CTRL_MQ_RSS_CONFIG: ::std::os::raw::c_uint = 1;
+pub const VIRTIO_NET_CTRL_MQ_HASH_CONFIG: ::std::os::raw::c_uint = 2;
+pub const VIRTIO_NET_RSS_HASH_TYPE_IPv4: ::std::os::raw::c_uint = 1;
+pub const VIRTIO_NET_RSS_HASH_TYPE_TCPv4: ::std::os::raw::c_uint = 2;
+pub const VIRTIO_NET_RSS_HASH_TYPE_UDPv4: ::std::os::raw::c_uint = 4;
+pub const VIRTIO_NET_RSS_HASH_TYPE_IPv6: ::std::os::raw::c_uint = 8;
+pub const VIRTIO_NET_RSS_HASH_TYPE_TCPv6: ::std::os::raw::c_uint = 16;
+pub const VIRTIO_NET_RSS_HASH_TYPE_UDPv6: ::std::os::raw::c_uint = 32;
+pub const VIRTIO_NET_RSS_HASH_TYPE_IP_EX: ::std::os::raw::c_uint = 64;
+pub const VIRTIO_NET_RSS_HASH_TYPE_TCP_EX: ::std::os::raw::c_uint = 128;
+pub const VIRTIO_NET_RSS_HASH_TYPE_UDP_EX: ::std::os::raw::c_uint = 256;
+pub const VIRTIO_NET_HASH_REPORT_NONE: ::std::os::raw::c_uint = 0;
+pub const VIRTIO_NET_HASH_REPORT_IPv4: ::std::os::raw::c_uint = 1;
+pub const VIRTIO_NET_HASH_REPORT_TCPv4: ::std::os::raw::c_uint = 2;
+pub const VIRTIO_NET_HASH_REPORT_UDPv4: ::std::os::raw::c_uint = 3;
+pub const VIRTIO_NET_HASH_REPORT_IPv6: ::std::os::raw::c_uint = 4;
+pub const VIRTIO_NET_HASH_REPORT_TCPv6: ::std::os::raw::c_uint = 5;
+pub const VIRTIO_NET_HASH_REPORT_UDPv6: ::std::os::raw::c_uint = 6;
+pub const VIRTIO_NET_HASH_REPORT_IPv6_EX: ::std::os::raw::c_uint = 7;
+pub const VIRTIO_NET_HASH_REPORT_TCPv6_EX: ::std::os::raw::c_uint = 8;
+pub const VIRTIO_NET_HASH_REPORT_UDPv6_EX: ::std::os::raw::c_uint = 9;
 pub const VIRTIO_NET_CTRL_GUEST_OFFLOADS: ::std::os::raw::c_uint = 5;
 pub const VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET: ::std::os::raw::c_uint = 0;
 pub type __s8 = ::std::os::raw::c_schar;
@@ -6,36 +6,45 @@
 //!
 //! The VM Control IPC protocol is synchronous, meaning that each `VmRequest` sent over a connection
 //! will receive a `VmResponse` for that request next time data is received over that connection.
+//! Requests that take long to complete, such as resizing a disk or dumping guest memory, instead
+//! answer with `VmResponse::OperationStarted` right away and continue in the background. Their
+//! progress can be queried, followed, or cancelled with further requests that name the operation.
 //!
 //! The wire message format is a little-endian C-struct of fixed size, along with a file descriptor
 //! if the request type expects one.
 
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
 pub mod gdb;
+mod operation;
 
+use std::cmp::min;
 use std::fmt::{self, Display};
 use std::fs::File;
 use std::io::{Seek, SeekFrom};
+use std::iter::Sum;
 use std::mem::ManuallyDrop;
+use std::ops::{Add, AddAssign};
 use std::result::Result as StdResult;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
-use libc::{EINVAL, EIO, ENODEV};
+use libc::{EBUSY, ECANCELED, EINVAL, EIO, ENODEV, ENOENT, ENOSPC};
 
 use base::{
-    error, AsRawDescriptor, Error as SysError, Event, ExternalMapping, FromRawDescriptor,
+    error, syslog, AsRawDescriptor, Error as SysError, Event, ExternalMapping, FromRawDescriptor,
     IntoRawDescriptor, MappedRegion, MemoryMappingBuilder, MmapError, RawDescriptor, Result,
-    SafeDescriptor,
+    SafeDescriptor, SharedMemory, SharedMemoryUnix,
 };
-use hypervisor::{IrqRoute, IrqSource, Vm};
+use hypervisor::{IrqRoute, IrqSource, VcpuExit, Vm};
 use msg_socket::{MsgError, MsgOnSocket, MsgReceiver, MsgResult, MsgSender, MsgSocket};
 use resources::{Alloc, GpuMemoryDesc, MmioType, SystemAllocator};
 use sync::Mutex;
-use vm_memory::GuestAddress;
+use vm_memory::{GuestAddress, GuestMemory, SharedRegion};
 
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
 pub use crate::gdb::*;
+pub use crate::operation::*;
 pub use hypervisor::MemSlot;
 
 /// Control the state of a particular VM CPU.
@@ -46,6 +55,214 @@ pub enum VcpuControl {
     RunState(VmRunMode),
 }
 
+/// Number of exits of a VM CPU handled by crosvm, by exit reason.
+///
+/// Only exits that return to crosvm are counted. Faults the hypervisor resolves on its own, such
+/// as EPT violations on guest RAM, never reach crosvm; those on emulated MMIO are counted as
+/// `mmio_read` and `mmio_write`.
+#[derive(Clone, Copy, Default, MsgOnSocket, Debug, PartialEq)]
+pub struct VcpuExitStats {
+    pub io_in: u64,
+    pub io_out: u64,
+    pub mmio_read: u64,
+    pub mmio_write: u64,
+    pub ioapic_eoi: u64,
+    pub irq_window_open: u64,
+    pub hlt: u64,
+    pub other: u64,
+}
+
+impl VcpuExitStats {
+    /// Returns the total number of exits.
+    pub fn total(&self) -> u64 {
+        self.io_in
+            + self.io_out
+            + self.mmio_read
+            + self.mmio_write
+            + self.ioapic_eoi
+            + self.irq_window_open
+            + self.hlt
+            + self.other
+    }
+}
+
+impl AddAssign for VcpuExitStats {
+    fn add_assign(&mut self, other: VcpuExitStats) {
+        self.io_in += other.io_in;
+        self.io_out += other.io_out;
+        self.mmio_read += other.mmio_read;
+        self.mmio_write += other.mmio_write;
+        self.ioapic_eoi += other.ioapic_eoi;
+        self.irq_window_open += other.irq_window_open;
+        self.hlt += other.hlt;
+        self.other += other.other;
+    }
+}
+
+impl Display for VcpuExitStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "total: {}", self.total())?;
+        write!(f, "\n    io_in: {}", self.io_in)?;
+        write!(f, "\n    io_out: {}", self.io_out)?;
+        write!(f, "\n    mmio_read: {}", self.mmio_read)?;
+        write!(f, "\n    mmio_write: {}", self.mmio_write)?;
+        write!(f, "\n    ioapic_eoi: {}", self.ioapic_eoi)?;
+        write!(f, "\n    irq_window_open: {}", self.irq_window_open)?;
+        write!(f, "\n    hlt: {}", self.hlt)?;
+        write!(f, "\n    other: {}", self.other)
+    }
+}
+
+/// Exit counters of a single VM CPU, updated by its thread and read by the control loop.
+#[derive(Default)]
+pub struct VcpuExitCounters {
+    io_in: AtomicU64,
+    io_out: AtomicU64,
+    mmio_read: AtomicU64,
+    mmio_write: AtomicU64,
+    ioapic_eoi: AtomicU64,
+    irq_window_open: AtomicU64,
+    hlt: AtomicU64,
+    other: AtomicU64,
+}
+
+impl VcpuExitCounters {
+    /// Counts one `exit`.
+    pub fn record(&self, exit: &VcpuExit) {
+        let counter = match exit {
+            VcpuExit::IoIn { .. } => &self.io_in,
+            VcpuExit::IoOut { .. } => &self.io_out,
+            VcpuExit::MmioRead { .. } => &self.mmio_read,
+            VcpuExit::MmioWrite { .. } => &self.mmio_write,
+            VcpuExit::IoapicEoi { .. } => &self.ioapic_eoi,
+            VcpuExit::IrqWindowOpen => &self.irq_window_open,
+            VcpuExit::Hlt => &self.hlt,
+            _ => &self.other,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns a snapshot of the counters.
+    pub fn stats(&self) -> VcpuExitStats {
+        VcpuExitStats {
+            io_in: self.io_in.load(Ordering::Relaxed),
+            io_out: self.io_out.load(Ordering::Relaxed),
+            mmio_read: self.mmio_read.load(Ordering::Relaxed),
+            mmio_write: self.mmio_write.load(Ordering::Relaxed),
+            ioapic_eoi: self.ioapic_eoi.load(Ordering::Relaxed),
+            irq_window_open: self.irq_window_open.load(Ordering::Relaxed),
+            hlt: self.hlt.load(Ordering::Relaxed),
+            other: self.other.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counters of the work done on a virtio queue. Stats of several queues can be summed to get the
+/// totals of a device.
+#[derive(Clone, Copy, Debug, Default, MsgOnSocket, PartialEq)]
+pub struct QueueStats {
+    /// Descriptor chains returned to the driver through the used ring.
+    pub descriptors: u64,
+    /// Bytes written to the driver's buffers, as reported in the used ring.
+    pub bytes: u64,
+    /// Times the device disabled driver notifications while processing the queue.
+    pub notifications_suppressed: u64,
+    /// Interrupts injected into the guest for this queue.
+    pub interrupts: u64,
+}
+
+impl AddAssign for QueueStats {
+    fn add_assign(&mut self, other: QueueStats) {
+        self.descriptors += other.descriptors;
+        self.bytes += other.bytes;
+        self.notifications_suppressed += other.notifications_suppressed;
+        self.interrupts += other.interrupts;
+    }
+}
+
+impl Add for QueueStats {
+    type Output = QueueStats;
+
+    fn add(mut self, other: QueueStats) -> QueueStats {
+        self += other;
+        self
+    }
+}
+
+impl Sum for QueueStats {
+    fn sum<I: Iterator<Item = QueueStats>>(iter: I) -> QueueStats {
+        iter.fold(QueueStats::default(), Add::add)
+    }
+}
+
+impl Display for QueueStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "descriptors: {}, bytes: {}, notifications_suppressed: {}, interrupts: {}",
+            self.descriptors, self.bytes, self.notifications_suppressed, self.interrupts
+        )
+    }
+}
+
+/// The queue counters of a virtio PCI device.
+#[derive(Debug, MsgOnSocket)]
+pub struct VirtioDeviceStats {
+    /// PCI address of the device.
+    pub bus: u8,
+    pub dev: u8,
+    pub func: u8,
+    /// Debug label of the device, e.g. `virtio-pci (virtio-block)`.
+    pub label: Vec<u8>,
+    /// Counters of each of the device's queues, in queue index order.
+    pub queues: Vec<QueueStats>,
+}
+
+impl VirtioDeviceStats {
+    /// Returns the counters of all of the device's queues added together.
+    pub fn total(&self) -> QueueStats {
+        self.queues.iter().copied().sum()
+    }
+}
+
+impl Display for VirtioDeviceStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}.{:0x} {}: {}",
+            self.bus,
+            self.dev,
+            self.func,
+            String::from_utf8_lossy(&self.label),
+            self.total()
+        )?;
+        for (index, queue) in self.queues.iter().enumerate() {
+            write!(f, "\n    queue {}: {}", index, queue)?;
+        }
+        StdResult::Ok(())
+    }
+}
+
+/// Wake time programmed into the guest's RTC alarm, shared by the CMOS device that owns the alarm
+/// registers and the control loop that wakes a suspended guest once the time is reached.
+#[derive(Clone, Default)]
+pub struct RtcWakeAlarm(Arc<AtomicU64>);
+
+impl RtcWakeAlarm {
+    /// Sets the wake time in seconds since the epoch, or disarms the alarm if `None`.
+    pub fn set(&self, wake_time: Option<u64>) {
+        self.0.store(wake_time.unwrap_or(0), Ordering::SeqCst);
+    }
+
+    /// Returns the pending wake time in seconds since the epoch, if the alarm is armed.
+    pub fn get(&self) -> Option<u64> {
+        match self.0.load(Ordering::SeqCst) {
+            0 => None,
+            wake_time => Some(wake_time),
+        }
+    }
+}
+
 /// A file descriptor either borrowed or owned by this.
 #[derive(Debug)]
 pub enum MaybeOwnedDescriptor {
@@ -147,15 +364,25 @@ pub const USB_CONTROL_MAX_PORTS: usize = 16;
 
 #[derive(MsgOnSocket, Debug)]
 pub enum BalloonControlCommand {
-    /// Set the size of the VM's balloon.
+    /// Set the size of the VM's balloon. Answered with `BalloonControlResult::Ok`, or an error if
+    /// the balloon can't be that large.
     Adjust {
         num_bytes: u64,
     },
     Stats,
+    /// Report the size the balloon was asked to be and the size the guest has inflated it to.
+    Size,
+    /// Start or stop a run of the guest reporting its free pages, which are given back to the
+    /// host as they come in.
+    FreePageHinting {
+        start: bool,
+    },
+    /// Report the errors the device ran into while serving these commands.
+    Diagnostics,
 }
 
 // BalloonStats holds stats returned from the stats_queue.
-#[derive(Default, MsgOnSocket, Debug)]
+#[derive(Clone, Default, MsgOnSocket, Debug)]
 pub struct BalloonStats {
     pub swap_in: Option<u64>,
     pub swap_out: Option<u64>,
@@ -206,18 +433,399 @@ impl Display for BalloonStats {
     }
 }
 
+/// Counts of the errors the balloon device ran into on its command socket, which tell why balloon
+/// commands stop working.
+#[derive(Clone, Copy, Default, MsgOnSocket, Debug, PartialEq)]
+pub struct BalloonDiagnostics {
+    /// Commands the device received but could not make sense of, which happens when the main
+    /// process and the device don't agree on the command format.
+    pub invalid_commands: u64,
+    /// Commands that could not be received because the socket failed.
+    pub command_recv_errors: u64,
+    /// Answers to commands that could not be sent back.
+    pub command_send_errors: u64,
+}
+
+impl Display for BalloonDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{")?;
+        write!(f, "\n    invalid_commands: {}", self.invalid_commands)?;
+        write!(f, "\n    command_recv_errors: {}", self.command_recv_errors)?;
+        write!(f, "\n    command_send_errors: {}", self.command_send_errors)?;
+        write!(f, "\n}}")
+    }
+}
+
 #[derive(MsgOnSocket, Debug)]
 pub enum BalloonControlResult {
+    Ok,
+    Err(SysError),
     Stats {
         stats: BalloonStats,
         balloon_actual: u64,
     },
+    Size {
+        num_bytes: u64,
+        actual_bytes: u64,
+    },
+    Diagnostics(BalloonDiagnostics),
+}
+
+/// Events the balloon device reports to the main process without being asked.
+#[derive(MsgOnSocket, Debug)]
+pub enum BalloonEvent {
+    /// The guest inflated or deflated the balloon to `actual_bytes`.
+    ActualChanged { actual_bytes: u64 },
+}
+
+impl Display for BalloonEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::BalloonEvent::*;
+
+        match self {
+            ActualChanged { actual_bytes } => write!(f, "balloon actual size: {}", actual_bytes),
+        }
+    }
+}
+
+/// Identifies a virtio device by its type and PCI address.
+#[derive(Clone, Copy, MsgOnSocket, Debug)]
+pub struct VirtioDeviceId {
+    pub device_type: u32,
+    pub bus: u8,
+    pub dev: u8,
+    pub func: u8,
+}
+
+impl Display for VirtioDeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "virtio device type {} at {:04x}:{:02x}.{:0x}",
+            self.device_type, self.bus, self.dev, self.func
+        )
+    }
+}
+
+/// Events the virtio transport reports to the main process when a guest driver can't use a
+/// device.
+#[derive(MsgOnSocket, Debug)]
+pub enum VirtioEvent {
+    /// The driver acked features the device doesn't offer, so the device refused FEATURES_OK.
+    FeaturesRejected {
+        id: VirtioDeviceId,
+        offered: u64,
+        acked: u64,
+    },
+    /// The driver gave up on the device by setting the FAILED status bit.
+    DriverFailed {
+        id: VirtioDeviceId,
+        offered: u64,
+        acked: u64,
+    },
+    /// A worker of the device stopped, and the driver was asked to reset the device.
+    NeedsReset { id: VirtioDeviceId },
+}
+
+impl Display for VirtioEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::VirtioEvent::*;
+
+        match self {
+            FeaturesRejected { id, offered, acked } => write!(
+                f,
+                "{}: driver acked unsupported features {:#x} (offered {:#x}, acked {:#x})",
+                id,
+                acked & !offered,
+                offered,
+                acked
+            ),
+            DriverFailed { id, offered, acked } => write!(
+                f,
+                "{}: driver failed (offered {:#x}, acked {:#x})",
+                id, offered, acked
+            ),
+            NeedsReset { id } => write!(f, "{}: device stopped working and needs a reset", id),
+        }
+    }
+}
+
+/// The size of the terminal a virtio console is attached to, which the main process sends the
+/// console whenever the terminal is resized.
+#[derive(Clone, Copy, Default, MsgOnSocket, Debug, PartialEq)]
+pub struct ConsoleSize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+/// A record the guest wrote to its telemetry port.
+#[derive(Clone, MsgOnSocket, Debug, PartialEq)]
+pub enum GuestTelemetryEvent {
+    /// The guest reached the boot milestone `name`, `uptime_ms` milliseconds after it booted.
+    BootMilestone { name: Vec<u8>, uptime_ms: u64 },
+    /// The share of time some and all non-idle tasks in the guest stalled on memory over the last
+    /// 10 seconds, in hundredths of a percent.
+    MemoryPressure { some_avg10: u32, full_avg10: u32 },
+    /// The guest's OOM killer killed the process `pid`.
+    OomKill { pid: u32, name: Vec<u8> },
+}
+
+impl Display for GuestTelemetryEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::GuestTelemetryEvent::*;
+
+        match self {
+            BootMilestone { name, uptime_ms } => write!(
+                f,
+                "guest reached boot milestone {} at {} ms",
+                String::from_utf8_lossy(name),
+                uptime_ms
+            ),
+            MemoryPressure {
+                some_avg10,
+                full_avg10,
+            } => write!(
+                f,
+                "guest memory pressure: some {}, full {}",
+                Percent(*some_avg10),
+                Percent(*full_avg10)
+            ),
+            OomKill { pid, name } => write!(
+                f,
+                "guest killed process {} ({}) out of memory",
+                pid,
+                String::from_utf8_lossy(name)
+            ),
+        }
+    }
+}
+
+// Displays hundredths of a percent.
+struct Percent(u32);
+
+impl Display for Percent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:02}%", self.0 / 100, self.0 % 100)
+    }
+}
+
+/// What the guest reported on its telemetry port since crosvm started.
+#[derive(Clone, Default, MsgOnSocket, Debug)]
+pub struct GuestTelemetry {
+    /// The boot milestones the guest reached and its uptime in milliseconds at each, in the order
+    /// it reported them.
+    pub boot_milestones: Vec<(Vec<u8>, u64)>,
+    /// The latest memory pressure, as `GuestTelemetryEvent::MemoryPressure`'s `(some_avg10,
+    /// full_avg10)`.
+    pub memory_pressure: Option<(u32, u32)>,
+    pub oom_kills: u64,
+    /// The pid and name of the process the OOM killer killed last.
+    pub last_oom_kill: Option<(u32, Vec<u8>)>,
+}
+
+impl GuestTelemetry {
+    /// The most boot milestones kept, so that a misbehaving guest can't make the list grow
+    /// forever.
+    pub const MAX_BOOT_MILESTONES: usize = 64;
+
+    /// Updates the summary with an `event` from the guest.
+    pub fn record(&mut self, event: &GuestTelemetryEvent) {
+        match event {
+            GuestTelemetryEvent::BootMilestone { name, uptime_ms } => {
+                if self.boot_milestones.len() < Self::MAX_BOOT_MILESTONES {
+                    self.boot_milestones.push((name.clone(), *uptime_ms));
+                }
+            }
+            GuestTelemetryEvent::MemoryPressure {
+                some_avg10,
+                full_avg10,
+            } => self.memory_pressure = Some((*some_avg10, *full_avg10)),
+            GuestTelemetryEvent::OomKill { pid, name } => {
+                self.oom_kills += 1;
+                self.last_oom_kill = Some((*pid, name.clone()));
+            }
+        }
+    }
+}
+
+impl Display for GuestTelemetry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "boot milestones:")?;
+        for (name, uptime_ms) in &self.boot_milestones {
+            write!(f, "\n  {}: {} ms", String::from_utf8_lossy(name), uptime_ms)?;
+        }
+        match self.memory_pressure {
+            Some((some, full)) => write!(
+                f,
+                "\nmemory pressure: some {}, full {}",
+                Percent(some),
+                Percent(full)
+            )?,
+            None => write!(f, "\nmemory pressure: not reported")?,
+        }
+        write!(f, "\noom kills: {}", self.oom_kills)?;
+        if let Some((pid, name)) = &self.last_oom_kill {
+            write!(f, " (last {} {})", pid, String::from_utf8_lossy(name))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(MsgOnSocket, Debug)]
+pub enum MemControlCommand {
+    /// Ask the guest to plug `requested_bytes` of the virtio-mem region. Answered with
+    /// `MemControlResult::Ok`, or an error if the size is not a multiple of the block size or
+    /// larger than the region.
+    Resize { requested_bytes: u64 },
+    /// Report how much of the region the guest has plugged and how much it was asked to.
+    Size,
+}
+
+#[derive(MsgOnSocket, Debug)]
+pub enum MemControlResult {
+    Ok,
+    Err(SysError),
+    Size {
+        plugged_bytes: u64,
+        requested_bytes: u64,
+        region_bytes: u64,
+    },
+}
+
+/// The faults the fault injection device introduces while serving its virtqueue. Each fault is
+/// disabled when its value is zero.
+#[derive(Clone, Copy, Default, MsgOnSocket, Debug, PartialEq)]
+pub struct FaultInjectionConfig {
+    /// Hold each request back for this many milliseconds before returning it in the used ring.
+    pub used_delay_ms: u64,
+    /// Return every `corrupt_every`th request with a used length past the end of its buffers.
+    pub corrupt_every: u64,
+    /// Skip every `drop_interrupt_every`th interrupt the device would send.
+    pub drop_interrupt_every: u64,
+}
+
+impl Display for FaultInjectionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "used_delay_ms={},corrupt_every={},drop_interrupt_every={}",
+            self.used_delay_ms, self.corrupt_every, self.drop_interrupt_every
+        )
+    }
+}
+
+/// How many requests the fault injection device served and how many faults it introduced.
+#[derive(Clone, Copy, Default, MsgOnSocket, Debug, PartialEq)]
+pub struct FaultInjectionStats {
+    pub requests: u64,
+    pub delayed: u64,
+    pub corrupted: u64,
+    pub dropped_interrupts: u64,
+}
+
+impl Display for FaultInjectionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "requests: {}, delayed: {}, corrupted: {}, dropped interrupts: {}",
+            self.requests, self.delayed, self.corrupted, self.dropped_interrupts
+        )
+    }
+}
+
+#[derive(MsgOnSocket, Debug)]
+pub enum FaultInjectionCommand {
+    /// Replace the faults the device introduces. Requests already held back keep their delay.
+    Configure(FaultInjectionConfig),
+    /// Report the current faults and how often they were introduced.
+    Status,
+}
+
+#[derive(MsgOnSocket, Debug)]
+pub enum FaultInjectionResult {
+    Ok,
+    Status {
+        config: FaultInjectionConfig,
+        stats: FaultInjectionStats,
+    },
+}
+
+/// The number of buckets in `DiskStats::latency_us`.
+pub const DISK_LATENCY_BUCKETS: usize = 24;
+
+/// Counts of the requests a block device has served since it was created.
+#[derive(Clone, Copy, Default, MsgOnSocket, Debug, PartialEq)]
+pub struct DiskStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub flushes: u64,
+    pub discards: u64,
+    pub write_zeroes: u64,
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    /// Bytes of the disk discarded, which punches holes in a sparse disk image.
+    pub discarded_bytes: u64,
+    pub zeroed_bytes: u64,
+    /// Requests taken from the queues that haven't completed yet.
+    pub in_flight: u64,
+    /// The most requests that have been in flight at once.
+    pub max_in_flight: u64,
+    /// Completed requests by latency. Entry `i` counts the requests that took from 2^i up to
+    /// 2^(i+1) microseconds; the first entry also counts faster requests and the last slower ones.
+    pub latency_us: [u64; DISK_LATENCY_BUCKETS],
+}
+
+impl Display for DiskStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "reads: {} ({} bytes), writes: {} ({} bytes), flushes: {}, \
+             discards: {} ({} bytes), write zeroes: {} ({} bytes), in flight: {} (max {})",
+            self.reads,
+            self.read_bytes,
+            self.writes,
+            self.written_bytes,
+            self.flushes,
+            self.discards,
+            self.discarded_bytes,
+            self.write_zeroes,
+            self.zeroed_bytes,
+            self.in_flight,
+            self.max_in_flight
+        )?;
+        for (i, &count) in self.latency_us.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            if i == DISK_LATENCY_BUCKETS - 1 {
+                write!(f, "\n>= {}us: {}", 1u64 << i, count)?;
+            } else {
+                write!(f, "\n< {}us: {}", 1u64 << (i + 1), count)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(MsgOnSocket, Debug)]
 pub enum DiskControlCommand {
-    /// Resize a disk to `new_size` in bytes.
+    /// Resize a disk to `new_size` in bytes. Shrinking fails with EINVAL if the disk may hold data
+    /// past the new size.
     Resize { new_size: u64 },
+    /// Interrupt the guest at most once every `min_interval_us` microseconds, or after every
+    /// request if zero.
+    SetInterruptCoalescing { min_interval_us: u64 },
+    /// Limit the disk to `iops` requests and `bytes_per_sec` bytes of data per second, allowing
+    /// bursts of up to `iops_burst` requests and `bytes_burst` bytes. A rate of zero is unlimited,
+    /// and a burst of zero is one second's worth.
+    SetThrottle {
+        iops: u64,
+        iops_burst: u64,
+        bytes_per_sec: u64,
+        bytes_burst: u64,
+    },
+    /// Report the disk's `DiskStats`.
+    GetStats,
 }
 
 impl Display for DiskControlCommand {
@@ -226,6 +834,20 @@ impl Display for DiskControlCommand {
 
         match self {
             Resize { new_size } => write!(f, "disk_resize {}", new_size),
+            SetInterruptCoalescing { min_interval_us } => {
+                write!(f, "disk_irq_coalescing {}", min_interval_us)
+            }
+            SetThrottle {
+                iops,
+                iops_burst,
+                bytes_per_sec,
+                bytes_burst,
+            } => write!(
+                f,
+                "disk_throttle iops={} iops_burst={} bps={} bps_burst={}",
+                iops, iops_burst, bytes_per_sec, bytes_burst
+            ),
+            GetStats => write!(f, "disk_stats"),
         }
     }
 }
@@ -234,6 +856,62 @@ impl Display for DiskControlCommand {
 pub enum DiskControlResult {
     Ok,
     Err(SysError),
+    Stats(DiskStats),
+}
+
+#[derive(MsgOnSocket, Debug)]
+pub enum NetControlCommand {
+    /// Interrupt the guest at most once every `min_interval_us` microseconds per queue, or after
+    /// every batch of packets if zero.
+    SetInterruptCoalescing { min_interval_us: u64 },
+    /// Write a copy of every frame the device sends or receives to `file`, in the pcapng format,
+    /// until `StopCapture`. Replaces any capture already running.
+    StartCapture { file: File },
+    /// Stop writing frames to the capture file.
+    StopCapture,
+}
+
+impl Display for NetControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::NetControlCommand::*;
+
+        match self {
+            SetInterruptCoalescing { min_interval_us } => {
+                write!(f, "net_irq_coalescing {}", min_interval_us)
+            }
+            StartCapture { .. } => write!(f, "net_capture_start"),
+            StopCapture => write!(f, "net_capture_stop"),
+        }
+    }
+}
+
+#[derive(MsgOnSocket, Debug)]
+pub enum NetControlResult {
+    Ok,
+    Err(SysError),
+}
+
+#[derive(MsgOnSocket, Debug)]
+pub enum PcieSlotControlCommand {
+    /// Show the guest a device being inserted into the slot of the root port if `present`, or
+    /// removed from it otherwise.
+    SetPresence { present: bool },
+}
+
+impl Display for PcieSlotControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PcieSlotControlCommand::*;
+
+        match self {
+            SetPresence { present } => write!(f, "pcie_slot_presence {}", present),
+        }
+    }
+}
+
+#[derive(MsgOnSocket, Debug)]
+pub enum PcieSlotControlResult {
+    Ok,
+    Err(SysError),
 }
 
 #[derive(MsgOnSocket, Debug)]
@@ -245,6 +923,11 @@ pub enum UsbControlCommand {
         pid: u16,
         descriptor: Option<MaybeOwnedDescriptor>,
     },
+    /// Attach an emulated mass storage device backed by the disk image in `descriptor`.
+    AttachMassStorage {
+        read_only: bool,
+        descriptor: Option<MaybeOwnedDescriptor>,
+    },
     DetachDevice {
         port: u8,
     },
@@ -317,12 +1000,28 @@ pub enum VmMemoryRequest {
         format: u32,
     },
     /// Register mmaped memory into the hypervisor's EPT.
+    /// The response variant is `VmResponse::RegisterMemory`.
     RegisterMmapMemory {
         descriptor: MaybeOwnedDescriptor,
         size: usize,
         offset: u64,
         gpa: u64,
     },
+    /// Add the shared memory represented by the given descriptor to the guest memory at `gpa`,
+    /// e.g. to hotplug memory. The response variant is `VmMemoryResponse::RegisterMemory`.
+    AddGuestMemory {
+        descriptor: MaybeOwnedDescriptor,
+        gpa: u64,
+    },
+    /// Remove the guest memory at the given memory slot that was previously added with
+    /// `AddGuestMemory`.
+    RemoveGuestMemory(MemSlot),
+    /// Reserve `size` bytes of the high MMIO address space for `alloc`, without mapping anything
+    /// there. The response variant is `VmMemoryResponse::AddressSpace`.
+    AllocateAddressSpace { alloc: Alloc, size: u64 },
+    /// Release the address space that was previously reserved for `alloc` with
+    /// `AllocateAddressSpace`.
+    ReleaseAddressSpace(Alloc),
 }
 
 impl VmMemoryRequest {
@@ -418,10 +1117,53 @@ impl VmMemoryRequest {
                     Err(_e) => return VmMemoryResponse::Err(SysError::new(EINVAL)),
                 };
                 match vm.add_memory_region(GuestAddress(gpa), Box::new(mmap), false, false) {
-                    Ok(_) => VmMemoryResponse::Ok,
+                    Ok(slot) => VmMemoryResponse::RegisterMemory {
+                        pfn: gpa >> 12,
+                        slot,
+                    },
+                    Err(e) => VmMemoryResponse::Err(e),
+                }
+            }
+            AddGuestMemory {
+                ref descriptor,
+                gpa,
+            } => {
+                // The received descriptor is always owned; it is duplicated because the request is
+                // only borrowed.
+                let shm = match descriptor {
+                    MaybeOwnedDescriptor::Owned(d) => {
+                        d.try_clone().and_then(SharedMemory::from_safe_descriptor)
+                    }
+                    MaybeOwnedDescriptor::Borrowed(_) => Err(SysError::new(EINVAL)),
+                };
+                match shm.and_then(|shm| vm.add_guest_memory_region(GuestAddress(gpa), shm)) {
+                    Ok(slot) => VmMemoryResponse::RegisterMemory {
+                        pfn: gpa >> 12,
+                        slot,
+                    },
                     Err(e) => VmMemoryResponse::Err(e),
                 }
             }
+            RemoveGuestMemory(slot) => match vm.remove_guest_memory_region(slot) {
+                Ok(()) => VmMemoryResponse::Ok,
+                Err(e) => VmMemoryResponse::Err(e),
+            },
+            AllocateAddressSpace { alloc, size } => {
+                match sys_allocator.mmio_allocator(MmioType::High).allocate(
+                    size,
+                    alloc,
+                    "vmcontrol_address_space".to_string(),
+                ) {
+                    Ok(gpa) => VmMemoryResponse::AddressSpace { gpa },
+                    Err(_e) => VmMemoryResponse::Err(SysError::new(ENOSPC)),
+                }
+            }
+            ReleaseAddressSpace(alloc) => {
+                match sys_allocator.mmio_allocator(MmioType::High).release(alloc) {
+                    Ok(()) => VmMemoryResponse::Ok,
+                    Err(_e) => VmMemoryResponse::Err(SysError::new(EINVAL)),
+                }
+            }
         }
     }
 }
@@ -442,6 +1184,10 @@ pub enum VmMemoryResponse {
         slot: MemSlot,
         desc: GpuMemoryDesc,
     },
+    /// The request to reserve address space was successfully done, starting at `gpa`.
+    AddressSpace {
+        gpa: u64,
+    },
     Ok,
     Err(SysError),
 }
@@ -766,17 +1512,91 @@ pub struct BatControl {
     pub control_socket: BatControlRequestSocket,
 }
 
+/// What the ivshmem broker hands each VM that joins the memory region it shares.
+#[derive(MsgOnSocket, Debug)]
+pub struct IvshmemSetup {
+    /// The id of the VM among the peers sharing the region.
+    pub peer_id: u16,
+    /// The shared memory region.
+    pub region: File,
+    /// The doorbell of every peer, indexed by peer id. A VM waits on its own doorbell and writes
+    /// to the others' to interrupt them.
+    pub doorbells: Vec<Event>,
+}
+
+/// A region of guest memory as described to a process that maps guest memory itself, such as a
+/// device backend that was not forked from crosvm. See `GuestMemory::shared_regions`.
+#[derive(Clone, Copy, MsgOnSocket, Debug, PartialEq)]
+pub struct GuestMemoryRegion {
+    pub guest_base: u64,
+    pub size: u64,
+    /// The index of the region's shared memory in `VmResponse::GuestMemory`.
+    pub shm_index: u64,
+    pub shm_offset: u64,
+}
+
+impl From<SharedRegion> for GuestMemoryRegion {
+    fn from(region: SharedRegion) -> Self {
+        GuestMemoryRegion {
+            guest_base: region.guest_base.offset(),
+            size: region.size,
+            shm_index: region.shm_index as u64,
+            shm_offset: region.shm_offset,
+        }
+    }
+}
+
+impl From<GuestMemoryRegion> for SharedRegion {
+    fn from(region: GuestMemoryRegion) -> Self {
+        SharedRegion {
+            guest_base: GuestAddress(region.guest_base),
+            size: region.size,
+            shm_index: region.shm_index as usize,
+            shm_offset: region.shm_offset,
+        }
+    }
+}
+
 pub type BalloonControlRequestSocket = MsgSocket<BalloonControlCommand, BalloonControlResult>;
 pub type BalloonControlResponseSocket = MsgSocket<BalloonControlResult, BalloonControlCommand>;
 
+pub type BalloonEventSendSocket = MsgSocket<BalloonEvent, ()>;
+pub type BalloonEventRecvSocket = MsgSocket<(), BalloonEvent>;
+
+pub type VirtioEventSendSocket = MsgSocket<VirtioEvent, ()>;
+pub type VirtioEventRecvSocket = MsgSocket<(), VirtioEvent>;
+
+pub type GuestTelemetrySendSocket = MsgSocket<GuestTelemetryEvent, ()>;
+pub type GuestTelemetryRecvSocket = MsgSocket<(), GuestTelemetryEvent>;
+
+pub type ConsoleResizeSendSocket = MsgSocket<ConsoleSize, ()>;
+pub type ConsoleResizeRecvSocket = MsgSocket<(), ConsoleSize>;
+
 pub type BatControlRequestSocket = MsgSocket<BatControlCommand, BatControlResult>;
 pub type BatControlResponseSocket = MsgSocket<BatControlResult, BatControlCommand>;
 
+pub type MemControlRequestSocket = MsgSocket<MemControlCommand, MemControlResult>;
+pub type MemControlResponseSocket = MsgSocket<MemControlResult, MemControlCommand>;
+
+pub type FaultInjectionRequestSocket = MsgSocket<FaultInjectionCommand, FaultInjectionResult>;
+pub type FaultInjectionResponseSocket = MsgSocket<FaultInjectionResult, FaultInjectionCommand>;
+
 pub type DiskControlRequestSocket = MsgSocket<DiskControlCommand, DiskControlResult>;
 pub type DiskControlResponseSocket = MsgSocket<DiskControlResult, DiskControlCommand>;
 
+pub type NetControlRequestSocket = MsgSocket<NetControlCommand, NetControlResult>;
+pub type NetControlResponseSocket = MsgSocket<NetControlResult, NetControlCommand>;
+
+pub type PcieSlotControlRequestSocket = MsgSocket<PcieSlotControlCommand, PcieSlotControlResult>;
+pub type PcieSlotControlResponseSocket = MsgSocket<PcieSlotControlResult, PcieSlotControlCommand>;
+
 pub type UsbControlSocket = MsgSocket<UsbControlCommand, UsbControlResult>;
 
+/// The broker side of a connection to the ivshmem broker. The connection stays open for as long
+/// as the VM uses its peer id.
+pub type IvshmemBrokerSocket = MsgSocket<IvshmemSetup, ()>;
+pub type IvshmemPeerSocket = MsgSocket<(), IvshmemSetup>;
+
 pub type VmMemoryControlRequestSocket = MsgSocket<VmMemoryRequest, VmMemoryResponse>;
 pub type VmMemoryControlResponseSocket = MsgSocket<VmMemoryResponse, VmMemoryRequest>;
 
@@ -802,16 +1622,70 @@ pub enum VmRequest {
     Resume,
     /// Command for balloon driver.
     BalloonCommand(BalloonControlCommand),
+    /// Turn the control socket into a stream of `VmResponse::BalloonEvent`s, sent whenever the
+    /// guest changes the size of the balloon. The socket takes no further requests.
+    SubscribeBalloonEvents,
+    /// Turn the control socket into a stream of `VmResponse::VirtioEvent`s, sent whenever a guest
+    /// driver fails to set up a virtio device. The socket takes no further requests.
+    SubscribeVirtioEvents,
+    /// Get what the guest reported on its telemetry port.
+    GuestTelemetry,
+    /// Turn the control socket into a stream of `VmResponse::GuestTelemetryEvent`s, sent whenever
+    /// the guest writes a record to its telemetry port. The socket takes no further requests.
+    SubscribeGuestTelemetry,
+    /// Command for the virtio-mem device.
+    MemCommand(MemControlCommand),
     /// Send a command to a disk chosen by `disk_index`.
     /// `disk_index` is a 0-based count of `--disk`, `--rwdisk`, and `-r` command-line options.
+    /// Resizing runs as an operation.
     DiskCommand {
         disk_index: usize,
         command: DiskControlCommand,
     },
+    /// Send a command to a virtio-net device chosen by `net_index`.
+    /// `net_index` is a 0-based count of the devices created from `--tap-fd`, `--user-net` and
+    /// `--host_ip`, in that order.
+    NetCommand {
+        net_index: usize,
+        command: NetControlCommand,
+    },
+    /// Send a command to the slot of the PCIe root port chosen by `port_index`, a 0-based count of
+    /// the ports created by `--pcie-root-ports`.
+    PcieSlotCommand {
+        port_index: usize,
+        command: PcieSlotControlCommand,
+    },
+    /// Command for the fault injection test device.
+    FaultInjectionCommand(FaultInjectionCommand),
     /// Command to use controller.
     UsbCommand(UsbControlCommand),
     /// Command to set battery.
     BatCommand(BatteryType, BatControlCommand),
+    /// Get the exit counts of the VCPU `cpu_id`, or of all VCPUs combined if `None`.
+    VcpuExitStats { cpu_id: Option<usize> },
+    /// Get the queue counters of every virtio PCI device.
+    VirtioStats,
+    /// Get the time the guest's RTC alarm is set to wake it at.
+    RtcWakeTime,
+    /// Replace the log filter of crosvm and its device processes. `filter` is UTF-8 in the syntax
+    /// of `base::syslog::LogFilter`.
+    SetLogFilter { filter: Vec<u8> },
+    /// Write the contents of guest memory to `file`, each byte at the offset of its guest physical
+    /// address. Runs as an operation.
+    DumpMemory { file: File },
+    /// Get the layout of guest memory and descriptors of the shared memory backing it, for a
+    /// process outside of crosvm to map it with `GuestMemory::from_shared_regions`. Regions added
+    /// or removed later are not reflected in the mapping.
+    GetGuestMemory,
+    /// Get the state of the operation `id`.
+    OperationStatus { id: u64 },
+    /// Ask the operation `id` to stop early. The operation may still complete if it was almost
+    /// done.
+    CancelOperation { id: u64 },
+    /// Get the state of the operation `id`, and if it is still running, turn the control socket
+    /// into a stream of `VmResponse::OperationState`s sent as the operation progresses, ending
+    /// with its final state. The socket takes no further requests.
+    SubscribeOperation { id: u64 },
 }
 
 fn register_memory(
@@ -864,19 +1738,102 @@ fn register_memory_hva(
     Ok((addr >> 12, slot))
 }
 
+fn disk_request(
+    sock: &DiskControlRequestSocket,
+    command: &DiskControlCommand,
+) -> Result<DiskControlResult> {
+    if let Err(e) = sock.send(command) {
+        error!("disk socket send failed: {}", e);
+        return Err(SysError::new(EINVAL));
+    }
+    match sock.recv() {
+        Ok(DiskControlResult::Err(e)) => Err(e),
+        Ok(result) => Ok(result),
+        Err(e) => {
+            error!("disk socket recv failed: {}", e);
+            Err(SysError::new(EINVAL))
+        }
+    }
+}
+
+fn share_guest_memory(mem: &GuestMemory) -> Result<(Vec<GuestMemoryRegion>, Vec<File>)> {
+    let (regions, shms) = mem.shared_regions();
+    let mut files = Vec::with_capacity(shms.len());
+    for shm in shms {
+        // Safe because dup returns a new descriptor, which is checked and then owned by the file.
+        let fd = unsafe { libc::dup(shm.as_raw_descriptor()) };
+        if fd < 0 {
+            return Err(SysError::last());
+        }
+        files.push(unsafe { File::from_raw_descriptor(fd) });
+    }
+    Ok((
+        regions.into_iter().map(GuestMemoryRegion::from).collect(),
+        files,
+    ))
+}
+
+fn dump_memory(mem: &GuestMemory, mut file: File, operation: &Operation) -> Result<()> {
+    // Progress is reported, and cancellation checked, after each chunk.
+    const CHUNK_SIZE: u64 = 16 << 20;
+
+    let mut regions = Vec::new();
+    mem.with_regions::<_, ()>(|_, gpa, size, _, _| {
+        regions.push((gpa, size as u64));
+        Ok(())
+    })
+    .unwrap();
+    let total = mem.memory_size();
+    let mut done = 0;
+    operation.set_progress(done, total);
+    for (gpa, size) in regions {
+        let mut offset = 0;
+        while offset < size {
+            if operation.is_cancelled() {
+                return Err(SysError::new(ECANCELED));
+            }
+            let count = min(CHUNK_SIZE, size - offset);
+            let addr = gpa.unchecked_add(offset);
+            file.seek(SeekFrom::Start(addr.offset()))?;
+            mem.write_from_memory(addr, &file, count as usize)
+                .map_err(|e| {
+                    error!("failed to dump guest memory: {}", e);
+                    SysError::new(EIO)
+                })?;
+            offset += count;
+            done += count;
+            operation.set_progress(done, total);
+        }
+    }
+    Ok(())
+}
+
 impl VmRequest {
     /// Executes this request on the given Vm and other mutable state.
     ///
     /// This does not return a result, instead encapsulating the success or failure in a
     /// `VmResponse` with the intended purpose of sending the response back over the  socket that
     /// received this `VmRequest`.
+    ///
+    /// `virtio_stats` collects the counters answering `VmRequest::VirtioStats`, as the devices
+    /// can't be passed in because of a dependency cycle between devices and vm_control.
     pub fn execute(
         &self,
         run_mode: &mut Option<VmRunMode>,
         balloon_host_socket: &BalloonControlRequestSocket,
-        disk_host_sockets: &[DiskControlRequestSocket],
+        mem_host_socket: Option<&MemControlRequestSocket>,
+        fault_injection_host_socket: Option<&FaultInjectionRequestSocket>,
+        disk_host_sockets: &[Arc<Mutex<DiskControlRequestSocket>>],
+        net_host_sockets: &[NetControlRequestSocket],
+        pcie_slot_host_sockets: &[PcieSlotControlRequestSocket],
         usb_control_socket: &UsbControlSocket,
         bat_control: &mut Option<BatControl>,
+        vcpu_exit_counters: &[VcpuExitCounters],
+        virtio_stats: impl FnOnce() -> Vec<VirtioDeviceStats>,
+        rtc_wake_alarm: &RtcWakeAlarm,
+        mem: &GuestMemory,
+        guest_telemetry: Option<&GuestTelemetry>,
+        operations: &mut Operations,
     ) -> VmResponse {
         match *self {
             VmRequest::Exit => {
@@ -891,28 +1848,98 @@ impl VmRequest {
                 *run_mode = Some(VmRunMode::Running);
                 VmResponse::Ok
             }
-            VmRequest::BalloonCommand(BalloonControlCommand::Adjust { num_bytes }) => {
-                match balloon_host_socket.send(&BalloonControlCommand::Adjust { num_bytes }) {
-                    Ok(_) => VmResponse::Ok,
-                    Err(_) => VmResponse::Err(SysError::last()),
+            VmRequest::BalloonCommand(ref command) => {
+                // Forward the request to the balloon device and pass on its answer.
+                if let Err(e) = balloon_host_socket.send(command) {
+                    error!("balloon socket send failed: {}", e);
+                    return VmResponse::Err(SysError::new(EINVAL));
+                }
+                match balloon_host_socket.recv() {
+                    Ok(BalloonControlResult::Ok) => VmResponse::Ok,
+                    Ok(BalloonControlResult::Err(e)) => VmResponse::Err(e),
+                    Ok(BalloonControlResult::Stats {
+                        stats,
+                        balloon_actual,
+                    }) => VmResponse::BalloonStats {
+                        stats,
+                        balloon_actual,
+                    },
+                    Ok(BalloonControlResult::Size {
+                        num_bytes,
+                        actual_bytes,
+                    }) => VmResponse::BalloonSize {
+                        num_bytes,
+                        actual_bytes,
+                    },
+                    Ok(BalloonControlResult::Diagnostics(diagnostics)) => {
+                        VmResponse::BalloonDiagnostics(diagnostics)
+                    }
+                    Err(e) => {
+                        error!("balloon socket recv failed: {}", e);
+                        VmResponse::Err(SysError::new(EINVAL))
+                    }
                 }
             }
-            VmRequest::BalloonCommand(BalloonControlCommand::Stats) => {
-                match balloon_host_socket.send(&BalloonControlCommand::Stats {}) {
-                    Ok(_) => match balloon_host_socket.recv() {
-                        Ok(BalloonControlResult::Stats {
-                            stats,
-                            balloon_actual,
-                        }) => VmResponse::BalloonStats {
-                            stats,
-                            balloon_actual,
-                        },
-                        Err(e) => {
-                            error!("balloon socket recv failed: {}", e);
-                            VmResponse::Err(SysError::last())
-                        }
+            // The caller moves the socket to its balloon event subscribers once it has been
+            // answered.
+            VmRequest::SubscribeBalloonEvents => VmResponse::Ok,
+            // Likewise, the caller moves the socket to its virtio event subscribers.
+            VmRequest::SubscribeVirtioEvents => VmResponse::Ok,
+            VmRequest::GuestTelemetry => match guest_telemetry {
+                Some(telemetry) => VmResponse::GuestTelemetry(telemetry.clone()),
+                None => VmResponse::Err(SysError::new(ENODEV)),
+            },
+            VmRequest::SubscribeGuestTelemetry => match guest_telemetry {
+                Some(_) => VmResponse::Ok,
+                None => VmResponse::Err(SysError::new(ENODEV)),
+            },
+            VmRequest::MemCommand(ref command) => {
+                // Forward the request to the virtio-mem device, if the VM has one.
+                let sock = match mem_host_socket {
+                    Some(sock) => sock,
+                    None => return VmResponse::Err(SysError::new(ENODEV)),
+                };
+                if let Err(e) = sock.send(command) {
+                    error!("virtio-mem socket send failed: {}", e);
+                    return VmResponse::Err(SysError::new(EINVAL));
+                }
+                match sock.recv() {
+                    Ok(MemControlResult::Ok) => VmResponse::Ok,
+                    Ok(MemControlResult::Err(e)) => VmResponse::Err(e),
+                    Ok(MemControlResult::Size {
+                        plugged_bytes,
+                        requested_bytes,
+                        region_bytes,
+                    }) => VmResponse::MemSize {
+                        plugged_bytes,
+                        requested_bytes,
+                        region_bytes,
                     },
-                    Err(_) => VmResponse::Err(SysError::last()),
+                    Err(e) => {
+                        error!("virtio-mem socket recv failed: {}", e);
+                        VmResponse::Err(SysError::new(EINVAL))
+                    }
+                }
+            }
+            VmRequest::FaultInjectionCommand(ref command) => {
+                // Forward the request to the fault injection device, if the VM has one.
+                let sock = match fault_injection_host_socket {
+                    Some(sock) => sock,
+                    None => return VmResponse::Err(SysError::new(ENODEV)),
+                };
+                if let Err(e) = sock.send(command) {
+                    error!("fault injection socket send failed: {}", e);
+                    return VmResponse::Err(SysError::new(EINVAL));
+                }
+                match sock.recv() {
+                    Ok(FaultInjectionResult::Ok) => VmResponse::Ok,
+                    Ok(FaultInjectionResult::Status { config, stats }) => {
+                        VmResponse::FaultInjectionStatus { config, stats }
+                    }
+                    Err(e) => {
+                        error!("fault injection socket recv failed: {}", e);
+                        VmResponse::Err(SysError::new(EINVAL))
+                    }
                 }
             }
             VmRequest::DiskCommand {
@@ -920,16 +1947,50 @@ impl VmRequest {
                 ref command,
             } => {
                 // Forward the request to the block device process via its control socket.
-                if let Some(sock) = disk_host_sockets.get(disk_index) {
+                let sock = match disk_host_sockets.get(disk_index) {
+                    Some(sock) => sock,
+                    None => return VmResponse::Err(SysError::new(ENODEV)),
+                };
+                match *command {
+                    DiskControlCommand::Resize { new_size } => {
+                        // Growing a disk may have to allocate or zero all of the new space, so
+                        // the resize waits for the block device on its own thread. The socket
+                        // stays locked until the device answers.
+                        let sock = sock.clone();
+                        let command = DiskControlCommand::Resize { new_size };
+                        match operations.start("disk_resize", move |_| {
+                            disk_request(&sock.lock(), &command).map(|_| ())
+                        }) {
+                            Ok(id) => VmResponse::OperationStarted { id },
+                            Err(e) => VmResponse::Err(e),
+                        }
+                    }
+                    _ => match sock.try_lock() {
+                        Ok(sock) => match disk_request(&sock, command) {
+                            Ok(DiskControlResult::Stats(stats)) => VmResponse::DiskStats(stats),
+                            Ok(_) => VmResponse::Ok,
+                            Err(e) => VmResponse::Err(e),
+                        },
+                        // A resize of this disk is still running.
+                        Err(_) => VmResponse::Err(SysError::new(EBUSY)),
+                    },
+                }
+            }
+            VmRequest::NetCommand {
+                net_index,
+                ref command,
+            } => {
+                // Forward the request to the net device process via its control socket.
+                if let Some(sock) = net_host_sockets.get(net_index) {
                     if let Err(e) = sock.send(command) {
-                        error!("disk socket send failed: {}", e);
+                        error!("net socket send failed: {}", e);
                         VmResponse::Err(SysError::new(EINVAL))
                     } else {
                         match sock.recv() {
-                            Ok(DiskControlResult::Ok) => VmResponse::Ok,
-                            Ok(DiskControlResult::Err(e)) => VmResponse::Err(e),
+                            Ok(NetControlResult::Ok) => VmResponse::Ok,
+                            Ok(NetControlResult::Err(e)) => VmResponse::Err(e),
                             Err(e) => {
-                                error!("disk socket recv failed: {}", e);
+                                error!("net socket recv failed: {}", e);
                                 VmResponse::Err(SysError::new(EINVAL))
                             }
                         }
@@ -938,6 +1999,28 @@ impl VmRequest {
                     VmResponse::Err(SysError::new(ENODEV))
                 }
             }
+            VmRequest::PcieSlotCommand {
+                port_index,
+                ref command,
+            } => {
+                // Forward the request to the root port process via its control socket.
+                let sock = match pcie_slot_host_sockets.get(port_index) {
+                    Some(sock) => sock,
+                    None => return VmResponse::Err(SysError::new(ENODEV)),
+                };
+                if let Err(e) = sock.send(command) {
+                    error!("pcie slot socket send failed: {}", e);
+                    return VmResponse::Err(SysError::new(EINVAL));
+                }
+                match sock.recv() {
+                    Ok(PcieSlotControlResult::Ok) => VmResponse::Ok,
+                    Ok(PcieSlotControlResult::Err(e)) => VmResponse::Err(e),
+                    Err(e) => {
+                        error!("pcie slot socket recv failed: {}", e);
+                        VmResponse::Err(SysError::new(EINVAL))
+                    }
+                }
+            }
             VmRequest::UsbCommand(ref cmd) => {
                 let res = usb_control_socket.send(cmd);
                 if let Err(e) = res {
@@ -977,6 +2060,65 @@ impl VmRequest {
                     None => VmResponse::BatResponse(BatControlResult::NoBatDevice),
                 }
             }
+            VmRequest::VcpuExitStats { cpu_id } => match cpu_id {
+                Some(cpu_id) => match vcpu_exit_counters.get(cpu_id) {
+                    Some(counters) => VmResponse::VcpuExitStats(counters.stats()),
+                    None => VmResponse::Err(SysError::new(EINVAL)),
+                },
+                None => {
+                    let mut stats = VcpuExitStats::default();
+                    for counters in vcpu_exit_counters {
+                        stats += counters.stats();
+                    }
+                    VmResponse::VcpuExitStats(stats)
+                }
+            },
+            VmRequest::VirtioStats => VmResponse::VirtioStats(virtio_stats()),
+            VmRequest::RtcWakeTime => VmResponse::RtcWakeTime(rtc_wake_alarm.get()),
+            VmRequest::SetLogFilter { ref filter } => {
+                let res = std::str::from_utf8(filter)
+                    .map_err(|e| e.to_string())
+                    .and_then(|filter| syslog::set_filter(filter).map_err(|e| e.to_string()));
+                match res {
+                    Ok(()) => VmResponse::Ok,
+                    Err(e) => {
+                        error!("failed to set log filter: {}", e);
+                        VmResponse::Err(SysError::new(EINVAL))
+                    }
+                }
+            }
+            VmRequest::DumpMemory { ref file } => {
+                let file = match file.try_clone() {
+                    Ok(file) => file,
+                    Err(e) => return VmResponse::Err(e.into()),
+                };
+                let mem = mem.clone();
+                match operations.start("dump_memory", move |operation| {
+                    dump_memory(&mem, file, operation)
+                }) {
+                    Ok(id) => VmResponse::OperationStarted { id },
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
+            VmRequest::GetGuestMemory => match share_guest_memory(mem) {
+                Ok((regions, shms)) => VmResponse::GuestMemory { regions, shms },
+                Err(e) => VmResponse::Err(e),
+            },
+            // The caller moves the socket to the operation's subscribers once it has been
+            // answered, if the operation is still running.
+            VmRequest::OperationStatus { id } | VmRequest::SubscribeOperation { id } => {
+                match operations.state(id) {
+                    Some(state) => VmResponse::OperationState { id, state },
+                    None => VmResponse::Err(SysError::new(ENOENT)),
+                }
+            }
+            VmRequest::CancelOperation { id } => {
+                if operations.cancel(id) {
+                    VmResponse::Ok
+                } else {
+                    VmResponse::Err(SysError::new(ENOENT))
+                }
+            }
         }
     }
 }
@@ -1006,10 +2148,55 @@ pub enum VmResponse {
         stats: BalloonStats,
         balloon_actual: u64,
     },
+    /// The size in bytes the balloon was asked to be and the size the guest inflated it to.
+    BalloonSize { num_bytes: u64, actual_bytes: u64 },
+    /// The errors the balloon device ran into on its command socket.
+    BalloonDiagnostics(BalloonDiagnostics),
+    /// An event from the balloon device, sent to sockets subscribed with
+    /// `VmRequest::SubscribeBalloonEvents`.
+    BalloonEvent(BalloonEvent),
+    /// An event from the virtio transport, sent to sockets subscribed with
+    /// `VmRequest::SubscribeVirtioEvents`.
+    VirtioEvent(VirtioEvent),
+    /// What the guest reported on its telemetry port.
+    GuestTelemetry(GuestTelemetry),
+    /// A record from the guest's telemetry port, sent to sockets subscribed with
+    /// `VmRequest::SubscribeGuestTelemetry`.
+    GuestTelemetryEvent(GuestTelemetryEvent),
+    /// The size in bytes of the virtio-mem region, how much of it the guest was asked to plug and
+    /// how much it has plugged.
+    MemSize {
+        plugged_bytes: u64,
+        requested_bytes: u64,
+        region_bytes: u64,
+    },
+    /// The faults the fault injection device introduces and how often it did so.
+    FaultInjectionStatus {
+        config: FaultInjectionConfig,
+        stats: FaultInjectionStats,
+    },
+    /// The requests a disk has served.
+    DiskStats(DiskStats),
     /// Results of usb control commands.
     UsbResponse(UsbControlResult),
     /// Results of battery control commands.
     BatResponse(BatControlResult),
+    /// Results of a VCPU exit stats request.
+    VcpuExitStats(VcpuExitStats),
+    /// The queue counters of each virtio PCI device, in PCI address order.
+    VirtioStats(Vec<VirtioDeviceStats>),
+    /// Time in seconds since the epoch the guest's RTC alarm will wake it at, if armed.
+    RtcWakeTime(Option<u64>),
+    /// The layout of guest memory and the shared memory backing it, indexed by the regions'
+    /// `shm_index`.
+    GuestMemory {
+        regions: Vec<GuestMemoryRegion>,
+        shms: Vec<File>,
+    },
+    /// The request continues in the background as the operation `id`.
+    OperationStarted { id: u64 },
+    /// The state of the operation `id`.
+    OperationState { id: u64, state: OperationState },
 }
 
 impl Display for VmResponse {
@@ -1037,8 +2224,64 @@ impl Display for VmResponse {
                 "balloon size: {}\nballoon stats: {}",
                 balloon_actual, stats
             ),
+            BalloonSize {
+                num_bytes,
+                actual_bytes,
+            } => write!(
+                f,
+                "balloon target size: {}\nballoon actual size: {}",
+                num_bytes, actual_bytes
+            ),
+            BalloonDiagnostics(diagnostics) => write!(f, "balloon diagnostics: {}", diagnostics),
+            BalloonEvent(event) => write!(f, "{}", event),
+            VirtioEvent(event) => write!(f, "{}", event),
+            GuestTelemetry(telemetry) => write!(f, "{}", telemetry),
+            GuestTelemetryEvent(event) => write!(f, "{}", event),
+            MemSize {
+                plugged_bytes,
+                requested_bytes,
+                region_bytes,
+            } => write!(
+                f,
+                "virtio-mem size: {}\nvirtio-mem requested: {}\nvirtio-mem plugged: {}",
+                region_bytes, requested_bytes, plugged_bytes
+            ),
+            FaultInjectionStatus { config, stats } => {
+                write!(
+                    f,
+                    "fault injection: {}\nfault injection stats: {}",
+                    config, stats
+                )
+            }
+            DiskStats(stats) => write!(f, "disk stats: {}", stats),
             UsbResponse(result) => write!(f, "usb control request get result {:?}", result),
             BatResponse(result) => write!(f, "{}", result),
+            VcpuExitStats(stats) => write!(f, "vcpu exits: {}", stats),
+            VirtioStats(devices) => {
+                write!(f, "virtio stats:")?;
+                for device in devices {
+                    write!(f, "\n{}", device)?;
+                }
+                StdResult::Ok(())
+            }
+            RtcWakeTime(Some(wake_time)) => write!(f, "rtc wake time: {}", wake_time),
+            RtcWakeTime(None) => write!(f, "rtc wake alarm not set"),
+            GuestMemory { regions, .. } => {
+                write!(f, "guest memory:")?;
+                for region in regions {
+                    write!(
+                        f,
+                        "\n{:#x}-{:#x} in shared memory {} at offset {:#x}",
+                        region.guest_base,
+                        region.guest_base + region.size,
+                        region.shm_index,
+                        region.shm_offset
+                    )?;
+                }
+                StdResult::Ok(())
+            }
+            OperationStarted { id } => write!(f, "operation {} started", id),
+            OperationState { id, state } => write!(f, "operation {}: {}", id, state),
         }
     }
 }
@@ -0,0 +1,227 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Control requests that take long to complete run as operations on their own threads, so the
+//! main process keeps serving the VM and the other control sockets in the meantime.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use libc::ECANCELED;
+
+use base::{error, Error as SysError, Event, RawDescriptor, Result};
+use msg_socket::MsgOnSocket;
+use sync::Mutex;
+
+/// The state of an operation started by a control request.
+#[derive(Clone, Copy, MsgOnSocket, Debug, PartialEq)]
+pub enum OperationState {
+    /// The operation has done `done` out of `total` units of work so far.
+    Running {
+        done: u64,
+        total: u64,
+    },
+    Completed,
+    Failed(SysError),
+    /// The operation stopped early because it was cancelled.
+    Cancelled,
+}
+
+impl OperationState {
+    /// Returns true if the operation won't change state anymore.
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, OperationState::Running { .. })
+    }
+}
+
+impl Display for OperationState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::OperationState::*;
+
+        match self {
+            Running { done, total } => write!(f, "running ({}/{})", done, total),
+            Completed => write!(f, "completed"),
+            Failed(e) => write!(f, "failed: {}", e),
+            Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// The progress of a running operation, shared between the thread running it and the main
+/// process.
+pub struct Operation {
+    done: AtomicU64,
+    total: AtomicU64,
+    cancelled: AtomicBool,
+    finished: Mutex<Option<OperationState>>,
+    update_evt: Event,
+}
+
+impl Operation {
+    /// Records that `done` out of `total` units of work are done.
+    pub fn set_progress(&self, done: u64, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+        self.done.store(done, Ordering::Relaxed);
+        self.notify();
+    }
+
+    /// Returns true once the operation was asked to stop. Operations should check this between
+    /// units of work, and fail with `ECANCELED` when it is set.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Returns the current state of the operation.
+    pub fn state(&self) -> OperationState {
+        match *self.finished.lock() {
+            Some(state) => state,
+            None => OperationState::Running {
+                done: self.done.load(Ordering::Relaxed),
+                total: self.total.load(Ordering::Relaxed),
+            },
+        }
+    }
+
+    fn finish(&self, result: Result<()>) {
+        let state = match result {
+            Ok(()) => OperationState::Completed,
+            Err(e) if e.errno() == ECANCELED => OperationState::Cancelled,
+            Err(e) => OperationState::Failed(e),
+        };
+        *self.finished.lock() = Some(state);
+        self.notify();
+    }
+
+    fn notify(&self) {
+        if let Err(e) = self.update_evt.write(1) {
+            error!("failed to signal operation update: {}", e);
+        }
+    }
+}
+
+/// The operations started by control requests, by id.
+///
+/// Finished operations are kept so their final state can still be queried.
+pub struct Operations {
+    next_id: u64,
+    update_evt: Event,
+    operations: BTreeMap<u64, Arc<Operation>>,
+}
+
+impl Operations {
+    pub fn new() -> Result<Operations> {
+        Ok(Operations {
+            next_id: 0,
+            update_evt: Event::new()?,
+            operations: BTreeMap::new(),
+        })
+    }
+
+    /// Returns the event signaled each time any operation makes progress or finishes.
+    pub fn update_evt(&self) -> &Event {
+        &self.update_evt
+    }
+
+    /// Runs `f` on a new thread named after `name`, and returns the id of the new operation.
+    pub fn start<F>(&mut self, name: &str, f: F) -> Result<u64>
+    where
+        F: FnOnce(&Operation) -> Result<()> + Send + 'static,
+    {
+        let id = self.next_id;
+        let operation = Arc::new(Operation {
+            done: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+            finished: Mutex::new(None),
+            update_evt: self.update_evt.try_clone()?,
+        });
+        let thread_operation = operation.clone();
+        thread::Builder::new()
+            .name(format!("{}_{}", name, id))
+            .spawn(move || {
+                let result = f(&thread_operation);
+                thread_operation.finish(result);
+            })?;
+
+        self.next_id += 1;
+        self.operations.insert(id, operation);
+        Ok(id)
+    }
+
+    /// Returns the state of the operation `id`, if it exists.
+    pub fn state(&self, id: u64) -> Option<OperationState> {
+        self.operations.get(&id).map(|operation| operation.state())
+    }
+
+    /// Asks the operation `id` to stop. Returns false if there is no such operation.
+    pub fn cancel(&self, id: u64) -> bool {
+        match self.operations.get(&id) {
+            Some(operation) => {
+                operation.cancelled.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+
+    fn wait_finished(operations: &Operations, id: u64) -> OperationState {
+        loop {
+            operations.update_evt().read().unwrap();
+            let state = operations.state(id).unwrap();
+            if state.is_finished() {
+                return state;
+            }
+        }
+    }
+
+    #[test]
+    fn operation_progress() {
+        let mut operations = Operations::new().unwrap();
+        let (tx, rx) = channel();
+        let id = operations
+            .start("progress", move |operation| {
+                operation.set_progress(1, 2);
+                // Wait for the test to check the progress before finishing.
+                rx.recv().unwrap();
+                Ok(())
+            })
+            .unwrap();
+
+        operations.update_evt().read().unwrap();
+        assert_eq!(
+            operations.state(id),
+            Some(OperationState::Running { done: 1, total: 2 })
+        );
+        tx.send(()).unwrap();
+        assert_eq!(wait_finished(&operations, id), OperationState::Completed);
+        assert_eq!(operations.state(id + 1), None);
+    }
+
+    #[test]
+    fn operation_cancel() {
+        let mut operations = Operations::new().unwrap();
+        let id = operations
+            .start("cancel", |operation| {
+                while !operation.is_cancelled() {
+                    thread::yield_now();
+                }
+                Err(SysError::new(ECANCELED))
+            })
+            .unwrap();
+
+        assert!(operations.cancel(id));
+        assert!(!operations.cancel(id + 1));
+        assert_eq!(wait_finished(&operations, id), OperationState::Cancelled);
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Log of guest memory written by the host outside of vCPU context.
+
+use std::mem::size_of;
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use base::{pagesize, MappedRegion, MemoryMapping, MemoryMappingBuilder};
+
+use crate::guest_address::GuestAddress;
+use crate::guest_memory::{Error, Result};
+
+const BITS_PER_WORD: u64 = 64;
+
+/// A bitmap of the guest pages written by device models, e.g. when a block or net worker DMAs
+/// into guest memory.
+///
+/// The hypervisor only tracks pages dirtied by vCPUs, so anything that snapshots or migrates guest
+/// memory also needs this log to capture device writes. Bit `n` of the bitmap is set when the page
+/// at guest address `n * pagesize()` was written. The bitmap lives in shared memory so that
+/// device processes forked after the log was created still report to the same log.
+pub struct DirtyLog {
+    bitmap: MemoryMapping,
+    num_pages: u64,
+}
+
+impl DirtyLog {
+    /// Creates an empty log covering the guest pages below `end`.
+    pub fn new(end: GuestAddress) -> Result<DirtyLog> {
+        let page_size = pagesize() as u64;
+        let num_pages = (end.offset() + page_size - 1) / page_size;
+        let num_words = (num_pages + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap = MemoryMappingBuilder::new((num_words.max(1) as usize) * size_of::<u64>())
+            .build()
+            .map_err(Error::MemoryMappingFailed)?;
+        Ok(DirtyLog { bitmap, num_pages })
+    }
+
+    fn words(&self) -> &[AtomicU64] {
+        let num_words = (self.num_pages + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        // Safe because the mapping is page aligned, at least `num_words` u64s long and lives as
+        // long as `self`, and all accesses to it are atomic.
+        unsafe {
+            slice::from_raw_parts(self.bitmap.as_ptr() as *const AtomicU64, num_words as usize)
+        }
+    }
+
+    /// Returns the number of guest pages covered by the log.
+    pub fn num_pages(&self) -> u64 {
+        self.num_pages
+    }
+
+    /// Marks the pages overlapping `len` bytes at `addr` as dirty. Pages beyond the end of the log
+    /// are ignored.
+    pub fn mark_dirty(&self, addr: GuestAddress, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let page_size = pagesize() as u64;
+        let first = addr.offset() / page_size;
+        let last = match addr.offset().checked_add(len - 1) {
+            Some(end) => (end / page_size).min(self.num_pages.saturating_sub(1)),
+            None => self.num_pages.saturating_sub(1),
+        };
+        if first >= self.num_pages {
+            return;
+        }
+
+        let words = self.words();
+        for page in first..=last {
+            let word = &words[(page / BITS_PER_WORD) as usize];
+            word.fetch_or(1 << (page % BITS_PER_WORD), Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the bitmap of pages dirtied since the last call and clears the log.
+    pub fn take_bitmap(&self) -> Vec<u64> {
+        self.words()
+            .iter()
+            .map(|word| word.swap(0, Ordering::Relaxed))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mark_and_take() {
+        let page_size = pagesize() as u64;
+        let log = DirtyLog::new(GuestAddress(page_size * 100)).unwrap();
+        assert_eq!(log.num_pages(), 100);
+
+        // Straddles pages 1 and 2.
+        log.mark_dirty(GuestAddress(page_size * 2 - 1), 2);
+        log.mark_dirty(GuestAddress(page_size * 70), 1);
+        // Ignored, past the end of the log.
+        log.mark_dirty(GuestAddress(page_size * 100), page_size);
+
+        assert_eq!(log.take_bitmap(), vec![0b110, 1 << 6]);
+        assert_eq!(log.take_bitmap(), vec![0, 0]);
+    }
+
+    #[test]
+    fn mark_clamps_to_end() {
+        let page_size = pagesize() as u64;
+        let log = DirtyLog::new(GuestAddress(page_size * 2)).unwrap();
+        log.mark_dirty(GuestAddress(page_size), u64::MAX);
+        assert_eq!(log.take_bitmap(), vec![0b10]);
+    }
+}
@@ -7,10 +7,11 @@
 use std::convert::AsRef;
 use std::convert::TryFrom;
 use std::fmt::{self, Display};
-use std::mem::size_of;
+use std::mem::{size_of, MaybeUninit};
 use std::result;
 use std::sync::Arc;
 
+use crate::dirty_log::DirtyLog;
 use crate::guest_address::GuestAddress;
 use base::{pagesize, Error as SysError};
 use base::{
@@ -28,8 +29,10 @@ use data_model::DataInit;
 pub enum Error {
     DescriptorChainOverflow,
     InvalidGuestAddress(GuestAddress),
+    InvalidSharedMemoryIndex(usize),
     MemoryAccess(GuestAddress, MmapError),
     MemoryMappingFailed(MmapError),
+    MemoryRegionNotFound(GuestAddress),
     MemoryRegionOverlap,
     MemoryRegionTooLarge(u64),
     MemoryNotAligned,
@@ -54,10 +57,14 @@ impl Display for Error {
                 "the combined length of all the buffers in a DescriptorChain is too large"
             ),
             InvalidGuestAddress(addr) => write!(f, "invalid guest address {}", addr),
+            InvalidSharedMemoryIndex(index) => {
+                write!(f, "no shared memory at index {} for the region", index)
+            }
             MemoryAccess(addr, e) => {
                 write!(f, "invalid guest memory access at addr={}: {}", addr, e)
             }
             MemoryMappingFailed(e) => write!(f, "failed to map guest memory: {}", e),
+            MemoryRegionNotFound(addr) => write!(f, "no memory region starts at {}", addr),
             MemoryRegionOverlap => write!(f, "memory regions overlap"),
             MemoryRegionTooLarge(size) => write!(f, "memory region size {} is too large", size),
             MemoryNotAligned => write!(f, "memfd regions must be page aligned"),
@@ -85,10 +92,31 @@ impl Display for Error {
     }
 }
 
+// The `f_type` that `statfs` reports for hugetlbfs.
+const HUGETLBFS_MAGIC: u32 = 0x958458f6;
+
+// Returns the size of the host pages backing `shm`. This is larger than the system page size for
+// memory on hugetlbfs, such as a memfd created with `MFD_HUGETLB`.
+fn backing_page_size(shm: &SharedMemory) -> u64 {
+    let mut buf = MaybeUninit::<libc::statfs>::uninit();
+    // Safe because the kernel only writes a `statfs` to `buf`, and the return value is checked.
+    let ret = unsafe { libc::fstatfs(shm.as_raw_descriptor(), buf.as_mut_ptr()) };
+    if ret == 0 {
+        // Safe because `fstatfs` succeeded, so it filled in `buf`.
+        let buf = unsafe { buf.assume_init() };
+        if buf.f_type as u32 == HUGETLBFS_MAGIC {
+            return buf.f_bsize as u64;
+        }
+    }
+    pagesize() as u64
+}
+
 struct MemoryRegion {
     mapping: MemoryMapping,
     guest_base: GuestAddress,
+    shm: Arc<SharedMemory>,
     memfd_offset: u64,
+    page_size: u64,
 }
 
 impl MemoryRegion {
@@ -106,12 +134,35 @@ impl MemoryRegion {
     }
 }
 
+/// Describes a region of guest memory to a process that maps it from shared memory it was sent,
+/// such as a device backend that was not forked from the process that created the memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SharedRegion {
+    pub guest_base: GuestAddress,
+    pub size: u64,
+    /// The index of the shared memory backing the region among those sent along with it.
+    pub shm_index: usize,
+    /// The offset of the region's memory in that shared memory.
+    pub shm_offset: u64,
+}
+
 /// Tracks a memory region and where it is mapped in the guest, along with a shm
 /// fd of the underlying memory regions.
+///
+/// A `GuestMemory` is an immutable snapshot of the guest's memory layout. Regions can be added or
+/// removed after the VM has started with `add_region` and `remove_region`, which return a new
+/// snapshot and leave `self` untouched. Each region's mapping is reference counted, so a device
+/// still holding an older snapshot can keep accessing a removed region until it drops that
+/// snapshot; the host memory is only unmapped once no snapshot refers to it.
+///
+/// Writes made through `GuestMemory` methods are recorded in the dirty log, if one was enabled
+/// with `enable_dirty_log`. Callers writing through slices or host addresses have to call
+/// `mark_dirty` themselves.
 #[derive(Clone)]
 pub struct GuestMemory {
-    regions: Arc<[MemoryRegion]>,
+    regions: Arc<[Arc<MemoryRegion>]>,
     memfd: Arc<SharedMemory>,
+    dirty_log: Option<Arc<DirtyLog>>,
 }
 
 impl AsRawDescriptor for GuestMemory {
@@ -159,9 +210,10 @@ impl GuestMemory {
     pub fn new(ranges: &[(GuestAddress, u64)]) -> Result<GuestMemory> {
         // Create memfd
 
-        let memfd = GuestMemory::create_memfd(ranges)?;
+        let memfd = Arc::new(GuestMemory::create_memfd(ranges)?);
+        let page_size = backing_page_size(&memfd);
         // Create memory regions
-        let mut regions = Vec::<MemoryRegion>::new();
+        let mut regions = Vec::<Arc<MemoryRegion>>::new();
         let mut offset = 0;
 
         for range in ranges {
@@ -178,25 +230,207 @@ impl GuestMemory {
             let size =
                 usize::try_from(range.1).map_err(|_| Error::MemoryRegionTooLarge(range.1))?;
             let mapping = MemoryMappingBuilder::new(size)
-                .from_descriptor(&memfd)
+                .from_descriptor(memfd.as_ref())
                 .offset(offset)
                 .build()
                 .map_err(Error::MemoryMappingFailed)?;
-            regions.push(MemoryRegion {
+            regions.push(Arc::new(MemoryRegion {
                 mapping,
                 guest_base: range.0,
+                shm: memfd.clone(),
                 memfd_offset: offset,
-            });
+                page_size,
+            }));
 
             offset += size as u64;
         }
 
         Ok(GuestMemory {
             regions: Arc::from(regions),
-            memfd: Arc::new(memfd),
+            memfd,
+            dirty_log: None,
+        })
+    }
+
+    /// Maps guest memory that another process described with `shared_regions`, from the shared
+    /// memory `shms` it sent along. Both processes see each other's writes to the memory.
+    ///
+    /// The first of `shms` becomes the memory `offset_from_base` is relative to. The returned
+    /// memory has no dirty log.
+    pub fn from_shared_regions(
+        shared_regions: &[SharedRegion],
+        shms: Vec<SharedMemory>,
+    ) -> Result<GuestMemory> {
+        let shms: Vec<Arc<SharedMemory>> = shms.into_iter().map(Arc::new).collect();
+        let memfd = match shms.first() {
+            Some(shm) => shm.clone(),
+            None => Arc::new(GuestMemory::create_memfd(&[])?),
+        };
+
+        let mut shared_regions = shared_regions.to_vec();
+        shared_regions.sort_by_key(|region| region.guest_base);
+        let mut regions = Vec::<Arc<MemoryRegion>>::with_capacity(shared_regions.len());
+        for region in shared_regions {
+            if let Some(last) = regions.last() {
+                if last.end() > region.guest_base {
+                    return Err(Error::MemoryRegionOverlap);
+                }
+            }
+            region
+                .guest_base
+                .checked_add(region.size)
+                .ok_or(Error::MemoryRegionOverlap)?;
+
+            let shm = shms
+                .get(region.shm_index)
+                .ok_or(Error::InvalidSharedMemoryIndex(region.shm_index))?;
+            let page_size = pagesize() as u64;
+            if region.size % page_size != 0 || region.shm_offset % page_size != 0 {
+                return Err(Error::MemoryNotAligned);
+            }
+            // Touching a mapping past the end of the shared memory would raise SIGBUS.
+            if region
+                .shm_offset
+                .checked_add(region.size)
+                .map_or(true, |end| end > shm.size())
+            {
+                return Err(Error::MemoryRegionTooLarge(region.size));
+            }
+
+            let size = usize::try_from(region.size)
+                .map_err(|_| Error::MemoryRegionTooLarge(region.size))?;
+            let mapping = MemoryMappingBuilder::new(size)
+                .from_descriptor(shm.as_ref())
+                .offset(region.shm_offset)
+                .build()
+                .map_err(Error::MemoryMappingFailed)?;
+            regions.push(Arc::new(MemoryRegion {
+                mapping,
+                guest_base: region.guest_base,
+                page_size: backing_page_size(shm),
+                shm: shm.clone(),
+                memfd_offset: region.shm_offset,
+            }));
+        }
+
+        Ok(GuestMemory {
+            regions: Arc::from(regions),
+            memfd,
+            dirty_log: None,
+        })
+    }
+
+    /// Describes this guest memory for sharing with another process, which maps it with
+    /// `from_shared_regions` after receiving the returned shared memory. Unlike with a forked
+    /// child, the other process does not see regions added or removed afterwards; it has to map a
+    /// new description.
+    ///
+    /// The first shared memory is the one backing the regions `new` created. Writes made by the
+    /// other process are not recorded in the dirty log.
+    pub fn shared_regions(&self) -> (Vec<SharedRegion>, Vec<&SharedMemory>) {
+        let mut shms: Vec<&SharedMemory> = vec![&self.memfd];
+        let mut shared_regions = Vec::with_capacity(self.regions.len());
+        for region in self.regions.iter() {
+            let shm_index = match shms.iter().position(|shm| std::ptr::eq(*shm, &*region.shm)) {
+                Some(index) => index,
+                None => {
+                    shms.push(&region.shm);
+                    shms.len() - 1
+                }
+            };
+            shared_regions.push(SharedRegion {
+                guest_base: region.guest_base,
+                size: region.mapping.size() as u64,
+                shm_index,
+                shm_offset: region.memfd_offset,
+            });
+        }
+        (shared_regions, shms)
+    }
+
+    /// Returns a new snapshot of this guest memory with the whole of `shm` mapped at
+    /// `guest_base`.
+    ///
+    /// The new region must be page aligned in size and must not overlap any existing region.
+    /// Making the region visible to the guest (e.g. registering a hypervisor memory slot) is left
+    /// to the caller.
+    pub fn add_region(&self, guest_base: GuestAddress, shm: SharedMemory) -> Result<GuestMemory> {
+        let size =
+            usize::try_from(shm.size()).map_err(|_| Error::MemoryRegionTooLarge(shm.size()))?;
+        if size % pagesize() != 0 {
+            return Err(Error::MemoryNotAligned);
+        }
+        let end = guest_base
+            .checked_add(size as u64)
+            .ok_or(Error::MemoryRegionOverlap)?;
+        if self.range_overlap(guest_base, end) {
+            return Err(Error::MemoryRegionOverlap);
+        }
+
+        let mapping = MemoryMappingBuilder::new(size)
+            .from_descriptor(&shm)
+            .build()
+            .map_err(Error::MemoryMappingFailed)?;
+        let mut regions = self.regions.to_vec();
+        regions.push(Arc::new(MemoryRegion {
+            mapping,
+            guest_base,
+            page_size: backing_page_size(&shm),
+            shm: Arc::new(shm),
+            memfd_offset: 0,
+        }));
+        regions.sort_by_key(|region| region.start());
+
+        Ok(GuestMemory {
+            regions: Arc::from(regions),
+            memfd: self.memfd.clone(),
+            dirty_log: self.dirty_log.clone(),
         })
     }
 
+    /// Returns a new snapshot of this guest memory without the region starting at `guest_base`.
+    ///
+    /// Existing snapshots, and any slices borrowed from them, stay valid until they are dropped.
+    pub fn remove_region(&self, guest_base: GuestAddress) -> Result<GuestMemory> {
+        let index = self
+            .regions
+            .iter()
+            .position(|region| region.start() == guest_base)
+            .ok_or(Error::MemoryRegionNotFound(guest_base))?;
+        let mut regions = self.regions.to_vec();
+        regions.remove(index);
+
+        Ok(GuestMemory {
+            regions: Arc::from(regions),
+            memfd: self.memfd.clone(),
+            dirty_log: self.dirty_log.clone(),
+        })
+    }
+
+    /// Starts logging writes to the guest pages currently below `end_addr()`.
+    ///
+    /// Only clones of this `GuestMemory` made afterwards share the log, so this should be called
+    /// before handing the memory to devices.
+    pub fn enable_dirty_log(&mut self) -> Result<()> {
+        if self.dirty_log.is_none() {
+            self.dirty_log = Some(Arc::new(DirtyLog::new(self.end_addr())?));
+        }
+        Ok(())
+    }
+
+    /// Returns the dirty log, if logging was enabled.
+    pub fn dirty_log(&self) -> Option<&DirtyLog> {
+        self.dirty_log.as_deref()
+    }
+
+    /// Records that `len` bytes at `addr` were written by the host. This is a no-op unless the
+    /// dirty log is enabled.
+    pub fn mark_dirty(&self, addr: GuestAddress, len: u64) {
+        if let Some(dirty_log) = &self.dirty_log {
+            dirty_log.mark_dirty(addr, len);
+        }
+    }
+
     /// Returns the end address of memory.
     ///
     /// # Examples
@@ -215,7 +449,7 @@ impl GuestMemory {
         self.regions
             .iter()
             .max_by_key(|region| region.start())
-            .map_or(GuestAddress(0), MemoryRegion::end)
+            .map_or(GuestAddress(0), |region| region.end())
     }
 
     /// Returns the total size of memory in bytes.
@@ -255,6 +489,16 @@ impl GuestMemory {
         self.regions.len() as u64
     }
 
+    /// Returns the size of the host pages backing the guest memory at `addr`. The host only gets
+    /// memory back from `remove_range` in whole pages of this size.
+    pub fn backing_page_size(&self, addr: GuestAddress) -> Result<u64> {
+        self.regions
+            .iter()
+            .find(|region| region.contains(addr))
+            .map(|region| region.page_size)
+            .ok_or(Error::InvalidGuestAddress(addr))
+    }
+
     /// Madvise away the address range in the host that is associated with the given guest range.
     pub fn remove_range(&self, addr: GuestAddress, count: u64) -> Result<()> {
         self.do_in_region(addr, move |mapping, offset| {
@@ -272,6 +516,9 @@ impl GuestMemory {
     ///  * size: usize
     ///  * host_addr: usize
     ///  * memfd_offset: usize
+    ///
+    /// For regions added with `add_region`, `memfd_offset` is the offset within that region's own
+    /// shared memory rather than within the memfd backing the initial regions.
     pub fn with_regions<F, E>(&self, mut cb: F) -> result::Result<(), E>
     where
         F: FnMut(usize, GuestAddress, usize, usize, u64) -> result::Result<(), E>,
@@ -308,11 +555,13 @@ impl GuestMemory {
     /// # }
     /// ```
     pub fn write_at_addr(&self, buf: &[u8], guest_addr: GuestAddress) -> Result<usize> {
-        self.do_in_region(guest_addr, move |mapping, offset| {
+        let written = self.do_in_region(guest_addr, move |mapping, offset| {
             mapping
                 .write_slice(buf, offset)
                 .map_err(|e| Error::MemoryAccess(guest_addr, e))
-        })
+        })?;
+        self.mark_dirty(guest_addr, written as u64);
+        Ok(written)
     }
 
     /// Writes the entire contents of a slice to guest memory at the specified
@@ -455,7 +704,9 @@ impl GuestMemory {
             mapping
                 .write_obj(val, offset)
                 .map_err(|e| Error::MemoryAccess(guest_addr, e))
-        })
+        })?;
+        self.mark_dirty(guest_addr, size_of::<T>() as u64);
+        Ok(())
     }
 
     /// Returns a `VolatileSlice` of `len` bytes starting at `addr`. Returns an error if the slice
@@ -552,7 +803,9 @@ impl GuestMemory {
             mapping
                 .read_to_memory(offset, src, count)
                 .map_err(|e| Error::MemoryAccess(guest_addr, e))
-        })
+        })?;
+        self.mark_dirty(guest_addr, count as u64);
+        Ok(())
     }
 
     /// Writes data from memory to a file descriptor.
@@ -642,7 +895,8 @@ impl GuestMemory {
     /// Due to potential gaps within GuestMemory, it is helpful to know the
     /// offset within the memfd where a given address is found. This offset
     /// can then be passed to another process mapping the memfd to read data
-    /// starting at that address.
+    /// starting at that address. Addresses within regions added by `add_region` are not backed by
+    /// self.memfd and return an error.
     ///
     /// # Arguments
     /// * `guest_addr` - Guest address to convert.
@@ -663,7 +917,7 @@ impl GuestMemory {
     pub fn offset_from_base(&self, guest_addr: GuestAddress) -> Result<u64> {
         self.regions
             .iter()
-            .find(|region| region.contains(guest_addr))
+            .find(|region| region.contains(guest_addr) && Arc::ptr_eq(&region.shm, &self.memfd))
             .ok_or(Error::InvalidGuestAddress(guest_addr))
             .map(|region| region.memfd_offset + guest_addr.offset_from(region.start()))
     }
@@ -691,6 +945,9 @@ unsafe impl BackingMemory for GuestMemory {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+
     use base::kernel_has_memfd;
 
     #[test]
@@ -743,6 +1000,118 @@ mod tests {
         assert!(gm.checked_offset(GuestAddress(0x5000), 0x1000).is_none());
     }
 
+    #[test]
+    fn add_remove_region() {
+        let gm = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        let shm = SharedMemory::anon(0x2000).unwrap();
+        let added = gm.add_region(GuestAddress(0x4000), shm).unwrap();
+        assert_eq!(added.num_regions(), 2);
+        assert_eq!(added.memory_size(), 0x3000);
+        assert_eq!(added.end_addr(), GuestAddress(0x6000));
+        assert!(!gm.address_in_range(GuestAddress(0x4000)));
+
+        added
+            .write_obj_at_addr(0x55aa55aau32, GuestAddress(0x5000))
+            .unwrap();
+        assert!(added.offset_from_base(GuestAddress(0x5000)).is_err());
+
+        // Overlapping and unaligned regions are rejected.
+        let overlap = SharedMemory::anon(0x1000).unwrap();
+        assert!(added.add_region(GuestAddress(0x5000), overlap).is_err());
+        let unaligned = SharedMemory::anon(0x100).unwrap();
+        assert!(added.add_region(GuestAddress(0x8000), unaligned).is_err());
+
+        // The old snapshot keeps the removed region accessible until it is dropped.
+        let removed = added.remove_region(GuestAddress(0x4000)).unwrap();
+        assert!(!removed.address_in_range(GuestAddress(0x5000)));
+        let val: u32 = added.read_obj_from_addr(GuestAddress(0x5000)).unwrap();
+        assert_eq!(val, 0x55aa55aa);
+        assert!(removed.remove_region(GuestAddress(0x4000)).is_err());
+
+        // The freed range can be reused.
+        let shm = SharedMemory::anon(0x1000).unwrap();
+        let readded = removed.add_region(GuestAddress(0x5000), shm).unwrap();
+        assert_eq!(readded.num_regions(), 2);
+        let val: u32 = readded.read_obj_from_addr(GuestAddress(0x5000)).unwrap();
+        assert_eq!(val, 0);
+    }
+
+    #[test]
+    fn shared_regions() {
+        if !kernel_has_memfd() {
+            return;
+        }
+
+        let page_size = pagesize() as u64;
+        let gm = GuestMemory::new(&[
+            (GuestAddress(0), page_size),
+            (GuestAddress(page_size * 4), page_size),
+        ])
+        .unwrap()
+        .add_region(
+            GuestAddress(page_size * 8),
+            SharedMemory::anon(page_size).unwrap(),
+        )
+        .unwrap();
+        let (regions, shms) = gm.shared_regions();
+        assert_eq!(shms.len(), 2);
+        assert_eq!(
+            regions[1],
+            SharedRegion {
+                guest_base: GuestAddress(page_size * 4),
+                size: page_size,
+                shm_index: 0,
+                shm_offset: page_size,
+            }
+        );
+        assert_eq!(regions[2].shm_index, 1);
+
+        // Stand in for another process by mapping duplicates of the descriptors.
+        let shms = shms
+            .into_iter()
+            .map(|shm| {
+                // Safe because dup returns a new descriptor that the file takes ownership of.
+                let file = unsafe { File::from_raw_fd(libc::dup(shm.as_raw_descriptor())) };
+                SharedMemory::from_file(file).unwrap()
+            })
+            .collect();
+        let remote = GuestMemory::from_shared_regions(&regions, shms).unwrap();
+        assert_eq!(remote.end_addr(), gm.end_addr());
+        assert_eq!(
+            remote
+                .offset_from_base(GuestAddress(page_size * 4))
+                .unwrap(),
+            page_size
+        );
+        gm.write_obj_at_addr(0x1234u32, GuestAddress(page_size * 4))
+            .unwrap();
+        remote
+            .write_obj_at_addr(0x5678u32, GuestAddress(page_size * 8))
+            .unwrap();
+        assert_eq!(
+            remote
+                .read_obj_from_addr::<u32>(GuestAddress(page_size * 4))
+                .unwrap(),
+            0x1234
+        );
+        assert_eq!(
+            gm.read_obj_from_addr::<u32>(GuestAddress(page_size * 8))
+                .unwrap(),
+            0x5678
+        );
+
+        // Regions past the end of their shared memory or without one are rejected.
+        let region = SharedRegion {
+            guest_base: GuestAddress(0),
+            size: page_size * 2,
+            shm_index: 0,
+            shm_offset: 0,
+        };
+        let shm = SharedMemory::anon(page_size).unwrap();
+        assert!(GuestMemory::from_shared_regions(&[region], vec![shm]).is_err());
+        assert!(GuestMemory::from_shared_regions(&[region], Vec::new()).is_err());
+    }
+
     #[test]
     fn test_read_u64() {
         let start_addr1 = GuestAddress(0x0);
@@ -811,6 +1180,16 @@ mod tests {
         assert_eq!(mem_size, size_region1 + size_region2);
     }
 
+    #[test]
+    fn backing_page_size_default() {
+        let gm = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+        assert_eq!(
+            gm.backing_page_size(GuestAddress(0x800)).unwrap(),
+            pagesize() as u64
+        );
+        assert!(gm.backing_page_size(GuestAddress(0x1000)).is_err());
+    }
+
     // Get the base address of the mapping for a GuestAddress.
     fn get_mapping(mem: &GuestMemory, addr: GuestAddress) -> Result<*const u8> {
         mem.do_in_region(addr, |mapping, _| Ok(mapping.as_ptr() as *const u8))
@@ -870,4 +1249,26 @@ mod tests {
             Ok(())
         });
     }
+
+    #[test]
+    fn dirty_log() {
+        if !kernel_has_memfd() {
+            return;
+        }
+
+        let page_size = pagesize() as u64;
+        let mut gm = GuestMemory::new(&[(GuestAddress(0), page_size * 4)]).unwrap();
+        gm.write_obj_at_addr(1u8, GuestAddress(0)).unwrap();
+        assert!(gm.dirty_log().is_none());
+
+        gm.enable_dirty_log().unwrap();
+        let device_mem = gm.clone();
+        device_mem
+            .write_obj_at_addr(1u8, GuestAddress(page_size))
+            .unwrap();
+        device_mem
+            .write_all_at_addr(&[0u8; 2], GuestAddress(page_size * 3 - 1))
+            .unwrap();
+        assert_eq!(gm.dirty_log().unwrap().take_bitmap(), vec![0b1110]);
+    }
 }
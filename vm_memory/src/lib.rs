@@ -3,9 +3,11 @@
 // found in the LICENSE file.
 //
 
+mod dirty_log;
 mod guest_address;
 pub mod guest_memory;
 
+pub use dirty_log::*;
 pub use guest_address::*;
 pub use guest_memory::Error as GuestMemoryError;
 pub use guest_memory::*;
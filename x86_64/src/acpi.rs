@@ -39,6 +39,19 @@ struct IOAPIC {
 // Safe as IOAPIC structure only contains raw data
 unsafe impl DataInit for IOAPIC {}
 
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct MCFGEntry {
+    _base_address: u64,
+    _segment_group: u16,
+    _start_bus: u8,
+    _end_bus: u8,
+    _reserved: u32,
+}
+
+// Safe as MCFGEntry structure only contains raw data
+unsafe impl DataInit for MCFGEntry {}
+
 const OEM_REVISION: u32 = 1;
 //DSDT
 const DSDT_REVISION: u8 = 6;
@@ -69,6 +82,11 @@ const MADT_TYPE_LOCAL_APIC: u8 = 0;
 const MADT_TYPE_IO_APIC: u8 = 1;
 // MADT flags
 const MADT_ENABLED: u32 = 1;
+// MCFG
+const MCFG_LEN: u32 = 44;
+const MCFG_REVISION: u8 = 1;
+// Each bus takes 1MiB of the ECAM window.
+const MCFG_BUS_SIZE_SHIFT: u64 = 20;
 // XSDT
 const XSDT_REVISION: u8 = 1;
 
@@ -90,7 +108,7 @@ fn create_dsdt_table(amls: Vec<u8>) -> SDT {
 }
 
 /// Create ACPI tables and return the RSDP.
-/// The basic tables DSDT/FACP/MADT/XSDT are constructed in this function.
+/// The basic tables DSDT/FACP/MADT/MCFG/XSDT are constructed in this function.
 /// # Arguments
 ///
 /// * `guest_mem` - The guest memory where the tables will be stored.
@@ -99,11 +117,15 @@ fn create_dsdt_table(amls: Vec<u8>) -> SDT {
 ///               is going to be used by the ACPI drivers to register
 ///               sci handler.
 /// * `acpi_dev_resource` - resouces needed by the ACPI devices for creating tables
+/// * `pcie_cfg_mmio_start` - Base address of the PCIe ECAM window, used to construct the MCFG.
+/// * `pcie_cfg_mmio_size` - Size of the PCIe ECAM window, used to construct the MCFG.
 pub fn create_acpi_tables(
     guest_mem: &GuestMemory,
     num_cpus: u8,
     sci_irq: u32,
     acpi_dev_resource: ACPIDevResource,
+    pcie_cfg_mmio_start: u64,
+    pcie_cfg_mmio_size: u64,
 ) -> Option<GuestAddress> {
     // RSDP is at the HI RSDP WINDOW
     let rsdp_offset = GuestAddress(super::ACPI_HI_RSDP_WINDOW_BASE);
@@ -220,6 +242,25 @@ pub fn create_acpi_tables(
     tables.push(offset.0);
     offset = offset.checked_add(madt.len() as u64)?;
 
+    // MCFG
+    let mut mcfg = SDT::new(
+        *b"MCFG",
+        MCFG_LEN,
+        MCFG_REVISION,
+        *b"CROSVM",
+        *b"CROSVMDT",
+        OEM_REVISION,
+    );
+    mcfg.append(MCFGEntry {
+        _base_address: pcie_cfg_mmio_start,
+        _end_bus: ((pcie_cfg_mmio_size >> MCFG_BUS_SIZE_SHIFT) - 1) as u8,
+        ..Default::default()
+    });
+
+    guest_mem.write_at_addr(mcfg.as_slice(), offset).ok()?;
+    tables.push(offset.0);
+    offset = offset.checked_add(mcfg.len() as u64)?;
+
     // XSDT
     let mut xsdt = SDT::new(
         *b"XSDT",
@@ -6,6 +6,7 @@ use std::arch::x86_64::{__cpuid, __cpuid_count};
 use std::fmt::{self, Display};
 use std::result;
 
+use arch::PvFeatures;
 use devices::{IrqChipCap, IrqChipX86_64};
 use hypervisor::{HypervisorX86_64, VcpuX86_64};
 
@@ -43,12 +44,19 @@ const ECX_TOPO_TYPE_SHIFT: u32 = 8; // Topology Level type.
 const ECX_TOPO_SMT_TYPE: u32 = 1; // SMT type.
 const ECX_TOPO_CORE_TYPE: u32 = 2; // CORE type.
 
+// KVM paravirtualized features, in eax of the KVM_CPUID_FEATURES leaf.
+const KVM_CPUID_FEATURES: u32 = 0x40000001;
+const EAX_KVM_FEATURE_STEAL_TIME_SHIFT: u32 = 5; // Steal time accounting.
+const EAX_KVM_FEATURE_PV_TLB_FLUSH_SHIFT: u32 = 9; // TLB flushes of preempted VCPUs.
+const EAX_KVM_FEATURE_PV_SEND_IPI_SHIFT: u32 = 11; // IPIs to several VCPUs in one hypercall.
+
 fn filter_cpuid(
     vcpu_id: usize,
     cpu_count: usize,
     cpuid: &mut hypervisor::CpuId,
     irq_chip: &dyn IrqChipX86_64,
     no_smt: bool,
+    pv_features: PvFeatures,
 ) -> Result<()> {
     let entries = &mut cpuid.cpu_id_entries;
 
@@ -118,6 +126,18 @@ fn filter_cpuid(
                     }
                 }
             }
+            KVM_CPUID_FEATURES => {
+                // Features the hypervisor doesn't support are already clear.
+                if !pv_features.steal_time {
+                    entry.eax &= !(1 << EAX_KVM_FEATURE_STEAL_TIME_SHIFT);
+                }
+                if !pv_features.tlb_flush {
+                    entry.eax &= !(1 << EAX_KVM_FEATURE_PV_TLB_FLUSH_SHIFT);
+                }
+                if !pv_features.send_ipi {
+                    entry.eax &= !(1 << EAX_KVM_FEATURE_PV_SEND_IPI_SHIFT);
+                }
+            }
             _ => (),
         }
     }
@@ -134,6 +154,8 @@ fn filter_cpuid(
 /// * `vcpu` - `VcpuX86_64` for setting CPU ID.
 /// * `vcpu_id` - The vcpu index of `vcpu`.
 /// * `nrcpus` - The number of vcpus being used by this VM.
+/// * `no_smt` - Whether each vcpu appears as a separate core.
+/// * `pv_features` - The paravirtualized features offered to the guest.
 pub fn setup_cpuid(
     hypervisor: &dyn HypervisorX86_64,
     irq_chip: &dyn IrqChipX86_64,
@@ -141,12 +163,13 @@ pub fn setup_cpuid(
     vcpu_id: usize,
     nrcpus: usize,
     no_smt: bool,
+    pv_features: PvFeatures,
 ) -> Result<()> {
     let mut cpuid = hypervisor
         .get_supported_cpuid()
         .map_err(Error::GetSupportedCpusFailed)?;
 
-    filter_cpuid(vcpu_id, nrcpus, &mut cpuid, irq_chip, no_smt)?;
+    filter_cpuid(vcpu_id, nrcpus, &mut cpuid, irq_chip, no_smt, pv_features)?;
 
     vcpu.set_cpuid(&cpuid)
         .map_err(Error::SetSupportedCpusFailed)
@@ -190,7 +213,10 @@ mod tests {
             edx: 0,
             ..Default::default()
         });
-        assert_eq!(Ok(()), filter_cpuid(1, 2, &mut cpuid, &irq_chip, false));
+        assert_eq!(
+            Ok(()),
+            filter_cpuid(1, 2, &mut cpuid, &irq_chip, false, PvFeatures::default())
+        );
 
         let entries = &mut cpuid.cpu_id_entries;
         assert_eq!(entries[0].function, 0);
@@ -203,4 +229,35 @@ mod tests {
         assert_ne!(0, entries[1].ecx & (1 << ECX_HYPERVISOR_SHIFT));
         assert_ne!(0, entries[1].edx & (1 << EDX_HTT_SHIFT));
     }
+
+    #[test]
+    fn pv_features() {
+        let mut cpuid = hypervisor::CpuId::new(1);
+        let guest_mem =
+            vm_memory::GuestMemory::new(&[(vm_memory::GuestAddress(0), 0x10000)]).unwrap();
+        let kvm = hypervisor::kvm::Kvm::new().unwrap();
+        let vm = hypervisor::kvm::KvmVm::new(&kvm, guest_mem).unwrap();
+        let irq_chip = devices::KvmKernelIrqChip::new(vm, 1).unwrap();
+
+        cpuid.cpu_id_entries.push(CpuIdEntry {
+            function: KVM_CPUID_FEATURES,
+            eax: (1 << EAX_KVM_FEATURE_STEAL_TIME_SHIFT)
+                | (1 << EAX_KVM_FEATURE_PV_TLB_FLUSH_SHIFT)
+                | (1 << EAX_KVM_FEATURE_PV_SEND_IPI_SHIFT),
+            ..Default::default()
+        });
+        let pv_features = PvFeatures {
+            steal_time: true,
+            tlb_flush: false,
+            send_ipi: false,
+        };
+        assert_eq!(
+            Ok(()),
+            filter_cpuid(0, 1, &mut cpuid, &irq_chip, false, pv_features)
+        );
+        assert_eq!(
+            cpuid.cpu_id_entries[0].eax,
+            1 << EAX_KVM_FEATURE_STEAL_TIME_SHIFT
+        );
+    }
 }
@@ -5,6 +5,7 @@
 mod fdt;
 
 const E820_RAM: u32 = 1;
+const E820_RESERVED: u32 = 2;
 const SETUP_DTB: u32 = 2;
 const X86_64_FDT_MAX_SIZE: u64 = 0x200000;
 
@@ -57,17 +58,19 @@ use crate::bootparam::boot_params;
 use acpi_tables::aml::Aml;
 use acpi_tables::sdt::SDT;
 use arch::{
-    get_serial_cmdline, GetSerialCmdlineError, RunnableLinuxVm, SerialHardware, SerialParameters,
-    VmComponents, VmImage,
+    get_serial_cmdline, ArchLayout, GetSerialCmdlineError, PvFeatures, RunnableLinuxVm,
+    SerialHardware, SerialParameters, VmComponents, VmImage,
 };
 use base::Event;
-use devices::{IrqChip, IrqChipX86_64, PciConfigIo, PciDevice};
+use devices::{
+    IrqChip, IrqChipX86_64, PciConfigIo, PciConfigMmio, PciDevice, PCIE_CONFIG_REGISTER_BITS,
+};
 use hypervisor::{HypervisorX86_64, VcpuX86_64, VmX86_64};
 use minijail::Minijail;
 use remain::sorted;
 use resources::SystemAllocator;
 use sync::Mutex;
-use vm_control::{BatControl, BatteryType};
+use vm_control::{BatControl, BatteryType, RtcWakeAlarm};
 use vm_memory::{GuestAddress, GuestMemory, GuestMemoryError};
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
 use {
@@ -90,6 +93,7 @@ pub enum Error {
     CreateIoapicDevice(base::Error),
     CreateIrqChip(Box<dyn StdError>),
     CreatePciRoot(arch::DeviceRegistrationError),
+    CreatePflash(devices::PflashError),
     CreatePit(base::Error),
     CreatePitDevice(devices::PitError),
     CreateSerialDevices(arch::DeviceRegistrationError),
@@ -100,6 +104,7 @@ pub enum Error {
     EnableSinglestep(base::Error),
     EnableSplitIrqchip(base::Error),
     GetSerialCmdline(GetSerialCmdlineError),
+    InvalidLayout(&'static str),
     KernelOffsetPastEnd,
     LoadBios(io::Error),
     LoadBzImage(bzimage::Error),
@@ -107,12 +112,15 @@ pub enum Error {
     LoadInitrd(arch::LoadImageError),
     LoadKernel(kernel_loader::Error),
     PageNotPresent,
+    PflashTooLarge(u64),
     Pstore(arch::pstore::Error),
     ReadingGuestMemory(vm_memory::GuestMemoryError),
     ReadRegs(base::Error),
     RegisterIrqfd(base::Error),
+    RegisterPflash(devices::BusError),
     RegisterVsock(arch::DeviceRegistrationError),
     SetHwBreakpoint(base::Error),
+    SetIdentityMapAddr(base::Error),
     SetLint(interrupts::Error),
     SetTssAddr(base::Error),
     SetupCpuid(cpuid::Error),
@@ -149,6 +157,7 @@ impl Display for Error {
             CreateIoapicDevice(e) => write!(f, "failed to create IOAPIC device: {}", e),
             CreateIrqChip(e) => write!(f, "failed to create IRQ chip: {}", e),
             CreatePciRoot(e) => write!(f, "failed to create a PCI root hub: {}", e),
+            CreatePflash(e) => write!(f, "failed to create pflash device: {}", e),
             CreatePit(e) => write!(f, "unable to create PIT: {}", e),
             CreatePitDevice(e) => write!(f, "unable to make PIT device: {}", e),
             CreateSerialDevices(e) => write!(f, "unable to create serial devices: {}", e),
@@ -159,6 +168,7 @@ impl Display for Error {
             EnableSinglestep(e) => write!(f, "failed to enable singlestep execution: {}", e),
             EnableSplitIrqchip(e) => write!(f, "failed to enable split irqchip: {}", e),
             GetSerialCmdline(e) => write!(f, "failed to get serial cmdline: {}", e),
+            InvalidLayout(reason) => write!(f, "invalid guest address space layout: {}", reason),
             KernelOffsetPastEnd => write!(f, "the kernel extends past the end of RAM"),
             LoadBios(e) => write!(f, "error loading bios: {}", e),
             LoadBzImage(e) => write!(f, "error loading kernel bzImage: {}", e),
@@ -166,12 +176,19 @@ impl Display for Error {
             LoadInitrd(e) => write!(f, "error loading initrd: {}", e),
             LoadKernel(e) => write!(f, "error loading Kernel: {}", e),
             PageNotPresent => write!(f, "error translating address: Page not present"),
+            PflashTooLarge(size) => write!(
+                f,
+                "pflash image is {:#x} bytes, at most {:#x} are supported",
+                size, PFLASH_MAX_LEN
+            ),
             Pstore(e) => write!(f, "failed to allocate pstore region: {}", e),
             ReadingGuestMemory(e) => write!(f, "error reading guest memory {}", e),
             ReadRegs(e) => write!(f, "error reading CPU registers {}", e),
             RegisterIrqfd(e) => write!(f, "error registering an IrqFd: {}", e),
+            RegisterPflash(e) => write!(f, "error registering pflash device: {}", e),
             RegisterVsock(e) => write!(f, "error registering virtual socket device: {}", e),
             SetHwBreakpoint(e) => write!(f, "failed to set a hardware breakpoint: {}", e),
+            SetIdentityMapAddr(e) => write!(f, "failed to set identity map addr: {}", e),
             SetLint(e) => write!(f, "failed to set interrupts: {}", e),
             SetTssAddr(e) => write!(f, "failed to set tss addr: {}", e),
             SetupCpuid(e) => write!(f, "failed to set up cpuid: {}", e),
@@ -200,9 +217,17 @@ pub struct X8664arch;
 const BOOT_STACK_POINTER: u64 = 0x8000;
 // Make sure it align to 256MB for MTRR convenient
 const MEM_32BIT_GAP_SIZE: u64 = 768 << 20;
+const MEM_32BIT_GAP_ALIGN: u64 = 256 << 20;
+// Leave at least 1 GiB of RAM below the gap for the kernel, the command line, and the initrd.
+const MAX_MEM_32BIT_GAP_SIZE: u64 = 3 << 30;
 const FIRST_ADDR_PAST_32BITS: u64 = 1 << 32;
-const END_ADDR_BEFORE_32BITS: u64 = FIRST_ADDR_PAST_32BITS - MEM_32BIT_GAP_SIZE;
-const MMIO_SIZE: u64 = MEM_32BIT_GAP_SIZE - 0x8000000;
+// The top of the gap holds the PCIe config window, the identity map and TSS pages, the IOAPIC,
+// the local APIC, and the BIOS. The rest is given to devices.
+const RESERVED_32BIT_GAP_SIZE: u64 = 0x8000000;
+// PCIe extended configuration space (ECAM) window, placed right after the low MMIO region and
+// large enough for buses 0 to 31.
+const PCIE_CFG_MMIO_START: u64 = FIRST_ADDR_PAST_32BITS - RESERVED_32BIT_GAP_SIZE;
+const PCIE_CFG_MMIO_SIZE: u64 = 0x2000000;
 const KERNEL_64BIT_ENTRY_OFFSET: u64 = 0x200;
 const ZERO_PAGE_OFFSET: u64 = 0x7000;
 /// The x86 reset vector for i386+ and x86_64 puts the processor into an "unreal mode" where it
@@ -210,7 +235,13 @@ const ZERO_PAGE_OFFSET: u64 = 0x7000;
 /// pointer at the effective physical address 0xFFFFFFF0.
 const BIOS_LEN: usize = 1 << 20;
 const BIOS_START: u64 = FIRST_ADDR_PAST_32BITS - (BIOS_LEN as u64);
-const TSS_ADDR: u64 = 0xfffbd000;
+// One page for the identity map, followed by three for the TSS.
+const IDENTITY_MAP_ADDR: u64 = 0xfffbc000;
+const IDENTITY_MAP_AND_TSS_SIZE: u64 = 0x4000;
+const LAPIC_MEM_LENGTH_BYTES: u64 = 0x1000;
+// The pflash device is mapped directly below the BIOS, and must not reach down into the local
+// APIC.
+const PFLASH_MAX_LEN: u64 = 1 << 20;
 
 const KERNEL_START_OFFSET: u64 = 0x200000;
 const CMDLINE_OFFSET: u64 = 0x20000;
@@ -228,6 +259,77 @@ pub const X86_64_SCI_IRQ: u32 = 5;
 pub const X86_64_IRQ_BASE: u32 = 9;
 const ACPI_HI_RSDP_WINDOW_BASE: u64 = 0x000E0000;
 
+/// The guest physical address map of a VM: the defaults, with the overrides of its `ArchLayout`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct MemoryLayout {
+    // Start of the gap below 4 GiB. RAM that doesn't fit below it goes above 4 GiB.
+    low_mmio_start: u64,
+    high_mmio_start: Option<u64>,
+    high_mmio_size: Option<u64>,
+    identity_map_addr: u64,
+}
+
+impl MemoryLayout {
+    fn new(layout: &ArchLayout) -> Result<MemoryLayout> {
+        if layout.ram_start.unwrap_or(0) != 0 {
+            return Err(Error::InvalidLayout("RAM must start at 0"));
+        }
+        let gap_size = layout.low_mmio_size.unwrap_or(MEM_32BIT_GAP_SIZE);
+        if gap_size % MEM_32BIT_GAP_ALIGN != 0
+            || gap_size <= RESERVED_32BIT_GAP_SIZE
+            || gap_size > MAX_MEM_32BIT_GAP_SIZE
+        {
+            return Err(Error::InvalidLayout(
+                "the low MMIO size must be a multiple of 256 MiB from 256 MiB to 3 GiB",
+            ));
+        }
+        let identity_map_addr = layout.identity_map_addr.unwrap_or(IDENTITY_MAP_ADDR);
+        if identity_map_addr % base::pagesize() as u64 != 0
+            || identity_map_addr < PCIE_CFG_MMIO_START + PCIE_CFG_MMIO_SIZE
+            || identity_map_addr > FIRST_ADDR_PAST_32BITS - IDENTITY_MAP_AND_TSS_SIZE
+        {
+            return Err(Error::InvalidLayout(
+                "the identity map must be page aligned, between the PCIe config window and 4 GiB",
+            ));
+        }
+        let overlaps_identity_map = |start: u64, len: u64| {
+            start < identity_map_addr + IDENTITY_MAP_AND_TSS_SIZE && identity_map_addr < start + len
+        };
+        if overlaps_identity_map(
+            mptable::IO_APIC_DEFAULT_PHYS_BASE as u64,
+            devices::IOAPIC_MEM_LENGTH_BYTES,
+        ) || overlaps_identity_map(
+            mptable::APIC_DEFAULT_PHYS_BASE as u64,
+            LAPIC_MEM_LENGTH_BYTES,
+        ) {
+            return Err(Error::InvalidLayout(
+                "the identity map must not overlap the IOAPIC or the local APIC",
+            ));
+        }
+        Ok(MemoryLayout {
+            low_mmio_start: FIRST_ADDR_PAST_32BITS - gap_size,
+            high_mmio_start: layout.high_mmio_start,
+            high_mmio_size: layout.high_mmio_size,
+            identity_map_addr,
+        })
+    }
+
+    // Size of the part of the gap below 4 GiB given to devices.
+    fn low_mmio_size(&self) -> u64 {
+        PCIE_CFG_MMIO_START - self.low_mmio_start
+    }
+
+    fn tss_addr(&self) -> u64 {
+        self.identity_map_addr + 0x1000
+    }
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        MemoryLayout::new(&ArchLayout::default()).unwrap()
+    }
+}
+
 fn configure_system(
     guest_mem: &GuestMemory,
     _mem_size: u64,
@@ -236,6 +338,7 @@ fn configure_system(
     cmdline_size: usize,
     setup_data: Option<GuestAddress>,
     initrd: Option<(GuestAddress, usize)>,
+    layout: &MemoryLayout,
     mut params: boot_params,
 ) -> Result<()> {
     const EBDA_START: u64 = 0x0009fc00;
@@ -244,7 +347,7 @@ fn configure_system(
     const KERNEL_LOADER_OTHER: u8 = 0xff;
     const KERNEL_MIN_ALIGNMENT_BYTES: u32 = 0x1000000; // Must be non-zero.
     let first_addr_past_32bits = GuestAddress(FIRST_ADDR_PAST_32BITS);
-    let end_32bit_gap_start = GuestAddress(END_ADDR_BEFORE_32BITS);
+    let end_32bit_gap_start = GuestAddress(layout.low_mmio_start);
 
     params.hdr.type_of_loader = KERNEL_LOADER_OTHER;
     params.hdr.boot_flag = KERNEL_BOOT_FLAG_MAGIC;
@@ -287,6 +390,13 @@ fn configure_system(
         }
     }
 
+    add_e820_entry(
+        &mut params,
+        PCIE_CFG_MMIO_START,
+        PCIE_CFG_MMIO_SIZE,
+        E820_RESERVED,
+    )?;
+
     let zero_page_addr = GuestAddress(ZERO_PAGE_OFFSET);
     guest_mem
         .checked_offset(zero_page_addr, mem::size_of::<boot_params>() as u64)
@@ -317,10 +427,14 @@ fn add_e820_entry(params: &mut boot_params, addr: u64, size: u64, mem_type: u32)
 /// These should be used to configure the GuestMemory structure for the platform.
 /// For x86_64 all addresses are valid from the start of the kernel except a
 /// carve out at the end of 32bit address space.
-fn arch_memory_regions(size: u64, has_bios: bool) -> Vec<(GuestAddress, u64)> {
+fn arch_memory_regions(
+    size: u64,
+    has_bios: bool,
+    layout: &MemoryLayout,
+) -> Vec<(GuestAddress, u64)> {
     let mem_end = GuestAddress(size);
     let first_addr_past_32bits = GuestAddress(FIRST_ADDR_PAST_32BITS);
-    let end_32bit_gap_start = GuestAddress(END_ADDR_BEFORE_32BITS);
+    let end_32bit_gap_start = GuestAddress(layout.low_mmio_start);
 
     let mut regions = Vec::new();
     if mem_end <= end_32bit_gap_start {
@@ -355,7 +469,7 @@ impl arch::LinuxArch for X8664arch {
         create_irq_chip: FI,
     ) -> std::result::Result<RunnableLinuxVm<V, Vcpu, I>, Self::Error>
     where
-        V: VmX86_64,
+        V: VmX86_64 + 'static,
         Vcpu: VcpuX86_64,
         I: IrqChipX86_64,
         FD: FnOnce(
@@ -370,17 +484,20 @@ impl arch::LinuxArch for X8664arch {
         E2: StdError + 'static,
         E3: StdError + 'static,
     {
+        let layout = MemoryLayout::new(&components.layout)?;
         let has_bios = matches!(components.vm_image, VmImage::Bios(_));
-        let mem = Self::setup_memory(components.memory_size, has_bios)?;
-        let mut resources = Self::get_resource_allocator(&mem, components.wayland_dmabuf);
+        let mem = Self::setup_memory(components.memory_size, has_bios, &layout)?;
+        let mut resources = Self::get_resource_allocator(&mem, components.wayland_dmabuf, &layout)?;
 
         let vcpu_count = components.vcpu_count;
         let mut vm = create_vm(mem.clone()).map_err(|e| Error::CreateVm(Box::new(e)))?;
         let mut irq_chip =
             create_irq_chip(&vm, vcpu_count).map_err(|e| Error::CreateIrqChip(Box::new(e)))?;
 
-        let tss_addr = GuestAddress(TSS_ADDR);
-        vm.set_tss_addr(tss_addr).map_err(Error::SetTssAddr)?;
+        vm.set_identity_map_addr(GuestAddress(layout.identity_map_addr))
+            .map_err(Error::SetIdentityMapAddr)?;
+        vm.set_tss_addr(GuestAddress(layout.tss_addr()))
+            .map_err(Error::SetTssAddr)?;
 
         let mut mmio_bus = devices::Bus::new();
 
@@ -397,16 +514,27 @@ impl arch::LinuxArch for X8664arch {
             4, // Share the four pin interrupts (INTx#)
         )
         .map_err(Error::CreatePciRoot)?;
-        let pci_bus = Arc::new(Mutex::new(PciConfigIo::new(pci)));
+        let pci = Arc::new(Mutex::new(pci));
+        let pci_bus = Arc::new(Mutex::new(PciConfigIo::new(pci.clone())));
+        let pcie_cfg_mmio = Arc::new(Mutex::new(PciConfigMmio::new(
+            pci.clone(),
+            PCIE_CONFIG_REGISTER_BITS,
+        )));
+        mmio_bus
+            .insert(pcie_cfg_mmio, PCIE_CFG_MMIO_START, PCIE_CFG_MMIO_SIZE)
+            .unwrap();
 
         // Event used to notify crosvm that guest OS is trying to suspend.
         let suspend_evt = Event::new().map_err(Error::CreateEvent)?;
+        let rtc_wake_alarm = RtcWakeAlarm::default();
 
         let mut io_bus = Self::setup_io_bus(
             irq_chip.pit_uses_speaker_port(),
             exit_evt.try_clone().map_err(Error::CloneEvent)?,
             Some(pci_bus.clone()),
             components.memory_size,
+            &layout,
+            rtc_wake_alarm.clone(),
         )?;
 
         Self::setup_serial_devices(
@@ -428,6 +556,10 @@ impl arch::LinuxArch for X8664arch {
             &mut mmio_bus,
         )?;
 
+        if let Some(pflash_image) = components.pflash_image.take() {
+            Self::setup_pflash(&mut mmio_bus, pflash_image)?;
+        }
+
         let ramoops_region = match components.pstore {
             Some(pstore) => Some(
                 arch::pstore::create_memory_region(&mut vm, &mut resources, &pstore)
@@ -450,9 +582,16 @@ impl arch::LinuxArch for X8664arch {
 
         // Note that this puts the mptable at 0x9FC00 in guest physical memory.
         mptable::setup_mptable(&mem, vcpu_count as u8, pci_irqs).map_err(Error::SetupMptable)?;
-        smbios::setup_smbios(&mem).map_err(Error::SetupSmbios)?;
+        smbios::setup_smbios(&mem, components.uuid).map_err(Error::SetupSmbios)?;
         // TODO (tjeznach) Write RSDP to bootconfig before writing to memory
-        acpi::create_acpi_tables(&mem, vcpu_count as u8, X86_64_SCI_IRQ, acpi_dev_resource);
+        acpi::create_acpi_tables(
+            &mem,
+            vcpu_count as u8,
+            X86_64_SCI_IRQ,
+            acpi_dev_resource,
+            PCIE_CFG_MMIO_START,
+            PCIE_CFG_MMIO_SIZE,
+        );
 
         match components.vm_image {
             VmImage::Bios(ref mut bios) => Self::load_bios(&mem, bios)?,
@@ -495,6 +634,7 @@ impl arch::LinuxArch for X8664arch {
                     components.initrd_image,
                     components.android_fstab,
                     kernel_end,
+                    &layout,
                     params,
                 )?;
             }
@@ -508,6 +648,8 @@ impl arch::LinuxArch for X8664arch {
             vcpus: None,
             vcpu_affinity: components.vcpu_affinity,
             no_smt: components.no_smt,
+            pv_features: components.pv_features,
+            layout: components.layout,
             irq_chip,
             has_bios,
             io_bus,
@@ -516,6 +658,8 @@ impl arch::LinuxArch for X8664arch {
             suspend_evt,
             rt_cpus: components.rt_cpus,
             bat_control,
+            rtc_wake_alarm,
+            pci_root: pci,
             #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
             gdb: components.gdb,
         })
@@ -530,16 +674,27 @@ impl arch::LinuxArch for X8664arch {
         num_cpus: usize,
         has_bios: bool,
         no_smt: bool,
+        pv_features: PvFeatures,
+        layout: ArchLayout,
     ) -> Result<()> {
-        cpuid::setup_cpuid(hypervisor, irq_chip, vcpu, vcpu_id, num_cpus, no_smt)
-            .map_err(Error::SetupCpuid)?;
+        cpuid::setup_cpuid(
+            hypervisor,
+            irq_chip,
+            vcpu,
+            vcpu_id,
+            num_cpus,
+            no_smt,
+            pv_features,
+        )
+        .map_err(Error::SetupCpuid)?;
 
         if has_bios {
             return Ok(());
         }
 
         let kernel_load_addr = GuestAddress(KERNEL_START_OFFSET);
-        regs::setup_msrs(vcpu, END_ADDR_BEFORE_32BITS).map_err(Error::SetupMsrs)?;
+        let layout = MemoryLayout::new(&layout)?;
+        regs::setup_msrs(vcpu, layout.low_mmio_start).map_err(Error::SetupMsrs)?;
         let kernel_end = guest_mem
             .checked_offset(kernel_load_addr, KERNEL_64BIT_ENTRY_OFFSET)
             .ok_or(Error::KernelOffsetPastEnd)?;
@@ -787,6 +942,20 @@ fn phys_addr(mem: &GuestMemory, vaddr: u64, sregs: &Sregs) -> Result<(u64, u64)>
 }
 
 impl X8664arch {
+    /// Maps a flash device backed by `pflash_image` directly below the BIOS, where UEFI firmware
+    /// looks for its variable store.
+    fn setup_pflash(mmio_bus: &mut devices::Bus, pflash_image: File) -> Result<()> {
+        let pflash = devices::Pflash::new(pflash_image).map_err(Error::CreatePflash)?;
+        let size = pflash.size();
+        if size > PFLASH_MAX_LEN {
+            return Err(Error::PflashTooLarge(size));
+        }
+        mmio_bus
+            .insert(Arc::new(Mutex::new(pflash)), BIOS_START - size, size)
+            .map_err(Error::RegisterPflash)?;
+        Ok(())
+    }
+
     /// Loads the bios from an open file.
     ///
     /// # Arguments
@@ -847,6 +1016,7 @@ impl X8664arch {
         initrd_file: Option<File>,
         android_fstab: Option<File>,
         kernel_end: u64,
+        layout: &MemoryLayout,
         params: boot_params,
     ) -> Result<()> {
         kernel_loader::load_cmdline(mem, GuestAddress(CMDLINE_OFFSET), cmdline)
@@ -906,6 +1076,7 @@ impl X8664arch {
             cmdline.to_bytes().len() + 1,
             setup_data,
             initrd,
+            layout,
             params,
         )?;
         Ok(())
@@ -914,23 +1085,45 @@ impl X8664arch {
     /// This creates a GuestMemory object for this VM
     ///
     /// * `mem_size` - Desired physical memory size in bytes for this VM
-    fn setup_memory(mem_size: u64, has_bios: bool) -> Result<GuestMemory> {
-        let arch_mem_regions = arch_memory_regions(mem_size, has_bios);
+    fn setup_memory(mem_size: u64, has_bios: bool, layout: &MemoryLayout) -> Result<GuestMemory> {
+        let arch_mem_regions = arch_memory_regions(mem_size, has_bios, layout);
         let mem = GuestMemory::new(&arch_mem_regions).map_err(Error::SetupGuestMemory)?;
         Ok(mem)
     }
 
-    /// This returns the start address of high mmio
+    /// This returns the start address and size of high mmio
     ///
     /// # Arguments
     ///
     /// * mem: The memory to be used by the guest
-    fn get_high_mmio_base(mem: &GuestMemory) -> u64 {
+    /// * layout: The layout of the guest address space, which may place high mmio
+    fn get_high_mmio_base_size(mem: &GuestMemory, layout: &MemoryLayout) -> Result<(u64, u64)> {
         // Put device memory at a 2MB boundary after physical memory or 4gb, whichever is greater.
         const MB: u64 = 1 << 20;
         const GB: u64 = 1 << 30;
-        let ram_end_round_2mb = (mem.end_addr().offset() + 2 * MB - 1) / (2 * MB) * (2 * MB);
-        std::cmp::max(ram_end_round_2mb, 4 * GB)
+        let base = match layout.high_mmio_start {
+            Some(base) if base < std::cmp::max(mem.end_addr().offset(), 4 * GB) => {
+                return Err(Error::InvalidLayout(
+                    "the high MMIO window must start above RAM and 4 GiB",
+                ))
+            }
+            Some(base) => base,
+            None => {
+                let ram_end_round_2mb =
+                    (mem.end_addr().offset() + 2 * MB - 1) / (2 * MB) * (2 * MB);
+                std::cmp::max(ram_end_round_2mb, 4 * GB)
+            }
+        };
+        let size = match layout.high_mmio_size {
+            Some(size) if size == 0 || base.checked_add(size - 1).is_none() => {
+                return Err(Error::InvalidLayout(
+                    "the high MMIO window must fit in the address space",
+                ))
+            }
+            Some(size) => size,
+            None => u64::max_value() - base,
+        };
+        Ok((base, size))
     }
 
     /// This returns a minimal kernel command for this architecture
@@ -942,14 +1135,18 @@ impl X8664arch {
     }
 
     /// Returns a system resource allocator.
-    fn get_resource_allocator(mem: &GuestMemory, gpu_allocation: bool) -> SystemAllocator {
-        let high_mmio_start = Self::get_high_mmio_base(mem);
-        SystemAllocator::builder()
+    fn get_resource_allocator(
+        mem: &GuestMemory,
+        gpu_allocation: bool,
+        layout: &MemoryLayout,
+    ) -> Result<SystemAllocator> {
+        let (high_mmio_start, high_mmio_size) = Self::get_high_mmio_base_size(mem, layout)?;
+        Ok(SystemAllocator::builder()
             .add_io_addresses(0xc000, 0x10000)
-            .add_low_mmio_addresses(END_ADDR_BEFORE_32BITS, MMIO_SIZE)
-            .add_high_mmio_addresses(high_mmio_start, u64::max_value() - high_mmio_start)
+            .add_low_mmio_addresses(layout.low_mmio_start, layout.low_mmio_size())
+            .add_high_mmio_addresses(high_mmio_start, high_mmio_size)
             .create_allocator(X86_64_IRQ_BASE, gpu_allocation)
-            .unwrap()
+            .unwrap())
     }
 
     /// Sets up the IO bus for this platform
@@ -959,11 +1156,15 @@ impl X8664arch {
     /// * - `pit_uses_speaker_port` - does the PIT use port 0x61 for the PC speaker
     /// * - `exit_evt` - the event object which should receive exit events
     /// * - `mem_size` - the size in bytes of physical ram for the guest
+    /// * - `layout` - the layout of the guest address space
+    /// * - `rtc_wake_alarm` - receives the wake time the guest programs into the RTC
     fn setup_io_bus(
         pit_uses_speaker_port: bool,
         exit_evt: Event,
         pci: Option<Arc<Mutex<devices::PciConfigIo>>>,
         mem_size: u64,
+        layout: &MemoryLayout,
+        rtc_wake_alarm: RtcWakeAlarm,
     ) -> Result<devices::Bus> {
         struct NoDevice;
         impl devices::BusDevice for NoDevice {
@@ -972,9 +1173,11 @@ impl X8664arch {
             }
         }
 
+        impl devices::Suspendable for NoDevice {}
+
         let mut io_bus = devices::Bus::new();
 
-        let mem_regions = arch_memory_regions(mem_size, false);
+        let mem_regions = arch_memory_regions(mem_size, false, layout);
 
         let mem_below_4g = mem_regions
             .iter()
@@ -988,13 +1191,13 @@ impl X8664arch {
             .map(|r| r.1)
             .sum();
 
-        io_bus
-            .insert(
-                Arc::new(Mutex::new(devices::Cmos::new(mem_below_4g, mem_above_4g))),
-                0x70,
-                0x2,
-            )
-            .unwrap();
+        let cmos = Arc::new(Mutex::new(devices::Cmos::new(
+            mem_below_4g,
+            mem_above_4g,
+            rtc_wake_alarm,
+        )));
+        io_bus.insert(cmos.clone(), 0x70, 0x2).unwrap();
+        io_bus.notify_on_resume(cmos);
 
         let nul_device = Arc::new(Mutex::new(NoDevice));
         let i8042 = Arc::new(Mutex::new(devices::I8042Device::new(
@@ -1148,7 +1351,11 @@ mod tests {
 
     #[test]
     fn regions_lt_4gb_nobios() {
-        let regions = arch_memory_regions(1u64 << 29, /* has_bios */ false);
+        let regions = arch_memory_regions(
+            1u64 << 29,
+            /* has_bios */ false,
+            &MemoryLayout::default(),
+        );
         assert_eq!(1, regions.len());
         assert_eq!(GuestAddress(0), regions[0].0);
         assert_eq!(1u64 << 29, regions[0].1);
@@ -1156,7 +1363,11 @@ mod tests {
 
     #[test]
     fn regions_gt_4gb_nobios() {
-        let regions = arch_memory_regions((1u64 << 32) + 0x8000, /* has_bios */ false);
+        let regions = arch_memory_regions(
+            (1u64 << 32) + 0x8000,
+            /* has_bios */ false,
+            &MemoryLayout::default(),
+        );
         assert_eq!(2, regions.len());
         assert_eq!(GuestAddress(0), regions[0].0);
         assert_eq!(GuestAddress(1u64 << 32), regions[1].0);
@@ -1164,7 +1375,11 @@ mod tests {
 
     #[test]
     fn regions_lt_4gb_bios() {
-        let regions = arch_memory_regions(1u64 << 29, /* has_bios */ true);
+        let regions = arch_memory_regions(
+            1u64 << 29,
+            /* has_bios */ true,
+            &MemoryLayout::default(),
+        );
         assert_eq!(2, regions.len());
         assert_eq!(GuestAddress(0), regions[0].0);
         assert_eq!(1u64 << 29, regions[0].1);
@@ -1174,7 +1389,11 @@ mod tests {
 
     #[test]
     fn regions_gt_4gb_bios() {
-        let regions = arch_memory_regions((1u64 << 32) + 0x8000, /* has_bios */ true);
+        let regions = arch_memory_regions(
+            (1u64 << 32) + 0x8000,
+            /* has_bios */ true,
+            &MemoryLayout::default(),
+        );
         assert_eq!(3, regions.len());
         assert_eq!(GuestAddress(0), regions[0].0);
         assert_eq!(GuestAddress(BIOS_START), regions[1].0);
@@ -1185,7 +1404,11 @@ mod tests {
     #[test]
     fn regions_eq_4gb_nobios() {
         // Test with size = 3328, which is exactly 4 GiB minus the size of the gap (768 MiB).
-        let regions = arch_memory_regions(3328 << 20, /* has_bios */ false);
+        let regions = arch_memory_regions(
+            3328 << 20,
+            /* has_bios */ false,
+            &MemoryLayout::default(),
+        );
         assert_eq!(1, regions.len());
         assert_eq!(GuestAddress(0), regions[0].0);
         assert_eq!(3328 << 20, regions[0].1);
@@ -1194,11 +1417,66 @@ mod tests {
     #[test]
     fn regions_eq_4gb_bios() {
         // Test with size = 3328, which is exactly 4 GiB minus the size of the gap (768 MiB).
-        let regions = arch_memory_regions(3328 << 20, /* has_bios */ true);
+        let regions = arch_memory_regions(
+            3328 << 20,
+            /* has_bios */ true,
+            &MemoryLayout::default(),
+        );
         assert_eq!(2, regions.len());
         assert_eq!(GuestAddress(0), regions[0].0);
         assert_eq!(3328 << 20, regions[0].1);
         assert_eq!(GuestAddress(BIOS_START), regions[1].0);
         assert_eq!(BIOS_LEN as u64, regions[1].1);
     }
+
+    #[test]
+    fn regions_larger_gap() {
+        let layout = MemoryLayout::new(&ArchLayout {
+            low_mmio_size: Some(2 << 30),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(layout.low_mmio_size(), (2 << 30) - RESERVED_32BIT_GAP_SIZE);
+        let regions = arch_memory_regions(3 << 30, /* has_bios */ false, &layout);
+        assert_eq!(2, regions.len());
+        assert_eq!((GuestAddress(0), 2 << 30), regions[0]);
+        assert_eq!((GuestAddress(1u64 << 32), 1 << 30), regions[1]);
+    }
+
+    #[test]
+    fn invalid_layouts() {
+        let invalid = [
+            ArchLayout {
+                ram_start: Some(1 << 20),
+                ..Default::default()
+            },
+            ArchLayout {
+                low_mmio_size: Some(100 << 20),
+                ..Default::default()
+            },
+            ArchLayout {
+                low_mmio_size: Some(4 << 30),
+                ..Default::default()
+            },
+            ArchLayout {
+                identity_map_addr: Some(0xfffbc800),
+                ..Default::default()
+            },
+            ArchLayout {
+                identity_map_addr: Some(0x1000),
+                ..Default::default()
+            },
+            ArchLayout {
+                identity_map_addr: Some(0xfebfe000),
+                ..Default::default()
+            },
+            ArchLayout {
+                identity_map_addr: Some(0xfee00000),
+                ..Default::default()
+            },
+        ];
+        for layout in &invalid {
+            assert!(MemoryLayout::new(layout).is_err(), "{:?}", layout);
+        }
+    }
 }
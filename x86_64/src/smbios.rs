@@ -154,7 +154,19 @@ fn write_string(mem: &GuestMemory, val: &str, mut curptr: GuestAddress) -> Resul
     Ok(curptr)
 }
 
-pub fn setup_smbios(mem: &GuestMemory) -> Result<()> {
+// Lays out a UUID given in RFC 4122 order the way SMBIOS stores it, with the first three fields
+// little-endian.
+fn smbios_uuid(uuid: [u8; 16]) -> [u8; 16] {
+    let mut smbios_uuid = uuid;
+    smbios_uuid[0..4].reverse();
+    smbios_uuid[4..6].reverse();
+    smbios_uuid[6..8].reverse();
+    smbios_uuid
+}
+
+/// Writes the SMBIOS tables to guest memory. `uuid` becomes the system UUID, in RFC 4122 byte
+/// order; without one the UUID is left unset.
+pub fn setup_smbios(mem: &GuestMemory, uuid: Option<[u8; 16]>) -> Result<()> {
     let physptr = GuestAddress(SMBIOS_START)
         .checked_add(mem::size_of::<Smbios30Entrypoint>() as u64)
         .ok_or(Error::NotEnoughMemory)?;
@@ -185,6 +197,9 @@ pub fn setup_smbios(mem: &GuestMemory) -> Result<()> {
         smbios_sysinfo.handle = handle;
         smbios_sysinfo.manufacturer = 1; // First string written in this section
         smbios_sysinfo.product_name = 2; // Second string written in this section
+        if let Some(uuid) = uuid {
+            smbios_sysinfo.uuid = smbios_uuid(uuid);
+        }
         curptr = write_and_incr(mem, smbios_sysinfo, curptr)?;
         curptr = write_string(mem, "ChromiumOS", curptr)?;
         curptr = write_string(mem, "crosvm", curptr)?;
@@ -237,11 +252,37 @@ mod tests {
     fn entrypoint_checksum() {
         let mem = GuestMemory::new(&[(GuestAddress(SMBIOS_START), 4096)]).unwrap();
 
-        setup_smbios(&mem).unwrap();
+        setup_smbios(&mem, None).unwrap();
 
         let smbios_ep: Smbios30Entrypoint =
             mem.read_obj_from_addr(GuestAddress(SMBIOS_START)).unwrap();
 
         assert_eq!(compute_checksum(&smbios_ep), 0);
     }
+
+    #[test]
+    fn system_uuid() {
+        let mem = GuestMemory::new(&[(GuestAddress(SMBIOS_START), 4096)]).unwrap();
+        let uuid = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+            0xee, 0xff,
+        ];
+
+        setup_smbios(&mem, Some(uuid)).unwrap();
+
+        // The system information follows the entry point and the BIOS information, which ends
+        // with two strings and the terminating null.
+        let bios_info_len = mem::size_of::<SmbiosBiosInfo>() + "crosvm\0".len() + "0\0".len() + 1;
+        let sysinfo_addr = GuestAddress(SMBIOS_START)
+            .unchecked_add((mem::size_of::<Smbios30Entrypoint>() + bios_info_len) as u64);
+        let sysinfo: SmbiosSysInfo = mem.read_obj_from_addr(sysinfo_addr).unwrap();
+        assert_eq!(sysinfo.typ, SYSTEM_INFORMATION);
+        assert_eq!(
+            sysinfo.uuid,
+            [
+                0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+                0xee, 0xff,
+            ]
+        );
+    }
 }